@@ -0,0 +1,112 @@
+use aws_lambda_events::event::s3::S3Event;
+use databricks_zerobus_ingest_sdk::{StreamConfigurationOptions, TableProperties};
+use lambda_runtime::{Error, LambdaEvent};
+#[cfg(feature = "otel")]
+use tracing::Instrument;
+use tracing::{error, info};
+#[cfg(feature = "otel")]
+use zerobus_common::otel;
+use zerobus_common::pipeline::{max_pending_bytes_from_env, Pipeline};
+use zerobus_common::s3::{self, decode_object_key, open_object};
+
+use crate::ingest::{ingest_access_log, ObjectSource};
+use crate::proto::load_descriptor_proto;
+use crate::sdk::init_sdk;
+
+/// Maximum number of unacknowledged records per stream; also bounds memory per file
+const MAX_INFLIGHT_RECORDS: usize = 1000;
+
+/// Lambda handler function
+pub async fn function_handler(event: LambdaEvent<S3Event>) -> Result<(), Error> {
+    let sdk = init_sdk().map_err(|e| Error::from(format!("Failed to initialize SDK: {}", e)))?;
+
+    let table_name = std::env::var("TABLE_NAME")
+        .map_err(|_| Error::from("TABLE_NAME environment variable must be set"))?;
+    let client_id = std::env::var("DATABRICKS_CLIENT_ID")
+        .map_err(|_| Error::from("DATABRICKS_CLIENT_ID environment variable must be set"))?;
+    let client_secret = std::env::var("DATABRICKS_CLIENT_SECRET")
+        .map_err(|_| Error::from("DATABRICKS_CLIENT_SECRET environment variable must be set"))?;
+
+    // Load descriptor
+    let descriptor_proto = load_descriptor_proto("elb_access_logs.proto", "table_elb_access_logs");
+
+    // Configure table properties
+    let table_properties = TableProperties {
+        table_name: table_name.clone(),
+        descriptor_proto,
+    };
+
+    // Configure stream options
+    let stream_options = StreamConfigurationOptions {
+        max_inflight_records: MAX_INFLIGHT_RECORDS,
+        ..Default::default()
+    };
+
+    // Create stream
+    let stream = sdk.create_stream(
+        table_properties,
+        client_id,
+        client_secret,
+        Some(stream_options),
+    );
+    #[cfg(feature = "otel")]
+    let stream = stream.instrument(otel::create_stream_span(&table_name));
+    let stream = stream
+        .await
+        .map_err(|e| Error::from(format!("Failed to create stream: {}", e)))?;
+    let max_pending_bytes = max_pending_bytes_from_env().map_err(|e| Error::from(e.to_string()))?;
+    let pipeline = Pipeline::new(stream, MAX_INFLIGHT_RECORDS).max_pending_bytes(max_pending_bytes);
+    #[cfg(feature = "otel")]
+    let pipeline = pipeline.trace_records(&table_name);
+    let mut pipeline = pipeline;
+
+    let s3 = s3::client().await;
+
+    for record in event.payload.records {
+        let (Some(bucket), Some(raw_key)) = (record.s3.bucket.name, record.s3.object.key) else {
+            error!("Skipping S3 notification without bucket or key");
+            continue;
+        };
+        let key = decode_object_key(&raw_key).map_err(|e| Error::from(e.to_string()))?;
+
+        info!("Processing s3://{}/{}", bucket, key);
+        let reader = open_object(s3, &bucket, &key)
+            .await
+            .map_err(|e| Error::from(format!("{:#}", e)))?;
+
+        let source = ObjectSource {
+            bucket: &bucket,
+            key: &key,
+        };
+        let stats = ingest_access_log(reader, &source, &mut pipeline)
+            .await
+            .map_err(|e| {
+                Error::from(format!("Failed to ingest s3://{}/{}: {:#}", bucket, key, e))
+            })?;
+
+        info!(
+            "Read {} requests from s3://{}/{} ({} malformed lines skipped)",
+            stats.rows, bucket, key, stats.malformed
+        );
+    }
+
+    // Wait for all remaining acks and close the stream
+    let finished = pipeline.finish();
+    #[cfg(feature = "otel")]
+    let finished = finished.instrument(otel::close_stream_span(&table_name));
+    let summary = finished
+        .await
+        .map_err(|e| Error::from(format!("Failed to close stream: {}", e)))?;
+
+    if summary.failed > 0 {
+        // Failing the invocation makes Lambda retry the notification; rows that were
+        // already acknowledged will be ingested again
+        return Err(Error::from(format!(
+            "{} rows were not acknowledged: {}",
+            summary.failed,
+            summary.first_error.unwrap_or_default()
+        )));
+    }
+
+    Ok(())
+}
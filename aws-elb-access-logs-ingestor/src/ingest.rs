@@ -0,0 +1,122 @@
+use anyhow::{Context, Result};
+use prost::Message;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+use tracing::warn;
+use zerobus_common::pipeline::{IngestSink, Pipeline};
+
+use crate::parser::{parse_line, AccessLogEntry};
+use crate::proto::elb_access_logs::TableElbAccessLogs;
+
+/// Where an access log file came from, stamped on every row
+pub struct ObjectSource<'a> {
+    pub bucket: &'a str,
+    pub key: &'a str,
+}
+
+/// Per-file counts
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct FileStats {
+    pub rows: u64,
+    pub malformed: u64,
+}
+
+/// Convert a parsed access log entry into a table row
+pub fn to_table_row(
+    entry: AccessLogEntry,
+    source: &ObjectSource<'_>,
+    ingested_at: i64,
+    ingested_date: i32,
+) -> TableElbAccessLogs {
+    TableElbAccessLogs {
+        variant: entry.variant.map(|v| v.as_str().to_string()),
+        request_type: entry.request_type,
+        time: entry.time,
+        elb: entry.elb,
+        client_ip: entry.client_ip,
+        client_port: entry.client_port,
+        target_ip: entry.target_ip,
+        target_port: entry.target_port,
+        request_processing_time: entry.request_processing_time,
+        target_processing_time: entry.target_processing_time,
+        response_processing_time: entry.response_processing_time,
+        elb_status_code: entry.elb_status_code,
+        target_status_code: entry.target_status_code,
+        received_bytes: entry.received_bytes,
+        sent_bytes: entry.sent_bytes,
+        request_verb: entry.request_verb,
+        request_url: entry.request_url,
+        request_protocol: entry.request_protocol,
+        user_agent: entry.user_agent,
+        ssl_cipher: entry.ssl_cipher,
+        ssl_protocol: entry.ssl_protocol,
+        target_group_arn: entry.target_group_arn,
+        trace_id: entry.trace_id,
+        domain_name: entry.domain_name,
+        chosen_cert_arn: entry.chosen_cert_arn,
+        matched_rule_priority: entry.matched_rule_priority,
+        request_creation_time: entry.request_creation_time,
+        actions_executed: entry.actions_executed,
+        redirect_url: entry.redirect_url,
+        error_reason: entry.error_reason,
+        target_port_list: entry.target_port_list,
+        target_status_code_list: entry.target_status_code_list,
+        classification: entry.classification,
+        classification_reason: entry.classification_reason,
+        conn_trace_id: entry.conn_trace_id,
+        s3_bucket: Some(source.bucket.to_string()),
+        s3_key: Some(source.key.to_string()),
+        ingested_at: Some(ingested_at),
+        ingested_date: Some(ingested_date),
+    }
+}
+
+/// Read an access log file line by line and ingest one row per request
+///
+/// Malformed or truncated lines are logged and skipped rather than failing the file.
+pub async fn ingest_access_log<R, S>(
+    reader: R,
+    source: &ObjectSource<'_>,
+    pipeline: &mut Pipeline<S>,
+) -> Result<FileStats>
+where
+    R: AsyncBufRead + Unpin,
+    S: IngestSink,
+{
+    // Get current timestamp in microseconds
+    let now = std::time::SystemTime::now();
+    let ingested_at = now
+        .duration_since(std::time::UNIX_EPOCH)
+        .context("Failed to get system time")?
+        .as_micros() as i64;
+
+    // Get the current date as int32 (days since Unix epoch)
+    let ingested_date = now
+        .duration_since(std::time::UNIX_EPOCH)
+        .context("Failed to get system time")?
+        .as_secs() as i32
+        / 86400;
+
+    let mut stats = FileStats::default();
+    let mut lines = reader.lines();
+    let mut line_number = 0;
+    while let Some(line) = lines.next_line().await? {
+        line_number += 1;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        match parse_line(&line) {
+            Ok(entry) => {
+                let row = to_table_row(entry, source, ingested_at, ingested_date);
+                pipeline.ingest(row.encode_to_vec()).await?;
+                stats.rows += 1;
+            }
+            Err(e) => {
+                warn!("Skipping {}:{}: {}", source.key, line_number, e);
+                stats.malformed += 1;
+            }
+        }
+    }
+
+    Ok(stats)
+}
@@ -0,0 +1,6 @@
+pub mod handler;
+pub mod ingest;
+pub mod parser;
+pub mod proto;
+pub mod sdk;
+pub mod tokenizer;
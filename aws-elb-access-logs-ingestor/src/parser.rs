@@ -0,0 +1,414 @@
+use anyhow::{bail, Context, Result};
+use chrono::DateTime;
+
+use crate::tokenizer::{tokenize, Token};
+
+/// Which load balancer produced the log line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogVariant {
+    /// Application Load Balancer: lines start with the request type (`http`, `https`, `h2`, ...)
+    Application,
+    /// Classic Load Balancer: lines start with the timestamp
+    Classic,
+}
+
+impl LogVariant {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Application => "alb",
+            Self::Classic => "classic",
+        }
+    }
+}
+
+/// One parsed access log entry; `-` placeholders become `None`
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct AccessLogEntry {
+    pub variant: Option<LogVariant>,
+    /// Request type (ALB only): http, https, h2, grpcs, ws, wss
+    pub request_type: Option<String>,
+    /// Time the response was generated, in microseconds since the Unix epoch
+    pub time: Option<i64>,
+    pub elb: Option<String>,
+    pub client_ip: Option<String>,
+    pub client_port: Option<i32>,
+    pub target_ip: Option<String>,
+    pub target_port: Option<i32>,
+    pub request_processing_time: Option<f64>,
+    pub target_processing_time: Option<f64>,
+    pub response_processing_time: Option<f64>,
+    pub elb_status_code: Option<i32>,
+    pub target_status_code: Option<i32>,
+    pub received_bytes: Option<i64>,
+    pub sent_bytes: Option<i64>,
+    pub request_verb: Option<String>,
+    pub request_url: Option<String>,
+    pub request_protocol: Option<String>,
+    pub user_agent: Option<String>,
+    pub ssl_cipher: Option<String>,
+    pub ssl_protocol: Option<String>,
+    pub target_group_arn: Option<String>,
+    pub trace_id: Option<String>,
+    pub domain_name: Option<String>,
+    pub chosen_cert_arn: Option<String>,
+    pub matched_rule_priority: Option<i32>,
+    /// Time the request was received, in microseconds since the Unix epoch
+    pub request_creation_time: Option<i64>,
+    pub actions_executed: Option<String>,
+    pub redirect_url: Option<String>,
+    pub error_reason: Option<String>,
+    pub target_port_list: Option<String>,
+    pub target_status_code_list: Option<String>,
+    pub classification: Option<String>,
+    pub classification_reason: Option<String>,
+    pub conn_trace_id: Option<String>,
+}
+
+/// Number of fields every classic ELB line has
+const CLASSIC_FIELDS: usize = 15;
+
+/// Number of fields an ALB line must have; later fields were added over time and are optional
+const ALB_REQUIRED_FIELDS: usize = 17;
+
+/// Parse one access log line, detecting the ALB or classic ELB variant
+pub fn parse_line(line: &str) -> Result<AccessLogEntry> {
+    let tokens = tokenize(line)?;
+    let Some(first) = tokens.first() else {
+        bail!("Empty log line");
+    };
+
+    // Classic lines start with an ISO 8601 timestamp; ALB lines with the request type
+    if first.value.starts_with(|c: char| c.is_ascii_digit()) {
+        parse_classic(&tokens)
+    } else {
+        parse_alb(&tokens)
+    }
+}
+
+fn parse_classic(tokens: &[Token]) -> Result<AccessLogEntry> {
+    if tokens.len() < CLASSIC_FIELDS {
+        bail!(
+            "Truncated classic ELB line: expected {} fields, found {}",
+            CLASSIC_FIELDS,
+            tokens.len()
+        );
+    }
+
+    let mut entry = AccessLogEntry {
+        variant: Some(LogVariant::Classic),
+        ..Default::default()
+    };
+    let mut fields = Fields::new(tokens);
+    entry.time = fields.timestamp("time")?;
+    entry.elb = fields.string();
+    (entry.client_ip, entry.client_port) = fields.address("client:port")?;
+    (entry.target_ip, entry.target_port) = fields.address("backend:port")?;
+    entry.request_processing_time = fields.number("request_processing_time")?;
+    entry.target_processing_time = fields.number("backend_processing_time")?;
+    entry.response_processing_time = fields.number("response_processing_time")?;
+    entry.elb_status_code = fields.number("elb_status_code")?;
+    entry.target_status_code = fields.number("backend_status_code")?;
+    entry.received_bytes = fields.number("received_bytes")?;
+    entry.sent_bytes = fields.number("sent_bytes")?;
+    (
+        entry.request_verb,
+        entry.request_url,
+        entry.request_protocol,
+    ) = fields.request();
+    entry.user_agent = fields.string();
+    entry.ssl_cipher = fields.string();
+    entry.ssl_protocol = fields.string();
+
+    Ok(entry)
+}
+
+fn parse_alb(tokens: &[Token]) -> Result<AccessLogEntry> {
+    if tokens.len() < ALB_REQUIRED_FIELDS {
+        bail!(
+            "Truncated ALB line: expected at least {} fields, found {}",
+            ALB_REQUIRED_FIELDS,
+            tokens.len()
+        );
+    }
+
+    let mut entry = AccessLogEntry {
+        variant: Some(LogVariant::Application),
+        ..Default::default()
+    };
+    let mut fields = Fields::new(tokens);
+    entry.request_type = fields.string();
+    entry.time = fields.timestamp("time")?;
+    entry.elb = fields.string();
+    (entry.client_ip, entry.client_port) = fields.address("client:port")?;
+    (entry.target_ip, entry.target_port) = fields.address("target:port")?;
+    entry.request_processing_time = fields.number("request_processing_time")?;
+    entry.target_processing_time = fields.number("target_processing_time")?;
+    entry.response_processing_time = fields.number("response_processing_time")?;
+    entry.elb_status_code = fields.number("elb_status_code")?;
+    entry.target_status_code = fields.number("target_status_code")?;
+    entry.received_bytes = fields.number("received_bytes")?;
+    entry.sent_bytes = fields.number("sent_bytes")?;
+    (
+        entry.request_verb,
+        entry.request_url,
+        entry.request_protocol,
+    ) = fields.request();
+    entry.user_agent = fields.string();
+    entry.ssl_cipher = fields.string();
+    entry.ssl_protocol = fields.string();
+    entry.target_group_arn = fields.string();
+
+    // Optional trailing fields, in the order AWS added them
+    entry.trace_id = fields.string();
+    entry.domain_name = fields.string();
+    entry.chosen_cert_arn = fields.string();
+    entry.matched_rule_priority = fields.number("matched_rule_priority")?;
+    entry.request_creation_time = fields.timestamp("request_creation_time")?;
+    entry.actions_executed = fields.string();
+    entry.redirect_url = fields.string();
+    entry.error_reason = fields.string();
+    entry.target_port_list = fields.string();
+    entry.target_status_code_list = fields.string();
+    entry.classification = fields.string();
+    entry.classification_reason = fields.string();
+    entry.conn_trace_id = fields.string();
+
+    Ok(entry)
+}
+
+/// Cursor over the tokens of one line; reading past the end yields `None` so optional
+/// trailing fields simply come out empty
+struct Fields<'a> {
+    tokens: std::slice::Iter<'a, Token>,
+}
+
+impl<'a> Fields<'a> {
+    fn new(tokens: &'a [Token]) -> Self {
+        Self {
+            tokens: tokens.iter(),
+        }
+    }
+
+    fn next_value(&mut self) -> Option<&'a str> {
+        self.tokens.next().and_then(Token::value)
+    }
+
+    fn string(&mut self) -> Option<String> {
+        self.next_value().map(str::to_string)
+    }
+
+    fn number<T: std::str::FromStr>(&mut self, name: &str) -> Result<Option<T>>
+    where
+        T::Err: std::error::Error + Send + Sync + 'static,
+    {
+        self.next_value()
+            .map(|value| {
+                value
+                    .parse()
+                    .with_context(|| format!("Invalid {}: {:?}", name, value))
+            })
+            .transpose()
+    }
+
+    fn timestamp(&mut self, name: &str) -> Result<Option<i64>> {
+        self.next_value()
+            .map(|value| {
+                DateTime::parse_from_rfc3339(value)
+                    .map(|t| t.timestamp_micros())
+                    .with_context(|| format!("Invalid {}: {:?}", name, value))
+            })
+            .transpose()
+    }
+
+    /// Split an `ip:port` field; IPv6 addresses keep their inner colons
+    fn address(&mut self, name: &str) -> Result<(Option<String>, Option<i32>)> {
+        let Some(value) = self.next_value() else {
+            return Ok((None, None));
+        };
+        let (ip, port) = value
+            .rsplit_once(':')
+            .with_context(|| format!("Invalid {}: {:?}", name, value))?;
+        let port = port
+            .parse()
+            .with_context(|| format!("Invalid port in {}: {:?}", name, value))?;
+        let ip = ip.trim_start_matches('[').trim_end_matches(']');
+        Ok((Some(ip.to_string()), Some(port)))
+    }
+
+    /// Split the quoted request field into verb, URL, and protocol
+    fn request(&mut self) -> (Option<String>, Option<String>, Option<String>) {
+        let Some(value) = self.next_value().map(str::trim) else {
+            return (None, None, None);
+        };
+        let part = |s: &str| (s != "-" && !s.is_empty()).then(|| s.to_string());
+
+        match value.split_once(' ') {
+            Some((verb, rest)) => match rest.rsplit_once(' ') {
+                Some((url, protocol)) => (part(verb), part(url), part(protocol)),
+                None => (part(verb), part(rest), None),
+            },
+            None => (part(value), None, None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALB_HTTP: &str = r#"http 2018-07-02T22:23:00.186641Z app/my-loadbalancer/50dc6c495c0c9188 192.168.131.39:2817 10.0.0.1:80 0.000 0.001 0.000 200 200 34 366 "GET http://www.example.com:80/ HTTP/1.1" "curl/7.46.0" - - arn:aws:elasticloadbalancing:us-east-2:123456789012:targetgroup/my-targets/73e2d6bc24d8a067 "Root=1-58337262-36d228ad5d99923122bbe354" "-" "-" 0 2018-07-02T22:22:48.364000Z "forward" "-" "-" "10.0.0.1:80" "200" "-" "-" TID_1234abcd5678ef90"#;
+
+    const ALB_HTTPS: &str = r#"https 2018-07-02T22:23:00.186641Z app/my-loadbalancer/50dc6c495c0c9188 192.168.131.39:2817 10.0.0.1:80 0.086 0.048 0.037 200 200 0 57 "GET https://www.example.com:443/ HTTP/1.1" "Mozilla/5.0 (Windows NT 10.0; Win64; x64) \"quoted\"" ECDHE-RSA-AES128-GCM-SHA256 TLSv1.2 arn:aws:elasticloadbalancing:us-east-2:123456789012:targetgroup/my-targets/73e2d6bc24d8a067 "Root=1-58337281-1d84f3d73c47ec4e58577259" "www.example.com" "arn:aws:acm:us-east-2:123456789012:certificate/12345678-1234-1234-1234-123456789012" 1 2018-07-02T22:22:48.364000Z "authenticate,forward" "-" "-" "10.0.0.1:80" "200" "-" "-""#;
+
+    const ALB_NO_TARGET: &str = r#"http 2018-11-30T22:23:00.186641Z app/my-loadbalancer/50dc6c495c0c9188 192.168.131.39:2817 - 0.000 0.001 0.000 502 - 34 366 "GET http://www.example.com:80/ HTTP/1.1" "curl/7.46.0" - - arn:aws:elasticloadbalancing:us-east-2:123456789012:targetgroup/my-targets/73e2d6bc24d8a067"#;
+
+    const ALB_IPV6: &str = r#"h2 2018-07-02T22:23:00.186641Z app/my-loadbalancer/50dc6c495c0c9188 2001:db8:85a3::8a2e:370:7334:2817 10.0.0.1:80 -1 -1 -1 460 - 34 0 "POST https://www.example.com:443/api/v1 HTTP/2.0" "-" ECDHE-RSA-AES128-GCM-SHA256 TLSv1.2 -"#;
+
+    const CLASSIC: &str = r#"2015-05-13T23:39:43.945958Z my-loadbalancer 192.168.131.39:2817 10.0.0.1:80 0.000073 0.001048 0.000057 200 200 0 29 "GET http://www.example.com:80/ HTTP/1.1" "curl/7.38.0" - -"#;
+
+    const CLASSIC_TCP: &str = r#"2015-05-13T23:39:43.945958Z my-loadbalancer 192.168.131.39:2817 10.0.0.1:80 0.001069 0.000028 0.000041 - - 82 305 "- - - " "-" - -"#;
+
+    #[test]
+    fn test_parse_alb_http() {
+        let entry = parse_line(ALB_HTTP).unwrap();
+
+        assert_eq!(Some(LogVariant::Application), entry.variant);
+        assert_eq!(Some("http".to_string()), entry.request_type);
+        assert_eq!(Some(1530570180186641), entry.time);
+        assert_eq!(
+            Some("app/my-loadbalancer/50dc6c495c0c9188".to_string()),
+            entry.elb
+        );
+        assert_eq!(Some("192.168.131.39".to_string()), entry.client_ip);
+        assert_eq!(Some(2817), entry.client_port);
+        assert_eq!(Some("10.0.0.1".to_string()), entry.target_ip);
+        assert_eq!(Some(80), entry.target_port);
+        assert_eq!(Some(0.001), entry.target_processing_time);
+        assert_eq!(Some(200), entry.elb_status_code);
+        assert_eq!(Some(34), entry.received_bytes);
+        assert_eq!(Some(366), entry.sent_bytes);
+        assert_eq!(Some("GET".to_string()), entry.request_verb);
+        assert_eq!(
+            Some("http://www.example.com:80/".to_string()),
+            entry.request_url
+        );
+        assert_eq!(Some("HTTP/1.1".to_string()), entry.request_protocol);
+        assert_eq!(Some("curl/7.46.0".to_string()), entry.user_agent);
+        assert_eq!(None, entry.ssl_cipher);
+        assert_eq!(
+            Some("Root=1-58337262-36d228ad5d99923122bbe354".to_string()),
+            entry.trace_id
+        );
+        assert_eq!(None, entry.domain_name);
+        assert_eq!(Some(0), entry.matched_rule_priority);
+        assert_eq!(Some(1530570168364000), entry.request_creation_time);
+        assert_eq!(Some("forward".to_string()), entry.actions_executed);
+        assert_eq!(Some("10.0.0.1:80".to_string()), entry.target_port_list);
+        assert_eq!(
+            Some("TID_1234abcd5678ef90".to_string()),
+            entry.conn_trace_id
+        );
+    }
+
+    #[test]
+    fn test_parse_alb_https_with_escaped_user_agent() {
+        let entry = parse_line(ALB_HTTPS).unwrap();
+
+        assert_eq!(
+            Some(r#"Mozilla/5.0 (Windows NT 10.0; Win64; x64) "quoted""#.to_string()),
+            entry.user_agent
+        );
+        assert_eq!(
+            Some("ECDHE-RSA-AES128-GCM-SHA256".to_string()),
+            entry.ssl_cipher
+        );
+        assert_eq!(Some("TLSv1.2".to_string()), entry.ssl_protocol);
+        assert_eq!(Some("www.example.com".to_string()), entry.domain_name);
+        assert_eq!(
+            Some("authenticate,forward".to_string()),
+            entry.actions_executed
+        );
+        // This line predates the conn_trace_id field
+        assert_eq!(None, entry.conn_trace_id);
+    }
+
+    #[test]
+    fn test_parse_alb_without_target_and_optional_fields() {
+        let entry = parse_line(ALB_NO_TARGET).unwrap();
+
+        assert_eq!(None, entry.target_ip);
+        assert_eq!(None, entry.target_port);
+        assert_eq!(Some(502), entry.elb_status_code);
+        assert_eq!(None, entry.target_status_code);
+        assert_eq!(None, entry.trace_id);
+        assert_eq!(None, entry.request_creation_time);
+    }
+
+    #[test]
+    fn test_parse_alb_ipv6_client_and_dispatch_failure() {
+        let entry = parse_line(ALB_IPV6).unwrap();
+
+        assert_eq!(
+            Some("2001:db8:85a3::8a2e:370:7334".to_string()),
+            entry.client_ip
+        );
+        assert_eq!(Some(2817), entry.client_port);
+        // -1 is a real value meaning the load balancer could not dispatch the request
+        assert_eq!(Some(-1.0), entry.request_processing_time);
+        assert_eq!(Some("POST".to_string()), entry.request_verb);
+        assert_eq!(Some("HTTP/2.0".to_string()), entry.request_protocol);
+        assert_eq!(None, entry.user_agent);
+        assert_eq!(None, entry.target_group_arn);
+    }
+
+    #[test]
+    fn test_parse_classic_http() {
+        let entry = parse_line(CLASSIC).unwrap();
+
+        assert_eq!(Some(LogVariant::Classic), entry.variant);
+        assert_eq!(None, entry.request_type);
+        assert_eq!(Some(1431560383945958), entry.time);
+        assert_eq!(Some("my-loadbalancer".to_string()), entry.elb);
+        assert_eq!(Some("10.0.0.1".to_string()), entry.target_ip);
+        assert_eq!(Some(0.000073), entry.request_processing_time);
+        assert_eq!(Some(200), entry.target_status_code);
+        assert_eq!(Some("GET".to_string()), entry.request_verb);
+        assert_eq!(Some("curl/7.38.0".to_string()), entry.user_agent);
+        assert_eq!(None, entry.ssl_protocol);
+    }
+
+    #[test]
+    fn test_parse_classic_tcp_listener() {
+        let entry = parse_line(CLASSIC_TCP).unwrap();
+
+        assert_eq!(None, entry.elb_status_code);
+        assert_eq!(None, entry.request_verb);
+        assert_eq!(None, entry.request_url);
+        assert_eq!(None, entry.request_protocol);
+        assert_eq!(None, entry.user_agent);
+    }
+
+    #[test]
+    fn test_truncated_lines_are_rejected() {
+        // Cut inside the quoted request field
+        assert!(parse_line(&ALB_HTTP[..180]).is_err());
+        // Cut before the required target group ARN
+        let cut = ALB_NO_TARGET.rfind(" arn:").unwrap();
+        assert!(parse_line(&ALB_NO_TARGET[..cut]).is_err());
+        // Classic line missing the SSL fields
+        assert!(parse_line(CLASSIC.trim_end_matches(" - -")).is_err());
+        assert!(parse_line("").is_err());
+    }
+
+    #[test]
+    fn test_invalid_values_are_rejected() {
+        let bad_status = ALB_HTTP.replacen(" 200 200 ", " OK 200 ", 1);
+        assert!(parse_line(&bad_status).is_err());
+
+        let bad_time = CLASSIC.replacen("2015-05-13T23:39:43.945958Z", "2015-05-13 23:39", 1);
+        assert!(parse_line(&bad_time).is_err());
+
+        let bad_port = CLASSIC.replacen("192.168.131.39:2817", "192.168.131.39", 1);
+        assert!(parse_line(&bad_port).is_err());
+    }
+}
@@ -0,0 +1,13 @@
+use prost_types::DescriptorProto;
+
+// Module for generated protobuf code
+pub mod elb_access_logs {
+    include!("../gen/rust/elb_access_logs.rs");
+}
+
+/// Load the protobuf descriptor from the embedded descriptor file
+pub fn load_descriptor_proto(file_name: &str, message_name: &str) -> DescriptorProto {
+    const DESCRIPTOR_BYTES: &[u8] = include_bytes!("../gen/descriptors/elb_access_logs.descriptor");
+
+    zerobus_common::descriptor::load_descriptor_proto(DESCRIPTOR_BYTES, file_name, message_name)
+}
@@ -0,0 +1,158 @@
+use anyhow::{bail, Result};
+
+/// One space-delimited field of an access log line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    pub value: String,
+    /// Whether the field was wrapped in double quotes in the source line
+    pub quoted: bool,
+}
+
+impl Token {
+    /// The field value, or `None` for the `-` placeholder used when a field has no value
+    pub fn value(&self) -> Option<&str> {
+        match self.value.as_str() {
+            "-" => None,
+            value => Some(value),
+        }
+    }
+}
+
+/// Split an access log line into fields
+///
+/// Fields are separated by single spaces. A field starting with `"` runs until the
+/// matching unescaped `"` and may contain spaces; `\"` and `\\` inside a quoted field
+/// are unescaped. An unterminated quote (a truncated line) is an error.
+pub fn tokenize(line: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = line.trim_end_matches(['\r', '\n']).chars().peekable();
+
+    loop {
+        // Skip separators between fields
+        while chars.peek() == Some(&' ') {
+            chars.next();
+        }
+        let Some(&first) = chars.peek() else {
+            break;
+        };
+
+        if first == '"' {
+            chars.next();
+            let mut value = String::new();
+            let mut closed = false;
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => match chars.next() {
+                        Some(escaped @ ('"' | '\\')) => value.push(escaped),
+                        Some(other) => {
+                            value.push('\\');
+                            value.push(other);
+                        }
+                        None => value.push('\\'),
+                    },
+                    '"' => {
+                        closed = true;
+                        break;
+                    }
+                    c => value.push(c),
+                }
+            }
+            if !closed {
+                bail!("Unterminated quoted field in field {}", tokens.len() + 1);
+            }
+            if let Some(&next) = chars.peek() {
+                if next != ' ' {
+                    bail!(
+                        "Unexpected character {:?} after quoted field {}",
+                        next,
+                        tokens.len() + 1
+                    );
+                }
+            }
+            tokens.push(Token {
+                value,
+                quoted: true,
+            });
+        } else {
+            let mut value = String::new();
+            while let Some(&c) = chars.peek() {
+                if c == ' ' {
+                    break;
+                }
+                value.push(c);
+                chars.next();
+            }
+            tokens.push(Token {
+                value,
+                quoted: false,
+            });
+        }
+    }
+
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(line: &str) -> Vec<String> {
+        tokenize(line)
+            .unwrap()
+            .into_iter()
+            .map(|t| t.value)
+            .collect()
+    }
+
+    #[test]
+    fn test_plain_fields() {
+        assert_eq!(vec!["a", "b", "c"], values("a b c"));
+    }
+
+    #[test]
+    fn test_quoted_fields_with_spaces() {
+        assert_eq!(
+            vec![
+                "x",
+                "GET http://example.com:80/ HTTP/1.1",
+                "curl/7.46.0",
+                "y"
+            ],
+            values(r#"x "GET http://example.com:80/ HTTP/1.1" "curl/7.46.0" y"#)
+        );
+    }
+
+    #[test]
+    fn test_escaped_quotes() {
+        assert_eq!(
+            vec![r#"Mozilla "quoted" agent"#, r"back\slash"],
+            values(r#""Mozilla \"quoted\" agent" "back\\slash""#)
+        );
+    }
+
+    #[test]
+    fn test_empty_quoted_field_and_placeholder() {
+        let tokens = tokenize(r#""" - "-""#).unwrap();
+        assert_eq!(Some(""), tokens[0].value());
+        assert_eq!(None, tokens[1].value());
+        assert!(!tokens[1].quoted);
+        assert_eq!(None, tokens[2].value());
+        assert!(tokens[2].quoted);
+    }
+
+    #[test]
+    fn test_trailing_whitespace_and_newline() {
+        assert_eq!(vec!["a", "b"], values("a b \r\n"));
+    }
+
+    #[test]
+    fn test_unterminated_quote() {
+        assert!(tokenize(r#"a "GET http://example.com/"#).is_err());
+        assert!(tokenize(r#"a "ends with escape\""#).is_err());
+    }
+
+    #[test]
+    fn test_garbage_after_quote() {
+        assert!(tokenize(r#"a "b"c"#).is_err());
+    }
+}
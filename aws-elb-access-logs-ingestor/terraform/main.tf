@@ -0,0 +1,147 @@
+# S3 bucket receiving load balancer access logs
+resource "aws_s3_bucket" "access_logs" {
+  bucket        = var.access_logs_bucket_name
+  force_destroy = var.force_destroy_bucket
+}
+
+resource "aws_s3_bucket_public_access_block" "access_logs" {
+  bucket = aws_s3_bucket.access_logs.id
+
+  block_public_acls       = true
+  block_public_policy     = true
+  ignore_public_acls      = true
+  restrict_public_buckets = true
+}
+
+# Allow Elastic Load Balancing to deliver access logs into the bucket
+data "aws_caller_identity" "current" {}
+
+data "aws_elb_service_account" "current" {}
+
+resource "aws_s3_bucket_policy" "access_logs_delivery" {
+  bucket = aws_s3_bucket.access_logs.id
+
+  policy = jsonencode({
+    Version = "2012-10-17"
+    Statement = [
+      {
+        Sid       = "ELBAccessLogsWrite"
+        Effect    = "Allow"
+        Principal = { AWS = data.aws_elb_service_account.current.arn }
+        Action    = "s3:PutObject"
+        Resource  = "${aws_s3_bucket.access_logs.arn}/AWSLogs/${data.aws_caller_identity.current.account_id}/*"
+      },
+      {
+        Sid       = "ELBLogDeliveryWrite"
+        Effect    = "Allow"
+        Principal = { Service = "logdelivery.elasticloadbalancing.amazonaws.com" }
+        Action    = "s3:PutObject"
+        Resource  = "${aws_s3_bucket.access_logs.arn}/AWSLogs/${data.aws_caller_identity.current.account_id}/*"
+      }
+    ]
+  })
+}
+
+# IAM Role for Lambda
+resource "aws_iam_role" "lambda_exec" {
+  name = "${var.function_name}-exec-role"
+
+  assume_role_policy = jsonencode({
+    Version = "2012-10-17"
+    Statement = [
+      {
+        Action = "sts:AssumeRole"
+        Effect = "Allow"
+        Principal = {
+          Service = "lambda.amazonaws.com"
+        }
+      }
+    ]
+  })
+}
+
+# IAM Policy for Lambda
+resource "aws_iam_role_policy" "lambda_policy" {
+  name = "${var.function_name}-policy"
+  role = aws_iam_role.lambda_exec.id
+
+  policy = jsonencode({
+    Version = "2012-10-17"
+    Statement = [
+      {
+        Effect   = "Allow"
+        Action   = ["s3:GetObject"]
+        Resource = "${aws_s3_bucket.access_logs.arn}/*"
+      },
+      {
+        Effect = "Allow"
+        Action = [
+          "logs:CreateLogGroup",
+          "logs:CreateLogStream",
+          "logs:PutLogEvents"
+        ]
+        Resource = "arn:aws:logs:${var.aws_region}:*:log-group:/aws/lambda/${var.function_name}:*"
+      }
+    ]
+  })
+}
+
+# CloudWatch Log Group
+resource "aws_cloudwatch_log_group" "lambda_logs" {
+  name              = "/aws/lambda/${var.function_name}"
+  retention_in_days = var.log_retention_days
+}
+
+# Lambda Function
+resource "aws_lambda_function" "access_logs_ingestor" {
+  filename         = local.lambda_zip_path
+  function_name    = var.function_name
+  role             = aws_iam_role.lambda_exec.arn
+  handler          = "bootstrap"
+  source_code_hash = filebase64sha256(local.lambda_zip_path)
+  runtime          = "provided.al2023"
+  architectures    = ["arm64"]
+
+  memory_size = var.memory_size
+  timeout     = var.timeout
+
+  environment {
+    variables = {
+      DATABRICKS_HOST          = var.databricks_host
+      DATABRICKS_CLIENT_ID     = var.databricks_client_id
+      DATABRICKS_CLIENT_SECRET = var.databricks_client_secret
+      ZEROBUS_ENDPOINT         = var.zerobus_endpoint
+      TABLE_NAME               = var.table_name
+    }
+  }
+
+  depends_on = [
+    aws_cloudwatch_log_group.lambda_logs,
+    aws_iam_role_policy.lambda_policy
+  ]
+}
+
+# Invoke the Lambda for every new access log object
+resource "aws_lambda_permission" "allow_s3" {
+  statement_id  = "AllowExecutionFromS3"
+  action        = "lambda:InvokeFunction"
+  function_name = aws_lambda_function.access_logs_ingestor.function_name
+  principal     = "s3.amazonaws.com"
+  source_arn    = aws_s3_bucket.access_logs.arn
+}
+
+resource "aws_s3_bucket_notification" "access_logs" {
+  bucket = aws_s3_bucket.access_logs.id
+
+  lambda_function {
+    lambda_function_arn = aws_lambda_function.access_logs_ingestor.arn
+    events              = ["s3:ObjectCreated:*"]
+    filter_prefix       = "AWSLogs/"
+  }
+
+  depends_on = [aws_lambda_permission.allow_s3]
+}
+
+locals {
+  lambda_zip_path = "${path.module}/../../../target/lambda/aws-elb-access-logs-ingestor/bootstrap.zip"
+}
@@ -0,0 +1,14 @@
+output "lambda_function_name" {
+  description = "Name of the Lambda function"
+  value       = aws_lambda_function.access_logs_ingestor.function_name
+}
+
+output "access_logs_bucket_name" {
+  description = "Name of the S3 bucket receiving access logs"
+  value       = aws_s3_bucket.access_logs.bucket
+}
+
+output "cloudwatch_log_group_name" {
+  description = "Name of the CloudWatch log group"
+  value       = aws_cloudwatch_log_group.lambda_logs.name
+}
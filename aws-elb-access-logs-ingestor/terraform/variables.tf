@@ -0,0 +1,69 @@
+variable "aws_region" {
+  description = "AWS region for resources"
+  type        = string
+  default     = "us-west-2"
+}
+
+variable "function_name" {
+  description = "Name of the Lambda function"
+  type        = string
+  default     = "zerobus-elb-access-logs-ingestor"
+}
+
+variable "access_logs_bucket_name" {
+  description = "Name of the S3 bucket that receives load balancer access logs"
+  type        = string
+}
+
+variable "force_destroy_bucket" {
+  description = "Allow terraform destroy to delete the bucket even if it contains access logs"
+  type        = bool
+  default     = false
+}
+
+variable "databricks_host" {
+  description = "Databricks workspace URL (e.g., https://myworkspace.cloud.databricks.com)"
+  type        = string
+  sensitive   = true
+}
+
+variable "databricks_client_id" {
+  description = "Databricks service principal client ID"
+  type        = string
+  sensitive   = true
+}
+
+variable "databricks_client_secret" {
+  description = "Databricks service principal client secret"
+  type        = string
+  sensitive   = true
+}
+
+variable "zerobus_endpoint" {
+  description = "Zerobus gRPC endpoint (e.g., https://<workspace_id>.zerobus.<region>.cloud.databricks.com)"
+  type        = string
+  sensitive   = true
+}
+
+variable "table_name" {
+  description = "Unity Catalog table name (e.g., main.network.elb_access_logs)"
+  type        = string
+}
+
+variable "memory_size" {
+  description = "Lambda function memory size in MB"
+  type        = number
+  default     = 512
+}
+
+variable "timeout" {
+  description = "Lambda function timeout in seconds"
+  type        = number
+  default     = 300
+}
+
+variable "log_retention_days" {
+  description = "CloudWatch log retention in days"
+  type        = number
+  default     = 7
+}
@@ -0,0 +1,39 @@
+//! Lambda events for the tests and benchmarks
+
+use lambda_runtime::{Context, LambdaEvent};
+use serde_json::{json, Map, Value};
+
+/// An event carrying `payload`, from the invocation with request ID `req-1`
+pub fn event(payload: Value) -> LambdaEvent<Value> {
+    let mut context = Context::default();
+    context.request_id = "req-1".to_string();
+    LambdaEvent::new(payload, context)
+}
+
+/// An EventBridge-style event whose `detail` holds a message of `len` bytes
+pub fn sized_payload(len: usize) -> Value {
+    json!({
+        "source": "com.example.orders",
+        "detail-type": "OrderPlaced",
+        "detail": {"order_id": "ord-1042", "message": "x".repeat(len)},
+    })
+}
+
+/// A payload `depth` levels deep, with a few scalar fields at every level
+///
+/// `nested_payload(1)` is a flat object, as measured by
+/// [`zerobus_common::json_depth::depth`].
+pub fn nested_payload(depth: usize) -> Value {
+    (1..depth).fold(level(None), |inner, _| level(Some(inner)))
+}
+
+fn level(child: Option<Value>) -> Value {
+    let mut object = Map::new();
+    object.insert("id".to_string(), json!("ord-1042"));
+    object.insert("amount".to_string(), json!(129.95));
+    object.insert("active".to_string(), json!(true));
+    if let Some(child) = child {
+        object.insert("child".to_string(), child);
+    }
+    Value::Object(object)
+}
@@ -1,30 +1,73 @@
+use anyhow::{Context, Result};
 use databricks_zerobus_ingest_sdk::{StreamConfigurationOptions, TableProperties};
 use lambda_runtime::{Error, LambdaEvent};
+use prost_types::DescriptorProto;
 use serde_json::Value;
-use tracing::{error, info};
+#[cfg(feature = "otel")]
+use tracing::Instrument;
+use tracing::{error, info, warn};
+use zerobus_common::clock::{Clock, SystemClock};
+#[cfg(feature = "otel")]
+use zerobus_common::otel;
+use zerobus_common::pipeline::{CreateStream, IngestSink};
+use zerobus_common::unacked::ReportDestination;
 
-use crate::ingest::ingest_event;
+use crate::ingest::{build_unacked_report, ingest_event, EventOptions};
 use crate::proto::load_descriptor_proto;
 use crate::sdk::init_sdk;
 
+/// Everything the handler reads from the environment
+///
+/// `function_handler` reads it on every invocation; tests build it from the variables
+/// they name with `zerobus_common::testing::handler::with_env`.
+#[derive(Debug)]
+pub struct HandlerConfig {
+    pub table_name: String,
+    pub client_id: String,
+    pub client_secret: String,
+    /// Schema of the rows, from the descriptor set embedded for `ENVIRONMENT`
+    pub descriptor_proto: DescriptorProto,
+    pub event: EventOptions,
+    pub unacked_destination: ReportDestination,
+}
+
+impl HandlerConfig {
+    pub fn from_env() -> Result<Self> {
+        Ok(Self {
+            table_name: std::env::var("TABLE_NAME")
+                .context("TABLE_NAME environment variable must be set")?,
+            client_id: std::env::var("DATABRICKS_CLIENT_ID")
+                .context("DATABRICKS_CLIENT_ID environment variable must be set")?,
+            client_secret: std::env::var("DATABRICKS_CLIENT_SECRET")
+                .context("DATABRICKS_CLIENT_SECRET environment variable must be set")?,
+            descriptor_proto: load_descriptor_proto("aws_raw_events.proto", "table_aws_raw_events")?,
+            event: EventOptions::from_env()?,
+            unacked_destination: ReportDestination::from_env(),
+        })
+    }
+}
+
 /// Lambda handler function
 pub async fn function_handler(event: LambdaEvent<Value>) -> Result<String, Error> {
     let sdk = init_sdk().map_err(|e| Error::from(format!("Failed to initialize SDK: {}", e)))?;
+    let config = HandlerConfig::from_env().map_err(|e| Error::from(e.to_string()))?;
+    handle_event(&event, sdk, &config, &SystemClock).await
+}
 
-    let table_name = std::env::var("TABLE_NAME")
-        .map_err(|_| Error::from("TABLE_NAME environment variable must be set"))?;
-    let client_id = std::env::var("DATABRICKS_CLIENT_ID")
-        .map_err(|_| Error::from("DATABRICKS_CLIENT_ID environment variable must be set"))?;
-    let client_secret = std::env::var("DATABRICKS_CLIENT_SECRET")
-        .map_err(|_| Error::from("DATABRICKS_CLIENT_SECRET environment variable must be set"))?;
-
-    // Load descriptor
-    let descriptor_proto = load_descriptor_proto("aws_raw_events.proto", "table_aws_raw_events");
+/// Ingest `event` into a stream `sdk` opens to the configured table, stamping its row
+/// with the time from `clock`
+pub async fn handle_event<C: CreateStream>(
+    event: &LambdaEvent<Value>,
+    sdk: &C,
+    config: &HandlerConfig,
+    clock: &dyn Clock,
+) -> Result<String, Error> {
+    let table_name = &config.table_name;
 
     // Configure table properties
     let table_properties = TableProperties {
         table_name: table_name.clone(),
-        descriptor_proto,
+        descriptor_proto: config.descriptor_proto.clone(),
     };
 
     // Configure stream options
@@ -34,15 +77,25 @@ pub async fn function_handler(event: LambdaEvent<Value>) -> Result<String, Error
     };
 
     // Create stream
-    let mut stream = sdk
-        .create_stream(table_properties, client_id, client_secret, Some(stream_options))
+    let stream = sdk.create_stream(
+        table_properties,
+        config.client_id.clone(),
+        config.client_secret.clone(),
+        Some(stream_options),
+    );
+    #[cfg(feature = "otel")]
+    let stream = stream.instrument(otel::create_stream_span(table_name));
+    let mut stream = stream
         .await
         .map_err(|e| Error::from(format!("Failed to create stream: {}", e)))?;
 
     info!("Processing event with request_id: {}", event.context.request_id);
 
     // Ingest the event
-    match ingest_event(&event, &mut stream).await {
+    let ingested = ingest_event(event, &mut stream, &config.event, clock);
+    #[cfg(feature = "otel")]
+    let ingested = ingested.instrument(otel::ingest_record_span(table_name));
+    match ingested.await {
         Ok(_) => {
             info!("Successfully processed event");
         }
@@ -53,16 +106,24 @@ pub async fn function_handler(event: LambdaEvent<Value>) -> Result<String, Error
     }
 
     // Flush all pending writes and close the stream
-    if let Err(e) = stream.close().await {
+    let closed = stream.close();
+    #[cfg(feature = "otel")]
+    let closed = closed.instrument(otel::close_stream_span(table_name));
+    if let Err(e) = closed.await {
         error!("Failed to close stream: {}", e);
 
         // Get unacknowledged records for potential retry
-        let unacked = stream.get_unacked_records().await.map_err(|e| {
+        let unacked = sdk.unacked_records(&mut stream).await.map_err(|e| {
             Error::from(format!("Failed to get unacked records: {}", e))
         })?;
         
         if !unacked.is_empty() {
             error!("Failed to acknowledge {} records", unacked.len());
+            let report =
+                build_unacked_report(table_name, &event.context.request_id, &e, &unacked);
+            if let Err(e) = report.write(&config.unacked_destination) {
+                warn!("{:#}", e);
+            }
             // Recreate the stream with the same configuration and automatically re-ingest all records that weren't acknowledged.
             sdk.recreate_stream(stream).await.map_err(|e| {
                 Error::from(format!("Failed to recreate stream: {}", e))
@@ -75,3 +136,173 @@ pub async fn function_handler(event: LambdaEvent<Value>) -> Result<String, Error
     Ok("Success".to_string())
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::event;
+    use crate::proto::aws_raw_events::TableAwsRawEvents;
+    use crate::proto::DESCRIPTOR_SETS;
+    use prost::Message;
+    use serde_json::json;
+    use zerobus_common::clock::FixedClock;
+    use zerobus_common::compress::PayloadCodec;
+    use zerobus_common::descriptor;
+    use zerobus_common::testing::handler::{invoke, with_env, MockStreams};
+    use zerobus_common::testing::MockSink;
+
+    const TABLE: &str = "main.default.aws_raw_events";
+
+    /// The configuration read from `vars` alone, and the variables the handler requires
+    fn config(vars: &[(&str, &str)]) -> HandlerConfig {
+        let mut all = vec![
+            ("TABLE_NAME", TABLE),
+            ("DATABRICKS_CLIENT_ID", "client-id"),
+            ("DATABRICKS_CLIENT_SECRET", "client-secret"),
+        ];
+        all.extend_from_slice(vars);
+        with_env(&all, HandlerConfig::from_env).unwrap()
+    }
+
+    fn rows(records: &[Vec<u8>]) -> Vec<TableAwsRawEvents> {
+        records
+            .iter()
+            .map(|record| TableAwsRawEvents::decode(record.as_slice()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_environment_selects_the_embedded_descriptor() {
+        for (environment, bytes) in DESCRIPTOR_SETS {
+            let expected = descriptor::load_descriptor_proto(
+                bytes,
+                "aws_raw_events.proto",
+                "table_aws_raw_events",
+            );
+            assert_eq!(expected, config(&[("ENVIRONMENT", environment)]).descriptor_proto);
+        }
+
+        let error = with_env(
+            &[
+                ("TABLE_NAME", TABLE),
+                ("DATABRICKS_CLIENT_ID", "client-id"),
+                ("DATABRICKS_CLIENT_SECRET", "client-secret"),
+                ("ENVIRONMENT", "staging"),
+            ],
+            HandlerConfig::from_env,
+        )
+        .unwrap_err();
+        assert_eq!(
+            "No descriptor set embedded for ENVIRONMENT=staging (available: dev, prod)",
+            error.to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_event_is_ingested_and_its_stream_closed() {
+        let streams = MockStreams::default();
+        let config = config(&[]);
+        let clock = FixedClock::at_unix_secs(1_718_020_860);
+        let event = event(json!({"detail-type": "Order Placed"}));
+
+        let invocation = invoke(&streams, |sdk| async move {
+            handle_event(&event, &sdk, &config, &clock).await
+        })
+        .await;
+
+        assert_eq!("Success", invocation.response.unwrap());
+        assert_eq!(vec![TABLE], invocation.calls.created);
+        assert!(invocation.calls.closed.contains(TABLE));
+        let rows = rows(invocation.calls.records_to(TABLE));
+        assert_eq!(1, rows.len());
+        assert_eq!(Some("req-1".to_string()), rows[0].request_id);
+        assert_eq!(Some(r#"{"detail-type":"Order Placed"}"#.to_string()), rows[0].payload);
+        assert_eq!(Some(1_718_020_860_000_000), rows[0].ingested_at);
+    }
+
+    #[tokio::test]
+    async fn test_event_the_payload_path_misses_is_skipped() {
+        let streams = MockStreams::default();
+        let config = config(&[("PAYLOAD_JSONPATH", "$.detail"), ("PAYLOAD_JSONPATH_MISS", "skip")]);
+        let event = event(json!({"detail-type": "Heartbeat"}));
+
+        let invocation = invoke(&streams, |sdk| async move {
+            handle_event(&event, &sdk, &config, &SystemClock).await
+        })
+        .await;
+
+        assert_eq!("Success", invocation.response.unwrap());
+        assert!(invocation.calls.records_to(TABLE).is_empty());
+        assert!(invocation.calls.closed.contains(TABLE));
+    }
+
+    #[tokio::test]
+    async fn test_selected_payload_is_stored_compressed() {
+        let streams = MockStreams::default();
+        let config = config(&[("PAYLOAD_JSONPATH", "$.detail"), ("COMPRESS_PAYLOAD", "zstd")]);
+        let event = event(json!({"detail-type": "Order Placed", "detail": {"order_id": "o-1"}}));
+
+        let invocation = invoke(&streams, |sdk| async move {
+            handle_event(&event, &sdk, &config, &SystemClock).await
+        })
+        .await;
+
+        assert_eq!("Success", invocation.response.unwrap());
+        let row = rows(invocation.calls.records_to(TABLE)).remove(0);
+        assert_eq!(None, row.payload);
+        assert_eq!(Some("zstd".to_string()), row.payload_codec);
+        let payload = PayloadCodec::Zstd.decompress(&row.payload_compressed.unwrap()).unwrap();
+        assert_eq!(json!({"order_id": "o-1"}), serde_json::from_slice::<Value>(&payload).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_stream_that_cannot_be_created_fails_the_invocation() {
+        let streams = MockStreams::default().fail_create_for(|_| true);
+        let config = config(&[]);
+        let event = event(json!({"hello": "world"}));
+
+        let invocation = invoke(&streams, |sdk| async move {
+            handle_event(&event, &sdk, &config, &SystemClock).await
+        })
+        .await;
+
+        let error = invocation.response.unwrap_err();
+        assert!(error.to_string().starts_with("Failed to create stream"), "{}", error);
+        assert_eq!(vec![TABLE], invocation.calls.created);
+        assert!(invocation.calls.records.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_failed_ack_fails_the_invocation() {
+        let streams = MockStreams::default().with_sink(MockSink::default().fail_acks_for(|_| true));
+        let config = config(&[]);
+        let event = event(json!({"hello": "world"}));
+
+        let invocation = invoke(&streams, |sdk| async move {
+            handle_event(&event, &sdk, &config, &SystemClock).await
+        })
+        .await;
+
+        let error = invocation.response.unwrap_err();
+        assert!(error.to_string().starts_with("Failed to ingest event"), "{}", error);
+        assert_eq!(1, invocation.calls.records_to(TABLE).len());
+        // The stream is left to be dropped, not closed
+        assert!(invocation.calls.closed.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_failed_close_with_every_record_acked_is_not_recreated() {
+        let streams = MockStreams::default().with_sink(MockSink::default().fail_close());
+        let config = config(&[]);
+        let event = event(json!({"hello": "world"}));
+
+        let invocation = invoke(&streams, |sdk| async move {
+            handle_event(&event, &sdk, &config, &SystemClock).await
+        })
+        .await;
+
+        let error = invocation.response.unwrap_err();
+        assert!(error.to_string().starts_with("Failed to close stream"), "{}", error);
+        assert!(invocation.calls.recreated.is_empty());
+        assert_eq!(1, invocation.calls.records_to(TABLE).len());
+    }
+}
@@ -1,19 +1,32 @@
 use anyhow::{Context, Result};
-use databricks_zerobus_ingest_sdk::ZerobusStream;
 use lambda_runtime::LambdaEvent;
 use prost::Message;
 use serde_json::Value;
-use tracing::info;
+use std::borrow::Cow;
+use tracing::{info, warn};
+use zerobus_common::clock::Clock;
+use zerobus_common::compress::PayloadCodec;
+use zerobus_common::json_depth::{depth, DepthLimit};
+use zerobus_common::json_path::PayloadPath;
+use zerobus_common::payload_log::PayloadLog;
+use zerobus_common::pipeline::IngestSink;
+use zerobus_common::unacked::UnackedReport;
+use zerobus_common::version;
 
 use crate::proto::aws_raw_events::TableAwsRawEvents;
 
-/// Ingest a Lambda event into Zerobus
-pub async fn ingest_event(
+/// Build the table row for a Lambda event
+///
+/// With a `depth_limit`, a payload nested deeper than the limit is rejected or truncated
+/// before it is serialized. `ingested_at` and `ingested_date` are read from `clock`.
+pub fn build_raw_event(
     event: &LambdaEvent<Value>,
-    stream: &mut ZerobusStream,
-) -> Result<()> {
+    pipeline_version: Option<&str>,
+    depth_limit: Option<&DepthLimit>,
+    clock: &dyn Clock,
+) -> Result<TableAwsRawEvents> {
     // Get current timestamp in microseconds
-    let now = std::time::SystemTime::now();
+    let now = clock.now();
     let ingested_at = now
         .duration_since(std::time::UNIX_EPOCH)
         .context("Failed to get system time")?
@@ -28,9 +41,23 @@ pub async fn ingest_event(
     // Extract request_id from context (minimal field)
     let request_id = event.context.request_id.clone();
 
+    // Only copy the payload when it has to be truncated
+    let payload = match depth_limit {
+        Some(limit) if depth(&event.payload) > limit.max_depth => {
+            let mut payload = event.payload.clone();
+            let truncated = limit.apply(&mut payload)?;
+            warn!(
+                "Truncated {} nested values beyond MAX_JSON_DEPTH ({})",
+                truncated, limit.max_depth
+            );
+            Cow::Owned(payload)
+        }
+        _ => Cow::Borrowed(&event.payload),
+    };
+
     // Serialize payload as JSON string
-    let payload_json = serde_json::to_string(&event.payload)
-        .context("Failed to serialize event payload to JSON")?;
+    let payload_json =
+        serde_json::to_string(&payload).context("Failed to serialize event payload to JSON")?;
 
     // Serialize entire context as JSON string
     let context_json = serde_json::to_string(&event.context)
@@ -40,21 +67,380 @@ pub async fn ingest_event(
     let deadline = event.context.deadline as i64;
 
     // Create protobuf message
-    let raw_event = TableAwsRawEvents {
-        request_id: Some(request_id.clone()),
+    Ok(TableAwsRawEvents {
+        request_id: Some(request_id),
         payload: Some(payload_json),
         context: Some(context_json),
         deadline: Some(deadline),
         ingested_at: Some(ingested_at),
         ingested_date: Some(ingested_date),
+        pipeline_version: pipeline_version.map(str::to_string),
+        payload_compressed: None,
+        payload_codec: None,
+    })
+}
+
+/// The event to ingest, with its payload narrowed to the sub-document `PAYLOAD_JSONPATH`
+/// selects
+///
+/// Returns `None` for an event to skip: one the path does not match, with
+/// `PAYLOAD_JSONPATH_MISS=skip`.
+pub fn select_payload<'a>(
+    event: &'a LambdaEvent<Value>,
+    payload_path: Option<&PayloadPath>,
+) -> Result<Option<Cow<'a, LambdaEvent<Value>>>> {
+    let Some(payload_path) = payload_path else {
+        return Ok(Some(Cow::Borrowed(event)));
+    };
+    Ok(payload_path
+        .extract(&event.payload)?
+        .map(|payload| Cow::Owned(LambdaEvent::new(payload, event.context.clone()))))
+}
+
+/// Move the payload of a row into `payload_compressed`, leaving `payload` unset
+pub fn compress_payload(row: &mut TableAwsRawEvents, codec: PayloadCodec) -> Result<()> {
+    if let Some(payload) = row.payload.take() {
+        row.payload_compressed = Some(codec.compress(payload.as_bytes())?);
+        row.payload_codec = Some(codec.name().to_string());
+    }
+    Ok(())
+}
+
+/// Describe the records a failed stream left unacknowledged, identified by request ID
+pub fn build_unacked_report<R: AsRef<[u8]>>(
+    table_name: &str,
+    request_id: &str,
+    error: impl std::fmt::Display,
+    unacked: impl IntoIterator<Item = R>,
+) -> UnackedReport {
+    UnackedReport::builder(table_name)
+        .error(error)
+        .batch_id(request_id)
+        .identify_with(|record| TableAwsRawEvents::decode(record).ok()?.request_id)
+        .build(unacked)
+}
+
+/// How events become rows, read from the environment once per invocation
+#[derive(Debug, Default)]
+pub struct EventOptions {
+    /// The sub-document of the payload to keep, from `PAYLOAD_JSONPATH`
+    pub payload_path: Option<PayloadPath>,
+    pub payload_log: PayloadLog,
+    pub depth_limit: Option<DepthLimit>,
+    /// Codec the payload is stored with in `payload_compressed`, from `COMPRESS_PAYLOAD`
+    pub codec: Option<PayloadCodec>,
+    /// Written into `pipeline_version`, from `STAMP_VERSION`
+    pub pipeline_version: Option<&'static str>,
+}
+
+impl EventOptions {
+    pub fn from_env() -> Result<Self> {
+        Ok(Self {
+            payload_path: PayloadPath::from_env()?,
+            payload_log: PayloadLog::from_env()?,
+            depth_limit: DepthLimit::from_env()?,
+            codec: PayloadCodec::from_env()?,
+            pipeline_version: version::stamped_pipeline_version(),
+        })
+    }
+}
+
+/// Ingest a Lambda event into Zerobus, stamping its row with the time from `clock`
+pub async fn ingest_event<S: IngestSink>(
+    event: &LambdaEvent<Value>,
+    sink: &mut S,
+    options: &EventOptions,
+    clock: &dyn Clock,
+) -> Result<()> {
+    let request_id = event.context.request_id.clone();
+    let Some(event) = select_payload(event, options.payload_path.as_ref())? else {
+        info!(
+            "Skipping event with request_id {}: PAYLOAD_JSONPATH matches nothing",
+            request_id
+        );
+        return Ok(());
     };
 
-    // Encode and ingest
-    let encoded = raw_event.encode_to_vec();
-    let ack_future = stream.ingest_record(encoded).await?;
-    ack_future.await?;
+    let payload_log = &options.payload_log;
+    let record = format!("event {}", request_id);
+    payload_log.log_sampled(&record, &event.payload);
+
+    let ingested = async {
+        let mut raw_event = build_raw_event(
+            &event,
+            options.pipeline_version,
+            options.depth_limit.as_ref(),
+            clock,
+        )?;
+        if let Some(codec) = options.codec {
+            compress_payload(&mut raw_event, codec)?;
+        }
+
+        // Encode and ingest
+        let encoded = raw_event.encode_to_vec();
+        let ack_future = sink.ingest(encoded).await?;
+        ack_future.await?;
+        Ok::<_, anyhow::Error>(())
+    };
+    if let Err(e) = ingested.await {
+        payload_log.log_failed(&record, &event.payload, format_args!("{:#}", e));
+        return Err(e);
+    }
 
     info!("Successfully ingested event with request_id: {}", request_id);
     Ok(())
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::event;
+    use crate::proto::load_descriptor_proto;
+    use base64::{engine::general_purpose, Engine as _};
+    use lambda_runtime::Context as LambdaContext;
+    use serde_json::json;
+    use zerobus_common::clock::{FixedClock, SystemClock};
+    use zerobus_common::json_depth::DepthMode;
+    use zerobus_common::json_path::{JsonPath, MissPolicy};
+    use zerobus_common::testing::conformance::{assert_conforms, UNICODE};
+    use zerobus_common::testing::handler::with_env;
+    use zerobus_common::testing::{hex_dump, MockSink};
+
+    fn payload_path(expression: &str, on_miss: MissPolicy) -> PayloadPath {
+        PayloadPath {
+            path: JsonPath::parse(expression).unwrap(),
+            on_miss,
+        }
+    }
+
+    fn limit(mode: DepthMode) -> DepthLimit {
+        DepthLimit { max_depth: 3, mode }
+    }
+
+    #[test]
+    fn test_pipeline_version_is_stamped() {
+        let mut context = LambdaContext::default();
+        context.request_id = "req-1".to_string();
+        let event = LambdaEvent::new(json!({"hello": "world"}), context);
+
+        let row = build_raw_event(&event, Some(version::PIPELINE_VERSION), None, &SystemClock).unwrap();
+        assert_eq!(Some("req-1".to_string()), row.request_id);
+        assert_eq!(Some(version::PIPELINE_VERSION.to_string()), row.pipeline_version);
+
+        let row = build_raw_event(&event, None, None, &SystemClock).unwrap();
+        assert_eq!(None, row.pipeline_version);
+    }
+
+    #[test]
+    fn test_close_failure_reports_unacked_records() {
+        let mut context = LambdaContext::default();
+        context.request_id = "req-1".to_string();
+        let event = LambdaEvent::new(json!({"hello": "world"}), context);
+        let unacked = vec![build_raw_event(&event, None, None, &SystemClock).unwrap().encode_to_vec()];
+
+        let report = build_unacked_report("main.default.raw", "req-1", "stream closed", &unacked);
+
+        assert_eq!(1, report.entries.len());
+        assert_eq!(Some("req-1".to_string()), report.entries[0].id);
+        assert_eq!(unacked[0].len(), report.entries[0].size_bytes);
+        assert_eq!(Some("stream closed".to_string()), report.error);
+    }
+
+    #[test]
+    fn test_payload_within_depth_is_kept() {
+        let payload = json!({"order": {"items": ["a", "b"]}});
+        let limit = limit(DepthMode::Reject);
+
+        let row = build_raw_event(&event(payload.clone()), None, Some(&limit), &SystemClock).unwrap();
+        assert_eq!(payload.to_string(), row.payload.unwrap());
+    }
+
+    #[test]
+    fn test_payload_beyond_depth_is_rejected() {
+        let payload = json!({"order": {"items": [{"options": {"gift": true}}]}});
+        let limit = limit(DepthMode::Reject);
+
+        let error = build_raw_event(&event(payload), None, Some(&limit), &SystemClock).unwrap_err();
+        assert!(error.to_string().contains("MAX_JSON_DEPTH (3)"));
+    }
+
+    #[test]
+    fn test_payload_beyond_depth_is_truncated() {
+        let payload = json!({"order": {"items": [{"options": {"gift": true}}], "id": 7}});
+        let limit = limit(DepthMode::Truncate);
+
+        let row = build_raw_event(&event(payload), None, Some(&limit), &SystemClock).unwrap();
+        assert_eq!(
+            json!({"order": {"items": ["[truncated]"], "id": 7}}),
+            serde_json::from_str::<Value>(&row.payload.unwrap()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_compressed_payload_round_trips() {
+        let event = event(json!({"message": "x".repeat(1000), "id": 7}));
+        let original = build_raw_event(&event, None, None, &SystemClock).unwrap().payload.unwrap();
+
+        for codec in [PayloadCodec::Gzip, PayloadCodec::Zstd] {
+            let mut row = build_raw_event(&event, None, None, &SystemClock).unwrap();
+            compress_payload(&mut row, codec).unwrap();
+
+            // Decode the stored row, as a reader of the table would
+            let row = TableAwsRawEvents::decode(row.encode_to_vec().as_slice()).unwrap();
+            assert_eq!(None, row.payload);
+            assert_eq!(Some(codec.name().to_string()), row.payload_codec);
+            let compressed = row.payload_compressed.unwrap();
+            assert!(compressed.len() < original.len());
+            assert_eq!(original.as_bytes(), codec.decompress(&compressed).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_payload_jsonpath_extracts_sub_document() {
+        let event = event(json!({
+            "detail-type": "Order Placed",
+            "detail": {"order_id": "o-1", "total": 12.5}
+        }));
+        let path = payload_path("$.detail", MissPolicy::Fail);
+
+        let selected = select_payload(&event, Some(&path)).unwrap().unwrap();
+        let row = build_raw_event(&selected, None, None, &SystemClock).unwrap();
+        assert_eq!(Some("req-1".to_string()), row.request_id);
+        assert_eq!(
+            json!({"order_id": "o-1", "total": 12.5}),
+            serde_json::from_str::<Value>(&row.payload.unwrap()).unwrap()
+        );
+
+        // Without a path the whole payload is kept
+        let selected = select_payload(&event, None).unwrap().unwrap();
+        assert_eq!(event.payload, selected.payload);
+    }
+
+    #[test]
+    fn test_payload_jsonpath_miss_follows_policy() {
+        let event = event(json!({"detail-type": "Heartbeat"}));
+
+        let skip = payload_path("$.detail", MissPolicy::Skip);
+        assert!(select_payload(&event, Some(&skip)).unwrap().is_none());
+
+        let fail = payload_path("$.detail", MissPolicy::Fail);
+        let error = select_payload(&event, Some(&fail)).unwrap_err();
+        assert!(error.to_string().contains("$.detail matches nothing"));
+    }
+
+    #[tokio::test]
+    async fn test_ingested_event_is_stamped_with_the_clock() {
+        let sink = MockSink::default();
+        let mut context = LambdaContext::default();
+        context.request_id = "req-1".to_string();
+        let event = LambdaEvent::new(json!({"hello": "world"}), context);
+        let clock = FixedClock::at_unix_secs(1_718_020_860);
+
+        ingest_event(&event, &mut sink.clone(), &EventOptions::default(), &clock).await.unwrap();
+
+        let records = sink.records();
+        assert_eq!(1, records.len());
+        let row = TableAwsRawEvents::decode(records[0].as_slice()).unwrap();
+        assert_eq!(Some(1_718_020_860_000_000), row.ingested_at);
+        // 2024-06-10, in days since Unix epoch
+        assert_eq!(Some(19_884), row.ingested_date);
+        assert_eq!(None, row.pipeline_version);
+    }
+
+    #[tokio::test]
+    async fn test_ingested_event_is_stamped_with_the_options_version() {
+        let sink = MockSink::default();
+        let event = LambdaEvent::new(json!({"hello": "world"}), LambdaContext::default());
+        let options = EventOptions {
+            pipeline_version: Some(version::PIPELINE_VERSION),
+            ..Default::default()
+        };
+
+        ingest_event(&event, &mut sink.clone(), &options, &SystemClock).await.unwrap();
+
+        let row = TableAwsRawEvents::decode(sink.records()[0].as_slice()).unwrap();
+        assert_eq!(Some(version::PIPELINE_VERSION.to_string()), row.pipeline_version);
+    }
+
+    #[test]
+    fn test_raw_event_snapshot() {
+        let mut context = LambdaContext::default();
+        context.request_id = "8f5e2a1c-3d4b-4f6a-9c7e-1b2d3e4f5a6b".to_string();
+        context.deadline = 1_718_020_890_000;
+        context.invoked_function_arn =
+            "arn:aws:lambda:us-east-2:123456789012:function:raw-events".to_string();
+        let event = LambdaEvent::new(
+            json!({"detail-type": "Scheduled Event", "source": "aws.events"}),
+            context,
+        );
+        let clock = FixedClock::at_unix_secs(1_718_020_860);
+
+        let encoded = build_raw_event(&event, Some("2024.06.1"), None, &clock)
+            .unwrap()
+            .encode_to_vec();
+        let decoded = TableAwsRawEvents::decode(encoded.as_slice()).unwrap();
+
+        insta::assert_debug_snapshot!("raw_event_fields", decoded);
+        insta::assert_snapshot!("raw_event_bytes", hex_dump(&encoded));
+    }
+
+    /// The JSON a generic tool would be handed for `row`
+    fn raw_event_json(row: &TableAwsRawEvents) -> Value {
+        json!({
+            "request_id": row.request_id,
+            "payload": row.payload,
+            "context": row.context,
+            "deadline": row.deadline,
+            "ingested_at": row.ingested_at,
+            "ingested_date": row.ingested_date,
+            "pipeline_version": row.pipeline_version,
+            "payload_compressed": row.payload_compressed.as_ref().map(|bytes| general_purpose::STANDARD.encode(bytes)),
+            "payload_codec": row.payload_codec,
+        })
+    }
+
+    #[test]
+    fn test_dynamic_encoder_conforms_to_prost() {
+        let descriptor = with_env(&[], || {
+            load_descriptor_proto("aws_raw_events.proto", "table_aws_raw_events")
+        })
+        .unwrap();
+        let all_fields = TableAwsRawEvents {
+            request_id: Some("8f5e2a1c-3d4b-4f6a-9c7e-1b2d3e4f5a6b".to_string()),
+            payload: Some(r#"{"detail-type":"Scheduled Event"}"#.to_string()),
+            context: Some(r#"{"deadline":1718020890000}"#.to_string()),
+            deadline: Some(1_718_020_890_000),
+            ingested_at: Some(1_718_020_860_000_000),
+            ingested_date: Some(19_884),
+            pipeline_version: Some("2024.06.1".to_string()),
+            payload_compressed: Some(vec![0x28, 0xb5, 0x2f, 0xfd, 0x00, 0xff].into()),
+            payload_codec: Some("zstd".to_string()),
+        };
+        let ints = |int64, int32| TableAwsRawEvents {
+            deadline: Some(int64),
+            ingested_at: Some(int64),
+            ingested_date: Some(int32),
+            ..Default::default()
+        };
+        let cases = [
+            ("empty", TableAwsRawEvents::default()),
+            ("all_fields", all_fields.clone()),
+            (
+                "unicode",
+                TableAwsRawEvents {
+                    payload: Some(json!({ "message": UNICODE }).to_string()),
+                    pipeline_version: Some(UNICODE.to_string()),
+                    payload_compressed: Some(UNICODE.as_bytes().to_vec().into()),
+                    ..all_fields
+                },
+            ),
+            ("max_ints", ints(i64::MAX, i32::MAX)),
+            ("min_ints", ints(i64::MIN, i32::MIN)),
+        ];
+
+        for (case, row) in cases {
+            let encoded = assert_conforms(&descriptor, case, &row, &raw_event_json(&row));
+            insta::assert_snapshot!(format!("conformance_{}", case), hex_dump(&encoded));
+        }
+    }
+}
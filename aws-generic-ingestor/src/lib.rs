@@ -1,3 +1,4 @@
+pub mod fixtures;
 pub mod handler;
 pub mod ingest;
 pub mod proto;
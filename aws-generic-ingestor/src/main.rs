@@ -1,17 +1,36 @@
 use aws_generic_ingestor::handler;
 use lambda_runtime::{run, service_fn, Error};
 
+/// The handler, in the root span of its invocation
+///
+/// Spans are exported before it returns, as Lambda may freeze the container right after.
+#[cfg(feature = "otel")]
+async fn traced_handler(event: lambda_runtime::LambdaEvent<serde_json::Value>) -> Result<String, Error> {
+    let request_id = event.context.request_id.clone();
+    let function_name = event.context.env_config.function_name.clone();
+    zerobus_common::otel::invocation(&request_id, &function_name, handler::function_handler(event)).await
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     // Install the default CryptoProvider early in your application
     rustls::crypto::aws_lc_rs::default_provider().install_default().unwrap();
 
-    tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::INFO)
-        .with_target(false)
-        .init();
+    #[cfg(feature = "otel")]
+    {
+        zerobus_common::otel::init()?;
+        run(service_fn(traced_handler)).await
+    }
+
+    #[cfg(not(feature = "otel"))]
+    {
+        tracing_subscriber::fmt()
+            .with_env_filter(zerobus_common::payload_log::log_directives("info"))
+            .with_target(false)
+            .init();
 
-    run(service_fn(handler::function_handler)).await
+        run(service_fn(handler::function_handler)).await
+    }
 }
 
 #[cfg(test)]
@@ -19,14 +38,30 @@ mod tests {
     use super::*;
     use lambda_runtime::{Context, LambdaEvent};
     use serde_json::json;
+    use zerobus_common::clock::SystemClock;
+    use zerobus_common::testing::handler::{invoke, with_env, MockStreams};
 
     #[tokio::test]
     async fn test_event_handler() {
+        let config = with_env(
+            &[
+                ("TABLE_NAME", "main.default.aws_raw_events"),
+                ("DATABRICKS_CLIENT_ID", "client-id"),
+                ("DATABRICKS_CLIENT_SECRET", "client-secret"),
+            ],
+            handler::HandlerConfig::from_env,
+        )
+        .unwrap();
         let event_value = json!({"test": "data"});
         let event = LambdaEvent::new(event_value, Context::default());
-        let response = handler::function_handler(event).await;
-        // Test will fail due to missing env vars, but verifies compilation
-        assert!(response.is_err());
+        let invocation = invoke(&MockStreams::default(), |sdk| async move {
+            handler::handle_event(&event, &sdk, &config, &SystemClock).await
+        })
+        .await;
+        assert_eq!("Success", invocation.response.unwrap());
+        assert_eq!(1, invocation.calls.records_to("main.default.aws_raw_events").len());
+
+        // Without the variables it requires there is no configuration to run with
+        assert!(with_env(&[], handler::HandlerConfig::from_env).is_err());
     }
 }
-
@@ -1,28 +1,25 @@
-use prost::Message;
+use anyhow::Result;
 use prost_types::DescriptorProto;
+use zerobus_common::descriptor::load_environment_descriptor_proto;
 
 // Module for generated protobuf code
 pub mod aws_raw_events {
     include!("../gen/rust/aws_raw_events.rs");
 }
 
-/// Load the protobuf descriptor from the embedded descriptor file
-pub fn load_descriptor_proto(file_name: &str, message_name: &str) -> DescriptorProto {
-    const DESCRIPTOR_BYTES: &[u8] = include_bytes!("../gen/descriptors/aws_raw_events.descriptor");
+/// Descriptor sets embedded per environment, as `make proto-compile` builds them
+pub const DESCRIPTOR_SETS: &[(&str, &[u8])] = &[
+    (
+        "dev",
+        include_bytes!("../gen/descriptors/aws_raw_events_dev.descriptor"),
+    ),
+    (
+        "prod",
+        include_bytes!("../gen/descriptors/aws_raw_events_prod.descriptor"),
+    ),
+];
 
-    let file_descriptor_set = prost_types::FileDescriptorSet::decode(DESCRIPTOR_BYTES)
-        .expect("Failed to decode descriptor file");
-
-    let file_descriptor_proto = file_descriptor_set
-        .file
-        .into_iter()
-        .find(|f| f.name.as_ref().map(|n| n.as_str()) == Some(file_name))
-        .expect("File descriptor not found");
-
-    file_descriptor_proto
-        .message_type
-        .into_iter()
-        .find(|m| m.name.as_ref().map(|n| n.as_str()) == Some(message_name))
-        .expect("Message descriptor not found")
+/// Load the protobuf descriptor embedded for `ENVIRONMENT`, the first set when it is unset
+pub fn load_descriptor_proto(file_name: &str, message_name: &str) -> Result<DescriptorProto> {
+    load_environment_descriptor_proto(DESCRIPTOR_SETS, file_name, message_name)
 }
-
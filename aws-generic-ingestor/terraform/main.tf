@@ -84,6 +84,12 @@ resource "aws_lambda_function" "generic_ingestor" {
       DATABRICKS_CLIENT_SECRET = var.databricks_client_secret
       ZEROBUS_ENDPOINT         = var.zerobus_endpoint
       TABLE_NAME               = var.table_name
+      STAMP_VERSION            = tostring(var.stamp_version)
+      MAX_JSON_DEPTH           = var.max_json_depth == null ? "" : tostring(var.max_json_depth)
+      JSON_DEPTH_MODE          = var.json_depth_mode
+      COMPRESS_PAYLOAD         = var.compress_payload
+      PAYLOAD_JSONPATH         = var.payload_jsonpath
+      PAYLOAD_JSONPATH_MISS    = var.payload_jsonpath_miss
     }
     # Note: Environment variables are encrypted at rest by default with AWS managed key
     # Custom KMS encryption requires additional configuration outside this module
@@ -86,3 +86,53 @@ variable "dead_letter_queue_arn" {
   default     = null
 }
 
+variable "stamp_version" {
+  description = "Write the ingestor version and git commit into the pipeline_version column of every row"
+  type        = bool
+  default     = false
+}
+
+variable "max_json_depth" {
+  description = "Maximum nesting of objects and arrays in event payloads (null disables the check)"
+  type        = number
+  default     = null
+}
+
+variable "json_depth_mode" {
+  description = "What to do with payloads nested deeper than max_json_depth: reject or truncate"
+  type        = string
+  default     = "reject"
+
+  validation {
+    condition     = contains(["reject", "truncate"], var.json_depth_mode)
+    error_message = "json_depth_mode must be \"reject\" or \"truncate\"."
+  }
+}
+
+variable "compress_payload" {
+  description = "Codec for storing payloads compressed in payload_compressed: gzip or zstd (empty stores plain JSON strings)"
+  type        = string
+  default     = ""
+
+  validation {
+    condition     = contains(["", "gzip", "zstd"], var.compress_payload)
+    error_message = "compress_payload must be \"\", \"gzip\", or \"zstd\"."
+  }
+}
+
+variable "payload_jsonpath" {
+  description = "JSONPath selecting the part of each payload to store, e.g. $.detail (empty stores the whole payload)"
+  type        = string
+  default     = ""
+}
+
+variable "payload_jsonpath_miss" {
+  description = "What to do with payloads payload_jsonpath matches nothing in: fail or skip"
+  type        = string
+  default     = "fail"
+
+  validation {
+    condition     = contains(["fail", "skip"], var.payload_jsonpath_miss)
+    error_message = "payload_jsonpath_miss must be \"fail\" or \"skip\"."
+  }
+}
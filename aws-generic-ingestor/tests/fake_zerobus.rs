@@ -0,0 +1,54 @@
+//! Invokes the handler against a fake Zerobus server on localhost.
+//!
+//! The handler keeps one SDK for the life of the process, created from the environment
+//! on its first invocation, so every invocation here goes through a single test and a
+//! single server.
+
+use aws_generic_ingestor::handler::function_handler;
+use aws_generic_ingestor::proto::aws_raw_events::TableAwsRawEvents;
+use fake_zerobus_server::FakeZerobus;
+use lambda_runtime::{Context, LambdaEvent};
+use prost::Message;
+use serde_json::json;
+
+fn event(request_id: &str) -> LambdaEvent<serde_json::Value> {
+    let mut context = Context::default();
+    context.request_id = request_id.to_string();
+    LambdaEvent::new(
+        json!({"detail-type": "test", "request": request_id}),
+        context,
+    )
+}
+
+#[tokio::test]
+async fn test_events_are_ingested_through_a_dropped_connection() {
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    // The second event arrives on a connection the server drops, and is sent again on
+    // the stream the SDK recovers
+    let server = FakeZerobus::new()
+        .drop_after_records(1)
+        .start()
+        .await
+        .unwrap();
+    server.set_env();
+    std::env::set_var("TABLE_NAME", "main.default.aws_raw_events");
+
+    assert_eq!("Success", function_handler(event("req-1")).await.unwrap());
+    assert_eq!("Success", function_handler(event("req-2")).await.unwrap());
+
+    let request_ids: Vec<Option<String>> = server
+        .acked_records()
+        .iter()
+        .map(|record| {
+            TableAwsRawEvents::decode(record.payload.as_slice())
+                .unwrap()
+                .request_id
+        })
+        .collect();
+    assert_eq!(
+        vec![Some("req-1".to_string()), Some("req-2".to_string())],
+        request_ids
+    );
+    // One stream per invocation, and the one recovered after the drop
+    assert_eq!(3, server.streams_created());
+}
@@ -0,0 +1,28 @@
+//! The compiled `table_aws_raw_events` descriptor against the live table named by
+//! `SCHEMA_CHECK_TABLE`
+//!
+//! Checks the descriptor embedded for `ENVIRONMENT`, as the handler loads it. Needs
+//! `DATABRICKS_HOST` and the credentials the function is deployed with. Without
+//! `SCHEMA_CHECK_TABLE` the test passes without checking anything.
+
+use aws_generic_ingestor::proto::load_descriptor_proto;
+use zerobus_common::auth::StreamAuth;
+use zerobus_common::schema_check;
+
+#[tokio::test]
+async fn test_descriptor_matches_the_live_table() {
+    let Ok(table_name) = std::env::var("SCHEMA_CHECK_TABLE") else {
+        eprintln!("SCHEMA_CHECK_TABLE is not set, skipping the live schema check");
+        return;
+    };
+    let databricks_host = std::env::var("DATABRICKS_HOST").expect("DATABRICKS_HOST must be set");
+    let auth = StreamAuth::from_env().await.unwrap();
+    let descriptor_proto =
+        load_descriptor_proto("aws_raw_events.proto", "table_aws_raw_events").unwrap();
+
+    if let Err(error) =
+        schema_check::check(&databricks_host, &auth, &table_name, &descriptor_proto).await
+    {
+        panic!("{:#}", error);
+    }
+}
@@ -0,0 +1,172 @@
+//! The event an AWS IoT rule invokes the function with
+//!
+//! The rule's SQL statement decides the event's shape. For devices publishing JSON, it
+//! selects the payload and adds the metadata fields:
+//!
+//! ```sql
+//! SELECT *, topic() AS topic, clientid() AS client_id, timestamp() AS received_at
+//! FROM 'fleet/+/+/telemetry'
+//! ```
+//!
+//! Devices publishing anything else, such as a packed binary frame, have their payload
+//! base64-encoded into `payload_base64` instead, since a Lambda event must be JSON:
+//!
+//! ```sql
+//! SELECT encode(*, 'base64') AS payload_base64, topic() AS topic,
+//!        clientid() AS client_id, timestamp() AS received_at
+//! FROM 'fleet/+/+/raw'
+//! ```
+
+use anyhow::{bail, Context, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde_json::{Map, Value};
+
+/// The message's topic, from `topic()`
+pub const TOPIC_FIELD: &str = "topic";
+/// The publishing client's ID, from `clientid()`
+pub const CLIENT_ID_FIELD: &str = "client_id";
+/// When IoT Core received the message, in milliseconds since Unix epoch, from
+/// `timestamp()`
+pub const RECEIVED_AT_FIELD: &str = "received_at";
+/// The whole payload, base64-encoded, from `encode(*, 'base64')`
+pub const BINARY_PAYLOAD_FIELD: &str = "payload_base64";
+
+/// What the device published
+#[derive(Debug, Clone, PartialEq)]
+pub enum Payload {
+    /// A JSON object, the device's telemetry
+    Json(Value),
+    /// Anything that is not a JSON object
+    Binary,
+}
+
+/// One message, split into the rule's metadata and the device's payload
+#[derive(Debug, Clone, PartialEq)]
+pub struct IotMessage {
+    pub topic: Option<String>,
+    pub client_id: Option<String>,
+    /// Milliseconds since Unix epoch
+    pub received_at: Option<i64>,
+    pub payload: Payload,
+    /// The payload's bytes: as published for binary payloads, the telemetry serialized
+    /// as JSON otherwise
+    pub raw: Vec<u8>,
+}
+
+impl IotMessage {
+    pub fn parse(event: Value) -> Result<Self> {
+        let Value::Object(mut fields) = event else {
+            bail!("Event is not a JSON object; select the payload with a SELECT * or encode(*, 'base64') rule");
+        };
+        let topic = take_string(&mut fields, TOPIC_FIELD)?;
+        let client_id = take_string(&mut fields, CLIENT_ID_FIELD)?;
+        let received_at =
+            match fields.remove(RECEIVED_AT_FIELD) {
+                None | Some(Value::Null) => None,
+                Some(value) => Some(value.as_i64().with_context(|| {
+                    format!("{} is not an integer: {}", RECEIVED_AT_FIELD, value)
+                })?),
+            };
+
+        let (payload, raw) = match fields.remove(BINARY_PAYLOAD_FIELD) {
+            Some(Value::String(encoded)) => {
+                let raw = STANDARD
+                    .decode(encoded.trim())
+                    .with_context(|| format!("{} is not base64", BINARY_PAYLOAD_FIELD))?;
+                // A JSON device behind a rule that encodes every payload
+                match serde_json::from_slice(&raw) {
+                    Ok(telemetry @ Value::Object(_)) => (Payload::Json(telemetry), raw),
+                    _ => (Payload::Binary, raw),
+                }
+            }
+            Some(other) => bail!("{} is not a string: {}", BINARY_PAYLOAD_FIELD, other),
+            None => {
+                let raw = serde_json::to_vec(&fields)?;
+                (Payload::Json(Value::Object(fields)), raw)
+            }
+        };
+        Ok(Self {
+            topic,
+            client_id,
+            received_at,
+            payload,
+            raw,
+        })
+    }
+
+    /// The device's telemetry, if it published a JSON object
+    pub fn telemetry(&self) -> Option<&Value> {
+        match &self.payload {
+            Payload::Json(telemetry) => Some(telemetry),
+            Payload::Binary => None,
+        }
+    }
+}
+
+fn take_string(fields: &mut Map<String, Value>, name: &str) -> Result<Option<String>> {
+    match fields.remove(name) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(value)) => Ok(Some(value)),
+        Some(other) => bail!("{} is not a string: {}", name, other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_json_payload_with_metadata() {
+        let event: Value =
+            serde_json::from_str(include_str!("../testdata/json-device.json")).unwrap();
+        let message = IotMessage::parse(event).unwrap();
+
+        assert_eq!(
+            Some("fleet/berlin/sensor-17/telemetry"),
+            message.topic.as_deref()
+        );
+        assert_eq!(Some("sensor-17"), message.client_id.as_deref());
+        assert_eq!(Some(1_718_020_865_123), message.received_at);
+        let telemetry = message.telemetry().unwrap();
+        assert_eq!(json!("21.5"), telemetry["temperature"]);
+        assert!(telemetry.get(TOPIC_FIELD).is_none());
+        assert_eq!(
+            telemetry,
+            &serde_json::from_slice::<Value>(&message.raw).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_binary_payload() {
+        let event: Value =
+            serde_json::from_str(include_str!("../testdata/binary-device.json")).unwrap();
+        let message = IotMessage::parse(event).unwrap();
+
+        assert_eq!(Payload::Binary, message.payload);
+        assert_eq!(
+            vec![0x01, 0x00, 0xd7, 0x08, 0x4c, 0x0f, 0xff, 0x00],
+            message.raw
+        );
+        assert_eq!(Some("meter-0042"), message.client_id.as_deref());
+    }
+
+    #[test]
+    fn test_encoded_json_payload_is_telemetry() {
+        let message = IotMessage::parse(json!({
+            "payload_base64": STANDARD.encode(br#"{"rpm": 1200}"#),
+            "topic": "fleet/berlin/pump-3/raw"
+        }))
+        .unwrap();
+        assert_eq!(json!(1200), message.telemetry().unwrap()["rpm"]);
+    }
+
+    #[test]
+    fn test_invalid_events() {
+        assert!(IotMessage::parse(json!([1, 2])).is_err());
+        assert!(IotMessage::parse(json!({"payload_base64": "not base64!"})).is_err());
+        assert!(IotMessage::parse(json!({"topic": 7})).is_err());
+        assert!(IotMessage::parse(json!({"received_at": "yesterday"})).is_err());
+    }
+}
@@ -0,0 +1,255 @@
+//! The event time of a message, from the device's own timestamp
+//!
+//! Device clocks drift, and devices without a real-time clock report times from
+//! whenever they booted. A device timestamp further ahead of the time IoT Core received
+//! the message than `MAX_CLOCK_SKEW_SECS` cannot be right: with `CLOCK_SKEW_MODE=flag`
+//! the row takes the receive time as its event time and is flagged, and with `reject`
+//! the message is not ingested. Messages without a device timestamp use the receive
+//! time.
+
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+use std::time::Duration;
+
+/// How far a device timestamp may be ahead of the receive time when
+/// MAX_CLOCK_SKEW_SECS is not set
+pub const DEFAULT_MAX_CLOCK_SKEW: Duration = Duration::from_secs(300);
+
+/// Unit of the device's timestamps
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeUnit {
+    Seconds,
+    Millis,
+    Micros,
+}
+
+impl TimeUnit {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "s" | "seconds" => Ok(TimeUnit::Seconds),
+            "ms" | "millis" | "milliseconds" => Ok(TimeUnit::Millis),
+            "us" | "micros" | "microseconds" => Ok(TimeUnit::Micros),
+            _ => bail!("Time unit must be s, ms, or us, got {:?}", value),
+        }
+    }
+
+    fn micros_per_unit(self) -> f64 {
+        match self {
+            TimeUnit::Seconds => 1_000_000.0,
+            TimeUnit::Millis => 1_000.0,
+            TimeUnit::Micros => 1.0,
+        }
+    }
+}
+
+/// What happens to a message whose device timestamp is too far in the future
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkewMode {
+    /// Ingest it with the receive time as its event time, flagged
+    Flag,
+    /// Fail the invocation without ingesting it
+    Reject,
+}
+
+/// Where the device timestamp is and how much to trust it
+#[derive(Debug, Clone, PartialEq)]
+pub struct EventTimeConfig {
+    /// JSON pointer to the timestamp in the telemetry
+    pub pointer: String,
+    pub unit: TimeUnit,
+    pub max_skew: Duration,
+    pub mode: SkewMode,
+}
+
+impl Default for EventTimeConfig {
+    fn default() -> Self {
+        Self {
+            pointer: "/timestamp".to_string(),
+            unit: TimeUnit::Millis,
+            max_skew: DEFAULT_MAX_CLOCK_SKEW,
+            mode: SkewMode::Flag,
+        }
+    }
+}
+
+impl EventTimeConfig {
+    /// Read `EVENT_TIME_FIELD`, `EVENT_TIME_UNIT`, `MAX_CLOCK_SKEW_SECS`, and
+    /// `CLOCK_SKEW_MODE`, each defaulting when unset
+    pub fn from_env() -> Result<Self> {
+        let var = |name: &str| {
+            std::env::var(name)
+                .ok()
+                .filter(|value| !value.trim().is_empty())
+        };
+        let mut config = Self::default();
+        if let Some(pointer) = var("EVENT_TIME_FIELD") {
+            if !pointer.starts_with('/') {
+                bail!(
+                    "EVENT_TIME_FIELD must be a JSON pointer such as /timestamp, got {:?}",
+                    pointer
+                );
+            }
+            config.pointer = pointer;
+        }
+        if let Some(unit) = var("EVENT_TIME_UNIT") {
+            config.unit = TimeUnit::parse(&unit).context("Invalid EVENT_TIME_UNIT")?;
+        }
+        if let Some(secs) = var("MAX_CLOCK_SKEW_SECS") {
+            config.max_skew = Duration::from_secs(
+                secs.trim()
+                    .parse()
+                    .context("MAX_CLOCK_SKEW_SECS must be a number of seconds")?,
+            );
+        }
+        if let Some(mode) = var("CLOCK_SKEW_MODE") {
+            config.mode = match mode.trim().to_ascii_lowercase().as_str() {
+                "flag" => SkewMode::Flag,
+                "reject" => SkewMode::Reject,
+                _ => bail!("CLOCK_SKEW_MODE must be flag or reject, got {:?}", mode),
+            };
+        }
+        Ok(config)
+    }
+
+    /// The event time of a message received at `received_at`, both in microseconds
+    /// since Unix epoch
+    ///
+    /// Fails when the device timestamp is not a number, and, with
+    /// [`SkewMode::Reject`], when it is too far in the future.
+    pub fn resolve(&self, telemetry: Option<&Value>, received_at: i64) -> Result<EventTime> {
+        let device_time = match telemetry.and_then(|telemetry| telemetry.pointer(&self.pointer)) {
+            None | Some(Value::Null) => None,
+            Some(value) => Some(self.to_micros(value).with_context(|| {
+                format!(
+                    "Device timestamp {} is not a valid time: {}",
+                    self.pointer, value
+                )
+            })?),
+        };
+
+        let Some(device_time) = device_time else {
+            return Ok(EventTime {
+                event_time: received_at,
+                device_time: None,
+                skewed: false,
+            });
+        };
+        let ahead = device_time.saturating_sub(received_at);
+        if ahead > self.max_skew.as_micros() as i64 {
+            if self.mode == SkewMode::Reject {
+                bail!(
+                    "Device timestamp is {:.1}s ahead of the receive time, more than the {}s allowed",
+                    ahead as f64 / 1e6,
+                    self.max_skew.as_secs()
+                );
+            }
+            return Ok(EventTime {
+                event_time: received_at,
+                device_time: Some(device_time),
+                skewed: true,
+            });
+        }
+        Ok(EventTime {
+            event_time: device_time,
+            device_time: Some(device_time),
+            skewed: false,
+        })
+    }
+
+    fn to_micros(&self, value: &Value) -> Result<i64> {
+        let number = match value {
+            Value::Number(number) => number.as_f64().context("Not a number")?,
+            Value::String(text) => text.trim().parse::<f64>().context("Not a number")?,
+            _ => bail!("Not a number"),
+        };
+        let micros = number * self.unit.micros_per_unit();
+        if !micros.is_finite() || micros < 0.0 || micros >= i64::MAX as f64 {
+            bail!("Out of range");
+        }
+        Ok(micros as i64)
+    }
+}
+
+/// The times of one message, in microseconds since Unix epoch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EventTime {
+    /// The device's timestamp, or the receive time when there is none or it is skewed
+    pub event_time: i64,
+    /// The device's timestamp as reported, even when skewed
+    pub device_time: Option<i64>,
+    /// Whether the device's timestamp was too far ahead of the receive time
+    pub skewed: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const RECEIVED_AT: i64 = 1_718_020_865_123_000;
+
+    #[test]
+    fn test_device_time_in_each_unit() {
+        let mut config = EventTimeConfig::default();
+        let resolved = config
+            .resolve(
+                Some(&json!({"timestamp": 1_718_020_864_000i64})),
+                RECEIVED_AT,
+            )
+            .unwrap();
+        assert_eq!(1_718_020_864_000_000, resolved.event_time);
+        assert!(!resolved.skewed);
+
+        config.unit = TimeUnit::Seconds;
+        config.pointer = "/meta/ts".to_string();
+        let resolved = config
+            .resolve(Some(&json!({"meta": {"ts": "1718020864.5"}})), RECEIVED_AT)
+            .unwrap();
+        assert_eq!(1_718_020_864_500_000, resolved.event_time);
+
+        // No device time: the receive time
+        let resolved = config.resolve(None, RECEIVED_AT).unwrap();
+        assert_eq!(RECEIVED_AT, resolved.event_time);
+        assert_eq!(None, resolved.device_time);
+    }
+
+    #[test]
+    fn test_future_timestamps_are_flagged_or_rejected() {
+        // An hour ahead, as from a device with its clock in the wrong time zone
+        let ahead = json!({"timestamp": 1_718_024_465_123i64});
+        let mut config = EventTimeConfig::default();
+
+        let flagged = config.resolve(Some(&ahead), RECEIVED_AT).unwrap();
+        assert_eq!(
+            EventTime {
+                event_time: RECEIVED_AT,
+                device_time: Some(1_718_024_465_123_000),
+                skewed: true,
+            },
+            flagged
+        );
+
+        config.mode = SkewMode::Reject;
+        let error = config.resolve(Some(&ahead), RECEIVED_AT).unwrap_err();
+        assert!(error.to_string().contains("3600.0s ahead"));
+
+        // Within the allowed skew, and any time in the past, is taken as it is
+        let close = json!({"timestamp": 1_718_020_925_123i64});
+        assert!(!config.resolve(Some(&close), RECEIVED_AT).unwrap().skewed);
+        let old = json!({"timestamp": 946_684_800_000i64});
+        assert_eq!(
+            946_684_800_000_000,
+            config.resolve(Some(&old), RECEIVED_AT).unwrap().event_time
+        );
+    }
+
+    #[test]
+    fn test_invalid_device_times() {
+        let config = EventTimeConfig::default();
+        for value in [json!("soon"), json!(-5), json!({"ms": 1}), json!(1e300)] {
+            assert!(config
+                .resolve(Some(&json!({"timestamp": value})), RECEIVED_AT)
+                .is_err());
+        }
+    }
+}
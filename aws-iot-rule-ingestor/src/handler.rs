@@ -0,0 +1,390 @@
+//! Ingesting the message of one invocation
+//!
+//! IoT Core invokes the function asynchronously, once per message, and retries a failed
+//! invocation twice before sending the event to the function's on-failure destination,
+//! if it has one. So an invocation only succeeds once its row is acknowledged, and a
+//! message that cannot be ingested, such as one rejected for clock skew, fails it.
+
+use anyhow::{Context, Result};
+use serde_json::Value;
+use zerobus_common::enrich::{Enricher, LookupItem};
+use zerobus_common::pipeline::{IngestSink, Pipeline};
+
+use crate::event::IotMessage;
+use crate::row::RowBuilder;
+
+/// Ingest the message in `event` and wait for its row to be acknowledged
+///
+/// With an `enricher`, the row is enriched after its columns are set, so the lookup
+/// key can be any of them, including those taken from the topic. `now` is the time of
+/// the invocation, in microseconds since Unix epoch.
+pub async fn ingest_event<S: IngestSink, L: LookupItem>(
+    rows: &RowBuilder,
+    enricher: Option<&Enricher<L>>,
+    pipeline: &mut Pipeline<S>,
+    event: Value,
+    now: i64,
+) -> Result<()> {
+    let message = IotMessage::parse(event)?;
+    let row = encode(rows, enricher, &message, now)
+        .await
+        .with_context(|| {
+            format!(
+                "Message from {} on {}",
+                message.client_id.as_deref().unwrap_or("unknown client"),
+                message.topic.as_deref().unwrap_or("unknown topic")
+            )
+        })?;
+    pipeline.ingest_batch([row]).await
+}
+
+async fn encode<L: LookupItem>(
+    rows: &RowBuilder,
+    enricher: Option<&Enricher<L>>,
+    message: &IotMessage,
+    now: i64,
+) -> Result<Vec<u8>> {
+    let mut row = Value::Object(rows.row(message, now)?);
+    if let Some(enricher) = enricher {
+        enricher.enrich(&mut row).await?;
+    }
+    rows.encode_row(&row)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event_time::{EventTimeConfig, SkewMode};
+    use crate::row::FieldMap;
+    use crate::topic::TopicPattern;
+    use prost::Message;
+    use prost_types::field_descriptor_proto::{Label, Type};
+    use prost_types::{DescriptorProto, FieldDescriptorProto};
+    use serde_json::{json, Map};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use zerobus_common::dynamic::DynamicEncoder;
+    use zerobus_common::enrich::EnrichConfig;
+    use zerobus_common::testing::MockSink;
+
+    const NOW: i64 = 1_718_020_900_000_000;
+
+    const NO_ENRICHMENT: Option<&Enricher<DeviceRegistry>> = None;
+
+    /// A DynamoDB table of devices by `device_id`, counting reads
+    #[derive(Default, Clone)]
+    struct DeviceRegistry {
+        reads: Arc<AtomicUsize>,
+    }
+
+    impl LookupItem for DeviceRegistry {
+        async fn lookup(
+            &self,
+            _table: &str,
+            _key_attribute: &str,
+            key: &Value,
+        ) -> Result<Option<Map<String, Value>>> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            Ok((key == "sensor-17").then(|| {
+                json!({"device_id": "sensor-17", "owner": "Acme Logistics"})
+                    .as_object()
+                    .cloned()
+                    .unwrap()
+            }))
+        }
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct TelemetryRow {
+        #[prost(string, optional, tag = "1")]
+        topic: Option<String>,
+        #[prost(string, optional, tag = "2")]
+        client_id: Option<String>,
+        #[prost(string, optional, tag = "3")]
+        site: Option<String>,
+        #[prost(string, optional, tag = "4")]
+        device_id: Option<String>,
+        #[prost(int64, optional, tag = "5")]
+        received_at: Option<i64>,
+        #[prost(int64, optional, tag = "6")]
+        event_time: Option<i64>,
+        #[prost(int64, optional, tag = "7")]
+        device_time: Option<i64>,
+        #[prost(bool, optional, tag = "8")]
+        clock_skewed: Option<bool>,
+        #[prost(double, optional, tag = "9")]
+        temperature: Option<f64>,
+        #[prost(int32, optional, tag = "10")]
+        humidity: Option<i32>,
+        #[prost(double, optional, tag = "11")]
+        battery_voltage: Option<f64>,
+        #[prost(bytes = "vec", optional, tag = "12")]
+        payload: Option<Vec<u8>>,
+        #[prost(string, optional, tag = "13")]
+        owner: Option<String>,
+    }
+
+    fn encoder() -> DynamicEncoder {
+        let field = |name: &str, number: i32, r#type: Type| FieldDescriptorProto {
+            name: Some(name.to_string()),
+            number: Some(number),
+            label: Some(Label::Optional as i32),
+            r#type: Some(r#type as i32),
+            ..Default::default()
+        };
+        let descriptor = DescriptorProto {
+            name: Some("table_telemetry".to_string()),
+            field: vec![
+                field("topic", 1, Type::String),
+                field("client_id", 2, Type::String),
+                field("site", 3, Type::String),
+                field("device_id", 4, Type::String),
+                field("received_at", 5, Type::Int64),
+                field("event_time", 6, Type::Int64),
+                field("device_time", 7, Type::Int64),
+                field("clock_skewed", 8, Type::Bool),
+                field("temperature", 9, Type::Double),
+                field("humidity", 10, Type::Int32),
+                field("battery_voltage", 11, Type::Double),
+                field("payload", 12, Type::Bytes),
+                field("owner", 13, Type::String),
+            ],
+            ..Default::default()
+        };
+        DynamicEncoder::new(&descriptor)
+            .unwrap()
+            .ignore_unknown_fields(true)
+    }
+
+    fn rows(field_map: Option<&str>, event_time: EventTimeConfig) -> RowBuilder {
+        RowBuilder::new(
+            encoder(),
+            field_map.map(|map| FieldMap::parse(map).unwrap()),
+            Some(TopicPattern::parse("fleet/{site}/{device_id}/#").unwrap()),
+            event_time,
+        )
+        .unwrap()
+    }
+
+    fn fixture(json: &str) -> Value {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn ingested(sink: &MockSink) -> Vec<TelemetryRow> {
+        sink.records()
+            .iter()
+            .map(|record| TelemetryRow::decode(record.as_slice()).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_json_device() {
+        let sink = MockSink::default();
+        let mut pipeline = Pipeline::new(sink.clone(), 10);
+        let event = fixture(include_str!("../testdata/json-device.json"));
+
+        ingest_event(
+            &rows(None, EventTimeConfig::default()),
+            NO_ENRICHMENT,
+            &mut pipeline,
+            event,
+            NOW,
+        )
+        .await
+        .unwrap();
+
+        let row = &ingested(&sink)[0];
+        assert_eq!(
+            Some("fleet/berlin/sensor-17/telemetry"),
+            row.topic.as_deref()
+        );
+        assert_eq!(Some("sensor-17"), row.client_id.as_deref());
+        assert_eq!(Some("berlin"), row.site.as_deref());
+        assert_eq!(Some("sensor-17"), row.device_id.as_deref());
+        assert_eq!(Some(1_718_020_865_123_000), row.received_at);
+        assert_eq!(Some(1_718_020_864_000_000), row.event_time);
+        assert_eq!(row.event_time, row.device_time);
+        assert_eq!(Some(false), row.clock_skewed);
+        // Sent as a string, coerced to the DOUBLE column
+        assert_eq!(Some(21.5), row.temperature);
+        assert_eq!(Some(48), row.humidity);
+        // Nested, so not a column without a field map
+        assert_eq!(None, row.battery_voltage);
+        let payload: Value = serde_json::from_slice(row.payload.as_ref().unwrap()).unwrap();
+        assert_eq!(json!("2.4.1"), payload["firmware"]);
+    }
+
+    #[tokio::test]
+    async fn test_binary_device() {
+        let sink = MockSink::default();
+        let mut pipeline = Pipeline::new(sink.clone(), 10);
+        let event = fixture(include_str!("../testdata/binary-device.json"));
+
+        ingest_event(
+            &rows(None, EventTimeConfig::default()),
+            NO_ENRICHMENT,
+            &mut pipeline,
+            event,
+            NOW,
+        )
+        .await
+        .unwrap();
+
+        let row = &ingested(&sink)[0];
+        assert_eq!(
+            Some(vec![0x01, 0x00, 0xd7, 0x08, 0x4c, 0x0f, 0xff, 0x00]),
+            row.payload
+        );
+        assert_eq!(Some("hamburg"), row.site.as_deref());
+        assert_eq!(Some("meter-0042"), row.device_id.as_deref());
+        // Nothing to take a device time from
+        assert_eq!(Some(1_718_020_866_450_000), row.event_time);
+        assert_eq!(None, row.device_time);
+        assert_eq!(None, row.temperature);
+    }
+
+    #[tokio::test]
+    async fn test_field_map() {
+        let sink = MockSink::default();
+        let mut pipeline = Pipeline::new(sink.clone(), 10);
+        let rows = rows(
+            Some(r#"{"temperature": "/temperature", "battery_voltage": "/battery/voltage"}"#),
+            EventTimeConfig::default(),
+        );
+        let event = fixture(include_str!("../testdata/json-device.json"));
+
+        ingest_event(&rows, NO_ENRICHMENT, &mut pipeline, event, NOW)
+            .await
+            .unwrap();
+
+        let row = &ingested(&sink)[0];
+        assert_eq!(Some(21.5), row.temperature);
+        assert_eq!(Some(3.71), row.battery_voltage);
+        // Not in the map
+        assert_eq!(None, row.humidity);
+        assert_eq!(Some("berlin"), row.site.as_deref());
+    }
+
+    #[tokio::test]
+    async fn test_enrichment() {
+        let registry = DeviceRegistry::default();
+        let config = EnrichConfig::parse(
+            r#"{"table": "devices", "key_field": "/device_id", "fields": ["owner"]}"#,
+        )
+        .unwrap();
+        let enricher = Enricher::new(config, registry.clone());
+        let rows = rows(None, EventTimeConfig::default());
+        let sink = MockSink::default();
+        let mut pipeline = Pipeline::new(sink.clone(), 10);
+
+        for fixture_json in [
+            include_str!("../testdata/json-device.json"),
+            include_str!("../testdata/json-device.json"),
+            include_str!("../testdata/binary-device.json"),
+        ] {
+            ingest_event(
+                &rows,
+                Some(&enricher),
+                &mut pipeline,
+                fixture(fixture_json),
+                NOW,
+            )
+            .await
+            .unwrap();
+        }
+
+        let ingested = ingested(&sink);
+        // Keyed by the device ID taken from the topic
+        assert_eq!(Some("Acme Logistics"), ingested[0].owner.as_deref());
+        assert_eq!(Some("Acme Logistics"), ingested[1].owner.as_deref());
+        // Not in the registry
+        assert_eq!(None, ingested[2].owner);
+        // The second message of sensor-17 was enriched from the cache
+        assert_eq!(2, registry.reads.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_clock_skew() {
+        // A day ahead of when IoT Core received it
+        let event = json!({
+            "timestamp": 1_718_107_265_123i64,
+            "temperature": 19.0,
+            "topic": "fleet/berlin/sensor-9/telemetry",
+            "client_id": "sensor-9",
+            "received_at": 1_718_020_865_123i64
+        });
+
+        let sink = MockSink::default();
+        let mut pipeline = Pipeline::new(sink.clone(), 10);
+        ingest_event(
+            &rows(None, EventTimeConfig::default()),
+            NO_ENRICHMENT,
+            &mut pipeline,
+            event.clone(),
+            NOW,
+        )
+        .await
+        .unwrap();
+        let row = &ingested(&sink)[0];
+        assert_eq!(Some(true), row.clock_skewed);
+        assert_eq!(Some(1_718_020_865_123_000), row.event_time);
+        assert_eq!(Some(1_718_107_265_123_000), row.device_time);
+
+        let sink = MockSink::default();
+        let mut pipeline = Pipeline::new(sink.clone(), 10);
+        let reject = EventTimeConfig {
+            mode: SkewMode::Reject,
+            ..Default::default()
+        };
+        let error = ingest_event(
+            &rows(None, reject),
+            NO_ENRICHMENT,
+            &mut pipeline,
+            event,
+            NOW,
+        )
+        .await
+        .unwrap_err();
+        assert!(format!("{:#}", error).starts_with("Message from sensor-9 on fleet/berlin/sensor-9/telemetry: Device timestamp is 86400.0s ahead"));
+        assert!(sink.records().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_messages_that_fail_the_invocation() {
+        let rows = rows(None, EventTimeConfig::default());
+        let sink = MockSink::default();
+        let mut pipeline = Pipeline::new(sink.clone(), 10);
+        for event in [
+            json!({"topic": "plant/berlin/sensor-17", "temperature": 20.1}),
+            json!({"topic": "fleet/berlin/sensor-17/telemetry", "humidity": "damp"}),
+            json!("21.5"),
+        ] {
+            assert!(
+                ingest_event(&rows, NO_ENRICHMENT, &mut pipeline, event, NOW)
+                    .await
+                    .is_err()
+            );
+        }
+        assert!(sink.records().is_empty());
+
+        let mut pipeline = Pipeline::new(MockSink::default().fail_acks_for(|_| true), 10);
+        let event = fixture(include_str!("../testdata/json-device.json"));
+        assert!(
+            ingest_event(&rows, NO_ENRICHMENT, &mut pipeline, event, NOW)
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_columns_missing_from_the_table() {
+        let field_map = FieldMap::parse(r#"{"rssi": "/rssi"}"#).unwrap();
+        let error = RowBuilder::new(encoder(), Some(field_map), None, Default::default())
+            .err()
+            .unwrap();
+        assert_eq!("Table has no column rssi", error.to_string());
+
+        let pattern = TopicPattern::parse("fleet/{region}/+").unwrap();
+        assert!(RowBuilder::new(encoder(), None, Some(pattern), Default::default()).is_err());
+    }
+}
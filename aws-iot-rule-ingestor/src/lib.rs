@@ -0,0 +1,5 @@
+pub mod event;
+pub mod event_time;
+pub mod handler;
+pub mod row;
+pub mod topic;
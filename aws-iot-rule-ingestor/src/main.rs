@@ -0,0 +1,186 @@
+use anyhow::{bail, Context, Result};
+use aws_iot_rule_ingestor::event_time::EventTimeConfig;
+use aws_iot_rule_ingestor::handler::ingest_event;
+use aws_iot_rule_ingestor::row::{FieldMap, RowBuilder};
+use aws_iot_rule_ingestor::topic::TopicPattern;
+use databricks_zerobus_ingest_sdk::{
+    StreamConfigurationOptions, TableProperties, ZerobusSdk, ZerobusStream,
+};
+use lambda_runtime::{run, service_fn, Error, LambdaEvent};
+use prost_types::DescriptorProto;
+use serde_json::{json, Value};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::{Mutex, OnceCell};
+#[cfg(feature = "otel")]
+use tracing::Instrument;
+use tracing::{error, info};
+use zerobus_common::descriptor::find_message_descriptor;
+use zerobus_common::dynamic::{coerce_from_env, DynamicEncoder, FieldErrorMode, KeyCase};
+use zerobus_common::enrich::Enricher;
+#[cfg(feature = "otel")]
+use zerobus_common::otel;
+use zerobus_common::pipeline::Pipeline;
+
+/// One message per invocation, so there is never more than one record in flight
+const MAX_INFLIGHT_RECORDS: usize = 1;
+
+/// What every invocation of this execution environment shares, set up on the first
+struct Ingestor {
+    sdk: ZerobusSdk,
+    table_name: String,
+    descriptor: DescriptorProto,
+    rows: RowBuilder,
+    enricher: Option<Enricher>,
+}
+
+static INGESTOR: OnceCell<Ingestor> = OnceCell::const_new();
+
+// Stream to TABLE_NAME, opened on first use and kept open across invocations
+static PIPELINE: Mutex<Option<Pipeline<ZerobusStream>>> = Mutex::const_new(None);
+
+fn env(name: &str) -> Result<String> {
+    std::env::var(name).with_context(|| format!("{} environment variable must be set", name))
+}
+
+async fn init_ingestor() -> Result<Ingestor> {
+    let zerobus_endpoint = env("ZEROBUS_ENDPOINT")?;
+    let databricks_host = env("DATABRICKS_HOST")?;
+    let table_name = env("TABLE_NAME")?;
+    let descriptor_set = std::env::var("DESCRIPTOR_SET")
+        .unwrap_or_else(|_| "gen/descriptors/tables.descriptor".to_string());
+    let message_name = std::env::var("MESSAGE_NAME").unwrap_or_else(|_| {
+        format!(
+            "table_{}",
+            table_name.rsplit('.').next().unwrap_or(&table_name)
+        )
+    });
+
+    let bytes = std::fs::read(&descriptor_set)
+        .with_context(|| format!("Failed to read descriptor set {}", descriptor_set))?;
+    let descriptor = find_message_descriptor(&bytes, &message_name)?;
+    // Devices send whatever their firmware sends; fields the table does not model are
+    // dropped rather than failing the message
+    let encoder = DynamicEncoder::new(&descriptor)?
+        .ignore_unknown_fields(true)
+        .coerce_types(coerce_from_env()?)
+        .field_error_mode(FieldErrorMode::from_env()?)
+        .key_case(KeyCase::from_env()?);
+    let rows = RowBuilder::new(
+        encoder,
+        FieldMap::from_env()?,
+        TopicPattern::from_env()?,
+        EventTimeConfig::from_env()?,
+    )?;
+    let enricher = Enricher::from_env().await?;
+    for column in enricher.iter().flat_map(Enricher::columns) {
+        if !rows.has_column(column) {
+            bail!("ENRICH: table has no column {}", column);
+        }
+    }
+
+    Ok(Ingestor {
+        sdk: ZerobusSdk::new(zerobus_endpoint, databricks_host)?,
+        table_name,
+        descriptor,
+        rows,
+        enricher,
+    })
+}
+
+async fn create_pipeline(ingestor: &Ingestor) -> Result<Pipeline<ZerobusStream>> {
+    let table_properties = TableProperties {
+        table_name: ingestor.table_name.clone(),
+        descriptor_proto: ingestor.descriptor.clone(),
+    };
+    let stream_options = StreamConfigurationOptions {
+        max_inflight_records: MAX_INFLIGHT_RECORDS,
+        ..Default::default()
+    };
+    let stream = ingestor.sdk.create_stream(
+        table_properties,
+        env("DATABRICKS_CLIENT_ID")?,
+        env("DATABRICKS_CLIENT_SECRET")?,
+        Some(stream_options),
+    );
+    #[cfg(feature = "otel")]
+    let stream = stream.instrument(otel::create_stream_span(&ingestor.table_name));
+    let stream = stream
+        .await
+        .with_context(|| format!("Failed to create stream to {}", ingestor.table_name))?;
+    let pipeline = Pipeline::new(stream, MAX_INFLIGHT_RECORDS);
+    #[cfg(feature = "otel")]
+    let pipeline = pipeline.trace_records(&ingestor.table_name);
+    Ok(pipeline)
+}
+
+async fn function_handler(event: LambdaEvent<Value>) -> Result<Value, Error> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| Error::from(format!("Failed to get system time: {}", e)))?
+        .as_micros() as i64;
+    let ingestor = INGESTOR
+        .get_or_try_init(init_ingestor)
+        .await
+        .map_err(|e| Error::from(format!("{:#}", e)))?;
+
+    let mut pipeline = PIPELINE.lock().await;
+    let mut active = match pipeline.take() {
+        Some(active) => active,
+        None => create_pipeline(ingestor)
+            .await
+            .map_err(|e| Error::from(format!("{:#}", e)))?,
+    };
+
+    let enricher = ingestor.enricher.as_ref();
+    match ingest_event(&ingestor.rows, enricher, &mut active, event.payload, now).await {
+        Ok(()) => {
+            info!("Ingested message of request {}", event.context.request_id);
+            *pipeline = Some(active);
+            Ok(json!({"ingested": 1}))
+        }
+        // The stream is dropped rather than reused after it may have failed, and the
+        // next invocation opens a new one; messages that cannot be ingested are rare
+        // enough that reopening after one costs little
+        Err(e) => {
+            error!(
+                "Failed to ingest message of request {}: {:#}",
+                event.context.request_id, e
+            );
+            Err(Error::from(format!("{:#}", e)))
+        }
+    }
+}
+
+/// The handler, in the root span of its invocation
+///
+/// Spans are exported before it returns, as Lambda may freeze the container right after.
+#[cfg(feature = "otel")]
+async fn traced_handler(event: LambdaEvent<Value>) -> Result<Value, Error> {
+    let request_id = event.context.request_id.clone();
+    let function_name = event.context.env_config.function_name.clone();
+    otel::invocation(&request_id, &function_name, function_handler(event)).await
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    // Install the default CryptoProvider early in your application
+    rustls::crypto::aws_lc_rs::default_provider()
+        .install_default()
+        .unwrap();
+
+    #[cfg(feature = "otel")]
+    {
+        otel::init()?;
+        run(service_fn(traced_handler)).await
+    }
+
+    #[cfg(not(feature = "otel"))]
+    {
+        tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::INFO)
+            .with_target(false)
+            .init();
+
+        run(service_fn(function_handler)).await
+    }
+}
@@ -0,0 +1,197 @@
+//! The table row of one message
+//!
+//! Without `FIELD_MAP`, each top-level field of the telemetry goes to the column of the
+//! same name. `FIELD_MAP` is a JSON object from column names to JSON pointers into the
+//! telemetry, for devices whose payloads are nested or named differently from the
+//! table:
+//!
+//! ```json
+//! {"temperature_c": "/sensors/temp", "battery_pct": "/power/battery"}
+//! ```
+//!
+//! Either way values are coerced to the column types, so a device sending
+//! `"temperature": "21.5"` fills a DOUBLE column. Then the metadata columns are set, each
+//! only when the table has it:
+//!
+//! - `topic`, `client_id` - From the rule
+//! - `received_at` - When IoT Core received the message, in microseconds
+//! - `event_time` - The device timestamp, or the receive time (see [`crate::event_time`])
+//! - `device_time` - The device timestamp as reported
+//! - `clock_skewed` - Whether the device timestamp was too far in the future
+//! - `payload` - The payload's bytes, for a BINARY column
+//!
+//! plus the columns of `TOPIC_PATTERN`.
+
+use anyhow::{bail, Context, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde_json::{Map, Value};
+use zerobus_common::dynamic::DynamicEncoder;
+
+use crate::event::{IotMessage, CLIENT_ID_FIELD, TOPIC_FIELD};
+use crate::event_time::EventTimeConfig;
+use crate::topic::TopicPattern;
+
+/// Columns set from the telemetry, each from a JSON pointer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldMap {
+    fields: Vec<(String, String)>,
+}
+
+impl FieldMap {
+    pub fn parse(map: &str) -> Result<Self> {
+        let object: Map<String, Value> =
+            serde_json::from_str(map).context("Field map is not a JSON object")?;
+        let mut fields = Vec::with_capacity(object.len());
+        for (column, pointer) in object {
+            match pointer {
+                Value::String(pointer) if pointer.starts_with('/') => {
+                    fields.push((column, pointer))
+                }
+                other => bail!(
+                    "Field map column {} must map to a JSON pointer such as /temp, got {}",
+                    column,
+                    other
+                ),
+            }
+        }
+        Ok(Self { fields })
+    }
+
+    /// Read `FIELD_MAP`; unset takes the telemetry's fields as they are
+    pub fn from_env() -> Result<Option<Self>> {
+        match std::env::var("FIELD_MAP") {
+            Ok(map) if !map.trim().is_empty() => {
+                Self::parse(&map).context("Invalid FIELD_MAP").map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    pub fn columns(&self) -> impl Iterator<Item = &str> {
+        self.fields.iter().map(|(column, _)| column.as_str())
+    }
+
+    fn apply(&self, telemetry: &Value, row: &mut Map<String, Value>) {
+        for (column, pointer) in &self.fields {
+            if let Some(value) = telemetry.pointer(pointer) {
+                row.insert(column.clone(), value.clone());
+            }
+        }
+    }
+}
+
+/// Builds and encodes the rows of messages
+pub struct RowBuilder {
+    encoder: DynamicEncoder,
+    field_map: Option<FieldMap>,
+    topic_pattern: Option<TopicPattern>,
+    event_time: EventTimeConfig,
+}
+
+impl RowBuilder {
+    /// Fails when the field map or topic pattern names a column the table does not have,
+    /// which would otherwise only show as columns that are never set
+    pub fn new(
+        encoder: DynamicEncoder,
+        field_map: Option<FieldMap>,
+        topic_pattern: Option<TopicPattern>,
+        event_time: EventTimeConfig,
+    ) -> Result<Self> {
+        let mapped = field_map.iter().flat_map(FieldMap::columns);
+        let captured = topic_pattern.iter().flat_map(TopicPattern::columns);
+        for column in mapped.chain(captured) {
+            if !encoder.has_field(column) {
+                bail!("Table has no column {}", column);
+            }
+        }
+        Ok(Self {
+            encoder,
+            field_map,
+            topic_pattern,
+            event_time,
+        })
+    }
+
+    /// The row of `message`, as a JSON object
+    ///
+    /// `now` stands in for the receive time, in microseconds since Unix epoch, when
+    /// the rule does not select `timestamp()`.
+    pub fn row(&self, message: &IotMessage, now: i64) -> Result<Map<String, Value>> {
+        let telemetry = message.telemetry();
+        let mut row = match (telemetry, &self.field_map) {
+            (Some(telemetry), Some(field_map)) => {
+                let mut row = Map::new();
+                field_map.apply(telemetry, &mut row);
+                row
+            }
+            (Some(Value::Object(telemetry)), None) => telemetry.clone(),
+            _ => Map::new(),
+        };
+
+        let received_at = message
+            .received_at
+            .map_or(now, |millis| millis.saturating_mul(1000));
+        let times = self.event_time.resolve(telemetry, received_at)?;
+
+        let mut set = |column: &str, value: Value| {
+            if self.encoder.has_field(column) {
+                row.insert(column.to_string(), value);
+            }
+        };
+        set(TOPIC_FIELD, message.topic.clone().into());
+        set(CLIENT_ID_FIELD, message.client_id.clone().into());
+        set("received_at", received_at.into());
+        set("event_time", times.event_time.into());
+        set("device_time", times.device_time.into());
+        set("clock_skewed", times.skewed.into());
+        set("payload", STANDARD.encode(&message.raw).into());
+
+        if let (Some(pattern), Some(topic)) = (&self.topic_pattern, &message.topic) {
+            let captures = pattern
+                .captures(topic)
+                .with_context(|| format!("Topic {} does not match TOPIC_PATTERN", topic))?;
+            for (column, value) in captures {
+                row.insert(column.to_string(), value.into());
+            }
+        }
+        Ok(row)
+    }
+
+    /// The encoded row of `message`
+    pub fn encode(&self, message: &IotMessage, now: i64) -> Result<Vec<u8>> {
+        let row = self.row(message, now)?;
+        self.encode_row(&Value::Object(row))
+    }
+
+    /// Encode a row built by [`RowBuilder::row`] and changed since, such as by enrichment
+    pub fn encode_row(&self, row: &Value) -> Result<Vec<u8>> {
+        self.encoder.encode(row)
+    }
+
+    pub fn has_column(&self, column: &str) -> bool {
+        self.encoder.has_field(column)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_field_map() {
+        let map =
+            FieldMap::parse(r#"{"temperature_c": "/sensors/temp", "rssi": "/rssi"}"#).unwrap();
+        let mut columns: Vec<&str> = map.columns().collect();
+        columns.sort();
+        assert_eq!(vec!["rssi", "temperature_c"], columns);
+
+        let mut row = Map::new();
+        map.apply(&json!({"sensors": {"temp": 21.5}}), &mut row);
+        assert_eq!(json!({"temperature_c": 21.5}), Value::Object(row));
+
+        assert!(FieldMap::parse(r#"{"temperature_c": "sensors.temp"}"#).is_err());
+        assert!(FieldMap::parse(r#"["/temp"]"#).is_err());
+    }
+}
@@ -0,0 +1,129 @@
+//! Columns taken from the segments of an MQTT topic
+//!
+//! `TOPIC_PATTERN` is the topic's shape, one pattern segment per topic segment:
+//!
+//! - `{name}` - The segment goes to the column `name`
+//! - `+` - Any segment, not stored
+//! - `#` - Any number of remaining segments, only as the last pattern segment
+//! - Anything else - The segment must be exactly that
+//!
+//! With `fleet/{site}/{device_id}/+/#`, the topic `fleet/berlin/sensor-17/telemetry/v2`
+//! sets `site` to `berlin` and `device_id` to `sensor-17`.
+
+use anyhow::{bail, Result};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Capture(String),
+    Any,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopicPattern {
+    segments: Vec<Segment>,
+    /// Whether the pattern ends with `#`
+    rest: bool,
+}
+
+impl TopicPattern {
+    pub fn parse(pattern: &str) -> Result<Self> {
+        let mut segments = Vec::new();
+        let mut rest = false;
+        let parts: Vec<&str> = pattern.trim().split('/').collect();
+        for (i, part) in parts.iter().enumerate() {
+            let segment = match *part {
+                "#" if i + 1 == parts.len() => {
+                    rest = true;
+                    continue;
+                }
+                "#" => bail!("Topic pattern {:?}: # must be the last segment", pattern),
+                "+" => Segment::Any,
+                _ => match part.strip_prefix('{').and_then(|p| p.strip_suffix('}')) {
+                    Some("") => bail!("Topic pattern {:?}: {{}} needs a column name", pattern),
+                    Some(name) => {
+                        if segments.contains(&Segment::Capture(name.to_string())) {
+                            bail!("Topic pattern {:?} names {} twice", pattern, name);
+                        }
+                        Segment::Capture(name.to_string())
+                    }
+                    None => Segment::Literal(part.to_string()),
+                },
+            };
+            segments.push(segment);
+        }
+        Ok(Self { segments, rest })
+    }
+
+    /// Read `TOPIC_PATTERN`; unset takes no columns from the topic
+    pub fn from_env() -> Result<Option<Self>> {
+        match std::env::var("TOPIC_PATTERN") {
+            Ok(pattern) if !pattern.trim().is_empty() => Self::parse(&pattern).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// Names of the columns the pattern sets
+    pub fn columns(&self) -> impl Iterator<Item = &str> {
+        self.segments.iter().filter_map(|segment| match segment {
+            Segment::Capture(name) => Some(name.as_str()),
+            _ => None,
+        })
+    }
+
+    /// The column values in `topic`, or `None` when the topic does not have the
+    /// pattern's shape
+    pub fn captures<'a>(&'a self, topic: &'a str) -> Option<Vec<(&'a str, &'a str)>> {
+        let parts: Vec<&str> = topic.split('/').collect();
+        if parts.len() < self.segments.len() || (!self.rest && parts.len() > self.segments.len()) {
+            return None;
+        }
+        let mut captures = Vec::new();
+        for (segment, part) in self.segments.iter().zip(parts) {
+            match segment {
+                Segment::Literal(literal) if literal != part => return None,
+                Segment::Capture(name) => captures.push((name.as_str(), part)),
+                _ => {}
+            }
+        }
+        Some(captures)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_captures() {
+        let pattern = TopicPattern::parse("fleet/{site}/{device_id}/+/#").unwrap();
+        assert_eq!(
+            vec!["site", "device_id"],
+            pattern.columns().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            Some(vec![("site", "berlin"), ("device_id", "sensor-17")]),
+            pattern.captures("fleet/berlin/sensor-17/telemetry/v2")
+        );
+        assert_eq!(
+            Some(vec![("site", "berlin"), ("device_id", "sensor-17")]),
+            pattern.captures("fleet/berlin/sensor-17/telemetry")
+        );
+        assert_eq!(None, pattern.captures("fleet/berlin/sensor-17"));
+        assert_eq!(None, pattern.captures("plant/berlin/sensor-17/telemetry"));
+
+        let exact = TopicPattern::parse("dt/{device_id}/temperature").unwrap();
+        assert_eq!(
+            Some(vec![("device_id", "t-1")]),
+            exact.captures("dt/t-1/temperature")
+        );
+        assert_eq!(None, exact.captures("dt/t-1/temperature/raw"));
+    }
+
+    #[test]
+    fn test_invalid_patterns() {
+        assert!(TopicPattern::parse("fleet/#/{device_id}").is_err());
+        assert!(TopicPattern::parse("fleet/{}").is_err());
+        assert!(TopicPattern::parse("{id}/{id}").is_err());
+    }
+}
@@ -0,0 +1,77 @@
+//! Benchmarks of the work done for every message: converting its attributes and
+//! encoding its row.
+//!
+//! Run with `cargo bench -p aws-lambda-sqs-ingestor`. Inputs come from
+//! [`aws_lambda_sqs_ingestor::fixtures`], the builders the unit tests use.
+//!
+//! Baseline medians from a release build on one vCPU of an Intel Xeon Linux VM; on
+//! other hardware, compare the ratios between them rather than the times:
+//!
+//! | benchmark                              | time     |
+//! |----------------------------------------|----------|
+//! | convert_message_attributes/0           | 15 ns    |
+//! | convert_message_attributes/10          | 2.5 µs   |
+//! | convert_message_attributes/100         | 31 µs    |
+//! | encode_table_sqs_messages/256          | 830 ns   |
+//! | encode_table_sqs_messages/4096         | 870 ns   |
+//! | encode_table_sqs_messages/65536        | 2.9 µs   |
+
+use aws_lambda_sqs_ingestor::attributes::{convert_message_attributes, AttributeFilter};
+use aws_lambda_sqs_ingestor::fixtures::{json_body, message_attributes, sqs_message};
+use aws_lambda_sqs_ingestor::sqs_messages::TableSqsMessages;
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use prost::Message;
+
+fn convert_attributes(c: &mut Criterion) {
+    let filter = AttributeFilter::default();
+    let mut group = c.benchmark_group("convert_message_attributes");
+    for count in [0, 10, 100] {
+        let attributes = message_attributes(count);
+        group.bench_with_input(
+            BenchmarkId::from_parameter(count),
+            &attributes,
+            |b, attributes| {
+                b.iter(|| convert_message_attributes(black_box(attributes), &filter).unwrap())
+            },
+        );
+    }
+    group.finish();
+}
+
+/// A row as the handler builds it for a message with a JSON body of `body_len` bytes
+/// and ten attributes
+fn table_row(body_len: usize) -> TableSqsMessages {
+    let message = sqs_message(
+        Some("059f36b4-87a3-44ab-83d2-661975830a7d"),
+        "1718020860000",
+    );
+    let attributes = message_attributes(10);
+    TableSqsMessages {
+        message_id: message.message_id,
+        receipt_handle: message.receipt_handle,
+        body: Some(json_body(body_len)),
+        attributes: message.attributes,
+        message_attributes: convert_message_attributes(&attributes, &AttributeFilter::default())
+            .unwrap(),
+        queue_arn: Some("arn:aws:sqs:us-west-2:123456789012:orders".to_string()),
+        aws_region: Some("us-west-2".to_string()),
+        ingested_at: Some(1_718_020_860_000_000),
+        ingested_date: Some(19_884),
+        ..Default::default()
+    }
+}
+
+fn encode_row(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode_table_sqs_messages");
+    for body_len in [256, 4 * 1024, 64 * 1024] {
+        let row = table_row(body_len);
+        group.throughput(Throughput::Bytes(row.encoded_len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(body_len), &row, |b, row| {
+            b.iter(|| black_box(row).encode_to_vec())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, convert_attributes, encode_row);
+criterion_main!(benches);
@@ -0,0 +1,293 @@
+//! Choosing which message attributes are stored, by name prefix, from
+//! `ATTR_INCLUDE_PREFIX` and `ATTR_EXCLUDE_PREFIX`, and converting them to the
+//! `message_attributes` column
+
+use crate::sqs_messages::table_sqs_messages::MessageAttributes;
+use aws_lambda_events::sqs::SqsMessageAttribute;
+use base64::{engine::general_purpose, Engine as _};
+use prost::bytes::Bytes;
+use serde_json::Value;
+use std::collections::HashMap;
+use tracing::warn;
+
+/// Keeps the attributes whose names start with an include prefix, or every attribute
+/// when there are none, unless they start with an exclude prefix
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AttributeFilter {
+    include: Vec<String>,
+    exclude: Vec<String>,
+}
+
+impl AttributeFilter {
+    /// Read comma-separated prefixes from `ATTR_INCLUDE_PREFIX` and `ATTR_EXCLUDE_PREFIX`
+    pub fn from_env() -> Self {
+        Self::new(
+            &std::env::var("ATTR_INCLUDE_PREFIX").unwrap_or_default(),
+            &std::env::var("ATTR_EXCLUDE_PREFIX").unwrap_or_default(),
+        )
+    }
+
+    pub fn new(include: &str, exclude: &str) -> Self {
+        Self {
+            include: prefixes(include),
+            exclude: prefixes(exclude),
+        }
+    }
+
+    pub fn keeps(&self, name: &str) -> bool {
+        let included = self.include.is_empty()
+            || self
+                .include
+                .iter()
+                .any(|prefix| name.starts_with(prefix.as_str()));
+        included
+            && !self
+                .exclude
+                .iter()
+                .any(|prefix| name.starts_with(prefix.as_str()))
+    }
+}
+
+/// Convert SQS message attributes to protobuf message attributes structure, keeping
+/// only those `filter` keeps
+///
+/// An attribute with no value of its data type fails the message, rather than being
+/// stored empty.
+pub fn convert_message_attributes(
+    attrs: &HashMap<String, SqsMessageAttribute>,
+    filter: &AttributeFilter,
+) -> anyhow::Result<HashMap<String, MessageAttributes>> {
+    let mut result = HashMap::new();
+
+    for (key, attr) in attrs.iter().filter(|(key, _)| filter.keeps(key)) {
+        let data_type = attr.data_type.as_deref().unwrap_or_default();
+        let has_value = if data_type.starts_with("Binary") {
+            attr.binary_value.is_some() || !attr.binary_list_values.is_empty()
+        } else {
+            attr.string_value.is_some() || !attr.string_list_values.is_empty()
+        };
+        if !has_value {
+            anyhow::bail!(
+                "Message attribute {:?} of data type {:?} has no value",
+                key,
+                data_type
+            );
+        }
+
+        // Base64Data holds the bytes already decoded from the event
+        let message_attr = MessageAttributes {
+            string_value: attr.string_value.clone(),
+            binary_value: attr
+                .binary_value
+                .as_ref()
+                .map(|value| Bytes::copy_from_slice(value)),
+            string_list_values: attr.string_list_values.clone(),
+            binary_list_values: attr
+                .binary_list_values
+                .iter()
+                .map(|value| Bytes::copy_from_slice(value))
+                .collect(),
+            data_type: attr.data_type.clone(),
+        };
+        result.insert(key.clone(), message_attr);
+    }
+
+    Ok(result)
+}
+
+/// What happens to a message with a binary attribute value that is not base64, from
+/// `ON_ATTR_DECODE_ERROR`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DecodeErrorPolicy {
+    /// Drop the attribute, with a warning, and ingest the message without it
+    Skip,
+    /// Report the message as a batch item failure, so SQS delivers it again
+    #[default]
+    Fail,
+}
+
+impl DecodeErrorPolicy {
+    pub fn new(value: &str) -> anyhow::Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "" | "fail" => Ok(DecodeErrorPolicy::Fail),
+            "skip" => Ok(DecodeErrorPolicy::Skip),
+            _ => anyhow::bail!("ON_ATTR_DECODE_ERROR must be skip or fail, got {:?}", value),
+        }
+    }
+
+    pub fn from_env() -> anyhow::Result<Self> {
+        Self::new(&std::env::var("ON_ATTR_DECODE_ERROR").unwrap_or_default())
+    }
+}
+
+/// Deal with the binary attribute values of a raw SQS event that are not base64, before
+/// the event is deserialized
+///
+/// `aws_lambda_events` decodes binary values while deserializing, and one it cannot
+/// decode fails the whole batch. Under [`DecodeErrorPolicy::Skip`] such attributes are
+/// removed. Under [`DecodeErrorPolicy::Fail`] their records are removed, and the message
+/// IDs of those records are returned to be reported as batch item failures; a record
+/// without a message ID cannot be reported, so it is kept and fails the batch as before.
+pub fn remove_undecodable_attributes(event: &mut Value, policy: DecodeErrorPolicy) -> Vec<String> {
+    let Some(records) = event.get_mut("Records").and_then(Value::as_array_mut) else {
+        return Vec::new();
+    };
+    let mut failed = Vec::new();
+    records.retain_mut(|record| {
+        let message_id = record
+            .get("messageId")
+            .and_then(Value::as_str)
+            .map(str::to_string);
+        let Some(attributes) = record
+            .get_mut("messageAttributes")
+            .and_then(Value::as_object_mut)
+        else {
+            return true;
+        };
+        let undecodable: Vec<String> = attributes
+            .iter()
+            .filter(|(_, attribute)| !decodes(attribute))
+            .map(|(name, _)| name.clone())
+            .collect();
+        if undecodable.is_empty() {
+            return true;
+        }
+        match (policy, message_id) {
+            (DecodeErrorPolicy::Skip, message_id) => {
+                for name in &undecodable {
+                    warn!(
+                        "Skipping message attribute {:?} of message {}: its binary value is not valid base64",
+                        name,
+                        message_id.as_deref().unwrap_or_default()
+                    );
+                    attributes.remove(name);
+                }
+                true
+            }
+            (DecodeErrorPolicy::Fail, Some(message_id)) => {
+                warn!(
+                    "Failing message {}: the binary values of message attributes {:?} are not valid base64",
+                    message_id, undecodable
+                );
+                failed.push(message_id);
+                false
+            }
+            (DecodeErrorPolicy::Fail, None) => true,
+        }
+    });
+    failed
+}
+
+/// Whether every binary value of an attribute is base64, as `Base64Data` decodes it
+fn decodes(attribute: &Value) -> bool {
+    let binary_list = attribute
+        .get("binaryListValues")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten();
+    attribute
+        .get("binaryValue")
+        .into_iter()
+        .chain(binary_list)
+        .all(|value| match value {
+            Value::Null => true,
+            Value::String(value) => general_purpose::STANDARD.decode(value).is_ok(),
+            _ => false,
+        })
+}
+
+fn prefixes(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|prefix| !prefix.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_lambda_events::event::sqs::SqsEvent;
+    use serde_json::json;
+
+    #[test]
+    fn test_prefix_lists() {
+        let filter = AttributeFilter::new(" app., ,tenant", "");
+        assert_eq!(vec!["app.", "tenant"], filter.include);
+        assert!(filter.exclude.is_empty());
+
+        // No prefixes keep everything
+        assert!(AttributeFilter::default().keeps("anything"));
+    }
+
+    fn event_with_binary_values(values: &[&str]) -> Value {
+        let records: Vec<Value> = values
+            .iter()
+            .enumerate()
+            .map(|(n, value)| {
+                json!({
+                    "messageId": format!("msg-{}", n + 1),
+                    "body": "hello",
+                    "messageAttributes": {
+                        "tenant": {"stringValue": "acme", "dataType": "String"},
+                        "checksum": {"binaryValue": value, "dataType": "Binary"},
+                    },
+                })
+            })
+            .collect();
+        json!({ "Records": records })
+    }
+
+    #[test]
+    fn test_valid_binary_attribute_is_decoded() {
+        let mut event = event_with_binary_values(&["3q2+7w=="]);
+
+        let failed = remove_undecodable_attributes(&mut event, DecodeErrorPolicy::Fail);
+
+        assert!(failed.is_empty());
+        let event: SqsEvent = serde_json::from_value(event).unwrap();
+        let attributes = &event.records[0].message_attributes;
+        assert_eq!(
+            Some(&vec![0xde, 0xad, 0xbe, 0xef]),
+            attributes["checksum"].binary_value.as_deref()
+        );
+    }
+
+    #[test]
+    fn test_undecodable_attribute_is_skipped() {
+        let mut event = event_with_binary_values(&["3q2+7w==", "not base64!"]);
+
+        let failed = remove_undecodable_attributes(&mut event, DecodeErrorPolicy::Skip);
+
+        assert!(failed.is_empty());
+        let event: SqsEvent = serde_json::from_value(event).unwrap();
+        assert_eq!(2, event.records.len());
+        let attributes = &event.records[1].message_attributes;
+        assert!(!attributes.contains_key("checksum"));
+        assert_eq!(Some("acme"), attributes["tenant"].string_value.as_deref());
+    }
+
+    #[test]
+    fn test_undecodable_attribute_fails_its_message() {
+        let mut event = event_with_binary_values(&["3q2+7w==", "not base64!", "3q2+7w=="]);
+        event["Records"][2]["messageAttributes"]["checksum"] =
+            json!({"binaryListValues": ["3q2+7w==", "%%%"], "dataType": "Binary"});
+
+        let failed = remove_undecodable_attributes(&mut event, DecodeErrorPolicy::Fail);
+
+        assert_eq!(vec!["msg-2", "msg-3"], failed);
+        let event: SqsEvent = serde_json::from_value(event).unwrap();
+        let ids: Vec<_> = event
+            .records
+            .iter()
+            .map(|record| record.message_id.as_deref())
+            .collect();
+        assert_eq!(vec![Some("msg-1")], ids);
+
+        assert_eq!(
+            DecodeErrorPolicy::Skip,
+            DecodeErrorPolicy::new(" Skip ").unwrap()
+        );
+        assert!(DecodeErrorPolicy::new("ignore").is_err());
+    }
+}
@@ -0,0 +1,195 @@
+//! Parsers turning SQS message bodies into JSON values, selected by `BODY_CONTENT_TYPE`
+
+use anyhow::{bail, Context, Result};
+use serde_json::{Map, Value};
+use tracing::warn;
+
+/// How message bodies are encoded
+#[derive(Debug, Clone, PartialEq)]
+pub enum BodyFormat {
+    Json,
+    /// `application/x-www-form-urlencoded`
+    Form,
+    /// CSV rows; without a configured header the first row is the header
+    Csv {
+        header: Option<Vec<String>>,
+    },
+    /// Anything else: the body is kept as a JSON string
+    Raw,
+}
+
+impl BodyFormat {
+    /// Read the format from `BODY_CONTENT_TYPE` (and `BODY_CSV_HEADER` for CSV)
+    ///
+    /// Returns `None` when the variable is unset or empty, in which case bodies are not
+    /// parsed.
+    pub fn from_env() -> Option<Self> {
+        let content_type = std::env::var("BODY_CONTENT_TYPE")
+            .ok()
+            .filter(|content_type| !content_type.trim().is_empty())?;
+        let header = std::env::var("BODY_CSV_HEADER").ok();
+        Some(Self::new(&content_type, header.as_deref()))
+    }
+
+    pub fn new(content_type: &str, csv_header: Option<&str>) -> Self {
+        match content_type.trim().to_ascii_lowercase().as_str() {
+            "json" | "application/json" => BodyFormat::Json,
+            "form" | "application/x-www-form-urlencoded" => BodyFormat::Form,
+            "csv" | "text/csv" => BodyFormat::Csv {
+                header: csv_header
+                    .filter(|header| !header.trim().is_empty())
+                    .map(|header| {
+                        header
+                            .split(',')
+                            .map(|name| name.trim().to_string())
+                            .collect()
+                    }),
+            },
+            "raw" => BodyFormat::Raw,
+            other => {
+                warn!(
+                    "Unsupported BODY_CONTENT_TYPE {:?}; bodies are kept as raw strings",
+                    other
+                );
+                BodyFormat::Raw
+            }
+        }
+    }
+
+    /// Parse a body; a body that does not match the format is kept as a raw string
+    pub fn parse(&self, body: &str) -> Value {
+        let parsed = match self {
+            BodyFormat::Json => serde_json::from_str(body).context("Body is not valid JSON"),
+            BodyFormat::Form => Ok(parse_form(body)),
+            BodyFormat::Csv { header } => parse_csv(body, header.as_deref()),
+            BodyFormat::Raw => return Value::String(body.to_string()),
+        };
+        parsed.unwrap_or_else(|e| {
+            warn!("Keeping body as a raw string: {:#}", e);
+            Value::String(body.to_string())
+        })
+    }
+}
+
+/// Form fields as a flat object; a key that appears more than once maps to an array
+fn parse_form(body: &str) -> Value {
+    let mut object = Map::new();
+    for (key, value) in form_urlencoded::parse(body.trim().as_bytes()) {
+        let value = Value::String(value.into_owned());
+        match object.get_mut(key.as_ref()) {
+            Some(Value::Array(values)) => values.push(value),
+            Some(existing) => *existing = Value::Array(vec![existing.take(), value]),
+            None => {
+                object.insert(key.into_owned(), value);
+            }
+        }
+    }
+    Value::Object(object)
+}
+
+/// CSV rows as objects keyed by the header: one row gives an object, several an array
+///
+/// Values beyond the header are keyed `column_<n>` (1-based); missing values are omitted.
+fn parse_csv(body: &str, header: Option<&[String]>) -> Result<Value> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(body.as_bytes());
+    let mut records = reader.records();
+
+    let header: Vec<String> = match header {
+        Some(header) => header.to_vec(),
+        None => match records.next() {
+            Some(row) => row
+                .context("Failed to read CSV header row")?
+                .iter()
+                .map(str::to_string)
+                .collect(),
+            None => bail!("CSV body is empty"),
+        },
+    };
+
+    let mut rows = Vec::new();
+    for record in records {
+        let record = record.context("Failed to read CSV row")?;
+        let row: Map<String, Value> = record
+            .iter()
+            .enumerate()
+            .map(|(i, value)| {
+                let key = header
+                    .get(i)
+                    .cloned()
+                    .unwrap_or_else(|| format!("column_{}", i + 1));
+                (key, Value::String(value.to_string()))
+            })
+            .collect();
+        rows.push(Value::Object(row));
+    }
+
+    Ok(match rows.len() {
+        1 => rows.remove(0),
+        _ => Value::Array(rows),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_form_body() {
+        let body = "order_id=42&customer=Jane+Doe&note=50%25+off&tag=a&tag=b";
+
+        assert_eq!(
+            json!({
+                "order_id": "42",
+                "customer": "Jane Doe",
+                "note": "50% off",
+                "tag": ["a", "b"],
+            }),
+            BodyFormat::new("form", None).parse(body)
+        );
+    }
+
+    #[test]
+    fn test_csv_body_with_first_row_header() {
+        let format = BodyFormat::new("text/csv", None);
+
+        assert_eq!(
+            json!({"id": "1", "name": "widget, large"}),
+            format.parse("id,name\n1,\"widget, large\"\n")
+        );
+        assert_eq!(
+            json!([
+                {"id": "1", "name": "widget"},
+                {"id": "2", "name": "gadget", "column_3": "extra"},
+            ]),
+            format.parse("id,name\n1,widget\n2,gadget,extra\n")
+        );
+    }
+
+    #[test]
+    fn test_csv_body_with_configured_header() {
+        let format = BodyFormat::new("csv", Some("id, name"));
+
+        assert_eq!(
+            json!([{"id": "1", "name": "widget"}, {"id": "2"}]),
+            format.parse("1,widget\n2")
+        );
+    }
+
+    #[test]
+    fn test_unsupported_and_invalid_bodies_fall_back_to_raw() {
+        assert_eq!(BodyFormat::Raw, BodyFormat::new("application/xml", None));
+        assert_eq!(
+            json!("<order/>"),
+            BodyFormat::new("application/xml", None).parse("<order/>")
+        );
+        assert_eq!(
+            json!("not json"),
+            BodyFormat::new("json", None).parse("not json")
+        );
+        assert_eq!(json!({"a": 1}), BodyFormat::Json.parse(r#"{"a": 1}"#));
+    }
+}
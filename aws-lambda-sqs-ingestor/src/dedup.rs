@@ -0,0 +1,135 @@
+//! Deduplicating FIFO messages by their producer-supplied `MessageDeduplicationId`
+
+use anyhow::{Context, Result};
+use aws_lambda_events::sqs::SqsMessage;
+use std::collections::{HashSet, VecDeque};
+
+/// Deduplication ids remembered when DEDUP_CAPACITY is not set
+pub const DEFAULT_CAPACITY: usize = 10_000;
+
+/// The key a message is deduplicated by, if it has a `MessageDeduplicationId`
+///
+/// Ids are scoped to their queue and message group: high-throughput FIFO queues
+/// deduplicate per message group, so the same id in two groups is two messages.
+pub fn dedup_key(message: &SqsMessage) -> Option<String> {
+    let id = message.attributes.get("MessageDeduplicationId")?;
+    let group = message
+        .attributes
+        .get("MessageGroupId")
+        .map(String::as_str)
+        .unwrap_or_default();
+    let queue = message.event_source_arn.as_deref().unwrap_or_default();
+    Some(format!("{}/{}/{}", queue, group, id))
+}
+
+/// Deduplication keys of recently ingested messages, evicting the oldest first
+///
+/// A key is claimed before its message is ingested and released again if the message
+/// fails, so only acknowledged messages stay claimed.
+pub struct DedupStore {
+    capacity: usize,
+    order: VecDeque<String>,
+    seen: HashSet<String>,
+}
+
+impl DedupStore {
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "capacity must be positive");
+        Self {
+            capacity,
+            order: VecDeque::with_capacity(capacity),
+            seen: HashSet::with_capacity(capacity),
+        }
+    }
+
+    /// A store when DEDUP_BY_DEDUPLICATION_ID is true, holding DEDUP_CAPACITY keys
+    pub fn from_env() -> Result<Option<Self>> {
+        let enabled = std::env::var("DEDUP_BY_DEDUPLICATION_ID")
+            .map(|value| value == "true" || value == "1")
+            .unwrap_or(false);
+        if !enabled {
+            return Ok(None);
+        }
+        let capacity = match std::env::var("DEDUP_CAPACITY") {
+            Ok(value) => value
+                .trim()
+                .parse::<usize>()
+                .ok()
+                .filter(|capacity| *capacity > 0)
+                .with_context(|| {
+                    format!("DEDUP_CAPACITY must be a positive integer, got {:?}", value)
+                })?,
+            Err(_) => DEFAULT_CAPACITY,
+        };
+        Ok(Some(Self::new(capacity)))
+    }
+
+    /// Claim `key`, returning false if it already is
+    pub fn claim(&mut self, key: &str) -> bool {
+        if self.seen.contains(key) {
+            return false;
+        }
+        if self.order.len() == self.capacity {
+            if let Some(evicted) = self.order.pop_front() {
+                self.seen.remove(&evicted);
+            }
+        }
+        self.order.push_back(key.to_string());
+        self.seen.insert(key.to_string());
+        true
+    }
+
+    /// Release `key`, so its message is ingested when delivered again
+    pub fn release(&mut self, key: &str) {
+        if self.seen.remove(key) {
+            self.order.retain(|claimed| claimed != key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fifo_message(group: &str, dedup_id: &str) -> SqsMessage {
+        SqsMessage {
+            event_source_arn: Some("arn:aws:sqs:us-west-2:123456789012:orders.fifo".to_string()),
+            attributes: [
+                ("MessageGroupId".to_string(), group.to_string()),
+                ("MessageDeduplicationId".to_string(), dedup_id.to_string()),
+            ]
+            .into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_dedup_key() {
+        assert_eq!(
+            Some("arn:aws:sqs:us-west-2:123456789012:orders.fifo/customer-1/order-7".to_string()),
+            dedup_key(&fifo_message("customer-1", "order-7"))
+        );
+        assert_ne!(
+            dedup_key(&fifo_message("customer-1", "order-7")),
+            dedup_key(&fifo_message("customer-2", "order-7"))
+        );
+        // Standard queues have no deduplication id
+        assert_eq!(None, dedup_key(&SqsMessage::default()));
+    }
+
+    #[test]
+    fn test_claims() {
+        let mut store = DedupStore::new(2);
+        assert!(store.claim("a"));
+        assert!(!store.claim("a"));
+
+        store.release("a");
+        assert!(store.claim("a"));
+
+        // The oldest claim is evicted once the store is full
+        assert!(store.claim("b"));
+        assert!(store.claim("c"));
+        assert!(store.claim("a"));
+        assert!(!store.claim("c"));
+    }
+}
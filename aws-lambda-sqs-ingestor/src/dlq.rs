@@ -0,0 +1,592 @@
+//! Forwarding messages that failed their last attempt to a dead-letter queue, selected
+//! by `DLQ_URL`, with the source metadata a redrive would lose
+
+use anyhow::{bail, Context, Result};
+use aws_lambda_events::sqs::SqsMessage;
+use aws_sdk_sqs::primitives::Blob;
+use aws_sdk_sqs::types::MessageAttributeValue;
+use base64::{engine::general_purpose, Engine as _};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::future::Future;
+
+/// Receive count of the last attempt when DLQ_MAX_RECEIVE_COUNT is not set; the
+/// Terraform queue's default `maxReceiveCount`
+pub const DEFAULT_MAX_RECEIVE_COUNT: u32 = 3;
+
+/// Most message attributes SQS accepts on one message
+const MAX_ATTRIBUTES: usize = 10;
+
+/// Longest error kept in the `zerobus.error` attribute
+const MAX_ERROR_LEN: usize = 1024;
+
+/// Attributes the `metadata` encoding adds, ahead of the original message attributes
+pub const SOURCE_ARN_ATTRIBUTE: &str = "zerobus.source_arn";
+pub const TARGET_TABLE_ATTRIBUTE: &str = "zerobus.target_table";
+pub const MESSAGE_ID_ATTRIBUTE: &str = "zerobus.message_id";
+pub const ERROR_ATTRIBUTE: &str = "zerobus.error";
+pub const SOURCE_METADATA_ATTRIBUTE: &str = "zerobus.source_metadata";
+
+/// What a forwarded message carries besides the original body
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeadLetterEncoding {
+    /// The original message attributes only, as an SQS redrive would move it
+    Body,
+    /// The original message attributes, plus the source queue ARN, target table,
+    /// message ID, error, and system attributes as `zerobus.*` attributes
+    Metadata,
+}
+
+impl DeadLetterEncoding {
+    pub fn new(value: &str) -> Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "" | "metadata" => Ok(DeadLetterEncoding::Metadata),
+            "body" => Ok(DeadLetterEncoding::Body),
+            _ => bail!("DLQ_ENCODING must be metadata or body, got {:?}", value),
+        }
+    }
+}
+
+/// Where and when failed messages are forwarded
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeadLetterQueue {
+    pub url: String,
+    /// Messages are forwarded once they fail the attempt with this receive count
+    pub max_receive_count: u32,
+    pub encoding: DeadLetterEncoding,
+}
+
+impl DeadLetterQueue {
+    /// A queue when DLQ_URL is set, with DLQ_MAX_RECEIVE_COUNT and DLQ_ENCODING
+    pub fn from_env() -> Result<Option<Self>> {
+        let Some(url) = std::env::var("DLQ_URL")
+            .ok()
+            .filter(|url| !url.trim().is_empty())
+        else {
+            return Ok(None);
+        };
+        let max_receive_count = match std::env::var("DLQ_MAX_RECEIVE_COUNT") {
+            Ok(value) => value
+                .trim()
+                .parse::<u32>()
+                .ok()
+                .filter(|count| *count > 0)
+                .with_context(|| {
+                    format!(
+                        "DLQ_MAX_RECEIVE_COUNT must be a positive integer, got {:?}",
+                        value
+                    )
+                })?,
+            Err(_) => DEFAULT_MAX_RECEIVE_COUNT,
+        };
+        let encoding = DeadLetterEncoding::new(&std::env::var("DLQ_ENCODING").unwrap_or_default())?;
+        Ok(Some(Self {
+            url: url.trim().to_string(),
+            max_receive_count,
+            encoding,
+        }))
+    }
+
+    /// Whether a failure of `message` is its last attempt, after which SQS would redrive
+    /// it; messages without an `ApproximateReceiveCount` are treated as such
+    pub fn is_last_attempt(&self, message: &SqsMessage) -> bool {
+        message
+            .attributes
+            .get("ApproximateReceiveCount")
+            .and_then(|count| count.parse::<u32>().ok())
+            .map_or(true, |count| count >= self.max_receive_count)
+    }
+
+    /// FIFO dead-letter queues need a message group and deduplication ID
+    fn is_fifo(&self) -> bool {
+        self.url.ends_with(".fifo")
+    }
+}
+
+/// A message attribute value; list values are reserved by SQS and never sent
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttributeValue {
+    String { data_type: String, value: String },
+    Binary { data_type: String, value: Vec<u8> },
+}
+
+impl AttributeValue {
+    fn string(value: impl Into<String>) -> Self {
+        AttributeValue::String {
+            data_type: "String".to_string(),
+            value: value.into(),
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        match self {
+            AttributeValue::String { data_type, value } => {
+                json!({"data_type": data_type, "string_value": value})
+            }
+            AttributeValue::Binary { data_type, value } => json!({
+                "data_type": data_type,
+                "binary_value": general_purpose::STANDARD.encode(value),
+            }),
+        }
+    }
+
+    fn to_sdk(&self) -> Result<MessageAttributeValue> {
+        let builder = match self {
+            AttributeValue::String { data_type, value } => MessageAttributeValue::builder()
+                .data_type(data_type)
+                .string_value(value),
+            AttributeValue::Binary { data_type, value } => MessageAttributeValue::builder()
+                .data_type(data_type)
+                .binary_value(Blob::new(value.clone())),
+        };
+        Ok(builder.build()?)
+    }
+}
+
+/// The message sent to the dead-letter queue for a failed message
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeadLetter {
+    pub body: String,
+    pub attributes: BTreeMap<String, AttributeValue>,
+    pub message_group_id: Option<String>,
+    pub message_deduplication_id: Option<String>,
+}
+
+impl DeadLetter {
+    /// Build the dead letter for `message`, which was routed to `target_table` and
+    /// failed with `error`
+    ///
+    /// SQS allows ten attributes per message. With the `metadata` encoding, the
+    /// `zerobus.*` attributes come first and the original attributes fill the rest in
+    /// name order; those that do not fit are kept in `zerobus.source_metadata` instead,
+    /// so nothing is lost.
+    pub fn new(
+        queue: &DeadLetterQueue,
+        message: &SqsMessage,
+        target_table: &str,
+        error: &str,
+    ) -> Self {
+        let mut original: BTreeMap<String, AttributeValue> = message
+            .message_attributes
+            .iter()
+            .filter_map(|(name, attribute)| {
+                let data_type = attribute
+                    .data_type
+                    .clone()
+                    .unwrap_or_else(|| "String".to_string());
+                let value = match (&attribute.string_value, &attribute.binary_value) {
+                    (Some(value), _) => AttributeValue::String {
+                        data_type,
+                        value: value.clone(),
+                    },
+                    (None, Some(value)) => AttributeValue::Binary {
+                        data_type,
+                        value: value.0.clone(),
+                    },
+                    (None, None) => return None,
+                };
+                Some((name.clone(), value))
+            })
+            .collect();
+
+        let mut attributes = BTreeMap::new();
+        if queue.encoding == DeadLetterEncoding::Metadata {
+            let mut metadata = BTreeMap::from([
+                (
+                    SOURCE_ARN_ATTRIBUTE.to_string(),
+                    AttributeValue::string(message.event_source_arn.as_deref().unwrap_or_default()),
+                ),
+                (
+                    TARGET_TABLE_ATTRIBUTE.to_string(),
+                    AttributeValue::string(target_table),
+                ),
+                (
+                    MESSAGE_ID_ATTRIBUTE.to_string(),
+                    AttributeValue::string(message.message_id.as_deref().unwrap_or_default()),
+                ),
+                (
+                    ERROR_ATTRIBUTE.to_string(),
+                    AttributeValue::string(truncate(error)),
+                ),
+            ]);
+            // SQS rejects empty string values
+            metadata.retain(|_, attribute| {
+                !matches!(attribute, AttributeValue::String { value, .. } if value.is_empty())
+            });
+
+            let room = MAX_ATTRIBUTES - metadata.len() - 1;
+            let overflow: Map<String, Value> = split_after(&mut original, room)
+                .into_iter()
+                .map(|(name, value)| (name, value.to_json()))
+                .collect();
+            let source_metadata = json!({
+                "aws_region": message.aws_region,
+                "receipt_handle": message.receipt_handle,
+                "md5_of_body": message.md5_of_body,
+                "md5_of_message_attributes": message.md5_of_message_attributes,
+                "attributes": message.attributes,
+                "message_attributes": overflow,
+            });
+            metadata.insert(
+                SOURCE_METADATA_ATTRIBUTE.to_string(),
+                AttributeValue::string(source_metadata.to_string()),
+            );
+            attributes.extend(metadata);
+        }
+        attributes.extend(original);
+
+        let (message_group_id, message_deduplication_id) = if queue.is_fifo() {
+            (
+                Some(
+                    message
+                        .attributes
+                        .get("MessageGroupId")
+                        .cloned()
+                        .unwrap_or_else(|| "zerobus-dead-letters".to_string()),
+                ),
+                message.message_id.clone(),
+            )
+        } else {
+            (None, None)
+        };
+
+        Self {
+            body: message.body.clone().unwrap_or_default(),
+            attributes,
+            message_group_id,
+            message_deduplication_id,
+        }
+    }
+}
+
+/// Split off the attributes after the first `keep`, in name order
+fn split_after(
+    attributes: &mut BTreeMap<String, AttributeValue>,
+    keep: usize,
+) -> BTreeMap<String, AttributeValue> {
+    match attributes.keys().nth(keep).cloned() {
+        Some(first) => attributes.split_off(&first),
+        None => BTreeMap::new(),
+    }
+}
+
+fn truncate(error: &str) -> &str {
+    match error.char_indices().nth(MAX_ERROR_LEN) {
+        Some((end, _)) => &error[..end],
+        None => error,
+    }
+}
+
+/// The SendMessage call the ingestor makes; implemented for the AWS SDK client, and by
+/// tests
+pub trait SendMessage: Send + Sync {
+    fn send_message(
+        &self,
+        queue_url: &str,
+        letter: &DeadLetter,
+    ) -> impl Future<Output = Result<()>> + Send;
+}
+
+impl SendMessage for aws_sdk_sqs::Client {
+    async fn send_message(&self, queue_url: &str, letter: &DeadLetter) -> Result<()> {
+        let attributes = letter
+            .attributes
+            .iter()
+            .map(|(name, value)| Ok((name.clone(), value.to_sdk()?)))
+            .collect::<Result<_>>()?;
+        self.send_message()
+            .queue_url(queue_url)
+            .message_body(&letter.body)
+            .set_message_attributes(Some(attributes))
+            .set_message_group_id(letter.message_group_id.clone())
+            .set_message_deduplication_id(letter.message_deduplication_id.clone())
+            .send()
+            .await
+            .context("SendMessage to the dead-letter queue failed")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_lambda_events::encodings::Base64Data;
+    use aws_lambda_events::sqs::SqsMessageAttribute;
+
+    fn queue(url: &str, encoding: DeadLetterEncoding) -> DeadLetterQueue {
+        DeadLetterQueue {
+            url: url.to_string(),
+            max_receive_count: 3,
+            encoding,
+        }
+    }
+
+    fn attribute(value: &str) -> SqsMessageAttribute {
+        SqsMessageAttribute {
+            string_value: Some(value.to_string()),
+            data_type: Some("String".to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn message(message_attributes: usize) -> SqsMessage {
+        SqsMessage {
+            message_id: Some("msg-1".to_string()),
+            receipt_handle: Some("handle-1".to_string()),
+            body: Some("hello".to_string()),
+            event_source_arn: Some("arn:aws:sqs:us-west-2:123456789012:orders".to_string()),
+            aws_region: Some("us-west-2".to_string()),
+            attributes: [
+                ("ApproximateReceiveCount".to_string(), "3".to_string()),
+                ("SentTimestamp".to_string(), "1700000000000".to_string()),
+            ]
+            .into(),
+            message_attributes: (0..message_attributes)
+                .map(|i| (format!("attr-{}", i), attribute(&i.to_string())))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn string_value(letter: &DeadLetter, name: &str) -> Option<String> {
+        match letter.attributes.get(name)? {
+            AttributeValue::String { value, .. } => Some(value.clone()),
+            AttributeValue::Binary { .. } => None,
+        }
+    }
+
+    #[test]
+    fn test_encoding_is_parsed() {
+        assert_eq!(
+            DeadLetterEncoding::Metadata,
+            DeadLetterEncoding::new("").unwrap()
+        );
+        assert_eq!(
+            DeadLetterEncoding::Metadata,
+            DeadLetterEncoding::new("Metadata").unwrap()
+        );
+        assert_eq!(
+            DeadLetterEncoding::Body,
+            DeadLetterEncoding::new("body").unwrap()
+        );
+        assert!(DeadLetterEncoding::new("envelope").is_err());
+    }
+
+    #[test]
+    fn test_last_attempt() {
+        let queue = queue(
+            "https://sqs.us-west-2.amazonaws.com/123456789012/dlq",
+            DeadLetterEncoding::Metadata,
+        );
+        let mut message = message(0);
+        assert!(queue.is_last_attempt(&message));
+        message
+            .attributes
+            .insert("ApproximateReceiveCount".to_string(), "2".to_string());
+        assert!(!queue.is_last_attempt(&message));
+        message.attributes.remove("ApproximateReceiveCount");
+        assert!(queue.is_last_attempt(&message));
+    }
+
+    #[test]
+    fn test_metadata_encoding_keeps_every_attribute() {
+        let queue = queue(
+            "https://sqs.us-west-2.amazonaws.com/123456789012/dlq",
+            DeadLetterEncoding::Metadata,
+        );
+        let mut message = message(7);
+        message.message_attributes.insert(
+            "attr-blob".to_string(),
+            SqsMessageAttribute {
+                binary_value: Some(Base64Data(vec![0xde, 0xad])),
+                data_type: Some("Binary".to_string()),
+                ..Default::default()
+            },
+        );
+        let letter = DeadLetter::new(&queue, &message, "main.default.orders", "stream closed");
+
+        assert_eq!("hello", letter.body);
+        assert_eq!(MAX_ATTRIBUTES, letter.attributes.len());
+        assert_eq!(
+            Some("arn:aws:sqs:us-west-2:123456789012:orders".to_string()),
+            string_value(&letter, SOURCE_ARN_ATTRIBUTE)
+        );
+        assert_eq!(
+            Some("main.default.orders".to_string()),
+            string_value(&letter, TARGET_TABLE_ATTRIBUTE)
+        );
+        assert_eq!(
+            Some("msg-1".to_string()),
+            string_value(&letter, MESSAGE_ID_ATTRIBUTE)
+        );
+        assert_eq!(
+            Some("stream closed".to_string()),
+            string_value(&letter, ERROR_ATTRIBUTE)
+        );
+        // Original attributes in name order, as many as fit
+        let kept: Vec<&str> = letter
+            .attributes
+            .keys()
+            .map(String::as_str)
+            .filter(|name| !name.starts_with("zerobus."))
+            .collect();
+        assert_eq!(vec!["attr-0", "attr-1", "attr-2", "attr-3", "attr-4"], kept);
+
+        let metadata: Value =
+            serde_json::from_str(&string_value(&letter, SOURCE_METADATA_ATTRIBUTE).unwrap())
+                .unwrap();
+        assert_eq!("us-west-2", metadata["aws_region"]);
+        assert_eq!("handle-1", metadata["receipt_handle"]);
+        assert_eq!("3", metadata["attributes"]["ApproximateReceiveCount"]);
+        assert_eq!(
+            json!({
+                "attr-5": {"data_type": "String", "string_value": "5"},
+                "attr-6": {"data_type": "String", "string_value": "6"},
+                "attr-blob": {"data_type": "Binary", "binary_value": "3q0="},
+            }),
+            metadata["message_attributes"]
+        );
+        assert_eq!(None, letter.message_group_id);
+    }
+
+    #[test]
+    fn test_body_encoding_only_forwards_original_attributes() {
+        let queue = queue(
+            "https://sqs.us-west-2.amazonaws.com/123456789012/dlq.fifo",
+            DeadLetterEncoding::Body,
+        );
+        let mut message = message(2);
+        message
+            .attributes
+            .insert("MessageGroupId".to_string(), "customer-1".to_string());
+        let letter = DeadLetter::new(&queue, &message, "main.default.orders", "stream closed");
+
+        assert_eq!(
+            vec!["attr-0", "attr-1"],
+            letter.attributes.keys().collect::<Vec<_>>()
+        );
+        assert_eq!(Some("customer-1".to_string()), letter.message_group_id);
+        assert_eq!(Some("msg-1".to_string()), letter.message_deduplication_id);
+    }
+
+    #[test]
+    fn test_long_errors_are_truncated() {
+        let error = "é".repeat(MAX_ERROR_LEN + 10);
+        assert_eq!(MAX_ERROR_LEN, truncate(&error).chars().count());
+        assert_eq!("short", truncate("short"));
+    }
+}
+
+/// Against the SQS API of LocalStack; run with `--features localstack`
+#[cfg(all(test, feature = "localstack"))]
+mod localstack_tests {
+    use super::*;
+    use aws_lambda_events::encodings::Base64Data;
+    use aws_lambda_events::sqs::SqsMessageAttribute;
+    use zerobus_common::localstack::{self, TestQueue};
+
+    fn failed_message() -> SqsMessage {
+        SqsMessage {
+            message_id: Some("msg-1".to_string()),
+            body: Some(r#"{"order_id": 42}"#.to_string()),
+            event_source_arn: Some("arn:aws:sqs:us-east-1:000000000000:orders".to_string()),
+            attributes: [("ApproximateReceiveCount".to_string(), "3".to_string())].into(),
+            message_attributes: [
+                (
+                    "tenant".to_string(),
+                    SqsMessageAttribute {
+                        string_value: Some("acme".to_string()),
+                        data_type: Some("String".to_string()),
+                        ..Default::default()
+                    },
+                ),
+                (
+                    "checksum".to_string(),
+                    SqsMessageAttribute {
+                        binary_value: Some(Base64Data(vec![0, 1, 254, 255])),
+                        data_type: Some("Binary".to_string()),
+                        ..Default::default()
+                    },
+                ),
+            ]
+            .into(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dead_letter_arrives_with_its_metadata() {
+        let config = localstack::config().await;
+        let queue = TestQueue::create(&config, "dlq", false).await.unwrap();
+        let dlq = DeadLetterQueue {
+            url: queue.url.clone(),
+            max_receive_count: 3,
+            encoding: DeadLetterEncoding::Metadata,
+        };
+        let letter = DeadLetter::new(&dlq, &failed_message(), "main.default.orders", "closed");
+
+        SendMessage::send_message(&queue.client, &queue.url, &letter)
+            .await
+            .unwrap();
+
+        let received = queue.receive().await.unwrap();
+        assert_eq!(1, received.len());
+        assert_eq!(Some(r#"{"order_id": 42}"#), received[0].body());
+        let attributes = received[0].message_attributes().unwrap();
+        let string = |name: &str| attributes[name].string_value().map(str::to_string);
+        assert_eq!(
+            Some("main.default.orders".to_string()),
+            string(TARGET_TABLE_ATTRIBUTE)
+        );
+        assert_eq!(Some("closed".to_string()), string(ERROR_ATTRIBUTE));
+        assert_eq!(Some("msg-1".to_string()), string(MESSAGE_ID_ATTRIBUTE));
+        assert_eq!(Some("acme".to_string()), string("tenant"));
+        assert_eq!(
+            Some(&[0, 1, 254, 255][..]),
+            attributes["checksum"]
+                .binary_value()
+                .map(|blob| blob.as_ref())
+        );
+        let metadata: Value =
+            serde_json::from_str(&string(SOURCE_METADATA_ATTRIBUTE).unwrap()).unwrap();
+        assert_eq!(
+            json!("3"),
+            metadata["attributes"]["ApproximateReceiveCount"]
+        );
+
+        queue.delete().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fifo_dead_letter_sent_twice_arrives_once() {
+        let config = localstack::config().await;
+        let queue = TestQueue::create(&config, "dlq", true).await.unwrap();
+        let dlq = DeadLetterQueue {
+            url: queue.url.clone(),
+            max_receive_count: 3,
+            encoding: DeadLetterEncoding::Body,
+        };
+        let letter = DeadLetter::new(&dlq, &failed_message(), "main.default.orders", "closed");
+
+        // A retried invocation can forward the same failure concurrently; the message ID
+        // is its deduplication ID
+        let (first, second) = tokio::join!(
+            SendMessage::send_message(&queue.client, &queue.url, &letter),
+            SendMessage::send_message(&queue.client, &queue.url, &letter),
+        );
+        first.unwrap();
+        second.unwrap();
+
+        let received = queue.receive().await.unwrap();
+        assert_eq!(1, received.len());
+        let attributes = received[0].attributes().unwrap();
+        assert_eq!(
+            Some("msg-1"),
+            attributes
+                .get(&aws_sdk_sqs::types::MessageSystemAttributeName::MessageDeduplicationId)
+                .map(String::as_str)
+        );
+
+        queue.delete().await.unwrap();
+    }
+}
@@ -0,0 +1,50 @@
+//! SQS messages for the tests and benchmarks, shaped like the ones Lambda delivers
+
+use aws_lambda_events::encodings::Base64Data;
+use aws_lambda_events::sqs::{SqsMessage, SqsMessageAttribute};
+use std::collections::HashMap;
+
+/// A message with a short body, sent at `sent_timestamp` (milliseconds since the epoch)
+pub fn sqs_message(message_id: Option<&str>, sent_timestamp: &str) -> SqsMessage {
+    SqsMessage {
+        message_id: message_id.map(str::to_string),
+        receipt_handle: Some("handle".to_string()),
+        body: Some("hello".to_string()),
+        attributes: [("SentTimestamp".to_string(), sent_timestamp.to_string())].into(),
+        ..Default::default()
+    }
+}
+
+/// A JSON order of `len` bytes, or of the fewest bytes one takes when `len` is less
+pub fn json_body(len: usize) -> String {
+    let order = r#"{"order_id": "ord-1042", "amount": 129.95, "currency": "USD", "note": ""}"#;
+    let note = "x".repeat(len.saturating_sub(order.len()));
+    order.replace(r#""note": """#, &format!(r#""note": "{}""#, note))
+}
+
+/// `count` message attributes, a mix of the string, number, and binary ones producers
+/// set
+pub fn message_attributes(count: usize) -> HashMap<String, SqsMessageAttribute> {
+    (0..count)
+        .map(|n| {
+            let attribute = match n % 3 {
+                0 => SqsMessageAttribute {
+                    string_value: Some(format!("tenant-{}", n)),
+                    data_type: Some("String".to_string()),
+                    ..Default::default()
+                },
+                1 => SqsMessageAttribute {
+                    string_value: Some((n * 17).to_string()),
+                    data_type: Some("Number".to_string()),
+                    ..Default::default()
+                },
+                _ => SqsMessageAttribute {
+                    binary_value: Some(Base64Data(vec![n as u8; 32])),
+                    data_type: Some("Binary".to_string()),
+                    ..Default::default()
+                },
+            };
+            (format!("app.attr_{}", n), attribute)
+        })
+        .collect()
+}
@@ -0,0 +1,151 @@
+//! Verifying the MD5 digests SQS sends with each message
+
+use anyhow::{bail, Result};
+use aws_lambda_events::sqs::{SqsMessage, SqsMessageAttribute};
+use std::collections::HashMap;
+
+/// Whether VERIFY_MD5 is true
+pub fn verify_from_env() -> bool {
+    std::env::var("VERIFY_MD5")
+        .map(|value| value == "true" || value == "1")
+        .unwrap_or(false)
+}
+
+/// Check the body and message attributes of `message` against `md5OfBody` and
+/// `md5OfMessageAttributes`
+///
+/// A digest that is missing is not checked: SQS leaves `md5OfMessageAttributes` unset
+/// for messages without attributes.
+pub fn verify(message: &SqsMessage) -> Result<()> {
+    if let Some(expected) = message.md5_of_body.as_deref() {
+        let body = message.body.as_deref().unwrap_or_default();
+        let actual = format!("{:x}", md5::compute(body.as_bytes()));
+        if !actual.eq_ignore_ascii_case(expected) {
+            bail!(
+                "MD5 of the body is {}, but the message says {}; it may be corrupted",
+                actual,
+                expected
+            );
+        }
+    }
+    if let Some(expected) = message.md5_of_message_attributes.as_deref() {
+        let actual = attributes_md5(&message.message_attributes);
+        if !actual.eq_ignore_ascii_case(expected) {
+            bail!(
+                "MD5 of the message attributes is {}, but the message says {}; they may be corrupted",
+                actual,
+                expected
+            );
+        }
+    }
+    Ok(())
+}
+
+/// MD5 of message attributes as SQS computes it
+///
+/// Attributes are taken in name order. Each contributes its name, data type, a transport
+/// type byte (1 for string values, 2 for binary), and its value; the name, data type,
+/// and value are each preceded by their length as a 4-byte big-endian integer.
+fn attributes_md5(attributes: &HashMap<String, SqsMessageAttribute>) -> String {
+    let mut names: Vec<&String> = attributes.keys().collect();
+    names.sort();
+
+    let mut context = md5::Context::new();
+    for name in names {
+        let attribute = &attributes[name];
+        let data_type = attribute.data_type.as_deref().unwrap_or_default();
+        append_length_prefixed(&mut context, name.as_bytes());
+        append_length_prefixed(&mut context, data_type.as_bytes());
+        match &attribute.binary_value {
+            Some(value) if data_type.starts_with("Binary") => {
+                context.consume([2u8]);
+                append_length_prefixed(&mut context, value.as_slice());
+            }
+            _ => {
+                context.consume([1u8]);
+                let value = attribute.string_value.as_deref().unwrap_or_default();
+                append_length_prefixed(&mut context, value.as_bytes());
+            }
+        }
+    }
+    format!("{:x}", context.compute())
+}
+
+fn append_length_prefixed(context: &mut md5::Context, bytes: &[u8]) {
+    context.consume((bytes.len() as u32).to_be_bytes());
+    context.consume(bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attribute(data_type: &str, value: &str) -> SqsMessageAttribute {
+        SqsMessageAttribute {
+            data_type: Some(data_type.to_string()),
+            string_value: Some(value.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_digests_sqs_sent_are_verified() {
+        let message = SqsMessage {
+            body: Some("hello".to_string()),
+            md5_of_body: Some("5d41402abc4b2a76b9719d911017c592".to_string()),
+            message_attributes: HashMap::from([
+                ("priority".to_string(), attribute("Number", "3")),
+                ("origin".to_string(), attribute("String", "web")),
+            ]),
+            md5_of_message_attributes: Some(attributes_md5(&HashMap::from([
+                ("origin".to_string(), attribute("String", "web")),
+                ("priority".to_string(), attribute("Number", "3")),
+            ]))),
+            ..Default::default()
+        };
+        verify(&message).unwrap();
+
+        // Only the digests that are present are checked
+        verify(&SqsMessage {
+            body: Some("anything".to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn test_attribute_digest_matches_sqs() {
+        // The bytes SQS digests for a single `String` attribute `color=blue`
+        let attributes = HashMap::from([("color".to_string(), attribute("String", "blue"))]);
+        let mut expected = md5::Context::new();
+        expected.consume(b"\x00\x00\x00\x05color\x00\x00\x00\x06String\x01\x00\x00\x00\x04blue");
+        assert_eq!(
+            format!("{:x}", expected.compute()),
+            attributes_md5(&attributes)
+        );
+    }
+
+    #[test]
+    fn test_mismatched_digests_fail() {
+        let body = SqsMessage {
+            body: Some("hello, tampered".to_string()),
+            md5_of_body: Some("5d41402abc4b2a76b9719d911017c592".to_string()),
+            ..Default::default()
+        };
+        let error = verify(&body).unwrap_err().to_string();
+        assert!(error.contains("MD5 of the body"), "{}", error);
+
+        let attributes = SqsMessage {
+            body: Some("hello".to_string()),
+            md5_of_body: Some("5d41402abc4b2a76b9719d911017c592".to_string()),
+            message_attributes: HashMap::from([("origin".to_string(), attribute("String", "api"))]),
+            md5_of_message_attributes: Some(attributes_md5(&HashMap::from([(
+                "origin".to_string(),
+                attribute("String", "web"),
+            )]))),
+            ..Default::default()
+        };
+        let error = verify(&attributes).unwrap_err().to_string();
+        assert!(error.contains("MD5 of the message attributes"), "{}", error);
+    }
+}
@@ -0,0 +1,10 @@
+//! The row conversions shared by the Lambda handler in `main.rs` and the benchmarks in
+//! `benches/`
+
+pub mod attributes;
+pub mod fixtures;
+
+// Module for generated protobuf code
+pub mod sqs_messages {
+    include!("../gen/rust/sqs_messages.rs");
+}
@@ -1,26 +1,67 @@
 use anyhow::{Context, Result};
 use aws_lambda_events::{
     event::sqs::{SqsBatchResponse, SqsEvent},
-    sqs::{BatchItemFailure, SqsMessage, SqsMessageAttribute},
+    sqs::{BatchItemFailure, SqsMessage},
 };
-use base64::{engine::general_purpose, Engine as _};
 use databricks_zerobus_ingest_sdk::{StreamConfigurationOptions, TableProperties, ZerobusSdk, ZerobusStream};
 use lambda_runtime::{run, service_fn, Error, LambdaEvent};
-use prost::bytes::Bytes;
 use prost::Message;
 use prost_types::DescriptorProto;
-use std::sync::OnceLock;
-use tracing::{error, info};
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{Mutex, OnceCell};
+use tracing::{error, info, warn, Instrument, Span};
+use zerobus_common::audit::{self, BatchAudit};
+use zerobus_common::cardinality::LabelGuard;
+use zerobus_common::chunk::{self, ChunkInfo, Splitter};
+use zerobus_common::clock::{Clock, SystemClock};
+use zerobus_common::compress::PayloadCodec;
+use zerobus_common::descriptor::{load_environment_descriptor_proto, schema_hash};
+use zerobus_common::distribution::{self, Distribution};
+use zerobus_common::errors::classify;
+#[cfg(feature = "otel")]
+use zerobus_common::otel;
+use zerobus_common::payload_log::PayloadLog;
+use zerobus_common::pipeline::{AckFuture, CreateStream, IngestSink};
+use zerobus_common::unacked::{ReportDestination, UnackedReport};
+use zerobus_common::version;
 
-// Module for generated protobuf code
-pub mod sqs_messages {
-    include!("../gen/rust/sqs_messages.rs");
-}
+mod body;
+mod dedup;
+mod dlq;
+mod integrity;
+mod metrics;
+mod role;
+mod routing;
+mod unwrap;
+
+use aws_lambda_sqs_ingestor::attributes::{
+    convert_message_attributes, remove_undecodable_attributes, AttributeFilter, DecodeErrorPolicy,
+};
+use aws_lambda_sqs_ingestor::sqs_messages;
+use crate::body::BodyFormat;
+use crate::dedup::{dedup_key, DedupStore};
+use crate::dlq::{DeadLetter, DeadLetterQueue, SendMessage};
+use crate::metrics::{InvocationMetrics, MetricsSink, Unit};
+use crate::role::{RoleClient, RoleConfig};
+use crate::routing::{group_by_queue, queue_name, record_region, QueueBatch, QueueRoutes};
+use crate::unwrap::{Unwrap, Unwrapped};
 use crate::sqs_messages::TableSqsMessages;
 
 // Global SDK instance for reuse across Lambda invocations
 static SDK: OnceLock<ZerobusSdk> = OnceLock::new();
 
+// Audit stream, deduplication ids, and metric labels of this execution environment
+static WARM: Warm<ZerobusStream> = Warm::new();
+
+// CloudWatch client for METRICS_SINK=cloudwatch_api, created on first use, as
+// METRICS_ASSUME_ROLE_ARN when set
+static CLOUDWATCH: OnceCell<RoleClient<aws_sdk_cloudwatch::Client>> = OnceCell::const_new();
+
+// SQS client for DLQ_URL, created on first use, as DLQ_ASSUME_ROLE_ARN when set
+static SQS: OnceCell<RoleClient<aws_sdk_sqs::Client>> = OnceCell::const_new();
+
 /// Initialize the Zerobus SDK (called once per Lambda container)
 fn init_sdk() -> Result<&'static ZerobusSdk> {
     SDK.get_or_init(|| {
@@ -35,61 +76,134 @@ fn init_sdk() -> Result<&'static ZerobusSdk> {
     Ok(SDK.get().expect("SDK should be initialized"))
 }
 
-/// Load the protobuf descriptor from the embedded descriptor file
-fn load_descriptor_proto(file_name: &str, message_name: &str) -> DescriptorProto {
-    const DESCRIPTOR_BYTES: &[u8] = include_bytes!("../gen/descriptors/sqs_messages.descriptor");
+/// The CloudWatch client, acting as the role for metrics if there is one
+async fn cloudwatch_client() -> Result<aws_sdk_cloudwatch::Client> {
+    let role_client = CLOUDWATCH
+        .get_or_try_init(|| async {
+            let config = aws_config::load_from_env().await;
+            let sts = aws_sdk_sts::Client::new(&config);
+            anyhow::Ok(RoleClient::new(RoleConfig::from_env("METRICS")?, sts, move |credentials| {
+                let mut builder = aws_sdk_cloudwatch::config::Builder::from(&config);
+                if let Some(credentials) = credentials {
+                    builder = builder.credentials_provider(credentials);
+                }
+                aws_sdk_cloudwatch::Client::from_conf(builder.build())
+            }))
+        })
+        .await?;
+    role_client.client().await
+}
 
-    let file_descriptor_set = prost_types::FileDescriptorSet::decode(DESCRIPTOR_BYTES)
-        .expect("Failed to decode descriptor file");
+/// The SQS client for the dead-letter queue, acting as the role for it if there is one
+async fn sqs_client() -> Result<aws_sdk_sqs::Client> {
+    let role_client = SQS
+        .get_or_try_init(|| async {
+            let config = aws_config::load_from_env().await;
+            let sts = aws_sdk_sts::Client::new(&config);
+            anyhow::Ok(RoleClient::new(RoleConfig::from_env("DLQ")?, sts, move |credentials| {
+                let mut builder = aws_sdk_sqs::config::Builder::from(&config);
+                if let Some(credentials) = credentials {
+                    builder = builder.credentials_provider(credentials);
+                }
+                aws_sdk_sqs::Client::from_conf(builder.build())
+            }))
+        })
+        .await?;
+    role_client.client().await
+}
 
-    let file_descriptor_proto = file_descriptor_set
-        .file
-        .into_iter()
-        .find(|f| f.name.as_ref().map(|n| n.as_str()) == Some(file_name))
-        .expect("File descriptor not found");
+/// Sends dead letters with the SQS client of [`sqs_client`], created on the first one
+struct DeadLetterClient;
 
-    file_descriptor_proto
-        .message_type
-        .into_iter()
-        .find(|m| m.name.as_ref().map(|n| n.as_str()) == Some(message_name))
-        .expect("Message descriptor not found")
-}
-
-/// Convert SQS message attributes to protobuf message attributes structure
-fn convert_message_attributes(
-    attrs: &std::collections::HashMap<String, SqsMessageAttribute>,
-) -> std::collections::HashMap<String, crate::sqs_messages::table_sqs_messages::MessageAttributes> {
-    let mut result = std::collections::HashMap::new();
-
-    for (key, attr) in attrs {
-        let binary_value = attr.binary_value.as_ref().map(|bv| {
-            // Base64Data might be a newtype wrapper - try Debug format or direct access
-            let b64_str = format!("{:?}", bv);
-            // Remove quotes if Debug adds them
-            let b64_str = b64_str.trim_matches('"');
-            Bytes::from(general_purpose::STANDARD.decode(b64_str).unwrap_or_default())
-        });
+impl SendMessage for DeadLetterClient {
+    async fn send_message(&self, queue_url: &str, letter: &DeadLetter) -> Result<()> {
+        let client = sqs_client().await.context("Failed to create the dead-letter queue client")?;
+        SendMessage::send_message(&client, queue_url, letter).await
+    }
+}
 
-        let binary_list_values: Vec<Bytes> = attr.binary_list_values
-            .iter()
-            .map(|bv| {
-                let b64_str = format!("{:?}", bv);
-                let b64_str = b64_str.trim_matches('"');
-                Bytes::from(general_purpose::STANDARD.decode(b64_str).unwrap_or_default())
-            })
-            .collect();
+/// State kept across the invocations of one execution environment
+struct Warm<S> {
+    /// Stream to AUDIT_TABLE, opened on first use and kept open
+    audit_stream: Mutex<Option<S>>,
+    /// Deduplication ids of acknowledged messages
+    dedup: Mutex<Option<DedupStore>>,
+    /// Queue and table pairs given metric dimensions of their own
+    metric_labels: OnceLock<LabelGuard>,
+}
 
-        let message_attr = crate::sqs_messages::table_sqs_messages::MessageAttributes {
-            string_value: attr.string_value.clone(),
-            binary_value,
-            string_list_values: attr.string_list_values.clone(),
-            binary_list_values,
-            data_type: attr.data_type.clone(),
-        };
-        result.insert(key.clone(), message_attr);
+impl<S> Warm<S> {
+    const fn new() -> Self {
+        Self {
+            audit_stream: Mutex::const_new(None),
+            dedup: Mutex::const_new(None),
+            metric_labels: OnceLock::new(),
+        }
     }
+}
 
-    result
+/// Everything the handler reads from the environment
+///
+/// `function_handler` reads it on every invocation; tests build it from the variables
+/// they name with `zerobus_common::testing::handler::with_env`.
+struct HandlerConfig {
+    client_id: String,
+    client_secret: String,
+    /// Schema of the rows, from the descriptor set embedded for `ENVIRONMENT`
+    descriptor_proto: DescriptorProto,
+    /// Optional table receiving one summary row per batch
+    audit_table: Option<String>,
+    /// Tables of the source queues, TABLE_NAME for those without a route
+    routes: QueueRoutes,
+    flush_every_n: Option<usize>,
+    options: RowOptions,
+    metrics_sink: MetricsSink,
+    metrics_namespace: String,
+    /// The guard of the `Queue` dimension, admitting `METRICS_MAX_SERIES` queue and table
+    /// pairs; only the first invocation's is kept
+    metric_labels: LabelGuard,
+    dead_letter_queue: Option<DeadLetterQueue>,
+    /// An empty store when deduplicating; only the first invocation's is kept
+    dedup: Option<DedupStore>,
+    unacked_destination: ReportDestination,
+}
+
+impl HandlerConfig {
+    fn from_env() -> Result<Self> {
+        let table_name = std::env::var("TABLE_NAME").context("TABLE_NAME environment variable must be set")?;
+        let client_id = std::env::var("DATABRICKS_CLIENT_ID")
+            .context("DATABRICKS_CLIENT_ID environment variable must be set")?;
+        let client_secret = std::env::var("DATABRICKS_CLIENT_SECRET")
+            .context("DATABRICKS_CLIENT_SECRET environment variable must be set")?;
+        Ok(Self {
+            descriptor_proto: load_descriptor_proto("sqs_messages.proto", "table_sqs_messages")?,
+            audit_table: std::env::var("AUDIT_TABLE")
+                .ok()
+                .filter(|table| !table.trim().is_empty()),
+            routes: QueueRoutes::from_env(&table_name),
+            flush_every_n: flush_every_n()?,
+            options: RowOptions::from_env()?,
+            metrics_sink: MetricsSink::from_env()?,
+            metrics_namespace: metrics::namespace_from_env(),
+            metric_labels: LabelGuard::from_env()?,
+            dead_letter_queue: DeadLetterQueue::from_env()?,
+            dedup: DedupStore::from_env()?,
+            unacked_destination: ReportDestination::from_env(),
+            client_id,
+            client_secret,
+        })
+    }
+}
+
+/// Descriptor sets embedded per environment, as `make proto-compile` builds them
+const DESCRIPTOR_SETS: &[(&str, &[u8])] = &[
+    ("dev", include_bytes!("../gen/descriptors/sqs_messages_dev.descriptor")),
+    ("prod", include_bytes!("../gen/descriptors/sqs_messages_prod.descriptor")),
+];
+
+/// Load the protobuf descriptor embedded for `ENVIRONMENT`, the first set when it is unset
+fn load_descriptor_proto(file_name: &str, message_name: &str) -> Result<DescriptorProto> {
+    load_environment_descriptor_proto(DESCRIPTOR_SETS, file_name, message_name)
 }
 
 /// Convert SQS message attributes (system attributes) to protobuf map
@@ -99,15 +213,18 @@ fn convert_attributes(
     attrs.clone()
 }
 
-/// Process a single SQS message and ingest it into Zerobus
-async fn process_message(
+/// Build the table row for a single SQS message, ingested at the time `clock` reads
+fn build_table_row(
     message: &SqsMessage,
-    stream: &mut ZerobusStream,
     aws_region: &str,
     event_source_arn: &str,
-) -> Result<()> {
+    pipeline_version: Option<&str>,
+    body_format: Option<&BodyFormat>,
+    attribute_filter: &AttributeFilter,
+    clock: &dyn Clock,
+) -> Result<TableSqsMessages> {
     // Get current timestamp in microseconds
-    let now = std::time::SystemTime::now();
+    let now = clock.now();
     let ingested_at = now
         .duration_since(std::time::UNIX_EPOCH)
         .context("Failed to get system time")?
@@ -124,13 +241,13 @@ async fn process_message(
         .as_ref()
         .context("Message ID is required")?
         .clone();
-    let message_id_for_log = message_id.clone();
     let receipt_handle = message
         .receipt_handle
         .as_ref()
         .context("Receipt handle is required")?
         .clone();
     let body = message.body.as_deref().unwrap_or_default().to_string();
+    let body_json = body_format.map(|format| format.parse(&body).to_string());
     let md5_of_body = message.md5_of_body.as_deref().unwrap_or_default().to_string();
     let md5_of_message_attributes = message
         .md5_of_message_attributes
@@ -140,10 +257,11 @@ async fn process_message(
 
     // Convert attributes
     let attributes = convert_attributes(&message.attributes);
-    let message_attributes = convert_message_attributes(&message.message_attributes);
+    let message_attributes = convert_message_attributes(&message.message_attributes, attribute_filter)
+        .with_context(|| format!("Invalid message attributes on message {}", message_id))?;
 
     // Create protobuf message
-    let sqs_message = TableSqsMessages {
+    Ok(TableSqsMessages {
         message_id: Some(message_id),
         receipt_handle: Some(receipt_handle),
         body: Some(body),
@@ -155,117 +273,2556 @@ async fn process_message(
         aws_region: Some(aws_region.to_string()),
         ingested_at: Some(ingested_at),
         ingested_date: Some(ingested_date),
-    };
+        pipeline_version: pipeline_version.map(str::to_string),
+        body_json,
+        body_compressed: None,
+        payload_codec: None,
+        ..Default::default()
+    })
+}
 
-    // Encode and ingest
-    let encoded = sqs_message.encode_to_vec();
-    let ack_future = stream.ingest_record(encoded).await?;
-    ack_future.await?;
+/// Fill the typed columns of an extracted notification
+///
+/// A body that does not hold the expected notification leaves them unset; the
+/// message is still ingested.
+fn apply_unwrap(row: &mut TableSqsMessages, unwrap: Unwrap) {
+    let body = row.body.as_deref().unwrap_or_default();
+    match unwrap.extract(body) {
+        Ok(Unwrapped::Ses(ses)) => {
+            row.ses_message_id = ses.message_id;
+            row.ses_event_type = ses.event_type;
+            row.ses_source = ses.source;
+            row.ses_destination = ses.destination;
+            row.ses_subject = ses.subject;
+            row.ses_timestamp = ses.timestamp;
+            row.ses_mail_headers = ses.headers;
+        }
+        Ok(Unwrapped::S3Batch(task)) => {
+            row.s3batch_schema_version = task.schema_version;
+            row.s3batch_invocation_id = task.invocation_id;
+            row.s3batch_job_id = task.job_id;
+            row.s3batch_task_id = task.task_id;
+            row.s3batch_bucket = task.bucket;
+            row.s3batch_key = task.key;
+            row.s3batch_version_id = task.version_id;
+        }
+        Err(e) => warn!(
+            "Message {}: body could not be unwrapped: {:#}",
+            row.message_id.as_deref().unwrap_or_default(),
+            e
+        ),
+    }
+}
 
-    info!("Successfully ingested message: {}", message_id_for_log);
+/// Move the body of a row into `body_compressed`, leaving `body` unset
+///
+/// `body_json` is parsed before compression and is kept as-is.
+fn compress_body(row: &mut TableSqsMessages, codec: PayloadCodec) -> Result<()> {
+    if let Some(body) = row.body.take() {
+        row.body_compressed = Some(codec.compress(body.as_bytes())?);
+        row.payload_codec = Some(codec.name().to_string());
+    }
     Ok(())
 }
 
-/// Lambda handler function
-async fn function_handler(event: LambdaEvent<SqsEvent>) -> Result<SqsBatchResponse, Error> {
-    let sdk = init_sdk().map_err(|e| Error::from(format!("Failed to initialize SDK: {}", e)))?;
+/// Split a row over the size limit into rows carrying one chunk of its body each
+///
+/// The chunks are cut from `body`, or from `body_compressed` when the body was
+/// compressed, and grouped by the message ID, so a redelivered message rewrites the
+/// same group. The other columns are repeated in every chunk, except `body_json`, which
+/// is as large as the body and is left to be parsed from the reassembled body. A row
+/// within the limit is returned as it is.
+fn split_row(mut row: TableSqsMessages, splitter: &Splitter) -> Result<Vec<TableSqsMessages>> {
+    let encoded_len = row.encoded_len();
+    if !splitter.needs_split(encoded_len) {
+        return Ok(vec![row]);
+    }
+    let body = row.body.take();
+    let compressed = row.body_compressed.take();
+    row.body_json = None;
+    let group_id = row.message_id.clone().unwrap_or_default();
+    // Reserve room for the chunk columns at their largest
+    row.chunk_group_id = Some(group_id.clone());
+    row.chunk_index = Some(i32::MAX);
+    row.total_chunks = Some(i32::MAX);
+    let chunk_bytes = splitter
+        .chunk_bytes(row.encoded_len())
+        .with_context(|| format!("Message {} of {} bytes cannot be split", group_id, encoded_len))?;
 
-    let table_name = std::env::var("TABLE_NAME")
-        .map_err(|_| Error::from("TABLE_NAME environment variable must be set"))?;
-    let client_id = std::env::var("DATABRICKS_CLIENT_ID")
-        .map_err(|_| Error::from("DATABRICKS_CLIENT_ID environment variable must be set"))?;
-    let client_secret = std::env::var("DATABRICKS_CLIENT_SECRET")
-        .map_err(|_| Error::from("DATABRICKS_CLIENT_SECRET environment variable must be set"))?;
+    let mut rows: Vec<TableSqsMessages> = match (body, compressed) {
+        (Some(body), _) => chunk::split_str(&body, chunk_bytes)
+            .into_iter()
+            .map(|part| TableSqsMessages { body: Some(part.to_string()), ..row.clone() })
+            .collect(),
+        (None, Some(compressed)) => chunk::split_bytes(&compressed, chunk_bytes)
+            .into_iter()
+            .map(|part| TableSqsMessages { body_compressed: Some(part.to_vec()), ..row.clone() })
+            .collect(),
+        (None, None) => anyhow::bail!("Message {} of {} bytes has no body to split", group_id, encoded_len),
+    };
+    let total = rows.len();
+    for (row, info) in rows.iter_mut().zip(ChunkInfo::group(&group_id, total)) {
+        row.chunk_index = Some(info.index);
+        row.total_chunks = Some(info.total);
+    }
+    info!("Split message {} of {} bytes into {} chunks", group_id, encoded_len, total);
+    Ok(rows)
+}
 
-    // Load descriptor
-    let descriptor_proto = load_descriptor_proto("sqs_messages.proto", "table_sqs_messages");
+/// How rows are built from messages, and their acks and sizes measured, read once per
+/// invocation
+#[derive(Debug, Default)]
+struct RowOptions {
+    body_format: Option<BodyFormat>,
+    unwrap: Option<Unwrap>,
+    codec: Option<PayloadCodec>,
+    splitter: Option<Splitter>,
+    verify_md5: bool,
+    attribute_filter: AttributeFilter,
+    payload_log: PayloadLog,
+    /// Ack latency buckets, in milliseconds, when not the defaults
+    latency_bounds_ms: Option<Vec<f64>>,
+    /// Record size buckets, in bytes, when not the defaults
+    size_bounds: Option<Vec<f64>>,
+    /// Request ID of the invocation ingesting the messages, for `consumer_request_id`
+    consumer_request_id: Option<String>,
+    /// Written into `pipeline_version`, from `STAMP_VERSION`
+    pipeline_version: Option<&'static str>,
+    /// Where `ingested_at` and ack times are read from, when not the system clock
+    clock: Option<Arc<dyn Clock>>,
+    /// Time left before the invocation's deadline when no more messages are sent
+    deadline_margin: Duration,
+    /// When to stop sending messages, leaving the rest for redelivery
+    deadline: Option<tokio::time::Instant>,
+}
 
-    // Configure table properties
-    let table_properties = TableProperties {
-        table_name: table_name.clone(),
-        descriptor_proto,
-    };
+impl RowOptions {
+    fn from_env() -> Result<Self> {
+        Ok(Self {
+            body_format: BodyFormat::from_env(),
+            unwrap: Unwrap::from_env()?,
+            codec: PayloadCodec::from_env()?,
+            splitter: Splitter::from_env()?,
+            verify_md5: integrity::verify_from_env(),
+            attribute_filter: AttributeFilter::from_env(),
+            payload_log: PayloadLog::from_env()?,
+            latency_bounds_ms: distribution::bounds_from_env("ACK_LATENCY_BUCKETS_MS")?,
+            size_bounds: distribution::bounds_from_env("RECORD_SIZE_BUCKETS")?,
+            consumer_request_id: None,
+            pipeline_version: version::stamped_pipeline_version(),
+            clock: None,
+            deadline_margin: deadline_margin()?,
+            deadline: None,
+        })
+    }
 
-    // Configure stream options
-    let stream_options = StreamConfigurationOptions {
-        max_inflight_records: 1000,
-        ..Default::default()
+    /// Tag the rows with the request ID of the invocation of `context`, so they can be
+    /// matched with its CloudWatch logs
+    fn consumed_by(mut self, context: &lambda_runtime::Context) -> Self {
+        self.consumer_request_id = Some(context.request_id.clone());
+        self
+    }
+
+    /// Stop sending messages `deadline_margin` before the invocation of `context` times
+    /// out; never for a context without a deadline
+    fn finish_by(mut self, context: &lambda_runtime::Context) -> Self {
+        if context.deadline == 0 {
+            return self;
+        }
+        let deadline = UNIX_EPOCH + Duration::from_millis(context.deadline);
+        let left = deadline.duration_since(self.clock().now()).unwrap_or_default();
+        self.deadline = Some(tokio::time::Instant::now() + left.saturating_sub(self.deadline_margin));
+        self
+    }
+
+    fn past_deadline(&self) -> bool {
+        self.deadline.is_some_and(|deadline| tokio::time::Instant::now() >= deadline)
+    }
+
+    fn clock(&self) -> &dyn Clock {
+        self.clock.as_deref().unwrap_or(&SystemClock)
+    }
+
+    fn ack_latency_ms(&self) -> Distribution {
+        self.latency_bounds_ms.clone().map_or_else(Distribution::latency, Distribution::new)
+    }
+
+    fn record_bytes(&self) -> Distribution {
+        self.size_bounds.clone().map_or_else(Distribution::size, Distribution::new)
+    }
+
+    fn ack_latencies(&self) -> AckLatencies {
+        AckLatencies {
+            ack_ms: self.ack_latency_ms(),
+            end_to_end_ms: Distribution::end_to_end(),
+            clock_skewed: 0,
+        }
+    }
+}
+
+/// Latencies of the acknowledged messages of a batch
+#[derive(Debug, Clone)]
+struct AckLatencies {
+    /// Time from sending each message until its ack, in milliseconds
+    ack_ms: Distribution,
+    /// Time from each message's `SentTimestamp` until its ack, in milliseconds
+    end_to_end_ms: Distribution,
+    /// Messages acknowledged before their `SentTimestamp`, from a producer's clock running
+    /// ahead; counted at zero in `end_to_end_ms`
+    clock_skewed: u64,
+}
+
+impl AckLatencies {
+    /// Observe a message acknowledged at `acked_at`, returning its end-to-end latency when
+    /// it has an event time
+    fn observe(&mut self, sent_at: Instant, event_time: Option<SystemTime>, acked_at: SystemTime) -> Option<Duration> {
+        self.ack_ms.observe(sent_at.elapsed().as_secs_f64() * 1000.0);
+        let event_time = event_time?;
+        let end_to_end = acked_at.duration_since(event_time).unwrap_or_else(|_| {
+            self.clock_skewed += 1;
+            Duration::ZERO
+        });
+        self.end_to_end_ms.observe(end_to_end.as_secs_f64() * 1000.0);
+        Some(end_to_end)
+    }
+
+    fn merge(&mut self, other: &AckLatencies) -> Result<()> {
+        self.ack_ms.merge(&other.ack_ms)?;
+        self.end_to_end_ms.merge(&other.end_to_end_ms)?;
+        self.clock_skewed += other.clock_skewed;
+        Ok(())
+    }
+}
+
+/// A message sent to the stream, waiting for its acknowledgment
+struct PendingAck {
+    message_id: String,
+    /// Encoded size of each record sent for it: one, or one per chunk when it was split
+    record_bytes: Vec<usize>,
+    sent_at: Instant,
+    /// `SentTimestamp` of the message, when SQS gave one
+    event_time: Option<SystemTime>,
+    ack_future: AckFuture,
+    /// Span of the message, ended once its ack is drained; records `enqueue_to_ack_ms`
+    span: Span,
+}
+
+/// Process a single SQS message and ingest it into Zerobus
+///
+/// The row is tagged with the queue ARN and region of the message itself, so batches
+/// mixing several queues are tagged correctly. Returns the ingested record with its
+/// acknowledgment future so the caller decides when to wait for durability (immediately,
+/// or at the next intra-batch flush). With `verify_md5`, a message whose digests do not
+/// match is not ingested. With a `splitter`, a row over the size limit is sent as
+/// several chunks, and the message is acknowledged once all of them are.
+async fn process_message<S: IngestSink>(
+    message: &SqsMessage,
+    stream: &mut S,
+    options: &RowOptions,
+) -> Result<PendingAck> {
+    options.payload_log.log_sampled(
+        &format!("message {}", message.message_id.as_deref().unwrap_or_default()),
+        message.body.as_deref().unwrap_or_default(),
+    );
+    if options.verify_md5 {
+        integrity::verify(message)?;
+    }
+    let mut sqs_message = build_table_row(
+        message,
+        &record_region(message),
+        message.event_source_arn.as_deref().unwrap_or_default(),
+        options.pipeline_version,
+        options.body_format.as_ref(),
+        &options.attribute_filter,
+        options.clock(),
+    )?;
+    sqs_message.consumer_request_id = options.consumer_request_id.clone();
+    if let Some(unwrap) = options.unwrap {
+        apply_unwrap(&mut sqs_message, unwrap);
+    }
+    if let Some(codec) = options.codec {
+        compress_body(&mut sqs_message, codec)?;
+    }
+    let message_id_for_log = sqs_message.message_id.clone().unwrap_or_default();
+    let rows = match &options.splitter {
+        Some(splitter) => split_row(sqs_message, splitter)?,
+        None => vec![sqs_message],
     };
 
-    // Create stream
-    let mut stream = sdk
-        .create_stream(table_properties, client_id, client_secret, Some(stream_options))
-        .await
-        .map_err(|e| Error::from(format!("Failed to create stream: {}", e)))?;
+    // Encode and ingest
+    let mut record_bytes = Vec::with_capacity(rows.len());
+    let mut ack_futures = Vec::with_capacity(rows.len());
+    for row in rows {
+        let encoded = row.encode_to_vec();
+        record_bytes.push(encoded.len());
+        ack_futures.push(stream.ingest(encoded).await?);
+    }
+
+    Ok(PendingAck {
+        message_id: message_id_for_log.clone(),
+        record_bytes,
+        sent_at: Instant::now(),
+        event_time: sent_at_micros(message)
+            .and_then(|micros| u64::try_from(micros).ok())
+            .map(|micros| UNIX_EPOCH + Duration::from_micros(micros)),
+        ack_future: Box::pin(async move {
+            for ack_future in ack_futures {
+                ack_future.await?;
+            }
+            info!("Successfully ingested message: {}", message_id_for_log);
+            Ok(())
+        }),
+        span: Span::none(),
+    })
+}
+
+/// Read the optional intra-batch flush interval from `FLUSH_EVERY_N`
+///
+/// Unset or `0` disables intra-batch flushing: each record's acknowledgment is awaited
+/// before the next record is ingested.
+fn flush_every_n() -> Result<Option<usize>> {
+    match std::env::var("FLUSH_EVERY_N") {
+        Ok(value) => {
+            let n: usize = value
+                .trim()
+                .parse()
+                .with_context(|| format!("FLUSH_EVERY_N must be a non-negative integer, got {:?}", value))?;
+            Ok((n > 0).then_some(n))
+        }
+        Err(_) => Ok(None),
+    }
+}
+
+/// Time left before the invocation's deadline for closing the streams and writing the
+/// audit rows, metrics, and dead letters
+const DEFAULT_DEADLINE_MARGIN: Duration = Duration::from_secs(2);
+
+/// Read how long before the invocation's deadline to stop sending messages from
+/// `DEADLINE_MARGIN_MS`
+fn deadline_margin() -> Result<Duration> {
+    match std::env::var("DEADLINE_MARGIN_MS") {
+        Ok(value) => {
+            let millis: u64 = value
+                .trim()
+                .parse()
+                .with_context(|| format!("DEADLINE_MARGIN_MS must be a number of milliseconds, got {:?}", value))?;
+            Ok(Duration::from_millis(millis))
+        }
+        Err(_) => Ok(DEFAULT_DEADLINE_MARGIN),
+    }
+}
+
+/// Whether the stream should be flushed after `ingested` records have been sent in this batch
+fn should_flush(ingested: usize, flush_every_n: Option<usize>) -> bool {
+    match flush_every_n {
+        Some(n) => ingested > 0 && ingested % n == 0,
+        None => false,
+    }
+}
+
+/// Error of the messages left unsent as the invocation's deadline nears
+const DEADLINE_ERROR: &str = "Not sent before the invocation's deadline";
+
+/// `event` field of the line logged for each message that fails, for a CloudWatch Logs
+/// metric filter to count
+const INGEST_FAILURE_EVENT: &str = "ingest_failure";
+
+/// Log that `message_id`, routed to `table_name`, failed with `error`
+///
+/// Each failure is logged with the same `event`, `table_name`, `message_id`, and
+/// `error_class` fields, whether it failed to send or was not acknowledged, so alarms
+/// need not match the wording of the message.
+fn log_ingest_failure(table_name: &str, message_id: &str, error: &anyhow::Error) {
+    error!(
+        event = INGEST_FAILURE_EVENT,
+        table_name,
+        message_id,
+        error_class = classify(error).as_str(),
+        "Failed to ingest message {}: {:#}",
+        message_id,
+        error
+    );
+}
+
+/// Span of one message from its ingest until its ack
+#[cfg(feature = "otel")]
+fn message_span(table_name: &str) -> Span {
+    otel::ingest_record_span(table_name)
+}
+
+/// Span of one message from its ingest until its ack; none, as spans are not exported
+#[cfg(not(feature = "otel"))]
+fn message_span(_table_name: &str) -> Span {
+    Span::none()
+}
+
+/// Await every pending acknowledgment, recording the messages that failed and why, and
+/// the latencies of those acknowledged
+async fn drain_acks(
+    table_name: &str,
+    pending: &mut Vec<PendingAck>,
+    batch_item_failures: &mut Vec<BatchItemFailure>,
+    errors: &mut HashMap<String, String>,
+    latencies: &mut AckLatencies,
+    clock: &dyn Clock,
+) {
+    for PendingAck { message_id, sent_at, event_time, ack_future, span, .. } in pending.drain(..) {
+        match ack_future.await {
+            Ok(_) => {
+                if let Some(enqueue_to_ack) = latencies.observe(sent_at, event_time, clock.now()) {
+                    span.record("enqueue_to_ack_ms", enqueue_to_ack.as_millis() as u64);
+                }
+                info!("Successfully processed message: {}", message_id);
+            }
+            Err(e) => {
+                log_ingest_failure(table_name, &message_id, &e);
+                errors.insert(message_id.clone(), format!("{:#}", e));
+                batch_item_failures.push(BatchItemFailure {
+                    item_identifier: message_id,
+                });
+            }
+        }
+    }
+}
+
+/// Flush the stream and confirm the acknowledgments of every record sent so far
+///
+/// The stream stays open for the rest of the batch. Records confirmed here are settled:
+/// a failure later in the batch only affects the records sent after this checkpoint.
+async fn checkpoint<S: IngestSink>(
+    stream: &mut S,
+    table_name: &str,
+    pending: &mut Vec<PendingAck>,
+    batch_item_failures: &mut Vec<BatchItemFailure>,
+    errors: &mut HashMap<String, String>,
+    latencies: &mut AckLatencies,
+    clock: &dyn Clock,
+) {
+    if let Err(e) = stream.flush().await {
+        error!("Failed to flush stream: {}", e);
+    }
+    drain_acks(table_name, pending, batch_item_failures, errors, latencies, clock).await;
+}
+
+/// What happened to the messages of one batch
+struct BatchOutcome {
+    /// Messages that failed to process or were not acknowledged
+    batch_item_failures: Vec<BatchItemFailure>,
+    /// Why each of `batch_item_failures` failed, by message ID
+    errors: HashMap<String, String>,
+    /// Messages in the batch
+    received: usize,
+    /// Earliest and latest `SentTimestamp` in the batch, microseconds since Unix epoch
+    window: Option<(i64, i64)>,
+    /// Ack and end-to-end latencies of the acknowledged messages
+    latencies: AckLatencies,
+    /// Encoded size of each row sent, in bytes
+    record_bytes: Distribution,
+}
 
-    // Extract AWS region and event source ARN from first record (all records from same queue)
-    let (event_source_arn, aws_region) = event
-        .payload
-        .records
-        .first()
-        .and_then(|r| Some((r.event_source_arn.as_ref().cloned().unwrap_or_default(), r.aws_region.as_ref().cloned().unwrap_or_default())))
-        .unwrap_or_default();
+/// `SentTimestamp` system attribute of a message (milliseconds) as microseconds
+fn sent_at_micros(message: &SqsMessage) -> Option<i64> {
+    message
+        .attributes
+        .get("SentTimestamp")
+        .and_then(|millis| millis.parse::<i64>().ok())
+        .map(|millis| millis * 1000)
+}
 
+/// Ingest every message of a batch and resolve their acknowledgments
+///
+/// With a `dedup` store, a message whose `MessageDeduplicationId` was already ingested
+/// is skipped and reported as processed. Claims of messages that fail are released, so
+/// their redeliveries are ingested. Once the deadline of `options` passes, no more
+/// messages are sent, and the rest are reported as failures to be redelivered. The
+/// stream is flushed but left open; closing it is up to the caller.
+async fn process_batch<S: IngestSink>(
+    table_name: &str,
+    records: &[SqsMessage],
+    stream: &mut S,
+    options: &RowOptions,
+    flush_every_n: Option<usize>,
+    mut dedup: Option<&mut DedupStore>,
+) -> BatchOutcome {
     let mut batch_item_failures = Vec::new();
+    let mut errors = HashMap::new();
+    let mut pending_acks: Vec<PendingAck> = Vec::new();
+    let mut latencies = options.ack_latencies();
+    let mut record_bytes = options.record_bytes();
+    let mut ingested = 0;
+    let mut left_for_redelivery = Vec::new();
 
     // Process each message
-    for record in event.payload.records {
+    for (position, record) in records.iter().enumerate() {
+        if options.past_deadline() {
+            let left = &records[position..];
+            warn!("Leaving {} messages of {} for redelivery: the invocation's deadline is near", left.len(), table_name);
+            for record in left {
+                let message_id = record.message_id.clone().unwrap_or_default();
+                errors.insert(message_id.clone(), DEADLINE_ERROR.to_string());
+                left_for_redelivery.push(BatchItemFailure {
+                    item_identifier: message_id,
+                });
+            }
+            break;
+        }
         let message_id = record.message_id.clone().unwrap_or_default();
 
-        match process_message(&record, &mut stream, &aws_region, &event_source_arn).await {
-            Ok(_) => {
-                info!("Successfully processed message: {}", message_id);
+        if let (Some(store), Some(key)) = (dedup.as_deref_mut(), dedup_key(record)) {
+            if !store.claim(&key) {
+                info!("Skipping message {}: deduplication id {} was already ingested", message_id, key);
+                continue;
+            }
+        }
+
+        let span = message_span(table_name);
+        match process_message(record, stream, options).instrument(span.clone()).await {
+            Ok(mut pending) => {
+                // Kept open until the message's ack is drained
+                pending.span = span;
+                for bytes in &pending.record_bytes {
+                    record_bytes.observe(*bytes as f64);
+                }
+                pending_acks.push(pending);
+                ingested += 1;
             }
             Err(e) => {
-                error!("Failed to process message {}: {}", message_id, e);
+                log_ingest_failure(table_name, &message_id, &e);
+                errors.insert(message_id.clone(), format!("{:#}", e));
                 batch_item_failures.push(BatchItemFailure {
                     item_identifier: message_id,
                 });
             }
         }
+
+        if flush_every_n.is_none() {
+            // No intra-batch flushing: wait for each record's ack before sending the next
+            drain_acks(table_name, &mut pending_acks, &mut batch_item_failures, &mut errors, &mut latencies, options.clock()).await;
+        } else if should_flush(ingested, flush_every_n) && !pending_acks.is_empty() {
+            // Checkpoint so acks drain progressively and in-flight records stay bounded
+            info!("Checkpointing stream after {} records", ingested);
+            checkpoint(stream, table_name, &mut pending_acks, &mut batch_item_failures, &mut errors, &mut latencies, options.clock()).await;
+        }
     }
 
-    // Flush all pending writes and close the stream
-    if let Err(e) = stream.close().await {
-        error!("Failed to close stream: {}", e);
-        
-        // TODO: check e.is_retryable and retry where possible
+    // Resolve acks for the tail of the batch (the records after the last checkpoint)
+    if !pending_acks.is_empty() {
+        checkpoint(stream, table_name, &mut pending_acks, &mut batch_item_failures, &mut errors, &mut latencies, options.clock()).await;
+    }
 
-        // TODO: use strema.get_unacked_records() so we can push unacknowledged records to a DLQ
-        let unacked = stream.get_unacked_records().await?;
-        println!("Failed to acknowledge {} records", unacked.len()); // TODO: switch to logging
-        
-        // Recreates the stream with the same configuration and automatically re-ingests all records that weren't acknowledged.
-        sdk.recreate_stream(stream).await?;
+    // Log what failed, redacted, whether it failed to send or was not acknowledged
+    for failure in &batch_item_failures {
+        if let Some(record) = records.iter().find(|record| record.message_id.as_deref() == Some(failure.item_identifier.as_str())) {
+            options.payload_log.log_failed(
+                &format!("message {}", failure.item_identifier),
+                record.body.as_deref().unwrap_or_default(),
+                errors.get(&failure.item_identifier).map(String::as_str).unwrap_or_default(),
+            );
+        }
     }
 
-    Ok(SqsBatchResponse {
+    if let Some(store) = dedup {
+        for failure in &batch_item_failures {
+            let failed = records
+                .iter()
+                .find(|record| record.message_id.as_deref() == Some(failure.item_identifier.as_str()));
+            if let Some(key) = failed.and_then(dedup_key) {
+                store.release(&key);
+            }
+        }
+    }
+    // Added after the claims are released: these messages were never claimed, and one
+    // may be a redelivery of a message already ingested
+    batch_item_failures.extend(left_for_redelivery);
+
+    let window = records
+        .iter()
+        .filter_map(sent_at_micros)
+        .fold(None, |window, sent_at| match window {
+            Some((first, last)) => Some((sent_at.min(first), sent_at.max(last))),
+            None => Some((sent_at, sent_at)),
+        });
+
+    BatchOutcome {
         batch_item_failures,
+        errors,
+        received: records.len(),
+        window,
+        latencies,
+        record_bytes,
+    }
+}
+
+/// Outcome of the records of one source queue
+struct QueueOutcome {
+    event_source_arn: String,
+    table_name: String,
+    outcome: BatchOutcome,
+}
+
+/// Ingest each queue's records into the stream of its table
+///
+/// `streams` holds an open stream per target table. Records routed to a table without
+/// a stream, because it could not be opened, are all reported as failures.
+async fn process_queues<S: IngestSink>(
+    batches: Vec<QueueBatch>,
+    streams: &mut HashMap<String, S>,
+    options: &RowOptions,
+    flush_every_n: Option<usize>,
+    mut dedup: Option<&mut DedupStore>,
+) -> Vec<QueueOutcome> {
+    let mut outcomes = Vec::with_capacity(batches.len());
+    for batch in batches {
+        let outcome = match streams.get_mut(&batch.table_name) {
+            Some(stream) => {
+                process_batch(&batch.table_name, &batch.records, stream, options, flush_every_n, dedup.as_deref_mut())
+                    .await
+            }
+            None => BatchOutcome {
+                batch_item_failures: batch
+                    .records
+                    .iter()
+                    .map(|record| BatchItemFailure {
+                        item_identifier: record.message_id.clone().unwrap_or_default(),
+                    })
+                    .collect(),
+                errors: batch
+                    .records
+                    .iter()
+                    .map(|record| {
+                        let message_id = record.message_id.clone().unwrap_or_default();
+                        let error = anyhow::anyhow!("No stream to {}", batch.table_name);
+                        log_ingest_failure(&batch.table_name, &message_id, &error);
+                        (message_id, error.to_string())
+                    })
+                    .collect(),
+                received: batch.records.len(),
+                window: None,
+                latencies: options.ack_latencies(),
+                record_bytes: options.record_bytes(),
+            },
+        };
+        outcomes.push(QueueOutcome {
+            event_source_arn: batch.event_source_arn,
+            table_name: batch.table_name,
+            outcome,
+        });
+    }
+    outcomes
+}
+
+/// Send the failures that were on their last attempt to the dead-letter queue
+///
+/// A message that is forwarded is removed from its queue's batch item failures, so SQS
+/// deletes it instead of redriving it without its source metadata. Messages that cannot
+/// be forwarded stay failures and are redriven as usual.
+async fn forward_dead_letters<C: SendMessage>(
+    outcomes: &mut [QueueOutcome],
+    records: &[SqsMessage],
+    queue: &DeadLetterQueue,
+    client: &C,
+) {
+    for outcome in outcomes.iter_mut() {
+        let mut remaining = Vec::new();
+        for failure in outcome.outcome.batch_item_failures.drain(..) {
+            let record = records
+                .iter()
+                .find(|record| record.message_id.as_deref() == Some(failure.item_identifier.as_str()))
+                .filter(|record| queue.is_last_attempt(record));
+            let Some(record) = record else {
+                remaining.push(failure);
+                continue;
+            };
+            let error = outcome
+                .outcome
+                .errors
+                .get(&failure.item_identifier)
+                .map(String::as_str)
+                .unwrap_or_default();
+            let letter = DeadLetter::new(queue, record, &outcome.table_name, error);
+            match client.send_message(&queue.url, &letter).await {
+                Ok(()) => info!("Forwarded message {} to the dead-letter queue", failure.item_identifier),
+                Err(e) => {
+                    error!(
+                        "Failed to forward message {} to the dead-letter queue: {:#}",
+                        failure.item_identifier, e
+                    );
+                    remaining.push(failure);
+                }
+            }
+        }
+        outcome.outcome.batch_item_failures = remaining;
+    }
+}
+
+/// Build the audit row summarizing a processed batch, finished at the time `clock` reads
+fn build_batch_audit(
+    outcome: &BatchOutcome,
+    request_id: &str,
+    event_source_arn: &str,
+    table_name: &str,
+    schema_hash: &str,
+    started_at: i64,
+    clock: &dyn Clock,
+) -> Result<BatchAudit> {
+    let now = clock
+        .now()
+        .duration_since(UNIX_EPOCH)
+        .context("Failed to get system time")?;
+    let failed = outcome.batch_item_failures.len();
+
+    Ok(BatchAudit {
+        batch_id: Some(request_id.to_string()),
+        source: Some(env!("CARGO_PKG_NAME").to_string()),
+        source_arn: Some(event_source_arn.to_string()),
+        target_table: Some(table_name.to_string()),
+        window_start: outcome.window.map(|(first, _)| first),
+        window_end: outcome.window.map(|(_, last)| last),
+        started_at: Some(started_at),
+        finished_at: Some(now.as_micros() as i64),
+        records_received: Some(outcome.received as i64),
+        records_ingested: Some((outcome.received - failed) as i64),
+        records_failed: Some(failed as i64),
+        schema_hash: Some(schema_hash.to_string()),
+        pipeline_version: Some(version::PIPELINE_VERSION.to_string()),
+        ingested_at: Some(now.as_micros() as i64),
+        ingested_date: Some(now.as_secs() as i32 / 86400),
+    })
+}
+
+/// Write an audit row to `audit_table`, opening the shared audit stream if needed
+///
+/// The stream is dropped on failure so the next invocation starts with a fresh one.
+async fn write_batch_audit<C: CreateStream>(
+    sdk: &C,
+    audit_stream: &Mutex<Option<C::Stream>>,
+    audit_table: String,
+    client_id: String,
+    client_secret: String,
+    batch_audit: &BatchAudit,
+) -> Result<()> {
+    let mut audit_stream = audit_stream.lock().await;
+    if audit_stream.is_none() {
+        let table_properties = TableProperties {
+            table_name: audit_table,
+            descriptor_proto: audit::audit_descriptor(),
+        };
+        let stream = sdk
+            .create_stream(table_properties, client_id, client_secret, None)
+            .await
+            .context("Failed to create audit stream")?;
+        *audit_stream = Some(stream);
+    }
+
+    let stream = audit_stream.as_mut().expect("audit stream should be open");
+    let result = audit::write_audit(stream, batch_audit).await;
+    if result.is_err() {
+        *audit_stream = None;
+    }
+    result
+}
+
+/// Describe the records a failed stream left unacknowledged, identified by SQS message ID
+fn build_unacked_report<R: AsRef<[u8]>>(
+    table_name: &str,
+    request_id: &str,
+    error: impl std::fmt::Display,
+    unacked: impl IntoIterator<Item = R>,
+) -> UnackedReport {
+    UnackedReport::builder(table_name)
+        .error(error)
+        .batch_id(request_id)
+        .identify_with(|record| TableSqsMessages::decode(record).ok()?.message_id)
+        .build(unacked)
+}
+
+/// Record the outcome of each queue, its ack latency, end-to-end latency, and record size
+/// distributions, its clock-skewed messages, and the invocation's duration
+fn build_invocation_metrics(
+    outcomes: &[QueueOutcome],
+    namespace: String,
+    labels: LabelGuard,
+    started_at: i64,
+    clock: &dyn Clock,
+) -> Result<InvocationMetrics> {
+    let now = clock
+        .now()
+        .duration_since(UNIX_EPOCH)
+        .context("Failed to get system time")?;
+    let mut metrics = InvocationMetrics::new(namespace, started_at / 1000).labels(labels);
+    // Queues past the label limit share dimensions, so their distributions are merged
+    let mut distributions: Vec<(Vec<(&'static str, String)>, AckLatencies, Distribution)> = Vec::new();
+    for queue in outcomes {
+        let queue_name = queue_name(&queue.event_source_arn);
+        metrics.record_queue(
+            queue_name,
+            &queue.table_name,
+            queue.outcome.received,
+            queue.outcome.batch_item_failures.len(),
+        );
+        let dimensions = metrics.queue_dimensions(queue_name, &queue.table_name);
+        match distributions.iter_mut().find(|(existing, _, _)| *existing == dimensions) {
+            Some((_, latencies, record_bytes)) => {
+                latencies.merge(&queue.outcome.latencies)?;
+                record_bytes.merge(&queue.outcome.record_bytes)?;
+            }
+            None => distributions.push((
+                dimensions,
+                queue.outcome.latencies.clone(),
+                queue.outcome.record_bytes.clone(),
+            )),
+        }
+    }
+    for (dimensions, latencies, record_bytes) in distributions {
+        metrics.record_distribution(
+            ["AckLatencyP50", "AckLatencyP90", "AckLatencyP99", "AckLatencyMax"],
+            "AckLatencyBuckets",
+            Unit::Milliseconds,
+            &latencies.ack_ms,
+            dimensions.clone(),
+        );
+        metrics.record_distribution(
+            ["EndToEndLatencyP50", "EndToEndLatencyP90", "EndToEndLatencyP99", "EndToEndLatencyMax"],
+            "EndToEndLatencyBuckets",
+            Unit::Milliseconds,
+            &latencies.end_to_end_ms,
+            dimensions.clone(),
+        );
+        if latencies.end_to_end_ms.count() > 0 {
+            metrics.record("ClockSkewedMessages", Unit::Count, latencies.clock_skewed as f64, dimensions.clone());
+        }
+        metrics.record_distribution(
+            ["RecordBytesP50", "RecordBytesP90", "RecordBytesP99", "RecordBytesMax"],
+            "RecordBytesBuckets",
+            Unit::Bytes,
+            &record_bytes,
+            dimensions,
+        );
+    }
+    let elapsed_micros = (now.as_micros() as i64 - started_at).max(0);
+    metrics.record("IngestDuration", Unit::Milliseconds, elapsed_micros as f64 / 1000.0, Vec::new());
+    Ok(metrics)
+}
+
+/// Publish an invocation's metrics as EMF log lines or with PutMetricData
+async fn flush_metrics(metrics: &InvocationMetrics, sink: MetricsSink) -> Result<()> {
+    match sink {
+        MetricsSink::Emf => {
+            // EMF lines must reach CloudWatch Logs as bare JSON, not through the log formatter
+            for line in metrics.emf_lines() {
+                println!("{}", line);
+            }
+            Ok(())
+        }
+        MetricsSink::CloudWatchApi => {
+            let client = cloudwatch_client().await?;
+            metrics.put(&client).await
+        }
+    }
+}
+
+/// Lambda handler function
+async fn function_handler(event: LambdaEvent<serde_json::Value>) -> Result<SqsBatchResponse, Error> {
+    let policy = DecodeErrorPolicy::from_env().map_err(|e| Error::from(e.to_string()))?;
+    let (event, undecodable) = parse_event(event, policy)?;
+    // An empty or heartbeat batch needs neither the SDK nor the Zerobus configuration
+    if event.payload.records.is_empty() {
+        info!("Received no records, request_id: {}", event.context.request_id);
+        return Ok(SqsBatchResponse {
+            batch_item_failures: undecodable,
+        });
+    }
+    let sdk = init_sdk().map_err(|e| Error::from(format!("Failed to initialize SDK: {}", e)))?;
+    let config = HandlerConfig::from_env().map_err(|e| Error::from(e.to_string()))?;
+    let mut response = handle_event(event, sdk, &DeadLetterClient, &WARM, config).await?;
+    response.batch_item_failures.extend(undecodable);
+    Ok(response)
+}
+
+/// Deserialize the SQS event, once the binary attribute values that are not base64 are
+/// dealt with under `policy`, with the failures of the messages it removed
+fn parse_event(
+    event: LambdaEvent<serde_json::Value>,
+    policy: DecodeErrorPolicy,
+) -> Result<(LambdaEvent<SqsEvent>, Vec<BatchItemFailure>), Error> {
+    let mut payload = event.payload;
+    let failed = remove_undecodable_attributes(&mut payload, policy);
+    let payload: SqsEvent = serde_json::from_value(payload)
+        .map_err(|e| Error::from(format!("Failed to parse SQS event: {}", e)))?;
+    let failures = failed
+        .into_iter()
+        .map(|message_id| BatchItemFailure {
+            item_identifier: message_id,
+        })
+        .collect();
+    Ok((LambdaEvent::new(payload, event.context), failures))
+}
+
+/// Ingest the batch of `event` into the streams `sdk` opens, forwarding the failures on
+/// their last attempt with `dead_letters`
+async fn handle_event<C: CreateStream, D: SendMessage>(
+    event: LambdaEvent<SqsEvent>,
+    sdk: &C,
+    dead_letters: &D,
+    warm: &Warm<C::Stream>,
+    config: HandlerConfig,
+) -> Result<SqsBatchResponse, Error> {
+    // Nothing to ingest, audit, or report: no stream is opened for an empty batch
+    if event.payload.records.is_empty() {
+        info!("Received no records, request_id: {}", event.context.request_id);
+        return Ok(SqsBatchResponse::default());
+    }
+
+    let HandlerConfig {
+        client_id,
+        client_secret,
+        descriptor_proto,
+        audit_table,
+        routes,
+        flush_every_n,
+        options,
+        metrics_sink,
+        metrics_namespace,
+        metric_labels,
+        dead_letter_queue,
+        dedup,
+        unacked_destination,
+    } = config;
+    let options = options.consumed_by(&event.context).finish_by(&event.context);
+
+    let started_at = options
+        .clock()
+        .now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| Error::from(format!("Failed to get system time: {}", e)))?
+        .as_micros() as i64;
+
+    let schema_hash = schema_hash(&descriptor_proto);
+
+    // Records may come from several queues, each routed to its own table
+    let batches = group_by_queue(&event.payload.records, &routes);
+
+    // Open one stream per target table; every table shares the same schema
+    let mut streams: HashMap<String, C::Stream> = HashMap::new();
+    for batch in &batches {
+        if streams.contains_key(&batch.table_name) {
+            continue;
+        }
+        let table_properties = TableProperties {
+            table_name: batch.table_name.clone(),
+            descriptor_proto: descriptor_proto.clone(),
+        };
+        let stream_options = StreamConfigurationOptions {
+            max_inflight_records: 1000,
+            ..Default::default()
+        };
+        let created = sdk.create_stream(table_properties, client_id.clone(), client_secret.clone(), Some(stream_options));
+        #[cfg(feature = "otel")]
+        let created = created.instrument(otel::create_stream_span(&batch.table_name));
+        match created.await {
+            Ok(stream) => {
+                streams.insert(batch.table_name.clone(), stream);
+            }
+            Err(e) => error!("Failed to create stream for {}: {}", batch.table_name, e),
+        }
+    }
+
+    let mut warm_dedup = warm.dedup.lock().await;
+    if warm_dedup.is_none() {
+        *warm_dedup = dedup;
+    }
+
+    let mut outcomes = process_queues(batches, &mut streams, &options, flush_every_n, warm_dedup.as_mut()).await;
+
+    // Flush all pending writes and close the streams
+    for (stream_table, mut stream) in streams {
+        let closed = stream.close();
+        #[cfg(feature = "otel")]
+        let closed = closed.instrument(otel::close_stream_span(&stream_table));
+        if let Err(e) = closed.await {
+            error!("Failed to close stream for {}: {}", stream_table, e);
+
+            // TODO: check e.is_retryable and retry where possible
+
+            let unacked = sdk.unacked_records(&mut stream).await?;
+            error!("Failed to acknowledge {} records", unacked.len());
+            let report = build_unacked_report(&stream_table, &event.context.request_id, &e, &unacked);
+            if let Err(e) = report.write(&unacked_destination) {
+                warn!("{:#}", e);
+            }
+
+            // Recreates the stream with the same configuration and automatically re-ingests all records that weren't acknowledged.
+            sdk.recreate_stream(stream)
+                .await
+                .map_err(|e| Error::from(format!("Failed to recreate stream: {:#}", e)))?;
+        }
+    }
+
+    // The audit rows are best-effort: a failure to write one does not fail the batch
+    if let Some(audit_table) = audit_table {
+        for queue in &outcomes {
+            let written = match build_batch_audit(
+                &queue.outcome,
+                &event.context.request_id,
+                &queue.event_source_arn,
+                &queue.table_name,
+                &schema_hash,
+                started_at,
+                options.clock(),
+            ) {
+                Ok(batch_audit) => {
+                    write_batch_audit(
+                        sdk,
+                        &warm.audit_stream,
+                        audit_table.clone(),
+                        client_id.clone(),
+                        client_secret.clone(),
+                        &batch_audit,
+                    )
+                    .await
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = written {
+                warn!("Failed to write batch audit row: {:#}", e);
+            }
+        }
+    }
+
+    // Like the audit rows, metrics are best-effort
+    let labels = warm.metric_labels.get_or_init(|| metric_labels).clone();
+    let invocation_metrics = build_invocation_metrics(&outcomes, metrics_namespace, labels, started_at, options.clock());
+    let published = match invocation_metrics {
+        Ok(invocation_metrics) => flush_metrics(&invocation_metrics, metrics_sink).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = published {
+        warn!("Failed to publish metrics: {:#}", e);
+    }
+
+    // After the audit rows and metrics, which count forwarded messages as failed
+    // Messages that cannot be forwarded stay batch item failures, left to the redrive policy
+    if let Some(queue) = dead_letter_queue {
+        forward_dead_letters(&mut outcomes, &event.payload.records, &queue, dead_letters).await;
+    }
+
+    Ok(SqsBatchResponse {
+        batch_item_failures: outcomes
+            .into_iter()
+            .flat_map(|queue| queue.outcome.batch_item_failures)
+            .collect(),
     })
 }
 
+/// The handler, in the root span of its invocation
+///
+/// Spans are exported before it returns, as Lambda may freeze the container right after.
+#[cfg(feature = "otel")]
+async fn traced_handler(event: LambdaEvent<serde_json::Value>) -> Result<SqsBatchResponse, Error> {
+    let request_id = event.context.request_id.clone();
+    let function_name = event.context.env_config.function_name.clone();
+    otel::invocation(&request_id, &function_name, function_handler(event)).await
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     // Install the default CryptoProvider early in your application
     rustls::crypto::aws_lc_rs::default_provider().install_default().unwrap();
 
-    tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::INFO)
-        .with_target(false)
-        .init();
+    #[cfg(feature = "otel")]
+    {
+        otel::init()?;
+        run(service_fn(traced_handler)).await
+    }
+
+    #[cfg(not(feature = "otel"))]
+    {
+        tracing_subscriber::fmt()
+            .with_env_filter(zerobus_common::payload_log::log_directives("info"))
+            .with_target(false)
+            .init();
 
-    run(service_fn(function_handler)).await
+        run(service_fn(function_handler)).await
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::dlq::{AttributeValue, DeadLetterEncoding, MESSAGE_ID_ATTRIBUTE, SOURCE_ARN_ATTRIBUTE, TARGET_TABLE_ATTRIBUTE};
+    use aws_lambda_events::encodings::Base64Data;
+    use aws_lambda_events::sqs::SqsMessageAttribute;
+    use aws_lambda_sqs_ingestor::fixtures::sqs_message;
+    use proptest::collection::{hash_map, vec};
+    use prost::bytes::Bytes;
+    use proptest::prelude::*;
+    use fake_zerobus_server::FakeZerobus;
     use lambda_runtime::{Context, LambdaEvent};
+    use serde_json::Value;
+    use std::collections::BTreeMap;
+    use std::path::Path;
+    use std::sync::Mutex as StdMutex;
+    use zerobus_common::clock::FixedClock;
+    use zerobus_common::descriptor;
+    use zerobus_common::testing::chaos::{ChaosSink, Faults, Scenario};
+    use zerobus_common::testing::conformance::{assert_conforms, UNICODE};
+    use zerobus_common::testing::handler::{invoke, with_env, EnvScope, MockStreams, SinkCalls};
+    use zerobus_common::testing::{hex_dump, MockSink};
+    use base64::{engine::general_purpose, Engine as _};
+    use sqs_messages::table_sqs_messages::MessageAttributes;
 
-    #[tokio::test]
-    async fn test_event_handler() {
-        let event = LambdaEvent::new(SqsEvent::default(), Context::default());
-        let response = function_handler(event).await.unwrap();
-        assert_eq!(SqsBatchResponse::default(), response);
+    #[derive(Default)]
+    struct MockSqs {
+        sent: StdMutex<Vec<(String, DeadLetter)>>,
+    }
+
+    impl SendMessage for MockSqs {
+        async fn send_message(&self, queue_url: &str, letter: &DeadLetter) -> Result<()> {
+            self.sent.lock().unwrap().push((queue_url.to_string(), letter.clone()));
+            Ok(())
+        }
+    }
+
+    /// The configuration read from `vars` alone, and the client variables the handler
+    /// requires
+    fn config(vars: &[(&str, &str)]) -> HandlerConfig {
+        let mut all = vec![
+            ("TABLE_NAME", "main.default.sqs"),
+            ("DATABRICKS_CLIENT_ID", "client-id"),
+            ("DATABRICKS_CLIENT_SECRET", "client-secret"),
+        ];
+        all.extend_from_slice(vars);
+        with_env(&all, HandlerConfig::from_env).unwrap()
+    }
+
+    /// A batch of `records`, from the invocation with request ID `request_id`
+    fn sqs_event(records: Vec<SqsMessage>, request_id: &str) -> LambdaEvent<SqsEvent> {
+        let mut context = Context::default();
+        context.request_id = request_id.to_string();
+        LambdaEvent::new(SqsEvent { records }, context)
+    }
+
+    fn messages(ids: &[&str]) -> Vec<SqsMessage> {
+        ids.iter().map(|id| sqs_message(Some(id), "1700000000000")).collect()
+    }
+
+    fn message_ids(records: &[Vec<u8>]) -> Vec<String> {
+        records
+            .iter()
+            .map(|record| TableSqsMessages::decode(record.as_slice()).unwrap().message_id.unwrap())
+            .collect()
+    }
+
+    fn failed_ids(response: &SqsBatchResponse) -> Vec<&str> {
+        response
+            .batch_item_failures
+            .iter()
+            .map(|failure| failure.item_identifier.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_environment_selects_the_embedded_descriptor() {
+        for (environment, bytes) in DESCRIPTOR_SETS {
+            let expected = descriptor::load_descriptor_proto(bytes, "sqs_messages.proto", "table_sqs_messages");
+            assert_eq!(expected, config(&[("ENVIRONMENT", environment)]).descriptor_proto);
+        }
+
+        let error = with_env(
+            &[
+                ("TABLE_NAME", "main.default.sqs"),
+                ("DATABRICKS_CLIENT_ID", "client-id"),
+                ("DATABRICKS_CLIENT_SECRET", "client-secret"),
+                ("ENVIRONMENT", "staging"),
+            ],
+            HandlerConfig::from_env,
+        )
+        .err()
+        .unwrap();
+        assert_eq!("No descriptor set embedded for ENVIRONMENT=staging (available: dev, prod)", error.to_string());
+    }
+
+    #[tokio::test]
+    async fn test_empty_batch_creates_no_stream() {
+        let streams = MockStreams::default().fail_create_for(|_| true);
+        let warm = Warm::new();
+        let event = LambdaEvent::new(SqsEvent::default(), Context::default());
+        let invocation = invoke(&streams, |sdk| async move {
+            handle_event(event, &sdk, &MockSqs::default(), &warm, config(&[])).await
+        })
+        .await;
+        assert_eq!(SqsBatchResponse::default(), invocation.response.unwrap());
+        assert_eq!(SinkCalls::default(), invocation.calls);
+    }
+
+    #[tokio::test]
+    async fn test_empty_event_needs_no_zerobus_environment() {
+        // No ZEROBUS_ENDPOINT, DATABRICKS_HOST, TABLE_NAME, or credentials
+        let _env = EnvScope::new(&[]);
+        let event = LambdaEvent::new(serde_json::json!({"Records": []}), Context::default());
+
+        let response = function_handler(event).await.unwrap();
+        assert_eq!(SqsBatchResponse::default(), response);
+    }
+
+    #[test]
+    fn test_pipeline_version_is_stamped() {
+        let message = SqsMessage {
+            message_id: Some("msg-1".to_string()),
+            receipt_handle: Some("handle-1".to_string()),
+            body: Some("hello".to_string()),
+            ..Default::default()
+        };
+
+        let row = build_table_row(
+            &message,
+            "us-west-2",
+            "arn:aws:sqs:us-west-2:123456789012:queue",
+            Some(version::PIPELINE_VERSION),
+            None,
+            &AttributeFilter::default(),
+            &SystemClock,
+        )
+        .unwrap();
+        assert_eq!(Some(version::PIPELINE_VERSION.to_string()), row.pipeline_version);
+
+        let row = build_table_row(&message, "us-west-2", "arn", None, None, &AttributeFilter::default(), &SystemClock).unwrap();
+        assert_eq!(None, row.pipeline_version);
+    }
+
+    #[tokio::test]
+    async fn test_stamp_version_is_read_with_the_options() {
+        let options = with_env(&[("STAMP_VERSION", "true")], RowOptions::from_env).unwrap();
+        assert_eq!(Some(version::PIPELINE_VERSION), options.pipeline_version);
+
+        // Rows are stamped from the options, whatever the environment says by then
+        let mut stream = MockSink::default();
+        let message = sqs_message(Some("msg-1"), "1700000000000");
+        process_message(&message, &mut stream, &options).await.unwrap();
+        let row = TableSqsMessages::decode(stream.records()[0].as_slice()).unwrap();
+        assert_eq!(Some(version::PIPELINE_VERSION.to_string()), row.pipeline_version);
+
+        let options = with_env(&[], RowOptions::from_env).unwrap();
+        assert_eq!(None, options.pipeline_version);
+    }
+
+    #[test]
+    fn test_message_attributes_are_filtered_by_prefix() {
+        let attributes: HashMap<String, SqsMessageAttribute> = ["app.order_id", "app.debug.trace", "tenant", "sys.retry", "AWSTraceHeader"]
+            .into_iter()
+            .map(|name| {
+                let attribute = SqsMessageAttribute {
+                    string_value: Some("v".to_string()),
+                    data_type: Some("String".to_string()),
+                    ..Default::default()
+                };
+                (name.to_string(), attribute)
+            })
+            .collect();
+        let kept = |filter: AttributeFilter| {
+            let mut names: Vec<String> = convert_message_attributes(&attributes, &filter).unwrap().into_keys().collect();
+            names.sort();
+            names
+        };
+
+        // Include only
+        assert_eq!(vec!["app.debug.trace", "app.order_id", "tenant"], kept(AttributeFilter::new("app.,tenant", "")));
+        // Exclude only
+        assert_eq!(vec!["app.debug.trace", "app.order_id", "tenant"], kept(AttributeFilter::new("", "sys.,AWS")));
+        // Both: an excluded prefix wins over an included one
+        assert_eq!(vec!["app.order_id"], kept(AttributeFilter::new("app.", "app.debug.")));
+        // Neither keeps every attribute
+        assert_eq!(5, kept(AttributeFilter::default()).len());
+    }
+
+    #[test]
+    fn test_attribute_without_a_value_fails_the_message() {
+        let attributes = HashMap::from([(
+            "app.image".to_string(),
+            SqsMessageAttribute {
+                string_value: Some("not binary".to_string()),
+                data_type: Some("Binary.png".to_string()),
+                ..Default::default()
+            },
+        )]);
+        let error = convert_message_attributes(&attributes, &AttributeFilter::default()).unwrap_err();
+        assert!(error.to_string().contains("\"app.image\""), "{:#}", error);
+
+        // Unless it is filtered out
+        assert!(convert_message_attributes(&attributes, &AttributeFilter::new("", "app.")).unwrap().is_empty());
+    }
+
+    /// Shrunk from `test_converted_attributes_keep_their_values`: binary values were
+    /// decoded from their Debug output, which is not base64, and stored empty
+    #[test]
+    fn test_binary_attribute_bytes_are_kept() {
+        let attributes = HashMap::from([(
+            String::new(),
+            SqsMessageAttribute {
+                binary_value: Some(Base64Data(vec![0])),
+                data_type: Some("Binary".to_string()),
+                ..Default::default()
+            },
+        )]);
+        let converted = convert_message_attributes(&attributes, &AttributeFilter::default()).unwrap();
+        assert_eq!(Some(Bytes::from(vec![0])), converted[""].binary_value);
+    }
+
+    /// Attributes as SQS delivers them: a data type, with values of that type
+    fn arb_attribute() -> impl Strategy<Value = SqsMessageAttribute> {
+        let strings = (
+            proptest::sample::select(vec!["String", "Number", "String.json", "Number.float"]),
+            any::<String>(),
+            vec(any::<String>(), 0..4),
+        )
+            .prop_map(|(data_type, value, list)| SqsMessageAttribute {
+                string_value: Some(value),
+                string_list_values: list,
+                data_type: Some(data_type.to_string()),
+                ..Default::default()
+            });
+        let binaries = (
+            proptest::sample::select(vec!["Binary", "Binary.gif"]),
+            vec(any::<u8>(), 0..64),
+            vec(vec(any::<u8>(), 0..32), 0..4),
+        )
+            .prop_map(|(data_type, value, list)| SqsMessageAttribute {
+                binary_value: Some(Base64Data(value)),
+                binary_list_values: list.into_iter().map(Base64Data).collect(),
+                data_type: Some(data_type.to_string()),
+                ..Default::default()
+            });
+        prop_oneof![strings, binaries]
+    }
+
+    proptest! {
+        #[test]
+        fn test_converted_attributes_keep_their_values(attributes in hash_map(any::<String>(), arb_attribute(), 0..10)) {
+            let converted = convert_message_attributes(&attributes, &AttributeFilter::default()).unwrap();
+
+            prop_assert_eq!(attributes.len(), converted.len());
+            for (name, attribute) in &attributes {
+                let row = &converted[name];
+                prop_assert_eq!(&attribute.data_type, &row.data_type);
+                prop_assert_eq!(&attribute.string_value, &row.string_value);
+                prop_assert_eq!(&attribute.string_list_values, &row.string_list_values);
+                prop_assert_eq!(attribute.binary_value.as_ref().map(|value| value.as_slice()), row.binary_value.as_deref());
+                let binaries: Vec<&[u8]> = attribute.binary_list_values.iter().map(|value| value.as_slice()).collect();
+                let row_binaries: Vec<&[u8]> = row.binary_list_values.iter().map(|value| value.as_ref()).collect();
+                prop_assert_eq!(binaries, row_binaries);
+            }
+        }
+
+        #[test]
+        fn test_filtering_keeps_exactly_the_attributes_it_keeps(
+            attributes in hash_map("[a-z.]{0,8}", arb_attribute(), 0..10),
+            include in "[a-z.,]{0,8}",
+            exclude in "[a-z.,]{0,8}",
+        ) {
+            let filter = AttributeFilter::new(&include, &exclude);
+            let converted = convert_message_attributes(&attributes, &filter).unwrap();
+
+            let mut kept: Vec<&String> = attributes.keys().filter(|name| filter.keeps(name)).collect();
+            let mut names: Vec<&String> = converted.keys().collect();
+            kept.sort();
+            names.sort();
+            prop_assert_eq!(kept, names);
+        }
+    }
+
+    #[test]
+    fn test_body_is_parsed_by_content_type() {
+        let message = SqsMessage {
+            message_id: Some("msg-1".to_string()),
+            receipt_handle: Some("handle-1".to_string()),
+            body: Some("id=7&status=shipped".to_string()),
+            ..Default::default()
+        };
+
+        let form = BodyFormat::new("form", None);
+        let row = build_table_row(&message, "us-west-2", "arn", None, Some(&form), &AttributeFilter::default(), &SystemClock).unwrap();
+        assert_eq!(
+            Some(r#"{"id":"7","status":"shipped"}"#.to_string()),
+            row.body_json
+        );
+        assert_eq!(Some("id=7&status=shipped".to_string()), row.body);
+
+        let row = build_table_row(&message, "us-west-2", "arn", None, None, &AttributeFilter::default(), &SystemClock).unwrap();
+        assert_eq!(None, row.body_json);
+    }
+
+    #[test]
+    fn test_table_row_snapshot() {
+        // One entry per map: prost encodes maps in HashMap order, which is not stable
+        let message = SqsMessage {
+            message_id: Some("059f36b4-87a3-44ab-83d2-661975830a7d".to_string()),
+            receipt_handle: Some("AQEBwJnKyrHigUMZj6rYigCgxlaS3SLy0a".to_string()),
+            body: Some(r#"{"order_id":42,"status":"shipped"}"#.to_string()),
+            md5_of_body: Some("e4e68fb7bd0e697a0ae8f1bb342846b3".to_string()),
+            md5_of_message_attributes: Some("00484c68d5e4a1d5e5c2e5c1e1b1a1f0".to_string()),
+            attributes: HashMap::from([("ApproximateReceiveCount".to_string(), "1".to_string())]),
+            message_attributes: HashMap::from([(
+                "tenant".to_string(),
+                SqsMessageAttribute {
+                    string_value: Some("acme".to_string()),
+                    data_type: Some("String".to_string()),
+                    ..Default::default()
+                },
+            )]),
+            ..Default::default()
+        };
+        let clock = FixedClock::at_unix_secs(1_718_020_860);
+
+        let encoded = build_table_row(
+            &message,
+            "us-east-2",
+            "arn:aws:sqs:us-east-2:123456789012:orders",
+            Some("2024.06.1"),
+            Some(&BodyFormat::new("json", None)),
+            &AttributeFilter::default(),
+            &clock,
+        )
+        .unwrap()
+        .encode_to_vec();
+        let decoded = TableSqsMessages::decode(encoded.as_slice()).unwrap();
+
+        insta::assert_debug_snapshot!("table_row_fields", decoded);
+        insta::assert_snapshot!("table_row_bytes", hex_dump(&encoded));
+    }
+
+    /// The JSON a generic tool would be handed for `row`
+    fn table_row_json(row: &TableSqsMessages) -> Value {
+        let base64 = |bytes: &[u8]| general_purpose::STANDARD.encode(bytes);
+        let message_attributes: serde_json::Map<String, Value> = row
+            .message_attributes
+            .iter()
+            .map(|(name, attribute)| {
+                let attribute = serde_json::json!({
+                    "string_value": attribute.string_value,
+                    "binary_value": attribute.binary_value.as_deref().map(base64),
+                    "string_list_values": attribute.string_list_values,
+                    "binary_list_values": attribute.binary_list_values.iter().map(|value| base64(value)).collect::<Vec<_>>(),
+                    "data_type": attribute.data_type,
+                });
+                (name.clone(), attribute)
+            })
+            .collect();
+        let fields = [
+            ("message_id", serde_json::json!(row.message_id)),
+            ("receipt_handle", serde_json::json!(row.receipt_handle)),
+            ("body", serde_json::json!(row.body)),
+            ("md5_of_body", serde_json::json!(row.md5_of_body)),
+            ("md5_of_message_attributes", serde_json::json!(row.md5_of_message_attributes)),
+            ("attributes", serde_json::json!(row.attributes)),
+            ("message_attributes", Value::Object(message_attributes)),
+            ("queue_arn", serde_json::json!(row.queue_arn)),
+            ("aws_region", serde_json::json!(row.aws_region)),
+            ("ingested_at", serde_json::json!(row.ingested_at)),
+            ("ingested_date", serde_json::json!(row.ingested_date)),
+            ("pipeline_version", serde_json::json!(row.pipeline_version)),
+            ("body_json", serde_json::json!(row.body_json)),
+            ("body_compressed", serde_json::json!(row.body_compressed.as_deref().map(base64))),
+            ("payload_codec", serde_json::json!(row.payload_codec)),
+            ("ses_message_id", serde_json::json!(row.ses_message_id)),
+            ("ses_event_type", serde_json::json!(row.ses_event_type)),
+            ("ses_source", serde_json::json!(row.ses_source)),
+            ("ses_destination", serde_json::json!(row.ses_destination)),
+            ("ses_subject", serde_json::json!(row.ses_subject)),
+            ("ses_timestamp", serde_json::json!(row.ses_timestamp)),
+            ("ses_mail_headers", serde_json::json!(row.ses_mail_headers)),
+            ("s3batch_schema_version", serde_json::json!(row.s3batch_schema_version)),
+            ("s3batch_invocation_id", serde_json::json!(row.s3batch_invocation_id)),
+            ("s3batch_job_id", serde_json::json!(row.s3batch_job_id)),
+            ("s3batch_task_id", serde_json::json!(row.s3batch_task_id)),
+            ("s3batch_bucket", serde_json::json!(row.s3batch_bucket)),
+            ("s3batch_key", serde_json::json!(row.s3batch_key)),
+            ("s3batch_version_id", serde_json::json!(row.s3batch_version_id)),
+            ("chunk_group_id", serde_json::json!(row.chunk_group_id)),
+            ("chunk_index", serde_json::json!(row.chunk_index)),
+            ("total_chunks", serde_json::json!(row.total_chunks)),
+            ("consumer_request_id", serde_json::json!(row.consumer_request_id)),
+        ];
+        Value::Object(fields.into_iter().map(|(name, value)| (name.to_string(), value)).collect())
+    }
+
+    #[test]
+    fn test_dynamic_encoder_conforms_to_prost() {
+        let descriptor = config(&[]).descriptor_proto;
+        let text = |value: &str| Some(value.to_string());
+        let attribute = MessageAttributes {
+            string_value: text("acme"),
+            binary_value: Some(vec![0x00, 0x01, 0xfe, 0xff].into()),
+            string_list_values: vec!["a".to_string(), String::new()],
+            binary_list_values: vec![vec![0x01].into(), Vec::new().into()],
+            data_type: text("String.tenant"),
+        };
+        let all_fields = TableSqsMessages {
+            message_id: text("059f36b4-87a3-44ab-83d2-661975830a7d"),
+            receipt_handle: text("AQEBwJnKyrHigUMZj6rYigCgxlaS3SLy0a"),
+            body: text(r#"{"order_id":42,"status":"shipped"}"#),
+            md5_of_body: text("e4e68fb7bd0e697a0ae8f1bb342846b3"),
+            md5_of_message_attributes: text("00484c68d5e4a1d5e5c2e5c1e1b1a1f0"),
+            attributes: HashMap::from([("ApproximateReceiveCount".to_string(), "1".to_string())]),
+            message_attributes: HashMap::from([("tenant".to_string(), attribute.clone())]),
+            queue_arn: text("arn:aws:sqs:us-east-2:123456789012:orders"),
+            aws_region: text("us-east-2"),
+            ingested_at: Some(1_718_020_860_000_000),
+            ingested_date: Some(19_884),
+            pipeline_version: text("2024.06.1"),
+            body_json: text(r#"{"order_id":42,"status":"shipped"}"#),
+            body_compressed: Some(vec![0x1f, 0x8b, 0x08, 0x00]),
+            payload_codec: text("gzip"),
+            ses_message_id: text("0100018f-ses"),
+            ses_event_type: text("Delivery"),
+            ses_source: text("sender@example.com"),
+            ses_destination: vec!["a@example.com".to_string(), "b@example.com".to_string()],
+            ses_subject: text("Your order shipped"),
+            ses_timestamp: text("2024-06-10T12:00:00.000Z"),
+            ses_mail_headers: HashMap::from([("From".to_string(), "sender@example.com".to_string())]),
+            s3batch_schema_version: text("1.0"),
+            s3batch_invocation_id: text("YXNkbGZqYWRmaiBhc2RmdW9hZHNmZGpmaGFzbGtkaGZza2RmaAo"),
+            s3batch_job_id: text("be4e5f4c-8d9a-4b5e-9c36-1c0c1c0c1c0c"),
+            s3batch_task_id: text("dGFza2lkZ29lc2hlcmUK"),
+            s3batch_bucket: text("orders-archive"),
+            s3batch_key: text("2024/06/10/orders.json"),
+            s3batch_version_id: text("3HL4kqtJlcpXroDTDmJ"),
+            chunk_group_id: text("059f36b4-87a3-44ab-83d2-661975830a7d"),
+            chunk_index: Some(1),
+            total_chunks: Some(3),
+            consumer_request_id: text("c6af9ac6-7b61-11e6-9a41-93e8deadbeef"),
+        };
+        let unicode = TableSqsMessages {
+            body: text(UNICODE),
+            attributes: HashMap::from([(UNICODE.to_string(), UNICODE.to_string())]),
+            message_attributes: HashMap::from([(
+                UNICODE.to_string(),
+                MessageAttributes {
+                    string_value: text(UNICODE),
+                    string_list_values: vec![UNICODE.to_string()],
+                    ..attribute.clone()
+                },
+            )]),
+            ses_destination: vec![UNICODE.to_string()],
+            ses_subject: text(UNICODE),
+            ..all_fields.clone()
+        };
+        let ints = |int64, int32| TableSqsMessages {
+            ingested_at: Some(int64),
+            ingested_date: Some(int32),
+            chunk_index: Some(int32),
+            total_chunks: Some(int32),
+            ..Default::default()
+        };
+        // prost writes these in HashMap order, so only their entries are compared
+        let entries = |prefix: &str| (0..32).map(|i| (format!("{}-{:02}", prefix, i), i.to_string())).collect();
+        let large_maps = TableSqsMessages {
+            attributes: entries("attribute"),
+            message_attributes: (0..8)
+                .map(|i| {
+                    let value = MessageAttributes { string_value: Some(i.to_string()), ..attribute.clone() };
+                    (format!("attribute-{}", i), value)
+                })
+                .collect(),
+            ses_mail_headers: entries("X-Header"),
+            ..Default::default()
+        };
+        let cases = [
+            ("empty", TableSqsMessages::default()),
+            ("all_fields", all_fields),
+            ("unicode", unicode),
+            ("max_ints", ints(i64::MAX, i32::MAX)),
+            ("min_ints", ints(i64::MIN, i32::MIN)),
+            ("large_maps", large_maps),
+        ];
+
+        for (case, row) in cases {
+            let encoded = assert_conforms(&descriptor, case, &row, &table_row_json(&row));
+            insta::assert_snapshot!(format!("conformance_{}", case), hex_dump(&encoded));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flush_every_n_checkpoints_the_batch() {
+        let flush_every_n = with_env(&[("FLUSH_EVERY_N", "100")], flush_every_n).unwrap();
+        let records: Vec<SqsMessage> = (1..=250)
+            .map(|n| sqs_message(Some(&format!("msg-{}", n)), "1700000000000"))
+            .collect();
+
+        // Flushed at every 100th record, then once for the tail
+        let mut stream = MockSink::default();
+        let outcome = process_batch("main.default.sqs", &records, &mut stream, &RowOptions::default(), flush_every_n, None).await;
+        assert!(outcome.batch_item_failures.is_empty());
+        assert_eq!(3, stream.flushes());
+        assert_eq!(vec![100, 200, 250], stream.flush_offsets());
+        assert_eq!(250, stream.records().len());
+
+        // Acks fail after the 150th record: only the records not acknowledged are reported
+        let mut stream = MockSink::default().fail_acks_for(|record| {
+            let row = TableSqsMessages::decode(record).unwrap();
+            let n: usize = row.message_id.unwrap().trim_start_matches("msg-").parse().unwrap();
+            n > 150
+        });
+        let outcome = process_batch("main.default.sqs", &records, &mut stream, &RowOptions::default(), flush_every_n, None).await;
+        let failed: Vec<&str> = outcome
+            .batch_item_failures
+            .iter()
+            .map(|failure| failure.item_identifier.as_str())
+            .collect();
+        let expected: Vec<String> = (151..=250).map(|n| format!("msg-{}", n)).collect();
+        assert_eq!(expected, failed);
+        assert!(outcome.errors["msg-151"].contains("mock ack failure"));
+        assert!(!outcome.errors.contains_key("msg-150"));
+        assert_eq!(vec![100, 200, 250], stream.flush_offsets());
+        assert!(!stream.closed());
+    }
+
+    #[test]
+    fn test_flush_every_n_disabled() {
+        assert!((1..=250).all(|ingested| !should_flush(ingested, None)));
+    }
+
+    #[tokio::test]
+    async fn test_checkpoint_settles_records_before_a_failure() {
+        let mut stream = MockSink::default().fail_ingests_with(|record| {
+            let row = TableSqsMessages::decode(record).unwrap();
+            // The stream breaks after the second message
+            matches!(row.message_id.as_deref(), Some("msg-3" | "msg-4")).then(|| anyhow::anyhow!("stream broke"))
+        });
+        let records: Vec<SqsMessage> = (1..=4)
+            .map(|n| sqs_message(Some(&format!("msg-{}", n)), "1700000000000"))
+            .collect();
+
+        // A checkpoint flushes and settles what was sent so far
+        let mut pending = Vec::new();
+        for record in &records[..2] {
+            pending.push(process_message(record, &mut stream, &RowOptions::default()).await.unwrap());
+        }
+        let mut failures = Vec::new();
+        let mut errors = HashMap::new();
+        let mut latencies = RowOptions::default().ack_latencies();
+        checkpoint(&mut stream, "main.default.sqs", &mut pending, &mut failures, &mut errors, &mut latencies, &SystemClock).await;
+        assert_eq!(1, stream.flushes());
+        assert!(pending.is_empty());
+        assert!(failures.is_empty());
+        assert_eq!(2, latencies.ack_ms.count());
+        assert_eq!(2, latencies.end_to_end_ms.count());
+
+        // With a checkpoint every 2 records, only the records after it are reported
+        let outcome = process_batch("main.default.sqs", &records, &mut stream, &RowOptions::default(), Some(2), None).await;
+        let failed: Vec<&str> = outcome
+            .batch_item_failures
+            .iter()
+            .map(|failure| failure.item_identifier.as_str())
+            .collect();
+        assert_eq!(vec!["msg-3", "msg-4"], failed);
+        assert!(outcome.errors["msg-3"].contains("stream broke"));
+        assert_eq!(2, stream.flushes());
+        assert!(!stream.closed());
+    }
+
+    /// Fields of every event logged while it is the default subscriber
+    #[derive(Clone, Default)]
+    struct CapturedEvents(Arc<StdMutex<Vec<BTreeMap<String, String>>>>);
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for CapturedEvents {
+        fn on_event(&self, event: &tracing::Event<'_>, _: tracing_subscriber::layer::Context<'_, S>) {
+            let mut fields = EventFields::default();
+            event.record(&mut fields);
+            self.0.lock().unwrap().push(fields.0);
+        }
+    }
+
+    #[derive(Default)]
+    struct EventFields(BTreeMap<String, String>);
+
+    impl tracing::field::Visit for EventFields {
+        fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+            self.0.insert(field.name().to_string(), value.to_string());
+        }
+
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            self.0.insert(field.name().to_string(), format!("{:?}", value));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_each_failed_message_is_logged_as_an_ingest_failure_event() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let captured = CapturedEvents::default();
+        let _guard = tracing::subscriber::set_default(tracing_subscriber::registry().with(captured.clone()));
+
+        let mut stream = MockSink::default()
+            .fail_ingests_with(|record| {
+                let row = TableSqsMessages::decode(record).unwrap();
+                (row.message_id.as_deref() == Some("msg-2")).then(|| anyhow::anyhow!("acknowledgment timed out"))
+            })
+            .fail_acks_for(|record| TableSqsMessages::decode(record).unwrap().message_id.as_deref() == Some("msg-3"));
+        let records: Vec<SqsMessage> = (1..=3)
+            .map(|n| sqs_message(Some(&format!("msg-{}", n)), "1700000000000"))
+            .collect();
+        let outcome = process_batch("main.default.orders", &records, &mut stream, &RowOptions::default(), None, None).await;
+        assert_eq!(2, outcome.batch_item_failures.len());
+
+        let failures: Vec<BTreeMap<String, String>> = captured
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|fields| fields.get("event").map(String::as_str) == Some(INGEST_FAILURE_EVENT))
+            .cloned()
+            .collect();
+        let described: Vec<(&str, &str, &str)> = failures
+            .iter()
+            .map(|fields| (fields["table_name"].as_str(), fields["message_id"].as_str(), fields["error_class"].as_str()))
+            .collect();
+        // One event per failed message, whether it failed to send or was not acknowledged
+        assert_eq!(
+            vec![
+                ("main.default.orders", "msg-2", "ack_timeout"),
+                ("main.default.orders", "msg-3", "retryable"),
+            ],
+            described
+        );
+        assert!(failures[0]["message"].contains("acknowledgment timed out"));
+    }
+
+    #[tokio::test]
+    async fn test_end_to_end_latency_is_measured_from_sent_timestamp() {
+        let an_hour_ahead = SystemTime::now() + Duration::from_secs(3600);
+        let ahead_ms = an_hour_ahead.duration_since(UNIX_EPOCH).unwrap().as_millis().to_string();
+        let records = vec![
+            sqs_message(Some("msg-1"), "1700000000000"),
+            // From a producer whose clock runs an hour ahead
+            sqs_message(Some("msg-2"), &ahead_ms),
+            // Without a SentTimestamp there is no end-to-end latency to measure
+            sqs_message(Some("msg-3"), ""),
+        ];
+        let mut stream = MockSink::default();
+
+        let outcome = process_batch("main.default.sqs", &records, &mut stream, &RowOptions::default(), None, None).await;
+        assert!(outcome.batch_item_failures.is_empty());
+        let latencies = &outcome.latencies;
+        assert_eq!(3, latencies.ack_ms.count());
+        assert_eq!(2, latencies.end_to_end_ms.count());
+        assert_eq!(1, latencies.clock_skewed);
+        // The skewed message is clamped to zero rather than counted as negative
+        assert_eq!(0.0, latencies.end_to_end_ms.min());
+        assert!(latencies.end_to_end_ms.max() > 3_600_000.0);
+    }
+
+    /// Fields recorded on spans after they were created, such as `enqueue_to_ack_ms`
+    #[derive(Clone, Default)]
+    struct RecordedSpanFields(Arc<StdMutex<BTreeMap<String, String>>>);
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for RecordedSpanFields {
+        fn on_record(&self, _: &tracing::span::Id, values: &tracing::span::Record<'_>, _: tracing_subscriber::layer::Context<'_, S>) {
+            let mut fields = EventFields::default();
+            values.record(&mut fields);
+            self.0.lock().unwrap().extend(fields.0);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_near_deadline_leaves_the_rest_of_the_batch_for_redelivery() {
+        // Every ack times out after 300ms, so each message takes that long to fail
+        let faults = Faults {
+            stall_every: Some(1),
+            ack_timeout: Some(Duration::from_millis(300)),
+            ..Default::default()
+        };
+        let sink = MockSink::default();
+        let mut stream = ChaosSink::new(sink.clone(), faults);
+        let records: Vec<SqsMessage> = (0..10)
+            .map(|n| sqs_message(Some(&format!("msg-{}", n)), "1700000000000"))
+            .collect();
+        let clock = FixedClock::at_unix_secs(1_700_000_000);
+        let mut context = Context::default();
+        // 2.5s left, less a margin of 1.5s
+        context.deadline = 1_700_000_002_500;
+        let options = RowOptions {
+            clock: Some(Arc::new(clock)),
+            deadline_margin: Duration::from_millis(1500),
+            ..Default::default()
+        }
+        .finish_by(&context);
+
+        let started = tokio::time::Instant::now();
+        let outcome = process_batch("main.default.sqs", &records, &mut stream, &options, None, None).await;
+
+        // Sent at 0, 300, 600, and 900ms; the deadline passed before the fifth
+        assert_eq!(Duration::from_millis(1200), started.elapsed());
+        assert_eq!(4, sink.records().len());
+        let failed: Vec<&str> =
+            outcome.batch_item_failures.iter().map(|failure| failure.item_identifier.as_str()).collect();
+        assert_eq!((0..10).map(|n| format!("msg-{}", n)).collect::<Vec<_>>(), failed);
+        assert_ne!(DEADLINE_ERROR, outcome.errors["msg-3"]);
+        for n in 4..10 {
+            assert_eq!(DEADLINE_ERROR, outcome.errors[&format!("msg-{}", n)]);
+        }
+
+        // Without a deadline in the context, the whole batch is sent
+        let options = RowOptions::default().finish_by(&Context::default());
+        assert!(!options.past_deadline());
+    }
+
+    #[tokio::test]
+    async fn test_enqueue_to_ack_is_recorded_on_the_message_span() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let message = sqs_message(Some("msg-1"), "1700000000000");
+        let mut stream = MockSink::default();
+        // Acknowledged, by this clock, 2.5s after SQS received the message
+        let options = RowOptions {
+            clock: Some(Arc::new(FixedClock(UNIX_EPOCH + Duration::from_millis(1_700_000_002_500)))),
+            ..Default::default()
+        };
+        let mut pending = process_message(&message, &mut stream, &options).await.unwrap();
+        let row = TableSqsMessages::decode(stream.records()[0].as_slice()).unwrap();
+        assert_eq!(Some(1_700_000_002_500_000), row.ingested_at);
+
+        // Acknowledged 1.25s after SQS received it
+        let mut latencies = RowOptions::default().ack_latencies();
+        let acked_at = UNIX_EPOCH + Duration::from_millis(1_700_000_001_250);
+        assert_eq!(
+            Some(Duration::from_millis(1250)),
+            latencies.observe(pending.sent_at, pending.event_time, acked_at)
+        );
+        assert_eq!(None, latencies.observe(pending.sent_at, None, acked_at));
+
+        // Draining the ack records the latency until the clock's time on the message's span
+        let recorded = RecordedSpanFields::default();
+        let _guard = tracing::subscriber::set_default(tracing_subscriber::registry().with(recorded.clone()));
+        pending.span = tracing::info_span!("ingest_record", enqueue_to_ack_ms = tracing::field::Empty);
+        let mut failures = Vec::new();
+        drain_acks("main.default.sqs", &mut vec![pending], &mut failures, &mut HashMap::new(), &mut latencies, options.clock()).await;
+
+        assert!(failures.is_empty());
+        assert_eq!("2500", recorded.0.lock().unwrap()["enqueue_to_ack_ms"]);
+    }
+
+    #[tokio::test]
+    async fn test_batch_audit_is_ingested() {
+        let records = vec![
+            sqs_message(Some("msg-1"), "1700000002000"),
+            // No message ID, so the message cannot be processed
+            sqs_message(None, "1700000001000"),
+            sqs_message(Some("msg-3"), "1700000003000"),
+        ];
+        let mut stream = MockSink::default();
+        let mut audit_stream = MockSink::default();
+
+        let outcome =
+            process_batch("main.default.sqs", &records, &mut stream, &RowOptions::default(), None, None).await;
+        let clock = FixedClock::at_unix_secs(1_718_020_860);
+        let batch_audit =
+            build_batch_audit(&outcome, "req-1", "arn", "main.default.sqs", "hash", 0, &clock).unwrap();
+        audit::write_audit(&mut audit_stream, &batch_audit).await.unwrap();
+
+        assert_eq!(2, stream.records().len());
+        let audit_records = audit_stream.records();
+        assert_eq!(1, audit_records.len());
+        let row = BatchAudit::decode(audit_records[0].as_slice()).unwrap();
+        assert_eq!(Some("req-1".to_string()), row.batch_id);
+        assert_eq!(Some(3), row.records_received);
+        assert_eq!(Some(2), row.records_ingested);
+        assert_eq!(Some(1), row.records_failed);
+        assert_eq!(Some(1_700_000_001_000_000), row.window_start);
+        assert_eq!(Some(1_700_000_003_000_000), row.window_end);
+        assert_eq!(Some("hash".to_string()), row.schema_hash);
+        assert_eq!(Some(1_718_020_860_000_000), row.finished_at);
+        assert_eq!(Some(1_718_020_860_000_000), row.ingested_at);
+        assert_eq!(Some(19_884), row.ingested_date);
+    }
+
+    #[test]
+    fn test_close_failure_reports_unacked_records() {
+        let unacked: Vec<Vec<u8>> = ["msg-1", "msg-2"]
+            .iter()
+            .map(|id| {
+                TableSqsMessages {
+                    message_id: Some(id.to_string()),
+                    body: Some("hello".to_string()),
+                    ..Default::default()
+                }
+                .encode_to_vec()
+            })
+            .collect();
+
+        let report =
+            build_unacked_report("main.default.sqs", "req-1", "stream closed", &unacked);
+
+        let ids: Vec<Option<&str>> = report.entries.iter().map(|e| e.id.as_deref()).collect();
+        assert_eq!(vec![Some("msg-1"), Some("msg-2")], ids);
+        assert_eq!(unacked[0].len(), report.entries[0].size_bytes);
+        assert_eq!(Some("req-1".to_string()), report.batch_id);
+        assert_eq!(Some("stream closed".to_string()), report.error);
+        assert_eq!(unacked.iter().map(Vec::len).sum::<usize>(), report.total_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_mixed_queues_are_tagged_and_routed() {
+        let orders = "arn:aws:sqs:us-west-2:123456789012:orders";
+        let returns = "arn:aws:sqs:eu-west-1:123456789012:returns";
+        let record = |id: &str, arn: &str, region: Option<&str>| SqsMessage {
+            event_source_arn: Some(arn.to_string()),
+            aws_region: region.map(str::to_string),
+            ..sqs_message(Some(id), "1700000000000")
+        };
+        let records = vec![
+            record("msg-1", orders, Some("us-west-2")),
+            record("msg-2", returns, Some("eu-west-1")),
+            // No awsRegion, so the region comes from the ARN
+            record("msg-3", returns, None),
+            record("msg-4", orders, Some("us-west-2")),
+        ];
+        let routes = QueueRoutes::new("main.default.sqs", "returns=main.default.returns");
+        let mut streams: HashMap<String, MockSink> = [
+            ("main.default.sqs".to_string(), MockSink::default()),
+            ("main.default.returns".to_string(), MockSink::default()),
+        ]
+        .into();
+
+        let options = RowOptions::default();
+        let outcomes =
+            process_queues(group_by_queue(&records, &routes), &mut streams, &options, None, None).await;
+
+        let tagged = |table: &str| -> Vec<(String, String, String)> {
+            streams[table]
+                .records()
+                .iter()
+                .map(|record| {
+                    let row = TableSqsMessages::decode(record.as_slice()).unwrap();
+                    (
+                        row.message_id.unwrap(),
+                        row.queue_arn.unwrap(),
+                        row.aws_region.unwrap(),
+                    )
+                })
+                .collect()
+        };
+        let tag = |id: &str, arn: &str, region: &str| (id.to_string(), arn.to_string(), region.to_string());
+        assert_eq!(
+            vec![tag("msg-1", orders, "us-west-2"), tag("msg-4", orders, "us-west-2")],
+            tagged("main.default.sqs")
+        );
+        assert_eq!(
+            vec![tag("msg-2", returns, "eu-west-1"), tag("msg-3", returns, "eu-west-1")],
+            tagged("main.default.returns")
+        );
+        let audited: Vec<(&str, &str, usize)> = outcomes
+            .iter()
+            .map(|q| (q.event_source_arn.as_str(), q.table_name.as_str(), q.outcome.received))
+            .collect();
+        assert_eq!(
+            vec![(orders, "main.default.sqs", 2), (returns, "main.default.returns", 2)],
+            audited
+        );
+        // Each acknowledged record is timed and sized for its own queue
+        for queue in &outcomes {
+            assert_eq!(2, queue.outcome.latencies.ack_ms.count());
+            assert_eq!(2, queue.outcome.record_bytes.count());
+        }
+
+        // A table whose stream could not be opened fails its queue's records only
+        streams.remove("main.default.returns");
+        let outcomes =
+            process_queues(group_by_queue(&records, &routes), &mut streams, &options, None, None).await;
+        let failed: Vec<&str> = outcomes
+            .iter()
+            .flat_map(|q| &q.outcome.batch_item_failures)
+            .map(|failure| failure.item_identifier.as_str())
+            .collect();
+        assert_eq!(vec!["msg-2", "msg-3"], failed);
+    }
+
+    #[test]
+    fn test_undecodable_attribute_is_a_batch_item_failure() {
+        let attribute = |value: &str| serde_json::json!({"checksum": {"binaryValue": value, "dataType": "Binary"}});
+        let payload = serde_json::json!({"Records": [
+            {"messageId": "msg-1", "body": "hello", "messageAttributes": attribute("3q2+7w==")},
+            {"messageId": "msg-2", "body": "hello", "messageAttributes": attribute("not base64!")},
+        ]});
+
+        let (event, failures) = parse_event(LambdaEvent::new(payload.clone(), Context::default()), DecodeErrorPolicy::Fail).unwrap();
+        assert_eq!(1, event.payload.records.len());
+        let failed: Vec<&str> = failures.iter().map(|failure| failure.item_identifier.as_str()).collect();
+        assert_eq!(vec!["msg-2"], failed);
+
+        let (event, failures) = parse_event(LambdaEvent::new(payload, Context::default()), DecodeErrorPolicy::Skip).unwrap();
+        assert_eq!(2, event.payload.records.len());
+        assert!(failures.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_rows_carry_the_consumer_request_id() {
+        let mut context = Context::default();
+        context.request_id = "8476a536-e9f4-11e8-9739-2dfe598c3fcd".to_string();
+        let records = vec![sqs_message(Some("msg-1"), "1700000000000"), sqs_message(Some("msg-2"), "1700000000000")];
+
+        let options = RowOptions::default().consumed_by(&context);
+        let mut stream = MockSink::default();
+        process_batch("main.default.sqs", &records, &mut stream, &options, None, None).await;
+
+        assert_eq!(2, stream.records().len());
+        for record in stream.records() {
+            let row = TableSqsMessages::decode(record.as_slice()).unwrap();
+            assert_eq!(Some(context.request_id.as_str()), row.consumer_request_id.as_deref());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_compressed_body_round_trips() {
+        let body = format!("{{\"order\": \"{}\"}}", "x".repeat(1000));
+        let records = vec![SqsMessage {
+            body: Some(body.clone()),
+            ..sqs_message(Some("msg-1"), "1700000000000")
+        }];
+
+        for codec in [PayloadCodec::Gzip, PayloadCodec::Zstd] {
+            let options = RowOptions {
+                body_format: Some(BodyFormat::Json),
+                codec: Some(codec),
+                ..Default::default()
+            };
+            let mut stream = MockSink::default();
+            process_batch("main.default.sqs", &records, &mut stream, &options, None, None).await;
+
+            let row = TableSqsMessages::decode(stream.records()[0].as_slice()).unwrap();
+            assert_eq!(None, row.body);
+            assert_eq!(Some(codec.name().to_string()), row.payload_codec);
+            assert!(row.body_json.is_some());
+            let compressed = row.body_compressed.unwrap();
+            assert!(compressed.len() < body.len());
+            assert_eq!(body.as_bytes(), codec.decompress(&compressed).unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_large_body_is_split_into_chunks() {
+        let body = "x".repeat(2500);
+        let records = vec![
+            SqsMessage {
+                body: Some(body.clone()),
+                ..sqs_message(Some("msg-1"), "1700000000000")
+            },
+            SqsMessage {
+                body: Some("small".to_string()),
+                ..sqs_message(Some("msg-2"), "1700000000000")
+            },
+        ];
+        let options = RowOptions {
+            body_format: Some(BodyFormat::Json),
+            splitter: Some(Splitter::new(1000)),
+            ..Default::default()
+        };
+
+        let mut stream = MockSink::default();
+        let outcome = process_batch("main.default.sqs", &records, &mut stream, &options, None, None).await;
+        assert!(outcome.batch_item_failures.is_empty());
+
+        let rows: Vec<TableSqsMessages> = stream
+            .records()
+            .iter()
+            .map(|record| TableSqsMessages::decode(record.as_slice()).unwrap())
+            .collect();
+        assert!(stream.records().iter().all(|record| record.len() <= 1000));
+        let (chunks, whole) = rows.split_at(rows.len() - 1);
+        assert_eq!(3, chunks.len());
+        for (index, row) in chunks.iter().enumerate() {
+            assert_eq!(Some("msg-1".to_string()), row.chunk_group_id);
+            assert_eq!(Some(index as i32), row.chunk_index);
+            assert_eq!(Some(3), row.total_chunks);
+            assert_eq!(None, row.body_json);
+            assert_eq!(row.queue_arn, whole[0].queue_arn);
+        }
+        let reassembled: String = chunks.iter().map(|row| row.body.clone().unwrap()).collect();
+        assert_eq!(body, reassembled);
+
+        // A record under the limit is sent whole, without chunk columns
+        assert_eq!(Some("small".to_string()), whole[0].body);
+        assert_eq!(None, whole[0].chunk_group_id);
+        assert_eq!(None, whole[0].total_chunks);
+        assert!(whole[0].body_json.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_ses_body_is_unwrapped_into_columns() {
+        let notification = serde_json::json!({
+            "notificationType": "Delivery",
+            "mail": {
+                "timestamp": "2024-06-10T12:00:00.000Z",
+                "source": "sender@example.com",
+                "messageId": "0100018f-aaaa-bbbb",
+                "destination": ["customer@example.com"],
+                "headers": [{"name": "Subject", "value": "Your order"}],
+                "commonHeaders": {"subject": "Your order"}
+            }
+        });
+        let envelope = serde_json::json!({
+            "Type": "Notification",
+            "Message": notification.to_string()
+        });
+        let records = vec![
+            SqsMessage {
+                body: Some(envelope.to_string()),
+                ..sqs_message(Some("msg-1"), "1700000000000")
+            },
+            SqsMessage {
+                body: Some("not a notification".to_string()),
+                ..sqs_message(Some("msg-2"), "1700000000000")
+            },
+        ];
+        let options = RowOptions {
+            unwrap: Some(Unwrap::Ses),
+            ..Default::default()
+        };
+
+        let mut stream = MockSink::default();
+        let outcome = process_batch("main.default.sqs", &records, &mut stream, &options, None, None).await;
+        assert!(outcome.batch_item_failures.is_empty());
+
+        let row = TableSqsMessages::decode(stream.records()[0].as_slice()).unwrap();
+        assert_eq!(Some("0100018f-aaaa-bbbb".to_string()), row.ses_message_id);
+        assert_eq!(Some("Delivery".to_string()), row.ses_event_type);
+        assert_eq!(vec!["customer@example.com".to_string()], row.ses_destination);
+        assert_eq!(Some("Your order".to_string()), row.ses_subject);
+        assert_eq!("Your order", row.ses_mail_headers["Subject"]);
+        assert_eq!(None, row.s3batch_task_id);
+
+        // A body that is not a notification is still ingested, without the columns
+        let row = TableSqsMessages::decode(stream.records()[1].as_slice()).unwrap();
+        assert_eq!(None, row.ses_message_id);
+        assert_eq!(Some("not a notification".to_string()), row.body);
+    }
+
+    #[tokio::test]
+    async fn test_md5_mismatch_is_a_batch_item_failure() {
+        let records = vec![
+            SqsMessage {
+                md5_of_body: Some("5d41402abc4b2a76b9719d911017c592".to_string()),
+                ..sqs_message(Some("msg-1"), "1700000000000")
+            },
+            SqsMessage {
+                body: Some("hello, tampered".to_string()),
+                md5_of_body: Some("5d41402abc4b2a76b9719d911017c592".to_string()),
+                ..sqs_message(Some("msg-2"), "1700000000000")
+            },
+        ];
+        let options = RowOptions {
+            verify_md5: true,
+            ..Default::default()
+        };
+
+        let mut stream = MockSink::default();
+        let outcome = process_batch("main.default.sqs", &records, &mut stream, &options, None, None).await;
+        let failed: Vec<&str> = outcome
+            .batch_item_failures
+            .iter()
+            .map(|failure| failure.item_identifier.as_str())
+            .collect();
+        assert_eq!(vec!["msg-2"], failed);
+        assert!(outcome.errors["msg-2"].contains("MD5 of the body"));
+        assert_eq!(1, stream.records().len());
+
+        // Without VERIFY_MD5 the digests are only stored
+        let mut stream = MockSink::default();
+        let outcome = process_batch("main.default.sqs", &records, &mut stream, &RowOptions::default(), None, None).await;
+        assert!(outcome.batch_item_failures.is_empty());
+        assert_eq!(2, stream.records().len());
+    }
+
+    #[tokio::test]
+    async fn test_same_deduplication_id_is_ingested_once() {
+        let fifo = |message_id: &str, dedup_id: &str| {
+            let mut message = sqs_message(Some(message_id), "1700000000000");
+            message.event_source_arn = Some("arn:aws:sqs:us-west-2:123456789012:orders.fifo".to_string());
+            message.attributes.insert("MessageGroupId".to_string(), "customer-1".to_string());
+            message
+                .attributes
+                .insert("MessageDeduplicationId".to_string(), dedup_id.to_string());
+            message
+        };
+        let ingested_ids = |sink: &MockSink| -> Vec<String> {
+            sink.records()
+                .iter()
+                .map(|record| TableSqsMessages::decode(record.as_slice()).unwrap().message_id.unwrap())
+                .collect()
+        };
+        let mut store = DedupStore::new(100);
+
+        // A redelivery in the same batch, and another in a later batch
+        let mut stream = MockSink::default();
+        let records = vec![fifo("msg-1", "order-7"), fifo("msg-2", "order-7"), fifo("msg-3", "order-8")];
+        let outcome =
+            process_batch("main.default.sqs", &records, &mut stream, &RowOptions::default(), None, Some(&mut store)).await;
+        assert!(outcome.batch_item_failures.is_empty());
+        let records = vec![fifo("msg-4", "order-7")];
+        let outcome =
+            process_batch("main.default.sqs", &records, &mut stream, &RowOptions::default(), None, Some(&mut store)).await;
+        assert!(outcome.batch_item_failures.is_empty());
+        assert_eq!(vec!["msg-1", "msg-3"], ingested_ids(&stream));
+
+        // A message that is not acknowledged is ingested when it is delivered again
+        let mut failing = MockSink::default().fail_acks_for(|_| true);
+        let records = vec![fifo("msg-5", "order-9")];
+        let outcome =
+            process_batch("main.default.sqs", &records, &mut failing, &RowOptions::default(), None, Some(&mut store)).await;
+        assert_eq!(1, outcome.batch_item_failures.len());
+        let records = vec![fifo("msg-6", "order-9")];
+        process_batch("main.default.sqs", &records, &mut stream, &RowOptions::default(), None, Some(&mut store)).await;
+        assert_eq!(vec!["msg-1", "msg-3", "msg-6"], ingested_ids(&stream));
+    }
+
+    #[tokio::test]
+    async fn test_dead_letter_carries_source_arn_and_target_table() {
+        let orders = "arn:aws:sqs:us-west-2:123456789012:orders";
+        let record = |id: &str, receive_count: &str| {
+            let mut message = sqs_message(Some(id), "1700000000000");
+            message.event_source_arn = Some(orders.to_string());
+            message
+                .attributes
+                .insert("ApproximateReceiveCount".to_string(), receive_count.to_string());
+            message
+        };
+        // msg-1 has attempts left, so SQS retries it; msg-2 was on its last
+        let records = vec![record("msg-1", "1"), record("msg-2", "3")];
+        let routes = QueueRoutes::new("main.default.sqs", "orders=main.default.orders");
+        let mut streams: HashMap<String, MockSink> =
+            [("main.default.orders".to_string(), MockSink::default().fail_acks_for(|_| true))].into();
+        let mut outcomes = process_queues(
+            group_by_queue(&records, &routes),
+            &mut streams,
+            &RowOptions::default(),
+            None,
+            None,
+        )
+        .await;
+
+        let queue = DeadLetterQueue {
+            url: "https://sqs.us-west-2.amazonaws.com/123456789012/orders-dlq".to_string(),
+            max_receive_count: 3,
+            encoding: DeadLetterEncoding::Metadata,
+        };
+        let client = MockSqs::default();
+        forward_dead_letters(&mut outcomes, &records, &queue, &client).await;
+
+        let failed: Vec<&str> = outcomes
+            .iter()
+            .flat_map(|q| &q.outcome.batch_item_failures)
+            .map(|failure| failure.item_identifier.as_str())
+            .collect();
+        assert_eq!(vec!["msg-1"], failed);
+
+        let sent = client.sent.lock().unwrap();
+        assert_eq!(1, sent.len());
+        let (url, letter) = &sent[0];
+        assert_eq!(&queue.url, url);
+        assert_eq!("hello", letter.body);
+        let string = |value: &str| AttributeValue::String {
+            data_type: "String".to_string(),
+            value: value.to_string(),
+        };
+        assert_eq!(Some(&string(orders)), letter.attributes.get(SOURCE_ARN_ATTRIBUTE));
+        assert_eq!(
+            Some(&string("main.default.orders")),
+            letter.attributes.get(TARGET_TABLE_ATTRIBUTE)
+        );
+    }
+
+    /// Runs the scenarios of `common/testdata/chaos` that have `sqs` expectations: their
+    /// messages, all received `receive_count` times, are processed against a faulty stream
+    /// and the failures on their last attempt forwarded to a dead-letter queue, all within
+    /// `deadline_ms`, as the Lambda timeout would cut the invocation short
+    #[tokio::test(start_paused = true)]
+    async fn test_chaos_scenarios() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../common/testdata/chaos");
+        let queue = DeadLetterQueue {
+            url: "https://sqs.us-west-2.amazonaws.com/123456789012/orders-dlq".to_string(),
+            max_receive_count: 3,
+            encoding: DeadLetterEncoding::Metadata,
+        };
+        let message_ids = |value: &Value| -> Vec<String> {
+            value.as_array().unwrap().iter().map(|index| format!("msg-{}", index)).collect()
+        };
+
+        for scenario in Scenario::load_dir(&dir).unwrap() {
+            let Some(expect) = scenario.expect("sqs") else {
+                continue;
+            };
+            let name = &scenario.name;
+            let receive_count = expect.get("receive_count").and_then(Value::as_u64).unwrap_or(1);
+            let records: Vec<SqsMessage> = (0..scenario.records)
+                .map(|index| {
+                    let mut message = sqs_message(Some(&format!("msg-{}", index)), "1700000000000");
+                    message
+                        .attributes
+                        .insert("ApproximateReceiveCount".to_string(), receive_count.to_string());
+                    message
+                })
+                .collect();
+            let flush_every_n = expect.get("flush_every_n").and_then(Value::as_u64).map(|n| n as usize);
+            let mut stream = ChaosSink::new(MockSink::default(), scenario.faults.clone());
+            let client = MockSqs::default();
+
+            let deadline = Duration::from_millis(expect["deadline_ms"].as_u64().unwrap());
+            let run = async {
+                let outcome =
+                    process_batch("main.default.sqs", &records, &mut stream, &RowOptions::default(), flush_every_n, None)
+                        .await;
+                let mut outcomes = vec![QueueOutcome {
+                    event_source_arn: "arn:aws:sqs:us-west-2:123456789012:orders".to_string(),
+                    table_name: "main.default.sqs".to_string(),
+                    outcome,
+                }];
+                forward_dead_letters(&mut outcomes, &records, &queue, &client).await;
+                outcomes
+            };
+            let outcomes = tokio::time::timeout(deadline, run)
+                .await
+                .unwrap_or_else(|_| panic!("{}: overran the deadline of {:?}", name, deadline));
+
+            let failed: Vec<String> = outcomes[0]
+                .outcome
+                .batch_item_failures
+                .iter()
+                .map(|failure| failure.item_identifier.clone())
+                .collect();
+            let quarantined: Vec<String> = client
+                .sent
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(_, letter)| match letter.attributes.get(MESSAGE_ID_ATTRIBUTE) {
+                    Some(AttributeValue::String { value, .. }) => value.clone(),
+                    other => panic!("{}: dead letter without a message ID: {:?}", name, other),
+                })
+                .collect();
+            assert_eq!(message_ids(&expect["failed"]), failed, "{}: batch item failures", name);
+            assert_eq!(message_ids(&expect["quarantined"]), quarantined, "{}: dead letters", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stream_is_recreated_after_the_connection_drops() {
+        let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+        // Refusing one reconnect more than the SDK retries makes its own recovery give up,
+        // so the stream fails to close and is recreated
+        let refused = StreamConfigurationOptions::default().recovery_retries + 1;
+        let server = FakeZerobus::new()
+            .drop_after_records(2)
+            .refuse_reconnects(refused)
+            .start()
+            .await
+            .unwrap();
+        // The SDK reads SSL_CERT_FILE when it connects, so the scope lasts the invocation
+        let mut vars = server.env();
+        vars.push(("TABLE_NAME", "main.default.sqs".to_string()));
+        let vars: Vec<(&str, &str)> = vars.iter().map(|(name, value)| (*name, value.as_str())).collect();
+        let _env = EnvScope::new(&vars);
+        let config = HandlerConfig::from_env().unwrap();
+        let sdk = ZerobusSdk::new(server.endpoint().to_string(), server.host().to_string()).unwrap();
+
+        let event = sqs_event(messages(&["msg-1", "msg-2", "msg-3", "msg-4"]), "req-1");
+        let response = handle_event(event, &sdk, &MockSqs::default(), &Warm::new(), config).await.unwrap();
+
+        assert_eq!(vec!["msg-3", "msg-4"], failed_ids(&response));
+        assert_eq!(2, server.acked_records().len());
+        assert_eq!(u64::from(refused), server.streams_refused());
+        // The stream the batch started on, and the one recreated after it failed
+        assert_eq!(2, server.streams_created());
+    }
+
+    #[tokio::test]
+    async fn test_batch_is_ingested_and_its_stream_closed() {
+        let streams = MockStreams::default();
+        let warm = Warm::new();
+        let mut config = config(&[]);
+        config.options.clock = Some(Arc::new(FixedClock::at_unix_secs(1_718_020_860)));
+        let event = sqs_event(messages(&["msg-1", "msg-2", "msg-3"]), "req-1");
+
+        let invocation =
+            invoke(&streams, |sdk| async move { handle_event(event, &sdk, &MockSqs::default(), &warm, config).await })
+                .await;
+
+        assert!(invocation.response.unwrap().batch_item_failures.is_empty());
+        let calls = invocation.calls;
+        assert_eq!(vec!["main.default.sqs"], calls.created);
+        assert!(calls.closed.contains("main.default.sqs"));
+        let records = calls.records_to("main.default.sqs");
+        assert_eq!(vec!["msg-1", "msg-2", "msg-3"], message_ids(records));
+        let row = TableSqsMessages::decode(records[0].as_slice()).unwrap();
+        assert_eq!(Some(1_718_020_860_000_000), row.ingested_at);
+        assert_eq!(Some("req-1".to_string()), row.consumer_request_id);
+    }
+
+    #[tokio::test]
+    async fn test_table_without_a_stream_fails_only_its_queue() {
+        let record = |id: &str, queue: &str| SqsMessage {
+            event_source_arn: Some(format!("arn:aws:sqs:us-west-2:123456789012:{}", queue)),
+            ..sqs_message(Some(id), "1700000000000")
+        };
+        let streams = MockStreams::default().fail_create_for(|table| table == "main.default.orders");
+        let warm = Warm::new();
+        let config = config(&[("QUEUE_TABLE_MAP", "orders=main.default.orders")]);
+        let event = sqs_event(
+            vec![record("msg-1", "orders"), record("msg-2", "returns"), record("msg-3", "orders")],
+            "req-1",
+        );
+
+        let invocation =
+            invoke(&streams, |sdk| async move { handle_event(event, &sdk, &MockSqs::default(), &warm, config).await })
+                .await;
+
+        assert_eq!(vec!["msg-1", "msg-3"], failed_ids(&invocation.response.unwrap()));
+        let calls = invocation.calls;
+        assert_eq!(vec!["main.default.orders", "main.default.sqs"], calls.created);
+        assert_eq!(vec!["msg-2"], message_ids(calls.records_to("main.default.sqs")));
+        assert!(calls.records_to("main.default.orders").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unacknowledged_message_is_a_batch_item_failure() {
+        let is_msg_2 = |record: &[u8]| TableSqsMessages::decode(record).unwrap().message_id.as_deref() == Some("msg-2");
+        let streams = MockStreams::default().with_sink(MockSink::default().fail_acks_for(is_msg_2));
+        let warm = Warm::new();
+        let event = sqs_event(messages(&["msg-1", "msg-2", "msg-3"]), "req-1");
+
+        let invocation = invoke(&streams, |sdk| async move {
+            handle_event(event, &sdk, &MockSqs::default(), &warm, config(&[])).await
+        })
+        .await;
+
+        assert_eq!(vec!["msg-2"], failed_ids(&invocation.response.unwrap()));
+        // Every message was sent; only the ack of msg-2 failed
+        assert_eq!(vec!["msg-1", "msg-2", "msg-3"], message_ids(invocation.calls.records_to("main.default.sqs")));
+    }
+
+    #[tokio::test]
+    async fn test_audit_stream_stays_open_across_invocations() {
+        let streams = MockStreams::default();
+        let warm = Warm::new();
+        let clock: Arc<dyn Clock> = Arc::new(FixedClock::at_unix_secs(1_718_020_860));
+        let audit_rows = |calls: &SinkCalls| -> Vec<BatchAudit> {
+            calls
+                .records_to("main.default.audit")
+                .iter()
+                .map(|record| BatchAudit::decode(record.as_slice()).unwrap())
+                .collect()
+        };
+
+        let mut calls = Vec::new();
+        for request_id in ["req-1", "req-2"] {
+            let mut config = config(&[("AUDIT_TABLE", "main.default.audit")]);
+            config.options.clock = Some(clock.clone());
+            let event = sqs_event(messages(&["msg-1", "msg-2"]), request_id);
+            let warm = &warm;
+            let invocation =
+                invoke(&streams, |sdk| async move { handle_event(event, &sdk, &MockSqs::default(), warm, config).await })
+                    .await;
+            invocation.response.unwrap();
+            calls.push(invocation.calls);
+        }
+
+        assert_eq!(vec!["main.default.sqs", "main.default.audit"], calls[0].created);
+        assert_eq!(vec!["main.default.sqs"], calls[1].created);
+        for (calls, request_id) in calls.iter().zip(["req-1", "req-2"]) {
+            let rows = audit_rows(calls);
+            assert_eq!(1, rows.len());
+            assert_eq!(Some(request_id.to_string()), rows[0].batch_id);
+            assert_eq!(Some(2), rows[0].records_ingested);
+            assert_eq!(Some(1_718_020_860_000_000), rows[0].started_at);
+        }
+        assert!(!calls[1].closed.contains("main.default.audit"));
+    }
+
+    #[tokio::test]
+    async fn test_deduplication_ids_are_kept_across_invocations() {
+        let fifo = |message_id: &str, dedup_id: &str| {
+            let mut message = sqs_message(Some(message_id), "1700000000000");
+            message
+                .attributes
+                .insert("MessageDeduplicationId".to_string(), dedup_id.to_string());
+            message
+        };
+        let streams = MockStreams::default();
+        let warm = Warm::new();
+
+        let mut ingested = Vec::new();
+        for (request_id, records) in [
+            ("req-1", vec![fifo("msg-1", "order-7"), fifo("msg-2", "order-8")]),
+            // A redelivery of order-7 in the next invocation of the same environment
+            ("req-2", vec![fifo("msg-3", "order-7"), fifo("msg-4", "order-9")]),
+        ] {
+            let config = config(&[("DEDUP_BY_DEDUPLICATION_ID", "true")]);
+            let event = sqs_event(records, request_id);
+            let warm = &warm;
+            let invocation =
+                invoke(&streams, |sdk| async move { handle_event(event, &sdk, &MockSqs::default(), warm, config).await })
+                    .await;
+            assert!(invocation.response.unwrap().batch_item_failures.is_empty());
+            ingested.push(message_ids(invocation.calls.records_to("main.default.sqs")));
+        }
+
+        assert_eq!(vec![vec!["msg-1", "msg-2"], vec!["msg-4"]], ingested);
+    }
+
+    #[tokio::test]
+    async fn test_last_attempt_failures_are_dead_lettered() {
+        let record = |id: &str, receive_count: &str| {
+            let mut message = sqs_message(Some(id), "1700000000000");
+            message
+                .attributes
+                .insert("ApproximateReceiveCount".to_string(), receive_count.to_string());
+            message
+        };
+        let streams = MockStreams::default().with_sink(MockSink::default().fail_acks_for(|_| true));
+        let warm = Warm::new();
+        let sqs = MockSqs::default();
+        let config = config(&[
+            ("DLQ_URL", "https://sqs.us-west-2.amazonaws.com/123456789012/orders-dlq"),
+            ("DLQ_MAX_RECEIVE_COUNT", "3"),
+        ]);
+        // msg-1 has attempts left, so SQS retries it; msg-2 was on its last
+        let event = sqs_event(vec![record("msg-1", "1"), record("msg-2", "3")], "req-1");
+
+        let sqs_ref = &sqs;
+        let invocation =
+            invoke(&streams, |sdk| async move { handle_event(event, &sdk, sqs_ref, &warm, config).await }).await;
+
+        assert_eq!(vec!["msg-1"], failed_ids(&invocation.response.unwrap()));
+        let sent = sqs.sent.lock().unwrap();
+        assert_eq!(1, sent.len());
+        assert_eq!("https://sqs.us-west-2.amazonaws.com/123456789012/orders-dlq", sent[0].0);
+        assert_eq!(
+            Some(&AttributeValue::String {
+                data_type: "String".to_string(),
+                value: "msg-2".to_string(),
+            }),
+            sent[0].1.attributes.get(MESSAGE_ID_ATTRIBUTE)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stream_failing_to_close_is_recreated() {
+        let report = std::env::temp_dir().join(format!("sqs-unacked-{}.jsonl", std::process::id()));
+        let streams = MockStreams::default().with_sink(MockSink::default().fail_close());
+        let warm = Warm::new();
+        let mut config = config(&[]);
+        config.unacked_destination = ReportDestination::File(report.clone());
+        let event = sqs_event(messages(&["msg-1", "msg-2"]), "req-1");
+
+        let invocation =
+            invoke(&streams, |sdk| async move { handle_event(event, &sdk, &MockSqs::default(), &warm, config).await })
+                .await;
+
+        // Every ack arrived before the close failed, so the batch still succeeds
+        assert!(invocation.response.unwrap().batch_item_failures.is_empty());
+        let calls = invocation.calls;
+        assert_eq!(vec!["main.default.sqs"], calls.recreated);
+        assert!(calls.closed.is_empty());
+        let line = std::fs::read_to_string(&report).unwrap();
+        std::fs::remove_file(&report).unwrap();
+        assert!(line.contains("main.default.sqs"), "{}", line);
     }
 }
@@ -0,0 +1,556 @@
+//! CloudWatch metrics of each invocation, written as embedded metric format (EMF) log
+//! lines or sent with PutMetricData, selected by `METRICS_SINK`
+
+use anyhow::{bail, Context, Result};
+use aws_sdk_cloudwatch::primitives::DateTime;
+use aws_sdk_cloudwatch::types::{Dimension, MetricDatum, StandardUnit};
+use serde_json::{json, Map, Value};
+use std::future::Future;
+use zerobus_common::cardinality::LabelGuard;
+use zerobus_common::distribution::Distribution;
+
+/// Namespace of the metrics when METRICS_NAMESPACE is not set
+pub const DEFAULT_NAMESPACE: &str = "Zerobus/SqsIngestor";
+
+/// Most metric data one PutMetricData call accepts
+const MAX_DATA_PER_CALL: usize = 1000;
+
+/// Where an invocation's metrics go
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricsSink {
+    /// A JSON log line per dimension set, which CloudWatch Logs turns into metrics
+    Emf,
+    /// PutMetricData calls at the end of the invocation
+    CloudWatchApi,
+}
+
+impl MetricsSink {
+    /// Read the sink from `METRICS_SINK`: `emf` (the default) or `cloudwatch_api`
+    pub fn from_env() -> Result<Self> {
+        match std::env::var("METRICS_SINK") {
+            Ok(value) => Self::new(&value),
+            Err(_) => Ok(MetricsSink::Emf),
+        }
+    }
+
+    pub fn new(value: &str) -> Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "" | "emf" => Ok(MetricsSink::Emf),
+            "cloudwatch_api" => Ok(MetricsSink::CloudWatchApi),
+            _ => bail!(
+                "METRICS_SINK must be emf or cloudwatch_api, got {:?}",
+                value
+            ),
+        }
+    }
+}
+
+/// Namespace from `METRICS_NAMESPACE`, or [`DEFAULT_NAMESPACE`]
+pub fn namespace_from_env() -> String {
+    std::env::var("METRICS_NAMESPACE")
+        .ok()
+        .filter(|namespace| !namespace.trim().is_empty())
+        .unwrap_or_else(|| DEFAULT_NAMESPACE.to_string())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unit {
+    Count,
+    Milliseconds,
+    Bytes,
+}
+
+impl Unit {
+    fn as_str(self) -> &'static str {
+        match self {
+            Unit::Count => "Count",
+            Unit::Milliseconds => "Milliseconds",
+            Unit::Bytes => "Bytes",
+        }
+    }
+
+    fn standard_unit(self) -> StandardUnit {
+        match self {
+            Unit::Count => StandardUnit::Count,
+            Unit::Milliseconds => StandardUnit::Milliseconds,
+            Unit::Bytes => StandardUnit::Bytes,
+        }
+    }
+}
+
+/// One value of one metric
+#[derive(Debug, Clone, PartialEq)]
+pub struct Datum {
+    pub name: &'static str,
+    pub unit: Unit,
+    pub value: f64,
+    pub dimensions: Vec<(&'static str, String)>,
+}
+
+/// Bucket counts of one distribution, logged alongside the metrics of its dimensions
+#[derive(Debug, Clone, PartialEq)]
+pub struct Buckets {
+    pub name: &'static str,
+    pub upper_bounds: Vec<f64>,
+    /// Observations per bucket, with a last one for those above every bound
+    pub counts: Vec<u64>,
+    pub dimensions: Vec<(&'static str, String)>,
+}
+
+/// The PutMetricData call the ingestor makes; implemented for the AWS SDK client, and by
+/// tests
+pub trait PutMetricData: Send + Sync {
+    fn put_metric_data(
+        &self,
+        namespace: &str,
+        data: Vec<MetricDatum>,
+    ) -> impl Future<Output = Result<()>> + Send;
+}
+
+impl PutMetricData for aws_sdk_cloudwatch::Client {
+    async fn put_metric_data(&self, namespace: &str, data: Vec<MetricDatum>) -> Result<()> {
+        self.put_metric_data()
+            .namespace(namespace)
+            .set_metric_data(Some(data))
+            .send()
+            .await
+            .context("PutMetricData failed")?;
+        Ok(())
+    }
+}
+
+/// Metrics gathered during an invocation and flushed once, at its end
+///
+/// Both sinks publish the same metric names, units, and dimensions, so dashboards and
+/// alarms work with either.
+#[derive(Debug, Clone)]
+pub struct InvocationMetrics {
+    namespace: String,
+    /// Milliseconds since Unix epoch
+    timestamp_ms: i64,
+    data: Vec<Datum>,
+    buckets: Vec<Buckets>,
+    labels: LabelGuard,
+}
+
+impl InvocationMetrics {
+    pub fn new(namespace: impl Into<String>, timestamp_ms: i64) -> Self {
+        Self {
+            namespace: namespace.into(),
+            timestamp_ms,
+            data: Vec::new(),
+            buckets: Vec::new(),
+            labels: LabelGuard::default(),
+        }
+    }
+
+    /// Give the queue and table pairs `labels` admits their own dimensions, and report
+    /// the queues of the others as `other`
+    ///
+    /// The guard outlives the invocation, so the pairs are bounded across the execution
+    /// environment rather than per batch.
+    pub fn labels(mut self, labels: LabelGuard) -> Self {
+        self.labels = labels;
+        self
+    }
+
+    /// The `Queue` and `Table` dimensions of a queue's metrics
+    pub fn queue_dimensions(&self, queue: &str, table: &str) -> Vec<(&'static str, String)> {
+        vec![
+            ("Queue", self.labels.source(table, queue).to_string()),
+            ("Table", table.to_string()),
+        ]
+    }
+
+    pub fn record(
+        &mut self,
+        name: &'static str,
+        unit: Unit,
+        value: f64,
+        dimensions: Vec<(&'static str, String)>,
+    ) {
+        self.data.push(Datum {
+            name,
+            unit,
+            value,
+            dimensions,
+        });
+    }
+
+    /// Record the outcome of one source queue's records
+    ///
+    /// Queues reported as `other` share their counts.
+    pub fn record_queue(&mut self, queue: &str, table: &str, received: usize, failed: usize) {
+        let dimensions = self.queue_dimensions(queue, table);
+        for (name, value) in [
+            ("MessagesReceived", received),
+            ("MessagesIngested", received.saturating_sub(failed)),
+            ("MessagesFailed", failed),
+        ] {
+            match self
+                .data
+                .iter_mut()
+                .find(|datum| datum.name == name && datum.dimensions == dimensions)
+            {
+                Some(datum) => datum.value += value as f64,
+                None => self.record(name, Unit::Count, value as f64, dimensions.clone()),
+            }
+        }
+    }
+
+    /// Record the p50, p90, p99, and max of a distribution as the metrics `names`, in
+    /// that order, and keep its bucket counts as `<buckets>`
+    ///
+    /// Nothing is recorded for a distribution without observations.
+    pub fn record_distribution(
+        &mut self,
+        names: [&'static str; 4],
+        buckets: &'static str,
+        unit: Unit,
+        distribution: &Distribution,
+        dimensions: Vec<(&'static str, String)>,
+    ) {
+        let Some(percentiles) = distribution.percentiles() else {
+            return;
+        };
+        let values = [
+            percentiles.p50,
+            percentiles.p90,
+            percentiles.p99,
+            percentiles.max,
+        ];
+        for (name, value) in names.into_iter().zip(values) {
+            self.record(name, unit, value, dimensions.clone());
+        }
+        self.buckets.push(Buckets {
+            name: buckets,
+            upper_bounds: distribution.bounds().to_vec(),
+            counts: distribution.bucket_counts().to_vec(),
+            dimensions,
+        });
+    }
+
+    /// One EMF document per dimension set, in the order the sets were first recorded
+    ///
+    /// EMF declares the dimensions of all the metrics in a document together, and the
+    /// values of the dimensions are top-level members, so data with different dimension
+    /// values go in different documents.
+    pub fn emf_lines(&self) -> Vec<String> {
+        let mut groups: Vec<(&[(&'static str, String)], Vec<&Datum>)> = Vec::new();
+        for datum in &self.data {
+            match groups
+                .iter_mut()
+                .find(|(dimensions, _)| *dimensions == datum.dimensions)
+            {
+                Some((_, data)) => data.push(datum),
+                None => groups.push((&datum.dimensions, vec![datum])),
+            }
+        }
+
+        groups
+            .into_iter()
+            .map(|(dimensions, data)| {
+                let names: Vec<&str> = dimensions.iter().map(|(name, _)| *name).collect();
+                let mut metrics: Vec<Value> = Vec::new();
+                let mut document = Map::new();
+                for (name, value) in dimensions {
+                    document.insert(name.to_string(), json!(value));
+                }
+                for datum in data {
+                    if !document.contains_key(datum.name) {
+                        metrics.push(json!({"Name": datum.name, "Unit": datum.unit.as_str()}));
+                    }
+                    document.insert(datum.name.to_string(), json!(datum.value));
+                }
+                // Not metrics, but searchable in CloudWatch Logs Insights
+                for buckets in self
+                    .buckets
+                    .iter()
+                    .filter(|buckets| buckets.dimensions == dimensions)
+                {
+                    document.insert(
+                        buckets.name.to_string(),
+                        json!({"UpperBounds": buckets.upper_bounds, "Counts": buckets.counts}),
+                    );
+                }
+                document.insert(
+                    "_aws".to_string(),
+                    json!({
+                        "Timestamp": self.timestamp_ms,
+                        "CloudWatchMetrics": [{
+                            "Namespace": self.namespace,
+                            "Dimensions": [names],
+                            "Metrics": metrics,
+                        }],
+                    }),
+                );
+                Value::Object(document).to_string()
+            })
+            .collect()
+    }
+
+    /// The metric data of PutMetricData calls
+    ///
+    /// Bucket counts are only logged in EMF documents; the percentiles go out through
+    /// either sink.
+    pub fn metric_data(&self) -> Vec<MetricDatum> {
+        let timestamp = DateTime::from_millis(self.timestamp_ms);
+        self.data
+            .iter()
+            .map(|datum| {
+                MetricDatum::builder()
+                    .metric_name(datum.name)
+                    .unit(datum.unit.standard_unit())
+                    .value(datum.value)
+                    .timestamp(timestamp)
+                    .set_dimensions(Some(
+                        datum
+                            .dimensions
+                            .iter()
+                            .map(|(name, value)| {
+                                Dimension::builder().name(*name).value(value).build()
+                            })
+                            .collect(),
+                    ))
+                    .build()
+            })
+            .collect()
+    }
+
+    /// Send the metrics with as few PutMetricData calls as the API allows
+    pub async fn put<C: PutMetricData>(&self, client: &C) -> Result<()> {
+        let data = self.metric_data();
+        for chunk in data.chunks(MAX_DATA_PER_CALL) {
+            client
+                .put_metric_data(&self.namespace, chunk.to_vec())
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Records each PutMetricData call instead of making it
+    #[derive(Default)]
+    struct MockCloudWatch {
+        calls: Mutex<Vec<(String, Vec<MetricDatum>)>>,
+    }
+
+    impl PutMetricData for MockCloudWatch {
+        async fn put_metric_data(&self, namespace: &str, data: Vec<MetricDatum>) -> Result<()> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push((namespace.to_string(), data));
+            Ok(())
+        }
+    }
+
+    fn metrics() -> InvocationMetrics {
+        let mut metrics = InvocationMetrics::new(DEFAULT_NAMESPACE, 1_718_020_800_000);
+        metrics.record_queue("orders", "main.default.orders", 10, 2);
+        metrics.record_queue("returns", "main.default.returns", 3, 0);
+        metrics
+    }
+
+    #[test]
+    fn test_metrics_sink_is_parsed() {
+        assert_eq!(MetricsSink::Emf, MetricsSink::new("emf").unwrap());
+        assert_eq!(MetricsSink::Emf, MetricsSink::new("").unwrap());
+        assert_eq!(
+            MetricsSink::CloudWatchApi,
+            MetricsSink::new(" CloudWatch_API ").unwrap()
+        );
+        assert!(MetricsSink::new("statsd").is_err());
+    }
+
+    #[test]
+    fn test_emf_document_per_dimension_set() {
+        let lines = metrics().emf_lines();
+        assert_eq!(2, lines.len());
+
+        let document: Value = serde_json::from_str(&lines[0]).unwrap();
+        assert_eq!(
+            json!({
+                "Queue": "orders",
+                "Table": "main.default.orders",
+                "MessagesReceived": 10.0,
+                "MessagesIngested": 8.0,
+                "MessagesFailed": 2.0,
+                "_aws": {
+                    "Timestamp": 1_718_020_800_000_i64,
+                    "CloudWatchMetrics": [{
+                        "Namespace": "Zerobus/SqsIngestor",
+                        "Dimensions": [["Queue", "Table"]],
+                        "Metrics": [
+                            {"Name": "MessagesReceived", "Unit": "Count"},
+                            {"Name": "MessagesIngested", "Unit": "Count"},
+                            {"Name": "MessagesFailed", "Unit": "Count"},
+                        ],
+                    }],
+                },
+            }),
+            document
+        );
+        let document: Value = serde_json::from_str(&lines[1]).unwrap();
+        assert_eq!("returns", document["Queue"]);
+        assert_eq!(3.0, document["MessagesIngested"]);
+    }
+
+    #[test]
+    fn test_queues_past_the_label_limit_share_dimensions() {
+        let labels = LabelGuard::new(2);
+        let mut metrics = InvocationMetrics::new(DEFAULT_NAMESPACE, 0).labels(labels.clone());
+        metrics.record_queue("orders", "main.default.orders", 10, 2);
+        metrics.record_queue("returns", "main.default.orders", 3, 0);
+        metrics.record_queue("refunds", "main.default.orders", 4, 1);
+        metrics.record_queue("exchanges", "main.default.orders", 5, 0);
+
+        let lines = metrics.emf_lines();
+        assert_eq!(3, lines.len());
+        let document: Value = serde_json::from_str(&lines[2]).unwrap();
+        assert_eq!("other", document["Queue"]);
+        assert_eq!("main.default.orders", document["Table"]);
+        assert_eq!(9.0, document["MessagesReceived"]);
+        assert_eq!(8.0, document["MessagesIngested"]);
+        assert_eq!(9, metrics.metric_data().len());
+
+        // The limit holds across invocations sharing the guard
+        let next = InvocationMetrics::new(DEFAULT_NAMESPACE, 0).labels(labels);
+        assert_eq!(
+            vec![
+                ("Queue", "returns".to_string()),
+                ("Table", "main.default.orders".to_string())
+            ],
+            next.queue_dimensions("returns", "main.default.orders")
+        );
+        assert_eq!(
+            ("Queue", "other".to_string()),
+            next.queue_dimensions("refunds", "main.default.orders")[0]
+        );
+    }
+
+    #[test]
+    fn test_distribution_percentiles_and_buckets() {
+        let mut latency = Distribution::new(vec![10.0, 100.0, 1000.0]);
+        for ms in [4.0, 8.0, 40.0, 60.0, 80.0, 90.0, 95.0, 97.0, 99.0, 400.0] {
+            latency.observe(ms);
+        }
+        let dimensions = vec![("Queue", "orders".to_string())];
+        let mut metrics = InvocationMetrics::new(DEFAULT_NAMESPACE, 0);
+        metrics.record_distribution(
+            [
+                "AckLatencyP50",
+                "AckLatencyP90",
+                "AckLatencyP99",
+                "AckLatencyMax",
+            ],
+            "AckLatencyBuckets",
+            Unit::Milliseconds,
+            &latency,
+            dimensions.clone(),
+        );
+        // Nothing observed, nothing recorded
+        metrics.record_distribution(
+            [
+                "RecordBytesP50",
+                "RecordBytesP90",
+                "RecordBytesP99",
+                "RecordBytesMax",
+            ],
+            "RecordBytesBuckets",
+            Unit::Bytes,
+            &Distribution::size(),
+            dimensions,
+        );
+
+        let lines = metrics.emf_lines();
+        assert_eq!(1, lines.len());
+        let document: Value = serde_json::from_str(&lines[0]).unwrap();
+        assert_eq!(100.0, document["AckLatencyP50"]);
+        assert_eq!(100.0, document["AckLatencyP90"]);
+        assert_eq!(400.0, document["AckLatencyP99"]);
+        assert_eq!(400.0, document["AckLatencyMax"]);
+        assert_eq!(
+            json!({"UpperBounds": [10.0, 100.0, 1000.0], "Counts": [2, 7, 1, 0]}),
+            document["AckLatencyBuckets"]
+        );
+        assert_eq!(
+            json!({"Name": "AckLatencyP50", "Unit": "Milliseconds"}),
+            document["_aws"]["CloudWatchMetrics"][0]["Metrics"][0]
+        );
+        assert_eq!(4, metrics.metric_data().len());
+    }
+
+    #[tokio::test]
+    async fn test_put_metric_data_matches_emf() {
+        let metrics = metrics();
+        let client = MockCloudWatch::default();
+        metrics.put(&client).await.unwrap();
+
+        let calls = client.calls.lock().unwrap();
+        assert_eq!(1, calls.len());
+        let (namespace, data) = &calls[0];
+        assert_eq!("Zerobus/SqsIngestor", namespace);
+        assert_eq!(6, data.len());
+
+        let failed = &data[2];
+        assert_eq!(Some("MessagesFailed"), failed.metric_name());
+        assert_eq!(Some(2.0), failed.value());
+        assert_eq!(Some(&StandardUnit::Count), failed.unit());
+        assert_eq!(
+            Some(&DateTime::from_millis(1_718_020_800_000)),
+            failed.timestamp()
+        );
+        let dimensions: Vec<(Option<&str>, Option<&str>)> = failed
+            .dimensions()
+            .iter()
+            .map(|dimension| (dimension.name(), dimension.value()))
+            .collect();
+        assert_eq!(
+            vec![
+                (Some("Queue"), Some("orders")),
+                (Some("Table"), Some("main.default.orders"))
+            ],
+            dimensions
+        );
+
+        // The same names and dimension values go out through either sink
+        for (datum, line) in data.chunks(3).zip(metrics.emf_lines()) {
+            let document: Value = serde_json::from_str(&line).unwrap();
+            for datum in datum {
+                let name = datum.metric_name().unwrap();
+                assert_eq!(datum.value(), document[name].as_f64());
+                for dimension in datum.dimensions() {
+                    assert_eq!(
+                        dimension.value(),
+                        document[dimension.name().unwrap()].as_str()
+                    );
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_put_is_split_into_calls_of_at_most_1000() {
+        let mut metrics = InvocationMetrics::new("Test", 0);
+        for queue in 0..400 {
+            metrics.record_queue(&format!("queue-{}", queue), "main.default.t", 1, 0);
+        }
+        let client = MockCloudWatch::default();
+        metrics.put(&client).await.unwrap();
+
+        let sizes: Vec<usize> = client
+            .calls
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, data)| data.len())
+            .collect();
+        assert_eq!(vec![1000, 200], sizes);
+    }
+}
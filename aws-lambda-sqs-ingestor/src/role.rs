@@ -0,0 +1,302 @@
+//! Cross-account access for the AWS clients through STS AssumeRole
+//!
+//! With `ASSUME_ROLE_ARN` set, the dead-letter queue and CloudWatch clients act as that
+//! role instead of the function's own, for queues and metrics in another account. Each
+//! integration can assume a role of its own with `DLQ_ASSUME_ROLE_ARN` or
+//! `METRICS_ASSUME_ROLE_ARN`, and likewise override `ASSUME_ROLE_SESSION_NAME` and
+//! `ASSUME_ROLE_EXTERNAL_ID`.
+//!
+//! The role's credentials are kept until shortly before they expire, and the client is
+//! rebuilt with new ones then. Callers needing a client at the same time wait for one
+//! refresh rather than each calling STS.
+
+use anyhow::{bail, Context, Result};
+use aws_sdk_sts::config::Credentials;
+use std::future::Future;
+use std::time::{Duration, SystemTime};
+use tokio::sync::Mutex;
+use tracing::info;
+
+/// Credentials are refreshed when they expire within this long
+pub const REFRESH_MARGIN: Duration = Duration::from_secs(300);
+
+/// Session name when ASSUME_ROLE_SESSION_NAME is not set
+pub const DEFAULT_SESSION_NAME: &str = "aws-lambda-sqs-ingestor";
+
+/// The role one integration assumes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoleConfig {
+    pub role_arn: String,
+    pub session_name: String,
+    pub external_id: Option<String>,
+}
+
+impl RoleConfig {
+    /// The role for `integration` (`DLQ` or `METRICS`), from its own variables or the
+    /// shared ones; `None` when neither names a role
+    pub fn from_env(integration: &str) -> Result<Option<Self>> {
+        Self::from_lookup(integration, |name| std::env::var(name).ok())
+    }
+
+    fn from_lookup(
+        integration: &str,
+        lookup: impl Fn(&str) -> Option<String>,
+    ) -> Result<Option<Self>> {
+        let set = |name: &str| {
+            lookup(name)
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty())
+        };
+        let var = |name: &str| set(&format!("{}_{}", integration, name)).or_else(|| set(name));
+        let Some(role_arn) = var("ASSUME_ROLE_ARN") else {
+            return Ok(None);
+        };
+        if !role_arn.starts_with("arn:") {
+            bail!(
+                "{}_ASSUME_ROLE_ARN or ASSUME_ROLE_ARN must be a role ARN, got {:?}",
+                integration,
+                role_arn
+            );
+        }
+        Ok(Some(Self {
+            role_arn,
+            session_name: var("ASSUME_ROLE_SESSION_NAME")
+                .unwrap_or_else(|| DEFAULT_SESSION_NAME.to_string()),
+            external_id: var("ASSUME_ROLE_EXTERNAL_ID"),
+        }))
+    }
+}
+
+/// The AssumeRole call the ingestor makes; implemented for the AWS SDK client, and by
+/// tests
+pub trait AssumeRole: Send + Sync {
+    fn assume_role(&self, role: &RoleConfig) -> impl Future<Output = Result<Credentials>> + Send;
+}
+
+impl AssumeRole for aws_sdk_sts::Client {
+    async fn assume_role(&self, role: &RoleConfig) -> Result<Credentials> {
+        let output = aws_sdk_sts::Client::assume_role(self)
+            .role_arn(&role.role_arn)
+            .role_session_name(&role.session_name)
+            .set_external_id(role.external_id.clone())
+            .send()
+            .await
+            .with_context(|| format!("AssumeRole {} failed", role.role_arn))?;
+        let credentials = output
+            .credentials()
+            .context("AssumeRole returned no credentials")?;
+        let expiry = SystemTime::try_from(*credentials.expiration())
+            .context("AssumeRole returned an invalid expiration")?;
+        Ok(Credentials::new(
+            credentials.access_key_id(),
+            credentials.secret_access_key(),
+            Some(credentials.session_token().to_string()),
+            Some(expiry),
+            "AssumeRole",
+        ))
+    }
+}
+
+struct Cached<C> {
+    client: C,
+    /// When the client's credentials expire; never for the function's own credentials
+    expires_at: Option<SystemTime>,
+}
+
+/// An AWS client acting as an assumed role, or as the function itself without one
+pub struct RoleClient<C, S = aws_sdk_sts::Client> {
+    role: Option<RoleConfig>,
+    sts: S,
+    /// Builds the client with the role's credentials, or the default ones given `None`
+    build: Box<dyn Fn(Option<Credentials>) -> C + Send + Sync>,
+    cached: Mutex<Option<Cached<C>>>,
+}
+
+impl<C: Clone, S: AssumeRole> RoleClient<C, S> {
+    pub fn new(
+        role: Option<RoleConfig>,
+        sts: S,
+        build: impl Fn(Option<Credentials>) -> C + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            role,
+            sts,
+            build: Box::new(build),
+            cached: Mutex::new(None),
+        }
+    }
+
+    /// The client, rebuilt first if its credentials are about to expire
+    pub async fn client(&self) -> Result<C> {
+        self.client_at(SystemTime::now()).await
+    }
+
+    async fn client_at(&self, now: SystemTime) -> Result<C> {
+        // Held across the STS call, so concurrent callers share one refresh
+        let mut cached = self.cached.lock().await;
+        if let Some(cached) = cached.as_ref() {
+            let fresh = cached
+                .expires_at
+                .is_none_or(|expires_at| now + REFRESH_MARGIN < expires_at);
+            if fresh {
+                return Ok(cached.client.clone());
+            }
+        }
+
+        let (client, expires_at) = match &self.role {
+            Some(role) => {
+                let credentials = self.sts.assume_role(role).await?;
+                let expires_at = credentials.expiry();
+                info!(
+                    "Assumed role {} as session {}",
+                    role.role_arn, role.session_name
+                );
+                ((self.build)(Some(credentials)), expires_at)
+            }
+            None => ((self.build)(None), None),
+        };
+        *cached = Some(Cached {
+            client: client.clone(),
+            expires_at,
+        });
+        Ok(client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    const NOW: Duration = Duration::from_secs(1_718_020_865);
+
+    /// Hands out credentials valid for an hour from `NOW`, numbered by call
+    #[derive(Default)]
+    struct MockSts {
+        calls: AtomicUsize,
+        roles: std::sync::Mutex<Vec<RoleConfig>>,
+    }
+
+    impl AssumeRole for Arc<MockSts> {
+        async fn assume_role(&self, role: &RoleConfig) -> Result<Credentials> {
+            let call = self.calls.fetch_add(1, Ordering::SeqCst) + 1;
+            self.roles.lock().unwrap().push(role.clone());
+            // Long enough for concurrent callers to pile up behind the refresh
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            Ok(Credentials::new(
+                format!("ASIA{}", call),
+                "secret",
+                Some("token".to_string()),
+                Some(SystemTime::UNIX_EPOCH + NOW + Duration::from_secs(3600)),
+                "mock",
+            ))
+        }
+    }
+
+    fn role(arn: &str) -> RoleConfig {
+        RoleConfig {
+            role_arn: arn.to_string(),
+            session_name: DEFAULT_SESSION_NAME.to_string(),
+            external_id: None,
+        }
+    }
+
+    /// A "client" that is the access key it was built with
+    fn client(role: Option<RoleConfig>, sts: &Arc<MockSts>) -> RoleClient<String, Arc<MockSts>> {
+        RoleClient::new(role, Arc::clone(sts), |credentials| {
+            credentials.map_or("default".to_string(), |credentials| {
+                credentials.access_key_id().to_string()
+            })
+        })
+    }
+
+    #[tokio::test]
+    async fn test_credentials_are_refreshed_before_expiry() {
+        let sts = Arc::new(MockSts::default());
+        let client = client(Some(role("arn:aws:iam::210987654321:role/dlq")), &sts);
+        let at = |secs: u64| SystemTime::UNIX_EPOCH + NOW + Duration::from_secs(secs);
+
+        assert_eq!("ASIA1", client.client_at(at(0)).await.unwrap());
+        // Cached while the credentials have more than the margin left
+        assert_eq!("ASIA1", client.client_at(at(3000)).await.unwrap());
+        assert_eq!(1, sts.calls.load(Ordering::SeqCst));
+
+        // Within five minutes of expiring: the client is rebuilt with new ones
+        assert_eq!("ASIA2", client.client_at(at(3400)).await.unwrap());
+        assert_eq!(2, sts.calls.load(Ordering::SeqCst));
+        for assumed in sts.roles.lock().unwrap().iter() {
+            assert_eq!("arn:aws:iam::210987654321:role/dlq", assumed.role_arn);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_callers_share_one_refresh() {
+        let sts = Arc::new(MockSts::default());
+        let client = Arc::new(client(
+            Some(role("arn:aws:iam::210987654321:role/dlq")),
+            &sts,
+        ));
+        let at = SystemTime::UNIX_EPOCH + NOW;
+
+        let tasks: Vec<_> = (0..8)
+            .map(|_| {
+                let client = Arc::clone(&client);
+                tokio::spawn(async move { client.client_at(at).await.unwrap() })
+            })
+            .collect();
+        for task in tasks {
+            assert_eq!("ASIA1", task.await.unwrap());
+        }
+        assert_eq!(1, sts.calls.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_no_role_uses_the_default_credentials() {
+        let sts = Arc::new(MockSts::default());
+        let client = client(None, &sts);
+        assert_eq!("default", client.client().await.unwrap());
+        assert_eq!("default", client.client().await.unwrap());
+        assert_eq!(0, sts.calls.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_per_integration_roles() {
+        let env: HashMap<&str, &str> = HashMap::from([
+            ("ASSUME_ROLE_ARN", "arn:aws:iam::210987654321:role/ingestor"),
+            ("ASSUME_ROLE_EXTERNAL_ID", "zerobus-7f3a"),
+            (
+                "METRICS_ASSUME_ROLE_ARN",
+                "arn:aws:iam::345678901234:role/metrics",
+            ),
+            ("METRICS_ASSUME_ROLE_SESSION_NAME", "sqs-ingestor-metrics"),
+            ("METRICS_ASSUME_ROLE_EXTERNAL_ID", " "),
+        ]);
+        let lookup = |name: &str| env.get(name).map(|value| value.to_string());
+
+        assert_eq!(
+            Some(RoleConfig {
+                role_arn: "arn:aws:iam::210987654321:role/ingestor".to_string(),
+                session_name: DEFAULT_SESSION_NAME.to_string(),
+                external_id: Some("zerobus-7f3a".to_string()),
+            }),
+            RoleConfig::from_lookup("DLQ", lookup).unwrap()
+        );
+        // A blank override falls back to the shared value
+        assert_eq!(
+            Some(RoleConfig {
+                role_arn: "arn:aws:iam::345678901234:role/metrics".to_string(),
+                session_name: "sqs-ingestor-metrics".to_string(),
+                external_id: Some("zerobus-7f3a".to_string()),
+            }),
+            RoleConfig::from_lookup("METRICS", lookup).unwrap()
+        );
+
+        assert_eq!(None, RoleConfig::from_lookup("DLQ", |_| None).unwrap());
+        assert!(RoleConfig::from_lookup("DLQ", |name| {
+            (name == "DLQ_ASSUME_ROLE_ARN").then(|| "ingestor".to_string())
+        })
+        .is_err());
+    }
+}
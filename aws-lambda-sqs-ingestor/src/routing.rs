@@ -0,0 +1,170 @@
+//! Per-queue routing for functions that receive records from more than one queue
+
+use aws_lambda_events::sqs::SqsMessage;
+use std::collections::HashMap;
+use tracing::warn;
+
+/// Maps source queues to target tables, selected by `QUEUE_TABLE_MAP`
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueueRoutes {
+    /// Table for queues without a route
+    default_table: String,
+    /// Keyed by queue ARN or queue name
+    routes: HashMap<String, String>,
+}
+
+impl QueueRoutes {
+    /// Routes from `QUEUE_TABLE_MAP`, falling back to `default_table`
+    pub fn from_env(default_table: &str) -> Self {
+        let map = std::env::var("QUEUE_TABLE_MAP").unwrap_or_default();
+        Self::new(default_table, &map)
+    }
+
+    /// Parse comma-separated `<queue>=<table>` pairs, where `<queue>` is a queue ARN or
+    /// a queue name
+    pub fn new(default_table: &str, map: &str) -> Self {
+        let routes = map
+            .split(',')
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty())
+            .filter_map(|pair| match pair.split_once('=') {
+                Some((queue, table)) if !queue.trim().is_empty() && !table.trim().is_empty() => {
+                    Some((queue.trim().to_string(), table.trim().to_string()))
+                }
+                _ => {
+                    warn!("Ignoring malformed QUEUE_TABLE_MAP entry {:?}", pair);
+                    None
+                }
+            })
+            .collect();
+        Self {
+            default_table: default_table.to_string(),
+            routes,
+        }
+    }
+
+    /// Target table for records from `event_source_arn`; an ARN route wins over a name route
+    pub fn table_for(&self, event_source_arn: &str) -> &str {
+        self.routes
+            .get(event_source_arn)
+            .or_else(|| self.routes.get(queue_name(event_source_arn)))
+            .unwrap_or(&self.default_table)
+    }
+}
+
+/// Last segment of a queue ARN (`arn:aws:sqs:<region>:<account>:<name>`)
+pub fn queue_name(event_source_arn: &str) -> &str {
+    event_source_arn
+        .rsplit(':')
+        .next()
+        .unwrap_or(event_source_arn)
+}
+
+/// Region of a record, from `awsRegion` or else from its queue ARN
+pub fn record_region(message: &SqsMessage) -> String {
+    message
+        .aws_region
+        .clone()
+        .filter(|region| !region.is_empty())
+        .or_else(|| {
+            let arn = message.event_source_arn.as_deref()?;
+            arn.split(':')
+                .nth(3)
+                .filter(|region| !region.is_empty())
+                .map(str::to_string)
+        })
+        .unwrap_or_default()
+}
+
+/// Records of one queue
+#[derive(Debug)]
+pub struct QueueBatch {
+    pub event_source_arn: String,
+    pub table_name: String,
+    pub records: Vec<SqsMessage>,
+}
+
+/// Split a batch by source queue, in the order each queue first appears
+///
+/// Records keep their relative order within a queue, so FIFO ordering is preserved.
+pub fn group_by_queue(records: &[SqsMessage], routes: &QueueRoutes) -> Vec<QueueBatch> {
+    let mut batches: Vec<QueueBatch> = Vec::new();
+    for record in records {
+        let arn = record.event_source_arn.as_deref().unwrap_or_default();
+        match batches.iter_mut().find(|batch| batch.event_source_arn == arn) {
+            Some(batch) => batch.records.push(record.clone()),
+            None => batches.push(QueueBatch {
+                event_source_arn: arn.to_string(),
+                table_name: routes.table_for(arn).to_string(),
+                records: vec![record.clone()],
+            }),
+        }
+    }
+    batches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORDERS: &str = "arn:aws:sqs:us-west-2:123456789012:orders";
+    const RETURNS: &str = "arn:aws:sqs:eu-west-1:123456789012:returns";
+
+    fn record(id: &str, arn: &str) -> SqsMessage {
+        SqsMessage {
+            message_id: Some(id.to_string()),
+            event_source_arn: Some(arn.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_routes_by_arn_then_name() {
+        let routes = QueueRoutes::new(
+            "main.default.sqs",
+            &format!("returns=main.default.returns, {}=main.default.orders, oops", ORDERS),
+        );
+
+        assert_eq!("main.default.orders", routes.table_for(ORDERS));
+        assert_eq!("main.default.returns", routes.table_for(RETURNS));
+        assert_eq!(
+            "main.default.sqs",
+            routes.table_for("arn:aws:sqs:us-west-2:123456789012:other")
+        );
+    }
+
+    #[test]
+    fn test_group_by_queue_keeps_order() {
+        let routes = QueueRoutes::new("main.default.sqs", "returns=main.default.returns");
+        let records = vec![
+            record("1", ORDERS),
+            record("2", RETURNS),
+            record("3", ORDERS),
+        ];
+
+        let batches = group_by_queue(&records, &routes);
+
+        let grouped: Vec<(&str, &str, Vec<&str>)> = batches
+            .iter()
+            .map(|batch| {
+                (
+                    queue_name(&batch.event_source_arn),
+                    batch.table_name.as_str(),
+                    batch
+                        .records
+                        .iter()
+                        .filter_map(|r| r.message_id.as_deref())
+                        .collect(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                ("orders", "main.default.sqs", vec!["1", "3"]),
+                ("returns", "main.default.returns", vec!["2"]),
+            ],
+            grouped
+        );
+        assert_eq!("eu-west-1", record_region(&records[1]));
+    }
+}
@@ -0,0 +1,292 @@
+//! Extracting the fields of AWS notifications carried in SQS bodies, selected by
+//! `BODY_UNWRAP`
+
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Which notification the bodies of a queue carry
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Unwrap {
+    /// SES notifications, event publishing records, and received-mail notifications,
+    /// delivered directly or inside an SNS envelope
+    Ses,
+    /// S3 Batch Operations Lambda invocation payloads (schema 1.0 and 2.0)
+    S3Batch,
+}
+
+/// The fields of an SES notification
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SesNotification {
+    pub message_id: Option<String>,
+    /// `notificationType` (Bounce, Complaint, Delivery, Received) or `eventType`
+    /// (Send, Open, Click, ...)
+    pub event_type: Option<String>,
+    pub source: Option<String>,
+    pub destination: Vec<String>,
+    pub subject: Option<String>,
+    pub timestamp: Option<String>,
+    /// Mail headers by name; repeated headers such as `Received` are joined with `\n`
+    pub headers: HashMap<String, String>,
+}
+
+/// The task of an S3 Batch Operations invocation
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct S3BatchTask {
+    pub schema_version: Option<String>,
+    pub invocation_id: Option<String>,
+    pub job_id: Option<String>,
+    pub task_id: Option<String>,
+    /// Bucket name, from `s3Bucket` or the name part of `s3BucketArn`
+    pub bucket: Option<String>,
+    pub key: Option<String>,
+    pub version_id: Option<String>,
+}
+
+/// What was extracted from a body
+#[derive(Debug, Clone, PartialEq)]
+pub enum Unwrapped {
+    Ses(SesNotification),
+    S3Batch(S3BatchTask),
+}
+
+impl Unwrap {
+    /// Read the mode from `BODY_UNWRAP`
+    ///
+    /// Returns `None` when the variable is unset or empty, in which case nothing is
+    /// extracted.
+    pub fn from_env() -> Result<Option<Self>> {
+        match std::env::var("BODY_UNWRAP") {
+            Ok(mode) if !mode.trim().is_empty() => Self::new(&mode).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    pub fn new(mode: &str) -> Result<Self> {
+        match mode.trim().to_ascii_lowercase().as_str() {
+            "ses" => Ok(Unwrap::Ses),
+            "s3batch" | "s3-batch" => Ok(Unwrap::S3Batch),
+            other => bail!(
+                "Unsupported BODY_UNWRAP {:?}; expected ses or s3batch",
+                other
+            ),
+        }
+    }
+
+    /// Extract the fields of the notification in `body`
+    pub fn extract(&self, body: &str) -> Result<Unwrapped> {
+        let value: Value = serde_json::from_str(body).context("Body is not JSON")?;
+        match self {
+            Unwrap::Ses => ses(&sns_message(value)?).map(Unwrapped::Ses),
+            Unwrap::S3Batch => s3_batch(&value).map(Unwrapped::S3Batch),
+        }
+    }
+}
+
+/// The `Message` of an SNS notification envelope, or `value` itself when the topic
+/// subscription uses raw message delivery
+fn sns_message(value: Value) -> Result<Value> {
+    let is_envelope = value.get("Type").and_then(Value::as_str) == Some("Notification");
+    match value.get("Message").and_then(Value::as_str) {
+        Some(message) if is_envelope => {
+            serde_json::from_str(message).context("SNS Message is not JSON")
+        }
+        _ => Ok(value),
+    }
+}
+
+fn string_at(value: &Value, pointer: &str) -> Option<String> {
+    value
+        .pointer(pointer)
+        .and_then(Value::as_str)
+        .map(str::to_string)
+}
+
+fn ses(value: &Value) -> Result<SesNotification> {
+    let mail = value
+        .get("mail")
+        .context("Not an SES notification: no mail")?;
+    let event_type =
+        string_at(value, "/notificationType").or_else(|| string_at(value, "/eventType"));
+
+    let mut headers: HashMap<String, String> = HashMap::new();
+    for header in mail
+        .get("headers")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+    {
+        let (Some(name), Some(value)) = (
+            header.get("name").and_then(Value::as_str),
+            header.get("value").and_then(Value::as_str),
+        ) else {
+            continue;
+        };
+        headers
+            .entry(name.to_string())
+            .and_modify(|joined| {
+                joined.push('\n');
+                joined.push_str(value);
+            })
+            .or_insert_with(|| value.to_string());
+    }
+
+    Ok(SesNotification {
+        message_id: string_at(mail, "/messageId"),
+        event_type,
+        source: string_at(mail, "/source"),
+        destination: mail
+            .get("destination")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+            .map(str::to_string)
+            .collect(),
+        subject: string_at(mail, "/commonHeaders/subject"),
+        timestamp: string_at(mail, "/timestamp"),
+        headers,
+    })
+}
+
+fn s3_batch(value: &Value) -> Result<S3BatchTask> {
+    let tasks = value
+        .get("tasks")
+        .and_then(Value::as_array)
+        .context("Not an S3 Batch Operations invocation: no tasks")?;
+    // S3 Batch Operations invokes Lambda with exactly one task
+    let task = tasks
+        .first()
+        .context("S3 Batch Operations invocation has no task")?;
+    let bucket = string_at(task, "/s3Bucket").or_else(|| {
+        string_at(task, "/s3BucketArn")
+            .map(|arn| arn.rsplit(':').next().unwrap_or(&arn).to_string())
+    });
+
+    Ok(S3BatchTask {
+        schema_version: string_at(value, "/invocationSchemaVersion"),
+        invocation_id: string_at(value, "/invocationId"),
+        job_id: string_at(value, "/job/id"),
+        task_id: string_at(task, "/taskId"),
+        bucket,
+        key: string_at(task, "/s3Key"),
+        version_id: string_at(task, "/s3VersionId"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn ses_bounce() -> Value {
+        json!({
+            "notificationType": "Bounce",
+            "bounce": {"bounceType": "Permanent", "bouncedRecipients": [{"emailAddress": "nobody@example.com"}]},
+            "mail": {
+                "timestamp": "2024-06-10T12:00:00.000Z",
+                "source": "sender@example.com",
+                "messageId": "0100018f-aaaa-bbbb",
+                "destination": ["nobody@example.com", "team@example.com"],
+                "headers": [
+                    {"name": "Received", "value": "from a"},
+                    {"name": "Received", "value": "from b"},
+                    {"name": "Subject", "value": "Your order"}
+                ],
+                "commonHeaders": {"from": ["sender@example.com"], "subject": "Your order"}
+            }
+        })
+    }
+
+    #[test]
+    fn test_ses_notification() {
+        let Unwrapped::Ses(ses) = Unwrap::Ses.extract(&ses_bounce().to_string()).unwrap() else {
+            panic!("expected an SES notification");
+        };
+
+        assert_eq!(Some("0100018f-aaaa-bbbb".to_string()), ses.message_id);
+        assert_eq!(Some("Bounce".to_string()), ses.event_type);
+        assert_eq!(Some("sender@example.com".to_string()), ses.source);
+        assert_eq!(
+            vec!["nobody@example.com", "team@example.com"],
+            ses.destination
+        );
+        assert_eq!(Some("Your order".to_string()), ses.subject);
+        assert_eq!(Some("2024-06-10T12:00:00.000Z".to_string()), ses.timestamp);
+        assert_eq!("from a\nfrom b", ses.headers["Received"]);
+        assert_eq!("Your order", ses.headers["Subject"]);
+    }
+
+    #[test]
+    fn test_ses_inside_sns_envelope() {
+        let mut event = ses_bounce();
+        event.as_object_mut().unwrap().remove("notificationType");
+        event["eventType"] = json!("Open");
+        let envelope = json!({
+            "Type": "Notification",
+            "MessageId": "sns-1",
+            "TopicArn": "arn:aws:sns:us-west-2:123456789012:ses-events",
+            "Message": event.to_string()
+        });
+
+        let Unwrapped::Ses(ses) = Unwrap::Ses.extract(&envelope.to_string()).unwrap() else {
+            panic!("expected an SES notification");
+        };
+        assert_eq!(Some("0100018f-aaaa-bbbb".to_string()), ses.message_id);
+        assert_eq!(Some("Open".to_string()), ses.event_type);
+
+        assert!(Unwrap::Ses
+            .extract(r#"{"Type": "Notification", "Message": "hi"}"#)
+            .is_err());
+        assert!(Unwrap::Ses.extract(r#"{"order": 7}"#).is_err());
+    }
+
+    #[test]
+    fn test_s3_batch_task() {
+        let v1 = json!({
+            "invocationSchemaVersion": "1.0",
+            "invocationId": "YXNkbGZqYWRmaiBhc2RmdW9hZHNmZGpmaGFzbGtkaGZza2RmaAo",
+            "job": {"id": "f3cc4f60-61f6-4a2b-8a21-d07600c373ce"},
+            "tasks": [{
+                "taskId": "dGFza2lkZ29lc2hlcmUK",
+                "s3Key": "customerImage1.jpg",
+                "s3VersionId": "1",
+                "s3BucketArn": "arn:aws:s3:::amzn-s3-demo-bucket"
+            }]
+        });
+        let Unwrapped::S3Batch(task) = Unwrap::S3Batch.extract(&v1.to_string()).unwrap() else {
+            panic!("expected an S3 Batch task");
+        };
+        assert_eq!(Some("1.0".to_string()), task.schema_version);
+        assert_eq!(
+            Some("f3cc4f60-61f6-4a2b-8a21-d07600c373ce".to_string()),
+            task.job_id
+        );
+        assert_eq!(Some("dGFza2lkZ29lc2hlcmUK".to_string()), task.task_id);
+        assert_eq!(Some("amzn-s3-demo-bucket".to_string()), task.bucket);
+        assert_eq!(Some("customerImage1.jpg".to_string()), task.key);
+        assert_eq!(Some("1".to_string()), task.version_id);
+
+        // Schema 2.0 names the bucket directly and may omit the version
+        let v2 = json!({
+            "invocationSchemaVersion": "2.0",
+            "invocationId": "inv-2",
+            "job": {"id": "job-2", "userArguments": {"k": "v"}},
+            "tasks": [{"taskId": "t-2", "s3Bucket": "reports", "s3Key": "2024/06/10.csv", "s3VersionId": null}]
+        });
+        let Unwrapped::S3Batch(task) = Unwrap::S3Batch.extract(&v2.to_string()).unwrap() else {
+            panic!("expected an S3 Batch task");
+        };
+        assert_eq!(Some("reports".to_string()), task.bucket);
+        assert_eq!(None, task.version_id);
+
+        assert!(Unwrap::S3Batch.extract(r#"{"tasks": []}"#).is_err());
+    }
+
+    #[test]
+    fn test_unwrap_modes() {
+        assert_eq!(Unwrap::Ses, Unwrap::new("SES").unwrap());
+        assert_eq!(Unwrap::S3Batch, Unwrap::new("s3batch").unwrap());
+        assert!(Unwrap::new("sns").is_err());
+    }
+}
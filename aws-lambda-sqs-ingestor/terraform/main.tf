@@ -41,7 +41,7 @@ resource "aws_iam_role_policy" "lambda_policy" {
 
   policy = jsonencode({
     Version = "2012-10-17"
-    Statement = [
+    Statement = concat([
       {
         Effect = "Allow"
         Action = [
@@ -55,6 +55,11 @@ resource "aws_iam_role_policy" "lambda_policy" {
           aws_sqs_queue.dlq.arn
         ]
       },
+      {
+        Effect   = "Allow"
+        Action   = "sqs:SendMessage"
+        Resource = aws_sqs_queue.dlq.arn
+      },
       {
         Effect = "Allow"
         Action = [
@@ -63,8 +68,24 @@ resource "aws_iam_role_policy" "lambda_policy" {
           "logs:PutLogEvents"
         ]
         Resource = "arn:aws:logs:${var.aws_region}:*:*"
+      },
+      {
+        Effect   = "Allow"
+        Action   = "cloudwatch:PutMetricData"
+        Resource = "*"
+        Condition = {
+          StringEquals = {
+            "cloudwatch:namespace" = var.metrics_namespace
+          }
+        }
       }
-    ]
+      ], var.assume_role_arn == "" ? [] : [
+      {
+        Effect   = "Allow"
+        Action   = "sts:AssumeRole"
+        Resource = var.assume_role_arn
+      }
+    ])
   })
 }
 
@@ -89,11 +110,32 @@ resource "aws_lambda_function" "sqs_ingestor" {
 
   environment {
     variables = {
-      DATABRICKS_HOST          = var.databricks_host
-      DATABRICKS_CLIENT_ID     = var.databricks_client_id
-      DATABRICKS_CLIENT_SECRET = var.databricks_client_secret
-      ZEROBUS_ENDPOINT         = var.zerobus_endpoint
-      TABLE_NAME               = var.table_name
+      DATABRICKS_HOST           = var.databricks_host
+      DATABRICKS_CLIENT_ID      = var.databricks_client_id
+      DATABRICKS_CLIENT_SECRET  = var.databricks_client_secret
+      ZEROBUS_ENDPOINT          = var.zerobus_endpoint
+      TABLE_NAME                = var.table_name
+      FLUSH_EVERY_N             = tostring(var.flush_every_n)
+      STAMP_VERSION             = tostring(var.stamp_version)
+      AUDIT_TABLE               = var.audit_table
+      BODY_CONTENT_TYPE         = var.body_content_type
+      BODY_CSV_HEADER           = var.body_csv_header
+      BODY_UNWRAP               = var.body_unwrap
+      COMPRESS_PAYLOAD          = var.compress_payload
+      SPLIT_LARGE_RECORDS       = tostring(var.split_large_records)
+      MAX_RECORD_BYTES          = tostring(var.max_record_bytes)
+      QUEUE_TABLE_MAP           = var.queue_table_map
+      DEDUP_BY_DEDUPLICATION_ID = tostring(var.dedup_by_deduplication_id)
+      VERIFY_MD5                = tostring(var.verify_md5)
+      ATTR_INCLUDE_PREFIX       = var.attr_include_prefix
+      ATTR_EXCLUDE_PREFIX       = var.attr_exclude_prefix
+      METRICS_SINK              = var.metrics_sink
+      METRICS_NAMESPACE         = var.metrics_namespace
+      DLQ_URL                   = var.forward_to_dlq ? aws_sqs_queue.dlq.url : ""
+      DLQ_MAX_RECEIVE_COUNT     = tostring(var.dlq_max_receive_count)
+      DLQ_ENCODING              = var.dlq_encoding
+      ASSUME_ROLE_ARN           = var.assume_role_arn
+      ASSUME_ROLE_EXTERNAL_ID   = var.assume_role_external_id
     }
   }
 
@@ -86,8 +86,143 @@ variable "dlq_max_receive_count" {
   default     = 3
 }
 
+variable "forward_to_dlq" {
+  description = "Have the function send messages that fail their last attempt to the DLQ itself, with their source metadata, instead of leaving them to the redrive policy"
+  type        = bool
+  default     = false
+}
+
+variable "dlq_encoding" {
+  description = "What messages forwarded to the DLQ carry besides the original body: metadata (source ARN, target table, error, and system attributes as message attributes) or body (original message attributes only)"
+  type        = string
+  default     = "metadata"
+
+  validation {
+    condition     = contains(["metadata", "body"], var.dlq_encoding)
+    error_message = "dlq_encoding must be \"metadata\" or \"body\"."
+  }
+}
+
+variable "assume_role_arn" {
+  description = "Role in another account to send to the DLQ and publish metrics as (empty uses the function's own role)"
+  type        = string
+  default     = ""
+}
+
+variable "assume_role_external_id" {
+  description = "External ID the assumed role's trust policy requires (empty sends none)"
+  type        = string
+  default     = ""
+}
+
 variable "log_retention_days" {
   description = "CloudWatch log retention in days"
   type        = number
   default     = 7
 }
+
+variable "flush_every_n" {
+  description = "Flush the Zerobus stream every N records within a batch (0 disables intra-batch flushing)"
+  type        = number
+  default     = 0
+}
+
+variable "stamp_version" {
+  description = "Write the ingestor version and git commit into the pipeline_version column of every row"
+  type        = bool
+  default     = false
+}
+
+variable "audit_table" {
+  description = "Unity Catalog table for per-batch audit rows (empty disables auditing)"
+  type        = string
+  default     = ""
+}
+
+variable "body_content_type" {
+  description = "Encoding of message bodies to parse into the body_json column: json, form, or csv (empty disables parsing)"
+  type        = string
+  default     = ""
+}
+
+variable "body_csv_header" {
+  description = "Comma-separated column names for csv bodies (empty uses the first row of each body)"
+  type        = string
+  default     = ""
+}
+
+variable "body_unwrap" {
+  description = "Notification whose fields are extracted from message bodies into typed columns: ses or s3batch (empty disables extraction)"
+  type        = string
+  default     = ""
+}
+
+variable "dedup_by_deduplication_id" {
+  description = "Skip FIFO messages whose MessageDeduplicationId was already ingested"
+  type        = bool
+  default     = false
+}
+
+variable "verify_md5" {
+  description = "Fail messages whose body or message attributes do not match their MD5 digests"
+  type        = bool
+  default     = false
+}
+
+variable "attr_include_prefix" {
+  description = "Comma-separated prefixes of the message attributes to store (empty stores every attribute)"
+  type        = string
+  default     = ""
+}
+
+variable "attr_exclude_prefix" {
+  description = "Comma-separated prefixes of message attributes not to store"
+  type        = string
+  default     = ""
+}
+
+variable "queue_table_map" {
+  description = "Comma-separated <queue>=<table> pairs routing records from other queues to other tables; <queue> is a queue ARN or name (empty sends every queue to table_name)"
+  type        = string
+  default     = ""
+}
+
+variable "compress_payload" {
+  description = "Codec for storing message bodies compressed in body_compressed: gzip or zstd (empty stores plain strings)"
+  type        = string
+  default     = ""
+
+  validation {
+    condition     = contains(["", "gzip", "zstd"], var.compress_payload)
+    error_message = "compress_payload must be \"\", \"gzip\", or \"zstd\"."
+  }
+}
+
+variable "split_large_records" {
+  description = "Split rows over max_record_bytes into chunks of the body instead of sending them whole"
+  type        = bool
+  default     = false
+}
+
+variable "max_record_bytes" {
+  description = "Largest encoded row sent whole when split_large_records is on"
+  type        = number
+  default     = 1048576
+}
+
+variable "metrics_sink" {
+  description = "Where invocation metrics go: emf (log lines) or cloudwatch_api (PutMetricData)"
+  type        = string
+  default     = "emf"
+
+  validation {
+    condition     = contains(["emf", "cloudwatch_api"], var.metrics_sink)
+    error_message = "metrics_sink must be \"emf\" or \"cloudwatch_api\"."
+  }
+}
+
+variable "metrics_namespace" {
+  description = "CloudWatch namespace of the invocation metrics"
+  type        = string
+  default     = "Zerobus/SqsIngestor"
+}
@@ -0,0 +1,43 @@
+//! The compiled `table_sqs_messages` descriptor against the live table named by
+//! `SCHEMA_CHECK_TABLE`
+//!
+//! The handler loads its descriptor in `main.rs`, so this reads the same embedded files,
+//! picking the one for `ENVIRONMENT` as the handler does. Needs `DATABRICKS_HOST` and the credentials the function is deployed with; without
+//! `SCHEMA_CHECK_TABLE` the test passes without checking anything.
+
+use zerobus_common::auth::StreamAuth;
+use zerobus_common::descriptor::load_environment_descriptor_proto;
+use zerobus_common::schema_check;
+
+const DESCRIPTOR_SETS: &[(&str, &[u8])] = &[
+    (
+        "dev",
+        include_bytes!("../gen/descriptors/sqs_messages_dev.descriptor"),
+    ),
+    (
+        "prod",
+        include_bytes!("../gen/descriptors/sqs_messages_prod.descriptor"),
+    ),
+];
+
+#[tokio::test]
+async fn test_descriptor_matches_the_live_table() {
+    let Ok(table_name) = std::env::var("SCHEMA_CHECK_TABLE") else {
+        eprintln!("SCHEMA_CHECK_TABLE is not set, skipping the live schema check");
+        return;
+    };
+    let databricks_host = std::env::var("DATABRICKS_HOST").expect("DATABRICKS_HOST must be set");
+    let auth = StreamAuth::from_env().await.unwrap();
+    let descriptor_proto = load_environment_descriptor_proto(
+        DESCRIPTOR_SETS,
+        "sqs_messages.proto",
+        "table_sqs_messages",
+    )
+    .unwrap();
+
+    if let Err(error) =
+        schema_check::check(&databricks_host, &auth, &table_name, &descriptor_proto).await
+    {
+        panic!("{:#}", error);
+    }
+}
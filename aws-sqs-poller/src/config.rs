@@ -0,0 +1,185 @@
+//! Queues, read from the JSON file named by SQS_POLLER_CONFIG
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use zerobus_common::router::TableRouter;
+
+/// How often queue backlogs are probed when the config does not say
+const DEFAULT_PROBE_INTERVAL_SECS: u64 = 30;
+
+/// Long poll wait of each receive when a queue does not say; the SQS maximum
+const DEFAULT_WAIT_TIME_SECS: i32 = 20;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Receives in flight at once across all queues, shared out by the scheduler
+    pub receive_capacity: usize,
+    /// How often each queue's `ApproximateNumberOfMessages` is read
+    #[serde(default = "default_probe_interval_secs")]
+    pub probe_interval_secs: u64,
+    /// Descriptor set holding a `table_<name>` message per target table
+    pub descriptor_set: PathBuf,
+    /// Table for queues without a `table` of their own
+    #[serde(default)]
+    pub default_table: Option<String>,
+    pub queues: Vec<QueueConfig>,
+}
+
+/// One queue to consume, and how much of the receive capacity it may take
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QueueConfig {
+    /// Names the queue in logs and metrics, and is its routing key
+    pub name: String,
+    pub url: String,
+    /// Share of the receive capacity relative to the other queues with a backlog
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+    /// Most receives in flight on this queue at once
+    #[serde(default = "default_concurrency")]
+    pub concurrency: usize,
+    /// Target table (default: `default_table`)
+    #[serde(default)]
+    pub table: Option<String>,
+    /// Drop message fields that are not columns instead of leaving the message on the
+    /// queue
+    #[serde(default)]
+    pub ignore_unknown_fields: bool,
+    #[serde(default = "default_wait_time_secs")]
+    pub wait_time_secs: i32,
+}
+
+fn default_probe_interval_secs() -> u64 {
+    DEFAULT_PROBE_INTERVAL_SECS
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+fn default_concurrency() -> usize {
+    1
+}
+
+fn default_wait_time_secs() -> i32 {
+    DEFAULT_WAIT_TIME_SECS
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let json = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Self::parse(&json).with_context(|| format!("Invalid config {}", path.display()))
+    }
+
+    pub fn parse(json: &str) -> Result<Self> {
+        let config: Config = serde_json::from_str(json)?;
+        if config.queues.is_empty() {
+            bail!("No queues are configured");
+        }
+        if config.receive_capacity == 0 {
+            bail!("receive_capacity must be positive");
+        }
+        if config.probe_interval_secs == 0 {
+            bail!("probe_interval_secs must be positive");
+        }
+
+        let router = config.router();
+        let mut names = HashSet::new();
+        for queue in &config.queues {
+            if !names.insert(queue.name.as_str()) {
+                bail!("Queue name {:?} is used twice", queue.name);
+            }
+            if queue.weight == 0 {
+                bail!("Queue {:?}: weight must be positive", queue.name);
+            }
+            if queue.concurrency == 0 {
+                bail!("Queue {:?}: concurrency must be positive", queue.name);
+            }
+            if !(0..=20).contains(&queue.wait_time_secs) {
+                bail!(
+                    "Queue {:?}: wait_time_secs must be between 0 and 20",
+                    queue.name
+                );
+            }
+            if router.route(&queue.name).is_none() {
+                bail!(
+                    "Queue {:?} has no table and there is no default_table",
+                    queue.name
+                );
+            }
+        }
+        Ok(config)
+    }
+
+    /// Routes from each queue's name to its table
+    pub fn router(&self) -> TableRouter {
+        TableRouter::from_routes(
+            self.queues
+                .iter()
+                .filter_map(|queue| queue.table.clone().map(|table| (queue.name.clone(), table))),
+            self.default_table.clone(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXAMPLE: &str = include_str!("../config/queues.example.json");
+
+    #[test]
+    fn test_example_config() {
+        let config = Config::parse(EXAMPLE).unwrap();
+
+        assert_eq!(12, config.receive_capacity);
+        assert_eq!(15, config.probe_interval_secs);
+        let priority = &config.queues[0];
+        assert_eq!(4, priority.weight);
+        assert_eq!(8, priority.concurrency);
+        assert_eq!(20, priority.wait_time_secs);
+
+        let audit = &config.queues[2];
+        assert_eq!(1, audit.weight);
+        assert_eq!(1, audit.concurrency);
+        assert!(audit.ignore_unknown_fields);
+
+        let router = config.router();
+        assert_eq!(Some("main.sqs.orders"), router.route("orders-bulk"));
+        assert_eq!(Some("main.sqs.events"), router.route("audit"));
+    }
+
+    #[test]
+    fn test_invalid_configs() {
+        let queue = r#"{"name": "a", "url": "https://sqs/a", "table": "main.sqs.a"}"#;
+        let config = |capacity: &str, queues: &str| {
+            Config::parse(&format!(
+                r#"{{"receive_capacity": {}, "descriptor_set": "t.descriptor", "queues": [{}]}}"#,
+                capacity, queues
+            ))
+        };
+        assert!(config("4", queue).is_ok());
+
+        for (capacity, queues) in [
+            ("0", queue.to_string()),
+            ("4", String::new()),
+            ("4", format!("{},{}", queue, queue)),
+            ("4", queue.replace(r#""table""#, r#""weight": 0, "table""#)),
+            (
+                "4",
+                queue.replace(r#""table""#, r#""concurrency": 0, "table""#),
+            ),
+            (
+                "4",
+                queue.replace(r#""table""#, r#""wait_time_secs": 30, "table""#),
+            ),
+            ("4", r#"{"name": "a", "url": "https://sqs/a"}"#.to_string()),
+        ] {
+            assert!(config(capacity, &queues).is_err(), "{}", queues);
+        }
+    }
+}
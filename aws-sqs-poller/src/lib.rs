@@ -0,0 +1,5 @@
+pub mod config;
+pub mod metrics;
+pub mod scheduler;
+pub mod sqs;
+pub mod worker;
@@ -0,0 +1,261 @@
+use anyhow::{bail, Context, Result};
+use aws_sqs_poller::config::Config;
+use aws_sqs_poller::metrics::Metrics;
+use aws_sqs_poller::worker::{run_scheduler, run_worker, Queue};
+use axum::routing::get;
+use axum::Router;
+use databricks_zerobus_ingest_sdk::{
+    StreamConfigurationOptions, TableProperties, ZerobusSdk, ZerobusStream,
+};
+use prost_types::DescriptorProto;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{watch, Mutex};
+use tokio::task::JoinHandle;
+use tracing::{error, info};
+use zerobus_common::auth::StreamAuth;
+use zerobus_common::descriptor::find_message_descriptor;
+use zerobus_common::dynamic::DynamicEncoder;
+use zerobus_common::endpoint::zerobus_endpoint_from_env;
+use zerobus_common::log_level;
+use zerobus_common::pipeline::{max_pending_bytes_from_env, Pipeline};
+use zerobus_common::router::message_name;
+use zerobus_common::shutdown;
+
+/// Maximum number of unacknowledged records per stream
+const MAX_INFLIGHT_RECORDS: usize = 10_000;
+
+/// Address metrics are served on when METRICS_ADDR is not set
+const DEFAULT_METRICS_ADDR: &str = "0.0.0.0:9090";
+
+type SharedPipeline = Arc<Mutex<Pipeline<ZerobusStream>>>;
+
+fn env(name: &str) -> Result<String> {
+    std::env::var(name).with_context(|| format!("{} environment variable must be set", name))
+}
+
+/// Opens a stream per table on first use; tables stay open until shutdown, across
+/// reconfigurations
+struct Tables {
+    sdk: ZerobusSdk,
+    auth: StreamAuth,
+    max_pending_bytes: Option<u64>,
+    pipelines: HashMap<String, SharedPipeline>,
+}
+
+impl Tables {
+    async fn pipeline(
+        &mut self,
+        table: &str,
+        descriptor: DescriptorProto,
+    ) -> Result<SharedPipeline> {
+        if let Some(pipeline) = self.pipelines.get(table) {
+            return Ok(Arc::clone(pipeline));
+        }
+        let table_properties = TableProperties {
+            table_name: table.to_string(),
+            descriptor_proto: descriptor,
+        };
+        let stream_options = StreamConfigurationOptions {
+            max_inflight_records: MAX_INFLIGHT_RECORDS,
+            ..Default::default()
+        };
+        let stream = self
+            .auth
+            .create_stream(&self.sdk, table_properties, Some(stream_options))
+            .await
+            .with_context(|| format!("Failed to create stream to {}", table))?;
+        info!("Opened stream to table: {}", table);
+        let pipeline = Arc::new(Mutex::new(
+            Pipeline::new(stream, MAX_INFLIGHT_RECORDS).max_pending_bytes(self.max_pending_bytes),
+        ));
+        self.pipelines
+            .insert(table.to_string(), Arc::clone(&pipeline));
+        Ok(pipeline)
+    }
+}
+
+/// Build a queue for each configured one, opening the streams of new tables
+async fn prepare(
+    config: &Config,
+    tables: &mut Tables,
+    metrics: &Metrics,
+) -> Result<Vec<Arc<Queue<ZerobusStream>>>> {
+    let descriptors = std::fs::read(&config.descriptor_set).with_context(|| {
+        format!(
+            "Failed to read descriptor set {}",
+            config.descriptor_set.display()
+        )
+    })?;
+    let router = config.router();
+    let mut queues = Vec::with_capacity(config.queues.len());
+    for queue in &config.queues {
+        let table = router
+            .route(&queue.name)
+            .with_context(|| format!("Queue {:?} has no table", queue.name))?;
+        let descriptor = find_message_descriptor(&descriptors, &message_name(table))
+            .with_context(|| format!("Queue {:?}", queue.name))?;
+        let encoder =
+            DynamicEncoder::new(&descriptor)?.ignore_unknown_fields(queue.ignore_unknown_fields);
+        let pipeline = tables.pipeline(table, descriptor).await?;
+        info!(
+            "Queue {:?}: {} -> {} (weight {}, concurrency {})",
+            queue.name, queue.url, table, queue.weight, queue.concurrency
+        );
+        queues.push(Arc::new(Queue::new(
+            queue.clone(),
+            encoder,
+            pipeline,
+            metrics.queue(&queue.name),
+        )));
+    }
+    Ok(queues)
+}
+
+/// The workers and scheduler of one configuration
+struct Running {
+    stop: watch::Sender<bool>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Running {
+    fn start(
+        config: &Config,
+        queues: Vec<Arc<Queue<ZerobusStream>>>,
+        client: Arc<aws_sdk_sqs::Client>,
+        metrics: &Metrics,
+    ) -> Self {
+        let (stop, stopped) = watch::channel(false);
+        let mut tasks = Vec::new();
+        let mut schedule = Vec::with_capacity(queues.len());
+        for queue in queues {
+            // Workers wait until the first probe tells them how many may receive
+            let (allowed_sender, allowed) = watch::channel(0);
+            for index in 0..queue.config.concurrency {
+                tasks.push(tokio::spawn(run_worker(
+                    Arc::clone(&queue),
+                    Arc::clone(&client),
+                    index,
+                    allowed.clone(),
+                    stopped.clone(),
+                )));
+            }
+            schedule.push((queue.config.clone(), allowed_sender));
+        }
+        tasks.push(tokio::spawn(run_scheduler(
+            schedule,
+            config.receive_capacity,
+            Duration::from_secs(config.probe_interval_secs),
+            client,
+            metrics.clone(),
+            stopped,
+        )));
+        Self { stop, tasks }
+    }
+
+    /// Stop receiving and wait for the batches being handled
+    async fn stop(self) {
+        let _ = self.stop.send(true);
+        for task in self.tasks {
+            let _ = task.await;
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let log_level = log_level::init()?;
+
+    let databricks_host = env("DATABRICKS_HOST")?;
+    let config_path = PathBuf::from(env("SQS_POLLER_CONFIG")?);
+    let metrics_addr =
+        std::env::var("METRICS_ADDR").unwrap_or_else(|_| DEFAULT_METRICS_ADDR.to_string());
+    let grace = shutdown::grace_from_env()?;
+    let config = Config::load(&config_path)?;
+
+    let auth = StreamAuth::from_env().await?;
+    let zerobus_endpoint = zerobus_endpoint_from_env(&databricks_host, &auth).await?;
+    let mut tables = Tables {
+        sdk: ZerobusSdk::new(zerobus_endpoint, databricks_host)?,
+        auth,
+        max_pending_bytes: max_pending_bytes_from_env()?,
+        pipelines: HashMap::new(),
+    };
+    let aws_config = aws_config::load_from_env().await;
+    let client = Arc::new(aws_sdk_sqs::Client::new(&aws_config));
+    let metrics = Metrics::default();
+
+    let listener = tokio::net::TcpListener::bind(&metrics_addr)
+        .await
+        .with_context(|| format!("Failed to bind {}", metrics_addr))?;
+    let served = metrics.clone();
+    let mut app = Router::new()
+        .route("/metrics", get(move || async move { served.render() }))
+        .route("/health", get(|| async { "OK" }));
+    if let Some(admin) = log_level::admin_routes_from_env(&log_level) {
+        app = app.merge(admin);
+    }
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    info!("Serving metrics on http://{}/metrics", metrics_addr);
+
+    let queues = prepare(&config, &mut tables, &metrics).await?;
+    let mut running = Running::start(&config, queues, Arc::clone(&client), &metrics);
+
+    let mut hangup = signal(SignalKind::hangup()).context("Failed to install SIGHUP handler")?;
+    let mut stop = std::pin::pin!(shutdown::signal());
+    loop {
+        tokio::select! {
+            _ = &mut stop => break,
+            _ = hangup.recv() => {}
+        }
+
+        log_level::reload_filter_file(&log_level);
+
+        // A config that cannot be loaded leaves the running one in place
+        info!("Reloading {}", config_path.display());
+        let reloaded = match Config::load(&config_path) {
+            Ok(config) => prepare(&config, &mut tables, &metrics)
+                .await
+                .map(|queues| (config, queues)),
+            Err(e) => Err(e),
+        };
+        match reloaded {
+            Ok((config, queues)) => {
+                running.stop().await;
+                metrics.retain(|queue| config.queues.iter().any(|q| q.name == queue));
+                running = Running::start(&config, queues, Arc::clone(&client), &metrics);
+                info!("Reloaded {} queues", config.queues.len());
+            }
+            Err(e) => error!("Keeping the running config: {:#}", e),
+        }
+    }
+    running.stop().await;
+
+    // Every worker has returned, so these are the last references to the pipelines.
+    // The tables share one grace period rather than each getting their own.
+    let started = Instant::now();
+    let mut unacked = 0;
+    for (table, pipeline) in tables.pipelines {
+        let Ok(pipeline) = Arc::try_unwrap(pipeline) else {
+            bail!("Pipeline of {} is still in use after shutdown", table);
+        };
+        let outcome = shutdown::drain(
+            pipeline.into_inner(),
+            grace.saturating_sub(started.elapsed()),
+        )
+        .await?;
+        info!(
+            "{}: shut down after ingesting {} rows ({} failed)",
+            table, outcome.summary.ingested, outcome.summary.failed
+        );
+        unacked += outcome.unacked.len();
+    }
+    if unacked > 0 {
+        bail!("{} rows were not acknowledged before shutdown", unacked);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,144 @@
+//! Per-queue counters, served in the Prometheus text format
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Messages of one queue, by what happened to them
+#[derive(Debug, Default)]
+pub struct QueueMetrics {
+    pub received: AtomicU64,
+    /// Rows acknowledged
+    pub ingested: AtomicU64,
+    /// Messages deleted from the queue once their rows were acknowledged
+    pub deleted: AtomicU64,
+    /// Messages left on the queue because they are not rows of the table
+    pub rejected: AtomicU64,
+    /// Messages left on the queue because their rows were not acknowledged
+    pub failed: AtomicU64,
+    /// Age, in milliseconds, of the oldest message of the last receive
+    pub oldest_age_ms: AtomicU64,
+    /// `ApproximateNumberOfMessages` at the last probe
+    pub backlog: AtomicU64,
+    /// Receives the scheduler currently allows in flight
+    pub allocated: AtomicU64,
+}
+
+/// The metrics of every queue, by name
+///
+/// Kept across reconfigurations, so counters of a queue that stays configured carry on.
+#[derive(Debug, Default, Clone)]
+pub struct Metrics {
+    queues: Arc<Mutex<BTreeMap<String, Arc<QueueMetrics>>>>,
+}
+
+impl Metrics {
+    /// The metrics of `queue`, created on first use
+    pub fn queue(&self, queue: &str) -> Arc<QueueMetrics> {
+        let mut queues = self.queues.lock().unwrap();
+        Arc::clone(queues.entry(queue.to_string()).or_default())
+    }
+
+    /// Stop reporting queues that are no longer configured
+    pub fn retain(&self, keep: impl Fn(&str) -> bool) {
+        self.queues.lock().unwrap().retain(|queue, _| keep(queue));
+    }
+
+    pub fn render(&self) -> String {
+        let queues = self.queues.lock().unwrap();
+        let mut text = String::new();
+        let series: [(&str, &str, &str, fn(&QueueMetrics) -> &AtomicU64); 8] = [
+            (
+                "sqs_poller_messages_received_total",
+                "counter",
+                "Messages received",
+                |m| &m.received,
+            ),
+            (
+                "sqs_poller_rows_ingested_total",
+                "counter",
+                "Rows acknowledged",
+                |m| &m.ingested,
+            ),
+            (
+                "sqs_poller_messages_deleted_total",
+                "counter",
+                "Messages deleted after their rows were acknowledged",
+                |m| &m.deleted,
+            ),
+            (
+                "sqs_poller_messages_rejected_total",
+                "counter",
+                "Messages left on the queue that are not rows of the table",
+                |m| &m.rejected,
+            ),
+            (
+                "sqs_poller_messages_failed_total",
+                "counter",
+                "Messages left on the queue whose rows were not acknowledged",
+                |m| &m.failed,
+            ),
+            (
+                "sqs_poller_oldest_message_age_ms",
+                "gauge",
+                "Age of the oldest message of the last receive",
+                |m| &m.oldest_age_ms,
+            ),
+            (
+                "sqs_poller_backlog_messages",
+                "gauge",
+                "ApproximateNumberOfMessages at the last probe",
+                |m| &m.backlog,
+            ),
+            (
+                "sqs_poller_allocated_receives",
+                "gauge",
+                "Receives the scheduler allows in flight",
+                |m| &m.allocated,
+            ),
+        ];
+        for (name, kind, help, value) in series {
+            let _ = writeln!(text, "# HELP {} {}", name, help);
+            let _ = writeln!(text, "# TYPE {} {}", name, kind);
+            for (queue, metrics) in queues.iter() {
+                let _ = writeln!(
+                    text,
+                    "{}{{queue=\"{}\"}} {}",
+                    name,
+                    queue,
+                    value(metrics).load(Ordering::Relaxed)
+                );
+            }
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics
+            .queue("orders")
+            .received
+            .fetch_add(10, Ordering::Relaxed);
+        metrics
+            .queue("orders")
+            .deleted
+            .fetch_add(9, Ordering::Relaxed);
+        metrics.queue("audit").backlog.store(250, Ordering::Relaxed);
+
+        let text = metrics.render();
+        assert!(text.contains("# TYPE sqs_poller_messages_received_total counter\n"));
+        assert!(text.contains("sqs_poller_messages_received_total{queue=\"orders\"} 10\n"));
+        assert!(text.contains("sqs_poller_messages_deleted_total{queue=\"orders\"} 9\n"));
+        assert!(text.contains("sqs_poller_backlog_messages{queue=\"audit\"} 250\n"));
+
+        metrics.retain(|queue| queue != "audit");
+        assert!(!metrics.render().contains("audit"));
+    }
+}
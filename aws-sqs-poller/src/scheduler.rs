@@ -0,0 +1,158 @@
+//! Sharing the receive capacity out among queues by weight and backlog
+
+/// Most messages one ReceiveMessage call returns
+pub const MESSAGES_PER_RECEIVE: u64 = 10;
+
+/// What the scheduler knows about a queue
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueDemand {
+    pub weight: u32,
+    /// Most receives the queue may have in flight
+    pub concurrency: usize,
+    /// `ApproximateNumberOfMessages` at the last probe
+    pub backlog: u64,
+}
+
+impl QueueDemand {
+    /// Receives that would clear the backlog in one round, up to the concurrency
+    fn wanted(&self) -> usize {
+        let receives = self.backlog.div_ceil(MESSAGES_PER_RECEIVE);
+        usize::try_from(receives)
+            .unwrap_or(usize::MAX)
+            .min(self.concurrency)
+    }
+}
+
+/// Number of receives each queue may have in flight, out of `capacity`
+///
+/// Slots are handed out one at a time, in three rounds:
+///
+/// 1. Every queue gets one, highest weight first, so queues that looked idle at the
+///    last probe keep long-polling and notice new messages.
+/// 2. Queues with a backlog share what is left in proportion to their weights, each
+///    up to the receives its backlog needs.
+/// 3. Any capacity still left is shared the same way among all queues up to their
+///    concurrency, in case the approximate backlogs undercount.
+///
+/// Within a round, the next slot goes to the queue with the fewest slots for its
+/// weight; ties go to the higher weight, then to the queue listed first. A queue with
+/// a low weight is therefore only held back while queues with higher weights have a
+/// backlog to use the capacity on. With less capacity than queues, the lowest-weight
+/// queues get no slot at all.
+pub fn allocate(capacity: usize, queues: &[QueueDemand]) -> Vec<usize> {
+    let mut slots = vec![0; queues.len()];
+    let mut remaining = capacity;
+
+    let mut by_weight: Vec<usize> = (0..queues.len()).collect();
+    by_weight.sort_by_key(|&index| std::cmp::Reverse(queues[index].weight));
+    for index in by_weight {
+        if remaining == 0 {
+            break;
+        }
+        if queues[index].concurrency > 0 {
+            slots[index] = 1;
+            remaining -= 1;
+        }
+    }
+
+    fill(&mut slots, &mut remaining, queues, QueueDemand::wanted);
+    fill(&mut slots, &mut remaining, queues, |queue| {
+        queue.concurrency
+    });
+    slots
+}
+
+/// Hand out `remaining` slots by weight, each queue up to `limit` of them
+fn fill(
+    slots: &mut [usize],
+    remaining: &mut usize,
+    queues: &[QueueDemand],
+    limit: impl Fn(&QueueDemand) -> usize,
+) {
+    while *remaining > 0 {
+        let mut next: Option<usize> = None;
+        for (index, queue) in queues.iter().enumerate() {
+            if queue.weight == 0 || slots[index] >= limit(queue) {
+                continue;
+            }
+            next = match next {
+                Some(best) if !fewer_for_weight(index, best, slots, queues) => Some(best),
+                _ => Some(index),
+            };
+        }
+        let Some(index) = next else {
+            return;
+        };
+        slots[index] += 1;
+        *remaining -= 1;
+    }
+}
+
+/// Whether queue `a` should get the next slot before queue `b`
+fn fewer_for_weight(a: usize, b: usize, slots: &[usize], queues: &[QueueDemand]) -> bool {
+    // slots[a] / weight[a] < slots[b] / weight[b], without division
+    let share_a = slots[a] as u128 * u128::from(queues[b].weight);
+    let share_b = slots[b] as u128 * u128::from(queues[a].weight);
+    share_a < share_b || (share_a == share_b && queues[a].weight > queues[b].weight)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(weight: u32, concurrency: usize, backlog: u64) -> QueueDemand {
+        QueueDemand {
+            weight,
+            concurrency,
+            backlog,
+        }
+    }
+
+    #[test]
+    fn test_capacity_is_shared_by_weight() {
+        let queues = [queue(3, 10, 1000), queue(1, 10, 1000)];
+        assert_eq!(vec![6, 2], allocate(8, &queues));
+        assert_eq!(vec![9, 3], allocate(12, &queues));
+
+        let queues = [queue(1, 10, 1000), queue(2, 10, 1000), queue(1, 10, 1000)];
+        assert_eq!(vec![2, 4, 2], allocate(8, &queues));
+    }
+
+    #[test]
+    fn test_idle_queues_keep_one_receive() {
+        // The high-weight queue is idle, so the low-weight one takes the rest
+        let queues = [queue(4, 8, 0), queue(1, 8, 1000)];
+        assert_eq!(vec![1, 7], allocate(8, &queues));
+
+        // Once the high-weight queue has a backlog, it takes most of the capacity
+        let queues = [queue(4, 8, 1000), queue(1, 8, 1000)];
+        assert_eq!(vec![6, 2], allocate(8, &queues));
+
+        // With no backlog anywhere, spare capacity is still shared by weight
+        let queues = [queue(4, 8, 0), queue(1, 8, 0)];
+        assert_eq!(vec![6, 2], allocate(8, &queues));
+    }
+
+    #[test]
+    fn test_backlog_and_concurrency_cap_slots() {
+        // 25 messages need 3 receives; the rest of the capacity goes where it is wanted
+        let queues = [queue(4, 8, 25), queue(1, 8, 1000)];
+        assert_eq!(vec![3, 5], allocate(8, &queues));
+
+        // Concurrency caps a queue even when capacity is left over
+        let queues = [queue(1, 2, 1000), queue(1, 3, 1000)];
+        assert_eq!(vec![2, 3], allocate(10, &queues));
+    }
+
+    #[test]
+    fn test_scarce_capacity_goes_to_high_weights() {
+        let queues = [queue(1, 4, 1000), queue(3, 4, 1000), queue(2, 4, 1000)];
+        assert_eq!(vec![0, 1, 1], allocate(2, &queues));
+        assert_eq!(vec![0, 0, 0], allocate(0, &queues));
+
+        // Equal weights fall back to the order queues are listed in
+        let queues = [queue(1, 4, 1000), queue(1, 4, 1000), queue(1, 4, 1000)];
+        assert_eq!(vec![1, 1, 0], allocate(2, &queues));
+        assert_eq!(vec![2, 1, 1], allocate(4, &queues));
+    }
+}
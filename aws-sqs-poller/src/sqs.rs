@@ -0,0 +1,95 @@
+//! [`QueueClient`] over the AWS SDK
+
+use anyhow::{Context, Result};
+use aws_sdk_sqs::types::{
+    DeleteMessageBatchRequestEntry, MessageSystemAttributeName, QueueAttributeName,
+};
+use aws_sdk_sqs::Client;
+use tracing::warn;
+
+use crate::worker::{Message, QueueClient};
+
+impl QueueClient for Client {
+    async fn receive(
+        &self,
+        url: &str,
+        max_messages: i32,
+        wait_time_secs: i32,
+    ) -> Result<Vec<Message>> {
+        let output = self
+            .receive_message()
+            .queue_url(url)
+            .max_number_of_messages(max_messages)
+            .wait_time_seconds(wait_time_secs)
+            .message_system_attribute_names(MessageSystemAttributeName::SentTimestamp)
+            .send()
+            .await?;
+        Ok(output
+            .messages
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|message| {
+                let sent_timestamp_ms = message
+                    .attributes()
+                    .and_then(|attributes| {
+                        attributes.get(&MessageSystemAttributeName::SentTimestamp)
+                    })
+                    .and_then(|timestamp| timestamp.parse().ok());
+                Some(Message {
+                    message_id: message.message_id?,
+                    receipt_handle: message.receipt_handle?,
+                    body: message.body.unwrap_or_default(),
+                    sent_timestamp_ms,
+                })
+            })
+            .collect())
+    }
+
+    async fn delete(&self, url: &str, messages: &[Message]) -> Result<u64> {
+        let mut deleted = 0;
+        // DeleteMessageBatch takes up to 10 entries
+        for chunk in messages.chunks(10) {
+            let entries = chunk
+                .iter()
+                .enumerate()
+                .map(|(index, message)| {
+                    DeleteMessageBatchRequestEntry::builder()
+                        .id(index.to_string())
+                        .receipt_handle(&message.receipt_handle)
+                        .build()
+                        .context("Invalid delete entry")
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let output = self
+                .delete_message_batch()
+                .queue_url(url)
+                .set_entries(Some(entries))
+                .send()
+                .await?;
+            for failure in output.failed() {
+                // The message is received again after its visibility timeout
+                warn!(
+                    "Failed to delete a message: {} {}",
+                    failure.code(),
+                    failure.message().unwrap_or_default()
+                );
+            }
+            deleted += output.successful().len() as u64;
+        }
+        Ok(deleted)
+    }
+
+    async fn backlog(&self, url: &str) -> Result<u64> {
+        let output = self
+            .get_queue_attributes()
+            .queue_url(url)
+            .attribute_names(QueueAttributeName::ApproximateNumberOfMessages)
+            .send()
+            .await?;
+        output
+            .attributes()
+            .and_then(|attributes| attributes.get(&QueueAttributeName::ApproximateNumberOfMessages))
+            .and_then(|count| count.parse().ok())
+            .context("No ApproximateNumberOfMessages in the queue's attributes")
+    }
+}
@@ -0,0 +1,444 @@
+//! Receiving each queue's messages, ingesting them, and deleting the ones that are
+//! durable
+
+use anyhow::{Context, Result};
+use serde_json::Value;
+use std::future::Future;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::sync::{watch, Mutex};
+use tracing::{error, info, warn};
+use zerobus_common::dynamic::DynamicEncoder;
+use zerobus_common::pipeline::{IngestSink, Pipeline};
+
+use crate::config::QueueConfig;
+use crate::metrics::{Metrics, QueueMetrics};
+use crate::scheduler::{allocate, QueueDemand, MESSAGES_PER_RECEIVE};
+
+/// Pause after a failed receive or delete before receiving again
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// A received message
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub message_id: String,
+    pub receipt_handle: String,
+    pub body: String,
+    /// `SentTimestamp`, in milliseconds since Unix epoch
+    pub sent_timestamp_ms: Option<u64>,
+}
+
+/// The SQS calls the poller makes; implemented for the AWS SDK client, and by tests
+pub trait QueueClient: Send + Sync + 'static {
+    /// Long-poll for up to `max_messages` messages
+    fn receive(
+        &self,
+        url: &str,
+        max_messages: i32,
+        wait_time_secs: i32,
+    ) -> impl Future<Output = Result<Vec<Message>>> + Send;
+
+    /// Delete `messages`, returning how many were deleted
+    fn delete(&self, url: &str, messages: &[Message]) -> impl Future<Output = Result<u64>> + Send;
+
+    /// `ApproximateNumberOfMessages` of the queue
+    fn backlog(&self, url: &str) -> impl Future<Output = Result<u64>> + Send;
+}
+
+/// A configured queue and the pipeline to its table
+///
+/// Queues routed to the same table share its pipeline, and their receives take turns
+/// on it, so each batch is deleted exactly when its rows are durable.
+pub struct Queue<S: IngestSink> {
+    pub config: QueueConfig,
+    encoder: DynamicEncoder,
+    pipeline: Arc<Mutex<Pipeline<S>>>,
+    metrics: Arc<QueueMetrics>,
+}
+
+impl<S: IngestSink> Queue<S> {
+    pub fn new(
+        config: QueueConfig,
+        encoder: DynamicEncoder,
+        pipeline: Arc<Mutex<Pipeline<S>>>,
+        metrics: Arc<QueueMetrics>,
+    ) -> Self {
+        Self {
+            config,
+            encoder,
+            pipeline,
+            metrics,
+        }
+    }
+
+    async fn receive<C: QueueClient>(&self, client: &C) -> Result<Vec<Message>> {
+        let messages = client
+            .receive(
+                &self.config.url,
+                MESSAGES_PER_RECEIVE as i32,
+                self.config.wait_time_secs,
+            )
+            .await
+            .with_context(|| format!("Failed to receive from {}", self.config.name))?;
+        self.metrics
+            .received
+            .fetch_add(messages.len() as u64, Ordering::Relaxed);
+        if let Some(oldest) = messages.iter().filter_map(|m| m.sent_timestamp_ms).min() {
+            let age = unix_millis().saturating_sub(oldest);
+            self.metrics.oldest_age_ms.store(age, Ordering::Relaxed);
+        }
+        Ok(messages)
+    }
+
+    /// Ingest the rows of `messages` and delete the messages once they are durable
+    ///
+    /// A message whose body is not a row of the table, or whose row is not
+    /// acknowledged, stays on the queue; it is received again after its visibility
+    /// timeout, until the queue's redrive policy moves it to a dead-letter queue.
+    pub async fn handle<C: QueueClient>(&self, client: &C, messages: Vec<Message>) -> Result<()> {
+        let mut records = Vec::with_capacity(messages.len());
+        let mut accepted = Vec::with_capacity(messages.len());
+        for message in messages {
+            let encoded = serde_json::from_str::<Value>(&message.body)
+                .context("Body is not valid JSON")
+                .and_then(|row| self.encoder.encode(&row));
+            match encoded {
+                Ok(record) => {
+                    let sent_at = message
+                        .sent_timestamp_ms
+                        .map(|ms| UNIX_EPOCH + Duration::from_millis(ms));
+                    records.push((record, sent_at));
+                    accepted.push(message);
+                }
+                Err(e) => {
+                    self.metrics.rejected.fetch_add(1, Ordering::Relaxed);
+                    warn!(
+                        "{}: leaving message {} on the queue: {:#}",
+                        self.config.name, message.message_id, e
+                    );
+                }
+            }
+        }
+        if records.is_empty() {
+            return Ok(());
+        }
+
+        let rows = records.len() as u64;
+        // Measured from when each message was sent, so the pipeline's end-to-end latency
+        // includes the time it waited on the queue
+        let ingested = self.pipeline.lock().await.ingest_batch_at(records).await;
+        if let Err(e) = ingested {
+            self.metrics.failed.fetch_add(rows, Ordering::Relaxed);
+            error!(
+                "{}: {} messages left on the queue for redelivery: {:#}",
+                self.config.name, rows, e
+            );
+            return Ok(());
+        }
+        self.metrics.ingested.fetch_add(rows, Ordering::Relaxed);
+
+        let deleted = client
+            .delete(&self.config.url, &accepted)
+            .await
+            .with_context(|| format!("Failed to delete from {}", self.config.name))?;
+        self.metrics.deleted.fetch_add(deleted, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// Receive from `queue` for as long as the scheduler allows worker `index` to
+///
+/// A receive in progress is abandoned on `stop`, but a batch that has been received is
+/// always handled to the end, so no rows are left unacknowledged.
+pub async fn run_worker<S: IngestSink, C: QueueClient>(
+    queue: Arc<Queue<S>>,
+    client: Arc<C>,
+    index: usize,
+    mut allowed: watch::Receiver<usize>,
+    mut stop: watch::Receiver<bool>,
+) {
+    loop {
+        if *stop.borrow() {
+            return;
+        }
+        if index >= *allowed.borrow_and_update() {
+            tokio::select! {
+                changed = allowed.changed() => if changed.is_err() { return },
+                _ = stop.changed() => return,
+            }
+            continue;
+        }
+
+        let messages = tokio::select! {
+            messages = queue.receive(client.as_ref()) => messages,
+            _ = stop.changed() => return,
+        };
+        let handled = match messages {
+            Ok(messages) => queue.handle(client.as_ref(), messages).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = handled {
+            error!("{:#}", e);
+            tokio::select! {
+                _ = tokio::time::sleep(RETRY_DELAY) => {}
+                _ = stop.changed() => return,
+            }
+        }
+    }
+}
+
+/// Probe every queue's backlog each `interval` and tell its workers how many of them
+/// may receive
+pub async fn run_scheduler<C: QueueClient>(
+    queues: Vec<(QueueConfig, watch::Sender<usize>)>,
+    capacity: usize,
+    interval: Duration,
+    client: Arc<C>,
+    metrics: Metrics,
+    mut stop: watch::Receiver<bool>,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = stop.changed() => return,
+        }
+
+        let mut demands = Vec::with_capacity(queues.len());
+        for (config, _) in &queues {
+            let backlog = match client.backlog(&config.url).await {
+                Ok(backlog) => {
+                    metrics
+                        .queue(&config.name)
+                        .backlog
+                        .store(backlog, Ordering::Relaxed);
+                    backlog
+                }
+                Err(e) => {
+                    // Assume a backlog so a queue that cannot be probed is still served
+                    warn!("{}: probe failed, assuming a backlog: {:#}", config.name, e);
+                    u64::MAX
+                }
+            };
+            demands.push(QueueDemand {
+                weight: config.weight,
+                concurrency: config.concurrency,
+                backlog,
+            });
+        }
+
+        let slots = allocate(capacity, &demands);
+        for ((config, allowed), slots) in queues.iter().zip(slots) {
+            metrics
+                .queue(&config.name)
+                .allocated
+                .store(slots as u64, Ordering::Relaxed);
+            allowed.send_if_modified(|current| {
+                if *current == slots {
+                    return false;
+                }
+                info!("{}: {} receives in flight", config.name, slots);
+                *current = slots;
+                true
+            });
+        }
+    }
+}
+
+/// Current time in milliseconds since Unix epoch
+fn unix_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost_types::field_descriptor_proto::{Label, Type};
+    use prost_types::{DescriptorProto, FieldDescriptorProto};
+    use std::collections::VecDeque;
+    use std::sync::Mutex as StdMutex;
+    use zerobus_common::testing::MockSink;
+
+    /// Hands out queued batches, then nothing, and records deletes
+    #[derive(Default)]
+    struct MockQueue {
+        batches: StdMutex<VecDeque<Vec<Message>>>,
+        deleted: StdMutex<Vec<String>>,
+        backlog: u64,
+    }
+
+    impl QueueClient for MockQueue {
+        async fn receive(&self, _url: &str, _max: i32, _wait: i32) -> Result<Vec<Message>> {
+            let batch = self.batches.lock().unwrap().pop_front();
+            match batch {
+                Some(batch) => Ok(batch),
+                None => {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                    Ok(Vec::new())
+                }
+            }
+        }
+
+        async fn delete(&self, _url: &str, messages: &[Message]) -> Result<u64> {
+            let mut deleted = self.deleted.lock().unwrap();
+            deleted.extend(messages.iter().map(|m| m.message_id.clone()));
+            Ok(messages.len() as u64)
+        }
+
+        async fn backlog(&self, _url: &str) -> Result<u64> {
+            Ok(self.backlog)
+        }
+    }
+
+    fn message(id: &str, body: &str) -> Message {
+        Message {
+            message_id: id.to_string(),
+            receipt_handle: format!("handle-{}", id),
+            body: body.to_string(),
+            sent_timestamp_ms: Some(unix_millis() - 60_000),
+        }
+    }
+
+    fn queue_config(name: &str, weight: u32, concurrency: usize) -> QueueConfig {
+        QueueConfig {
+            name: name.to_string(),
+            url: format!("https://sqs.us-east-1.amazonaws.com/123456789012/{}", name),
+            weight,
+            concurrency,
+            table: Some("main.sqs.orders".to_string()),
+            ignore_unknown_fields: false,
+            wait_time_secs: 0,
+        }
+    }
+
+    fn queue(sink: MockSink, metrics: &Metrics) -> Arc<Queue<MockSink>> {
+        let descriptor = DescriptorProto {
+            name: Some("table_orders".to_string()),
+            field: vec![FieldDescriptorProto {
+                name: Some("order_id".to_string()),
+                number: Some(1),
+                label: Some(Label::Optional as i32),
+                r#type: Some(Type::String as i32),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        Arc::new(Queue::new(
+            queue_config("orders", 1, 1),
+            DynamicEncoder::new(&descriptor).unwrap(),
+            Arc::new(Mutex::new(Pipeline::new(sink, 100))),
+            metrics.queue("orders"),
+        ))
+    }
+
+    #[tokio::test]
+    async fn test_acknowledged_messages_are_deleted() {
+        let client = MockQueue::default();
+        let metrics = Metrics::default();
+        let sink = MockSink::default();
+        let messages = vec![
+            message("m-1", r#"{"order_id": "o-1"}"#),
+            message("m-2", "not json"),
+            message("m-3", r#"{"order_id": "o-3", "colour": "red"}"#),
+            message("m-4", r#"{"order_id": "o-4"}"#),
+        ];
+
+        queue(sink.clone(), &metrics)
+            .handle(&client, messages)
+            .await
+            .unwrap();
+
+        assert_eq!(2, sink.records().len());
+        assert_eq!(vec!["m-1", "m-4"], *client.deleted.lock().unwrap());
+        let orders = metrics.queue("orders");
+        assert_eq!(2, orders.ingested.load(Ordering::Relaxed));
+        assert_eq!(2, orders.deleted.load(Ordering::Relaxed));
+        assert_eq!(2, orders.rejected.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn test_unacknowledged_messages_stay_on_the_queue() {
+        let client = MockQueue::default();
+        let metrics = Metrics::default();
+        let sink = MockSink::default().fail_acks_for(|_| true);
+
+        queue(sink, &metrics)
+            .handle(&client, vec![message("m-1", r#"{"order_id": "o-1"}"#)])
+            .await
+            .unwrap();
+
+        assert!(client.deleted.lock().unwrap().is_empty());
+        assert_eq!(1, metrics.queue("orders").failed.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn test_workers_receive_only_while_allowed() {
+        let client = Arc::new(MockQueue::default());
+        client
+            .batches
+            .lock()
+            .unwrap()
+            .push_back(vec![message("m-1", r#"{"order_id": "o-1"}"#)]);
+        let metrics = Metrics::default();
+        let sink = MockSink::default();
+        let queue = queue(sink.clone(), &metrics);
+        let (allowed_sender, allowed) = watch::channel(0);
+        let (stop_sender, stop) = watch::channel(false);
+
+        let worker = tokio::spawn(run_worker(queue, Arc::clone(&client), 0, allowed, stop));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(sink.records().is_empty());
+
+        allowed_sender.send(1).unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(1, sink.records().len());
+        assert_eq!(1, metrics.queue("orders").received.load(Ordering::Relaxed));
+        assert!(
+            metrics
+                .queue("orders")
+                .oldest_age_ms
+                .load(Ordering::Relaxed)
+                >= 60_000
+        );
+
+        stop_sender.send(true).unwrap();
+        worker.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_scheduler_allocates_by_probed_backlog() {
+        let client = Arc::new(MockQueue {
+            backlog: 1000,
+            ..Default::default()
+        });
+        let metrics = Metrics::default();
+        let (high_sender, high) = watch::channel(0);
+        let (low_sender, low) = watch::channel(0);
+        let (stop_sender, stop) = watch::channel(false);
+
+        let scheduler = tokio::spawn(run_scheduler(
+            vec![
+                (queue_config("high", 3, 10), high_sender),
+                (queue_config("low", 1, 10), low_sender),
+            ],
+            8,
+            Duration::from_secs(15),
+            client,
+            metrics.clone(),
+            stop,
+        ));
+        tokio::time::sleep(Duration::from_millis(1)).await;
+
+        assert_eq!(6, *high.borrow());
+        assert_eq!(2, *low.borrow());
+        assert_eq!(1000, metrics.queue("low").backlog.load(Ordering::Relaxed));
+        assert_eq!(6, metrics.queue("high").allocated.load(Ordering::Relaxed));
+
+        stop_sender.send(true).unwrap();
+        scheduler.await.unwrap();
+    }
+}
@@ -0,0 +1,122 @@
+use anyhow::{Context, Result};
+use prost::Message;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+use tracing::warn;
+use zerobus_common::pipeline::{IngestSink, Pipeline};
+
+use crate::parser::{FlowLogFormat, FlowLogRecord};
+use crate::proto::vpc_flow_logs::TableVpcFlowLogs;
+
+/// Where a flow log file came from, stamped on every row
+pub struct ObjectSource<'a> {
+    pub bucket: &'a str,
+    pub key: &'a str,
+}
+
+/// Per-file counts
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct FileStats {
+    pub rows: u64,
+    pub malformed: u64,
+}
+
+/// Convert a parsed flow log line into a table row
+pub fn to_table_row(
+    record: FlowLogRecord,
+    source: &ObjectSource<'_>,
+    ingested_at: i64,
+    ingested_date: i32,
+) -> TableVpcFlowLogs {
+    TableVpcFlowLogs {
+        version: record.version,
+        account_id: record.account_id,
+        interface_id: record.interface_id,
+        srcaddr: record.srcaddr,
+        dstaddr: record.dstaddr,
+        srcport: record.srcport,
+        dstport: record.dstport,
+        protocol: record.protocol,
+        packets: record.packets,
+        bytes: record.bytes,
+        // Flow logs use epoch seconds; TIMESTAMP columns are microseconds
+        start_time: record.start.map(|s| s * 1_000_000),
+        end_time: record.end.map(|s| s * 1_000_000),
+        action: record.action.map(|a| a.as_str().to_string()),
+        log_status: record.log_status.map(|s| s.as_str().to_string()),
+        vpc_id: record.vpc_id,
+        subnet_id: record.subnet_id,
+        instance_id: record.instance_id,
+        tcp_flags: record.tcp_flags,
+        flow_type: record.flow_type,
+        pkt_srcaddr: record.pkt_srcaddr,
+        pkt_dstaddr: record.pkt_dstaddr,
+        region: record.region,
+        az_id: record.az_id,
+        sublocation_type: record.sublocation_type,
+        sublocation_id: record.sublocation_id,
+        pkt_src_aws_service: record.pkt_src_aws_service,
+        pkt_dst_aws_service: record.pkt_dst_aws_service,
+        flow_direction: record.flow_direction,
+        traffic_path: record.traffic_path,
+        extra_fields: record.extra_fields,
+        s3_bucket: Some(source.bucket.to_string()),
+        s3_key: Some(source.key.to_string()),
+        ingested_at: Some(ingested_at),
+        ingested_date: Some(ingested_date),
+    }
+}
+
+/// Read a flow log file line by line and ingest one row per data line
+///
+/// The first line is the header describing the field layout. Lines that don't match
+/// the header are logged and skipped rather than failing the whole file.
+pub async fn ingest_flow_log<R, S>(
+    reader: R,
+    source: &ObjectSource<'_>,
+    pipeline: &mut Pipeline<S>,
+) -> Result<FileStats>
+where
+    R: AsyncBufRead + Unpin,
+    S: IngestSink,
+{
+    // Get current timestamp in microseconds
+    let now = std::time::SystemTime::now();
+    let ingested_at = now
+        .duration_since(std::time::UNIX_EPOCH)
+        .context("Failed to get system time")?
+        .as_micros() as i64;
+
+    // Get the current date as int32 (days since Unix epoch)
+    let ingested_date = now
+        .duration_since(std::time::UNIX_EPOCH)
+        .context("Failed to get system time")?
+        .as_secs() as i32
+        / 86400;
+
+    let mut lines = reader.lines();
+    let header = lines.next_line().await?.context("Flow log file is empty")?;
+    let format = FlowLogFormat::from_header(&header)?;
+
+    let mut stats = FileStats::default();
+    let mut line_number = 1;
+    while let Some(line) = lines.next_line().await? {
+        line_number += 1;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        match format.parse_line(&line) {
+            Ok(record) => {
+                let row = to_table_row(record, source, ingested_at, ingested_date);
+                pipeline.ingest(row.encode_to_vec()).await?;
+                stats.rows += 1;
+            }
+            Err(e) => {
+                warn!("Skipping {}:{}: {}", source.key, line_number, e);
+                stats.malformed += 1;
+            }
+        }
+    }
+
+    Ok(stats)
+}
@@ -0,0 +1,5 @@
+pub mod handler;
+pub mod ingest;
+pub mod parser;
+pub mod proto;
+pub mod sdk;
@@ -0,0 +1,43 @@
+use aws_vpc_flow_logs_ingestor::handler;
+use lambda_runtime::{run, service_fn, Error};
+
+/// The handler, in the root span of its invocation
+///
+/// Spans are exported before it returns, as Lambda may freeze the container right after.
+#[cfg(feature = "otel")]
+async fn traced_handler(
+    event: lambda_runtime::LambdaEvent<aws_lambda_events::event::s3::S3Event>,
+) -> Result<(), Error> {
+    let request_id = event.context.request_id.clone();
+    let function_name = event.context.env_config.function_name.clone();
+    zerobus_common::otel::invocation(
+        &request_id,
+        &function_name,
+        handler::function_handler(event),
+    )
+    .await
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    // Install the default CryptoProvider early in your application
+    rustls::crypto::aws_lc_rs::default_provider()
+        .install_default()
+        .unwrap();
+
+    #[cfg(feature = "otel")]
+    {
+        zerobus_common::otel::init()?;
+        run(service_fn(traced_handler)).await
+    }
+
+    #[cfg(not(feature = "otel"))]
+    {
+        tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::INFO)
+            .with_target(false)
+            .init();
+
+        run(service_fn(handler::function_handler)).await
+    }
+}
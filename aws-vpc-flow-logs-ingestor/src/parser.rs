@@ -0,0 +1,416 @@
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// A field that can appear in a flow log format
+///
+/// The default (v2) format and every custom format write a header line naming the
+/// fields in order, so parsing is driven entirely by the header. Fields without a
+/// typed column are kept by name in [`FlowLogField::Other`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FlowLogField {
+    Version,
+    AccountId,
+    InterfaceId,
+    SrcAddr,
+    DstAddr,
+    SrcPort,
+    DstPort,
+    Protocol,
+    Packets,
+    Bytes,
+    Start,
+    End,
+    Action,
+    LogStatus,
+    // v3
+    VpcId,
+    SubnetId,
+    InstanceId,
+    TcpFlags,
+    Type,
+    PktSrcAddr,
+    PktDstAddr,
+    // v4
+    Region,
+    AzId,
+    SublocationType,
+    SublocationId,
+    // v5
+    PktSrcAwsService,
+    PktDstAwsService,
+    FlowDirection,
+    TrafficPath,
+    Other(String),
+}
+
+impl FlowLogField {
+    pub fn from_header_name(name: &str) -> Self {
+        match name {
+            "version" => Self::Version,
+            "account-id" => Self::AccountId,
+            "interface-id" => Self::InterfaceId,
+            "srcaddr" => Self::SrcAddr,
+            "dstaddr" => Self::DstAddr,
+            "srcport" => Self::SrcPort,
+            "dstport" => Self::DstPort,
+            "protocol" => Self::Protocol,
+            "packets" => Self::Packets,
+            "bytes" => Self::Bytes,
+            "start" => Self::Start,
+            "end" => Self::End,
+            "action" => Self::Action,
+            "log-status" => Self::LogStatus,
+            "vpc-id" => Self::VpcId,
+            "subnet-id" => Self::SubnetId,
+            "instance-id" => Self::InstanceId,
+            "tcp-flags" => Self::TcpFlags,
+            "type" => Self::Type,
+            "pkt-srcaddr" => Self::PktSrcAddr,
+            "pkt-dstaddr" => Self::PktDstAddr,
+            "region" => Self::Region,
+            "az-id" => Self::AzId,
+            "sublocation-type" => Self::SublocationType,
+            "sublocation-id" => Self::SublocationId,
+            "pkt-src-aws-service" => Self::PktSrcAwsService,
+            "pkt-dst-aws-service" => Self::PktDstAwsService,
+            "flow-direction" => Self::FlowDirection,
+            "traffic-path" => Self::TrafficPath,
+            other => Self::Other(other.to_string()),
+        }
+    }
+}
+
+/// Whether the traffic was permitted by security groups and network ACLs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Accept,
+    Reject,
+}
+
+impl Action {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Accept => "ACCEPT",
+            Self::Reject => "REJECT",
+        }
+    }
+}
+
+impl FromStr for Action {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ACCEPT" => Ok(Self::Accept),
+            "REJECT" => Ok(Self::Reject),
+            other => bail!("Unknown action: {}", other),
+        }
+    }
+}
+
+/// Logging status of the flow log record
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogStatus {
+    /// Data is logging normally
+    Ok,
+    /// There was no network traffic during the aggregation interval
+    NoData,
+    /// Some records were skipped during the aggregation interval
+    SkipData,
+}
+
+impl LogStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Ok => "OK",
+            Self::NoData => "NODATA",
+            Self::SkipData => "SKIPDATA",
+        }
+    }
+}
+
+impl FromStr for LogStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "OK" => Ok(Self::Ok),
+            "NODATA" => Ok(Self::NoData),
+            "SKIPDATA" => Ok(Self::SkipData),
+            other => bail!("Unknown log-status: {}", other),
+        }
+    }
+}
+
+/// One parsed flow log line; a `-` placeholder in the source becomes `None`
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct FlowLogRecord {
+    pub version: Option<i32>,
+    pub account_id: Option<String>,
+    pub interface_id: Option<String>,
+    pub srcaddr: Option<String>,
+    pub dstaddr: Option<String>,
+    pub srcport: Option<i32>,
+    pub dstport: Option<i32>,
+    pub protocol: Option<i32>,
+    pub packets: Option<i64>,
+    pub bytes: Option<i64>,
+    /// Start of the aggregation interval, in seconds since the Unix epoch
+    pub start: Option<i64>,
+    /// End of the aggregation interval, in seconds since the Unix epoch
+    pub end: Option<i64>,
+    pub action: Option<Action>,
+    pub log_status: Option<LogStatus>,
+    pub vpc_id: Option<String>,
+    pub subnet_id: Option<String>,
+    pub instance_id: Option<String>,
+    pub tcp_flags: Option<i32>,
+    pub flow_type: Option<String>,
+    pub pkt_srcaddr: Option<String>,
+    pub pkt_dstaddr: Option<String>,
+    pub region: Option<String>,
+    pub az_id: Option<String>,
+    pub sublocation_type: Option<String>,
+    pub sublocation_id: Option<String>,
+    pub pkt_src_aws_service: Option<String>,
+    pub pkt_dst_aws_service: Option<String>,
+    pub flow_direction: Option<String>,
+    pub traffic_path: Option<i32>,
+    /// Fields without a typed column, keyed by their header name
+    pub extra_fields: HashMap<String, String>,
+}
+
+/// Field layout of a flow log file, read from its header line
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlowLogFormat {
+    fields: Vec<FlowLogField>,
+}
+
+impl FlowLogFormat {
+    /// Build the format from the header line of a flow log file
+    pub fn from_header(header: &str) -> Result<Self> {
+        let fields: Vec<FlowLogField> = header
+            .split_whitespace()
+            .map(FlowLogField::from_header_name)
+            .collect();
+
+        if fields.is_empty() {
+            bail!("Flow log header is empty");
+        }
+        for (i, field) in fields.iter().enumerate() {
+            if fields[..i].contains(field) {
+                bail!("Flow log header contains duplicate field {:?}", field);
+            }
+        }
+
+        Ok(Self { fields })
+    }
+
+    pub fn fields(&self) -> &[FlowLogField] {
+        &self.fields
+    }
+
+    /// Parse one data line according to this format
+    pub fn parse_line(&self, line: &str) -> Result<FlowLogRecord> {
+        let values: Vec<&str> = line.split_whitespace().collect();
+        if values.len() != self.fields.len() {
+            bail!(
+                "Expected {} fields but found {}",
+                self.fields.len(),
+                values.len()
+            );
+        }
+
+        let mut record = FlowLogRecord::default();
+        for (field, value) in self.fields.iter().zip(values) {
+            // `-` marks a field that does not apply or was not captured (e.g. NODATA)
+            if value == "-" {
+                continue;
+            }
+
+            match field {
+                FlowLogField::Version => record.version = Some(parse_int(field, value)?),
+                FlowLogField::AccountId => record.account_id = Some(value.to_string()),
+                FlowLogField::InterfaceId => record.interface_id = Some(value.to_string()),
+                FlowLogField::SrcAddr => record.srcaddr = Some(value.to_string()),
+                FlowLogField::DstAddr => record.dstaddr = Some(value.to_string()),
+                FlowLogField::SrcPort => record.srcport = Some(parse_int(field, value)?),
+                FlowLogField::DstPort => record.dstport = Some(parse_int(field, value)?),
+                FlowLogField::Protocol => record.protocol = Some(parse_int(field, value)?),
+                FlowLogField::Packets => record.packets = Some(parse_int(field, value)?),
+                FlowLogField::Bytes => record.bytes = Some(parse_int(field, value)?),
+                FlowLogField::Start => record.start = Some(parse_int(field, value)?),
+                FlowLogField::End => record.end = Some(parse_int(field, value)?),
+                FlowLogField::Action => record.action = Some(value.parse()?),
+                FlowLogField::LogStatus => record.log_status = Some(value.parse()?),
+                FlowLogField::VpcId => record.vpc_id = Some(value.to_string()),
+                FlowLogField::SubnetId => record.subnet_id = Some(value.to_string()),
+                FlowLogField::InstanceId => record.instance_id = Some(value.to_string()),
+                FlowLogField::TcpFlags => record.tcp_flags = Some(parse_int(field, value)?),
+                FlowLogField::Type => record.flow_type = Some(value.to_string()),
+                FlowLogField::PktSrcAddr => record.pkt_srcaddr = Some(value.to_string()),
+                FlowLogField::PktDstAddr => record.pkt_dstaddr = Some(value.to_string()),
+                FlowLogField::Region => record.region = Some(value.to_string()),
+                FlowLogField::AzId => record.az_id = Some(value.to_string()),
+                FlowLogField::SublocationType => record.sublocation_type = Some(value.to_string()),
+                FlowLogField::SublocationId => record.sublocation_id = Some(value.to_string()),
+                FlowLogField::PktSrcAwsService => {
+                    record.pkt_src_aws_service = Some(value.to_string())
+                }
+                FlowLogField::PktDstAwsService => {
+                    record.pkt_dst_aws_service = Some(value.to_string())
+                }
+                FlowLogField::FlowDirection => record.flow_direction = Some(value.to_string()),
+                FlowLogField::TrafficPath => record.traffic_path = Some(parse_int(field, value)?),
+                FlowLogField::Other(name) => {
+                    record.extra_fields.insert(name.clone(), value.to_string());
+                }
+            }
+        }
+
+        Ok(record)
+    }
+}
+
+fn parse_int<T: FromStr>(field: &FlowLogField, value: &str) -> Result<T>
+where
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    value
+        .parse()
+        .with_context(|| format!("Invalid integer {:?} for field {:?}", value, field))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const V2_HEADER: &str = "version account-id interface-id srcaddr dstaddr srcport dstport protocol packets bytes start end action log-status";
+
+    const V3_HEADER: &str = "version vpc-id subnet-id instance-id interface-id account-id type srcaddr dstaddr srcport dstport pkt-srcaddr pkt-dstaddr protocol bytes packets start end action tcp-flags log-status";
+
+    const CUSTOM_HEADER: &str = "region az-id srcaddr dstaddr flow-direction traffic-path pkt-dst-aws-service ecs-cluster-name log-status";
+
+    #[test]
+    fn test_parse_v2_line() {
+        let format = FlowLogFormat::from_header(V2_HEADER).unwrap();
+        let record = format
+            .parse_line("2 123456789010 eni-1235b8ca123456789 172.31.16.139 172.31.16.21 20641 22 6 20 4249 1418530010 1418530070 ACCEPT OK")
+            .unwrap();
+
+        assert_eq!(Some(2), record.version);
+        assert_eq!(Some("123456789010".to_string()), record.account_id);
+        assert_eq!(Some("172.31.16.139".to_string()), record.srcaddr);
+        assert_eq!(Some(20641), record.srcport);
+        assert_eq!(Some(22), record.dstport);
+        assert_eq!(Some(6), record.protocol);
+        assert_eq!(Some(20), record.packets);
+        assert_eq!(Some(4249), record.bytes);
+        assert_eq!(Some(1418530010), record.start);
+        assert_eq!(Some(1418530070), record.end);
+        assert_eq!(Some(Action::Accept), record.action);
+        assert_eq!(Some(LogStatus::Ok), record.log_status);
+        assert!(record.extra_fields.is_empty());
+    }
+
+    #[test]
+    fn test_parse_v2_nodata_line() {
+        let format = FlowLogFormat::from_header(V2_HEADER).unwrap();
+        let record = format
+            .parse_line(
+                "2 123456789010 eni-1235b8ca123456789 - - - - - - - 1431280876 1431280934 - NODATA",
+            )
+            .unwrap();
+
+        assert_eq!(
+            Some("eni-1235b8ca123456789".to_string()),
+            record.interface_id
+        );
+        assert_eq!(None, record.srcaddr);
+        assert_eq!(None, record.srcport);
+        assert_eq!(None, record.bytes);
+        assert_eq!(None, record.action);
+        assert_eq!(Some(1431280876), record.start);
+        assert_eq!(Some(LogStatus::NoData), record.log_status);
+    }
+
+    #[test]
+    fn test_parse_v2_skipdata_line() {
+        let format = FlowLogFormat::from_header(V2_HEADER).unwrap();
+        let record = format
+            .parse_line("2 123456789010 eni-11111111aaaaaaaaa - - - - - - - 1431280876 1431280934 - SKIPDATA")
+            .unwrap();
+
+        assert_eq!(Some(LogStatus::SkipData), record.log_status);
+        assert_eq!(None, record.packets);
+    }
+
+    #[test]
+    fn test_parse_v3_line() {
+        let format = FlowLogFormat::from_header(V3_HEADER).unwrap();
+        let record = format
+            .parse_line("3 vpc-abcdefab012345678 subnet-aaaaaaaa012345678 i-01234567890123456 eni-1235b8ca123456789 123456789012 IPv4 52.213.180.42 10.0.0.62 43416 5001 52.213.180.42 10.0.0.62 6 568 8 1566848875 1566848933 ACCEPT 2 OK")
+            .unwrap();
+
+        assert_eq!(Some(3), record.version);
+        assert_eq!(Some("vpc-abcdefab012345678".to_string()), record.vpc_id);
+        assert_eq!(Some("i-01234567890123456".to_string()), record.instance_id);
+        assert_eq!(Some("IPv4".to_string()), record.flow_type);
+        assert_eq!(Some("52.213.180.42".to_string()), record.pkt_srcaddr);
+        // v3 swaps the order of bytes and packets relative to v2
+        assert_eq!(Some(568), record.bytes);
+        assert_eq!(Some(8), record.packets);
+        assert_eq!(Some(2), record.tcp_flags);
+        assert_eq!(Some(Action::Accept), record.action);
+    }
+
+    #[test]
+    fn test_parse_custom_fields() {
+        let format = FlowLogFormat::from_header(CUSTOM_HEADER).unwrap();
+        assert_eq!(
+            &FlowLogField::Other("ecs-cluster-name".to_string()),
+            &format.fields()[7]
+        );
+
+        let record = format
+            .parse_line("us-east-1 use1-az2 10.0.1.5 52.94.0.10 egress 8 AMAZON prod-cluster OK")
+            .unwrap();
+
+        assert_eq!(Some("us-east-1".to_string()), record.region);
+        assert_eq!(Some("use1-az2".to_string()), record.az_id);
+        assert_eq!(Some("egress".to_string()), record.flow_direction);
+        assert_eq!(Some(8), record.traffic_path);
+        assert_eq!(Some("AMAZON".to_string()), record.pkt_dst_aws_service);
+        assert_eq!(
+            Some(&"prod-cluster".to_string()),
+            record.extra_fields.get("ecs-cluster-name")
+        );
+        assert_eq!(None, record.version);
+    }
+
+    #[test]
+    fn test_truncated_line_is_rejected() {
+        let format = FlowLogFormat::from_header(V2_HEADER).unwrap();
+        assert!(format
+            .parse_line("2 123456789010 eni-1235b8ca123456789")
+            .is_err());
+    }
+
+    #[test]
+    fn test_invalid_values_are_rejected() {
+        let format = FlowLogFormat::from_header(V2_HEADER).unwrap();
+        assert!(format
+            .parse_line("2 123456789010 eni-1 10.0.0.1 10.0.0.2 http 22 6 20 4249 1418530010 1418530070 ACCEPT OK")
+            .is_err());
+        assert!(format
+            .parse_line("2 123456789010 eni-1 10.0.0.1 10.0.0.2 80 22 6 20 4249 1418530010 1418530070 ALLOW OK")
+            .is_err());
+    }
+
+    #[test]
+    fn test_invalid_headers_are_rejected() {
+        assert!(FlowLogFormat::from_header("   ").is_err());
+        assert!(FlowLogFormat::from_header("version srcaddr srcaddr").is_err());
+    }
+}
@@ -0,0 +1,14 @@
+output "lambda_function_name" {
+  description = "Name of the Lambda function"
+  value       = aws_lambda_function.flow_logs_ingestor.function_name
+}
+
+output "flow_logs_bucket_name" {
+  description = "Name of the S3 bucket receiving flow logs"
+  value       = aws_s3_bucket.flow_logs.bucket
+}
+
+output "cloudwatch_log_group_name" {
+  description = "Name of the CloudWatch log group"
+  value       = aws_cloudwatch_log_group.lambda_logs.name
+}
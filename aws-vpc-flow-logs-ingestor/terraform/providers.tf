@@ -0,0 +1,12 @@
+provider "aws" {
+  region = var.aws_region
+  default_tags {
+    tags = {
+      "DeployedBy"  = "Terraform"
+      "Service"     = "zerobus-vpc-flow-logs-ingestor"
+      "Environment" = terraform.workspace
+      "Version"     = "0.1.0"
+    }
+  }
+}
+
@@ -0,0 +1,37 @@
+use anyhow::{Context, Result};
+use azure_functions_ingestor::scaffold::FunctionApp;
+use std::path::PathBuf;
+
+/// Write host.json and <function>/function.json into the directory given as the only
+/// argument, configured from the environment:
+///
+/// - `FUNCTION_NAME` - Function name (default: `ingest`)
+/// - `FUNCTION_ROUTE` - Route under `/api` (default: the function name)
+/// - `FUNCTION_AUTH_LEVEL` - `anonymous`, `function`, or `admin` (default: `function`)
+/// - `REJECTED_QUEUE` - Storage queue for payloads that do not fit the table (default:
+///   unset, no rejected output)
+fn main() -> Result<()> {
+    let dir = std::env::args()
+        .nth(1)
+        .map(PathBuf::from)
+        .context("Usage: scaffold <app directory>")?;
+
+    let defaults = FunctionApp::default();
+    let function = std::env::var("FUNCTION_NAME").unwrap_or(defaults.function);
+    let app = FunctionApp {
+        route: std::env::var("FUNCTION_ROUTE").unwrap_or_else(|_| function.clone()),
+        function,
+        auth_level: std::env::var("FUNCTION_AUTH_LEVEL").unwrap_or(defaults.auth_level),
+        executable: defaults.executable,
+        rejected_queue: std::env::var("REJECTED_QUEUE")
+            .ok()
+            .filter(|queue| !queue.is_empty()),
+    };
+    app.write(&dir)?;
+    println!(
+        "Wrote host.json and {}/function.json to {}",
+        app.function,
+        dir.display()
+    );
+    Ok(())
+}
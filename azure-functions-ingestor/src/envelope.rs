@@ -0,0 +1,166 @@
+//! The custom handler request and response envelopes of the Azure Functions host
+//!
+//! The host POSTs each invocation to `/<function name>` as a JSON envelope holding the
+//! trigger's input under its binding name, and reads back an envelope holding a value
+//! for each output binding. See
+//! <https://learn.microsoft.com/azure/azure-functions/functions-custom-handlers>.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+/// One invocation, as sent by the host
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct InvokeRequest {
+    /// Input binding data by binding name
+    #[serde(rename = "Data", default)]
+    pub data: Map<String, Value>,
+    /// Trigger metadata, such as `sys.MethodName` and `sys.UtcNow`
+    #[serde(rename = "Metadata", default)]
+    pub metadata: Map<String, Value>,
+}
+
+/// The HTTP request of an HTTP trigger
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct HttpRequestData {
+    #[serde(rename = "Url", default)]
+    pub url: String,
+    #[serde(rename = "Method", default)]
+    pub method: String,
+    #[serde(rename = "Query", default)]
+    pub query: HashMap<String, String>,
+    /// Header values by name, as the host sends them
+    #[serde(rename = "Headers", default)]
+    pub headers: HashMap<String, Vec<String>>,
+    /// Route parameters
+    #[serde(rename = "Params", default)]
+    pub params: HashMap<String, String>,
+    #[serde(rename = "Body", default)]
+    pub body: Option<String>,
+}
+
+impl InvokeRequest {
+    /// The HTTP request of the trigger bound as `binding`
+    pub fn http_request(&self, binding: &str) -> Result<HttpRequestData> {
+        let data = self
+            .data
+            .get(binding)
+            .with_context(|| format!("Invocation has no {:?} input", binding))?;
+        serde_json::from_value(data.clone())
+            .with_context(|| format!("Input {:?} is not an HTTP request", binding))
+    }
+}
+
+impl HttpRequestData {
+    /// The first value of a header, matched case-insensitively
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .and_then(|(_, values)| values.first())
+            .map(String::as_str)
+    }
+}
+
+/// The answer to one invocation
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct InvokeResponse {
+    /// Output binding values by binding name
+    #[serde(rename = "Outputs")]
+    pub outputs: Map<String, Value>,
+    /// Lines written to the function's invocation log
+    #[serde(rename = "Logs")]
+    pub logs: Vec<String>,
+    #[serde(rename = "ReturnValue", skip_serializing_if = "Option::is_none")]
+    pub return_value: Option<Value>,
+}
+
+/// The HTTP response of an `http` output binding
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct HttpResponseData {
+    #[serde(rename = "statusCode")]
+    pub status_code: u16,
+    pub body: String,
+    pub headers: HashMap<String, String>,
+}
+
+impl HttpResponseData {
+    pub fn json(status_code: u16, body: &Value) -> Self {
+        Self {
+            status_code,
+            body: body.to_string(),
+            headers: [("Content-Type".to_string(), "application/json".to_string())].into(),
+        }
+    }
+}
+
+impl InvokeResponse {
+    /// Set the output binding `binding` to `value`
+    pub fn output(&mut self, binding: &str, value: impl Serialize) -> Result<()> {
+        let value = serde_json::to_value(value)
+            .with_context(|| format!("Failed to serialize output {:?}", binding))?;
+        self.outputs.insert(binding.to_string(), value);
+        Ok(())
+    }
+
+    pub fn log(&mut self, line: impl Into<String>) {
+        self.logs.push(line.into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_http_trigger_request() {
+        let request: InvokeRequest = serde_json::from_value(json!({
+            "Data": {
+                "req": {
+                    "Url": "https://orders-func.azurewebsites.net/api/ingest?source=pos",
+                    "Method": "POST",
+                    "Query": {"source": "pos"},
+                    "Headers": {"Content-Type": ["application/json"], "X-Request-Id": ["r-1"]},
+                    "Params": {},
+                    "Body": "{\"order_id\": 7}"
+                }
+            },
+            "Metadata": {"sys": {"MethodName": "ingest"}}
+        }))
+        .unwrap();
+
+        let http = request.http_request("req").unwrap();
+        assert_eq!("POST", http.method);
+        assert_eq!("pos", http.query["source"]);
+        assert_eq!(Some("application/json"), http.header("content-type"));
+        assert_eq!(Some("{\"order_id\": 7}"), http.body.as_deref());
+        assert!(request.http_request("trigger").is_err());
+    }
+
+    #[test]
+    fn test_response_envelope() {
+        let mut response = InvokeResponse::default();
+        response
+            .output("res", HttpResponseData::json(202, &json!({"ingested": 1})))
+            .unwrap();
+        response.output("rejected", vec!["{}"]).unwrap();
+        response.log("ingested 1 row");
+
+        assert_eq!(
+            json!({
+                "Outputs": {
+                    "res": {
+                        "statusCode": 202,
+                        "body": "{\"ingested\":1}",
+                        "headers": {"Content-Type": "application/json"}
+                    },
+                    "rejected": ["{}"]
+                },
+                "Logs": ["ingested 1 row"]
+            }),
+            serde_json::to_value(&response).unwrap()
+        );
+    }
+}
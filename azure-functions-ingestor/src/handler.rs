@@ -0,0 +1,262 @@
+//! The custom handler: one HTTP-triggered function ingesting JSON payloads
+
+use axum::extract::State;
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
+use zerobus_common::dynamic::DynamicEncoder;
+use zerobus_common::pipeline::{IngestSink, Pipeline};
+
+use crate::envelope::{HttpResponseData, InvokeRequest, InvokeResponse};
+
+/// Binding name of the HTTP trigger in function.json
+pub const REQUEST_BINDING: &str = "req";
+/// Binding name of the HTTP output in function.json
+pub const RESPONSE_BINDING: &str = "res";
+/// Binding name of the queue output receiving payloads that do not fit the table
+pub const REJECTED_BINDING: &str = "rejected";
+
+/// Encodes the payloads of each invocation and ingests them into one table
+pub struct Handler<S: IngestSink> {
+    encoder: DynamicEncoder,
+    pipeline: Mutex<Pipeline<S>>,
+    /// Whether the function has a `rejected` output binding to send rejects to
+    rejected_output: bool,
+}
+
+impl<S: IngestSink> Handler<S> {
+    pub fn new(encoder: DynamicEncoder, pipeline: Pipeline<S>, rejected_output: bool) -> Self {
+        Self {
+            encoder,
+            pipeline: Mutex::new(pipeline),
+            rejected_output,
+        }
+    }
+
+    /// Take the pipeline back once the server has stopped, so it can be finished
+    pub fn into_pipeline(self) -> Pipeline<S> {
+        self.pipeline.into_inner()
+    }
+
+    /// Answer one invocation
+    ///
+    /// The body is a JSON object or an array of objects, each becoming a row. The
+    /// HTTP response is 202 once every row that fits the table is durable, 400 when
+    /// the body is not JSON or no item fits, and 500 when rows are not acknowledged,
+    /// so the caller retries. Items that do not fit are written to the `rejected`
+    /// output binding, if the function has one.
+    pub async fn invoke(&self, request: &InvokeRequest) -> InvokeResponse {
+        let mut response = InvokeResponse::default();
+        let (status, body) = self.handle(request, &mut response).await;
+        let _ = response.output(RESPONSE_BINDING, HttpResponseData::json(status, &body));
+        response
+    }
+
+    async fn handle(&self, request: &InvokeRequest, response: &mut InvokeResponse) -> (u16, Value) {
+        let http = match request.http_request(REQUEST_BINDING) {
+            Ok(http) => http,
+            Err(e) => return bad_request(response, format!("{:#}", e)),
+        };
+        let payload: Value = match serde_json::from_str(http.body.as_deref().unwrap_or_default()) {
+            Ok(payload) => payload,
+            Err(e) => return bad_request(response, format!("Body is not valid JSON: {}", e)),
+        };
+        let items = match payload {
+            Value::Array(items) => items,
+            item => vec![item],
+        };
+
+        let mut records = Vec::with_capacity(items.len());
+        let mut rejected = Vec::new();
+        for item in items {
+            match self.encoder.encode(&item) {
+                Ok(record) => records.push(record),
+                Err(e) => {
+                    warn!("Rejecting item: {:#}", e);
+                    rejected.push(json!({"error": format!("{:#}", e), "item": item}).to_string());
+                }
+            }
+        }
+        if !rejected.is_empty() {
+            response.log(format!("Rejected {} items", rejected.len()));
+            if self.rejected_output {
+                let _ = response.output(REJECTED_BINDING, &rejected);
+            }
+        }
+        if records.is_empty() {
+            return (400, json!({"ingested": 0, "rejected": rejected.len()}));
+        }
+
+        let ingested = records.len();
+        let mut pipeline = self.pipeline.lock().await;
+        match pipeline.ingest_batch(records).await {
+            Ok(()) => {
+                info!("Ingested {} rows", ingested);
+                response.log(format!("Ingested {} rows", ingested));
+                (
+                    202,
+                    json!({"ingested": ingested, "rejected": rejected.len()}),
+                )
+            }
+            Err(e) => {
+                error!("Failed to ingest: {:#}", e);
+                response.log(format!("Failed to ingest: {:#}", e));
+                (500, json!({"error": format!("{:#}", e)}))
+            }
+        }
+    }
+}
+
+fn bad_request(response: &mut InvokeResponse, error: String) -> (u16, Value) {
+    warn!("Rejecting invocation: {}", error);
+    response.log(error.clone());
+    (400, json!({ "error": error }))
+}
+
+/// The function at `/<function>`, where the host sends its invocations, plus a health
+/// check
+pub fn router<S: IngestSink + 'static>(function: &str, handler: Arc<Handler<S>>) -> Router {
+    Router::new()
+        .route(&format!("/{}", function), post(invoke::<S>))
+        .route("/health", get(|| async { "OK" }))
+        .with_state(handler)
+}
+
+async fn invoke<S: IngestSink>(
+    State(handler): State<Arc<Handler<S>>>,
+    Json(request): Json<InvokeRequest>,
+) -> Json<InvokeResponse> {
+    Json(handler.invoke(&request).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use prost::Message;
+    use prost_types::field_descriptor_proto::{Label, Type};
+    use prost_types::{DescriptorProto, FieldDescriptorProto};
+    use tower::ServiceExt;
+    use zerobus_common::testing::MockSink;
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct OrderRow {
+        #[prost(string, optional, tag = "1")]
+        order_id: Option<String>,
+        #[prost(int64, optional, tag = "2")]
+        quantity: Option<i64>,
+    }
+
+    fn handler(sink: MockSink) -> Arc<Handler<MockSink>> {
+        let field = |name: &str, number: i32, r#type: Type| FieldDescriptorProto {
+            name: Some(name.to_string()),
+            number: Some(number),
+            label: Some(Label::Optional as i32),
+            r#type: Some(r#type as i32),
+            ..Default::default()
+        };
+        let descriptor = DescriptorProto {
+            name: Some("table_orders".to_string()),
+            field: vec![
+                field("order_id", 1, Type::String),
+                field("quantity", 2, Type::Int64),
+            ],
+            ..Default::default()
+        };
+        let encoder = DynamicEncoder::new(&descriptor).unwrap();
+        Arc::new(Handler::new(encoder, Pipeline::new(sink, 100), true))
+    }
+
+    /// Send `body` through the host envelope and return the response envelope
+    async fn invoke(handler: Arc<Handler<MockSink>>, body: &str) -> Value {
+        let envelope = json!({
+            "Data": {
+                "req": {
+                    "Url": "https://orders-func.azurewebsites.net/api/ingest",
+                    "Method": "POST",
+                    "Query": {},
+                    "Headers": {"Content-Type": ["application/json"]},
+                    "Params": {},
+                    "Body": body
+                }
+            },
+            "Metadata": {}
+        });
+        let request = Request::post("/ingest")
+            .header("Content-Type", "application/json")
+            .body(Body::from(envelope.to_string()))
+            .unwrap();
+        let response = router("ingest", handler).oneshot(request).await.unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    fn http_status(response: &Value) -> u64 {
+        response["Outputs"]["res"]["statusCode"].as_u64().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_payloads_are_ingested() {
+        let sink = MockSink::default();
+        let response = invoke(
+            handler(sink.clone()),
+            r#"[{"order_id": "o-1", "quantity": 2}, {"order_id": "o-2", "quantity": 5}]"#,
+        )
+        .await;
+
+        assert_eq!(202, http_status(&response));
+        assert_eq!(
+            r#"{"ingested":2,"rejected":0}"#,
+            response["Outputs"]["res"]["body"]
+        );
+        assert_eq!(None, response["Outputs"].get("rejected"));
+        assert_eq!(json!(["Ingested 2 rows"]), response["Logs"]);
+
+        let rows: Vec<OrderRow> = sink
+            .records()
+            .iter()
+            .map(|record| OrderRow::decode(record.as_slice()).unwrap())
+            .collect();
+        assert_eq!(Some("o-2".to_string()), rows[1].order_id);
+        assert_eq!(Some(5), rows[1].quantity);
+    }
+
+    #[tokio::test]
+    async fn test_items_that_do_not_fit_go_to_the_rejected_output() {
+        let sink = MockSink::default();
+        let response = invoke(
+            handler(sink.clone()),
+            r#"[{"order_id": "o-1"}, {"order_id": "o-2", "colour": "red"}, 7]"#,
+        )
+        .await;
+
+        assert_eq!(202, http_status(&response));
+        assert_eq!(1, sink.records().len());
+        let rejected = response["Outputs"]["rejected"].as_array().unwrap();
+        assert_eq!(2, rejected.len());
+        let first: Value = serde_json::from_str(rejected[0].as_str().unwrap()).unwrap();
+        assert_eq!("red", first["item"]["colour"]);
+
+        // Nothing fits, or nothing is JSON
+        let response = invoke(handler(sink.clone()), r#"{"colour": "red"}"#).await;
+        assert_eq!(400, http_status(&response));
+        let response = invoke(handler(sink.clone()), "order_id=o-1").await;
+        assert_eq!(400, http_status(&response));
+        assert_eq!(1, sink.records().len());
+    }
+
+    #[tokio::test]
+    async fn test_unacknowledged_rows_fail_the_invocation() {
+        let sink = MockSink::default().fail_acks_for(|_| true);
+        let response = invoke(handler(sink), r#"{"order_id": "o-1"}"#).await;
+
+        assert_eq!(500, http_status(&response));
+    }
+}
@@ -0,0 +1,251 @@
+//! Service principal credentials read from Azure Key Vault with a managed identity
+
+use anyhow::{bail, Context, Result};
+use reqwest::{Client, Url};
+use serde::Deserialize;
+use zerobus_common::credentials::{CredentialProvider, Credentials};
+
+/// Resource whose tokens Key Vault accepts
+const KEY_VAULT_RESOURCE: &str = "https://vault.azure.net";
+
+/// Key Vault REST API version of the secret requests
+const KEY_VAULT_API_VERSION: &str = "7.4";
+
+/// The Azure Instance Metadata Service, which serves managed identity tokens on VMs
+/// and containers outside App Service
+const IMDS_TOKEN_ENDPOINT: &str = "http://169.254.169.254/metadata/identity/oauth2/token";
+
+/// Where managed identity tokens come from
+#[derive(Debug, Clone, PartialEq)]
+pub enum IdentityEndpoint {
+    /// The App Service and Functions endpoint, from `IDENTITY_ENDPOINT` and
+    /// `IDENTITY_HEADER`
+    AppService { url: String, header: String },
+    /// The Instance Metadata Service
+    Imds { url: String },
+}
+
+impl IdentityEndpoint {
+    /// The App Service endpoint when the Functions host provides one, IMDS otherwise
+    pub fn from_env() -> Self {
+        match (
+            std::env::var("IDENTITY_ENDPOINT"),
+            std::env::var("IDENTITY_HEADER"),
+        ) {
+            (Ok(url), Ok(header)) => IdentityEndpoint::AppService { url, header },
+            _ => IdentityEndpoint::Imds {
+                url: IMDS_TOKEN_ENDPOINT.to_string(),
+            },
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Token {
+    access_token: String,
+}
+
+#[derive(Deserialize)]
+struct Secret {
+    value: String,
+}
+
+/// Reads the client ID and secret from two Key Vault secrets
+///
+/// Each call fetches a fresh token and the current secret versions, so a rotated
+/// secret is picked up the next time a stream is created.
+pub struct KeyVaultCredentials {
+    client: Client,
+    vault_url: Url,
+    identity: IdentityEndpoint,
+    /// Client ID of a user-assigned identity; the system-assigned one when unset
+    identity_client_id: Option<String>,
+    client_id_secret: String,
+    client_secret_secret: String,
+}
+
+impl KeyVaultCredentials {
+    pub fn new(
+        vault_url: &str,
+        identity: IdentityEndpoint,
+        identity_client_id: Option<String>,
+        client_id_secret: &str,
+        client_secret_secret: &str,
+    ) -> Result<Self> {
+        let vault_url =
+            Url::parse(vault_url).with_context(|| format!("Invalid vault URL {:?}", vault_url))?;
+        Ok(Self {
+            client: Client::new(),
+            vault_url,
+            identity,
+            identity_client_id,
+            client_id_secret: client_id_secret.to_string(),
+            client_secret_secret: client_secret_secret.to_string(),
+        })
+    }
+
+    /// A managed identity token for Key Vault
+    async fn token(&self) -> Result<String> {
+        let mut query = vec![("resource", KEY_VAULT_RESOURCE)];
+        if let Some(client_id) = &self.identity_client_id {
+            query.push(("client_id", client_id.as_str()));
+        }
+        let request = match &self.identity {
+            IdentityEndpoint::AppService { url, header } => {
+                query.push(("api-version", "2019-08-01"));
+                self.client
+                    .get(url)
+                    .query(&query)
+                    .header("X-IDENTITY-HEADER", header)
+            }
+            IdentityEndpoint::Imds { url } => {
+                query.push(("api-version", "2018-02-01"));
+                self.client
+                    .get(url)
+                    .query(&query)
+                    .header("Metadata", "true")
+            }
+        };
+
+        let response = request
+            .send()
+            .await
+            .context("Managed identity token request failed")?;
+        if !response.status().is_success() {
+            bail!(
+                "Managed identity endpoint answered {}: {}",
+                response.status(),
+                response.text().await.unwrap_or_default()
+            );
+        }
+        let token: Token = response
+            .json()
+            .await
+            .context("Invalid managed identity token response")?;
+        Ok(token.access_token)
+    }
+
+    async fn secret(&self, token: &str, name: &str) -> Result<String> {
+        let url = self
+            .vault_url
+            .join(&format!("secrets/{}", name))
+            .with_context(|| format!("Invalid secret name {:?}", name))?;
+        let response = self
+            .client
+            .get(url)
+            .query(&[("api-version", KEY_VAULT_API_VERSION)])
+            .bearer_auth(token)
+            .send()
+            .await
+            .with_context(|| format!("Request for secret {} failed", name))?;
+        if !response.status().is_success() {
+            bail!(
+                "Key Vault answered {} for secret {}",
+                response.status(),
+                name
+            );
+        }
+        let secret: Secret = response
+            .json()
+            .await
+            .with_context(|| format!("Invalid response for secret {}", name))?;
+        Ok(secret.value)
+    }
+}
+
+impl CredentialProvider for KeyVaultCredentials {
+    async fn credentials(&self) -> Result<Credentials> {
+        let token = self.token().await?;
+        Ok(Credentials {
+            client_id: self.secret(&token, &self.client_id_secret).await?,
+            client_secret: self.secret(&token, &self.client_secret_secret).await?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::{Path, Query};
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::get;
+    use axum::{Json, Router};
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+
+    /// A managed identity endpoint and a vault holding two secrets
+    async fn mock_azure() -> String {
+        let app = Router::new()
+            .route(
+                "/msi/token",
+                get(
+                    |headers: HeaderMap, Query(query): Query<HashMap<String, String>>| async move {
+                        if headers.get("X-IDENTITY-HEADER").map(|h| h.as_bytes())
+                            != Some(b"identity-secret".as_slice())
+                            || query.get("resource").map(String::as_str) != Some(KEY_VAULT_RESOURCE)
+                        {
+                            return Err(StatusCode::UNAUTHORIZED);
+                        }
+                        Ok(Json(
+                            json!({"access_token": "vault-token", "expires_on": "1718020800"}),
+                        ))
+                    },
+                ),
+            )
+            .route(
+                "/secrets/:name",
+                get(|headers: HeaderMap, Path(name): Path<String>| async move {
+                    if headers.get("Authorization").map(|h| h.as_bytes())
+                        != Some(b"Bearer vault-token".as_slice())
+                    {
+                        return Err(StatusCode::UNAUTHORIZED);
+                    }
+                    let value = match name.as_str() {
+                        "databricks-client-id" => "sp-id",
+                        "databricks-client-secret" => "sp-secret",
+                        _ => return Err(StatusCode::NOT_FOUND),
+                    };
+                    Ok(Json::<Value>(json!({"value": value, "id": name})))
+                }),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}", address)
+    }
+
+    fn provider(base: &str, header: &str, client_secret_secret: &str) -> KeyVaultCredentials {
+        KeyVaultCredentials::new(
+            &format!("{}/", base),
+            IdentityEndpoint::AppService {
+                url: format!("{}/msi/token", base),
+                header: header.to_string(),
+            },
+            None,
+            "databricks-client-id",
+            client_secret_secret,
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_credentials_from_key_vault() {
+        let base = mock_azure().await;
+
+        let credentials = provider(&base, "identity-secret", "databricks-client-secret")
+            .credentials()
+            .await
+            .unwrap();
+        assert_eq!("sp-id", credentials.client_id);
+        assert_eq!("sp-secret", credentials.client_secret);
+
+        assert!(provider(&base, "wrong", "databricks-client-secret")
+            .credentials()
+            .await
+            .is_err());
+        assert!(provider(&base, "identity-secret", "missing")
+            .credentials()
+            .await
+            .is_err());
+    }
+}
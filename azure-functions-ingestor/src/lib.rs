@@ -0,0 +1,4 @@
+pub mod envelope;
+pub mod handler;
+pub mod keyvault;
+pub mod scaffold;
@@ -0,0 +1,143 @@
+use anyhow::{bail, Context, Result};
+use azure_functions_ingestor::handler::{router, Handler, REJECTED_BINDING};
+use azure_functions_ingestor::keyvault::{IdentityEndpoint, KeyVaultCredentials};
+use azure_functions_ingestor::scaffold::has_output;
+use databricks_zerobus_ingest_sdk::{StreamConfigurationOptions, TableProperties, ZerobusSdk};
+use std::sync::Arc;
+use tracing::info;
+use zerobus_common::credentials::{CredentialProvider, Credentials, EnvCredentials};
+use zerobus_common::descriptor::find_message_descriptor;
+use zerobus_common::dynamic::{coerce_from_env, DynamicEncoder, FieldErrorMode, KeyCase};
+use zerobus_common::pipeline::Pipeline;
+use zerobus_common::shutdown;
+
+/// Maximum number of unacknowledged records on the stream
+const MAX_INFLIGHT_RECORDS: usize = 10_000;
+
+/// Port to listen on when the Functions host does not set FUNCTIONS_CUSTOMHANDLER_PORT
+const DEFAULT_PORT: &str = "8080";
+
+fn env(name: &str) -> Result<String> {
+    std::env::var(name).with_context(|| format!("{} environment variable must be set", name))
+}
+
+fn env_or(name: &str, default: &str) -> String {
+    std::env::var(name).unwrap_or_else(|_| default.to_string())
+}
+
+/// Credentials from Key Vault when KEY_VAULT_URL is set, from the environment otherwise
+async fn load_credentials() -> Result<Credentials> {
+    match std::env::var("KEY_VAULT_URL") {
+        Ok(vault_url) => {
+            info!("Reading credentials from {}", vault_url);
+            KeyVaultCredentials::new(
+                &vault_url,
+                IdentityEndpoint::from_env(),
+                std::env::var("AZURE_CLIENT_ID").ok(),
+                &env_or("KEY_VAULT_CLIENT_ID_SECRET", "databricks-client-id"),
+                &env_or("KEY_VAULT_CLIENT_SECRET_SECRET", "databricks-client-secret"),
+            )?
+            .credentials()
+            .await
+        }
+        Err(_) => EnvCredentials::default().credentials().await,
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .with_target(false)
+        .init();
+
+    let zerobus_endpoint = env("ZEROBUS_ENDPOINT")?;
+    let databricks_host = env("DATABRICKS_HOST")?;
+    let table_name = env("TABLE_NAME")?;
+    let descriptor_set = env_or("DESCRIPTOR_SET", "gen/descriptors/tables.descriptor");
+    let message_name = std::env::var("MESSAGE_NAME").unwrap_or_else(|_| {
+        format!(
+            "table_{}",
+            table_name.rsplit('.').next().unwrap_or(&table_name)
+        )
+    });
+    let ignore_unknown_fields = std::env::var("IGNORE_UNKNOWN_FIELDS")
+        .map(|value| value == "true" || value == "1")
+        .unwrap_or(false);
+    let function = env_or("FUNCTION_NAME", "ingest");
+    let port = env_or("FUNCTIONS_CUSTOMHANDLER_PORT", DEFAULT_PORT);
+    let grace = shutdown::grace_from_env()?;
+
+    let bytes = std::fs::read(&descriptor_set)
+        .with_context(|| format!("Failed to read descriptor set {}", descriptor_set))?;
+    let descriptor = find_message_descriptor(&bytes, &message_name)?;
+    let encoder = DynamicEncoder::new(&descriptor)?
+        .ignore_unknown_fields(ignore_unknown_fields)
+        .coerce_types(coerce_from_env()?)
+        .field_error_mode(FieldErrorMode::from_env()?)
+        .key_case(KeyCase::from_env()?);
+
+    // Rejected payloads are only written when the deployed function binds the queue
+    let function_json = format!("{}/function.json", function);
+    let rejected_output = match std::fs::read_to_string(&function_json) {
+        Ok(json) => has_output(&serde_json::from_str(&json)?, REJECTED_BINDING),
+        Err(_) => false,
+    };
+
+    let credentials = load_credentials().await?;
+    let sdk = ZerobusSdk::new(zerobus_endpoint, databricks_host)?;
+    let table_properties = TableProperties {
+        table_name: table_name.clone(),
+        descriptor_proto: descriptor,
+    };
+    let stream_options = StreamConfigurationOptions {
+        max_inflight_records: MAX_INFLIGHT_RECORDS,
+        ..Default::default()
+    };
+    let stream = sdk
+        .create_stream(
+            table_properties,
+            credentials.client_id,
+            credentials.client_secret,
+            Some(stream_options),
+        )
+        .await
+        .with_context(|| format!("Failed to create stream to {}", table_name))?;
+
+    let handler = Arc::new(Handler::new(
+        encoder,
+        Pipeline::new(stream, MAX_INFLIGHT_RECORDS),
+        rejected_output,
+    ));
+
+    let listen_addr = format!("127.0.0.1:{}", port);
+    let listener = tokio::net::TcpListener::bind(&listen_addr)
+        .await
+        .with_context(|| format!("Failed to bind {}", listen_addr))?;
+    info!(
+        "Handling {} invocations on http://{} -> {}",
+        function, listen_addr, table_name
+    );
+
+    axum::serve(listener, router(&function, Arc::clone(&handler)))
+        .with_graceful_shutdown(shutdown::signal())
+        .await?;
+
+    // Every invocation has returned, so this is the last reference to the handler
+    let Ok(handler) = Arc::try_unwrap(handler) else {
+        bail!("Handler is still in use after shutdown");
+    };
+    let outcome = shutdown::drain(handler.into_pipeline(), grace).await?;
+    info!(
+        "Shut down after ingesting {} rows ({} failed)",
+        outcome.summary.ingested, outcome.summary.failed
+    );
+    if !outcome.unacked.is_empty() {
+        bail!(
+            "{} rows were not acknowledged before shutdown",
+            outcome.unacked.len()
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,156 @@
+//! The host.json and function.json files a custom handler app is deployed with
+
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use std::path::Path;
+
+use crate::handler::{REJECTED_BINDING, REQUEST_BINDING, RESPONSE_BINDING};
+
+/// What the generated function looks like
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionApp {
+    /// Function name, which is also the path the host POSTs invocations to
+    pub function: String,
+    /// Route under `/api` the function is triggered on
+    pub route: String,
+    /// `anonymous`, `function`, or `admin`
+    pub auth_level: String,
+    /// File name of the handler executable in the app
+    pub executable: String,
+    /// Storage queue rejected payloads are written to; no rejected output when unset
+    pub rejected_queue: Option<String>,
+}
+
+impl Default for FunctionApp {
+    fn default() -> Self {
+        Self {
+            function: "ingest".to_string(),
+            route: "ingest".to_string(),
+            auth_level: "function".to_string(),
+            executable: "handler".to_string(),
+            rejected_queue: None,
+        }
+    }
+}
+
+impl FunctionApp {
+    /// host.json, running the handler as a custom handler that receives the invocation
+    /// envelope rather than forwarded HTTP requests
+    pub fn host_json(&self) -> Value {
+        json!({
+            "version": "2.0",
+            "logging": {
+                "applicationInsights": {
+                    "samplingSettings": {"isEnabled": true, "excludedTypes": "Request"}
+                }
+            },
+            "extensionBundle": {
+                "id": "Microsoft.Azure.Functions.ExtensionBundle",
+                "version": "[4.*, 5.0.0)"
+            },
+            "customHandler": {
+                "description": {
+                    "defaultExecutablePath": self.executable,
+                    "workingDirectory": "",
+                    "arguments": []
+                },
+                "enableForwardingHttpRequest": false
+            }
+        })
+    }
+
+    /// function.json of the HTTP-triggered function and its output bindings
+    pub fn function_json(&self) -> Value {
+        let mut bindings = vec![
+            json!({
+                "authLevel": self.auth_level,
+                "type": "httpTrigger",
+                "direction": "in",
+                "name": REQUEST_BINDING,
+                "methods": ["post"],
+                "route": self.route
+            }),
+            json!({"type": "http", "direction": "out", "name": RESPONSE_BINDING}),
+        ];
+        if let Some(queue) = &self.rejected_queue {
+            bindings.push(json!({
+                "type": "queue",
+                "direction": "out",
+                "name": REJECTED_BINDING,
+                "queueName": queue,
+                "connection": "AzureWebJobsStorage"
+            }));
+        }
+        json!({ "bindings": bindings })
+    }
+
+    /// Write `host.json` and `<function>/function.json` under `dir`
+    pub fn write(&self, dir: &Path) -> Result<()> {
+        let function_dir = dir.join(&self.function);
+        std::fs::create_dir_all(&function_dir)
+            .with_context(|| format!("Failed to create {}", function_dir.display()))?;
+        for (path, value) in [
+            (dir.join("host.json"), self.host_json()),
+            (function_dir.join("function.json"), self.function_json()),
+        ] {
+            let json = serde_json::to_string_pretty(&value)?;
+            std::fs::write(&path, json + "\n")
+                .with_context(|| format!("Failed to write {}", path.display()))?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether a function.json has an output binding named `binding`
+pub fn has_output(function_json: &Value, binding: &str) -> bool {
+    function_json["bindings"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .any(|b| b["direction"] == "out" && b["name"] == binding)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scaffolding() {
+        let dir = tempfile::tempdir().unwrap();
+        let app = FunctionApp {
+            rejected_queue: Some("ingest-rejected".to_string()),
+            ..Default::default()
+        };
+        app.write(dir.path()).unwrap();
+
+        let host: Value =
+            serde_json::from_slice(&std::fs::read(dir.path().join("host.json")).unwrap()).unwrap();
+        assert_eq!(
+            "handler",
+            host["customHandler"]["description"]["defaultExecutablePath"]
+        );
+        assert_eq!(
+            Value::Bool(false),
+            host["customHandler"]["enableForwardingHttpRequest"]
+        );
+
+        let function: Value = serde_json::from_slice(
+            &std::fs::read(dir.path().join("ingest/function.json")).unwrap(),
+        )
+        .unwrap();
+        let names: Vec<&str> = function["bindings"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|binding| binding["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(vec!["req", "res", "rejected"], names);
+        assert_eq!("ingest-rejected", function["bindings"][2]["queueName"]);
+        assert!(has_output(&function, REJECTED_BINDING));
+
+        // Without a queue there is no rejected output
+        let function = FunctionApp::default().function_json();
+        assert_eq!(2, function["bindings"].as_array().unwrap().len());
+        assert!(!has_output(&function, REJECTED_BINDING));
+    }
+}
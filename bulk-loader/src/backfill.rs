@@ -0,0 +1,977 @@
+//! Backfilling JSONL or Parquet objects from S3, with a manifest of what each object
+//! loaded
+
+use anyhow::{bail, Context, Result};
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::Client;
+use bytes::Bytes;
+use futures::{stream, StreamExt};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+use tracing::{info, warn};
+use zerobus_common::durable;
+use zerobus_common::dynamic::DynamicEncoder;
+use zerobus_common::pipeline::{IngestSink, Pipeline};
+use zerobus_common::s3::{decompress, open_object};
+
+use crate::column_map::ColumnMap;
+use crate::parquet_file::parquet_rows;
+use crate::rate::RateLimiter;
+
+/// One page of a bucket listing
+#[derive(Debug, Default)]
+pub struct ListPage {
+    pub keys: Vec<String>,
+    /// Token for the next page, if there is one
+    pub next: Option<String>,
+}
+
+/// The S3 operations the backfill needs
+///
+/// Implemented for the S3 client; tests substitute an in-memory bucket.
+pub trait ObjectStore: Sync {
+    fn list_page(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        token: Option<String>,
+    ) -> impl Future<Output = Result<ListPage>> + Send;
+
+    /// Open an object for reading, gunzipping `.gz` objects
+    fn open(
+        &self,
+        bucket: &str,
+        key: &str,
+    ) -> impl Future<Output = Result<Box<dyn AsyncBufRead + Unpin + Send>>> + Send;
+
+    /// Read a whole object, or `None` if it does not exist
+    fn get(&self, bucket: &str, key: &str) -> impl Future<Output = Result<Option<Vec<u8>>>> + Send;
+
+    fn put(
+        &self,
+        bucket: &str,
+        key: &str,
+        body: Vec<u8>,
+    ) -> impl Future<Output = Result<()>> + Send;
+}
+
+impl ObjectStore for Client {
+    async fn list_page(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        token: Option<String>,
+    ) -> Result<ListPage> {
+        let output = self
+            .list_objects_v2()
+            .bucket(bucket)
+            .prefix(prefix)
+            .set_continuation_token(token)
+            .send()
+            .await
+            .with_context(|| format!("Failed to list s3://{}/{}", bucket, prefix))?;
+        Ok(ListPage {
+            keys: output
+                .contents()
+                .iter()
+                .filter_map(|object| object.key().map(str::to_string))
+                .collect(),
+            next: output
+                .next_continuation_token()
+                .filter(|_| output.is_truncated().unwrap_or_default())
+                .map(str::to_string),
+        })
+    }
+
+    async fn open(&self, bucket: &str, key: &str) -> Result<Box<dyn AsyncBufRead + Unpin + Send>> {
+        open_object(self, bucket, key).await
+    }
+
+    async fn get(&self, bucket: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        let object = match self.get_object().bucket(bucket).key(key).send().await {
+            Ok(object) => object,
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_no_such_key()) => return Ok(None),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to get s3://{}/{}", bucket, key))
+            }
+        };
+        let body = object
+            .body
+            .collect()
+            .await
+            .with_context(|| format!("Failed to read s3://{}/{}", bucket, key))?;
+        Ok(Some(body.into_bytes().to_vec()))
+    }
+
+    async fn put(&self, bucket: &str, key: &str, body: Vec<u8>) -> Result<()> {
+        self.put_object()
+            .bucket(bucket)
+            .key(key)
+            .body(ByteStream::from(body))
+            .send()
+            .await
+            .with_context(|| format!("Failed to put s3://{}/{}", bucket, key))?;
+        Ok(())
+    }
+}
+
+/// Every key under `prefix`, following continuation tokens
+///
+/// Keys ending in `/` are folder placeholders and are left out.
+pub async fn list_keys<St: ObjectStore>(
+    store: &St,
+    bucket: &str,
+    prefix: &str,
+) -> Result<Vec<String>> {
+    let mut keys = Vec::new();
+    let mut token = None;
+    loop {
+        let page = store.list_page(bucket, prefix, token).await?;
+        keys.extend(page.keys.into_iter().filter(|key| !key.ends_with('/')));
+        match page.next {
+            Some(next) => token = Some(next),
+            None => return Ok(keys),
+        }
+    }
+}
+
+/// What loading one object did
+#[derive(Debug, Clone, PartialEq)]
+pub struct ManifestEntry {
+    pub key: String,
+    /// Rows acknowledged; in a dry run, rows that parsed and encoded
+    pub rows_ingested: u64,
+    /// Malformed rows plus rows that were not acknowledged
+    pub rows_failed: u64,
+    pub first_error: Option<String>,
+    /// The object was read to the end and every row that encoded was acknowledged
+    ///
+    /// Complete objects are skipped on rerun. Malformed rows do not make an object
+    /// incomplete, since loading it again would fail them the same way.
+    pub complete: bool,
+}
+
+impl ManifestEntry {
+    fn new(key: &str) -> Self {
+        Self {
+            key: key.to_string(),
+            rows_ingested: 0,
+            rows_failed: 0,
+            first_error: None,
+            complete: false,
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "key": self.key,
+            "rows_ingested": self.rows_ingested,
+            "rows_failed": self.rows_failed,
+            "first_error": self.first_error,
+            "complete": self.complete,
+        })
+    }
+
+    fn from_json(value: &Value) -> Option<Self> {
+        Some(Self {
+            key: value["key"].as_str()?.to_string(),
+            rows_ingested: value["rows_ingested"].as_u64().unwrap_or_default(),
+            rows_failed: value["rows_failed"].as_u64().unwrap_or_default(),
+            first_error: value["first_error"].as_str().map(str::to_string),
+            complete: value["complete"].as_bool().unwrap_or_default(),
+        })
+    }
+}
+
+/// One entry per object, written as JSON lines sorted by key
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Manifest {
+    entries: BTreeMap<String, ManifestEntry>,
+}
+
+impl Manifest {
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut entries = BTreeMap::new();
+        for (i, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry = serde_json::from_str::<Value>(line)
+                .ok()
+                .as_ref()
+                .and_then(ManifestEntry::from_json)
+                .with_context(|| format!("Malformed manifest entry on line {}", i + 1))?;
+            entries.insert(entry.key.clone(), entry);
+        }
+        Ok(Self { entries })
+    }
+
+    pub fn to_jsonl(&self) -> String {
+        self.entries
+            .values()
+            .map(|entry| format!("{}\n", entry.to_json()))
+            .collect()
+    }
+
+    pub fn is_complete(&self, key: &str) -> bool {
+        self.entries.get(key).is_some_and(|entry| entry.complete)
+    }
+
+    /// Record the outcome of an object, replacing the entry of an earlier run
+    pub fn insert(&mut self, entry: ManifestEntry) {
+        self.entries.insert(entry.key.clone(), entry);
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &ManifestEntry> {
+        self.entries.values()
+    }
+}
+
+/// Where the manifest is read from and written to
+#[derive(Debug, Clone, PartialEq)]
+pub enum ManifestLocation {
+    Local(PathBuf),
+    S3 { bucket: String, key: String },
+}
+
+impl ManifestLocation {
+    /// `s3://<bucket>/<key>`, or else a local path
+    pub fn parse(location: &str) -> Result<Self> {
+        match location.strip_prefix("s3://") {
+            Some(rest) => match rest.split_once('/') {
+                Some((bucket, key)) if !bucket.is_empty() && !key.is_empty() => Ok(Self::S3 {
+                    bucket: bucket.to_string(),
+                    key: key.to_string(),
+                }),
+                _ => bail!(
+                    "S3 manifest location must be s3://<bucket>/<key>, got {:?}",
+                    location
+                ),
+            },
+            None => Ok(Self::Local(PathBuf::from(location))),
+        }
+    }
+
+    /// The manifest of an earlier run; empty if there is none yet
+    pub async fn load<St: ObjectStore>(&self, store: &St) -> Result<Manifest> {
+        let text = match self {
+            Self::Local(path) => match std::fs::read_to_string(path) {
+                Ok(text) => text,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    return Ok(Manifest::default())
+                }
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Failed to read {}", path.display()))
+                }
+            },
+            Self::S3 { bucket, key } => match store.get(bucket, key).await? {
+                Some(bytes) => String::from_utf8(bytes).context("Manifest is not valid UTF-8")?,
+                None => return Ok(Manifest::default()),
+            },
+        };
+        Manifest::parse(&text)
+    }
+
+    pub async fn save<St: ObjectStore>(&self, store: &St, manifest: &Manifest) -> Result<()> {
+        let text = manifest.to_jsonl();
+        match self {
+            // An interrupted save, even by a power loss, keeps the previous manifest. It
+            // is read as is, since a run that found no objects leaves an empty one.
+            Self::Local(path) => durable::replace_file(path, text.as_bytes()),
+            Self::S3 { bucket, key } => store.put(bucket, key, text.into_bytes()).await,
+        }
+    }
+}
+
+/// Opens a stream per object
+pub trait SinkFactory: Sync {
+    type Sink: IngestSink;
+
+    fn open(&self) -> impl Future<Output = Result<Self::Sink>> + Send;
+}
+
+/// How the objects under the prefix are encoded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ObjectFormat {
+    /// One JSON object per line, gzipped when the key ends in `.gz`
+    #[default]
+    Jsonl,
+    /// Apache Parquet, read into memory whole and decoded one row group at a time
+    Parquet,
+}
+
+pub struct BackfillOptions {
+    pub bucket: String,
+    pub prefix: String,
+    pub format: ObjectFormat,
+    /// Fields read from columns of another name
+    pub column_map: Option<ColumnMap>,
+    /// Objects loaded at the same time, one stream each
+    pub concurrency: usize,
+    /// Rows sent before waiting for their acknowledgments
+    pub max_inflight: usize,
+    /// Parse and encode every row without opening streams
+    pub dry_run: bool,
+}
+
+/// Load every object under the prefix that the manifest does not mark complete
+///
+/// Outcomes are recorded in `manifest`; the entries of this run are also returned.
+pub async fn backfill<St: ObjectStore, F: SinkFactory>(
+    store: &St,
+    factory: &F,
+    encoder: &DynamicEncoder,
+    options: &BackfillOptions,
+    limiter: Option<&RateLimiter>,
+    manifest: &mut Manifest,
+) -> Result<Vec<ManifestEntry>> {
+    let keys = list_keys(store, &options.bucket, &options.prefix).await?;
+    let total = keys.len();
+    let pending: Vec<String> = keys
+        .into_iter()
+        .filter(|key| !manifest.is_complete(key))
+        .collect();
+    info!(
+        "{} objects under s3://{}/{}, {} already complete",
+        total,
+        options.bucket,
+        options.prefix,
+        total - pending.len()
+    );
+
+    let entries: Vec<ManifestEntry> = stream::iter(&pending)
+        .map(|key| load_object(store, factory, encoder, options, limiter, key))
+        .buffer_unordered(options.concurrency.max(1))
+        .collect()
+        .await;
+
+    if !options.dry_run {
+        for entry in &entries {
+            manifest.insert(entry.clone());
+        }
+    }
+    Ok(entries)
+}
+
+async fn load_object<St: ObjectStore, F: SinkFactory>(
+    store: &St,
+    factory: &F,
+    encoder: &DynamicEncoder,
+    options: &BackfillOptions,
+    limiter: Option<&RateLimiter>,
+    key: &str,
+) -> ManifestEntry {
+    let started = Instant::now();
+    let object = match options.format {
+        ObjectFormat::Jsonl => store.open(&options.bucket, key).await.map(Object::Lines),
+        ObjectFormat::Parquet => match store.get(&options.bucket, key).await {
+            Ok(Some(bytes)) => Ok(Object::Parquet(bytes)),
+            Ok(None) => Err(anyhow::anyhow!("Object no longer exists")),
+            Err(e) => Err(e),
+        },
+    };
+    let object = match object {
+        Ok(object) => object,
+        Err(e) => return failed_entry(key, e),
+    };
+    let pipeline = if options.dry_run {
+        None
+    } else {
+        match factory.open().await {
+            Ok(sink) => Some(Pipeline::new(sink, options.max_inflight)),
+            Err(e) => return failed_entry(key, e),
+        }
+    };
+    let load = ObjectLoad::new(key, encoder, options.column_map.as_ref(), pipeline, limiter);
+    let entry = match object {
+        Object::Lines(reader) => load_lines(load, reader).await,
+        Object::Parquet(bytes) => load_parquet(load, bytes).await,
+    };
+    let elapsed = started.elapsed();
+    info!(
+        "{}: {} rows ingested, {} failed in {:.1?} ({:.0} rows/s)",
+        key,
+        entry.rows_ingested,
+        entry.rows_failed,
+        elapsed,
+        rows_per_second(entry.rows_ingested, elapsed)
+    );
+    entry
+}
+
+/// An object opened for reading, in the form its format is read from
+enum Object {
+    Lines(Box<dyn AsyncBufRead + Unpin + Send>),
+    Parquet(Vec<u8>),
+}
+
+/// Rows per second over `elapsed`, for the throughput the logs and summary report
+pub fn rows_per_second(rows: u64, elapsed: Duration) -> f64 {
+    rows as f64 / elapsed.as_secs_f64().max(f64::EPSILON)
+}
+
+fn failed_entry(key: &str, error: anyhow::Error) -> ManifestEntry {
+    warn!("{}: {:#}", key, error);
+    let mut entry = ManifestEntry::new(key);
+    entry.first_error = Some(format!("{:#}", error));
+    entry
+}
+
+/// The rows of one object on their way through the pipeline
+///
+/// Without a pipeline, rows are only mapped and encoded, and counted as ingested.
+pub struct ObjectLoad<'a, S: IngestSink> {
+    entry: ManifestEntry,
+    encoder: &'a DynamicEncoder,
+    column_map: Option<&'a ColumnMap>,
+    pipeline: Option<Pipeline<S>>,
+    limiter: Option<&'a RateLimiter>,
+    malformed: u64,
+    encoded: u64,
+}
+
+impl<'a, S: IngestSink> ObjectLoad<'a, S> {
+    pub fn new(
+        key: &str,
+        encoder: &'a DynamicEncoder,
+        column_map: Option<&'a ColumnMap>,
+        pipeline: Option<Pipeline<S>>,
+        limiter: Option<&'a RateLimiter>,
+    ) -> Self {
+        Self {
+            entry: ManifestEntry::new(key),
+            encoder,
+            column_map,
+            pipeline,
+            limiter,
+            malformed: 0,
+            encoded: 0,
+        }
+    }
+
+    /// Count a row that could not be read, keeping the first error
+    fn malformed(&mut self, location: &str, error: anyhow::Error) {
+        self.malformed += 1;
+        self.entry
+            .first_error
+            .get_or_insert_with(|| format!("{}: {:#}", location, error));
+    }
+
+    /// Map, encode, and send one row; `false` once the stream is broken and the rest
+    /// of the object has to wait for a rerun
+    async fn send(&mut self, location: &str, mut value: Value) -> bool {
+        if let Some(column_map) = self.column_map {
+            column_map.apply(&mut value);
+        }
+        let record = match self.encoder.encode(&value) {
+            Ok(record) => record,
+            Err(e) => {
+                self.malformed(location, e);
+                return true;
+            }
+        };
+        self.encoded += 1;
+
+        let Some(pipeline) = self.pipeline.as_mut() else {
+            return true;
+        };
+        if let Some(limiter) = self.limiter {
+            limiter.acquire().await;
+        }
+        match pipeline.ingest(record).await {
+            Ok(()) => true,
+            Err(e) => {
+                self.entry.first_error.get_or_insert(format!("{:#}", e));
+                false
+            }
+        }
+    }
+
+    /// Record why the object could not be read to the end
+    fn read_failed(&mut self, error: impl std::fmt::Display) {
+        self.entry.first_error.get_or_insert(error.to_string());
+    }
+
+    /// Wait for the rows that were sent, and record the outcome
+    async fn finish(mut self, read_to_end: bool) -> ManifestEntry {
+        match self.pipeline {
+            Some(pipeline) => {
+                // Counts up to the last drain, in case the final one fails
+                let drained = pipeline.summary().clone();
+                let (summary, finished) = match pipeline.finish().await {
+                    Ok(summary) => (summary, true),
+                    Err(e) => {
+                        self.entry.first_error.get_or_insert(format!("{:#}", e));
+                        (drained, false)
+                    }
+                };
+                self.entry.rows_ingested = summary.ingested;
+                self.entry.rows_failed = self.malformed + summary.failed;
+                if let Some(error) = summary.first_error {
+                    self.entry.first_error.get_or_insert(error);
+                }
+                self.entry.complete = read_to_end && finished && summary.failed == 0;
+            }
+            None => {
+                self.entry.rows_ingested = self.encoded;
+                self.entry.rows_failed = self.malformed;
+                self.entry.complete = read_to_end;
+            }
+        }
+        self.entry
+    }
+}
+
+/// Stream the JSONL rows of one object through the pipeline
+pub async fn load_lines<S: IngestSink>(
+    mut load: ObjectLoad<'_, S>,
+    reader: impl AsyncBufRead + Unpin,
+) -> ManifestEntry {
+    let mut lines = reader.lines();
+    let mut line_number = 0u64;
+
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => return load.finish(true).await,
+            Err(e) => {
+                load.read_failed(format!("Failed to read object: {}", e));
+                return load.finish(false).await;
+            }
+        };
+        line_number += 1;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let location = format!("line {}", line_number);
+        match serde_json::from_str(&line).context("Malformed JSON") {
+            Ok(value) => {
+                if !load.send(&location, value).await {
+                    return load.finish(false).await;
+                }
+            }
+            Err(e) => load.malformed(&location, e),
+        }
+    }
+}
+
+/// Stream the rows of one Parquet object through the pipeline
+pub async fn load_parquet<S: IngestSink>(
+    mut load: ObjectLoad<'_, S>,
+    bytes: Vec<u8>,
+) -> ManifestEntry {
+    let rows = match parquet_rows(Bytes::from(bytes)) {
+        Ok(rows) => rows,
+        Err(e) => {
+            load.read_failed(format!("{:#}", e));
+            return load.finish(false).await;
+        }
+    };
+    for row in rows {
+        let row = match row {
+            Ok(row) => row,
+            // A row group that cannot be decoded ends the object
+            Err(e) => {
+                load.read_failed(format!("{:#}", e));
+                return load.finish(false).await;
+            }
+        };
+        let location = format!("row {}", row.number);
+        match row.value {
+            Ok(value) => {
+                if !load.send(&location, value).await {
+                    return load.finish(false).await;
+                }
+            }
+            Err(e) => load.malformed(&location, e),
+        }
+    }
+    load.finish(true).await
+}
+
+/// Decompress an in-memory object the same way S3 objects are read
+pub fn open_bytes(key: &str, bytes: Vec<u8>) -> Box<dyn AsyncBufRead + Unpin + Send> {
+    decompress(key, std::io::Cursor::new(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::{ArrayRef, Int64Array, RecordBatch};
+    use flate2::write::GzEncoder;
+    use parquet::arrow::ArrowWriter;
+    use parquet::file::properties::WriterProperties;
+    use prost_types::field_descriptor_proto::{Label, Type};
+    use prost_types::{DescriptorProto, FieldDescriptorProto};
+    use std::collections::HashMap;
+    use std::io::Write;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use zerobus_common::testing::MockSink;
+
+    /// A bucket in memory that lists `page_size` keys per page
+    #[derive(Default)]
+    struct MockStore {
+        objects: Mutex<BTreeMap<String, Vec<u8>>>,
+        page_size: usize,
+        list_calls: AtomicUsize,
+    }
+
+    impl MockStore {
+        fn with_objects(page_size: usize, objects: &[(&str, Vec<u8>)]) -> Self {
+            Self {
+                objects: Mutex::new(
+                    objects
+                        .iter()
+                        .map(|(key, body)| (key.to_string(), body.clone()))
+                        .collect(),
+                ),
+                page_size,
+                list_calls: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    impl ObjectStore for MockStore {
+        async fn list_page(
+            &self,
+            _bucket: &str,
+            prefix: &str,
+            token: Option<String>,
+        ) -> Result<ListPage> {
+            self.list_calls.fetch_add(1, Ordering::SeqCst);
+            let start: usize = token.map(|t| t.parse().unwrap()).unwrap_or_default();
+            let keys: Vec<String> = self
+                .objects
+                .lock()
+                .unwrap()
+                .keys()
+                .filter(|key| key.starts_with(prefix))
+                .cloned()
+                .collect();
+            let end = (start + self.page_size).min(keys.len());
+            Ok(ListPage {
+                keys: keys[start..end].to_vec(),
+                next: (end < keys.len()).then(|| end.to_string()),
+            })
+        }
+
+        async fn open(
+            &self,
+            _bucket: &str,
+            key: &str,
+        ) -> Result<Box<dyn AsyncBufRead + Unpin + Send>> {
+            let bytes = self.objects.lock().unwrap().get(key).cloned();
+            Ok(open_bytes(key, bytes.context("No such key")?))
+        }
+
+        async fn get(&self, _bucket: &str, key: &str) -> Result<Option<Vec<u8>>> {
+            Ok(self.objects.lock().unwrap().get(key).cloned())
+        }
+
+        async fn put(&self, _bucket: &str, key: &str, body: Vec<u8>) -> Result<()> {
+            self.objects.lock().unwrap().insert(key.to_string(), body);
+            Ok(())
+        }
+    }
+
+    /// Hands out clones of one sink, so every object's rows land in it
+    struct MockFactory(MockSink);
+
+    impl SinkFactory for MockFactory {
+        type Sink = MockSink;
+
+        async fn open(&self) -> Result<MockSink> {
+            Ok(self.0.clone())
+        }
+    }
+
+    fn encoder() -> DynamicEncoder {
+        let descriptor = DescriptorProto {
+            name: Some("event".to_string()),
+            field: vec![FieldDescriptorProto {
+                name: Some("id".to_string()),
+                number: Some(1),
+                label: Some(Label::Optional as i32),
+                r#type: Some(Type::Int64 as i32),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        DynamicEncoder::new(&descriptor).unwrap()
+    }
+
+    fn jsonl(ids: &[i64]) -> Vec<u8> {
+        ids.iter()
+            .map(|id| format!("{{\"id\": {}}}\n", id))
+            .collect::<String>()
+            .into_bytes()
+    }
+
+    fn gzip(bytes: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(bytes).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// A Parquet file holding `ids` in a `legacy_id` column, two rows per row group
+    fn parquet(ids: &[i64]) -> Vec<u8> {
+        let column: ArrayRef = Arc::new(Int64Array::from(ids.to_vec()));
+        let batch = RecordBatch::try_from_iter([("legacy_id", column)]).unwrap();
+        let properties = WriterProperties::builder()
+            .set_max_row_group_size(2)
+            .build();
+        let mut writer =
+            ArrowWriter::try_new(Vec::new(), batch.schema(), Some(properties)).unwrap();
+        writer.write(&batch).unwrap();
+        writer.into_inner().unwrap()
+    }
+
+    fn options(dry_run: bool) -> BackfillOptions {
+        BackfillOptions {
+            bucket: "bucket".to_string(),
+            prefix: "events/".to_string(),
+            format: ObjectFormat::Jsonl,
+            column_map: None,
+            concurrency: 2,
+            max_inflight: 2,
+            dry_run,
+        }
+    }
+
+    fn by_key(entries: Vec<ManifestEntry>) -> HashMap<String, ManifestEntry> {
+        entries.into_iter().map(|e| (e.key.clone(), e)).collect()
+    }
+
+    #[tokio::test]
+    async fn test_list_keys_follows_pages() {
+        let objects: Vec<(String, Vec<u8>)> = (0..5)
+            .map(|i| (format!("events/{}.jsonl", i), Vec::new()))
+            .chain([
+                ("events/".to_string(), Vec::new()),
+                ("other/x".to_string(), Vec::new()),
+            ])
+            .collect();
+        let objects: Vec<(&str, Vec<u8>)> = objects
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.clone()))
+            .collect();
+        let store = MockStore::with_objects(2, &objects);
+
+        let keys = list_keys(&store, "bucket", "events/").await.unwrap();
+
+        assert_eq!(
+            vec![
+                "events/0.jsonl",
+                "events/1.jsonl",
+                "events/2.jsonl",
+                "events/3.jsonl",
+                "events/4.jsonl"
+            ],
+            keys
+        );
+        // Six keys under the prefix, two per page
+        assert_eq!(3, store.list_calls.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_gzip_objects_and_malformed_rows() {
+        let mut plain = jsonl(&[1, 2]);
+        plain.extend_from_slice(b"{oops\n\n{\"id\": \"x\"}\n");
+        let store = MockStore::with_objects(
+            10,
+            &[
+                ("events/a.jsonl.gz", gzip(&jsonl(&[10, 11, 12]))),
+                ("events/b.jsonl", plain),
+            ],
+        );
+        let sink = MockSink::default();
+        let mut manifest = Manifest::default();
+
+        let entries = backfill(
+            &store,
+            &MockFactory(sink.clone()),
+            &encoder(),
+            &options(false),
+            None,
+            &mut manifest,
+        )
+        .await
+        .unwrap();
+
+        let entries = by_key(entries);
+        let gz = &entries["events/a.jsonl.gz"];
+        assert_eq!(
+            (3, 0, true),
+            (gz.rows_ingested, gz.rows_failed, gz.complete)
+        );
+        let plain = &entries["events/b.jsonl"];
+        assert_eq!(
+            (2, 2, true),
+            (plain.rows_ingested, plain.rows_failed, plain.complete)
+        );
+        assert!(plain.first_error.as_deref().unwrap().starts_with("line 3:"));
+        assert_eq!(5, sink.records().len());
+        assert_eq!(2, manifest.entries().count());
+    }
+
+    #[tokio::test]
+    async fn test_local_manifest_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("manifests").join("events.jsonl");
+        let location = ManifestLocation::Local(path.clone());
+        let store = MockStore::default();
+
+        // A run that found no objects
+        location.save(&store, &Manifest::default()).await.unwrap();
+        assert_eq!(Manifest::default(), location.load(&store).await.unwrap());
+
+        let mut manifest = Manifest::default();
+        manifest.insert(ManifestEntry {
+            complete: true,
+            rows_ingested: 2,
+            ..ManifestEntry::new("events/a.jsonl")
+        });
+        location.save(&store, &manifest).await.unwrap();
+        assert_eq!(manifest, location.load(&store).await.unwrap());
+        assert!(!path.with_extension("jsonl.tmp").exists());
+    }
+
+    #[tokio::test]
+    async fn test_rerun_skips_complete_objects() {
+        let store = MockStore::with_objects(
+            1,
+            &[
+                ("events/a.jsonl", jsonl(&[1, 2])),
+                ("events/b.jsonl", jsonl(&[3, 4])),
+                ("events/c.jsonl", jsonl(&[5])),
+            ],
+        );
+        let location = ManifestLocation::parse("s3://bucket/manifests/events.jsonl").unwrap();
+
+        // The first run fails to acknowledge a row of b
+        let rejected = encoder().encode(&json!({"id": 4})).unwrap();
+        let failing =
+            MockSink::default().fail_acks_for(move |record| record == rejected.as_slice());
+        let mut manifest = location.load(&store).await.unwrap();
+        backfill(
+            &store,
+            &MockFactory(failing),
+            &encoder(),
+            &options(false),
+            None,
+            &mut manifest,
+        )
+        .await
+        .unwrap();
+        location.save(&store, &manifest).await.unwrap();
+
+        let saved = Manifest::parse(
+            &String::from_utf8(
+                store
+                    .get("bucket", "manifests/events.jsonl")
+                    .await
+                    .unwrap()
+                    .unwrap(),
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert!(saved.is_complete("events/a.jsonl"));
+        assert!(!saved.is_complete("events/b.jsonl"));
+
+        // A dry run counts rows without recording anything
+        let counted = backfill(
+            &store,
+            &MockFactory(MockSink::default()),
+            &encoder(),
+            &options(true),
+            None,
+            &mut manifest,
+        )
+        .await
+        .unwrap();
+        assert_eq!(vec![("events/b.jsonl".to_string(), 2)], {
+            let mut counted: Vec<_> = counted
+                .into_iter()
+                .map(|e| (e.key, e.rows_ingested))
+                .collect();
+            counted.sort();
+            counted
+        });
+        assert_eq!(saved, manifest);
+
+        let sink = MockSink::default();
+        let mut manifest = location.load(&store).await.unwrap();
+        let entries = backfill(
+            &store,
+            &MockFactory(sink.clone()),
+            &encoder(),
+            &options(false),
+            None,
+            &mut manifest,
+        )
+        .await
+        .unwrap();
+
+        let keys: Vec<&str> = entries.iter().map(|e| e.key.as_str()).collect();
+        assert_eq!(vec!["events/b.jsonl"], keys);
+        assert_eq!(2, sink.records().len());
+        assert!(manifest.entries().all(|e| e.complete));
+    }
+
+    #[tokio::test]
+    async fn test_parquet_objects_are_mapped_and_ingested() {
+        let store = MockStore::with_objects(
+            10,
+            &[
+                ("events/a.parquet", parquet(&[1, 2, 3])),
+                ("events/b.parquet", b"not parquet".to_vec()),
+            ],
+        );
+        let sink = MockSink::default();
+        let mut manifest = Manifest::default();
+        let options = BackfillOptions {
+            format: ObjectFormat::Parquet,
+            column_map: Some(ColumnMap::parse(r#"{"id": "legacy_id"}"#).unwrap()),
+            ..options(false)
+        };
+
+        let entries = backfill(
+            &store,
+            &MockFactory(sink.clone()),
+            &encoder(),
+            &options,
+            None,
+            &mut manifest,
+        )
+        .await
+        .unwrap();
+
+        let entries = by_key(entries);
+        let loaded = &entries["events/a.parquet"];
+        assert_eq!(
+            (3, 0, true),
+            (loaded.rows_ingested, loaded.rows_failed, loaded.complete)
+        );
+        let expected: Vec<Vec<u8>> = [1, 2, 3]
+            .into_iter()
+            .map(|id| encoder().encode(&json!({ "id": id })).unwrap())
+            .collect();
+        assert_eq!(expected, sink.records());
+
+        // An object that is not Parquet is left incomplete, to retry
+        let unreadable = &entries["events/b.parquet"];
+        assert!(!unreadable.complete);
+        assert!(unreadable
+            .first_error
+            .as_deref()
+            .unwrap()
+            .contains("Parquet"));
+    }
+}
@@ -0,0 +1,246 @@
+use anyhow::{bail, Context, Result};
+use bulk_loader::backfill::{
+    backfill, rows_per_second, BackfillOptions, ManifestLocation, ObjectFormat, SinkFactory,
+};
+use bulk_loader::column_map::ColumnMap;
+use bulk_loader::rate::RateLimiter;
+use clap::Parser;
+use databricks_zerobus_ingest_sdk::{
+    StreamConfigurationOptions, TableProperties, ZerobusSdk, ZerobusStream,
+};
+use prost_types::DescriptorProto;
+use std::path::PathBuf;
+use std::time::Instant;
+use tracing::info;
+use zerobus_common::descriptor::find_message_descriptor;
+use zerobus_common::dynamic::DynamicEncoder;
+use zerobus_common::s3;
+
+/// Backfill JSONL or Parquet objects under an S3 prefix into a Unity Catalog table
+///
+/// JSONL objects ending in `.gz` are decompressed on the fly. The manifest records the
+/// rows each object loaded; objects it marks complete are skipped when the command is
+/// rerun.
+#[derive(Parser, Debug)]
+#[command(name = "zb-backfill", version)]
+struct Args {
+    /// Objects to load, as s3://<bucket>/<prefix>
+    #[arg(long)]
+    source: String,
+
+    /// How the objects are encoded
+    #[arg(long, value_enum, default_value_t = ObjectFormat::Jsonl)]
+    format: ObjectFormat,
+
+    /// Target table, e.g. main.bronze.events
+    #[arg(long)]
+    table: String,
+
+    /// Descriptor set and message to encode rows with, as <path>#<message>
+    #[arg(long)]
+    descriptor: String,
+
+    /// JSON file mapping fields to the columns they are read from, for columns named
+    /// differently from their field
+    #[arg(long)]
+    column_map: Option<PathBuf>,
+
+    /// Local path or s3://<bucket>/<key> to read the previous manifest from and write
+    /// the new one to
+    #[arg(long)]
+    manifest: String,
+
+    /// Objects loaded at the same time, one stream each
+    #[arg(long, default_value_t = 8)]
+    concurrency: usize,
+
+    /// Maximum rows per second across all objects
+    #[arg(long)]
+    rate: Option<u32>,
+
+    /// Unacknowledged rows per stream
+    #[arg(long, default_value_t = 1000)]
+    max_inflight: usize,
+
+    /// Drop input fields the table does not have instead of failing the row
+    #[arg(long)]
+    ignore_unknown_fields: bool,
+
+    /// With --ignore-unknown-fields, log every row whose extra fields are dropped
+    #[arg(long)]
+    warn_unknown_fields: bool,
+
+    /// Read and encode every row without ingesting anything or writing the manifest
+    #[arg(long)]
+    dry_run: bool,
+}
+
+/// Opens a Zerobus stream to the target table for each object
+struct StreamFactory {
+    /// `None` in a dry run, which never opens a stream
+    sdk: Option<ZerobusSdk>,
+    table: String,
+    descriptor_proto: DescriptorProto,
+    max_inflight: usize,
+    client_id: String,
+    client_secret: String,
+}
+
+impl SinkFactory for StreamFactory {
+    type Sink = ZerobusStream;
+
+    async fn open(&self) -> Result<ZerobusStream> {
+        let table_properties = TableProperties {
+            table_name: self.table.clone(),
+            descriptor_proto: self.descriptor_proto.clone(),
+        };
+        let stream_options = StreamConfigurationOptions {
+            max_inflight_records: self.max_inflight,
+            ..Default::default()
+        };
+        let Some(sdk) = &self.sdk else {
+            bail!("No streams are opened in a dry run");
+        };
+        sdk.create_stream(
+            table_properties,
+            self.client_id.clone(),
+            self.client_secret.clone(),
+            Some(stream_options),
+        )
+        .await
+        .context("Failed to create stream")
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .with_target(false)
+        .init();
+
+    let args = Args::parse();
+
+    let Some((bucket, prefix)) = args
+        .source
+        .strip_prefix("s3://")
+        .map(|rest| rest.split_once('/').unwrap_or((rest, "")))
+    else {
+        bail!(
+            "--source must be s3://<bucket>/<prefix>, got {:?}",
+            args.source
+        );
+    };
+    let Some((descriptor_path, message_name)) = args.descriptor.rsplit_once('#') else {
+        bail!(
+            "--descriptor must be <path>#<message>, got {:?}",
+            args.descriptor
+        );
+    };
+    let descriptor_bytes = std::fs::read(descriptor_path)
+        .with_context(|| format!("Failed to read descriptor {}", descriptor_path))?;
+    let descriptor_proto = find_message_descriptor(&descriptor_bytes, message_name)?;
+    let encoder = DynamicEncoder::new(&descriptor_proto)?
+        .ignore_unknown_fields(args.ignore_unknown_fields)
+        .warn_unknown_fields(args.warn_unknown_fields);
+
+    // Credentials are only needed when rows are actually ingested
+    let env = |name: &str| -> Result<String> {
+        if args.dry_run {
+            return Ok(std::env::var(name).unwrap_or_default());
+        }
+        std::env::var(name).with_context(|| format!("{} environment variable must be set", name))
+    };
+    let sdk = if args.dry_run {
+        None
+    } else {
+        Some(ZerobusSdk::new(
+            env("ZEROBUS_ENDPOINT")?,
+            env("DATABRICKS_HOST")?,
+        )?)
+    };
+    let factory = StreamFactory {
+        sdk,
+        table: args.table.clone(),
+        descriptor_proto,
+        max_inflight: args.max_inflight.max(1),
+        client_id: env("DATABRICKS_CLIENT_ID")?,
+        client_secret: env("DATABRICKS_CLIENT_SECRET")?,
+    };
+
+    let store = s3::client().await;
+    let location = ManifestLocation::parse(&args.manifest)?;
+    let mut manifest = location.load(store).await?;
+    let column_map = args
+        .column_map
+        .as_deref()
+        .map(ColumnMap::load)
+        .transpose()?;
+    let options = BackfillOptions {
+        bucket: bucket.to_string(),
+        prefix: prefix.to_string(),
+        format: args.format,
+        column_map,
+        concurrency: args.concurrency.max(1),
+        max_inflight: args.max_inflight.max(1),
+        dry_run: args.dry_run,
+    };
+    let limiter = args.rate.map(RateLimiter::new);
+
+    let started = Instant::now();
+    let entries = backfill(
+        store,
+        &factory,
+        &encoder,
+        &options,
+        limiter.as_ref(),
+        &mut manifest,
+    )
+    .await?;
+
+    for entry in entries.iter().filter(|e| e.rows_failed > 0 || !e.complete) {
+        println!(
+            "s3://{}/{}: {} rows failed: {}",
+            bucket,
+            entry.key,
+            entry.rows_failed,
+            entry.first_error.as_deref().unwrap_or("incomplete")
+        );
+    }
+    let elapsed = started.elapsed();
+    let incomplete = entries.iter().filter(|e| !e.complete).count();
+    let rows = entries.iter().map(|e| e.rows_ingested).sum::<u64>();
+    println!(
+        "Total: {} objects, {} rows {}, {} rows failed, {} objects incomplete",
+        entries.len(),
+        rows,
+        if args.dry_run { "counted" } else { "ingested" },
+        entries.iter().map(|e| e.rows_failed).sum::<u64>(),
+        incomplete,
+    );
+    println!(
+        "Throughput: {:.0} rows/s over {:.1?}",
+        rows_per_second(rows, elapsed),
+        elapsed
+    );
+    if encoder.records_with_unknown_fields() > 0 {
+        println!(
+            "{} rows had fields the table does not have; those fields were dropped",
+            encoder.records_with_unknown_fields()
+        );
+    }
+
+    if args.dry_run {
+        return Ok(());
+    }
+    location.save(store, &manifest).await?;
+    info!("Manifest written to {}", args.manifest);
+    if incomplete > 0 {
+        bail!(
+            "{} of {} objects did not finish; rerun to retry them",
+            incomplete,
+            entries.len()
+        );
+    }
+    Ok(())
+}
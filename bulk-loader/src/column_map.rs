@@ -0,0 +1,90 @@
+//! Reading table fields from input columns of another name, with `--column-map`
+//!
+//! The map is a JSON file holding an object from field names to the columns they are
+//! read from:
+//!
+//! ```json
+//! {"event_id": "id", "occurred_at": "ts"}
+//! ```
+//!
+//! Columns the map does not name keep their own name, so only columns whose name
+//! differs from their field need an entry.
+
+use anyhow::{bail, Context, Result};
+use serde_json::{Map, Value};
+use std::path::Path;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ColumnMap {
+    /// Field name and the column it is read from
+    fields: Vec<(String, String)>,
+}
+
+impl ColumnMap {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("Invalid column map {}", path.display()))
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let Value::Object(object) = serde_json::from_str(text).context("Malformed JSON")? else {
+            bail!("The column map must be a JSON object of field names to column names");
+        };
+        let fields = object
+            .into_iter()
+            .map(|(field, column)| match column {
+                Value::String(column) => Ok((field, column)),
+                other => bail!(
+                    "Column of field {:?} must be a string, got {}",
+                    field,
+                    other
+                ),
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { fields })
+    }
+
+    /// Move each mapped column of a row to its field
+    ///
+    /// A column the row does not have leaves its field unset. Every mapped value is
+    /// taken before any is put back, so two columns can swap names.
+    pub fn apply(&self, row: &mut Value) {
+        let Value::Object(object) = row else {
+            return;
+        };
+        let values: Vec<(&str, Option<Value>)> = self
+            .fields
+            .iter()
+            .map(|(field, column)| (field.as_str(), object.get(column).cloned()))
+            .collect();
+        for (_, column) in &self.fields {
+            object.remove(column);
+        }
+        let mapped: Map<String, Value> = values
+            .into_iter()
+            .filter_map(|(field, value)| Some((field.to_string(), value?)))
+            .collect();
+        object.extend(mapped);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_mapped_columns_move_to_their_fields() {
+        let map = ColumnMap::parse(r#"{"event_id": "id", "id": "legacy_id", "site": "missing"}"#)
+            .unwrap();
+        let mut row = json!({"id": 7, "legacy_id": "L-7", "name": "widget"});
+
+        map.apply(&mut row);
+
+        assert_eq!(json!({"event_id": 7, "id": "L-7", "name": "widget"}), row);
+
+        assert!(ColumnMap::parse(r#"["id"]"#).is_err());
+        assert!(ColumnMap::parse(r#"{"event_id": 1}"#).is_err());
+    }
+}
@@ -0,0 +1,101 @@
+use anyhow::{Context, Result};
+use glob::Pattern;
+use std::path::{Path, PathBuf};
+
+use crate::progress::SIDECAR_EXTENSION;
+
+/// Input files under `input`, sorted by path
+///
+/// `input` may be a single file. In a directory, hidden files and `.progress` sidecars
+/// are skipped, subdirectories are only walked when `recursive` is set, and `pattern`
+/// (if any) is matched against each file's path relative to `input`.
+pub fn find_files(
+    input: &Path,
+    recursive: bool,
+    pattern: Option<&Pattern>,
+) -> Result<Vec<PathBuf>> {
+    if input.is_file() {
+        return Ok(vec![input.to_path_buf()]);
+    }
+
+    let mut files = Vec::new();
+    walk(input, input, recursive, pattern, &mut files)?;
+    files.sort();
+    Ok(files)
+}
+
+fn walk(
+    root: &Path,
+    dir: &Path,
+    recursive: bool,
+    pattern: Option<&Pattern>,
+    files: &mut Vec<PathBuf>,
+) -> Result<()> {
+    let entries = std::fs::read_dir(dir)
+        .with_context(|| format!("Failed to read directory {}", dir.display()))?;
+    for entry in entries {
+        let path = entry
+            .with_context(|| format!("Failed to read directory {}", dir.display()))?
+            .path();
+        let hidden = path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+        if hidden {
+            continue;
+        }
+
+        if path.is_dir() {
+            if recursive {
+                walk(root, &path, recursive, pattern, files)?;
+            }
+            continue;
+        }
+        if path
+            .extension()
+            .is_some_and(|ext| ext == SIDECAR_EXTENSION || ext == "tmp")
+        {
+            continue;
+        }
+        let relative = path.strip_prefix(root).unwrap_or(&path);
+        if pattern.is_some_and(|pattern| !pattern.matches_path(relative)) {
+            continue;
+        }
+        files.push(path);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_files() {
+        let dir = tempfile::tempdir().unwrap();
+        for file in [
+            "a.csv",
+            "a.csv.progress",
+            "b.jsonl",
+            ".hidden.csv",
+            "2024/01/c.csv",
+            "2024/d.txt",
+        ] {
+            let path = dir.path().join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "").unwrap();
+        }
+        let relative = |files: Vec<PathBuf>| -> Vec<String> {
+            files
+                .iter()
+                .map(|f| f.strip_prefix(dir.path()).unwrap().display().to_string())
+                .collect()
+        };
+
+        let top_level = find_files(dir.path(), false, None).unwrap();
+        assert_eq!(vec!["a.csv", "b.jsonl"], relative(top_level));
+
+        let csv = Pattern::new("**/*.csv").unwrap();
+        let all_csv = find_files(dir.path(), true, Some(&csv)).unwrap();
+        assert_eq!(vec!["2024/01/c.csv", "a.csv"], relative(all_csv));
+    }
+}
@@ -0,0 +1,8 @@
+pub mod backfill;
+pub mod column_map;
+pub mod discover;
+pub mod load;
+pub mod parquet_file;
+pub mod progress;
+pub mod rate;
+pub mod reader;
@@ -0,0 +1,307 @@
+//! Loading one input file into a sink, resuming from its `.progress` sidecar
+
+use anyhow::{Context, Result};
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use zerobus_common::dynamic::DynamicEncoder;
+use zerobus_common::pipeline::{AckFuture, IngestSink};
+
+use crate::progress::{self, Progress};
+use crate::rate::RateLimiter;
+use crate::reader::{read_rows, Format};
+
+pub struct LoadOptions {
+    pub format: Format,
+    /// Rows sent before waiting for their acknowledgments
+    pub max_inflight: usize,
+}
+
+/// A row that was skipped because it could not be parsed or encoded
+#[derive(Debug)]
+pub struct RowFailure {
+    pub line: u64,
+    pub error: String,
+}
+
+/// Outcome of loading one file
+#[derive(Debug)]
+pub struct FileSummary {
+    pub path: PathBuf,
+    /// Rows acknowledged in this run
+    pub rows: u64,
+    /// Encoded bytes acknowledged in this run
+    pub bytes: u64,
+    /// Rows skipped because an earlier run already loaded them
+    pub skipped: u64,
+    pub failures: Vec<RowFailure>,
+    /// Why loading stopped before the end of the file
+    pub error: Option<String>,
+    /// The sidecar marked the file as fully loaded, so it was not read
+    pub already_complete: bool,
+}
+
+impl FileSummary {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            rows: 0,
+            bytes: 0,
+            skipped: 0,
+            failures: Vec::new(),
+            error: None,
+            already_complete: false,
+        }
+    }
+
+    pub fn failed(&self) -> bool {
+        self.error.is_some() || !self.failures.is_empty()
+    }
+}
+
+/// A sent row waiting for its acknowledgment; `ack` is `None` for a malformed row
+struct InFlight {
+    number: u64,
+    line: u64,
+    bytes: usize,
+    ack: Option<AckFuture>,
+}
+
+/// Load `path` into `sink`
+///
+/// Rows already recorded in the sidecar are skipped and the sidecar is updated after
+/// every batch of acknowledgments, so a crashed or failed load can simply be rerun.
+/// Malformed rows are reported and skipped; a row the sink rejects ends the file.
+pub async fn load_file<S: IngestSink>(
+    path: &Path,
+    encoder: &DynamicEncoder,
+    sink: &mut S,
+    options: &LoadOptions,
+    limiter: Option<&RateLimiter>,
+) -> FileSummary {
+    let mut summary = FileSummary::new(path);
+    if let Err(e) = load_rows(path, encoder, sink, options, limiter, &mut summary).await {
+        summary.error = Some(format!("{:#}", e));
+    }
+    summary
+}
+
+async fn load_rows<S: IngestSink>(
+    path: &Path,
+    encoder: &DynamicEncoder,
+    sink: &mut S,
+    options: &LoadOptions,
+    limiter: Option<&RateLimiter>,
+    summary: &mut FileSummary,
+) -> Result<()> {
+    let mut progress = progress::load(path)?;
+    if progress.complete {
+        summary.already_complete = true;
+        return Ok(());
+    }
+
+    let mut in_flight = VecDeque::new();
+    let mut failure = None;
+    for row in read_rows(path, options.format)? {
+        let row = match row {
+            Ok(row) => row,
+            Err(e) => {
+                failure = Some(e);
+                break;
+            }
+        };
+        if row.number <= progress.acked_rows {
+            summary.skipped += 1;
+            continue;
+        }
+
+        let (bytes, ack) = match row.value.and_then(|value| encoder.encode(&value)) {
+            Ok(record) => {
+                if let Some(limiter) = limiter {
+                    limiter.acquire().await;
+                }
+                let bytes = record.len();
+                match sink.ingest(record).await {
+                    Ok(ack) => (bytes, Some(ack)),
+                    Err(e) => {
+                        failure = Some(e.context(format!("Failed to send line {}", row.line)));
+                        break;
+                    }
+                }
+            }
+            Err(e) => {
+                summary.failures.push(RowFailure {
+                    line: row.line,
+                    error: format!("{:#}", e),
+                });
+                (0, None)
+            }
+        };
+        in_flight.push_back(InFlight {
+            number: row.number,
+            line: row.line,
+            bytes,
+            ack,
+        });
+
+        if in_flight.len() >= options.max_inflight {
+            let drained = drain(&mut in_flight, &mut progress, summary).await;
+            progress::save(path, &progress)?;
+            if let Err(e) = drained {
+                failure = Some(e);
+                break;
+            }
+        }
+    }
+
+    // Rows sent before a failure still count towards the progress of the file
+    if failure.is_none() {
+        failure = sink.flush().await.err();
+    }
+    let drained = drain(&mut in_flight, &mut progress, summary).await;
+    let failure = failure.or(drained.err());
+    progress.complete = failure.is_none();
+    progress::save(path, &progress)?;
+
+    match failure {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Await in-flight rows in order, advancing the progress past each acknowledged or
+/// malformed row; the first failed acknowledgment abandons the rest
+async fn drain(
+    in_flight: &mut VecDeque<InFlight>,
+    progress: &mut Progress,
+    summary: &mut FileSummary,
+) -> Result<()> {
+    while let Some(row) = in_flight.pop_front() {
+        if let Some(ack) = row.ack {
+            if let Err(e) = ack.await {
+                in_flight.clear();
+                return Err(e).with_context(|| format!("Line {} was not acknowledged", row.line));
+            }
+            summary.rows += 1;
+            summary.bytes += row.bytes as u64;
+        }
+        progress.acked_rows = row.number;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost::Message;
+    use prost_types::field_descriptor_proto::{Label, Type};
+    use prost_types::{DescriptorProto, FieldDescriptorProto};
+    use zerobus_common::testing::MockSink;
+
+    #[derive(Clone, PartialEq, Message)]
+    struct TestRow {
+        #[prost(int64, optional, tag = "1")]
+        id: Option<i64>,
+        #[prost(string, optional, tag = "2")]
+        name: Option<String>,
+    }
+
+    fn encoder() -> DynamicEncoder {
+        let field = |name: &str, number: i32, field_type: Type| FieldDescriptorProto {
+            name: Some(name.to_string()),
+            number: Some(number),
+            label: Some(Label::Optional as i32),
+            r#type: Some(field_type as i32),
+            ..Default::default()
+        };
+        DynamicEncoder::new(&DescriptorProto {
+            name: Some("table_test".to_string()),
+            field: vec![field("id", 1, Type::Int64), field("name", 2, Type::String)],
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    fn ids(sink: &MockSink) -> Vec<i64> {
+        sink.records()
+            .iter()
+            .map(|record| TestRow::decode(record.as_slice()).unwrap().id.unwrap())
+            .collect()
+    }
+
+    fn write_input(dir: &Path, contents: &str) -> PathBuf {
+        let path = dir.join("rows.jsonl");
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn options(max_inflight: usize) -> LoadOptions {
+        LoadOptions {
+            format: Format::Jsonl,
+            max_inflight,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_malformed_rows_are_reported_and_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_input(
+            dir.path(),
+            "{\"id\": 1, \"name\": \"a\"}\n{oops\n{\"id\": \"two\"}\n{\"id\": 4}\n",
+        );
+        let mut sink = MockSink::default();
+
+        let summary = load_file(&path, &encoder(), &mut sink, &options(10), None).await;
+
+        assert_eq!(vec![1, 4], ids(&sink));
+        assert_eq!(2, summary.rows);
+        let lines: Vec<u64> = summary.failures.iter().map(|f| f.line).collect();
+        assert_eq!(vec![2, 3], lines);
+        assert!(summary.error.is_none());
+        assert!(summary.failed());
+        assert_eq!(
+            Progress {
+                acked_rows: 4,
+                complete: true
+            },
+            progress::load(&path).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resume_after_failed_ack() {
+        let dir = tempfile::tempdir().unwrap();
+        let rows: String = (1..=6).map(|id| format!("{{\"id\": {}}}\n", id)).collect();
+        let path = write_input(dir.path(), &rows);
+
+        // The first run dies on row 4, after rows 1-3 were acknowledged
+        let mut crashing = MockSink::default()
+            .fail_acks_for(|record| TestRow::decode(record).unwrap().id == Some(4));
+        let summary = load_file(&path, &encoder(), &mut crashing, &options(2), None).await;
+        assert!(summary
+            .error
+            .unwrap()
+            .contains("Line 4 was not acknowledged"));
+        assert_eq!(3, summary.rows);
+        assert_eq!(
+            Progress {
+                acked_rows: 3,
+                complete: false
+            },
+            progress::load(&path).unwrap()
+        );
+
+        // The rerun picks up where the acknowledgments stopped
+        let mut sink = MockSink::default();
+        let summary = load_file(&path, &encoder(), &mut sink, &options(2), None).await;
+        assert_eq!(vec![4, 5, 6], ids(&sink));
+        assert_eq!((3, 3), (summary.skipped, summary.rows));
+        assert!(!summary.failed());
+        assert!(progress::load(&path).unwrap().complete);
+
+        // A completed file is not read again
+        let mut sink = MockSink::default();
+        let summary = load_file(&path, &encoder(), &mut sink, &options(2), None).await;
+        assert!(summary.already_complete);
+        assert!(sink.records().is_empty());
+    }
+}
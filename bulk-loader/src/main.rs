@@ -0,0 +1,244 @@
+use anyhow::{bail, Context, Result};
+use bulk_loader::discover::find_files;
+use bulk_loader::load::{load_file, FileSummary, LoadOptions};
+use bulk_loader::parquet_file::{check_schema, read_schema};
+use bulk_loader::rate::RateLimiter;
+use bulk_loader::reader::Format;
+use clap::Parser;
+use databricks_zerobus_ingest_sdk::{
+    StreamConfigurationOptions, TableProperties, ZerobusSdk, ZerobusStream,
+};
+use futures::{stream, StreamExt};
+use prost_types::DescriptorProto;
+use std::path::{Path, PathBuf};
+use tracing::info;
+use zerobus_common::descriptor::find_message_descriptor;
+use zerobus_common::dynamic::DynamicEncoder;
+use zerobus_common::pipeline::IngestSink;
+
+/// Load a directory of CSV, JSONL, Parquet, or Avro files into a Unity Catalog table
+///
+/// Progress is kept in a `.progress` file next to each input, so an interrupted load
+/// can be rerun and continues after the last acknowledged row.
+#[derive(Parser, Debug)]
+#[command(name = "zb-load", version)]
+struct Args {
+    /// File or directory to load
+    #[arg(long)]
+    input: PathBuf,
+
+    #[arg(long, value_enum)]
+    format: Format,
+
+    /// Target table, e.g. main.bronze.events
+    #[arg(long)]
+    table: String,
+
+    /// Descriptor set and message to encode rows with, as <path>#<message>
+    #[arg(long)]
+    descriptor: String,
+
+    /// Walk subdirectories of the input directory
+    #[arg(long)]
+    recursive: bool,
+
+    /// Only load files whose path relative to the input matches this glob
+    #[arg(long)]
+    glob: Option<glob::Pattern>,
+
+    /// Files loaded at the same time, one stream each
+    #[arg(long, default_value_t = 4)]
+    concurrency: usize,
+
+    /// Maximum rows per second across all files
+    #[arg(long)]
+    rate: Option<u32>,
+
+    /// Unacknowledged rows per stream
+    #[arg(long, default_value_t = 1000)]
+    max_inflight: usize,
+
+    /// Drop input columns the table does not have instead of failing the row
+    #[arg(long)]
+    ignore_unknown_fields: bool,
+
+    /// With --ignore-unknown-fields, log every row whose extra columns are dropped
+    #[arg(long)]
+    warn_unknown_fields: bool,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .with_target(false)
+        .init();
+
+    let args = Args::parse();
+
+    let zerobus_endpoint = std::env::var("ZEROBUS_ENDPOINT")
+        .context("ZEROBUS_ENDPOINT environment variable must be set")?;
+    let databricks_host = std::env::var("DATABRICKS_HOST")
+        .context("DATABRICKS_HOST environment variable must be set")?;
+    let client_id = std::env::var("DATABRICKS_CLIENT_ID")
+        .context("DATABRICKS_CLIENT_ID environment variable must be set")?;
+    let client_secret = std::env::var("DATABRICKS_CLIENT_SECRET")
+        .context("DATABRICKS_CLIENT_SECRET environment variable must be set")?;
+
+    let Some((descriptor_path, message_name)) = args.descriptor.rsplit_once('#') else {
+        bail!(
+            "--descriptor must be <path>#<message>, got {:?}",
+            args.descriptor
+        );
+    };
+    let descriptor_bytes = std::fs::read(descriptor_path)
+        .with_context(|| format!("Failed to read descriptor {}", descriptor_path))?;
+    let descriptor_proto = find_message_descriptor(&descriptor_bytes, message_name)?;
+    let encoder = DynamicEncoder::new(&descriptor_proto)?
+        .ignore_unknown_fields(args.ignore_unknown_fields)
+        .warn_unknown_fields(args.warn_unknown_fields);
+
+    let files = find_files(&args.input, args.recursive, args.glob.as_ref())?;
+    if files.is_empty() {
+        bail!("No input files found in {}", args.input.display());
+    }
+    if args.format == Format::Parquet {
+        check_parquet_schemas(&files, &descriptor_proto, args.ignore_unknown_fields)?;
+    }
+    info!("Loading {} files into {}", files.len(), args.table);
+
+    let sdk = ZerobusSdk::new(zerobus_endpoint, databricks_host)?;
+    let options = LoadOptions {
+        format: args.format,
+        max_inflight: args.max_inflight.max(1),
+    };
+    let limiter = args.rate.map(RateLimiter::new);
+
+    let summaries: Vec<FileSummary> = stream::iter(&files)
+        .map(|path| async {
+            let stream = open_stream(
+                &sdk,
+                &args.table,
+                &descriptor_proto,
+                &options,
+                &client_id,
+                &client_secret,
+            );
+            let mut stream = match stream.await {
+                Ok(stream) => stream,
+                Err(e) => return failed_to_start(path, e),
+            };
+            let mut summary =
+                load_file(path, &encoder, &mut stream, &options, limiter.as_ref()).await;
+            if let Err(e) = IngestSink::close(&mut stream).await {
+                summary.error.get_or_insert(format!("{:#}", e));
+            }
+            print_file_summary(&summary);
+            summary
+        })
+        .buffer_unordered(args.concurrency.max(1))
+        .collect()
+        .await;
+
+    let failed = summaries.iter().filter(|s| s.failed()).count();
+    println!(
+        "Total: {} files, {} rows ({} bytes) loaded, {} rows skipped from earlier runs, {} malformed rows, {} files failed",
+        summaries.len(),
+        summaries.iter().map(|s| s.rows).sum::<u64>(),
+        summaries.iter().map(|s| s.bytes).sum::<u64>(),
+        summaries.iter().map(|s| s.skipped).sum::<u64>(),
+        summaries.iter().map(|s| s.failures.len()).sum::<usize>(),
+        failed,
+    );
+    if encoder.records_with_unknown_fields() > 0 {
+        println!(
+            "{} rows had columns the table does not have; those columns were dropped",
+            encoder.records_with_unknown_fields()
+        );
+    }
+    if failed > 0 {
+        bail!("{} of {} files failed", failed, summaries.len());
+    }
+    Ok(())
+}
+
+/// Compare every file's schema with the table before anything is ingested
+fn check_parquet_schemas(
+    files: &[PathBuf],
+    descriptor_proto: &DescriptorProto,
+    ignore_unknown_fields: bool,
+) -> Result<()> {
+    let mut mismatched = 0;
+    for path in files {
+        let diff = check_schema(&read_schema(path)?, descriptor_proto, ignore_unknown_fields);
+        if diff.has_changes() {
+            println!("{}:\n{}", path.display(), diff);
+        }
+        if diff.has_errors() {
+            mismatched += 1;
+        }
+    }
+    if mismatched > 0 {
+        bail!(
+            "{} of {} files do not match the table schema; nothing was loaded",
+            mismatched,
+            files.len()
+        );
+    }
+    Ok(())
+}
+
+async fn open_stream(
+    sdk: &ZerobusSdk,
+    table_name: &str,
+    descriptor_proto: &DescriptorProto,
+    options: &LoadOptions,
+    client_id: &str,
+    client_secret: &str,
+) -> Result<ZerobusStream> {
+    let table_properties = TableProperties {
+        table_name: table_name.to_string(),
+        descriptor_proto: descriptor_proto.clone(),
+    };
+    let stream_options = StreamConfigurationOptions {
+        max_inflight_records: options.max_inflight,
+        ..Default::default()
+    };
+    sdk.create_stream(
+        table_properties,
+        client_id.to_string(),
+        client_secret.to_string(),
+        Some(stream_options),
+    )
+    .await
+    .context("Failed to create stream")
+}
+
+fn failed_to_start(path: &Path, error: anyhow::Error) -> FileSummary {
+    let mut summary = FileSummary::new(path);
+    summary.error = Some(format!("{:#}", error));
+    print_file_summary(&summary);
+    summary
+}
+
+fn print_file_summary(summary: &FileSummary) {
+    let path = summary.path.display();
+    if summary.already_complete {
+        println!("{}: already loaded", path);
+        return;
+    }
+    println!(
+        "{}: {} rows ({} bytes) loaded, {} skipped, {} malformed",
+        path,
+        summary.rows,
+        summary.bytes,
+        summary.skipped,
+        summary.failures.len()
+    );
+    for failure in &summary.failures {
+        println!("  {}:{}: {}", path, failure.line, failure.error);
+    }
+    if let Some(error) = &summary.error {
+        println!("  {}: stopped: {}", path, error);
+    }
+}
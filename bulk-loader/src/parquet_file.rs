@@ -0,0 +1,821 @@
+//! Reading Parquet files row by row and checking their Arrow schema against the table
+//!
+//! Rows are converted to the same JSON objects the CSV and JSONL readers produce, so the
+//! [`DynamicEncoder`](zerobus_common::dynamic::DynamicEncoder) does the final coercion to
+//! field types. Values the encoder cannot infer from JSON are normalized here:
+//! timestamps become microseconds since the Unix epoch, dates become days since the
+//! epoch, decimals become doubles, and binary values become base64.
+
+use anyhow::{bail, Context, Result};
+use arrow_array::cast::AsArray;
+use arrow_array::types::*;
+use arrow_array::{Array, RecordBatch};
+use arrow_schema::{DataType, Field, Fields, Schema, SchemaRef, TimeUnit};
+use base64::{engine::general_purpose, Engine as _};
+use parquet::arrow::arrow_reader::{ParquetRecordBatchReader, ParquetRecordBatchReaderBuilder};
+use parquet::file::reader::ChunkReader;
+use prost_types::field_descriptor_proto::{Label, Type};
+use prost_types::{DescriptorProto, FieldDescriptorProto};
+use serde_json::{json, Map, Value};
+use std::fmt;
+use std::fs::File;
+use std::path::Path;
+
+use crate::reader::Row;
+
+/// Rows decoded at a time; memory stays bounded by one row group plus one batch
+const BATCH_SIZE: usize = 1024;
+
+/// Arrow schema of a Parquet file, read from its footer
+pub fn read_schema(path: &Path) -> Result<SchemaRef> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let builder = ParquetRecordBatchReaderBuilder::try_new(file)
+        .with_context(|| format!("Failed to read Parquet metadata of {}", path.display()))?;
+    Ok(builder.schema().clone())
+}
+
+/// Stream the rows of a Parquet file, one row group at a time
+///
+/// `file` is a local file, or the bytes of an object read whole, since the metadata
+/// needed to find the row groups is at the end.
+pub fn parquet_rows<R: ChunkReader + 'static>(file: R) -> Result<ParquetRows> {
+    let batches = ParquetRecordBatchReaderBuilder::try_new(file)
+        .context("Failed to read Parquet metadata")?
+        .with_batch_size(BATCH_SIZE)
+        .build()
+        .context("Failed to read Parquet file")?;
+    Ok(ParquetRows {
+        batches,
+        batch: None,
+        row: 0,
+        number: 0,
+    })
+}
+
+pub struct ParquetRows {
+    batches: ParquetRecordBatchReader,
+    batch: Option<RecordBatch>,
+    row: usize,
+    number: u64,
+}
+
+impl Iterator for ParquetRows {
+    type Item = Result<Row>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(batch) = self.batch.as_ref().filter(|b| self.row < b.num_rows()) {
+                let value = batch_row(batch, self.row);
+                self.row += 1;
+                self.number += 1;
+                return Some(Ok(Row {
+                    number: self.number,
+                    line: self.number,
+                    value,
+                }));
+            }
+            match self.batches.next()? {
+                Ok(batch) => {
+                    self.batch = Some(batch);
+                    self.row = 0;
+                }
+                Err(e) => return Some(Err(e).context("Failed to read Parquet row group")),
+            }
+        }
+    }
+}
+
+/// One row of a batch as an object keyed by column name
+fn batch_row(batch: &RecordBatch, row: usize) -> Result<Value> {
+    let schema = batch.schema();
+    let mut object = Map::new();
+    for (field, column) in schema.fields().iter().zip(batch.columns()) {
+        let value =
+            to_json(column.as_ref(), row).with_context(|| format!("Column {:?}", field.name()))?;
+        object.insert(field.name().clone(), value);
+    }
+    Ok(Value::Object(object))
+}
+
+/// Convert one value of an Arrow array; nulls at any depth become `null`
+fn to_json(array: &dyn Array, row: usize) -> Result<Value> {
+    if array.is_null(row) {
+        return Ok(Value::Null);
+    }
+    Ok(match array.data_type() {
+        DataType::Null => Value::Null,
+        DataType::Boolean => Value::Bool(array.as_boolean().value(row)),
+        DataType::Int8 => json!(array.as_primitive::<Int8Type>().value(row)),
+        DataType::Int16 => json!(array.as_primitive::<Int16Type>().value(row)),
+        DataType::Int32 => json!(array.as_primitive::<Int32Type>().value(row)),
+        DataType::Int64 => json!(array.as_primitive::<Int64Type>().value(row)),
+        DataType::UInt8 => json!(array.as_primitive::<UInt8Type>().value(row)),
+        DataType::UInt16 => json!(array.as_primitive::<UInt16Type>().value(row)),
+        DataType::UInt32 => json!(array.as_primitive::<UInt32Type>().value(row)),
+        DataType::UInt64 => json!(array.as_primitive::<UInt64Type>().value(row)),
+        DataType::Float32 => float(f64::from(array.as_primitive::<Float32Type>().value(row))),
+        DataType::Float64 => float(array.as_primitive::<Float64Type>().value(row)),
+        DataType::Decimal128(_, scale) => {
+            let unscaled = array.as_primitive::<Decimal128Type>().value(row);
+            float(unscaled as f64 / 10f64.powi(i32::from(*scale)))
+        }
+        DataType::Utf8 => json!(array.as_string::<i32>().value(row)),
+        DataType::LargeUtf8 => json!(array.as_string::<i64>().value(row)),
+        DataType::Utf8View => json!(array.as_string_view().value(row)),
+        DataType::Binary => base64(array.as_binary::<i32>().value(row)),
+        DataType::LargeBinary => base64(array.as_binary::<i64>().value(row)),
+        DataType::BinaryView => base64(array.as_binary_view().value(row)),
+        DataType::Timestamp(unit, _) => {
+            let value = match unit {
+                TimeUnit::Second => array.as_primitive::<TimestampSecondType>().value(row),
+                TimeUnit::Millisecond => {
+                    array.as_primitive::<TimestampMillisecondType>().value(row)
+                }
+                TimeUnit::Microsecond => {
+                    array.as_primitive::<TimestampMicrosecondType>().value(row)
+                }
+                TimeUnit::Nanosecond => array.as_primitive::<TimestampNanosecondType>().value(row),
+            };
+            json!(to_micros(value, unit)?)
+        }
+        DataType::Date32 => json!(array.as_primitive::<Date32Type>().value(row)),
+        DataType::Date64 => {
+            json!(array
+                .as_primitive::<Date64Type>()
+                .value(row)
+                .div_euclid(86_400_000))
+        }
+        DataType::Struct(_) => {
+            let array = array.as_struct();
+            let mut object = Map::new();
+            for (field, column) in array.fields().iter().zip(array.columns()) {
+                object.insert(field.name().clone(), to_json(column.as_ref(), row)?);
+            }
+            Value::Object(object)
+        }
+        DataType::List(_) => list(array.as_list::<i32>().value(row).as_ref())?,
+        DataType::LargeList(_) => list(array.as_list::<i64>().value(row).as_ref())?,
+        DataType::Map(_, _) => {
+            let entries = array.as_map().value(row);
+            let (keys, values) = (entries.column(0), entries.column(1));
+            let mut object = Map::new();
+            for i in 0..entries.len() {
+                let key = match to_json(keys.as_ref(), i)? {
+                    Value::String(key) => key,
+                    key => key.to_string(),
+                };
+                object.insert(key, to_json(values.as_ref(), i)?);
+            }
+            Value::Object(object)
+        }
+        other => bail!("Unsupported Arrow type {}", other),
+    })
+}
+
+fn list(values: &dyn Array) -> Result<Value> {
+    (0..values.len())
+        .map(|i| to_json(values, i))
+        .collect::<Result<Vec<_>>>()
+        .map(Value::Array)
+}
+
+/// Non-finite floats have no JSON number, but the encoder parses them from text
+fn float(value: f64) -> Value {
+    if value.is_finite() {
+        json!(value)
+    } else {
+        Value::String(value.to_string())
+    }
+}
+
+fn base64(bytes: &[u8]) -> Value {
+    Value::String(general_purpose::STANDARD.encode(bytes))
+}
+
+fn to_micros(value: i64, unit: &TimeUnit) -> Result<i64> {
+    match unit {
+        TimeUnit::Second => value.checked_mul(1_000_000),
+        TimeUnit::Millisecond => value.checked_mul(1_000),
+        TimeUnit::Microsecond => Some(value),
+        TimeUnit::Nanosecond => Some(value.div_euclid(1_000)),
+    }
+    .with_context(|| format!("Timestamp {} is out of range", value))
+}
+
+/// How a column lines up with the table
+#[derive(Debug, Clone, PartialEq)]
+pub enum ColumnDiff {
+    /// The column fills the field, with a note when its values are converted
+    Matched {
+        note: Option<String>,
+    },
+    /// The column fills the field, but values may lose precision or fail to convert
+    Lossy {
+        note: String,
+    },
+    /// The table has a field the file does not; it is left unset
+    Missing {
+        required: bool,
+    },
+    /// The file has a column the table does not
+    Extra {
+        ignored: bool,
+    },
+    Incompatible {
+        reason: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiffLine {
+    /// Column name; nested columns are joined with `.`
+    pub path: String,
+    pub column_type: Option<String>,
+    pub field_type: Option<String>,
+    pub diff: ColumnDiff,
+}
+
+impl DiffLine {
+    pub fn is_error(&self) -> bool {
+        matches!(
+            self.diff,
+            ColumnDiff::Missing { required: true }
+                | ColumnDiff::Extra { ignored: false }
+                | ColumnDiff::Incompatible { .. }
+        )
+    }
+}
+
+/// Column-by-column comparison of a Parquet schema with the table descriptor
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SchemaDiff {
+    pub lines: Vec<DiffLine>,
+}
+
+impl SchemaDiff {
+    /// Some column cannot be loaded as-is
+    pub fn has_errors(&self) -> bool {
+        self.lines.iter().any(DiffLine::is_error)
+    }
+
+    /// Anything other than columns that match exactly
+    pub fn has_changes(&self) -> bool {
+        self.lines
+            .iter()
+            .any(|line| line.diff != ColumnDiff::Matched { note: None })
+    }
+}
+
+impl fmt::Display for SchemaDiff {
+    /// One line per column: ` ` matches, `~` converted, `-` missing from the file,
+    /// `+` not in the table, `!` cannot be loaded
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for line in &self.lines {
+            let column = line.column_type.as_deref().unwrap_or("(no column)");
+            let field = line.field_type.as_deref().unwrap_or("(no field)");
+            let (marker, note) = match &line.diff {
+                ColumnDiff::Matched { note } => {
+                    (if note.is_some() { '~' } else { ' ' }, note.clone())
+                }
+                ColumnDiff::Lossy { note } => ('~', Some(note.clone())),
+                ColumnDiff::Missing { required: false } => ('-', Some("left unset".to_string())),
+                ColumnDiff::Missing { required: true } => {
+                    ('!', Some("required by the table".to_string()))
+                }
+                ColumnDiff::Extra { ignored: true } => ('+', Some("dropped".to_string())),
+                ColumnDiff::Extra { ignored: false } => (
+                    '!',
+                    Some("not in the table; pass --ignore-unknown-fields to drop it".to_string()),
+                ),
+                ColumnDiff::Incompatible { reason } => ('!', Some(reason.clone())),
+            };
+            write!(f, "{} {}: {} -> {}", marker, line.path, column, field)?;
+            if let Some(note) = note {
+                write!(f, " ({})", note)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Compare the columns of `schema` with the fields of `descriptor`, by name
+pub fn check_schema(
+    schema: &Schema,
+    descriptor: &DescriptorProto,
+    ignore_unknown_fields: bool,
+) -> SchemaDiff {
+    let mut diff = SchemaDiff::default();
+    check_fields(
+        schema.fields(),
+        descriptor,
+        descriptor,
+        "",
+        ignore_unknown_fields,
+        &mut diff,
+    );
+    diff
+}
+
+fn check_fields(
+    columns: &Fields,
+    message: &DescriptorProto,
+    root: &DescriptorProto,
+    prefix: &str,
+    ignore_unknown_fields: bool,
+    diff: &mut SchemaDiff,
+) {
+    for field in &message.field {
+        let path = format!("{}{}", prefix, field.name());
+        let Some(column) = columns.iter().find(|c| c.name() == field.name()) else {
+            diff.lines.push(DiffLine {
+                path,
+                column_type: None,
+                field_type: Some(field_type_name(field, root)),
+                diff: ColumnDiff::Missing {
+                    required: field.label() == Label::Required,
+                },
+            });
+            continue;
+        };
+
+        let nested = match (column.data_type(), nested_message(field, root)) {
+            (DataType::Struct(children), Some(nested)) if field.label() != Label::Repeated => {
+                Some((children, nested))
+            }
+            _ => None,
+        };
+        let line_diff = match nested {
+            Some(_) => ColumnDiff::Matched { note: None },
+            None => check_type(column, field, root),
+        };
+        diff.lines.push(DiffLine {
+            path: path.clone(),
+            column_type: Some(column.data_type().to_string()),
+            field_type: Some(field_type_name(field, root)),
+            diff: line_diff,
+        });
+        if let Some((children, nested)) = nested {
+            let prefix = format!("{}.", path);
+            check_fields(children, nested, root, &prefix, ignore_unknown_fields, diff);
+        }
+    }
+
+    for column in columns {
+        if !message.field.iter().any(|f| f.name() == column.name()) {
+            diff.lines.push(DiffLine {
+                path: format!("{}{}", prefix, column.name()),
+                column_type: Some(column.data_type().to_string()),
+                field_type: None,
+                diff: ColumnDiff::Extra {
+                    ignored: ignore_unknown_fields,
+                },
+            });
+        }
+    }
+}
+
+/// Check a column against a field, including repeated and map fields
+fn check_type(column: &Field, field: &FieldDescriptorProto, root: &DescriptorProto) -> ColumnDiff {
+    let nullable_required = column.is_nullable() && field.label() == Label::Required;
+    let diff = if field.label() != Label::Repeated {
+        check_value(column.data_type(), field, root)
+    } else if let Some(entry) = nested_message(field, root).filter(|m| is_map_entry(m)) {
+        match (column.data_type(), entry.field.first(), entry.field.get(1)) {
+            (DataType::Map(entries, _), Some(key), Some(value)) => match entries.data_type() {
+                DataType::Struct(kv) if kv.len() == 2 => worst(
+                    check_value(kv[0].data_type(), key, root),
+                    check_value(kv[1].data_type(), value, root),
+                ),
+                _ => incompatible("malformed map column"),
+            },
+            _ => incompatible("expected a map column"),
+        }
+    } else {
+        match column.data_type() {
+            DataType::List(item) | DataType::LargeList(item) => {
+                check_value(item.data_type(), field, root)
+            }
+            _ => incompatible("expected a list column"),
+        }
+    };
+
+    match diff {
+        ColumnDiff::Matched { .. } if nullable_required => ColumnDiff::Lossy {
+            note: "nullable column for a required field; null rows will fail".to_string(),
+        },
+        diff => diff,
+    }
+}
+
+/// Check one value of a column against the element type of a field
+fn check_value(
+    data_type: &DataType,
+    field: &FieldDescriptorProto,
+    root: &DescriptorProto,
+) -> ColumnDiff {
+    let target = field.r#type();
+    let integer = matches!(
+        target,
+        Type::Int64
+            | Type::Uint64
+            | Type::Int32
+            | Type::Fixed64
+            | Type::Fixed32
+            | Type::Uint32
+            | Type::Sfixed32
+            | Type::Sfixed64
+            | Type::Sint32
+            | Type::Sint64
+            | Type::Enum
+    );
+    let floating = matches!(target, Type::Double | Type::Float);
+    let matched = ColumnDiff::Matched { note: None };
+
+    match data_type {
+        DataType::Null => matched,
+        DataType::Boolean if target == Type::Bool || target == Type::String => matched,
+        DataType::Int8
+        | DataType::Int16
+        | DataType::Int32
+        | DataType::Int64
+        | DataType::UInt8
+        | DataType::UInt16
+        | DataType::UInt32
+        | DataType::UInt64
+            if integer || floating || target == Type::String =>
+        {
+            matched
+        }
+        DataType::Float32 | DataType::Float64 if floating || target == Type::String => matched,
+        DataType::Decimal128(_, _) if floating || target == Type::String => ColumnDiff::Lossy {
+            note: "decimal converted to double; precision may be lost".to_string(),
+        },
+        DataType::Utf8 | DataType::LargeUtf8 | DataType::Utf8View => match target {
+            Type::String | Type::Enum => matched,
+            Type::Bytes | Type::Message | Type::Group => {
+                incompatible("text cannot fill this field")
+            }
+            _ => ColumnDiff::Lossy {
+                note: "text is parsed; rows that do not parse fail".to_string(),
+            },
+        },
+        DataType::Binary | DataType::LargeBinary | DataType::BinaryView
+            if target == Type::Bytes =>
+        {
+            matched
+        }
+        DataType::Timestamp(unit, _) if integer => match unit {
+            TimeUnit::Microsecond => matched,
+            TimeUnit::Nanosecond => ColumnDiff::Lossy {
+                note: "nanoseconds truncated to microseconds".to_string(),
+            },
+            TimeUnit::Second | TimeUnit::Millisecond => ColumnDiff::Matched {
+                note: Some(format!("{:?} converted to microseconds", unit).to_lowercase()),
+            },
+        },
+        DataType::Date32 if integer => matched,
+        DataType::Date64 if integer => ColumnDiff::Matched {
+            note: Some("milliseconds converted to days".to_string()),
+        },
+        DataType::Struct(children) if target == Type::Message => {
+            match nested_message(field, root) {
+                // Nested columns are compared field by field
+                Some(nested) => {
+                    let mut diff = SchemaDiff::default();
+                    check_fields(children, nested, root, "", false, &mut diff);
+                    let errors: Vec<&str> = diff
+                        .lines
+                        .iter()
+                        .filter(|line| line.is_error())
+                        .map(|line| line.path.as_str())
+                        .collect();
+                    if errors.is_empty() {
+                        matched
+                    } else {
+                        incompatible(&format!(
+                            "nested columns do not match: {}",
+                            errors.join(", ")
+                        ))
+                    }
+                }
+                None => incompatible("message type not found in the descriptor"),
+            }
+        }
+        other => incompatible(&format!(
+            "{} cannot fill a {} field",
+            other,
+            scalar_name(field)
+        )),
+    }
+}
+
+fn incompatible(reason: &str) -> ColumnDiff {
+    ColumnDiff::Incompatible {
+        reason: reason.to_string(),
+    }
+}
+
+/// The more serious of two diffs, for map keys and values
+fn worst(a: ColumnDiff, b: ColumnDiff) -> ColumnDiff {
+    let rank = |diff: &ColumnDiff| match diff {
+        ColumnDiff::Incompatible { .. } => 2,
+        ColumnDiff::Lossy { .. } => 1,
+        _ => 0,
+    };
+    if rank(&b) > rank(&a) {
+        b
+    } else {
+        a
+    }
+}
+
+/// The nested message a message-typed field refers to
+fn nested_message<'a>(
+    field: &FieldDescriptorProto,
+    root: &'a DescriptorProto,
+) -> Option<&'a DescriptorProto> {
+    if field.r#type() != Type::Message {
+        return None;
+    }
+    let name = field.type_name().rsplit('.').next()?;
+    find_nested(root, name)
+}
+
+fn find_nested<'a>(message: &'a DescriptorProto, name: &str) -> Option<&'a DescriptorProto> {
+    message.nested_type.iter().find_map(|nested| {
+        if nested.name() == name {
+            Some(nested)
+        } else {
+            find_nested(nested, name)
+        }
+    })
+}
+
+fn is_map_entry(message: &DescriptorProto) -> bool {
+    message
+        .options
+        .as_ref()
+        .and_then(|options| options.map_entry)
+        .unwrap_or(false)
+}
+
+/// `int64`, `string`, or the message or enum name
+fn scalar_name(field: &FieldDescriptorProto) -> String {
+    match field.r#type() {
+        Type::Message | Type::Enum => field
+            .type_name()
+            .rsplit('.')
+            .next()
+            .unwrap_or_default()
+            .to_string(),
+        other => other
+            .as_str_name()
+            .trim_start_matches("TYPE_")
+            .to_lowercase(),
+    }
+}
+
+/// `int64`, `repeated string`, `map<string, int64>`, ...
+fn field_type_name(field: &FieldDescriptorProto, root: &DescriptorProto) -> String {
+    if field.label() != Label::Repeated {
+        return scalar_name(field);
+    }
+    match nested_message(field, root).filter(|m| is_map_entry(m)) {
+        Some(entry) => {
+            let names: Vec<String> = entry.field.iter().map(scalar_name).collect();
+            format!("map<{}>", names.join(", "))
+        }
+        None => format!("repeated {}", scalar_name(field)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::{
+        ArrayRef, Decimal128Array, Int32Array, Int64Array, StringArray, StructArray,
+        TimestampMicrosecondArray, TimestampMillisecondArray, TimestampNanosecondArray,
+        TimestampSecondArray,
+    };
+    use arrow_buffer::NullBuffer;
+    use parquet::arrow::ArrowWriter;
+    use parquet::file::properties::WriterProperties;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use zerobus_common::dynamic::DynamicEncoder;
+
+    /// Write a fixture with two rows per row group, so reads cross row groups
+    fn write_fixture(dir: &Path, columns: Vec<(&str, ArrayRef)>) -> PathBuf {
+        let batch = RecordBatch::try_from_iter(columns).unwrap();
+        let path = dir.join("fixture.parquet");
+        let properties = WriterProperties::builder()
+            .set_max_row_group_size(2)
+            .build();
+        let mut writer = ArrowWriter::try_new(
+            File::create(&path).unwrap(),
+            batch.schema(),
+            Some(properties),
+        )
+        .unwrap();
+        writer.write(&batch).unwrap();
+        writer.close().unwrap();
+        path
+    }
+
+    fn rows(path: &Path) -> Vec<Value> {
+        parquet_rows(File::open(path).unwrap())
+            .unwrap()
+            .map(|row| row.unwrap().value.unwrap())
+            .collect()
+    }
+
+    fn field(name: &str, number: i32, field_type: Type) -> FieldDescriptorProto {
+        FieldDescriptorProto {
+            name: Some(name.to_string()),
+            number: Some(number),
+            label: Some(Label::Optional as i32),
+            r#type: Some(field_type as i32),
+            ..Default::default()
+        }
+    }
+
+    fn message(name: &str, fields: Vec<FieldDescriptorProto>) -> DescriptorProto {
+        DescriptorProto {
+            name: Some(name.to_string()),
+            field: fields,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_timestamps_in_every_unit_become_micros() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_fixture(
+            dir.path(),
+            vec![
+                (
+                    "ts_s",
+                    Arc::new(TimestampSecondArray::from(vec![Some(1_700_000_000), None])),
+                ),
+                (
+                    "ts_ms",
+                    Arc::new(TimestampMillisecondArray::from(vec![
+                        Some(1_700_000_000_123),
+                        None,
+                    ])),
+                ),
+                (
+                    "ts_us",
+                    Arc::new(TimestampMicrosecondArray::from(vec![
+                        Some(1_700_000_000_123_456),
+                        None,
+                    ])),
+                ),
+                (
+                    "ts_ns",
+                    Arc::new(TimestampNanosecondArray::from(vec![
+                        Some(1_700_000_000_123_456_789),
+                        None,
+                    ])),
+                ),
+            ],
+        );
+        let descriptor = message(
+            "table_events",
+            vec![
+                field("ts_s", 1, Type::Int64),
+                field("ts_ms", 2, Type::Int64),
+                field("ts_us", 3, Type::Int64),
+                field("ts_ns", 4, Type::Int64),
+            ],
+        );
+
+        let rows = rows(&path);
+        assert_eq!(
+            json!({
+                "ts_s": 1_700_000_000_000_000i64,
+                "ts_ms": 1_700_000_000_123_000i64,
+                "ts_us": 1_700_000_000_123_456i64,
+                "ts_ns": 1_700_000_000_123_456i64,
+            }),
+            rows[0]
+        );
+        assert_eq!(
+            json!({"ts_s": null, "ts_ms": null, "ts_us": null, "ts_ns": null}),
+            rows[1]
+        );
+
+        let diff = check_schema(&read_schema(&path).unwrap(), &descriptor, false);
+        assert!(!diff.has_errors());
+        let diffs: Vec<&ColumnDiff> = diff.lines.iter().map(|line| &line.diff).collect();
+        assert_eq!(
+            vec![
+                &ColumnDiff::Matched {
+                    note: Some("second converted to microseconds".to_string())
+                },
+                &ColumnDiff::Matched {
+                    note: Some("millisecond converted to microseconds".to_string())
+                },
+                &ColumnDiff::Matched { note: None },
+                &ColumnDiff::Lossy {
+                    note: "nanoseconds truncated to microseconds".to_string()
+                },
+            ],
+            diffs
+        );
+    }
+
+    #[test]
+    fn test_nested_nullability() {
+        let dir = tempfile::tempdir().unwrap();
+        let address = StructArray::try_new(
+            Fields::from(vec![
+                Field::new("city", DataType::Utf8, true),
+                Field::new("zip", DataType::Int32, true),
+            ]),
+            vec![
+                Arc::new(StringArray::from(vec![Some("Oslo"), None, None])),
+                Arc::new(Int32Array::from(vec![Some(150), None, Some(8000)])),
+            ],
+            // The second row has no address at all
+            Some(NullBuffer::from(vec![true, false, true])),
+        )
+        .unwrap();
+        let path = write_fixture(
+            dir.path(),
+            vec![
+                ("id", Arc::new(Int64Array::from(vec![1, 2, 3]))),
+                ("address", Arc::new(address)),
+            ],
+        );
+        let mut address_field = field("address", 2, Type::Message);
+        address_field.type_name = Some(".people.table_people.Address".to_string());
+        let mut descriptor = message(
+            "table_people",
+            vec![field("id", 1, Type::Int64), address_field],
+        );
+        descriptor.nested_type = vec![message(
+            "Address",
+            vec![field("city", 1, Type::String), field("zip", 2, Type::Int32)],
+        )];
+
+        let rows = rows(&path);
+        assert_eq!(
+            vec![
+                json!({"id": 1, "address": {"city": "Oslo", "zip": 150}}),
+                json!({"id": 2, "address": null}),
+                json!({"id": 3, "address": {"city": null, "zip": 8000}}),
+            ],
+            rows
+        );
+
+        let diff = check_schema(&read_schema(&path).unwrap(), &descriptor, false);
+        assert!(!diff.has_changes(), "{}", diff);
+        let encoder = DynamicEncoder::new(&descriptor).unwrap();
+        for row in &rows {
+            encoder.encode(row).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_schema_diff_reports_missing_and_extra_columns() {
+        let dir = tempfile::tempdir().unwrap();
+        let price = Decimal128Array::from(vec![Some(1234)])
+            .with_precision_and_scale(10, 2)
+            .unwrap();
+        let path = write_fixture(
+            dir.path(),
+            vec![
+                ("id", Arc::new(Int64Array::from(vec![7]))),
+                ("price", Arc::new(price)),
+                ("note", Arc::new(StringArray::from(vec!["extra"]))),
+            ],
+        );
+        let descriptor = message(
+            "table_products",
+            vec![
+                field("id", 1, Type::Int64),
+                field("price", 2, Type::Double),
+                field("name", 3, Type::String),
+            ],
+        );
+        let schema = read_schema(&path).unwrap();
+
+        let diff = check_schema(&schema, &descriptor, false);
+        assert!(diff.has_errors());
+        assert_eq!(
+            "  id: Int64 -> int64\n\
+             ~ price: Decimal128(10, 2) -> double (decimal converted to double; precision may be lost)\n\
+             - name: (no column) -> string (left unset)\n\
+             ! note: Utf8 -> (no field) (not in the table; pass --ignore-unknown-fields to drop it)\n",
+            diff.to_string()
+        );
+        assert!(!check_schema(&schema, &descriptor, true).has_errors());
+
+        assert_eq!(
+            vec![json!({"id": 7, "price": 12.34, "note": "extra"})],
+            rows(&path)
+        );
+    }
+}
@@ -0,0 +1,91 @@
+//! `.progress` sidecars recording how far each input file has been loaded
+
+use anyhow::{bail, Context, Result};
+use std::path::{Path, PathBuf};
+use zerobus_common::durable;
+
+/// Extension of the sidecar written next to each input file
+pub const SIDECAR_EXTENSION: &str = "progress";
+
+/// How far a file has been loaded
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    /// Every row up to and including this one (1-based) has been acknowledged, or was
+    /// malformed and reported
+    pub acked_rows: u64,
+    /// The whole file has been loaded
+    pub complete: bool,
+}
+
+/// `data/part-1.csv` -> `data/part-1.csv.progress`
+pub fn sidecar_path(path: &Path) -> PathBuf {
+    let mut sidecar = path.as_os_str().to_owned();
+    sidecar.push(".");
+    sidecar.push(SIDECAR_EXTENSION);
+    PathBuf::from(sidecar)
+}
+
+/// Read the sidecar of `path`; a missing sidecar means nothing was loaded yet
+///
+/// An empty sidecar is an error rather than nothing loaded, which would send every
+/// row of the file again.
+pub fn load(path: &Path) -> Result<Progress> {
+    let sidecar = sidecar_path(path);
+    let Some(contents) = durable::read_file(&sidecar)? else {
+        return Ok(Progress::default());
+    };
+    let contents = String::from_utf8(contents)
+        .with_context(|| format!("{} is not a progress file", sidecar.display()))?;
+
+    let mut progress = Progress::default();
+    for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+        match line.split_once('=') {
+            Some(("acked_rows", value)) => {
+                progress.acked_rows = value
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("Invalid acked_rows in {}", sidecar.display()))?
+            }
+            Some(("complete", value)) => progress.complete = value.trim() == "true",
+            _ => bail!("Unexpected line {:?} in {}", line, sidecar.display()),
+        }
+    }
+    Ok(progress)
+}
+
+/// Write the sidecar of `path`
+///
+/// Replaced with [`durable::replace_file`], so a crash or power loss leaves the
+/// previous sidecar or this one.
+pub fn save(path: &Path, progress: &Progress) -> Result<()> {
+    let contents = format!(
+        "acked_rows={}\ncomplete={}\n",
+        progress.acked_rows, progress.complete
+    );
+    durable::replace_file(&sidecar_path(path), contents.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("part-1.csv");
+        assert_eq!(Progress::default(), load(&path).unwrap());
+
+        let progress = Progress {
+            acked_rows: 42,
+            complete: true,
+        };
+        save(&path, &progress).unwrap();
+
+        assert_eq!(progress, load(&path).unwrap());
+        assert!(dir.path().join("part-1.csv.progress").exists());
+        assert!(!dir.path().join("part-1.csv.progress.tmp").exists());
+
+        std::fs::write(dir.path().join("part-1.csv.progress"), "").unwrap();
+        assert!(load(&path).is_err());
+    }
+}
@@ -0,0 +1,47 @@
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Spaces rows evenly so all files together stay under a rows-per-second limit
+pub struct RateLimiter {
+    interval: Duration,
+    next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(rows_per_second: u32) -> Self {
+        Self {
+            interval: Duration::from_secs(1) / rows_per_second.max(1),
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Wait for the next free slot
+    pub async fn acquire(&self) {
+        let slot = {
+            let mut next = self.next.lock().unwrap();
+            let slot = (*next).max(Instant::now());
+            *next = slot + self.interval;
+            slot
+        };
+        tokio::time::sleep_until(slot).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate_limiter_spaces_rows() {
+        let limiter = RateLimiter::new(10);
+        let start = Instant::now();
+
+        for _ in 0..25 {
+            limiter.acquire().await;
+        }
+
+        // The first row goes immediately, the other 24 at 100ms intervals
+        assert_eq!(Duration::from_millis(2400), start.elapsed());
+    }
+}
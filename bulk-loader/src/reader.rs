@@ -0,0 +1,245 @@
+use anyhow::{anyhow, Context, Result};
+use serde_json::{Map, Value};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use zerobus_common::avro::AvroConverter;
+
+use crate::parquet_file::parquet_rows;
+
+/// Input file format
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    /// Comma-separated values with a header row
+    Csv,
+    /// One JSON object per line
+    Jsonl,
+    /// Apache Parquet, read one row group at a time
+    Parquet,
+    /// Avro object container files, read one block at a time
+    Avro,
+}
+
+/// One row of an input file
+#[derive(Debug)]
+pub struct Row {
+    /// 1-based position of the row among the rows of the file
+    pub number: u64,
+    /// 1-based line the row starts on; for Parquet and Avro, the same as `number`
+    pub line: u64,
+    /// The row as a JSON object, or why it could not be read
+    pub value: Result<Value>,
+}
+
+/// Stream the rows of a file
+///
+/// A row that cannot be parsed is yielded with an error so the caller can skip it and
+/// carry on; the outer error is reserved for I/O failures that end the file.
+pub fn read_rows(path: &Path, format: Format) -> Result<Box<dyn Iterator<Item = Result<Row>>>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    Ok(match format {
+        Format::Csv => Box::new(csv_rows(file)?),
+        Format::Jsonl => Box::new(jsonl_rows(BufReader::new(file))),
+        Format::Parquet => Box::new(parquet_rows(file)?),
+        Format::Avro => Box::new(avro_rows(file)?),
+    })
+}
+
+/// CSV rows as objects keyed by the header; empty cells become `null`
+fn csv_rows(file: File) -> Result<impl Iterator<Item = Result<Row>>> {
+    let mut reader = csv::ReaderBuilder::new().from_reader(file);
+    let header: Vec<String> = reader
+        .headers()
+        .context("Failed to read CSV header")?
+        .iter()
+        .map(str::to_string)
+        .collect();
+
+    Ok(reader.into_records().enumerate().map(move |(i, record)| {
+        let line = match &record {
+            Ok(record) => record.position().map(|p| p.line()),
+            Err(e) => e.position().map(|p| p.line()),
+        }
+        .unwrap_or_default();
+        if let Err(e) = &record {
+            if let csv::ErrorKind::Io(_) = e.kind() {
+                return Err(anyhow!("Failed to read CSV: {}", e));
+            }
+        }
+
+        let value = record
+            .map_err(|e| anyhow!("Malformed CSV row: {}", e))
+            .map(|record| {
+                let object: Map<String, Value> = header
+                    .iter()
+                    .zip(record.iter())
+                    .map(|(name, cell)| {
+                        let value = match cell {
+                            "" => Value::Null,
+                            cell => Value::String(cell.to_string()),
+                        };
+                        (name.clone(), value)
+                    })
+                    .collect();
+                Value::Object(object)
+            });
+        Ok(Row {
+            number: i as u64 + 1,
+            line,
+            value,
+        })
+    }))
+}
+
+/// Non-blank lines parsed as JSON
+fn jsonl_rows(reader: impl BufRead) -> impl Iterator<Item = Result<Row>> {
+    reader
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !matches!(line, Ok(text) if text.trim().is_empty()))
+        .enumerate()
+        .map(|(i, (line_index, line))| {
+            let text = line.context("Failed to read line")?;
+            Ok(Row {
+                number: i as u64 + 1,
+                line: line_index as u64 + 1,
+                value: serde_json::from_str(&text).context("Malformed JSON"),
+            })
+        })
+}
+
+/// Avro records converted with the file's writer schema
+fn avro_rows(file: File) -> Result<impl Iterator<Item = Result<Row>>> {
+    let reader = apache_avro::Reader::new(BufReader::new(file))
+        .context("Failed to read Avro container header")?;
+    let converter = AvroConverter::new(reader.writer_schema())?;
+
+    Ok(reader.enumerate().map(move |(i, record)| {
+        // A record that cannot be decoded leaves the rest of its block unreadable
+        let record = record.context("Failed to read Avro record")?;
+        let number = i as u64 + 1;
+        Ok(Row {
+            number,
+            line: number,
+            value: converter.convert(&record),
+        })
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use apache_avro::types::Value as AvroValue;
+    use apache_avro::Decimal;
+    use serde_json::json;
+    use std::io::Write;
+
+    fn rows(contents: &str, format: Format) -> Vec<Row> {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(contents.as_bytes()).unwrap();
+        read_rows(file.path(), format)
+            .unwrap()
+            .map(|row| row.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_csv_rows_with_malformed_row() {
+        let rows = rows("id,name\n1,widget\n2\n3,\n", Format::Csv);
+
+        assert_eq!(3, rows.len());
+        assert_eq!(
+            json!({"id": "1", "name": "widget"}),
+            *rows[0].value.as_ref().unwrap()
+        );
+        assert!(rows[1].value.is_err());
+        assert_eq!((2, 3), (rows[1].number, rows[1].line));
+        assert_eq!(
+            json!({"id": "3", "name": null}),
+            *rows[2].value.as_ref().unwrap()
+        );
+        assert_eq!(4, rows[2].line);
+    }
+
+    #[test]
+    fn test_jsonl_rows_skip_blank_lines() {
+        let rows = rows("{\"id\": 1}\n\n{oops\n  \n{\"id\": 3}\n", Format::Jsonl);
+
+        let positions: Vec<_> = rows.iter().map(|row| (row.number, row.line)).collect();
+        assert_eq!(vec![(1, 1), (2, 3), (3, 5)], positions);
+        assert!(rows[1].value.is_err());
+        assert_eq!(json!({"id": 3}), *rows[2].value.as_ref().unwrap());
+    }
+
+    #[test]
+    fn test_avro_rows_with_logical_types() {
+        let schema = apache_avro::Schema::parse_str(
+            r#"{
+                "type": "record",
+                "name": "Event",
+                "fields": [
+                    {"name": "id", "type": "long"},
+                    {"name": "at", "type": {"type": "long", "logicalType": "timestamp-millis"}},
+                    {"name": "day", "type": {"type": "int", "logicalType": "date"}},
+                    {"name": "amount", "type": ["null", {
+                        "type": "fixed", "name": "Amount", "size": 4,
+                        "logicalType": "decimal", "precision": 9, "scale": 2
+                    }]},
+                    {"name": "kind", "type": {"type": "enum", "name": "Kind", "symbols": ["A", "B"]}},
+                    {"name": "tags", "type": {"type": "map", "values": "string"}}
+                ]
+            }"#,
+        )
+        .unwrap();
+        let event = |id: i64, amount: Option<i32>| {
+            let amount = match amount {
+                Some(cents) => AvroValue::Union(
+                    1,
+                    Box::new(AvroValue::Decimal(Decimal::from(cents.to_be_bytes()))),
+                ),
+                None => AvroValue::Union(0, Box::new(AvroValue::Null)),
+            };
+            AvroValue::Record(vec![
+                ("id".to_string(), AvroValue::Long(id)),
+                (
+                    "at".to_string(),
+                    AvroValue::TimestampMillis(1_700_000_000_000 + id),
+                ),
+                ("day".to_string(), AvroValue::Date(19_675)),
+                ("amount".to_string(), amount),
+                ("kind".to_string(), AvroValue::Enum(1, "B".to_string())),
+                (
+                    "tags".to_string(),
+                    AvroValue::Map(
+                        [("env".to_string(), AvroValue::String("prod".to_string()))].into(),
+                    ),
+                ),
+            ])
+        };
+        let mut writer = apache_avro::Writer::new(&schema, Vec::new());
+        writer.append(event(1, Some(-1999))).unwrap();
+        writer.append(event(2, None)).unwrap();
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(&writer.into_inner().unwrap()).unwrap();
+
+        let rows: Vec<Row> = read_rows(file.path(), Format::Avro)
+            .unwrap()
+            .map(|row| row.unwrap())
+            .collect();
+
+        assert_eq!(2, rows.len());
+        assert_eq!((2, 2), (rows[1].number, rows[1].line));
+        assert_eq!(
+            json!({
+                "id": 1,
+                "at": 1_700_000_000_001_000i64,
+                "day": 19_675,
+                "amount": -19.99,
+                "kind": "B",
+                "tags": {"env": "prod"},
+            }),
+            *rows[0].value.as_ref().unwrap()
+        );
+        assert_eq!(Value::Null, rows[1].value.as_ref().unwrap()["amount"]);
+    }
+}
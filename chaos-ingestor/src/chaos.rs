@@ -0,0 +1,327 @@
+//! Sending generated records with faults, retrying the failures that may succeed, and
+//! dead-lettering the rest
+
+use anyhow::{Context, Result};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::warn;
+use zerobus_common::errors::{classify, ErrorClass};
+use zerobus_common::pipeline::{IngestSink, Pipeline};
+
+use crate::event::ChaosEvent;
+use crate::faults::{Fault, FaultInjector};
+use crate::sink::{DelayingSink, Delays};
+
+/// Default for `MAX_ATTEMPTS`
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+
+/// Pause before sending a round of retries
+const RETRY_BACKOFF: Duration = Duration::from_millis(200);
+
+/// A record that was not acknowledged
+struct Failure {
+    event: ChaosEvent,
+    fault: Option<Fault>,
+    error: anyhow::Error,
+}
+
+/// What a run did
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ChaosReport {
+    pub generated: u64,
+    /// Records given each fault, by [`Fault::name`]
+    pub faults: BTreeMap<&'static str, u64>,
+    /// Records acknowledged, on their first attempt or a retry
+    pub ingested: u64,
+    /// Sends of records that had failed before
+    pub retried: u64,
+    /// Records given up on
+    pub dead_lettered: u64,
+    /// Failed attempts, by class
+    pub failed_by_class: BTreeMap<ErrorClass, u64>,
+}
+
+impl ChaosReport {
+    /// Records whose fault no retry can fix: oversized and malformed ones
+    pub fn expected_dead_letters(&self) -> u64 {
+        [Fault::Oversized, Fault::Malformed]
+            .iter()
+            .filter_map(|fault| self.faults.get(fault.name()))
+            .sum()
+    }
+
+    /// Problems with how the run's records ended up; none when every record was either
+    /// ingested or dead-lettered, and only the faults that cannot succeed were
+    /// dead-lettered
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let settled = self.ingested + self.dead_lettered;
+        if settled != self.generated {
+            problems.push(format!(
+                "{} records were generated, but {} were ingested or dead-lettered",
+                self.generated, settled
+            ));
+        }
+        if self.dead_lettered != self.expected_dead_letters() {
+            problems.push(format!(
+                "{} records were dead-lettered, but {} were oversized or malformed",
+                self.dead_lettered,
+                self.expected_dead_letters()
+            ));
+        }
+        problems
+    }
+}
+
+/// Generates records into a pipeline, injecting faults and handling what fails
+///
+/// Failures are handled at each [`Chaos::checkpoint`]: records that failed with a
+/// retryable error are sent again, up to the attempt limit, and the others are written
+/// to the dead letters in the JSON lines format `zb-replay` reads.
+pub struct Chaos<S: IngestSink> {
+    pipeline: Pipeline<DelayingSink<S>>,
+    delays: Delays,
+    injector: FaultInjector,
+    table: String,
+    payload_bytes: usize,
+    max_attempts: u32,
+    failures: Arc<Mutex<Vec<Failure>>>,
+    dead_letters: Option<Box<dyn Write + Send>>,
+    report: ChaosReport,
+}
+
+impl<S: IngestSink> Chaos<S> {
+    /// Send records for `table` into `sink`, with at most `max_inflight` unacknowledged,
+    /// failing acknowledgments that take longer than `ack_timeout`
+    pub fn new(
+        sink: S,
+        max_inflight: usize,
+        ack_timeout: Duration,
+        injector: FaultInjector,
+        table: impl Into<String>,
+    ) -> Self {
+        let sink = DelayingSink::new(sink, ack_timeout);
+        let delays = sink.delays();
+        Self {
+            pipeline: Pipeline::new(sink, max_inflight),
+            delays,
+            injector,
+            table: table.into(),
+            payload_bytes: 256,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            failures: Arc::default(),
+            dead_letters: None,
+            report: ChaosReport::default(),
+        }
+    }
+
+    /// Pad each record's payload to `bytes`
+    pub fn payload_bytes(mut self, bytes: usize) -> Self {
+        self.payload_bytes = bytes;
+        self
+    }
+
+    /// Send a record at most `attempts` times before dead-lettering it
+    pub fn max_attempts(mut self, attempts: u32) -> Self {
+        self.max_attempts = attempts.max(1);
+        self
+    }
+
+    /// Write dead-lettered records to `writer`, one JSON object per line
+    pub fn dead_letters(mut self, writer: impl Write + Send + 'static) -> Self {
+        self.dead_letters = Some(Box::new(writer));
+        self
+    }
+
+    /// Generate the next record and send it, with its fault if it draws one
+    pub async fn generate(&mut self) {
+        let event = ChaosEvent::generate(self.report.generated, self.payload_bytes);
+        self.report.generated += 1;
+        let fault = self.injector.next_fault();
+        if let Some(fault) = fault {
+            *self.report.faults.entry(fault.name()).or_default() += 1;
+        }
+        self.send(event, fault).await;
+    }
+
+    async fn send(&mut self, event: ChaosEvent, fault: Option<Fault>) {
+        // Only the first attempt is delayed, as a retry takes another route
+        if let (Some(Fault::Delay(delay)), None) = (fault, event.attempt) {
+            self.delays.delay_next(delay);
+        }
+        let record = self.injector.encode(&event, fault);
+        let failures = Arc::clone(&self.failures);
+        let sent = self
+            .pipeline
+            .ingest_with_callback(record, move |result| {
+                if let Err(error) = result {
+                    failures.lock().unwrap().push(Failure {
+                        event,
+                        fault,
+                        error,
+                    });
+                }
+            })
+            .await;
+        // A record that could not be sent has already been reported to its callback, but
+        // only as text; keep the error itself so it is classified by its type
+        if let Err(e) = sent {
+            warn!("Failed to send a record: {:#}", e);
+            if let Some(failure) = self.failures.lock().unwrap().last_mut() {
+                failure.error = e;
+            }
+        }
+    }
+
+    /// Wait until every record sent so far is acknowledged, retried until it is, or
+    /// dead-lettered
+    pub async fn checkpoint(&mut self) -> Result<()> {
+        loop {
+            self.pipeline.drain().await?;
+            let failures = std::mem::take(&mut *self.failures.lock().unwrap());
+            if failures.is_empty() {
+                return Ok(());
+            }
+            let mut retries = Vec::new();
+            for failure in failures {
+                let class = classify(&failure.error);
+                *self.report.failed_by_class.entry(class).or_default() += 1;
+                let attempts = failure.event.attempt.unwrap_or(0) as u32 + 1;
+                if class.is_retryable() && attempts < self.max_attempts {
+                    retries.push(failure);
+                } else {
+                    self.dead_letter(&failure)?;
+                }
+            }
+            if !retries.is_empty() {
+                tokio::time::sleep(RETRY_BACKOFF).await;
+            }
+            for Failure {
+                mut event, fault, ..
+            } in retries
+            {
+                event.attempt = Some(event.attempt.unwrap_or(0) + 1);
+                self.report.retried += 1;
+                self.send(event, fault).await;
+            }
+        }
+    }
+
+    fn dead_letter(&mut self, failure: &Failure) -> Result<()> {
+        self.report.dead_lettered += 1;
+        let Some(writer) = &mut self.dead_letters else {
+            return Ok(());
+        };
+        let line = serde_json::json!({
+            "table_name": self.table,
+            "record": BASE64_STANDARD.encode(self.injector.encode(&failure.event, failure.fault)),
+            "error": format!("{:#}", failure.error),
+            "error_class": classify(&failure.error).as_str(),
+            "fault": failure.fault.map(|fault| fault.name()),
+        });
+        writeln!(writer, "{}", line).context("Failed to write a dead letter")
+    }
+
+    /// Counts so far
+    pub fn report(&self) -> &ChaosReport {
+        &self.report
+    }
+
+    /// Settle every record sent, close the sink, and return the report
+    pub async fn finish(mut self) -> Result<ChaosReport> {
+        self.checkpoint().await?;
+        if let Some(writer) = &mut self.dead_letters {
+            writer.flush().context("Failed to write the dead letters")?;
+        }
+        let summary = self.pipeline.finish().await?;
+        self.report.ingested = summary.ingested;
+        Ok(self.report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::faults::FaultRates;
+    use crate::sink::FakeEndpoint;
+
+    /// Collects what is written to it, for reading back after the run
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_faults_are_retried_or_dead_lettered_against_the_fake_endpoint() {
+        let injector = FaultInjector::new(FaultRates {
+            oversized: 0.05,
+            malformed: 0.05,
+            delay: 0.1,
+        })
+        .unwrap()
+        .oversized_bytes(4096)
+        // Every delay is past the ack timeout
+        .max_delay(Duration::from_secs(10))
+        .seed(1);
+        let endpoint = FakeEndpoint::new(4096);
+        let dead_letters = Buffer::default();
+        let mut chaos = Chaos::new(
+            endpoint.clone(),
+            50,
+            Duration::from_millis(1),
+            injector,
+            "main.chaos.events",
+        )
+        .dead_letters(dead_letters.clone());
+        for i in 0..1000 {
+            chaos.generate().await;
+            if i % 100 == 99 {
+                chaos.checkpoint().await.unwrap();
+            }
+        }
+        let report = chaos.finish().await.unwrap();
+
+        assert!(report.problems().is_empty(), "{:?}", report.problems());
+        assert_eq!(1000, report.generated);
+        // Delays draw between 0 and 10s, so almost all of them time out and are retried
+        let delayed = report.faults["delay"];
+        assert!(report.retried > delayed * 9 / 10 && report.retried <= delayed);
+        assert_eq!(report.ingested, endpoint.accepted());
+        assert_eq!(
+            Some(&report.retried),
+            report.failed_by_class.get(&ErrorClass::AckTimeout)
+        );
+
+        let dead_letters = String::from_utf8(dead_letters.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<serde_json::Value> = dead_letters
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(report.dead_lettered as usize, lines.len());
+        assert!(lines
+            .iter()
+            .all(|line| line["table_name"] == "main.chaos.events"));
+        let oversized = lines
+            .iter()
+            .find(|line| line["fault"] == "oversized")
+            .unwrap();
+        assert_eq!("terminal", oversized["error_class"]);
+        let malformed = lines
+            .iter()
+            .find(|line| line["fault"] == "malformed")
+            .unwrap();
+        assert_eq!("schema", malformed["error_class"]);
+    }
+}
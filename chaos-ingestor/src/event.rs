@@ -0,0 +1,70 @@
+//! The generated records and the table they are written to
+
+use prost::Message;
+use prost_types::field_descriptor_proto::{Label, Type};
+use prost_types::{DescriptorProto, FieldDescriptorProto};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Name of the chaos event message in its descriptor
+pub const MESSAGE_NAME: &str = "table_chaos_events";
+
+/// One generated record
+#[derive(Clone, PartialEq, Message)]
+pub struct ChaosEvent {
+    /// Position in the run, from 0
+    #[prost(int64, optional, tag = "1")]
+    pub seq: Option<i64>,
+    /// [`crate::faults::Fault::name`] of the fault injected into the record, if any
+    #[prost(string, optional, tag = "2")]
+    pub fault: Option<String>,
+    /// Filler, to give records a realistic size
+    #[prost(string, optional, tag = "3")]
+    pub payload: Option<String>,
+    /// When the record was generated, microseconds since Unix epoch
+    #[prost(int64, optional, tag = "4")]
+    pub generated_at: Option<i64>,
+    /// Times the record was sent before, when it is retried
+    #[prost(int32, optional, tag = "5")]
+    pub attempt: Option<i32>,
+}
+
+impl ChaosEvent {
+    /// The record at `seq`, with `payload_bytes` of filler
+    pub fn generate(seq: u64, payload_bytes: usize) -> Self {
+        let mut payload = format!("event-{}", seq);
+        let filler = payload_bytes.saturating_sub(payload.len());
+        payload.push_str(&".".repeat(filler));
+        Self {
+            seq: Some(seq as i64),
+            fault: None,
+            payload: Some(payload),
+            generated_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|now| now.as_micros() as i64)
+                .ok(),
+            attempt: None,
+        }
+    }
+}
+
+/// Descriptor for creating a stream to the chaos events table
+pub fn descriptor() -> DescriptorProto {
+    let field = |name: &str, number: i32, r#type: Type| FieldDescriptorProto {
+        name: Some(name.to_string()),
+        number: Some(number),
+        r#type: Some(r#type as i32),
+        label: Some(Label::Optional as i32),
+        ..Default::default()
+    };
+    DescriptorProto {
+        name: Some(MESSAGE_NAME.to_string()),
+        field: vec![
+            field("seq", 1, Type::Int64),
+            field("fault", 2, Type::String),
+            field("payload", 3, Type::String),
+            field("generated_at", 4, Type::Int64),
+            field("attempt", 5, Type::Int32),
+        ],
+        ..Default::default()
+    }
+}
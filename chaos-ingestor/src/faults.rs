@@ -0,0 +1,321 @@
+//! Deciding which generated records carry a fault, and turning them into the bytes sent
+//!
+//! Every record draws one number from a seeded generator, which picks at most one fault,
+//! so over a run each fault reaches its configured fraction of the records.
+//!
+//! - `FAULT_OVERSIZED_RATE` - Fraction of records padded past `OVERSIZED_BYTES`
+//!   (default: `0`)
+//! - `FAULT_MALFORMED_RATE` - Fraction of records sent as bytes that do not decode as
+//!   the table's message (default: `0`)
+//! - `FAULT_DELAY_RATE` - Fraction of records whose acknowledgment is held back, as a
+//!   slow network would (default: `0`)
+//! - `FAULT_DELAY_MS` - Longest delay; each delay is drawn evenly up to it (default:
+//!   [`DEFAULT_MAX_DELAY`], 5s)
+//! - `OVERSIZED_BYTES` - Size oversized records are padded past (default:
+//!   [`DEFAULT_MAX_RECORD_BYTES`], 1MB)
+//! - `FAULT_SEED` - Seed, to repeat the faults of an earlier run (default: the time)
+
+use anyhow::{anyhow, bail, Result};
+use prost::Message;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::info;
+use zerobus_common::chunk::DEFAULT_MAX_RECORD_BYTES;
+
+use crate::event::ChaosEvent;
+
+/// Default for `FAULT_DELAY_MS`
+pub const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(5);
+
+/// A length-delimited field 3 claiming more bytes than follow it, which no decoder
+/// accepts
+const TRUNCATED_FIELD: [u8; 2] = [0x1A, 0x7F];
+
+/// A fault injected into one record
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Larger than the stream accepts
+    Oversized,
+    /// Not a valid encoding of the table's message
+    Malformed,
+    /// Acknowledged only after this long
+    Delay(Duration),
+}
+
+impl Fault {
+    /// The value of the `fault` column and of report keys
+    pub fn name(&self) -> &'static str {
+        match self {
+            Fault::Oversized => "oversized",
+            Fault::Malformed => "malformed",
+            Fault::Delay(_) => "delay",
+        }
+    }
+}
+
+/// Fraction of records given each fault
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FaultRates {
+    pub oversized: f64,
+    pub malformed: f64,
+    pub delay: f64,
+}
+
+impl FaultRates {
+    fn validate(&self) -> Result<()> {
+        for (name, rate) in [
+            ("oversized", self.oversized),
+            ("malformed", self.malformed),
+            ("delay", self.delay),
+        ] {
+            if !(0.0..=1.0).contains(&rate) {
+                bail!(
+                    "The {} fault rate must be between 0 and 1, got {}",
+                    name,
+                    rate
+                );
+            }
+        }
+        let total = self.oversized + self.malformed + self.delay;
+        if total > 1.0 {
+            bail!(
+                "Fault rates add up to {}; a record gets at most one fault, so they must add up to at most 1",
+                total
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Picks the fault of each record
+#[derive(Debug, Clone)]
+pub struct FaultInjector {
+    rates: FaultRates,
+    max_delay: Duration,
+    oversized_bytes: usize,
+    state: u64,
+}
+
+impl FaultInjector {
+    /// Inject faults at `rates`, which must each be within `0..=1` and add up to at
+    /// most 1
+    pub fn new(rates: FaultRates) -> Result<Self> {
+        rates.validate()?;
+        Ok(Self {
+            rates,
+            max_delay: DEFAULT_MAX_DELAY,
+            oversized_bytes: DEFAULT_MAX_RECORD_BYTES,
+            state: 0,
+        })
+    }
+
+    /// Hold back acknowledgments for at most `max_delay`
+    pub fn max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Pad oversized records past `bytes`
+    pub fn oversized_bytes(mut self, bytes: usize) -> Self {
+        self.oversized_bytes = bytes;
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.state = seed;
+        self
+    }
+
+    /// Read the `FAULT_*` variables and `OVERSIZED_BYTES`
+    pub fn from_env() -> Result<Self> {
+        let rates = FaultRates {
+            oversized: number_env("FAULT_OVERSIZED_RATE")?.unwrap_or(0.0),
+            malformed: number_env("FAULT_MALFORMED_RATE")?.unwrap_or(0.0),
+            delay: number_env("FAULT_DELAY_RATE")?.unwrap_or(0.0),
+        };
+        let seed = match number_env("FAULT_SEED")? {
+            Some(seed) => seed,
+            None => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|now| now.as_nanos() as u64)
+                .unwrap_or_default(),
+        };
+        info!("Fault seed: {}", seed);
+        let mut injector = Self::new(rates)?.seed(seed);
+        if let Some(max_delay) = number_env::<u64>("FAULT_DELAY_MS")? {
+            injector = injector.max_delay(Duration::from_millis(max_delay));
+        }
+        if let Some(bytes) = number_env("OVERSIZED_BYTES")? {
+            injector = injector.oversized_bytes(bytes);
+        }
+        Ok(injector)
+    }
+
+    pub fn rates(&self) -> FaultRates {
+        self.rates
+    }
+
+    /// The fault of the next record, if it gets one
+    pub fn next_fault(&mut self) -> Option<Fault> {
+        let draw = self.next_unit();
+        let FaultRates {
+            oversized,
+            malformed,
+            delay,
+        } = self.rates;
+        if draw < oversized {
+            Some(Fault::Oversized)
+        } else if draw < oversized + malformed {
+            Some(Fault::Malformed)
+        } else if draw < oversized + malformed + delay {
+            Some(Fault::Delay(self.max_delay.mul_f64(self.next_unit())))
+        } else {
+            None
+        }
+    }
+
+    /// The bytes to send for `event` with `fault`
+    ///
+    /// A delayed record is sent as is; its delay is applied to the acknowledgment.
+    pub fn encode(&self, event: &ChaosEvent, fault: Option<Fault>) -> Vec<u8> {
+        let mut event = event.clone();
+        event.fault = fault.map(|fault| fault.name().to_string());
+        match fault {
+            Some(Fault::Oversized) => {
+                let payload = event.payload.get_or_insert_with(String::new);
+                let padding = (self.oversized_bytes + 1).saturating_sub(payload.len());
+                payload.push_str(&"x".repeat(padding));
+                event.encode_to_vec()
+            }
+            Some(Fault::Malformed) => {
+                let mut record = event.encode_to_vec();
+                record.extend_from_slice(&TRUNCATED_FIELD);
+                record
+            }
+            Some(Fault::Delay(_)) | None => event.encode_to_vec(),
+        }
+    }
+
+    /// A number in `0..1`; splitmix64, so the sequence only depends on the seed
+    fn next_unit(&mut self) -> f64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// The number in the variable `name`; `None` when it is not set
+fn number_env<T: std::str::FromStr>(name: &str) -> Result<Option<T>> {
+    match std::env::var(name) {
+        Ok(value) if !value.trim().is_empty() => value
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| anyhow!("{} must be a number, got {:?}", name, value)),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_faults_reach_their_configured_fraction() {
+        let rates = FaultRates {
+            oversized: 0.02,
+            malformed: 0.05,
+            delay: 0.1,
+        };
+        let mut injector = FaultInjector::new(rates)
+            .unwrap()
+            .max_delay(Duration::from_millis(100))
+            .seed(42);
+        let total = 100_000;
+        let (mut oversized, mut malformed, mut delayed) = (0, 0, 0);
+        for _ in 0..total {
+            match injector.next_fault() {
+                Some(Fault::Oversized) => oversized += 1,
+                Some(Fault::Malformed) => malformed += 1,
+                Some(Fault::Delay(delay)) => {
+                    assert!(delay <= Duration::from_millis(100));
+                    delayed += 1;
+                }
+                None => {}
+            }
+        }
+        for (name, count, rate) in [
+            ("oversized", oversized, rates.oversized),
+            ("malformed", malformed, rates.malformed),
+            ("delay", delayed, rates.delay),
+        ] {
+            let fraction = count as f64 / total as f64;
+            // Within five standard deviations of the rate
+            let tolerance = 5.0 * (rate * (1.0 - rate) / total as f64).sqrt();
+            assert!(
+                (fraction - rate).abs() <= tolerance,
+                "{}: {} of records, configured {}",
+                name,
+                fraction,
+                rate
+            );
+        }
+    }
+
+    #[test]
+    fn test_same_seed_injects_the_same_faults() {
+        let faults = |seed| {
+            let mut injector = FaultInjector::new(FaultRates {
+                oversized: 0.3,
+                malformed: 0.3,
+                delay: 0.3,
+            })
+            .unwrap()
+            .seed(seed);
+            (0..100).map(|_| injector.next_fault()).collect::<Vec<_>>()
+        };
+        assert_eq!(faults(7), faults(7));
+        assert_ne!(faults(7), faults(8));
+
+        let mut none = FaultInjector::new(FaultRates::default()).unwrap();
+        assert!((0..1000).all(|_| none.next_fault().is_none()));
+    }
+
+    #[test]
+    fn test_faulty_records() {
+        let injector = FaultInjector::new(FaultRates::default())
+            .unwrap()
+            .oversized_bytes(1000);
+        let event = ChaosEvent::generate(3, 64);
+
+        let oversized = injector.encode(&event, Some(Fault::Oversized));
+        assert!(oversized.len() > 1000);
+        let decoded = ChaosEvent::decode(oversized.as_slice()).unwrap();
+        assert_eq!(Some("oversized"), decoded.fault.as_deref());
+
+        let malformed = injector.encode(&event, Some(Fault::Malformed));
+        assert!(ChaosEvent::decode(malformed.as_slice()).is_err());
+
+        let delayed = injector.encode(&event, Some(Fault::Delay(Duration::ZERO)));
+        let decoded = ChaosEvent::decode(delayed.as_slice()).unwrap();
+        assert_eq!(Some("delay"), decoded.fault.as_deref());
+        assert_eq!(event.payload, decoded.payload);
+    }
+
+    #[test]
+    fn test_rates_are_validated() {
+        let rates = |oversized, malformed, delay| {
+            FaultInjector::new(FaultRates {
+                oversized,
+                malformed,
+                delay,
+            })
+        };
+        assert!(rates(0.5, 0.5, 0.0).is_ok());
+        assert!(rates(0.5, 0.4, 0.2).is_err());
+        assert!(rates(-0.1, 0.0, 0.0).is_err());
+        assert!(rates(f64::NAN, 0.0, 0.0).is_err());
+    }
+}
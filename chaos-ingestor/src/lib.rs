@@ -0,0 +1,4 @@
+pub mod chaos;
+pub mod event;
+pub mod faults;
+pub mod sink;
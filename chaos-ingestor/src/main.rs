@@ -0,0 +1,188 @@
+use anyhow::{bail, Context, Result};
+use chaos_ingestor::chaos::{Chaos, ChaosReport, DEFAULT_MAX_ATTEMPTS};
+use chaos_ingestor::event;
+use chaos_ingestor::faults::FaultInjector;
+use chaos_ingestor::sink::{FakeEndpoint, DEFAULT_ACK_TIMEOUT};
+use databricks_zerobus_ingest_sdk::{StreamConfigurationOptions, TableProperties, ZerobusSdk};
+use std::fs::OpenOptions;
+use std::io::BufWriter;
+use std::pin::pin;
+use std::time::Duration;
+use tokio::time::MissedTickBehavior;
+use tracing::{info, warn};
+use zerobus_common::chunk::DEFAULT_MAX_RECORD_BYTES;
+use zerobus_common::pipeline::IngestSink;
+use zerobus_common::shutdown;
+
+/// Records generated when RECORD_COUNT is not set
+const DEFAULT_RECORD_COUNT: u64 = 10_000;
+
+/// Generation rate when RECORDS_PER_SEC is not set
+const DEFAULT_RECORDS_PER_SEC: u64 = 1_000;
+
+/// Filler per record when PAYLOAD_BYTES is not set
+const DEFAULT_PAYLOAD_BYTES: u64 = 256;
+
+/// Maximum number of unacknowledged records when MAX_INFLIGHT is not set
+const DEFAULT_MAX_INFLIGHT: u64 = 1_000;
+
+/// Records between checkpoints when CHECKPOINT_EVERY is not set
+const DEFAULT_CHECKPOINT_EVERY: u64 = 1_000;
+
+fn env(name: &str) -> Result<String> {
+    std::env::var(name).with_context(|| format!("{} environment variable must be set", name))
+}
+
+/// How the run is paced and settled
+struct Settings {
+    record_count: u64,
+    records_per_sec: u64,
+    checkpoint_every: u64,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .with_target(false)
+        .init();
+
+    let table = env("TABLE_NAME")?;
+    let target = std::env::var("TARGET").unwrap_or_else(|_| "zerobus".to_string());
+    let injector = FaultInjector::from_env()?;
+    let settings = Settings {
+        record_count: positive_env("RECORD_COUNT", DEFAULT_RECORD_COUNT)?,
+        records_per_sec: positive_env("RECORDS_PER_SEC", DEFAULT_RECORDS_PER_SEC)?,
+        checkpoint_every: positive_env("CHECKPOINT_EVERY", DEFAULT_CHECKPOINT_EVERY)?,
+    };
+    let max_inflight = positive_env("MAX_INFLIGHT", DEFAULT_MAX_INFLIGHT)? as usize;
+    let ack_timeout = Duration::from_millis(positive_env(
+        "ACK_TIMEOUT_MS",
+        DEFAULT_ACK_TIMEOUT.as_millis() as u64,
+    )?);
+    let payload_bytes = positive_env("PAYLOAD_BYTES", DEFAULT_PAYLOAD_BYTES)? as usize;
+    let max_attempts = positive_env("MAX_ATTEMPTS", DEFAULT_MAX_ATTEMPTS as u64)? as u32;
+    let dead_letters = match std::env::var("DLQ_PATH") {
+        Ok(path) if !path.trim().is_empty() => Some((
+            BufWriter::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .with_context(|| format!("Failed to open DLQ_PATH {}", path))?,
+            ),
+            path,
+        )),
+        _ => None,
+    };
+    let rates = injector.rates();
+    info!(
+        "Generating {} records for {} against {}: {} oversized, {} malformed, {} delayed",
+        settings.record_count, table, target, rates.oversized, rates.malformed, rates.delay
+    );
+
+    let report = match target.as_str() {
+        "fake" => {
+            let max_record_bytes =
+                positive_env("OVERSIZED_BYTES", DEFAULT_MAX_RECORD_BYTES as u64)?;
+            let sink = FakeEndpoint::new(max_record_bytes as usize);
+            let chaos = Chaos::new(sink, max_inflight, ack_timeout, injector, &table);
+            run(chaos, payload_bytes, max_attempts, dead_letters, &settings).await?
+        }
+        "zerobus" => {
+            let sdk = ZerobusSdk::new(env("ZEROBUS_ENDPOINT")?, env("DATABRICKS_HOST")?)?;
+            let table_properties = TableProperties {
+                table_name: table.clone(),
+                descriptor_proto: event::descriptor(),
+            };
+            let stream_options = StreamConfigurationOptions {
+                max_inflight_records: max_inflight,
+                ..Default::default()
+            };
+            let stream = sdk
+                .create_stream(
+                    table_properties,
+                    env("DATABRICKS_CLIENT_ID")?,
+                    env("DATABRICKS_CLIENT_SECRET")?,
+                    Some(stream_options),
+                )
+                .await
+                .with_context(|| format!("Failed to create stream to {}", table))?;
+            let chaos = Chaos::new(stream, max_inflight, ack_timeout, injector, &table);
+            run(chaos, payload_bytes, max_attempts, dead_letters, &settings).await?
+        }
+        _ => bail!("TARGET must be \"zerobus\" or \"fake\", got {:?}", target),
+    };
+
+    info!(
+        "Generated {} records with faults {:?}: {} ingested, {} retries, {} dead-lettered",
+        report.generated, report.faults, report.ingested, report.retried, report.dead_lettered
+    );
+    for (class, count) in &report.failed_by_class {
+        info!("  {} failed attempts: {}", class, count);
+    }
+    let problems = report.problems();
+    for problem in &problems {
+        warn!("{}", problem);
+    }
+    if !problems.is_empty() {
+        bail!("The run did not settle every record as expected");
+    }
+    Ok(())
+}
+
+/// Generate records at the configured rate until the count is reached or a shutdown
+/// signal arrives, settling them every `checkpoint_every` records
+async fn run<S: IngestSink>(
+    chaos: Chaos<S>,
+    payload_bytes: usize,
+    max_attempts: u32,
+    dead_letters: Option<(BufWriter<std::fs::File>, String)>,
+    settings: &Settings,
+) -> Result<ChaosReport> {
+    let mut chaos = chaos
+        .payload_bytes(payload_bytes)
+        .max_attempts(max_attempts);
+    if let Some((writer, path)) = dead_letters {
+        info!("Writing dead letters to {}", path);
+        chaos = chaos.dead_letters(writer);
+    }
+
+    let mut ticks =
+        tokio::time::interval(Duration::from_secs(1).div_f64(settings.records_per_sec as f64));
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Burst);
+    let mut shutdown_signal = pin!(shutdown::signal());
+    for generated in 1..=settings.record_count {
+        tokio::select! {
+            _ = ticks.tick() => {}
+            _ = &mut shutdown_signal => {
+                info!("Stopping after {} records", generated - 1);
+                break;
+            }
+        }
+        chaos.generate().await;
+        if generated % settings.checkpoint_every == 0 {
+            chaos.checkpoint().await?;
+            let report = chaos.report();
+            info!(
+                "{} records: {} retries, {} dead-lettered so far",
+                report.generated, report.retried, report.dead_lettered
+            );
+        }
+    }
+    chaos.finish().await
+}
+
+fn positive_env(name: &str, default: u64) -> Result<u64> {
+    let value = match std::env::var(name) {
+        Ok(value) => value
+            .trim()
+            .parse::<u64>()
+            .with_context(|| format!("{} must be a positive integer, got {:?}", name, value))?,
+        Err(_) => default,
+    };
+    if value == 0 {
+        bail!("{} must be a positive integer, got 0", name);
+    }
+    Ok(value)
+}
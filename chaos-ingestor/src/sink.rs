@@ -0,0 +1,149 @@
+//! Sinks for the chaos run: one holding back acknowledgments, and a fake endpoint
+
+use anyhow::{anyhow, Result};
+use databricks_zerobus_ingest_sdk::ZerobusError;
+use prost::Message;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+use zerobus_common::pipeline::{AckFuture, IngestSink};
+
+use crate::event::ChaosEvent;
+
+/// Default for `ACK_TIMEOUT_MS`
+pub const DEFAULT_ACK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Sets the delay of the next record sent through a [`DelayingSink`]
+#[derive(Debug, Clone, Default)]
+pub struct Delays(Arc<Mutex<Option<Duration>>>);
+
+impl Delays {
+    /// Hold back the acknowledgment of the next record for `delay`
+    pub fn delay_next(&self, delay: Duration) {
+        *self.0.lock().unwrap() = Some(delay);
+    }
+
+    fn take(&self) -> Option<Duration> {
+        self.0.lock().unwrap().take()
+    }
+}
+
+/// Holds back the acknowledgments of chosen records, and fails any acknowledgment that
+/// takes longer than the timeout
+///
+/// The timeout runs from the record's send, so a delay past it fails as an ack timeout
+/// whether or not the acknowledgment would have come.
+pub struct DelayingSink<S> {
+    inner: S,
+    delays: Delays,
+    ack_timeout: Duration,
+}
+
+impl<S: IngestSink> DelayingSink<S> {
+    pub fn new(inner: S, ack_timeout: Duration) -> Self {
+        Self {
+            inner,
+            delays: Delays::default(),
+            ack_timeout,
+        }
+    }
+
+    /// The handle setting the delay of the next record
+    pub fn delays(&self) -> Delays {
+        self.delays.clone()
+    }
+}
+
+impl<S: IngestSink> IngestSink for DelayingSink<S> {
+    async fn ingest(&mut self, record: Vec<u8>) -> Result<AckFuture> {
+        let delay = self.delays.take();
+        let sent_at = Instant::now();
+        let ack = self.inner.ingest(record).await?;
+        let ack_timeout = self.ack_timeout;
+        Ok(Box::pin(async move {
+            let acknowledged = async {
+                if let Some(delay) = delay {
+                    tokio::time::sleep_until(sent_at + delay).await;
+                }
+                ack.await
+            };
+            match tokio::time::timeout_at(sent_at + ack_timeout, acknowledged).await {
+                Ok(result) => result,
+                Err(_) => Err(anyhow!(
+                    "Record acknowledgment timed out after {:?}",
+                    ack_timeout
+                )),
+            }
+        }))
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        self.inner.flush().await
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        self.inner.close().await
+    }
+}
+
+/// Stands in for a Zerobus stream, rejecting records the way the server does
+///
+/// Records over the size limit are refused when sent, and records that are not a
+/// [`ChaosEvent`] fail their acknowledgment, each with the error the SDK reports.
+/// Clones share their counts.
+#[derive(Debug, Clone)]
+pub struct FakeEndpoint {
+    max_record_bytes: usize,
+    accepted: Arc<AtomicU64>,
+}
+
+impl FakeEndpoint {
+    pub fn new(max_record_bytes: usize) -> Self {
+        Self {
+            max_record_bytes,
+            accepted: Arc::default(),
+        }
+    }
+
+    /// Records acknowledged so far
+    pub fn accepted(&self) -> u64 {
+        self.accepted.load(Ordering::Relaxed)
+    }
+}
+
+impl IngestSink for FakeEndpoint {
+    async fn ingest(&mut self, record: Vec<u8>) -> Result<AckFuture> {
+        if record.len() > self.max_record_bytes {
+            return Err(ZerobusError::InvalidArgument(format!(
+                "Record of {} bytes exceeds the maximum of {} bytes",
+                record.len(),
+                self.max_record_bytes
+            ))
+            .into());
+        }
+        let decoded = ChaosEvent::decode(record.as_slice());
+        let accepted = Arc::clone(&self.accepted);
+        Ok(Box::pin(async move {
+            match decoded {
+                Ok(_) => {
+                    accepted.fetch_add(1, Ordering::Relaxed);
+                    Ok(())
+                }
+                Err(e) => Err(ZerobusError::InvalidArgument(format!(
+                    "Record does not match the table schema: {}",
+                    e
+                ))
+                .into()),
+            }
+        }))
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
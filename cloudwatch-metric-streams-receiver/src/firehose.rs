@@ -0,0 +1,196 @@
+//! Firehose HTTP endpoint delivery requests and responses
+//!
+//! Firehose POSTs a batch of records as a JSON document, each record's data
+//! base64-encoded, and expects the request ID back in a JSON response:
+//!
+//! ```json
+//! {
+//!   "requestId": "ed4acda5-034f-9f42-bba1-f29aea6d7d8f",
+//!   "timestamp": 1578090901599,
+//!   "records": [{"data": "eyJtZXRyaWNfc3RyZWFtX25hbWUiOiAi..."}]
+//! }
+//! ```
+//!
+//! Anything but a 200 response is retried until the stream's retry duration runs out,
+//! after which the records go to the stream's S3 backup bucket.
+
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose, Engine as _};
+use flate2::read::GzDecoder;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io::Read;
+
+/// Header holding the access key configured on the Firehose stream's destination
+pub const ACCESS_KEY_HEADER: &str = "X-Amz-Firehose-Access-Key";
+
+/// Header repeating the body's request ID
+pub const REQUEST_ID_HEADER: &str = "X-Amz-Firehose-Request-Id";
+
+/// Room for the largest buffer Firehose sends to an HTTP endpoint, base64 included
+///
+/// Also the most a gzipped request may inflate to, so a small body cannot expand into
+/// gigabytes.
+pub const MAX_REQUEST_BYTES: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeliveryRequest {
+    pub request_id: String,
+    /// When Firehose sent the request, in milliseconds since Unix epoch
+    pub timestamp: i64,
+    #[serde(default)]
+    pub records: Vec<FirehoseRecord>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FirehoseRecord {
+    /// Base64-encoded record data
+    pub data: String,
+}
+
+impl DeliveryRequest {
+    /// Parse a request body, gunzipping it first if the stream compresses requests
+    pub fn parse(body: &[u8], gzipped: bool) -> Result<Self> {
+        if gzipped {
+            let mut json = Vec::new();
+            GzDecoder::new(body)
+                .take(MAX_REQUEST_BYTES as u64 + 1)
+                .read_to_end(&mut json)
+                .context("Body is not valid gzip")?;
+            if json.len() > MAX_REQUEST_BYTES {
+                bail!("Body inflates to more than {} bytes", MAX_REQUEST_BYTES);
+            }
+            return Self::parse(&json, false);
+        }
+        serde_json::from_slice(body).context("Body is not a Firehose delivery request")
+    }
+
+    /// Each record's decoded data, in order
+    pub fn data(&self) -> Result<Vec<Vec<u8>>> {
+        self.records
+            .iter()
+            .enumerate()
+            .map(|(index, record)| {
+                general_purpose::STANDARD
+                    .decode(&record.data)
+                    .with_context(|| format!("Record {} is not base64", index))
+            })
+            .collect()
+    }
+}
+
+/// The response body Firehose expects, echoing the request ID; with an error message
+/// for anything but a 200
+pub fn response(request_id: &str, now_ms: i64, error: Option<&str>) -> Value {
+    let mut body = json!({ "requestId": request_id, "timestamp": now_ms });
+    if let Some(error) = error {
+        body["errorMessage"] = error.into();
+    }
+    body
+}
+
+/// Compare access keys without revealing through timing how much of a guess was right
+pub fn access_key_matches(expected: &str, given: Option<&str>) -> bool {
+    let Some(given) = given else {
+        return false;
+    };
+    expected.len() == given.len()
+        && expected
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    fn request_body(records: &[&[u8]]) -> Vec<u8> {
+        let records: Vec<Value> = records
+            .iter()
+            .map(|data| json!({ "data": general_purpose::STANDARD.encode(data) }))
+            .collect();
+        json!({
+            "requestId": "ed4acda5-034f-9f42-bba1-f29aea6d7d8f",
+            "timestamp": 1_578_090_901_599i64,
+            "records": records
+        })
+        .to_string()
+        .into_bytes()
+    }
+
+    #[test]
+    fn test_request_decoding() {
+        let request = DeliveryRequest::parse(&request_body(&[b"first", b"second"]), false).unwrap();
+
+        assert_eq!("ed4acda5-034f-9f42-bba1-f29aea6d7d8f", request.request_id);
+        assert_eq!(1_578_090_901_599, request.timestamp);
+        assert_eq!(
+            vec![b"first".to_vec(), b"second".to_vec()],
+            request.data().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_gzipped_request() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&request_body(&[b"first"])).unwrap();
+        let body = encoder.finish().unwrap();
+
+        let request = DeliveryRequest::parse(&body, true).unwrap();
+        assert_eq!(vec![b"first".to_vec()], request.data().unwrap());
+        assert!(DeliveryRequest::parse(&body, false).is_err());
+    }
+
+    #[test]
+    fn test_gzipped_request_inflating_past_the_limit() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder
+            .write_all(&vec![b' '; MAX_REQUEST_BYTES + 1])
+            .unwrap();
+        let body = encoder.finish().unwrap();
+        assert!(body.len() < 1024 * 1024);
+
+        let error = DeliveryRequest::parse(&body, true).unwrap_err();
+        assert_eq!(
+            format!("Body inflates to more than {} bytes", MAX_REQUEST_BYTES),
+            error.to_string()
+        );
+    }
+
+    #[test]
+    fn test_invalid_requests() {
+        assert!(DeliveryRequest::parse(b"{\"records\": []}", false).is_err());
+        assert!(DeliveryRequest::parse(b"not json", true).is_err());
+
+        let mut request = DeliveryRequest::parse(&request_body(&[b"first"]), false).unwrap();
+        request.records[0].data = "%%%".to_string();
+        let error = request.data().unwrap_err();
+        assert_eq!("Record 0 is not base64", error.to_string());
+    }
+
+    #[test]
+    fn test_response() {
+        assert_eq!(
+            json!({"requestId": "r-1", "timestamp": 1000}),
+            response("r-1", 1000, None)
+        );
+        assert_eq!(
+            json!({"requestId": "r-1", "timestamp": 1000, "errorMessage": "Bad record"}),
+            response("r-1", 1000, Some("Bad record"))
+        );
+    }
+
+    #[test]
+    fn test_access_key_matches() {
+        assert!(access_key_matches("s3cret", Some("s3cret")));
+        assert!(!access_key_matches("s3cret", Some("s3creT")));
+        assert!(!access_key_matches("s3cret", Some("s3cret-and-more")));
+        assert!(!access_key_matches("s3cret", None));
+    }
+}
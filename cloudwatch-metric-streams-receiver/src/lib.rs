@@ -0,0 +1,3 @@
+pub mod firehose;
+pub mod metric;
+pub mod server;
@@ -0,0 +1,125 @@
+use anyhow::{bail, Context, Result};
+use cloudwatch_metric_streams_receiver::server::{router, Receiver, FIREHOSE_PATH};
+use databricks_zerobus_ingest_sdk::{StreamConfigurationOptions, TableProperties, ZerobusSdk};
+use std::sync::Arc;
+use tracing::{info, warn};
+use zerobus_common::descriptor::find_message_descriptor;
+use zerobus_common::dynamic::{coerce_from_env, DynamicEncoder, FieldErrorMode, KeyCase};
+use zerobus_common::log_level;
+use zerobus_common::pipeline::Pipeline;
+use zerobus_common::shutdown;
+
+/// Maximum number of unacknowledged records on the stream
+const MAX_INFLIGHT_RECORDS: usize = 10_000;
+
+/// Port to listen on when PORT is not set
+const DEFAULT_PORT: &str = "8080";
+
+fn env(name: &str) -> Result<String> {
+    std::env::var(name).with_context(|| format!("{} environment variable must be set", name))
+}
+
+fn env_or(name: &str, default: &str) -> String {
+    std::env::var(name).unwrap_or_else(|_| default.to_string())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let log_level = log_level::init()?;
+    tokio::spawn(log_level::on_hangup(log_level.clone()));
+
+    let zerobus_endpoint = env("ZEROBUS_ENDPOINT")?;
+    let databricks_host = env("DATABRICKS_HOST")?;
+    let client_id = env("DATABRICKS_CLIENT_ID")?;
+    let client_secret = env("DATABRICKS_CLIENT_SECRET")?;
+    let table_name = env("TABLE_NAME")?;
+    let descriptor_set = env_or("DESCRIPTOR_SET", "gen/descriptors/tables.descriptor");
+    let message_name = std::env::var("MESSAGE_NAME").unwrap_or_else(|_| {
+        format!(
+            "table_{}",
+            table_name.rsplit('.').next().unwrap_or(&table_name)
+        )
+    });
+    let access_key = std::env::var("FIREHOSE_ACCESS_KEY")
+        .ok()
+        .filter(|key| !key.is_empty());
+    if access_key.is_none() {
+        warn!(
+            "FIREHOSE_ACCESS_KEY is not set; accepting unauthenticated requests on {}",
+            FIREHOSE_PATH
+        );
+    }
+    let port = env_or("PORT", DEFAULT_PORT);
+    let grace = shutdown::grace_from_env()?;
+
+    let bytes = std::fs::read(&descriptor_set)
+        .with_context(|| format!("Failed to read descriptor set {}", descriptor_set))?;
+    let descriptor = find_message_descriptor(&bytes, &message_name)?;
+    // Metric records carry fields this table does not model, such as new statistics
+    // CloudWatch adds; they are dropped rather than rejecting the whole delivery
+    let encoder = DynamicEncoder::new(&descriptor)?
+        .ignore_unknown_fields(true)
+        .coerce_types(coerce_from_env()?)
+        .field_error_mode(FieldErrorMode::from_env()?)
+        .key_case(KeyCase::from_env()?);
+
+    let sdk = ZerobusSdk::new(zerobus_endpoint, databricks_host)?;
+    let table_properties = TableProperties {
+        table_name: table_name.clone(),
+        descriptor_proto: descriptor,
+    };
+    let stream_options = StreamConfigurationOptions {
+        max_inflight_records: MAX_INFLIGHT_RECORDS,
+        ..Default::default()
+    };
+    let stream = sdk
+        .create_stream(
+            table_properties,
+            client_id,
+            client_secret,
+            Some(stream_options),
+        )
+        .await
+        .with_context(|| format!("Failed to create stream to {}", table_name))?;
+
+    let receiver = Arc::new(Receiver::new(
+        encoder,
+        Pipeline::new(stream, MAX_INFLIGHT_RECORDS),
+        access_key,
+    ));
+
+    let listen_addr = format!("0.0.0.0:{}", port);
+    let listener = tokio::net::TcpListener::bind(&listen_addr)
+        .await
+        .with_context(|| format!("Failed to bind {}", listen_addr))?;
+    info!(
+        "Receiving Firehose deliveries on http://{}{} -> {}",
+        listen_addr, FIREHOSE_PATH, table_name
+    );
+
+    let mut app = router(Arc::clone(&receiver));
+    if let Some(admin) = log_level::admin_routes_from_env(&log_level) {
+        app = app.merge(admin);
+    }
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown::signal())
+        .await?;
+
+    // Every request has returned, so this is the last reference to the receiver
+    let Ok(receiver) = Arc::try_unwrap(receiver) else {
+        bail!("Receiver is still in use after shutdown");
+    };
+    let outcome = shutdown::drain(receiver.into_pipeline(), grace).await?;
+    info!(
+        "Shut down after ingesting {} metrics ({} failed)",
+        outcome.summary.ingested, outcome.summary.failed
+    );
+    if !outcome.unacked.is_empty() {
+        bail!(
+            "{} metrics were not acknowledged before shutdown",
+            outcome.unacked.len()
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,238 @@
+//! CloudWatch Metric Streams records in the JSON output format
+//!
+//! Each Firehose record holds one or more metric records, each a JSON object with one
+//! metric's statistics over one period:
+//!
+//! ```json
+//! {"metric_stream_name":"metrics-to-delta","account_id":"123456789012","region":"us-east-1",
+//!  "namespace":"AWS/EC2","metric_name":"CPUUtilization","dimensions":{"InstanceId":"i-0a1b2c3d"},
+//!  "timestamp":1718020800000,"value":{"max":41.5,"min":2.0,"sum":87.5,"count":5.0,"p99":40.9},
+//!  "unit":"Percent"}
+//! ```
+//!
+//! Metric streams end each record with a newline, but Firehose does not promise a
+//! delimiter between the records it batches, so records are split where one JSON
+//! object ends and the next begins rather than on newlines.
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct MetricRecord {
+    #[serde(default)]
+    pub metric_stream_name: String,
+    #[serde(default)]
+    pub account_id: String,
+    #[serde(default)]
+    pub region: String,
+    pub namespace: String,
+    pub metric_name: String,
+    #[serde(default)]
+    pub dimensions: BTreeMap<String, String>,
+    /// Start of the period, in milliseconds since Unix epoch
+    pub timestamp: i64,
+    pub value: MetricValue,
+    /// CloudWatch unit, such as `Percent`, `Bytes`, or `None`
+    #[serde(default)]
+    pub unit: Option<String>,
+}
+
+/// The statistics of one period
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct MetricValue {
+    pub min: f64,
+    pub max: f64,
+    pub sum: f64,
+    pub count: f64,
+    /// Additional statistics the stream is configured to include, such as `p99` or
+    /// `TM(10%:90%)`, by name
+    #[serde(flatten)]
+    pub statistics: BTreeMap<String, f64>,
+}
+
+/// The metric records in the data of one Firehose record
+pub fn parse_records(data: &[u8]) -> Result<Vec<MetricRecord>> {
+    match data.iter().find(|byte| !byte.is_ascii_whitespace()) {
+        None => return Ok(Vec::new()),
+        Some(b'{') => {}
+        Some(_) => bail!(
+            "Record data is not JSON; set the metric stream's output format to JSON, as the OpenTelemetry formats are not supported"
+        ),
+    }
+    serde_json::Deserializer::from_slice(data)
+        .into_iter::<Value>()
+        .enumerate()
+        .map(|(index, value)| {
+            let value =
+                value.with_context(|| format!("Metric record {} is not valid JSON", index))?;
+            serde_json::from_value(value)
+                .with_context(|| format!("Metric record {} is not a metric stream record", index))
+        })
+        .collect()
+}
+
+impl MetricRecord {
+    /// The record as a row of the metrics table
+    ///
+    /// `timestamp` is in microseconds since Unix epoch, for a TIMESTAMP column. A unit
+    /// of `None` leaves the column unset.
+    pub fn row(&self) -> Map<String, Value> {
+        let mut row = Map::new();
+        row.insert(
+            "metric_stream_name".into(),
+            self.metric_stream_name.clone().into(),
+        );
+        row.insert("account_id".into(), self.account_id.clone().into());
+        row.insert("region".into(), self.region.clone().into());
+        row.insert("namespace".into(), self.namespace.clone().into());
+        row.insert("metric_name".into(), self.metric_name.clone().into());
+        row.insert(
+            "dimensions".into(),
+            Value::Object(
+                self.dimensions
+                    .iter()
+                    .map(|(name, value)| (name.clone(), value.clone().into()))
+                    .collect(),
+            ),
+        );
+        row.insert(
+            "timestamp".into(),
+            self.timestamp.saturating_mul(1000).into(),
+        );
+        if let Some(unit) = self.unit.as_deref().filter(|unit| *unit != "None") {
+            row.insert("unit".into(), unit.into());
+        }
+        row.insert("min".into(), self.value.min.into());
+        row.insert("max".into(), self.value.max.into());
+        row.insert("sum".into(), self.value.sum.into());
+        row.insert("count".into(), self.value.count.into());
+        row.insert(
+            "statistics".into(),
+            Value::Object(
+                self.value
+                    .statistics
+                    .iter()
+                    .map(|(name, value)| (name.clone(), (*value).into()))
+                    .collect(),
+            ),
+        );
+        row
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const FIXTURE: &str = include_str!("../testdata/metric-stream.jsonl");
+
+    #[test]
+    fn test_fixture_records() {
+        let records = parse_records(FIXTURE.as_bytes()).unwrap();
+
+        assert_eq!(5, records.len());
+        let cpu = &records[0];
+        assert_eq!("metrics-to-delta", cpu.metric_stream_name);
+        assert_eq!("123456789012", cpu.account_id);
+        assert_eq!("us-east-1", cpu.region);
+        assert_eq!("AWS/EC2", cpu.namespace);
+        assert_eq!("CPUUtilization", cpu.metric_name);
+        assert_eq!(
+            BTreeMap::from([("InstanceId".to_string(), "i-0a1b2c3d4e5f67890".to_string())]),
+            cpu.dimensions
+        );
+        assert_eq!(1_718_020_800_000, cpu.timestamp);
+        assert_eq!(Some("Percent".to_string()), cpu.unit);
+        assert_eq!(2.0, cpu.value.min);
+        assert_eq!(41.5, cpu.value.max);
+        assert_eq!(87.5, cpu.value.sum);
+        assert_eq!(5.0, cpu.value.count);
+        assert!(cpu.value.statistics.is_empty());
+
+        // Additional statistics, such as percentiles and trimmed means
+        let latency = &records[2];
+        assert_eq!("TargetResponseTime", latency.metric_name);
+        assert_eq!(Some(&0.412), latency.value.statistics.get("p99"));
+        assert_eq!(Some(&0.087), latency.value.statistics.get("TM(10%:90%)"));
+    }
+
+    #[test]
+    fn test_records_without_delimiters() {
+        // As Firehose may batch them: one object right after the other
+        let concatenated: String = FIXTURE.lines().collect();
+        assert!(!concatenated.contains('\n'));
+
+        assert_eq!(
+            parse_records(FIXTURE.as_bytes()).unwrap(),
+            parse_records(concatenated.as_bytes()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_custom_namespaces_and_unknown_fields() {
+        let data = br#"{"namespace":"Checkout/Orders","metric_name":"OrdersPlaced","dimensions":{"Store":"berlin-1","Channel":"app"},"timestamp":1718020860000,"value":{"max":4,"min":0,"sum":17,"count":12},"unit":"Count","future_field":{"a":1}}"#;
+
+        let records = parse_records(data).unwrap();
+        assert_eq!(1, records.len());
+        assert_eq!("Checkout/Orders", records[0].namespace);
+        assert_eq!("", records[0].metric_stream_name);
+        assert_eq!(2, records[0].dimensions.len());
+        assert_eq!(17.0, records[0].value.sum);
+    }
+
+    #[test]
+    fn test_invalid_records() {
+        assert!(parse_records(b"").unwrap().is_empty());
+        assert!(parse_records(b" \n").unwrap().is_empty());
+
+        let error = parse_records(b"\x0a\x8f\x01\x12").unwrap_err();
+        assert!(error.to_string().contains("output format to JSON"));
+
+        let first = FIXTURE.lines().next().unwrap();
+        let error =
+            parse_records(format!("{}{{\"namespace\": \"AWS/EC2\"", first).as_bytes()).unwrap_err();
+        assert_eq!("Metric record 1 is not valid JSON", error.to_string());
+
+        let error = parse_records(format!("{}\n{{\"namespace\": \"AWS/EC2\"}}", first).as_bytes())
+            .unwrap_err();
+        assert_eq!(
+            "Metric record 1 is not a metric stream record",
+            error.to_string()
+        );
+    }
+
+    #[test]
+    fn test_row() {
+        let records = parse_records(FIXTURE.as_bytes()).unwrap();
+
+        assert_eq!(
+            json!({
+                "metric_stream_name": "metrics-to-delta",
+                "account_id": "123456789012",
+                "region": "us-east-1",
+                "namespace": "AWS/ApplicationELB",
+                "metric_name": "TargetResponseTime",
+                "dimensions": {
+                    "LoadBalancer": "app/orders-alb/50dc6c495c0c9188",
+                    "TargetGroup": "targetgroup/orders/73e2d6bc24d8a067"
+                },
+                "timestamp": 1_718_020_800_000_000i64,
+                "unit": "Seconds",
+                "min": 0.004,
+                "max": 0.538,
+                "sum": 41.27,
+                "count": 1184.0,
+                "statistics": {"p99": 0.412, "TM(10%:90%)": 0.087}
+            }),
+            Value::Object(records[2].row())
+        );
+
+        // A unit of None is no unit
+        let row = records[4].row();
+        assert_eq!("CartValue", records[4].metric_name);
+        assert!(!row.contains_key("unit"));
+    }
+}
@@ -0,0 +1,461 @@
+//! The Firehose HTTP endpoint
+
+use anyhow::{Context, Result};
+use axum::body::Bytes;
+use axum::extract::{DefaultBodyLimit, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde_json::Value;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
+use zerobus_common::dynamic::DynamicEncoder;
+use zerobus_common::pipeline::{IngestSink, Pipeline};
+
+use crate::firehose::{self, DeliveryRequest, ACCESS_KEY_HEADER, REQUEST_ID_HEADER};
+use crate::metric;
+
+/// Path the Firehose stream's HTTP endpoint destination points at
+pub const FIREHOSE_PATH: &str = "/firehose";
+
+/// Encodes the metric records Firehose delivers and ingests them into the metrics table
+///
+/// Requests take turns on the one stream, so each response reports exactly whether its
+/// rows are durable.
+pub struct Receiver<S: IngestSink> {
+    encoder: DynamicEncoder,
+    pipeline: Mutex<Pipeline<S>>,
+    /// Access key the Firehose destination is configured with; every request is
+    /// accepted when unset
+    access_key: Option<String>,
+}
+
+impl<S: IngestSink> Receiver<S> {
+    pub fn new(encoder: DynamicEncoder, pipeline: Pipeline<S>, access_key: Option<String>) -> Self {
+        Self {
+            encoder,
+            pipeline: Mutex::new(pipeline),
+            access_key,
+        }
+    }
+
+    /// Take the pipeline back once the server has stopped, so it can be finished
+    pub fn into_pipeline(self) -> Pipeline<S> {
+        self.pipeline.into_inner()
+    }
+
+    /// The rows of every metric record in the request, with the request ID for a
+    /// table with a `firehose_request_id` column
+    fn rows(&self, request: &DeliveryRequest) -> Result<Vec<Vec<u8>>> {
+        let mut rows = Vec::new();
+        for (index, data) in request.data()?.iter().enumerate() {
+            let records =
+                metric::parse_records(data).with_context(|| format!("Record {}", index))?;
+            for record in records {
+                let mut row = record.row();
+                if self.encoder.has_field("firehose_request_id") {
+                    row.insert(
+                        "firehose_request_id".into(),
+                        request.request_id.clone().into(),
+                    );
+                }
+                let encoded = self.encoder.encode(&Value::Object(row)).with_context(|| {
+                    format!(
+                        "Record {}: {} {}",
+                        index, record.namespace, record.metric_name
+                    )
+                })?;
+                rows.push(encoded);
+            }
+        }
+        Ok(rows)
+    }
+
+    async fn ingest(&self, records: Vec<Vec<u8>>) -> Result<()> {
+        let mut pipeline = self.pipeline.lock().await;
+        pipeline.ingest_batch(records).await
+    }
+}
+
+/// The Firehose endpoint, plus a health check
+pub fn router<S: IngestSink + 'static>(receiver: Arc<Receiver<S>>) -> Router {
+    Router::new()
+        .route(FIREHOSE_PATH, post(deliver::<S>))
+        .route("/health", get(|| async { "OK" }))
+        .layer(DefaultBodyLimit::max(firehose::MAX_REQUEST_BYTES))
+        .with_state(receiver)
+}
+
+/// Handle one Firehose delivery request
+///
+/// Firehose retries a request until it gets a 200, and once its retry duration runs
+/// out writes the records to its S3 backup bucket. So the response is 200 only once
+/// every row is durable: 401 when the access key does not match, 400 when a record is
+/// not a metric the table can hold, and 500 when rows are not acknowledged. Nothing
+/// from a rejected request is ingested, so a retry does not duplicate rows.
+async fn deliver<S: IngestSink>(
+    State(receiver): State<Arc<Receiver<S>>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> (StatusCode, Json<Value>) {
+    let header_request_id = headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+
+    if let Some(access_key) = &receiver.access_key {
+        let given = headers
+            .get(ACCESS_KEY_HEADER)
+            .and_then(|value| value.to_str().ok());
+        if !firehose::access_key_matches(access_key, given) {
+            warn!("Rejecting request {}: wrong access key", header_request_id);
+            return reply(
+                StatusCode::UNAUTHORIZED,
+                &header_request_id,
+                Some("Access key does not match"),
+            );
+        }
+    }
+
+    let gzipped = headers
+        .get(header::CONTENT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|encoding| encoding.eq_ignore_ascii_case("gzip"));
+    let request = match DeliveryRequest::parse(&body, gzipped) {
+        Ok(request) => request,
+        Err(e) => {
+            warn!("Rejecting request {}: {:#}", header_request_id, e);
+            return reply(
+                StatusCode::BAD_REQUEST,
+                &header_request_id,
+                Some(&format!("{:#}", e)),
+            );
+        }
+    };
+    let request_id = &request.request_id;
+    let rows = match receiver.rows(&request) {
+        Ok(rows) => rows,
+        Err(e) => {
+            warn!("Rejecting request {}: {:#}", request_id, e);
+            return reply(
+                StatusCode::BAD_REQUEST,
+                request_id,
+                Some(&format!("{:#}", e)),
+            );
+        }
+    };
+
+    let ingested = rows.len();
+    match receiver.ingest(rows).await {
+        Ok(()) => {
+            info!(
+                "Ingested {} metrics from {} records of request {}",
+                ingested,
+                request.records.len(),
+                request_id
+            );
+            reply(StatusCode::OK, request_id, None)
+        }
+        Err(e) => {
+            error!("Failed to ingest request {}: {:#}", request_id, e);
+            reply(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                request_id,
+                Some(&format!("{:#}", e)),
+            )
+        }
+    }
+}
+
+fn reply(status: StatusCode, request_id: &str, error: Option<&str>) -> (StatusCode, Json<Value>) {
+    let now_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as i64);
+    (status, Json(firehose::response(request_id, now_ms, error)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::{to_bytes, Body};
+    use axum::http::Request;
+    use base64::{engine::general_purpose, Engine as _};
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use prost::Message;
+    use prost_types::field_descriptor_proto::{Label, Type};
+    use prost_types::{DescriptorProto, FieldDescriptorProto, MessageOptions};
+    use serde_json::json;
+    use std::collections::HashMap;
+    use std::io::Write;
+    use tower::ServiceExt;
+    use zerobus_common::testing::MockSink;
+
+    const REQUEST: &str = include_str!("../testdata/firehose-request.json");
+    const ACCESS_KEY: &str = "firehose-access-key";
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct MetricRow {
+        #[prost(string, optional, tag = "1")]
+        metric_stream_name: Option<String>,
+        #[prost(string, optional, tag = "2")]
+        account_id: Option<String>,
+        #[prost(string, optional, tag = "3")]
+        region: Option<String>,
+        #[prost(string, optional, tag = "4")]
+        namespace: Option<String>,
+        #[prost(string, optional, tag = "5")]
+        metric_name: Option<String>,
+        #[prost(map = "string, string", tag = "6")]
+        dimensions: HashMap<String, String>,
+        #[prost(int64, optional, tag = "7")]
+        timestamp: Option<i64>,
+        #[prost(string, optional, tag = "8")]
+        unit: Option<String>,
+        #[prost(double, optional, tag = "9")]
+        min: Option<f64>,
+        #[prost(double, optional, tag = "10")]
+        max: Option<f64>,
+        #[prost(double, optional, tag = "11")]
+        sum: Option<f64>,
+        #[prost(double, optional, tag = "12")]
+        count: Option<f64>,
+        #[prost(map = "string, double", tag = "13")]
+        statistics: HashMap<String, f64>,
+        #[prost(string, optional, tag = "14")]
+        firehose_request_id: Option<String>,
+    }
+
+    fn receiver(sink: MockSink, access_key: Option<&str>) -> Arc<Receiver<MockSink>> {
+        let field = |name: &str, number: i32, r#type: Type| FieldDescriptorProto {
+            name: Some(name.to_string()),
+            number: Some(number),
+            label: Some(Label::Optional as i32),
+            r#type: Some(r#type as i32),
+            ..Default::default()
+        };
+        let map = |name: &str, number: i32, entry: &str| FieldDescriptorProto {
+            label: Some(Label::Repeated as i32),
+            type_name: Some(format!(".table_metrics.{}", entry)),
+            ..field(name, number, Type::Message)
+        };
+        let entry = |name: &str, value: Type| DescriptorProto {
+            name: Some(name.to_string()),
+            field: vec![field("key", 1, Type::String), field("value", 2, value)],
+            options: Some(MessageOptions {
+                map_entry: Some(true),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let descriptor = DescriptorProto {
+            name: Some("table_metrics".to_string()),
+            field: vec![
+                field("metric_stream_name", 1, Type::String),
+                field("account_id", 2, Type::String),
+                field("region", 3, Type::String),
+                field("namespace", 4, Type::String),
+                field("metric_name", 5, Type::String),
+                map("dimensions", 6, "DimensionsEntry"),
+                field("timestamp", 7, Type::Int64),
+                field("unit", 8, Type::String),
+                field("min", 9, Type::Double),
+                field("max", 10, Type::Double),
+                field("sum", 11, Type::Double),
+                field("count", 12, Type::Double),
+                map("statistics", 13, "StatisticsEntry"),
+                field("firehose_request_id", 14, Type::String),
+            ],
+            nested_type: vec![
+                entry("DimensionsEntry", Type::String),
+                entry("StatisticsEntry", Type::Double),
+            ],
+            ..Default::default()
+        };
+        let encoder = DynamicEncoder::new(&descriptor).unwrap();
+        Arc::new(Receiver::new(
+            encoder,
+            Pipeline::new(sink, 100),
+            access_key.map(str::to_string),
+        ))
+    }
+
+    /// A request whose records hold `data`
+    fn request(data: &[&str]) -> String {
+        let records: Vec<Value> = data
+            .iter()
+            .map(|data| json!({ "data": general_purpose::STANDARD.encode(data) }))
+            .collect();
+        json!({"requestId": "r-1", "timestamp": 1_718_020_865_123i64, "records": records})
+            .to_string()
+    }
+
+    async fn post(
+        receiver: Arc<Receiver<MockSink>>,
+        headers: &[(&str, &str)],
+        body: impl Into<Body>,
+    ) -> (StatusCode, Value) {
+        let mut request = Request::post(FIREHOSE_PATH)
+            .header("Content-Type", "application/json")
+            .header(REQUEST_ID_HEADER, "ed4acda5-034f-9f42-bba1-f29aea6d7d8f");
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let response = router(receiver)
+            .oneshot(request.body(body.into()).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    fn rows(sink: &MockSink) -> Vec<MetricRow> {
+        sink.records()
+            .iter()
+            .map(|record| MetricRow::decode(record.as_slice()).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_fixture_request_is_ingested() {
+        let sink = MockSink::default();
+        let (status, body) = post(
+            receiver(sink.clone(), Some(ACCESS_KEY)),
+            &[(ACCESS_KEY_HEADER, ACCESS_KEY)],
+            REQUEST,
+        )
+        .await;
+
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!("ed4acda5-034f-9f42-bba1-f29aea6d7d8f", body["requestId"]);
+        assert!(body["timestamp"].as_i64().unwrap() > 0);
+        assert!(body.get("errorMessage").is_none());
+
+        // Three metrics concatenated in the first record, two on lines of the second
+        let rows = rows(&sink);
+        let names: Vec<&str> = rows
+            .iter()
+            .map(|row| row.metric_name.as_deref().unwrap())
+            .collect();
+        assert_eq!(
+            vec![
+                "CPUUtilization",
+                "NetworkIn",
+                "TargetResponseTime",
+                "ApproximateNumberOfMessagesVisible",
+                "CartValue"
+            ],
+            names
+        );
+        let latency = &rows[2];
+        assert_eq!(Some("AWS/ApplicationELB"), latency.namespace.as_deref());
+        assert_eq!(
+            Some("targetgroup/orders/73e2d6bc24d8a067"),
+            latency.dimensions.get("TargetGroup").map(String::as_str)
+        );
+        assert_eq!(Some(1_718_020_800_000_000), latency.timestamp);
+        assert_eq!(Some("Seconds"), latency.unit.as_deref());
+        assert_eq!(Some(1184.0), latency.count);
+        assert_eq!(Some(&0.412), latency.statistics.get("p99"));
+        assert_eq!(
+            Some("ed4acda5-034f-9f42-bba1-f29aea6d7d8f"),
+            latency.firehose_request_id.as_deref()
+        );
+
+        // The custom namespace, with no unit
+        assert_eq!(Some("Checkout/Orders"), rows[4].namespace.as_deref());
+        assert_eq!(None, rows[4].unit);
+    }
+
+    #[tokio::test]
+    async fn test_gzipped_request() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(REQUEST.as_bytes()).unwrap();
+        let sink = MockSink::default();
+
+        let (status, _) = post(
+            receiver(sink.clone(), None),
+            &[("Content-Encoding", "gzip")],
+            encoder.finish().unwrap(),
+        )
+        .await;
+
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(5, rows(&sink).len());
+    }
+
+    #[tokio::test]
+    async fn test_wrong_access_key_is_rejected() {
+        let sink = MockSink::default();
+        for headers in [vec![], vec![(ACCESS_KEY_HEADER, "guess")]] {
+            let (status, body) =
+                post(receiver(sink.clone(), Some(ACCESS_KEY)), &headers, REQUEST).await;
+            assert_eq!(StatusCode::UNAUTHORIZED, status);
+            assert_eq!("ed4acda5-034f-9f42-bba1-f29aea6d7d8f", body["requestId"]);
+            assert_eq!("Access key does not match", body["errorMessage"]);
+        }
+        assert!(sink.records().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unknown_namespaces_are_ingested_like_any_other() {
+        let sink = MockSink::default();
+        let (status, _) = post(
+            receiver(sink.clone(), None),
+            &[],
+            request(&[
+                r#"{"metric_stream_name":"metrics-to-delta","account_id":"123456789012","region":"eu-central-1","namespace":"Checkout/Orders","metric_name":"OrdersPlaced","dimensions":{"Store":"berlin-1","Channel":"app"},"timestamp":1718020860000,"value":{"max":4,"min":0,"sum":17,"count":12,"p50":1},"unit":"Count"}"#,
+            ]),
+        )
+        .await;
+
+        assert_eq!(StatusCode::OK, status);
+        let rows = rows(&sink);
+        assert_eq!(1, rows.len());
+        assert_eq!(Some("Checkout/Orders"), rows[0].namespace.as_deref());
+        assert_eq!(2, rows[0].dimensions.len());
+        assert_eq!(Some(&1.0), rows[0].statistics.get("p50"));
+    }
+
+    #[tokio::test]
+    async fn test_bad_records_reject_the_whole_request() {
+        let sink = MockSink::default();
+        let first = include_str!("../testdata/metric-stream.jsonl")
+            .lines()
+            .next()
+            .unwrap();
+        for (body, error) in [
+            (
+                request(&[first, "\u{a}\u{8f}otel"]),
+                "Record 1: Record data is not JSON",
+            ),
+            (
+                request(&[first, r#"{"namespace":"AWS/EC2"}"#]),
+                "Record 1: Metric record 0 is not a metric stream record",
+            ),
+            (
+                "not json".to_string(),
+                "Body is not a Firehose delivery request",
+            ),
+        ] {
+            let (status, response) = post(receiver(sink.clone(), None), &[], body).await;
+            assert_eq!(StatusCode::BAD_REQUEST, status);
+            let message = response["errorMessage"].as_str().unwrap();
+            assert!(message.starts_with(error), "{}", message);
+        }
+        assert!(sink.records().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unacknowledged_rows_answer_500() {
+        let sink = MockSink::default().fail_acks_for(|_| true);
+        let (status, body) = post(receiver(sink, None), &[], REQUEST).await;
+
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, status);
+        assert!(body["errorMessage"].is_string());
+    }
+}
@@ -0,0 +1,24 @@
+use std::process::Command;
+
+fn main() {
+    // Allow CI to inject the commit when the build runs outside a git checkout
+    println!("cargo:rerun-if-env-changed=ZEROBUS_GIT_SHA");
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/refs/heads");
+
+    let sha = std::env::var("ZEROBUS_GIT_SHA")
+        .ok()
+        .filter(|sha| !sha.is_empty())
+        .or_else(|| {
+            Command::new("git")
+                .args(["rev-parse", "--short=12", "HEAD"])
+                .output()
+                .ok()
+                .filter(|output| output.status.success())
+                .and_then(|output| String::from_utf8(output.stdout).ok())
+                .map(|sha| sha.trim().to_string())
+        })
+        .unwrap_or_else(|| "unknown".to_string());
+
+    println!("cargo:rustc-env=ZEROBUS_GIT_SHA={}", sha);
+}
@@ -0,0 +1,168 @@
+//! One summary row per processed batch, written to a separate audit table.
+//!
+//! Per-record columns tell you where a row came from; the audit row tells you what a
+//! whole batch did: how many records arrived, how many landed, which source and schema
+//! they belonged to, and when. The audit message is defined here rather than generated
+//! with buf so every example writes the same shape to a shared audit table.
+
+use anyhow::Result;
+use prost::Message;
+use prost_types::field_descriptor_proto::{Label, Type};
+use prost_types::{DescriptorProto, FieldDescriptorProto};
+
+use crate::pipeline::IngestSink;
+
+/// Name of the audit message in its descriptor
+pub const AUDIT_MESSAGE_NAME: &str = "table_batch_audit";
+
+/// Summary of one processed batch
+#[derive(Clone, PartialEq, Message)]
+pub struct BatchAudit {
+    /// Identifier of the batch, e.g. the Lambda request ID
+    #[prost(string, optional, tag = "1")]
+    pub batch_id: Option<String>,
+    /// Example that processed the batch, e.g. `aws-lambda-sqs-ingestor`
+    #[prost(string, optional, tag = "2")]
+    pub source: Option<String>,
+    /// ARN (or other identifier) of the resource the batch was read from
+    #[prost(string, optional, tag = "3")]
+    pub source_arn: Option<String>,
+    /// Table the batch's records were ingested into
+    #[prost(string, optional, tag = "4")]
+    pub target_table: Option<String>,
+    /// Earliest source timestamp in the batch, microseconds since Unix epoch
+    #[prost(int64, optional, tag = "5")]
+    pub window_start: Option<i64>,
+    /// Latest source timestamp in the batch, microseconds since Unix epoch
+    #[prost(int64, optional, tag = "6")]
+    pub window_end: Option<i64>,
+    /// When processing of the batch started, microseconds since Unix epoch
+    #[prost(int64, optional, tag = "7")]
+    pub started_at: Option<i64>,
+    /// When processing of the batch finished, microseconds since Unix epoch
+    #[prost(int64, optional, tag = "8")]
+    pub finished_at: Option<i64>,
+    #[prost(int64, optional, tag = "9")]
+    pub records_received: Option<i64>,
+    #[prost(int64, optional, tag = "10")]
+    pub records_ingested: Option<i64>,
+    #[prost(int64, optional, tag = "11")]
+    pub records_failed: Option<i64>,
+    /// [`crate::descriptor::schema_hash`] of the target table's descriptor
+    #[prost(string, optional, tag = "12")]
+    pub schema_hash: Option<String>,
+    /// [`crate::version::PIPELINE_VERSION`] of the binary that processed the batch
+    #[prost(string, optional, tag = "13")]
+    pub pipeline_version: Option<String>,
+    #[prost(int64, optional, tag = "14")]
+    pub ingested_at: Option<i64>,
+    #[prost(int32, optional, tag = "15")]
+    pub ingested_date: Option<i32>,
+}
+
+/// Columns of the audit table, in tag order; must match [`BatchAudit`]
+const AUDIT_FIELDS: &[(&str, Type)] = &[
+    ("batch_id", Type::String),
+    ("source", Type::String),
+    ("source_arn", Type::String),
+    ("target_table", Type::String),
+    ("window_start", Type::Int64),
+    ("window_end", Type::Int64),
+    ("started_at", Type::Int64),
+    ("finished_at", Type::Int64),
+    ("records_received", Type::Int64),
+    ("records_ingested", Type::Int64),
+    ("records_failed", Type::Int64),
+    ("schema_hash", Type::String),
+    ("pipeline_version", Type::String),
+    ("ingested_at", Type::Int64),
+    ("ingested_date", Type::Int32),
+];
+
+/// Descriptor for creating a stream to the audit table
+pub fn audit_descriptor() -> DescriptorProto {
+    descriptor(AUDIT_MESSAGE_NAME, AUDIT_FIELDS)
+}
+
+/// Descriptor of a message with optional `fields` numbered from 1, in order
+pub(crate) fn descriptor(name: &str, fields: &[(&str, Type)]) -> DescriptorProto {
+    DescriptorProto {
+        name: Some(name.to_string()),
+        field: fields
+            .iter()
+            .enumerate()
+            .map(|(i, (name, field_type))| FieldDescriptorProto {
+                name: Some(name.to_string()),
+                number: Some(i as i32 + 1),
+                label: Some(Label::Optional as i32),
+                r#type: Some(*field_type as i32),
+                ..Default::default()
+            })
+            .collect(),
+        ..Default::default()
+    }
+}
+
+/// Ingest one audit row and wait until it is acknowledged
+pub async fn write_audit<S: IngestSink>(sink: &mut S, audit: &BatchAudit) -> Result<()> {
+    let ack = sink.ingest(audit.encode_to_vec()).await?;
+    sink.flush().await?;
+    ack.await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockSink;
+
+    #[test]
+    fn test_descriptor_matches_message() {
+        let descriptor = audit_descriptor();
+        assert_eq!(Some(AUDIT_MESSAGE_NAME.to_string()), descriptor.name);
+        assert_eq!(15, descriptor.field.len());
+
+        // Fields must encode under the numbers the descriptor gives their columns
+        let audit = BatchAudit {
+            records_failed: Some(3),
+            ingested_date: Some(7),
+            ..Default::default()
+        };
+        let records_failed = descriptor
+            .field
+            .iter()
+            .find(|f| f.name.as_deref() == Some("records_failed"))
+            .unwrap();
+        let ingested_date = descriptor
+            .field
+            .iter()
+            .find(|f| f.name.as_deref() == Some("ingested_date"))
+            .unwrap();
+        let tag = |number: i32| (number as u8) << 3;
+        assert_eq!(
+            vec![
+                tag(records_failed.number.unwrap()),
+                3,
+                tag(ingested_date.number.unwrap()),
+                7
+            ],
+            audit.encode_to_vec()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_write_audit() {
+        let mut sink = MockSink::default();
+        let audit = BatchAudit {
+            batch_id: Some("req-1".to_string()),
+            records_received: Some(10),
+            ..Default::default()
+        };
+
+        write_audit(&mut sink, &audit).await.unwrap();
+
+        let records = sink.records();
+        assert_eq!(1, records.len());
+        assert_eq!(audit, BatchAudit::decode(records[0].as_slice()).unwrap());
+        assert_eq!(1, sink.flushes());
+    }
+}
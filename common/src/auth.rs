@@ -0,0 +1,333 @@
+//! How streams authenticate, selected by `AUTH_METHOD`
+//!
+//! `oauth` (the default) hands the service principal's client ID and secret to the SDK,
+//! which fetches and refreshes tokens itself. `token_file` reads a token that something
+//! else keeps fresh, such as a sidecar, from `TOKEN_FILE_PATH`, so the process never
+//! holds a client secret.
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use databricks_zerobus_ingest_sdk::{
+    HeadersProvider, StreamConfigurationOptions, TableProperties, ZerobusError, ZerobusResult,
+    ZerobusSdk, ZerobusStream,
+};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+use crate::clock::{Clock, SystemClock};
+use crate::credentials::{CredentialProvider, Credentials, EnvCredentials};
+
+/// A token expiring within this long is read again, in case it has been refreshed
+const EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+
+/// What streams are created with
+#[derive(Debug, Clone)]
+pub enum StreamAuth {
+    /// Service principal credentials, exchanged for tokens by the SDK
+    OAuth(Credentials),
+    /// A token read from a file before each use
+    TokenFile(Arc<TokenFile>),
+}
+
+impl StreamAuth {
+    /// Read the method from `AUTH_METHOD`: `oauth` (the default), with credentials from
+    /// `DATABRICKS_CLIENT_ID` and `DATABRICKS_CLIENT_SECRET`, or `token_file`, with the
+    /// token in `TOKEN_FILE_PATH`
+    pub async fn from_env() -> Result<Self> {
+        let method = std::env::var("AUTH_METHOD").unwrap_or_default();
+        match method.trim().to_ascii_lowercase().as_str() {
+            "" | "oauth" => Ok(StreamAuth::OAuth(
+                EnvCredentials::default().credentials().await?,
+            )),
+            "token_file" => {
+                let path = std::env::var("TOKEN_FILE_PATH").context(
+                    "TOKEN_FILE_PATH environment variable must be set with AUTH_METHOD=token_file",
+                )?;
+                let file = TokenFile::new(path);
+                // Fail at startup rather than on the first stream
+                file.token()?;
+                info!("Reading tokens from {}", file.path().display());
+                Ok(StreamAuth::TokenFile(Arc::new(file)))
+            }
+            _ => bail!("AUTH_METHOD must be oauth or token_file, got {:?}", method),
+        }
+    }
+
+    pub async fn create_stream(
+        &self,
+        sdk: &ZerobusSdk,
+        table_properties: TableProperties,
+        options: Option<StreamConfigurationOptions>,
+    ) -> Result<ZerobusStream> {
+        let stream = match self {
+            StreamAuth::OAuth(credentials) => {
+                sdk.create_stream(
+                    table_properties,
+                    credentials.client_id.clone(),
+                    credentials.client_secret.clone(),
+                    options,
+                )
+                .await?
+            }
+            StreamAuth::TokenFile(file) => {
+                let headers = TokenFileHeaders {
+                    file: Arc::clone(file),
+                    table_name: table_properties.table_name.clone(),
+                };
+                sdk.create_stream_with_headers_provider(
+                    table_properties,
+                    Arc::new(headers),
+                    options,
+                )
+                .await?
+            }
+        };
+        Ok(stream)
+    }
+}
+
+/// A token kept up to date in a file by another process
+///
+/// The file is read again when its modification time changes, or when the token it
+/// held is a JWT that is about to expire. If the file cannot be read while a rewrite is
+/// in progress, the last token is used for as long as it is valid.
+#[derive(Debug)]
+pub struct TokenFile {
+    path: PathBuf,
+    cached: Mutex<Option<CachedToken>>,
+}
+
+#[derive(Debug, Clone)]
+struct CachedToken {
+    token: String,
+    modified: Option<SystemTime>,
+    expires: Option<SystemTime>,
+}
+
+impl CachedToken {
+    fn expiring(&self, now: SystemTime) -> bool {
+        self.expiring_by(now + EXPIRY_MARGIN)
+    }
+
+    fn expiring_by(&self, time: SystemTime) -> bool {
+        self.expires.is_some_and(|expires| expires <= time)
+    }
+}
+
+impl TokenFile {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            cached: Mutex::new(None),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The current token
+    pub fn token(&self) -> Result<String> {
+        self.token_with_clock(&SystemClock)
+    }
+
+    /// The current token, judging whether the cached one expires by the time of `clock`
+    pub fn token_with_clock(&self, clock: &dyn Clock) -> Result<String> {
+        let mut cached = self.cached.lock().unwrap();
+        let now = clock.now();
+        let modified = std::fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        if let Some(token) = cached.as_ref() {
+            if modified.is_some() && token.modified == modified && !token.expiring(now) {
+                return Ok(token.token.clone());
+            }
+        }
+
+        match self.read() {
+            Ok(token) => {
+                let expires = jwt_expiry(&token);
+                if expires.is_some_and(|expires| expires <= now) {
+                    warn!("The token in {} has expired", self.path.display());
+                }
+                *cached = Some(CachedToken {
+                    token: token.clone(),
+                    modified,
+                    expires,
+                });
+                Ok(token)
+            }
+            Err(e) => match cached.as_ref() {
+                Some(token) if !token.expiring_by(now) => {
+                    warn!("Using the last token read: {:#}", e);
+                    Ok(token.token.clone())
+                }
+                _ => Err(e),
+            },
+        }
+    }
+
+    fn read(&self) -> Result<String> {
+        let contents = std::fs::read_to_string(&self.path)
+            .with_context(|| format!("Failed to read token file {}", self.path.display()))?;
+        let token = contents.trim();
+        if token.is_empty() {
+            bail!("Token file {} is empty", self.path.display());
+        }
+        Ok(token.to_string())
+    }
+}
+
+/// Headers of a stream authenticated with the token in a file
+struct TokenFileHeaders {
+    file: Arc<TokenFile>,
+    table_name: String,
+}
+
+#[async_trait]
+impl HeadersProvider for TokenFileHeaders {
+    async fn get_headers(&self) -> ZerobusResult<HashMap<&'static str, String>> {
+        let token = self
+            .file
+            .token()
+            .map_err(|e| ZerobusError::InvalidArgument(format!("{:#}", e)))?;
+        Ok(HashMap::from([
+            ("authorization", format!("Bearer {}", token)),
+            ("x-databricks-zerobus-table-name", self.table_name.clone()),
+        ]))
+    }
+}
+
+/// When a JWT's `exp` claim says it expires; `None` for tokens that are not JWTs
+fn jwt_expiry(token: &str) -> Option<SystemTime> {
+    let payload = token.split('.').nth(1)?;
+    let claims: serde_json::Value =
+        serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).ok()?).ok()?;
+    let exp = claims.get("exp")?.as_u64()?;
+    Some(UNIX_EPOCH + Duration::from_secs(exp))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::FixedClock;
+
+    fn jwt(exp: SystemTime) -> String {
+        let exp = exp.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let claims = serde_json::json!({"sub": "sidecar", "exp": exp}).to_string();
+        format!(
+            "{}.{}.signature",
+            URL_SAFE_NO_PAD.encode(r#"{"alg":"RS256"}"#),
+            URL_SAFE_NO_PAD.encode(claims)
+        )
+    }
+
+    /// Write `token` and set the file's modification time, as a sidecar's refresh would
+    fn write(path: &Path, token: &str, modified: SystemTime) {
+        std::fs::write(path, format!("{}\n", token)).unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_token_is_read_again_after_update() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("token");
+        let start = SystemTime::now() - Duration::from_secs(60);
+        write(&path, "dapi-first", start);
+
+        let headers = TokenFileHeaders {
+            file: Arc::new(TokenFile::new(&path)),
+            table_name: "main.default.events".to_string(),
+        };
+        let sent = headers.get_headers().await.unwrap();
+        assert_eq!("Bearer dapi-first", sent["authorization"]);
+        assert_eq!(
+            "main.default.events",
+            sent["x-databricks-zerobus-table-name"]
+        );
+
+        write(&path, "dapi-second", start + Duration::from_secs(30));
+        let sent = headers.get_headers().await.unwrap();
+        assert_eq!("Bearer dapi-second", sent["authorization"]);
+
+        // While the file is being replaced the last token is still used
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!("dapi-second", headers.file.token().unwrap());
+    }
+
+    #[test]
+    fn test_expiring_token_is_read_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("token");
+        let modified = SystemTime::now() - Duration::from_secs(60);
+        let now = SystemTime::now();
+
+        let expiring = jwt(now + Duration::from_secs(10));
+        write(&path, &expiring, modified);
+        let file = TokenFile::new(&path);
+        assert_eq!(expiring, file.token().unwrap());
+
+        // Rewritten without the modification time changing: the expiring token is
+        // enough to read the file again
+        let fresh = jwt(now + Duration::from_secs(3600));
+        write(&path, &fresh, modified);
+        assert_eq!(fresh, file.token().unwrap());
+        assert_eq!(fresh, file.token().unwrap());
+
+        // An expired token is not used in place of a file that cannot be read
+        write(&path, &jwt(now - Duration::from_secs(1)), now);
+        file.token().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(file.token().is_err());
+    }
+
+    #[test]
+    fn test_token_expiry_is_judged_by_the_clock() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("token");
+        let issued = UNIX_EPOCH + Duration::from_secs(1_718_020_860);
+        let expires = issued + Duration::from_secs(3600);
+
+        let first = jwt(expires);
+        write(&path, &first, issued);
+        let file = TokenFile::new(&path);
+        assert_eq!(first, file.token_with_clock(&FixedClock(issued)).unwrap());
+
+        // Rewritten without the modification time changing: the cached token is kept
+        // until it is about to expire
+        let second = jwt(expires + Duration::from_secs(3600));
+        write(&path, &second, issued);
+        let before_margin = FixedClock(expires - EXPIRY_MARGIN - Duration::from_secs(1));
+        assert_eq!(first, file.token_with_clock(&before_margin).unwrap());
+        let within_margin = FixedClock(expires - EXPIRY_MARGIN);
+        assert_eq!(second, file.token_with_clock(&within_margin).unwrap());
+
+        // The last token stands in for a file that cannot be read only until it expires
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(second, file.token_with_clock(&FixedClock(expires)).unwrap());
+        let expired = FixedClock(expires + Duration::from_secs(3600));
+        assert!(file.token_with_clock(&expired).is_err());
+    }
+
+    #[test]
+    fn test_missing_or_empty_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("token");
+        let file = TokenFile::new(&path);
+        assert!(file.token().is_err());
+
+        std::fs::write(&path, "\n").unwrap();
+        let error = file.token().unwrap_err();
+        assert!(error.to_string().contains("is empty"), "{}", error);
+    }
+}
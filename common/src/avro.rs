@@ -0,0 +1,474 @@
+//! Avro records as JSON objects, from container files or Schema Registry payloads.
+//!
+//! Like the other generic readers, Avro is converted to the JSON objects a
+//! [`DynamicEncoder`](crate::dynamic::DynamicEncoder) encodes by field name. Logical types
+//! are normalized to the representations the tables use: timestamps become
+//! microseconds since the Unix epoch, dates become days since the epoch, and decimals
+//! become doubles.
+//!
+//! Kafka producers using Confluent serializers prefix every payload with a magic byte
+//! and the ID of the writer schema; [`ConfluentDecoder`] resolves those IDs against a
+//! Schema Registry and caches the schemas.
+
+use anyhow::{anyhow, bail, Context, Result};
+use apache_avro::schema::{Name, ResolvedSchema, Schema};
+use apache_avro::types::Value as AvroValue;
+use apache_avro::Decimal;
+use base64::{engine::general_purpose, Engine as _};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+/// Converts the values of one writer schema to JSON
+#[derive(Debug, Clone)]
+pub struct AvroConverter {
+    schema: Schema,
+    /// Named types the schema defines, so references to them can be followed
+    names: HashMap<Name, Schema>,
+}
+
+impl AvroConverter {
+    pub fn new(schema: &Schema) -> Result<Self> {
+        let resolved = ResolvedSchema::try_from(schema).context("Invalid Avro schema")?;
+        let names = resolved
+            .get_names()
+            .iter()
+            .map(|(name, schema)| (name.clone(), (*schema).clone()))
+            .collect();
+        Ok(Self {
+            schema: schema.clone(),
+            names,
+        })
+    }
+
+    pub fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    /// Convert a value written with this converter's schema
+    pub fn convert(&self, value: &AvroValue) -> Result<Value> {
+        self.convert_with(value, &self.schema)
+    }
+
+    fn convert_with(&self, value: &AvroValue, schema: &Schema) -> Result<Value> {
+        let schema = match schema {
+            Schema::Ref { name } => self
+                .names
+                .get(name)
+                .ok_or_else(|| anyhow!("Unknown Avro type {}", name))?,
+            schema => schema,
+        };
+
+        Ok(match (value, schema) {
+            (AvroValue::Null, _) => Value::Null,
+            (AvroValue::Union(index, inner), Schema::Union(union)) => {
+                let variant = union
+                    .variants()
+                    .get(*index as usize)
+                    .ok_or_else(|| anyhow!("Union branch {} is out of range", index))?;
+                self.convert_with(inner, variant)?
+            }
+            (AvroValue::Record(fields), Schema::Record(record)) => {
+                let mut object = Map::new();
+                for ((name, value), field) in fields.iter().zip(&record.fields) {
+                    let value = self
+                        .convert_with(value, &field.schema)
+                        .with_context(|| format!("Field {:?}", name))?;
+                    object.insert(name.clone(), value);
+                }
+                Value::Object(object)
+            }
+            (AvroValue::Array(items), Schema::Array(array)) => Value::Array(
+                items
+                    .iter()
+                    .map(|item| self.convert_with(item, &array.items))
+                    .collect::<Result<_>>()?,
+            ),
+            (AvroValue::Map(entries), Schema::Map(map)) => {
+                let mut object = Map::new();
+                for (key, value) in entries {
+                    object.insert(key.clone(), self.convert_with(value, &map.types)?);
+                }
+                Value::Object(object)
+            }
+            (AvroValue::Decimal(decimal), Schema::Decimal(decimal_schema)) => {
+                decimal_to_json(decimal, decimal_schema.scale)?
+            }
+            // Without a matching schema branch, fall back to what the value says about itself
+            (value, _) => scalar_to_json(value)?,
+        })
+    }
+}
+
+/// Convert a value that does not need its schema
+fn scalar_to_json(value: &AvroValue) -> Result<Value> {
+    Ok(match value {
+        AvroValue::Null => Value::Null,
+        AvroValue::Boolean(b) => Value::Bool(*b),
+        AvroValue::Int(v) => json!(v),
+        AvroValue::Long(v) => json!(v),
+        AvroValue::Float(v) => float(f64::from(*v)),
+        AvroValue::Double(v) => float(*v),
+        AvroValue::Bytes(bytes) | AvroValue::Fixed(_, bytes) => {
+            Value::String(general_purpose::STANDARD.encode(bytes))
+        }
+        AvroValue::String(s) => Value::String(s.clone()),
+        AvroValue::Enum(_, symbol) => Value::String(symbol.clone()),
+        AvroValue::Uuid(uuid) => Value::String(uuid.to_string()),
+        AvroValue::Date(days) => json!(days),
+        AvroValue::TimeMillis(millis) => json!(i64::from(*millis) * 1_000),
+        AvroValue::TimeMicros(micros) => json!(micros),
+        AvroValue::TimestampMillis(millis) | AvroValue::LocalTimestampMillis(millis) => {
+            json!(millis
+                .checked_mul(1_000)
+                .with_context(|| format!("Timestamp {} is out of range", millis))?)
+        }
+        AvroValue::TimestampMicros(micros) | AvroValue::LocalTimestampMicros(micros) => {
+            json!(micros)
+        }
+        AvroValue::TimestampNanos(nanos) | AvroValue::LocalTimestampNanos(nanos) => {
+            json!(nanos.div_euclid(1_000))
+        }
+        AvroValue::Duration(duration) => json!({
+            "months": u32::from(duration.months()),
+            "days": u32::from(duration.days()),
+            "millis": u32::from(duration.millis()),
+        }),
+        AvroValue::BigDecimal(decimal) => float(
+            decimal
+                .to_string()
+                .parse()
+                .context("Decimal does not fit in a double")?,
+        ),
+        AvroValue::Union(_, inner) => scalar_to_json(inner)?,
+        AvroValue::Array(items) => {
+            Value::Array(items.iter().map(scalar_to_json).collect::<Result<_>>()?)
+        }
+        AvroValue::Map(entries) => Value::Object(
+            entries
+                .iter()
+                .map(|(key, value)| Ok((key.clone(), scalar_to_json(value)?)))
+                .collect::<Result<_>>()?,
+        ),
+        AvroValue::Record(fields) => Value::Object(
+            fields
+                .iter()
+                .map(|(name, value)| Ok((name.clone(), scalar_to_json(value)?)))
+                .collect::<Result<_>>()?,
+        ),
+        AvroValue::Decimal(_) => bail!("Decimal value without a decimal schema"),
+    })
+}
+
+/// A decimal as a double; precision beyond a double's is lost
+fn decimal_to_json(decimal: &Decimal, scale: usize) -> Result<Value> {
+    let bytes: Vec<u8> = decimal.try_into().context("Invalid decimal")?;
+    if bytes.len() > 16 {
+        bail!("Decimal of {} bytes does not fit in a double", bytes.len());
+    }
+    // Big-endian two's complement, sign-extended to 128 bits
+    let fill = if bytes.first().is_some_and(|b| b & 0x80 != 0) {
+        0xff
+    } else {
+        0
+    };
+    let mut buf = [fill; 16];
+    buf[16 - bytes.len()..].copy_from_slice(&bytes);
+    let unscaled = i128::from_be_bytes(buf);
+    Ok(float(unscaled as f64 / 10f64.powi(scale as i32)))
+}
+
+/// Non-finite floats have no JSON number, but the encoder parses them from text
+fn float(value: f64) -> Value {
+    if value.is_finite() {
+        json!(value)
+    } else {
+        Value::String(value.to_string())
+    }
+}
+
+/// Magic byte that starts every Confluent wire-format payload
+const CONFLUENT_MAGIC: u8 = 0;
+
+/// Credentials for a Schema Registry
+#[derive(Debug, Clone)]
+pub enum RegistryAuth {
+    Basic { username: String, password: String },
+    Bearer(String),
+}
+
+/// Fetches writer schemas by ID from a Confluent-compatible Schema Registry
+///
+/// Schemas never change once registered, so each ID is fetched at most once.
+pub struct SchemaRegistry {
+    base_url: String,
+    auth: Option<RegistryAuth>,
+    client: reqwest::Client,
+    cache: RwLock<HashMap<u32, Arc<AvroConverter>>>,
+}
+
+impl SchemaRegistry {
+    pub fn new(base_url: impl Into<String>, auth: Option<RegistryAuth>) -> Self {
+        Self {
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            auth,
+            client: reqwest::Client::new(),
+            cache: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Read `SCHEMA_REGISTRY_URL`, with `SCHEMA_REGISTRY_USERNAME` and
+    /// `SCHEMA_REGISTRY_PASSWORD` or `SCHEMA_REGISTRY_TOKEN` for authentication
+    pub fn from_env() -> Result<Self> {
+        let url = std::env::var("SCHEMA_REGISTRY_URL")
+            .context("SCHEMA_REGISTRY_URL environment variable must be set")?;
+        let username = std::env::var("SCHEMA_REGISTRY_USERNAME").ok();
+        let password = std::env::var("SCHEMA_REGISTRY_PASSWORD").ok();
+        let token = std::env::var("SCHEMA_REGISTRY_TOKEN").ok();
+        let auth = match (username, password, token) {
+            (Some(username), Some(password), _) => Some(RegistryAuth::Basic { username, password }),
+            (None, None, Some(token)) => Some(RegistryAuth::Bearer(token)),
+            (None, None, None) => None,
+            _ => bail!(
+                "Set both SCHEMA_REGISTRY_USERNAME and SCHEMA_REGISTRY_PASSWORD, or only SCHEMA_REGISTRY_TOKEN"
+            ),
+        };
+        Ok(Self::new(url, auth))
+    }
+
+    /// The converter for schema `id`, fetching the schema on first use
+    pub async fn converter(&self, id: u32) -> Result<Arc<AvroConverter>> {
+        if let Some(converter) = self.cache.read().await.get(&id) {
+            return Ok(converter.clone());
+        }
+
+        let mut cache = self.cache.write().await;
+        // Another task may have fetched it while we waited for the lock
+        if let Some(converter) = cache.get(&id) {
+            return Ok(converter.clone());
+        }
+        let converter = Arc::new(self.fetch(id).await?);
+        cache.insert(id, converter.clone());
+        Ok(converter)
+    }
+
+    async fn fetch(&self, id: u32) -> Result<AvroConverter> {
+        let url = format!("{}/schemas/ids/{}", self.base_url, id);
+        let mut request = self.client.get(&url);
+        request = match &self.auth {
+            Some(RegistryAuth::Basic { username, password }) => {
+                request.basic_auth(username, Some(password))
+            }
+            Some(RegistryAuth::Bearer(token)) => request.bearer_auth(token),
+            None => request,
+        };
+        let response: Value = request
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .with_context(|| format!("Failed to fetch schema {} from {}", id, self.base_url))?
+            .json()
+            .await
+            .with_context(|| format!("Invalid response for schema {}", id))?;
+
+        if let Some(schema_type) = response["schemaType"].as_str() {
+            if schema_type != "AVRO" {
+                bail!("Schema {} is {}, not Avro", id, schema_type);
+            }
+        }
+        let schema = response["schema"]
+            .as_str()
+            .with_context(|| format!("Response for schema {} has no schema", id))?;
+        let schema = Schema::parse_str(schema)
+            .with_context(|| format!("Schema {} is not a valid Avro schema", id))?;
+        AvroConverter::new(&schema)
+    }
+}
+
+/// Decodes Confluent wire-format Avro payloads: a zero byte, the big-endian schema ID,
+/// then the Avro-encoded datum
+pub struct ConfluentDecoder {
+    registry: SchemaRegistry,
+}
+
+impl ConfluentDecoder {
+    pub fn new(registry: SchemaRegistry) -> Self {
+        Self { registry }
+    }
+
+    pub async fn decode(&self, payload: &[u8]) -> Result<Value> {
+        let (id, mut datum) = match payload {
+            [CONFLUENT_MAGIC, a, b, c, d, datum @ ..] => {
+                (u32::from_be_bytes([*a, *b, *c, *d]), datum)
+            }
+            [CONFLUENT_MAGIC, ..] => bail!("Payload is too short for the Confluent wire format"),
+            _ => bail!("Payload does not start with the Confluent magic byte"),
+        };
+        let converter = self.registry.converter(id).await?;
+        let value = apache_avro::from_avro_datum(converter.schema(), &mut datum, None)
+            .with_context(|| format!("Payload does not match schema {}", id))?;
+        converter.convert(&value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use apache_avro::to_avro_datum;
+    use axum::extract::{Path, State};
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::get;
+    use axum::{Json, Router};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const ORDER_SCHEMA: &str = r#"{
+        "type": "record",
+        "name": "Order",
+        "fields": [
+            {"name": "id", "type": "long"},
+            {"name": "placed_at", "type": {"type": "long", "logicalType": "timestamp-millis"}},
+            {"name": "ship_date", "type": ["null", {"type": "int", "logicalType": "date"}]},
+            {"name": "total", "type": {"type": "bytes", "logicalType": "decimal", "precision": 10, "scale": 2}},
+            {"name": "lines", "type": {"type": "array", "items": {
+                "type": "record",
+                "name": "Line",
+                "fields": [
+                    {"name": "sku", "type": "string"},
+                    {"name": "price", "type": {"type": "bytes", "logicalType": "decimal", "precision": 8, "scale": 3}}
+                ]
+            }}},
+            {"name": "return_line", "type": ["null", "Line"]}
+        ]
+    }"#;
+
+    fn order() -> AvroValue {
+        let line = |sku: &str, price: i64| {
+            AvroValue::Record(vec![
+                ("sku".to_string(), AvroValue::String(sku.to_string())),
+                (
+                    "price".to_string(),
+                    AvroValue::Decimal(Decimal::from(price.to_be_bytes())),
+                ),
+            ])
+        };
+        AvroValue::Record(vec![
+            ("id".to_string(), AvroValue::Long(7)),
+            (
+                "placed_at".to_string(),
+                AvroValue::TimestampMillis(1_700_000_000_123),
+            ),
+            (
+                "ship_date".to_string(),
+                AvroValue::Union(1, Box::new(AvroValue::Date(19_700))),
+            ),
+            (
+                "total".to_string(),
+                AvroValue::Decimal(Decimal::from((-1234i32).to_be_bytes())),
+            ),
+            (
+                "lines".to_string(),
+                AvroValue::Array(vec![line("a", 1_500), line("b", -250)]),
+            ),
+            (
+                "return_line".to_string(),
+                AvroValue::Union(1, Box::new(line("b", 250))),
+            ),
+        ])
+    }
+
+    #[test]
+    fn test_logical_types_and_named_references() {
+        let schema = Schema::parse_str(ORDER_SCHEMA).unwrap();
+
+        let value = AvroConverter::new(&schema)
+            .unwrap()
+            .convert(&order())
+            .unwrap();
+
+        assert_eq!(
+            json!({
+                "id": 7,
+                "placed_at": 1_700_000_000_123_000i64,
+                "ship_date": 19_700,
+                "total": -12.34,
+                "lines": [{"sku": "a", "price": 1.5}, {"sku": "b", "price": -0.25}],
+                "return_line": {"sku": "b", "price": 0.25},
+            }),
+            value
+        );
+    }
+
+    #[derive(Clone, Default)]
+    struct Registry {
+        requests: Arc<AtomicUsize>,
+    }
+
+    async fn schema_by_id(
+        State(registry): State<Registry>,
+        Path(id): Path<u32>,
+        headers: HeaderMap,
+    ) -> Result<Json<Value>, StatusCode> {
+        registry.requests.fetch_add(1, Ordering::SeqCst);
+        // base64("reader:secret")
+        if headers.get("authorization").and_then(|v| v.to_str().ok())
+            != Some("Basic cmVhZGVyOnNlY3JldA==")
+        {
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+        match id {
+            42 => Ok(Json(json!({ "schema": ORDER_SCHEMA }))),
+            _ => Err(StatusCode::NOT_FOUND),
+        }
+    }
+
+    /// Serve a registry with one schema on a random local port
+    async fn start_registry() -> (String, Registry) {
+        let registry = Registry::default();
+        let app = Router::new()
+            .route("/schemas/ids/:id", get(schema_by_id))
+            .with_state(registry.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (url, registry)
+    }
+
+    fn confluent_payload(schema_id: u32) -> Vec<u8> {
+        let schema = Schema::parse_str(ORDER_SCHEMA).unwrap();
+        let mut payload = vec![CONFLUENT_MAGIC];
+        payload.extend_from_slice(&schema_id.to_be_bytes());
+        payload.extend(to_avro_datum(&schema, order()).unwrap());
+        payload
+    }
+
+    #[tokio::test]
+    async fn test_confluent_payloads_with_cached_schema() {
+        let (url, registry) = start_registry().await;
+        let auth = RegistryAuth::Basic {
+            username: "reader".to_string(),
+            password: "secret".to_string(),
+        };
+        let decoder = ConfluentDecoder::new(SchemaRegistry::new(url, Some(auth)));
+
+        for _ in 0..3 {
+            let value = decoder.decode(&confluent_payload(42)).await.unwrap();
+            assert_eq!(json!(7), value["id"]);
+            assert_eq!(json!(-12.34), value["total"]);
+        }
+        assert_eq!(1, registry.requests.load(Ordering::SeqCst));
+
+        let unknown = decoder.decode(&confluent_payload(99)).await.unwrap_err();
+        assert!(format!("{:#}", unknown).contains("Failed to fetch schema 99"));
+        assert!(decoder.decode(b"{\"id\": 7}").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_registry_rejects_missing_credentials() {
+        let (url, _) = start_registry().await;
+        let decoder = ConfluentDecoder::new(SchemaRegistry::new(url, None));
+
+        let error = decoder.decode(&confluent_payload(42)).await.unwrap_err();
+        assert!(format!("{:#}", error).contains("401"));
+    }
+}
@@ -0,0 +1,124 @@
+//! Bounding the label values of metrics.
+//!
+//! Tables and sources come from configuration and from the data, so a topic pattern or a
+//! routing rule can produce any number of them. A [`LabelGuard`] admits label sets up to
+//! a limit and turns the rest away, for the caller to report as [`OVERFLOW`], so neither
+//! a Prometheus scrape nor the CloudWatch dimensions grow without bound.
+
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use tracing::warn;
+
+/// Label sets admitted when `METRICS_MAX_SERIES` is not set
+pub const DEFAULT_MAX_SERIES: usize = 256;
+
+/// Label value of whatever is past the limit
+pub const OVERFLOW: &str = "other";
+
+/// The label sets admitted so far, shared by its clones
+#[derive(Debug, Clone)]
+pub struct LabelGuard {
+    max: usize,
+    admitted: Arc<Mutex<Admitted>>,
+}
+
+#[derive(Debug, Default)]
+struct Admitted {
+    sets: HashSet<Vec<String>>,
+    overflowed: bool,
+}
+
+impl Default for LabelGuard {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_SERIES)
+    }
+}
+
+impl LabelGuard {
+    /// Admit at most `max` label sets
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            admitted: Arc::default(),
+        }
+    }
+
+    /// A guard admitting `METRICS_MAX_SERIES` label sets, or [`DEFAULT_MAX_SERIES`]
+    pub fn from_env() -> Result<Self> {
+        Ok(Self::new(
+            max_series_from_env()?.unwrap_or(DEFAULT_MAX_SERIES),
+        ))
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// Whether `labels` have their own series: they were admitted before, or there is
+    /// still room for them
+    ///
+    /// The first label set turned away is logged, once.
+    pub fn admit(&self, labels: &[&str]) -> bool {
+        let mut admitted = self.admitted.lock().unwrap();
+        let labels: Vec<String> = labels.iter().map(|label| label.to_string()).collect();
+        if admitted.sets.contains(&labels) {
+            return true;
+        }
+        if admitted.sets.len() < self.max {
+            admitted.sets.insert(labels);
+            return true;
+        }
+        if !admitted.overflowed {
+            warn!(
+                "More than {} metric label sets; {:?} and any further ones are reported as {:?}",
+                self.max, labels, OVERFLOW
+            );
+            admitted.overflowed = true;
+        }
+        false
+    }
+
+    /// `source`, or [`OVERFLOW`] when `table` and `source` are past the limit
+    pub fn source<'a>(&self, table: &str, source: &'a str) -> &'a str {
+        if self.admit(&[table, source]) {
+            source
+        } else {
+            OVERFLOW
+        }
+    }
+}
+
+/// `METRICS_MAX_SERIES`, when set
+pub fn max_series_from_env() -> Result<Option<usize>> {
+    match std::env::var("METRICS_MAX_SERIES") {
+        Ok(value) if !value.trim().is_empty() => {
+            let max: usize = value
+                .trim()
+                .parse()
+                .with_context(|| format!("Invalid METRICS_MAX_SERIES {:?}", value))?;
+            Ok(Some(max))
+        }
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_label_sets_past_the_limit_are_turned_away() {
+        let guard = LabelGuard::new(2);
+        assert!(guard.admit(&["main.default.orders", "orders"]));
+        assert!(guard.admit(&["main.default.returns", "returns"]));
+        assert!(!guard.admit(&["main.default.refunds", "refunds"]));
+        // Those admitted keep their series, and those turned away stay out
+        assert!(guard.admit(&["main.default.orders", "orders"]));
+        assert!(!guard.admit(&["main.default.refunds", "refunds"]));
+
+        let shared = guard.clone();
+        assert_eq!("returns", shared.source("main.default.returns", "returns"));
+        assert_eq!(OVERFLOW, shared.source("main.default.orders", "orders-eu"));
+    }
+}
@@ -0,0 +1,187 @@
+//! Splitting records too large for the stream into ordered chunks
+//!
+//! A record whose encoded size is above the limit has its payload column cut into
+//! chunks, each sent as a record of its own with the other columns repeated. Every chunk
+//! of a payload carries the same `chunk_group_id`, its `chunk_index` from 0, and
+//! `total_chunks`, so a query reassembles the payload by concatenating the chunks of a
+//! group in index order. A group with fewer than `total_chunks` rows is incomplete.
+//!
+//! - `SPLIT_LARGE_RECORDS` - Set to `true` to split records over the limit instead of
+//!   sending them whole (default: `false`)
+//! - `MAX_RECORD_BYTES` - Largest encoded record sent whole (default:
+//!   [`DEFAULT_MAX_RECORD_BYTES`], 1MB)
+
+use anyhow::{anyhow, bail, Result};
+
+/// Default for `MAX_RECORD_BYTES`
+pub const DEFAULT_MAX_RECORD_BYTES: usize = 1024 * 1024;
+
+/// Most bytes the payload column adds to a record besides the payload: a key for field
+/// numbers up to 2047 and a length of up to 5 bytes
+const PAYLOAD_FIELD_OVERHEAD: usize = 2 + 5;
+
+/// Where a chunk belongs in its payload
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkInfo {
+    pub group_id: String,
+    pub index: i32,
+    pub total: i32,
+}
+
+impl ChunkInfo {
+    /// The metadata of each of `total` chunks of the group `group_id`, in order
+    pub fn group(group_id: &str, total: usize) -> impl Iterator<Item = ChunkInfo> + '_ {
+        let total = total as i32;
+        (0..total).map(move |index| ChunkInfo {
+            group_id: group_id.to_string(),
+            index,
+            total,
+        })
+    }
+}
+
+/// Decides which records are split, and into chunks of what size
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Splitter {
+    max_record_bytes: usize,
+}
+
+impl Default for Splitter {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_RECORD_BYTES)
+    }
+}
+
+impl Splitter {
+    pub fn new(max_record_bytes: usize) -> Self {
+        Self { max_record_bytes }
+    }
+
+    /// Read `SPLIT_LARGE_RECORDS` and `MAX_RECORD_BYTES`; `None` unless splitting is on
+    pub fn from_env() -> Result<Option<Self>> {
+        let enabled = std::env::var("SPLIT_LARGE_RECORDS")
+            .map(|value| value.trim().eq_ignore_ascii_case("true"))
+            .unwrap_or(false);
+        if !enabled {
+            return Ok(None);
+        }
+        match std::env::var("MAX_RECORD_BYTES") {
+            Ok(value) if !value.trim().is_empty() => match value.trim().parse::<usize>() {
+                Ok(max) if max > 0 => Ok(Some(Self::new(max))),
+                _ => bail!(
+                    "MAX_RECORD_BYTES must be a positive number of bytes, got {:?}",
+                    value
+                ),
+            },
+            _ => Ok(Some(Self::default())),
+        }
+    }
+
+    pub fn max_record_bytes(&self) -> usize {
+        self.max_record_bytes
+    }
+
+    /// Whether a record of `encoded_len` bytes is over the limit
+    pub fn needs_split(&self, encoded_len: usize) -> bool {
+        encoded_len > self.max_record_bytes
+    }
+
+    /// Room for the payload in each chunk of a record whose other columns, chunk
+    /// metadata included, encode to `overhead` bytes
+    ///
+    /// Fails when those columns alone leave no room under the limit.
+    pub fn chunk_bytes(&self, overhead: usize) -> Result<usize> {
+        self.max_record_bytes
+            .checked_sub(overhead + PAYLOAD_FIELD_OVERHEAD)
+            .filter(|room| *room > 0)
+            .ok_or_else(|| {
+                anyhow!(
+                    "Columns besides the payload take {} bytes, leaving no room for chunks under MAX_RECORD_BYTES ({})",
+                    overhead,
+                    self.max_record_bytes
+                )
+            })
+    }
+}
+
+/// Cut `payload` into chunks of at most `chunk_bytes`; a single empty chunk when it is
+/// empty
+pub fn split_bytes(payload: &[u8], chunk_bytes: usize) -> Vec<&[u8]> {
+    if payload.is_empty() {
+        return vec![payload];
+    }
+    payload.chunks(chunk_bytes.max(1)).collect()
+}
+
+/// Cut `text` into chunks of at most `chunk_bytes`, never inside a character
+///
+/// A character longer than `chunk_bytes` gets a chunk of its own, over the size.
+pub fn split_str(text: &str, chunk_bytes: usize) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    let mut rest = text;
+    while rest.len() > chunk_bytes {
+        let mut end = chunk_bytes;
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        if end == 0 {
+            end = rest.chars().next().map_or(rest.len(), char::len_utf8);
+        }
+        let (chunk, tail) = rest.split_at(end);
+        chunks.push(chunk);
+        rest = tail;
+    }
+    if !rest.is_empty() || chunks.is_empty() {
+        chunks.push(rest);
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_payload_over_the_limit_is_split_in_order() {
+        let payload: Vec<u8> = (0..=255).cycle().take(2500).collect();
+        let chunks = split_bytes(&payload, 1000);
+        assert_eq!(
+            vec![1000, 1000, 500],
+            chunks.iter().map(|c| c.len()).collect::<Vec<_>>()
+        );
+        assert_eq!(payload, chunks.concat());
+
+        let groups: Vec<ChunkInfo> = ChunkInfo::group("msg-1", chunks.len()).collect();
+        assert_eq!(
+            vec![(0, 3), (1, 3), (2, 3)],
+            groups
+                .iter()
+                .map(|c| (c.index, c.total))
+                .collect::<Vec<_>>()
+        );
+        assert!(groups.iter().all(|c| c.group_id == "msg-1"));
+
+        assert_eq!(vec![b"".as_slice()], split_bytes(b"", 10));
+    }
+
+    #[test]
+    fn test_text_is_split_on_character_boundaries() {
+        let chunks = split_str("abcééf", 4);
+        assert_eq!(vec!["abc", "éé", "f"], chunks);
+        assert_eq!(vec!["abc"], split_str("abc", 4));
+        // A character wider than a chunk is kept whole
+        assert_eq!(vec!["é", "é"], split_str("éé", 1));
+    }
+
+    #[test]
+    fn test_chunk_room_leaves_space_for_the_other_columns() {
+        let splitter = Splitter::new(1000);
+        assert!(!splitter.needs_split(1000));
+        assert!(splitter.needs_split(1001));
+        assert_eq!(
+            1000 - 200 - PAYLOAD_FIELD_OVERHEAD,
+            splitter.chunk_bytes(200).unwrap()
+        );
+        assert!(splitter.chunk_bytes(995).is_err());
+    }
+}
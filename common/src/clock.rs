@@ -0,0 +1,58 @@
+//! Where rows get their ingestion time from.
+//!
+//! The encode paths take a [`Clock`] rather than calling `SystemTime::now()`, so a test
+//! can pin the time with a [`FixedClock`] and compare encoded rows byte for byte. The
+//! pipeline, metrics series, watermark counters, and audit rows can be given one as
+//! well, so end-to-end latencies and stamped times are exact in tests, and so can the
+//! self-test's canary IDs and the token file's expiry check.
+
+use std::fmt::Debug;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub trait Clock: Send + Sync + Debug {
+    fn now(&self) -> SystemTime;
+}
+
+/// The system's wall clock, used outside tests
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock stopped at one time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FixedClock(pub SystemTime);
+
+impl FixedClock {
+    /// Stopped `secs` seconds after the Unix epoch
+    pub fn at_unix_secs(secs: u64) -> Self {
+        Self(UNIX_EPOCH + Duration::from_secs(secs))
+    }
+}
+
+impl Clock for FixedClock {
+    fn now(&self) -> SystemTime {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fixed_clock_does_not_move() {
+        let clock = FixedClock::at_unix_secs(1_718_020_860);
+        let first = clock.now();
+        std::thread::sleep(Duration::from_millis(2));
+        assert_eq!(first, clock.now());
+        assert_eq!(
+            1_718_020_860,
+            first.duration_since(UNIX_EPOCH).unwrap().as_secs()
+        );
+    }
+}
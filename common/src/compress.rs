@@ -0,0 +1,106 @@
+//! Client-side compression of large payload columns, selected by `COMPRESS_PAYLOAD`.
+//!
+//! A compressed payload is stored in a `BINARY` column next to a `payload_codec` column
+//! naming the codec, so queries can decompress it with a UDF. This is only about how
+//! payloads are stored; it has nothing to do with decompressing inbound bodies.
+
+use anyhow::{bail, Context, Result};
+use std::io::{Read, Write};
+
+/// zstd level used for payloads; the library default, a good speed/ratio balance
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadCodec {
+    Gzip,
+    Zstd,
+}
+
+impl PayloadCodec {
+    /// Read `COMPRESS_PAYLOAD` (`gzip` or `zstd`)
+    ///
+    /// Returns `None` when the variable is unset, empty, or `none`, in which case
+    /// payloads are stored as plain strings.
+    pub fn from_env() -> Result<Option<Self>> {
+        match std::env::var("COMPRESS_PAYLOAD") {
+            Ok(codec) => Self::parse(&codec),
+            Err(_) => Ok(None),
+        }
+    }
+
+    pub fn parse(codec: &str) -> Result<Option<Self>> {
+        Ok(match codec.trim().to_ascii_lowercase().as_str() {
+            "" | "none" => None,
+            "gzip" => Some(PayloadCodec::Gzip),
+            "zstd" => Some(PayloadCodec::Zstd),
+            _ => bail!(
+                "COMPRESS_PAYLOAD must be \"gzip\" or \"zstd\", got {:?}",
+                codec
+            ),
+        })
+    }
+
+    /// The value written to the `payload_codec` column
+    pub fn name(&self) -> &'static str {
+        match self {
+            PayloadCodec::Gzip => "gzip",
+            PayloadCodec::Zstd => "zstd",
+        }
+    }
+
+    pub fn compress(&self, payload: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            PayloadCodec::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder
+                    .write_all(payload)
+                    .and_then(|()| encoder.finish())
+                    .context("Failed to gzip payload")
+            }
+            PayloadCodec::Zstd => {
+                zstd::encode_all(payload, ZSTD_LEVEL).context("Failed to zstd-compress payload")
+            }
+        }
+    }
+
+    pub fn decompress(&self, compressed: &[u8]) -> Result<Vec<u8>> {
+        let mut payload = Vec::new();
+        match self {
+            PayloadCodec::Gzip => flate2::read::GzDecoder::new(compressed)
+                .read_to_end(&mut payload)
+                .context("Failed to gunzip payload")?,
+            PayloadCodec::Zstd => zstd::Decoder::new(compressed)
+                .and_then(|mut decoder| decoder.read_to_end(&mut payload))
+                .context("Failed to zstd-decompress payload")?,
+        };
+        Ok(payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let payload = r#"{"message": "hello"}"#.repeat(100);
+
+        for codec in [PayloadCodec::Gzip, PayloadCodec::Zstd] {
+            let compressed = codec.compress(payload.as_bytes()).unwrap();
+            assert!(compressed.len() < payload.len());
+            assert_eq!(payload.as_bytes(), codec.decompress(&compressed).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(None, PayloadCodec::parse("").unwrap());
+        assert_eq!(None, PayloadCodec::parse("none").unwrap());
+        assert_eq!(
+            Some(PayloadCodec::Zstd),
+            PayloadCodec::parse(" ZSTD ").unwrap()
+        );
+        assert!(PayloadCodec::parse("brotli").is_err());
+    }
+}
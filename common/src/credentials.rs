@@ -0,0 +1,88 @@
+//! Where a service principal's OAuth credentials come from
+
+use anyhow::{Context, Result};
+use std::future::Future;
+
+/// Client ID and secret of the service principal streams are created with
+#[derive(Clone, PartialEq)]
+pub struct Credentials {
+    pub client_id: String,
+    pub client_secret: String,
+}
+
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Credentials")
+            .field("client_id", &self.client_id)
+            .field("client_secret", &"<redacted>")
+            .finish()
+    }
+}
+
+/// Loads credentials when a stream is created
+///
+/// [`EnvCredentials`] reads them from the environment; examples running where secrets
+/// live in a vault implement this over the vault instead.
+pub trait CredentialProvider: Send + Sync {
+    fn credentials(&self) -> impl Future<Output = Result<Credentials>> + Send;
+}
+
+/// Credentials from `DATABRICKS_CLIENT_ID` and `DATABRICKS_CLIENT_SECRET`
+#[derive(Debug, Clone)]
+pub struct EnvCredentials {
+    client_id_var: String,
+    client_secret_var: String,
+}
+
+impl Default for EnvCredentials {
+    fn default() -> Self {
+        Self::new("DATABRICKS_CLIENT_ID", "DATABRICKS_CLIENT_SECRET")
+    }
+}
+
+impl EnvCredentials {
+    /// Credentials from other variables
+    pub fn new(client_id_var: &str, client_secret_var: &str) -> Self {
+        Self {
+            client_id_var: client_id_var.to_string(),
+            client_secret_var: client_secret_var.to_string(),
+        }
+    }
+}
+
+impl CredentialProvider for EnvCredentials {
+    async fn credentials(&self) -> Result<Credentials> {
+        let var = |name: &str| {
+            std::env::var(name)
+                .with_context(|| format!("{} environment variable must be set", name))
+        };
+        Ok(Credentials {
+            client_id: var(&self.client_id_var)?,
+            client_secret: var(&self.client_secret_var)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::handler::EnvScope;
+
+    #[tokio::test]
+    async fn test_env_credentials() {
+        let _env = EnvScope::new(&[
+            ("TEST_ENV_CREDENTIALS_ID", "sp-id"),
+            ("TEST_ENV_CREDENTIALS_SECRET", "sp-secret"),
+        ]);
+        let provider =
+            EnvCredentials::new("TEST_ENV_CREDENTIALS_ID", "TEST_ENV_CREDENTIALS_SECRET");
+
+        let credentials = provider.credentials().await.unwrap();
+        assert_eq!("sp-id", credentials.client_id);
+        assert_eq!("sp-secret", credentials.client_secret);
+        assert!(!format!("{:?}", credentials).contains("sp-secret"));
+
+        let missing = EnvCredentials::new("TEST_ENV_CREDENTIALS_ID", "TEST_ENV_CREDENTIALS_UNSET");
+        assert!(missing.credentials().await.is_err());
+    }
+}
@@ -0,0 +1,102 @@
+//! Remembering recent ids, so a delivery or event a source sends again is ingested once.
+//!
+//! Webhook sources retry or redeliver with the id of the original, such as GitHub's
+//! `X-GitHub-Delivery` or Slack's `event_id`. The ids are kept in memory, so a restart
+//! forgets them.
+
+use std::collections::{HashSet, VecDeque};
+
+/// The most recently seen ids, evicting the least recently seen first
+pub struct RecentIds {
+    capacity: usize,
+    order: VecDeque<String>,
+    seen: HashSet<String>,
+}
+
+impl RecentIds {
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "capacity must be positive");
+        Self {
+            capacity,
+            order: VecDeque::with_capacity(capacity),
+            seen: HashSet::with_capacity(capacity),
+        }
+    }
+
+    /// Record `id` as seen, returning false if it already was
+    pub fn insert(&mut self, id: &str) -> bool {
+        if self.seen.contains(id) {
+            // Retries tend to come in bursts; keep a retried id around the longest
+            if let Some(position) = self.order.iter().position(|seen| seen == id) {
+                let id = self.order.remove(position).expect("position is in range");
+                self.order.push_back(id);
+            }
+            return false;
+        }
+
+        if self.order.len() == self.capacity {
+            if let Some(evicted) = self.order.pop_front() {
+                self.seen.remove(&evicted);
+            }
+        }
+        self.order.push_back(id.to_string());
+        self.seen.insert(id.to_string());
+        true
+    }
+
+    /// Forget `id`, so that it is accepted when sent again
+    pub fn remove(&mut self, id: &str) {
+        if self.seen.remove(id) {
+            self.order.retain(|seen| seen != id);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duplicates_are_rejected() {
+        let mut recent = RecentIds::new(3);
+        assert!(recent.insert("a"));
+        assert!(recent.insert("b"));
+        assert!(!recent.insert("a"));
+        assert!(!recent.insert("b"));
+        assert_eq!(2, recent.len());
+    }
+
+    #[test]
+    fn test_least_recently_seen_is_evicted() {
+        let mut recent = RecentIds::new(3);
+        assert!(recent.insert("a"));
+        assert!(recent.insert("b"));
+        assert!(recent.insert("c"));
+        // Seeing "a" again makes "b" the oldest
+        assert!(!recent.insert("a"));
+        assert!(recent.insert("d"));
+
+        assert_eq!(3, recent.len());
+        assert!(recent.insert("b"));
+        assert!(!recent.insert("a"));
+        assert!(!recent.insert("d"));
+    }
+
+    #[test]
+    fn test_removed_ids_are_accepted_again() {
+        let mut recent = RecentIds::new(2);
+        assert!(recent.insert("a"));
+        recent.remove("a");
+        recent.remove("never seen");
+        assert!(recent.is_empty());
+        assert!(recent.insert("a"));
+    }
+}
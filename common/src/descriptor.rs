@@ -0,0 +1,249 @@
+use anyhow::{Context, Result};
+use prost::Message;
+use prost_types::DescriptorProto;
+use std::future::Future;
+use std::path::PathBuf;
+
+/// Load a message descriptor from an embedded `FileDescriptorSet`
+///
+/// `descriptor_bytes` is usually the output of `buf build --as-file-descriptor-set`
+/// pulled in with `include_bytes!`.
+pub fn load_descriptor_proto(
+    descriptor_bytes: &[u8],
+    file_name: &str,
+    message_name: &str,
+) -> DescriptorProto {
+    let file_descriptor_set = prost_types::FileDescriptorSet::decode(descriptor_bytes)
+        .expect("Failed to decode descriptor file");
+
+    let file_descriptor_proto = file_descriptor_set
+        .file
+        .into_iter()
+        .find(|f| f.name.as_deref() == Some(file_name))
+        .expect("File descriptor not found");
+
+    file_descriptor_proto
+        .message_type
+        .into_iter()
+        .find(|m| m.name.as_deref() == Some(message_name))
+        .expect("Message descriptor not found")
+}
+
+/// Load a message descriptor from the embedded `FileDescriptorSet` built for `ENVIRONMENT`
+///
+/// For binaries deployed to several environments whose tables differ slightly. `sets`
+/// pairs each environment with its descriptor set, e.g.
+/// `("prod", include_bytes!("../gen/descriptors/schema_prod.descriptor"))`; the first
+/// one is used when `ENVIRONMENT` is not set. Fails for an `ENVIRONMENT` without a set,
+/// rather than streaming with another environment's schema.
+pub fn load_environment_descriptor_proto(
+    sets: &[(&str, &[u8])],
+    file_name: &str,
+    message_name: &str,
+) -> Result<DescriptorProto> {
+    let environment = std::env::var("ENVIRONMENT").ok();
+    let descriptor_bytes = select_descriptor_set(sets, environment.as_deref())?;
+    Ok(load_descriptor_proto(
+        descriptor_bytes,
+        file_name,
+        message_name,
+    ))
+}
+
+/// The descriptor set embedded for `environment`, or the first one when it is unset
+pub fn select_descriptor_set<'a>(
+    sets: &[(&str, &'a [u8])],
+    environment: Option<&str>,
+) -> Result<&'a [u8]> {
+    let environment = environment.map(str::trim).filter(|e| !e.is_empty());
+    let found = match environment {
+        Some(environment) => sets.iter().find(|(name, _)| *name == environment),
+        None => sets.first(),
+    };
+    found.map(|(_, bytes)| *bytes).with_context(|| {
+        let available: Vec<&str> = sets.iter().map(|(name, _)| *name).collect();
+        format!(
+            "No descriptor set embedded for ENVIRONMENT={} (available: {})",
+            environment.unwrap_or_default(),
+            available.join(", ")
+        )
+    })
+}
+
+/// Find a message descriptor by name in any file of an encoded `FileDescriptorSet`
+///
+/// For tools that take a descriptor file at runtime, where a missing message is a user
+/// error rather than a build problem.
+pub fn find_message_descriptor(
+    descriptor_bytes: &[u8],
+    message_name: &str,
+) -> Result<DescriptorProto> {
+    let file_descriptor_set = prost_types::FileDescriptorSet::decode(descriptor_bytes)
+        .context("Failed to decode descriptor file")?;
+
+    file_descriptor_set
+        .file
+        .into_iter()
+        .flat_map(|f| f.message_type)
+        .find(|m| m.name.as_deref() == Some(message_name))
+        .with_context(|| format!("Message descriptor {} not found", message_name))
+}
+
+/// Stable fingerprint of a message descriptor, as 16 hex digits
+///
+/// Two descriptors hash the same exactly when their encoded form is identical, so the
+/// hash changes whenever a field is added, removed, renamed, or retyped. Uses 64-bit
+/// FNV-1a, which is stable across Rust releases unlike `DefaultHasher`.
+pub fn schema_hash(descriptor: &DescriptorProto) -> String {
+    const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    let hash = descriptor
+        .encode_to_vec()
+        .iter()
+        .fold(FNV_OFFSET_BASIS, |hash, &byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME)
+        });
+    format!("{:016x}", hash)
+}
+
+/// Provides the descriptor a stream should be created with
+///
+/// Streams look the descriptor up again whenever the table schema changes underneath
+/// them, so a source that can observe schema updates (a descriptor file redeployed next
+/// to the binary, or one built from the live table) lets an ingestor follow schema
+/// evolution without a restart.
+pub trait DescriptorSource: Send + Sync {
+    fn fetch(&self) -> impl Future<Output = Result<DescriptorProto>> + Send;
+}
+
+/// A descriptor compiled into the binary; it never changes
+pub struct EmbeddedDescriptor(pub DescriptorProto);
+
+impl DescriptorSource for EmbeddedDescriptor {
+    async fn fetch(&self) -> Result<DescriptorProto> {
+        Ok(self.0.clone())
+    }
+}
+
+/// A `FileDescriptorSet` on disk, re-read on every fetch
+pub struct DescriptorFile {
+    pub path: PathBuf,
+    pub file_name: String,
+    pub message_name: String,
+}
+
+impl DescriptorSource for DescriptorFile {
+    async fn fetch(&self) -> Result<DescriptorProto> {
+        let bytes = std::fs::read(&self.path)
+            .with_context(|| format!("Failed to read descriptor file {}", self.path.display()))?;
+        let file_descriptor_set = prost_types::FileDescriptorSet::decode(bytes.as_slice())
+            .context("Failed to decode descriptor file")?;
+
+        file_descriptor_set
+            .file
+            .into_iter()
+            .find(|f| f.name.as_deref() == Some(self.file_name.as_str()))
+            .with_context(|| format!("File descriptor {} not found", self.file_name))?
+            .message_type
+            .into_iter()
+            .find(|m| m.name.as_deref() == Some(self.message_name.as_str()))
+            .with_context(|| format!("Message descriptor {} not found", self.message_name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::handler::with_env;
+    use prost_types::FieldDescriptorProto;
+
+    fn descriptor(fields: &[&str]) -> DescriptorProto {
+        DescriptorProto {
+            name: Some("table_example".to_string()),
+            field: fields
+                .iter()
+                .enumerate()
+                .map(|(i, name)| FieldDescriptorProto {
+                    name: Some(name.to_string()),
+                    number: Some(i as i32 + 1),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_find_message_descriptor() {
+        let set = prost_types::FileDescriptorSet {
+            file: vec![
+                prost_types::FileDescriptorProto {
+                    name: Some("a.proto".to_string()),
+                    ..Default::default()
+                },
+                prost_types::FileDescriptorProto {
+                    name: Some("b.proto".to_string()),
+                    message_type: vec![descriptor(&["id"])],
+                    ..Default::default()
+                },
+            ],
+        };
+        let bytes = set.encode_to_vec();
+
+        let found = find_message_descriptor(&bytes, "table_example").unwrap();
+        assert_eq!(descriptor(&["id"]), found);
+        assert!(find_message_descriptor(&bytes, "missing").is_err());
+        assert!(find_message_descriptor(b"not a descriptor", "table_example").is_err());
+    }
+
+    #[test]
+    fn test_environment_descriptor() {
+        let set = |fields: &[&str]| {
+            prost_types::FileDescriptorSet {
+                file: vec![prost_types::FileDescriptorProto {
+                    name: Some("schema.proto".to_string()),
+                    message_type: vec![descriptor(fields)],
+                    ..Default::default()
+                }],
+            }
+            .encode_to_vec()
+        };
+        let dev = set(&["id"]);
+        let prod = set(&["id", "region"]);
+        let sets: &[(&str, &[u8])] = &[("dev", &dev), ("prod", &prod)];
+
+        let load = |vars: &[(&str, &str)]| {
+            with_env(vars, || {
+                load_environment_descriptor_proto(sets, "schema.proto", "table_example")
+            })
+        };
+        assert_eq!(
+            descriptor(&["id", "region"]),
+            load(&[("ENVIRONMENT", "prod")]).unwrap()
+        );
+        assert_eq!(
+            descriptor(&["id"]),
+            load(&[("ENVIRONMENT", "dev")]).unwrap()
+        );
+        assert_eq!(descriptor(&["id"]), load(&[]).unwrap());
+
+        let error = load(&[("ENVIRONMENT", "staging")]).unwrap_err();
+        assert_eq!(
+            "No descriptor set embedded for ENVIRONMENT=staging (available: dev, prod)",
+            error.to_string()
+        );
+    }
+
+    #[test]
+    fn test_schema_hash() {
+        let hash = schema_hash(&descriptor(&["id", "payload"]));
+
+        assert_eq!(16, hash.len());
+        assert_eq!(hash, schema_hash(&descriptor(&["id", "payload"])));
+        assert_ne!(hash, schema_hash(&descriptor(&["id", "payload", "extra"])));
+        assert_ne!(hash, schema_hash(&descriptor(&["id", "body"])));
+        // FNV-1a of no input is the offset basis
+        assert_eq!("cbf29ce484222325", schema_hash(&DescriptorProto::default()));
+    }
+}
@@ -0,0 +1,313 @@
+//! Distributions of ack latency, end-to-end latency, and record size, counted into fixed
+//! buckets
+//!
+//! A [`Distribution`] never stores the observations themselves, so it stays the same
+//! size however many records a pipeline sees. Percentiles are estimated from the
+//! buckets: an estimate is the upper bound of the bucket the percentile falls in, never
+//! more than the largest observation, so it is off by at most the width of one bucket.
+//!
+//! - `ACK_LATENCY_BUCKETS_MS` - Comma-separated upper bounds of the ack latency buckets,
+//!   in milliseconds (default: [`DEFAULT_LATENCY_BOUNDS_MS`], 1ms to 30s)
+//! - `RECORD_SIZE_BUCKETS` - Comma-separated upper bounds of the record size buckets, in
+//!   bytes (default: [`DEFAULT_SIZE_BOUNDS`], 256B to 1MB)
+
+use anyhow::{anyhow, bail, Result};
+use std::sync::Arc;
+
+/// Upper bounds of the ack latency buckets, in milliseconds: log-spaced from 1ms to 30s
+pub const DEFAULT_LATENCY_BOUNDS_MS: &[f64] = &[
+    1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0, 1000.0, 2000.0, 5000.0, 10000.0, 20000.0,
+    30000.0,
+];
+
+/// Upper bounds of the end-to-end latency buckets, in milliseconds: from 100ms to an hour,
+/// since an event can wait on its producer or a queue far longer than on its ack
+pub const DEFAULT_END_TO_END_BOUNDS_MS: &[f64] = &[
+    100.0, 500.0, 1000.0, 5000.0, 15000.0, 60000.0, 300000.0, 900000.0, 3600000.0,
+];
+
+/// Upper bounds of the record size buckets, in bytes: powers of two from 256B to 1MB
+pub const DEFAULT_SIZE_BOUNDS: &[f64] = &[
+    256.0, 512.0, 1024.0, 2048.0, 4096.0, 8192.0, 16384.0, 32768.0, 65536.0, 131072.0, 262144.0,
+    524288.0, 1048576.0,
+];
+
+/// Percentile estimates of a distribution
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Percentiles {
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+/// Observations counted into buckets with fixed upper bounds
+#[derive(Debug, Clone, PartialEq)]
+pub struct Distribution {
+    bounds: Arc<[f64]>,
+    /// Observations per bucket, with a last one for those above every bound
+    counts: Vec<u64>,
+    count: u64,
+    sum: f64,
+    min: f64,
+    max: f64,
+}
+
+impl Default for Distribution {
+    /// A single bucket, counting every observation
+    fn default() -> Self {
+        Self::new(Vec::new())
+    }
+}
+
+impl Distribution {
+    /// Buckets with these upper bounds, sorted and without duplicates or NaNs
+    pub fn new(bounds: impl Into<Vec<f64>>) -> Self {
+        let mut bounds: Vec<f64> = bounds.into();
+        bounds.retain(|bound| !bound.is_nan());
+        bounds.sort_by(f64::total_cmp);
+        bounds.dedup();
+        Self {
+            counts: vec![0; bounds.len() + 1],
+            bounds: bounds.into(),
+            count: 0,
+            sum: 0.0,
+            min: 0.0,
+            max: 0.0,
+        }
+    }
+
+    /// Ack latencies, in milliseconds, with the default buckets
+    pub fn latency() -> Self {
+        Self::new(DEFAULT_LATENCY_BOUNDS_MS)
+    }
+
+    /// Times from an event until its record was acknowledged, in milliseconds, with the
+    /// default buckets
+    pub fn end_to_end() -> Self {
+        Self::new(DEFAULT_END_TO_END_BOUNDS_MS)
+    }
+
+    /// Record sizes, in bytes, with the default buckets
+    pub fn size() -> Self {
+        Self::new(DEFAULT_SIZE_BOUNDS)
+    }
+
+    /// The same buckets, with nothing observed
+    pub fn empty(&self) -> Self {
+        Self {
+            bounds: Arc::clone(&self.bounds),
+            counts: vec![0; self.counts.len()],
+            count: 0,
+            sum: 0.0,
+            min: 0.0,
+            max: 0.0,
+        }
+    }
+
+    pub fn observe(&mut self, value: f64) {
+        let bucket = self.bounds.partition_point(|bound| *bound < value);
+        self.counts[bucket] += 1;
+        if self.count == 0 || value < self.min {
+            self.min = value;
+        }
+        if self.count == 0 || value > self.max {
+            self.max = value;
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+
+    /// Add the observations of `other`, which must have the same buckets
+    pub fn merge(&mut self, other: &Distribution) -> Result<()> {
+        if self.bounds != other.bounds {
+            bail!("Cannot merge distributions with different buckets");
+        }
+        if other.count == 0 {
+            return Ok(());
+        }
+        for (count, other) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other;
+        }
+        (self.min, self.max) = if self.count == 0 {
+            (other.min, other.max)
+        } else {
+            (self.min.min(other.min), self.max.max(other.max))
+        };
+        self.count += other.count;
+        self.sum += other.sum;
+        Ok(())
+    }
+
+    /// Number of observations
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Sum of the observations
+    pub fn sum(&self) -> f64 {
+        self.sum
+    }
+
+    /// Smallest observation; 0 when there are none
+    pub fn min(&self) -> f64 {
+        self.min
+    }
+
+    /// Largest observation; 0 when there are none
+    pub fn max(&self) -> f64 {
+        self.max
+    }
+
+    /// Upper bounds of the buckets; the last bucket, above every bound, has none
+    pub fn bounds(&self) -> &[f64] {
+        &self.bounds
+    }
+
+    /// Observations per bucket, with a last one for those above every bound
+    pub fn bucket_counts(&self) -> &[u64] {
+        &self.counts
+    }
+
+    /// Estimate of the value at quantile `q` (from 0 to 1); `None` with no observations
+    ///
+    /// The estimate is the upper bound of the bucket holding the `q`-th observation,
+    /// capped at the largest observation.
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((q.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut cumulative = 0;
+        for (bucket, count) in self.counts.iter().enumerate() {
+            cumulative += count;
+            if cumulative >= rank {
+                let upper = self.bounds.get(bucket).copied().unwrap_or(self.max);
+                return Some(upper.min(self.max));
+            }
+        }
+        Some(self.max)
+    }
+
+    /// p50, p90, p99, and max; `None` with no observations
+    pub fn percentiles(&self) -> Option<Percentiles> {
+        Some(Percentiles {
+            p50: self.quantile(0.5)?,
+            p90: self.quantile(0.9)?,
+            p99: self.quantile(0.99)?,
+            max: self.max,
+        })
+    }
+}
+
+/// Bucket bounds from the comma-separated numbers in the variable `name`; `None` when
+/// it is not set
+pub fn bounds_from_env(name: &str) -> Result<Option<Vec<f64>>> {
+    match std::env::var(name) {
+        Ok(value) if !value.trim().is_empty() => parse_bounds(&value)
+            .map(Some)
+            .map_err(|e| anyhow!("Invalid {}: {}", name, e)),
+        _ => Ok(None),
+    }
+}
+
+fn parse_bounds(value: &str) -> Result<Vec<f64>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|bound| !bound.is_empty())
+        .map(|bound| match bound.parse::<f64>() {
+            Ok(bound) if bound.is_finite() && bound > 0.0 => Ok(bound),
+            _ => bail!("bucket bound {:?} must be a positive number", bound),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bucket bounds around `value`: the bound below it, or 0, and the bound above it
+    fn bucket_of(bounds: &[f64], value: f64) -> (f64, f64) {
+        let bucket = bounds.partition_point(|bound| *bound < value);
+        let lower = if bucket == 0 { 0.0 } else { bounds[bucket - 1] };
+        (lower, bounds.get(bucket).copied().unwrap_or(f64::INFINITY))
+    }
+
+    #[test]
+    fn test_percentiles_are_within_bucket_resolution() {
+        // Synthetic latencies from 0.5ms to 5s, spread unevenly
+        let latencies: Vec<f64> = (1..=1000)
+            .map(|i| 0.5 * (i as f64 / 1000.0 * 10f64.ln() * 4.0).exp())
+            .collect();
+        let mut distribution = Distribution::latency();
+        for latency in &latencies {
+            distribution.observe(*latency);
+        }
+
+        let mut sorted = latencies.clone();
+        sorted.sort_by(f64::total_cmp);
+        for q in [0.5, 0.9, 0.99] {
+            let exact = sorted[(q * sorted.len() as f64).ceil() as usize - 1];
+            let estimate = distribution.quantile(q).unwrap();
+            let (lower, upper) = bucket_of(DEFAULT_LATENCY_BOUNDS_MS, exact);
+            assert!(
+                lower <= estimate && estimate <= upper && estimate >= exact,
+                "p{}: exact {} estimate {}",
+                q * 100.0,
+                exact,
+                estimate
+            );
+        }
+
+        let percentiles = distribution.percentiles().unwrap();
+        assert_eq!(sorted[999], percentiles.max);
+        assert!(percentiles.p50 <= percentiles.p90 && percentiles.p90 <= percentiles.p99);
+        assert_eq!(1000, distribution.count());
+        assert_eq!(1000, distribution.bucket_counts().iter().sum::<u64>());
+    }
+
+    #[test]
+    fn test_estimates_are_capped_at_the_largest_observation() {
+        let mut distribution = Distribution::size();
+        for size in [300.0, 310.0, 320.0] {
+            distribution.observe(size);
+        }
+        // All three fall in the 256B-512B bucket
+        assert_eq!(Some(320.0), distribution.quantile(0.5));
+
+        // Beyond the last bound, only the largest observation is known
+        let mut distribution = Distribution::size();
+        distribution.observe(4_000_000.0);
+        assert_eq!(Some(4_000_000.0), distribution.quantile(0.99));
+        assert_eq!(1, *distribution.bucket_counts().last().unwrap());
+
+        assert_eq!(None, Distribution::size().percentiles());
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut first = Distribution::new(vec![10.0, 100.0]);
+        let mut second = first.empty();
+        first.observe(5.0);
+        second.observe(50.0);
+        second.observe(500.0);
+
+        first.merge(&second).unwrap();
+        assert_eq!(&[1, 1, 1], first.bucket_counts());
+        assert_eq!(5.0, first.min());
+        assert_eq!(500.0, first.max());
+        assert_eq!(555.0, first.sum());
+
+        assert!(first.merge(&Distribution::size()).is_err());
+    }
+
+    #[test]
+    fn test_bounds_are_parsed() {
+        assert_eq!(vec![1.0, 2.5, 10.0], parse_bounds("1, 2.5,,10").unwrap());
+        assert!(parse_bounds("1,-2").is_err());
+        assert!(parse_bounds("1,fast").is_err());
+        // Given out of order, they are sorted
+        assert_eq!(&[1.0, 5.0], Distribution::new(vec![5.0, 1.0, 5.0]).bounds());
+    }
+}
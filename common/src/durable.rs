@@ -0,0 +1,114 @@
+//! Small state files that survive a crash or a power loss.
+//!
+//! Checkpoints, cursors, and watermarks decide where an ingestor resumes. Writing one
+//! in place, or renaming a temporary file over it without syncing, can leave an empty
+//! file after a power loss, which would read as "nothing saved yet" and resume from the
+//! start or the end of the source instead of from the last acknowledged position.
+//! [`replace_file`] leaves either the old contents or the new ones, and [`read_file`]
+//! refuses an empty file rather than treating it as a first start.
+
+use anyhow::{bail, Context, Result};
+use std::fs::File;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+/// Replace the file at `path` with `contents`, creating its directory if needed
+///
+/// The contents are written to `<path>.tmp` and synced, the temporary file is renamed
+/// over `path`, and the directory is synced. Until the rename, `path` holds its old
+/// contents; once this returns, it holds `contents` even after a power loss.
+pub fn replace_file(path: &Path, contents: &[u8]) -> Result<()> {
+    let dir = match path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        Some(dir) => dir,
+        None => Path::new("."),
+    };
+    std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+
+    let temporary = temporary_path(path)?;
+    let mut file = File::create(&temporary)
+        .with_context(|| format!("Failed to create {}", temporary.display()))?;
+    file.write_all(contents)
+        .and_then(|()| file.sync_data())
+        .with_context(|| format!("Failed to write {}", temporary.display()))?;
+    std::fs::rename(&temporary, path)
+        .with_context(|| format!("Failed to replace {}", path.display()))?;
+    sync_dir(dir)
+}
+
+/// The contents of the file at `path`, or `None` when there is no file
+///
+/// An empty file is an error: [`replace_file`] never leaves one behind, so it is a file
+/// that lost its contents, and starting over from it would skip or repeat the source.
+pub fn read_file(path: &Path) -> Result<Option<Vec<u8>>> {
+    match std::fs::read(path) {
+        Ok(contents) if contents.is_empty() => bail!(
+            "{} is empty; delete it to start over without it",
+            path.display()
+        ),
+        Ok(contents) => Ok(Some(contents)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+/// Make a created or renamed file's directory entry durable
+pub fn sync_dir(dir: &Path) -> Result<()> {
+    #[cfg(unix)]
+    File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .with_context(|| format!("Failed to sync {}", dir.display()))?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+/// `path` with `.tmp` added to its file name
+fn temporary_path(path: &Path) -> Result<PathBuf> {
+    let mut name = path
+        .file_name()
+        .with_context(|| format!("{} is not a file path", path.display()))?
+        .to_os_string();
+    name.push(".tmp");
+    Ok(path.with_file_name(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replace_and_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state").join("cursor");
+
+        assert_eq!(None, read_file(&path).unwrap());
+        replace_file(&path, b"s=1;i=4ece7\n").unwrap();
+        replace_file(&path, b"s=1;i=4ece9\n").unwrap();
+
+        assert_eq!(Some(b"s=1;i=4ece9\n".to_vec()), read_file(&path).unwrap());
+        let names: Vec<_> = std::fs::read_dir(path.parent().unwrap())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(vec!["cursor"], names);
+    }
+
+    #[test]
+    fn test_empty_file_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("checkpoints");
+        std::fs::write(&path, b"").unwrap();
+
+        let error = read_file(&path).unwrap_err();
+        assert!(error.to_string().contains("is empty"), "{}", error);
+    }
+
+    #[test]
+    fn test_temporary_path_keeps_the_extension() {
+        assert_eq!(
+            Path::new("state/watermarks.json.tmp"),
+            temporary_path(Path::new("state/watermarks.json")).unwrap()
+        );
+        assert!(temporary_path(Path::new("/")).is_err());
+    }
+}
@@ -0,0 +1,1756 @@
+//! Encode JSON values as protobuf records using only a runtime descriptor.
+//!
+//! The examples normally encode rows with structs generated by buf. Generic tools (bulk
+//! loaders, bridges) only learn the table's schema at runtime, so they hand JSON objects
+//! to a [`DynamicEncoder`] built from the same `DescriptorProto` the stream is created
+//! with. The output is byte-identical to what prost produces for the equivalent
+//! generated struct with proto2 semantics: fields in field number order whatever order
+//! they are declared in, map entries sorted by key, `null` and missing fields omitted,
+//! repeated scalars unpacked unless `[packed = true]`. Sorted map entries match prost's
+//! `btree_map` output; a `HashMap` field has no stable order of its own, so
+//! `testing::conformance` compares its entries in any order.
+//!
+//! The schema is resolved once, when the encoder is built, and every record reuses it.
+//! The same schema checks records that arrive already encoded, with
+//! [`DynamicEncoder::validate`], or with [`DynamicEncoder::check`] where the check is
+//! left to the operator (`VALIDATE_RECORDS`).
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{engine::general_purpose, Engine as _};
+use prost::encoding::{decode_key, decode_varint, encode_key, encode_varint, WireType};
+use prost_types::field_descriptor_proto::{Label, Type};
+use prost_types::{DescriptorProto, EnumDescriptorProto, FieldDescriptorProto};
+use serde_json::{Map, Value};
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::warn;
+
+/// Nesting depth at which [`DynamicEncoder::validate`] gives up, as prost does
+const MAX_VALIDATION_DEPTH: u32 = 100;
+
+#[cfg(test)]
+thread_local! {
+    /// Schemas prepared on this thread, so tests can check that records reuse one
+    static SCHEMAS_PREPARED: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// What to do with a field whose value cannot be converted to the field's type
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FieldErrorMode {
+    /// Fail the record
+    #[default]
+    Fail,
+    /// Leave the field unset and encode the rest of the record
+    Null,
+}
+
+impl FieldErrorMode {
+    /// Read `FIELD_ERROR_MODE`: `fail` (the default) or `null`
+    pub fn from_env() -> Result<Self> {
+        match std::env::var("FIELD_ERROR_MODE") {
+            Ok(mode) => match mode.trim().to_ascii_lowercase().as_str() {
+                "" | "fail" => Ok(Self::Fail),
+                "null" => Ok(Self::Null),
+                _ => bail!(
+                    "FIELD_ERROR_MODE must be \"fail\" or \"null\", got {:?}",
+                    mode
+                ),
+            },
+            Err(_) => Ok(Self::Fail),
+        }
+    }
+}
+
+/// How object keys are rewritten before they are matched against field names
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum KeyCase {
+    /// Match keys exactly
+    #[default]
+    AsIs,
+    /// `eventType` matches the field `event_type`
+    Snake,
+    /// `event_type` matches the field `eventType`
+    Camel,
+}
+
+impl KeyCase {
+    /// Read `KEY_CASE`: `as_is` (the default), `snake`, or `camel`
+    pub fn from_env() -> Result<Self> {
+        match std::env::var("KEY_CASE") {
+            Ok(case) => Self::new(&case),
+            Err(_) => Ok(Self::AsIs),
+        }
+    }
+
+    /// Parse a `KEY_CASE` value
+    pub fn new(case: &str) -> Result<Self> {
+        match case.trim().to_ascii_lowercase().as_str() {
+            "" | "as_is" => Ok(Self::AsIs),
+            "snake" => Ok(Self::Snake),
+            "camel" => Ok(Self::Camel),
+            _ => bail!(
+                "KEY_CASE must be \"snake\", \"camel\", or \"as_is\", got {:?}",
+                case
+            ),
+        }
+    }
+
+    /// The field name `key` stands for
+    pub fn apply<'a>(&self, key: &'a str) -> Cow<'a, str> {
+        match self {
+            Self::AsIs => Cow::Borrowed(key),
+            Self::Snake => Cow::Owned(to_snake_case(key)),
+            Self::Camel => Cow::Owned(to_camel_case(key)),
+        }
+    }
+}
+
+/// Read `COERCE`, the setting for [`DynamicEncoder::coerce_types`]: `true` (the
+/// default) or `false`
+pub fn coerce_from_env() -> Result<bool> {
+    match std::env::var("COERCE") {
+        Ok(value) => match value.trim().to_ascii_lowercase().as_str() {
+            "" | "true" | "1" => Ok(true),
+            "false" | "0" => Ok(false),
+            _ => bail!("COERCE must be \"true\" or \"false\", got {:?}", value),
+        },
+        Err(_) => Ok(true),
+    }
+}
+
+/// Read `VALIDATE_RECORDS`, the setting for [`DynamicEncoder::validate_records`]:
+/// `false` (the default) or `true`
+pub fn validate_records_from_env() -> Result<bool> {
+    match std::env::var("VALIDATE_RECORDS") {
+        Ok(value) => match value.trim().to_ascii_lowercase().as_str() {
+            "" | "false" | "0" => Ok(false),
+            "true" | "1" => Ok(true),
+            _ => bail!(
+                "VALIDATE_RECORDS must be \"true\" or \"false\", got {:?}",
+                value
+            ),
+        },
+        Err(_) => Ok(false),
+    }
+}
+
+/// Type of a single (non-repeated) value
+#[derive(Debug, Clone, PartialEq)]
+enum Kind {
+    Double,
+    Float,
+    Int64,
+    Uint64,
+    Int32,
+    Fixed64,
+    Fixed32,
+    Bool,
+    String,
+    Bytes,
+    Uint32,
+    Sfixed32,
+    Sfixed64,
+    Sint32,
+    Sint64,
+    /// Index into [`DynamicEncoder::enums`]
+    Enum(usize),
+    /// Index into [`DynamicEncoder::messages`]
+    Message(usize),
+}
+
+impl Kind {
+    fn wire_type(&self) -> WireType {
+        match self {
+            Kind::Double | Kind::Fixed64 | Kind::Sfixed64 => WireType::SixtyFourBit,
+            Kind::Float | Kind::Fixed32 | Kind::Sfixed32 => WireType::ThirtyTwoBit,
+            Kind::String | Kind::Bytes | Kind::Message(_) => WireType::LengthDelimited,
+            _ => WireType::Varint,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Cardinality {
+    Single,
+    Repeated {
+        packed: bool,
+    },
+    /// A `map<K, V>` field, encoded as repeated key/value entry messages
+    Map {
+        key: Kind,
+        value: Kind,
+    },
+}
+
+#[derive(Debug, Clone)]
+struct Field {
+    name: String,
+    number: u32,
+    kind: Kind,
+    cardinality: Cardinality,
+}
+
+impl Field {
+    /// Whether the field's values are messages, which check their own fields
+    fn holds_messages(&self) -> bool {
+        match &self.cardinality {
+            Cardinality::Map { value, .. } => matches!(value, Kind::Message(_)),
+            _ => matches!(self.kind, Kind::Message(_)),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct MessageSchema {
+    name: String,
+    fields: Vec<Field>,
+    by_name: HashMap<String, usize>,
+    by_number: HashMap<u32, usize>,
+}
+
+#[derive(Debug, Default)]
+struct EnumSchema {
+    values: HashMap<String, i32>,
+}
+
+/// A value coerced to the representation of its field type
+#[derive(Debug, PartialEq)]
+enum Scalar {
+    Signed(i64),
+    Unsigned(u64),
+    Float(f32),
+    Double(f64),
+    Bool(bool),
+    /// Strings, bytes, and already encoded messages
+    Bytes(Vec<u8>),
+}
+
+impl Scalar {
+    /// Whether prost would treat the value as the type's default (map entries skip those)
+    fn is_default(&self) -> bool {
+        match self {
+            Scalar::Signed(v) => *v == 0,
+            Scalar::Unsigned(v) => *v == 0,
+            Scalar::Float(v) => *v == 0.0,
+            Scalar::Double(v) => *v == 0.0,
+            Scalar::Bool(v) => !*v,
+            Scalar::Bytes(v) => v.is_empty(),
+        }
+    }
+}
+
+/// Encodes JSON objects as records of one message type
+#[derive(Debug)]
+pub struct DynamicEncoder {
+    /// The root message is always index 0
+    messages: Vec<MessageSchema>,
+    enums: Vec<EnumSchema>,
+    ignore_unknown_fields: bool,
+    warn_unknown_fields: bool,
+    /// Records encoded with fields that were dropped
+    records_with_unknown_fields: AtomicU64,
+    coerce_types: bool,
+    field_error_mode: FieldErrorMode,
+    /// Fields left unset because their values could not be converted
+    fields_left_unset: AtomicU64,
+    validate_records: bool,
+    /// Records checked by [`DynamicEncoder::validate`]
+    records_validated: AtomicU64,
+    key_case: KeyCase,
+}
+
+impl DynamicEncoder {
+    /// Build an encoder for `descriptor` and the types nested inside it
+    ///
+    /// Fails if a field refers to a message or enum that is not nested in the
+    /// descriptor; Zerobus descriptors are always self-contained.
+    pub fn new(descriptor: &DescriptorProto) -> Result<Self> {
+        let root = descriptor.name.clone().unwrap_or_default();
+        let mut message_names = Vec::new();
+        let mut enum_names = Vec::new();
+        collect_types(descriptor, &root, &mut message_names, &mut enum_names);
+
+        let enums = enum_names
+            .iter()
+            .map(|(_, enum_type)| EnumSchema {
+                values: enum_type
+                    .value
+                    .iter()
+                    .map(|v| (v.name().to_string(), v.number()))
+                    .collect(),
+            })
+            .collect();
+
+        let mut messages = Vec::with_capacity(message_names.len());
+        for (name, message) in &message_names {
+            let mut schema = MessageSchema {
+                name: name.clone(),
+                ..Default::default()
+            };
+            schema.fields = message
+                .field
+                .iter()
+                .map(|field| resolve_field(field, &message_names, &enum_names))
+                .collect::<Result<_>>()
+                .with_context(|| format!("Unsupported field in {}", name))?;
+            // prost writes fields by number, not in the order they are declared
+            schema.fields.sort_by_key(|field| field.number);
+            for (position, field) in schema.fields.iter().enumerate() {
+                schema.by_name.insert(field.name.clone(), position);
+                schema.by_number.insert(field.number, position);
+            }
+            messages.push(schema);
+        }
+        #[cfg(test)]
+        SCHEMAS_PREPARED.with(|prepared| prepared.set(prepared.get() + 1));
+
+        Ok(Self {
+            messages,
+            enums,
+            ignore_unknown_fields: false,
+            warn_unknown_fields: false,
+            records_with_unknown_fields: AtomicU64::new(0),
+            coerce_types: true,
+            field_error_mode: FieldErrorMode::Fail,
+            fields_left_unset: AtomicU64::new(0),
+            validate_records: false,
+            records_validated: AtomicU64::new(0),
+            key_case: KeyCase::AsIs,
+        })
+    }
+
+    /// Skip object keys that are not fields of the message instead of failing
+    pub fn ignore_unknown_fields(mut self, ignore: bool) -> Self {
+        self.ignore_unknown_fields = ignore;
+        self
+    }
+
+    /// Log every record whose unknown fields are dropped, naming the fields
+    ///
+    /// Only matters when unknown fields are ignored; otherwise they fail the record.
+    /// Drops are also counted in [`DynamicEncoder::records_with_unknown_fields`].
+    pub fn warn_unknown_fields(mut self, warn: bool) -> Self {
+        self.warn_unknown_fields = warn;
+        self
+    }
+
+    /// Number of records encoded so far that had fields dropped, when warning about them
+    pub fn records_with_unknown_fields(&self) -> u64 {
+        self.records_with_unknown_fields.load(Ordering::Relaxed)
+    }
+
+    /// Convert strings to numbers and booleans, and numbers to booleans, for fields of
+    /// those types (the default)
+    ///
+    /// Text-based sources such as CSV need this. Turned off, a number or boolean field
+    /// only takes a JSON value of its own type, and anything else is an error handled
+    /// by [`DynamicEncoder::field_error_mode`].
+    pub fn coerce_types(mut self, coerce: bool) -> Self {
+        self.coerce_types = coerce;
+        self
+    }
+
+    /// What to do with a value that cannot be converted to its field's type; failing
+    /// the record by default
+    ///
+    /// With [`FieldErrorMode::Null`] the field is left unset and a warning is logged.
+    /// Only scalar fields are left unset: a nested message handles its own fields, and
+    /// unknown fields are governed by [`DynamicEncoder::ignore_unknown_fields`].
+    pub fn field_error_mode(mut self, mode: FieldErrorMode) -> Self {
+        self.field_error_mode = mode;
+        self
+    }
+
+    /// Number of fields left unset so far under [`FieldErrorMode::Null`]
+    pub fn fields_left_unset(&self) -> u64 {
+        self.fields_left_unset.load(Ordering::Relaxed)
+    }
+
+    /// Have [`DynamicEncoder::check`] validate records; off by default
+    ///
+    /// Validation costs a pass over every record, which a producer that is trusted to
+    /// encode its records correctly can skip. A record that does not match the table's
+    /// message is then only rejected by the server, failing its stream.
+    pub fn validate_records(mut self, validate: bool) -> Self {
+        self.validate_records = validate;
+        self
+    }
+
+    /// Number of records checked by [`DynamicEncoder::validate`] so far
+    pub fn records_validated(&self) -> u64 {
+        self.records_validated.load(Ordering::Relaxed)
+    }
+
+    /// Rewrite object keys to `case` before matching them to fields; they are matched
+    /// exactly by default
+    ///
+    /// Applies to the keys of nested messages too, but not to the keys of map fields,
+    /// which are data. Two keys that rewrite to the same field fail the record.
+    pub fn key_case(mut self, case: KeyCase) -> Self {
+        self.key_case = case;
+        self
+    }
+
+    /// Keys of `value` that are not fields of the message, as dotted paths
+    ///
+    /// Objects inside message fields are checked too, so a stray key in a nested
+    /// struct is reported as `address.unit`.
+    pub fn unknown_fields(&self, value: &Value) -> Vec<String> {
+        let mut unknown = BTreeSet::new();
+        if let Some(object) = value.as_object() {
+            self.collect_unknown_fields(0, object, "", &mut unknown);
+        }
+        unknown.into_iter().collect()
+    }
+
+    fn collect_unknown_fields(
+        &self,
+        index: usize,
+        object: &Map<String, Value>,
+        prefix: &str,
+        unknown: &mut BTreeSet<String>,
+    ) {
+        let schema = &self.messages[index];
+        for (key, value) in object {
+            let path = format!("{}{}", prefix, key);
+            let Some(&position) = schema.by_name.get(self.key_case.apply(key).as_ref()) else {
+                unknown.insert(path);
+                continue;
+            };
+            let field = &schema.fields[position];
+            let Kind::Message(nested) = field.kind else {
+                continue;
+            };
+            let prefix = format!("{}.", path);
+            match (&field.cardinality, value) {
+                (Cardinality::Single, Value::Object(object)) => {
+                    self.collect_unknown_fields(nested, object, &prefix, unknown);
+                }
+                (Cardinality::Repeated { .. }, Value::Array(items)) => {
+                    for object in items.iter().filter_map(Value::as_object) {
+                        self.collect_unknown_fields(nested, object, &prefix, unknown);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Whether the root message has a field called `name`
+    pub fn has_field(&self, name: &str) -> bool {
+        self.messages[0].by_name.contains_key(name)
+    }
+
+    /// Encode a JSON object as one record
+    ///
+    /// Scalars are coerced leniently so text-based sources work: numbers and booleans
+    /// may be given as strings unless [`DynamicEncoder::coerce_types`] is off, string
+    /// fields accept any value (non-strings are stored as JSON), and bytes fields take
+    /// base64.
+    pub fn encode(&self, value: &Value) -> Result<Vec<u8>> {
+        let object = value
+            .as_object()
+            .ok_or_else(|| anyhow!("Expected a JSON object, got {}", type_name(value)))?;
+        let record = self.encode_message(0, object)?;
+
+        if self.warn_unknown_fields && self.ignore_unknown_fields {
+            let unknown = self.unknown_fields(value);
+            if !unknown.is_empty() {
+                self.records_with_unknown_fields
+                    .fetch_add(1, Ordering::Relaxed);
+                warn!(
+                    "Dropped fields not in {}: {}",
+                    self.messages[0].name,
+                    unknown.join(", ")
+                );
+            }
+        }
+        Ok(record)
+    }
+
+    /// Check that an already encoded record is a valid instance of the message
+    ///
+    /// For records that arrive encoded, which would otherwise only be rejected by the
+    /// server. Uses the schema prepared by [`DynamicEncoder::new`], so a record costs one
+    /// pass over its bytes however often this is called. Every field must be declared
+    /// with a matching wire type, strings must be UTF-8, and nested messages are checked
+    /// the same way. Repeated numbers are accepted packed or not, as any parser does.
+    pub fn validate(&self, record: &[u8]) -> Result<()> {
+        self.records_validated.fetch_add(1, Ordering::Relaxed);
+        self.validate_message(0, record, 0)
+    }
+
+    /// [`DynamicEncoder::validate`] an already encoded record when
+    /// [`DynamicEncoder::validate_records`] is on; accept it unchecked otherwise
+    pub fn check(&self, record: &[u8]) -> Result<()> {
+        if self.validate_records {
+            self.validate(record)?;
+        }
+        Ok(())
+    }
+
+    fn validate_message(&self, index: usize, mut buf: &[u8], depth: u32) -> Result<()> {
+        let schema = &self.messages[index];
+        if depth > MAX_VALIDATION_DEPTH {
+            bail!("Messages nested more than {} deep", MAX_VALIDATION_DEPTH);
+        }
+        while !buf.is_empty() {
+            let (number, wire_type) = decode_key(&mut buf)
+                .map_err(|e| anyhow!("Invalid field key in {}: {}", schema.name, e))?;
+            let Some(&position) = schema.by_number.get(&number) else {
+                bail!(
+                    "Unknown field number {} for message {}",
+                    number,
+                    schema.name
+                );
+            };
+            let field = &schema.fields[position];
+            self.validate_field(field, wire_type, &mut buf, depth)
+                .with_context(|| format!("Field {:?}", field.name))?;
+        }
+        Ok(())
+    }
+
+    /// Check one value of `field` and advance `buf` past it
+    fn validate_field(
+        &self,
+        field: &Field,
+        wire_type: WireType,
+        buf: &mut &[u8],
+        depth: u32,
+    ) -> Result<()> {
+        let expected = field.kind.wire_type();
+        if wire_type != expected {
+            let packable = matches!(field.cardinality, Cardinality::Repeated { .. })
+                && expected != WireType::LengthDelimited;
+            if !(packable && wire_type == WireType::LengthDelimited) {
+                bail!("Expected wire type {:?}, got {:?}", expected, wire_type);
+            }
+            let mut packed = take_length_delimited(buf)?;
+            while !packed.is_empty() {
+                skip_fixed_or_varint(expected, &mut packed)?;
+            }
+            return Ok(());
+        }
+
+        if expected != WireType::LengthDelimited {
+            return skip_fixed_or_varint(expected, buf);
+        }
+        let bytes = take_length_delimited(buf)?;
+        match field.kind {
+            Kind::String => {
+                std::str::from_utf8(bytes).context("String is not valid UTF-8")?;
+            }
+            Kind::Message(nested) => self.validate_message(nested, bytes, depth + 1)?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn encode_message(&self, index: usize, object: &Map<String, Value>) -> Result<Vec<u8>> {
+        let schema = &self.messages[index];
+        let renamed = self.rename_keys(object)?;
+        if !self.ignore_unknown_fields {
+            let unknown = match &renamed {
+                Some(renamed) => renamed
+                    .iter()
+                    .find(|(name, _)| !schema.by_name.contains_key(name.as_str()))
+                    .map(|(_, (key, _))| *key),
+                None => object
+                    .keys()
+                    .find(|key| !schema.by_name.contains_key(*key))
+                    .map(String::as_str),
+            };
+            if let Some(unknown) = unknown {
+                bail!("Unknown field {:?} for message {}", unknown, schema.name);
+            }
+        }
+
+        let mut buf = Vec::new();
+        for field in &schema.fields {
+            let value = match &renamed {
+                Some(renamed) => renamed.get(&field.name).map(|(_, value)| *value),
+                None => object.get(&field.name),
+            };
+            let Some(value) = value.filter(|value| !value.is_null()) else {
+                continue;
+            };
+            // Values are all converted before anything is written, so a field that fails
+            // leaves `buf` as it was
+            let Err(e) = self.encode_field(field, value, &mut buf) else {
+                continue;
+            };
+            let e = e.context(format!("Field {:?}", field.name));
+            if self.field_error_mode == FieldErrorMode::Fail || field.holds_messages() {
+                return Err(e);
+            }
+            self.fields_left_unset.fetch_add(1, Ordering::Relaxed);
+            warn!("Leaving a field of {} unset: {:#}", schema.name, e);
+        }
+        Ok(buf)
+    }
+
+    /// The keys and values of `object` by the field names the keys stand for, unless
+    /// keys are matched as they are
+    fn rename_keys<'a>(
+        &self,
+        object: &'a Map<String, Value>,
+    ) -> Result<Option<HashMap<String, (&'a str, &'a Value)>>> {
+        if self.key_case == KeyCase::AsIs {
+            return Ok(None);
+        }
+        let mut renamed = HashMap::with_capacity(object.len());
+        for (key, value) in object {
+            let name = self.key_case.apply(key).into_owned();
+            if let Some((other, _)) = renamed.insert(name.clone(), (key.as_str(), value)) {
+                bail!(
+                    "Keys {:?} and {:?} both name the field {:?}",
+                    other,
+                    key,
+                    name
+                );
+            }
+        }
+        Ok(Some(renamed))
+    }
+
+    fn encode_field(&self, field: &Field, value: &Value, buf: &mut Vec<u8>) -> Result<()> {
+        match &field.cardinality {
+            Cardinality::Single => {
+                let scalar = self.coerce(&field.kind, value)?;
+                encode_tagged(field.number, &field.kind, &scalar, buf);
+            }
+            Cardinality::Repeated { packed } => {
+                let items = value
+                    .as_array()
+                    .ok_or_else(|| anyhow!("Expected an array, got {}", type_name(value)))?;
+                let scalars = items
+                    .iter()
+                    .enumerate()
+                    .map(|(i, item)| {
+                        self.coerce(&field.kind, item)
+                            .with_context(|| format!("Element {}", i))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                if *packed && field.kind.wire_type() != WireType::LengthDelimited {
+                    if scalars.is_empty() {
+                        return Ok(());
+                    }
+                    let mut packed = Vec::new();
+                    for scalar in &scalars {
+                        encode_raw(&field.kind, scalar, &mut packed);
+                    }
+                    encode_key(field.number, WireType::LengthDelimited, buf);
+                    encode_varint(packed.len() as u64, buf);
+                    buf.extend_from_slice(&packed);
+                } else {
+                    for scalar in &scalars {
+                        encode_tagged(field.number, &field.kind, scalar, buf);
+                    }
+                }
+            }
+            Cardinality::Map { key, value: kind } => {
+                let entries = value
+                    .as_object()
+                    .ok_or_else(|| anyhow!("Expected an object, got {}", type_name(value)))?;
+                let mut coerced = entries
+                    .iter()
+                    .map(|(k, v)| {
+                        // Keys are always strings in JSON, whatever their type
+                        let key_scalar = self.convert(key, &Value::String(k.clone()))?;
+                        let value_scalar = match v {
+                            // A null value is the value type's default
+                            Value::Null => None,
+                            v => Some(self.coerce(kind, v)?),
+                        };
+                        Ok((key_scalar, value_scalar))
+                    })
+                    .collect::<Result<Vec<_>>>()
+                    .context("Invalid map entry")?;
+                // Match prost's BTreeMap ordering, which sorts by the typed key
+                coerced.sort_by(|(a, _), (b, _)| compare_keys(a, b));
+
+                for (key_scalar, value_scalar) in coerced {
+                    let mut entry = Vec::new();
+                    // Like prost, leave out keys and values equal to their defaults
+                    if !key_scalar.is_default() {
+                        encode_tagged(1, key, &key_scalar, &mut entry);
+                    }
+                    if let Some(value_scalar) = value_scalar.filter(|v| !v.is_default()) {
+                        encode_tagged(2, kind, &value_scalar, &mut entry);
+                    }
+                    encode_key(field.number, WireType::LengthDelimited, buf);
+                    encode_varint(entry.len() as u64, buf);
+                    buf.extend_from_slice(&entry);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Convert a JSON value to the representation of `kind`, checking its JSON type
+    /// first unless types are coerced
+    fn coerce(&self, kind: &Kind, value: &Value) -> Result<Scalar> {
+        if !self.coerce_types {
+            check_json_type(kind, value)?;
+        }
+        self.convert(kind, value)
+    }
+
+    /// Convert a JSON value to the representation of `kind`
+    fn convert(&self, kind: &Kind, value: &Value) -> Result<Scalar> {
+        Ok(match kind {
+            Kind::Double => Scalar::Double(to_f64(value)?),
+            Kind::Float => Scalar::Float(to_f64(value)? as f32),
+            Kind::Int64 | Kind::Sint64 | Kind::Sfixed64 => Scalar::Signed(to_i64(value)?),
+            Kind::Int32 | Kind::Sint32 | Kind::Sfixed32 => {
+                let v = to_i64(value)?;
+                i32::try_from(v).with_context(|| format!("{} does not fit in an int32", v))?;
+                Scalar::Signed(v)
+            }
+            Kind::Uint64 | Kind::Fixed64 => Scalar::Unsigned(to_u64(value)?),
+            Kind::Uint32 | Kind::Fixed32 => {
+                let v = to_u64(value)?;
+                u32::try_from(v).with_context(|| format!("{} does not fit in a uint32", v))?;
+                Scalar::Unsigned(v)
+            }
+            Kind::Bool => Scalar::Bool(to_bool(value)?),
+            Kind::String => Scalar::Bytes(match value {
+                Value::String(s) => s.as_bytes().to_vec(),
+                other => other.to_string().into_bytes(),
+            }),
+            Kind::Bytes => match value {
+                Value::String(s) => Scalar::Bytes(
+                    general_purpose::STANDARD
+                        .decode(s)
+                        .context("Bytes must be base64")?,
+                ),
+                other => bail!("Expected a base64 string, got {}", type_name(other)),
+            },
+            Kind::Enum(index) => Scalar::Signed(match value {
+                Value::String(name) => match self.enums[*index].values.get(name) {
+                    Some(number) => i64::from(*number),
+                    None => {
+                        to_i64(value).with_context(|| format!("Unknown enum value {:?}", name))?
+                    }
+                },
+                other => to_i64(other)?,
+            }),
+            Kind::Message(index) => {
+                let object = value
+                    .as_object()
+                    .ok_or_else(|| anyhow!("Expected an object, got {}", type_name(value)))?;
+                Scalar::Bytes(self.encode_message(*index, object)?)
+            }
+        })
+    }
+}
+
+/// Register `message` and every type nested in it under its dotted path
+fn collect_types<'a>(
+    message: &'a DescriptorProto,
+    path: &str,
+    messages: &mut Vec<(String, &'a DescriptorProto)>,
+    enums: &mut Vec<(String, &'a EnumDescriptorProto)>,
+) {
+    messages.push((path.to_string(), message));
+    for enum_type in &message.enum_type {
+        enums.push((format!("{}.{}", path, enum_type.name()), enum_type));
+    }
+    for nested in &message.nested_type {
+        collect_types(
+            nested,
+            &format!("{}.{}", path, nested.name()),
+            messages,
+            enums,
+        );
+    }
+}
+
+/// Find the type a `type_name` such as `.pkg.table_x.Nested` refers to
+///
+/// Type names are fully qualified with the package, which a lone `DescriptorProto`
+/// does not carry, so the longest registered path the name ends with wins.
+fn find_type<T>(type_name: &str, types: &[(String, T)]) -> Option<usize> {
+    let type_name = type_name.trim_start_matches('.');
+    types
+        .iter()
+        .enumerate()
+        .filter(|(_, (path, _))| {
+            type_name == path
+                || type_name
+                    .strip_suffix(path.as_str())
+                    .is_some_and(|prefix| prefix.ends_with('.'))
+        })
+        .max_by_key(|(_, (path, _))| path.len())
+        .map(|(index, _)| index)
+}
+
+fn resolve_field(
+    field: &FieldDescriptorProto,
+    messages: &[(String, &DescriptorProto)],
+    enums: &[(String, &EnumDescriptorProto)],
+) -> Result<Field> {
+    let name = field.name().to_string();
+    let number = u32::try_from(field.number())
+        .ok()
+        .filter(|n| *n > 0)
+        .with_context(|| format!("Field {:?} has invalid number {}", name, field.number()))?;
+
+    let kind = match field.r#type() {
+        Type::Double => Kind::Double,
+        Type::Float => Kind::Float,
+        Type::Int64 => Kind::Int64,
+        Type::Uint64 => Kind::Uint64,
+        Type::Int32 => Kind::Int32,
+        Type::Fixed64 => Kind::Fixed64,
+        Type::Fixed32 => Kind::Fixed32,
+        Type::Bool => Kind::Bool,
+        Type::String => Kind::String,
+        Type::Bytes => Kind::Bytes,
+        Type::Uint32 => Kind::Uint32,
+        Type::Sfixed32 => Kind::Sfixed32,
+        Type::Sfixed64 => Kind::Sfixed64,
+        Type::Sint32 => Kind::Sint32,
+        Type::Sint64 => Kind::Sint64,
+        Type::Enum => Kind::Enum(
+            find_type(field.type_name(), enums)
+                .with_context(|| format!("Enum {} not found", field.type_name()))?,
+        ),
+        Type::Message => Kind::Message(
+            find_type(field.type_name(), messages)
+                .with_context(|| format!("Message {} not found", field.type_name()))?,
+        ),
+        Type::Group => bail!("Field {:?} is a group, which is not supported", name),
+    };
+
+    let repeated = field.label() == Label::Repeated;
+    let cardinality = match &kind {
+        Kind::Message(index) if repeated && is_map_entry(messages[*index].1) => {
+            let entry = messages[*index].1;
+            let key = entry.field.iter().find(|f| f.number() == 1);
+            let value = entry.field.iter().find(|f| f.number() == 2);
+            match (key, value) {
+                (Some(key), Some(value)) => Cardinality::Map {
+                    key: resolve_field(key, messages, enums)?.kind,
+                    value: resolve_field(value, messages, enums)?.kind,
+                },
+                _ => bail!("Map entry for {:?} is missing its key or value", name),
+            }
+        }
+        _ if repeated => Cardinality::Repeated {
+            packed: field
+                .options
+                .as_ref()
+                .and_then(|o| o.packed)
+                .unwrap_or(false),
+        },
+        _ => Cardinality::Single,
+    };
+
+    Ok(Field {
+        name,
+        number,
+        kind,
+        cardinality,
+    })
+}
+
+fn is_map_entry(message: &DescriptorProto) -> bool {
+    message
+        .options
+        .as_ref()
+        .and_then(|options| options.map_entry)
+        .unwrap_or(false)
+}
+
+/// Write a field key followed by the value
+fn encode_tagged(number: u32, kind: &Kind, scalar: &Scalar, buf: &mut Vec<u8>) {
+    encode_key(number, kind.wire_type(), buf);
+    encode_raw(kind, scalar, buf);
+}
+
+/// Write a value without its key, as it appears inside a packed field
+fn encode_raw(kind: &Kind, scalar: &Scalar, buf: &mut Vec<u8>) {
+    match (kind, scalar) {
+        (Kind::Sint32, Scalar::Signed(v)) => {
+            let v = *v as i32;
+            encode_varint(((v << 1) ^ (v >> 31)) as u32 as u64, buf);
+        }
+        (Kind::Sint64, Scalar::Signed(v)) => encode_varint(((v << 1) ^ (v >> 63)) as u64, buf),
+        (Kind::Sfixed32, Scalar::Signed(v)) => buf.extend_from_slice(&(*v as i32).to_le_bytes()),
+        (Kind::Sfixed64, Scalar::Signed(v)) => buf.extend_from_slice(&v.to_le_bytes()),
+        // int32 and enums are sign-extended to 64 bits, as in prost
+        (_, Scalar::Signed(v)) => encode_varint(*v as u64, buf),
+        (Kind::Fixed32, Scalar::Unsigned(v)) => buf.extend_from_slice(&(*v as u32).to_le_bytes()),
+        (Kind::Fixed64, Scalar::Unsigned(v)) => buf.extend_from_slice(&v.to_le_bytes()),
+        (_, Scalar::Unsigned(v)) => encode_varint(*v, buf),
+        (_, Scalar::Float(v)) => buf.extend_from_slice(&v.to_le_bytes()),
+        (_, Scalar::Double(v)) => buf.extend_from_slice(&v.to_le_bytes()),
+        (_, Scalar::Bool(v)) => encode_varint(u64::from(*v), buf),
+        (_, Scalar::Bytes(v)) => {
+            encode_varint(v.len() as u64, buf);
+            buf.extend_from_slice(v);
+        }
+    }
+}
+
+/// Advance `buf` past a varint or fixed-width value
+fn skip_fixed_or_varint(wire_type: WireType, buf: &mut &[u8]) -> Result<()> {
+    match wire_type {
+        WireType::Varint => {
+            decode_varint(buf).map_err(|e| anyhow!("Invalid varint: {}", e))?;
+        }
+        WireType::SixtyFourBit => {
+            take(buf, 8)?;
+        }
+        WireType::ThirtyTwoBit => {
+            take(buf, 4)?;
+        }
+        other => bail!("Unexpected wire type {:?}", other),
+    }
+    Ok(())
+}
+
+/// Split a length-prefixed value off the front of `buf`
+fn take_length_delimited<'a>(buf: &mut &'a [u8]) -> Result<&'a [u8]> {
+    let len = decode_varint(buf).map_err(|e| anyhow!("Invalid length: {}", e))?;
+    take(buf, usize::try_from(len).unwrap_or(usize::MAX))
+}
+
+/// Split `len` bytes off the front of `buf`
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if buf.len() < len {
+        bail!(
+            "Record ends {} bytes into a value of {} bytes",
+            buf.len(),
+            len
+        );
+    }
+    let (value, rest) = buf.split_at(len);
+    *buf = rest;
+    Ok(value)
+}
+
+fn compare_keys(a: &Scalar, b: &Scalar) -> std::cmp::Ordering {
+    match (a, b) {
+        (Scalar::Signed(a), Scalar::Signed(b)) => a.cmp(b),
+        (Scalar::Unsigned(a), Scalar::Unsigned(b)) => a.cmp(b),
+        (Scalar::Bool(a), Scalar::Bool(b)) => a.cmp(b),
+        (Scalar::Bytes(a), Scalar::Bytes(b)) => a.cmp(b),
+        _ => std::cmp::Ordering::Equal,
+    }
+}
+
+/// `eventType` and `HTTPStatus` as `event_type` and `http_status`
+fn to_snake_case(key: &str) -> String {
+    let chars: Vec<char> = key.chars().collect();
+    let mut snake = String::with_capacity(key.len() + 4);
+    for (i, &c) in chars.iter().enumerate() {
+        if !c.is_uppercase() {
+            snake.push(c);
+            continue;
+        }
+        // A word starts at an upper-case letter after a lower-case one or a digit, and
+        // at the last letter of an acronym followed by a lower-case one
+        let boundary = match i.checked_sub(1).map(|p| chars[p]) {
+            Some(prev) if prev.is_lowercase() || prev.is_ascii_digit() => true,
+            Some(prev) if prev.is_uppercase() => {
+                matches!(chars.get(i + 1), Some(next) if next.is_lowercase())
+            }
+            _ => false,
+        };
+        if boundary {
+            snake.push('_');
+        }
+        snake.extend(c.to_lowercase());
+    }
+    snake
+}
+
+/// `event_type` as `eventType`; leading underscores are kept
+fn to_camel_case(key: &str) -> String {
+    let mut camel = String::with_capacity(key.len());
+    let mut upper = false;
+    for c in key.chars() {
+        if c == '_' && !camel.trim_start_matches('_').is_empty() {
+            upper = true;
+        } else if upper {
+            camel.extend(c.to_uppercase());
+            upper = false;
+        } else {
+            camel.push(c);
+        }
+    }
+    camel
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "an array",
+        Value::Object(_) => "an object",
+    }
+}
+
+/// Fail unless a number or boolean field was given a JSON value of its own type
+fn check_json_type(kind: &Kind, value: &Value) -> Result<()> {
+    let (matches, expected) = match kind {
+        Kind::Bool => (value.is_boolean(), "a boolean"),
+        Kind::Double
+        | Kind::Float
+        | Kind::Int64
+        | Kind::Uint64
+        | Kind::Int32
+        | Kind::Fixed64
+        | Kind::Fixed32
+        | Kind::Uint32
+        | Kind::Sfixed32
+        | Kind::Sfixed64
+        | Kind::Sint32
+        | Kind::Sint64 => (value.is_number(), "a number"),
+        _ => return Ok(()),
+    };
+    if !matches {
+        bail!(
+            "Expected {}, got {} (types are not coerced)",
+            expected,
+            type_name(value)
+        );
+    }
+    Ok(())
+}
+
+fn to_i64(value: &Value) -> Result<i64> {
+    match value {
+        Value::Number(n) => n
+            .as_i64()
+            .or_else(|| {
+                n.as_f64()
+                    .filter(|f| f.fract() == 0.0 && f.abs() < 9.2e18)
+                    .map(|f| f as i64)
+            })
+            .with_context(|| format!("{} is not a 64-bit integer", n)),
+        Value::String(s) => s
+            .trim()
+            .parse()
+            .with_context(|| format!("{:?} is not an integer", s)),
+        Value::Bool(b) => Ok(i64::from(*b)),
+        other => bail!("Expected an integer, got {}", type_name(other)),
+    }
+}
+
+fn to_u64(value: &Value) -> Result<u64> {
+    match value {
+        Value::Number(n) => n
+            .as_u64()
+            .with_context(|| format!("{} is not an unsigned 64-bit integer", n)),
+        Value::String(s) => s
+            .trim()
+            .parse()
+            .with_context(|| format!("{:?} is not an unsigned integer", s)),
+        Value::Bool(b) => Ok(u64::from(*b)),
+        other => bail!("Expected an unsigned integer, got {}", type_name(other)),
+    }
+}
+
+fn to_f64(value: &Value) -> Result<f64> {
+    match value {
+        Value::Number(n) => n.as_f64().with_context(|| format!("{} is not a number", n)),
+        // Also covers "NaN", "inf", and "-inf", which JSON numbers cannot express
+        Value::String(s) => s
+            .trim()
+            .parse()
+            .with_context(|| format!("{:?} is not a number", s)),
+        other => bail!("Expected a number, got {}", type_name(other)),
+    }
+}
+
+fn to_bool(value: &Value) -> Result<bool> {
+    match value {
+        Value::Bool(b) => Ok(*b),
+        Value::String(s) => match s.trim().to_ascii_lowercase().as_str() {
+            "true" | "1" => Ok(true),
+            "false" | "0" => Ok(false),
+            _ => bail!("{:?} is not a boolean", s),
+        },
+        Value::Number(n) => match n.as_u64() {
+            Some(0) => Ok(false),
+            Some(1) => Ok(true),
+            _ => bail!("{} is not a boolean", n),
+        },
+        other => bail!("Expected a boolean, got {}", type_name(other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::collection::{btree_map, vec};
+    use proptest::prelude::*;
+    use prost::Message;
+    use prost_types::{FieldOptions, MessageOptions};
+    use serde_json::json;
+    use std::collections::BTreeMap;
+
+    /// Hand-written equivalent of what prost-build generates for the test descriptor
+    #[derive(Clone, PartialEq, Message)]
+    struct Row {
+        #[prost(string, optional, tag = "1")]
+        name: Option<String>,
+        #[prost(int64, optional, tag = "2")]
+        count: Option<i64>,
+        #[prost(int32, optional, tag = "3")]
+        delta: Option<i32>,
+        #[prost(double, optional, tag = "4")]
+        ratio: Option<f64>,
+        #[prost(bool, optional, tag = "5")]
+        active: Option<bool>,
+        #[prost(bytes = "vec", optional, tag = "6")]
+        payload: Option<Vec<u8>>,
+        #[prost(string, repeated, tag = "7")]
+        tags: Vec<String>,
+        #[prost(sint64, repeated, packed = "true", tag = "8")]
+        offsets: Vec<i64>,
+        #[prost(btree_map = "string, string", tag = "9")]
+        labels: BTreeMap<String, String>,
+        #[prost(message, optional, tag = "10")]
+        nested: Option<Nested>,
+        #[prost(enumeration = "Color", optional, tag = "11")]
+        color: Option<i32>,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    struct Nested {
+        #[prost(uint32, optional, tag = "1")]
+        id: Option<u32>,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+    enum Color {
+        Red = 0,
+        Blue = 1,
+    }
+
+    fn field(name: &str, number: i32, field_type: Type, label: Label) -> FieldDescriptorProto {
+        FieldDescriptorProto {
+            name: Some(name.to_string()),
+            number: Some(number),
+            label: Some(label as i32),
+            r#type: Some(field_type as i32),
+            ..Default::default()
+        }
+    }
+
+    fn typed(mut field: FieldDescriptorProto, type_name: &str) -> FieldDescriptorProto {
+        field.type_name = Some(type_name.to_string());
+        field
+    }
+
+    fn descriptor() -> DescriptorProto {
+        let mut offsets = field("offsets", 8, Type::Sint64, Label::Repeated);
+        offsets.options = Some(FieldOptions {
+            packed: Some(true),
+            ..Default::default()
+        });
+        DescriptorProto {
+            name: Some("table_rows".to_string()),
+            field: vec![
+                field("name", 1, Type::String, Label::Optional),
+                field("count", 2, Type::Int64, Label::Optional),
+                field("delta", 3, Type::Int32, Label::Optional),
+                field("ratio", 4, Type::Double, Label::Optional),
+                field("active", 5, Type::Bool, Label::Optional),
+                field("payload", 6, Type::Bytes, Label::Optional),
+                field("tags", 7, Type::String, Label::Repeated),
+                offsets,
+                typed(
+                    field("labels", 9, Type::Message, Label::Repeated),
+                    ".pkg.table_rows.LabelsEntry",
+                ),
+                typed(
+                    field("nested", 10, Type::Message, Label::Optional),
+                    ".pkg.table_rows.Nested",
+                ),
+                typed(
+                    field("color", 11, Type::Enum, Label::Optional),
+                    ".pkg.table_rows.Color",
+                ),
+            ],
+            nested_type: vec![
+                DescriptorProto {
+                    name: Some("LabelsEntry".to_string()),
+                    field: vec![
+                        field("key", 1, Type::String, Label::Optional),
+                        field("value", 2, Type::String, Label::Optional),
+                    ],
+                    options: Some(MessageOptions {
+                        map_entry: Some(true),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                DescriptorProto {
+                    name: Some("Nested".to_string()),
+                    field: vec![field("id", 1, Type::Uint32, Label::Optional)],
+                    ..Default::default()
+                },
+            ],
+            enum_type: vec![EnumDescriptorProto {
+                name: Some("Color".to_string()),
+                value: vec![
+                    prost_types::EnumValueDescriptorProto {
+                        name: Some("RED".to_string()),
+                        number: Some(0),
+                        ..Default::default()
+                    },
+                    prost_types::EnumValueDescriptorProto {
+                        name: Some("BLUE".to_string()),
+                        number: Some(1),
+                        ..Default::default()
+                    },
+                ],
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_matches_prost_encoding() {
+        let encoder = DynamicEncoder::new(&descriptor()).unwrap();
+        let value = json!({
+            "name": "widget",
+            "count": -5,
+            "delta": -1,
+            "ratio": 0.25,
+            "active": false,
+            "payload": "AAEC",
+            "tags": ["a", "", "c"],
+            "offsets": [-1, 0, 300],
+            "labels": {"b": "2", "a": "", "": "x"},
+            "nested": {"id": 7},
+            "color": "BLUE",
+        });
+
+        let expected = Row {
+            name: Some("widget".to_string()),
+            count: Some(-5),
+            delta: Some(-1),
+            ratio: Some(0.25),
+            active: Some(false),
+            payload: Some(vec![0, 1, 2]),
+            tags: vec!["a".to_string(), String::new(), "c".to_string()],
+            offsets: vec![-1, 0, 300],
+            labels: BTreeMap::from([
+                ("b".to_string(), "2".to_string()),
+                ("a".to_string(), String::new()),
+                (String::new(), "x".to_string()),
+            ]),
+            nested: Some(Nested { id: Some(7) }),
+            color: Some(Color::Blue as i32),
+        };
+        assert_eq!(expected.encode_to_vec(), encoder.encode(&value).unwrap());
+    }
+
+    #[test]
+    fn test_fields_are_encoded_by_number_not_declaration_order() {
+        // Columns added to a table after it was created can be declared before older ones
+        let mut descriptor = descriptor();
+        descriptor.field.reverse();
+        let encoder = DynamicEncoder::new(&descriptor).unwrap();
+        let value = json!({"color": "BLUE", "nested": {"id": 1}, "count": 2, "name": "x"});
+
+        let expected = Row {
+            name: Some("x".to_string()),
+            count: Some(2),
+            nested: Some(Nested { id: Some(1) }),
+            color: Some(Color::Blue as i32),
+            ..Default::default()
+        };
+        let encoded = encoder.encode(&value).unwrap();
+        assert_eq!(expected.encode_to_vec(), encoded);
+        // Field 1 first, and field 11 last
+        assert_eq!(0x0a, encoded[0]);
+        assert_eq!(&[0x58, 0x01], &encoded[encoded.len() - 2..]);
+        assert!(encoder.validate(&encoded).is_ok());
+    }
+
+    #[test]
+    fn test_null_and_missing_fields_are_omitted() {
+        let encoder = DynamicEncoder::new(&descriptor()).unwrap();
+
+        let encoded = encoder.encode(&json!({"name": null, "count": 3})).unwrap();
+
+        let expected = Row {
+            count: Some(3),
+            ..Default::default()
+        };
+        assert_eq!(expected.encode_to_vec(), encoded);
+    }
+
+    #[test]
+    fn test_lenient_coercion_from_strings() {
+        let encoder = DynamicEncoder::new(&descriptor()).unwrap();
+
+        let encoded = encoder
+            .encode(
+                &json!({"count": "42", "ratio": "1.5", "active": "true", "color": 1, "name": 12}),
+            )
+            .unwrap();
+
+        let row = Row::decode(encoded.as_slice()).unwrap();
+        assert_eq!(Some(42), row.count);
+        assert_eq!(Some(1.5), row.ratio);
+        assert_eq!(Some(true), row.active);
+        assert_eq!(Some(Color::Blue as i32), row.color);
+        assert_eq!(Some("12".to_string()), row.name);
+    }
+
+    #[test]
+    fn test_strict_types_without_coercion() {
+        let encoder = DynamicEncoder::new(&descriptor())
+            .unwrap()
+            .coerce_types(false);
+
+        let error = encoder.encode(&json!({"count": "42"})).unwrap_err();
+        assert!(
+            format!("{:#}", error).contains("Expected a number, got a string"),
+            "{:#}",
+            error
+        );
+        assert!(encoder.encode(&json!({"ratio": "1.5"})).is_err());
+        assert!(encoder.encode(&json!({"active": "true"})).is_err());
+        assert!(encoder.encode(&json!({"active": 1})).is_err());
+        assert!(encoder.encode(&json!({"offsets": [1, "2"]})).is_err());
+
+        // Values of the right type, enum names, and map keys are still taken
+        let encoded = encoder
+            .encode(&json!({"count": 42, "active": true, "color": "BLUE", "labels": {"a": "1"}}))
+            .unwrap();
+        let row = Row::decode(encoded.as_slice()).unwrap();
+        assert_eq!(Some(42), row.count);
+        assert_eq!(Some(true), row.active);
+        assert_eq!(Some(Color::Blue as i32), row.color);
+    }
+
+    #[test]
+    fn test_failed_coercion_per_field_error_mode() {
+        let value = json!({"name": "a", "count": "many", "active": "yes", "ratio": "0.5"});
+
+        let error = DynamicEncoder::new(&descriptor())
+            .unwrap()
+            .encode(&value)
+            .unwrap_err();
+        assert!(format!("{:#}", error).contains("\"count\""));
+
+        let encoder = DynamicEncoder::new(&descriptor())
+            .unwrap()
+            .field_error_mode(FieldErrorMode::Null);
+        let row = Row::decode(encoder.encode(&value).unwrap().as_slice()).unwrap();
+        assert_eq!(Some("a".to_string()), row.name);
+        assert_eq!(None, row.count);
+        assert_eq!(None, row.active);
+        assert_eq!(Some(0.5), row.ratio);
+        assert_eq!(2, encoder.fields_left_unset());
+
+        // Nested messages leave their own fields unset; unknown fields still fail
+        let row = encoder.encode(&json!({"nested": {"id": -1}})).unwrap();
+        assert_eq!(
+            Some(Nested { id: None }),
+            Row::decode(row.as_slice()).unwrap().nested
+        );
+        assert!(encoder.encode(&json!({"nested": {"unit": 1}})).is_err());
+        assert!(encoder.encode(&json!({"nested": 7})).is_err());
+    }
+
+    #[test]
+    fn test_warns_about_dropped_fields() {
+        let encoder = DynamicEncoder::new(&descriptor())
+            .unwrap()
+            .ignore_unknown_fields(true)
+            .warn_unknown_fields(true);
+        let row = json!({"name": "a", "extra": 1, "nested": {"id": 2, "unit": "4B"}});
+
+        assert_eq!(vec!["extra", "nested.unit"], encoder.unknown_fields(&row));
+        assert_eq!(
+            encoder
+                .encode(&json!({"name": "a", "nested": {"id": 2}}))
+                .unwrap(),
+            encoder.encode(&row).unwrap()
+        );
+        assert_eq!(1, encoder.records_with_unknown_fields());
+
+        // Maps are keyed by data, not field names
+        assert!(encoder
+            .unknown_fields(&json!({"labels": {"anything": "x"}}))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_errors_name_the_field() {
+        let encoder = DynamicEncoder::new(&descriptor()).unwrap();
+
+        let error = encoder.encode(&json!({"count": "many"})).unwrap_err();
+        assert!(format!("{:#}", error).contains("\"count\""));
+
+        let error = encoder
+            .encode(&json!({"delta": 3_000_000_000u64}))
+            .unwrap_err();
+        assert!(format!("{:#}", error).contains("int32"));
+
+        let error = encoder.encode(&json!({"nested": {"id": -1}})).unwrap_err();
+        assert!(format!("{:#}", error).contains("\"nested\""));
+
+        assert!(encoder.encode(&json!({"typo": 1})).is_err());
+        assert!(encoder
+            .ignore_unknown_fields(true)
+            .encode(&json!({"typo": 1}))
+            .is_ok());
+        assert!(DynamicEncoder::new(&descriptor())
+            .unwrap()
+            .encode(&json!([1]))
+            .is_err());
+    }
+
+    fn full_row(id: u32) -> Row {
+        Row {
+            name: Some("widget".to_string()),
+            count: Some(-5),
+            delta: Some(-1),
+            ratio: Some(0.25),
+            active: Some(true),
+            payload: Some(vec![0, 1, 2]),
+            tags: vec!["a".to_string(), "c".to_string()],
+            offsets: vec![-1, 0, 300],
+            labels: BTreeMap::from([("a".to_string(), "1".to_string())]),
+            nested: Some(Nested { id: Some(id) }),
+            color: Some(Color::Blue as i32),
+        }
+    }
+
+    #[test]
+    fn test_validate_accepts_well_formed_records() {
+        let encoder = DynamicEncoder::new(&descriptor()).unwrap();
+
+        encoder.validate(&full_row(7).encode_to_vec()).unwrap();
+        encoder.validate(&[]).unwrap();
+
+        // A packed field sent unpacked is still valid
+        let mut unpacked = Vec::new();
+        encode_key(8, WireType::Varint, &mut unpacked);
+        encode_varint(1, &mut unpacked);
+        encoder.validate(&unpacked).unwrap();
+    }
+
+    #[test]
+    fn test_validate_rejects_malformed_records() {
+        let encoder = DynamicEncoder::new(&descriptor()).unwrap();
+        let error = |record: &[u8]| format!("{:#}", encoder.validate(record).unwrap_err());
+
+        let mut unknown = Vec::new();
+        encode_key(12, WireType::Varint, &mut unknown);
+        encode_varint(1, &mut unknown);
+        assert!(error(&unknown).contains("Unknown field number 12"));
+
+        // "count" is an int64, sent as a string
+        let mut wrong_type = Vec::new();
+        encode_key(2, WireType::LengthDelimited, &mut wrong_type);
+        encode_varint(1, &mut wrong_type);
+        wrong_type.push(b'x');
+        assert!(error(&wrong_type).contains("\"count\""));
+
+        let mut not_utf8 = Vec::new();
+        encode_key(1, WireType::LengthDelimited, &mut not_utf8);
+        encode_varint(2, &mut not_utf8);
+        not_utf8.extend_from_slice(&[0xff, 0xfe]);
+        assert!(error(&not_utf8).contains("UTF-8"));
+
+        let mut bad_nested = Vec::new();
+        encode_key(10, WireType::LengthDelimited, &mut bad_nested);
+        encode_varint(unknown.len() as u64, &mut bad_nested);
+        bad_nested.extend_from_slice(&unknown);
+        assert!(error(&bad_nested).contains("\"nested\""));
+
+        let record = Row {
+            name: Some("widget".to_string()),
+            ..Default::default()
+        }
+        .encode_to_vec();
+        assert!(error(&record[..record.len() - 1]).contains("Record ends"));
+    }
+
+    #[test]
+    fn test_check_validates_only_when_enabled() {
+        let mut unknown = Vec::new();
+        encode_key(12, WireType::Varint, &mut unknown);
+        encode_varint(1, &mut unknown);
+        let valid = full_row(7).encode_to_vec();
+
+        let skipping = DynamicEncoder::new(&descriptor()).unwrap();
+        skipping.check(&unknown).unwrap();
+        skipping.check(&valid).unwrap();
+        assert_eq!(0, skipping.records_validated());
+
+        let validating = DynamicEncoder::new(&descriptor())
+            .unwrap()
+            .validate_records(true);
+        validating.check(&valid).unwrap();
+        let error = validating.check(&unknown).unwrap_err();
+        assert!(error.to_string().contains("Unknown field number 12"));
+        assert_eq!(2, validating.records_validated());
+    }
+
+    #[test]
+    fn test_validating_many_records_reuses_the_prepared_schema() {
+        const RECORDS: u32 = 20_000;
+        let records: Vec<Vec<u8>> = (0..RECORDS)
+            .map(|id| full_row(id).encode_to_vec())
+            .collect();
+        let prepared_before = SCHEMAS_PREPARED.with(|prepared| prepared.get());
+
+        let encoder = DynamicEncoder::new(&descriptor()).unwrap();
+        let started = std::time::Instant::now();
+        for record in &records {
+            encoder.validate(record).unwrap();
+        }
+        let elapsed = started.elapsed();
+
+        // One schema for every record, rather than one per record
+        assert_eq!(
+            prepared_before + 1,
+            SCHEMAS_PREPARED.with(|prepared| prepared.get())
+        );
+        println!(
+            "Validated {} records in {:?} ({:.0} records/s)",
+            RECORDS,
+            elapsed,
+            f64::from(RECORDS) / elapsed.as_secs_f64()
+        );
+    }
+
+    /// Rows with any values, bar the doubles JSON cannot hold
+    fn cased_descriptor(event_type: &str, source_id: &str) -> DescriptorProto {
+        DescriptorProto {
+            name: Some("table_events".to_string()),
+            field: vec![
+                field(event_type, 1, Type::String, Label::Optional),
+                typed(
+                    field("origin", 2, Type::Message, Label::Optional),
+                    ".pkg.table_events.Origin",
+                ),
+            ],
+            nested_type: vec![DescriptorProto {
+                name: Some("Origin".to_string()),
+                field: vec![field(source_id, 1, Type::Int64, Label::Optional)],
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_snake_key_case_maps_camel_case_keys() {
+        let descriptor = cased_descriptor("event_type", "source_id");
+        let encoder = DynamicEncoder::new(&descriptor)
+            .unwrap()
+            .key_case(KeyCase::Snake);
+
+        let camel = json!({"eventType": "click", "origin": {"sourceId": 7}});
+        let snake = json!({"event_type": "click", "origin": {"source_id": 7}});
+        assert_eq!(
+            encoder.encode(&snake).unwrap(),
+            encoder.encode(&camel).unwrap()
+        );
+        assert!(encoder.unknown_fields(&camel).is_empty());
+
+        let error = encoder
+            .encode(&json!({"eventType": "click", "event_type": "view"}))
+            .unwrap_err();
+        assert!(format!("{:#}", error).contains("both name the field \"event_type\""));
+    }
+
+    #[test]
+    fn test_as_is_key_case_preserves_keys() {
+        let descriptor = cased_descriptor("event_type", "source_id");
+        let encoder = DynamicEncoder::new(&descriptor).unwrap();
+
+        let error = encoder.encode(&json!({"eventType": "click"})).unwrap_err();
+        assert!(format!("{:#}", error).contains("Unknown field \"eventType\""));
+
+        let encoder = encoder.ignore_unknown_fields(true);
+        assert_eq!(
+            vec!["eventType"],
+            encoder.unknown_fields(&json!({"eventType": "click"}))
+        );
+        assert!(encoder
+            .encode(&json!({"eventType": "click"}))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_camel_key_case_maps_snake_case_keys() {
+        let descriptor = cased_descriptor("eventType", "sourceId");
+        let encoder = DynamicEncoder::new(&descriptor)
+            .unwrap()
+            .key_case(KeyCase::Camel);
+
+        assert_eq!(
+            encoder
+                .encode(&json!({"eventType": "click", "origin": {"sourceId": 7}}))
+                .unwrap(),
+            encoder
+                .encode(&json!({"event_type": "click", "origin": {"source_id": 7}}))
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_key_case_conversions() {
+        for (key, snake) in [
+            ("eventType", "event_type"),
+            ("event_type", "event_type"),
+            ("HTTPStatus", "http_status"),
+            ("userID", "user_id"),
+            ("ipv4Address", "ipv4_address"),
+            ("_privateKey", "_private_key"),
+        ] {
+            assert_eq!(snake, KeyCase::Snake.apply(key), "{}", key);
+        }
+        for (key, camel) in [
+            ("event_type", "eventType"),
+            ("eventType", "eventType"),
+            ("_private_key", "_privateKey"),
+        ] {
+            assert_eq!(camel, KeyCase::Camel.apply(key), "{}", key);
+        }
+        assert_eq!("eventType", KeyCase::AsIs.apply("eventType"));
+
+        assert_eq!(KeyCase::AsIs, KeyCase::new("").unwrap());
+        assert_eq!(KeyCase::Snake, KeyCase::new("SNAKE").unwrap());
+        assert!(KeyCase::new("kebab").is_err());
+    }
+
+    fn arb_row() -> impl Strategy<Value = Row> {
+        use proptest::num::f64::{NEGATIVE, NORMAL, POSITIVE, SUBNORMAL, ZERO};
+        let scalars = (
+            proptest::option::of(any::<String>()),
+            proptest::option::of(any::<i64>()),
+            proptest::option::of(any::<i32>()),
+            proptest::option::of(POSITIVE | NEGATIVE | NORMAL | SUBNORMAL | ZERO),
+            proptest::option::of(any::<bool>()),
+            proptest::option::of(vec(any::<u8>(), 0..64)),
+        );
+        let collections = (
+            vec(any::<String>(), 0..8),
+            vec(any::<i64>(), 0..8),
+            btree_map(any::<String>(), any::<String>(), 0..8),
+            proptest::option::of(proptest::option::of(any::<u32>())),
+            proptest::option::of(prop_oneof![Just(Color::Red), Just(Color::Blue)]),
+        );
+        (scalars, collections).prop_map(
+            |(
+                (name, count, delta, ratio, active, payload),
+                (tags, offsets, labels, nested, color),
+            )| Row {
+                name,
+                count,
+                delta,
+                ratio,
+                active,
+                payload,
+                tags,
+                offsets,
+                labels,
+                nested: nested.map(|id| Nested { id }),
+                color: color.map(|color| color as i32),
+            },
+        )
+    }
+
+    /// The JSON a row is ingested from
+    fn row_json(row: &Row) -> Value {
+        json!({
+            "name": row.name,
+            "count": row.count,
+            "delta": row.delta,
+            "ratio": row.ratio,
+            "active": row.active,
+            "payload": row.payload.as_ref().map(|payload| general_purpose::STANDARD.encode(payload)),
+            "tags": row.tags,
+            "offsets": row.offsets,
+            "labels": row.labels,
+            "nested": row.nested.as_ref().map(|nested| json!({"id": nested.id})),
+            "color": row.color.map(|color| if color == Color::Blue as i32 { "BLUE" } else { "RED" }),
+        })
+    }
+
+    /// Mostly the names in the test descriptor, so values reach their fields
+    fn arb_field_name() -> impl Strategy<Value = String> {
+        prop_oneof![
+            3 => proptest::sample::select(vec![
+                "name", "count", "delta", "ratio", "active", "payload", "tags", "offsets",
+                "labels", "nested", "color", "id",
+            ])
+            .prop_map(str::to_string),
+            1 => any::<String>(),
+        ]
+    }
+
+    /// JSON of any shape, nested up to four levels deep
+    fn arb_json() -> impl Strategy<Value = Value> {
+        let leaf = prop_oneof![
+            Just(Value::Null),
+            any::<bool>().prop_map(Value::from),
+            any::<i64>().prop_map(Value::from),
+            any::<u64>().prop_map(Value::from),
+            any::<f64>().prop_map(Value::from),
+            any::<String>().prop_map(Value::from),
+        ];
+        leaf.prop_recursive(4, 64, 8, |inner| {
+            prop_oneof![
+                vec(inner.clone(), 0..8).prop_map(Value::from),
+                btree_map(arb_field_name(), inner, 0..8)
+                    .prop_map(|object| Value::Object(object.into_iter().collect())),
+            ]
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn test_rows_round_trip_through_json(row in arb_row()) {
+            let encoder = DynamicEncoder::new(&descriptor()).unwrap();
+            let encoded = encoder.encode(&row_json(&row)).unwrap();
+            prop_assert!(encoder.validate(&encoded).is_ok());
+            prop_assert_eq!(row, Row::decode(encoded.as_slice()).unwrap());
+        }
+
+        #[test]
+        fn test_any_json_encodes_or_fails_without_panicking(
+            fields in btree_map(arb_field_name(), arb_json(), 0..12),
+            coerce in any::<bool>(),
+            null_on_error in any::<bool>(),
+        ) {
+            let mode = if null_on_error { FieldErrorMode::Null } else { FieldErrorMode::Fail };
+            let encoder = DynamicEncoder::new(&descriptor())
+                .unwrap()
+                .coerce_types(coerce)
+                .field_error_mode(mode);
+            // Whatever is encoded is a record of the table
+            if let Ok(encoded) = encoder.encode(&Value::Object(fields.into_iter().collect())) {
+                prop_assert!(encoder.validate(&encoded).is_ok());
+            }
+        }
+
+        #[test]
+        fn test_any_bytes_validate_without_panicking(record in vec(any::<u8>(), 0..256)) {
+            let encoder = DynamicEncoder::new(&descriptor()).unwrap();
+            let _ = encoder.validate(&record);
+        }
+    }
+}
@@ -0,0 +1,245 @@
+//! The Zerobus endpoint streams are created against, given or discovered
+//!
+//! `ZEROBUS_ENDPOINT` names the endpoint explicitly. With `DISCOVER_ENDPOINT=true` it is
+//! resolved from `DATABRICKS_HOST` instead: the workspace's metastore assignment gives
+//! its ID, and the metastore gives the cloud and region, which together make up
+//! `https://<workspace_id>.zerobus.<region>.<cloud domain>`.
+
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+use tokio::sync::OnceCell;
+use tracing::info;
+
+use crate::auth::StreamAuth;
+use crate::workspace::WorkspaceApi;
+
+/// Resolves the Zerobus endpoint of one workspace, calling its API at most once
+pub struct EndpointDiscovery {
+    api: WorkspaceApi,
+    endpoint: OnceCell<String>,
+}
+
+impl EndpointDiscovery {
+    pub fn new(databricks_host: impl Into<String>) -> Self {
+        Self {
+            api: WorkspaceApi::new(databricks_host),
+            endpoint: OnceCell::new(),
+        }
+    }
+
+    /// The workspace's Zerobus endpoint, discovered on first use with a token for
+    /// `auth` and cached after that
+    pub async fn endpoint(&self, auth: &StreamAuth) -> Result<String> {
+        self.endpoint
+            .get_or_try_init(|| self.discover(auth))
+            .await
+            .cloned()
+    }
+
+    async fn discover(&self, auth: &StreamAuth) -> Result<String> {
+        let host = self.api.host();
+        let token = self.api.token(auth).await?;
+        let assignment = self
+            .api
+            .get(
+                "/api/2.1/unity-catalog/current-metastore-assignment",
+                &token,
+            )
+            .await?;
+        let workspace_id = match &assignment["workspace_id"] {
+            Value::Number(id) => id.to_string(),
+            Value::String(id) => id.clone(),
+            _ => bail!("Metastore assignment of {} has no workspace_id", host),
+        };
+        let metastore_id = assignment["metastore_id"]
+            .as_str()
+            .with_context(|| format!("Metastore assignment of {} has no metastore_id", host))?;
+        let metastore = self
+            .api
+            .get(
+                &format!("/api/2.1/unity-catalog/metastores/{}", metastore_id),
+                &token,
+            )
+            .await?;
+        let region = metastore["region"]
+            .as_str()
+            .with_context(|| format!("Metastore {} has no region", metastore_id))?;
+        let cloud = metastore["cloud"].as_str().unwrap_or("aws");
+
+        let endpoint = zerobus_endpoint(&workspace_id, cloud, region)?;
+        info!("Discovered Zerobus endpoint {} for {}", endpoint, host);
+        Ok(endpoint)
+    }
+}
+
+/// The endpoint of a workspace in a cloud's region
+fn zerobus_endpoint(workspace_id: &str, cloud: &str, region: &str) -> Result<String> {
+    let domain = match cloud.to_ascii_lowercase().as_str() {
+        "aws" => "cloud.databricks.com",
+        "azure" => "azuredatabricks.net",
+        _ => bail!(
+            "Cannot discover the Zerobus endpoint of a workspace on {}; set ZEROBUS_ENDPOINT",
+            cloud
+        ),
+    };
+    Ok(format!(
+        "https://{}.zerobus.{}.{}",
+        workspace_id, region, domain
+    ))
+}
+
+/// `ZEROBUS_ENDPOINT`, or the endpoint discovered for `databricks_host` when
+/// `DISCOVER_ENDPOINT` is `true`
+pub async fn zerobus_endpoint_from_env(databricks_host: &str, auth: &StreamAuth) -> Result<String> {
+    let discover = match std::env::var("DISCOVER_ENDPOINT") {
+        Ok(value) => match value.trim().to_ascii_lowercase().as_str() {
+            "" | "false" | "0" => false,
+            "true" | "1" => true,
+            _ => bail!(
+                "DISCOVER_ENDPOINT must be \"true\" or \"false\", got {:?}",
+                value
+            ),
+        },
+        Err(_) => false,
+    };
+    if discover {
+        return EndpointDiscovery::new(databricks_host).endpoint(auth).await;
+    }
+    std::env::var("ZEROBUS_ENDPOINT")
+        .context("ZEROBUS_ENDPOINT environment variable must be set, or DISCOVER_ENDPOINT=true")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::credentials::Credentials;
+    use axum::extract::{Path, State};
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::{get, post};
+    use axum::{Json, Router};
+    use databricks_zerobus_ingest_sdk::ZerobusSdk;
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[derive(Clone, Default)]
+    struct Workspace {
+        requests: Arc<AtomicUsize>,
+    }
+
+    fn authorized(headers: &HeaderMap) -> Result<(), StatusCode> {
+        match headers.get("authorization").and_then(|v| v.to_str().ok()) {
+            Some("Bearer workspace-token") => Ok(()),
+            _ => Err(StatusCode::UNAUTHORIZED),
+        }
+    }
+
+    async fn token(
+        State(workspace): State<Workspace>,
+        headers: HeaderMap,
+        body: String,
+    ) -> Result<Json<Value>, StatusCode> {
+        workspace.requests.fetch_add(1, Ordering::SeqCst);
+        // base64("sp-id:sp-secret")
+        if headers.get("authorization").and_then(|v| v.to_str().ok())
+            != Some("Basic c3AtaWQ6c3Atc2VjcmV0")
+            || !body.contains("grant_type=client_credentials")
+        {
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+        Ok(Json(
+            json!({"access_token": "workspace-token", "expires_in": 3600}),
+        ))
+    }
+
+    async fn assignment(
+        State(workspace): State<Workspace>,
+        headers: HeaderMap,
+    ) -> Result<Json<Value>, StatusCode> {
+        workspace.requests.fetch_add(1, Ordering::SeqCst);
+        authorized(&headers)?;
+        Ok(Json(json!({
+            "workspace_id": 1234567890123456u64,
+            "metastore_id": "b169b504-4c54-49f2-bc3a-adf4b128f36d",
+            "default_catalog_name": "main"
+        })))
+    }
+
+    async fn metastore(
+        State(workspace): State<Workspace>,
+        Path(id): Path<String>,
+        headers: HeaderMap,
+    ) -> Result<Json<Value>, StatusCode> {
+        workspace.requests.fetch_add(1, Ordering::SeqCst);
+        authorized(&headers)?;
+        if id != "b169b504-4c54-49f2-bc3a-adf4b128f36d" {
+            return Err(StatusCode::NOT_FOUND);
+        }
+        Ok(Json(
+            json!({"metastore_id": id, "cloud": "aws", "region": "us-west-2"}),
+        ))
+    }
+
+    /// Serve the workspace API calls discovery makes on a random local port
+    async fn start_workspace() -> (String, Workspace) {
+        let workspace = Workspace::default();
+        let app = Router::new()
+            .route("/oidc/v1/token", post(token))
+            .route(
+                "/api/2.1/unity-catalog/current-metastore-assignment",
+                get(assignment),
+            )
+            .route("/api/2.1/unity-catalog/metastores/:id", get(metastore))
+            .with_state(workspace.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (url, workspace)
+    }
+
+    fn oauth(client_secret: &str) -> StreamAuth {
+        StreamAuth::OAuth(Credentials {
+            client_id: "sp-id".to_string(),
+            client_secret: client_secret.to_string(),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_discovered_endpoint_initializes_the_sdk() {
+        let (host, workspace) = start_workspace().await;
+        let discovery = EndpointDiscovery::new(format!("{}/", host));
+
+        let endpoint = discovery.endpoint(&oauth("sp-secret")).await.unwrap();
+        assert_eq!(
+            "https://1234567890123456.zerobus.us-west-2.cloud.databricks.com",
+            endpoint
+        );
+        // Cached: the second call does not reach the workspace
+        assert_eq!(
+            endpoint,
+            discovery.endpoint(&oauth("sp-secret")).await.unwrap()
+        );
+        assert_eq!(3, workspace.requests.load(Ordering::SeqCst));
+
+        ZerobusSdk::new(endpoint, host).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_discovery_fails_with_wrong_credentials() {
+        let (host, _) = start_workspace().await;
+        let error = EndpointDiscovery::new(host)
+            .endpoint(&oauth("wrong"))
+            .await
+            .unwrap_err();
+        assert!(format!("{:#}", error).contains("401"));
+    }
+
+    #[test]
+    fn test_endpoint_per_cloud() {
+        assert_eq!(
+            "https://42.zerobus.westeurope.azuredatabricks.net",
+            zerobus_endpoint("42", "AZURE", "westeurope").unwrap()
+        );
+        assert!(zerobus_endpoint("42", "gcp", "us-central1").is_err());
+    }
+}
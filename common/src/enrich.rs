@@ -0,0 +1,690 @@
+//! Enriching records with reference data looked up in DynamoDB
+//!
+//! `ENRICH` is a JSON object naming a lookup table, the JSON pointer to the key in each
+//! record, and the attributes of the item found to add to the record as columns:
+//!
+//! ```json
+//! {"table": "tenants", "key_field": "/tenant_code",
+//!  "fields": {"tenant_name": "name", "tenant_tier": "tier"}, "ttl_secs": 300}
+//! ```
+//!
+//! `fields` maps columns to item attributes, or is a list of attributes stored in
+//! columns of the same name. The key is looked up by the table's partition key,
+//! `key_attribute`, which defaults to the last segment of `key_field`.
+//!
+//! Items are cached for `ttl_secs`, so a batch of records sharing a key costs one read.
+//! Keys with no item are cached too, and leave the enrichment columns null, as do
+//! records without a key. A failed read fails the record rather than ingesting it
+//! without its reference data.
+
+use anyhow::{bail, Context, Result};
+use aws_sdk_dynamodb::types::AttributeValue;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde_json::{Map, Number, Value};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// How long an item is cached when `ttl_secs` is not set
+pub const DEFAULT_TTL: Duration = Duration::from_secs(300);
+
+/// Keys cached when `max_entries` is not set
+pub const DEFAULT_MAX_ENTRIES: usize = 10_000;
+
+/// What `ENRICH` configures
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnrichConfig {
+    pub table: String,
+    /// JSON pointer to the key in each record
+    pub key_field: String,
+    /// Partition key attribute of the table
+    pub key_attribute: String,
+    /// Columns and the item attributes they are set from
+    pub fields: Vec<(String, String)>,
+    pub ttl: Duration,
+    pub max_entries: usize,
+}
+
+impl EnrichConfig {
+    pub fn parse(config: &str) -> Result<Self> {
+        let object: Map<String, Value> =
+            serde_json::from_str(config).context("Enrichment config is not a JSON object")?;
+        for name in object.keys() {
+            if ![
+                "table",
+                "key_field",
+                "key_attribute",
+                "fields",
+                "ttl_secs",
+                "max_entries",
+            ]
+            .contains(&name.as_str())
+            {
+                bail!("Unknown enrichment setting {:?}", name);
+            }
+        }
+        let string = |name: &str| match object.get(name) {
+            None => Ok(None),
+            Some(Value::String(value)) if !value.is_empty() => Ok(Some(value.clone())),
+            Some(other) => bail!(
+                "Enrichment {} must be a non-empty string, got {}",
+                name,
+                other
+            ),
+        };
+        let number = |name: &str| match object.get(name) {
+            None => Ok(None),
+            Some(value) => value
+                .as_u64()
+                .filter(|number| *number > 0)
+                .map(Some)
+                .with_context(|| {
+                    format!(
+                        "Enrichment {} must be a positive integer, got {}",
+                        name, value
+                    )
+                }),
+        };
+
+        let table = string("table")?.context("Enrichment config must name a table")?;
+        let key_field = string("key_field")?.context("Enrichment config must name a key_field")?;
+        if !key_field.starts_with('/') {
+            bail!(
+                "Enrichment key_field must be a JSON pointer such as /tenant_code, got {:?}",
+                key_field
+            );
+        }
+        let key_attribute = match string("key_attribute")? {
+            Some(attribute) => attribute,
+            None => key_field.rsplit('/').next().unwrap_or_default().to_string(),
+        };
+
+        let fields = match object.get("fields") {
+            Some(Value::Object(fields)) => fields
+                .iter()
+                .map(|(column, attribute)| match attribute {
+                    Value::String(attribute) => Ok((column.clone(), attribute.clone())),
+                    other => bail!(
+                        "Enrichment column {} must map to an attribute name, got {}",
+                        column,
+                        other
+                    ),
+                })
+                .collect::<Result<Vec<_>>>()?,
+            Some(Value::Array(attributes)) => attributes
+                .iter()
+                .map(|attribute| match attribute {
+                    Value::String(attribute) => Ok((attribute.clone(), attribute.clone())),
+                    other => bail!("Enrichment fields must be attribute names, got {}", other),
+                })
+                .collect::<Result<Vec<_>>>()?,
+            _ => bail!("Enrichment config must list the fields to add"),
+        };
+        if fields.is_empty() {
+            bail!("Enrichment config must list the fields to add");
+        }
+
+        Ok(Self {
+            table,
+            key_field,
+            key_attribute,
+            fields,
+            ttl: number("ttl_secs")?.map_or(DEFAULT_TTL, Duration::from_secs),
+            max_entries: number("max_entries")?.map_or(DEFAULT_MAX_ENTRIES, |max| max as usize),
+        })
+    }
+
+    /// Read `ENRICH`; unset enriches nothing
+    pub fn from_env() -> Result<Option<Self>> {
+        match std::env::var("ENRICH") {
+            Ok(config) if !config.trim().is_empty() => {
+                Self::parse(&config).context("Invalid ENRICH").map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+/// The GetItem call enrichment makes; implemented for the AWS SDK client, and by tests
+pub trait LookupItem: Send + Sync {
+    /// The item whose `key_attribute` is `key`, a JSON string or number, with its
+    /// attributes as JSON
+    fn lookup(
+        &self,
+        table: &str,
+        key_attribute: &str,
+        key: &Value,
+    ) -> impl Future<Output = Result<Option<Map<String, Value>>>> + Send;
+}
+
+impl LookupItem for aws_sdk_dynamodb::Client {
+    async fn lookup(
+        &self,
+        table: &str,
+        key_attribute: &str,
+        key: &Value,
+    ) -> Result<Option<Map<String, Value>>> {
+        let key_value = match key {
+            Value::String(key) => AttributeValue::S(key.clone()),
+            Value::Number(key) => AttributeValue::N(key.to_string()),
+            other => bail!("Enrichment key must be a string or number, got {}", other),
+        };
+        let output = self
+            .get_item()
+            .table_name(table)
+            .key(key_attribute, key_value)
+            .send()
+            .await
+            .with_context(|| format!("Failed to look up {} in {}", key, table))?;
+        output
+            .item
+            .map(|item| {
+                item.into_iter()
+                    .map(|(name, value)| attribute_to_json(value).map(|value| (name, value)))
+                    .collect::<Result<_>>()
+            })
+            .transpose()
+            .with_context(|| format!("Failed to read the item for {} in {}", key, table))
+    }
+}
+
+/// An attribute value as JSON: sets and lists as arrays, maps as objects, and binary
+/// values base64-encoded as BINARY columns take them
+///
+/// Fails on a type this SDK version does not know, rather than turning it into a null.
+pub fn attribute_to_json(value: AttributeValue) -> Result<Value> {
+    let number = |number: String| {
+        number
+            .parse::<i64>()
+            .map(Value::from)
+            .ok()
+            .or_else(|| {
+                number
+                    .parse::<f64>()
+                    .ok()
+                    .and_then(Number::from_f64)
+                    .map(Value::Number)
+            })
+            .unwrap_or(Value::String(number))
+    };
+    Ok(match value {
+        AttributeValue::S(value) => Value::String(value),
+        AttributeValue::N(value) => number(value),
+        AttributeValue::Bool(value) => Value::Bool(value),
+        AttributeValue::Null(_) => Value::Null,
+        AttributeValue::B(value) => Value::String(STANDARD.encode(value.as_ref())),
+        AttributeValue::Ss(values) => values.into_iter().map(Value::String).collect(),
+        AttributeValue::Ns(values) => values.into_iter().map(number).collect(),
+        AttributeValue::Bs(values) => values
+            .iter()
+            .map(|value| Value::String(STANDARD.encode(value.as_ref())))
+            .collect(),
+        AttributeValue::L(values) => Value::Array(
+            values
+                .into_iter()
+                .map(attribute_to_json)
+                .collect::<Result<_>>()?,
+        ),
+        AttributeValue::M(values) => Value::Object(
+            values
+                .into_iter()
+                .map(|(name, value)| {
+                    let value = attribute_to_json(value)
+                        .with_context(|| format!("In attribute {:?}", name))?;
+                    Ok((name, value))
+                })
+                .collect::<Result<_>>()?,
+        ),
+        other => bail!("Unsupported DynamoDB attribute value {:?}", other),
+    })
+}
+
+struct Cached {
+    /// The enrichment columns, or `None` when the table has no item for the key
+    columns: Option<Map<String, Value>>,
+    expires_at: Instant,
+}
+
+/// Adds the columns of `ENRICH` to records, from a TTL cache of the lookup table
+pub struct Enricher<L = aws_sdk_dynamodb::Client> {
+    config: EnrichConfig,
+    lookup: L,
+    /// Keyed by the key's JSON, so the string `"7"` and the number `7` are not confused
+    cache: Mutex<HashMap<String, Cached>>,
+}
+
+impl Enricher {
+    /// An enricher when `ENRICH` is set, reading with the default AWS configuration
+    pub async fn from_env() -> Result<Option<Self>> {
+        let Some(config) = EnrichConfig::from_env()? else {
+            return Ok(None);
+        };
+        let aws_config = aws_config::load_from_env().await;
+        info!(
+            "Enriching records from DynamoDB table {} by {}",
+            config.table, config.key_field
+        );
+        Ok(Some(Self::new(
+            config,
+            aws_sdk_dynamodb::Client::new(&aws_config),
+        )))
+    }
+}
+
+impl<L: LookupItem> Enricher<L> {
+    pub fn new(config: EnrichConfig, lookup: L) -> Self {
+        Self {
+            config,
+            lookup,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The columns enrichment sets, to check against the table's
+    pub fn columns(&self) -> impl Iterator<Item = &str> {
+        self.config.fields.iter().map(|(column, _)| column.as_str())
+    }
+
+    /// Set the enrichment columns of `record`, a JSON object, from the item of its key
+    pub async fn enrich(&self, record: &mut Value) -> Result<()> {
+        self.enrich_at(record, Instant::now()).await
+    }
+
+    async fn enrich_at(&self, record: &mut Value, now: Instant) -> Result<()> {
+        let key = record
+            .pointer(&self.config.key_field)
+            .filter(|key| key.is_string() || key.is_number())
+            .cloned();
+        let columns = match key {
+            Some(key) => self.columns_at(&key, now).await?,
+            None => None,
+        };
+        let Some(record) = record.as_object_mut() else {
+            bail!("Record to enrich is not a JSON object");
+        };
+        for (column, _) in &self.config.fields {
+            let value = columns
+                .as_ref()
+                .and_then(|columns| columns.get(column))
+                .cloned()
+                .unwrap_or(Value::Null);
+            record.insert(column.clone(), value);
+        }
+        Ok(())
+    }
+
+    /// The enrichment columns of `key`, from the cache or else the table
+    async fn columns_at(&self, key: &Value, now: Instant) -> Result<Option<Map<String, Value>>> {
+        let cache_key = key.to_string();
+        if let Some(cached) = self.cache.lock().unwrap().get(&cache_key) {
+            if now < cached.expires_at {
+                return Ok(cached.columns.clone());
+            }
+        }
+
+        // Not held across the read: records of other keys need not wait for it
+        let item = self
+            .lookup
+            .lookup(&self.config.table, &self.config.key_attribute, key)
+            .await?;
+        let columns = item.map(|mut item| {
+            self.config
+                .fields
+                .iter()
+                .filter_map(|(column, attribute)| Some((column.clone(), item.remove(attribute)?)))
+                .collect::<Map<String, Value>>()
+        });
+
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= self.config.max_entries && !cache.contains_key(&cache_key) {
+            cache.retain(|_, cached| now < cached.expires_at);
+            // Every entry is live: start over rather than track which is oldest
+            if cache.len() >= self.config.max_entries {
+                cache.clear();
+            }
+        }
+        cache.insert(
+            cache_key,
+            Cached {
+                columns: columns.clone(),
+                expires_at: now + self.config.ttl,
+            },
+        );
+        Ok(columns)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_sdk_dynamodb::primitives::Blob;
+    use proptest::collection::{hash_map, vec};
+    use proptest::prelude::*;
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// A lookup table of tenants by code, counting reads
+    #[derive(Default)]
+    struct MockTable {
+        reads: AtomicUsize,
+    }
+
+    impl LookupItem for Arc<MockTable> {
+        async fn lookup(
+            &self,
+            table: &str,
+            key_attribute: &str,
+            key: &Value,
+        ) -> Result<Option<Map<String, Value>>> {
+            assert_eq!("tenants", table);
+            assert_eq!("tenant_code", key_attribute);
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            let item = match key.as_str() {
+                Some("acme") => json!({"tenant_code": "acme", "name": "Acme Corp", "tier": 2}),
+                Some("globex") => json!({"tenant_code": "globex", "name": "Globex"}),
+                Some("down") => bail!("ProvisionedThroughputExceededException"),
+                _ => return Ok(None),
+            };
+            Ok(item.as_object().cloned())
+        }
+    }
+
+    fn enricher(table: &Arc<MockTable>) -> Enricher<Arc<MockTable>> {
+        let config = EnrichConfig::parse(
+            r#"{"table": "tenants", "key_field": "/tenant_code",
+                "fields": {"tenant_name": "name", "tenant_tier": "tier"}, "ttl_secs": 60}"#,
+        )
+        .unwrap();
+        Enricher::new(config, Arc::clone(table))
+    }
+
+    #[tokio::test]
+    async fn test_records_are_enriched_from_the_cache() {
+        let table = Arc::new(MockTable::default());
+        let enricher = enricher(&table);
+        let start = Instant::now();
+
+        let mut first = json!({"tenant_code": "acme", "amount": 12});
+        enricher.enrich_at(&mut first, start).await.unwrap();
+        assert_eq!(
+            json!({"tenant_code": "acme", "amount": 12, "tenant_name": "Acme Corp", "tenant_tier": 2}),
+            first
+        );
+
+        // Same key within the TTL: served from the cache
+        let mut second = json!({"tenant_code": "acme", "amount": 30});
+        let later = start + Duration::from_secs(59);
+        enricher.enrich_at(&mut second, later).await.unwrap();
+        assert_eq!(json!("Acme Corp"), second["tenant_name"]);
+        assert_eq!(1, table.reads.load(Ordering::SeqCst));
+
+        // An attribute the item lacks leaves its column null
+        let mut other = json!({"tenant_code": "globex"});
+        enricher.enrich_at(&mut other, later).await.unwrap();
+        assert_eq!(json!("Globex"), other["tenant_name"]);
+        assert_eq!(Value::Null, other["tenant_tier"]);
+        assert_eq!(2, table.reads.load(Ordering::SeqCst));
+
+        // Expired: read again
+        let mut third = json!({"tenant_code": "acme"});
+        let expired = start + Duration::from_secs(60);
+        enricher.enrich_at(&mut third, expired).await.unwrap();
+        assert_eq!(json!("Acme Corp"), third["tenant_name"]);
+        assert_eq!(3, table.reads.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_misses_leave_the_columns_null() {
+        let table = Arc::new(MockTable::default());
+        let enricher = enricher(&table);
+        let now = Instant::now();
+
+        for _ in 0..2 {
+            let mut record = json!({"tenant_code": "initech"});
+            enricher.enrich_at(&mut record, now).await.unwrap();
+            assert_eq!(
+                json!({"tenant_code": "initech", "tenant_name": null, "tenant_tier": null}),
+                record
+            );
+        }
+        // Keys without an item are cached too
+        assert_eq!(1, table.reads.load(Ordering::SeqCst));
+
+        // No key, or one that is not a string or number: nothing to look up
+        for mut record in [json!({"amount": 1}), json!({"tenant_code": {"id": "acme"}})] {
+            enricher.enrich_at(&mut record, now).await.unwrap();
+            assert_eq!(Value::Null, record["tenant_name"]);
+        }
+        assert_eq!(1, table.reads.load(Ordering::SeqCst));
+
+        let mut record = json!({"tenant_code": "down"});
+        assert!(enricher.enrich_at(&mut record, now).await.is_err());
+        assert!(enricher.enrich_at(&mut json!("acme"), now).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_cache_is_bounded() {
+        let table = Arc::new(MockTable::default());
+        let mut config = enricher(&table).config;
+        config.max_entries = 2;
+        let enricher = Enricher::new(config, Arc::clone(&table));
+        let now = Instant::now();
+
+        for code in ["acme", "globex", "initech"] {
+            enricher
+                .enrich_at(&mut json!({ "tenant_code": code }), now)
+                .await
+                .unwrap();
+        }
+        assert!(enricher.cache.lock().unwrap().len() <= 2);
+    }
+
+    #[test]
+    fn test_parse() {
+        let config = EnrichConfig::parse(
+            r#"{"table": "devices", "key_field": "/device/id", "fields": ["site", "owner"]}"#,
+        )
+        .unwrap();
+        assert_eq!("id", config.key_attribute);
+        assert_eq!(
+            vec![
+                ("site".to_string(), "site".to_string()),
+                ("owner".to_string(), "owner".to_string())
+            ],
+            config.fields
+        );
+        assert_eq!(DEFAULT_TTL, config.ttl);
+        assert_eq!(DEFAULT_MAX_ENTRIES, config.max_entries);
+
+        for invalid in [
+            r#"{"table": "devices", "key_field": "device_id", "fields": ["site"]}"#,
+            r#"{"table": "devices", "key_field": "/device_id", "fields": []}"#,
+            r#"{"table": "devices", "key_field": "/device_id"}"#,
+            r#"{"key_field": "/device_id", "fields": ["site"]}"#,
+            r#"{"table": "devices", "key_field": "/device_id", "fields": ["site"], "ttl_secs": 0}"#,
+            r#"{"table": "devices", "key_field": "/device_id", "fields": ["site"], "ttl": 60}"#,
+        ] {
+            assert!(EnrichConfig::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_attribute_to_json() {
+        let item = AttributeValue::M(HashMap::from([
+            (
+                "name".to_string(),
+                AttributeValue::S("Acme Corp".to_string()),
+            ),
+            ("tier".to_string(), AttributeValue::N("2".to_string())),
+            ("rate".to_string(), AttributeValue::N("0.25".to_string())),
+            ("active".to_string(), AttributeValue::Bool(true)),
+            (
+                "regions".to_string(),
+                AttributeValue::Ss(vec!["eu".to_string(), "us".to_string()]),
+            ),
+            ("parent".to_string(), AttributeValue::Null(true)),
+        ]));
+        assert_eq!(
+            json!({
+                "name": "Acme Corp",
+                "tier": 2,
+                "rate": 0.25,
+                "active": true,
+                "regions": ["eu", "us"],
+                "parent": null
+            }),
+            attribute_to_json(item).unwrap()
+        );
+    }
+
+    /// Numbers as DynamoDB stores them: integers, and decimals of any magnitude
+    fn arb_number() -> impl Strategy<Value = String> {
+        use proptest::num::f64::{NEGATIVE, NORMAL, POSITIVE, SUBNORMAL, ZERO};
+        prop_oneof![
+            any::<i64>().prop_map(|number| number.to_string()),
+            (POSITIVE | NEGATIVE | NORMAL | SUBNORMAL | ZERO).prop_map(|number| number.to_string()),
+        ]
+    }
+
+    /// DynamoDB values of every type, nested up to four levels deep; sets are never
+    /// empty, as DynamoDB does not allow it
+    fn arb_attribute() -> impl Strategy<Value = AttributeValue> {
+        let leaf = prop_oneof![
+            any::<String>().prop_map(AttributeValue::S),
+            arb_number().prop_map(AttributeValue::N),
+            any::<bool>().prop_map(AttributeValue::Bool),
+            Just(AttributeValue::Null(true)),
+            vec(any::<u8>(), 0..32).prop_map(|value| AttributeValue::B(Blob::new(value))),
+            vec(any::<String>(), 1..8).prop_map(AttributeValue::Ss),
+            vec(arb_number(), 1..8).prop_map(AttributeValue::Ns),
+            vec(vec(any::<u8>(), 0..16), 1..4)
+                .prop_map(|values| AttributeValue::Bs(values.into_iter().map(Blob::new).collect())),
+        ];
+        leaf.prop_recursive(4, 64, 8, |inner| {
+            prop_oneof![
+                vec(inner.clone(), 0..8).prop_map(AttributeValue::L),
+                hash_map(any::<String>(), inner, 0..8).prop_map(AttributeValue::M),
+            ]
+        })
+    }
+
+    /// Check `json` holds what `attribute` does
+    fn check_converted(attribute: &AttributeValue, json: &Value) -> Result<(), TestCaseError> {
+        let check_number = |number: &str, json: &Value| {
+            match number.parse::<i64>() {
+                Ok(integer) => prop_assert_eq!(Some(integer), json.as_i64()),
+                Err(_) => prop_assert_eq!(Some(number.parse::<f64>().unwrap()), json.as_f64()),
+            }
+            Ok(())
+        };
+        let check_binary = |value: &Blob, json: &Value| {
+            let decoded = STANDARD.decode(json.as_str().unwrap_or_default()).unwrap();
+            prop_assert_eq!(value.as_ref(), decoded.as_slice());
+            Ok(())
+        };
+        match attribute {
+            AttributeValue::S(value) => prop_assert_eq!(&Value::String(value.clone()), json),
+            AttributeValue::N(value) => check_number(value, json)?,
+            AttributeValue::Bool(value) => prop_assert_eq!(&Value::Bool(*value), json),
+            AttributeValue::Null(_) => prop_assert_eq!(&Value::Null, json),
+            AttributeValue::B(value) => check_binary(value, json)?,
+            AttributeValue::Ss(values) => prop_assert_eq!(&json!(values), json),
+            AttributeValue::Ns(values) => {
+                prop_assert_eq!(Some(values.len()), json.as_array().map(Vec::len));
+                for (value, json) in values.iter().zip(json.as_array().unwrap()) {
+                    check_number(value, json)?;
+                }
+            }
+            AttributeValue::Bs(values) => {
+                prop_assert_eq!(Some(values.len()), json.as_array().map(Vec::len));
+                for (value, json) in values.iter().zip(json.as_array().unwrap()) {
+                    check_binary(value, json)?;
+                }
+            }
+            AttributeValue::L(values) => {
+                prop_assert_eq!(Some(values.len()), json.as_array().map(Vec::len));
+                for (value, json) in values.iter().zip(json.as_array().unwrap()) {
+                    check_converted(value, json)?;
+                }
+            }
+            AttributeValue::M(values) => {
+                let object = json.as_object();
+                prop_assert_eq!(Some(values.len()), object.map(Map::len));
+                for (name, value) in values {
+                    prop_assert!(object.unwrap().contains_key(name), "{:?} is missing", name);
+                    check_converted(value, &object.unwrap()[name])?;
+                }
+            }
+            other => prop_assert!(false, "Unexpected attribute {:?}", other),
+        }
+        Ok(())
+    }
+
+    proptest! {
+        #[test]
+        fn test_any_item_converts_to_json(
+            item in hash_map(any::<String>(), arb_attribute(), 0..8),
+        ) {
+            let item = AttributeValue::M(item);
+            let json = attribute_to_json(item.clone()).unwrap();
+            check_converted(&item, &json)?;
+        }
+    }
+}
+
+/// Against the DynamoDB API of LocalStack; run with `--features enrich,localstack`
+#[cfg(all(test, feature = "localstack"))]
+mod localstack_tests {
+    use super::*;
+    use crate::localstack::{self, TestTable};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_records_are_enriched_from_the_table() {
+        let config = localstack::config().await;
+        let table = TestTable::create(&config, "tenants", "tenant_code")
+            .await
+            .unwrap();
+        table
+            .client
+            .put_item()
+            .table_name(&table.name)
+            .item("tenant_code", AttributeValue::S("acme".to_string()))
+            .item("name", AttributeValue::S("Acme Corp".to_string()))
+            .item("tier", AttributeValue::N("2".to_string()))
+            .item(
+                "regions",
+                AttributeValue::Ss(vec!["eu-west-1".to_string(), "us-east-1".to_string()]),
+            )
+            .send()
+            .await
+            .unwrap();
+        let enrich = EnrichConfig::parse(&format!(
+            r#"{{"table": "{}", "key_field": "/tenant_code",
+                "fields": {{"tenant_name": "name", "tenant_tier": "tier", "tenant_regions": "regions"}}}}"#,
+            table.name
+        ))
+        .unwrap();
+        let enricher = Enricher::new(enrich, table.client.clone());
+
+        let mut found = json!({"tenant_code": "acme"});
+        let mut missing = json!({"tenant_code": "globex"});
+        let (first, second) =
+            tokio::join!(enricher.enrich(&mut found), enricher.enrich(&mut missing));
+        first.unwrap();
+        second.unwrap();
+
+        assert_eq!(json!("Acme Corp"), found["tenant_name"]);
+        assert_eq!(json!(2), found["tenant_tier"]);
+        let mut regions = found["tenant_regions"].as_array().unwrap().clone();
+        regions.sort_by_key(|region| region.to_string());
+        assert_eq!(vec![json!("eu-west-1"), json!("us-east-1")], regions);
+        assert_eq!(Value::Null, missing["tenant_name"]);
+
+        table.delete().await.unwrap();
+    }
+}
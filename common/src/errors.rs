@@ -0,0 +1,241 @@
+//! Classifying ingest failures, so alerts can tell a Zerobus blip from a broken schema.
+//!
+//! Every failure falls into one [`ErrorClass`]. Errors from the SDK are classified by
+//! [`classify_sdk`], which matches every `ZerobusError` variant without a catch-all arm:
+//! an SDK upgrade that adds a variant fails to compile here until it is classified.
+
+use databricks_zerobus_ingest_sdk::ZerobusError;
+use std::fmt;
+
+use crate::supervisor::is_schema_change;
+
+/// What kind of failure an error is, and so whether sending again can help
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ErrorClass {
+    /// A transient failure, such as the server being unavailable or the stream closing
+    Retryable,
+    /// A failure sending again will not fix, such as an invalid table name or argument
+    Terminal,
+    /// The server did not acknowledge records in time
+    AckTimeout,
+    /// Credentials were missing, invalid, or lacked permission
+    Auth,
+    /// Records do not match the table schema
+    Schema,
+}
+
+impl ErrorClass {
+    pub const ALL: [ErrorClass; 5] = [
+        ErrorClass::Retryable,
+        ErrorClass::Terminal,
+        ErrorClass::AckTimeout,
+        ErrorClass::Auth,
+        ErrorClass::Schema,
+    ];
+
+    /// Label value used in metrics and logs
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorClass::Retryable => "retryable",
+            ErrorClass::Terminal => "terminal",
+            ErrorClass::AckTimeout => "ack_timeout",
+            ErrorClass::Auth => "auth",
+            ErrorClass::Schema => "schema",
+        }
+    }
+
+    /// Whether sending the records again may succeed
+    ///
+    /// Ack timeouts are retried: the records may still be acknowledged on a new stream.
+    pub fn is_retryable(self) -> bool {
+        match self {
+            ErrorClass::Retryable | ErrorClass::AckTimeout => true,
+            ErrorClass::Terminal | ErrorClass::Auth | ErrorClass::Schema => false,
+        }
+    }
+}
+
+impl fmt::Display for ErrorClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// gRPC status codes carried by the SDK's stream errors
+const INVALID_ARGUMENT: i32 = 3;
+const DEADLINE_EXCEEDED: i32 = 4;
+const NOT_FOUND: i32 = 5;
+const ALREADY_EXISTS: i32 = 6;
+const PERMISSION_DENIED: i32 = 7;
+const FAILED_PRECONDITION: i32 = 9;
+const OUT_OF_RANGE: i32 = 11;
+const UNIMPLEMENTED: i32 = 12;
+const UNAUTHENTICATED: i32 = 16;
+
+// Ack timeouts raised outside the SDK, e.g. by a sink waiting on the server, are
+// recognized by their message.
+const ACK_TIMEOUT_MARKERS: &[&str] = &["ack timeout", "lack of ack", "acknowledgment timed out"];
+
+/// Class of an ingest error
+///
+/// The first cause that is a `ZerobusError` decides, through [`classify_sdk`]. Schema
+/// changes and ack timeouts are also recognized in errors from other sinks. Anything
+/// else is taken to be transient and [`ErrorClass::Retryable`].
+pub fn classify(error: &anyhow::Error) -> ErrorClass {
+    if let Some(sdk_error) = error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<ZerobusError>())
+    {
+        return classify_sdk(sdk_error);
+    }
+    if is_schema_change(error) {
+        return ErrorClass::Schema;
+    }
+    let is_ack_timeout = error.chain().any(|cause| {
+        let message = cause.to_string().to_ascii_lowercase();
+        ACK_TIMEOUT_MARKERS
+            .iter()
+            .any(|marker| message.contains(marker))
+    });
+    if is_ack_timeout {
+        return ErrorClass::AckTimeout;
+    }
+    ErrorClass::Retryable
+}
+
+/// Class of an SDK error
+pub fn classify_sdk(error: &ZerobusError) -> ErrorClass {
+    match error {
+        ZerobusError::ChannelCreationError(_) => ErrorClass::Retryable,
+        ZerobusError::UnexpectedStreamResponseError(_) => ErrorClass::Retryable,
+        ZerobusError::InvalidUCTokenError(_) => ErrorClass::Auth,
+        ZerobusError::InvalidTableName(_) => ErrorClass::Terminal,
+        ZerobusError::InvalidArgument(message) => classify_message(message, ErrorClass::Terminal),
+        ZerobusError::CreateStreamError(status) | ZerobusError::StreamClosedError(status) => {
+            classify_status(status.code() as i32, status.message())
+        }
+    }
+}
+
+/// Class of a gRPC status, by its code and message
+fn classify_status(code: i32, message: &str) -> ErrorClass {
+    match code {
+        UNAUTHENTICATED | PERMISSION_DENIED => ErrorClass::Auth,
+        DEADLINE_EXCEEDED => ErrorClass::AckTimeout,
+        INVALID_ARGUMENT | FAILED_PRECONDITION => classify_message(message, ErrorClass::Terminal),
+        NOT_FOUND | ALREADY_EXISTS | OUT_OF_RANGE | UNIMPLEMENTED => ErrorClass::Terminal,
+        _ => classify_message(message, ErrorClass::Retryable),
+    }
+}
+
+/// [`ErrorClass::Schema`] when `message` reports a schema mismatch, `otherwise` if not
+fn classify_message(message: &str, otherwise: ErrorClass) -> ErrorClass {
+    if is_schema_change(&anyhow::anyhow!("{}", message)) {
+        ErrorClass::Schema
+    } else {
+        otherwise
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::supervisor::SchemaChanged;
+
+    #[test]
+    fn test_sdk_errors_are_classified() {
+        let cases = [
+            (
+                ZerobusError::ChannelCreationError("connection refused".into()),
+                ErrorClass::Retryable,
+            ),
+            (
+                ZerobusError::UnexpectedStreamResponseError("empty response".into()),
+                ErrorClass::Retryable,
+            ),
+            (
+                ZerobusError::InvalidUCTokenError("token expired".into()),
+                ErrorClass::Auth,
+            ),
+            (
+                ZerobusError::InvalidTableName("main.nope".into()),
+                ErrorClass::Terminal,
+            ),
+            (
+                ZerobusError::InvalidArgument("max_inflight_records must be positive".into()),
+                ErrorClass::Terminal,
+            ),
+            (
+                ZerobusError::InvalidArgument("record does not match the table schema".into()),
+                ErrorClass::Schema,
+            ),
+        ];
+        for (error, class) in cases {
+            assert_eq!(class, classify_sdk(&error), "{}", error);
+            // Wrapped in context, the SDK error still decides
+            let error = anyhow::Error::new(error).context("Failed to ingest");
+            assert_eq!(class, classify(&error), "{:#}", error);
+        }
+    }
+
+    #[test]
+    fn test_status_codes_are_classified() {
+        let cases = [
+            (UNAUTHENTICATED, "", ErrorClass::Auth),
+            (PERMISSION_DENIED, "", ErrorClass::Auth),
+            (DEADLINE_EXCEEDED, "", ErrorClass::AckTimeout),
+            (INVALID_ARGUMENT, "bad request", ErrorClass::Terminal),
+            (
+                INVALID_ARGUMENT,
+                "Schema mismatch for column id",
+                ErrorClass::Schema,
+            ),
+            (
+                FAILED_PRECONDITION,
+                "schema has changed",
+                ErrorClass::Schema,
+            ),
+            (NOT_FOUND, "", ErrorClass::Terminal),
+            (UNIMPLEMENTED, "", ErrorClass::Terminal),
+            // Unavailable, internal, and resource exhausted
+            (14, "", ErrorClass::Retryable),
+            (13, "", ErrorClass::Retryable),
+            (8, "", ErrorClass::Retryable),
+        ];
+        for (code, message, class) in cases {
+            assert_eq!(
+                class,
+                classify_status(code, message),
+                "{} {}",
+                code,
+                message
+            );
+        }
+    }
+
+    #[test]
+    fn test_other_errors_are_classified() {
+        assert_eq!(
+            ErrorClass::Schema,
+            classify(&anyhow::Error::new(SchemaChanged("column dropped".into())))
+        );
+        assert_eq!(
+            ErrorClass::AckTimeout,
+            classify(&anyhow::anyhow!("Server lack of ack timeout after 60000ms"))
+        );
+        assert_eq!(
+            ErrorClass::Retryable,
+            classify(&anyhow::anyhow!("mock ack failure"))
+        );
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        let retried: Vec<&str> = ErrorClass::ALL
+            .into_iter()
+            .filter(|class| class.is_retryable())
+            .map(ErrorClass::as_str)
+            .collect();
+        assert_eq!(vec!["retryable", "ack_timeout"], retried);
+    }
+}
@@ -0,0 +1,528 @@
+//! A row in an audit table for every record that did not make it into its table.
+//!
+//! Each record that failed for good, was quarantined, or was filtered out by policy gets
+//! one [`FailureAudit`] row in a table of its own, such as `main.ops._ingest_audit`, so
+//! there is a lasting record of it in Delta. The row holds a SHA-256 digest of the
+//! payload rather than the payload itself.
+//!
+//! Audit rows are written by a background task through their own stream, and never hold
+//! up or fail the records being audited: [`FailureAuditor::record`] only queues the row.
+//! The queue is bounded, and drops its oldest row when full. Each row is tried a few
+//! times, recreating the stream after a failure, and counted as a write failure when it
+//! is dropped or every attempt fails.
+//!
+//! - `FAILURE_AUDIT_TABLE` - Table audit rows are written to (default: unset, no audit)
+//! - `FAILURE_AUDIT_QUEUE` - Audit rows waiting to be written before the oldest is
+//!   dropped (default: [`DEFAULT_QUEUE_CAPACITY`])
+
+use anyhow::{Context, Result};
+use prost::Message;
+use prost_types::field_descriptor_proto::Type;
+use prost_types::DescriptorProto;
+use sha2::{Digest, Sha256};
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+use crate::audit;
+use crate::clock::{Clock, SystemClock};
+use crate::errors::classify;
+use crate::pipeline::IngestSink;
+use crate::supervisor::StreamFactory;
+use crate::version::PIPELINE_VERSION;
+
+/// Name of the failure audit message in its descriptor
+pub const FAILURE_AUDIT_MESSAGE_NAME: &str = "table_ingest_audit";
+
+/// Default for `FAILURE_AUDIT_QUEUE`
+pub const DEFAULT_QUEUE_CAPACITY: usize = 1000;
+
+/// Attempts at writing one audit row before it counts as a write failure
+pub const WRITE_ATTEMPTS: u32 = 3;
+
+/// Pause before the second attempt, doubled before each further one
+const RETRY_BACKOFF: Duration = Duration::from_millis(200);
+
+/// How long one attempt waits for its acknowledgment
+const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What became of an audited record
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditOutcome {
+    /// Failed for good: not acknowledged, or not usable at all
+    Failed,
+    /// Set aside for later inspection or replay instead of its table
+    Quarantined,
+    /// Skipped on purpose, e.g. because nothing routes it to a table
+    Filtered,
+}
+
+impl AuditOutcome {
+    /// The value written to the `outcome` column
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AuditOutcome::Failed => "failed",
+            AuditOutcome::Quarantined => "quarantined",
+            AuditOutcome::Filtered => "filtered",
+        }
+    }
+}
+
+/// One audited record
+#[derive(Clone, PartialEq, Message)]
+pub struct FailureAudit {
+    /// Identifier of what the record arrived in, e.g. a Lambda request ID or a Kafka
+    /// topic, partition, and offset
+    #[prost(string, optional, tag = "1")]
+    pub request_id: Option<String>,
+    /// Example that handled the record, e.g. `kafka-bridge`
+    #[prost(string, optional, tag = "2")]
+    pub source: Option<String>,
+    /// Where the record was read from, e.g. a topic or queue ARN
+    #[prost(string, optional, tag = "3")]
+    pub source_id: Option<String>,
+    /// Table the record was meant for, when it was routed to one
+    #[prost(string, optional, tag = "4")]
+    pub target_table: Option<String>,
+    /// [`AuditOutcome::as_str`]
+    #[prost(string, optional, tag = "5")]
+    pub outcome: Option<String>,
+    /// [`crate::errors::ErrorClass`] of the failure, for failed records
+    #[prost(string, optional, tag = "6")]
+    pub error_class: Option<String>,
+    /// Why the record failed or was set aside
+    #[prost(string, optional, tag = "7")]
+    pub error_message: Option<String>,
+    /// Hex SHA-256 digest of the payload
+    #[prost(string, optional, tag = "8")]
+    pub payload_sha256: Option<String>,
+    #[prost(int64, optional, tag = "9")]
+    pub payload_size: Option<i64>,
+    /// When the record was produced, microseconds since Unix epoch, when known
+    #[prost(int64, optional, tag = "10")]
+    pub event_time: Option<i64>,
+    /// When the record was given up on, microseconds since Unix epoch
+    #[prost(int64, optional, tag = "11")]
+    pub audited_at: Option<i64>,
+    /// [`PIPELINE_VERSION`] of the binary that handled the record
+    #[prost(string, optional, tag = "12")]
+    pub pipeline_version: Option<String>,
+    #[prost(int32, optional, tag = "13")]
+    pub audited_date: Option<i32>,
+}
+
+/// Columns of the failure audit table, in tag order; must match [`FailureAudit`]
+const FAILURE_AUDIT_FIELDS: &[(&str, Type)] = &[
+    ("request_id", Type::String),
+    ("source", Type::String),
+    ("source_id", Type::String),
+    ("target_table", Type::String),
+    ("outcome", Type::String),
+    ("error_class", Type::String),
+    ("error_message", Type::String),
+    ("payload_sha256", Type::String),
+    ("payload_size", Type::Int64),
+    ("event_time", Type::Int64),
+    ("audited_at", Type::Int64),
+    ("pipeline_version", Type::String),
+    ("audited_date", Type::Int32),
+];
+
+/// Descriptor for creating a stream to the failure audit table
+pub fn failure_audit_descriptor() -> DescriptorProto {
+    audit::descriptor(FAILURE_AUDIT_MESSAGE_NAME, FAILURE_AUDIT_FIELDS)
+}
+
+fn micros(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_micros() as i64)
+        .unwrap_or_default()
+}
+
+impl FailureAudit {
+    /// A row for a record with `payload` that ended with `outcome`, audited now
+    pub fn new(outcome: AuditOutcome, payload: &[u8]) -> Self {
+        Self::with_clock(outcome, payload, &SystemClock)
+    }
+
+    /// [`FailureAudit::new`], audited at the time `clock` reads
+    pub fn with_clock(outcome: AuditOutcome, payload: &[u8], clock: &dyn Clock) -> Self {
+        let mut sha256 = String::with_capacity(64);
+        for byte in Sha256::digest(payload) {
+            let _ = write!(sha256, "{:02x}", byte);
+        }
+        let audited_at = micros(clock.now());
+        Self {
+            outcome: Some(outcome.as_str().to_string()),
+            payload_sha256: Some(sha256),
+            payload_size: Some(payload.len() as i64),
+            audited_at: Some(audited_at),
+            audited_date: Some((audited_at / 86_400_000_000) as i32),
+            pipeline_version: Some(PIPELINE_VERSION.to_string()),
+            ..Default::default()
+        }
+    }
+
+    /// Record `error` as the reason, with its class
+    pub fn error(mut self, error: &anyhow::Error) -> Self {
+        self.error_class = Some(classify(error).as_str().to_string());
+        self.error_message = Some(format!("{:#}", error));
+        self
+    }
+
+    /// Record `reason` as why the record was set aside, without an error class
+    pub fn reason(mut self, reason: impl Into<String>) -> Self {
+        self.error_message = Some(reason.into());
+        self
+    }
+
+    pub fn produced_at(mut self, event_time: Option<SystemTime>) -> Self {
+        self.event_time = event_time.map(micros);
+        self
+    }
+}
+
+/// Read `FAILURE_AUDIT_TABLE` and `FAILURE_AUDIT_QUEUE`; `None` when no table is set
+pub fn from_env() -> Result<Option<(String, usize)>> {
+    let Some(table) = std::env::var("FAILURE_AUDIT_TABLE")
+        .ok()
+        .filter(|table| !table.trim().is_empty())
+    else {
+        return Ok(None);
+    };
+    let capacity = match std::env::var("FAILURE_AUDIT_QUEUE") {
+        Ok(value) if !value.trim().is_empty() => value
+            .trim()
+            .parse::<usize>()
+            .ok()
+            .filter(|capacity| *capacity > 0)
+            .with_context(|| {
+                format!(
+                    "FAILURE_AUDIT_QUEUE must be a positive integer, got {:?}",
+                    value
+                )
+            })?,
+        _ => DEFAULT_QUEUE_CAPACITY,
+    };
+    Ok(Some((table.trim().to_string(), capacity)))
+}
+
+struct Queue {
+    rows: Mutex<VecDeque<FailureAudit>>,
+    capacity: usize,
+    ready: Notify,
+    closed: AtomicBool,
+    written: AtomicU64,
+    dropped: AtomicU64,
+    write_failures: Arc<AtomicU64>,
+}
+
+/// Queues audit rows for a background writer; clones share the queue
+#[derive(Clone)]
+pub struct FailureAuditor {
+    queue: Arc<Queue>,
+}
+
+impl FailureAuditor {
+    /// Start writing audit rows through streams from `factory`, keeping up to `capacity`
+    /// rows waiting, and counting rows that are never written in `write_failures`
+    ///
+    /// The stream is opened with the first row. The returned task ends once the
+    /// auditor is closed and the rows queued before are written.
+    pub fn spawn<F>(
+        factory: F,
+        capacity: usize,
+        write_failures: Arc<AtomicU64>,
+    ) -> (Self, JoinHandle<()>)
+    where
+        F: StreamFactory + 'static,
+        F::Sink: 'static,
+    {
+        let queue = Arc::new(Queue {
+            rows: Mutex::new(VecDeque::with_capacity(capacity.min(1024))),
+            capacity: capacity.max(1),
+            ready: Notify::new(),
+            closed: AtomicBool::new(false),
+            written: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            write_failures,
+        });
+        let task = tokio::spawn(run(factory, Arc::clone(&queue)));
+        (Self { queue }, task)
+    }
+
+    /// Queue `row` to be written, dropping the oldest queued row if the queue is full
+    ///
+    /// Never waits.
+    pub fn record(&self, row: FailureAudit) {
+        let mut rows = self.queue.rows.lock().unwrap();
+        if rows.len() >= self.queue.capacity {
+            rows.pop_front();
+            self.queue.write_failures.fetch_add(1, Ordering::Relaxed);
+            if self.queue.dropped.fetch_add(1, Ordering::Relaxed) == 0 {
+                warn!(
+                    "Failure audit queue is full ({} rows); dropping the oldest rows",
+                    self.queue.capacity
+                );
+            }
+        }
+        rows.push_back(row);
+        drop(rows);
+        self.queue.ready.notify_one();
+    }
+
+    /// Stop taking rows; the writer finishes the queued ones and closes its stream
+    pub fn close(&self) {
+        self.queue.closed.store(true, Ordering::Relaxed);
+        self.queue.ready.notify_one();
+    }
+
+    /// Rows written and acknowledged
+    pub fn written(&self) -> u64 {
+        self.queue.written.load(Ordering::Relaxed)
+    }
+
+    /// Rows dropped from a full queue
+    pub fn dropped(&self) -> u64 {
+        self.queue.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Rows never written: dropped from a full queue, or failed every attempt
+    pub fn write_failures(&self) -> u64 {
+        self.queue.write_failures.load(Ordering::Relaxed)
+    }
+
+    /// Rows waiting to be written
+    pub fn queued(&self) -> usize {
+        self.queue.rows.lock().unwrap().len()
+    }
+}
+
+async fn run<F: StreamFactory>(factory: F, queue: Arc<Queue>) {
+    let mut sink = None;
+    loop {
+        let next = queue.rows.lock().unwrap().pop_front();
+        let Some(row) = next else {
+            if queue.closed.load(Ordering::Relaxed) {
+                break;
+            }
+            queue.ready.notified().await;
+            continue;
+        };
+        if write(&factory, &mut sink, &row).await {
+            queue.written.fetch_add(1, Ordering::Relaxed);
+        } else {
+            queue.write_failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    if let Some(mut sink) = sink {
+        if let Err(e) = sink.close().await {
+            warn!("Failed to close the failure audit stream: {:#}", e);
+        }
+    }
+}
+
+/// Write one row, opening the stream if needed and recreating it after a failure
+async fn write<F: StreamFactory>(
+    factory: &F,
+    sink: &mut Option<F::Sink>,
+    row: &FailureAudit,
+) -> bool {
+    let record = row.encode_to_vec();
+    let mut backoff = RETRY_BACKOFF;
+    for attempt in 1..=WRITE_ATTEMPTS {
+        let result = async {
+            if sink.is_none() {
+                *sink = Some(factory.create(failure_audit_descriptor()).await?);
+            }
+            let stream = sink.as_mut().expect("stream was just opened");
+            let ack = stream.ingest(record.clone()).await?;
+            stream.flush().await?;
+            tokio::time::timeout(ACK_TIMEOUT, ack)
+                .await
+                .context("Timed out waiting for the acknowledgment")?
+        }
+        .await;
+        match result {
+            Ok(()) => return true,
+            Err(e) => {
+                warn!(
+                    "Failed to write a failure audit row (attempt {} of {}): {:#}",
+                    attempt, WRITE_ATTEMPTS, e
+                );
+                *sink = None;
+                if attempt < WRITE_ATTEMPTS {
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+            }
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::FixedClock;
+    use crate::testing::MockSink;
+    use anyhow::anyhow;
+
+    /// Hands out clones of one mock sink, failing the first `fail_creates` creations
+    #[derive(Clone, Default)]
+    struct MockFactory {
+        sink: MockSink,
+        fail_creates: usize,
+        creates: Arc<AtomicU64>,
+    }
+
+    impl StreamFactory for MockFactory {
+        type Sink = MockSink;
+
+        async fn create(&self, descriptor: DescriptorProto) -> Result<MockSink> {
+            assert_eq!(Some(FAILURE_AUDIT_MESSAGE_NAME), descriptor.name.as_deref());
+            let created = self.creates.fetch_add(1, Ordering::Relaxed) as usize;
+            if created < self.fail_creates {
+                return Err(anyhow!("server unavailable"));
+            }
+            Ok(self.sink.clone())
+        }
+    }
+
+    #[test]
+    fn test_descriptor_matches_message() {
+        let descriptor = failure_audit_descriptor();
+        assert_eq!(13, descriptor.field.len());
+
+        let row = FailureAudit {
+            payload_size: Some(3),
+            audited_date: Some(7),
+            ..Default::default()
+        };
+        let number = |name: &str| {
+            descriptor
+                .field
+                .iter()
+                .find(|f| f.name.as_deref() == Some(name))
+                .unwrap()
+                .number
+                .unwrap()
+        };
+        let tag = |number: i32| (number as u8) << 3;
+        assert_eq!(
+            vec![
+                tag(number("payload_size")),
+                3,
+                tag(number("audited_date")),
+                7
+            ],
+            row.encode_to_vec()
+        );
+    }
+
+    #[test]
+    fn test_row_describes_the_payload_and_error() {
+        let clock = FixedClock::at_unix_secs(1_718_020_860);
+        let row = FailureAudit::with_clock(AuditOutcome::Failed, b"abc", &clock)
+            .error(&anyhow!("stream closed"))
+            .produced_at(Some(UNIX_EPOCH + Duration::from_secs(1)));
+        assert_eq!(Some("failed"), row.outcome.as_deref());
+        assert_eq!(
+            Some("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"),
+            row.payload_sha256.as_deref()
+        );
+        assert_eq!(Some(3), row.payload_size);
+        assert_eq!(Some("stream closed"), row.error_message.as_deref());
+        assert!(row.error_class.is_some());
+        assert_eq!(Some(1_000_000), row.event_time);
+        assert_eq!(Some(1_718_020_860_000_000), row.audited_at);
+        assert_eq!(Some(19_884), row.audited_date);
+
+        let row = FailureAudit::new(AuditOutcome::Filtered, b"").reason("unrouted");
+        assert_eq!(None, row.error_class);
+        assert_eq!(Some("unrouted"), row.error_message.as_deref());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stream_is_opened_on_use_and_recreated_after_a_failure() {
+        let factory = MockFactory {
+            fail_creates: 1,
+            ..Default::default()
+        };
+        let (auditor, task) = FailureAuditor::spawn(factory.clone(), 10, Arc::default());
+        assert_eq!(0, factory.creates.load(Ordering::Relaxed));
+
+        let row = FailureAudit::new(AuditOutcome::Quarantined, b"{}");
+        auditor.record(row.clone());
+        auditor.close();
+        task.await.unwrap();
+
+        // The first stream could not be opened, so the row went through a second one
+        assert_eq!(2, factory.creates.load(Ordering::Relaxed));
+        assert_eq!(1, auditor.written());
+        assert_eq!(0, auditor.write_failures());
+        let records = factory.sink.records();
+        assert_eq!(
+            vec![row],
+            records
+                .iter()
+                .map(|r| FailureAudit::decode(r.as_slice()).unwrap())
+                .collect::<Vec<_>>()
+        );
+        assert!(factory.sink.closed());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_row_failing_every_attempt_is_counted() {
+        let factory = MockFactory {
+            sink: MockSink::default().fail_acks_for(|_| true),
+            ..Default::default()
+        };
+        let write_failures = Arc::new(AtomicU64::new(0));
+        let (auditor, task) =
+            FailureAuditor::spawn(factory.clone(), 10, Arc::clone(&write_failures));
+
+        auditor.record(FailureAudit::new(AuditOutcome::Failed, b"x"));
+        auditor.close();
+        task.await.unwrap();
+
+        assert_eq!(1, write_failures.load(Ordering::Relaxed));
+        assert_eq!(0, auditor.written());
+        // A fresh stream for every attempt
+        assert_eq!(
+            WRITE_ATTEMPTS as u64,
+            factory.creates.load(Ordering::Relaxed)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_drops_the_oldest_rows_without_waiting() {
+        // Acknowledgments never arrive, so the writer never catches up
+        let factory = MockFactory {
+            sink: MockSink::default().stall_acks_for(|_| true),
+            ..Default::default()
+        };
+        let (auditor, task) = FailureAuditor::spawn(factory, 2, Arc::default());
+
+        for size in 1..=5 {
+            auditor.record(FailureAudit::new(AuditOutcome::Failed, &vec![0; size]));
+        }
+        assert_eq!(3, auditor.dropped());
+        assert_eq!(3, auditor.write_failures());
+        let queued: Vec<i64> = auditor
+            .queue
+            .rows
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|row| row.payload_size.unwrap())
+            .collect();
+        assert_eq!(vec![4, 5], queued);
+        task.abort();
+    }
+}
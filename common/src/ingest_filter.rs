@@ -0,0 +1,306 @@
+//! Ingesting only the records that match a condition on their payload.
+//!
+//! `INGEST_FILTER` holds an expression such as `$.eventType == "purchase"`, evaluated on
+//! each record's JSON before it is encoded. Records it does not match are dropped and
+//! counted, without reaching a stream, so what is not needed downstream is not paid for.
+//!
+//! An expression is one or more comparisons joined with `&&` and `||`, where `&&` binds
+//! tighter; there are no parentheses. Each comparison is a JSONPath (see
+//! [`crate::json_path`]), an operator (`==`, `!=`, `<`, `<=`, `>`, or `>=`), and a JSON
+//! literal: a string in double or single quotes, a number, `true`, `false`, or `null`.
+//! A path alone matches when it selects a value other than `null` and `false`.
+//!
+//! Numbers compare by value, so `1` equals `1.0`, and strings compare by their bytes.
+//! A comparison is false when the path selects nothing, or when the value and the
+//! literal are of different types, except `!=`, which is true then. A path with a
+//! wildcard matches when any value it selects does, and `!=` when none is equal.
+
+use anyhow::{anyhow, bail, Result};
+use serde_json::Value;
+use std::cmp::Ordering;
+
+use crate::json_path::JsonPath;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operator {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Operator {
+    /// Longer operators first, so `<=` is not read as `<`
+    const ALL: [(&'static str, Operator); 6] = [
+        ("==", Operator::Eq),
+        ("!=", Operator::Ne),
+        ("<=", Operator::Le),
+        (">=", Operator::Ge),
+        ("<", Operator::Lt),
+        (">", Operator::Gt),
+    ];
+}
+
+/// One path, compared with a literal or tested for a value
+#[derive(Debug, Clone, PartialEq)]
+struct Comparison {
+    path: JsonPath,
+    test: Option<(Operator, Value)>,
+}
+
+impl Comparison {
+    fn matches(&self, record: &Value) -> bool {
+        let values = self.path.select_each(record);
+        let Some((operator, literal)) = &self.test else {
+            return values
+                .iter()
+                .any(|value| !matches!(value, Value::Null | Value::Bool(false)));
+        };
+        let any_ordered = |accept: fn(Ordering) -> bool| {
+            values
+                .iter()
+                .any(|value| order(value, literal).is_some_and(accept))
+        };
+        match operator {
+            Operator::Eq => values.iter().any(|value| equal(value, literal)),
+            Operator::Ne => !values.iter().any(|value| equal(value, literal)),
+            Operator::Lt => any_ordered(Ordering::is_lt),
+            Operator::Le => any_ordered(Ordering::is_le),
+            Operator::Gt => any_ordered(Ordering::is_gt),
+            Operator::Ge => any_ordered(Ordering::is_ge),
+        }
+    }
+}
+
+fn equal(value: &Value, literal: &Value) -> bool {
+    match (value, literal) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64() == b.as_f64(),
+        _ => value == literal,
+    }
+}
+
+/// How `value` orders against `literal`, when both are numbers or both are strings
+fn order(value: &Value, literal: &Value) -> Option<Ordering> {
+    match (value, literal) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64()?.partial_cmp(&b.as_f64()?),
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        _ => None,
+    }
+}
+
+/// A parsed `INGEST_FILTER` expression
+#[derive(Debug, Clone, PartialEq)]
+pub struct IngestFilter {
+    expression: String,
+    /// Alternatives, each matching when all of its comparisons do
+    any: Vec<Vec<Comparison>>,
+}
+
+impl IngestFilter {
+    pub fn parse(expression: &str) -> Result<Self> {
+        let invalid =
+            |reason: String| anyhow!("Invalid INGEST_FILTER {:?}: {}", expression, reason);
+        let any = split(expression, "||")
+            .into_iter()
+            .map(|alternative| {
+                split(alternative, "&&")
+                    .into_iter()
+                    .map(|comparison| parse_comparison(comparison).map_err(invalid))
+                    .collect::<Result<Vec<_>>>()
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            expression: expression.trim().to_string(),
+            any,
+        })
+    }
+
+    /// Read `INGEST_FILTER`; `None` when it is unset or empty, and every record is
+    /// ingested
+    pub fn from_env() -> Result<Option<Self>> {
+        match std::env::var("INGEST_FILTER") {
+            Ok(expression) if !expression.trim().is_empty() => Ok(Some(Self::parse(&expression)?)),
+            _ => Ok(None),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.expression
+    }
+
+    /// Whether `record` is to be ingested
+    pub fn matches(&self, record: &Value) -> bool {
+        self.any
+            .iter()
+            .any(|all| all.iter().all(|comparison| comparison.matches(record)))
+    }
+}
+
+/// Split `input` at each `separator` outside of quotes
+fn split<'a>(input: &'a str, separator: &str) -> Vec<&'a str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in input.char_indices() {
+        match quote {
+            Some(_) if escaped => escaped = false,
+            Some(_) if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if input[i..].starts_with(separator) && i >= start => {
+                parts.push(&input[start..i]);
+                start = i + separator.len();
+            }
+            None => {}
+        }
+    }
+    parts.push(&input[start..]);
+    parts
+}
+
+/// Where the operator of `comparison` starts, outside of quotes, and which it is
+fn find_operator(comparison: &str) -> Option<(usize, &'static str, Operator)> {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in comparison.char_indices() {
+        match quote {
+            Some(_) if escaped => escaped = false,
+            Some(_) if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None => {
+                if let Some((token, operator)) = Operator::ALL
+                    .iter()
+                    .find(|(token, _)| comparison[i..].starts_with(token))
+                {
+                    return Some((i, token, *operator));
+                }
+            }
+        }
+    }
+    None
+}
+
+fn parse_comparison(comparison: &str) -> std::result::Result<Comparison, String> {
+    let comparison = comparison.trim();
+    if comparison.is_empty() {
+        return Err("a comparison is missing around && or ||".to_string());
+    }
+    let Some((at, token, operator)) = find_operator(comparison) else {
+        let path = JsonPath::parse(comparison).map_err(|e| e.to_string())?;
+        return Ok(Comparison { path, test: None });
+    };
+    let path = JsonPath::parse(&comparison[..at]).map_err(|e| e.to_string())?;
+    let literal = parse_literal(comparison[at + token.len()..].trim())
+        .map_err(|e| format!("after {}: {}", token, e))?;
+    Ok(Comparison {
+        path,
+        test: Some((operator, literal)),
+    })
+}
+
+/// A JSON literal, or a string in single quotes
+fn parse_literal(literal: &str) -> Result<Value> {
+    if let Some(inner) = literal
+        .strip_prefix('\'')
+        .and_then(|rest| rest.strip_suffix('\''))
+    {
+        let mut unescaped = String::with_capacity(inner.len());
+        let mut chars = inner.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => unescaped.extend(chars.next()),
+                c => unescaped.push(c),
+            }
+        }
+        return Ok(Value::String(unescaped));
+    }
+    match serde_json::from_str::<Value>(literal) {
+        Ok(value @ (Value::Array(_) | Value::Object(_))) => {
+            bail!("{} is not a string, number, boolean, or null", value)
+        }
+        Ok(value) => Ok(value),
+        Err(_) if literal.is_empty() => bail!("a value is missing"),
+        Err(_) => bail!("{} is not a JSON value", literal),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn matches(expression: &str, record: &Value) -> bool {
+        IngestFilter::parse(expression).unwrap().matches(record)
+    }
+
+    #[test]
+    fn test_comparisons() {
+        let purchase = json!({
+            "eventType": "purchase",
+            "amount": 12,
+            "user": {"tier": "gold", "beta": false},
+            "items": [{"sku": "a"}, {"sku": "b"}],
+            "note": "a && b"
+        });
+        assert!(matches(r#"$.eventType == "purchase""#, &purchase));
+        assert!(matches("$.eventType == 'purchase'", &purchase));
+        assert!(!matches(r#"$.eventType != "purchase""#, &purchase));
+        assert!(matches("$.amount == 12.0", &purchase));
+        assert!(matches("$.amount >= 12 && $.amount < 100", &purchase));
+        assert!(!matches("$.amount > 12", &purchase));
+        assert!(matches(r#"$.user.tier <= "silver""#, &purchase));
+        // A number is not compared with a string
+        assert!(!matches(r#"$.amount > "1""#, &purchase));
+        assert!(matches(r#"$.items[*].sku == "b""#, &purchase));
+        assert!(!matches(r#"$.items[*].sku != "b""#, &purchase));
+        assert!(matches(r#"$.note == "a && b""#, &purchase));
+
+        // A path alone tests for a value
+        assert!(matches("$.user.tier", &purchase));
+        assert!(!matches("$.user.beta", &purchase));
+        assert!(!matches("$.coupon", &purchase));
+
+        // A missing value is unequal to everything, and in no order
+        assert!(matches(r#"$.coupon != "FREE""#, &purchase));
+        assert!(!matches("$.coupon < 1", &purchase));
+    }
+
+    #[test]
+    fn test_and_binds_tighter_than_or() {
+        let expression = r#"$.type == "refund" || $.type == "purchase" && $.amount > 100"#;
+        assert!(matches(expression, &json!({"type": "refund", "amount": 1})));
+        assert!(matches(
+            expression,
+            &json!({"type": "purchase", "amount": 500})
+        ));
+        assert!(!matches(
+            expression,
+            &json!({"type": "purchase", "amount": 5})
+        ));
+        assert!(!matches(
+            expression,
+            &json!({"type": "view", "amount": 500})
+        ));
+    }
+
+    #[test]
+    fn test_invalid_expressions() {
+        for (expression, reason) in [
+            ("eventType == 1", "it must start with $"),
+            ("$.eventType ==", "a value is missing"),
+            ("$.eventType == purchase", "purchase is not a JSON value"),
+            ("$.tags == [1]", "is not a string, number, boolean, or null"),
+            ("$.a == 1 &&", "a comparison is missing"),
+        ] {
+            let error = IngestFilter::parse(expression).unwrap_err().to_string();
+            assert!(error.contains("Invalid INGEST_FILTER"), "{}", error);
+            assert!(error.contains(reason), "{}: {}", expression, error);
+        }
+    }
+}
@@ -0,0 +1,148 @@
+//! A guard against deeply nested JSON payloads.
+//!
+//! Ingestors that store caller-supplied JSON verbatim have no schema to bound its shape,
+//! so a payload nested thousands of levels deep costs as much to serialize, store, and
+//! query as the caller likes. `MAX_JSON_DEPTH` caps the nesting before a record is
+//! encoded, either rejecting the payload or cutting it off at the limit.
+
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+
+/// Replaces every object or array cut off by [`DepthMode::Truncate`]
+pub const TRUNCATED_MARKER: &str = "[truncated]";
+
+/// What to do with a payload nested deeper than the limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DepthMode {
+    /// Fail the record
+    Reject,
+    /// Replace the objects and arrays beyond the limit with [`TRUNCATED_MARKER`]
+    Truncate,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DepthLimit {
+    /// Levels of nested objects and arrays allowed; `{"a": [1]}` is 2 levels deep
+    pub max_depth: usize,
+    pub mode: DepthMode,
+}
+
+impl DepthLimit {
+    /// Read `MAX_JSON_DEPTH` and `JSON_DEPTH_MODE` (`reject`, the default, or `truncate`)
+    ///
+    /// Returns `None` when `MAX_JSON_DEPTH` is unset or empty, in which case payloads are
+    /// not checked.
+    pub fn from_env() -> Result<Option<Self>> {
+        let Some(max_depth) = std::env::var("MAX_JSON_DEPTH")
+            .ok()
+            .filter(|value| !value.trim().is_empty())
+        else {
+            return Ok(None);
+        };
+        let max_depth = max_depth.trim().parse().with_context(|| {
+            format!(
+                "MAX_JSON_DEPTH must be a non-negative integer, got {:?}",
+                max_depth
+            )
+        })?;
+        let mode = match std::env::var("JSON_DEPTH_MODE") {
+            Ok(mode) => match mode.trim().to_ascii_lowercase().as_str() {
+                "" | "reject" => DepthMode::Reject,
+                "truncate" => DepthMode::Truncate,
+                _ => bail!(
+                    "JSON_DEPTH_MODE must be \"reject\" or \"truncate\", got {:?}",
+                    mode
+                ),
+            },
+            Err(_) => DepthMode::Reject,
+        };
+        Ok(Some(Self { max_depth, mode }))
+    }
+
+    /// Check `value` against the limit, truncating it in place in [`DepthMode::Truncate`]
+    ///
+    /// Returns the number of objects and arrays that were cut off.
+    pub fn apply(&self, value: &mut Value) -> Result<usize> {
+        if depth(value) <= self.max_depth {
+            return Ok(0);
+        }
+        match self.mode {
+            DepthMode::Reject => bail!(
+                "JSON payload is nested deeper than MAX_JSON_DEPTH ({})",
+                self.max_depth
+            ),
+            DepthMode::Truncate => Ok(truncate(value, self.max_depth)),
+        }
+    }
+}
+
+/// Levels of nested objects and arrays in `value`; scalars are 0 levels deep
+pub fn depth(value: &Value) -> usize {
+    // Iterative, so a hostile payload cannot overflow the stack
+    let mut deepest = 0;
+    let mut pending = vec![(value, 1)];
+    while let Some((value, level)) = pending.pop() {
+        match value {
+            Value::Array(items) => pending.extend(items.iter().map(|item| (item, level + 1))),
+            Value::Object(fields) => {
+                pending.extend(fields.values().map(|field| (field, level + 1)))
+            }
+            _ => continue,
+        }
+        deepest = deepest.max(level);
+    }
+    deepest
+}
+
+/// Replace containers more than `remaining` levels down with the marker
+fn truncate(value: &mut Value, remaining: usize) -> usize {
+    if !(value.is_array() || value.is_object()) {
+        return 0;
+    }
+    if remaining == 0 {
+        *value = Value::String(TRUNCATED_MARKER.to_string());
+        return 1;
+    }
+    match value {
+        Value::Array(items) => items
+            .iter_mut()
+            .map(|item| truncate(item, remaining - 1))
+            .sum(),
+        Value::Object(fields) => fields
+            .values_mut()
+            .map(|field| truncate(field, remaining - 1))
+            .sum(),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_depth() {
+        assert_eq!(0, depth(&json!("scalar")));
+        assert_eq!(1, depth(&json!({"a": 1, "b": "c"})));
+        assert_eq!(2, depth(&json!({"a": []})));
+        assert_eq!(2, depth(&json!({"a": [1]})));
+        assert_eq!(3, depth(&json!([{"a": 1}, {"b": {"c": null}}])));
+    }
+
+    #[test]
+    fn test_truncate_at_limit() {
+        let limit = DepthLimit {
+            max_depth: 2,
+            mode: DepthMode::Truncate,
+        };
+        let mut value = json!({"a": {"b": {"c": 1}, "d": [1, [2]]}, "e": 3});
+
+        assert_eq!(2, limit.apply(&mut value).unwrap());
+        assert_eq!(
+            json!({"a": {"b": "[truncated]", "d": "[truncated]"}, "e": 3}),
+            value
+        );
+        assert_eq!(2, depth(&value));
+    }
+}
@@ -0,0 +1,343 @@
+//! Extracting a sub-document of a JSON payload with a JSONPath expression.
+//!
+//! Ingestors that store a whole event often only need part of it, such as the `detail`
+//! of an EventBridge envelope. `PAYLOAD_JSONPATH` picks that part out before the record
+//! is encoded, and `PAYLOAD_JSONPATH_MISS` decides what happens to a payload the path
+//! does not match.
+//!
+//! The supported subset of JSONPath is the root `$`, child names (`.name`, `['name']`,
+//! or `["name"]`), array indexes (`[0]`, or `[-1]` counting from the end), and the
+//! wildcard (`.*` or `[*]`). Recursive descent (`..`), slices, and filters are not.
+
+use anyhow::{bail, Result};
+use serde_json::Value;
+
+/// What to do with a payload the path does not match
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MissPolicy {
+    /// Fail the record
+    Fail,
+    /// Leave the record out
+    Skip,
+}
+
+/// One step of a path
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    /// A member of an object
+    Name(String),
+    /// An element of an array; negative indexes count from the end
+    Index(i64),
+    /// Every member of an object or element of an array
+    Wildcard,
+}
+
+/// A parsed JSONPath expression
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JsonPath {
+    expression: String,
+    segments: Vec<Segment>,
+}
+
+impl JsonPath {
+    pub fn parse(expression: &str) -> Result<Self> {
+        let invalid =
+            |reason: &str| anyhow::anyhow!("Invalid JSONPath {:?}: {}", expression, reason);
+        let Some(mut rest) = expression.trim().strip_prefix('$') else {
+            return Err(invalid("it must start with $"));
+        };
+
+        let mut segments = Vec::new();
+        while !rest.is_empty() {
+            if rest.starts_with("..") {
+                return Err(invalid("recursive descent (..) is not supported"));
+            }
+            if let Some(after) = rest.strip_prefix('.') {
+                let end = after.find(['.', '[']).unwrap_or(after.len());
+                let name = &after[..end];
+                segments.push(match name {
+                    "" => return Err(invalid("a name must follow .")),
+                    "*" => Segment::Wildcard,
+                    _ => Segment::Name(name.to_string()),
+                });
+                rest = &after[end..];
+            } else if let Some(after) = rest.strip_prefix('[') {
+                let (segment, after) = parse_bracket(after).map_err(|reason| invalid(&reason))?;
+                segments.push(segment);
+                rest = after;
+            } else {
+                return Err(invalid("expected . or [ after each step"));
+            }
+        }
+
+        Ok(Self {
+            expression: expression.trim().to_string(),
+            segments,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.expression
+    }
+
+    /// The part of `value` the path selects, or `None` if it selects nothing
+    ///
+    /// A path without a wildcard selects at most one value, which is returned as is. A
+    /// path with a wildcard returns an array of everything it selected, in document
+    /// order. A member whose value is `null` is selected like any other.
+    pub fn select(&self, value: &Value) -> Option<Value> {
+        let current = self.select_each(value);
+        if current.is_empty() {
+            return None;
+        }
+
+        if self.segments.contains(&Segment::Wildcard) {
+            Some(Value::Array(current.into_iter().cloned().collect()))
+        } else {
+            current.first().map(|&value| value.clone())
+        }
+    }
+
+    /// Every value the path selects in `value`, in document order, without collecting
+    /// them into an array
+    pub fn select_each<'a>(&self, value: &'a Value) -> Vec<&'a Value> {
+        let mut current = vec![value];
+        for segment in &self.segments {
+            current = current
+                .into_iter()
+                .flat_map(|value| step(value, segment))
+                .collect();
+            if current.is_empty() {
+                break;
+            }
+        }
+        current
+    }
+}
+
+/// The values `segment` selects from `value`
+fn step<'a>(value: &'a Value, segment: &Segment) -> Vec<&'a Value> {
+    match (segment, value) {
+        (Segment::Name(name), Value::Object(fields)) => fields.get(name).into_iter().collect(),
+        (Segment::Index(index), Value::Array(items)) => {
+            let index = if *index < 0 {
+                items.len() as i64 + index
+            } else {
+                *index
+            };
+            usize::try_from(index)
+                .ok()
+                .and_then(|index| items.get(index))
+                .into_iter()
+                .collect()
+        }
+        (Segment::Wildcard, Value::Object(fields)) => fields.values().collect(),
+        (Segment::Wildcard, Value::Array(items)) => items.iter().collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Parse what follows a `[`, up to and including the `]`
+fn parse_bracket(input: &str) -> std::result::Result<(Segment, &str), String> {
+    if let Some(quote) = input.chars().next().filter(|c| *c == '\'' || *c == '"') {
+        let mut name = String::new();
+        let mut chars = input[1..].char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some((_, escaped)) => name.push(escaped),
+                    None => break,
+                },
+                c if c == quote => {
+                    let after = &input[1 + i + 1..];
+                    return match after.strip_prefix(']') {
+                        Some(after) => Ok((Segment::Name(name), after)),
+                        None => Err("expected ] after a quoted name".to_string()),
+                    };
+                }
+                c => name.push(c),
+            }
+        }
+        return Err("unterminated quoted name".to_string());
+    }
+
+    let Some(end) = input.find(']') else {
+        return Err("unterminated [".to_string());
+    };
+    let inside = input[..end].trim();
+    let segment = if inside == "*" {
+        Segment::Wildcard
+    } else {
+        Segment::Index(inside.parse().map_err(|_| {
+            format!(
+                "[{}] is not an index; slices and filters are not supported",
+                inside
+            )
+        })?)
+    };
+    Ok((segment, &input[end + 1..]))
+}
+
+/// `PAYLOAD_JSONPATH` and the policy for payloads it does not match
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PayloadPath {
+    pub path: JsonPath,
+    pub on_miss: MissPolicy,
+}
+
+impl PayloadPath {
+    /// Read `PAYLOAD_JSONPATH` and `PAYLOAD_JSONPATH_MISS` (`fail`, the default, or
+    /// `skip`)
+    ///
+    /// Returns `None` when `PAYLOAD_JSONPATH` is unset or empty, in which case the whole
+    /// payload is kept.
+    pub fn from_env() -> Result<Option<Self>> {
+        let Some(expression) = std::env::var("PAYLOAD_JSONPATH")
+            .ok()
+            .filter(|value| !value.trim().is_empty())
+        else {
+            return Ok(None);
+        };
+        let path = JsonPath::parse(&expression)?;
+        let on_miss = match std::env::var("PAYLOAD_JSONPATH_MISS") {
+            Ok(policy) => match policy.trim().to_ascii_lowercase().as_str() {
+                "" | "fail" => MissPolicy::Fail,
+                "skip" => MissPolicy::Skip,
+                _ => bail!(
+                    "PAYLOAD_JSONPATH_MISS must be \"fail\" or \"skip\", got {:?}",
+                    policy
+                ),
+            },
+            Err(_) => MissPolicy::Fail,
+        };
+        Ok(Some(Self { path, on_miss }))
+    }
+
+    /// The sub-document of `payload` to ingest
+    ///
+    /// Returns `None` for a payload the path does not match in [`MissPolicy::Skip`],
+    /// and fails in [`MissPolicy::Fail`].
+    pub fn extract(&self, payload: &Value) -> Result<Option<Value>> {
+        match (self.path.select(payload), self.on_miss) {
+            (Some(selected), _) => Ok(Some(selected)),
+            (None, MissPolicy::Skip) => Ok(None),
+            (None, MissPolicy::Fail) => bail!(
+                "PAYLOAD_JSONPATH {} matches nothing in the payload",
+                self.path.as_str()
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn select(expression: &str, value: &Value) -> Option<Value> {
+        JsonPath::parse(expression).unwrap().select(value)
+    }
+
+    fn envelope() -> Value {
+        json!({
+            "detail-type": "Order Placed",
+            "detail": {
+                "data": {"order_id": "o-1", "items": [{"sku": "a"}, {"sku": "b"}]},
+                "meta": null
+            }
+        })
+    }
+
+    #[test]
+    fn test_select() {
+        let envelope = envelope();
+        assert_eq!(
+            Some(json!({"order_id": "o-1", "items": [{"sku": "a"}, {"sku": "b"}]})),
+            select("$.detail.data", &envelope)
+        );
+        assert_eq!(Some(envelope.clone()), select("$", &envelope));
+        assert_eq!(
+            Some(json!("Order Placed")),
+            select("$['detail-type']", &envelope)
+        );
+        assert_eq!(
+            Some(json!({"sku": "b"})),
+            select(r#"$["detail"].data.items[1]"#, &envelope)
+        );
+        assert_eq!(
+            Some(json!("b")),
+            select("$.detail.data.items[-1].sku", &envelope)
+        );
+        assert_eq!(
+            Some(json!(["a", "b"])),
+            select("$.detail.data.items[*].sku", &envelope)
+        );
+        assert_eq!(Some(Value::Null), select("$.detail.meta", &envelope));
+    }
+
+    #[test]
+    fn test_select_misses() {
+        let envelope = envelope();
+        for expression in [
+            "$.detail.missing",
+            "$.detail.data.items[2]",
+            "$.detail.data.items[-3]",
+            "$.detail.data.order_id.value",
+            "$.detail.data[0]",
+            "$.detail.data.items[*].price",
+        ] {
+            assert_eq!(None, select(expression, &envelope), "{}", expression);
+        }
+    }
+
+    #[test]
+    fn test_invalid_paths() {
+        for expression in [
+            "detail.data",
+            "$.",
+            "$..data",
+            "$.detail[0:2]",
+            "$.detail[?(@.data)]",
+            "$['detail",
+            "$['detail'x]",
+            "$detail",
+        ] {
+            assert!(JsonPath::parse(expression).is_err(), "{}", expression);
+        }
+        assert_eq!(Some(json!(1)), select(r#"$['it\'s']"#, &json!({"it's": 1})));
+    }
+
+    #[test]
+    fn test_extract_per_miss_policy() {
+        let envelope = envelope();
+        let path = |expression: &str, on_miss| PayloadPath {
+            path: JsonPath::parse(expression).unwrap(),
+            on_miss,
+        };
+
+        let matched = path("$.detail.data", MissPolicy::Skip)
+            .extract(&envelope)
+            .unwrap();
+        assert_eq!(
+            Some(json!("o-1")),
+            matched.map(|data| data["order_id"].clone())
+        );
+
+        assert_eq!(
+            None,
+            path("$.detail.payload", MissPolicy::Skip)
+                .extract(&envelope)
+                .unwrap()
+        );
+        let error = path("$.detail.payload", MissPolicy::Fail)
+            .extract(&envelope)
+            .unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("$.detail.payload matches nothing"),
+            "{}",
+            error
+        );
+    }
+}
@@ -0,0 +1,64 @@
+//! Building blocks shared by the Zerobus examples.
+//!
+//! Each example stays self-contained for its source-specific parsing and schema, and
+//! uses this crate for the parts that are identical everywhere: loading embedded
+//! descriptors and pushing encoded records through a stream with bounded in-flight acks.
+
+pub mod audit;
+pub mod auth;
+#[cfg(feature = "avro")]
+pub mod avro;
+pub mod cardinality;
+pub mod chunk;
+pub mod clock;
+#[cfg(feature = "compress")]
+pub mod compress;
+pub mod credentials;
+pub mod dedup;
+pub mod descriptor;
+pub mod distribution;
+pub mod durable;
+pub mod dynamic;
+#[cfg(feature = "enrich")]
+pub mod enrich;
+#[cfg(feature = "endpoint-discovery")]
+pub mod endpoint;
+pub mod errors;
+#[cfg(feature = "failure-audit")]
+pub mod failure_audit;
+pub mod ingest_filter;
+pub mod json_depth;
+pub mod json_path;
+#[cfg(feature = "localstack")]
+pub mod localstack;
+#[cfg(feature = "log-level")]
+pub mod log_level;
+#[cfg(feature = "prometheus")]
+pub mod metrics;
+pub mod payload_log;
+pub mod pipeline;
+pub mod redact;
+pub mod rng;
+pub mod router;
+#[cfg(feature = "otel")]
+pub mod otel;
+#[cfg(feature = "s3")]
+pub mod s3;
+#[cfg(feature = "schema-check")]
+pub mod schema_check;
+#[cfg(feature = "self-test")]
+pub mod self_test;
+#[cfg(feature = "shutdown")]
+pub mod shutdown;
+#[cfg(feature = "stats")]
+pub mod stats;
+pub mod supervisor;
+pub mod transaction;
+pub mod unacked;
+pub mod version;
+pub mod watermark;
+#[cfg(any(feature = "endpoint-discovery", feature = "schema-check"))]
+mod workspace;
+
+#[cfg(any(test, feature = "test-util"))]
+pub mod testing;
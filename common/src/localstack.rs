@@ -0,0 +1,259 @@
+//! Resources in LocalStack for the integration tests behind the `localstack` feature.
+//!
+//! The tests run the real AWS SDK code paths against `LOCALSTACK_ENDPOINT` (default:
+//! `http://localhost:4566`) instead of the hand-written mocks the unit tests use. Each
+//! test creates the queue, bucket, or table it needs under a name no other test uses, so
+//! suites can run in parallel against one LocalStack, and deletes it at the end. A test
+//! that fails before then leaves its resources behind; restarting LocalStack clears them.
+
+use anyhow::{Context, Result};
+use aws_config::{BehaviorVersion, Region, SdkConfig};
+use aws_sdk_dynamodb::types::{
+    AttributeDefinition, BillingMode, KeySchemaElement, KeyType, ScalarAttributeType, TableStatus,
+};
+use aws_sdk_sqs::types::{Message, QueueAttributeName};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Endpoint used when `LOCALSTACK_ENDPOINT` is not set
+pub const DEFAULT_ENDPOINT: &str = "http://localhost:4566";
+
+/// SDK configuration for LocalStack: its endpoint, `us-east-1`, and the placeholder
+/// credentials it accepts
+pub async fn config() -> SdkConfig {
+    let endpoint = std::env::var("LOCALSTACK_ENDPOINT")
+        .ok()
+        .filter(|endpoint| !endpoint.trim().is_empty())
+        .unwrap_or_else(|| DEFAULT_ENDPOINT.to_string());
+    aws_config::defaults(BehaviorVersion::latest())
+        .endpoint_url(endpoint)
+        .region(Region::new("us-east-1"))
+        .credentials_provider(aws_sdk_s3::config::Credentials::new(
+            "test",
+            "test",
+            None,
+            None,
+            "localstack",
+        ))
+        .load()
+        .await
+}
+
+/// A name for a test resource, unique across the tests of this process and across runs
+///
+/// Lowercase letters, digits, and hyphens only, which every service accepts.
+pub fn unique_name(prefix: &str) -> String {
+    static NEXT: AtomicU32 = AtomicU32::new(0);
+    let run = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+        % 1_000_000_000;
+    format!(
+        "zb-{}-{}-{}-{}",
+        prefix,
+        std::process::id(),
+        run,
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// A queue created for one test
+pub struct TestQueue {
+    pub client: aws_sdk_sqs::Client,
+    pub url: String,
+}
+
+impl TestQueue {
+    /// A standard queue, or a FIFO queue when `fifo` is set
+    pub async fn create(config: &SdkConfig, prefix: &str, fifo: bool) -> Result<Self> {
+        let client = aws_sdk_sqs::Client::new(config);
+        let mut name = unique_name(prefix);
+        let mut attributes = HashMap::new();
+        if fifo {
+            name.push_str(".fifo");
+            attributes.insert(QueueAttributeName::FifoQueue, "true".to_string());
+        }
+        let output = client
+            .create_queue()
+            .queue_name(&name)
+            .set_attributes(Some(attributes))
+            .send()
+            .await
+            .with_context(|| format!("Failed to create queue {}", name))?;
+        let url = output
+            .queue_url
+            .with_context(|| format!("No URL for queue {}", name))?;
+        Ok(Self { client, url })
+    }
+
+    /// The messages waiting in the queue, with all of their attributes; waits up to a
+    /// few seconds for the first
+    pub async fn receive(&self) -> Result<Vec<Message>> {
+        let output = self
+            .client
+            .receive_message()
+            .queue_url(&self.url)
+            .max_number_of_messages(10)
+            .wait_time_seconds(5)
+            .message_attribute_names("All")
+            .message_system_attribute_names(
+                aws_sdk_sqs::types::MessageSystemAttributeName::All,
+            )
+            .send()
+            .await
+            .with_context(|| format!("Failed to receive from {}", self.url))?;
+        Ok(output.messages.unwrap_or_default())
+    }
+
+    pub async fn delete(self) -> Result<()> {
+        self.client
+            .delete_queue()
+            .queue_url(&self.url)
+            .send()
+            .await
+            .with_context(|| format!("Failed to delete queue {}", self.url))?;
+        Ok(())
+    }
+}
+
+/// A bucket created for one test
+pub struct TestBucket {
+    pub client: aws_sdk_s3::Client,
+    pub name: String,
+}
+
+impl TestBucket {
+    pub async fn create(config: &SdkConfig, prefix: &str) -> Result<Self> {
+        // LocalStack serves buckets by path, not as subdomains of the endpoint
+        let s3_config = aws_sdk_s3::config::Builder::from(config)
+            .force_path_style(true)
+            .build();
+        let client = aws_sdk_s3::Client::from_conf(s3_config);
+        let name = unique_name(prefix);
+        client
+            .create_bucket()
+            .bucket(&name)
+            .send()
+            .await
+            .with_context(|| format!("Failed to create bucket {}", name))?;
+        Ok(Self { client, name })
+    }
+
+    pub async fn put(&self, key: &str, body: impl Into<Vec<u8>>) -> Result<()> {
+        self.client
+            .put_object()
+            .bucket(&self.name)
+            .key(key)
+            .body(body.into().into())
+            .send()
+            .await
+            .with_context(|| format!("Failed to put s3://{}/{}", self.name, key))?;
+        Ok(())
+    }
+
+    /// Delete every object, then the bucket
+    pub async fn delete(self) -> Result<()> {
+        let listed = self
+            .client
+            .list_objects_v2()
+            .bucket(&self.name)
+            .send()
+            .await
+            .with_context(|| format!("Failed to list {}", self.name))?;
+        for object in listed.contents() {
+            if let Some(key) = object.key() {
+                self.client
+                    .delete_object()
+                    .bucket(&self.name)
+                    .key(key)
+                    .send()
+                    .await
+                    .with_context(|| format!("Failed to delete s3://{}/{}", self.name, key))?;
+            }
+        }
+        self.client
+            .delete_bucket()
+            .bucket(&self.name)
+            .send()
+            .await
+            .with_context(|| format!("Failed to delete bucket {}", self.name))?;
+        Ok(())
+    }
+}
+
+/// A table created for one test, with a string partition key and on-demand capacity
+pub struct TestTable {
+    pub client: aws_sdk_dynamodb::Client,
+    pub name: String,
+}
+
+impl TestTable {
+    /// Create the table and wait until it is active
+    pub async fn create(config: &SdkConfig, prefix: &str, partition_key: &str) -> Result<Self> {
+        let client = aws_sdk_dynamodb::Client::new(config);
+        let name = unique_name(prefix);
+        client
+            .create_table()
+            .table_name(&name)
+            .attribute_definitions(
+                AttributeDefinition::builder()
+                    .attribute_name(partition_key)
+                    .attribute_type(ScalarAttributeType::S)
+                    .build()?,
+            )
+            .key_schema(
+                KeySchemaElement::builder()
+                    .attribute_name(partition_key)
+                    .key_type(KeyType::Hash)
+                    .build()?,
+            )
+            .billing_mode(BillingMode::PayPerRequest)
+            .send()
+            .await
+            .with_context(|| format!("Failed to create table {}", name))?;
+
+        for _ in 0..50 {
+            let described = client
+                .describe_table()
+                .table_name(&name)
+                .send()
+                .await
+                .with_context(|| format!("Failed to describe table {}", name))?;
+            let status = described.table().and_then(|table| table.table_status());
+            if status == Some(&TableStatus::Active) {
+                return Ok(Self { client, name });
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        anyhow::bail!("Table {} did not become active", name)
+    }
+
+    pub async fn delete(self) -> Result<()> {
+        self.client
+            .delete_table()
+            .table_name(&self.name)
+            .send()
+            .await
+            .with_context(|| format!("Failed to delete table {}", self.name))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unique_names() {
+        let first = unique_name("dlq");
+        let second = unique_name("dlq");
+        assert_ne!(first, second);
+        assert!(first.starts_with("zb-dlq-"), "{}", first);
+        assert!(first.len() <= 63, "{}", first);
+        assert!(first
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-'));
+    }
+}
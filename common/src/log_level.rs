@@ -0,0 +1,604 @@
+//! Changing the log filter of a running ingestor.
+//!
+//! [`init`] installs the examples' usual log format behind an [`EnvFilter`] that can be
+//! swapped at runtime, so a long-running ingestor can be made verbose while a problem
+//! is looked into and quiet again afterwards, without a restart. Ingestors expose it
+//! through SIGHUP ([`on_hangup`]) or an admin endpoint calling [`LogLevel::set_filter`].
+//!
+//! The filter is read from, in order of precedence:
+//!
+//! - `LOG_FILTER_FILE` - File holding a filter such as `info,zerobus_common=debug`;
+//!   read again on SIGHUP
+//! - `RUST_LOG` - Filter in the same syntax
+//! - `LOG_LEVEL` - A single level (default: `info`)
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::level_filters::LevelFilter;
+use tracing::Subscriber;
+use tracing::{info, warn};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::reload::{self, Handle};
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Registry};
+
+/// Level logged at when none of the variables are set
+pub const DEFAULT_LEVEL: LevelFilter = LevelFilter::INFO;
+
+/// The filter in effect, as it was given
+struct Current {
+    directives: String,
+    /// Bumped on every change, so a pending revert can tell it was overtaken
+    generation: u64,
+}
+
+/// Handle to the filter of the subscriber installed by [`init`]
+#[derive(Clone)]
+pub struct LogLevel {
+    handle: Handle<EnvFilter, Registry>,
+    initial: String,
+    current: Arc<Mutex<Current>>,
+}
+
+impl LogLevel {
+    /// The most verbose level logged, for any target
+    pub fn get(&self) -> LevelFilter {
+        max_level(&self.filter())
+    }
+
+    /// The filter in effect, such as `info` or `warn,kafka_bridge=debug`
+    pub fn filter(&self) -> String {
+        self.current.lock().unwrap().directives.clone()
+    }
+
+    /// Log events at `level` and above from now on, for every target
+    pub fn set(&self, level: LevelFilter) -> Result<()> {
+        self.set_filter(&level.to_string())
+    }
+
+    /// Filter events with `directives` from now on
+    ///
+    /// Directives that do not parse are rejected, leaving the filter in effect as it is.
+    pub fn set_filter(&self, directives: &str) -> Result<()> {
+        let mut current = self.current.lock().unwrap();
+        self.apply(&mut current, directives)
+    }
+
+    /// Filter events with `directives` for `revert_after`, then go back to the filter
+    /// in effect before, unless the filter was changed again in the meantime
+    pub fn set_filter_for(&self, directives: &str, revert_after: Duration) -> Result<()> {
+        let (previous, generation) = {
+            let mut current = self.current.lock().unwrap();
+            let previous = current.directives.clone();
+            self.apply(&mut current, directives)?;
+            (previous, current.generation)
+        };
+        info!("Log filter reverts to {} in {:?}", previous, revert_after);
+        let level = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(revert_after).await;
+            let mut current = level.current.lock().unwrap();
+            if current.generation != generation {
+                return;
+            }
+            if let Err(e) = level.apply(&mut current, &previous) {
+                warn!("Failed to revert the log filter: {:#}", e);
+            }
+        });
+        Ok(())
+    }
+
+    /// Switch between the filter the ingestor started with and a more verbose level:
+    /// `debug`, or `trace` if it started at `debug`
+    ///
+    /// Returns the new filter.
+    pub fn toggle_verbose(&self) -> Result<String> {
+        let verbose = if max_level(&self.initial) >= LevelFilter::DEBUG {
+            LevelFilter::TRACE
+        } else {
+            LevelFilter::DEBUG
+        };
+        let mut current = self.current.lock().unwrap();
+        let directives = if current.directives == self.initial {
+            verbose.to_string()
+        } else {
+            self.initial.clone()
+        };
+        self.apply(&mut current, &directives)?;
+        Ok(directives)
+    }
+
+    fn apply(&self, current: &mut Current, directives: &str) -> Result<()> {
+        let directives = directives.trim();
+        let filter = parse_filter(directives)?;
+        if current.directives == directives {
+            return Ok(());
+        }
+        let previous = std::mem::replace(&mut current.directives, directives.to_string());
+        current.generation += 1;
+        // Logged while the more verbose of the two filters is in effect
+        let more_verbose = max_level(directives) > max_level(&previous);
+        if more_verbose {
+            self.handle
+                .reload(filter)
+                .context("Failed to change the log filter")?;
+            info!("Log filter changed from {} to {}", previous, directives);
+        } else {
+            info!("Log filter changing from {} to {}", previous, directives);
+            self.handle
+                .reload(filter)
+                .context("Failed to change the log filter")?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse a filter such as `debug` or `info,kafka_bridge=trace`
+pub fn parse_filter(directives: &str) -> Result<EnvFilter> {
+    if directives.trim().is_empty() {
+        bail!("Log filter is empty");
+    }
+    EnvFilter::builder()
+        .parse(directives.trim())
+        .map_err(|e| anyhow!("Invalid log filter {:?}: {}", directives, e))
+}
+
+/// Parse a level name such as `info` or `DEBUG`; `off` silences logging
+pub fn parse_level(value: &str) -> Result<LevelFilter> {
+    match value.trim().parse::<LevelFilter>() {
+        Ok(level) if !value.trim().is_empty() => Ok(level),
+        _ => bail!(
+            "Log level must be one of off, error, warn, info, debug, or trace, got {:?}",
+            value
+        ),
+    }
+}
+
+/// `LOG_FILTER_FILE`, when it is set
+pub fn filter_file_from_env() -> Option<PathBuf> {
+    std::env::var_os("LOG_FILTER_FILE")
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Read the filter from `LOG_FILTER_FILE`, `RUST_LOG`, or `LOG_LEVEL`, falling back to
+/// [`DEFAULT_LEVEL`]
+pub fn filter_from_env() -> Result<String> {
+    if let Some(path) = filter_file_from_env() {
+        let directives = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read LOG_FILTER_FILE {}", path.display()))?;
+        parse_filter(&directives).with_context(|| format!("In {}", path.display()))?;
+        return Ok(directives.trim().to_string());
+    }
+    match std::env::var("RUST_LOG") {
+        Ok(value) if !value.trim().is_empty() => {
+            parse_filter(&value).context("Invalid RUST_LOG")?;
+            return Ok(value.trim().to_string());
+        }
+        _ => {}
+    }
+    match std::env::var("LOG_LEVEL") {
+        Ok(value) if !value.trim().is_empty() => Ok(parse_level(&value)
+            .context("Invalid LOG_LEVEL")?
+            .to_string()),
+        _ => Ok(DEFAULT_LEVEL.to_string()),
+    }
+}
+
+/// Install the global subscriber, logging to stdout with the filter from the
+/// environment
+pub fn init() -> Result<LogLevel> {
+    let (subscriber, level) = build(&filter_from_env()?, std::io::stdout)?;
+    subscriber
+        .try_init()
+        .context("Failed to install the log subscriber")?;
+    Ok(level)
+}
+
+/// A subscriber formatting events to `writer`, and the handle to its filter
+fn build<W>(initial: &str, writer: W) -> Result<(impl Subscriber + Send + Sync, LogLevel)>
+where
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    let (filter, handle) = reload::Layer::new(parse_filter(initial)?);
+    let subscriber = tracing_subscriber::registry().with(filter).with(
+        tracing_subscriber::fmt::layer()
+            .with_target(false)
+            .with_writer(writer),
+    );
+    let level = LogLevel {
+        handle,
+        initial: initial.trim().to_string(),
+        current: Arc::new(Mutex::new(Current {
+            directives: initial.trim().to_string(),
+            generation: 0,
+        })),
+    };
+    Ok((subscriber, level))
+}
+
+/// The most verbose level `directives` log at; directives that do not parse log nothing
+fn max_level(directives: &str) -> LevelFilter {
+    parse_filter(directives)
+        .ok()
+        .and_then(|filter| filter.max_level_hint())
+        .unwrap_or(LevelFilter::OFF)
+}
+
+/// Set the filter in `LOG_FILTER_FILE` again, if it is set, for ingestors reloading
+/// their config on SIGHUP
+///
+/// A file that does not hold a valid filter leaves the one in effect, and is logged.
+pub fn reload_filter_file(level: &LogLevel) {
+    let Some(path) = filter_file_from_env() else {
+        return;
+    };
+    if let Err(e) = filter_from_env().and_then(|directives| level.set_filter(&directives)) {
+        warn!(
+            "Keeping the log filter {} rather than the one in {}: {:#}",
+            level.filter(),
+            path.display(),
+            e
+        );
+    }
+}
+
+/// On every SIGHUP, for as long as the ingestor runs, read `LOG_FILTER_FILE` again, or
+/// without one, toggle between the starting filter and a verbose level
+///
+/// For ingestors that do not already use SIGHUP for something else, such as reloading
+/// their config. Does nothing on platforms without SIGHUP.
+pub async fn on_hangup(level: LogLevel) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                warn!("Failed to install SIGHUP handler: {}", e);
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            if filter_file_from_env().is_some() {
+                reload_filter_file(&level);
+            } else if let Err(e) = level.toggle_verbose() {
+                warn!("{:#}", e);
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = level;
+}
+
+#[cfg(feature = "log-level-admin")]
+pub use admin::admin_routes_from_env;
+
+/// `PUT /admin/log-level` for the HTTP examples
+#[cfg(feature = "log-level-admin")]
+mod admin {
+    use super::*;
+    use axum::extract::Query;
+    use axum::http::{header, HeaderMap, StatusCode};
+    use axum::routing::get;
+    use axum::Router;
+    use std::collections::HashMap;
+
+    /// Routes changing the log filter, authenticated by the bearer token in
+    /// `ADMIN_TOKEN`; none when it is not set
+    ///
+    /// `GET /admin/log-level` answers the filter in effect. `PUT /admin/log-level` sets
+    /// the filter in the body, answering 400 and leaving the filter as it is when it
+    /// does not parse; with `?revert_after=<seconds>`, the filter in effect before comes
+    /// back after that long. `GET` and `POST /loglevel`, which the SQS poller served
+    /// before, do the same behind the same token.
+    pub fn admin_routes_from_env(level: &LogLevel) -> Option<Router> {
+        let token = match std::env::var("ADMIN_TOKEN") {
+            Ok(token) if !token.trim().is_empty() => token.trim().to_string(),
+            _ => return None,
+        };
+        info!("Serving PUT /admin/log-level and POST /loglevel");
+        Some(admin_routes(level, token))
+    }
+
+    pub(super) fn admin_routes(level: &LogLevel, token: String) -> Router {
+        let token = Arc::new(token);
+        let (get_level, get_token) = (level.clone(), Arc::clone(&token));
+        let level = level.clone();
+        let route = get(move |headers: HeaderMap| async move {
+            match authorized(&get_token, &headers) {
+                Ok(()) => (StatusCode::OK, format!("{}\n", get_level.filter())),
+                Err(rejection) => rejection,
+            }
+        });
+        let set = move |headers: HeaderMap,
+                        Query(query): Query<HashMap<String, String>>,
+                        body: String| async move {
+            put_filter(&level, &token, &headers, query.get("revert_after"), &body)
+        };
+        Router::new()
+            .route("/admin/log-level", route.clone().put(set.clone()))
+            .route("/loglevel", route.post(set))
+    }
+
+    pub(super) fn put_filter(
+        level: &LogLevel,
+        token: &str,
+        headers: &HeaderMap,
+        revert_after: Option<&String>,
+        body: &str,
+    ) -> (StatusCode, String) {
+        if let Err(rejection) = authorized(token, headers) {
+            return rejection;
+        }
+        let revert_after = match revert_after.map(|secs| secs.trim().parse::<u64>()) {
+            None => None,
+            Some(Ok(secs)) if secs > 0 => Some(Duration::from_secs(secs)),
+            Some(_) => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    "revert_after must be a positive number of seconds\n".to_string(),
+                )
+            }
+        };
+        let changed = match revert_after {
+            Some(revert_after) => level.set_filter_for(body, revert_after),
+            None => level.set_filter(body),
+        };
+        match changed {
+            Ok(()) => (StatusCode::OK, format!("{}\n", level.filter())),
+            Err(e) => (StatusCode::BAD_REQUEST, format!("{:#}\n", e)),
+        }
+    }
+
+    fn authorized(token: &str, headers: &HeaderMap) -> Result<(), (StatusCode, String)> {
+        let given = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .unwrap_or_default();
+        if same_token(given.trim().as_bytes(), token.as_bytes()) {
+            Ok(())
+        } else {
+            Err((StatusCode::UNAUTHORIZED, "Unauthorized\n".to_string()))
+        }
+    }
+
+    /// Compare without returning early, so the time taken does not tell how much of the
+    /// token was guessed right
+    fn same_token(given: &[u8], token: &[u8]) -> bool {
+        given.len() == token.len()
+            && given
+                .iter()
+                .zip(token)
+                .fold(0, |differs, (a, b)| differs | (a ^ b))
+                == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tracing::{debug, trace};
+
+    /// Collects what the subscriber writes
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Captured {
+        fn text(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_reload_changes_the_effective_level() {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let (subscriber, level) = build("info", move || writer.clone()).unwrap();
+
+        tracing::subscriber::with_default(subscriber, || {
+            debug!("hidden at info");
+            info!("shown at info");
+            assert_eq!(LevelFilter::INFO, level.get());
+
+            level.set(LevelFilter::DEBUG).unwrap();
+            debug!("shown at debug");
+            trace!("hidden at debug");
+            assert_eq!(LevelFilter::DEBUG, level.get());
+
+            level.set(LevelFilter::WARN).unwrap();
+            info!("hidden at warn");
+        });
+
+        let text = captured.text();
+        assert!(text.contains("shown at info"));
+        assert!(text.contains("shown at debug"));
+        assert!(text.contains("Log filter changed from info to debug"));
+        assert!(text.contains("Log filter changing from debug to warn"));
+        assert!(!text.contains("hidden"), "{}", text);
+    }
+
+    #[test]
+    fn test_filter_applies_per_target() {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let (subscriber, level) = build("info", move || writer.clone()).unwrap();
+
+        tracing::subscriber::with_default(subscriber, || {
+            level.set_filter("warn,chatty=debug").unwrap();
+            debug!(target: "chatty", "shown for chatty");
+            debug!(target: "quiet", "hidden for quiet");
+            info!(target: "quiet", "hidden at warn");
+            assert_eq!(LevelFilter::DEBUG, level.get());
+            assert_eq!("warn,chatty=debug", level.filter());
+        });
+
+        let text = captured.text();
+        assert!(text.contains("shown for chatty"));
+        assert!(!text.contains("hidden"), "{}", text);
+    }
+
+    #[test]
+    fn test_invalid_filter_keeps_the_current_one() {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let (subscriber, level) = build("info", move || writer.clone()).unwrap();
+
+        tracing::subscriber::with_default(subscriber, || {
+            level.set_filter("warn").unwrap();
+            assert!(level.set_filter("info,kafka_bridge=loud").is_err());
+            assert!(level.set_filter("[").is_err());
+            assert!(level.set_filter(" ").is_err());
+            assert_eq!("warn", level.filter());
+            info!("hidden at warn");
+        });
+
+        assert!(!captured.text().contains("hidden"));
+        assert!(build("=debug", std::io::sink).is_err());
+    }
+
+    #[test]
+    fn test_toggle_verbose_switches_back_to_the_starting_level() {
+        let (subscriber, level) = build("info", std::io::sink).unwrap();
+        tracing::subscriber::with_default(subscriber, || {
+            assert_eq!("debug", level.toggle_verbose().unwrap());
+            assert_eq!("info", level.toggle_verbose().unwrap());
+
+            // From a filter set by hand, the first toggle goes back to the start
+            level.set(LevelFilter::ERROR).unwrap();
+            assert_eq!("info", level.toggle_verbose().unwrap());
+        });
+
+        let (_subscriber, level) = build("debug", std::io::sink).unwrap();
+        assert_eq!("trace", level.toggle_verbose().unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_filter_reverts_after_the_timeout() {
+        let (_subscriber, level) = build("info", std::io::sink).unwrap();
+
+        level
+            .set_filter_for("debug", Duration::from_secs(60))
+            .unwrap();
+        tokio::time::sleep(Duration::from_secs(59)).await;
+        assert_eq!("debug", level.filter());
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!("info", level.filter());
+
+        // A change made before the timeout is kept
+        level
+            .set_filter_for("trace", Duration::from_secs(60))
+            .unwrap();
+        level.set_filter("warn").unwrap();
+        tokio::time::sleep(Duration::from_secs(61)).await;
+        assert_eq!("warn", level.filter());
+
+        // So is the filter, when the new one is rejected
+        assert!(level
+            .set_filter_for("info,=", Duration::from_secs(60))
+            .is_err());
+        tokio::time::sleep(Duration::from_secs(61)).await;
+        assert_eq!("warn", level.filter());
+    }
+
+    #[cfg(feature = "log-level-admin")]
+    #[tokio::test(start_paused = true)]
+    async fn test_admin_put_is_authenticated_and_validated() {
+        use axum::http::{HeaderMap, HeaderValue, StatusCode};
+
+        let (_subscriber, level) = build("info", std::io::sink).unwrap();
+        let mut headers = HeaderMap::new();
+        let put = |headers: &HeaderMap, revert_after: Option<&str>, body: &str| {
+            let revert_after = revert_after.map(str::to_string);
+            admin::put_filter(&level, "s3cret", headers, revert_after.as_ref(), body).0
+        };
+
+        assert_eq!(StatusCode::UNAUTHORIZED, put(&headers, None, "debug"));
+        headers.insert("authorization", HeaderValue::from_static("Bearer s3cre"));
+        assert_eq!(StatusCode::UNAUTHORIZED, put(&headers, None, "debug"));
+        assert_eq!("info", level.filter());
+
+        headers.insert("authorization", HeaderValue::from_static("Bearer s3cret"));
+        assert_eq!(StatusCode::BAD_REQUEST, put(&headers, None, "info,x=loud"));
+        assert_eq!(
+            StatusCode::BAD_REQUEST,
+            put(&headers, Some("soon"), "debug")
+        );
+        assert_eq!("info", level.filter());
+
+        assert_eq!(StatusCode::OK, put(&headers, Some("300"), "debug\n"));
+        assert_eq!("debug", level.filter());
+        tokio::time::sleep(Duration::from_secs(301)).await;
+        assert_eq!("info", level.filter());
+    }
+
+    #[cfg(feature = "log-level-admin")]
+    #[tokio::test]
+    async fn test_loglevel_is_an_authenticated_alias() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (_subscriber, level) = build("info", std::io::sink).unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = admin::admin_routes(&level, "s3cret".to_string());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let request = |method: &str, token: &str, body: &str| {
+            let request = format!(
+                "{} /loglevel HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer {}\r\n\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                method,
+                token,
+                body.len(),
+                body
+            );
+            async move {
+                let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+                stream.write_all(request.as_bytes()).await.unwrap();
+                let mut response = String::new();
+                stream.read_to_string(&mut response).await.unwrap();
+                response
+            }
+        };
+
+        let response = request("POST", "wrong", "debug").await;
+        assert!(response.starts_with("HTTP/1.1 401"), "{}", response);
+        assert_eq!("info", level.filter());
+
+        let response = request("POST", "s3cret", "debug").await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("\r\n\r\ndebug\n"), "{}", response);
+        assert_eq!("debug", level.filter());
+
+        let response = request("GET", "s3cret", "").await;
+        assert!(response.ends_with("\r\n\r\ndebug\n"), "{}", response);
+        let response = request("POST", "s3cret", "info,x=loud").await;
+        assert!(response.starts_with("HTTP/1.1 400"), "{}", response);
+        assert_eq!("debug", level.filter());
+    }
+
+    #[test]
+    fn test_parse_level() {
+        assert_eq!(LevelFilter::DEBUG, parse_level("DEBUG").unwrap());
+        assert_eq!(LevelFilter::WARN, parse_level(" warn\n").unwrap());
+        assert_eq!(LevelFilter::OFF, parse_level("off").unwrap());
+        assert!(parse_level("").is_err());
+        assert!(parse_level("verbose").is_err());
+    }
+}
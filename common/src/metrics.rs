@@ -0,0 +1,800 @@
+//! Prometheus metrics for the long-running examples.
+//!
+//! A [`Metrics`] registry holds a [`Series`] of counters, histograms, and gauges per
+//! table and source, and [`serve_from_env`] serves them on `GET /metrics` at
+//! `METRICS_ADDR` in the Prometheus text format. Table and source are the only labels,
+//! besides the kind of a source-specific count. Past `METRICS_MAX_SERIES` label sets,
+//! further ones share a single `other` series (see [`crate::cardinality`]).
+
+use anyhow::{Context, Result};
+use axum::routing::get;
+use axum::Router;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::time::Instant;
+use tracing::info;
+
+use crate::cardinality::{self, LabelGuard, OVERFLOW};
+use crate::clock::{Clock, SystemClock};
+use crate::distribution::{self, DEFAULT_LATENCY_BOUNDS_MS, DEFAULT_SIZE_BOUNDS};
+use crate::errors::ErrorClass;
+use crate::pipeline::{AckObserver, AckProgress, IngestSink, Pipeline};
+
+/// Address metrics are served on when METRICS_ADDR is not set
+pub const DEFAULT_ADDR: &str = "0.0.0.0:9090";
+
+/// Upper bounds of the end-to-end latency buckets, in seconds
+const END_TO_END_BUCKETS: &[f64] = &[0.1, 0.5, 1.0, 5.0, 15.0, 60.0, 300.0, 900.0, 3600.0];
+
+/// Name, help, and value of a counter family
+type CounterFamily = (&'static str, &'static str, fn(&Series) -> &AtomicU64);
+
+/// Name, help, and value of a histogram family
+type HistogramFamily = (&'static str, &'static str, fn(&Series) -> &Histogram);
+
+/// Series by table and source
+type SeriesMap = BTreeMap<(String, String), Arc<Series>>;
+
+/// Observations counted into fixed buckets
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: Arc<[f64]>,
+    /// Observations per bucket, with a last one for those above every bound
+    buckets: Box<[AtomicU64]>,
+    count: AtomicU64,
+    /// Sum of the observations, as the bits of an `f64`
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: impl Into<Arc<[f64]>>) -> Self {
+        let bounds = bounds.into();
+        Self {
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            bounds,
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0f64.to_bits()),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(self.bounds.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        let _ = self
+            .sum
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some((f64::from_bits(bits) + value).to_bits())
+            });
+    }
+
+    /// Number of observations
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Sum of the observations
+    pub fn sum(&self) -> f64 {
+        f64::from_bits(self.sum.load(Ordering::Relaxed))
+    }
+
+    fn render(&self, text: &mut String, name: &str, labels: &[(&str, &str)]) {
+        let mut cumulative = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let le = match self.bounds.get(i) {
+                Some(bound) => bound.to_string(),
+                None => "+Inf".to_string(),
+            };
+            let mut bucket_labels = labels.to_vec();
+            bucket_labels.push(("le", &le));
+            let _ = writeln!(
+                text,
+                "{}_bucket{} {}",
+                name,
+                render_labels(&bucket_labels),
+                cumulative
+            );
+        }
+        let labels = render_labels(labels);
+        let _ = writeln!(text, "{}_sum{} {}", name, labels, self.sum());
+        let _ = writeln!(text, "{}_count{} {}", name, labels, self.count());
+    }
+}
+
+/// Metrics of one table and source
+///
+/// Either label may be empty, for metrics that are only about a table or a source.
+#[derive(Debug)]
+pub struct Series {
+    /// Records acknowledged
+    pub ingested: AtomicU64,
+    /// Records that failed to send or were not acknowledged
+    pub failed: AtomicU64,
+    /// Records skipped before reaching a table: filtered out, unrouted, or malformed
+    pub filtered: AtomicU64,
+    /// Times the stream to the table was recreated
+    pub recreations: AtomicU64,
+    /// Records acknowledged before their event time, from a producer's clock running ahead
+    pub clock_skewed: AtomicU64,
+    /// Records sent and not yet acknowledged
+    pub in_flight: AtomicU64,
+    /// Time from sending a record until its acknowledgment was drained, in seconds
+    pub ack_latency: Histogram,
+    /// Encoded size of each record sent, in bytes
+    pub record_bytes: Histogram,
+    /// Time from each record's event time until it was sent, in seconds
+    pub end_to_end_latency: Histogram,
+    /// Time from each record's event time until its acknowledgment was drained, in
+    /// seconds; zero for event times in the future
+    pub event_to_ack_latency: Histogram,
+    /// Records the source has yet to deliver, reported once set
+    lag: AtomicU64,
+    lag_known: AtomicBool,
+    /// Counts specific to the source, by kind
+    counts: Mutex<BTreeMap<&'static str, u64>>,
+    /// Failures, by class
+    errors: Mutex<BTreeMap<ErrorClass, u64>>,
+    /// When the stream to the table was last opened
+    stream_opened: Mutex<Option<Instant>>,
+    /// What end-to-end latencies are measured until
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for Series {
+    fn default() -> Self {
+        Self::new(&HistogramBounds::default(), Arc::new(SystemClock))
+    }
+}
+
+impl Series {
+    fn new(bounds: &HistogramBounds, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            ingested: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            filtered: AtomicU64::new(0),
+            recreations: AtomicU64::new(0),
+            clock_skewed: AtomicU64::new(0),
+            in_flight: AtomicU64::new(0),
+            ack_latency: Histogram::new(Arc::clone(&bounds.latency_secs)),
+            record_bytes: Histogram::new(Arc::clone(&bounds.size)),
+            end_to_end_latency: Histogram::new(END_TO_END_BUCKETS),
+            event_to_ack_latency: Histogram::new(END_TO_END_BUCKETS),
+            lag: AtomicU64::new(0),
+            lag_known: AtomicBool::new(false),
+            counts: Mutex::new(BTreeMap::new()),
+            errors: Mutex::new(BTreeMap::new()),
+            stream_opened: Mutex::new(None),
+            clock,
+        }
+    }
+
+    /// Add `n` to the source-specific count of `kind`, such as Kafka tombstones
+    pub fn count(&self, kind: &'static str, n: u64) {
+        *self.counts.lock().unwrap().entry(kind).or_default() += n;
+    }
+
+    /// Add `n` failures of `class`, for failures that do not go through a pipeline
+    pub fn count_error(&self, class: ErrorClass, n: u64) {
+        *self.errors.lock().unwrap().entry(class).or_default() += n;
+    }
+
+    /// Record that the stream to the table was opened, or recreated, just now
+    pub fn mark_stream_opened(&self) {
+        *self.stream_opened.lock().unwrap() = Some(Instant::now());
+    }
+
+    /// Record how far the source is behind, for sources that can tell
+    pub fn set_lag(&self, lag: u64) {
+        self.lag.store(lag, Ordering::Relaxed);
+        self.lag_known.store(true, Ordering::Relaxed);
+    }
+
+    /// Record a record sent now, as the series' clock reads it, whose event happened at
+    /// `event_time`
+    ///
+    /// Event times in the future, from skewed clocks, are not observed.
+    pub fn observe_event_time(&self, event_time: SystemTime) {
+        if let Ok(elapsed) = self.clock.now().duration_since(event_time) {
+            self.end_to_end_latency.observe(elapsed.as_secs_f64());
+        }
+    }
+
+    /// Update the counters and in-flight gauge from the pipeline this series is about
+    ///
+    /// A series should be kept for one pipeline, since its counters are taken from that
+    /// pipeline's running totals.
+    pub fn observe_pipeline<S: IngestSink>(&self, pipeline: &Pipeline<S>) {
+        let summary = pipeline.summary();
+        self.ingested.fetch_max(summary.ingested, Ordering::Relaxed);
+        self.failed.fetch_max(summary.failed, Ordering::Relaxed);
+        let mut errors = self.errors.lock().unwrap();
+        for (class, failed) in &summary.failed_by_class {
+            let count = errors.entry(*class).or_default();
+            *count = (*count).max(*failed);
+        }
+        drop(errors);
+        self.in_flight
+            .store(pipeline.pending() as u64, Ordering::Relaxed);
+    }
+
+    /// An observer recording the ack latency, event-to-ack latency, and acknowledged
+    /// records of a pipeline; see [`Pipeline::ack_observer`]
+    pub fn ack_observer(self: &Arc<Self>) -> impl AckObserver + 'static {
+        let series = Arc::clone(self);
+        move |progress: AckProgress| {
+            series.ack_latency.observe(progress.latency.as_secs_f64());
+            if let Some(end_to_end) = progress.end_to_end {
+                series
+                    .event_to_ack_latency
+                    .observe(end_to_end.as_secs_f64());
+            }
+            if progress.clock_skewed {
+                series.clock_skewed.fetch_add(1, Ordering::Relaxed);
+            }
+            series.ingested.fetch_max(progress.acked, Ordering::Relaxed);
+        }
+    }
+}
+
+/// The values of one series at one point in time, as [`Metrics::snapshot`] takes them
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub table: String,
+    pub source: String,
+    pub ingested: u64,
+    pub failed: u64,
+    pub filtered: u64,
+    /// Records whose size was observed in `record_bytes`
+    pub sent: u64,
+    /// Encoded bytes of the records sent
+    pub bytes: u64,
+    pub errors: BTreeMap<ErrorClass, u64>,
+    pub in_flight: u64,
+    /// `None` when the source does not report its lag
+    pub lag: Option<u64>,
+    pub stream_opened: Option<Instant>,
+}
+
+/// Upper bounds of the ack latency and record size histograms
+#[derive(Debug, Clone)]
+struct HistogramBounds {
+    latency_secs: Arc<[f64]>,
+    size: Arc<[f64]>,
+}
+
+impl Default for HistogramBounds {
+    /// The same buckets as an [`IngestSummary`](crate::pipeline::IngestSummary)
+    fn default() -> Self {
+        Self {
+            latency_secs: DEFAULT_LATENCY_BOUNDS_MS
+                .iter()
+                .map(|ms| ms / 1000.0)
+                .collect(),
+            size: DEFAULT_SIZE_BOUNDS.into(),
+        }
+    }
+}
+
+/// Every series of a service, by table and source
+#[derive(Debug, Default, Clone)]
+pub struct Metrics {
+    series: Arc<Mutex<SeriesMap>>,
+    cached_streams: Arc<AtomicU64>,
+    audit_write_failures: Arc<AtomicU64>,
+    bounds: HistogramBounds,
+    guard: LabelGuard,
+    /// Given to new series; the system clock when unset
+    clock: Option<Arc<dyn Clock>>,
+}
+
+impl Metrics {
+    /// Count ack latencies and record sizes into buckets with these upper bounds, in
+    /// milliseconds and bytes, instead of the defaults
+    ///
+    /// Latencies are still reported in seconds. Only series created afterwards use the
+    /// new buckets.
+    pub fn histogram_bounds(
+        mut self,
+        latency_ms: Option<Vec<f64>>,
+        size: Option<Vec<f64>>,
+    ) -> Self {
+        if let Some(bounds) = latency_ms {
+            self.bounds.latency_secs = sorted(bounds.iter().map(|ms| ms / 1000.0).collect());
+        }
+        if let Some(bounds) = size {
+            self.bounds.size = sorted(bounds);
+        }
+        self
+    }
+
+    /// Give at most `max` label sets a series of their own, instead of
+    /// [`cardinality::DEFAULT_MAX_SERIES`]
+    pub fn max_series(mut self, max: usize) -> Self {
+        self.guard = LabelGuard::new(max);
+        self
+    }
+
+    /// Measure the end-to-end latencies of series created afterwards until the time
+    /// `clock` reads, instead of the system clock
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// The series of `table` and `source`, created on first use
+    pub fn series(&self, table: &str, source: &str) -> Arc<Series> {
+        let mut series = self.series.lock().unwrap();
+        let key = (table.to_string(), source.to_string());
+        if let Some(existing) = series.get(&key) {
+            return Arc::clone(existing);
+        }
+        let key = if self.guard.admit(&[table, source]) {
+            key
+        } else {
+            (OVERFLOW.to_string(), OVERFLOW.to_string())
+        };
+        Arc::clone(series.entry(key).or_insert_with(|| {
+            let clock = self.clock.clone().unwrap_or_else(|| Arc::new(SystemClock));
+            Arc::new(Series::new(&self.bounds, clock))
+        }))
+    }
+
+    /// Record how many streams the service holds open
+    pub fn set_cached_streams(&self, streams: usize) {
+        self.cached_streams.store(streams as u64, Ordering::Relaxed);
+    }
+
+    /// Record that the service opened `streams` more streams, for services whose
+    /// streams are held by several tasks
+    pub fn add_cached_streams(&self, streams: usize) {
+        self.cached_streams
+            .fetch_add(streams as u64, Ordering::Relaxed);
+    }
+
+    /// Counter of audit rows that were never written, for
+    /// [`crate::failure_audit::FailureAuditor::spawn`]
+    pub fn audit_write_failures(&self) -> Arc<AtomicU64> {
+        Arc::clone(&self.audit_write_failures)
+    }
+
+    /// The current values of every series
+    pub fn snapshot(&self) -> Vec<Snapshot> {
+        let series = self.series.lock().unwrap();
+        series
+            .iter()
+            .map(|((table, source), s)| Snapshot {
+                table: table.clone(),
+                source: source.clone(),
+                ingested: s.ingested.load(Ordering::Relaxed),
+                failed: s.failed.load(Ordering::Relaxed),
+                filtered: s.filtered.load(Ordering::Relaxed),
+                sent: s.record_bytes.count(),
+                bytes: s.record_bytes.sum() as u64,
+                errors: s.errors.lock().unwrap().clone(),
+                in_flight: s.in_flight.load(Ordering::Relaxed),
+                lag: s
+                    .lag_known
+                    .load(Ordering::Relaxed)
+                    .then(|| s.lag.load(Ordering::Relaxed)),
+                stream_opened: *s.stream_opened.lock().unwrap(),
+            })
+            .collect()
+    }
+
+    pub fn render(&self) -> String {
+        let series = self.series.lock().unwrap();
+        let mut text = String::new();
+        let counters: [CounterFamily; 5] = [
+            (
+                "zerobus_records_ingested_total",
+                "Records acknowledged",
+                |s| &s.ingested,
+            ),
+            (
+                "zerobus_records_failed_total",
+                "Records that failed to send or were not acknowledged",
+                |s| &s.failed,
+            ),
+            (
+                "zerobus_records_filtered_total",
+                "Records skipped before reaching a table",
+                |s| &s.filtered,
+            ),
+            (
+                "zerobus_stream_recreations_total",
+                "Times a stream was recreated",
+                |s| &s.recreations,
+            ),
+            (
+                "zerobus_clock_skewed_records_total",
+                "Records acknowledged before their event time, counted at zero latency",
+                |s| &s.clock_skewed,
+            ),
+        ];
+        for (name, help, value) in counters {
+            header(&mut text, name, "counter", help);
+            for ((table, source), s) in series.iter() {
+                let labels = render_labels(&[("table", table), ("source", source)]);
+                let _ = writeln!(
+                    text,
+                    "{}{} {}",
+                    name,
+                    labels,
+                    value(s).load(Ordering::Relaxed)
+                );
+            }
+        }
+
+        header(
+            &mut text,
+            "zerobus_source_records_total",
+            "counter",
+            "Source-specific counts, by kind",
+        );
+        for ((table, source), s) in series.iter() {
+            for (kind, count) in s.counts.lock().unwrap().iter() {
+                let labels = render_labels(&[("table", table), ("source", source), ("kind", kind)]);
+                let _ = writeln!(text, "zerobus_source_records_total{} {}", labels, count);
+            }
+        }
+
+        header(
+            &mut text,
+            "zerobus_errors_total",
+            "counter",
+            "Failures, by class: retryable, terminal, ack_timeout, auth, or schema",
+        );
+        for ((table, source), s) in series.iter() {
+            for (class, count) in s.errors.lock().unwrap().iter() {
+                let labels = render_labels(&[
+                    ("table", table),
+                    ("source", source),
+                    ("class", class.as_str()),
+                ]);
+                let _ = writeln!(text, "zerobus_errors_total{} {}", labels, count);
+            }
+        }
+
+        let histograms: [HistogramFamily; 4] = [
+            (
+                "zerobus_ack_latency_seconds",
+                "Time from sending a record until its acknowledgment",
+                |s| &s.ack_latency,
+            ),
+            (
+                "zerobus_record_bytes",
+                "Encoded size of each record sent",
+                |s| &s.record_bytes,
+            ),
+            (
+                "zerobus_end_to_end_latency_seconds",
+                "Time from a record's event time until it was sent",
+                |s| &s.end_to_end_latency,
+            ),
+            (
+                "zerobus_event_to_ack_latency_seconds",
+                "Time from a record's event time until its acknowledgment",
+                |s| &s.event_to_ack_latency,
+            ),
+        ];
+        for (name, help, histogram) in histograms {
+            header(&mut text, name, "histogram", help);
+            // Series that never observe a histogram, such as per-table ones, leave it out
+            for ((table, source), s) in series.iter().filter(|(_, s)| histogram(s).count() > 0) {
+                histogram(s).render(&mut text, name, &[("table", table), ("source", source)]);
+            }
+        }
+
+        header(
+            &mut text,
+            "zerobus_in_flight_records",
+            "gauge",
+            "Records sent and not yet acknowledged",
+        );
+        for ((table, source), s) in series.iter() {
+            let labels = render_labels(&[("table", table), ("source", source)]);
+            let in_flight = s.in_flight.load(Ordering::Relaxed);
+            let _ = writeln!(text, "zerobus_in_flight_records{} {}", labels, in_flight);
+        }
+
+        header(
+            &mut text,
+            "zerobus_consumer_lag",
+            "gauge",
+            "Records the source has yet to deliver",
+        );
+        for ((table, source), s) in series
+            .iter()
+            .filter(|(_, s)| s.lag_known.load(Ordering::Relaxed))
+        {
+            let labels = render_labels(&[("table", table), ("source", source)]);
+            let _ = writeln!(
+                text,
+                "zerobus_consumer_lag{} {}",
+                labels,
+                s.lag.load(Ordering::Relaxed)
+            );
+        }
+
+        header(
+            &mut text,
+            "zerobus_cached_streams",
+            "gauge",
+            "Streams held open",
+        );
+        let _ = writeln!(
+            text,
+            "zerobus_cached_streams {}",
+            self.cached_streams.load(Ordering::Relaxed)
+        );
+
+        header(
+            &mut text,
+            "zerobus_audit_write_failures_total",
+            "counter",
+            "Failure audit rows that were dropped or could not be written",
+        );
+        let _ = writeln!(
+            text,
+            "zerobus_audit_write_failures_total {}",
+            self.audit_write_failures.load(Ordering::Relaxed)
+        );
+        text
+    }
+}
+
+fn sorted(mut bounds: Vec<f64>) -> Arc<[f64]> {
+    bounds.retain(|bound| !bound.is_nan());
+    bounds.sort_by(f64::total_cmp);
+    bounds.dedup();
+    bounds.into()
+}
+
+fn header(text: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(text, "# HELP {} {}", name, help);
+    let _ = writeln!(text, "# TYPE {} {}", name, kind);
+}
+
+/// `{name="value",...}` of the labels with a value, or nothing when none has one
+fn render_labels(labels: &[(&str, &str)]) -> String {
+    let labels: Vec<String> = labels
+        .iter()
+        .filter(|(_, value)| !value.is_empty())
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect();
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels.join(","))
+    }
+}
+
+/// `GET /metrics`, rendering `metrics`
+pub fn router(metrics: Metrics) -> Router {
+    Router::new().route("/metrics", get(move || async move { metrics.render() }))
+}
+
+/// Serve `metrics` on `addr` in the background, returning the address bound
+pub async fn serve(addr: &str, metrics: Metrics) -> Result<SocketAddr> {
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to bind {}", addr))?;
+    let local_addr = listener.local_addr()?;
+    tokio::spawn(async move { axum::serve(listener, router(metrics)).await });
+    Ok(local_addr)
+}
+
+/// A registry served on `METRICS_ADDR`, or [`DEFAULT_ADDR`], with the histogram buckets
+/// of `ACK_LATENCY_BUCKETS_MS` and `RECORD_SIZE_BUCKETS`, and the series limit of
+/// `METRICS_MAX_SERIES`
+pub async fn serve_from_env() -> Result<Metrics> {
+    let addr = std::env::var("METRICS_ADDR").unwrap_or_else(|_| DEFAULT_ADDR.to_string());
+    let mut metrics = Metrics::default().histogram_bounds(
+        distribution::bounds_from_env("ACK_LATENCY_BUCKETS_MS")?,
+        distribution::bounds_from_env("RECORD_SIZE_BUCKETS")?,
+    );
+    if let Some(max) = cardinality::max_series_from_env()? {
+        metrics = metrics.max_series(max);
+    }
+    let local_addr = serve(&addr, metrics.clone()).await?;
+    info!("Serving metrics on http://{}/metrics", local_addr);
+    Ok(metrics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::FixedClock;
+    use crate::testing::MockSink;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    async fn scrape(addr: SocketAddr) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        response
+    }
+
+    #[tokio::test]
+    async fn test_scrape_after_ingesting() {
+        let metrics = Metrics::default();
+        let addr = serve("127.0.0.1:0", metrics.clone()).await.unwrap();
+
+        let table = "main.default.events";
+        let series = metrics.series(table, "orders");
+        let sink = MockSink::default().fail_acks_for(|record| record == [2]);
+        let mut pipeline = Pipeline::new(sink, 10).ack_observer(series.ack_observer());
+        let produced = [
+            Some(SystemTime::now() - Duration::from_secs(2)),
+            // From a producer whose clock runs ahead
+            Some(SystemTime::now() + Duration::from_secs(60)),
+            None,
+        ];
+        for (i, event_time) in produced.into_iter().enumerate() {
+            pipeline.ingest_at(vec![i as u8], event_time).await.unwrap();
+            series.record_bytes.observe(1.0);
+            series.observe_event_time(SystemTime::now());
+        }
+        series.observe_pipeline(&pipeline);
+        assert_eq!(3, series.in_flight.load(Ordering::Relaxed));
+        pipeline.drain().await.unwrap();
+        series.observe_pipeline(&pipeline);
+        series.filtered.fetch_add(1, Ordering::Relaxed);
+        series.count("tombstone", 1);
+        series.count_error(ErrorClass::Auth, 1);
+        metrics.series("", "orders").set_lag(42);
+        metrics.set_cached_streams(1);
+        metrics
+            .audit_write_failures()
+            .fetch_add(2, Ordering::Relaxed);
+
+        let text = scrape(addr).await;
+        let labels = format!("{{table=\"{}\",source=\"orders\"}}", table);
+        for family in [
+            "# TYPE zerobus_records_ingested_total counter\n",
+            "# TYPE zerobus_records_failed_total counter\n",
+            "# TYPE zerobus_records_filtered_total counter\n",
+            "# TYPE zerobus_stream_recreations_total counter\n",
+            "# TYPE zerobus_clock_skewed_records_total counter\n",
+            "# TYPE zerobus_source_records_total counter\n",
+            "# TYPE zerobus_errors_total counter\n",
+            "# TYPE zerobus_ack_latency_seconds histogram\n",
+            "# TYPE zerobus_record_bytes histogram\n",
+            "# TYPE zerobus_end_to_end_latency_seconds histogram\n",
+            "# TYPE zerobus_event_to_ack_latency_seconds histogram\n",
+            "# TYPE zerobus_in_flight_records gauge\n",
+            "# TYPE zerobus_consumer_lag gauge\n",
+            "# TYPE zerobus_cached_streams gauge\n",
+            "# TYPE zerobus_audit_write_failures_total counter\n",
+        ] {
+            assert!(text.contains(family), "{} missing from\n{}", family, text);
+        }
+        for sample in [
+            format!("zerobus_records_ingested_total{} 2\n", labels),
+            format!("zerobus_records_failed_total{} 1\n", labels),
+            format!("zerobus_records_filtered_total{} 1\n", labels),
+            format!("zerobus_ack_latency_seconds_count{} 2\n", labels),
+            format!(
+                "zerobus_record_bytes_bucket{{table=\"{}\",source=\"orders\",le=\"256\"}} 3\n",
+                table
+            ),
+            format!("zerobus_end_to_end_latency_seconds_count{} 3\n", labels),
+            // Only the acknowledged records with an event time, the skewed one at zero
+            format!("zerobus_event_to_ack_latency_seconds_count{} 2\n", labels),
+            format!(
+                "zerobus_event_to_ack_latency_seconds_bucket{{table=\"{}\",source=\"orders\",le=\"0.1\"}} 1\n",
+                table
+            ),
+            format!("zerobus_clock_skewed_records_total{} 1\n", labels),
+            format!("zerobus_in_flight_records{} 0\n", labels),
+            format!(
+                "zerobus_source_records_total{{table=\"{}\",source=\"orders\",kind=\"tombstone\"}} 1\n",
+                table
+            ),
+            format!(
+                "zerobus_errors_total{{table=\"{}\",source=\"orders\",class=\"retryable\"}} 1\n",
+                table
+            ),
+            format!(
+                "zerobus_errors_total{{table=\"{}\",source=\"orders\",class=\"auth\"}} 1\n",
+                table
+            ),
+            "zerobus_consumer_lag{source=\"orders\"} 42\n".to_string(),
+            "zerobus_cached_streams 1\n".to_string(),
+            "zerobus_audit_write_failures_total 2\n".to_string(),
+        ] {
+            assert!(text.contains(&sample), "{} missing from\n{}", sample, text);
+        }
+    }
+
+    #[test]
+    fn test_end_to_end_latency_is_measured_until_the_clock() {
+        let clock = FixedClock::at_unix_secs(1_718_020_860);
+        let metrics = Metrics::default().clock(Arc::new(clock));
+        let series = metrics.series("main.default.events", "orders");
+
+        series.observe_event_time(clock.now() - Duration::from_millis(2500));
+        // From a producer whose clock runs ahead
+        series.observe_event_time(clock.now() + Duration::from_secs(60));
+
+        assert_eq!(1, series.end_to_end_latency.count());
+        assert_eq!(2.5, series.end_to_end_latency.sum());
+    }
+
+    #[test]
+    fn test_histogram_bounds_are_configurable() {
+        let metrics = Metrics::default().histogram_bounds(Some(vec![250.0, 50.0]), None);
+        let series = metrics.series("main.default.events", "orders");
+        series.ack_latency.observe(0.1);
+        series.record_bytes.observe(100.0);
+
+        let text = metrics.render();
+        let labels = "table=\"main.default.events\",source=\"orders\"";
+        for sample in [
+            // Given in milliseconds, reported in seconds
+            format!(
+                "zerobus_ack_latency_seconds_bucket{{{},le=\"0.05\"}} 0\n",
+                labels
+            ),
+            format!(
+                "zerobus_ack_latency_seconds_bucket{{{},le=\"0.25\"}} 1\n",
+                labels
+            ),
+            format!(
+                "zerobus_ack_latency_seconds_bucket{{{},le=\"+Inf\"}} 1\n",
+                labels
+            ),
+            format!("zerobus_record_bytes_bucket{{{},le=\"256\"}} 1\n", labels),
+        ] {
+            assert!(text.contains(&sample), "{} missing from\n{}", sample, text);
+        }
+        assert!(!text.contains("zerobus_ack_latency_seconds_bucket{table=\"main.default.events\",source=\"orders\",le=\"0.001\"}"));
+    }
+
+    #[test]
+    fn test_series_are_bounded() {
+        let metrics = Metrics::default().max_series(20);
+        for i in 0..30 {
+            metrics
+                .series("main.default.events", &format!("topic-{}", i))
+                .filtered
+                .fetch_add(1, Ordering::Relaxed);
+        }
+        let text = metrics.render();
+        let filtered: Vec<&str> = text
+            .lines()
+            .filter(|line| line.starts_with("zerobus_records_filtered_total{"))
+            .collect();
+        assert_eq!(21, filtered.len());
+        assert!(filtered
+            .contains(&"zerobus_records_filtered_total{table=\"other\",source=\"other\"} 10"));
+
+        // Label values are escaped
+        assert_eq!(
+            "{source=\"say \\\"hi\\\"\"}",
+            render_labels(&[("table", ""), ("source", "say \"hi\"")])
+        );
+    }
+}
@@ -0,0 +1,275 @@
+//! Exporting spans to an OpenTelemetry collector from the Lambda examples.
+//!
+//! [`init`] installs the examples' usual log format and, when
+//! `OTEL_EXPORTER_OTLP_ENDPOINT` or `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT` is set, a layer
+//! exporting spans over OTLP/HTTP. The exporter reads the other standard
+//! `OTEL_EXPORTER_OTLP_*` variables, such as headers and timeouts. The service name is
+//! `OTEL_SERVICE_NAME`, or the function's name.
+//!
+//! Each invocation gets a root span carrying the `faas.*` attributes, with child spans for
+//! creating and closing the stream and, through [`Pipeline::trace_records`], for every
+//! record from its ingest until its ack. Spans are exported in batches, and Lambda
+//! freezes the container as soon as the handler returns, so [`invocation`] flushes them
+//! before it does.
+//!
+//! [`Pipeline::trace_records`]: crate::pipeline::Pipeline::trace_records
+
+use anyhow::{Context, Result};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::SpanExporter;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use tracing::level_filters::LevelFilter;
+use tracing::{info_span, warn, Instrument, Span, Subscriber};
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
+
+use crate::payload_log;
+
+/// Name of the tracer the spans are created with
+const TRACER_NAME: &str = "zerobus-ingestor";
+
+/// Provider installed by [`init`], kept for [`flush`]
+static PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+/// Whether the next invocation is the first of this container
+static COLD_START: AtomicBool = AtomicBool::new(true);
+
+/// Whether an OTLP endpoint is configured
+pub fn export_enabled() -> bool {
+    [
+        "OTEL_EXPORTER_OTLP_ENDPOINT",
+        "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT",
+    ]
+    .iter()
+    .any(|name| std::env::var(name).is_ok_and(|value| !value.trim().is_empty()))
+}
+
+/// Install the global subscriber, logging to stdout and exporting spans when an OTLP
+/// endpoint is configured
+pub fn init() -> Result<()> {
+    let provider = if export_enabled() {
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .build()
+            .context("Failed to create the OTLP span exporter")?;
+        Some(
+            SdkTracerProvider::builder()
+                .with_batch_exporter(exporter)
+                .with_resource(resource())
+                .build(),
+        )
+    } else {
+        None
+    };
+    build(provider.as_ref(), std::io::stdout)
+        .try_init()
+        .context("Failed to install the log subscriber")?;
+    if let Some(provider) = provider {
+        let _ = PROVIDER.set(provider);
+    }
+    Ok(())
+}
+
+/// `OTEL_SERVICE_NAME` and `OTEL_RESOURCE_ATTRIBUTES`, with the function's name as the
+/// service name when neither sets one
+fn resource() -> Resource {
+    let resource = Resource::builder();
+    match std::env::var("AWS_LAMBDA_FUNCTION_NAME") {
+        Ok(function_name) if std::env::var("OTEL_SERVICE_NAME").is_err() => {
+            resource.with_service_name(function_name).build()
+        }
+        _ => resource.build(),
+    }
+}
+
+/// A subscriber formatting events to `writer`, and exporting spans through `provider`
+fn build<W>(provider: Option<&SdkTracerProvider>, writer: W) -> impl Subscriber + Send + Sync
+where
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    let spans = provider.map(|provider| {
+        tracing_opentelemetry::layer()
+            .with_tracer(provider.tracer(TRACER_NAME))
+            .with_filter(LevelFilter::INFO)
+    });
+    // Sampled payloads are logged at debug level when DEBUG_SAMPLE_RATE is set
+    let events = payload_log::log_directives("info")
+        .parse::<Targets>()
+        .unwrap_or_else(|_| Targets::new().with_default(LevelFilter::INFO));
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_target(false)
+                .with_writer(writer)
+                .with_filter(events),
+        )
+        .with(spans)
+}
+
+/// Run `handler` in the root span of one invocation, then export the spans it ended
+///
+/// The first invocation of a container is marked as its cold start. An error the
+/// handler returns sets the span's status.
+pub async fn invocation<T, E: Display>(
+    request_id: &str,
+    function_name: &str,
+    handler: impl Future<Output = std::result::Result<T, E>>,
+) -> std::result::Result<T, E> {
+    let span = info_span!(
+        "invocation",
+        otel.kind = "server",
+        otel.status_code = tracing::field::Empty,
+        otel.status_description = tracing::field::Empty,
+        faas.invocation_id = request_id,
+        faas.name = function_name,
+        faas.coldstart = COLD_START.swap(false, Ordering::Relaxed),
+    );
+    let result = handler.instrument(span.clone()).await;
+    if let Err(e) = &result {
+        span.record("otel.status_code", "ERROR");
+        span.record("otel.status_description", e.to_string());
+    }
+    drop(span);
+    flush();
+    result
+}
+
+/// Span around creating the stream to `table`
+pub fn create_stream_span(table: &str) -> Span {
+    info_span!("create_stream", zerobus.table = table)
+}
+
+/// Span around ingesting one record into `table` and waiting for its ack, for records
+/// written to a stream directly rather than through a traced pipeline
+///
+/// Records from a queue set `enqueue_to_ack_ms` once acknowledged: the time from their
+/// enqueue, such as an SQS `SentTimestamp`, until their ack.
+pub fn ingest_record_span(table: &str) -> Span {
+    info_span!(
+        "ingest_record",
+        zerobus.table = table,
+        enqueue_to_ack_ms = tracing::field::Empty,
+    )
+}
+
+/// Span around closing the stream to `table`, which waits for its remaining acks
+pub fn close_stream_span(table: &str) -> Span {
+    info_span!("close_stream", zerobus.table = table)
+}
+
+/// Export the spans ended so far; does nothing when spans are not exported
+///
+/// Blocks until the collector has answered or the export timed out.
+pub fn flush() {
+    if let Some(provider) = PROVIDER.get() {
+        if let Err(e) = provider.force_flush() {
+            warn!("Failed to export spans: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::Pipeline;
+    use crate::testing::MockSink;
+    use anyhow::anyhow;
+    use opentelemetry::trace::{SpanKind, Status};
+    use opentelemetry::Value;
+    use opentelemetry_sdk::trace::{InMemorySpanExporter, SpanData};
+
+    fn attribute(span: &SpanData, key: &str) -> Option<Value> {
+        span.attributes
+            .iter()
+            .find(|attribute| attribute.key.as_str() == key)
+            .map(|attribute| attribute.value.clone())
+    }
+
+    #[tokio::test]
+    async fn test_invocation_spans() {
+        let exporter = InMemorySpanExporter::default();
+        let provider = SdkTracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let _subscriber = build(Some(&provider), std::io::sink).set_default();
+
+        let table = "main.default.events";
+        let sink = MockSink::default().fail_acks_for(|record| record == [1]);
+        let result = invocation("req-1", "ingestor", async {
+            let stream = create_stream_span(table).in_scope(|| sink.clone());
+            let mut pipeline = Pipeline::new(stream, 10).trace_records(table);
+            for i in 0..2u8 {
+                pipeline.ingest(vec![i]).await?;
+            }
+            let summary = pipeline
+                .finish()
+                .instrument(close_stream_span(table))
+                .await?;
+            if summary.failed > 0 {
+                return Err(anyhow!("{} rows were not acknowledged", summary.failed));
+            }
+            Ok(())
+        })
+        .await;
+        assert!(result.is_err());
+        provider.force_flush().unwrap();
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let names: Vec<&str> = spans.iter().map(|span| span.name.as_ref()).collect();
+        assert_eq!(
+            vec![
+                "create_stream",
+                "ingest_record",
+                "ingest_record",
+                "close_stream",
+                "invocation"
+            ],
+            names
+        );
+
+        let root = spans.last().unwrap();
+        assert_eq!(SpanKind::Server, root.span_kind);
+        assert_eq!(
+            Some(Value::from("req-1")),
+            attribute(root, "faas.invocation_id")
+        );
+        assert_eq!(Some(Value::from("ingestor")), attribute(root, "faas.name"));
+        assert_eq!(Some(Value::from(true)), attribute(root, "faas.coldstart"));
+        assert!(matches!(root.status, Status::Error { .. }));
+        for span in &spans[..4] {
+            assert_eq!(root.span_context.span_id(), span.parent_span_id);
+            assert_eq!(root.span_context.trace_id(), span.span_context.trace_id());
+            assert_eq!(Some(Value::from(table)), attribute(span, "zerobus.table"));
+        }
+
+        // The record that was not acknowledged fails its span
+        assert_eq!(Status::Unset, spans[1].status);
+        assert!(matches!(spans[2].status, Status::Error { .. }));
+        assert_eq!(
+            Some(Value::from(1)),
+            attribute(&spans[2], "zerobus.record_bytes")
+        );
+
+        // Only the first invocation of a container is a cold start
+        invocation("req-2", "ingestor", async { Ok::<_, anyhow::Error>(()) })
+            .await
+            .unwrap();
+        provider.force_flush().unwrap();
+        let spans = exporter.get_finished_spans().unwrap();
+        let root = spans.last().unwrap();
+        assert_eq!(
+            Some(Value::from("req-2")),
+            attribute(root, "faas.invocation_id")
+        );
+        assert_eq!(Some(Value::from(false)), attribute(root, "faas.coldstart"));
+        assert_eq!(Status::Unset, root.status);
+    }
+}
@@ -0,0 +1,273 @@
+//! Logging example payloads without logging every one of them
+//!
+//! A sample of the records is logged at debug level, and every record that fails is
+//! logged at warn level. Either way the payload goes through [`Redact`] first and is
+//! cut to a maximum size, so turning it on neither floods the logs nor leaks the
+//! fields that must never be stored.
+//!
+//! - `DEBUG_SAMPLE_RATE` - Fraction of records whose payload is logged, from `0` to `1`
+//!   (default: `0`, only failed records are logged)
+//! - `DEBUG_MAX_PAYLOAD_BYTES` - Longest payload logged; longer ones are cut and marked
+//!   (default: `2048`)
+//! - `DEBUG_REDACT_FIELDS` - Comma-separated JSON pointers redacted in logged payloads
+
+use anyhow::{anyhow, Context, Result};
+use serde_json::Value;
+use std::fmt;
+use tracing::{debug, warn};
+
+use crate::redact::Redact;
+use crate::rng::{OsRng, Rng, SeededRng};
+
+/// Default for `DEBUG_MAX_PAYLOAD_BYTES`
+pub const DEFAULT_MAX_PAYLOAD_BYTES: usize = 2048;
+
+/// Target of the payload log lines, so they can be let through on their own
+pub const LOG_TARGET: &str = "zerobus_common::payload_log";
+
+/// Log filter directives at `level`, letting the sampled payloads through at debug
+/// level when `DEBUG_SAMPLE_RATE` is above 0
+///
+/// Debug logs of the SDK and its transport stay filtered out.
+pub fn log_directives(level: &str) -> String {
+    let sampling = std::env::var("DEBUG_SAMPLE_RATE")
+        .ok()
+        .and_then(|rate| rate.trim().parse::<f64>().ok())
+        .is_some_and(|rate| rate > 0.0);
+    if sampling {
+        format!("{},{}=debug", level, LOG_TARGET)
+    } else {
+        level.to_string()
+    }
+}
+
+/// A payload to log: raw bytes, parsed as JSON when they are, or a parsed JSON value
+#[derive(Debug, Clone, Copy)]
+pub enum Payload<'a> {
+    Bytes(&'a [u8]),
+    Json(&'a Value),
+}
+
+impl<'a> From<&'a [u8]> for Payload<'a> {
+    fn from(bytes: &'a [u8]) -> Self {
+        Payload::Bytes(bytes)
+    }
+}
+
+impl<'a> From<&'a str> for Payload<'a> {
+    fn from(text: &'a str) -> Self {
+        Payload::Bytes(text.as_bytes())
+    }
+}
+
+impl<'a> From<&'a Value> for Payload<'a> {
+    fn from(value: &'a Value) -> Self {
+        Payload::Json(value)
+    }
+}
+
+/// Logs sampled and failed payloads, redacted and capped in size
+///
+/// The sampler is a [`SeededRng`]: two loggers with the same seed sample the same
+/// records, in tests or when reproducing what a run logged.
+#[derive(Debug)]
+pub struct PayloadLog {
+    redact: Redact,
+    sample_rate: f64,
+    max_bytes: usize,
+    sampler: SeededRng,
+}
+
+impl Default for PayloadLog {
+    fn default() -> Self {
+        Self::new(Redact::default())
+    }
+}
+
+impl PayloadLog {
+    /// Log only failed payloads, redacted by `redact`
+    pub fn new(redact: Redact) -> Self {
+        Self {
+            redact,
+            sample_rate: 0.0,
+            max_bytes: DEFAULT_MAX_PAYLOAD_BYTES,
+            sampler: SeededRng::new(0),
+        }
+    }
+
+    /// Log the payload of this fraction of records; clamped to `0..=1`
+    pub fn sample_rate(mut self, rate: f64) -> Self {
+        self.sample_rate = if rate.is_nan() {
+            0.0
+        } else {
+            rate.clamp(0.0, 1.0)
+        };
+        self
+    }
+
+    /// Cut logged payloads to at most `max_bytes`
+    pub fn max_payload_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Seed the sampler, which is otherwise seeded by [`OsRng`] when the logger is read
+    /// from the environment
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.sampler = SeededRng::new(seed);
+        self
+    }
+
+    /// Read `DEBUG_SAMPLE_RATE`, `DEBUG_MAX_PAYLOAD_BYTES`, and `DEBUG_REDACT_FIELDS`
+    pub fn from_env() -> Result<Self> {
+        let var = |name: &str| {
+            std::env::var(name)
+                .ok()
+                .filter(|value| !value.trim().is_empty())
+        };
+        let redact = match var("DEBUG_REDACT_FIELDS") {
+            Some(fields) => Redact::parse(&fields).context("Invalid DEBUG_REDACT_FIELDS")?,
+            None => Redact::default(),
+        };
+        let mut log = Self::new(redact).seed(OsRng.next_u64());
+        if let Some(rate) = var("DEBUG_SAMPLE_RATE") {
+            let rate: f64 = rate
+                .trim()
+                .parse()
+                .ok()
+                .filter(|rate| (0.0..=1.0).contains(rate))
+                .ok_or_else(|| {
+                    anyhow!("DEBUG_SAMPLE_RATE must be between 0 and 1, got {:?}", rate)
+                })?;
+            log = log.sample_rate(rate);
+        }
+        if let Some(max_bytes) = var("DEBUG_MAX_PAYLOAD_BYTES") {
+            let max_bytes = max_bytes.trim().parse().with_context(|| {
+                format!(
+                    "DEBUG_MAX_PAYLOAD_BYTES must be a number of bytes, got {:?}",
+                    max_bytes
+                )
+            })?;
+            log = log.max_payload_bytes(max_bytes);
+        }
+        Ok(log)
+    }
+
+    /// Whether the next record is sampled; never with a sample rate of 0
+    pub fn sample(&self) -> bool {
+        self.sample_rate > 0.0 && self.sampler.next_f64() < self.sample_rate
+    }
+
+    /// Log the payload of `record` at debug level if it is sampled
+    pub fn log_sampled<'a>(&self, record: &str, payload: impl Into<Payload<'a>>) {
+        if self.sample() {
+            debug!(
+                target: LOG_TARGET,
+                "Sampled payload of {}: {}",
+                record,
+                self.render(payload)
+            );
+        }
+    }
+
+    /// Log the payload of `record`, which failed with `error`, at warn level
+    pub fn log_failed<'a>(
+        &self,
+        record: &str,
+        payload: impl Into<Payload<'a>>,
+        error: impl fmt::Display,
+    ) {
+        warn!(
+            target: LOG_TARGET,
+            "Payload of failed {} ({}): {}",
+            record,
+            error,
+            self.render(payload)
+        );
+    }
+
+    /// The payload as it is logged: redacted, then cut to the maximum size
+    ///
+    /// Bytes that are not JSON cannot be redacted, so only their size is given when
+    /// there are fields to redact.
+    pub fn render<'a>(&self, payload: impl Into<Payload<'a>>) -> String {
+        let text = match payload.into() {
+            Payload::Json(value) => self.redacted(value.clone()),
+            Payload::Bytes(bytes) => match serde_json::from_slice::<Value>(bytes) {
+                Ok(value) => self.redacted(value),
+                Err(_) if !self.redact.is_empty() => {
+                    return format!("<{} bytes, not JSON so not redacted>", bytes.len())
+                }
+                Err(_) => String::from_utf8_lossy(bytes).into_owned(),
+            },
+        };
+        truncate(text, self.max_bytes)
+    }
+
+    fn redacted(&self, mut value: Value) -> String {
+        self.redact.apply(&mut value);
+        value.to_string()
+    }
+}
+
+/// Cut `text` to at most `max_bytes` on a character boundary, saying how much was cut
+fn truncate(mut text: String, max_bytes: usize) -> String {
+    if text.len() <= max_bytes {
+        return text;
+    }
+    let mut end = max_bytes;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    let cut = text.len() - end;
+    text.truncate(end);
+    text.push_str(&format!("...[truncated {} bytes]", cut));
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_sampling_is_deterministic_under_a_seed() {
+        let sampled = |seed| {
+            let log = PayloadLog::default().sample_rate(0.1).seed(seed);
+            (0..1000).map(|_| log.sample()).collect::<Vec<bool>>()
+        };
+        let first = sampled(42);
+        assert_eq!(first, sampled(42));
+        assert_ne!(first, sampled(7));
+
+        // Close to the rate
+        let count = first.iter().filter(|sampled| **sampled).count();
+        assert!((60..140).contains(&count), "{}", count);
+
+        let never = PayloadLog::default().seed(42);
+        assert!((0..1000).all(|_| !never.sample()));
+        let always = PayloadLog::default().sample_rate(1.0);
+        assert!((0..1000).all(|_| always.sample()));
+    }
+
+    #[test]
+    fn test_payloads_are_redacted_and_truncated() {
+        let log = PayloadLog::new(Redact::parse("/customer/email").unwrap());
+        let event = json!({"id": 7, "customer": {"email": "ada@example.com"}});
+        let rendered = r#"{"customer":{"email":"[REDACTED]"},"id":7}"#;
+        assert_eq!(rendered, log.render(&event));
+        assert_eq!(rendered, log.render(event.to_string().as_str()));
+
+        // Not JSON, so it cannot be redacted
+        assert_eq!(
+            "<14 bytes, not JSON so not redacted>",
+            log.render("email=ada@x.io")
+        );
+        assert_eq!("plain text", PayloadLog::default().render("plain text"));
+
+        let log = PayloadLog::default().max_payload_bytes(4);
+        assert_eq!("abcd...[truncated 2 bytes]", log.render("abcdef"));
+        // Never cut inside a character
+        assert_eq!("abc...[truncated 2 bytes]", log.render("abcé"));
+    }
+}
@@ -0,0 +1,920 @@
+use anyhow::{anyhow, bail, Result};
+use databricks_zerobus_ingest_sdk::{
+    StreamConfigurationOptions, TableProperties, ZerobusSdk, ZerobusStream,
+};
+use std::collections::{BTreeMap, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{error, info, info_span, Instrument, Span};
+
+use crate::clock::{Clock, SystemClock};
+use crate::distribution::Distribution;
+use crate::errors::{classify, ErrorClass};
+use crate::payload_log::PayloadLog;
+
+/// Acknowledgment of a single ingested record, resolved once Zerobus has durably written it
+pub type AckFuture = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+/// Told the outcome of one record once the pipeline has drained its acknowledgment
+pub type AckCallback = Box<dyn FnOnce(Result<()>) + Send>;
+
+/// Progress of a pipeline, reported as each record is acknowledged
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AckProgress {
+    /// Records acknowledged so far, this one included
+    pub acked: u64,
+    /// Time from sending this record until the pipeline drained its acknowledgment
+    pub latency: Duration,
+    /// Time from the record's event time until its acknowledgment was drained; `None`
+    /// when it was ingested without one
+    pub end_to_end: Option<Duration>,
+    /// Whether the event time was in the future, so `end_to_end` was clamped to zero
+    pub clock_skewed: bool,
+}
+
+/// Told of every acknowledgment as the pipeline drains it, e.g. to log or chart live
+/// progress of a long-running ingestor
+///
+/// Records that fail are not reported. Implemented for closures taking an
+/// [`AckProgress`].
+pub trait AckObserver: Send {
+    fn on_ack(&mut self, progress: AckProgress);
+}
+
+impl<F: FnMut(AckProgress) + Send> AckObserver for F {
+    fn on_ack(&mut self, progress: AckProgress) {
+        self(progress)
+    }
+}
+
+/// Destination for encoded records
+///
+/// Implemented for `ZerobusStream`; tests substitute an in-memory sink.
+pub trait IngestSink: Send {
+    /// Send one encoded record, returning a future that resolves when it is acknowledged
+    fn ingest(&mut self, record: Vec<u8>) -> impl Future<Output = Result<AckFuture>> + Send;
+
+    /// Force pending records to be transmitted
+    fn flush(&mut self) -> impl Future<Output = Result<()>> + Send;
+
+    /// Flush and gracefully close the sink
+    fn close(&mut self) -> impl Future<Output = Result<()>> + Send;
+}
+
+impl IngestSink for ZerobusStream {
+    async fn ingest(&mut self, record: Vec<u8>) -> Result<AckFuture> {
+        let ack_future = self.ingest_record(record).await?;
+        Ok(Box::pin(async move {
+            ack_future.await?;
+            Ok(())
+        }))
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        ZerobusStream::flush(self).await?;
+        Ok(())
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        ZerobusStream::close(self).await?;
+        Ok(())
+    }
+}
+
+/// Opens the streams a handler ingests into
+///
+/// Implemented for `ZerobusSdk`; tests substitute
+/// [`MockStreams`](crate::testing::handler::MockStreams), whose streams are
+/// [`MockSink`](crate::testing::MockSink)s.
+pub trait CreateStream: Sync {
+    type Stream: IngestSink;
+
+    fn create_stream(
+        &self,
+        table_properties: TableProperties,
+        client_id: String,
+        client_secret: String,
+        options: Option<StreamConfigurationOptions>,
+    ) -> impl Future<Output = Result<Self::Stream>> + Send;
+
+    /// Replace a stream that failed, re-ingesting the records it left unacknowledged
+    fn recreate_stream(
+        &self,
+        stream: Self::Stream,
+    ) -> impl Future<Output = Result<Self::Stream>> + Send;
+
+    /// The records `stream` has sent but not had acknowledged
+    fn unacked_records(
+        &self,
+        stream: &mut Self::Stream,
+    ) -> impl Future<Output = Result<Vec<Vec<u8>>>> + Send;
+}
+
+impl CreateStream for ZerobusSdk {
+    type Stream = ZerobusStream;
+
+    async fn create_stream(
+        &self,
+        table_properties: TableProperties,
+        client_id: String,
+        client_secret: String,
+        options: Option<StreamConfigurationOptions>,
+    ) -> Result<ZerobusStream> {
+        Ok(
+            ZerobusSdk::create_stream(self, table_properties, client_id, client_secret, options)
+                .await?,
+        )
+    }
+
+    async fn recreate_stream(&self, stream: ZerobusStream) -> Result<ZerobusStream> {
+        Ok(ZerobusSdk::recreate_stream(self, stream).await?)
+    }
+
+    async fn unacked_records(&self, stream: &mut ZerobusStream) -> Result<Vec<Vec<u8>>> {
+        Ok(stream.get_unacked_records().await?.into_iter().collect())
+    }
+}
+
+/// Counts reported once a pipeline has drained
+#[derive(Debug, Clone, PartialEq)]
+pub struct IngestSummary {
+    /// Records acknowledged by Zerobus
+    pub ingested: u64,
+    /// Records that failed to send or were never acknowledged
+    pub failed: u64,
+    /// Encoded bytes of all acknowledged records
+    pub bytes: u64,
+    /// First error seen, kept for reporting
+    pub first_error: Option<String>,
+    /// `failed`, broken down by the class of each failure
+    pub failed_by_class: BTreeMap<ErrorClass, u64>,
+    /// Time from sending each acknowledged record until its ack was drained, in
+    /// milliseconds
+    pub ack_latency_ms: Distribution,
+    /// Encoded size of each record sent, acknowledged or not, in bytes
+    pub record_bytes: Distribution,
+    /// Time from the event time of each acknowledged record that has one until its ack
+    /// was drained, in milliseconds: how stale data is when it lands
+    pub end_to_end_ms: Distribution,
+    /// Acknowledged records whose event time was after their ack, counted at zero in
+    /// `end_to_end_ms`; a sign of a producer's clock running ahead
+    pub clock_skewed: u64,
+}
+
+impl Default for IngestSummary {
+    fn default() -> Self {
+        Self {
+            ingested: 0,
+            failed: 0,
+            bytes: 0,
+            first_error: None,
+            failed_by_class: BTreeMap::new(),
+            ack_latency_ms: Distribution::latency(),
+            record_bytes: Distribution::size(),
+            end_to_end_ms: Distribution::end_to_end(),
+            clock_skewed: 0,
+        }
+    }
+}
+
+impl IngestSummary {
+    fn record_failure(&mut self, error: &anyhow::Error) -> ErrorClass {
+        let class = classify(error);
+        self.failed += 1;
+        *self.failed_by_class.entry(class).or_default() += 1;
+        if self.first_error.is_none() {
+            self.first_error = Some(format!("{:#}", error));
+        }
+        class
+    }
+}
+
+/// A sent record waiting for its acknowledgment
+struct Pending {
+    size: u64,
+    sent_at: Instant,
+    /// When the record's event happened, as its source tells
+    event_time: Option<SystemTime>,
+    ack_future: AckFuture,
+    on_ack: Option<AckCallback>,
+    /// Source payload, kept to be logged if the record fails
+    payload: Option<Vec<u8>>,
+    /// Open from the record's ingest until its ack is drained
+    span: Option<Span>,
+}
+
+/// Pushes encoded records into a sink while keeping the number of unacknowledged
+/// records bounded
+///
+/// Once `max_pending` acks are outstanding the sink is flushed and every pending ack is
+/// awaited before more records are accepted, so memory use stays flat no matter how
+/// many records a source produces. With [`Pipeline::max_pending_bytes`], the encoded
+/// bytes of unacknowledged records are bounded as well.
+pub struct Pipeline<S: IngestSink> {
+    sink: S,
+    max_pending: usize,
+    /// Ceiling on `pending_bytes`; unbounded when unset
+    max_pending_bytes: Option<u64>,
+    pending: VecDeque<Pending>,
+    /// Encoded bytes of the records in `pending`
+    pending_bytes: u64,
+    summary: IngestSummary,
+    observer: Option<Box<dyn AckObserver>>,
+    /// Table named on the span of every record; records get no span when unset
+    traced_table: Option<String>,
+    /// Logs sampled payloads, and the payloads of records that fail
+    payload_log: Option<PayloadLog>,
+    /// End-to-end latencies are measured until the time it reads
+    clock: Arc<dyn Clock>,
+}
+
+/// Read `MAX_PENDING_BYTES`, the ceiling for [`Pipeline::max_pending_bytes`]; `None`
+/// when it is not set
+pub fn max_pending_bytes_from_env() -> Result<Option<u64>> {
+    match std::env::var("MAX_PENDING_BYTES") {
+        Ok(value) if !value.trim().is_empty() => value
+            .trim()
+            .parse::<u64>()
+            .ok()
+            .filter(|bytes| *bytes > 0)
+            .map(Some)
+            .ok_or_else(|| {
+                anyhow!(
+                    "MAX_PENDING_BYTES must be a positive number of bytes, got {:?}",
+                    value
+                )
+            }),
+        _ => Ok(None),
+    }
+}
+
+impl<S: IngestSink> Pipeline<S> {
+    pub fn new(sink: S, max_pending: usize) -> Self {
+        Self {
+            sink,
+            max_pending: max_pending.max(1),
+            max_pending_bytes: None,
+            pending: VecDeque::new(),
+            pending_bytes: 0,
+            summary: IngestSummary::default(),
+            observer: None,
+            traced_table: None,
+            payload_log: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Bound the encoded bytes of unacknowledged records to `max_bytes`
+    ///
+    /// A record that would take the pending bytes past the ceiling is held back while
+    /// the sink is flushed and the oldest acks are awaited, until the pending bytes are
+    /// at or below half the ceiling. Slow acks then block the source instead of growing
+    /// memory. A single record larger than the ceiling waits for every pending ack.
+    pub fn max_pending_bytes(mut self, max_bytes: Option<u64>) -> Self {
+        self.max_pending_bytes = max_bytes.map(|max_bytes| max_bytes.max(1));
+        self
+    }
+
+    /// Count ack latencies, in milliseconds, and record sizes, in bytes, into buckets
+    /// with these upper bounds instead of the defaults; see [`crate::distribution`]
+    pub fn distribution_bounds(
+        mut self,
+        latency_ms: Option<Vec<f64>>,
+        size: Option<Vec<f64>>,
+    ) -> Self {
+        if let Some(bounds) = latency_ms {
+            self.summary.ack_latency_ms = Distribution::new(bounds);
+        }
+        if let Some(bounds) = size {
+            self.summary.record_bytes = Distribution::new(bounds);
+        }
+        self
+    }
+
+    /// Report each acknowledgment to `observer` as it is drained
+    ///
+    /// Acks are drained oldest first once the window fills or on an explicit drain, so
+    /// the latency is an upper bound: an ack that resolved while an older one was
+    /// awaited is reported when the pipeline reaches it.
+    pub fn ack_observer(mut self, observer: impl AckObserver + 'static) -> Self {
+        self.observer = Some(Box::new(observer));
+        self
+    }
+
+    /// Give every record an `ingest_record` span, from its ingest until its ack is
+    /// drained, naming `table` in its `zerobus.table` attribute
+    ///
+    /// The span is a child of the span current at ingest, and its status is set when the
+    /// record fails. For exporting spans with [`crate::otel`]; one span per record is
+    /// too many for most log formats.
+    pub fn trace_records(mut self, table: impl Into<String>) -> Self {
+        self.traced_table = Some(table.into());
+        self
+    }
+
+    /// Log the source payloads given to [`Pipeline::ingest_with_payload`] with `log`: a
+    /// sample of them at debug level, and every one whose record fails
+    ///
+    /// Without it, payloads are not kept.
+    pub fn payload_log(mut self, log: PayloadLog) -> Self {
+        self.payload_log = Some(log);
+        self
+    }
+
+    /// Measure end-to-end latencies until the time `clock` reads when an ack is drained,
+    /// instead of the system clock
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Ingest one encoded record, draining acknowledgments first if the window is full
+    pub async fn ingest(&mut self, record: Vec<u8>) -> Result<()> {
+        self.send(record, None, None, None).await
+    }
+
+    /// Ingest one encoded record whose event happened at `event_time`, such as a Kafka
+    /// record's timestamp, so its end-to-end latency is measured once it is acknowledged
+    pub async fn ingest_at(
+        &mut self,
+        record: Vec<u8>,
+        event_time: Option<SystemTime>,
+    ) -> Result<()> {
+        self.send(record, event_time, None, None).await
+    }
+
+    /// Ingest one encoded record along with the payload it was built from, which is
+    /// logged by the [`Pipeline::payload_log`] if it is sampled or the record fails
+    pub async fn ingest_with_payload(&mut self, record: Vec<u8>, payload: Vec<u8>) -> Result<()> {
+        let payload = self.payload_log.as_ref().map(|log| {
+            log.log_sampled("record", payload.as_slice());
+            payload
+        });
+        self.send(record, None, None, payload).await
+    }
+
+    /// Ingest one encoded record and report its outcome to `on_ack`
+    ///
+    /// `on_ack` runs when a later drain reaches the record's acknowledgment, or right
+    /// away if it could not be sent. For sources that answer each record on its own,
+    /// e.g. a producer waiting for a per-frame reply. It never runs if the pipeline is
+    /// dropped, or given up on with [`Pipeline::into_sink`], before that.
+    pub async fn ingest_with_callback(
+        &mut self,
+        record: Vec<u8>,
+        on_ack: impl FnOnce(Result<()>) + Send + 'static,
+    ) -> Result<()> {
+        self.send(record, None, Some(Box::new(on_ack)), None).await
+    }
+
+    async fn send(
+        &mut self,
+        record: Vec<u8>,
+        event_time: Option<SystemTime>,
+        on_ack: Option<AckCallback>,
+        payload: Option<Vec<u8>>,
+    ) -> Result<()> {
+        let size = record.len() as u64;
+        if self.pending.len() >= self.max_pending {
+            self.drain().await?;
+        } else if let Some(max_bytes) = self.max_pending_bytes {
+            if self.pending_bytes + size > max_bytes {
+                self.drain_to(Some(max_bytes / 2)).await?;
+            }
+        }
+
+        let span = self.traced_table.as_deref().map(|table| {
+            info_span!(
+                "ingest_record",
+                zerobus.table = table,
+                zerobus.record_bytes = size as i64,
+                otel.status_code = tracing::field::Empty,
+                otel.status_description = tracing::field::Empty,
+            )
+        });
+        let ingested = match &span {
+            Some(span) => self.sink.ingest(record).instrument(span.clone()).await,
+            None => self.sink.ingest(record).await,
+        };
+        match ingested {
+            Ok(ack_future) => {
+                self.summary.record_bytes.observe(size as f64);
+                self.pending_bytes += size;
+                self.pending.push_back(Pending {
+                    size,
+                    sent_at: Instant::now(),
+                    event_time,
+                    ack_future,
+                    on_ack,
+                    payload,
+                    span,
+                });
+                Ok(())
+            }
+            Err(e) => {
+                self.summary.record_failure(&e);
+                if let Some(span) = &span {
+                    fail_span(span, &e);
+                }
+                if let (Some(log), Some(payload)) = (&self.payload_log, &payload) {
+                    log.log_failed("record", payload.as_slice(), format_args!("{:#}", e));
+                }
+                if let Some(on_ack) = on_ack {
+                    on_ack(Err(anyhow!("{:#}", e)));
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Flush the sink and wait for every outstanding acknowledgment
+    pub async fn drain(&mut self) -> Result<()> {
+        self.drain_to(None).await
+    }
+
+    /// Flush the sink and wait for the oldest acknowledgments until at most
+    /// `low_water` bytes are pending, or for all of them
+    async fn drain_to(&mut self, low_water: Option<u64>) -> Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        self.sink.flush().await?;
+        while !low_water.is_some_and(|low_water| self.pending_bytes <= low_water) {
+            let Some(pending) = self.pending.pop_front() else {
+                break;
+            };
+            self.pending_bytes -= pending.size;
+            let result = pending.ack_future.await;
+            match &result {
+                Ok(()) => {
+                    let latency = pending.sent_at.elapsed();
+                    self.summary.ingested += 1;
+                    self.summary.bytes += pending.size;
+                    self.summary
+                        .ack_latency_ms
+                        .observe(latency.as_secs_f64() * 1000.0);
+                    let end_to_end = pending
+                        .event_time
+                        .map(|event_time| end_to_end(event_time, self.clock.now()));
+                    if let Some((end_to_end, clock_skewed)) = end_to_end {
+                        self.summary
+                            .end_to_end_ms
+                            .observe(end_to_end.as_secs_f64() * 1000.0);
+                        self.summary.clock_skewed += u64::from(clock_skewed);
+                    }
+                    if let Some(observer) = &mut self.observer {
+                        observer.on_ack(AckProgress {
+                            acked: self.summary.ingested,
+                            latency,
+                            end_to_end: end_to_end.map(|(end_to_end, _)| end_to_end),
+                            clock_skewed: end_to_end.is_some_and(|(_, skewed)| skewed),
+                        });
+                    }
+                }
+                Err(e) => {
+                    let class = self.summary.record_failure(e);
+                    error!("Record was not acknowledged ({}): {:#}", class, e);
+                    if let Some(span) = &pending.span {
+                        fail_span(span, e);
+                    }
+                    if let (Some(log), Some(payload)) = (&self.payload_log, &pending.payload) {
+                        log.log_failed("record", payload.as_slice(), format_args!("{:#}", e));
+                    }
+                }
+            }
+            if let Some(on_ack) = pending.on_ack {
+                on_ack(result);
+            }
+        }
+        Ok(())
+    }
+
+    /// Ingest a group of records and wait until every one of them is acknowledged
+    ///
+    /// Fails if any record of the group could not be sent or was not acknowledged.
+    /// Outstanding acks are drained even after a send error, so failures are never
+    /// attributed to the next group. Suited to request/response sources that can only
+    /// answer once their records are durable.
+    pub async fn ingest_batch(&mut self, records: impl IntoIterator<Item = Vec<u8>>) -> Result<()> {
+        self.ingest_batch_at(records.into_iter().map(|record| (record, None)))
+            .await
+    }
+
+    /// [`Pipeline::ingest_batch`] for records with the event time of each, as
+    /// [`Pipeline::ingest_at`] takes it
+    pub async fn ingest_batch_at(
+        &mut self,
+        records: impl IntoIterator<Item = (Vec<u8>, Option<SystemTime>)>,
+    ) -> Result<()> {
+        let failed_before = self.summary.failed;
+
+        let mut result = Ok(());
+        for (record, event_time) in records {
+            if let Err(e) = self.ingest_at(record, event_time).await {
+                result = Err(e);
+                break;
+            }
+        }
+
+        let drained = self.drain().await;
+        result?;
+        drained?;
+
+        let failed = self.summary.failed - failed_before;
+        if failed > 0 {
+            bail!("{} records were not acknowledged", failed);
+        }
+        Ok(())
+    }
+
+    /// Number of records sent but not yet acknowledged
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Encoded bytes of the records sent but not yet acknowledged
+    pub fn pending_bytes(&self) -> u64 {
+        self.pending_bytes
+    }
+
+    /// Counts so far; acks still pending are not included
+    pub fn summary(&self) -> &IngestSummary {
+        &self.summary
+    }
+
+    /// Give up on outstanding acks and return the sink, e.g. to inspect what it still
+    /// holds once a shutdown deadline has passed
+    pub fn into_sink(self) -> S {
+        self.sink
+    }
+
+    /// Drain outstanding acks, close the sink, and return the final summary
+    pub async fn finish(mut self) -> Result<IngestSummary> {
+        self.drain().await?;
+        self.sink.close().await?;
+        info!(
+            "Pipeline finished: {} ingested, {} failed, {} bytes",
+            self.summary.ingested, self.summary.failed, self.summary.bytes
+        );
+        if let Some(latency) = self.summary.ack_latency_ms.percentiles() {
+            info!(
+                "Ack latency: p50 {}ms, p90 {}ms, p99 {}ms, max {:.1}ms",
+                latency.p50, latency.p90, latency.p99, latency.max
+            );
+        }
+        let end_to_end = &self.summary.end_to_end_ms;
+        if let Some(median) = end_to_end.quantile(0.5) {
+            info!(
+                "End-to-end latency: min {:.1}ms, median {}ms, max {:.1}ms ({} clock-skewed)",
+                end_to_end.min(),
+                median,
+                end_to_end.max(),
+                self.summary.clock_skewed
+            );
+        }
+        Ok(self.summary)
+    }
+}
+
+/// Time from `event_time` until `now`, and whether it was clamped to zero because
+/// `event_time` is after `now`
+fn end_to_end(event_time: SystemTime, now: SystemTime) -> (Duration, bool) {
+    match now.duration_since(event_time) {
+        Ok(elapsed) => (elapsed, false),
+        Err(_) => (Duration::ZERO, true),
+    }
+}
+
+fn fail_span(span: &Span, error: &anyhow::Error) {
+    span.record("otel.status_code", "ERROR");
+    span.record("otel.status_description", format!("{:#}", error));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::FixedClock;
+    use crate::redact::Redact;
+    use crate::testing::handler::with_env;
+    use crate::testing::MockSink;
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn test_pipeline_bounds_pending_acks() {
+        let sink = MockSink::default();
+        let mut pipeline = Pipeline::new(sink.clone(), 3);
+
+        for i in 0..7u8 {
+            pipeline.ingest(vec![i]).await.unwrap();
+            assert!(pipeline.pending() <= 3);
+        }
+
+        let summary = pipeline.finish().await.unwrap();
+        assert_eq!(7, summary.ingested);
+        assert_eq!(0, summary.failed);
+        assert_eq!(7, summary.bytes);
+        // Two window drains plus the final drain
+        assert_eq!(3, sink.flushes());
+        assert!(sink.closed());
+        assert_eq!(7, sink.records().len());
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_counts_failed_acks() {
+        let sink = MockSink::default().fail_acks_for(|record| record == [1]);
+        let mut pipeline = Pipeline::new(sink, 10);
+
+        for i in 0..3u8 {
+            pipeline.ingest(vec![i]).await.unwrap();
+        }
+
+        let summary = pipeline.finish().await.unwrap();
+        assert_eq!(2, summary.ingested);
+        assert_eq!(1, summary.failed);
+        assert!(summary.first_error.is_some());
+        assert_eq!(
+            BTreeMap::from([(ErrorClass::Retryable, 1)]),
+            summary.failed_by_class
+        );
+    }
+
+    #[tokio::test]
+    async fn test_summary_has_latency_and_size_distributions() {
+        let sink = MockSink::default().fail_acks_for(|record| record.len() == 3000);
+        let mut pipeline = Pipeline::new(sink, 10).distribution_bounds(Some(vec![1000.0]), None);
+
+        for size in [100, 300, 3000, 2_000_000] {
+            pipeline.ingest(vec![0; size]).await.unwrap();
+        }
+        let summary = pipeline.finish().await.unwrap();
+
+        // Every record sent is sized, failed or not
+        assert_eq!(4, summary.record_bytes.count());
+        assert_eq!(
+            &[1, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+            summary.record_bytes.bucket_counts()
+        );
+        assert_eq!(Some(2_000_000.0), summary.record_bytes.quantile(0.99));
+
+        // Only acknowledged records have a latency, all well under a second here
+        assert_eq!(&[1000.0], summary.ack_latency_ms.bounds());
+        assert_eq!(&[3, 0], summary.ack_latency_ms.bucket_counts());
+    }
+
+    #[tokio::test]
+    async fn test_ingest_with_callback_reports_each_record() {
+        let sink = MockSink::default()
+            .fail_acks_for(|record| record == [1])
+            .fail_ingests_with(|record| (record == [2]).then(|| anyhow!("stream closed")));
+        let mut pipeline = Pipeline::new(sink, 10);
+        let outcomes = Arc::new(Mutex::new(Vec::new()));
+
+        for i in 0..3u8 {
+            let outcomes = Arc::clone(&outcomes);
+            let _ = pipeline
+                .ingest_with_callback(vec![i], move |result| {
+                    outcomes
+                        .lock()
+                        .unwrap()
+                        .push((i, result.map_err(|e| e.to_string())));
+                })
+                .await;
+        }
+        // The failed send is reported at once; acks only once drained
+        assert_eq!(
+            vec![(2, Err("stream closed".to_string()))],
+            *outcomes.lock().unwrap()
+        );
+
+        pipeline.drain().await.unwrap();
+        assert_eq!(
+            vec![
+                (2, Err("stream closed".to_string())),
+                (0, Ok(())),
+                (1, Err("mock ack failure".to_string())),
+            ],
+            *outcomes.lock().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_payloads_are_kept_for_the_payload_log() {
+        let payload = br#"{"email": "ada@example.com"}"#.to_vec();
+
+        // Without a payload log there is nothing to log them with
+        let mut pipeline = Pipeline::new(MockSink::default(), 10);
+        pipeline
+            .ingest_with_payload(vec![0], payload.clone())
+            .await
+            .unwrap();
+        assert_eq!(None, pipeline.pending[0].payload);
+
+        let sink = MockSink::default().fail_acks_for(|record| record == [1]);
+        let log = PayloadLog::new(Redact::parse("/email").unwrap()).sample_rate(1.0);
+        let mut pipeline = Pipeline::new(sink, 10).payload_log(log);
+        for i in 0..2u8 {
+            pipeline
+                .ingest_with_payload(vec![i], payload.clone())
+                .await
+                .unwrap();
+        }
+        assert_eq!(Some(&payload), pipeline.pending[1].payload.as_ref());
+
+        let summary = pipeline.finish().await.unwrap();
+        assert_eq!(1, summary.ingested);
+        assert_eq!(1, summary.failed);
+    }
+
+    #[tokio::test]
+    async fn test_ingest_batch_reports_only_its_own_failures() {
+        let sink = MockSink::default().fail_acks_for(|record| record == [1]);
+        let mut pipeline = Pipeline::new(sink.clone(), 10);
+
+        let error = pipeline
+            .ingest_batch(vec![vec![0], vec![1]])
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("1 records"));
+        assert_eq!(0, pipeline.pending());
+
+        pipeline.ingest_batch(vec![vec![2], vec![3]]).await.unwrap();
+        assert_eq!(3, pipeline.summary().ingested);
+        assert_eq!(2, sink.flushes());
+    }
+
+    #[tokio::test]
+    async fn test_end_to_end_latency_is_measured_from_the_event_time() {
+        let sink = MockSink::default().fail_acks_for(|record| record == [3]);
+        let clock = FixedClock::at_unix_secs(1_718_020_860);
+        let now = clock.now();
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let observed = Arc::clone(&reports);
+        let mut pipeline = Pipeline::new(sink, 10).clock(Arc::new(clock)).ack_observer(
+            move |progress: AckProgress| {
+                observed
+                    .lock()
+                    .unwrap()
+                    .push((progress.end_to_end, progress.clock_skewed));
+            },
+        );
+
+        pipeline
+            .ingest_at(vec![0], Some(now - Duration::from_secs(120)))
+            .await
+            .unwrap();
+        // A producer whose clock runs a minute ahead
+        pipeline
+            .ingest_at(vec![1], Some(now + Duration::from_secs(60)))
+            .await
+            .unwrap();
+        pipeline.ingest_at(vec![2], None).await.unwrap();
+        // Not acknowledged, so it never lands
+        pipeline
+            .ingest_at(vec![3], Some(now - Duration::from_secs(1)))
+            .await
+            .unwrap();
+        let summary = pipeline.finish().await.unwrap();
+
+        assert_eq!(3, summary.ingested);
+        assert_eq!(
+            vec![
+                (Some(Duration::from_secs(120)), false),
+                (Some(Duration::ZERO), true),
+                (None, false)
+            ],
+            *reports.lock().unwrap()
+        );
+        // Only the records with an event time are observed, the skewed one at zero
+        assert_eq!(2, summary.end_to_end_ms.count());
+        assert_eq!(1, summary.clock_skewed);
+        assert_eq!(0.0, summary.end_to_end_ms.min());
+        assert_eq!(120_000.0, summary.end_to_end_ms.max());
+        assert_eq!(120_000.0, summary.end_to_end_ms.sum());
+        // The median is the upper bound of the first bucket, where the skewed one fell
+        assert_eq!(Some(100.0), summary.end_to_end_ms.quantile(0.5));
+    }
+
+    #[tokio::test]
+    async fn test_records_without_an_event_time_have_no_end_to_end_latency() {
+        let mut pipeline = Pipeline::new(MockSink::default(), 10);
+        pipeline.ingest_batch(vec![vec![0], vec![1]]).await.unwrap();
+        pipeline.ingest(vec![2]).await.unwrap();
+
+        let summary = pipeline.finish().await.unwrap();
+        assert_eq!(3, summary.ingested);
+        assert_eq!(0, summary.end_to_end_ms.count());
+        assert_eq!(0, summary.clock_skewed);
+        assert_eq!(None, summary.end_to_end_ms.quantile(0.5));
+    }
+
+    /// Counts acknowledgments, keeping the cumulative count of each report
+    #[derive(Clone, Default)]
+    struct CountingObserver {
+        reports: Arc<Mutex<Vec<u64>>>,
+    }
+
+    impl AckObserver for CountingObserver {
+        fn on_ack(&mut self, progress: AckProgress) {
+            self.reports.lock().unwrap().push(progress.acked);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ack_observer_is_told_of_each_acknowledgment() {
+        let sink = MockSink::default().fail_acks_for(|record| record == [2]);
+        let observer = CountingObserver::default();
+        let mut pipeline = Pipeline::new(sink, 2).ack_observer(observer.clone());
+
+        for i in 0..5u8 {
+            pipeline.ingest(vec![i]).await.unwrap();
+        }
+        // Two full windows have been drained, and record 2 was not acknowledged
+        assert_eq!(vec![1, 2, 3], *observer.reports.lock().unwrap());
+
+        let summary = pipeline.finish().await.unwrap();
+        assert_eq!(4, summary.ingested);
+        // Once per acknowledged record; the failed one is not reported
+        assert_eq!(vec![1, 2, 3, 4], *observer.reports.lock().unwrap());
+    }
+
+    /// A sink whose acks resolve only when the test releases them, oldest first
+    #[derive(Clone, Default)]
+    struct GatedSink {
+        gates: Arc<Mutex<VecDeque<tokio::sync::oneshot::Sender<()>>>>,
+    }
+
+    impl GatedSink {
+        fn release(&self) {
+            let gate = self.gates.lock().unwrap().pop_front().unwrap();
+            gate.send(()).unwrap();
+        }
+    }
+
+    impl IngestSink for GatedSink {
+        async fn ingest(&mut self, _record: Vec<u8>) -> Result<AckFuture> {
+            let (gate, released) = tokio::sync::oneshot::channel();
+            self.gates.lock().unwrap().push_back(gate);
+            Ok(Box::pin(async move {
+                released.await?;
+                Ok(())
+            }))
+        }
+
+        async fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        async fn close(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Let the spawned ingest run until it waits on an ack
+    async fn settle() {
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pending_bytes_ceiling_blocks_until_acks_drain() {
+        let sink = GatedSink::default();
+        let mut pipeline = Pipeline::new(sink.clone(), 100).max_pending_bytes(Some(1000));
+
+        for _ in 0..5 {
+            pipeline.ingest(vec![0; 200]).await.unwrap();
+        }
+        assert_eq!(1000, pipeline.pending_bytes());
+
+        // The next record would pass the ceiling, so it waits until the pending bytes
+        // are back at half of it
+        let ingest = tokio::spawn(async move {
+            pipeline.ingest(vec![0; 200]).await.unwrap();
+            pipeline
+        });
+        for _ in 0..2 {
+            settle().await;
+            assert!(!ingest.is_finished());
+            sink.release();
+        }
+        settle().await;
+        assert!(!ingest.is_finished());
+        sink.release();
+
+        let pipeline = ingest.await.unwrap();
+        assert_eq!(3, pipeline.pending());
+        assert_eq!(600, pipeline.pending_bytes());
+        assert_eq!(3, pipeline.summary().ingested);
+    }
+
+    #[test]
+    fn test_max_pending_bytes_from_env() {
+        let read = |value| with_env(&[("MAX_PENDING_BYTES", value)], max_pending_bytes_from_env);
+        assert_eq!(Some(67_108_864), read("67108864").unwrap());
+        assert!(read("64MB").is_err());
+        assert_eq!(None, with_env(&[], max_pending_bytes_from_env).unwrap());
+    }
+}
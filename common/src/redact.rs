@@ -0,0 +1,48 @@
+//! Replacing values at JSON pointers, before an event is stored or logged
+
+use anyhow::{bail, Result};
+use serde_json::Value;
+
+/// What a redacted value is replaced with
+pub const REDACTED: &str = "[REDACTED]";
+
+/// Values replaced before an event is stored anywhere
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Redact {
+    pointers: Vec<String>,
+}
+
+impl Redact {
+    pub fn parse(fields: &str) -> Result<Self> {
+        let pointers = fields
+            .split(',')
+            .map(str::trim)
+            .filter(|pointer| !pointer.is_empty())
+            .map(|pointer| {
+                if !pointer.starts_with('/') {
+                    bail!(
+                        "Redacted field {:?} must be a JSON pointer such as /customer/email",
+                        pointer
+                    );
+                }
+                Ok(pointer.to_string())
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { pointers })
+    }
+
+    /// Whether no value is redacted
+    pub fn is_empty(&self) -> bool {
+        self.pointers.is_empty()
+    }
+
+    /// Replace the value at each pointer the event has; values that are null are left
+    /// null
+    pub fn apply(&self, event: &mut Value) {
+        for pointer in &self.pointers {
+            if let Some(value) = event.pointer_mut(pointer).filter(|value| !value.is_null()) {
+                *value = Value::from(REDACTED);
+            }
+        }
+    }
+}
@@ -0,0 +1,97 @@
+//! Where sampling decisions and retry jitter get their randomness from.
+//!
+//! Like the [`Clock`](crate::clock::Clock) of the encode paths, code that draws random
+//! numbers takes an [`Rng`], so a test can swap the operating system's randomness for a
+//! [`SeededRng`] and see the same draws every run.
+
+use std::collections::hash_map::RandomState;
+use std::fmt::Debug;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Increment of the splitmix64 state, the golden ratio in 64 bits
+const GOLDEN_GAMMA: u64 = 0x9E37_79B9_7F4A_7C15;
+
+pub trait Rng: Send + Sync + Debug {
+    fn next_u64(&self) -> u64;
+
+    /// A number in `0..1`, from the top 53 bits of [`Rng::next_u64`]
+    fn next_f64(&self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Between half of `delay` and all of it, so clients that failed together do not
+    /// all retry at the same moment
+    fn jitter(&self, delay: Duration) -> Duration {
+        delay.mul_f64(0.5 + self.next_f64() / 2.0)
+    }
+}
+
+/// Randomness seeded by the operating system, used outside tests
+///
+/// Every draw hashes with fresh keys from std's `RandomState`, which takes its seed from
+/// the operating system, so no dependency is needed for the few numbers drawn here.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OsRng;
+
+impl Rng for OsRng {
+    fn next_u64(&self) -> u64 {
+        RandomState::new().build_hasher().finish()
+    }
+}
+
+/// A splitmix64 generator: the sequence of draws depends only on the seed
+#[derive(Debug, Default)]
+pub struct SeededRng {
+    state: AtomicU64,
+}
+
+impl SeededRng {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            state: AtomicU64::new(seed),
+        }
+    }
+}
+
+impl Rng for SeededRng {
+    fn next_u64(&self) -> u64 {
+        let mut z = self
+            .state
+            .fetch_add(GOLDEN_GAMMA, Ordering::Relaxed)
+            .wrapping_add(GOLDEN_GAMMA);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seeded_rng_repeats_its_draws() {
+        let draws = |seed| {
+            let rng = SeededRng::new(seed);
+            (0..100).map(|_| rng.next_u64()).collect::<Vec<_>>()
+        };
+        assert_eq!(draws(42), draws(42));
+        assert_ne!(draws(42), draws(7));
+
+        // The first output of splitmix64 seeded with 0
+        assert_eq!(0xE220_A839_7B1D_CDAF, SeededRng::new(0).next_u64());
+    }
+
+    #[test]
+    fn test_jitter_stays_within_half_of_the_delay() {
+        let rng = SeededRng::new(1);
+        let delay = Duration::from_millis(1000);
+        for _ in 0..1000 {
+            let jittered = rng.jitter(delay);
+            assert!((delay / 2..=delay).contains(&jittered), "{:?}", jittered);
+        }
+        assert!((0..1000).all(|_| (0.0..1.0).contains(&OsRng.next_f64())));
+    }
+}
@@ -0,0 +1,125 @@
+//! Routing of records to target tables by a source-specific key
+
+use std::collections::HashMap;
+use tracing::warn;
+
+/// Maps a routing key to a target table, selected by `TABLE_ROUTES`
+///
+/// What the key is depends on the source, e.g. a Kafka topic, the `<db>.<table>` of a
+/// CDC event, or a queue name.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct TableRouter {
+    routes: HashMap<String, String>,
+    /// Table for keys without a route; keys are skipped when unset
+    default_table: Option<String>,
+}
+
+impl TableRouter {
+    /// Routes from `TABLE_ROUTES`, falling back to `DEFAULT_TABLE`
+    pub fn from_env() -> Self {
+        let routes = std::env::var("TABLE_ROUTES").unwrap_or_default();
+        let default_table = std::env::var("DEFAULT_TABLE").ok();
+        Self::new(&routes, default_table.as_deref())
+    }
+
+    /// Parse comma-separated `<key>=<table>` pairs
+    pub fn new(routes: &str, default_table: Option<&str>) -> Self {
+        let routes = routes
+            .split(',')
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty())
+            .filter_map(|pair| match pair.split_once('=') {
+                Some((key, table)) if !key.trim().is_empty() && !table.trim().is_empty() => {
+                    Some((key.trim().to_string(), table.trim().to_string()))
+                }
+                _ => {
+                    warn!("Ignoring malformed TABLE_ROUTES entry {:?}", pair);
+                    None
+                }
+            })
+            .collect();
+        Self {
+            routes,
+            default_table: default_table
+                .map(str::trim)
+                .filter(|table| !table.is_empty())
+                .map(str::to_string),
+        }
+    }
+
+    /// Routes from `(key, table)` pairs, e.g. read from a config file
+    pub fn from_routes(
+        routes: impl IntoIterator<Item = (String, String)>,
+        default_table: Option<String>,
+    ) -> Self {
+        Self {
+            routes: routes.into_iter().collect(),
+            default_table,
+        }
+    }
+
+    /// Target table for `key`, if it has one
+    pub fn route(&self, key: &str) -> Option<&str> {
+        self.routes
+            .get(key)
+            .or(self.default_table.as_ref())
+            .map(String::as_str)
+    }
+
+    /// Every table a key can be routed to, each once, e.g. to open their streams up
+    /// front
+    pub fn tables(&self) -> Vec<&str> {
+        let mut tables: Vec<&str> = self
+            .routes
+            .values()
+            .chain(self.default_table.iter())
+            .map(String::as_str)
+            .collect();
+        tables.sort_unstable();
+        tables.dedup();
+        tables
+    }
+}
+
+/// Name of the message `zerobus-generate` writes for `table`: `table_<name>`
+pub fn message_name(table: &str) -> String {
+    format!("table_{}", table.rsplit('.').next().unwrap_or(table))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_routes() {
+        let router = TableRouter::new(
+            "inventory.customers=main.cdc.customers, shop.orders = main.cdc.orders,broken",
+            None,
+        );
+
+        assert_eq!(
+            Some("main.cdc.customers"),
+            router.route("inventory.customers")
+        );
+        assert_eq!(Some("main.cdc.orders"), router.route("shop.orders"));
+        assert_eq!(None, router.route("shop.accounts"));
+
+        let router = TableRouter::new("", Some("main.cdc.everything"));
+        assert_eq!(Some("main.cdc.everything"), router.route("shop.accounts"));
+
+        let router = TableRouter::new(
+            "a=main.cdc.orders,b=main.cdc.customers,c=main.cdc.orders",
+            Some("main.cdc.customers"),
+        );
+        assert_eq!(
+            vec!["main.cdc.customers", "main.cdc.orders"],
+            router.tables()
+        );
+    }
+
+    #[test]
+    fn test_message_name() {
+        assert_eq!("table_customers", message_name("main.cdc.customers"));
+        assert_eq!("table_orders", message_name("orders"));
+    }
+}
@@ -0,0 +1,139 @@
+use anyhow::{Context, Result};
+use async_compression::tokio::bufread::GzipDecoder;
+use aws_sdk_s3::Client;
+use percent_encoding::percent_decode_str;
+use std::sync::OnceLock;
+use tokio::io::{AsyncBufRead, AsyncRead, BufReader};
+
+// S3 client reused across Lambda invocations
+static CLIENT: OnceLock<Client> = OnceLock::new();
+
+/// S3 client built from the default AWS configuration (created once per container)
+pub async fn client() -> &'static Client {
+    if let Some(client) = CLIENT.get() {
+        return client;
+    }
+    let config = aws_config::load_from_env().await;
+    CLIENT.get_or_init(|| Client::new(&config))
+}
+
+/// Decode an object key from an S3 event notification
+///
+/// Keys in notifications are URL-encoded, with spaces sent as `+`.
+pub fn decode_object_key(key: &str) -> Result<String> {
+    let key = key.replace('+', " ");
+    Ok(percent_decode_str(&key)
+        .decode_utf8()
+        .context("Object key is not valid UTF-8")?
+        .into_owned())
+}
+
+/// Open an S3 object as a buffered line reader, decompressing `.gz` objects on the fly
+///
+/// The body is streamed, so memory use does not depend on the object size.
+pub async fn open_object(
+    client: &Client,
+    bucket: &str,
+    key: &str,
+) -> Result<Box<dyn AsyncBufRead + Unpin + Send>> {
+    let object = client
+        .get_object()
+        .bucket(bucket)
+        .key(key)
+        .send()
+        .await
+        .with_context(|| format!("Failed to get s3://{}/{}", bucket, key))?;
+
+    Ok(decompress(key, object.body.into_async_read()))
+}
+
+/// Wrap an object body in a buffered reader, gunzipping it when the key ends in `.gz`
+pub fn decompress(
+    key: &str,
+    body: impl AsyncRead + Unpin + Send + 'static,
+) -> Box<dyn AsyncBufRead + Unpin + Send> {
+    let body = BufReader::new(body);
+    if key.ends_with(".gz") {
+        let mut decoder = GzipDecoder::new(body);
+        // Log delivery services may write concatenated gzip members
+        decoder.multiple_members(true);
+        Box::new(BufReader::new(decoder))
+    } else {
+        Box::new(body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_object_key() {
+        assert_eq!(
+            "AWSLogs/123456789012/vpcflowlogs/us-east-1/2024/01/01/file name.log.gz",
+            decode_object_key(
+                "AWSLogs/123456789012/vpcflowlogs/us-east-1/2024/01/01/file+name.log.gz"
+            )
+            .unwrap()
+        );
+        assert_eq!("a=b/c.log", decode_object_key("a%3Db/c.log").unwrap());
+    }
+}
+
+/// Against the S3 API of LocalStack; run with `--features s3,localstack`
+#[cfg(all(test, feature = "localstack"))]
+mod localstack_tests {
+    use super::*;
+    use crate::localstack::{self, TestBucket};
+    use async_compression::tokio::write::GzipEncoder;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+    const LINES: [&str; 2] = [r#"{"id": 1}"#, r#"{"id": 2}"#];
+
+    async fn read_lines(bucket: &TestBucket, notified_key: &str) -> Vec<String> {
+        let key = decode_object_key(notified_key).unwrap();
+        let reader = open_object(&bucket.client, &bucket.name, &key)
+            .await
+            .unwrap();
+        let mut lines = reader.lines();
+        let mut read = Vec::new();
+        while let Some(line) = lines.next_line().await.unwrap() {
+            read.push(line);
+        }
+        read
+    }
+
+    #[tokio::test]
+    async fn test_objects_named_in_notifications_are_read_back() {
+        let config = localstack::config().await;
+        let bucket = TestBucket::create(&config, "objects").await.unwrap();
+
+        let plain = LINES.join("\n");
+        bucket
+            .put("logs/day one.jsonl", plain.clone())
+            .await
+            .unwrap();
+        let mut gzipped = GzipEncoder::new(Vec::new());
+        gzipped.write_all(plain.as_bytes()).await.unwrap();
+        gzipped.shutdown().await.unwrap();
+        bucket
+            .put("logs/day one.jsonl.gz", gzipped.into_inner())
+            .await
+            .unwrap();
+
+        // Keys arrive URL-encoded, as in an event notification
+        assert_eq!(
+            LINES.to_vec(),
+            read_lines(&bucket, "logs/day+one.jsonl").await
+        );
+        assert_eq!(
+            LINES.to_vec(),
+            read_lines(&bucket, "logs/day+one.jsonl.gz").await
+        );
+
+        let missing = open_object(&bucket.client, &bucket.name, "logs/missing.jsonl").await;
+        assert!(missing.is_err());
+
+        bucket.delete().await.unwrap();
+    }
+}
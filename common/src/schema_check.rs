@@ -0,0 +1,893 @@
+//! Checking a descriptor against the live schema of its table
+//!
+//! Descriptors are compiled into the examples, so a table altered after the build only
+//! shows up once records are rejected. With `SCHEMA_CHECK` set, the table's columns are
+//! fetched from Unity Catalog at startup and compared with the descriptor first:
+//!
+//! - `off` - No check (the default)
+//! - `warn` - Log each mismatch and carry on
+//! - `strict` - Refuse to start on any mismatch
+//!
+//! A field without a column, a column that cannot be left out without a field, and a
+//! field whose type cannot be written to its column are mismatches. Columns are compared
+//! by their full type from `type_json`, so the elements of arrays, the keys and values of
+//! maps, and the fields of structs are compared too. [`check`] compares once and fails
+//! with a [`report`] of the differences, for a `schema-check` command.
+
+use anyhow::{bail, Context, Result};
+use prost_types::field_descriptor_proto::{Label, Type};
+use prost_types::{DescriptorProto, FieldDescriptorProto};
+use serde_json::Value;
+use std::fmt;
+use tracing::{info, warn};
+
+use crate::auth::StreamAuth;
+use crate::workspace::WorkspaceApi;
+
+/// A column of a Unity Catalog table, or a field of a struct column
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Column {
+    pub name: String,
+    /// The `type_name` Unity Catalog reports, such as `LONG` or `STRING`
+    pub type_name: String,
+    pub nullable: bool,
+    /// The full type, from the `type_json` Unity Catalog reports; without it only
+    /// `type_name` is compared
+    pub data_type: Option<DataType>,
+}
+
+/// A Delta type, as `type_json` spells it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DataType {
+    /// A type without parts, by its `type_name`, such as `LONG` or `DECIMAL`
+    Primitive(String),
+    Array(Box<DataType>),
+    /// The key and value types
+    Map(Box<DataType>, Box<DataType>),
+    Struct(Vec<Column>),
+}
+
+impl DataType {
+    /// Parse the `type` of a `type_json` field: a name like `"long"`, or an object for
+    /// an array, a map, or a struct
+    pub fn parse(json: &Value) -> Result<Self> {
+        if let Some(name) = json.as_str() {
+            return Ok(DataType::Primitive(type_name(name)));
+        }
+        let part = |name: &str| {
+            DataType::parse(&json[name]).with_context(|| format!("Bad {} in {}", name, json))
+        };
+        match json["type"].as_str() {
+            Some("array") => Ok(DataType::Array(Box::new(part("elementType")?))),
+            Some("map") => Ok(DataType::Map(
+                Box::new(part("keyType")?),
+                Box::new(part("valueType")?),
+            )),
+            Some("struct") => json["fields"]
+                .as_array()
+                .with_context(|| format!("Struct without fields: {}", json))?
+                .iter()
+                .map(parse_struct_field)
+                .collect::<Result<_>>()
+                .map(DataType::Struct),
+            _ => bail!("Unknown type {}", json),
+        }
+    }
+
+    /// The `type_name` Unity Catalog reports for a column of this type
+    pub fn type_name(&self) -> &str {
+        match self {
+            DataType::Primitive(name) => name,
+            DataType::Array(_) => "ARRAY",
+            DataType::Map(..) => "MAP",
+            DataType::Struct(_) => "STRUCT",
+        }
+    }
+}
+
+/// The `type_name` of a primitive `type_json` type: `long` is `LONG`, `integer` is `INT`,
+/// and `decimal(10,2)` is `DECIMAL`
+fn type_name(json_name: &str) -> String {
+    let name = json_name.split('(').next().unwrap_or_default();
+    match name {
+        "integer" => "INT".to_string(),
+        "varchar" => "STRING".to_string(),
+        _ => name.to_ascii_uppercase(),
+    }
+}
+
+/// A field of a struct in `type_json`, or a whole column's `type_json`
+fn parse_struct_field(json: &Value) -> Result<Column> {
+    let data_type = DataType::parse(&json["type"])?;
+    Ok(Column {
+        name: json["name"]
+            .as_str()
+            .with_context(|| format!("Field without a name: {}", json))?
+            .to_string(),
+        type_name: data_type.type_name().to_string(),
+        nullable: json["nullable"].as_bool().unwrap_or(true),
+        data_type: Some(data_type),
+    })
+}
+
+/// A difference between a descriptor and its table
+///
+/// Fields and columns inside other columns are named by their path, such as
+/// `device.model`, `tags.element`, or `attributes.value`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mismatch {
+    /// The descriptor has a field the table has no column for
+    UnknownField { field: String },
+    /// The table has a non-nullable column the descriptor has no field for
+    MissingColumn { column: String },
+    /// The field's type cannot be written to the column
+    Type {
+        field: String,
+        field_type: String,
+        column_type: String,
+    },
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mismatch::UnknownField { field } => {
+                write!(f, "field {} has no column in the table", field)
+            }
+            Mismatch::MissingColumn { column } => {
+                write!(f, "column {} is not nullable, but has no field", column)
+            }
+            Mismatch::Type {
+                field,
+                field_type,
+                column_type,
+            } => write!(
+                f,
+                "field {} is {}, which cannot be written to a {} column",
+                field, field_type, column_type
+            ),
+        }
+    }
+}
+
+/// What to do when the descriptor does not match the table, read from `SCHEMA_CHECK`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchemaCheck {
+    Off,
+    Warn,
+    Strict,
+}
+
+impl SchemaCheck {
+    pub fn from_env() -> Result<Self> {
+        match std::env::var("SCHEMA_CHECK") {
+            Ok(value) => match value.trim().to_ascii_lowercase().as_str() {
+                "" | "off" => Ok(SchemaCheck::Off),
+                "warn" => Ok(SchemaCheck::Warn),
+                "strict" => Ok(SchemaCheck::Strict),
+                _ => bail!("SCHEMA_CHECK must be off, warn, or strict, got {:?}", value),
+            },
+            Err(_) => Ok(SchemaCheck::Off),
+        }
+    }
+}
+
+/// Fetch the columns of `table`, a full `catalog.schema.table` name
+pub async fn fetch_columns(
+    databricks_host: &str,
+    auth: &StreamAuth,
+    table: &str,
+) -> Result<Vec<Column>> {
+    let api = WorkspaceApi::new(databricks_host);
+    let token = api.token(auth).await?;
+    let info = api
+        .get(&format!("/api/2.1/unity-catalog/tables/{}", table), &token)
+        .await?;
+    parse_columns(table, &info)
+}
+
+/// The columns of a `tables` API response
+fn parse_columns(table: &str, info: &Value) -> Result<Vec<Column>> {
+    info["columns"]
+        .as_array()
+        .with_context(|| format!("Table {} has no columns", table))?
+        .iter()
+        .map(|column| {
+            let field = |name: &str| {
+                column[name]
+                    .as_str()
+                    .map(str::to_string)
+                    .with_context(|| format!("A column of {} has no {}", table, name))
+            };
+            let name = field("name")?;
+            let data_type = match column["type_json"].as_str() {
+                Some(type_json) => {
+                    let type_json: Value = serde_json::from_str(type_json)
+                        .with_context(|| format!("Column {} has a bad type_json", name))?;
+                    let data_type = DataType::parse(&type_json["type"])
+                        .with_context(|| format!("Column {} has a bad type_json", name))?;
+                    Some(data_type)
+                }
+                None => None,
+            };
+            Ok(Column {
+                type_name: field("type_name")?,
+                nullable: column["nullable"].as_bool().unwrap_or(true),
+                name,
+                data_type,
+            })
+        })
+        .collect()
+}
+
+/// The differences between `descriptor` and the table with `columns`; none when every
+/// record the descriptor encodes can be written to the table
+pub fn compare(descriptor: &DescriptorProto, columns: &[Column]) -> Vec<Mismatch> {
+    let mut mismatches = Vec::new();
+    compare_fields(descriptor, descriptor, "", columns, &mut mismatches);
+    mismatches
+}
+
+/// Compare the fields of `message` with `columns`, the columns of the table or the
+/// fields of a struct at `path`
+fn compare_fields(
+    root: &DescriptorProto,
+    message: &DescriptorProto,
+    path: &str,
+    columns: &[Column],
+    mismatches: &mut Vec<Mismatch>,
+) {
+    let join = |name: &str| match path {
+        "" => name.to_string(),
+        _ => format!("{}.{}", path, name),
+    };
+    let column = |name: &str| {
+        columns
+            .iter()
+            .find(|column| column.name.eq_ignore_ascii_case(name))
+    };
+    for field in &message.field {
+        let Some(column) = column(field.name()) else {
+            mismatches.push(Mismatch::UnknownField {
+                field: join(field.name()),
+            });
+            continue;
+        };
+        compare_type(
+            root,
+            &join(field.name()),
+            &shape(root, field),
+            &column.type_name,
+            column.data_type.as_ref(),
+            mismatches,
+        );
+    }
+    for column in columns {
+        let has_field = message
+            .field
+            .iter()
+            .any(|field| field.name().eq_ignore_ascii_case(&column.name));
+        if !column.nullable && !has_field {
+            mismatches.push(Mismatch::MissingColumn {
+                column: join(&column.name),
+            });
+        }
+    }
+}
+
+/// Compare a field of `shape` with a column of `type_name`, and then their parts when the
+/// column's full type is known
+fn compare_type(
+    root: &DescriptorProto,
+    path: &str,
+    shape: &Shape,
+    type_name: &str,
+    data_type: Option<&DataType>,
+    mismatches: &mut Vec<Mismatch>,
+) {
+    if !field_types(&type_name.to_ascii_uppercase()).contains(&shape.name()) {
+        mismatches.push(Mismatch::Type {
+            field: path.to_string(),
+            field_type: shape.name().to_string(),
+            column_type: type_name.to_string(),
+        });
+        return;
+    }
+    let mut part = |name: &str, shape: &Shape, data_type: &DataType| {
+        compare_type(
+            root,
+            &format!("{}.{}", path, name),
+            shape,
+            data_type.type_name(),
+            Some(data_type),
+            mismatches,
+        )
+    };
+    match (shape, data_type) {
+        (Shape::Repeated(element), Some(DataType::Array(element_type))) => {
+            part("element", element, element_type);
+        }
+        (Shape::Map(key, value), Some(DataType::Map(key_type, value_type))) => {
+            part("key", key, key_type);
+            part("value", value, value_type);
+        }
+        (Shape::Message(Some(message)), Some(DataType::Struct(fields))) => {
+            compare_fields(root, message, path, fields, mismatches);
+        }
+        _ => {}
+    }
+}
+
+/// What a field holds, as far as columns are concerned
+#[derive(Debug)]
+enum Shape<'a> {
+    /// A scalar, by its name in [`field_types`]
+    Scalar(&'static str),
+    /// A message, with its descriptor when it is nested in the descriptor compared
+    Message(Option<&'a DescriptorProto>),
+    Repeated(Box<Shape<'a>>),
+    Map(Box<Shape<'a>>, Box<Shape<'a>>),
+}
+
+impl Shape<'_> {
+    /// How the field is described in mismatches, and matched to column types
+    fn name(&self) -> &'static str {
+        match self {
+            Shape::Scalar(name) => name,
+            Shape::Message(_) => "message",
+            Shape::Repeated(_) => "repeated",
+            Shape::Map(..) => "map",
+        }
+    }
+}
+
+fn shape<'a>(root: &'a DescriptorProto, field: &FieldDescriptorProto) -> Shape<'a> {
+    let message = match field.r#type() {
+        Type::Message | Type::Group => Some(nested(root, field.type_name())),
+        _ => None,
+    };
+    if field.label() == Label::Repeated {
+        if let Some(Some(entry)) = message {
+            if entry.options.as_ref().and_then(|o| o.map_entry) == Some(true) {
+                let part = |number| {
+                    entry
+                        .field
+                        .iter()
+                        .find(|field| field.number() == number)
+                        .map_or(Shape::Scalar("unknown"), |field| shape(root, field))
+                };
+                return Shape::Map(Box::new(part(1)), Box::new(part(2)));
+            }
+        }
+        return Shape::Repeated(Box::new(match message {
+            Some(message) => Shape::Message(message),
+            None => scalar(field),
+        }));
+    }
+    match message {
+        Some(message) => Shape::Message(message),
+        None => scalar(field),
+    }
+}
+
+fn scalar<'a>(field: &FieldDescriptorProto) -> Shape<'a> {
+    Shape::Scalar(match field.r#type() {
+        Type::Double => "double",
+        Type::Float => "float",
+        Type::Int64 | Type::Sint64 | Type::Sfixed64 | Type::Uint64 | Type::Fixed64 => "int64",
+        Type::Int32 | Type::Sint32 | Type::Sfixed32 | Type::Uint32 | Type::Fixed32 => "int32",
+        Type::Bool => "bool",
+        Type::String => "string",
+        Type::Bytes => "bytes",
+        Type::Enum => "enum",
+        Type::Message | Type::Group => "message",
+    })
+}
+
+/// The message `type_name` names, when it is `root` or nested in it
+fn nested<'a>(root: &'a DescriptorProto, type_name: &str) -> Option<&'a DescriptorProto> {
+    let mut path = type_name
+        .split('.')
+        .skip_while(|name| Some(*name) != root.name.as_deref());
+    path.next()?;
+    path.try_fold(root, |message, name| {
+        message
+            .nested_type
+            .iter()
+            .find(|nested| nested.name() == name)
+    })
+}
+
+/// The field types that can be written to a column whose `type_name` is `column_type`
+fn field_types(column_type: &str) -> &'static [&'static str] {
+    match column_type {
+        "DOUBLE" => &["double"],
+        "FLOAT" => &["float"],
+        "LONG" => &["int64"],
+        // Timestamps are microseconds since Unix epoch
+        "TIMESTAMP" | "TIMESTAMP_NTZ" => &["int64"],
+        "INT" => &["int32", "enum"],
+        "SHORT" | "BYTE" => &["int32"],
+        // Dates are days since Unix epoch
+        "DATE" => &["int32"],
+        "BOOLEAN" => &["bool"],
+        "STRING" => &["string", "enum"],
+        "CHAR" | "VARIANT" => &["string"],
+        "BINARY" => &["bytes"],
+        "STRUCT" => &["message"],
+        "ARRAY" => &["repeated"],
+        "MAP" => &["map"],
+        _ => &[],
+    }
+}
+
+/// A report of `mismatches`, by the columns added, removed, and retyped since the
+/// descriptor was generated
+pub fn report(table: &str, mismatches: &[Mismatch]) -> String {
+    let mut lines: Vec<_> = mismatches
+        .iter()
+        .map(|mismatch| match mismatch {
+            Mismatch::MissingColumn { column } => (
+                0,
+                format!("  added    {} (not nullable, and no field)", column),
+            ),
+            Mismatch::UnknownField { field } => {
+                (1, format!("  removed  {} (the field has no column)", field))
+            }
+            Mismatch::Type {
+                field,
+                field_type,
+                column_type,
+            } => (
+                2,
+                format!(
+                    "  retyped  {} (the field is {}, the column {})",
+                    field, field_type, column_type
+                ),
+            ),
+        })
+        .collect();
+    lines.sort_by_key(|(kind, _)| *kind);
+    let mut report = format!(
+        "The descriptor differs from {} in {} ways:",
+        table,
+        mismatches.len()
+    );
+    for (_, line) in lines {
+        report.push('\n');
+        report.push_str(&line);
+    }
+    report
+}
+
+/// Compare `descriptor` with the live schema of `table` once, failing with a [`report`]
+/// when they differ
+pub async fn check(
+    databricks_host: &str,
+    auth: &StreamAuth,
+    table: &str,
+    descriptor: &DescriptorProto,
+) -> Result<()> {
+    let columns = fetch_columns(databricks_host, auth, table)
+        .await
+        .with_context(|| format!("Failed to fetch the schema of {}", table))?;
+    let mismatches = compare(descriptor, &columns);
+    if !mismatches.is_empty() {
+        bail!("{}", report(table, &mismatches));
+    }
+    Ok(())
+}
+
+/// Compare `descriptor` with the live schema of `table`, as `SCHEMA_CHECK` says
+///
+/// Fails with `strict` when they differ, and with `warn` or `strict` when the schema
+/// cannot be fetched.
+pub async fn check_from_env(
+    databricks_host: &str,
+    auth: &StreamAuth,
+    table: &str,
+    descriptor: &DescriptorProto,
+) -> Result<()> {
+    let mode = SchemaCheck::from_env()?;
+    if mode == SchemaCheck::Off {
+        return Ok(());
+    }
+    let columns = fetch_columns(databricks_host, auth, table)
+        .await
+        .with_context(|| format!("Failed to fetch the schema of {}", table))?;
+    let mismatches = compare(descriptor, &columns);
+    if mismatches.is_empty() {
+        info!("Descriptor matches the schema of {}", table);
+        return Ok(());
+    }
+    for mismatch in &mismatches {
+        warn!("Descriptor does not match {}: {}", table, mismatch);
+    }
+    if mode == SchemaCheck::Strict {
+        bail!(
+            "Descriptor does not match the schema of {} in {} ways; set SCHEMA_CHECK=warn to start anyway",
+            table,
+            mismatches.len()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::TokenFile;
+    use axum::extract::Path;
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::get;
+    use axum::{Json, Router};
+    use prost_types::MessageOptions;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    fn field(name: &str, number: i32, r#type: Type, label: Label) -> FieldDescriptorProto {
+        FieldDescriptorProto {
+            name: Some(name.to_string()),
+            number: Some(number),
+            r#type: Some(r#type as i32),
+            label: Some(label as i32),
+            type_name: (r#type == Type::Message).then(|| format!(".table_events.{}", name)),
+            ..Default::default()
+        }
+    }
+
+    /// `id`, `name`, `tags`, `attributes` (a map), `created_at`, and `device` (a
+    /// message)
+    fn descriptor() -> DescriptorProto {
+        let map_entry = DescriptorProto {
+            name: Some("attributes".to_string()),
+            options: Some(MessageOptions {
+                map_entry: Some(true),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        DescriptorProto {
+            name: Some("table_events".to_string()),
+            field: vec![
+                field("id", 1, Type::Int64, Label::Optional),
+                field("name", 2, Type::String, Label::Optional),
+                field("tags", 3, Type::String, Label::Repeated),
+                field("attributes", 4, Type::Message, Label::Repeated),
+                field("created_at", 5, Type::Int64, Label::Optional),
+                field("device", 6, Type::Message, Label::Optional),
+            ],
+            nested_type: vec![map_entry],
+            ..Default::default()
+        }
+    }
+
+    /// A `tables` API response for the table of [`descriptor`]
+    fn table_info() -> Value {
+        json!({
+            "full_name": "main.default.events",
+            "columns": [
+                {"name": "id", "type_name": "LONG", "nullable": false},
+                {"name": "Name", "type_name": "STRING", "nullable": true},
+                {"name": "tags", "type_name": "ARRAY", "nullable": true},
+                {"name": "attributes", "type_name": "MAP", "nullable": true},
+                {"name": "created_at", "type_name": "TIMESTAMP", "nullable": true},
+                {"name": "device", "type_name": "STRUCT", "nullable": true},
+                {"name": "comment", "type_name": "STRING", "nullable": true}
+            ]
+        })
+    }
+
+    #[test]
+    fn test_descriptor_matching_its_table() {
+        let columns = parse_columns("main.default.events", &table_info()).unwrap();
+        // Names match regardless of case, and a nullable column may be left out
+        assert_eq!(Vec::<Mismatch>::new(), compare(&descriptor(), &columns));
+    }
+
+    #[test]
+    fn test_descriptor_drifted_from_its_table() {
+        let mut info = table_info();
+        let columns = info["columns"].as_array_mut().unwrap();
+        // id was changed to a string, tags was dropped, and a required column added
+        columns[0]["type_name"] = json!("STRING");
+        columns.remove(2);
+        columns.push(json!({"name": "tenant", "type_name": "STRING", "nullable": false}));
+        let columns = parse_columns("main.default.events", &info).unwrap();
+
+        let mismatches = compare(&descriptor(), &columns);
+        assert_eq!(
+            vec![
+                Mismatch::Type {
+                    field: "id".to_string(),
+                    field_type: "int64".to_string(),
+                    column_type: "STRING".to_string(),
+                },
+                Mismatch::UnknownField {
+                    field: "tags".to_string(),
+                },
+                Mismatch::MissingColumn {
+                    column: "tenant".to_string(),
+                },
+            ],
+            mismatches
+        );
+        assert_eq!(
+            "field id is int64, which cannot be written to a STRING column",
+            mismatches[0].to_string()
+        );
+
+        // A repeated field is not a map, and a map is not an array
+        let mut swapped = descriptor();
+        swapped.field[2].name = Some("attributes".to_string());
+        swapped.field[3].name = Some("tags".to_string());
+        let columns = parse_columns("main.default.events", &table_info()).unwrap();
+        assert_eq!(2, compare(&swapped, &columns).len());
+    }
+
+    /// A `tables` API response captured from a workspace, with a column of each kind
+    fn captured_table_info() -> Value {
+        serde_json::from_str(include_str!("../testdata/uc_table.json")).unwrap()
+    }
+
+    fn message_field(
+        name: &str,
+        number: i32,
+        type_name: &str,
+        label: Label,
+    ) -> FieldDescriptorProto {
+        FieldDescriptorProto {
+            type_name: Some(format!(".device_events.table_device_events.{}", type_name)),
+            ..field(name, number, Type::Message, label)
+        }
+    }
+
+    fn message(name: &str, field: Vec<FieldDescriptorProto>) -> DescriptorProto {
+        DescriptorProto {
+            name: Some(name.to_string()),
+            field,
+            ..Default::default()
+        }
+    }
+
+    /// The descriptor generated from the captured table, without its `comment` column
+    fn device_events_descriptor() -> DescriptorProto {
+        let mut attributes_entry = message(
+            "AttributesEntry",
+            vec![
+                field("key", 1, Type::String, Label::Optional),
+                field("value", 2, Type::Int64, Label::Optional),
+            ],
+        );
+        attributes_entry.options = Some(MessageOptions {
+            map_entry: Some(true),
+            ..Default::default()
+        });
+        let device = message(
+            "Device",
+            vec![
+                field("model", 1, Type::String, Label::Optional),
+                field("firmware", 2, Type::Int32, Label::Optional),
+                field("seen_at", 3, Type::Int64, Label::Optional),
+            ],
+        );
+        let readings = message(
+            "Readings",
+            vec![
+                field("sensor", 1, Type::String, Label::Optional),
+                field("value", 2, Type::Double, Label::Optional),
+            ],
+        );
+        DescriptorProto {
+            nested_type: vec![attributes_entry, device, readings],
+            ..message(
+                "table_device_events",
+                vec![
+                    field("id", 1, Type::Int64, Label::Optional),
+                    field("name", 2, Type::String, Label::Optional),
+                    field("reading_count", 3, Type::Int32, Label::Optional),
+                    field("created_at", 4, Type::Int64, Label::Optional),
+                    field("created_date", 5, Type::Int32, Label::Optional),
+                    field("tags", 6, Type::String, Label::Repeated),
+                    message_field("attributes", 7, "AttributesEntry", Label::Repeated),
+                    message_field("device", 8, "Device", Label::Optional),
+                    message_field("readings", 9, "Readings", Label::Repeated),
+                ],
+            )
+        }
+    }
+
+    #[test]
+    fn test_type_json_of_a_captured_table() {
+        let columns = parse_columns("main.default.device_events", &captured_table_info()).unwrap();
+        let primitive = |name: &str| DataType::Primitive(name.to_string());
+        let struct_field = |name: &str, type_name: &str, nullable| Column {
+            name: name.to_string(),
+            type_name: type_name.to_string(),
+            nullable,
+            data_type: Some(primitive(type_name)),
+        };
+        let types: Vec<_> = columns
+            .iter()
+            .map(|column| (column.name.as_str(), column.data_type.clone().unwrap()))
+            .collect();
+        assert_eq!(
+            vec![
+                ("id", primitive("LONG")),
+                ("name", primitive("STRING")),
+                ("reading_count", primitive("INT")),
+                ("created_at", primitive("TIMESTAMP")),
+                ("created_date", primitive("DATE")),
+                ("tags", DataType::Array(Box::new(primitive("STRING")))),
+                (
+                    "attributes",
+                    DataType::Map(Box::new(primitive("STRING")), Box::new(primitive("LONG")))
+                ),
+                (
+                    "device",
+                    DataType::Struct(vec![
+                        struct_field("model", "STRING", false),
+                        struct_field("firmware", "INT", true),
+                        struct_field("seen_at", "TIMESTAMP", true),
+                    ])
+                ),
+                (
+                    "readings",
+                    DataType::Array(Box::new(DataType::Struct(vec![
+                        struct_field("sensor", "STRING", true),
+                        struct_field("value", "DOUBLE", true),
+                    ])))
+                ),
+                ("comment", primitive("STRING")),
+            ],
+            types
+        );
+        // Each type_name agrees with its type_json
+        for column in &columns {
+            assert_eq!(
+                column.type_name,
+                column.data_type.as_ref().unwrap().type_name()
+            );
+        }
+    }
+
+    #[test]
+    fn test_delta_types_map_to_field_types() {
+        for (column_type, expected) in [
+            ("STRING", &["string", "enum"][..]),
+            ("LONG", &["int64"]),
+            ("INT", &["int32", "enum"]),
+            ("SHORT", &["int32"]),
+            ("TIMESTAMP", &["int64"]),
+            ("TIMESTAMP_NTZ", &["int64"]),
+            ("DATE", &["int32"]),
+            ("ARRAY", &["repeated"]),
+            ("MAP", &["map"]),
+            ("STRUCT", &["message"]),
+            ("DECIMAL", &[]),
+        ] {
+            assert_eq!(expected, field_types(column_type), "{}", column_type);
+        }
+        for (json_name, type_name) in [
+            ("long", "LONG"),
+            ("integer", "INT"),
+            ("timestamp_ntz", "TIMESTAMP_NTZ"),
+            ("decimal(10,2)", "DECIMAL"),
+            ("varchar(64)", "STRING"),
+        ] {
+            assert_eq!(type_name, super::type_name(json_name));
+        }
+        assert!(DataType::parse(&json!({"type": "udt"})).is_err());
+    }
+
+    #[test]
+    fn test_descriptor_matching_a_captured_table() {
+        let columns = parse_columns("main.default.device_events", &captured_table_info()).unwrap();
+        assert_eq!(
+            Vec::<Mismatch>::new(),
+            compare(&device_events_descriptor(), &columns)
+        );
+    }
+
+    #[test]
+    fn test_nested_types_are_compared() {
+        let mut info = captured_table_info();
+        let columns = info["columns"].as_array_mut().unwrap();
+        // The map's values became strings, the struct gained a required field, and the
+        // array's elements became longs
+        columns[6]["type_json"] = json!(
+            r#"{"name":"attributes","type":{"type":"map","keyType":"string","valueType":"string","valueContainsNull":true},"nullable":true,"metadata":{}}"#
+        );
+        columns[7]["type_json"] = json!(
+            r#"{"name":"device","type":{"type":"struct","fields":[{"name":"model","type":"string","nullable":false,"metadata":{}},{"name":"firmware","type":"integer","nullable":true,"metadata":{}},{"name":"seen_at","type":"timestamp","nullable":true,"metadata":{}},{"name":"site","type":"string","nullable":false,"metadata":{}}]},"nullable":true,"metadata":{}}"#
+        );
+        columns[5]["type_json"] = json!(
+            r#"{"name":"tags","type":{"type":"array","elementType":"long","containsNull":true},"nullable":true,"metadata":{}}"#
+        );
+        let columns = parse_columns("main.default.device_events", &info).unwrap();
+
+        // And the descriptor has a reading field the table does not
+        let mut descriptor = device_events_descriptor();
+        descriptor.nested_type[2]
+            .field
+            .push(field("unit", 3, Type::String, Label::Optional));
+
+        let mismatches = compare(&descriptor, &columns);
+        assert_eq!(
+            vec![
+                Mismatch::Type {
+                    field: "tags.element".to_string(),
+                    field_type: "string".to_string(),
+                    column_type: "LONG".to_string(),
+                },
+                Mismatch::Type {
+                    field: "attributes.value".to_string(),
+                    field_type: "int64".to_string(),
+                    column_type: "STRING".to_string(),
+                },
+                Mismatch::MissingColumn {
+                    column: "device.site".to_string(),
+                },
+                Mismatch::UnknownField {
+                    field: "readings.element.unit".to_string(),
+                },
+            ],
+            mismatches
+        );
+        assert_eq!(
+            "The descriptor differs from main.default.device_events in 4 ways:\n  \
+             added    device.site (not nullable, and no field)\n  \
+             removed  readings.element.unit (the field has no column)\n  \
+             retyped  tags.element (the field is string, the column LONG)\n  \
+             retyped  attributes.value (the field is int64, the column STRING)",
+            report("main.default.device_events", &mismatches)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_columns_are_fetched_from_unity_catalog() {
+        async fn table(
+            Path(name): Path<String>,
+            headers: HeaderMap,
+        ) -> Result<Json<Value>, StatusCode> {
+            if headers.get("authorization").and_then(|v| v.to_str().ok())
+                != Some("Bearer workspace-token")
+            {
+                return Err(StatusCode::UNAUTHORIZED);
+            }
+            if name != "main.default.events" {
+                return Err(StatusCode::NOT_FOUND);
+            }
+            Ok(Json(table_info()))
+        }
+        let app = Router::new().route("/api/2.1/unity-catalog/tables/:name", get(table));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let host = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("token");
+        std::fs::write(&path, "workspace-token\n").unwrap();
+        let auth = StreamAuth::TokenFile(Arc::new(TokenFile::new(&path)));
+
+        let columns = fetch_columns(&host, &auth, "main.default.events")
+            .await
+            .unwrap();
+        assert_eq!(7, columns.len());
+        assert_eq!(
+            Column {
+                name: "id".to_string(),
+                type_name: "LONG".to_string(),
+                nullable: false,
+                data_type: None,
+            },
+            columns[0]
+        );
+        assert!(compare(&descriptor(), &columns).is_empty());
+
+        let error = fetch_columns(&host, &auth, "main.default.missing")
+            .await
+            .unwrap_err();
+        assert!(format!("{:#}", error).contains("404"));
+    }
+}
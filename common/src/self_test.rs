@@ -0,0 +1,205 @@
+//! A startup self-test, for deployment smoke tests.
+//!
+//! With `SELF_TEST=true`, a service ingests a single canary record into its table rather
+//! than starting, waits for the acknowledgment, and exits: with 0 once the canary is
+//! acknowledged, and non-zero when it is not. The canary carries [`CANARY_MARKER`] in a
+//! column the service picks, so it can be found in the table and left out of queries.
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::info;
+
+use crate::clock::Clock;
+use crate::pipeline::{IngestSink, Pipeline};
+
+/// Prefix of the value that marks a canary record
+pub const CANARY_MARKER: &str = "zerobus-self-test";
+
+/// Time to wait for the canary's acknowledgment when `SELF_TEST_TIMEOUT_SECS` is not set
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How a self-test runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelfTest {
+    /// A canary not acknowledged by then fails the test, rather than hanging the deploy
+    pub timeout: Duration,
+}
+
+impl Default for SelfTest {
+    fn default() -> Self {
+        Self {
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+}
+
+/// What a passing self-test saw
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelfTestReport {
+    pub canary_id: String,
+    /// From sending the canary until it was acknowledged and the stream closed
+    pub elapsed: Duration,
+}
+
+impl SelfTest {
+    /// A self-test when `SELF_TEST` is `true`; `None` when it is unset or `false`
+    pub fn from_env() -> Result<Option<Self>> {
+        let enabled = match std::env::var("SELF_TEST") {
+            Ok(value) => match value.trim().to_ascii_lowercase().as_str() {
+                "" | "false" | "0" => false,
+                "true" | "1" => true,
+                _ => bail!("SELF_TEST must be \"true\" or \"false\", got {:?}", value),
+            },
+            Err(_) => false,
+        };
+        if !enabled {
+            return Ok(None);
+        }
+        let timeout = match std::env::var("SELF_TEST_TIMEOUT_SECS") {
+            Ok(value) if !value.trim().is_empty() => {
+                let secs: u64 = value
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("Invalid SELF_TEST_TIMEOUT_SECS {:?}", value))?;
+                Duration::from_secs(secs)
+            }
+            _ => DEFAULT_TIMEOUT,
+        };
+        Ok(Some(Self { timeout }))
+    }
+
+    /// Ingest the record `canary` makes of a fresh canary ID into `sink`, and wait for
+    /// its acknowledgment
+    ///
+    /// `canary` puts the ID, which starts with [`CANARY_MARKER`] and carries the time
+    /// from `clock`, in a column of the row. The canary passes once it is acknowledged
+    /// and the sink is closed.
+    pub async fn run<S: IngestSink>(
+        &self,
+        sink: S,
+        table: &str,
+        clock: &dyn Clock,
+        canary: impl FnOnce(&str) -> Vec<u8>,
+    ) -> Result<SelfTestReport> {
+        let canary_id = canary_id(clock.now());
+        let record = canary(&canary_id);
+        info!("Self-test: ingesting canary {} into {}", canary_id, table);
+
+        let started = Instant::now();
+        let mut pipeline = Pipeline::new(sink, 1);
+        let summary = tokio::time::timeout(self.timeout, async move {
+            pipeline.ingest(record).await?;
+            pipeline.finish().await
+        })
+        .await
+        .map_err(|_| {
+            anyhow!(
+                "Self-test failed: canary {} was not acknowledged by {} within {:?}",
+                canary_id,
+                table,
+                self.timeout
+            )
+        })?
+        .with_context(|| format!("Self-test failed: canary {} to {}", canary_id, table))?;
+        if summary.ingested != 1 {
+            bail!(
+                "Self-test failed: canary {} was not acknowledged by {}: {}",
+                canary_id,
+                table,
+                summary
+                    .first_error
+                    .as_deref()
+                    .unwrap_or("no acknowledgment")
+            );
+        }
+
+        let elapsed = started.elapsed();
+        info!(
+            "Self-test passed: canary {} acknowledged by {} in {:?}",
+            canary_id, table, elapsed
+        );
+        Ok(SelfTestReport { canary_id, elapsed })
+    }
+}
+
+/// The marker and the time, so each canary can be told apart
+pub fn canary_id(now: SystemTime) -> String {
+    let micros = now
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros();
+    format!("{}-{}", CANARY_MARKER, micros)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{FixedClock, SystemClock};
+    use crate::testing::MockSink;
+
+    fn encode(canary_id: &str) -> Vec<u8> {
+        canary_id.as_bytes().to_vec()
+    }
+
+    #[tokio::test]
+    async fn test_self_test_ingests_one_canary() {
+        let sink = MockSink::default();
+        let report = SelfTest::default()
+            .run(sink.clone(), "main.default.stats", &SystemClock, encode)
+            .await
+            .unwrap();
+
+        assert!(report.canary_id.starts_with(CANARY_MARKER));
+        assert_eq!(vec![report.canary_id.into_bytes()], sink.records());
+        assert!(sink.closed());
+    }
+
+    #[tokio::test]
+    async fn test_canary_id_carries_the_clock_time() {
+        let sink = MockSink::default();
+        let clock = FixedClock::at_unix_secs(1_718_020_860);
+        let report = SelfTest::default()
+            .run(sink.clone(), "main.default.stats", &clock, encode)
+            .await
+            .unwrap();
+
+        assert_eq!("zerobus-self-test-1718020860000000", report.canary_id);
+        assert_eq!(vec![report.canary_id.into_bytes()], sink.records());
+    }
+
+    #[tokio::test]
+    async fn test_unacknowledged_canary_fails_the_self_test() {
+        let sink = MockSink::default().fail_acks_for(|_| true);
+        let error = SelfTest::default()
+            .run(sink.clone(), "main.default.stats", &SystemClock, encode)
+            .await
+            .unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("was not acknowledged by main.default.stats"),
+            "{:#}",
+            error
+        );
+        assert_eq!(1, sink.records().len());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stalled_canary_times_out() {
+        let sink = MockSink::default().stall_acks_for(|_| true);
+        let self_test = SelfTest {
+            timeout: Duration::from_secs(5),
+        };
+        let error = self_test
+            .run(sink, "main.default.stats", &SystemClock, encode)
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("within 5s"), "{:#}", error);
+    }
+
+    #[test]
+    fn test_canary_id() {
+        let now = UNIX_EPOCH + Duration::from_micros(1_718_020_860_000_000);
+        assert_eq!("zerobus-self-test-1718020860000000", canary_id(now));
+    }
+}
@@ -0,0 +1,218 @@
+//! Bounded shutdown for long-running ingestors.
+//!
+//! Orchestrators send SIGTERM and kill the process a fixed time later, so draining a
+//! stream on shutdown must not wait indefinitely. [`drain`] flushes and waits for
+//! outstanding acks until `SHUTDOWN_GRACE_MS` has passed, then closes the stream, or
+//! gives up and returns the records it still has unacknowledged.
+
+use anyhow::{Context, Result};
+use databricks_zerobus_ingest_sdk::ZerobusStream;
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+use crate::pipeline::{IngestSink, IngestSummary, Pipeline};
+
+/// Grace period when `SHUTDOWN_GRACE_MS` is not set; leaves headroom within the 30s
+/// most orchestrators allow between SIGTERM and SIGKILL
+pub const DEFAULT_GRACE: Duration = Duration::from_secs(20);
+
+/// A sink that can list the records it has sent but not had acknowledged
+pub trait UnackedSink: IngestSink {
+    fn unacked_records(&mut self) -> impl Future<Output = Result<Vec<Vec<u8>>>> + Send;
+}
+
+impl UnackedSink for ZerobusStream {
+    async fn unacked_records(&mut self) -> Result<Vec<Vec<u8>>> {
+        Ok(self.get_unacked_records().await?.into_iter().collect())
+    }
+}
+
+/// How a pipeline ended
+#[derive(Debug)]
+pub struct DrainOutcome {
+    /// Counts of the records acknowledged before the deadline
+    pub summary: IngestSummary,
+    /// Records still unacknowledged when the grace period ran out; empty if the
+    /// stream was drained and closed in time
+    pub unacked: Vec<Vec<u8>>,
+}
+
+/// Read `SHUTDOWN_GRACE_MS`, falling back to [`DEFAULT_GRACE`]
+pub fn grace_from_env() -> Result<Duration> {
+    match std::env::var("SHUTDOWN_GRACE_MS") {
+        Ok(value) if !value.trim().is_empty() => {
+            let millis = value.trim().parse::<u64>().with_context(|| {
+                format!(
+                    "SHUTDOWN_GRACE_MS must be a number of milliseconds, got {:?}",
+                    value
+                )
+            })?;
+            Ok(Duration::from_millis(millis))
+        }
+        _ => Ok(DEFAULT_GRACE),
+    }
+}
+
+/// Resolve on Ctrl+C or, on Unix, SIGTERM
+pub async fn signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    info!("Shutting down");
+}
+
+/// Flush `pipeline`, wait up to `grace` for its acks, and close its sink
+///
+/// If the deadline passes first, the sink is dropped without closing it, since
+/// closing waits for the same acks, and the records it still had unacknowledged
+/// are logged and returned.
+pub async fn drain<S: UnackedSink>(
+    mut pipeline: Pipeline<S>,
+    grace: Duration,
+) -> Result<DrainOutcome> {
+    let deadline = Instant::now() + grace;
+    match tokio::time::timeout_at(deadline, pipeline.drain()).await {
+        Ok(drained) => drained?,
+        Err(_) => {
+            let summary = pipeline.summary().clone();
+            let unacked = pipeline.into_sink().unacked_records().await?;
+            warn!(
+                "Shutdown grace period of {:?} ran out with {} records ({} bytes) unacknowledged",
+                grace,
+                unacked.len(),
+                unacked.iter().map(Vec::len).sum::<usize>()
+            );
+            return Ok(DrainOutcome { summary, unacked });
+        }
+    }
+
+    // Nothing is pending any more, so closing only has to end the stream
+    let summary = pipeline.finish().await?;
+    Ok(DrainOutcome {
+        summary,
+        unacked: Vec::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::chaos::{ChaosSink, Scenario};
+    use crate::testing::MockSink;
+    use serde_json::Value;
+    use std::collections::BTreeMap;
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test(start_paused = true)]
+    async fn test_drain_waits_for_acks_and_closes() {
+        let sink = MockSink::default();
+        let mut pipeline = Pipeline::new(sink.clone(), 100);
+        for record in [vec![1], vec![2], vec![3]] {
+            pipeline.ingest(record).await.unwrap();
+        }
+
+        let outcome = drain(pipeline, Duration::from_secs(5)).await.unwrap();
+
+        assert_eq!(3, outcome.summary.ingested);
+        assert!(outcome.unacked.is_empty());
+        assert_eq!(1, sink.flushes());
+        assert!(sink.closed());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_drain_returns_unacked_after_grace() {
+        let sink = MockSink::default().stall_acks_for(|record| record[0] >= 2);
+        let mut pipeline = Pipeline::new(sink.clone(), 100);
+        for record in [vec![1], vec![2], vec![3]] {
+            pipeline.ingest(record).await.unwrap();
+        }
+
+        let started = Instant::now();
+        let outcome = drain(pipeline, Duration::from_secs(5)).await.unwrap();
+
+        assert_eq!(Duration::from_secs(5), started.elapsed());
+        assert_eq!(1, outcome.summary.ingested);
+        assert_eq!(vec![vec![2], vec![3]], outcome.unacked);
+        assert_eq!(1, sink.flushes());
+        assert!(!sink.closed());
+    }
+
+    /// The record indices of an expectation, such as `"failed": [1, 2]`
+    fn indices(value: &Value) -> Vec<usize> {
+        value
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|index| index.as_u64().unwrap() as usize)
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_chaos_scenarios() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/chaos");
+        for scenario in Scenario::load_dir(&dir).unwrap() {
+            let Some(expect) = scenario.expect("pipeline") else {
+                continue;
+            };
+            let name = &scenario.name;
+            let sink = ChaosSink::new(MockSink::default(), scenario.faults.clone());
+            // A window of the whole run, so sending never waits on an ack
+            let mut pipeline = Pipeline::new(sink, scenario.records.max(1));
+            let outcomes = Arc::new(Mutex::new(BTreeMap::new()));
+            for index in 0..scenario.records {
+                let outcomes = Arc::clone(&outcomes);
+                let record = index.to_string().into_bytes();
+                let _ = pipeline
+                    .ingest_with_callback(record, move |result| {
+                        outcomes.lock().unwrap().insert(index, result.is_ok());
+                    })
+                    .await;
+            }
+
+            let grace = Duration::from_millis(expect["grace_ms"].as_u64().unwrap());
+            let started = Instant::now();
+            let outcome = drain(pipeline, grace).await.unwrap();
+            assert!(
+                started.elapsed() <= grace,
+                "{}: the drain overran its grace period",
+                name
+            );
+
+            let failed: Vec<usize> = outcomes
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(_, acked)| !**acked)
+                .map(|(index, _)| *index)
+                .collect();
+            let unacked: Vec<usize> = outcome
+                .unacked
+                .iter()
+                .map(|record| String::from_utf8_lossy(record).parse().unwrap())
+                .collect();
+            assert_eq!(expect["acked"], outcome.summary.ingested, "{}: acked", name);
+            assert_eq!(indices(&expect["failed"]), failed, "{}: failed", name);
+            assert_eq!(indices(&expect["unacked"]), unacked, "{}: unacked", name);
+        }
+    }
+}
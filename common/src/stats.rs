@@ -0,0 +1,456 @@
+//! Periodic stats reports for the long-running examples.
+//!
+//! A [`StatsReporter`] takes a [`Metrics::snapshot`] every interval and logs how each
+//! series changed since the previous one as a single JSON line: records in and out,
+//! bytes, failures by class, and the current in-flight records, lag, and stream age.
+//! Operators get a heartbeat without scraping `/metrics`. With a [`PublishStats`]
+//! target, such as CloudWatch, each report is pushed there as well.
+
+use anyhow::{Context, Result};
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+use crate::errors::ErrorClass;
+use crate::metrics::{Metrics, Snapshot};
+
+/// What changed in one series over a report's period
+#[derive(Debug, Clone, PartialEq)]
+pub struct SeriesStats {
+    pub table: String,
+    pub source: String,
+    /// Records sent or filtered out
+    pub records_in: u64,
+    /// Records acknowledged
+    pub records_out: u64,
+    /// Encoded bytes of the records sent
+    pub bytes: u64,
+    /// Records that failed, by class
+    pub errors: BTreeMap<ErrorClass, u64>,
+    /// Records in flight at the end of the period
+    pub in_flight: u64,
+    /// Lag at the end of the period, for sources that report it
+    pub lag: Option<u64>,
+    /// Time since the stream was opened, for series that track their stream
+    pub stream_age: Option<Duration>,
+}
+
+/// One report: every series, over the time since the previous report
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatsReport {
+    pub period: Duration,
+    pub series: Vec<SeriesStats>,
+}
+
+impl StatsReport {
+    pub fn to_json(&self) -> Value {
+        let period = self.period.as_secs_f64();
+        let series: Vec<Value> = self
+            .series
+            .iter()
+            .map(|s| {
+                let errors: Map<String, Value> = s
+                    .errors
+                    .iter()
+                    .map(|(class, count)| (class.to_string(), json!(count)))
+                    .collect();
+                let per_sec = if period > 0.0 {
+                    s.records_out as f64 / period
+                } else {
+                    0.0
+                };
+                json!({
+                    "table": s.table,
+                    "source": s.source,
+                    "records_in": s.records_in,
+                    "records_out": s.records_out,
+                    "records_out_per_sec": per_sec,
+                    "bytes": s.bytes,
+                    "errors": errors,
+                    "in_flight": s.in_flight,
+                    "lag": s.lag,
+                    "stream_age_secs": s.stream_age.map(|age| age.as_secs()),
+                })
+            })
+            .collect();
+        json!({"period_secs": period, "series": series})
+    }
+}
+
+/// Somewhere reports are pushed to besides the log
+pub trait PublishStats: Send + Sync {
+    fn publish(&self, report: &StatsReport) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// Reports are only logged
+impl PublishStats for () {
+    async fn publish(&self, _report: &StatsReport) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<P: PublishStats> PublishStats for Option<P> {
+    async fn publish(&self, report: &StatsReport) -> Result<()> {
+        match self {
+            Some(publisher) => publisher.publish(report).await,
+            None => Ok(()),
+        }
+    }
+}
+
+/// Read `STATS_INTERVAL_SECS`; unset or `0` turns reports off
+pub fn interval_from_env() -> Result<Option<Duration>> {
+    match std::env::var("STATS_INTERVAL_SECS") {
+        Ok(value) if !value.trim().is_empty() => {
+            let secs = value.trim().parse::<u64>().with_context(|| {
+                format!(
+                    "STATS_INTERVAL_SECS must be a number of seconds, got {:?}",
+                    value
+                )
+            })?;
+            Ok((secs > 0).then_some(Duration::from_secs(secs)))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Logs, and optionally publishes, what changed in a registry every interval
+pub struct StatsReporter<P = ()> {
+    metrics: Metrics,
+    interval: Duration,
+    publisher: P,
+    previous: HashMap<(String, String), Snapshot>,
+    reported_at: Instant,
+}
+
+impl StatsReporter {
+    pub fn new(metrics: Metrics, interval: Duration) -> Self {
+        Self {
+            metrics,
+            interval,
+            publisher: (),
+            previous: HashMap::new(),
+            reported_at: Instant::now(),
+        }
+    }
+}
+
+impl<P: PublishStats + 'static> StatsReporter<P> {
+    /// Push each report to `publisher` too
+    pub fn publish_to<Q: PublishStats>(self, publisher: Q) -> StatsReporter<Q> {
+        StatsReporter {
+            metrics: self.metrics,
+            interval: self.interval,
+            publisher,
+            previous: self.previous,
+            reported_at: self.reported_at,
+        }
+    }
+
+    /// Snapshot the registry and diff it against the previous snapshot
+    ///
+    /// Series with nothing new and nothing in flight are left out.
+    pub fn report(&mut self) -> StatsReport {
+        let now = Instant::now();
+        let period = now - self.reported_at;
+        self.reported_at = now;
+
+        let mut series = Vec::new();
+        for current in self.metrics.snapshot() {
+            let key = (current.table.clone(), current.source.clone());
+            let stats = match self.previous.get(&key) {
+                Some(previous) => diff(previous, &current, now),
+                None => diff(&empty(&current), &current, now),
+            };
+            let active = stats.records_in > 0
+                || stats.records_out > 0
+                || !stats.errors.is_empty()
+                || stats.in_flight > 0;
+            if active {
+                series.push(stats);
+            }
+            self.previous.insert(key, current);
+        }
+        StatsReport { period, series }
+    }
+
+    /// Report every interval until `stop` turns true, then a last time
+    pub async fn run(mut self, mut stop: watch::Receiver<bool>) {
+        loop {
+            let next = self.reported_at + self.interval;
+            // A dropped sender stops the reporter too
+            let stopped = tokio::time::timeout_at(next, stop.wait_for(|stopped| *stopped))
+                .await
+                .is_ok();
+            self.emit().await;
+            if stopped {
+                return;
+            }
+        }
+    }
+
+    /// Run in the background; the task ends after the report made once `stop` is true
+    pub fn spawn(self, stop: watch::Receiver<bool>) -> JoinHandle<()> {
+        tokio::spawn(self.run(stop))
+    }
+
+    async fn emit(&mut self) {
+        let report = self.report();
+        info!("Stats: {}", report.to_json());
+        if let Err(e) = self.publisher.publish(&report).await {
+            warn!("Failed to publish stats: {:#}", e);
+        }
+    }
+}
+
+/// A snapshot of a series before anything happened to it
+fn empty(current: &Snapshot) -> Snapshot {
+    Snapshot {
+        table: current.table.clone(),
+        source: current.source.clone(),
+        ingested: 0,
+        failed: 0,
+        filtered: 0,
+        sent: 0,
+        bytes: 0,
+        errors: BTreeMap::new(),
+        in_flight: 0,
+        lag: None,
+        stream_opened: None,
+    }
+}
+
+fn diff(previous: &Snapshot, current: &Snapshot, now: Instant) -> SeriesStats {
+    let errors = current
+        .errors
+        .iter()
+        .map(|(class, count)| {
+            let before = previous.errors.get(class).copied().unwrap_or_default();
+            (*class, count.saturating_sub(before))
+        })
+        .filter(|(_, count)| *count > 0)
+        .collect();
+    SeriesStats {
+        table: current.table.clone(),
+        source: current.source.clone(),
+        records_in: (current.sent + current.filtered)
+            .saturating_sub(previous.sent + previous.filtered),
+        records_out: current.ingested.saturating_sub(previous.ingested),
+        bytes: current.bytes.saturating_sub(previous.bytes),
+        errors,
+        in_flight: current.in_flight,
+        lag: current.lag,
+        stream_age: current.stream_opened.map(|opened| now - opened),
+    }
+}
+
+#[cfg(feature = "cloudwatch")]
+pub use cloudwatch::CloudWatchStats;
+
+#[cfg(feature = "cloudwatch")]
+mod cloudwatch {
+    use super::*;
+    use aws_sdk_cloudwatch::types::{Dimension, MetricDatum, StandardUnit};
+
+    /// Namespace of the stats when STATS_CLOUDWATCH_NAMESPACE is set but empty
+    pub const DEFAULT_NAMESPACE: &str = "Zerobus/Stats";
+
+    /// Most metric data one PutMetricData call accepts
+    const MAX_DATA_PER_CALL: usize = 1000;
+
+    /// Publishes reports to CloudWatch with PutMetricData
+    pub struct CloudWatchStats {
+        client: aws_sdk_cloudwatch::Client,
+        namespace: String,
+    }
+
+    impl CloudWatchStats {
+        /// Publish under `STATS_CLOUDWATCH_NAMESPACE`, or `None` when it is not set
+        pub async fn from_env() -> Option<Self> {
+            let namespace = std::env::var("STATS_CLOUDWATCH_NAMESPACE").ok()?;
+            let namespace = match namespace.trim() {
+                "" => DEFAULT_NAMESPACE.to_string(),
+                namespace => namespace.to_string(),
+            };
+            let config = aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await;
+            Some(Self {
+                client: aws_sdk_cloudwatch::Client::new(&config),
+                namespace,
+            })
+        }
+    }
+
+    impl PublishStats for CloudWatchStats {
+        async fn publish(&self, report: &StatsReport) -> Result<()> {
+            let data = metric_data(report);
+            for chunk in data.chunks(MAX_DATA_PER_CALL) {
+                self.client
+                    .put_metric_data()
+                    .namespace(&self.namespace)
+                    .set_metric_data(Some(chunk.to_vec()))
+                    .send()
+                    .await
+                    .context("PutMetricData failed")?;
+            }
+            Ok(())
+        }
+    }
+
+    /// A datum per figure of each series, dimensioned by its table and source
+    pub(super) fn metric_data(report: &StatsReport) -> Vec<MetricDatum> {
+        let mut data = Vec::new();
+        for s in &report.series {
+            let dimensions: Vec<Dimension> = [("Table", &s.table), ("Source", &s.source)]
+                .into_iter()
+                .filter(|(_, value)| !value.is_empty())
+                .map(|(name, value)| Dimension::builder().name(name).value(value).build())
+                .collect();
+            let mut datum =
+                |name: &str, unit: StandardUnit, value: f64, class: Option<ErrorClass>| {
+                    let mut dimensions = dimensions.clone();
+                    if let Some(class) = class {
+                        dimensions.push(
+                            Dimension::builder()
+                                .name("Class")
+                                .value(class.as_str())
+                                .build(),
+                        );
+                    }
+                    data.push(
+                        MetricDatum::builder()
+                            .metric_name(name)
+                            .unit(unit)
+                            .value(value)
+                            .set_dimensions(Some(dimensions))
+                            .build(),
+                    );
+                };
+            datum("RecordsIn", StandardUnit::Count, s.records_in as f64, None);
+            datum(
+                "RecordsOut",
+                StandardUnit::Count,
+                s.records_out as f64,
+                None,
+            );
+            datum("Bytes", StandardUnit::Bytes, s.bytes as f64, None);
+            for (class, count) in &s.errors {
+                datum("Errors", StandardUnit::Count, *count as f64, Some(*class));
+            }
+            datum("InFlight", StandardUnit::Count, s.in_flight as f64, None);
+            if let Some(lag) = s.lag {
+                datum("Lag", StandardUnit::Count, lag as f64, None);
+            }
+            if let Some(age) = s.stream_age {
+                datum("StreamAge", StandardUnit::Seconds, age.as_secs_f64(), None);
+            }
+        }
+        data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::Ordering;
+    use std::sync::{Arc, Mutex};
+
+    /// Keeps every report it is given
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<StatsReport>>>);
+
+    impl PublishStats for Recorder {
+        async fn publish(&self, report: &StatsReport) -> Result<()> {
+            self.0.lock().unwrap().push(report.clone());
+            Ok(())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reports_are_diffs_of_snapshots() {
+        let metrics = Metrics::default();
+        let series = metrics.series("main.default.events", "orders");
+        series.mark_stream_opened();
+        let mut reporter = StatsReporter::new(metrics.clone(), Duration::from_secs(60));
+
+        series.ingested.store(90, Ordering::Relaxed);
+        series.failed.store(2, Ordering::Relaxed);
+        series.filtered.store(3, Ordering::Relaxed);
+        for _ in 0..95 {
+            series.record_bytes.observe(100.0);
+        }
+        series.count_error(ErrorClass::Schema, 2);
+        series.in_flight.store(3, Ordering::Relaxed);
+        series.set_lag(7);
+        tokio::time::advance(Duration::from_secs(60)).await;
+
+        let report = reporter.report();
+        assert_eq!(Duration::from_secs(60), report.period);
+        assert_eq!(
+            vec![SeriesStats {
+                table: "main.default.events".to_string(),
+                source: "orders".to_string(),
+                records_in: 98,
+                records_out: 90,
+                bytes: 9500,
+                errors: BTreeMap::from([(ErrorClass::Schema, 2)]),
+                in_flight: 3,
+                lag: Some(7),
+                stream_age: Some(Duration::from_secs(60)),
+            }],
+            report.series
+        );
+        let json = report.to_json();
+        assert_eq!(json!(1.5), json["series"][0]["records_out_per_sec"]);
+        assert_eq!(json!({"schema": 2}), json["series"][0]["errors"]);
+
+        // Only what happened since the last report counts
+        series.ingested.store(100, Ordering::Relaxed);
+        series.count_error(ErrorClass::Retryable, 1);
+        series.in_flight.store(0, Ordering::Relaxed);
+        tokio::time::advance(Duration::from_secs(30)).await;
+        let report = reporter.report();
+        assert_eq!(Duration::from_secs(30), report.period);
+        assert_eq!(10, report.series[0].records_out);
+        assert_eq!(0, report.series[0].records_in);
+        assert_eq!(
+            BTreeMap::from([(ErrorClass::Retryable, 1)]),
+            report.series[0].errors
+        );
+        assert_eq!(Some(Duration::from_secs(90)), report.series[0].stream_age);
+
+        // A quiet series is left out
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert!(reporter.report().series.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reporter_runs_until_stopped() {
+        let metrics = Metrics::default();
+        let series = metrics.series("main.default.events", "orders");
+        let recorder = Recorder::default();
+        let (stop_sender, stop) = watch::channel(false);
+        let task = StatsReporter::new(metrics.clone(), Duration::from_secs(10))
+            .publish_to(recorder.clone())
+            .spawn(stop);
+
+        series.ingested.store(5, Ordering::Relaxed);
+        tokio::time::sleep(Duration::from_secs(25)).await;
+        series.ingested.store(8, Ordering::Relaxed);
+        stop_sender.send(true).unwrap();
+        task.await.unwrap();
+
+        // Two on schedule, and a last one on shutdown
+        let reports = recorder.0.lock().unwrap();
+        let out: Vec<u64> = reports
+            .iter()
+            .map(|report| report.series.iter().map(|s| s.records_out).sum())
+            .collect();
+        assert_eq!(vec![5, 0, 3], out);
+        assert_eq!(Duration::from_secs(5), reports[2].period);
+    }
+}
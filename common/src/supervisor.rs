@@ -0,0 +1,265 @@
+use anyhow::Result;
+use databricks_zerobus_ingest_sdk::{
+    StreamConfigurationOptions, TableProperties, ZerobusSdk, ZerobusStream,
+};
+use prost_types::DescriptorProto;
+use std::fmt;
+use std::future::Future;
+use tracing::{info, warn};
+
+use crate::descriptor::DescriptorSource;
+use crate::pipeline::{AckFuture, IngestSink};
+
+/// Creates streams for one table
+///
+/// Implemented over `ZerobusSdk`; tests substitute a factory handing out in-memory sinks.
+pub trait StreamFactory: Send + Sync {
+    type Sink: IngestSink;
+
+    fn create(
+        &self,
+        descriptor: DescriptorProto,
+    ) -> impl Future<Output = Result<Self::Sink>> + Send;
+}
+
+/// Creates Zerobus streams with fixed credentials and options
+pub struct SdkStreamFactory<'a> {
+    pub sdk: &'a ZerobusSdk,
+    pub table_name: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub options: Option<StreamConfigurationOptions>,
+}
+
+impl StreamFactory for SdkStreamFactory<'_> {
+    type Sink = ZerobusStream;
+
+    async fn create(&self, descriptor: DescriptorProto) -> Result<ZerobusStream> {
+        let table_properties = TableProperties {
+            table_name: self.table_name.clone(),
+            descriptor_proto: descriptor,
+        };
+        let stream = self
+            .sdk
+            .create_stream(
+                table_properties,
+                self.client_id.clone(),
+                self.client_secret.clone(),
+                self.options.clone(),
+            )
+            .await?;
+        Ok(stream)
+    }
+}
+
+/// Marker error for a record rejected because the table schema changed
+///
+/// Sinks that can tell a schema change apart from other failures may return this
+/// directly; errors from the SDK are recognized by their message instead.
+#[derive(Debug)]
+pub struct SchemaChanged(pub String);
+
+impl fmt::Display for SchemaChanged {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "table schema changed: {}", self.0)
+    }
+}
+
+impl std::error::Error for SchemaChanged {}
+
+// The SDK reports schema mismatches as stream errors carrying the server's message
+// rather than a dedicated variant, so they are matched on that message.
+const SCHEMA_CHANGE_MARKERS: &[&str] = &[
+    "schema mismatch",
+    "schema has changed",
+    "schema changed",
+    "does not match the table schema",
+    "invalid descriptor",
+];
+
+/// Whether an ingest error means the table schema no longer matches the stream's descriptor
+pub fn is_schema_change(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        if cause.is::<SchemaChanged>() {
+            return true;
+        }
+        let message = cause.to_string().to_ascii_lowercase();
+        SCHEMA_CHANGE_MARKERS
+            .iter()
+            .any(|marker| message.contains(marker))
+    })
+}
+
+/// Keeps a long-running stream alive across table schema changes
+///
+/// When a record is rejected because the schema changed, the supervisor fetches the
+/// current descriptor, recreates the stream with it, and retries the record once on the
+/// new stream. It is itself an [`IngestSink`], so it can be dropped into a
+/// [`Pipeline`](crate::pipeline::Pipeline) in place of a bare stream.
+pub struct StreamSupervisor<F: StreamFactory, D: DescriptorSource> {
+    factory: F,
+    descriptors: D,
+    sink: F::Sink,
+    recreations: u64,
+}
+
+impl<F: StreamFactory, D: DescriptorSource> StreamSupervisor<F, D> {
+    /// Fetch the descriptor and open the first stream
+    pub async fn start(factory: F, descriptors: D) -> Result<Self> {
+        let descriptor = descriptors.fetch().await?;
+        let sink = factory.create(descriptor).await?;
+        Ok(Self {
+            factory,
+            descriptors,
+            sink,
+            recreations: 0,
+        })
+    }
+
+    /// Number of times the stream was recreated after a schema change
+    pub fn recreations(&self) -> u64 {
+        self.recreations
+    }
+
+    /// Re-fetch the descriptor and replace the current stream
+    pub async fn recreate(&mut self) -> Result<()> {
+        let descriptor = self.descriptors.fetch().await?;
+        let sink = self.factory.create(descriptor).await?;
+        let mut old = std::mem::replace(&mut self.sink, sink);
+        // The old stream is already failing; closing it is best-effort
+        if let Err(e) = old.close().await {
+            warn!("Failed to close stream after schema change: {:#}", e);
+        }
+        self.recreations += 1;
+        info!("Recreated stream with refreshed descriptor");
+        Ok(())
+    }
+
+    /// Recreate the stream if `error` is a schema change; returns whether it did
+    ///
+    /// Long-running consumers call this with errors from ack futures, which resolve
+    /// after [`IngestSink::ingest`] has returned.
+    pub async fn recover(&mut self, error: &anyhow::Error) -> Result<bool> {
+        if !is_schema_change(error) {
+            return Ok(false);
+        }
+        warn!("Table schema changed, recreating stream: {:#}", error);
+        self.recreate().await?;
+        Ok(true)
+    }
+}
+
+impl<F: StreamFactory, D: DescriptorSource> IngestSink for StreamSupervisor<F, D> {
+    async fn ingest(&mut self, record: Vec<u8>) -> Result<AckFuture> {
+        match self.sink.ingest(record.clone()).await {
+            Err(e) if is_schema_change(&e) => {
+                warn!("Table schema changed, recreating stream: {:#}", e);
+                self.recreate().await?;
+                self.sink.ingest(record).await
+            }
+            result => result,
+        }
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        self.sink.flush().await
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        self.sink.close().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockSink;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    /// Hands out the given sinks in order
+    struct MockFactory {
+        sinks: Mutex<Vec<MockSink>>,
+        created: AtomicUsize,
+    }
+
+    impl StreamFactory for MockFactory {
+        type Sink = MockSink;
+
+        async fn create(&self, _descriptor: DescriptorProto) -> Result<MockSink> {
+            self.created.fetch_add(1, Ordering::SeqCst);
+            Ok(self.sinks.lock().unwrap().remove(0))
+        }
+    }
+
+    #[derive(Default)]
+    struct CountingDescriptors {
+        fetched: Arc<AtomicUsize>,
+    }
+
+    impl DescriptorSource for CountingDescriptors {
+        async fn fetch(&self) -> Result<DescriptorProto> {
+            let n = self.fetched.fetch_add(1, Ordering::SeqCst);
+            Ok(DescriptorProto {
+                name: Some(format!("v{}", n + 1)),
+                ..Default::default()
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_schema_change_refetches_descriptor_and_recreates_stream() {
+        let stale = MockSink::default()
+            .fail_ingests_with(|_| Some(anyhow::Error::new(SchemaChanged("column added".into()))));
+        let fresh = MockSink::default();
+        let factory = MockFactory {
+            sinks: Mutex::new(vec![stale.clone(), fresh.clone()]),
+            created: AtomicUsize::new(0),
+        };
+        let descriptors = CountingDescriptors::default();
+        let fetched = descriptors.fetched.clone();
+
+        let mut supervisor = StreamSupervisor::start(factory, descriptors).await.unwrap();
+        let ack = supervisor.ingest(vec![1, 2, 3]).await.unwrap();
+        ack.await.unwrap();
+
+        assert_eq!(2, fetched.load(Ordering::SeqCst));
+        assert_eq!(2, supervisor.factory.created.load(Ordering::SeqCst));
+        assert_eq!(1, supervisor.recreations());
+        assert!(stale.closed());
+        assert_eq!(vec![vec![1, 2, 3]], fresh.records());
+    }
+
+    #[tokio::test]
+    async fn test_other_errors_do_not_recreate_stream() {
+        let failing =
+            MockSink::default().fail_ingests_with(|_| Some(anyhow::anyhow!("connection reset")));
+        let factory = MockFactory {
+            sinks: Mutex::new(vec![failing]),
+            created: AtomicUsize::new(0),
+        };
+
+        let mut supervisor = StreamSupervisor::start(factory, CountingDescriptors::default())
+            .await
+            .unwrap();
+
+        assert!(supervisor.ingest(vec![1]).await.is_err());
+        assert_eq!(0, supervisor.recreations());
+    }
+
+    #[test]
+    fn test_is_schema_change() {
+        assert!(is_schema_change(&anyhow::Error::new(SchemaChanged(
+            "x".into()
+        ))));
+        assert!(is_schema_change(&anyhow::anyhow!(
+            "Stream closed: Invalid argument: record does not match the table schema"
+        )));
+        assert!(is_schema_change(
+            &anyhow::Error::new(SchemaChanged("x".into())).context("Failed to ingest")
+        ));
+        assert!(!is_schema_change(&anyhow::anyhow!(
+            "connection reset by peer"
+        )));
+    }
+}
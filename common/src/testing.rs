@@ -0,0 +1,273 @@
+//! In-memory sink used by unit tests, here and (with the `test-util` feature) in the examples,
+//! a hex dump for snapshots of encoded rows, in [`chaos`], a sink injecting faults, in
+//! [`handler`], a harness running Lambda handlers against in-memory streams, and in
+//! [`conformance`], checks of the dynamic encoder against generated rows.
+
+pub mod chaos;
+pub mod conformance;
+pub mod handler;
+
+use crate::pipeline::{AckFuture, IngestSink};
+use crate::transaction::TransactionalSink;
+use anyhow::{anyhow, Result};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+
+type AckPredicate = Arc<dyn Fn(&[u8]) -> bool + Send + Sync>;
+type IngestFailure = Arc<dyn Fn(&[u8]) -> Option<anyhow::Error> + Send + Sync>;
+
+/// Transaction boundary seen by a [`MockSink`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransactionEvent {
+    Begin,
+    Commit,
+    Abort,
+}
+
+#[derive(Default)]
+struct MockSinkState {
+    records: Vec<Vec<u8>>,
+    /// Records of the open transaction, not yet visible in `records`
+    staged: Option<Vec<Vec<u8>>>,
+    transactions: Vec<TransactionEvent>,
+    /// Records whose ack has not resolved yet, by send order
+    unacked: BTreeMap<u64, Vec<u8>>,
+    sent: u64,
+    /// Records sent before each flush
+    flush_offsets: Vec<u64>,
+    closed: bool,
+}
+
+/// Records everything sent to it; clones share state so tests can inspect a sink
+/// after handing it to the code under test
+#[derive(Clone, Default)]
+pub struct MockSink {
+    state: Arc<Mutex<MockSinkState>>,
+    fail_ack: Option<AckPredicate>,
+    stall_ack: Option<AckPredicate>,
+    fail_ingest: Option<IngestFailure>,
+    fail_close: bool,
+}
+
+impl MockSink {
+    /// Fail the acknowledgment of every record matching `predicate`
+    pub fn fail_acks_for(
+        mut self,
+        predicate: impl Fn(&[u8]) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.fail_ack = Some(Arc::new(predicate));
+        self
+    }
+
+    /// Never resolve the acknowledgment of any record matching `predicate`
+    pub fn stall_acks_for(
+        mut self,
+        predicate: impl Fn(&[u8]) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.stall_ack = Some(Arc::new(predicate));
+        self
+    }
+
+    /// Reject sending every record for which `failure` returns an error
+    pub fn fail_ingests_with(
+        mut self,
+        failure: impl Fn(&[u8]) -> Option<anyhow::Error> + Send + Sync + 'static,
+    ) -> Self {
+        self.fail_ingest = Some(Arc::new(failure));
+        self
+    }
+
+    /// Fail every close, as a stream does when acknowledgments are still missing at the
+    /// end
+    pub fn fail_close(mut self) -> Self {
+        self.fail_close = true;
+        self
+    }
+
+    /// Visible records; records of an open or aborted transaction are not included
+    pub fn records(&self) -> Vec<Vec<u8>> {
+        self.state.lock().unwrap().records.clone()
+    }
+
+    pub fn flushes(&self) -> usize {
+        self.state.lock().unwrap().flush_offsets.len()
+    }
+
+    /// How many records had been sent when each flush happened, in flush order
+    pub fn flush_offsets(&self) -> Vec<u64> {
+        self.state.lock().unwrap().flush_offsets.clone()
+    }
+
+    pub fn closed(&self) -> bool {
+        self.state.lock().unwrap().closed
+    }
+
+    pub fn transactions(&self) -> Vec<TransactionEvent> {
+        self.state.lock().unwrap().transactions.clone()
+    }
+
+    /// A sink failing like this one, with records of its own
+    fn detached(&self) -> Self {
+        Self {
+            state: Arc::default(),
+            ..self.clone()
+        }
+    }
+
+    fn unacked(&self) -> Vec<Vec<u8>> {
+        self.state
+            .lock()
+            .unwrap()
+            .unacked
+            .values()
+            .cloned()
+            .collect()
+    }
+}
+
+impl IngestSink for MockSink {
+    async fn ingest(&mut self, record: Vec<u8>) -> Result<AckFuture> {
+        if let Some(error) = self
+            .fail_ingest
+            .as_ref()
+            .and_then(|failure| failure(&record))
+        {
+            return Err(error);
+        }
+        let fail = self
+            .fail_ack
+            .as_ref()
+            .is_some_and(|predicate| predicate(&record));
+        let stall = self
+            .stall_ack
+            .as_ref()
+            .is_some_and(|predicate| predicate(&record));
+        let mut state = self.state.lock().unwrap();
+        let id = state.sent;
+        state.sent += 1;
+        state.unacked.insert(id, record.clone());
+        match state.staged.as_mut() {
+            Some(staged) => staged.push(record),
+            None => state.records.push(record),
+        }
+        drop(state);
+        let state = Arc::clone(&self.state);
+        Ok(Box::pin(async move {
+            if stall {
+                std::future::pending::<()>().await;
+            }
+            state.lock().unwrap().unacked.remove(&id);
+            if fail {
+                Err(anyhow!("mock ack failure"))
+            } else {
+                Ok(())
+            }
+        }))
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let sent = state.sent;
+        state.flush_offsets.push(sent);
+        Ok(())
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        if self.fail_close {
+            return Err(anyhow!("mock close failure"));
+        }
+        self.state.lock().unwrap().closed = true;
+        Ok(())
+    }
+}
+
+impl TransactionalSink for MockSink {
+    async fn begin(&mut self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.staged.is_some() {
+            return Err(anyhow!("transaction already open"));
+        }
+        state.staged = Some(Vec::new());
+        state.transactions.push(TransactionEvent::Begin);
+        Ok(())
+    }
+
+    async fn commit(&mut self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let staged = state
+            .staged
+            .take()
+            .ok_or_else(|| anyhow!("no open transaction"))?;
+        state.records.extend(staged);
+        state.transactions.push(TransactionEvent::Commit);
+        Ok(())
+    }
+
+    async fn abort(&mut self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state
+            .staged
+            .take()
+            .ok_or_else(|| anyhow!("no open transaction"))?;
+        state.transactions.push(TransactionEvent::Abort);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "shutdown")]
+impl crate::shutdown::UnackedSink for MockSink {
+    async fn unacked_records(&mut self) -> Result<Vec<Vec<u8>>> {
+        Ok(self.unacked())
+    }
+}
+
+/// `hexdump -C` style lines of `bytes`: the offset, 16 bytes in hex, and their printable
+/// ASCII, so a snapshot diff points at the bytes that changed
+pub fn hex_dump(bytes: &[u8]) -> String {
+    let mut dump = String::new();
+    for (line, chunk) in bytes.chunks(16).enumerate() {
+        let _ = write!(dump, "{:08x} ", line * 16);
+        for index in 0..16 {
+            if index == 8 {
+                dump.push(' ');
+            }
+            match chunk.get(index) {
+                Some(byte) => {
+                    let _ = write!(dump, " {:02x}", byte);
+                }
+                None => dump.push_str("   "),
+            }
+        }
+        let ascii: String = chunk
+            .iter()
+            .map(|&byte| {
+                if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        let _ = writeln!(dump, "  |{}|", ascii);
+    }
+    let _ = writeln!(dump, "{:08x}", bytes.len());
+    dump
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hex_dump_matches_hexdump_c() {
+        let dump = hex_dump(b"\x0a\x0fHello, Zerobus!\x10\x80");
+        assert_eq!(
+            "00000000  0a 0f 48 65 6c 6c 6f 2c  20 5a 65 72 6f 62 75 73  |..Hello, Zerobus|\n\
+             00000010  21 10 80                                          |!..|\n\
+             00000013\n",
+            dump
+        );
+        assert_eq!("00000000\n", hex_dump(&[]));
+    }
+}
@@ -0,0 +1,417 @@
+//! A sink injecting faults into another, for resilience tests driven by JSON scenarios
+//!
+//! A scenario names how many records are sent, the faults injected while they are, and
+//! what each harness should observe, under the harness's name:
+//!
+//! ```json
+//! {
+//!   "name": "lost_acks_time_out",
+//!   "description": "Every third ack is lost and times out after 30s",
+//!   "records": 6,
+//!   "faults": {"stall_every": 3, "ack_timeout_ms": 30000},
+//!   "expect": {
+//!     "pipeline": {"grace_ms": 40000, "acked": 4, "failed": [2, 5], "unacked": []},
+//!     "sqs": {"receive_count": 3, "deadline_ms": 65000, "failed": [], "quarantined": [2, 5]}
+//!   }
+//! }
+//! ```
+//!
+//! Every fault is optional:
+//!
+//! - `fail_ingests` - `{"after": A, "count": N}`: after A sends, the next N fail with a
+//!   retryable error
+//! - `ack_delay_ms` - `{"max": D, "seed": S}`: each ack is held back up to D
+//!   milliseconds, drawn evenly from a generator seeded with S
+//! - `stall_every` - K: the ack of every Kth accepted record never resolves
+//! - `ack_timeout_ms` - T: stalled acks fail as timed out after T milliseconds instead,
+//!   as the server's ack timeout makes them
+//! - `die_after` - M: once M records were accepted, every send fails, as on a stream
+//!   closed for good
+//!
+//! Records whose ack has not resolved are reported as unacknowledged, and closing fails
+//! while there are any. The scenarios live in `common/testdata/chaos/`, one per file, so
+//! a new case needs no code.
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::pipeline::{AckFuture, IngestSink};
+use crate::rng::{Rng, SeededRng};
+
+/// Faults a [`ChaosSink`] injects, counted in the order records are sent
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Faults {
+    /// Sends before the failing ones
+    pub fail_after: u64,
+    /// Sends failing with a retryable error
+    pub fail_count: u64,
+    /// Longest ack delay, and the seed delays are drawn with
+    pub ack_delay: Option<(Duration, u64)>,
+    pub stall_every: Option<u64>,
+    pub ack_timeout: Option<Duration>,
+    pub die_after: Option<u64>,
+}
+
+impl Faults {
+    fn parse(value: &Value) -> Result<Self> {
+        let Value::Object(object) = value else {
+            bail!("faults must be a JSON object, got {}", value);
+        };
+        let mut faults = Faults::default();
+        for (name, value) in object {
+            match name.as_str() {
+                "fail_ingests" => {
+                    faults.fail_after = number(value, "after")?.unwrap_or(0);
+                    faults.fail_count = number(value, "count")?
+                        .ok_or_else(|| anyhow!("fail_ingests needs a count"))?;
+                }
+                "ack_delay_ms" => {
+                    let max =
+                        number(value, "max")?.ok_or_else(|| anyhow!("ack_delay_ms needs a max"))?;
+                    let seed = number(value, "seed")?.unwrap_or(0);
+                    faults.ack_delay = Some((Duration::from_millis(max), seed));
+                }
+                "stall_every" => faults.stall_every = Some(count(value, name)?),
+                "ack_timeout_ms" => {
+                    faults.ack_timeout = Some(Duration::from_millis(count(value, name)?))
+                }
+                "die_after" => faults.die_after = Some(count(value, name)?),
+                other => bail!("Unknown fault {:?}", other),
+            }
+        }
+        Ok(faults)
+    }
+}
+
+/// A fault script with the outcomes each harness should observe under it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scenario {
+    pub name: String,
+    pub description: String,
+    /// Records sent, in order; harnesses refer to them by their index
+    pub records: usize,
+    pub faults: Faults,
+    /// What each harness should observe, by harness name
+    pub expect: Map<String, Value>,
+}
+
+impl Scenario {
+    pub fn parse(text: &str) -> Result<Self> {
+        let Value::Object(mut object) = serde_json::from_str(text).context("Malformed JSON")?
+        else {
+            bail!("A scenario must be a JSON object");
+        };
+        let mut string = |name: &str| match object.remove(name) {
+            Some(Value::String(value)) => Ok(value),
+            None => Ok(String::new()),
+            Some(other) => bail!("{} must be a string, got {}", name, other),
+        };
+        let name = string("name")?;
+        let description = string("description")?;
+        let records = match object.remove("records") {
+            Some(value) => count(&value, "records")? as usize,
+            None => bail!("A scenario needs a number of records"),
+        };
+        let faults = match object.remove("faults") {
+            Some(value) => Faults::parse(&value)?,
+            None => Faults::default(),
+        };
+        let expect = match object.remove("expect") {
+            Some(Value::Object(expect)) => expect,
+            Some(other) => bail!("expect must be an object of harness names, got {}", other),
+            None => Map::new(),
+        };
+        if name.is_empty() {
+            bail!("A scenario needs a name");
+        }
+        Ok(Self {
+            name,
+            description,
+            records,
+            faults,
+            expect,
+        })
+    }
+
+    /// Every `*.json` scenario in `dir`, in file name order
+    pub fn load_dir(dir: &Path) -> Result<Vec<Self>> {
+        let mut paths: Vec<_> = std::fs::read_dir(dir)
+            .with_context(|| format!("Failed to list {}", dir.display()))?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<std::io::Result<_>>()?;
+        paths.retain(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "json")
+        });
+        paths.sort();
+        paths
+            .iter()
+            .map(|path| {
+                let text = std::fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read {}", path.display()))?;
+                Self::parse(&text).with_context(|| format!("Invalid scenario {}", path.display()))
+            })
+            .collect()
+    }
+
+    /// What `harness` should observe, when the scenario runs under it
+    pub fn expect(&self, harness: &str) -> Option<&Map<String, Value>> {
+        self.expect.get(harness).and_then(Value::as_object)
+    }
+}
+
+/// The field `name` of `value` as a non-negative integer, if it has one
+fn number(value: &Value, name: &str) -> Result<Option<u64>> {
+    value.get(name).map(|value| count(value, name)).transpose()
+}
+
+fn count(value: &Value, name: &str) -> Result<u64> {
+    value
+        .as_u64()
+        .ok_or_else(|| anyhow!("{} must be a non-negative integer, got {}", name, value))
+}
+
+#[derive(Default)]
+struct ChaosState {
+    /// Calls to `ingest`, failed or not
+    attempts: u64,
+    /// Records passed on to the inner sink
+    accepted: u64,
+    /// Generator ack delays are drawn from, so they only depend on the seed
+    draws: SeededRng,
+    /// Accepted records whose ack future has not resolved, by position, with when their
+    /// ack arrives; `None` when it never does
+    outstanding: BTreeMap<u64, (Vec<u8>, Option<Instant>)>,
+}
+
+impl ChaosState {
+    /// Records whose ack has not arrived by `now`
+    fn unacked(&self, now: Instant) -> Vec<Vec<u8>> {
+        self.outstanding
+            .values()
+            .filter(|(_, arrives_at)| !arrives_at.is_some_and(|arrives_at| arrives_at <= now))
+            .map(|(record, _)| record.clone())
+            .collect()
+    }
+}
+
+/// Passes records on to `S`, injecting the faults of a scenario on the way
+///
+/// Delays are measured with tokio's clock, so tests run them instantly with
+/// `start_paused`.
+pub struct ChaosSink<S> {
+    inner: S,
+    faults: Faults,
+    state: Arc<Mutex<ChaosState>>,
+}
+
+impl<S: IngestSink> ChaosSink<S> {
+    pub fn new(inner: S, faults: Faults) -> Self {
+        let draws = SeededRng::new(faults.ack_delay.map_or(0, |(_, seed)| seed));
+        Self {
+            inner,
+            faults,
+            state: Arc::new(Mutex::new(ChaosState {
+                draws,
+                ..Default::default()
+            })),
+        }
+    }
+
+    /// Records whose ack has not arrived yet: delayed ones, and stalled ones that have
+    /// not timed out
+    pub fn unacked(&self) -> Vec<Vec<u8>> {
+        self.state.lock().unwrap().unacked(Instant::now())
+    }
+}
+
+impl<S: IngestSink> IngestSink for ChaosSink<S> {
+    async fn ingest(&mut self, record: Vec<u8>) -> Result<AckFuture> {
+        let (position, arrives_at, stalled) = {
+            let mut state = self.state.lock().unwrap();
+            let attempt = state.attempts;
+            state.attempts += 1;
+            if self
+                .faults
+                .die_after
+                .is_some_and(|die_after| state.accepted >= die_after)
+            {
+                bail!("chaos: the stream is closed for good");
+            }
+            if (self.faults.fail_after..self.faults.fail_after + self.faults.fail_count)
+                .contains(&attempt)
+            {
+                bail!("chaos: injected failure, the server is unavailable");
+            }
+            state.accepted += 1;
+            let position = state.accepted;
+            let stalled = self
+                .faults
+                .stall_every
+                .is_some_and(|every| every > 0 && position % every == 0);
+            let delay = match self.faults.ack_delay {
+                Some((max, _)) => max.mul_f64(state.draws.next_f64()),
+                None => Duration::ZERO,
+            };
+            let now = Instant::now();
+            let arrives_at = match (stalled, self.faults.ack_timeout) {
+                (false, _) => Some(now + delay),
+                (true, Some(timeout)) => Some(now + timeout),
+                (true, None) => None,
+            };
+            state
+                .outstanding
+                .insert(position, (record.clone(), arrives_at));
+            (position, arrives_at, stalled)
+        };
+
+        let ack_future = match self.inner.ingest(record).await {
+            Ok(ack_future) => ack_future,
+            Err(e) => {
+                self.state.lock().unwrap().outstanding.remove(&position);
+                return Err(e);
+            }
+        };
+        let state = Arc::clone(&self.state);
+        Ok(Box::pin(async move {
+            match arrives_at {
+                Some(arrives_at) => tokio::time::sleep_until(arrives_at).await,
+                None => std::future::pending::<()>().await,
+            }
+            state.lock().unwrap().outstanding.remove(&position);
+            if stalled {
+                bail!("chaos: acknowledgment timed out");
+            }
+            ack_future.await
+        }))
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        self.inner.flush().await
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        let unacked = self.unacked();
+        if !unacked.is_empty() {
+            bail!(
+                "chaos: {} records were unacknowledged at close",
+                unacked.len()
+            );
+        }
+        self.inner.close().await
+    }
+}
+
+#[cfg(feature = "shutdown")]
+impl<S: IngestSink> crate::shutdown::UnackedSink for ChaosSink<S> {
+    async fn unacked_records(&mut self) -> Result<Vec<Vec<u8>>> {
+        Ok(self.unacked())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::{classify, ErrorClass};
+    use crate::testing::MockSink;
+
+    #[test]
+    fn test_scenarios_parse() {
+        let scenario = Scenario::parse(
+            r#"{"name": "mixed", "records": 4,
+                "faults": {"fail_ingests": {"after": 1, "count": 2}, "ack_delay_ms": {"max": 50, "seed": 7},
+                           "stall_every": 3, "ack_timeout_ms": 1000, "die_after": 9},
+                "expect": {"pipeline": {"acked": 2}}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            Faults {
+                fail_after: 1,
+                fail_count: 2,
+                ack_delay: Some((Duration::from_millis(50), 7)),
+                stall_every: Some(3),
+                ack_timeout: Some(Duration::from_secs(1)),
+                die_after: Some(9),
+            },
+            scenario.faults
+        );
+        assert_eq!(
+            Some(&Value::from(2)),
+            scenario.expect("pipeline").unwrap().get("acked")
+        );
+        assert!(scenario.expect("sqs").is_none());
+
+        assert!(Scenario::parse(r#"{"name": "no records"}"#).is_err());
+        assert!(
+            Scenario::parse(r#"{"name": "typo", "records": 1, "faults": {"stall": 2}}"#).is_err()
+        );
+        assert!(Scenario::parse(r#"{"name": "negative", "records": -1}"#).is_err());
+
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/chaos");
+        let scenarios = Scenario::load_dir(&dir).unwrap();
+        assert!(scenarios.len() >= 5, "{} scenarios", scenarios.len());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_faults_are_injected_in_send_order() {
+        let inner = MockSink::default();
+        let faults = Faults {
+            fail_after: 1,
+            fail_count: 1,
+            stall_every: Some(2),
+            die_after: Some(3),
+            ..Default::default()
+        };
+        let mut sink = ChaosSink::new(inner.clone(), faults);
+
+        let first = sink.ingest(vec![0]).await.unwrap();
+        let failed = sink.ingest(vec![1]).await.err().unwrap();
+        assert_eq!(ErrorClass::Retryable, classify(&failed));
+        let stalled = sink.ingest(vec![2]).await.unwrap();
+        let third = sink.ingest(vec![3]).await.unwrap();
+        assert!(sink.ingest(vec![4]).await.is_err());
+        assert_eq!(vec![vec![0], vec![2], vec![3]], inner.records());
+
+        first.await.unwrap();
+        third.await.unwrap();
+        let stalled = tokio::time::timeout(Duration::from_secs(3600), stalled).await;
+        assert!(stalled.is_err());
+        assert_eq!(vec![vec![2]], sink.unacked());
+        assert!(sink.close().await.is_err());
+        assert!(!inner.closed());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stalled_acks_time_out_and_delays_are_seeded() {
+        let faults = Faults {
+            ack_delay: Some((Duration::from_millis(100), 7)),
+            stall_every: Some(2),
+            ack_timeout: Some(Duration::from_secs(30)),
+            ..Default::default()
+        };
+        let mut sink = ChaosSink::new(MockSink::default(), faults.clone());
+
+        let started = Instant::now();
+        let delayed = sink.ingest(vec![0]).await.unwrap();
+        let stalled = sink.ingest(vec![1]).await.unwrap();
+        delayed.await.unwrap();
+        let delay = started.elapsed();
+        assert!(delay <= Duration::from_millis(100), "{:?}", delay);
+        let timed_out = stalled.await.unwrap_err();
+        assert_eq!(ErrorClass::AckTimeout, classify(&timed_out));
+        assert_eq!(Duration::from_secs(30), started.elapsed());
+        assert!(sink.unacked().is_empty());
+        sink.close().await.unwrap();
+
+        // The same seed delays the same record by the same time
+        let mut again = ChaosSink::new(MockSink::default(), faults);
+        let started = Instant::now();
+        again.ingest(vec![0]).await.unwrap().await.unwrap();
+        assert_eq!(delay, started.elapsed());
+    }
+}
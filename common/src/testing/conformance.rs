@@ -0,0 +1,269 @@
+//! Checking that the [`DynamicEncoder`] writes what prost writes for the generated rows.
+//!
+//! Each example with generated row types passes instances of them, and the JSON a
+//! generic tool would be handed for each, to [`assert_conforms`]. The instances are the
+//! reference: the dynamic record must decode back to the instance, and must be
+//! byte-identical to prost's encoding of it.
+//!
+//! Byte identity has one exception. prost writes a map field generated as a `HashMap` in
+//! the map's iteration order, which changes from one run to the next, while the dynamic
+//! encoder always sorts entries by key. Such a record is wire-legal either way, so the
+//! entries of each top-level map field are compared in any order; every other field
+//! must still be written in the same order with the same bytes.
+
+use crate::dynamic::DynamicEncoder;
+use crate::testing::hex_dump;
+use anyhow::{bail, Result};
+use prost::encoding::{decode_key, decode_varint, WireType};
+use prost::Message;
+use prost_types::field_descriptor_proto::{Label, Type};
+use prost_types::DescriptorProto;
+use serde_json::Value;
+use std::collections::BTreeSet;
+use std::fmt::Debug;
+
+/// Text for the string columns of the Unicode instances: accented Latin, CJK, Arabic
+/// written right to left, a four-byte emoji joined with another, a combining accent, and
+/// a NUL
+pub const UNICODE: &str = "h\u{e9}llo w\u{f6}rld \u{4f60}\u{597d} \u{645}\u{631}\u{62d}\u{628}\u{627} \u{1f980}\u{200d}\u{1f4bb} e\u{301} \u{0}";
+
+/// Encode `value` with a [`DynamicEncoder`] for `descriptor` and check it against
+/// `message`, the generated row `value` stands for
+///
+/// Returns the dynamic record, for a snapshot. `case` names the instance in failures.
+pub fn assert_conforms<M>(
+    descriptor: &DescriptorProto,
+    case: &str,
+    message: &M,
+    value: &Value,
+) -> Vec<u8>
+where
+    M: Message + Default + PartialEq + Debug,
+{
+    let encoder = DynamicEncoder::new(descriptor)
+        .unwrap_or_else(|e| panic!("{}: descriptor not supported: {:#}", case, e));
+    let actual = encoder
+        .encode(value)
+        .unwrap_or_else(|e| panic!("{}: failed to encode {}: {:#}", case, value, e));
+    let expected = message.encode_to_vec();
+
+    if actual != expected {
+        let maps = map_fields(descriptor);
+        let same = match (canonical(&actual, &maps), canonical(&expected, &maps)) {
+            (Ok(actual), Ok(expected)) => actual == expected,
+            _ => false,
+        };
+        assert!(
+            same,
+            "{}: the dynamic record differs from prost's\ndynamic:\n{}\nprost:\n{}",
+            case,
+            hex_dump(&actual),
+            hex_dump(&expected)
+        );
+    }
+
+    let decoded = M::decode(actual.as_slice())
+        .unwrap_or_else(|e| panic!("{}: prost cannot decode the dynamic record: {}", case, e));
+    assert_eq!(
+        message, &decoded,
+        "{}: the dynamic record decodes to another row",
+        case
+    );
+    if let Err(e) = encoder.validate(&actual) {
+        panic!("{}: the dynamic record does not validate: {:#}", case, e);
+    }
+    actual
+}
+
+/// Numbers of the map fields declared directly in `descriptor`
+fn map_fields(descriptor: &DescriptorProto) -> BTreeSet<i32> {
+    descriptor
+        .field
+        .iter()
+        .filter(|field| field.label() == Label::Repeated && field.r#type() == Type::Message)
+        .filter(|field| {
+            let entry = field.type_name().rsplit('.').next().unwrap_or_default();
+            descriptor.nested_type.iter().any(|nested| {
+                nested.name() == entry
+                    && nested
+                        .options
+                        .as_ref()
+                        .is_some_and(|options| options.map_entry())
+            })
+        })
+        .filter_map(|field| field.number)
+        .collect()
+}
+
+/// `record` with the entries of each run of a map field in `maps` sorted by their bytes
+fn canonical(record: &[u8], maps: &BTreeSet<i32>) -> Result<Vec<u8>> {
+    let mut fields: Vec<(u32, &[u8])> = Vec::new();
+    let mut buf = record;
+    while !buf.is_empty() {
+        let start = record.len() - buf.len();
+        let (number, wire_type) = decode_key(&mut buf)?;
+        let len = match wire_type {
+            WireType::Varint => {
+                decode_varint(&mut buf)?;
+                0
+            }
+            WireType::SixtyFourBit => 8,
+            WireType::ThirtyTwoBit => 4,
+            WireType::LengthDelimited => decode_varint(&mut buf)? as usize,
+            WireType::StartGroup | WireType::EndGroup => bail!("Groups are not supported"),
+        };
+        if len > buf.len() {
+            bail!("Field {} runs past the end of the record", number);
+        }
+        buf = &buf[len..];
+        fields.push((number, &record[start..record.len() - buf.len()]));
+    }
+
+    let mut canonical = Vec::with_capacity(record.len());
+    for run in fields.chunk_by(|(a, _), (b, _)| a == b) {
+        let mut entries: Vec<&[u8]> = run.iter().map(|(_, bytes)| *bytes).collect();
+        if maps.contains(&(run[0].0 as i32)) {
+            entries.sort();
+        }
+        for bytes in entries {
+            canonical.extend_from_slice(bytes);
+        }
+    }
+    Ok(canonical)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost_types::{FieldDescriptorProto, MessageOptions};
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    /// What prost-build generates for a map column without `btree_map`
+    #[derive(Clone, PartialEq, Message)]
+    struct Labeled {
+        #[prost(string, optional, tag = "1")]
+        name: Option<String>,
+        #[prost(map = "string, string", tag = "2")]
+        labels: HashMap<String, String>,
+        #[prost(string, repeated, tag = "3")]
+        tags: Vec<String>,
+    }
+
+    fn field(name: &str, number: i32, label: Label, field_type: Type) -> FieldDescriptorProto {
+        FieldDescriptorProto {
+            name: Some(name.to_string()),
+            number: Some(number),
+            label: Some(label as i32),
+            r#type: Some(field_type as i32),
+            ..Default::default()
+        }
+    }
+
+    fn descriptor() -> DescriptorProto {
+        DescriptorProto {
+            name: Some("table_labeled".to_string()),
+            field: vec![
+                field("name", 1, Label::Optional, Type::String),
+                FieldDescriptorProto {
+                    type_name: Some(".pkg.table_labeled.LabelsEntry".to_string()),
+                    ..field("labels", 2, Label::Repeated, Type::Message)
+                },
+                field("tags", 3, Label::Repeated, Type::String),
+            ],
+            nested_type: vec![DescriptorProto {
+                name: Some("LabelsEntry".to_string()),
+                field: vec![
+                    field("key", 1, Label::Optional, Type::String),
+                    field("value", 2, Label::Optional, Type::String),
+                ],
+                options: Some(MessageOptions {
+                    map_entry: Some(true),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_hash_map_entries_conform_in_any_order() {
+        let labels: HashMap<String, String> = (0..64)
+            .map(|i| (format!("key-{}", i), i.to_string()))
+            .collect();
+        let message = Labeled {
+            name: Some("row".to_string()),
+            labels: labels.clone(),
+            tags: vec!["b".to_string(), "a".to_string()],
+        };
+        let value = json!({"name": "row", "labels": labels, "tags": ["b", "a"]});
+
+        let encoded = assert_conforms(&descriptor(), "labeled", &message, &value);
+
+        // The dynamic record sorts the entries whatever order prost wrote them in
+        let decoded = Labeled::decode(encoded.as_slice()).unwrap();
+        assert_eq!(message, decoded);
+        assert_eq!(&[0x12, 0x0a, 0x0a, 0x05], &encoded[5..9]);
+        assert_eq!(b"key-0", &encoded[9..14]);
+    }
+
+    #[test]
+    fn test_only_map_entries_may_be_reordered() {
+        let maps = map_fields(&descriptor());
+        assert_eq!(BTreeSet::from([2]), maps);
+
+        let entry = |key: &str| {
+            Labeled {
+                labels: HashMap::from([(key.to_string(), String::new())]),
+                ..Default::default()
+            }
+            .encode_to_vec()
+        };
+        let tag = |tag: &str| {
+            Labeled {
+                tags: vec![tag.to_string()],
+                ..Default::default()
+            }
+            .encode_to_vec()
+        };
+        let name = Labeled {
+            name: Some("row".to_string()),
+            ..Default::default()
+        }
+        .encode_to_vec();
+
+        let swapped_entries = [entry("a"), entry("b")].concat();
+        let entries = [entry("b"), entry("a")].concat();
+        assert_eq!(
+            canonical(&swapped_entries, &maps).unwrap(),
+            canonical(&entries, &maps).unwrap()
+        );
+
+        // Elements of a repeated field keep their order, as do fields
+        let tags = [tag("a"), tag("b")].concat();
+        let swapped_tags = [tag("b"), tag("a")].concat();
+        assert_ne!(
+            canonical(&tags, &maps).unwrap(),
+            canonical(&swapped_tags, &maps).unwrap()
+        );
+        let fields = [name.clone(), entry("a")].concat();
+        let swapped_fields = [entry("a"), name].concat();
+        assert_ne!(
+            canonical(&fields, &maps).unwrap(),
+            canonical(&swapped_fields, &maps).unwrap()
+        );
+
+        assert!(canonical(&[0x0a, 0x05, b'r'], &maps).is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "other: the dynamic record differs from prost's")]
+    fn test_record_of_another_row_does_not_conform() {
+        let message = Labeled {
+            name: Some("row".to_string()),
+            ..Default::default()
+        };
+        assert_conforms(&descriptor(), "other", &message, &json!({"name": "other"}));
+    }
+}
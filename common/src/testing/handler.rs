@@ -0,0 +1,387 @@
+//! Runs a Lambda handler against in-memory streams
+//!
+//! The handlers take their configuration as a parameter and open their streams through a
+//! [`CreateStream`], so a test builds the configuration with [`with_env`], which reads it
+//! from the given variables alone, and calls the handler through [`invoke`] with
+//! [`MockStreams`]. What the handler sent to each table comes back with its response:
+//!
+//! ```ignore
+//! let config = with_env(&[("TABLE_NAME", "main.default.sqs")], HandlerConfig::from_env)?;
+//! let streams = MockStreams::default();
+//! let invocation = invoke(&streams, |sdk| async move { handle_event(event, &sdk, &config).await }).await;
+//! assert_eq!(1, invocation.calls.records["main.default.sqs"].len());
+//! ```
+
+use anyhow::{bail, Result};
+use databricks_zerobus_ingest_sdk::{StreamConfigurationOptions, TableProperties};
+use std::collections::{BTreeMap, BTreeSet};
+use std::future::Future;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use super::MockSink;
+use crate::pipeline::CreateStream;
+
+type TablePredicate = Arc<dyn Fn(&str) -> bool + Send + Sync>;
+
+/// Held while a configuration is read, as the environment is shared by every test thread
+static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+/// Variables left in place for the configuration to read, as the toolchain and the
+/// operating system need them
+const KEPT_VARS: [&str; 3] = ["PATH", "HOME", "TMPDIR"];
+
+fn kept(name: &str) -> bool {
+    KEPT_VARS.contains(&name) || name.starts_with("CARGO") || name.starts_with("RUST")
+}
+
+/// The process environment reduced to the variables given, until dropped
+///
+/// Every other variable is removed, apart from `PATH`, `HOME`, `TMPDIR`, and the `CARGO`
+/// and `RUST` ones, so a configuration read in the scope does not depend on what the
+/// shell or an earlier test left set. Scopes are taken one at a time, across threads.
+pub struct EnvScope {
+    saved: Vec<(String, String)>,
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl EnvScope {
+    pub fn new(vars: &[(&str, &str)]) -> Self {
+        // A test that panicked in its scope still restored the environment on the way out
+        Self::locked(
+            ENV_LOCK
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner()),
+            vars,
+        )
+    }
+
+    fn locked(lock: MutexGuard<'static, ()>, vars: &[(&str, &str)]) -> Self {
+        let saved: Vec<(String, String)> = std::env::vars().collect();
+        for (name, _) in &saved {
+            if !kept(name) {
+                std::env::remove_var(name);
+            }
+        }
+        for (name, value) in vars {
+            std::env::set_var(name, value);
+        }
+        Self { saved, _lock: lock }
+    }
+}
+
+impl Drop for EnvScope {
+    fn drop(&mut self) {
+        for (name, _) in std::env::vars() {
+            std::env::remove_var(name);
+        }
+        for (name, value) in &self.saved {
+            std::env::set_var(name, value);
+        }
+    }
+}
+
+/// Build a configuration from `vars` and no other variables, with [`EnvScope`]
+pub fn with_env<T>(vars: &[(&str, &str)], build: impl FnOnce() -> T) -> T {
+    let _scope = EnvScope::new(vars);
+    build()
+}
+
+#[derive(Default)]
+struct StreamsState {
+    /// Every stream opened to each table, the recreated ones included, in opening order
+    sinks: BTreeMap<String, Vec<MockSink>>,
+    created: Vec<String>,
+    recreated: Vec<String>,
+}
+
+/// Streams to [`MockSink`]s, one per [`CreateStream::create_stream`] call; clones share
+/// the streams, so a test can inspect them after handing a clone to the handler
+#[derive(Clone, Default)]
+pub struct MockStreams {
+    state: Arc<Mutex<StreamsState>>,
+    /// Faults of every new stream, unless its table has its own
+    template: MockSink,
+    tables: BTreeMap<String, MockSink>,
+    fail_create: Option<TablePredicate>,
+}
+
+impl MockStreams {
+    /// Open every stream with the faults of `sink`, but records of its own
+    pub fn with_sink(mut self, sink: MockSink) -> Self {
+        self.template = sink;
+        self
+    }
+
+    /// Open the streams to `table` with the faults of `sink`
+    pub fn with_table_sink(mut self, table: &str, sink: MockSink) -> Self {
+        self.tables.insert(table.to_string(), sink);
+        self
+    }
+
+    /// Fail to open a stream to every table matching `predicate`
+    pub fn fail_create_for(
+        mut self,
+        predicate: impl Fn(&str) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.fail_create = Some(Arc::new(predicate));
+        self
+    }
+
+    /// What was sent to the streams so far
+    pub fn calls(&self) -> SinkCalls {
+        let state = self.state.lock().unwrap();
+        let mut calls = SinkCalls {
+            created: state.created.clone(),
+            recreated: state.recreated.clone(),
+            ..SinkCalls::default()
+        };
+        for (table, sinks) in &state.sinks {
+            calls.records.insert(
+                table.clone(),
+                sinks.iter().flat_map(MockSink::records).collect(),
+            );
+            calls
+                .flushes
+                .insert(table.clone(), sinks.iter().map(MockSink::flushes).sum());
+            if sinks.iter().any(MockSink::closed) {
+                calls.closed.insert(table.clone());
+            }
+        }
+        calls
+    }
+
+    fn open(&self, table: &str) -> MockSink {
+        let sink = self.tables.get(table).unwrap_or(&self.template).detached();
+        let mut state = self.state.lock().unwrap();
+        state
+            .sinks
+            .entry(table.to_string())
+            .or_default()
+            .push(sink.clone());
+        sink
+    }
+}
+
+impl CreateStream for MockStreams {
+    type Stream = MockSink;
+
+    async fn create_stream(
+        &self,
+        table_properties: TableProperties,
+        _client_id: String,
+        _client_secret: String,
+        _options: Option<StreamConfigurationOptions>,
+    ) -> Result<MockSink> {
+        let table = table_properties.table_name;
+        self.state.lock().unwrap().created.push(table.clone());
+        if self
+            .fail_create
+            .as_ref()
+            .is_some_and(|predicate| predicate(&table))
+        {
+            bail!("mock create failure for {}", table);
+        }
+        Ok(self.open(&table))
+    }
+
+    async fn recreate_stream(&self, stream: MockSink) -> Result<MockSink> {
+        let table = {
+            let state = self.state.lock().unwrap();
+            state
+                .sinks
+                .iter()
+                .find(|(_, sinks)| {
+                    sinks
+                        .iter()
+                        .any(|sink| Arc::ptr_eq(&sink.state, &stream.state))
+                })
+                .map(|(table, _)| table.clone())
+        };
+        let Some(table) = table else {
+            bail!("recreating a stream these streams did not open");
+        };
+        self.state.lock().unwrap().recreated.push(table.clone());
+        // Like the SDK, the new stream is sent the records the old one left unacknowledged
+        let recreated = self.open(&table);
+        recreated
+            .state
+            .lock()
+            .unwrap()
+            .records
+            .extend(stream.unacked());
+        Ok(recreated)
+    }
+
+    async fn unacked_records(&self, stream: &mut MockSink) -> Result<Vec<Vec<u8>>> {
+        Ok(stream.unacked())
+    }
+}
+
+/// What a handler sent to [`MockStreams`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SinkCalls {
+    /// Tables a stream was asked for, in order, including those that failed to open
+    pub created: Vec<String>,
+    /// Records sent to each table, across all of its streams
+    pub records: BTreeMap<String, Vec<Vec<u8>>>,
+    pub flushes: BTreeMap<String, usize>,
+    /// Tables with a stream that was closed
+    pub closed: BTreeSet<String>,
+    /// Tables whose stream was recreated after it failed
+    pub recreated: Vec<String>,
+}
+
+impl SinkCalls {
+    /// Records sent to `table`, none if no stream to it was opened
+    pub fn records_to(&self, table: &str) -> &[Vec<u8>] {
+        self.records.get(table).map_or(&[], Vec::as_slice)
+    }
+}
+
+/// A handler's response, and what it sent to the streams during the invocation
+#[derive(Debug)]
+pub struct Invocation<R> {
+    pub response: R,
+    pub calls: SinkCalls,
+}
+
+/// Run `handler` with a clone of `streams` as its SDK
+///
+/// The calls are those of this invocation only: streams `streams` opened before, as for
+/// an earlier invocation, are not included.
+pub async fn invoke<R, F, Fut>(streams: &MockStreams, handler: F) -> Invocation<R>
+where
+    F: FnOnce(MockStreams) -> Fut,
+    Fut: Future<Output = R>,
+{
+    let before = streams.calls();
+    let response = handler(streams.clone()).await;
+    let mut calls = streams.calls();
+    calls.created.drain(..before.created.len());
+    calls.recreated.drain(..before.recreated.len());
+    for (table, records) in &before.records {
+        if let Some(now) = calls.records.get_mut(table) {
+            now.drain(..records.len());
+        }
+    }
+    for (table, flushes) in &before.flushes {
+        if let Some(now) = calls.flushes.get_mut(table) {
+            *now -= flushes;
+        }
+    }
+    calls.closed.retain(|table| !before.closed.contains(table));
+    calls.records.retain(|_, records| !records.is_empty());
+    calls.flushes.retain(|_, flushes| *flushes > 0);
+    Invocation { response, calls }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::IngestSink;
+
+    fn table(name: &str) -> TableProperties {
+        TableProperties {
+            table_name: name.to_string(),
+            descriptor_proto: Default::default(),
+        }
+    }
+
+    async fn send(sdk: &MockStreams, name: &str, records: &[&[u8]]) -> Result<MockSink> {
+        let mut stream = sdk
+            .create_stream(table(name), String::new(), String::new(), None)
+            .await?;
+        for record in records {
+            let _ = stream.ingest(record.to_vec()).await?.await;
+        }
+        stream.close().await?;
+        Ok(stream)
+    }
+
+    #[tokio::test]
+    async fn test_invocation_records_only_its_own_calls() {
+        let streams = MockStreams::default().fail_create_for(|table| table.ends_with("missing"));
+        send(&streams, "main.default.a", &[b"earlier"])
+            .await
+            .unwrap();
+
+        let invocation = invoke(&streams, |sdk| async move {
+            send(&sdk, "main.default.a", &[b"one", b"two"])
+                .await
+                .unwrap();
+            send(&sdk, "main.default.missing", &[]).await.is_err()
+        })
+        .await;
+
+        assert!(invocation.response);
+        assert_eq!(
+            vec!["main.default.a", "main.default.missing"],
+            invocation.calls.created
+        );
+        assert_eq!(
+            vec![b"one".to_vec(), b"two".to_vec()],
+            invocation.calls.records_to("main.default.a")
+        );
+        assert!(invocation
+            .calls
+            .records_to("main.default.missing")
+            .is_empty());
+        assert_eq!(3, streams.calls().records_to("main.default.a").len());
+    }
+
+    #[tokio::test]
+    async fn test_recreated_stream_is_sent_the_unacked_records() {
+        let stalled = MockSink::default()
+            .stall_acks_for(|record| record == b"stalled")
+            .fail_close();
+        let streams = MockStreams::default().with_table_sink("main.default.a", stalled);
+        let mut stream = streams
+            .create_stream(table("main.default.a"), String::new(), String::new(), None)
+            .await
+            .unwrap();
+        stream
+            .ingest(b"acked".to_vec())
+            .await
+            .unwrap()
+            .await
+            .unwrap();
+        let _pending = stream.ingest(b"stalled".to_vec()).await.unwrap();
+        assert!(stream.close().await.is_err());
+
+        assert_eq!(
+            vec![b"stalled".to_vec()],
+            streams.unacked_records(&mut stream).await.unwrap()
+        );
+        streams.recreate_stream(stream).await.unwrap();
+
+        let calls = streams.calls();
+        assert_eq!(vec!["main.default.a"], calls.recreated);
+        assert!(calls.closed.is_empty());
+        let sent: Vec<&[u8]> = calls
+            .records_to("main.default.a")
+            .iter()
+            .map(Vec::as_slice)
+            .collect();
+        assert_eq!(vec![b"acked".as_slice(), b"stalled", b"stalled"], sent);
+    }
+
+    #[test]
+    fn test_env_scope_holds_only_the_given_vars() {
+        let lock = ENV_LOCK
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        std::env::set_var("TEST_HANDLER_LEFTOVER", "1");
+        let scope = EnvScope::locked(lock, &[("TEST_HANDLER_TABLE", "main.default.a")]);
+        assert_eq!(
+            Ok("main.default.a".to_string()),
+            std::env::var("TEST_HANDLER_TABLE")
+        );
+        assert!(std::env::var("TEST_HANDLER_LEFTOVER").is_err());
+        assert!(std::env::var("PATH").is_ok());
+
+        drop(scope);
+        assert_eq!(Ok("1".to_string()), std::env::var("TEST_HANDLER_LEFTOVER"));
+        assert!(std::env::var("TEST_HANDLER_TABLE").is_err());
+        std::env::remove_var("TEST_HANDLER_LEFTOVER");
+    }
+}
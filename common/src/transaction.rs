@@ -0,0 +1,158 @@
+//! All-or-nothing ingestion of a batch of records.
+//!
+//! The Zerobus SDK (0.1.x) has no transaction or commit primitives: every record is
+//! visible in the table as soon as it is acknowledged, so a batch that fails halfway
+//! leaves its acknowledged records behind. `ZerobusStream` therefore does not implement
+//! [`TransactionalSink`]. The trait describes the begin/commit/abort flow a sink would
+//! need, so that examples can adopt it without restructuring once the SDK offers it.
+
+use anyhow::{anyhow, Context, Result};
+use std::future::Future;
+use tracing::{error, warn};
+
+use crate::pipeline::IngestSink;
+
+/// A sink whose records only become visible once the enclosing transaction commits
+pub trait TransactionalSink: IngestSink {
+    /// Start a transaction; records ingested until `commit` or `abort` belong to it
+    fn begin(&mut self) -> impl Future<Output = Result<()>> + Send;
+
+    /// Make every record of the open transaction visible at once
+    fn commit(&mut self) -> impl Future<Output = Result<()>> + Send;
+
+    /// Discard every record of the open transaction
+    fn abort(&mut self) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// Ingest `records` in a single transaction
+///
+/// The transaction is committed only after every record has been acknowledged; any
+/// failure to send or acknowledge a record aborts it, so either the whole batch is
+/// visible or none of it is. Returns the number of records committed.
+pub async fn ingest_transaction<S: TransactionalSink>(
+    sink: &mut S,
+    records: Vec<Vec<u8>>,
+) -> Result<usize> {
+    sink.begin().await.context("Failed to begin transaction")?;
+
+    match send_and_acknowledge(sink, records).await {
+        Ok(count) => {
+            sink.commit()
+                .await
+                .context("Failed to commit transaction")?;
+            Ok(count)
+        }
+        Err(e) => {
+            warn!("Aborting transaction: {:#}", e);
+            if let Err(abort_error) = sink.abort().await {
+                error!("Failed to abort transaction: {:#}", abort_error);
+            }
+            Err(e)
+        }
+    }
+}
+
+async fn send_and_acknowledge<S: IngestSink>(sink: &mut S, records: Vec<Vec<u8>>) -> Result<usize> {
+    let mut acks = Vec::with_capacity(records.len());
+    for record in records {
+        acks.push(sink.ingest(record).await?);
+    }
+
+    sink.flush().await?;
+
+    let count = acks.len();
+    let mut failed = 0;
+    for ack in acks {
+        if let Err(e) = ack.await {
+            error!("Record was not acknowledged: {:#}", e);
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        return Err(anyhow!(
+            "{} of {} records were not acknowledged",
+            failed,
+            count
+        ));
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{MockSink, TransactionEvent};
+
+    fn batch() -> Vec<Vec<u8>> {
+        vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]
+    }
+
+    #[tokio::test]
+    async fn test_commit_after_all_acks() {
+        let mut sink = MockSink::default();
+
+        let committed = ingest_transaction(&mut sink, batch()).await.unwrap();
+
+        assert_eq!(3, committed);
+        assert_eq!(
+            vec![TransactionEvent::Begin, TransactionEvent::Commit],
+            sink.transactions()
+        );
+        assert_eq!(batch(), sink.records());
+        assert_eq!(1, sink.flushes());
+    }
+
+    #[tokio::test]
+    async fn test_abort_when_a_record_is_not_acknowledged() {
+        let mut sink = MockSink::default().fail_acks_for(|record| record == b"b");
+
+        let error = ingest_transaction(&mut sink, batch()).await.unwrap_err();
+
+        assert!(error.to_string().contains("1 of 3"));
+        assert_eq!(
+            vec![TransactionEvent::Begin, TransactionEvent::Abort],
+            sink.transactions()
+        );
+        // Records that were acknowledged are discarded along with the failed one
+        assert!(sink.records().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_abort_when_a_record_cannot_be_sent() {
+        let mut sink = MockSink::default()
+            .fail_ingests_with(|record| (record == b"c").then(|| anyhow!("stream closed")));
+
+        assert!(ingest_transaction(&mut sink, batch()).await.is_err());
+
+        assert_eq!(
+            vec![TransactionEvent::Begin, TransactionEvent::Abort],
+            sink.transactions()
+        );
+        assert!(sink.records().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_batches_are_independent() {
+        let mut sink = MockSink::default().fail_acks_for(|record| record == b"bad");
+
+        ingest_transaction(&mut sink, vec![b"x".to_vec()])
+            .await
+            .unwrap();
+        assert!(
+            ingest_transaction(&mut sink, vec![b"y".to_vec(), b"bad".to_vec()])
+                .await
+                .is_err()
+        );
+
+        assert_eq!(vec![b"x".to_vec()], sink.records());
+        assert_eq!(
+            vec![
+                TransactionEvent::Begin,
+                TransactionEvent::Commit,
+                TransactionEvent::Begin,
+                TransactionEvent::Abort,
+            ],
+            sink.transactions()
+        );
+    }
+}
@@ -0,0 +1,246 @@
+//! Structured reports of the records a stream still had unacknowledged when it failed.
+//!
+//! When `close` fails, the records returned by `get_unacked_records` are the ones whose
+//! fate is unknown. Recreating the stream replays them, but an operator still wants to
+//! know which records were affected and how large they were. The report lists them as a
+//! single JSON line, written to stderr or appended to `UNACKED_REPORT_PATH`.
+
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use std::fmt::Display;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+
+use crate::clock::{Clock, SystemClock};
+
+type Identify<'a> = Box<dyn Fn(&[u8]) -> Option<String> + 'a>;
+
+/// One unacknowledged record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnackedEntry {
+    /// Position in the list returned by the stream, oldest first
+    pub index: usize,
+    /// Identifier decoded from the record, e.g. the SQS message ID
+    pub id: Option<String>,
+    pub size_bytes: usize,
+}
+
+/// Unacknowledged records of one failed stream
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnackedReport {
+    pub table: String,
+    /// The error that made the stream fail
+    pub error: Option<String>,
+    /// Identifier of the batch being processed, e.g. the Lambda request ID
+    pub batch_id: Option<String>,
+    /// When the report was built, microseconds since Unix epoch
+    pub reported_at: i64,
+    pub entries: Vec<UnackedEntry>,
+}
+
+/// Builds an [`UnackedReport`] from the raw records of a stream
+pub struct UnackedReportBuilder<'a> {
+    table: String,
+    error: Option<String>,
+    batch_id: Option<String>,
+    identify: Option<Identify<'a>>,
+    /// Where `reported_at` is read from; the system clock when unset
+    clock: Option<&'a dyn Clock>,
+}
+
+impl UnackedReport {
+    pub fn builder<'a>(table: impl Into<String>) -> UnackedReportBuilder<'a> {
+        UnackedReportBuilder {
+            table: table.into(),
+            error: None,
+            batch_id: None,
+            identify: None,
+            clock: None,
+        }
+    }
+
+    pub fn total_bytes(&self) -> usize {
+        self.entries.iter().map(|entry| entry.size_bytes).sum()
+    }
+
+    pub fn to_json(&self) -> Value {
+        let records: Vec<Value> = self
+            .entries
+            .iter()
+            .map(|entry| {
+                json!({
+                    "index": entry.index,
+                    "id": entry.id,
+                    "size_bytes": entry.size_bytes,
+                })
+            })
+            .collect();
+        json!({
+            "table": self.table,
+            "error": self.error,
+            "batch_id": self.batch_id,
+            "reported_at": self.reported_at,
+            "unacked_records": self.entries.len(),
+            "unacked_bytes": self.total_bytes(),
+            "records": records,
+        })
+    }
+
+    /// Write the report as one JSON line
+    pub fn write(&self, destination: &ReportDestination) -> Result<()> {
+        let line = format!("{}\n", self.to_json());
+        match destination {
+            ReportDestination::Stderr => std::io::stderr()
+                .write_all(line.as_bytes())
+                .context("Failed to write unacked report to stderr"),
+            ReportDestination::File(path) => std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| file.write_all(line.as_bytes()))
+                .with_context(|| format!("Failed to write unacked report to {}", path.display())),
+        }
+    }
+}
+
+impl<'a> UnackedReportBuilder<'a> {
+    pub fn error(mut self, error: impl Display) -> Self {
+        self.error = Some(error.to_string());
+        self
+    }
+
+    pub fn batch_id(mut self, batch_id: impl Into<String>) -> Self {
+        self.batch_id = Some(batch_id.into());
+        self
+    }
+
+    /// Extract an identifier from each encoded record, usually by decoding it
+    pub fn identify_with(mut self, identify: impl Fn(&[u8]) -> Option<String> + 'a) -> Self {
+        self.identify = Some(Box::new(identify));
+        self
+    }
+
+    /// Read `reported_at` from `clock` instead of the system clock
+    pub fn clock(mut self, clock: &'a dyn Clock) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    pub fn build<R: AsRef<[u8]>>(self, records: impl IntoIterator<Item = R>) -> UnackedReport {
+        let entries = records
+            .into_iter()
+            .enumerate()
+            .map(|(index, record)| {
+                let record = record.as_ref();
+                UnackedEntry {
+                    index,
+                    id: self.identify.as_ref().and_then(|identify| identify(record)),
+                    size_bytes: record.len(),
+                }
+            })
+            .collect();
+        let reported_at = self
+            .clock
+            .unwrap_or(&SystemClock)
+            .now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_micros() as i64)
+            .unwrap_or_default();
+
+        UnackedReport {
+            table: self.table,
+            error: self.error,
+            batch_id: self.batch_id,
+            reported_at,
+            entries,
+        }
+    }
+}
+
+/// Where unacked reports are written
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReportDestination {
+    Stderr,
+    /// Reports are appended, one JSON line each
+    File(PathBuf),
+}
+
+impl ReportDestination {
+    /// `UNACKED_REPORT_PATH`, or stderr when it is unset, empty, or `-`
+    pub fn from_env() -> Self {
+        match std::env::var("UNACKED_REPORT_PATH") {
+            Ok(path) if !path.trim().is_empty() && path.trim() != "-" => {
+                ReportDestination::File(PathBuf::from(path.trim()))
+            }
+            _ => ReportDestination::Stderr,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::FixedClock;
+
+    #[test]
+    fn test_report_entries_and_json() {
+        let records = vec![b"id-1:payload".to_vec(), b"no identifier".to_vec()];
+        let clock = FixedClock::at_unix_secs(1_718_020_860);
+
+        let report = UnackedReport::builder("main.default.events")
+            .error("stream closed")
+            .batch_id("request-1")
+            .clock(&clock)
+            .identify_with(|record| {
+                let text = std::str::from_utf8(record).ok()?;
+                text.split_once(':').map(|(id, _)| id.to_string())
+            })
+            .build(&records);
+
+        assert_eq!(
+            vec![
+                UnackedEntry {
+                    index: 0,
+                    id: Some("id-1".to_string()),
+                    size_bytes: 12,
+                },
+                UnackedEntry {
+                    index: 1,
+                    id: None,
+                    size_bytes: 13,
+                },
+            ],
+            report.entries
+        );
+        let json = report.to_json();
+        assert_eq!(json["unacked_records"], 2);
+        assert_eq!(json["unacked_bytes"], 25);
+        assert_eq!(json["error"], "stream closed");
+        assert_eq!(json["reported_at"], 1_718_020_860_000_000i64);
+        assert_eq!(json["records"][0]["id"], "id-1");
+        assert_eq!(json["records"][1]["id"], Value::Null);
+    }
+
+    #[test]
+    fn test_reports_are_appended_to_file() {
+        let path = std::env::temp_dir().join(format!("unacked-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let destination = ReportDestination::File(path.clone());
+
+        for table in ["a", "b"] {
+            UnackedReport::builder(table)
+                .build([vec![0u8; 3]])
+                .write(&destination)
+                .unwrap();
+        }
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let tables: Vec<String> = contents
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap()["table"].to_string())
+            .collect();
+        assert_eq!(vec!["\"a\"", "\"b\""], tables);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,31 @@
+//! Build information for stamping ingested rows with the pipeline that produced them.
+
+/// Crate version and git commit the examples were built from, e.g. `0.1.0+1a2b3c4d5e6f`.
+pub const PIPELINE_VERSION: &str = concat!(env!("CARGO_PKG_VERSION"), "+", env!("ZEROBUS_GIT_SHA"));
+
+/// Whether rows should carry [`PIPELINE_VERSION`], controlled by `STAMP_VERSION=true`.
+pub fn stamp_version() -> bool {
+    std::env::var("STAMP_VERSION")
+        .map(|value| value.eq_ignore_ascii_case("true"))
+        .unwrap_or(false)
+}
+
+/// The version to write into the `pipeline_version` column of rows, if stamping is enabled.
+///
+/// Read once per invocation, with the rest of its options, so every row of it carries the
+/// same value.
+pub fn stamped_pipeline_version() -> Option<&'static str> {
+    stamp_version().then_some(PIPELINE_VERSION)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pipeline_version_format() {
+        let (version, sha) = PIPELINE_VERSION.split_once('+').unwrap();
+        assert_eq!(env!("CARGO_PKG_VERSION"), version);
+        assert!(!sha.is_empty());
+    }
+}
@@ -0,0 +1,239 @@
+//! A monotonically non-decreasing `watermark` column for incremental downstreams.
+//!
+//! Streaming tables and materialized views built on an ingested table process it
+//! incrementally, and a column that never goes backwards within a stream tells them how
+//! far the stream has got. `WATERMARK_FIELD` selects what the watermark follows: the
+//! running maximum of an event time field, so a late row carries the watermark already
+//! reached rather than its own older time, or a counter for rows without an event time.
+
+use anyhow::{bail, Context, Result};
+use serde_json::{Map, Value};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+use crate::clock::{Clock, SystemClock};
+
+/// Column the watermark is written to
+pub const WATERMARK_COLUMN: &str = "watermark";
+
+/// What a [`Watermark`] follows
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WatermarkSource {
+    /// The largest value of this top-level field seen so far, in the field's own unit
+    EventTime(String),
+    /// A counter that increases by at least one per row, starting from the current time
+    /// in microseconds since Unix epoch, so it also keeps increasing across restarts
+    Counter,
+}
+
+impl WatermarkSource {
+    /// Read `WATERMARK_FIELD`; unset or empty stamps no watermark
+    pub fn from_env() -> Result<Option<Self>> {
+        match std::env::var("WATERMARK_FIELD") {
+            Ok(value) if !value.trim().is_empty() => Self::parse(&value)
+                .map(Some)
+                .context("Invalid WATERMARK_FIELD"),
+            _ => Ok(None),
+        }
+    }
+
+    /// `counter` for a counter, otherwise the name of the event time field
+    pub fn parse(value: &str) -> Result<Self> {
+        let value = value.trim();
+        if value.is_empty() {
+            bail!("Watermark field must not be empty");
+        }
+        if value.eq_ignore_ascii_case("counter") {
+            return Ok(WatermarkSource::Counter);
+        }
+        Ok(WatermarkSource::EventTime(value.to_string()))
+    }
+}
+
+/// The watermark of one stream
+///
+/// Keep one per stream: the watermark only orders the rows stamped by the same
+/// `Watermark`.
+#[derive(Debug, Clone)]
+pub struct Watermark {
+    source: WatermarkSource,
+    current: Option<i64>,
+    /// Where a counter starts from
+    clock: Arc<dyn Clock>,
+}
+
+impl Watermark {
+    pub fn new(source: WatermarkSource) -> Self {
+        Self {
+            source,
+            current: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Start a counter from the time `clock` reads, instead of the system clock
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// The last watermark stamped, if any
+    pub fn current(&self) -> Option<i64> {
+        self.current
+    }
+
+    /// Advance the watermark for `row` and write it to the row's [`WATERMARK_COLUMN`]
+    ///
+    /// A row without an event time, or with a null one, carries the watermark already
+    /// reached, and is left without one before any event time has been seen. Fails,
+    /// leaving the watermark as it was, when the event time is not an integer.
+    pub fn stamp(&mut self, row: &mut Map<String, Value>) -> Result<Option<i64>> {
+        let next = match &self.source {
+            WatermarkSource::EventTime(field) => match row.get(field) {
+                None | Some(Value::Null) => self.current,
+                Some(value) => {
+                    let event_time = event_time(value).with_context(|| {
+                        format!("Event time field {:?} is not an integer", field)
+                    })?;
+                    Some(
+                        self.current
+                            .map_or(event_time, |current| current.max(event_time)),
+                    )
+                }
+            },
+            WatermarkSource::Counter => {
+                let now = self
+                    .clock
+                    .now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |since| since.as_micros() as i64);
+                Some(self.current.map_or(now, |current| (current + 1).max(now)))
+            }
+        };
+        if let Some(watermark) = next {
+            row.insert(WATERMARK_COLUMN.to_string(), watermark.into());
+        }
+        self.current = next;
+        Ok(next)
+    }
+}
+
+/// An integer, or a string holding one, as columns accept them
+fn event_time(value: &Value) -> Result<i64> {
+    match value {
+        Value::Number(number) => number.as_i64().context("Not an integer"),
+        Value::String(text) => text.trim().parse().context("Not an integer"),
+        _ => bail!("Not an integer"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::FixedClock;
+    use serde_json::json;
+
+    fn row(value: Value) -> Map<String, Value> {
+        match value {
+            Value::Object(row) => row,
+            _ => panic!("not an object"),
+        }
+    }
+
+    #[test]
+    fn test_watermark_is_non_decreasing_across_a_batch_ordered_by_event_time() {
+        let mut watermark = Watermark::new(WatermarkSource::EventTime("event_time".into()));
+        let mut batch: Vec<Map<String, Value>> = [
+            1_718_020_800_000_000i64,
+            1_718_020_800_000_000,
+            1_718_020_800_500_000,
+            1_718_020_801_000_000,
+        ]
+        .iter()
+        .map(|event_time| row(json!({"id": 1, "event_time": event_time})))
+        .collect();
+
+        for row in &mut batch {
+            watermark.stamp(row).unwrap();
+        }
+
+        let stamped: Vec<i64> = batch
+            .iter()
+            .map(|row| row[WATERMARK_COLUMN].as_i64().unwrap())
+            .collect();
+        assert!(stamped.windows(2).all(|pair| pair[0] <= pair[1]));
+        // In event time order, each row's watermark is its own event time
+        assert!(batch
+            .iter()
+            .all(|row| row[WATERMARK_COLUMN] == row["event_time"]));
+        assert_eq!(Some(1_718_020_801_000_000), watermark.current());
+    }
+
+    #[test]
+    fn test_late_and_missing_event_times_keep_the_watermark() {
+        let mut watermark = Watermark::new(WatermarkSource::EventTime("event_time".into()));
+
+        let mut early = row(json!({"id": 1}));
+        assert_eq!(None, watermark.stamp(&mut early).unwrap());
+        assert!(!early.contains_key(WATERMARK_COLUMN));
+
+        watermark
+            .stamp(&mut row(json!({"event_time": "2000"})))
+            .unwrap();
+        let mut late = row(json!({"event_time": 1000}));
+        assert_eq!(Some(2000), watermark.stamp(&mut late).unwrap());
+        assert_eq!(json!(2000), late[WATERMARK_COLUMN]);
+        assert_eq!(
+            Some(2000),
+            watermark
+                .stamp(&mut row(json!({"event_time": null})))
+                .unwrap()
+        );
+
+        let error = watermark
+            .stamp(&mut row(json!({"event_time": "yesterday"})))
+            .unwrap_err();
+        assert!(error.to_string().contains("\"event_time\""));
+        assert_eq!(Some(2000), watermark.current());
+    }
+
+    #[test]
+    fn test_counter_strictly_increases() {
+        let mut watermark = Watermark::new(WatermarkSource::Counter);
+        let stamped: Vec<i64> = (0..1000)
+            .map(|_| watermark.stamp(&mut Map::new()).unwrap().unwrap())
+            .collect();
+        assert!(stamped.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn test_counter_starts_from_the_clock() {
+        let clock = FixedClock::at_unix_secs(1_718_020_860);
+        let mut watermark = Watermark::new(WatermarkSource::Counter).clock(Arc::new(clock));
+        let mut row = Map::new();
+
+        assert_eq!(
+            Some(1_718_020_860_000_000),
+            watermark.stamp(&mut row).unwrap()
+        );
+        // The clock has not moved, so the counter does
+        assert_eq!(
+            Some(1_718_020_860_000_001),
+            watermark.stamp(&mut row).unwrap()
+        );
+        assert_eq!(json!(1_718_020_860_000_001i64), row[WATERMARK_COLUMN]);
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            WatermarkSource::EventTime("event_time".to_string()),
+            WatermarkSource::parse(" event_time ").unwrap()
+        );
+        assert_eq!(
+            WatermarkSource::Counter,
+            WatermarkSource::parse("COUNTER").unwrap()
+        );
+        assert!(WatermarkSource::parse("").is_err());
+    }
+}
@@ -0,0 +1,63 @@
+//! Calls to the Databricks workspace REST API, authenticated like the streams are
+
+use anyhow::{Context, Result};
+use serde_json::Value;
+
+use crate::auth::StreamAuth;
+
+/// The REST API of one workspace
+pub(crate) struct WorkspaceApi {
+    host: String,
+    client: reqwest::Client,
+}
+
+impl WorkspaceApi {
+    pub(crate) fn new(databricks_host: impl Into<String>) -> Self {
+        Self {
+            host: databricks_host.into().trim_end_matches('/').to_string(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    pub(crate) fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// A workspace token: the one in the token file, or one exchanged for the service
+    /// principal's credentials
+    pub(crate) async fn token(&self, auth: &StreamAuth) -> Result<String> {
+        let credentials = match auth {
+            StreamAuth::TokenFile(file) => return file.token(),
+            StreamAuth::OAuth(credentials) => credentials,
+        };
+        let response: Value = self
+            .client
+            .post(format!("{}/oidc/v1/token", self.host))
+            .basic_auth(&credentials.client_id, Some(&credentials.client_secret))
+            .form(&[("grant_type", "client_credentials"), ("scope", "all-apis")])
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .with_context(|| format!("Failed to get a token from {}", self.host))?
+            .json()
+            .await
+            .context("Invalid token response")?;
+        response["access_token"]
+            .as_str()
+            .map(str::to_string)
+            .context("Token response has no access_token")
+    }
+
+    pub(crate) async fn get(&self, path: &str, token: &str) -> Result<Value> {
+        self.client
+            .get(format!("{}{}", self.host, path))
+            .bearer_auth(token)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .with_context(|| format!("Failed to call {}{}", self.host, path))?
+            .json()
+            .await
+            .with_context(|| format!("Invalid response from {}", path))
+    }
+}
@@ -0,0 +1,158 @@
+//! Polling every running container's stats
+
+use anyhow::Result;
+use futures::future::join_all;
+use std::future::Future;
+use tracing::{debug, warn};
+
+use crate::labels::LabelFilter;
+use crate::proto::container_stats::TableContainerStats;
+use crate::row::to_row;
+use crate::stats::{ContainerSummary, Stats};
+
+/// The Docker Engine API calls a poll makes; implemented over the daemon's socket by
+/// [`DockerClient`](crate::docker::DockerClient), and by tests
+pub trait DockerApi: Send + Sync {
+    /// The running containers
+    fn containers(&self) -> impl Future<Output = Result<Vec<ContainerSummary>>> + Send;
+
+    /// One stats sample of a container; `None` if the container no longer exists
+    fn stats(&self, id: &str) -> impl Future<Output = Result<Option<Stats>>> + Send;
+}
+
+/// What one poll found
+#[derive(Debug, Default)]
+pub struct Poll {
+    /// One row per container, in the order Docker listed them
+    pub rows: Vec<TableContainerStats>,
+    /// Containers that stopped or were removed between being listed and read
+    pub gone: usize,
+    /// Containers whose stats could not be read
+    pub failed: usize,
+}
+
+/// Read the stats of every running container
+///
+/// Containers are read concurrently, since Docker takes about a second to answer each
+/// one while it waits for a second CPU sample. Containers come and go between the list
+/// and the reads: one that has gone is left out of the poll rather than failing it.
+/// Fails only if the containers cannot be listed.
+pub async fn poll<D: DockerApi>(
+    docker: &D,
+    host: &str,
+    labels: &LabelFilter,
+    ingested_at: i64,
+) -> Result<Poll> {
+    let containers = docker.containers().await?;
+    let samples = join_all(
+        containers
+            .iter()
+            .map(|container| docker.stats(&container.id)),
+    )
+    .await;
+
+    let mut poll = Poll::default();
+    for (container, sample) in containers.iter().zip(samples) {
+        match sample {
+            Ok(Some(stats)) if stats.is_running() => {
+                poll.rows
+                    .push(to_row(host, container, &stats, labels, ingested_at));
+            }
+            Ok(_) => {
+                debug!("Container {} is gone", container.id);
+                poll.gone += 1;
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to read stats of container {}: {:#}",
+                    container.id, e
+                );
+                poll.failed += 1;
+            }
+        }
+    }
+    Ok(poll)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::testing::fixture;
+    use anyhow::bail;
+    use std::collections::HashMap;
+
+    /// Answers from fixtures; containers without one are gone
+    struct MockDocker {
+        containers: Vec<ContainerSummary>,
+        stats: HashMap<String, &'static str>,
+    }
+
+    impl DockerApi for MockDocker {
+        async fn containers(&self) -> Result<Vec<ContainerSummary>> {
+            Ok(self.containers.clone())
+        }
+
+        async fn stats(&self, id: &str) -> Result<Option<Stats>> {
+            match self.stats.get(id) {
+                Some(&"error") => bail!("connection reset"),
+                Some(name) => Ok(Some(serde_json::from_str(&fixture(name)).unwrap())),
+                None => Ok(None),
+            }
+        }
+    }
+
+    fn docker(stats: [Option<&'static str>; 3]) -> MockDocker {
+        let containers: Vec<ContainerSummary> =
+            serde_json::from_str(&fixture("containers.json")).unwrap();
+        let stats = containers
+            .iter()
+            .zip(stats)
+            .filter_map(|(container, name)| Some((container.id.clone(), name?)))
+            .collect();
+        MockDocker { containers, stats }
+    }
+
+    #[tokio::test]
+    async fn test_poll() {
+        let docker = docker([
+            Some("stats_cgroup_v2.json"),
+            Some("stats_cgroup_v1.json"),
+            Some("stats_first_sample.json"),
+        ]);
+        let poll = poll(&docker, "docker-01", &LabelFilter::default(), 0)
+            .await
+            .unwrap();
+        let names: Vec<&str> = poll.rows.iter().map(|row| row.container_name()).collect();
+        assert_eq!(vec!["shop-web-1", "batch-worker", "migrate"], names);
+        assert_eq!(0, poll.gone);
+    }
+
+    #[tokio::test]
+    async fn test_containers_gone_mid_poll_are_skipped() {
+        // batch-worker was removed after the list (404), and migrate exited (zeroed
+        // stats)
+        let docker = docker([
+            Some("stats_cgroup_v2.json"),
+            None,
+            Some("stats_stopped.json"),
+        ]);
+        let poll = poll(&docker, "docker-01", &LabelFilter::default(), 0)
+            .await
+            .unwrap();
+        assert_eq!(1, poll.rows.len());
+        assert_eq!("shop-web-1", poll.rows[0].container_name());
+        assert_eq!(2, poll.gone);
+        assert_eq!(0, poll.failed);
+    }
+
+    #[tokio::test]
+    async fn test_failed_read_does_not_fail_poll() {
+        let docker = docker([Some("error"), Some("stats_cgroup_v1.json"), None]);
+        let poll = poll(&docker, "docker-01", &LabelFilter::default(), 0)
+            .await
+            .unwrap();
+        assert_eq!(1, poll.rows.len());
+        assert_eq!(1, poll.failed);
+        assert_eq!(1, poll.gone);
+    }
+}
@@ -0,0 +1,88 @@
+//! A client of the Docker Engine API on the daemon's Unix socket
+
+use anyhow::{bail, Context, Result};
+use http_body_util::{BodyExt, Empty};
+use hyper::body::Bytes;
+use hyper::StatusCode;
+use hyper_util::client::legacy::Client;
+use hyperlocal::{UnixClientExt, UnixConnector};
+use serde::Deserialize;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::collector::DockerApi;
+use crate::stats::{ContainerSummary, Stats};
+
+/// Longest a request may take; a stats read takes about a second
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct DockerClient {
+    client: Client<UnixConnector, Empty<Bytes>>,
+    socket: PathBuf,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Info {
+    name: String,
+}
+
+impl DockerClient {
+    pub fn new(socket: impl Into<PathBuf>) -> Self {
+        Self {
+            client: Client::unix(),
+            socket: socket.into(),
+        }
+    }
+
+    /// The daemon's host name, as `docker info` shows it
+    pub async fn host_name(&self) -> Result<String> {
+        let (status, body) = self.get("/info").await?;
+        let info: Info = parse(status, &body, "/info")?;
+        Ok(info.name)
+    }
+
+    async fn get(&self, path: &str) -> Result<(StatusCode, Bytes)> {
+        let uri = hyperlocal::Uri::new(&self.socket, path).into();
+        let request = async {
+            let response = self.client.get(uri).await?;
+            let status = response.status();
+            let body = response.into_body().collect().await?.to_bytes();
+            anyhow::Ok((status, body))
+        };
+        tokio::time::timeout(REQUEST_TIMEOUT, request)
+            .await
+            .with_context(|| format!("GET {} timed out", path))?
+            .with_context(|| format!("GET {} on {} failed", path, self.socket.display()))
+    }
+}
+
+impl DockerApi for DockerClient {
+    async fn containers(&self) -> Result<Vec<ContainerSummary>> {
+        let (status, body) = self.get("/containers/json").await?;
+        parse(status, &body, "/containers/json")
+    }
+
+    async fn stats(&self, id: &str) -> Result<Option<Stats>> {
+        let path = format!("/containers/{}/stats?stream=false", id);
+        let (status, body) = self.get(&path).await?;
+        match status {
+            // Removed, or stopped and being removed
+            StatusCode::NOT_FOUND | StatusCode::CONFLICT => Ok(None),
+            _ => parse(status, &body, &path).map(Some),
+        }
+    }
+}
+
+fn parse<T: serde::de::DeserializeOwned>(status: StatusCode, body: &[u8], path: &str) -> Result<T> {
+    if !status.is_success() {
+        bail!(
+            "GET {} returned {}: {}",
+            path,
+            status,
+            String::from_utf8_lossy(body).trim()
+        );
+    }
+    serde_json::from_slice(body)
+        .with_context(|| format!("Failed to parse response of GET {}", path))
+}
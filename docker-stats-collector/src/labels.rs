@@ -0,0 +1,132 @@
+//! Flattening container labels into the `labels` column
+
+use std::collections::{BTreeMap, HashMap};
+
+/// Longest label value kept; longer values are cut at a character boundary
+pub const MAX_LABEL_VALUE_BYTES: usize = 1024;
+
+/// Compose project of containers started by `docker compose`
+pub const COMPOSE_PROJECT: &str = "com.docker.compose.project";
+
+/// Compose service of containers started by `docker compose`
+pub const COMPOSE_SERVICE: &str = "com.docker.compose.service";
+
+/// Which labels are kept
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LabelFilter {
+    /// Keys must start with one of these; every label is kept when empty
+    prefixes: Vec<String>,
+}
+
+impl LabelFilter {
+    pub fn new(prefixes: Vec<String>) -> Self {
+        Self { prefixes }
+    }
+
+    /// Read the prefixes from `LABEL_PREFIXES`, separated by commas
+    pub fn from_env() -> Self {
+        let prefixes = std::env::var("LABEL_PREFIXES")
+            .map(|value| {
+                value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|prefix| !prefix.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default();
+        Self::new(prefixes)
+    }
+
+    /// The labels to store, sorted by key
+    ///
+    /// Image labels are already merged into a container's labels by Docker, so these
+    /// are every label `docker inspect` shows. The compose project and service have
+    /// their own columns and are left out.
+    pub fn flatten(&self, labels: Option<&HashMap<String, String>>) -> BTreeMap<String, String> {
+        let Some(labels) = labels else {
+            return BTreeMap::new();
+        };
+        labels
+            .iter()
+            .filter(|(key, _)| key.as_str() != COMPOSE_PROJECT && key.as_str() != COMPOSE_SERVICE)
+            .filter(|(key, _)| self.keeps(key))
+            .map(|(key, value)| {
+                (
+                    key.clone(),
+                    truncate(value, MAX_LABEL_VALUE_BYTES).to_string(),
+                )
+            })
+            .collect()
+    }
+
+    fn keeps(&self, key: &str) -> bool {
+        self.prefixes.is_empty() || self.prefixes.iter().any(|prefix| key.starts_with(prefix))
+    }
+}
+
+/// The longest prefix of `value` of at most `max` bytes that ends on a character
+fn truncate(value: &str, max: usize) -> &str {
+    if value.len() <= max {
+        return value;
+    }
+    let mut end = max;
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    &value[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::testing::fixture;
+    use crate::stats::ContainerSummary;
+
+    fn containers() -> Vec<ContainerSummary> {
+        serde_json::from_str(&fixture("containers.json")).unwrap()
+    }
+
+    #[test]
+    fn test_flatten_labels() {
+        let containers = containers();
+        let labels = LabelFilter::default().flatten(containers[0].labels.as_ref());
+        assert_eq!(
+            vec![
+                "com.docker.compose.config-hash",
+                "com.docker.compose.container-number",
+                "maintainer",
+                "team"
+            ],
+            labels.keys().collect::<Vec<_>>()
+        );
+        assert_eq!("storefront", labels["team"]);
+
+        // Containers without labels list them as {} or null
+        assert!(LabelFilter::default()
+            .flatten(containers[1].labels.as_ref())
+            .is_empty());
+        assert!(LabelFilter::default()
+            .flatten(containers[2].labels.as_ref())
+            .is_empty());
+    }
+
+    #[test]
+    fn test_label_prefixes() {
+        let containers = containers();
+        let filter = LabelFilter::new(vec!["team".to_string(), "org.opencontainers.".to_string()]);
+        let labels = filter.flatten(containers[0].labels.as_ref());
+        assert_eq!(vec!["team"], labels.keys().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_long_values_are_cut_at_a_character() {
+        let labels = HashMap::from([("note".to_string(), "é".repeat(MAX_LABEL_VALUE_BYTES))]);
+        let flattened = LabelFilter::default().flatten(Some(&labels));
+        assert_eq!(MAX_LABEL_VALUE_BYTES, flattened["note"].len());
+
+        let labels = HashMap::from([("note".to_string(), format!("a{}", "é".repeat(600)))]);
+        let flattened = LabelFilter::default().flatten(Some(&labels));
+        assert_eq!(MAX_LABEL_VALUE_BYTES - 1, flattened["note"].len());
+    }
+}
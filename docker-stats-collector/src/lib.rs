@@ -0,0 +1,6 @@
+pub mod collector;
+pub mod docker;
+pub mod labels;
+pub mod proto;
+pub mod row;
+pub mod stats;
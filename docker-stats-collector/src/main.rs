@@ -0,0 +1,164 @@
+use anyhow::{bail, Context, Result};
+use databricks_zerobus_ingest_sdk::{StreamConfigurationOptions, TableProperties, ZerobusSdk};
+use docker_stats_collector::collector::poll;
+use docker_stats_collector::docker::DockerClient;
+use docker_stats_collector::labels::LabelFilter;
+use docker_stats_collector::proto::load_descriptor_proto;
+use docker_stats_collector::row::canary_row;
+use prost::Message;
+use std::pin::pin;
+use std::time::{Duration, SystemTime};
+use tokio::time::MissedTickBehavior;
+use tracing::{error, info};
+use zerobus_common::auth::StreamAuth;
+use zerobus_common::clock::SystemClock;
+use zerobus_common::endpoint::zerobus_endpoint_from_env;
+use zerobus_common::pipeline::Pipeline;
+use zerobus_common::schema_check;
+use zerobus_common::self_test::SelfTest;
+use zerobus_common::shutdown;
+
+/// Maximum number of unacknowledged records per stream
+const MAX_INFLIGHT_RECORDS: usize = 10_000;
+
+/// Socket of the Docker daemon when DOCKER_SOCKET is not set
+const DEFAULT_DOCKER_SOCKET: &str = "/var/run/docker.sock";
+
+/// Time between polls when POLL_INTERVAL_SECS is not set
+const DEFAULT_POLL_INTERVAL_SECS: u64 = 30;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .with_target(false)
+        .init();
+
+    let databricks_host = std::env::var("DATABRICKS_HOST")
+        .context("DATABRICKS_HOST environment variable must be set")?;
+    let table_name =
+        std::env::var("TABLE_NAME").context("TABLE_NAME environment variable must be set")?;
+
+    // `docker-stats-collector schema-check`: compare the descriptor with the table, then exit
+    if std::env::args().nth(1).as_deref() == Some("schema-check") {
+        let auth = StreamAuth::from_env().await?;
+        let descriptor_proto =
+            load_descriptor_proto("container_stats.proto", "table_container_stats");
+        schema_check::check(&databricks_host, &auth, &table_name, &descriptor_proto).await?;
+        info!("Descriptor matches the schema of {}", table_name);
+        return Ok(());
+    }
+
+    let socket =
+        std::env::var("DOCKER_SOCKET").unwrap_or_else(|_| DEFAULT_DOCKER_SOCKET.to_string());
+    let poll_interval = poll_interval()?;
+    let labels = LabelFilter::from_env();
+    let grace = shutdown::grace_from_env()?;
+    let self_test = SelfTest::from_env()?;
+
+    let docker = DockerClient::new(&socket);
+    let host = match std::env::var("HOST_NAME") {
+        Ok(host) => host,
+        Err(_) => docker.host_name().await?,
+    };
+    info!("Collecting stats of containers on {} from {}", host, socket);
+
+    let auth = StreamAuth::from_env().await?;
+    let descriptor_proto = load_descriptor_proto("container_stats.proto", "table_container_stats");
+    schema_check::check_from_env(&databricks_host, &auth, &table_name, &descriptor_proto).await?;
+    let zerobus_endpoint = zerobus_endpoint_from_env(&databricks_host, &auth).await?;
+    let sdk = ZerobusSdk::new(zerobus_endpoint, databricks_host)?;
+    let table_properties = TableProperties {
+        table_name: table_name.clone(),
+        descriptor_proto,
+    };
+    let stream_options = StreamConfigurationOptions {
+        max_inflight_records: MAX_INFLIGHT_RECORDS,
+        ..Default::default()
+    };
+    let stream = auth
+        .create_stream(&sdk, table_properties, Some(stream_options))
+        .await
+        .context("Failed to create stream")?;
+    info!("Created stream to table: {}", table_name);
+
+    // A deployment smoke test: one canary row, then exit with its outcome
+    if let Some(self_test) = self_test {
+        let ingested_at = now_micros()?;
+        self_test
+            .run(stream, &table_name, &SystemClock, |canary_id| {
+                canary_row(&host, canary_id, ingested_at).encode_to_vec()
+            })
+            .await?;
+        return Ok(());
+    }
+
+    let mut pipeline = Pipeline::new(stream, MAX_INFLIGHT_RECORDS);
+    let mut ticks = tokio::time::interval(poll_interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut stop = pin!(shutdown::signal());
+    loop {
+        tokio::select! {
+            _ = &mut stop => break,
+            _ = ticks.tick() => {}
+        }
+
+        // A daemon that is restarting fails the poll; the next one tries again
+        let polled = match poll(&docker, &host, &labels, now_micros()?).await {
+            Ok(polled) => polled,
+            Err(e) => {
+                error!("Failed to list containers: {:#}", e);
+                continue;
+            }
+        };
+        let rows = polled.rows.len();
+        match pipeline
+            .ingest_batch(polled.rows.iter().map(|row| row.encode_to_vec()))
+            .await
+        {
+            Ok(()) => info!(
+                "Ingested stats of {} containers ({} gone, {} unreadable)",
+                rows, polled.gone, polled.failed
+            ),
+            Err(e) => error!("Failed to ingest stats of {} containers: {:#}", rows, e),
+        }
+    }
+
+    let outcome = shutdown::drain(pipeline, grace).await?;
+    info!(
+        "Shut down: {} rows ingested, {} failed",
+        outcome.summary.ingested, outcome.summary.failed
+    );
+    if !outcome.unacked.is_empty() {
+        bail!(
+            "{} rows were not acknowledged before shutdown",
+            outcome.unacked.len()
+        );
+    }
+    Ok(())
+}
+
+/// Read the time between polls from `POLL_INTERVAL_SECS`
+fn poll_interval() -> Result<Duration> {
+    let secs = match std::env::var("POLL_INTERVAL_SECS") {
+        Ok(value) => value.trim().parse::<u64>().with_context(|| {
+            format!(
+                "POLL_INTERVAL_SECS must be a positive integer, got {:?}",
+                value
+            )
+        })?,
+        Err(_) => DEFAULT_POLL_INTERVAL_SECS,
+    };
+    if secs == 0 {
+        bail!("POLL_INTERVAL_SECS must be a positive integer, got 0");
+    }
+    Ok(Duration::from_secs(secs))
+}
+
+/// Current time in microseconds since Unix epoch
+fn now_micros() -> Result<i64> {
+    Ok(SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .context("Failed to get system time")?
+        .as_micros() as i64)
+}
@@ -0,0 +1,147 @@
+//! Mapping a container's stats to a row of the table
+
+use chrono::DateTime;
+
+use crate::labels::{LabelFilter, COMPOSE_PROJECT, COMPOSE_SERVICE};
+use crate::proto::container_stats::TableContainerStats;
+use crate::stats::{ContainerSummary, Stats};
+
+/// Build the row of one container
+///
+/// `ingested_at` is microseconds since Unix epoch.
+pub fn to_row(
+    host: &str,
+    container: &ContainerSummary,
+    stats: &Stats,
+    labels: &LabelFilter,
+    ingested_at: i64,
+) -> TableContainerStats {
+    let label = |key: &str| {
+        container
+            .labels
+            .as_ref()
+            .and_then(|labels| labels.get(key))
+            .cloned()
+    };
+    let network = stats.network_totals();
+    let block_io = stats.block_io();
+    TableContainerStats {
+        host: Some(host.to_string()),
+        container_id: Some(container.id.clone()),
+        container_name: container.name().map(str::to_string),
+        image: Some(container.image.clone()),
+        labels: labels.flatten(container.labels.as_ref()),
+        compose_project: label(COMPOSE_PROJECT),
+        compose_service: label(COMPOSE_SERVICE),
+        read_at: DateTime::parse_from_rfc3339(&stats.read)
+            .ok()
+            .map(|read| read.timestamp_micros()),
+        cpu_percent: stats.cpu_percent(),
+        online_cpus: Some(stats.online_cpus() as i32).filter(|&cpus| cpus > 0),
+        memory_usage: stats.memory_usage().map(saturating_i64),
+        memory_limit: stats.memory_stats.limit.map(saturating_i64),
+        memory_percent: stats.memory_percent(),
+        network_rx_bytes: network.as_ref().map(|n| saturating_i64(n.rx_bytes)),
+        network_tx_bytes: network.as_ref().map(|n| saturating_i64(n.tx_bytes)),
+        network_rx_packets: network.as_ref().map(|n| saturating_i64(n.rx_packets)),
+        network_tx_packets: network.as_ref().map(|n| saturating_i64(n.tx_packets)),
+        network_rx_errors: network.as_ref().map(|n| saturating_i64(n.rx_errors)),
+        network_tx_errors: network.as_ref().map(|n| saturating_i64(n.tx_errors)),
+        network_rx_dropped: network.as_ref().map(|n| saturating_i64(n.rx_dropped)),
+        network_tx_dropped: network.as_ref().map(|n| saturating_i64(n.tx_dropped)),
+        block_read_bytes: block_io.map(|(read, _)| saturating_i64(read)),
+        block_write_bytes: block_io.map(|(_, write)| saturating_i64(write)),
+        pids: stats.pids_stats.current.map(saturating_i64),
+        ingested_at: Some(ingested_at),
+        ingested_date: Some((ingested_at / 86_400_000_000) as i32),
+    }
+}
+
+/// The row of a `SELF_TEST` canary: the canary ID in place of a container's ID and name,
+/// and no stats
+pub fn canary_row(host: &str, canary_id: &str, ingested_at: i64) -> TableContainerStats {
+    TableContainerStats {
+        host: Some(host.to_string()),
+        container_id: Some(canary_id.to_string()),
+        container_name: Some(canary_id.to_string()),
+        ingested_at: Some(ingested_at),
+        ingested_date: Some((ingested_at / 86_400_000_000) as i32),
+        ..Default::default()
+    }
+}
+
+/// Counters are unsigned; a limit of "unlimited" is reported near `u64::MAX`
+fn saturating_i64(value: u64) -> i64 {
+    i64::try_from(value).unwrap_or(i64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::testing::fixture;
+
+    const INGESTED_AT: i64 = 1_718_020_860_000_000;
+
+    #[test]
+    fn test_row() {
+        let containers: Vec<ContainerSummary> =
+            serde_json::from_str(&fixture("containers.json")).unwrap();
+        let stats: Stats = serde_json::from_str(&fixture("stats_cgroup_v2.json")).unwrap();
+        let row = to_row(
+            "docker-01",
+            &containers[0],
+            &stats,
+            &LabelFilter::default(),
+            INGESTED_AT,
+        );
+
+        assert_eq!(Some("docker-01"), row.host.as_deref());
+        assert_eq!(Some(containers[0].id.as_str()), row.container_id.as_deref());
+        assert_eq!(Some("shop-web-1"), row.container_name.as_deref());
+        assert_eq!(Some("nginx:1.27"), row.image.as_deref());
+        assert_eq!(Some("shop"), row.compose_project.as_deref());
+        assert_eq!(Some("web"), row.compose_service.as_deref());
+        assert_eq!("storefront", row.labels["team"]);
+        assert_eq!(Some(1_718_020_801_512_345), row.read_at);
+        assert_eq!(Some(50.0), row.cpu_percent);
+        assert_eq!(Some(4), row.online_cpus);
+        assert_eq!(Some(104_857_600), row.memory_usage);
+        assert_eq!(Some(2_147_483_648), row.memory_limit);
+        assert_eq!(Some(5_243_904), row.network_rx_bytes);
+        assert_eq!(Some(10_485_760), row.block_read_bytes);
+        assert_eq!(Some(12), row.pids);
+        assert_eq!(Some(19884), row.ingested_date);
+    }
+
+    #[test]
+    fn test_first_sample_row() {
+        let containers: Vec<ContainerSummary> =
+            serde_json::from_str(&fixture("containers.json")).unwrap();
+        let stats: Stats = serde_json::from_str(&fixture("stats_first_sample.json")).unwrap();
+        let row = to_row(
+            "docker-01",
+            &containers[2],
+            &stats,
+            &LabelFilter::default(),
+            INGESTED_AT,
+        );
+
+        assert_eq!(Some("migrate"), row.container_name.as_deref());
+        assert_eq!(None, row.cpu_percent);
+        assert_eq!(None, row.compose_project);
+        assert!(row.labels.is_empty());
+        assert_eq!(Some(4_194_304), row.memory_usage);
+        assert_eq!(None, row.network_rx_bytes);
+        assert_eq!(None, row.block_read_bytes);
+    }
+
+    #[test]
+    fn test_canary_row() {
+        let row = canary_row("docker-01", "zerobus-self-test-1", INGESTED_AT);
+        assert_eq!(Some("zerobus-self-test-1"), row.container_name.as_deref());
+        assert_eq!(Some("zerobus-self-test-1"), row.container_id.as_deref());
+        assert_eq!(None, row.cpu_percent);
+        assert!(row.labels.is_empty());
+        assert_eq!(Some(19884), row.ingested_date);
+    }
+}
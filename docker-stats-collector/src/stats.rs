@@ -0,0 +1,304 @@
+//! The parts of the Docker Engine API's responses the collector reads, and the figures
+//! `docker stats` derives from them
+
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// An entry of `GET /containers/json`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ContainerSummary {
+    pub id: String,
+    #[serde(default)]
+    pub names: Vec<String>,
+    #[serde(default)]
+    pub image: String,
+    /// `null` for containers created without labels
+    #[serde(default)]
+    pub labels: Option<HashMap<String, String>>,
+}
+
+impl ContainerSummary {
+    /// The container's name, without the leading `/` the API adds
+    pub fn name(&self) -> Option<&str> {
+        self.names
+            .first()
+            .map(|name| name.strip_prefix('/').unwrap_or(name))
+    }
+}
+
+/// A response of `GET /containers/{id}/stats?stream=false`
+///
+/// Fields missing from a response, as most are for a container that has stopped, read
+/// as zero or empty.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Stats {
+    /// When the stats were read, as RFC 3339; `0001-01-01T00:00:00Z` if the container is
+    /// not running
+    pub read: String,
+    pub pids_stats: PidsStats,
+    pub blkio_stats: BlkioStats,
+    pub cpu_stats: CpuStats,
+    /// The sample before `cpu_stats`, about a second earlier
+    pub precpu_stats: CpuStats,
+    pub memory_stats: MemoryStats,
+    pub networks: Option<HashMap<String, NetworkStats>>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct PidsStats {
+    pub current: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct BlkioStats {
+    /// `null` on cgroup v2 hosts where no IO has been accounted yet
+    pub io_service_bytes_recursive: Option<Vec<BlkioEntry>>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct BlkioEntry {
+    /// `Read`, `Write`, `Total`, ... on cgroup v1; `read` and `write` on cgroup v2
+    pub op: String,
+    pub value: u64,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct CpuStats {
+    pub cpu_usage: CpuUsage,
+    /// Nanoseconds of CPU time the host has spent, summed over its CPUs
+    pub system_cpu_usage: Option<u64>,
+    /// Missing from cgroup v1 hosts on older Engine versions
+    pub online_cpus: Option<u32>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct CpuUsage {
+    /// Nanoseconds of CPU time the container has used
+    pub total_usage: u64,
+    /// Per-CPU usage; only reported on cgroup v1
+    pub percpu_usage: Option<Vec<u64>>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct MemoryStats {
+    pub usage: Option<u64>,
+    pub limit: Option<u64>,
+    pub stats: HashMap<String, u64>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct NetworkStats {
+    pub rx_bytes: u64,
+    pub rx_packets: u64,
+    pub rx_errors: u64,
+    pub rx_dropped: u64,
+    pub tx_bytes: u64,
+    pub tx_packets: u64,
+    pub tx_errors: u64,
+    pub tx_dropped: u64,
+}
+
+impl Stats {
+    /// Whether the stats describe a running container
+    ///
+    /// A container that stops between being listed and having its stats read is
+    /// answered with zeroed stats rather than an error.
+    pub fn is_running(&self) -> bool {
+        !self.read.is_empty() && !self.read.starts_with("0001-01-01")
+    }
+
+    /// CPUs the container could use, as `docker stats` counts them
+    pub fn online_cpus(&self) -> u32 {
+        match self.cpu_stats.online_cpus {
+            Some(cpus) if cpus > 0 => cpus,
+            _ => self
+                .cpu_stats
+                .cpu_usage
+                .percpu_usage
+                .as_ref()
+                .map_or(0, |usage| usage.len() as u32),
+        }
+    }
+
+    /// CPU use between the two samples, where 100 is one whole CPU, as `docker stats`
+    /// shows it
+    ///
+    /// `None` when there is no earlier sample to compare with, as for a container that
+    /// started within the last second. Counters that went backwards, as they do when a
+    /// container restarts, read as idle.
+    pub fn cpu_percent(&self) -> Option<f64> {
+        let previous_system = self
+            .precpu_stats
+            .system_cpu_usage
+            .filter(|&usage| usage > 0)?;
+        let system = self.cpu_stats.system_cpu_usage?;
+        let cpus = self.online_cpus();
+        if cpus == 0 {
+            return None;
+        }
+
+        let cpu_delta = self
+            .cpu_stats
+            .cpu_usage
+            .total_usage
+            .checked_sub(self.precpu_stats.cpu_usage.total_usage);
+        let system_delta = system.checked_sub(previous_system);
+        match (cpu_delta, system_delta) {
+            (Some(cpu), Some(system)) if cpu > 0 && system > 0 => {
+                Some(cpu as f64 / system as f64 * cpus as f64 * 100.0)
+            }
+            _ => Some(0.0),
+        }
+    }
+
+    /// Memory in use, less the page cache the kernel can reclaim, as `docker stats`
+    /// shows it
+    ///
+    /// The reclaimable cache is `inactive_file` on cgroup v2 and `total_inactive_file`
+    /// on cgroup v1.
+    pub fn memory_usage(&self) -> Option<u64> {
+        let usage = self.memory_stats.usage?;
+        let stats = &self.memory_stats.stats;
+        let inactive = stats
+            .get("total_inactive_file")
+            .or_else(|| stats.get("inactive_file"))
+            .copied()
+            .unwrap_or(0);
+        Some(usage.saturating_sub(inactive))
+    }
+
+    pub fn memory_percent(&self) -> Option<f64> {
+        let limit = self.memory_stats.limit.filter(|&limit| limit > 0)?;
+        Some(self.memory_usage()? as f64 / limit as f64 * 100.0)
+    }
+
+    /// Counters summed over every interface
+    pub fn network_totals(&self) -> Option<NetworkStats> {
+        let networks = self.networks.as_ref()?;
+        Some(
+            networks
+                .values()
+                .fold(NetworkStats::default(), |total, network| NetworkStats {
+                    rx_bytes: total.rx_bytes + network.rx_bytes,
+                    rx_packets: total.rx_packets + network.rx_packets,
+                    rx_errors: total.rx_errors + network.rx_errors,
+                    rx_dropped: total.rx_dropped + network.rx_dropped,
+                    tx_bytes: total.tx_bytes + network.tx_bytes,
+                    tx_packets: total.tx_packets + network.tx_packets,
+                    tx_errors: total.tx_errors + network.tx_errors,
+                    tx_dropped: total.tx_dropped + network.tx_dropped,
+                }),
+        )
+    }
+
+    /// Bytes read from and written to block devices, summed over devices
+    pub fn block_io(&self) -> Option<(u64, u64)> {
+        let entries = self.blkio_stats.io_service_bytes_recursive.as_ref()?;
+        let sum = |op: &str| {
+            entries
+                .iter()
+                .filter(|entry| entry.op.eq_ignore_ascii_case(op))
+                .map(|entry| entry.value)
+                .sum()
+        };
+        Some((sum("read"), sum("write")))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod testing {
+    /// A fixture under `tests/fixtures`
+    pub fn fixture(name: &str) -> String {
+        let path = format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name);
+        std::fs::read_to_string(&path).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::fixture;
+    use super::*;
+
+    fn stats(name: &str) -> Stats {
+        serde_json::from_str(&fixture(name)).unwrap()
+    }
+
+    #[test]
+    fn test_cpu_percent() {
+        // 0.5s of CPU time out of 4s across 4 CPUs is half of one CPU
+        let stats = stats("stats_cgroup_v2.json");
+        assert_eq!(4, stats.online_cpus());
+        assert_eq!(Some(50.0), stats.cpu_percent());
+
+        // Without online_cpus, the CPUs are counted from the per-CPU usage
+        let stats = self::stats("stats_cgroup_v1.json");
+        assert_eq!(2, stats.online_cpus());
+        assert_eq!(Some(20.0), stats.cpu_percent());
+    }
+
+    #[test]
+    fn test_cpu_percent_without_earlier_sample() {
+        let stats = stats("stats_first_sample.json");
+        assert!(stats.is_running());
+        assert_eq!(None, stats.cpu_percent());
+    }
+
+    #[test]
+    fn test_cpu_counters_going_backwards_read_as_idle() {
+        let mut stats = stats("stats_cgroup_v2.json");
+        stats.precpu_stats.cpu_usage.total_usage = stats.cpu_stats.cpu_usage.total_usage + 1;
+        assert_eq!(Some(0.0), stats.cpu_percent());
+
+        let mut stats = self::stats("stats_cgroup_v2.json");
+        stats.cpu_stats.system_cpu_usage = stats.precpu_stats.system_cpu_usage;
+        assert_eq!(Some(0.0), stats.cpu_percent());
+    }
+
+    #[test]
+    fn test_memory_excludes_inactive_file_cache() {
+        let stats = stats("stats_cgroup_v2.json");
+        assert_eq!(Some(100 * 1024 * 1024), stats.memory_usage());
+        assert_eq!(Some(4.8828125), stats.memory_percent());
+
+        let stats = self::stats("stats_cgroup_v1.json");
+        assert_eq!(Some(190 * 1024 * 1024), stats.memory_usage());
+    }
+
+    #[test]
+    fn test_network_and_block_io_are_summed() {
+        let stats = stats("stats_cgroup_v2.json");
+        let network = stats.network_totals().unwrap();
+        assert_eq!(5_243_904, network.rx_bytes);
+        assert_eq!(1_049_088, network.tx_bytes);
+        assert_eq!(4104, network.rx_packets);
+        assert_eq!(1, network.rx_errors);
+        assert_eq!(3, network.rx_dropped);
+        assert_eq!(3, network.tx_errors);
+        assert_eq!(Some((10_485_760, 2_097_152)), stats.block_io());
+
+        // Totals and sync/async splits on cgroup v1 are not counted twice
+        let stats = self::stats("stats_cgroup_v1.json");
+        assert_eq!(Some((5120, 8192)), stats.block_io());
+
+        let stats = self::stats("stats_first_sample.json");
+        assert!(stats.network_totals().is_none());
+        assert_eq!(None, stats.block_io());
+    }
+
+    #[test]
+    fn test_stopped_container() {
+        let stats = stats("stats_stopped.json");
+        assert!(!stats.is_running());
+        assert_eq!(None, stats.cpu_percent());
+        assert_eq!(None, stats.memory_usage());
+    }
+}
@@ -0,0 +1,25 @@
+//! The compiled descriptor against the live table named by `SCHEMA_CHECK_TABLE`
+//!
+//! Needs a workspace: `DATABRICKS_HOST` and the stream's credentials, as the collector
+//! reads them. Without `SCHEMA_CHECK_TABLE` the test passes without checking anything.
+
+use docker_stats_collector::proto::load_descriptor_proto;
+use zerobus_common::auth::StreamAuth;
+use zerobus_common::schema_check;
+
+#[tokio::test]
+async fn test_descriptor_matches_the_live_table() {
+    let Ok(table_name) = std::env::var("SCHEMA_CHECK_TABLE") else {
+        eprintln!("SCHEMA_CHECK_TABLE is not set, skipping the live schema check");
+        return;
+    };
+    let databricks_host = std::env::var("DATABRICKS_HOST").expect("DATABRICKS_HOST must be set");
+    let auth = StreamAuth::from_env().await.unwrap();
+    let descriptor_proto = load_descriptor_proto("container_stats.proto", "table_container_stats");
+
+    if let Err(error) =
+        schema_check::check(&databricks_host, &auth, &table_name, &descriptor_proto).await
+    {
+        panic!("{:#}", error);
+    }
+}
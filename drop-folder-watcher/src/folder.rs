@@ -0,0 +1,348 @@
+//! The drop folder and the subfolders files move through while they are loaded
+
+use anyhow::{Context, Result};
+use bulk_loader::progress;
+use bulk_loader::reader::Format;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Files are moved here while they are loaded, so writers and other watchers leave
+/// them alone
+pub const PROCESSING_DIR: &str = ".processing";
+/// Files whose every row was loaded
+pub const PROCESSED_DIR: &str = "processed";
+/// Files that were not fully loaded, each next to a sidecar saying why
+pub const FAILED_DIR: &str = "failed";
+/// Hashes of the files already loaded
+pub const LEDGER_FILE: &str = ".zerobus-ledger.jsonl";
+/// Extension of the sidecar written next to each failed file
+pub const ERROR_EXTENSION: &str = "error.txt";
+
+/// Extensions of files that are still being written or downloaded
+const PARTIAL_EXTENSIONS: &[&str] = &["tmp", "part", "partial", "crdownload", "download"];
+
+/// A file waiting in the drop folder
+#[derive(Debug)]
+pub struct Candidate {
+    pub path: PathBuf,
+    pub len: u64,
+    pub modified: Option<SystemTime>,
+}
+
+/// Where a loaded file ends up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Destination {
+    Processed,
+    Failed,
+}
+
+pub struct DropFolder {
+    root: PathBuf,
+}
+
+impl DropFolder {
+    /// Use `root` as the drop folder, creating the subfolders it needs
+    pub fn new(root: &Path) -> Result<Self> {
+        let folder = Self {
+            root: root.to_path_buf(),
+        };
+        for dir in [
+            folder.processing_dir(),
+            folder.dir(Destination::Processed),
+            folder.dir(Destination::Failed),
+        ] {
+            std::fs::create_dir_all(&dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        Ok(folder)
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn processing_dir(&self) -> PathBuf {
+        self.root.join(PROCESSING_DIR)
+    }
+
+    pub fn dir(&self, destination: Destination) -> PathBuf {
+        match destination {
+            Destination::Processed => self.root.join(PROCESSED_DIR),
+            Destination::Failed => self.root.join(FAILED_DIR),
+        }
+    }
+
+    pub fn ledger_path(&self) -> PathBuf {
+        self.root.join(LEDGER_FILE)
+    }
+
+    /// Files dropped into the folder, by name
+    ///
+    /// Subfolders, hidden files, and files that are still being written under a
+    /// temporary name are left out.
+    pub fn scan(&self) -> Result<Vec<Candidate>> {
+        let entries = std::fs::read_dir(&self.root)
+            .with_context(|| format!("Failed to list {}", self.root.display()))?;
+        let mut candidates = Vec::new();
+        for entry in entries {
+            let entry = entry.with_context(|| format!("Failed to list {}", self.root.display()))?;
+            let path = entry.path();
+            if is_ignored(&path) {
+                continue;
+            }
+            // The file may have been removed or claimed since it was listed
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            if !metadata.is_file() {
+                continue;
+            }
+            candidates.push(Candidate {
+                path,
+                len: metadata.len(),
+                modified: metadata.modified().ok(),
+            });
+        }
+        candidates.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(candidates)
+    }
+
+    /// Files left in the processing folder by a run that stopped before finishing them
+    pub fn unfinished(&self) -> Result<Vec<PathBuf>> {
+        let dir = self.processing_dir();
+        let entries =
+            std::fs::read_dir(&dir).with_context(|| format!("Failed to list {}", dir.display()))?;
+        let mut files = Vec::new();
+        for entry in entries {
+            let path = entry
+                .with_context(|| format!("Failed to list {}", dir.display()))?
+                .path();
+            if path.is_file() && !is_sidecar(&path) {
+                files.push(path);
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    /// Move `path` into the processing folder
+    ///
+    /// Returns `None` if the file cannot be moved yet: a writer still has it open and
+    /// locked, or a file of the same name is still being loaded.
+    pub fn claim(&self, path: &Path) -> Result<Option<PathBuf>> {
+        let name = path
+            .file_name()
+            .with_context(|| format!("{} has no file name", path.display()))?;
+        let claimed = self.processing_dir().join(name);
+        if claimed.exists() {
+            return Ok(None);
+        }
+        match std::fs::rename(path, &claimed) {
+            Ok(()) => Ok(Some(claimed)),
+            Err(e) if is_locked(&e) || e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Failed to claim {}", path.display())),
+        }
+    }
+
+    /// Move a claimed file to `destination` and remove its progress sidecar
+    ///
+    /// A file of the same name already there is kept, and this one is renamed
+    /// `orders (2).csv`. Returns where the file was moved.
+    pub fn finish(&self, claimed: &Path, destination: Destination) -> Result<PathBuf> {
+        let name = claimed
+            .file_name()
+            .with_context(|| format!("{} has no file name", claimed.display()))?;
+        let target = unique_path(&self.dir(destination), Path::new(name));
+        std::fs::rename(claimed, &target).with_context(|| {
+            format!(
+                "Failed to move {} to {}",
+                claimed.display(),
+                target.display()
+            )
+        })?;
+        let sidecar = progress::sidecar_path(claimed);
+        match std::fs::remove_file(&sidecar) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to remove {}", sidecar.display()))
+            }
+        }
+        Ok(target)
+    }
+
+    /// Write the sidecar explaining why `failed` was not loaded
+    pub fn write_error(&self, failed: &Path, text: &str) -> Result<PathBuf> {
+        let sidecar = error_path(failed);
+        std::fs::write(&sidecar, text)
+            .with_context(|| format!("Failed to write {}", sidecar.display()))?;
+        Ok(sidecar)
+    }
+}
+
+/// `failed/orders.csv` -> `failed/orders.csv.error.txt`
+pub fn error_path(path: &Path) -> PathBuf {
+    let mut sidecar = path.as_os_str().to_owned();
+    sidecar.push(".");
+    sidecar.push(ERROR_EXTENSION);
+    PathBuf::from(sidecar)
+}
+
+/// The format a file is read as, from its extension
+///
+/// `.json` files are read as JSON lines, one object per line.
+pub fn format_of(path: &Path) -> Option<Format> {
+    let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+    match extension.as_str() {
+        "csv" => Some(Format::Csv),
+        "jsonl" | "ndjson" | "json" => Some(Format::Jsonl),
+        _ => None,
+    }
+}
+
+/// True for an error renaming a file that another process has open
+///
+/// Windows refuses to move a file while a writer holds it without sharing, and
+/// reports a sharing or lock violation. Elsewhere a rename fails with a permission
+/// error while a writer has taken a mandatory lock.
+pub fn is_locked(error: &io::Error) -> bool {
+    const ERROR_SHARING_VIOLATION: i32 = 32;
+    const ERROR_LOCK_VIOLATION: i32 = 33;
+    if error.kind() == io::ErrorKind::PermissionDenied {
+        return true;
+    }
+    cfg!(windows)
+        && matches!(
+            error.raw_os_error(),
+            Some(ERROR_SHARING_VIOLATION | ERROR_LOCK_VIOLATION)
+        )
+}
+
+fn is_ignored(path: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        return true;
+    };
+    // Hidden files, and the lock files Office and LibreOffice keep next to open files
+    if name.starts_with('.') || name.starts_with("~$") || name.ends_with('~') {
+        return true;
+    }
+    is_sidecar(path)
+        || path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .is_some_and(|extension| {
+                PARTIAL_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str())
+            })
+}
+
+fn is_sidecar(path: &Path) -> bool {
+    let name = path.to_string_lossy();
+    name.ends_with(&format!(".{}", progress::SIDECAR_EXTENSION))
+        || name.ends_with(&format!(".{}", ERROR_EXTENSION))
+}
+
+/// `dir/name`, or `dir/stem (2).ext` and so on if that is taken
+fn unique_path(dir: &Path, name: &Path) -> PathBuf {
+    let target = dir.join(name);
+    if !target.exists() {
+        return target;
+    }
+    let stem = name
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let extension = name
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+    (2..)
+        .map(|n| dir.join(format!("{} ({}){}", stem, n, extension)))
+        .find(|target| !target.exists())
+        .expect("some numbered name is free")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scan_skips_partial_hidden_and_sidecar_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let folder = DropFolder::new(dir.path()).unwrap();
+        for name in [
+            "b.csv",
+            "a.jsonl",
+            "notes.xlsx",
+            "upload.csv.part",
+            "report.tmp",
+            "~$orders.csv",
+            ".DS_Store",
+            "a.jsonl.progress",
+        ] {
+            std::fs::write(dir.path().join(name), "").unwrap();
+        }
+
+        let names: Vec<String> = folder
+            .scan()
+            .unwrap()
+            .into_iter()
+            .map(|c| c.path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+
+        // Unsupported files are returned so they can be moved to failed/ with a reason
+        assert_eq!(vec!["a.jsonl", "b.csv", "notes.xlsx"], names);
+    }
+
+    #[test]
+    fn test_finish_keeps_earlier_file_of_the_same_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let folder = DropFolder::new(dir.path()).unwrap();
+
+        for contents in ["first", "second", "third"] {
+            std::fs::write(dir.path().join("orders.csv"), contents).unwrap();
+            let claimed = folder
+                .claim(&dir.path().join("orders.csv"))
+                .unwrap()
+                .unwrap();
+            folder.finish(&claimed, Destination::Processed).unwrap();
+        }
+
+        let processed = folder.dir(Destination::Processed);
+        let read = |name: &str| std::fs::read_to_string(processed.join(name)).unwrap();
+        assert_eq!("first", read("orders.csv"));
+        assert_eq!("second", read("orders (2).csv"));
+        assert_eq!("third", read("orders (3).csv"));
+        assert!(folder.unfinished().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_claim_waits_for_a_file_of_the_same_name_being_loaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let folder = DropFolder::new(dir.path()).unwrap();
+        let path = dir.path().join("orders.csv");
+        std::fs::write(&path, "first").unwrap();
+        let claimed = folder.claim(&path).unwrap().unwrap();
+
+        std::fs::write(&path, "second").unwrap();
+        assert_eq!(None, folder.claim(&path).unwrap());
+        assert_eq!("second", std::fs::read_to_string(&path).unwrap());
+        assert_eq!(vec![claimed], folder.unfinished().unwrap());
+    }
+
+    #[test]
+    fn test_is_locked() {
+        assert!(is_locked(&io::Error::from(io::ErrorKind::PermissionDenied)));
+        assert!(!is_locked(&io::Error::from(io::ErrorKind::NotFound)));
+        assert_eq!(cfg!(windows), is_locked(&io::Error::from_raw_os_error(32)));
+    }
+
+    #[test]
+    fn test_format_of() {
+        assert_eq!(Some(Format::Csv), format_of(Path::new("orders.CSV")));
+        assert_eq!(Some(Format::Jsonl), format_of(Path::new("events.ndjson")));
+        assert_eq!(Some(Format::Jsonl), format_of(Path::new("events.json")));
+        assert_eq!(None, format_of(Path::new("orders.xlsx")));
+        assert_eq!(None, format_of(Path::new("orders")));
+    }
+}
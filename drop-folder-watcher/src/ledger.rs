@@ -0,0 +1,213 @@
+//! The ledger of file contents already loaded, so a file dropped twice is loaded once
+
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// What happened the last time a file with these contents was loaded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LedgerEntry {
+    /// SHA-256 of the file's contents, in hex
+    pub sha256: String,
+    /// Name the file was dropped under
+    pub file: String,
+    /// When the entry was recorded, in RFC 3339
+    pub at: String,
+    /// Rows acknowledged or reported as malformed, counted from the start of the file
+    pub acked_rows: u64,
+    /// Every row of the file was loaded
+    pub complete: bool,
+}
+
+impl LedgerEntry {
+    fn to_json(&self) -> Value {
+        json!({
+            "sha256": self.sha256,
+            "file": self.file,
+            "at": self.at,
+            "acked_rows": self.acked_rows,
+            "complete": self.complete,
+        })
+    }
+
+    fn from_json(value: &Value) -> Result<Self> {
+        let string = |name: &str| {
+            value
+                .get(name)
+                .and_then(Value::as_str)
+                .map(str::to_string)
+                .with_context(|| format!("Ledger entry has no {}", name))
+        };
+        Ok(Self {
+            sha256: string("sha256")?,
+            file: string("file")?,
+            at: string("at")?,
+            acked_rows: value.get("acked_rows").and_then(Value::as_u64).unwrap_or(0),
+            complete: value
+                .get("complete")
+                .and_then(Value::as_bool)
+                .unwrap_or(false),
+        })
+    }
+}
+
+/// Append-only JSONL file with one entry per load attempt
+///
+/// The last entry for a hash wins. Entries are only ever appended, so a crash while
+/// writing one loses at most that entry.
+pub struct Ledger {
+    path: PathBuf,
+    entries: HashMap<String, LedgerEntry>,
+}
+
+impl Ledger {
+    /// Read the ledger at `path`; a missing ledger is empty
+    pub fn open(path: &Path) -> Result<Self> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+        let mut entries = HashMap::new();
+        let lines: Vec<&str> = contents.lines().collect();
+        for (index, line) in lines.iter().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry = match serde_json::from_str(line)
+                .map_err(anyhow::Error::from)
+                .and_then(|value| LedgerEntry::from_json(&value))
+            {
+                Ok(entry) => entry,
+                // A crash while appending leaves a partial last line; cut it off so the
+                // next entry starts on a line of its own
+                Err(_) if index == lines.len() - 1 && !contents.ends_with('\n') => {
+                    truncate(path, (contents.len() - line.len()) as u64)?;
+                    break;
+                }
+                Err(e) => bail!(
+                    "Invalid entry on line {} of {}: {:#}",
+                    index + 1,
+                    path.display(),
+                    e
+                ),
+            };
+            entries.insert(entry.sha256.clone(), entry);
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            entries,
+        })
+    }
+
+    pub fn get(&self, sha256: &str) -> Option<&LedgerEntry> {
+        self.entries.get(sha256)
+    }
+
+    /// Append `entry` and wait for it to reach the disk
+    pub fn record(&mut self, entry: LedgerEntry) -> Result<()> {
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("Failed to open {}", self.path.display()))?;
+        writeln!(file, "{}", entry.to_json())
+            .and_then(|_| file.sync_data())
+            .with_context(|| format!("Failed to write {}", self.path.display()))?;
+        self.entries.insert(entry.sha256.clone(), entry);
+        Ok(())
+    }
+}
+
+fn truncate(path: &Path, len: u64) -> Result<()> {
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(path)
+        .and_then(|file| file.set_len(len))
+        .with_context(|| format!("Failed to truncate {}", path.display()))
+}
+
+/// SHA-256 of the contents of `path`, in hex
+pub fn hash_file(path: &Path) -> Result<String> {
+    let mut file =
+        std::fs::File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = file
+            .read(&mut buffer)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(sha256: &str, acked_rows: u64, complete: bool) -> LedgerEntry {
+        LedgerEntry {
+            sha256: sha256.to_string(),
+            file: "orders.csv".to_string(),
+            at: "2024-09-25T17:15:36Z".to_string(),
+            acked_rows,
+            complete,
+        }
+    }
+
+    #[test]
+    fn test_last_entry_for_a_hash_wins_after_reopening() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ledger.jsonl");
+
+        let mut ledger = Ledger::open(&path).unwrap();
+        assert_eq!(None, ledger.get("abc"));
+        ledger.record(entry("abc", 2, false)).unwrap();
+        ledger.record(entry("def", 5, true)).unwrap();
+        ledger.record(entry("abc", 7, true)).unwrap();
+
+        let ledger = Ledger::open(&path).unwrap();
+        assert_eq!(Some(&entry("abc", 7, true)), ledger.get("abc"));
+        assert_eq!(Some(&entry("def", 5, true)), ledger.get("def"));
+    }
+
+    #[test]
+    fn test_partial_last_line_is_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ledger.jsonl");
+        let mut ledger = Ledger::open(&path).unwrap();
+        ledger.record(entry("abc", 7, true)).unwrap();
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        write!(file, "{{\"sha256\": \"def\", \"fi").unwrap();
+
+        let mut ledger = Ledger::open(&path).unwrap();
+        assert!(ledger.get("abc").is_some());
+        assert_eq!(None, ledger.get("def"));
+
+        ledger.record(entry("def", 1, false)).unwrap();
+        let ledger = Ledger::open(&path).unwrap();
+        assert_eq!(Some(&entry("def", 1, false)), ledger.get("def"));
+    }
+
+    #[test]
+    fn test_hash_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("empty.csv");
+        std::fs::write(&path, "").unwrap();
+
+        assert_eq!(
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            hash_file(&path).unwrap()
+        );
+    }
+}
@@ -0,0 +1,4 @@
+pub mod folder;
+pub mod ledger;
+pub mod stability;
+pub mod watcher;
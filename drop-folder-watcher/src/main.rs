@@ -0,0 +1,176 @@
+use anyhow::{bail, Context, Result};
+use bulk_loader::backfill::SinkFactory;
+use bulk_loader::rate::RateLimiter;
+use clap::Parser;
+use databricks_zerobus_ingest_sdk::{
+    StreamConfigurationOptions, TableProperties, ZerobusSdk, ZerobusStream,
+};
+use drop_folder_watcher::folder::DropFolder;
+use drop_folder_watcher::watcher::{WatchOptions, Watcher};
+use prost_types::DescriptorProto;
+use std::path::PathBuf;
+use std::time::Duration;
+use tracing::info;
+use zerobus_common::descriptor::find_message_descriptor;
+use zerobus_common::dynamic::{coerce_from_env, DynamicEncoder, FieldErrorMode, KeyCase};
+use zerobus_common::shutdown;
+
+/// Load CSV and JSONL files dropped into a folder into a Unity Catalog table
+///
+/// A file is loaded once it has stopped changing for --settle-secs, then moved to
+/// processed/ or, with a .error.txt file explaining why, to failed/. A ledger of the
+/// hashes of loaded files keeps the same file from being loaded twice.
+#[derive(Parser, Debug)]
+#[command(name = "zb-drop-folder", version)]
+struct Args {
+    /// Folder to watch
+    #[arg(long)]
+    dir: PathBuf,
+
+    /// Target table, e.g. main.bronze.orders
+    #[arg(long)]
+    table: String,
+
+    /// Descriptor set and message to encode rows with, as <path>#<message>
+    #[arg(long)]
+    descriptor: String,
+
+    /// Seconds a file's size and modification time must stay the same before it is
+    /// loaded; longer than the longest pause of the slowest writer
+    #[arg(long, default_value_t = 5)]
+    settle_secs: u64,
+
+    /// Seconds between listings of the folder
+    #[arg(long, default_value_t = 2)]
+    poll_interval_secs: u64,
+
+    /// Files loaded at the same time, one stream each
+    #[arg(long, default_value_t = 4)]
+    concurrency: usize,
+
+    /// Maximum rows per second across all files
+    #[arg(long)]
+    rate: Option<u32>,
+
+    /// Unacknowledged rows per stream
+    #[arg(long, default_value_t = 1000)]
+    max_inflight: usize,
+
+    /// Drop columns the table does not have instead of failing the row
+    #[arg(long)]
+    ignore_unknown_fields: bool,
+
+    /// With --ignore-unknown-fields, log every row whose extra columns are dropped
+    #[arg(long)]
+    warn_unknown_fields: bool,
+}
+
+/// Opens a Zerobus stream to the target table for each file
+struct StreamFactory {
+    sdk: ZerobusSdk,
+    table: String,
+    descriptor_proto: DescriptorProto,
+    max_inflight: usize,
+    client_id: String,
+    client_secret: String,
+}
+
+impl SinkFactory for StreamFactory {
+    type Sink = ZerobusStream;
+
+    async fn open(&self) -> Result<ZerobusStream> {
+        let table_properties = TableProperties {
+            table_name: self.table.clone(),
+            descriptor_proto: self.descriptor_proto.clone(),
+        };
+        let stream_options = StreamConfigurationOptions {
+            max_inflight_records: self.max_inflight,
+            ..Default::default()
+        };
+        self.sdk
+            .create_stream(
+                table_properties,
+                self.client_id.clone(),
+                self.client_secret.clone(),
+                Some(stream_options),
+            )
+            .await
+            .context("Failed to create stream")
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .with_target(false)
+        .init();
+
+    let args = Args::parse();
+
+    let zerobus_endpoint = std::env::var("ZEROBUS_ENDPOINT")
+        .context("ZEROBUS_ENDPOINT environment variable must be set")?;
+    let databricks_host = std::env::var("DATABRICKS_HOST")
+        .context("DATABRICKS_HOST environment variable must be set")?;
+    let client_id = std::env::var("DATABRICKS_CLIENT_ID")
+        .context("DATABRICKS_CLIENT_ID environment variable must be set")?;
+    let client_secret = std::env::var("DATABRICKS_CLIENT_SECRET")
+        .context("DATABRICKS_CLIENT_SECRET environment variable must be set")?;
+
+    let Some((descriptor_path, message_name)) = args.descriptor.rsplit_once('#') else {
+        bail!(
+            "--descriptor must be <path>#<message>, got {:?}",
+            args.descriptor
+        );
+    };
+    let descriptor_bytes = std::fs::read(descriptor_path)
+        .with_context(|| format!("Failed to read descriptor {}", descriptor_path))?;
+    let descriptor_proto = find_message_descriptor(&descriptor_bytes, message_name)?;
+    let encoder = DynamicEncoder::new(&descriptor_proto)?
+        .ignore_unknown_fields(args.ignore_unknown_fields)
+        .warn_unknown_fields(args.warn_unknown_fields)
+        .coerce_types(coerce_from_env()?)
+        .field_error_mode(FieldErrorMode::from_env()?)
+        .key_case(KeyCase::from_env()?);
+
+    let folder = DropFolder::new(&args.dir)?;
+    let factory = StreamFactory {
+        sdk: ZerobusSdk::new(zerobus_endpoint, databricks_host)?,
+        table: args.table.clone(),
+        descriptor_proto,
+        max_inflight: args.max_inflight.max(1),
+        client_id,
+        client_secret,
+    };
+    let options = WatchOptions {
+        concurrency: args.concurrency.max(1),
+        max_inflight: args.max_inflight.max(1),
+        settle: Duration::from_secs(args.settle_secs),
+        poll_interval: Duration::from_secs(args.poll_interval_secs.max(1)),
+    };
+    let limiter = args.rate.map(RateLimiter::new);
+    let watcher = Watcher::new(folder, factory, encoder, options, limiter)?;
+
+    info!(
+        "Watching {} for files to load into {}",
+        args.dir.display(),
+        args.table
+    );
+    watcher.run(shutdown::signal()).await?;
+
+    let encoder = watcher.encoder();
+    info!("Shut down");
+    if encoder.records_with_unknown_fields() > 0 {
+        info!(
+            "{} rows had columns the table does not have; those columns were dropped",
+            encoder.records_with_unknown_fields()
+        );
+    }
+    if encoder.fields_left_unset() > 0 {
+        info!(
+            "{} values could not be converted and were left unset",
+            encoder.fields_left_unset()
+        );
+    }
+    Ok(())
+}
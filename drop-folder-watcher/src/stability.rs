@@ -0,0 +1,135 @@
+//! Deciding when a dropped file has been completely written
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Size and modification time of a file when it was last seen to change
+struct Observation {
+    len: u64,
+    modified: Option<SystemTime>,
+    since: Instant,
+}
+
+/// Tracks files in the drop folder until they stop growing
+///
+/// Nothing tells a watcher that a writer has finished a file, so a file is treated as
+/// complete once neither its size nor its modification time has changed for the settle
+/// time. Writers that pause for longer than that must write under a temporary name and
+/// rename the file when done.
+pub struct StabilityTracker {
+    settle: Duration,
+    seen: HashMap<PathBuf, Observation>,
+}
+
+impl StabilityTracker {
+    pub fn new(settle: Duration) -> Self {
+        Self {
+            settle,
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Record the size and modification time of `path` at `now`
+    ///
+    /// Returns true once the file has looked the same for the settle time. A file seen
+    /// for the first time is never ready, so it is always seen at least twice.
+    pub fn observe(
+        &mut self,
+        path: &Path,
+        len: u64,
+        modified: Option<SystemTime>,
+        now: Instant,
+    ) -> bool {
+        match self.seen.get_mut(path) {
+            Some(seen) if seen.len == len && seen.modified == modified => {
+                now.saturating_duration_since(seen.since) >= self.settle
+            }
+            Some(seen) => {
+                *seen = Observation {
+                    len,
+                    modified,
+                    since: now,
+                };
+                false
+            }
+            None => {
+                self.seen.insert(
+                    path.to_path_buf(),
+                    Observation {
+                        len,
+                        modified,
+                        since: now,
+                    },
+                );
+                false
+            }
+        }
+    }
+
+    /// Stop tracking `path`, once it was claimed or has disappeared
+    pub fn forget(&mut self, path: &Path) {
+        self.seen.remove(path);
+    }
+
+    /// Stop tracking every file `present` returns false for
+    pub fn retain(&mut self, mut present: impl FnMut(&Path) -> bool) {
+        self.seen.retain(|path, _| present(path));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SETTLE: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn test_ready_after_settle_time_unchanged() {
+        let mut tracker = StabilityTracker::new(SETTLE);
+        let path = Path::new("drop/orders.csv");
+        let start = Instant::now();
+
+        assert!(!tracker.observe(path, 100, None, start));
+        assert!(!tracker.observe(path, 100, None, start + Duration::from_secs(4)));
+        assert!(tracker.observe(path, 100, None, start + Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn test_growth_restarts_settle_time() {
+        let mut tracker = StabilityTracker::new(SETTLE);
+        let path = Path::new("drop/orders.csv");
+        let start = Instant::now();
+
+        assert!(!tracker.observe(path, 100, None, start));
+        assert!(!tracker.observe(path, 200, None, start + Duration::from_secs(4)));
+        assert!(!tracker.observe(path, 200, None, start + Duration::from_secs(8)));
+        assert!(tracker.observe(path, 200, None, start + Duration::from_secs(9)));
+    }
+
+    #[test]
+    fn test_rewrite_with_same_size_restarts_settle_time() {
+        let mut tracker = StabilityTracker::new(SETTLE);
+        let path = Path::new("drop/orders.csv");
+        let start = Instant::now();
+        let before = SystemTime::UNIX_EPOCH;
+        let after = before + Duration::from_secs(1);
+
+        assert!(!tracker.observe(path, 100, Some(before), start));
+        assert!(!tracker.observe(path, 100, Some(after), start + SETTLE));
+        assert!(tracker.observe(path, 100, Some(after), start + SETTLE * 2));
+    }
+
+    #[test]
+    fn test_zero_settle_time_still_needs_two_observations() {
+        let mut tracker = StabilityTracker::new(Duration::ZERO);
+        let path = Path::new("drop/orders.csv");
+        let now = Instant::now();
+
+        assert!(!tracker.observe(path, 100, None, now));
+        assert!(tracker.observe(path, 100, None, now));
+
+        tracker.forget(path);
+        assert!(!tracker.observe(path, 100, None, now));
+    }
+}
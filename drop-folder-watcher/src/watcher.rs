@@ -0,0 +1,651 @@
+//! Claiming files once they settle and loading them a few at a time
+
+use anyhow::{Context, Result};
+use bulk_loader::backfill::SinkFactory;
+use bulk_loader::load::{load_file, FileSummary, LoadOptions};
+use bulk_loader::progress::{self, Progress};
+use bulk_loader::rate::RateLimiter;
+use bulk_loader::reader::Format;
+use chrono::{SecondsFormat, Utc};
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use std::collections::{HashSet, VecDeque};
+use std::fmt::Write;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, error, info, warn};
+use zerobus_common::dynamic::DynamicEncoder;
+use zerobus_common::pipeline::IngestSink;
+
+use crate::folder::{format_of, Destination, DropFolder};
+use crate::ledger::{hash_file, Ledger, LedgerEntry};
+use crate::stability::StabilityTracker;
+
+pub struct WatchOptions {
+    /// Files loaded at the same time, one stream each
+    pub concurrency: usize,
+    /// Unacknowledged rows per stream
+    pub max_inflight: usize,
+    /// How long a file must stay unchanged before it is loaded
+    pub settle: Duration,
+    /// How often the drop folder is listed
+    pub poll_interval: Duration,
+}
+
+/// What happened to one dropped file
+#[derive(Debug)]
+pub struct Outcome {
+    /// Where the file was moved
+    pub moved_to: PathBuf,
+    pub summary: FileSummary,
+    /// The earlier load of the same contents, if this file was not loaded because of it
+    pub duplicate_of: Option<LedgerEntry>,
+}
+
+impl Outcome {
+    pub fn destination(&self) -> Destination {
+        if self.duplicate_of.is_some() || self.summary.failed() {
+            Destination::Failed
+        } else {
+            Destination::Processed
+        }
+    }
+}
+
+/// The ledger, and the hashes of the files being loaded right now
+struct LedgerState {
+    ledger: Ledger,
+    loading: HashSet<String>,
+}
+
+pub struct Watcher<F> {
+    folder: DropFolder,
+    factory: F,
+    encoder: DynamicEncoder,
+    options: WatchOptions,
+    limiter: Option<RateLimiter>,
+    ledger: Mutex<LedgerState>,
+    /// Woken whenever a file is done, for files waiting on one with the same contents
+    finished: Notify,
+}
+
+impl<F: SinkFactory> Watcher<F> {
+    pub fn new(
+        folder: DropFolder,
+        factory: F,
+        encoder: DynamicEncoder,
+        options: WatchOptions,
+        limiter: Option<RateLimiter>,
+    ) -> Result<Self> {
+        let ledger = Ledger::open(&folder.ledger_path())?;
+        Ok(Self {
+            folder,
+            factory,
+            encoder,
+            options,
+            limiter,
+            ledger: Mutex::new(LedgerState {
+                ledger,
+                loading: HashSet::new(),
+            }),
+            finished: Notify::new(),
+        })
+    }
+
+    pub fn folder(&self) -> &DropFolder {
+        &self.folder
+    }
+
+    pub fn encoder(&self) -> &DynamicEncoder {
+        &self.encoder
+    }
+
+    /// Watch the folder until `shutdown` resolves
+    ///
+    /// Files left in the processing folder by an earlier run are loaded first. On
+    /// shutdown no more files are claimed, and the files being loaded are finished.
+    pub async fn run(&self, shutdown: impl Future<Output = ()>) -> Result<()> {
+        let concurrency = self.options.concurrency.max(1);
+        let mut tracker = StabilityTracker::new(self.options.settle);
+        let mut waiting: VecDeque<PathBuf> = self.folder.unfinished()?.into();
+        if !waiting.is_empty() {
+            info!(
+                "Resuming {} files left in {}",
+                waiting.len(),
+                self.folder.processing_dir().display()
+            );
+        }
+
+        let mut loading = FuturesUnordered::new();
+        let mut ticker = tokio::time::interval(self.options.poll_interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        tokio::pin!(shutdown);
+        loop {
+            while loading.len() < concurrency {
+                let Some(path) = waiting.pop_front() else {
+                    break;
+                };
+                loading.push(async move {
+                    let outcome = self.process(&path).await;
+                    (path, outcome)
+                });
+            }
+
+            tokio::select! {
+                _ = &mut shutdown => break,
+                Some((path, outcome)) = loading.next() => log_outcome(&path, outcome),
+                _ = ticker.tick() => {
+                    // Files are only claimed when they can start, so the rest stay in
+                    // the drop folder where they can still be removed
+                    let room = concurrency.saturating_sub(loading.len() + waiting.len());
+                    match self.claim_ready(&mut tracker, Instant::now(), room) {
+                        Ok(claimed) => waiting.extend(claimed),
+                        Err(e) => warn!("Failed to scan {}: {:#}", self.folder.root().display(), e),
+                    }
+                }
+            }
+        }
+
+        if !loading.is_empty() {
+            info!("Finishing {} files being loaded", loading.len());
+        }
+        while let Some((path, outcome)) = loading.next().await {
+            log_outcome(&path, outcome);
+        }
+        Ok(())
+    }
+
+    /// Observe the files in the drop folder at `now`, and claim up to `limit` of those
+    /// that have stopped changing
+    pub fn claim_ready(
+        &self,
+        tracker: &mut StabilityTracker,
+        now: Instant,
+        limit: usize,
+    ) -> Result<Vec<PathBuf>> {
+        let candidates = self.folder.scan()?;
+        let present: HashSet<&Path> = candidates.iter().map(|c| c.path.as_path()).collect();
+        tracker.retain(|path| present.contains(path));
+
+        let mut claimed = Vec::new();
+        for candidate in &candidates {
+            let settled = tracker.observe(&candidate.path, candidate.len, candidate.modified, now);
+            if !settled || claimed.len() >= limit {
+                continue;
+            }
+            match self.folder.claim(&candidate.path)? {
+                Some(path) => {
+                    tracker.forget(&candidate.path);
+                    claimed.push(path);
+                }
+                None => debug!("{} is still in use", candidate.path.display()),
+            }
+        }
+        Ok(claimed)
+    }
+
+    /// Load a claimed file and move it to processed/ or failed/
+    ///
+    /// A file whose contents the ledger records as loaded is not loaded again. A file
+    /// the ledger records as partly loaded continues after the rows already loaded.
+    /// An error means the file could not be moved or recorded and was left where it is.
+    pub async fn process(&self, claimed: &Path) -> Result<Outcome> {
+        let name = claimed
+            .file_name()
+            .with_context(|| format!("{} has no file name", claimed.display()))?
+            .to_string_lossy()
+            .into_owned();
+        let Some(format) = format_of(claimed) else {
+            let mut summary = FileSummary::new(claimed);
+            summary.error =
+                Some("Unsupported file type; drop .csv, .jsonl, or .json files".to_string());
+            return self.finish(claimed, &name, summary, None);
+        };
+
+        let sha256 = hash_file(claimed)?;
+        let previous = self.start_loading(&sha256).await;
+        let outcome = self.load(claimed, &name, format, &sha256, previous).await;
+        self.ledger.lock().unwrap().loading.remove(&sha256);
+        self.finished.notify_waiters();
+        outcome
+    }
+
+    /// Wait until no other file with the same contents is being loaded, then return
+    /// what the ledger knows about them
+    async fn start_loading(&self, sha256: &str) -> Option<LedgerEntry> {
+        loop {
+            let notified = self.finished.notified();
+            let started = {
+                let mut state = self.ledger.lock().unwrap();
+                state
+                    .loading
+                    .insert(sha256.to_string())
+                    .then(|| state.ledger.get(sha256).cloned())
+            };
+            match started {
+                Some(previous) => return previous,
+                None => notified.await,
+            }
+        }
+    }
+
+    async fn load(
+        &self,
+        claimed: &Path,
+        name: &str,
+        format: Format,
+        sha256: &str,
+        previous: Option<LedgerEntry>,
+    ) -> Result<Outcome> {
+        // A sidecar means an earlier run had already started on this very file
+        let resuming = progress::sidecar_path(claimed).exists();
+        if let Some(previous) = previous.filter(|_| !resuming) {
+            if previous.complete {
+                return self.finish(claimed, name, FileSummary::new(claimed), Some(previous));
+            }
+            let progress = Progress {
+                acked_rows: previous.acked_rows,
+                complete: false,
+            };
+            progress::save(claimed, &progress)?;
+        }
+
+        let options = LoadOptions {
+            format,
+            max_inflight: self.options.max_inflight.max(1),
+        };
+        let summary = match self.factory.open().await {
+            Ok(mut sink) => {
+                let mut summary = load_file(
+                    claimed,
+                    &self.encoder,
+                    &mut sink,
+                    &options,
+                    self.limiter.as_ref(),
+                )
+                .await;
+                if let Err(e) = sink.close().await {
+                    summary.error.get_or_insert(format!("{:#}", e));
+                }
+                summary
+            }
+            Err(e) => {
+                let mut summary = FileSummary::new(claimed);
+                summary.error = Some(format!("{:#}", e));
+                summary
+            }
+        };
+
+        let progress = progress::load(claimed)?;
+        self.ledger.lock().unwrap().ledger.record(LedgerEntry {
+            sha256: sha256.to_string(),
+            file: name.to_string(),
+            at: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            acked_rows: progress.acked_rows,
+            complete: progress.complete,
+        })?;
+        self.finish(claimed, name, summary, None)
+    }
+
+    fn finish(
+        &self,
+        claimed: &Path,
+        name: &str,
+        summary: FileSummary,
+        duplicate_of: Option<LedgerEntry>,
+    ) -> Result<Outcome> {
+        let mut outcome = Outcome {
+            moved_to: PathBuf::new(),
+            summary,
+            duplicate_of,
+        };
+        let destination = outcome.destination();
+        outcome.moved_to = self.folder.finish(claimed, destination)?;
+        if destination == Destination::Failed {
+            self.folder
+                .write_error(&outcome.moved_to, &error_report(name, &outcome))?;
+        }
+        Ok(outcome)
+    }
+}
+
+/// The text of the sidecar next to a failed file, for whoever dropped it
+fn error_report(name: &str, outcome: &Outcome) -> String {
+    let mut report = String::new();
+    if let Some(original) = &outcome.duplicate_of {
+        let _ = writeln!(
+            report,
+            "{} was not loaded: a file with the same contents, {}, was already loaded at {}.",
+            name, original.file, original.at
+        );
+        return report;
+    }
+
+    let summary = &outcome.summary;
+    let _ = writeln!(report, "{} was not fully loaded.", name);
+    let _ = writeln!(report);
+    if let Some(error) = &summary.error {
+        let _ = writeln!(report, "Error: {}", error);
+    }
+    let _ = writeln!(report, "Rows loaded: {}", summary.rows + summary.skipped);
+    if !summary.failures.is_empty() {
+        let _ = writeln!(report, "Rows skipped because they could not be read:");
+        for failure in &summary.failures {
+            let _ = writeln!(report, "  line {}: {}", failure.line, failure.error);
+        }
+    }
+    let _ = writeln!(report);
+    if summary.error.is_some() {
+        let _ = writeln!(
+            report,
+            "To retry, move the file back into the drop folder unchanged; the rows already loaded are skipped."
+        );
+    } else {
+        let _ = writeln!(
+            report,
+            "Every other row was loaded. To load the skipped rows, drop a file with only those rows, corrected."
+        );
+    }
+    report
+}
+
+fn log_outcome(claimed: &Path, outcome: Result<Outcome>) {
+    let outcome = match outcome {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            error!(
+                "{}: {:#}; it is retried when the watcher restarts",
+                claimed.display(),
+                e
+            );
+            return;
+        }
+    };
+    let moved_to = outcome.moved_to.display();
+    let summary = &outcome.summary;
+    if let Some(original) = &outcome.duplicate_of {
+        warn!(
+            "{}: same contents as {}, loaded at {}; not loaded again",
+            moved_to, original.file, original.at
+        );
+    } else if let Some(error) = &summary.error {
+        warn!(
+            "{}: {} rows loaded, {} malformed, then stopped: {}",
+            moved_to,
+            summary.rows,
+            summary.failures.len(),
+            error
+        );
+    } else if !summary.failures.is_empty() {
+        warn!(
+            "{}: {} rows loaded, {} malformed",
+            moved_to,
+            summary.rows,
+            summary.failures.len()
+        );
+    } else {
+        info!("{}: {} rows loaded", moved_to, summary.rows);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::folder::{error_path, FAILED_DIR, PROCESSED_DIR};
+    use prost::Message;
+    use prost_types::field_descriptor_proto::{Label, Type};
+    use prost_types::{DescriptorProto, FieldDescriptorProto};
+    use std::fs::OpenOptions;
+    use std::io::Write as _;
+    use zerobus_common::testing::MockSink;
+
+    #[derive(Clone, PartialEq, Message)]
+    struct TestRow {
+        #[prost(int64, optional, tag = "1")]
+        id: Option<i64>,
+    }
+
+    struct MockFactory(MockSink);
+
+    impl SinkFactory for MockFactory {
+        type Sink = MockSink;
+
+        async fn open(&self) -> Result<MockSink> {
+            Ok(self.0.clone())
+        }
+    }
+
+    fn encoder() -> DynamicEncoder {
+        let descriptor = DescriptorProto {
+            name: Some("order".to_string()),
+            field: vec![FieldDescriptorProto {
+                name: Some("id".to_string()),
+                number: Some(1),
+                label: Some(Label::Optional as i32),
+                r#type: Some(Type::Int64 as i32),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        DynamicEncoder::new(&descriptor).unwrap()
+    }
+
+    fn watcher(root: &Path, sink: &MockSink, settle: Duration) -> Watcher<MockFactory> {
+        let options = WatchOptions {
+            concurrency: 2,
+            max_inflight: 2,
+            settle,
+            poll_interval: Duration::from_secs(1),
+        };
+        Watcher::new(
+            DropFolder::new(root).unwrap(),
+            MockFactory(sink.clone()),
+            encoder(),
+            options,
+            None,
+        )
+        .unwrap()
+    }
+
+    fn ids(sink: &MockSink) -> Vec<i64> {
+        sink.records()
+            .iter()
+            .map(|record| TestRow::decode(record.as_slice()).unwrap().id.unwrap())
+            .collect()
+    }
+
+    /// Observe the folder twice, so files settle with a zero settle time
+    fn claim_all(watcher: &Watcher<MockFactory>) -> Vec<PathBuf> {
+        let mut tracker = StabilityTracker::new(Duration::ZERO);
+        let now = Instant::now();
+        watcher.claim_ready(&mut tracker, now, usize::MAX).unwrap();
+        watcher.claim_ready(&mut tracker, now, usize::MAX).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_slow_writer_is_claimed_once_file_stops_growing() {
+        let dir = tempfile::tempdir().unwrap();
+        let sink = MockSink::default();
+        let watcher = watcher(dir.path(), &sink, Duration::from_secs(5));
+        let path = dir.path().join("orders.csv");
+        let mut tracker = StabilityTracker::new(Duration::from_secs(5));
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        std::fs::write(&path, "id\n1\n").unwrap();
+        assert!(watcher
+            .claim_ready(&mut tracker, at(0), 4)
+            .unwrap()
+            .is_empty());
+        // The writer pauses, then appends more rows
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"2\n3\n")
+            .unwrap();
+        assert!(watcher
+            .claim_ready(&mut tracker, at(4), 4)
+            .unwrap()
+            .is_empty());
+        assert!(watcher
+            .claim_ready(&mut tracker, at(8), 4)
+            .unwrap()
+            .is_empty());
+        assert!(path.exists());
+
+        let claimed = watcher.claim_ready(&mut tracker, at(9), 4).unwrap();
+        assert_eq!(1, claimed.len());
+        assert!(!path.exists());
+
+        let outcome = watcher.process(&claimed[0]).await.unwrap();
+        assert_eq!(Destination::Processed, outcome.destination());
+        assert_eq!(
+            dir.path().join(PROCESSED_DIR).join("orders.csv"),
+            outcome.moved_to
+        );
+        assert_eq!(vec![1, 2, 3], ids(&sink));
+        assert!(watcher.folder().unfinished().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_claim_limit_leaves_files_in_the_drop_folder() {
+        let dir = tempfile::tempdir().unwrap();
+        let watcher = watcher(dir.path(), &MockSink::default(), Duration::ZERO);
+        for name in ["a.csv", "b.csv", "c.csv"] {
+            std::fs::write(dir.path().join(name), "id\n1\n").unwrap();
+        }
+        let mut tracker = StabilityTracker::new(Duration::ZERO);
+        let now = Instant::now();
+
+        watcher.claim_ready(&mut tracker, now, 2).unwrap();
+        let claimed = watcher.claim_ready(&mut tracker, now, 2).unwrap();
+
+        assert_eq!(2, claimed.len());
+        assert!(dir.path().join("c.csv").exists());
+        let claimed = watcher.claim_ready(&mut tracker, now, 2).unwrap();
+        assert_eq!(1, claimed.len());
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_drop_is_loaded_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let sink = MockSink::default();
+        let watcher = watcher(dir.path(), &sink, Duration::ZERO);
+
+        std::fs::write(dir.path().join("orders.csv"), "id\n1\n2\n").unwrap();
+        for claimed in claim_all(&watcher) {
+            watcher.process(&claimed).await.unwrap();
+        }
+        // The same export dropped again later, under another name
+        std::fs::write(dir.path().join("orders (copy).csv"), "id\n1\n2\n").unwrap();
+        let claimed = claim_all(&watcher);
+        let outcome = watcher.process(&claimed[0]).await.unwrap();
+
+        assert_eq!(vec![1, 2], ids(&sink));
+        assert_eq!(Destination::Failed, outcome.destination());
+        assert_eq!("orders.csv", outcome.duplicate_of.unwrap().file);
+        let report = std::fs::read_to_string(error_path(&outcome.moved_to)).unwrap();
+        assert!(report.contains("same contents, orders.csv"), "{}", report);
+    }
+
+    #[tokio::test]
+    async fn test_duplicates_dropped_together_are_loaded_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let sink = MockSink::default();
+        let watcher = watcher(dir.path(), &sink, Duration::ZERO);
+        std::fs::write(dir.path().join("a.csv"), "id\n1\n2\n").unwrap();
+        std::fs::write(dir.path().join("b.csv"), "id\n1\n2\n").unwrap();
+
+        let claimed = claim_all(&watcher);
+        let (a, b) = tokio::join!(watcher.process(&claimed[0]), watcher.process(&claimed[1]));
+
+        assert_eq!(vec![1, 2], ids(&sink));
+        let duplicates = [a.unwrap(), b.unwrap()]
+            .iter()
+            .filter(|outcome| outcome.duplicate_of.is_some())
+            .count();
+        assert_eq!(1, duplicates);
+    }
+
+    #[tokio::test]
+    async fn test_malformed_rows_move_file_to_failed_with_sidecar() {
+        let dir = tempfile::tempdir().unwrap();
+        let sink = MockSink::default();
+        let watcher = watcher(dir.path(), &sink, Duration::ZERO);
+        std::fs::write(dir.path().join("orders.csv"), "id\n1\nnot a number\n3\n").unwrap();
+        std::fs::write(dir.path().join("orders.xlsx"), "PK").unwrap();
+
+        let mut outcomes = Vec::new();
+        for claimed in claim_all(&watcher) {
+            outcomes.push(watcher.process(&claimed).await.unwrap());
+        }
+
+        assert_eq!(vec![1, 3], ids(&sink));
+        let failed = dir.path().join(FAILED_DIR);
+        assert_eq!(failed.join("orders.csv"), outcomes[0].moved_to);
+        let report = std::fs::read_to_string(failed.join("orders.csv.error.txt")).unwrap();
+        assert!(report.contains("Rows loaded: 2"), "{}", report);
+        assert!(report.contains("line 3:"), "{}", report);
+        assert!(!failed.join("orders.csv.progress").exists());
+
+        assert_eq!(failed.join("orders.xlsx"), outcomes[1].moved_to);
+        let report = std::fs::read_to_string(failed.join("orders.xlsx.error.txt")).unwrap();
+        assert!(report.contains("Unsupported file type"), "{}", report);
+    }
+
+    #[tokio::test]
+    async fn test_failed_file_dropped_again_resumes_after_loaded_rows() {
+        let dir = tempfile::tempdir().unwrap();
+        let failing = MockSink::default()
+            .fail_acks_for(|record| TestRow::decode(record).unwrap().id == Some(3));
+        let contents = "id\n1\n2\n3\n4\n";
+        std::fs::write(dir.path().join("orders.csv"), contents).unwrap();
+
+        let outcome = {
+            let watcher = watcher(dir.path(), &failing, Duration::ZERO);
+            let claimed = claim_all(&watcher);
+            watcher.process(&claimed[0]).await.unwrap()
+        };
+        assert_eq!(Destination::Failed, outcome.destination());
+        assert_eq!(vec![1, 2, 3, 4], ids(&failing));
+
+        // After a restart, the file is moved back into the drop folder unchanged
+        std::fs::rename(&outcome.moved_to, dir.path().join("orders.csv")).unwrap();
+        let sink = MockSink::default();
+        let watcher = watcher(dir.path(), &sink, Duration::ZERO);
+        let claimed = claim_all(&watcher);
+        let outcome = watcher.process(&claimed[0]).await.unwrap();
+
+        assert_eq!(Destination::Processed, outcome.destination());
+        assert_eq!(2, outcome.summary.skipped);
+        assert_eq!(vec![3, 4], ids(&sink));
+    }
+
+    #[tokio::test]
+    async fn test_run_resumes_unfinished_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let sink = MockSink::default();
+        let watcher = watcher(dir.path(), &sink, Duration::ZERO);
+        // An earlier run stopped after loading the first row
+        let claimed = watcher.folder().processing_dir().join("orders.jsonl");
+        std::fs::write(&claimed, "{\"id\": 1}\n{\"id\": 2}\n").unwrap();
+        progress::save(
+            &claimed,
+            &Progress {
+                acked_rows: 1,
+                complete: false,
+            },
+        )
+        .unwrap();
+
+        watcher.run(std::future::ready(())).await.unwrap();
+
+        assert_eq!(vec![2], ids(&sink));
+        assert!(dir.path().join(PROCESSED_DIR).join("orders.jsonl").exists());
+    }
+}
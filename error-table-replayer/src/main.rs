@@ -0,0 +1,208 @@
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use databricks_zerobus_ingest_sdk::{
+    StreamConfigurationOptions, TableProperties, ZerobusSdk, ZerobusStream,
+};
+use error_table_replayer::record::{source_files, Fields};
+use error_table_replayer::replay::{ReplaySummary, Replayer, SinkFactory};
+use prost_types::DescriptorProto;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::PathBuf;
+use tracing::info;
+
+/// Re-ingest records that failed ingestion, as exported from an error table
+///
+/// Each line of the source is a JSON object naming the target table and holding the
+/// encoded record as base64. Records are sent to their tables as they are, so they
+/// are never re-encoded.
+#[derive(Parser, Debug)]
+#[command(name = "zb-replay", version)]
+struct Args {
+    /// Export file, or directory of .json/.jsonl files such as Spark writes
+    #[arg(long)]
+    source: PathBuf,
+
+    /// Descriptor set holding a table_<name> message per target table
+    #[arg(long, required_unless_present = "dry_run")]
+    descriptor_set: Option<PathBuf>,
+
+    /// Only replay records for this table; may be repeated
+    #[arg(long = "table")]
+    tables: Vec<String>,
+
+    /// Field naming the target table
+    #[arg(long, default_value = "table_name")]
+    table_field: String,
+
+    /// Field holding the encoded record as base64
+    #[arg(long, default_value = "record")]
+    record_field: String,
+
+    /// Field holding why the record failed
+    #[arg(long, default_value = "error")]
+    error_field: String,
+
+    /// Read and count the records without ingesting them
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Write records that fail again to this file, in the same format
+    #[arg(long)]
+    failed_output: Option<PathBuf>,
+
+    /// Unacknowledged records per stream
+    #[arg(long, default_value_t = 1000)]
+    max_inflight: usize,
+}
+
+fn env(name: &str) -> Result<String> {
+    std::env::var(name).with_context(|| format!("{} environment variable must be set", name))
+}
+
+/// Opens a Zerobus stream per target table
+struct StreamFactory {
+    sdk: ZerobusSdk,
+    client_id: String,
+    client_secret: String,
+    max_inflight: usize,
+}
+
+impl SinkFactory for StreamFactory {
+    type Sink = ZerobusStream;
+
+    async fn open(&self, table: &str, descriptor: DescriptorProto) -> Result<ZerobusStream> {
+        let table_properties = TableProperties {
+            table_name: table.to_string(),
+            descriptor_proto: descriptor,
+        };
+        let stream_options = StreamConfigurationOptions {
+            max_inflight_records: self.max_inflight,
+            ..Default::default()
+        };
+        self.sdk
+            .create_stream(
+                table_properties,
+                self.client_id.clone(),
+                self.client_secret.clone(),
+                Some(stream_options),
+            )
+            .await
+            .with_context(|| format!("Failed to create stream to {}", table))
+    }
+}
+
+/// Stands in for the stream factory in a dry run, which opens no streams
+struct DryRun;
+
+impl SinkFactory for DryRun {
+    type Sink = ZerobusStream;
+
+    async fn open(&self, table: &str, _descriptor: DescriptorProto) -> Result<ZerobusStream> {
+        bail!("A dry run does not open a stream to {}", table)
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .with_target(false)
+        .init();
+
+    let args = Args::parse();
+    let fields = Fields {
+        table: args.table_field.clone(),
+        record: args.record_field.clone(),
+        error: args.error_field.clone(),
+    };
+    let files = source_files(&args.source)?;
+    if files.is_empty() {
+        bail!("No export files found in {}", args.source.display());
+    }
+
+    let summary = if args.dry_run {
+        let replayer = Replayer::new(DryRun, Vec::new(), 1).dry_run(true);
+        replay(replayer, &args, &files, &fields).await?
+    } else {
+        let factory = StreamFactory {
+            sdk: ZerobusSdk::new(env("ZEROBUS_ENDPOINT")?, env("DATABRICKS_HOST")?)?,
+            client_id: env("DATABRICKS_CLIENT_ID")?,
+            client_secret: env("DATABRICKS_CLIENT_SECRET")?,
+            max_inflight: args.max_inflight.max(1),
+        };
+        let descriptor_path = args
+            .descriptor_set
+            .as_ref()
+            .expect("clap requires --descriptor-set without --dry-run");
+        let descriptors = std::fs::read(descriptor_path).with_context(|| {
+            format!(
+                "Failed to read descriptor set {}",
+                descriptor_path.display()
+            )
+        })?;
+        let replayer = Replayer::new(factory, descriptors, args.max_inflight.max(1));
+        replay(replayer, &args, &files, &fields).await?
+    };
+
+    print_summary(&summary, args.dry_run);
+    if let Some(path) = &args.failed_output {
+        if !summary.failed_records.is_empty() {
+            let file = std::fs::File::create(path)
+                .with_context(|| format!("Failed to create {}", path.display()))?;
+            let mut writer = BufWriter::new(file);
+            for record in &summary.failed_records {
+                writeln!(writer, "{}", record.to_json(&fields))?;
+            }
+            writer.flush()?;
+            println!(
+                "Wrote {} records that failed again to {}",
+                summary.failed_records.len(),
+                path.display()
+            );
+        }
+    }
+    if summary.failed() > 0 {
+        bail!("{} records failed again", summary.failed());
+    }
+    Ok(())
+}
+
+async fn replay<F: SinkFactory>(
+    mut replayer: Replayer<F>,
+    args: &Args,
+    files: &[PathBuf],
+    fields: &Fields,
+) -> Result<ReplaySummary> {
+    replayer = replayer.only_tables(args.tables.iter().cloned());
+    for path in files {
+        info!("Reading {}", path.display());
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        replayer
+            .replay_lines(&path.display().to_string(), BufReader::new(file), fields)
+            .await?;
+    }
+    replayer.finish().await
+}
+
+fn print_summary(summary: &ReplaySummary, dry_run: bool) {
+    for (table, counts) in &summary.tables {
+        if dry_run {
+            println!("{}: {} records would be replayed", table, counts.records);
+        } else {
+            println!(
+                "{}: {} records, {} replayed, {} failed",
+                table, counts.records, counts.replayed, counts.failed
+            );
+        }
+    }
+    println!(
+        "Total: {} records for {} tables, {} replayed, {} failed, {} skipped for other tables, {} malformed lines",
+        summary.tables.values().map(|counts| counts.records).sum::<u64>(),
+        summary.tables.len(),
+        summary.replayed(),
+        summary.failed(),
+        summary.skipped,
+        summary.malformed,
+    );
+}
@@ -0,0 +1,188 @@
+//! Failed records as they are exported from an error table, one JSON object per line
+
+use anyhow::{bail, Context, Result};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use serde_json::{Map, Value};
+use std::path::{Path, PathBuf};
+
+/// A record that failed ingestion: its encoded bytes and the table it was meant for
+#[derive(Debug, Clone, PartialEq)]
+pub struct FailedRecord {
+    pub table: String,
+    pub record: Vec<u8>,
+    /// Why it failed, if the source recorded it
+    pub error: Option<String>,
+}
+
+/// Names of the fields holding each part of a failed record
+#[derive(Debug, Clone)]
+pub struct Fields {
+    pub table: String,
+    /// Holds the encoded record as base64, which is how Spark writes `BINARY` to JSON
+    pub record: String,
+    pub error: String,
+}
+
+impl Default for Fields {
+    fn default() -> Self {
+        Self {
+            table: "table_name".to_string(),
+            record: "record".to_string(),
+            error: "error".to_string(),
+        }
+    }
+}
+
+impl FailedRecord {
+    /// Parse one line of an export
+    pub fn from_json(line: &str, fields: &Fields) -> Result<Self> {
+        let value: Value = serde_json::from_str(line).context("Not valid JSON")?;
+        let Value::Object(object) = value else {
+            bail!("Not a JSON object");
+        };
+
+        let table = match object.get(&fields.table) {
+            Some(Value::String(table)) if !table.is_empty() => table.clone(),
+            _ => bail!("Missing {:?} field", fields.table),
+        };
+        let record = match object.get(&fields.record) {
+            Some(Value::String(record)) => BASE64_STANDARD
+                .decode(record)
+                .with_context(|| format!("{:?} field is not base64", fields.record))?,
+            _ => bail!("Missing {:?} field", fields.record),
+        };
+        let error = object
+            .get(&fields.error)
+            .and_then(Value::as_str)
+            .map(str::to_string);
+
+        Ok(Self {
+            table,
+            record,
+            error,
+        })
+    }
+
+    /// Format as a line that [`FailedRecord::from_json`] reads back, so records that
+    /// fail again can be replayed later
+    pub fn to_json(&self, fields: &Fields) -> String {
+        let mut object = Map::new();
+        object.insert(fields.table.clone(), Value::from(self.table.as_str()));
+        object.insert(
+            fields.record.clone(),
+            Value::from(BASE64_STANDARD.encode(&self.record)),
+        );
+        if let Some(error) = &self.error {
+            object.insert(fields.error.clone(), Value::from(error.as_str()));
+        }
+        Value::Object(object).to_string()
+    }
+}
+
+/// The files to read for `source`: the file itself, or the JSON files of a directory
+///
+/// A directory written by Spark also holds `_SUCCESS` markers and `.crc` checksums;
+/// only `.json` and `.jsonl` files not starting with `_` or `.` are read, in name order.
+pub fn source_files(source: &Path) -> Result<Vec<PathBuf>> {
+    if !source.is_dir() {
+        if !source.exists() {
+            bail!("{} does not exist", source.display());
+        }
+        return Ok(vec![source.to_path_buf()]);
+    }
+
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(source)
+        .with_context(|| format!("Failed to read directory {}", source.display()))?
+    {
+        let path = entry?.path();
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        let is_json = matches!(
+            path.extension().and_then(|e| e.to_str()),
+            Some("json" | "jsonl")
+        );
+        if path.is_file() && is_json && !name.starts_with(['_', '.']) {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_exported_record() {
+        let line = r#"{"table_name": "main.default.orders", "record": "CAESA2Zvbw==", "error": "stream closed", "failed_at": "2025-01-01T00:00:00Z"}"#;
+
+        let record = FailedRecord::from_json(line, &Fields::default()).unwrap();
+
+        assert_eq!("main.default.orders", record.table);
+        assert_eq!(
+            vec![0x08, 0x01, 0x12, 0x03, b'f', b'o', b'o'],
+            record.record
+        );
+        assert_eq!(Some("stream closed".to_string()), record.error);
+        assert_eq!(
+            record,
+            FailedRecord::from_json(&record.to_json(&Fields::default()), &Fields::default())
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_custom_field_names() {
+        let fields = Fields {
+            table: "target".to_string(),
+            record: "payload".to_string(),
+            error: "reason".to_string(),
+        };
+        let line = r#"{"target": "orders", "payload": ""}"#;
+
+        let record = FailedRecord::from_json(line, &fields).unwrap();
+
+        assert_eq!("orders", record.table);
+        assert!(record.record.is_empty());
+        assert_eq!(None, record.error);
+    }
+
+    #[test]
+    fn test_malformed_records() {
+        let fields = Fields::default();
+        assert!(FailedRecord::from_json("not json", &fields).is_err());
+        assert!(FailedRecord::from_json("[]", &fields).is_err());
+        assert!(FailedRecord::from_json(r#"{"record": "AA=="}"#, &fields).is_err());
+        assert!(FailedRecord::from_json(r#"{"table_name": "t"}"#, &fields).is_err());
+        let not_base64 = r#"{"table_name": "t", "record": "not base64!"}"#;
+        assert!(FailedRecord::from_json(not_base64, &fields).is_err());
+    }
+
+    #[test]
+    fn test_source_files_skip_spark_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in [
+            "part-00001.json",
+            "part-00000.json",
+            "_SUCCESS",
+            ".part-00000.json.crc",
+            "notes.txt",
+        ] {
+            std::fs::write(dir.path().join(name), b"").unwrap();
+        }
+
+        let files = source_files(dir.path()).unwrap();
+
+        assert_eq!(
+            vec![
+                dir.path().join("part-00000.json"),
+                dir.path().join("part-00001.json")
+            ],
+            files
+        );
+        let file = dir.path().join("notes.txt");
+        assert_eq!(vec![file.clone()], source_files(&file).unwrap());
+        assert!(source_files(&dir.path().join("missing")).is_err());
+    }
+}
@@ -0,0 +1,348 @@
+//! Re-ingesting failed records into the tables they were meant for
+
+use anyhow::{Context, Result};
+use prost_types::DescriptorProto;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::Future;
+use std::io::BufRead;
+use std::sync::{Arc, Mutex};
+use tracing::{info, warn};
+use zerobus_common::descriptor::find_message_descriptor;
+use zerobus_common::pipeline::{IngestSink, Pipeline};
+
+use crate::record::{FailedRecord, Fields};
+
+/// Opens a sink to a target table
+///
+/// Implemented over `ZerobusSdk` by the binary; tests hand out in-memory sinks.
+pub trait SinkFactory {
+    type Sink: IngestSink;
+
+    fn open(
+        &self,
+        table: &str,
+        descriptor: DescriptorProto,
+    ) -> impl Future<Output = Result<Self::Sink>> + Send;
+}
+
+/// Name of the message `zerobus-generate` writes for `table`: `table_<name>`
+pub fn message_name(table: &str) -> String {
+    format!("table_{}", table.rsplit('.').next().unwrap_or(table))
+}
+
+/// Records of one target table, by what became of them
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TableCounts {
+    /// Records read for the table
+    pub records: u64,
+    /// Records acknowledged; always 0 in a dry run
+    pub replayed: u64,
+    /// Records that failed again
+    pub failed: u64,
+}
+
+/// Outcome of a replay
+#[derive(Debug, Default)]
+pub struct ReplaySummary {
+    pub tables: BTreeMap<String, TableCounts>,
+    /// Lines that are not failed records
+    pub malformed: u64,
+    /// Records for tables that were not selected
+    pub skipped: u64,
+    /// Records that failed again, to be written out for another replay
+    pub failed_records: Vec<FailedRecord>,
+}
+
+impl ReplaySummary {
+    pub fn replayed(&self) -> u64 {
+        self.tables.values().map(|counts| counts.replayed).sum()
+    }
+
+    pub fn failed(&self) -> u64 {
+        self.tables.values().map(|counts| counts.failed).sum()
+    }
+}
+
+/// Sends failed records back to their tables, one stream per table opened on first use
+///
+/// A dry run only reads and counts the records; no stream is opened.
+pub struct Replayer<F: SinkFactory> {
+    factory: F,
+    /// Encoded `FileDescriptorSet` holding a `table_<name>` message per target table
+    descriptors: Vec<u8>,
+    max_inflight: usize,
+    dry_run: bool,
+    only_tables: Option<HashSet<String>>,
+    pipelines: HashMap<String, Pipeline<F::Sink>>,
+    failed_again: Arc<Mutex<Vec<FailedRecord>>>,
+    summary: ReplaySummary,
+}
+
+impl<F: SinkFactory> Replayer<F> {
+    pub fn new(factory: F, descriptors: Vec<u8>, max_inflight: usize) -> Self {
+        Self {
+            factory,
+            descriptors,
+            max_inflight,
+            dry_run: false,
+            only_tables: None,
+            pipelines: HashMap::new(),
+            failed_again: Arc::new(Mutex::new(Vec::new())),
+            summary: ReplaySummary::default(),
+        }
+    }
+
+    /// Count the records without ingesting them
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Replay only the records of these tables, counting the rest as skipped
+    pub fn only_tables(mut self, tables: impl IntoIterator<Item = String>) -> Self {
+        let tables: HashSet<String> = tables.into_iter().collect();
+        self.only_tables = (!tables.is_empty()).then_some(tables);
+        self
+    }
+
+    /// Replay every line of an export; `source` names it in warnings
+    ///
+    /// Malformed lines are counted and skipped. Errors are only returned when the export
+    /// cannot be read, or a table's stream cannot be opened or written to.
+    pub async fn replay_lines(
+        &mut self,
+        source: &str,
+        reader: impl BufRead,
+        fields: &Fields,
+    ) -> Result<()> {
+        for (index, line) in reader.lines().enumerate() {
+            let line = line.with_context(|| format!("Failed to read {}", source))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match FailedRecord::from_json(&line, fields) {
+                Ok(record) => self.replay(record).await?,
+                Err(e) => {
+                    warn!(
+                        "{}:{}: skipping malformed record: {:#}",
+                        source,
+                        index + 1,
+                        e
+                    );
+                    self.summary.malformed += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Replay one record
+    pub async fn replay(&mut self, record: FailedRecord) -> Result<()> {
+        if let Some(only_tables) = &self.only_tables {
+            if !only_tables.contains(&record.table) {
+                self.summary.skipped += 1;
+                return Ok(());
+            }
+        }
+        self.summary
+            .tables
+            .entry(record.table.clone())
+            .or_default()
+            .records += 1;
+        if self.dry_run {
+            return Ok(());
+        }
+
+        let encoded = record.record.clone();
+        let failed_again = Arc::clone(&self.failed_again);
+        let pipeline = self.pipeline(&record.table).await?;
+        pipeline
+            .ingest_with_callback(encoded, move |result| {
+                if let Err(e) = result {
+                    failed_again.lock().unwrap().push(FailedRecord {
+                        error: Some(format!("{:#}", e)),
+                        ..record
+                    });
+                }
+            })
+            .await
+            .context("Failed to send record")
+    }
+
+    /// Wait for every acknowledgment, close the streams, and return the counts
+    pub async fn finish(mut self) -> Result<ReplaySummary> {
+        for (table, pipeline) in self.pipelines {
+            let summary = pipeline
+                .finish()
+                .await
+                .with_context(|| format!("Failed to finish the stream to {}", table))?;
+            let counts = self.summary.tables.entry(table).or_default();
+            counts.replayed = summary.ingested;
+            counts.failed = summary.failed;
+        }
+        self.summary.failed_records = std::mem::take(&mut *self.failed_again.lock().unwrap());
+        Ok(self.summary)
+    }
+
+    async fn pipeline(&mut self, table: &str) -> Result<&mut Pipeline<F::Sink>> {
+        if !self.pipelines.contains_key(table) {
+            let descriptor = find_message_descriptor(&self.descriptors, &message_name(table))
+                .with_context(|| format!("No descriptor for table {}", table))?;
+            let sink = self.factory.open(table, descriptor).await?;
+            info!("Opened stream to table: {}", table);
+            self.pipelines
+                .insert(table.to_string(), Pipeline::new(sink, self.max_inflight));
+        }
+        Ok(self
+            .pipelines
+            .get_mut(table)
+            .expect("pipeline was just inserted"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost::Message;
+    use prost_types::{FileDescriptorProto, FileDescriptorSet};
+    use std::io::Cursor;
+    use zerobus_common::testing::MockSink;
+
+    /// Hands out one shared mock sink per table
+    #[derive(Clone, Default)]
+    struct MockFactory {
+        sinks: Arc<Mutex<HashMap<String, MockSink>>>,
+    }
+
+    impl MockFactory {
+        fn sink(&self, table: &str) -> Option<MockSink> {
+            self.sinks.lock().unwrap().get(table).cloned()
+        }
+    }
+
+    impl SinkFactory for MockFactory {
+        type Sink = MockSink;
+
+        async fn open(&self, table: &str, _descriptor: DescriptorProto) -> Result<MockSink> {
+            let mut sinks = self.sinks.lock().unwrap();
+            let sink = sinks
+                .entry(table.to_string())
+                .or_insert_with(|| MockSink::default().fail_acks_for(|record| record == b"bad"));
+            Ok(sink.clone())
+        }
+    }
+
+    fn descriptors() -> Vec<u8> {
+        let message = |name: &str| DescriptorProto {
+            name: Some(name.to_string()),
+            ..Default::default()
+        };
+        FileDescriptorSet {
+            file: vec![FileDescriptorProto {
+                name: Some("tables.proto".to_string()),
+                message_type: vec![message("table_orders"), message("table_customers")],
+                ..Default::default()
+            }],
+        }
+        .encode_to_vec()
+    }
+
+    // "b3JkZXItMQ==" is "order-1", "YmFk" is "bad", "Y3VzdG9tZXItMQ==" is "customer-1"
+    const EXPORT: &str = r#"{"table_name": "main.shop.orders", "record": "b3JkZXItMQ==", "error": "stream closed"}
+{"table_name": "main.shop.customers", "record": "Y3VzdG9tZXItMQ=="}
+
+{"table_name": "main.shop.orders", "record": "YmFk"}
+not a record
+{"table_name": "main.shop.orders", "record": "b3JkZXItMQ=="}
+"#;
+
+    async fn replay(replayer: &mut Replayer<MockFactory>) {
+        replayer
+            .replay_lines("export.json", Cursor::new(EXPORT), &Fields::default())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_dry_run_counts_without_ingesting() {
+        let factory = MockFactory::default();
+        let mut replayer = Replayer::new(factory.clone(), descriptors(), 10).dry_run(true);
+
+        replay(&mut replayer).await;
+        let summary = replayer.finish().await.unwrap();
+
+        assert_eq!(3, summary.tables["main.shop.orders"].records);
+        assert_eq!(1, summary.tables["main.shop.customers"].records);
+        assert_eq!(0, summary.replayed());
+        assert_eq!(1, summary.malformed);
+        assert!(factory.sinks.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_records_are_replayed_to_their_tables() {
+        let factory = MockFactory::default();
+        let mut replayer = Replayer::new(factory.clone(), descriptors(), 10);
+
+        replay(&mut replayer).await;
+        let summary = replayer.finish().await.unwrap();
+
+        let orders = factory.sink("main.shop.orders").unwrap();
+        assert_eq!(
+            vec![b"order-1".to_vec(), b"bad".to_vec(), b"order-1".to_vec()],
+            orders.records()
+        );
+        assert!(orders.closed());
+        let customers = factory.sink("main.shop.customers").unwrap();
+        assert_eq!(vec![b"customer-1".to_vec()], customers.records());
+
+        assert_eq!(
+            TableCounts {
+                records: 3,
+                replayed: 2,
+                failed: 1
+            },
+            summary.tables["main.shop.orders"]
+        );
+        assert_eq!(3, summary.replayed());
+        assert_eq!(1, summary.failed_records.len());
+        assert_eq!(b"bad".to_vec(), summary.failed_records[0].record);
+        assert_eq!(
+            Some("mock ack failure".to_string()),
+            summary.failed_records[0].error
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unselected_tables_are_skipped() {
+        let factory = MockFactory::default();
+        let mut replayer = Replayer::new(factory.clone(), descriptors(), 10)
+            .only_tables(["main.shop.customers".to_string()]);
+
+        replay(&mut replayer).await;
+        let summary = replayer.finish().await.unwrap();
+
+        assert_eq!(3, summary.skipped);
+        assert_eq!(1, summary.replayed());
+        assert!(factory.sink("main.shop.orders").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_table_without_descriptor_is_an_error() {
+        let mut replayer = Replayer::new(MockFactory::default(), descriptors(), 10);
+        let record = FailedRecord {
+            table: "main.shop.refunds".to_string(),
+            record: vec![1],
+            error: None,
+        };
+
+        let error = replayer.replay(record).await.unwrap_err();
+
+        assert!(format!("{:#}", error).contains("No descriptor for table main.shop.refunds"));
+    }
+
+    #[test]
+    fn test_message_name() {
+        assert_eq!("table_orders", message_name("main.shop.orders"));
+        assert_eq!("table_orders", message_name("orders"));
+    }
+}
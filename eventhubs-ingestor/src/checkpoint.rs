@@ -0,0 +1,92 @@
+//! The file holding, per partition, the last event whose row was acknowledged
+
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use zerobus_common::durable;
+
+/// The sequence number of the last acknowledged event of each partition, by partition id
+pub type Checkpoints = BTreeMap<String, i64>;
+
+/// One `<partition id> <sequence number>` line per partition
+pub struct CheckpointFile {
+    path: PathBuf,
+}
+
+impl CheckpointFile {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// The saved checkpoints, empty on the first start
+    ///
+    /// An empty file is an error, as every partition would otherwise start from
+    /// `EVENTHUB_START` again.
+    pub async fn load(&self) -> Result<Checkpoints> {
+        let path = self.path.clone();
+        let Some(contents) = tokio::task::spawn_blocking(move || durable::read_file(&path))
+            .await
+            .context("Failed to read the checkpoints")??
+        else {
+            return Ok(Checkpoints::new());
+        };
+        let text = String::from_utf8(contents)
+            .with_context(|| format!("{} is not a checkpoint file", self.path.display()))?;
+        let mut checkpoints = Checkpoints::new();
+        for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            let parsed = line
+                .split_once(' ')
+                .and_then(|(partition, sequence)| Some((partition, sequence.parse().ok()?)));
+            let Some((partition, sequence_number)) = parsed else {
+                bail!("Invalid checkpoint in {}: {:?}", self.path.display(), line);
+            };
+            checkpoints.insert(partition.to_string(), sequence_number);
+        }
+        Ok(checkpoints)
+    }
+
+    /// Saved with [`durable::replace_file`], so after a crash the file holds either
+    /// the previous checkpoints or these
+    pub async fn save(&self, checkpoints: &Checkpoints) -> Result<()> {
+        let text: String = checkpoints
+            .iter()
+            .map(|(partition, sequence_number)| format!("{} {}\n", partition, sequence_number))
+            .collect();
+        let path = self.path.clone();
+        tokio::task::spawn_blocking(move || durable::replace_file(&path, text.as_bytes()))
+            .await
+            .context("Failed to save the checkpoints")?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_save_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state").join("checkpoints");
+        let file = CheckpointFile::new(&path);
+
+        assert!(file.load().await.unwrap().is_empty());
+        let mut checkpoints = Checkpoints::from([("0".to_string(), 17), ("1".to_string(), 4)]);
+        file.save(&checkpoints).await.unwrap();
+        checkpoints.insert("1".to_string(), 9);
+        file.save(&checkpoints).await.unwrap();
+
+        // Reopened, as on the next start
+        let file = CheckpointFile::new(&path);
+        assert_eq!(checkpoints, file.load().await.unwrap());
+        assert!(!dir.path().join("state").join("checkpoints.tmp").exists());
+
+        tokio::fs::write(&path, "0 17\n1 nine\n").await.unwrap();
+        let error = file.load().await.unwrap_err();
+        assert!(error.to_string().ends_with("\"1 nine\""), "{}", error);
+
+        // Emptied by a crash, which must not restart every partition from scratch
+        tokio::fs::write(&path, "").await.unwrap();
+        let error = file.load().await.unwrap_err();
+        assert!(error.to_string().contains("is empty"), "{}", error);
+    }
+}
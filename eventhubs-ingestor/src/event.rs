@@ -0,0 +1,62 @@
+//! Events received from a partition of an event hub
+
+use anyhow::{bail, Result};
+use std::time::SystemTime;
+
+/// An event and the partition it was received from
+///
+/// Only the parts that make up a row are kept, so rows can be built and tested without
+/// a connection to Event Hubs.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Event {
+    pub partition_id: String,
+    pub body: Vec<u8>,
+    pub partition_key: Option<String>,
+    pub offset: Option<String>,
+    pub sequence_number: Option<i64>,
+    pub enqueued_time: Option<SystemTime>,
+}
+
+/// Where a partition with no checkpoint is read from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Start {
+    /// Only events enqueued after the ingestor starts
+    Latest,
+    /// Every event still retained by the hub
+    Earliest,
+}
+
+impl Start {
+    /// Read the start from `EVENTHUB_START`: `latest` (the default) or `earliest`
+    pub fn from_env() -> Result<Self> {
+        match std::env::var("EVENTHUB_START") {
+            Ok(value) => Self::new(&value),
+            Err(_) => Ok(Start::Latest),
+        }
+    }
+
+    pub fn new(value: &str) -> Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "" | "latest" => Ok(Start::Latest),
+            "earliest" => Ok(Start::Earliest),
+            _ => bail!("EVENTHUB_START must be latest or earliest, got {:?}", value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_start() {
+        assert_eq!(Start::Latest, Start::new("").unwrap());
+        assert_eq!(Start::Latest, Start::new("latest").unwrap());
+        assert_eq!(Start::Earliest, Start::new(" Earliest ").unwrap());
+        let error = Start::new("beginning").unwrap_err();
+        assert_eq!(
+            "EVENTHUB_START must be latest or earliest, got \"beginning\"",
+            error.to_string()
+        );
+    }
+}
@@ -0,0 +1,190 @@
+//! Ingesting events and tracking which of them are safe to checkpoint
+
+use anyhow::{bail, Result};
+use prost::Message;
+use std::collections::VecDeque;
+use tracing::warn;
+use zerobus_common::pipeline::{IngestSink, IngestSummary, Pipeline};
+
+use crate::checkpoint::Checkpoints;
+use crate::event::Event;
+use crate::row::to_row;
+
+/// Ingests events from every partition and works out each partition's checkpoint
+///
+/// Rows are acknowledged in the order they were sent, whichever partition their events
+/// came from. A partition's checkpoint only moves to an event once its row and every
+/// row sent before it have been acknowledged, so a restart resumes after the last event
+/// known to be in the table. If a row is not acknowledged the checkpoints stop
+/// advancing and an error is returned; restarting receives every event after them
+/// again.
+pub struct EventIngestor<S: IngestSink> {
+    pipeline: Pipeline<S>,
+    /// Rows handed to the pipeline so far
+    sent: u64,
+    /// Events not yet acknowledged: the number of rows sent up to each, its partition,
+    /// and its sequence number
+    pending: VecDeque<(u64, String, i64)>,
+    acknowledged: Checkpoints,
+}
+
+impl<S: IngestSink> EventIngestor<S> {
+    /// `checkpoints` are where receiving resumes, i.e. the last saved checkpoints
+    pub fn new(pipeline: Pipeline<S>, checkpoints: Checkpoints) -> Self {
+        Self {
+            pipeline,
+            sent: 0,
+            pending: VecDeque::new(),
+            acknowledged: checkpoints,
+        }
+    }
+
+    /// Ingest the row of `event`
+    ///
+    /// `ingested_at` is microseconds since Unix epoch.
+    pub async fn handle(&mut self, event: &Event, ingested_at: i64) -> Result<()> {
+        let row = to_row(event, ingested_at);
+        self.pipeline.ingest(row.encode_to_vec()).await?;
+        self.sent += 1;
+        match event.sequence_number {
+            Some(sequence_number) => {
+                self.pending
+                    .push_back((self.sent, event.partition_id.clone(), sequence_number))
+            }
+            None => warn!(
+                "Event from partition {} has no sequence number; it cannot be checkpointed",
+                event.partition_id
+            ),
+        }
+        self.advance()?;
+        Ok(())
+    }
+
+    /// Wait for every outstanding ack and return the checkpoints that are safe to save
+    pub async fn checkpoint(&mut self) -> Result<&Checkpoints> {
+        self.pipeline.drain().await?;
+        self.advance()?;
+        Ok(self.acknowledged())
+    }
+
+    /// For each partition, the latest event whose row, and every row before it, is
+    /// acknowledged
+    pub fn acknowledged(&self) -> &Checkpoints {
+        &self.acknowledged
+    }
+
+    /// Drain outstanding acks and close the sink
+    pub async fn finish(mut self) -> Result<(IngestSummary, Checkpoints)> {
+        let checkpointed = self.checkpoint().await.map(|_| ());
+        let summary = self.pipeline.finish().await?;
+        checkpointed?;
+        Ok((summary, self.acknowledged))
+    }
+
+    /// Move each partition's checkpoint past every event whose row has been acknowledged
+    fn advance(&mut self) -> Result<()> {
+        let summary = self.pipeline.summary();
+        if summary.failed > 0 {
+            // Acks after a failure no longer line up with the rows sent, so nothing
+            // past the last checkpoints can be trusted
+            bail!(
+                "{} rows were not acknowledged: {}",
+                summary.failed,
+                summary.first_error.as_deref().unwrap_or("unknown error")
+            );
+        }
+        while let Some((rows, _, _)) = self.pending.front() {
+            if *rows > summary.ingested {
+                break;
+            }
+            if let Some((_, partition, sequence_number)) = self.pending.pop_front() {
+                self.acknowledged.insert(partition, sequence_number);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::eventhubs_events::TableEventhubsEvents;
+    use zerobus_common::testing::MockSink;
+
+    const INGESTED_AT: i64 = 1_718_020_860_000_000;
+
+    fn event(partition: &str, sequence_number: i64) -> Event {
+        Event {
+            partition_id: partition.to_string(),
+            body: format!("{}-{}", partition, sequence_number).into_bytes(),
+            sequence_number: Some(sequence_number),
+            ..Default::default()
+        }
+    }
+
+    fn checkpoints(partitions: &[(&str, i64)]) -> Checkpoints {
+        partitions
+            .iter()
+            .map(|(partition, sequence_number)| (partition.to_string(), *sequence_number))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_checkpoints_advance_after_acks() {
+        let sink = MockSink::default();
+        let mut ingestor =
+            EventIngestor::new(Pipeline::new(sink.clone(), 100), checkpoints(&[("0", 9)]));
+
+        for event in [event("0", 10), event("1", 3), event("0", 11)] {
+            ingestor.handle(&event, INGESTED_AT).await.unwrap();
+        }
+        assert_eq!(3, sink.records().len());
+        assert_eq!(&checkpoints(&[("0", 9)]), ingestor.acknowledged());
+
+        assert_eq!(
+            &checkpoints(&[("0", 11), ("1", 3)]),
+            ingestor.checkpoint().await.unwrap()
+        );
+
+        ingestor.handle(&event("1", 4), INGESTED_AT).await.unwrap();
+        let (summary, checkpoints) = ingestor.finish().await.unwrap();
+        assert_eq!(4, summary.ingested);
+        assert_eq!(self::checkpoints(&[("0", 11), ("1", 4)]), checkpoints);
+        assert!(sink.closed());
+    }
+
+    #[tokio::test]
+    async fn test_window_drain_advances_checkpoints() {
+        // With a window of one row, sending each row drains the previous one
+        let mut ingestor =
+            EventIngestor::new(Pipeline::new(MockSink::default(), 1), Checkpoints::new());
+        for event in [event("0", 1), event("1", 1), event("0", 2)] {
+            ingestor.handle(&event, INGESTED_AT).await.unwrap();
+        }
+        assert_eq!(&checkpoints(&[("0", 1), ("1", 1)]), ingestor.acknowledged());
+    }
+
+    #[tokio::test]
+    async fn test_failed_ack_holds_checkpoints() {
+        let sink = MockSink::default()
+            .fail_acks_for(|record| TableEventhubsEvents::decode(record).unwrap().body() == b"1-2");
+        let mut ingestor = EventIngestor::new(Pipeline::new(sink, 100), Checkpoints::new());
+
+        for event in [event("0", 1), event("1", 1)] {
+            ingestor.handle(&event, INGESTED_AT).await.unwrap();
+        }
+        assert_eq!(
+            &checkpoints(&[("0", 1), ("1", 1)]),
+            ingestor.checkpoint().await.unwrap()
+        );
+
+        // The second event of partition 1 is never acknowledged, so neither it nor
+        // anything sent after it, from any partition, is checkpointed
+        for event in [event("1", 2), event("0", 2)] {
+            ingestor.handle(&event, INGESTED_AT).await.unwrap();
+        }
+        let error = ingestor.checkpoint().await.unwrap_err();
+        assert!(error.to_string().contains("1 rows were not acknowledged"));
+        assert_eq!(&checkpoints(&[("0", 1), ("1", 1)]), ingestor.acknowledged());
+    }
+}
@@ -0,0 +1,5 @@
+pub mod checkpoint;
+pub mod event;
+pub mod ingest;
+pub mod proto;
+pub mod row;
@@ -0,0 +1,293 @@
+use anyhow::{bail, Context, Result};
+use azure_identity::DefaultAzureCredential;
+use azure_messaging_eventhubs::models::ReceivedEventData;
+use azure_messaging_eventhubs::{
+    ConsumerClient, OpenReceiverOptions, StartLocation, StartPosition,
+};
+use databricks_zerobus_ingest_sdk::{StreamConfigurationOptions, TableProperties, ZerobusSdk};
+use eventhubs_ingestor::checkpoint::CheckpointFile;
+use eventhubs_ingestor::event::{Event, Start};
+use eventhubs_ingestor::ingest::EventIngestor;
+use eventhubs_ingestor::proto::load_descriptor_proto;
+use futures::StreamExt;
+use std::pin::pin;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tokio::time::MissedTickBehavior;
+use tracing::{error, info};
+use zerobus_common::pipeline::{IngestSink, Pipeline};
+use zerobus_common::shutdown;
+
+/// Maximum number of unacknowledged records per stream when MAX_INFLIGHT is not set
+const DEFAULT_MAX_INFLIGHT: usize = 10_000;
+
+/// How often the checkpoints are saved when CHECKPOINT_INTERVAL_SECS is not set
+const DEFAULT_CHECKPOINT_INTERVAL_SECS: u64 = 5;
+
+/// Where the checkpoints are kept when CHECKPOINT_FILE is not set
+const DEFAULT_CHECKPOINT_FILE: &str = "eventhubs-ingestor.checkpoints";
+
+/// The consumer group read when CONSUMER_GROUP is not set
+const DEFAULT_CONSUMER_GROUP: &str = "$Default";
+
+/// Events received from all partitions that may wait to be ingested
+const RECEIVED_BUFFER_SIZE: usize = 1024;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .with_target(false)
+        .init();
+
+    let zerobus_endpoint = std::env::var("ZEROBUS_ENDPOINT")
+        .context("ZEROBUS_ENDPOINT environment variable must be set")?;
+    let databricks_host = std::env::var("DATABRICKS_HOST")
+        .context("DATABRICKS_HOST environment variable must be set")?;
+    let client_id = std::env::var("DATABRICKS_CLIENT_ID")
+        .context("DATABRICKS_CLIENT_ID environment variable must be set")?;
+    let client_secret = std::env::var("DATABRICKS_CLIENT_SECRET")
+        .context("DATABRICKS_CLIENT_SECRET environment variable must be set")?;
+    let table_name =
+        std::env::var("TABLE_NAME").context("TABLE_NAME environment variable must be set")?;
+    let namespace = std::env::var("EVENTHUBS_NAMESPACE")
+        .context("EVENTHUBS_NAMESPACE environment variable must be set")?;
+    let eventhub_name =
+        std::env::var("EVENTHUB_NAME").context("EVENTHUB_NAME environment variable must be set")?;
+    let consumer_group =
+        std::env::var("CONSUMER_GROUP").unwrap_or_else(|_| DEFAULT_CONSUMER_GROUP.to_string());
+    let max_inflight = positive_env("MAX_INFLIGHT", DEFAULT_MAX_INFLIGHT as u64)? as usize;
+    let checkpoint_interval = Duration::from_secs(positive_env(
+        "CHECKPOINT_INTERVAL_SECS",
+        DEFAULT_CHECKPOINT_INTERVAL_SECS,
+    )?);
+    let checkpoint_file = CheckpointFile::new(
+        std::env::var("CHECKPOINT_FILE").unwrap_or_else(|_| DEFAULT_CHECKPOINT_FILE.to_string()),
+    );
+    let start = Start::from_env()?;
+
+    let checkpoints = checkpoint_file.load().await?;
+
+    let sdk = ZerobusSdk::new(zerobus_endpoint, databricks_host)?;
+    let table_properties = TableProperties {
+        table_name: table_name.clone(),
+        descriptor_proto: load_descriptor_proto("eventhubs_events.proto", "table_eventhubs_events"),
+    };
+    let stream_options = StreamConfigurationOptions {
+        max_inflight_records: max_inflight,
+        ..Default::default()
+    };
+    let stream = sdk
+        .create_stream(
+            table_properties,
+            client_id,
+            client_secret,
+            Some(stream_options),
+        )
+        .await
+        .context("Failed to create stream")?;
+    info!("Created stream to table: {}", table_name);
+
+    // Azure CLI login, a managed identity, or AZURE_CLIENT_ID, AZURE_TENANT_ID, and
+    // AZURE_CLIENT_SECRET; the identity needs the Azure Event Hubs Data Receiver role
+    let credential = DefaultAzureCredential::new().context("Failed to find Azure credentials")?;
+    let consumer = ConsumerClient::builder()
+        .with_consumer_group(consumer_group.clone())
+        .open(&namespace, eventhub_name.clone(), credential)
+        .await
+        .with_context(|| format!("Failed to connect to {}/{}", namespace, eventhub_name))?;
+    let consumer = Arc::new(consumer);
+    let partition_ids = consumer
+        .get_eventhub_properties()
+        .await
+        .context("Failed to read the event hub's partitions")?
+        .partition_ids;
+    info!(
+        "Receiving {} partitions of {} as consumer group {}",
+        partition_ids.len(),
+        eventhub_name,
+        consumer_group
+    );
+
+    let (sender, mut received) = mpsc::channel(RECEIVED_BUFFER_SIZE);
+    let mut receivers = JoinSet::new();
+    for partition_id in partition_ids {
+        let checkpoint = checkpoints.get(&partition_id).copied();
+        match checkpoint {
+            Some(sequence_number) => info!(
+                "Receiving partition {} after sequence number {}",
+                partition_id, sequence_number
+            ),
+            None => info!(
+                "No checkpoint for partition {}; receiving from {:?}",
+                partition_id, start
+            ),
+        }
+        receivers.spawn(receive_partition(
+            consumer.clone(),
+            partition_id,
+            start_position(checkpoint, start),
+            sender.clone(),
+        ));
+    }
+    drop(sender);
+
+    let mut ingestor = EventIngestor::new(Pipeline::new(stream, max_inflight), checkpoints.clone());
+    let read = ingest_events(
+        &mut received,
+        &mut ingestor,
+        &checkpoint_file,
+        checkpoint_interval,
+    )
+    .await;
+    if let Err(e) = &read {
+        error!("Receiving stopped: {:#}", e);
+    }
+    receivers.shutdown().await;
+
+    // Whatever happened, save the progress that is known to be durable
+    let acknowledged = ingestor.acknowledged().clone();
+    let acknowledged = match ingestor.finish().await {
+        Ok((summary, acknowledged)) => {
+            info!(
+                "Shut down: {} rows ingested, {} failed",
+                summary.ingested, summary.failed
+            );
+            acknowledged
+        }
+        Err(e) => {
+            error!("Failed to drain outstanding rows: {:#}", e);
+            acknowledged
+        }
+    };
+    if acknowledged != checkpoints {
+        checkpoint_file.save(&acknowledged).await?;
+        info!("Saved checkpoints {:?}", acknowledged);
+    }
+
+    read
+}
+
+/// Ingest received events until Ctrl+C or SIGTERM, saving the checkpoints every
+/// `checkpoint_interval`
+async fn ingest_events<S: IngestSink>(
+    received: &mut mpsc::Receiver<Result<Event>>,
+    ingestor: &mut EventIngestor<S>,
+    checkpoint_file: &CheckpointFile,
+    checkpoint_interval: Duration,
+) -> Result<()> {
+    let mut saved = ingestor.acknowledged().clone();
+    let mut checkpoint = tokio::time::interval(checkpoint_interval);
+    checkpoint.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut shutdown = pin!(shutdown::signal());
+
+    loop {
+        tokio::select! {
+            event = received.recv() => {
+                let Some(event) = event else {
+                    bail!("Every partition receiver stopped");
+                };
+                ingestor.handle(&event?, now_micros()?).await?;
+            }
+            _ = checkpoint.tick() => {
+                let acknowledged = ingestor.checkpoint().await?;
+                if *acknowledged != saved {
+                    checkpoint_file.save(acknowledged).await?;
+                    saved = acknowledged.clone();
+                }
+            }
+            _ = &mut shutdown => return Ok(()),
+        }
+    }
+}
+
+/// Send every event of `partition_id` from `position` on to `events`, and the error
+/// that stops receiving if there is one
+async fn receive_partition(
+    consumer: Arc<ConsumerClient>,
+    partition_id: String,
+    position: StartPosition,
+    events: mpsc::Sender<Result<Event>>,
+) {
+    let options = OpenReceiverOptions {
+        start_position: Some(position),
+        ..Default::default()
+    };
+    let receiver = match consumer
+        .open_receiver_on_partition(partition_id.clone(), Some(options))
+        .await
+    {
+        Ok(receiver) => receiver,
+        Err(e) => {
+            let error = anyhow::Error::new(e).context(format!(
+                "Failed to open a receiver on partition {}",
+                partition_id
+            ));
+            let _ = events.send(Err(error)).await;
+            return;
+        }
+    };
+    let mut stream = pin!(receiver.stream_events());
+    while let Some(received) = stream.next().await {
+        let event = received
+            .map(|received| to_event(&partition_id, &received))
+            .with_context(|| format!("Failed to receive from partition {}", partition_id));
+        let failed = event.is_err();
+        // A closed channel means ingesting stopped
+        if events.send(event).await.is_err() || failed {
+            return;
+        }
+    }
+}
+
+/// Resume after the checkpointed event, or from `start` on a partition with none
+fn start_position(checkpoint: Option<i64>, start: Start) -> StartPosition {
+    let location = match (checkpoint, start) {
+        (Some(sequence_number), _) => StartLocation::SequenceNumber(sequence_number),
+        (None, Start::Latest) => StartLocation::Latest,
+        (None, Start::Earliest) => StartLocation::Earliest,
+    };
+    StartPosition {
+        location,
+        inclusive: false,
+    }
+}
+
+fn to_event(partition_id: &str, received: &ReceivedEventData) -> Event {
+    Event {
+        partition_id: partition_id.to_string(),
+        body: received
+            .event_data()
+            .body()
+            .map(<[u8]>::to_vec)
+            .unwrap_or_default(),
+        partition_key: received.partition_key().clone(),
+        offset: received.offset().clone(),
+        sequence_number: *received.sequence_number(),
+        enqueued_time: *received.enqueued_time(),
+    }
+}
+
+fn positive_env(name: &str, default: u64) -> Result<u64> {
+    let value = match std::env::var(name) {
+        Ok(value) => value
+            .trim()
+            .parse::<u64>()
+            .with_context(|| format!("{} must be a positive integer, got {:?}", name, value))?,
+        Err(_) => default,
+    };
+    if value == 0 {
+        bail!("{} must be a positive integer, got 0", name);
+    }
+    Ok(value)
+}
+
+/// Current time in microseconds since Unix epoch
+fn now_micros() -> Result<i64> {
+    Ok(SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .context("Failed to get system time")?
+        .as_micros() as i64)
+}
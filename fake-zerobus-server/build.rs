@@ -0,0 +1,11 @@
+fn main() -> std::io::Result<()> {
+    // The service definition comes from the SDK rather than from a Unity Catalog
+    // table, so it is compiled here with tonic-build, as a server only. A bundled
+    // protoc keeps the build from depending on one being installed.
+    let protoc = protoc_bin_vendored::protoc_bin_path().expect("bundled protoc not available");
+    std::env::set_var("PROTOC", protoc);
+
+    tonic_build::configure()
+        .build_client(false)
+        .compile_protos(&["proto/zerobus_service.proto"], &["proto/"])
+}
@@ -0,0 +1,273 @@
+//! A fake Zerobus server for end-to-end tests.
+//!
+//! [`FakeZerobus::start`] serves the Zerobus gRPC surface the SDK uses on localhost,
+//! over TLS with a self-signed certificate, along with a token endpoint that accepts any
+//! credentials. Pointing an example at [`FakeZerobusServer::env`] runs it unchanged
+//! against the fake: streams are created, records are acknowledged in offset order, and
+//! every acknowledged record is kept for the test to inspect.
+//!
+//! Faults are injected on request: acknowledgments can be delayed, every Nth one can fail
+//! its stream, and the connection can be dropped partway through, optionally refusing
+//! the reconnects that follow so the SDK's own recovery gives up and the caller's
+//! `recreate_stream` path runs.
+
+use anyhow::{Context, Result};
+use axum::routing::post;
+use axum::{Json, Router};
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::transport::{Identity, Server, ServerTlsConfig};
+use tracing::warn;
+
+mod service;
+
+use crate::service::{FakeService, State};
+
+/// Messages of the Zerobus service, compiled by build.rs
+pub mod proto {
+    tonic::include_proto!("databricks.zerobus");
+}
+
+/// Client ID and secret the examples are given; the token endpoint accepts any
+pub const FAKE_CLIENT_ID: &str = "fake-client-id";
+pub const FAKE_CLIENT_SECRET: &str = "fake-client-secret";
+
+/// The faults to inject, and how to start a server with them
+#[derive(Debug, Clone, Default)]
+pub struct FakeZerobus {
+    pub(crate) ack_delay: Duration,
+    pub(crate) fail_every_nth_ack: Option<u64>,
+    pub(crate) drop_after_records: Option<u64>,
+    pub(crate) refused_reconnects: u32,
+}
+
+impl FakeZerobus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Acknowledge each record `delay` after it arrives, rather than right away
+    pub fn ack_delay(mut self, delay: Duration) -> Self {
+        self.ack_delay = delay;
+        self
+    }
+
+    /// Fail the stream in place of every `n`th acknowledgment, counted across streams
+    ///
+    /// The stream ends with an `INTERNAL` status and the record is not kept.
+    pub fn fail_every_nth_ack(mut self, n: u64) -> Self {
+        self.fail_every_nth_ack = Some(n.max(1));
+        self
+    }
+
+    /// Drop the connection when record `k + 1` arrives, counted across streams
+    ///
+    /// The first `k` records are acknowledged; the one that trips the drop is not, and
+    /// the stream ends with an `UNAVAILABLE` status. This happens once per server.
+    pub fn drop_after_records(mut self, k: u64) -> Self {
+        self.drop_after_records = Some(k);
+        self
+    }
+
+    /// Refuse the next `n` streams created after the connection drops, with an
+    /// `UNAVAILABLE` status
+    ///
+    /// Refusing at least as many as the SDK's `recovery_retries` makes its own
+    /// recovery give up, so the stream fails and has to be recreated.
+    pub fn refuse_reconnects(mut self, n: u32) -> Self {
+        self.refused_reconnects = n;
+        self
+    }
+
+    /// Serve Zerobus on a free localhost port, and the token endpoint on another
+    pub async fn start(self) -> Result<FakeZerobusServer> {
+        let certificate = certificate()?;
+        let identity = Identity::from_pem(&certificate.cert_pem, &certificate.key_pem);
+
+        let grpc = TcpListener::bind("127.0.0.1:0").await?;
+        let grpc_port = grpc.local_addr()?.port();
+        let token = TcpListener::bind("127.0.0.1:0").await?;
+        let token_addr = token.local_addr()?;
+
+        let state = Arc::new(Mutex::new(State::default()));
+        let (stop, stopped) = watch::channel(());
+
+        let grpc_server = Server::builder()
+            .tls_config(ServerTlsConfig::new().identity(identity))?
+            .add_service(FakeService::new(self, Arc::clone(&state)).into_server())
+            .serve_with_incoming_shutdown(TcpListenerStream::new(grpc), dropped(stopped.clone()));
+        tokio::spawn(async move {
+            if let Err(e) = grpc_server.await {
+                warn!("Fake Zerobus server failed: {}", e);
+            }
+        });
+
+        let app = Router::new().route("/oidc/v1/token", post(issue_token));
+        tokio::spawn(async move {
+            if let Err(e) = axum::serve(token, app)
+                .with_graceful_shutdown(dropped(stopped))
+                .await
+            {
+                warn!("Fake token endpoint failed: {}", e);
+            }
+        });
+
+        Ok(FakeZerobusServer {
+            endpoint: format!("https://localhost:{}", grpc_port),
+            host: format!("http://{}", token_addr),
+            cert_path: certificate.path.clone(),
+            state,
+            _stop: stop,
+        })
+    }
+}
+
+/// A self-signed certificate for `localhost`, and the file it is written to
+struct SelfSigned {
+    cert_pem: String,
+    key_pem: String,
+    path: PathBuf,
+}
+
+static CERTIFICATE: Mutex<Option<Arc<SelfSigned>>> = Mutex::new(None);
+
+/// The certificate every server in this process presents
+///
+/// `SSL_CERT_FILE` is process-wide, and the SDK may read it only once, so servers
+/// started by tests running in parallel share one certificate rather than each
+/// replacing the file the others are trusted through.
+fn certificate() -> Result<Arc<SelfSigned>> {
+    let mut certificate = CERTIFICATE.lock().unwrap();
+    if let Some(certificate) = &*certificate {
+        return Ok(Arc::clone(certificate));
+    }
+    let certified =
+        rcgen::generate_simple_self_signed(vec!["localhost".to_string(), "127.0.0.1".to_string()])
+            .context("Failed to generate a self-signed certificate")?;
+    let cert_pem = certified.cert.pem();
+    let path = std::env::temp_dir().join(format!("fake-zerobus-{}.pem", std::process::id()));
+    std::fs::write(&path, &cert_pem)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    let generated = Arc::new(SelfSigned {
+        cert_pem,
+        key_pem: certified.key_pair.serialize_pem(),
+        path,
+    });
+    *certificate = Some(Arc::clone(&generated));
+    Ok(generated)
+}
+
+/// Resolves once the server handle is dropped
+async fn dropped(mut stopped: watch::Receiver<()>) {
+    while stopped.changed().await.is_ok() {}
+}
+
+async fn issue_token() -> Json<Value> {
+    Json(json!({
+        "access_token": "fake-token",
+        "token_type": "Bearer",
+        "expires_in": 3600,
+    }))
+}
+
+/// A record the fake acknowledged
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AckedRecord {
+    pub table_name: String,
+    pub offset_id: i64,
+    /// The encoded protobuf, or the bytes of a JSON record
+    pub payload: Vec<u8>,
+}
+
+/// A running fake, stopped when dropped
+pub struct FakeZerobusServer {
+    endpoint: String,
+    host: String,
+    cert_path: PathBuf,
+    state: Arc<Mutex<State>>,
+    _stop: watch::Sender<()>,
+}
+
+impl FakeZerobusServer {
+    /// `ZEROBUS_ENDPOINT` for the fake
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    /// `DATABRICKS_HOST` for the fake, serving `/oidc/v1/token`
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// The self-signed certificate, to trust with `SSL_CERT_FILE`
+    pub fn cert_path(&self) -> &Path {
+        &self.cert_path
+    }
+
+    /// The environment an example reads to reach Zerobus, pointing at the fake
+    pub fn env(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("ZEROBUS_ENDPOINT", self.endpoint.clone()),
+            ("DATABRICKS_HOST", self.host.clone()),
+            ("DATABRICKS_CLIENT_ID", FAKE_CLIENT_ID.to_string()),
+            ("DATABRICKS_CLIENT_SECRET", FAKE_CLIENT_SECRET.to_string()),
+            ("SSL_CERT_FILE", self.cert_path.display().to_string()),
+        ]
+    }
+
+    /// Set [`Self::env`] in this process, for examples that run in-process
+    ///
+    /// The variables are process-wide, so tests doing this must not run in parallel
+    /// with others that read them.
+    pub fn set_env(&self) {
+        for (name, value) in self.env() {
+            std::env::set_var(name, value);
+        }
+    }
+
+    /// Every record acknowledged so far, in the order of their acknowledgments
+    pub fn acked_records(&self) -> Vec<AckedRecord> {
+        self.state.lock().unwrap().acked.clone()
+    }
+
+    /// Records received, acknowledged or not, counting those sent again on a new stream
+    pub fn records_received(&self) -> u64 {
+        self.state.lock().unwrap().records_received
+    }
+
+    /// Streams created, including those recovered or recreated after a fault
+    pub fn streams_created(&self) -> u64 {
+        self.state.lock().unwrap().streams_created
+    }
+
+    /// Streams refused by [`FakeZerobus::refuse_reconnects`]
+    pub fn streams_refused(&self) -> u64 {
+        self.state.lock().unwrap().streams_refused
+    }
+
+    /// Wait up to `timeout` for `n` records to be acknowledged, for callers that do
+    /// not wait for their acknowledgments themselves
+    pub async fn wait_for_acks(&self, n: usize, timeout: Duration) -> Result<Vec<AckedRecord>> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let acked = self.acked_records();
+            if acked.len() >= n {
+                return Ok(acked);
+            }
+            if tokio::time::Instant::now() >= deadline {
+                anyhow::bail!(
+                    "{} of {} records were acknowledged within {:?}",
+                    acked.len(),
+                    n,
+                    timeout
+                );
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+}
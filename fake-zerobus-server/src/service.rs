@@ -0,0 +1,230 @@
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status, Streaming};
+
+use crate::proto::ephemeral_stream_request::Payload as RequestPayload;
+use crate::proto::ephemeral_stream_response::Payload as ResponsePayload;
+use crate::proto::ingest_record_request::Record;
+use crate::proto::zerobus_server::{Zerobus, ZerobusServer};
+use crate::proto::{
+    CreateIngestStreamResponse, EphemeralStreamRequest, EphemeralStreamResponse,
+    IngestRecordResponse,
+};
+use crate::{AckedRecord, FakeZerobus};
+
+/// Responses buffered per stream before acknowledgments wait for the client to read
+const RESPONSE_BUFFER: usize = 1024;
+
+/// What the server has seen, across all of its streams
+#[derive(Debug, Default)]
+pub(crate) struct State {
+    pub(crate) streams_created: u64,
+    pub(crate) streams_refused: u64,
+    pub(crate) records_received: u64,
+    pub(crate) acks: u64,
+    pub(crate) acked: Vec<AckedRecord>,
+    dropped: bool,
+    reconnects_to_refuse: u32,
+}
+
+/// A record waiting for its acknowledgment
+struct Pending {
+    offset_id: i64,
+    payload: Vec<u8>,
+    due: Instant,
+}
+
+type StreamResponse = Result<EphemeralStreamResponse, Status>;
+
+#[derive(Clone)]
+pub(crate) struct FakeService {
+    faults: FakeZerobus,
+    state: Arc<Mutex<State>>,
+}
+
+impl FakeService {
+    pub(crate) fn new(faults: FakeZerobus, state: Arc<Mutex<State>>) -> Self {
+        Self { faults, state }
+    }
+
+    pub(crate) fn into_server(self) -> ZerobusServer<Self> {
+        ZerobusServer::new(self)
+    }
+
+    /// Serve one stream: its creation, then records until the client closes it or a
+    /// fault ends it
+    async fn serve(
+        self,
+        mut requests: Streaming<EphemeralStreamRequest>,
+        responses: mpsc::Sender<StreamResponse>,
+    ) {
+        let table_name = match requests.message().await {
+            Ok(Some(EphemeralStreamRequest {
+                payload: Some(RequestPayload::CreateStream(create)),
+            })) => create.table_name.unwrap_or_default(),
+            Ok(_) => {
+                let status = Status::invalid_argument("The first message must create the stream");
+                let _ = responses.send(Err(status)).await;
+                return;
+            }
+            Err(_) => return,
+        };
+
+        let stream_id = {
+            let mut state = self.state.lock().unwrap();
+            if state.reconnects_to_refuse > 0 {
+                state.reconnects_to_refuse -= 1;
+                state.streams_refused += 1;
+                None
+            } else {
+                state.streams_created += 1;
+                Some(format!("fake-stream-{}", state.streams_created))
+            }
+        };
+        let Some(stream_id) = stream_id else {
+            let status = Status::unavailable("Stream refused by the fake server");
+            let _ = responses.send(Err(status)).await;
+            return;
+        };
+        let created = EphemeralStreamResponse {
+            payload: Some(ResponsePayload::CreateStreamResponse(
+                CreateIngestStreamResponse {
+                    stream_id: Some(stream_id),
+                },
+            )),
+        };
+        if responses.send(Ok(created)).await.is_err() {
+            return;
+        }
+
+        // Acknowledgments go out from a task of their own, each at its record's due time,
+        // so a delay does not hold up the records behind it
+        let (pending, to_acknowledge) = mpsc::unbounded_channel();
+        let acknowledging = tokio::spawn(self.clone().acknowledge(
+            table_name,
+            to_acknowledge,
+            responses.clone(),
+        ));
+
+        loop {
+            let request = match requests.message().await {
+                Ok(Some(request)) => request,
+                // The client closed its side: acknowledge what is left, then end
+                Ok(None) => break,
+                Err(_) => {
+                    acknowledging.abort();
+                    return;
+                }
+            };
+            let Some(RequestPayload::IngestRecord(record)) = request.payload else {
+                acknowledging.abort();
+                let status =
+                    Status::invalid_argument("Only records may follow the stream creation");
+                let _ = responses.send(Err(status)).await;
+                return;
+            };
+            let payload = match record.record {
+                Some(Record::ProtoEncodedRecord(bytes)) => bytes,
+                Some(Record::JsonRecord(json)) => json.into_bytes(),
+                None => Vec::new(),
+            };
+
+            if self.received_one() {
+                // The records before this one are still acknowledged, this one is not
+                drop(pending);
+                let _ = acknowledging.await;
+                let status = Status::unavailable("Connection dropped by the fake server");
+                let _ = responses.send(Err(status)).await;
+                return;
+            }
+            let due = Instant::now() + self.faults.ack_delay;
+            let pending_record = Pending {
+                offset_id: record.offset_id.unwrap_or_default(),
+                payload,
+                due,
+            };
+            // The acknowledging task only stops early when it failed the stream
+            if pending.send(pending_record).is_err() {
+                return;
+            }
+        }
+        drop(pending);
+        let _ = acknowledging.await;
+    }
+
+    /// Count a record, returning whether it is the one to drop the connection on
+    fn received_one(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.records_received += 1;
+        let drop_now = !state.dropped
+            && self
+                .faults
+                .drop_after_records
+                .is_some_and(|k| state.records_received == k + 1);
+        if drop_now {
+            state.dropped = true;
+            state.reconnects_to_refuse = self.faults.refused_reconnects;
+        }
+        drop_now
+    }
+
+    async fn acknowledge(
+        self,
+        table_name: String,
+        mut to_acknowledge: mpsc::UnboundedReceiver<Pending>,
+        responses: mpsc::Sender<StreamResponse>,
+    ) {
+        while let Some(record) = to_acknowledge.recv().await {
+            tokio::time::sleep_until(record.due).await;
+            let fail = {
+                let mut state = self.state.lock().unwrap();
+                state.acks += 1;
+                let fail = self
+                    .faults
+                    .fail_every_nth_ack
+                    .is_some_and(|n| state.acks % n == 0);
+                if !fail {
+                    state.acked.push(AckedRecord {
+                        table_name: table_name.clone(),
+                        offset_id: record.offset_id,
+                        payload: record.payload,
+                    });
+                }
+                fail
+            };
+            let response = if fail {
+                Err(Status::internal(format!(
+                    "Injected failure acknowledging offset {}",
+                    record.offset_id
+                )))
+            } else {
+                Ok(EphemeralStreamResponse {
+                    payload: Some(ResponsePayload::IngestRecordResponse(
+                        IngestRecordResponse {
+                            durability_ack_up_to_offset: Some(record.offset_id),
+                        },
+                    )),
+                })
+            };
+            if responses.send(response).await.is_err() || fail {
+                return;
+            }
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Zerobus for FakeService {
+    type EphemeralStreamStream = ReceiverStream<StreamResponse>;
+
+    async fn ephemeral_stream(
+        &self,
+        request: Request<Streaming<EphemeralStreamRequest>>,
+    ) -> Result<Response<Self::EphemeralStreamStream>, Status> {
+        let (responses, stream) = mpsc::channel(RESPONSE_BUFFER);
+        tokio::spawn(self.clone().serve(request.into_inner(), responses));
+        Ok(Response::new(ReceiverStream::new(stream)))
+    }
+}
@@ -0,0 +1,196 @@
+//! Runs the SDK against the fake: acknowledgments, each injected fault, and the
+//! recovery from a dropped connection, both the SDK's own and `recreate_stream`.
+
+use databricks_zerobus_ingest_sdk::{
+    StreamConfigurationOptions, TableProperties, ZerobusSdk, ZerobusStream,
+};
+use fake_zerobus_server::{FakeZerobus, FakeZerobusServer, FAKE_CLIENT_ID, FAKE_CLIENT_SECRET};
+use prost_types::field_descriptor_proto::{Label, Type};
+use prost_types::{DescriptorProto, FieldDescriptorProto};
+use std::time::{Duration, Instant};
+use zerobus_common::pipeline::Pipeline;
+
+const TABLE_NAME: &str = "main.default.fake_events";
+
+/// A table with one string column; the fake does not decode records
+fn descriptor() -> DescriptorProto {
+    DescriptorProto {
+        name: Some("table_fake_events".to_string()),
+        field: vec![FieldDescriptorProto {
+            name: Some("msg".to_string()),
+            number: Some(1),
+            label: Some(Label::Optional as i32),
+            r#type: Some(Type::String as i32),
+            ..Default::default()
+        }],
+        ..Default::default()
+    }
+}
+
+async fn start(fake: FakeZerobus) -> (FakeZerobusServer, ZerobusSdk) {
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let server = fake.start().await.unwrap();
+    // Every server in this process presents the same certificate
+    std::env::set_var("SSL_CERT_FILE", server.cert_path());
+    let sdk = ZerobusSdk::new(server.endpoint().to_string(), server.host().to_string()).unwrap();
+    (server, sdk)
+}
+
+async fn create_stream(sdk: &ZerobusSdk, options: StreamConfigurationOptions) -> ZerobusStream {
+    let table_properties = TableProperties {
+        table_name: TABLE_NAME.to_string(),
+        descriptor_proto: descriptor(),
+    };
+    sdk.create_stream(
+        table_properties,
+        FAKE_CLIENT_ID.to_string(),
+        FAKE_CLIENT_SECRET.to_string(),
+        Some(options),
+    )
+    .await
+    .unwrap()
+}
+
+/// Options that leave a dropped connection to the caller
+fn without_recovery() -> StreamConfigurationOptions {
+    StreamConfigurationOptions {
+        recovery: false,
+        ..Default::default()
+    }
+}
+
+fn record(index: usize) -> Vec<u8> {
+    format!("record-{}", index).into_bytes()
+}
+
+fn payloads(server: &FakeZerobusServer) -> Vec<Vec<u8>> {
+    let mut payloads: Vec<_> = server
+        .acked_records()
+        .into_iter()
+        .map(|record| record.payload)
+        .collect();
+    payloads.sort();
+    payloads
+}
+
+#[tokio::test]
+async fn test_records_are_acknowledged_in_offset_order() {
+    let (server, sdk) = start(FakeZerobus::new()).await;
+    let mut stream = create_stream(&sdk, StreamConfigurationOptions::default()).await;
+
+    let mut acks = Vec::new();
+    for index in 0..10 {
+        acks.push(stream.ingest_record(record(index)).await.unwrap());
+    }
+    for ack in acks {
+        ack.await.unwrap();
+    }
+    stream.close().await.unwrap();
+
+    let acked = server.acked_records();
+    assert_eq!(
+        (0..10).collect::<Vec<i64>>(),
+        acked
+            .iter()
+            .map(|record| record.offset_id)
+            .collect::<Vec<_>>()
+    );
+    assert!(acked.iter().all(|record| record.table_name == TABLE_NAME));
+    assert_eq!(record(0), acked[0].payload);
+    assert_eq!(1, server.streams_created());
+}
+
+#[tokio::test]
+async fn test_acknowledgments_wait_for_the_delay() {
+    let delay = Duration::from_millis(300);
+    let (server, sdk) = start(FakeZerobus::new().ack_delay(delay)).await;
+    let mut stream = create_stream(&sdk, StreamConfigurationOptions::default()).await;
+
+    let started = Instant::now();
+    let ack = stream.ingest_record(record(0)).await.unwrap();
+    ack.await.unwrap();
+    assert!(started.elapsed() >= delay, "{:?}", started.elapsed());
+    stream.close().await.unwrap();
+    assert_eq!(1, server.acked_records().len());
+}
+
+#[tokio::test]
+async fn test_every_nth_ack_fails_the_stream() {
+    let (server, sdk) = start(FakeZerobus::new().fail_every_nth_ack(3)).await;
+    let mut stream = create_stream(&sdk, without_recovery()).await;
+
+    let mut acks = Vec::new();
+    for index in 0..2 {
+        acks.push(stream.ingest_record(record(index)).await.unwrap());
+    }
+    for ack in acks {
+        ack.await.unwrap();
+    }
+    // The third acknowledgment is the one replaced by a failure
+    let failed = stream.ingest_record(record(2)).await.unwrap();
+    assert!(failed.await.is_err());
+    assert!(stream.close().await.is_err());
+    assert_eq!(vec![record(0), record(1)], payloads(&server));
+}
+
+#[tokio::test]
+async fn test_sdk_recovers_from_a_dropped_connection() {
+    let (server, sdk) = start(FakeZerobus::new().drop_after_records(2)).await;
+    let mut stream = create_stream(&sdk, StreamConfigurationOptions::default()).await;
+
+    let mut acks = Vec::new();
+    for index in 0..5 {
+        acks.push(stream.ingest_record(record(index)).await.unwrap());
+    }
+    for ack in acks {
+        ack.await.unwrap();
+    }
+    stream.close().await.unwrap();
+
+    // The record the connection dropped on was sent again on a stream of its own
+    assert_eq!((0..5).map(record).collect::<Vec<_>>(), payloads(&server));
+    assert_eq!(2, server.streams_created());
+}
+
+#[tokio::test]
+async fn test_dropped_connection_is_recovered_by_recreating_the_stream() {
+    let (server, sdk) = start(FakeZerobus::new().drop_after_records(3)).await;
+    let mut stream = create_stream(&sdk, without_recovery()).await;
+
+    let mut acks = Vec::new();
+    for index in 0..6 {
+        acks.push(stream.ingest_record(record(index)).await.unwrap());
+    }
+    let mut acked = 0;
+    for ack in acks {
+        if ack.await.is_ok() {
+            acked += 1;
+        }
+    }
+    assert_eq!(3, acked);
+
+    // The way the examples recover from a stream that failed to close
+    assert!(stream.close().await.is_err());
+    let unacked = stream.get_unacked_records().await.unwrap();
+    assert_eq!(3, unacked.len());
+    let mut recreated = sdk.recreate_stream(stream).await.unwrap();
+    recreated.flush().await.unwrap();
+    recreated.close().await.unwrap();
+
+    assert_eq!((0..6).map(record).collect::<Vec<_>>(), payloads(&server));
+    assert_eq!(2, server.streams_created());
+}
+
+#[tokio::test]
+async fn test_pipeline_drains_into_the_fake() {
+    let (server, sdk) = start(FakeZerobus::new().ack_delay(Duration::from_millis(20))).await;
+    let stream = create_stream(&sdk, StreamConfigurationOptions::default()).await;
+
+    let mut pipeline = Pipeline::new(stream, 4);
+    pipeline.ingest_batch((0..20).map(record)).await.unwrap();
+    let summary = pipeline.finish().await.unwrap();
+
+    assert_eq!(20, summary.ingested);
+    assert_eq!(0, summary.failed);
+    assert_eq!(20, server.acked_records().len());
+}
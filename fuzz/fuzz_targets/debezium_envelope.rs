@@ -0,0 +1,12 @@
+//! The value of a record on a Debezium topic, which any client allowed to produce to it
+//! can write
+#![no_main]
+
+use kafka_bridge::debezium::parse;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = parse("dbserver1.inventory.customers", Some(data));
+    // Unwrapped rows fall back to the topic for their database and table
+    let _ = parse("customers", Some(data));
+});
@@ -0,0 +1,82 @@
+//! JSON records encoded for a descriptor, as the config-driven examples receive them
+//!
+//! Records are built from [`Json`] rather than parsed from bytes, so most of them get
+//! past the JSON parser and name the descriptor's fields.
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use serde_json::{Map, Number, Value};
+use std::sync::OnceLock;
+use zerobus_common::dynamic::{DynamicEncoder, FieldErrorMode};
+use zerobus_fuzz::{descriptor, FIELD_NAMES};
+
+fn encoders() -> &'static [DynamicEncoder; 2] {
+    static ENCODERS: OnceLock<[DynamicEncoder; 2]> = OnceLock::new();
+    ENCODERS.get_or_init(|| {
+        let strict = DynamicEncoder::new(&descriptor()).unwrap();
+        let lenient = DynamicEncoder::new(&descriptor())
+            .unwrap()
+            .ignore_unknown_fields(true)
+            .coerce_types(true)
+            .field_error_mode(FieldErrorMode::Null);
+        [strict, lenient]
+    })
+}
+
+#[derive(Debug, Arbitrary)]
+enum Key {
+    Field(u8),
+    Other(String),
+}
+
+impl Key {
+    fn name(&self) -> String {
+        match self {
+            Key::Field(index) => FIELD_NAMES[usize::from(*index) % FIELD_NAMES.len()].to_string(),
+            Key::Other(name) => name.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Arbitrary)]
+enum Json {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Uint(u64),
+    Float(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(Key, Json)>),
+}
+
+impl Json {
+    fn value(&self) -> Value {
+        match self {
+            Json::Null => Value::Null,
+            Json::Bool(value) => Value::Bool(*value),
+            Json::Int(value) => Value::from(*value),
+            Json::Uint(value) => Value::from(*value),
+            Json::Float(value) => Number::from_f64(*value).map_or(Value::Null, Value::Number),
+            Json::String(value) => Value::String(value.clone()),
+            Json::Array(values) => Value::Array(values.iter().map(Json::value).collect()),
+            Json::Object(fields) => Value::Object(
+                fields
+                    .iter()
+                    .map(|(key, value)| (key.name(), value.value()))
+                    .collect::<Map<_, _>>(),
+            ),
+        }
+    }
+}
+
+fuzz_target!(|record: Json| {
+    let value = record.value();
+    for encoder in encoders() {
+        if let Ok(encoded) = encoder.encode(&value) {
+            // What the encoder writes, it must accept back
+            encoder.validate(&encoded).unwrap();
+        }
+    }
+});
@@ -0,0 +1,14 @@
+//! Encoded records checked against a descriptor before they are sent, as with
+//! `VALIDATE_RECORDS`, for the examples that receive records already encoded
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use std::sync::OnceLock;
+use zerobus_common::dynamic::DynamicEncoder;
+use zerobus_fuzz::descriptor;
+
+fuzz_target!(|record: &[u8]| {
+    static ENCODER: OnceLock<DynamicEncoder> = OnceLock::new();
+    let encoder = ENCODER.get_or_init(|| DynamicEncoder::new(&descriptor()).unwrap());
+    let _ = encoder.validate(record);
+});
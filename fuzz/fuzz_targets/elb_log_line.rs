@@ -0,0 +1,14 @@
+//! An access log line, from a log object anyone able to write to the bucket controls
+#![no_main]
+
+use aws_elb_access_logs_ingestor::{parser::parse_line, tokenizer::tokenize};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // The ingestor reads objects line by line as UTF-8
+    let Ok(line) = std::str::from_utf8(data) else {
+        return;
+    };
+    let _ = tokenize(line);
+    let _ = parse_line(line);
+});
@@ -0,0 +1,14 @@
+//! A Firehose delivery request: a body that may be gzipped, with base64 record data,
+//! from anyone who can reach the endpoint before the access key is checked
+#![no_main]
+
+use cloudwatch_metric_streams_receiver::firehose::DeliveryRequest;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    for gzipped in [false, true] {
+        if let Ok(request) = DeliveryRequest::parse(data, gzipped) {
+            let _ = request.data();
+        }
+    }
+});
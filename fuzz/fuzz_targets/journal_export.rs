@@ -0,0 +1,31 @@
+//! `journalctl -o export` output, which carries whatever any process on the host logged,
+//! arriving in reads of any size
+#![no_main]
+
+use journald_reader::export::{Entry, ExportParser};
+use libfuzzer_sys::fuzz_target;
+
+fn parse(output: &[u8], chunk_len: usize) -> Result<Vec<Entry>, String> {
+    let mut parser = ExportParser::new();
+    let mut entries = Vec::new();
+    for chunk in output.chunks(chunk_len) {
+        parser.feed(chunk);
+        while let Some(entry) = parser.next_entry().map_err(|e| e.to_string())? {
+            entries.push(entry);
+        }
+    }
+    entries.extend(parser.finish().map_err(|e| e.to_string())?);
+    Ok(entries)
+}
+
+fuzz_target!(|output: &[u8]| {
+    let whole = parse(output, output.len().max(1));
+    // How the output is split into reads must not change what is parsed from it
+    for chunk_len in [1, 7] {
+        let chunked = parse(output, chunk_len);
+        assert_eq!(whole.is_ok(), chunked.is_ok());
+        if let (Ok(whole), Ok(chunked)) = (&whole, chunked) {
+            assert_eq!(whole, &chunked);
+        }
+    }
+});
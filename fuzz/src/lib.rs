@@ -0,0 +1,92 @@
+//! What the fuzz targets in `fuzz_targets/` share
+
+use prost_types::field_descriptor_proto::{Label, Type};
+use prost_types::{
+    DescriptorProto, EnumDescriptorProto, EnumValueDescriptorProto, FieldDescriptorProto,
+};
+
+/// Field names of [`descriptor`] and of the types nested in it
+pub const FIELD_NAMES: [&str; 14] = [
+    "id", "name", "score", "active", "payload", "count", "tags", "address", "labels", "status",
+    "city", "zip", "key", "value",
+];
+
+fn field(name: &str, number: i32, field_type: Type, label: Label) -> FieldDescriptorProto {
+    FieldDescriptorProto {
+        name: Some(name.to_string()),
+        number: Some(number),
+        r#type: Some(field_type as i32),
+        label: Some(label as i32),
+        ..Default::default()
+    }
+}
+
+fn typed(mut field: FieldDescriptorProto, type_name: &str) -> FieldDescriptorProto {
+    field.type_name = Some(type_name.to_string());
+    field
+}
+
+/// A table with every kind of column: scalars, a repeated field, a nested struct, a
+/// map, and an enum
+pub fn descriptor() -> DescriptorProto {
+    DescriptorProto {
+        name: Some("table_events".to_string()),
+        field: vec![
+            field("id", 1, Type::Int64, Label::Optional),
+            field("name", 2, Type::String, Label::Optional),
+            field("score", 3, Type::Double, Label::Optional),
+            field("active", 4, Type::Bool, Label::Optional),
+            field("payload", 5, Type::Bytes, Label::Optional),
+            field("count", 6, Type::Int32, Label::Optional),
+            field("tags", 7, Type::String, Label::Repeated),
+            typed(
+                field("address", 8, Type::Message, Label::Optional),
+                ".pkg.table_events.Address",
+            ),
+            typed(
+                field("labels", 9, Type::Message, Label::Repeated),
+                ".pkg.table_events.LabelsEntry",
+            ),
+            typed(
+                field("status", 10, Type::Enum, Label::Optional),
+                ".pkg.table_events.Status",
+            ),
+        ],
+        nested_type: vec![
+            DescriptorProto {
+                name: Some("Address".to_string()),
+                field: vec![
+                    field("city", 1, Type::String, Label::Optional),
+                    field("zip", 2, Type::Uint32, Label::Optional),
+                ],
+                ..Default::default()
+            },
+            DescriptorProto {
+                name: Some("LabelsEntry".to_string()),
+                field: vec![
+                    field("key", 1, Type::String, Label::Optional),
+                    field("value", 2, Type::String, Label::Optional),
+                ],
+                options: Some(prost_types::MessageOptions {
+                    map_entry: Some(true),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        ],
+        enum_type: vec![EnumDescriptorProto {
+            name: Some("Status".to_string()),
+            value: ["UNKNOWN", "ACTIVE", "DELETED"]
+                .into_iter()
+                .zip(0..)
+                .map(|(name, number)| EnumValueDescriptorProto {
+                    name: Some(name.to_string()),
+                    number: Some(number),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }],
+        ..Default::default()
+    }
+}
@@ -0,0 +1,161 @@
+//! Pub/Sub push delivery envelopes
+//!
+//! A push subscription POSTs each message wrapped in a JSON envelope, with the message
+//! data base64-encoded:
+//!
+//! ```json
+//! {
+//!   "message": {
+//!     "data": "eyJvcmRlcl9pZCI6ICJvLTEifQ==",
+//!     "attributes": {"source": "pos"},
+//!     "messageId": "2070443601311540",
+//!     "publishTime": "2024-06-10T12:00:00.123Z",
+//!     "orderingKey": "customer-1"
+//!   },
+//!   "subscription": "projects/my-project/subscriptions/orders-push",
+//!   "deliveryAttempt": 1
+//! }
+//! ```
+
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose, Engine as _};
+use chrono::DateTime;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PushEnvelope {
+    pub message: PubsubMessage,
+    #[serde(default)]
+    pub subscription: String,
+    /// Set when the subscription has a dead-letter policy
+    #[serde(default)]
+    pub delivery_attempt: Option<i64>,
+}
+
+// The envelope repeats messageId and publishTime in snake_case for older clients;
+// those copies are ignored
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PubsubMessage {
+    /// Base64-encoded payload; absent for messages with attributes only
+    #[serde(default)]
+    pub data: Option<String>,
+    #[serde(default)]
+    pub attributes: BTreeMap<String, String>,
+    pub message_id: String,
+    /// RFC 3339 timestamp
+    pub publish_time: String,
+    #[serde(default)]
+    pub ordering_key: Option<String>,
+}
+
+impl PushEnvelope {
+    pub fn parse(body: &[u8]) -> Result<Self> {
+        serde_json::from_slice(body).context("Body is not a Pub/Sub push envelope")
+    }
+
+    /// The decoded message data
+    pub fn data(&self) -> Result<Vec<u8>> {
+        match &self.message.data {
+            Some(data) => general_purpose::STANDARD
+                .decode(data)
+                .context("Message data is not base64"),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// The message data as a JSON object, the row's payload columns
+    pub fn payload(&self) -> Result<Map<String, Value>> {
+        let data = self.data()?;
+        match serde_json::from_slice(&data).context("Message data is not valid JSON")? {
+            Value::Object(payload) => Ok(payload),
+            _ => bail!("Message data is not a JSON object"),
+        }
+    }
+
+    /// Delivery metadata, by the column it is stored in
+    ///
+    /// `publish_time` is in microseconds since Unix epoch, for a TIMESTAMP column.
+    pub fn metadata(&self) -> Result<Map<String, Value>> {
+        let publish_time = DateTime::parse_from_rfc3339(&self.message.publish_time)
+            .with_context(|| format!("Invalid publishTime {:?}", self.message.publish_time))?;
+        let mut metadata = Map::new();
+        metadata.insert("message_id".into(), self.message.message_id.clone().into());
+        metadata.insert(
+            "publish_time".into(),
+            publish_time.timestamp_micros().into(),
+        );
+        metadata.insert("subscription".into(), self.subscription.clone().into());
+        metadata.insert(
+            "attributes".into(),
+            serde_json::to_value(&self.message.attributes)?,
+        );
+        if let Some(ordering_key) = &self.message.ordering_key {
+            metadata.insert("ordering_key".into(), ordering_key.clone().into());
+        }
+        if let Some(attempt) = self.delivery_attempt {
+            metadata.insert("delivery_attempt".into(), attempt.into());
+        }
+        Ok(metadata)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn push_body(data: &[u8]) -> Vec<u8> {
+        json!({
+            "message": {
+                "data": general_purpose::STANDARD.encode(data),
+                "attributes": {"source": "pos"},
+                "messageId": "2070443601311540",
+                "message_id": "2070443601311540",
+                "publishTime": "2024-06-10T12:00:00.123Z",
+                "publish_time": "2024-06-10T12:00:00.123Z",
+                "orderingKey": "customer-1"
+            },
+            "subscription": "projects/my-project/subscriptions/orders-push",
+            "deliveryAttempt": 2
+        })
+        .to_string()
+        .into_bytes()
+    }
+
+    #[test]
+    fn test_envelope_decoding() {
+        let envelope = PushEnvelope::parse(&push_body(br#"{"order_id": "o-1"}"#)).unwrap();
+
+        assert_eq!(br#"{"order_id": "o-1"}"#.to_vec(), envelope.data().unwrap());
+        assert_eq!("o-1", envelope.payload().unwrap()["order_id"]);
+
+        let metadata = envelope.metadata().unwrap();
+        assert_eq!("2070443601311540", metadata["message_id"]);
+        assert_eq!(1_718_020_800_123_000i64, metadata["publish_time"]);
+        assert_eq!(
+            "projects/my-project/subscriptions/orders-push",
+            metadata["subscription"]
+        );
+        assert_eq!(json!({"source": "pos"}), metadata["attributes"]);
+        assert_eq!("customer-1", metadata["ordering_key"]);
+        assert_eq!(2, metadata["delivery_attempt"]);
+    }
+
+    #[test]
+    fn test_invalid_envelopes() {
+        assert!(PushEnvelope::parse(br#"{"order_id": "o-1"}"#).is_err());
+
+        let envelope = PushEnvelope::parse(&push_body(b"not json")).unwrap();
+        assert!(envelope.payload().is_err());
+        let envelope = PushEnvelope::parse(&push_body(b"[1, 2]")).unwrap();
+        assert!(envelope.payload().is_err());
+
+        let mut envelope = PushEnvelope::parse(&push_body(b"{}")).unwrap();
+        envelope.message.data = Some("%%%".to_string());
+        assert!(envelope.data().is_err());
+    }
+}
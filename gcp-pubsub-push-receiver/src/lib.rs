@@ -0,0 +1,4 @@
+pub mod envelope;
+pub mod oidc;
+pub mod secret_manager;
+pub mod server;
@@ -0,0 +1,153 @@
+use anyhow::{bail, Context, Result};
+use databricks_zerobus_ingest_sdk::{StreamConfigurationOptions, TableProperties, ZerobusSdk};
+use gcp_pubsub_push_receiver::oidc::TokenVerifier;
+use gcp_pubsub_push_receiver::secret_manager::SecretManagerCredentials;
+use gcp_pubsub_push_receiver::server::{router, Receiver, INGEST_PATH, PUSH_PATH};
+use std::sync::Arc;
+use tracing::{info, warn};
+use zerobus_common::credentials::{CredentialProvider, Credentials, EnvCredentials};
+use zerobus_common::descriptor::find_message_descriptor;
+use zerobus_common::dynamic::{coerce_from_env, DynamicEncoder, FieldErrorMode, KeyCase};
+use zerobus_common::log_level;
+use zerobus_common::pipeline::Pipeline;
+use zerobus_common::shutdown;
+
+/// Maximum number of unacknowledged records on the stream
+const MAX_INFLIGHT_RECORDS: usize = 10_000;
+
+/// Port to listen on when Cloud Run does not set PORT
+const DEFAULT_PORT: &str = "8080";
+
+fn env(name: &str) -> Result<String> {
+    std::env::var(name).with_context(|| format!("{} environment variable must be set", name))
+}
+
+fn env_or(name: &str, default: &str) -> String {
+    std::env::var(name).unwrap_or_else(|_| default.to_string())
+}
+
+/// Credentials from Secret Manager when GCP_SECRET_CLIENT_ID is set, from the
+/// environment otherwise
+async fn load_credentials() -> Result<Credentials> {
+    match std::env::var("GCP_SECRET_CLIENT_ID") {
+        Ok(client_id_secret) => {
+            let client_secret_secret = env("GCP_SECRET_CLIENT_SECRET")?;
+            info!("Reading credentials from Secret Manager");
+            let project = std::env::var("SECRET_MANAGER_PROJECT")
+                .or_else(|_| std::env::var("GOOGLE_CLOUD_PROJECT"))
+                .ok();
+            SecretManagerCredentials::new(project, &client_id_secret, &client_secret_secret)
+                .credentials()
+                .await
+        }
+        Err(_) => EnvCredentials::default().credentials().await,
+    }
+}
+
+/// A verifier for the configured push identity, unless VERIFY_PUSH_TOKEN is false
+fn token_verifier() -> Result<Option<TokenVerifier>> {
+    let verify = std::env::var("VERIFY_PUSH_TOKEN")
+        .map(|value| value != "false" && value != "0")
+        .unwrap_or(true);
+    if !verify {
+        warn!("Accepting unauthenticated pushes on {}", PUSH_PATH);
+        return Ok(None);
+    }
+    Ok(Some(TokenVerifier::new(
+        env("PUSH_AUDIENCE")?,
+        env("PUSH_SERVICE_ACCOUNT")?,
+    )))
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let log_level = log_level::init()?;
+    tokio::spawn(log_level::on_hangup(log_level.clone()));
+
+    let zerobus_endpoint = env("ZEROBUS_ENDPOINT")?;
+    let databricks_host = env("DATABRICKS_HOST")?;
+    let table_name = env("TABLE_NAME")?;
+    let descriptor_set = env_or("DESCRIPTOR_SET", "gen/descriptors/tables.descriptor");
+    let message_name = std::env::var("MESSAGE_NAME").unwrap_or_else(|_| {
+        format!(
+            "table_{}",
+            table_name.rsplit('.').next().unwrap_or(&table_name)
+        )
+    });
+    let ignore_unknown_fields = std::env::var("IGNORE_UNKNOWN_FIELDS")
+        .map(|value| value == "true" || value == "1")
+        .unwrap_or(false);
+    let port = env_or("PORT", DEFAULT_PORT);
+    let verifier = token_verifier()?;
+    let grace = shutdown::grace_from_env()?;
+
+    let bytes = std::fs::read(&descriptor_set)
+        .with_context(|| format!("Failed to read descriptor set {}", descriptor_set))?;
+    let descriptor = find_message_descriptor(&bytes, &message_name)?;
+    let encoder = DynamicEncoder::new(&descriptor)?
+        .ignore_unknown_fields(ignore_unknown_fields)
+        .coerce_types(coerce_from_env()?)
+        .field_error_mode(FieldErrorMode::from_env()?)
+        .key_case(KeyCase::from_env()?);
+
+    let credentials = load_credentials().await?;
+    let sdk = ZerobusSdk::new(zerobus_endpoint, databricks_host)?;
+    let table_properties = TableProperties {
+        table_name: table_name.clone(),
+        descriptor_proto: descriptor,
+    };
+    let stream_options = StreamConfigurationOptions {
+        max_inflight_records: MAX_INFLIGHT_RECORDS,
+        ..Default::default()
+    };
+    let stream = sdk
+        .create_stream(
+            table_properties,
+            credentials.client_id,
+            credentials.client_secret,
+            Some(stream_options),
+        )
+        .await
+        .with_context(|| format!("Failed to create stream to {}", table_name))?;
+
+    let receiver = Arc::new(Receiver::new(
+        encoder,
+        Pipeline::new(stream, MAX_INFLIGHT_RECORDS),
+        verifier,
+    ));
+
+    let listen_addr = format!("0.0.0.0:{}", port);
+    let listener = tokio::net::TcpListener::bind(&listen_addr)
+        .await
+        .with_context(|| format!("Failed to bind {}", listen_addr))?;
+    info!(
+        "Receiving pushes on http://{}{} and JSON on {} -> {}",
+        listen_addr, PUSH_PATH, INGEST_PATH, table_name
+    );
+
+    let mut app = router(Arc::clone(&receiver));
+    if let Some(admin) = log_level::admin_routes_from_env(&log_level) {
+        app = app.merge(admin);
+    }
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown::signal())
+        .await?;
+
+    // Every request has returned, so this is the last reference to the receiver
+    let Ok(receiver) = Arc::try_unwrap(receiver) else {
+        bail!("Receiver is still in use after shutdown");
+    };
+    let outcome = shutdown::drain(receiver.into_pipeline(), grace).await?;
+    info!(
+        "Shut down after ingesting {} rows ({} failed)",
+        outcome.summary.ingested, outcome.summary.failed
+    );
+    if !outcome.unacked.is_empty() {
+        bail!(
+            "{} rows were not acknowledged before shutdown",
+            outcome.unacked.len()
+        );
+    }
+
+    Ok(())
+}
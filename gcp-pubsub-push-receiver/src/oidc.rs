@@ -0,0 +1,306 @@
+//! Verifying the OIDC token Pub/Sub attaches to authenticated push requests
+//!
+//! With authentication enabled on a push subscription, each request carries
+//! `Authorization: Bearer <JWT>`, signed by Google for the subscription's service
+//! account and issued for its configured audience.
+
+use anyhow::{anyhow, bail, Context, Result};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use reqwest::Client;
+use serde::Deserialize;
+use std::collections::HashMap;
+use tokio::sync::RwLock;
+
+/// Google's OAuth2 signing keys, as a JWK set
+pub const GOOGLE_CERTS_URL: &str = "https://www.googleapis.com/oauth2/v3/certs";
+
+const ISSUERS: [&str; 2] = ["accounts.google.com", "https://accounts.google.com"];
+
+/// Why a push request was not verified
+#[derive(Debug)]
+pub enum VerifyError {
+    /// The token is missing, malformed, expired, or for someone else; answered 401
+    Invalid(anyhow::Error),
+    /// The signing keys could not be fetched; answered 503 so Pub/Sub retries
+    Unavailable(anyhow::Error),
+}
+
+impl std::fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerifyError::Invalid(e) => write!(f, "Invalid push token: {:#}", e),
+            VerifyError::Unavailable(e) => write!(f, "Cannot verify push token: {:#}", e),
+        }
+    }
+}
+
+/// Claims of a verified token
+#[derive(Debug, Clone, Deserialize)]
+pub struct Claims {
+    pub email: String,
+    #[serde(default)]
+    pub email_verified: bool,
+    pub sub: String,
+}
+
+#[derive(Deserialize)]
+struct Jwk {
+    kid: String,
+    n: String,
+    e: String,
+}
+
+#[derive(Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+/// Parse a JWK set into decoding keys by key id
+pub fn parse_jwks(json: &[u8]) -> Result<HashMap<String, DecodingKey>> {
+    let jwks: Jwks = serde_json::from_slice(json).context("Invalid JWK set")?;
+    jwks.keys
+        .into_iter()
+        .map(|jwk| {
+            let key = DecodingKey::from_rsa_components(&jwk.n, &jwk.e)
+                .with_context(|| format!("Invalid key {}", jwk.kid))?;
+            Ok((jwk.kid, key))
+        })
+        .collect()
+}
+
+/// Checks push tokens against the expected audience and service account
+pub struct TokenVerifier {
+    audience: String,
+    service_account: String,
+    keys: RwLock<HashMap<String, DecodingKey>>,
+    /// Where keys are fetched from when a token names one not seen yet
+    jwks_url: Option<String>,
+    client: Client,
+}
+
+impl TokenVerifier {
+    /// Verify against Google's signing keys, fetched on first use and again whenever
+    /// Google rotates them
+    pub fn new(audience: String, service_account: String) -> Self {
+        Self::with_jwks_url(audience, service_account, GOOGLE_CERTS_URL.to_string())
+    }
+
+    /// Verify against the keys served at `jwks_url`
+    pub fn with_jwks_url(audience: String, service_account: String, jwks_url: String) -> Self {
+        Self {
+            audience,
+            service_account,
+            keys: RwLock::new(HashMap::new()),
+            jwks_url: Some(jwks_url),
+            client: Client::new(),
+        }
+    }
+
+    /// Verify against a fixed set of keys
+    pub fn with_keys(
+        audience: String,
+        service_account: String,
+        keys: HashMap<String, DecodingKey>,
+    ) -> Self {
+        Self {
+            audience,
+            service_account,
+            keys: RwLock::new(keys),
+            jwks_url: None,
+            client: Client::new(),
+        }
+    }
+
+    /// Verify the `Authorization` header of a push request
+    pub async fn verify(&self, authorization: Option<&str>) -> Result<Claims, VerifyError> {
+        let token = authorization
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or_else(|| VerifyError::Invalid(anyhow!("No bearer token")))?;
+        let header =
+            decode_header(token).map_err(|e| VerifyError::Invalid(anyhow!("Malformed: {}", e)))?;
+        let kid = header
+            .kid
+            .ok_or_else(|| VerifyError::Invalid(anyhow!("Token names no signing key")))?;
+
+        let key = match self.key(&kid).await {
+            Some(key) => key,
+            None => {
+                self.refresh().await.map_err(VerifyError::Unavailable)?;
+                self.key(&kid)
+                    .await
+                    .ok_or_else(|| VerifyError::Invalid(anyhow!("Unknown signing key {}", kid)))?
+            }
+        };
+
+        self.check(token, &key).map_err(VerifyError::Invalid)
+    }
+
+    fn check(&self, token: &str, key: &DecodingKey) -> Result<Claims> {
+        let mut validation = Validation::new(Algorithm::RS256);
+        validation.set_audience(&[&self.audience]);
+        validation.set_issuer(&ISSUERS);
+        let claims = decode::<Claims>(token, key, &validation)?.claims;
+        if !claims.email_verified || claims.email != self.service_account {
+            bail!(
+                "Token is for {}, not {}",
+                claims.email,
+                self.service_account
+            );
+        }
+        Ok(claims)
+    }
+
+    async fn key(&self, kid: &str) -> Option<DecodingKey> {
+        self.keys.read().await.get(kid).cloned()
+    }
+
+    /// Replace the keys with the current JWK set; a no-op with fixed keys
+    async fn refresh(&self) -> Result<()> {
+        let Some(url) = &self.jwks_url else {
+            return Ok(());
+        };
+        let response = self
+            .client
+            .get(url)
+            .send()
+            .await
+            .with_context(|| format!("Failed to fetch {}", url))?
+            .error_for_status()?;
+        let keys = parse_jwks(&response.bytes().await?)?;
+        *self.keys.write().await = keys;
+        Ok(())
+    }
+}
+
+/// Tokens signed with the key in testdata, whose public half is testdata/jwks.json
+#[cfg(test)]
+pub(crate) mod testing {
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::{json, Value};
+
+    pub const AUDIENCE: &str = "https://orders-receiver-abc123-uc.a.run.app/pubsub/push";
+    pub const SERVICE_ACCOUNT: &str = "pubsub-push@my-project.iam.gserviceaccount.com";
+    pub const JWKS: &[u8] = include_bytes!("../testdata/jwks.json");
+
+    pub fn claims() -> Value {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        json!({
+            "iss": "https://accounts.google.com",
+            "aud": AUDIENCE,
+            "sub": "1234567890",
+            "email": SERVICE_ACCOUNT,
+            "email_verified": true,
+            "iat": now,
+            "exp": now + 3600
+        })
+    }
+
+    pub fn token(claims: &Value, kid: &str) -> String {
+        let key = EncodingKey::from_rsa_pem(include_bytes!("../testdata/test_key.pem")).unwrap();
+        let header = Header {
+            kid: Some(kid.to_string()),
+            ..Header::new(jsonwebtoken::Algorithm::RS256)
+        };
+        encode(&header, claims, &key).unwrap()
+    }
+
+    pub fn bearer(claims: &Value) -> String {
+        format!("Bearer {}", token(claims, "test-key"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::*;
+    use super::*;
+    use axum::routing::get;
+    use axum::Router;
+    use serde_json::Value;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn verifier() -> TokenVerifier {
+        TokenVerifier::with_keys(
+            AUDIENCE.to_string(),
+            SERVICE_ACCOUNT.to_string(),
+            parse_jwks(JWKS).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_valid_token() {
+        let claims = verifier().verify(Some(&bearer(&claims()))).await.unwrap();
+        assert_eq!(SERVICE_ACCOUNT, claims.email);
+    }
+
+    #[tokio::test]
+    async fn test_token_verification_failures() {
+        let verifier = verifier();
+        let with = |field: &str, value: Value| {
+            let mut claims = claims();
+            claims[field] = value;
+            bearer(&claims)
+        };
+        let invalid = [
+            None,
+            Some("Basic dXNlcjpwYXNz".to_string()),
+            Some("Bearer not-a-jwt".to_string()),
+            Some(with("aud", "https://someone-else.a.run.app".into())),
+            Some(with(
+                "email",
+                "attacker@evil.iam.gserviceaccount.com".into(),
+            )),
+            Some(with("email_verified", false.into())),
+            Some(with("iss", "https://evil.example.com".into())),
+            Some(with("exp", 1_000_000_000u64.into())),
+            Some(format!("Bearer {}", token(&claims(), "rotated-away"))),
+        ];
+
+        for authorization in invalid {
+            let result = verifier.verify(authorization.as_deref()).await;
+            assert!(
+                matches!(result, Err(VerifyError::Invalid(_))),
+                "{:?} was accepted",
+                authorization
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_keys_are_fetched_for_unknown_key_ids() {
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&fetches);
+        let app = Router::new().route(
+            "/certs",
+            get(move || {
+                counter.fetch_add(1, Ordering::Relaxed);
+                async { JWKS }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let verifier = TokenVerifier::with_jwks_url(
+            AUDIENCE.to_string(),
+            SERVICE_ACCOUNT.to_string(),
+            format!("http://{}/certs", address),
+        );
+        for _ in 0..3 {
+            verifier.verify(Some(&bearer(&claims()))).await.unwrap();
+        }
+        assert_eq!(1, fetches.load(Ordering::Relaxed));
+
+        // Keys that cannot be fetched make verification unavailable rather than invalid
+        let verifier = TokenVerifier::with_jwks_url(
+            AUDIENCE.to_string(),
+            SERVICE_ACCOUNT.to_string(),
+            format!("http://{}/missing", address),
+        );
+        let result = verifier.verify(Some(&bearer(&claims()))).await;
+        assert!(matches!(result, Err(VerifyError::Unavailable(_))));
+    }
+}
@@ -0,0 +1,251 @@
+//! Service principal credentials read from GCP Secret Manager with the service's
+//! identity
+
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose, Engine as _};
+use reqwest::Client;
+use serde::Deserialize;
+use zerobus_common::credentials::{CredentialProvider, Credentials};
+
+/// The metadata server, which serves the attached service account's tokens on Cloud
+/// Run, GKE, and Compute Engine
+pub const METADATA_URL: &str = "http://metadata.google.internal/computeMetadata/v1";
+
+pub const SECRET_MANAGER_URL: &str = "https://secretmanager.googleapis.com/v1";
+
+#[derive(Deserialize)]
+struct Token {
+    access_token: String,
+}
+
+#[derive(Deserialize)]
+struct AccessResponse {
+    payload: Payload,
+}
+
+#[derive(Deserialize)]
+struct Payload {
+    /// Base64-encoded secret value
+    data: String,
+}
+
+/// Reads the client ID and secret from two Secret Manager secrets
+///
+/// A secret is either a name in the service's project, whose latest version is read,
+/// or a full resource name such as `projects/my-project/secrets/client-id/versions/3`.
+/// Each call fetches a fresh token and the secret versions, so a rotated secret is
+/// picked up the next time a stream is created.
+pub struct SecretManagerCredentials {
+    client: Client,
+    metadata_url: String,
+    secret_manager_url: String,
+    /// Project of short secret names; the metadata server's project when unset
+    project: Option<String>,
+    client_id_secret: String,
+    client_secret_secret: String,
+}
+
+impl SecretManagerCredentials {
+    pub fn new(
+        project: Option<String>,
+        client_id_secret: &str,
+        client_secret_secret: &str,
+    ) -> Self {
+        Self::with_endpoints(
+            METADATA_URL,
+            SECRET_MANAGER_URL,
+            project,
+            client_id_secret,
+            client_secret_secret,
+        )
+    }
+
+    /// Read through other metadata server and Secret Manager endpoints
+    pub fn with_endpoints(
+        metadata_url: &str,
+        secret_manager_url: &str,
+        project: Option<String>,
+        client_id_secret: &str,
+        client_secret_secret: &str,
+    ) -> Self {
+        Self {
+            client: Client::new(),
+            metadata_url: metadata_url.trim_end_matches('/').to_string(),
+            secret_manager_url: secret_manager_url.trim_end_matches('/').to_string(),
+            project,
+            client_id_secret: client_id_secret.to_string(),
+            client_secret_secret: client_secret_secret.to_string(),
+        }
+    }
+
+    async fn metadata(&self, path: &str) -> Result<reqwest::Response> {
+        let response = self
+            .client
+            .get(format!("{}/{}", self.metadata_url, path))
+            .header("Metadata-Flavor", "Google")
+            .send()
+            .await
+            .context("Metadata server request failed")?;
+        if !response.status().is_success() {
+            bail!(
+                "Metadata server answered {} for {}",
+                response.status(),
+                path
+            );
+        }
+        Ok(response)
+    }
+
+    /// An access token of the attached service account
+    async fn token(&self) -> Result<String> {
+        let token: Token = self
+            .metadata("instance/service-accounts/default/token")
+            .await?
+            .json()
+            .await
+            .context("Invalid metadata server token response")?;
+        Ok(token.access_token)
+    }
+
+    async fn project(&self) -> Result<String> {
+        match &self.project {
+            Some(project) => Ok(project.clone()),
+            None => Ok(self.metadata("project/project-id").await?.text().await?),
+        }
+    }
+
+    /// The resource name of the secret version `secret` refers to
+    async fn version(&self, secret: &str) -> Result<String> {
+        if secret.starts_with("projects/") {
+            if secret.contains("/versions/") {
+                return Ok(secret.to_string());
+            }
+            return Ok(format!("{}/versions/latest", secret));
+        }
+        Ok(format!(
+            "projects/{}/secrets/{}/versions/latest",
+            self.project().await?,
+            secret
+        ))
+    }
+
+    async fn secret(&self, token: &str, secret: &str) -> Result<String> {
+        let version = self.version(secret).await?;
+        let response = self
+            .client
+            .get(format!("{}/{}:access", self.secret_manager_url, version))
+            .bearer_auth(token)
+            .send()
+            .await
+            .with_context(|| format!("Request for secret {} failed", version))?;
+        if !response.status().is_success() {
+            bail!(
+                "Secret Manager answered {} for {}",
+                response.status(),
+                version
+            );
+        }
+        let access: AccessResponse = response
+            .json()
+            .await
+            .with_context(|| format!("Invalid response for secret {}", version))?;
+        let value = general_purpose::STANDARD
+            .decode(access.payload.data)
+            .with_context(|| format!("Secret {} is not base64", version))?;
+        let value =
+            String::from_utf8(value).with_context(|| format!("Secret {} is not UTF-8", version))?;
+        // Secrets created with `echo ... | gcloud secrets create` end in a newline
+        Ok(value.trim_end().to_string())
+    }
+}
+
+impl CredentialProvider for SecretManagerCredentials {
+    async fn credentials(&self) -> Result<Credentials> {
+        let token = self.token().await?;
+        Ok(Credentials {
+            client_id: self.secret(&token, &self.client_id_secret).await?,
+            client_secret: self.secret(&token, &self.client_secret_secret).await?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::{HeaderMap, StatusCode, Uri};
+    use axum::routing::get;
+    use axum::{Json, Router};
+    use serde_json::json;
+
+    /// A metadata server and a Secret Manager holding two secrets in `my-project`
+    async fn mock_gcp() -> String {
+        let app = Router::new()
+            .route(
+                "/metadata/instance/service-accounts/default/token",
+                get(|headers: HeaderMap| async move {
+                    if headers.get("Metadata-Flavor").map(|h| h.as_bytes())
+                        != Some(b"Google".as_slice())
+                    {
+                        return Err(StatusCode::FORBIDDEN);
+                    }
+                    Ok(Json(
+                        json!({"access_token": "gcp-token", "expires_in": 3599}),
+                    ))
+                }),
+            )
+            .route(
+                "/metadata/project/project-id",
+                get(|| async { "my-project" }),
+            )
+            // Secret version paths end in `:access`, which is matched here rather than
+            // as a route
+            .fallback(|headers: HeaderMap, uri: Uri| async move {
+                if headers.get("Authorization").map(|h| h.as_bytes())
+                    != Some(b"Bearer gcp-token".as_slice())
+                {
+                    return Err(StatusCode::UNAUTHORIZED);
+                }
+                let path = uri
+                    .path()
+                    .trim_start_matches("/v1/projects/my-project/secrets/");
+                let value = match path {
+                    "databricks-client-id/versions/latest:access" => "sp-id",
+                    "databricks-client-secret/versions/2:access" => "sp-secret\n",
+                    _ => return Err(StatusCode::NOT_FOUND),
+                };
+                let data = general_purpose::STANDARD.encode(value);
+                Ok(Json(json!({"name": uri.path(), "payload": {"data": data}})))
+            });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}", address)
+    }
+
+    fn provider(base: &str, client_secret_secret: &str) -> SecretManagerCredentials {
+        SecretManagerCredentials::with_endpoints(
+            &format!("{}/metadata", base),
+            &format!("{}/v1", base),
+            None,
+            "databricks-client-id",
+            client_secret_secret,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_credentials_from_secret_manager() {
+        let base = mock_gcp().await;
+
+        let credentials = provider(
+            &base,
+            "projects/my-project/secrets/databricks-client-secret/versions/2",
+        )
+        .credentials()
+        .await
+        .unwrap();
+        assert_eq!("sp-id", credentials.client_id);
+        assert_eq!("sp-secret", credentials.client_secret);
+
+        assert!(provider(&base, "missing").credentials().await.is_err());
+    }
+}
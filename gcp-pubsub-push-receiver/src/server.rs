@@ -0,0 +1,372 @@
+//! The push endpoint and the plain JSON endpoint
+
+use anyhow::Result;
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::routing::{get, post};
+use axum::Router;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
+use zerobus_common::dynamic::DynamicEncoder;
+use zerobus_common::pipeline::{IngestSink, Pipeline};
+
+use crate::envelope::PushEnvelope;
+use crate::oidc::{TokenVerifier, VerifyError};
+
+/// Path Pub/Sub push subscriptions deliver to
+pub const PUSH_PATH: &str = "/pubsub/push";
+/// Path plain JSON posts are sent to
+pub const INGEST_PATH: &str = "/ingest";
+
+/// Encodes pushed messages and posted objects and ingests them into one table
+///
+/// Requests take turns on the one stream, so each response reports exactly whether its
+/// rows are durable.
+pub struct Receiver<S: IngestSink> {
+    encoder: DynamicEncoder,
+    pipeline: Mutex<Pipeline<S>>,
+    /// Checks the token on push requests; every push is accepted when unset
+    verifier: Option<TokenVerifier>,
+}
+
+impl<S: IngestSink> Receiver<S> {
+    pub fn new(
+        encoder: DynamicEncoder,
+        pipeline: Pipeline<S>,
+        verifier: Option<TokenVerifier>,
+    ) -> Self {
+        Self {
+            encoder,
+            pipeline: Mutex::new(pipeline),
+            verifier,
+        }
+    }
+
+    /// Take the pipeline back once the server has stopped, so it can be finished
+    pub fn into_pipeline(self) -> Pipeline<S> {
+        self.pipeline.into_inner()
+    }
+
+    /// The row of a pushed message: the message data's fields, plus each delivery
+    /// metadata field the table has a column for and the data does not set
+    fn push_row(&self, envelope: &PushEnvelope) -> Result<Vec<u8>> {
+        let mut row = envelope.payload()?;
+        for (column, value) in envelope.metadata()? {
+            if self.encoder.has_field(&column) && !row.contains_key(&column) {
+                row.insert(column, value);
+            }
+        }
+        self.encoder.encode(&Value::Object(row))
+    }
+
+    async fn ingest(&self, records: Vec<Vec<u8>>) -> Result<()> {
+        let mut pipeline = self.pipeline.lock().await;
+        pipeline.ingest_batch(records).await
+    }
+}
+
+/// The push and JSON endpoints, plus a health check
+pub fn router<S: IngestSink + 'static>(receiver: Arc<Receiver<S>>) -> Router {
+    Router::new()
+        .route(PUSH_PATH, post(push::<S>))
+        .route(INGEST_PATH, post(ingest::<S>))
+        .route("/health", get(|| async { "OK" }))
+        .with_state(receiver)
+}
+
+/// Handle one Pub/Sub push delivery
+///
+/// Pub/Sub acknowledges the message on 204 and redelivers it, with backoff, on any
+/// other status. So the response is 204 only once the row is durable: 401 when the
+/// token does not verify, 400 when the message is not a row of the table, and 503
+/// when the row is not acknowledged or the signing keys cannot be fetched.
+async fn push<S: IngestSink>(
+    State(receiver): State<Arc<Receiver<S>>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> (StatusCode, String) {
+    if let Some(verifier) = &receiver.verifier {
+        let authorization = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok());
+        match verifier.verify(authorization).await {
+            Ok(_) => {}
+            Err(e @ VerifyError::Invalid(_)) => {
+                warn!("Rejecting push: {}", e);
+                return (StatusCode::UNAUTHORIZED, e.to_string());
+            }
+            Err(e @ VerifyError::Unavailable(_)) => {
+                error!("{}", e);
+                return (StatusCode::SERVICE_UNAVAILABLE, e.to_string());
+            }
+        }
+    }
+
+    let envelope = match PushEnvelope::parse(&body) {
+        Ok(envelope) => envelope,
+        Err(e) => {
+            warn!("Rejecting push: {:#}", e);
+            return (StatusCode::BAD_REQUEST, format!("{:#}", e));
+        }
+    };
+    let message_id = &envelope.message.message_id;
+    let record = match receiver.push_row(&envelope) {
+        Ok(record) => record,
+        Err(e) => {
+            warn!("Rejecting message {}: {:#}", message_id, e);
+            return (StatusCode::BAD_REQUEST, format!("{:#}", e));
+        }
+    };
+
+    match receiver.ingest(vec![record]).await {
+        Ok(()) => {
+            info!("Ingested message {}", message_id);
+            (StatusCode::NO_CONTENT, String::new())
+        }
+        Err(e) => {
+            error!("Failed to ingest message {}: {:#}", message_id, e);
+            (StatusCode::SERVICE_UNAVAILABLE, format!("{:#}", e))
+        }
+    }
+}
+
+/// Handle a plain JSON post: an object, or an array of objects, each becoming a row
+///
+/// Answers 202 once every row is durable, 400 without ingesting anything when an item
+/// is not a row of the table, and 500 when rows are not acknowledged.
+async fn ingest<S: IngestSink>(
+    State(receiver): State<Arc<Receiver<S>>>,
+    body: Bytes,
+) -> (StatusCode, String) {
+    let items = match serde_json::from_slice(&body) {
+        Ok(Value::Array(items)) => items,
+        Ok(item) => vec![item],
+        Err(e) => return bad_request(format!("Body is not valid JSON: {}", e)),
+    };
+
+    let mut records = Vec::with_capacity(items.len());
+    for (index, item) in items.iter().enumerate() {
+        match receiver.encoder.encode(item) {
+            Ok(record) => records.push(record),
+            Err(e) => return bad_request(format!("Item {}: {:#}", index, e)),
+        }
+    }
+
+    let ingested = records.len();
+    match receiver.ingest(records).await {
+        Ok(()) => {
+            info!("Ingested {} rows", ingested);
+            (
+                StatusCode::ACCEPTED,
+                json!({ "ingested": ingested }).to_string(),
+            )
+        }
+        Err(e) => {
+            error!("Failed to ingest: {:#}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e))
+        }
+    }
+}
+
+fn bad_request(error: String) -> (StatusCode, String) {
+    warn!("Rejecting request: {}", error);
+    (StatusCode::BAD_REQUEST, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::oidc::parse_jwks;
+    use crate::oidc::testing::{bearer, claims, AUDIENCE, JWKS, SERVICE_ACCOUNT};
+    use axum::body::Body;
+    use axum::http::Request;
+    use base64::{engine::general_purpose, Engine as _};
+    use prost::Message;
+    use prost_types::field_descriptor_proto::{Label, Type};
+    use prost_types::{DescriptorProto, FieldDescriptorProto};
+    use tower::ServiceExt;
+    use zerobus_common::testing::MockSink;
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct OrderRow {
+        #[prost(string, optional, tag = "1")]
+        order_id: Option<String>,
+        #[prost(int64, optional, tag = "2")]
+        quantity: Option<i64>,
+        #[prost(string, optional, tag = "3")]
+        message_id: Option<String>,
+        #[prost(int64, optional, tag = "4")]
+        publish_time: Option<i64>,
+    }
+
+    fn receiver(sink: MockSink, verify: bool) -> Arc<Receiver<MockSink>> {
+        let field = |name: &str, number: i32, r#type: Type| FieldDescriptorProto {
+            name: Some(name.to_string()),
+            number: Some(number),
+            label: Some(Label::Optional as i32),
+            r#type: Some(r#type as i32),
+            ..Default::default()
+        };
+        let descriptor = DescriptorProto {
+            name: Some("table_orders".to_string()),
+            field: vec![
+                field("order_id", 1, Type::String),
+                field("quantity", 2, Type::Int64),
+                field("message_id", 3, Type::String),
+                field("publish_time", 4, Type::Int64),
+            ],
+            ..Default::default()
+        };
+        let encoder = DynamicEncoder::new(&descriptor).unwrap();
+        let verifier = verify.then(|| {
+            TokenVerifier::with_keys(
+                AUDIENCE.to_string(),
+                SERVICE_ACCOUNT.to_string(),
+                parse_jwks(JWKS).unwrap(),
+            )
+        });
+        Arc::new(Receiver::new(encoder, Pipeline::new(sink, 100), verifier))
+    }
+
+    fn push_body(data: &str) -> String {
+        json!({
+            "message": {
+                "data": general_purpose::STANDARD.encode(data),
+                "attributes": {"source": "pos"},
+                "messageId": "2070443601311540",
+                "publishTime": "2024-06-10T12:00:00.123Z"
+            },
+            "subscription": "projects/my-project/subscriptions/orders-push"
+        })
+        .to_string()
+    }
+
+    async fn post(
+        receiver: Arc<Receiver<MockSink>>,
+        path: &str,
+        authorization: Option<&str>,
+        body: String,
+    ) -> StatusCode {
+        let mut request = Request::post(path).header("Content-Type", "application/json");
+        if let Some(authorization) = authorization {
+            request = request.header("Authorization", authorization);
+        }
+        let request = request.body(Body::from(body)).unwrap();
+        router(receiver).oneshot(request).await.unwrap().status()
+    }
+
+    fn rows(sink: &MockSink) -> Vec<OrderRow> {
+        sink.records()
+            .iter()
+            .map(|record| OrderRow::decode(record.as_slice()).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_pushed_messages_are_ingested() {
+        let sink = MockSink::default();
+        let status = post(
+            receiver(sink.clone(), true),
+            PUSH_PATH,
+            Some(&bearer(&claims())),
+            push_body(r#"{"order_id": "o-1", "quantity": 2}"#),
+        )
+        .await;
+
+        assert_eq!(StatusCode::NO_CONTENT, status);
+        let rows = rows(&sink);
+        assert_eq!(1, rows.len());
+        assert_eq!(Some("o-1".to_string()), rows[0].order_id);
+        assert_eq!(Some(2), rows[0].quantity);
+        assert_eq!(Some("2070443601311540".to_string()), rows[0].message_id);
+        assert_eq!(Some(1_718_020_800_123_000), rows[0].publish_time);
+    }
+
+    #[tokio::test]
+    async fn test_unverified_pushes_are_rejected() {
+        let sink = MockSink::default();
+        let body = push_body(r#"{"order_id": "o-1"}"#);
+        let mut wrong_audience = claims();
+        wrong_audience["aud"] = "https://someone-else.a.run.app".into();
+
+        for authorization in [None, Some(bearer(&wrong_audience))] {
+            let status = post(
+                receiver(sink.clone(), true),
+                PUSH_PATH,
+                authorization.as_deref(),
+                body.clone(),
+            )
+            .await;
+            assert_eq!(StatusCode::UNAUTHORIZED, status);
+        }
+        assert!(sink.records().is_empty());
+
+        // Without a verifier, the same push is accepted
+        let status = post(receiver(sink.clone(), false), PUSH_PATH, None, body).await;
+        assert_eq!(StatusCode::NO_CONTENT, status);
+    }
+
+    #[tokio::test]
+    async fn test_retry_inducing_status_codes() {
+        // Unacknowledged rows answer 503, so Pub/Sub redelivers the message
+        let sink = MockSink::default().fail_acks_for(|_| true);
+        let status = post(
+            receiver(sink, false),
+            PUSH_PATH,
+            None,
+            push_body(r#"{"order_id": "o-1"}"#),
+        )
+        .await;
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, status);
+
+        // Messages that are not rows of the table answer 400
+        let sink = MockSink::default();
+        for body in [
+            push_body(r#"{"order_id": "o-1", "colour": "red"}"#),
+            push_body("not json"),
+            r#"{"order_id": "o-1"}"#.to_string(),
+        ] {
+            let status = post(receiver(sink.clone(), false), PUSH_PATH, None, body).await;
+            assert_eq!(StatusCode::BAD_REQUEST, status);
+        }
+        assert!(sink.records().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_json_posts() {
+        let sink = MockSink::default();
+        let status = post(
+            receiver(sink.clone(), true),
+            INGEST_PATH,
+            None,
+            r#"[{"order_id": "o-1", "quantity": 2}, {"order_id": "o-2"}]"#.to_string(),
+        )
+        .await;
+        assert_eq!(StatusCode::ACCEPTED, status);
+        assert_eq!(2, rows(&sink).len());
+
+        // One item that does not fit rejects the whole post
+        let status = post(
+            receiver(sink.clone(), true),
+            INGEST_PATH,
+            None,
+            r#"[{"order_id": "o-3"}, {"colour": "red"}]"#.to_string(),
+        )
+        .await;
+        assert_eq!(StatusCode::BAD_REQUEST, status);
+        assert_eq!(2, rows(&sink).len());
+
+        let sink = MockSink::default().fail_acks_for(|_| true);
+        let status = post(
+            receiver(sink, true),
+            INGEST_PATH,
+            None,
+            r#"{"order_id": "o-1"}"#.to_string(),
+        )
+        .await;
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, status);
+    }
+}
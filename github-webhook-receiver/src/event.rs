@@ -0,0 +1,89 @@
+//! Turning a webhook delivery into a table row.
+
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+
+use crate::proto::github_events::TableGithubEvents;
+
+/// Header holding the GUID GitHub gives each delivery; redeliveries keep it
+pub const DELIVERY_HEADER: &str = "X-GitHub-Delivery";
+
+/// Header naming the event that triggered the delivery, such as `push`
+pub const EVENT_HEADER: &str = "X-GitHub-Event";
+
+/// Build the row for one delivery
+///
+/// The repository, sender, and action get their own columns when the event has them;
+/// `push` events, for one, have no action. The body is kept verbatim as the payload.
+pub fn to_table_row(
+    delivery_id: &str,
+    event_type: &str,
+    body: &[u8],
+    ingested_at: i64,
+    ingested_date: i32,
+) -> Result<TableGithubEvents> {
+    let payload = std::str::from_utf8(body).context("Payload is not UTF-8")?;
+    let event: Value = serde_json::from_str(payload).context("Payload is not valid JSON")?;
+    if !event.is_object() {
+        bail!("Payload is not a JSON object");
+    }
+
+    Ok(TableGithubEvents {
+        delivery_id: Some(delivery_id.to_string()),
+        event_type: Some(event_type.to_string()),
+        repository: string_at(&event, "/repository/full_name"),
+        sender: string_at(&event, "/sender/login"),
+        action: string_at(&event, "/action"),
+        payload: Some(payload.to_string()),
+        ingested_at: Some(ingested_at),
+        ingested_date: Some(ingested_date),
+    })
+}
+
+fn string_at(event: &Value, pointer: &str) -> Option<String> {
+    event
+        .pointer(pointer)
+        .and_then(Value::as_str)
+        .map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PUSH_EVENT: &[u8] = include_bytes!("../testdata/push_event.json");
+
+    #[test]
+    fn test_push_event() {
+        let row = to_table_row("delivery-1", "push", PUSH_EVENT, 1_000_000, 20_000).unwrap();
+
+        assert_eq!(Some("delivery-1".to_string()), row.delivery_id);
+        assert_eq!(Some("push".to_string()), row.event_type);
+        assert_eq!(
+            Some("baxterthehacker/public-repo".to_string()),
+            row.repository
+        );
+        assert_eq!(Some("baxterthehacker".to_string()), row.sender);
+        assert_eq!(None, row.action);
+        assert_eq!(PUSH_EVENT, row.payload.unwrap().as_bytes());
+    }
+
+    #[test]
+    fn test_action_is_extracted() {
+        let body = br#"{"action": "opened", "number": 1, "sender": {"login": "octocat"}}"#;
+
+        let row = to_table_row("delivery-2", "pull_request", body, 0, 0).unwrap();
+
+        assert_eq!(Some("opened".to_string()), row.action);
+        assert_eq!(Some("octocat".to_string()), row.sender);
+        // Events such as those of a GitHub App installation have no repository
+        assert_eq!(None, row.repository);
+    }
+
+    #[test]
+    fn test_invalid_payloads() {
+        assert!(to_table_row("d", "push", b"payload=%7B%7D", 0, 0).is_err());
+        assert!(to_table_row("d", "push", b"[]", 0, 0).is_err());
+        assert!(to_table_row("d", "push", &[0xff, 0xfe], 0, 0).is_err());
+    }
+}
@@ -0,0 +1,4 @@
+pub mod event;
+pub mod proto;
+pub mod server;
+pub mod signature;
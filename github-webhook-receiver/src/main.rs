@@ -0,0 +1,111 @@
+use anyhow::{bail, Context, Result};
+use databricks_zerobus_ingest_sdk::{StreamConfigurationOptions, TableProperties, ZerobusSdk};
+use github_webhook_receiver::proto::load_descriptor_proto;
+use github_webhook_receiver::server::{router, AppState};
+use tracing::info;
+use zerobus_common::log_level;
+use zerobus_common::pipeline::Pipeline;
+use zerobus_common::shutdown;
+
+/// Maximum number of unacknowledged records per stream
+const MAX_INFLIGHT_RECORDS: usize = 10_000;
+
+/// Address to listen on when LISTEN_ADDR is not set
+const DEFAULT_LISTEN_ADDR: &str = "0.0.0.0:8080";
+
+/// Delivery ids remembered for deduplication when DEDUP_CAPACITY is not set
+const DEFAULT_DEDUP_CAPACITY: usize = 10_000;
+
+fn env(name: &str) -> Result<String> {
+    std::env::var(name).with_context(|| format!("{} environment variable must be set", name))
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let log_level = log_level::init()?;
+    tokio::spawn(log_level::on_hangup(log_level.clone()));
+
+    let zerobus_endpoint = env("ZEROBUS_ENDPOINT")?;
+    let databricks_host = env("DATABRICKS_HOST")?;
+    let client_id = env("DATABRICKS_CLIENT_ID")?;
+    let client_secret = env("DATABRICKS_CLIENT_SECRET")?;
+    let table_name = env("TABLE_NAME")?;
+    let webhook_secret = env("WEBHOOK_SECRET")?;
+    if webhook_secret.is_empty() {
+        bail!("WEBHOOK_SECRET must not be empty");
+    }
+    let listen_addr =
+        std::env::var("LISTEN_ADDR").unwrap_or_else(|_| DEFAULT_LISTEN_ADDR.to_string());
+    let dedup_capacity = match std::env::var("DEDUP_CAPACITY") {
+        Ok(value) => value
+            .trim()
+            .parse::<usize>()
+            .ok()
+            .filter(|capacity| *capacity > 0)
+            .with_context(|| {
+                format!("DEDUP_CAPACITY must be a positive integer, got {:?}", value)
+            })?,
+        Err(_) => DEFAULT_DEDUP_CAPACITY,
+    };
+    let grace = shutdown::grace_from_env()?;
+
+    let sdk = ZerobusSdk::new(zerobus_endpoint, databricks_host)?;
+
+    let table_properties = TableProperties {
+        table_name: table_name.clone(),
+        descriptor_proto: load_descriptor_proto("github_events.proto", "table_github_events"),
+    };
+    let stream_options = StreamConfigurationOptions {
+        max_inflight_records: MAX_INFLIGHT_RECORDS,
+        ..Default::default()
+    };
+    let stream = sdk
+        .create_stream(
+            table_properties,
+            client_id,
+            client_secret,
+            Some(stream_options),
+        )
+        .await
+        .context("Failed to create stream")?;
+    info!("Created stream to table: {}", table_name);
+
+    let state = AppState::new(
+        Pipeline::new(stream, MAX_INFLIGHT_RECORDS),
+        webhook_secret,
+        dedup_capacity,
+    );
+
+    let listener = tokio::net::TcpListener::bind(&listen_addr)
+        .await
+        .with_context(|| format!("Failed to bind {}", listen_addr))?;
+    info!(
+        "Listening for webhook deliveries on http://{}/github/webhook",
+        listen_addr
+    );
+
+    let mut app = router(state.clone());
+    if let Some(admin) = log_level::admin_routes_from_env(&log_level) {
+        app = app.merge(admin);
+    }
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown::signal())
+        .await?;
+
+    // Every handler has returned, so this is the last reference to the pipeline
+    if let Some(pipeline) = state.into_pipeline() {
+        let outcome = shutdown::drain(pipeline, grace).await?;
+        info!(
+            "Shut down after ingesting {} events ({} failed)",
+            outcome.summary.ingested, outcome.summary.failed
+        );
+        if !outcome.unacked.is_empty() {
+            bail!(
+                "{} events were not acknowledged before shutdown",
+                outcome.unacked.len()
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,281 @@
+use anyhow::{Context, Result};
+use axum::body::Bytes;
+use axum::extract::{DefaultBodyLimit, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::routing::{get, post};
+use axum::Router;
+use prost::Message;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
+use zerobus_common::dedup::RecentIds;
+use zerobus_common::pipeline::{IngestSink, Pipeline};
+
+use crate::event::{to_table_row, DELIVERY_HEADER, EVENT_HEADER};
+use crate::signature::{verify, SIGNATURE_HEADER};
+
+/// GitHub caps webhook payloads at 25 MB
+const MAX_PAYLOAD_BYTES: usize = 25 * 1024 * 1024;
+
+/// Shared handler state
+///
+/// Deliveries take turns on the one stream, checking for a duplicate and ingesting
+/// under the same lock, so a delivery sent twice at once is still ingested once.
+pub struct AppState<S: IngestSink> {
+    secret: Arc<Vec<u8>>,
+    inner: Arc<Mutex<Inner<S>>>,
+}
+
+struct Inner<S: IngestSink> {
+    pipeline: Pipeline<S>,
+    recent: RecentIds,
+}
+
+impl<S: IngestSink> Clone for AppState<S> {
+    fn clone(&self) -> Self {
+        Self {
+            secret: Arc::clone(&self.secret),
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<S: IngestSink> AppState<S> {
+    /// State checking signatures with `secret` and remembering the last
+    /// `dedup_capacity` delivery ids
+    pub fn new(pipeline: Pipeline<S>, secret: impl Into<Vec<u8>>, dedup_capacity: usize) -> Self {
+        Self {
+            secret: Arc::new(secret.into()),
+            inner: Arc::new(Mutex::new(Inner {
+                pipeline,
+                recent: RecentIds::new(dedup_capacity),
+            })),
+        }
+    }
+
+    /// Take the pipeline back once the server has stopped, so it can be finished
+    pub fn into_pipeline(self) -> Option<Pipeline<S>> {
+        Arc::try_unwrap(self.inner)
+            .ok()
+            .map(|inner| inner.into_inner().pipeline)
+    }
+}
+
+/// Routes for the webhook endpoint and a health check
+pub fn router<S: IngestSink + 'static>(state: AppState<S>) -> Router {
+    Router::new()
+        .route("/github/webhook", post(webhook::<S>))
+        .layer(DefaultBodyLimit::max(MAX_PAYLOAD_BYTES))
+        .route("/health", get(|| async { "OK" }))
+        .with_state(state)
+}
+
+/// Handle a webhook delivery
+///
+/// Answers 401 unless the body carries a valid signature, 400 for deliveries that
+/// are not GitHub events, and 202 once the event is durable or if it was already
+/// ingested. GitHub does not retry failed deliveries by itself, but a 500 marks the
+/// delivery as failed so it can be redelivered from the webhook's settings.
+async fn webhook<S: IngestSink + 'static>(
+    State(state): State<AppState<S>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> (StatusCode, String) {
+    let signature = headers
+        .get(SIGNATURE_HEADER)
+        .and_then(|value| value.to_str().ok());
+    if let Err(e) = verify(&state.secret, &body, signature) {
+        warn!("Rejecting unsigned delivery: {:#}", e);
+        return (StatusCode::UNAUTHORIZED, format!("{:#}", e));
+    }
+
+    let (delivery_id, event_type) = match (
+        header(&headers, DELIVERY_HEADER),
+        header(&headers, EVENT_HEADER),
+    ) {
+        (Ok(delivery_id), Ok(event_type)) => (delivery_id, event_type),
+        (Err(e), _) | (_, Err(e)) => {
+            warn!("Rejecting delivery: {:#}", e);
+            return (StatusCode::BAD_REQUEST, format!("{:#}", e));
+        }
+    };
+
+    let row = match ingestion_time().and_then(|(ingested_at, ingested_date)| {
+        to_table_row(delivery_id, event_type, &body, ingested_at, ingested_date)
+    }) {
+        Ok(row) => row,
+        Err(e) => {
+            warn!("Rejecting delivery {}: {:#}", delivery_id, e);
+            return (StatusCode::BAD_REQUEST, format!("{:#}", e));
+        }
+    };
+
+    let mut inner = state.inner.lock().await;
+    if !inner.recent.insert(delivery_id) {
+        info!("Skipping duplicate delivery {}", delivery_id);
+        return (StatusCode::ACCEPTED, "Duplicate delivery".to_string());
+    }
+
+    match inner.pipeline.ingest_batch([row.encode_to_vec()]).await {
+        Ok(()) => {
+            info!("Ingested {} event {}", event_type, delivery_id);
+            (StatusCode::ACCEPTED, String::new())
+        }
+        Err(e) => {
+            // Accept the delivery when it is sent again
+            inner.recent.remove(delivery_id);
+            error!("Failed to ingest delivery {}: {:#}", delivery_id, e);
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e))
+        }
+    }
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Result<&'a str> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| !value.is_empty())
+        .with_context(|| format!("Missing {} header", name))
+}
+
+/// Current time as (microseconds, days) since Unix epoch
+fn ingestion_time() -> Result<(i64, i32)> {
+    let since_epoch = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .context("Failed to get system time")?;
+    Ok((
+        since_epoch.as_micros() as i64,
+        since_epoch.as_secs() as i32 / 86400,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::github_events::TableGithubEvents;
+    use crate::signature::sign;
+    use axum::body::Body;
+    use axum::http::Request;
+    use tower::ServiceExt;
+    use zerobus_common::testing::MockSink;
+
+    const SECRET: &str = "test-secret";
+    const PUSH_EVENT: &[u8] = include_bytes!("../testdata/push_event.json");
+
+    fn app(sink: MockSink) -> Router {
+        router(AppState::new(Pipeline::new(sink, 100), SECRET, 10))
+    }
+
+    fn delivery(delivery_id: &str, signature: Option<String>) -> Request<Body> {
+        let mut request = Request::post("/github/webhook")
+            .header("Content-Type", "application/json")
+            .header(EVENT_HEADER, "push")
+            .header(DELIVERY_HEADER, delivery_id);
+        if let Some(signature) = signature {
+            request = request.header(SIGNATURE_HEADER, signature);
+        }
+        request.body(Body::from(PUSH_EVENT)).unwrap()
+    }
+
+    fn signed(delivery_id: &str) -> Request<Body> {
+        delivery(delivery_id, Some(sign(SECRET.as_bytes(), PUSH_EVENT)))
+    }
+
+    async fn send(app: Router, request: Request<Body>) -> StatusCode {
+        app.oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn test_captured_push_event_is_ingested() {
+        let sink = MockSink::default();
+
+        let status = send(app(sink.clone()), signed("72d3162e-cc78")).await;
+
+        assert_eq!(StatusCode::ACCEPTED, status);
+        let records = sink.records();
+        assert_eq!(1, records.len());
+        let row = TableGithubEvents::decode(records[0].as_slice()).unwrap();
+        assert_eq!(Some("72d3162e-cc78".to_string()), row.delivery_id);
+        assert_eq!(Some("push".to_string()), row.event_type);
+        assert_eq!(
+            Some("baxterthehacker/public-repo".to_string()),
+            row.repository
+        );
+        assert_eq!(Some("baxterthehacker".to_string()), row.sender);
+        assert_eq!(1, sink.flushes());
+    }
+
+    #[tokio::test]
+    async fn test_bad_signatures_are_unauthorized() {
+        let sink = MockSink::default();
+        let app = app(sink.clone());
+
+        let forged = sign(b"another secret", PUSH_EVENT);
+        let status_forged = send(app.clone(), delivery("a", Some(forged))).await;
+        let status_missing = send(app, delivery("b", None)).await;
+
+        assert_eq!(StatusCode::UNAUTHORIZED, status_forged);
+        assert_eq!(StatusCode::UNAUTHORIZED, status_missing);
+        assert!(sink.records().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_missing_headers_are_bad_request() {
+        let sink = MockSink::default();
+        let request = Request::post("/github/webhook")
+            .header(SIGNATURE_HEADER, sign(SECRET.as_bytes(), PUSH_EVENT))
+            .header(EVENT_HEADER, "push")
+            .body(Body::from(PUSH_EVENT))
+            .unwrap();
+
+        let status = send(app(sink.clone()), request).await;
+
+        assert_eq!(StatusCode::BAD_REQUEST, status);
+        assert!(sink.records().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_form_encoded_payload_is_bad_request() {
+        let body = b"payload=%7B%22zen%22%3A%22Keep%20it%20logically%20awesome.%22%7D";
+        let request = Request::post("/github/webhook")
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .header(SIGNATURE_HEADER, sign(SECRET.as_bytes(), body))
+            .header(EVENT_HEADER, "ping")
+            .header(DELIVERY_HEADER, "c")
+            .body(Body::from(&body[..]))
+            .unwrap();
+
+        assert_eq!(
+            StatusCode::BAD_REQUEST,
+            send(app(MockSink::default()), request).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_redelivery_is_ingested_once() {
+        let sink = MockSink::default();
+        let app = app(sink.clone());
+
+        assert_eq!(StatusCode::ACCEPTED, send(app.clone(), signed("a")).await);
+        assert_eq!(StatusCode::ACCEPTED, send(app.clone(), signed("a")).await);
+        assert_eq!(StatusCode::ACCEPTED, send(app, signed("b")).await);
+
+        assert_eq!(2, sink.records().len());
+    }
+
+    #[tokio::test]
+    async fn test_failed_delivery_can_be_redelivered() {
+        let failing = Arc::new(std::sync::atomic::AtomicBool::new(true));
+        let should_fail = Arc::clone(&failing);
+        let sink = MockSink::default()
+            .fail_acks_for(move |_| should_fail.load(std::sync::atomic::Ordering::SeqCst));
+        let app = app(sink.clone());
+
+        let status_failed = send(app.clone(), signed("a")).await;
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, status_failed);
+
+        failing.store(false, std::sync::atomic::Ordering::SeqCst);
+        assert_eq!(StatusCode::ACCEPTED, send(app, signed("a")).await);
+        assert_eq!(2, sink.records().len());
+    }
+}
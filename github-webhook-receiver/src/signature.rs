@@ -0,0 +1,76 @@
+//! Verifying the HMAC signature GitHub sends with every delivery.
+
+use anyhow::{anyhow, Context, Result};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Header holding `sha256=<hex>`, the HMAC-SHA256 of the body keyed with the webhook secret
+pub const SIGNATURE_HEADER: &str = "X-Hub-Signature-256";
+
+/// Check `signature`, the value of the signature header, against the body
+///
+/// The digest is compared in constant time, so response times do not reveal how much
+/// of a forged signature was right.
+pub fn verify(secret: &[u8], body: &[u8], signature: Option<&str>) -> Result<()> {
+    let signature = signature.with_context(|| format!("Missing {} header", SIGNATURE_HEADER))?;
+    let digest = signature
+        .strip_prefix("sha256=")
+        .and_then(|digest| hex::decode(digest).ok())
+        .with_context(|| format!("Malformed {} header", SIGNATURE_HEADER))?;
+
+    mac(secret, body)
+        .verify_slice(&digest)
+        .map_err(|_| anyhow!("Signature does not match the payload"))
+}
+
+/// The signature header value for `body`, as GitHub computes it
+pub fn sign(secret: &[u8], body: &[u8]) -> String {
+    format!(
+        "sha256={}",
+        hex::encode(mac(secret, body).finalize().into_bytes())
+    )
+}
+
+fn mac(secret: &[u8], body: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(body);
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Example from GitHub's "Validating webhook deliveries" guide
+    const SECRET: &[u8] = b"It's a Secret to Everybody";
+    const BODY: &[u8] = b"Hello, World!";
+    const SIGNATURE: &str =
+        "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
+
+    #[test]
+    fn test_valid_signature() {
+        assert_eq!(SIGNATURE, sign(SECRET, BODY));
+        verify(SECRET, BODY, Some(SIGNATURE)).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_signature() {
+        let error = verify(b"wrong secret", BODY, Some(SIGNATURE)).unwrap_err();
+        assert_eq!("Signature does not match the payload", error.to_string());
+        assert!(verify(SECRET, b"Hello, World?", Some(SIGNATURE)).is_err());
+
+        // SHA-1 signatures from the legacy X-Hub-Signature header are not accepted
+        let sha1 = "sha1=01dc10d0c83e72ed246219cdd91669667fe2ca59";
+        assert!(verify(SECRET, BODY, Some(sha1)).is_err());
+        assert!(verify(SECRET, BODY, Some("sha256=not-hex")).is_err());
+        assert!(verify(SECRET, BODY, Some(&SIGNATURE[..20])).is_err());
+    }
+
+    #[test]
+    fn test_missing_signature() {
+        let error = verify(SECRET, BODY, None).unwrap_err();
+        assert_eq!("Missing X-Hub-Signature-256 header", error.to_string());
+    }
+}
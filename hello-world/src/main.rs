@@ -2,6 +2,8 @@ use anyhow::Result;
 use databricks_zerobus_ingest_sdk::{ZerobusSdk, TableProperties, StreamConfigurationOptions};
 use prost::Message;
 use prost_types::DescriptorProto;
+use zerobus_common::clock::{Clock, SystemClock};
+use zerobus_common::descriptor::load_environment_descriptor_proto;
 
 // Example protobuf message - in a real application, this would be generated
 // from your Unity Catalog table schema using the zerobus CLI tool
@@ -43,7 +45,7 @@ async fn main() -> Result<()> {
     let descriptor_proto = load_descriptor_proto(
         "zerobus_hello_world.proto",
         "table_zerobus_hello_world"
-    );
+    )?;
 
     println!("Initializing Zerobus SDK...");
 
@@ -80,13 +82,7 @@ async fn main() -> Result<()> {
     println!("Stream created successfully!");
 
     // Step 5: Create and encode a hello world message
-    let now = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)?
-        .as_micros() as i64; // Convert to microseconds
-    let hello_msg = TableZerobusHelloWorld {
-        msg: Some("Hello, Zerobus!".to_string()),
-        ingested_at: Some(now),
-    };
+    let hello_msg = hello_message(&SystemClock)?;
 
     println!("\nSending message: {}", hello_msg.msg.as_ref().unwrap());
 
@@ -116,26 +112,91 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
+/// The hello world message, stamped with the time `clock` reads
+fn hello_message(clock: &dyn Clock) -> Result<TableZerobusHelloWorld> {
+    let now = clock.now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_micros() as i64; // Convert to microseconds
+    Ok(TableZerobusHelloWorld {
+        msg: Some("Hello, Zerobus!".to_string()),
+        ingested_at: Some(now),
+    })
+}
+
+// Embed the descriptor files at compile time, one per environment
+const DESCRIPTOR_SETS: &[(&str, &[u8])] = &[
+    ("dev", include_bytes!("../gen/descriptors/zerobus_hello_world_dev.descriptor")),
+    ("prod", include_bytes!("../gen/descriptors/zerobus_hello_world_prod.descriptor")),
+];
+
+// Pick the descriptor for ENVIRONMENT (the first when it is unset); an environment
+// without one is an error
 fn load_descriptor_proto(
     file_name: &str,
     message_name: &str
-) -> DescriptorProto {
-    // Embed the descriptor file at compile time
-    const DESCRIPTOR_BYTES: &[u8] = include_bytes!("../gen/descriptors/zerobus_hello_world.descriptor");
-    
-    let file_descriptor_set = prost_types::FileDescriptorSet::decode(
-        DESCRIPTOR_BYTES
-    ).unwrap();
-
-    let file_descriptor_proto = file_descriptor_set
-        .file
-        .into_iter()
-        .find(|f| f.name.as_ref().map(|n| n.as_str()) == Some(file_name))
-        .expect("File descriptor not found");
-
-    file_descriptor_proto
-        .message_type
-        .into_iter()
-        .find(|m| m.name.as_ref().map(|n| n.as_str()) == Some(message_name))
-        .expect("Message descriptor not found")
-}
\ No newline at end of file
+) -> Result<DescriptorProto> {
+    load_environment_descriptor_proto(DESCRIPTOR_SETS, file_name, message_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+    use zerobus_common::clock::FixedClock;
+    use zerobus_common::descriptor;
+    use zerobus_common::testing::conformance::{assert_conforms, UNICODE};
+    use zerobus_common::testing::handler::with_env;
+    use zerobus_common::testing::hex_dump;
+
+    #[test]
+    fn test_hello_message_snapshot() {
+        let clock = FixedClock::at_unix_secs(1_718_020_860);
+        let encoded = hello_message(&clock).unwrap().encode_to_vec();
+        let decoded = TableZerobusHelloWorld::decode(encoded.as_slice()).unwrap();
+
+        insta::assert_debug_snapshot!("hello_message_fields", decoded);
+        insta::assert_snapshot!("hello_message_bytes", hex_dump(&encoded));
+    }
+
+    #[test]
+    fn test_environment_selects_the_embedded_descriptor() {
+        let load = |environment| {
+            with_env(&[("ENVIRONMENT", environment)], || {
+                load_descriptor_proto("zerobus_hello_world.proto", "table_zerobus_hello_world")
+            })
+        };
+        for (environment, bytes) in DESCRIPTOR_SETS {
+            let expected = descriptor::load_descriptor_proto(bytes, "zerobus_hello_world.proto", "table_zerobus_hello_world");
+            assert_eq!(expected, load(environment).unwrap());
+        }
+
+        let error = load("staging").unwrap_err();
+        assert_eq!("No descriptor set embedded for ENVIRONMENT=staging (available: dev, prod)", error.to_string());
+    }
+
+    /// The JSON a generic tool would be handed for `row`
+    fn row_json(row: &TableZerobusHelloWorld) -> Value {
+        json!({"msg": row.msg, "ingested_at": row.ingested_at})
+    }
+
+    #[test]
+    fn test_dynamic_encoder_conforms_to_prost() {
+        let descriptor = with_env(&[], || load_descriptor_proto("zerobus_hello_world.proto", "table_zerobus_hello_world")).unwrap();
+        let row = |msg: &str, ingested_at| TableZerobusHelloWorld {
+            msg: Some(msg.to_string()),
+            ingested_at: Some(ingested_at),
+        };
+        let cases = [
+            ("empty", TableZerobusHelloWorld::default()),
+            ("all_fields", row("Hello, Zerobus!", 1_718_020_860_000_000)),
+            ("unicode", row(UNICODE, 1_718_020_860_000_000)),
+            ("max_ints", row("", i64::MAX)),
+            ("min_ints", row("", i64::MIN)),
+        ];
+
+        for (case, row) in cases {
+            let encoded = assert_conforms(&descriptor, case, &row, &row_json(&row));
+            insta::assert_snapshot!(format!("conformance_{}", case), hex_dump(&encoded));
+        }
+    }
+}
@@ -0,0 +1,42 @@
+//! Runs the example binary against a fake Zerobus server on localhost.
+
+use fake_zerobus_server::FakeZerobus;
+use hello_world_proto::TableZerobusHelloWorld;
+use prost::Message;
+use std::process::Command;
+
+mod hello_world_proto {
+    include!("../gen/rust/zerobus_hello_world.rs");
+}
+
+#[tokio::test]
+async fn test_hello_world_message_is_acknowledged() {
+    let server = FakeZerobus::new().start().await.unwrap();
+
+    let mut command = Command::new(env!("CARGO_BIN_EXE_hello-world"));
+    command
+        .envs(server.env())
+        .env("TABLE_NAME", "main.default.zerobus_hello_world");
+    let output = tokio::task::spawn_blocking(move || command.output())
+        .await
+        .unwrap()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "{}{}",
+        stdout,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(
+        stdout.contains("Message acknowledged successfully!"),
+        "{}",
+        stdout
+    );
+
+    let acked = server.acked_records();
+    assert_eq!(1, acked.len());
+    assert_eq!("main.default.zerobus_hello_world", acked[0].table_name);
+    let message = TableZerobusHelloWorld::decode(acked[0].payload.as_slice()).unwrap();
+    assert_eq!(Some("Hello, Zerobus!".to_string()), message.msg);
+}
@@ -0,0 +1,44 @@
+//! The compiled `table_zerobus_hello_world` descriptor against the live table named by
+//! `SCHEMA_CHECK_TABLE`
+//!
+//! The example is a single binary, so this reads the descriptor files it embeds, picking
+//! the one for `ENVIRONMENT` as the example does. Needs
+//! `DATABRICKS_HOST`, `DATABRICKS_CLIENT_ID`, and `DATABRICKS_CLIENT_SECRET`; without
+//! `SCHEMA_CHECK_TABLE` the test passes without checking anything.
+
+use zerobus_common::auth::StreamAuth;
+use zerobus_common::descriptor::load_environment_descriptor_proto;
+use zerobus_common::schema_check;
+
+const DESCRIPTOR_SETS: &[(&str, &[u8])] = &[
+    (
+        "dev",
+        include_bytes!("../gen/descriptors/zerobus_hello_world_dev.descriptor"),
+    ),
+    (
+        "prod",
+        include_bytes!("../gen/descriptors/zerobus_hello_world_prod.descriptor"),
+    ),
+];
+
+#[tokio::test]
+async fn test_descriptor_matches_the_live_table() {
+    let Ok(table_name) = std::env::var("SCHEMA_CHECK_TABLE") else {
+        eprintln!("SCHEMA_CHECK_TABLE is not set, skipping the live schema check");
+        return;
+    };
+    let databricks_host = std::env::var("DATABRICKS_HOST").expect("DATABRICKS_HOST must be set");
+    let auth = StreamAuth::from_env().await.unwrap();
+    let descriptor_proto = load_environment_descriptor_proto(
+        DESCRIPTOR_SETS,
+        "zerobus_hello_world.proto",
+        "table_zerobus_hello_world",
+    )
+    .unwrap();
+
+    if let Err(error) =
+        schema_check::check(&databricks_host, &auth, &table_name, &descriptor_proto).await
+    {
+        panic!("{:#}", error);
+    }
+}
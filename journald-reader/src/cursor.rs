@@ -0,0 +1,68 @@
+//! The file holding the cursor of the last entry whose row was acknowledged
+
+use anyhow::{Context, Result};
+use std::path::PathBuf;
+use zerobus_common::durable;
+
+pub struct CursorFile {
+    path: PathBuf,
+}
+
+impl CursorFile {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// The saved cursor, or `None` on the first start
+    ///
+    /// A file without a cursor is an error rather than a first start, which would
+    /// resume from `JOURNAL_START` instead of the last acknowledged entry.
+    pub async fn load(&self) -> Result<Option<String>> {
+        let path = self.path.clone();
+        let Some(contents) = tokio::task::spawn_blocking(move || durable::read_file(&path))
+            .await
+            .context("Failed to read the cursor")??
+        else {
+            return Ok(None);
+        };
+        let cursor = String::from_utf8(contents)
+            .ok()
+            .map(|cursor| cursor.trim().to_string())
+            .filter(|cursor| !cursor.is_empty())
+            .with_context(|| format!("{} holds no cursor", self.path.display()))?;
+        Ok(Some(cursor))
+    }
+
+    /// Replaced with [`durable::replace_file`], so it survives a crash
+    pub async fn save(&self, cursor: &str) -> Result<()> {
+        let (path, contents) = (self.path.clone(), format!("{}\n", cursor));
+        tokio::task::spawn_blocking(move || durable::replace_file(&path, contents.as_bytes()))
+            .await
+            .context("Failed to save the cursor")?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_save_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state").join("cursor");
+        let file = CursorFile::new(&path);
+
+        assert_eq!(None, file.load().await.unwrap());
+        file.save("s=1;i=4ece7").await.unwrap();
+        file.save("s=1;i=4ece9").await.unwrap();
+
+        // Reopened, as on the next start
+        let file = CursorFile::new(&path);
+        assert_eq!(Some("s=1;i=4ece9".to_string()), file.load().await.unwrap());
+        assert!(!path.with_extension("tmp").exists());
+
+        // Left empty by a crash, it must not read as a first start
+        tokio::fs::write(&path, "\n").await.unwrap();
+        assert!(file.load().await.is_err());
+    }
+}
@@ -0,0 +1,339 @@
+//! Parsing the [journal export format] that `journalctl -o export` writes
+//!
+//! Each entry is a run of fields ended by an empty line. A field is either a text line,
+//! `NAME=value`, or, when the value is not plain text (it has a newline, a control
+//! character, or invalid UTF-8), binary: the name on a line of its own, the value's
+//! length as a 64-bit little-endian integer, the value, and a newline.
+//!
+//! [journal export format]: https://systemd.io/JOURNAL_EXPORT_FORMATS/#journal-export-format
+
+use anyhow::{bail, Result};
+
+/// Largest binary field accepted, well above journald's own limit on entry sizes
+pub const MAX_FIELD_SIZE: u64 = 64 * 1024 * 1024;
+
+/// One journal entry, with its fields in the order they were written
+///
+/// A field may be written more than once; each value is kept.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Entry {
+    pub fields: Vec<(String, Vec<u8>)>,
+}
+
+impl Entry {
+    /// The first value of `name`
+    pub fn get(&self, name: &str) -> Option<&[u8]> {
+        self.fields
+            .iter()
+            .find(|(field, _)| field == name)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    /// The first value of `name`, if it is UTF-8
+    pub fn get_str(&self, name: &str) -> Option<&str> {
+        self.get(name)
+            .and_then(|value| std::str::from_utf8(value).ok())
+    }
+
+    /// `__CURSOR`, which `journalctl --after-cursor` resumes after
+    pub fn cursor(&self) -> Option<&str> {
+        self.get_str("__CURSOR")
+    }
+}
+
+/// Parses entries out of export output as it arrives, in chunks of any size
+#[derive(Debug, Default)]
+pub struct ExportParser {
+    buffer: Vec<u8>,
+    /// Start of the first field not yet parsed
+    position: usize,
+    /// Fields of the entry being parsed
+    fields: Vec<(String, Vec<u8>)>,
+}
+
+impl ExportParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append output read from `journalctl`
+    pub fn feed(&mut self, data: &[u8]) {
+        // Drop what has been parsed before growing the buffer
+        if self.position > 0 {
+            self.buffer.drain(..self.position);
+            self.position = 0;
+        }
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// The next complete entry, or `None` until more output is fed
+    pub fn next_entry(&mut self) -> Result<Option<Entry>> {
+        loop {
+            let rest = &self.buffer[self.position..];
+            let Some(newline) = rest.iter().position(|&byte| byte == b'\n') else {
+                return Ok(None);
+            };
+
+            if newline == 0 {
+                self.position += 1;
+                if self.fields.is_empty() {
+                    // Blank lines between entries carry nothing
+                    continue;
+                }
+                return Ok(Some(Entry {
+                    fields: std::mem::take(&mut self.fields),
+                }));
+            }
+
+            let line = &rest[..newline];
+            if let Some(equals) = line.iter().position(|&byte| byte == b'=') {
+                let name = field_name(&line[..equals])?;
+                let value = line[equals + 1..].to_vec();
+                self.fields.push((name, value));
+                self.position += newline + 1;
+                continue;
+            }
+
+            // A binary field: the name, then the size and the value after the newline
+            let name = field_name(line)?;
+            let data = &rest[newline + 1..];
+            let Some(size) = data
+                .get(..8)
+                .and_then(|size| <[u8; 8]>::try_from(size).ok())
+            else {
+                return Ok(None);
+            };
+            let size = u64::from_le_bytes(size);
+            if size > MAX_FIELD_SIZE {
+                bail!(
+                    "Binary field {} is {} bytes, more than the {} accepted",
+                    name,
+                    size,
+                    MAX_FIELD_SIZE
+                );
+            }
+            let size = size as usize;
+            let Some(&terminator) = data.get(8 + size) else {
+                return Ok(None);
+            };
+            if terminator != b'\n' {
+                bail!(
+                    "Binary field {} of {} bytes is not followed by a newline",
+                    name,
+                    size
+                );
+            }
+            let value = data[8..8 + size].to_vec();
+            self.fields.push((name, value));
+            self.position += newline + 1 + 8 + size + 1;
+        }
+    }
+
+    /// The output has ended; fails if it ended partway through a field
+    ///
+    /// An entry whose fields are complete but that is missing its closing empty line is
+    /// returned, since the output may have been cut off right after it.
+    pub fn finish(&mut self) -> Result<Option<Entry>> {
+        let entry = self.next_entry()?;
+        if entry.is_some() {
+            return Ok(entry);
+        }
+        if self.position < self.buffer.len() {
+            bail!(
+                "Export output ends early, partway through a field ({} bytes left over)",
+                self.buffer.len() - self.position
+            );
+        }
+        if self.fields.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Entry {
+            fields: std::mem::take(&mut self.fields),
+        }))
+    }
+}
+
+/// Field names are uppercase letters, digits, and underscores, and do not start with a
+/// digit
+fn field_name(bytes: &[u8]) -> Result<String> {
+    let valid = !bytes.is_empty()
+        && !bytes[0].is_ascii_digit()
+        && bytes
+            .iter()
+            .all(|&byte| byte.is_ascii_uppercase() || byte.is_ascii_digit() || byte == b'_');
+    if !valid {
+        bail!(
+            "Invalid field name {:?}",
+            String::from_utf8_lossy(&bytes[..bytes.len().min(64)])
+        );
+    }
+    Ok(String::from_utf8(bytes.to_vec())?)
+}
+
+#[cfg(test)]
+pub(crate) mod testing {
+    /// A fixture under `tests/fixtures`
+    pub fn fixture(name: &str) -> Vec<u8> {
+        let path = format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name);
+        std::fs::read(&path).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::fixture;
+    use super::*;
+
+    /// Every entry of `output`, fed in chunks of `chunk` bytes
+    fn parse(output: &[u8], chunk: usize) -> Result<Vec<Entry>> {
+        let mut parser = ExportParser::new();
+        let mut entries = Vec::new();
+        for data in output.chunks(chunk) {
+            parser.feed(data);
+            while let Some(entry) = parser.next_entry()? {
+                entries.push(entry);
+            }
+        }
+        entries.extend(parser.finish()?);
+        Ok(entries)
+    }
+
+    fn value<'a>(entry: &'a Entry, name: &str) -> &'a str {
+        entry.get_str(name).unwrap()
+    }
+
+    #[test]
+    fn test_text_fields() {
+        let entries = parse(&fixture("text.export"), usize::MAX).unwrap();
+        assert_eq!(3, entries.len());
+
+        let entry = &entries[0];
+        assert_eq!(
+            concat!(
+                "s=739ad463348b4ceca5a9e69c95a3c93f;i=4ece7;b=6c7c6013a8854b66a5e2a5d5c9e7c1a3;",
+                "m=1b2a5c;t=61a87e3821240;x=8d9b1f0ce4a1c72e"
+            ),
+            entry.cursor().unwrap()
+        );
+        assert_eq!("1718020800123456", value(entry, "__REALTIME_TIMESTAMP"));
+        assert_eq!("web-01", value(entry, "_HOSTNAME"));
+        assert_eq!("nginx.service", value(entry, "_SYSTEMD_UNIT"));
+        assert_eq!("6", value(entry, "PRIORITY"));
+        assert_eq!("1234", value(entry, "_PID"));
+        assert_eq!("GET /health HTTP/1.1 200", value(entry, "MESSAGE"));
+
+        // A value may itself contain '=': only the first one separates the name
+        let entry = &entries[1];
+        assert_eq!("query=a=b&c=d", value(entry, "MESSAGE"));
+        assert_eq!("", value(entry, "EMPTY_FIELD"));
+
+        assert_eq!("kernel", value(&entries[2], "_TRANSPORT"));
+        assert_eq!(None, entries[2].get("_SYSTEMD_UNIT"));
+    }
+
+    #[test]
+    fn test_binary_fields() {
+        let entries = parse(&fixture("binary.export"), usize::MAX).unwrap();
+        assert_eq!(3, entries.len());
+
+        // A multi-line message is written as a binary field
+        let entry = &entries[0];
+        assert_eq!(
+            "Traceback (most recent call last):\n  File \"app.py\", line 3\nValueError: bad\n",
+            value(entry, "MESSAGE")
+        );
+        assert_eq!("app.service", value(entry, "_SYSTEMD_UNIT"));
+
+        // Binary values are raw bytes: invalid UTF-8, and embedded empty lines and
+        // '=' that would end the entry or split the field if they were text
+        let entry = &entries[1];
+        assert_eq!(Some(&[0xff, 0xfe, 0x00, 0x41][..]), entry.get("MESSAGE"));
+        assert_eq!(None, entry.get_str("MESSAGE"));
+        assert_eq!("a=b\n\nc=d", value(entry, "BLOB"));
+        assert_eq!("sshd", value(entry, "SYSLOG_IDENTIFIER"));
+
+        // An empty binary value, and a repeated field keeping every value
+        let entry = &entries[2];
+        assert_eq!(Some(&[][..]), entry.get("EMPTY"));
+        let tags: Vec<&[u8]> = entry
+            .fields
+            .iter()
+            .filter(|(name, _)| name == "TAG")
+            .map(|(_, value)| value.as_slice())
+            .collect();
+        assert_eq!(vec![&b"first"[..], &b"second\nline"[..]], tags);
+        assert_eq!("first", value(entry, "TAG"));
+    }
+
+    #[test]
+    fn test_chunking_does_not_change_entries() {
+        for name in ["text.export", "binary.export"] {
+            let output = fixture(name);
+            let whole = parse(&output, usize::MAX).unwrap();
+            for chunk in [1, 2, 7, 8, 9, 64] {
+                assert_eq!(
+                    whole,
+                    parse(&output, chunk).unwrap(),
+                    "{} by {}",
+                    name,
+                    chunk
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_entries_are_returned_as_they_complete() {
+        let output = fixture("text.export");
+        let first_entry_end = output.windows(2).position(|pair| pair == b"\n\n").unwrap() + 2;
+
+        let mut parser = ExportParser::new();
+        parser.feed(&output[..first_entry_end - 1]);
+        assert_eq!(None, parser.next_entry().unwrap());
+        parser.feed(&output[first_entry_end - 1..first_entry_end]);
+        assert!(parser.next_entry().unwrap().is_some());
+        assert_eq!(None, parser.next_entry().unwrap());
+    }
+
+    #[test]
+    fn test_truncated_output() {
+        let error = parse(&fixture("truncated.export"), 16).unwrap_err();
+        assert!(error.to_string().contains("ends early"), "{}", error);
+
+        // Cut off after a complete field but before the closing empty line
+        let entries = parse(b"__CURSOR=s=1\nMESSAGE=hi\n", 4).unwrap();
+        assert_eq!(1, entries.len());
+        assert_eq!("hi", value(&entries[0], "MESSAGE"));
+    }
+
+    #[test]
+    fn test_malformed_output() {
+        let mut binary = b"MESSAGE\n".to_vec();
+        binary.extend_from_slice(&3u64.to_le_bytes());
+        binary.extend_from_slice(b"abcX\n\n");
+        let error = parse(&binary, usize::MAX).unwrap_err();
+        assert!(
+            error.to_string().contains("not followed by a newline"),
+            "{}",
+            error
+        );
+
+        let mut oversized = b"MESSAGE\n".to_vec();
+        oversized.extend_from_slice(&(MAX_FIELD_SIZE + 1).to_le_bytes());
+        let error = parse(&oversized, usize::MAX).unwrap_err();
+        assert!(error.to_string().contains("more than"), "{}", error);
+
+        for output in [
+            &b"message=lowercase\n\n"[..],
+            &b"1ST=digit\n\n"[..],
+            &b"=empty name\n\n"[..],
+        ] {
+            let error = parse(output, usize::MAX).unwrap_err();
+            assert!(
+                error.to_string().contains("Invalid field name"),
+                "{}",
+                error
+            );
+        }
+    }
+}
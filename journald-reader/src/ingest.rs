@@ -0,0 +1,173 @@
+//! Ingesting journal entries and tracking which cursor is safe to resume after
+
+use anyhow::{bail, Result};
+use prost::Message;
+use std::collections::VecDeque;
+use tracing::warn;
+use zerobus_common::pipeline::{IngestSink, IngestSummary, Pipeline};
+
+use crate::export::Entry;
+use crate::row::to_row;
+
+/// Ingests entries in journal order and works out the cursor to checkpoint
+///
+/// An entry's cursor is only checkpointed once its row and every row before it have been
+/// acknowledged, so a restart resumes after the last entry known to be in the table. If
+/// a row is not acknowledged the cursor stops advancing and an error is returned;
+/// restarting reads everything after the last checkpointed entry again.
+pub struct JournalIngestor<S: IngestSink> {
+    pipeline: Pipeline<S>,
+    /// Rows handed to the pipeline so far
+    sent: u64,
+    /// Cursors not yet acknowledged, with the number of rows sent up to each
+    cursors: VecDeque<(u64, String)>,
+    acknowledged: Option<String>,
+}
+
+impl<S: IngestSink> JournalIngestor<S> {
+    /// `cursor` is where reading resumes, i.e. the last checkpointed cursor
+    pub fn new(pipeline: Pipeline<S>, cursor: Option<String>) -> Self {
+        Self {
+            pipeline,
+            sent: 0,
+            cursors: VecDeque::new(),
+            acknowledged: cursor,
+        }
+    }
+
+    /// Ingest the row of `entry`
+    ///
+    /// `ingested_at` is microseconds since Unix epoch.
+    pub async fn handle(&mut self, entry: &Entry, ingested_at: i64) -> Result<()> {
+        let row = to_row(entry, ingested_at);
+        self.pipeline.ingest(row.encode_to_vec()).await?;
+        self.sent += 1;
+        match entry.cursor() {
+            Some(cursor) => self.cursors.push_back((self.sent, cursor.to_string())),
+            None => warn!("Journal entry has no __CURSOR; it cannot be checkpointed"),
+        }
+        self.advance()?;
+        Ok(())
+    }
+
+    /// Wait for every outstanding ack and return the cursor that is safe to checkpoint
+    pub async fn checkpoint(&mut self) -> Result<Option<&str>> {
+        self.pipeline.drain().await?;
+        self.advance()?;
+        Ok(self.acknowledged())
+    }
+
+    /// Cursor of the latest entry whose row, and every row before it, is acknowledged
+    pub fn acknowledged(&self) -> Option<&str> {
+        self.acknowledged.as_deref()
+    }
+
+    /// Drain outstanding acks and close the sink
+    pub async fn finish(mut self) -> Result<(IngestSummary, Option<String>)> {
+        let checkpointed = self.checkpoint().await.map(|_| ());
+        let summary = self.pipeline.finish().await?;
+        checkpointed?;
+        Ok((summary, self.acknowledged))
+    }
+
+    /// Move the cursor past every entry whose row has been acknowledged
+    fn advance(&mut self) -> Result<()> {
+        let summary = self.pipeline.summary();
+        if summary.failed > 0 {
+            // Acks after a failure no longer line up with the rows sent, so nothing
+            // past the last acknowledged cursor can be trusted
+            bail!(
+                "{} rows were not acknowledged: {}",
+                summary.failed,
+                summary.first_error.as_deref().unwrap_or("unknown error")
+            );
+        }
+        while let Some((rows, _)) = self.cursors.front() {
+            if *rows > summary.ingested {
+                break;
+            }
+            self.acknowledged = self.cursors.pop_front().map(|(_, cursor)| cursor);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::export::testing::fixture;
+    use crate::export::ExportParser;
+    use crate::proto::journal_entries::TableJournalEntries;
+    use zerobus_common::testing::MockSink;
+
+    const INGESTED_AT: i64 = 1_718_020_860_000_000;
+
+    fn entries() -> Vec<Entry> {
+        let mut parser = ExportParser::new();
+        parser.feed(&fixture("text.export"));
+        parser.feed(&fixture("binary.export"));
+        let mut entries = Vec::new();
+        while let Some(entry) = parser.next_entry().unwrap() {
+            entries.push(entry);
+        }
+        entries
+    }
+
+    #[tokio::test]
+    async fn test_cursor_advances_after_acks() {
+        let entries = entries();
+        let sink = MockSink::default();
+        let mut ingestor =
+            JournalIngestor::new(Pipeline::new(sink.clone(), 100), Some("s=0".to_string()));
+
+        for entry in &entries[..3] {
+            ingestor.handle(entry, INGESTED_AT).await.unwrap();
+        }
+        assert_eq!(3, sink.records().len());
+        assert_eq!(Some("s=0"), ingestor.acknowledged());
+
+        assert_eq!(entries[2].cursor(), ingestor.checkpoint().await.unwrap());
+
+        for entry in &entries[3..] {
+            ingestor.handle(entry, INGESTED_AT).await.unwrap();
+        }
+        let (summary, cursor) = ingestor.finish().await.unwrap();
+        assert_eq!(6, summary.ingested);
+        assert_eq!(entries[5].cursor(), cursor.as_deref());
+        assert!(sink.closed());
+    }
+
+    #[tokio::test]
+    async fn test_window_drain_advances_cursor() {
+        // With a window of one row, sending each row drains the previous one
+        let entries = entries();
+        let mut ingestor = JournalIngestor::new(Pipeline::new(MockSink::default(), 1), None);
+        for entry in &entries[..3] {
+            ingestor.handle(entry, INGESTED_AT).await.unwrap();
+        }
+        assert_eq!(entries[1].cursor(), ingestor.acknowledged());
+    }
+
+    #[tokio::test]
+    async fn test_failed_ack_holds_cursor() {
+        let entries = entries();
+        let sink = MockSink::default().fail_acks_for(|record| {
+            TableJournalEntries::decode(record).unwrap().systemd_unit() == "ssh.service"
+        });
+        let mut ingestor = JournalIngestor::new(Pipeline::new(sink, 100), None);
+
+        for entry in &entries[..4] {
+            ingestor.handle(entry, INGESTED_AT).await.unwrap();
+        }
+        assert_eq!(entries[3].cursor(), ingestor.checkpoint().await.unwrap());
+
+        // The sshd entry is never acknowledged, so neither it nor anything after it is
+        // checkpointed
+        for entry in &entries[4..] {
+            ingestor.handle(entry, INGESTED_AT).await.unwrap();
+        }
+        let error = ingestor.checkpoint().await.unwrap_err();
+        assert!(error.to_string().contains("1 rows were not acknowledged"));
+        assert_eq!(entries[3].cursor(), ingestor.acknowledged());
+    }
+}
@@ -0,0 +1,92 @@
+//! The `journalctl` command line the reader runs
+
+use anyhow::{bail, Result};
+
+/// Where reading starts when there is no saved cursor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Start {
+    /// Only entries written after the reader starts
+    Now,
+    /// Every entry still in the journal
+    All,
+}
+
+impl Start {
+    /// Read the start from `JOURNAL_START`: `now` (the default) or `all`
+    pub fn from_env() -> Result<Self> {
+        match std::env::var("JOURNAL_START") {
+            Ok(value) => Self::new(&value),
+            Err(_) => Ok(Start::Now),
+        }
+    }
+
+    pub fn new(value: &str) -> Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "" | "now" => Ok(Start::Now),
+            "all" => Ok(Start::All),
+            _ => bail!("JOURNAL_START must be now or all, got {:?}", value),
+        }
+    }
+}
+
+/// Arguments of `journalctl`, resuming after `cursor` when there is one
+///
+/// `extra` are appended as-is, e.g. `--unit=nginx.service` or `--priority=warning` to
+/// filter entries. They must not change the output format.
+pub fn args(cursor: Option<&str>, start: Start, extra: &[String]) -> Vec<String> {
+    let mut args = vec!["--output=export".to_string(), "--follow".to_string()];
+    match (cursor, start) {
+        // --follow alone starts from the last 10 entries; --no-tail reads every entry
+        // after the cursor instead
+        (Some(cursor), _) => {
+            args.push(format!("--after-cursor={}", cursor));
+            args.push("--no-tail".to_string());
+        }
+        (None, Start::All) => args.push("--no-tail".to_string()),
+        (None, Start::Now) => args.push("--lines=0".to_string()),
+    }
+    args.extend(extra.iter().cloned());
+    args
+}
+
+/// Split `JOURNALCTL_ARGS` on whitespace
+pub fn extra_args_from_env() -> Vec<String> {
+    std::env::var("JOURNALCTL_ARGS")
+        .map(|value| value.split_whitespace().map(str::to_string).collect())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_args() {
+        let extra = vec!["--unit=nginx.service".to_string()];
+        assert_eq!(
+            vec![
+                "--output=export",
+                "--follow",
+                "--after-cursor=s=1;i=2",
+                "--no-tail",
+                "--unit=nginx.service"
+            ],
+            args(Some("s=1;i=2"), Start::Now, &extra)
+        );
+        assert_eq!(
+            vec!["--output=export", "--follow", "--lines=0"],
+            args(None, Start::Now, &[])
+        );
+        assert_eq!(
+            vec!["--output=export", "--follow", "--no-tail"],
+            args(None, Start::All, &[])
+        );
+    }
+
+    #[test]
+    fn test_start_is_parsed() {
+        assert_eq!(Start::Now, Start::new("").unwrap());
+        assert_eq!(Start::All, Start::new(" ALL ").unwrap());
+        assert!(Start::new("yesterday").is_err());
+    }
+}
@@ -0,0 +1,6 @@
+pub mod cursor;
+pub mod export;
+pub mod ingest;
+pub mod journalctl;
+pub mod proto;
+pub mod row;
@@ -0,0 +1,187 @@
+use anyhow::{bail, Context, Result};
+use databricks_zerobus_ingest_sdk::{StreamConfigurationOptions, TableProperties, ZerobusSdk};
+use journald_reader::cursor::CursorFile;
+use journald_reader::export::ExportParser;
+use journald_reader::ingest::JournalIngestor;
+use journald_reader::journalctl::{self, Start};
+use journald_reader::proto::load_descriptor_proto;
+use std::pin::pin;
+use std::process::Stdio;
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::process::Command;
+use tokio::time::MissedTickBehavior;
+use tracing::{error, info};
+use zerobus_common::pipeline::{IngestSink, Pipeline};
+use zerobus_common::shutdown;
+
+/// Maximum number of unacknowledged records per stream when MAX_INFLIGHT is not set
+const DEFAULT_MAX_INFLIGHT: usize = 10_000;
+
+/// How often the cursor is checkpointed when CHECKPOINT_INTERVAL_SECS is not set
+const DEFAULT_CHECKPOINT_INTERVAL_SECS: u64 = 5;
+
+/// Where the cursor is kept when CURSOR_FILE is not set
+const DEFAULT_CURSOR_FILE: &str = "journald-reader.cursor";
+
+/// Bytes read from journalctl at a time
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .with_target(false)
+        .init();
+
+    let zerobus_endpoint = std::env::var("ZEROBUS_ENDPOINT")
+        .context("ZEROBUS_ENDPOINT environment variable must be set")?;
+    let databricks_host = std::env::var("DATABRICKS_HOST")
+        .context("DATABRICKS_HOST environment variable must be set")?;
+    let client_id = std::env::var("DATABRICKS_CLIENT_ID")
+        .context("DATABRICKS_CLIENT_ID environment variable must be set")?;
+    let client_secret = std::env::var("DATABRICKS_CLIENT_SECRET")
+        .context("DATABRICKS_CLIENT_SECRET environment variable must be set")?;
+    let table_name =
+        std::env::var("TABLE_NAME").context("TABLE_NAME environment variable must be set")?;
+    let max_inflight = positive_env("MAX_INFLIGHT", DEFAULT_MAX_INFLIGHT as u64)? as usize;
+    let checkpoint_interval = Duration::from_secs(positive_env(
+        "CHECKPOINT_INTERVAL_SECS",
+        DEFAULT_CHECKPOINT_INTERVAL_SECS,
+    )?);
+    let cursor_file = CursorFile::new(
+        std::env::var("CURSOR_FILE").unwrap_or_else(|_| DEFAULT_CURSOR_FILE.to_string()),
+    );
+    let program = std::env::var("JOURNALCTL").unwrap_or_else(|_| "journalctl".to_string());
+    let start = Start::from_env()?;
+    let extra_args = journalctl::extra_args_from_env();
+
+    let cursor = cursor_file.load().await?;
+
+    let sdk = ZerobusSdk::new(zerobus_endpoint, databricks_host)?;
+    let table_properties = TableProperties {
+        table_name: table_name.clone(),
+        descriptor_proto: load_descriptor_proto("journal_entries.proto", "table_journal_entries"),
+    };
+    let stream_options = StreamConfigurationOptions {
+        max_inflight_records: max_inflight,
+        ..Default::default()
+    };
+    let stream = sdk
+        .create_stream(
+            table_properties,
+            client_id,
+            client_secret,
+            Some(stream_options),
+        )
+        .await
+        .context("Failed to create stream")?;
+    info!("Created stream to table: {}", table_name);
+
+    let args = journalctl::args(cursor.as_deref(), start, &extra_args);
+    let mut child = Command::new(&program)
+        .args(&args)
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("Failed to run {}", program))?;
+    let stdout = child.stdout.take().context("journalctl has no stdout")?;
+    match &cursor {
+        Some(cursor) => info!("Reading the journal after cursor {}", cursor),
+        None => info!("No saved cursor; reading the journal from {:?}", start),
+    }
+
+    let mut ingestor = JournalIngestor::new(Pipeline::new(stream, max_inflight), cursor.clone());
+    let read = read_entries(stdout, &mut ingestor, &cursor_file, checkpoint_interval).await;
+    if let Err(e) = &read {
+        error!("Reading stopped: {:#}", e);
+    }
+    let _ = child.kill().await;
+
+    // Whatever happened, save the progress that is known to be durable
+    let acknowledged = ingestor.acknowledged().map(str::to_string);
+    let acknowledged = match ingestor.finish().await {
+        Ok((summary, acknowledged)) => {
+            info!(
+                "Shut down: {} rows ingested, {} failed",
+                summary.ingested, summary.failed
+            );
+            acknowledged
+        }
+        Err(e) => {
+            error!("Failed to drain outstanding rows: {:#}", e);
+            acknowledged
+        }
+    };
+    if let Some(acknowledged) = acknowledged.filter(|saved| Some(saved) != cursor.as_ref()) {
+        cursor_file.save(&acknowledged).await?;
+        info!("Saved cursor {}", acknowledged);
+    }
+
+    read
+}
+
+/// Ingest entries from journalctl's output until Ctrl+C or SIGTERM, saving the cursor
+/// every `checkpoint_interval`
+async fn read_entries<S: IngestSink>(
+    mut output: impl AsyncRead + Unpin,
+    ingestor: &mut JournalIngestor<S>,
+    cursor_file: &CursorFile,
+    checkpoint_interval: Duration,
+) -> Result<()> {
+    let mut parser = ExportParser::new();
+    let mut buffer = vec![0; READ_BUFFER_SIZE];
+    let mut saved = ingestor.acknowledged().map(str::to_string);
+    let mut checkpoint = tokio::time::interval(checkpoint_interval);
+    checkpoint.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut shutdown = pin!(shutdown::signal());
+
+    loop {
+        tokio::select! {
+            read = output.read(&mut buffer) => {
+                let read = read.context("Failed to read journalctl output")?;
+                if read == 0 {
+                    if let Some(entry) = parser.finish()? {
+                        ingestor.handle(&entry, now_micros()?).await?;
+                    }
+                    // With --follow, journalctl only exits on an error it has logged
+                    bail!("journalctl exited");
+                }
+                parser.feed(&buffer[..read]);
+                while let Some(entry) = parser.next_entry()? {
+                    ingestor.handle(&entry, now_micros()?).await?;
+                }
+            }
+            _ = checkpoint.tick() => {
+                let acknowledged = ingestor.checkpoint().await?;
+                if let Some(acknowledged) = acknowledged.filter(|a| Some(*a) != saved.as_deref()) {
+                    cursor_file.save(acknowledged).await?;
+                    saved = Some(acknowledged.to_string());
+                }
+            }
+            _ = &mut shutdown => return Ok(()),
+        }
+    }
+}
+
+fn positive_env(name: &str, default: u64) -> Result<u64> {
+    let value = match std::env::var(name) {
+        Ok(value) => value
+            .trim()
+            .parse::<u64>()
+            .with_context(|| format!("{} must be a positive integer, got {:?}", name, value))?,
+        Err(_) => default,
+    };
+    if value == 0 {
+        bail!("{} must be a positive integer, got 0", name);
+    }
+    Ok(value)
+}
+
+/// Current time in microseconds since Unix epoch
+fn now_micros() -> Result<i64> {
+    Ok(SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .context("Failed to get system time")?
+        .as_micros() as i64)
+}
@@ -0,0 +1,195 @@
+//! Mapping journal entries to rows of the table
+
+use prost::bytes::Bytes;
+use std::collections::BTreeMap;
+
+use crate::export::Entry;
+use crate::proto::journal_entries::TableJournalEntries;
+
+/// Build the row of `entry`
+///
+/// The cursor, the realtime timestamp, and the standard fields `_HOSTNAME`,
+/// `_SYSTEMD_UNIT`, `PRIORITY`, `MESSAGE`, `_PID`, and `SYSLOG_IDENTIFIER` go in typed
+/// columns. Every other field goes in `fields`, or in `binary_fields` when its value is
+/// not UTF-8, as does a standard field whose value does not fit its column or that is
+/// repeated. Repeated values of one field are joined with newlines.
+///
+/// A `MESSAGE` that is not UTF-8 is stored in `message` with invalid sequences replaced,
+/// and as-is in `binary_fields`.
+///
+/// `ingested_at` is microseconds since Unix epoch.
+pub fn to_row(entry: &Entry, ingested_at: i64) -> TableJournalEntries {
+    let mut row = TableJournalEntries {
+        ingested_at: Some(ingested_at),
+        ingested_date: Some((ingested_at / 86_400_000_000) as i32),
+        ..Default::default()
+    };
+    let mut binary_fields: BTreeMap<String, Vec<u8>> = BTreeMap::new();
+
+    for (name, value) in &entry.fields {
+        if set_column(&mut row, name, value) {
+            continue;
+        }
+        match std::str::from_utf8(value) {
+            Ok(text) => {
+                row.fields
+                    .entry(name.clone())
+                    .and_modify(|values| {
+                        values.push('\n');
+                        values.push_str(text);
+                    })
+                    .or_insert_with(|| text.to_string());
+            }
+            Err(_) => {
+                if name == "MESSAGE" && row.message.is_none() {
+                    row.message = Some(String::from_utf8_lossy(value).into_owned());
+                }
+                binary_fields
+                    .entry(name.clone())
+                    .and_modify(|values| {
+                        values.push(b'\n');
+                        values.extend_from_slice(value);
+                    })
+                    .or_insert_with(|| value.clone());
+            }
+        }
+    }
+
+    row.binary_fields = binary_fields
+        .into_iter()
+        .map(|(name, value)| (name, Bytes::from(value)))
+        .collect();
+    row
+}
+
+/// Store the first value of a standard field in its column; false if it does not fit
+fn set_column(row: &mut TableJournalEntries, name: &str, value: &[u8]) -> bool {
+    let Ok(text) = std::str::from_utf8(value) else {
+        return false;
+    };
+    match name {
+        "__CURSOR" => set_once(&mut row.cursor, Some(text.to_string())),
+        "__REALTIME_TIMESTAMP" => set_once(&mut row.realtime_timestamp, text.parse().ok()),
+        "_HOSTNAME" => set_once(&mut row.hostname, Some(text.to_string())),
+        "_SYSTEMD_UNIT" => set_once(&mut row.systemd_unit, Some(text.to_string())),
+        // Syslog priorities run from 0 (emerg) to 7 (debug)
+        "PRIORITY" => set_once(
+            &mut row.priority,
+            text.parse()
+                .ok()
+                .filter(|priority| (0..=7).contains(priority)),
+        ),
+        "MESSAGE" => set_once(&mut row.message, Some(text.to_string())),
+        "_PID" => set_once(&mut row.pid, text.parse().ok()),
+        "SYSLOG_IDENTIFIER" => set_once(&mut row.syslog_identifier, Some(text.to_string())),
+        _ => false,
+    }
+}
+
+fn set_once<T>(column: &mut Option<T>, value: Option<T>) -> bool {
+    match (column.is_none(), value) {
+        (true, Some(value)) => {
+            *column = Some(value);
+            true
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::export::testing::fixture;
+    use crate::export::ExportParser;
+
+    const INGESTED_AT: i64 = 1_718_020_860_000_000;
+
+    fn rows(name: &str) -> Vec<TableJournalEntries> {
+        let mut parser = ExportParser::new();
+        parser.feed(&fixture(name));
+        let mut rows = Vec::new();
+        while let Some(entry) = parser.next_entry().unwrap() {
+            rows.push(to_row(&entry, INGESTED_AT));
+        }
+        rows
+    }
+
+    #[test]
+    fn test_standard_fields_are_typed_columns() {
+        let row = &rows("text.export")[0];
+        assert!(row.cursor.as_deref().unwrap().contains(";i=4ece7;"));
+        assert_eq!(Some(1_718_020_800_123_456), row.realtime_timestamp);
+        assert_eq!(Some("web-01"), row.hostname.as_deref());
+        assert_eq!(Some("nginx.service"), row.systemd_unit.as_deref());
+        assert_eq!(Some(6), row.priority);
+        assert_eq!(Some("GET /health HTTP/1.1 200"), row.message.as_deref());
+        assert_eq!(Some(1234), row.pid);
+        assert_eq!(Some("nginx"), row.syslog_identifier.as_deref());
+        assert_eq!(Some(INGESTED_AT), row.ingested_at);
+        assert_eq!(Some(19884), row.ingested_date);
+
+        // The rest, address fields included, go in the map
+        assert_eq!("stdout", row.fields["_TRANSPORT"]);
+        assert_eq!("/system.slice/nginx.service", row.fields["_SYSTEMD_CGROUP"]);
+        assert_eq!("1780316", row.fields["__MONOTONIC_TIMESTAMP"]);
+        for column in ["__CURSOR", "_HOSTNAME", "MESSAGE", "PRIORITY", "_PID"] {
+            assert!(!row.fields.contains_key(column), "{}", column);
+        }
+        assert!(row.binary_fields.is_empty());
+
+        // A kernel message has no unit or PID
+        let row = &rows("text.export")[2];
+        assert_eq!(None, row.systemd_unit);
+        assert_eq!(None, row.pid);
+        assert_eq!(Some(3), row.priority);
+    }
+
+    #[test]
+    fn test_binary_fields() {
+        let rows = rows("binary.export");
+
+        let row = &rows[0];
+        assert_eq!(
+            Some(
+                "Traceback (most recent call last):\n  File \"app.py\", line 3\nValueError: bad\n"
+            ),
+            row.message.as_deref()
+        );
+
+        // Not UTF-8: kept as-is in binary_fields, and lossily in the message column
+        let row = &rows[1];
+        assert_eq!(Some("\u{fffd}\u{fffd}\u{0}A"), row.message.as_deref());
+        assert_eq!(
+            &[0xff, 0xfe, 0x00, 0x41][..],
+            &row.binary_fields["MESSAGE"][..]
+        );
+        assert_eq!("a=b\n\nc=d", row.fields["BLOB"]);
+
+        // Repeated values are joined, and a priority that is not a number stays in the
+        // map
+        let row = &rows[2];
+        assert_eq!("first\nsecond\nline", row.fields["TAG"]);
+        assert_eq!("", row.fields["EMPTY"]);
+        assert_eq!(None, row.priority);
+        assert_eq!("notice", row.fields["PRIORITY"]);
+    }
+
+    #[test]
+    fn test_repeated_standard_field() {
+        let entry = Entry {
+            fields: vec![
+                ("MESSAGE".to_string(), b"first".to_vec()),
+                ("_PID".to_string(), b"not a pid".to_vec()),
+                ("MESSAGE".to_string(), b"second".to_vec()),
+                ("PRIORITY".to_string(), b"9".to_vec()),
+            ],
+        };
+        let row = to_row(&entry, INGESTED_AT);
+        assert_eq!(Some("first"), row.message.as_deref());
+        assert_eq!("second", row.fields["MESSAGE"]);
+        assert_eq!(None, row.pid);
+        assert_eq!("not a pid", row.fields["_PID"]);
+        assert_eq!(None, row.priority);
+        assert_eq!("9", row.fields["PRIORITY"]);
+    }
+}
@@ -0,0 +1,310 @@
+//! How the bridge authenticates to Kafka, selected by `KAFKA_AUTH`
+
+use anyhow::{bail, Context, Result};
+use rdkafka::client::OAuthToken;
+use rdkafka::consumer::ConsumerContext;
+use rdkafka::error::KafkaError;
+use rdkafka::types::RDKafkaErrorCode;
+use rdkafka::{ClientConfig, ClientContext};
+use std::error::Error;
+use std::sync::Mutex;
+use std::time::SystemTime;
+use tracing::error;
+
+use crate::msk_iam::{self, CredentialCache};
+
+/// A SCRAM mechanism, named as librdkafka names it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScramMechanism {
+    Sha256,
+    Sha512,
+}
+
+impl ScramMechanism {
+    fn name(self) -> &'static str {
+        match self {
+            ScramMechanism::Sha256 => "SCRAM-SHA-256",
+            ScramMechanism::Sha512 => "SCRAM-SHA-512",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KafkaAuth {
+    /// No authentication or encryption
+    Plaintext,
+    /// Username and password over TLS
+    Scram {
+        mechanism: ScramMechanism,
+        username: String,
+        password: String,
+    },
+    /// Amazon MSK IAM over TLS, with tokens signed by AWS credentials
+    MskIam {
+        /// Region of the cluster; from the AWS configuration when unset
+        region: Option<String>,
+    },
+}
+
+impl KafkaAuth {
+    /// From `KAFKA_AUTH` and the variables that mode needs
+    pub fn from_env() -> Result<Self> {
+        Self::parse(&std::env::var("KAFKA_AUTH").unwrap_or_default(), |name| {
+            std::env::var(name).ok()
+        })
+    }
+
+    /// Parse `mode`, reading its settings with `var`
+    pub fn parse(mode: &str, var: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let required = |name: &str| {
+            var(name)
+                .filter(|value| !value.is_empty())
+                .with_context(|| format!("{} must be set for KAFKA_AUTH={}", name, mode.trim()))
+        };
+        let mechanism = match mode.trim().to_ascii_lowercase().as_str() {
+            "" | "plaintext" => return Ok(KafkaAuth::Plaintext),
+            "msk-iam" => {
+                return Ok(KafkaAuth::MskIam {
+                    region: var("AWS_REGION").filter(|region| !region.is_empty()),
+                })
+            }
+            "scram-sha-256" => ScramMechanism::Sha256,
+            "scram-sha-512" => ScramMechanism::Sha512,
+            _ => bail!(
+                "KAFKA_AUTH must be \"plaintext\", \"scram-sha-256\", \"scram-sha-512\", or \"msk-iam\", got {:?}",
+                mode
+            ),
+        };
+        Ok(KafkaAuth::Scram {
+            mechanism,
+            username: required("KAFKA_USERNAME")?,
+            password: required("KAFKA_PASSWORD")?,
+        })
+    }
+
+    /// Set the librdkafka properties for this mode on `config`
+    pub fn configure(&self, config: &mut ClientConfig) {
+        match self {
+            KafkaAuth::Plaintext => {}
+            KafkaAuth::Scram {
+                mechanism,
+                username,
+                password,
+            } => {
+                config
+                    .set("security.protocol", "SASL_SSL")
+                    .set("sasl.mechanism", mechanism.name())
+                    .set("sasl.username", username)
+                    .set("sasl.password", password);
+            }
+            KafkaAuth::MskIam { .. } => {
+                config
+                    .set("security.protocol", "SASL_SSL")
+                    .set("sasl.mechanism", "OAUTHBEARER");
+            }
+        }
+    }
+}
+
+/// Signs MSK IAM tokens for librdkafka with the latest AWS credentials
+pub struct MskIamSigner {
+    pub region: String,
+    pub credentials: CredentialCache,
+}
+
+/// The consumer's context: answers librdkafka's token refreshes in MSK IAM mode, and
+/// remembers authentication failures so the bridge can exit on them
+///
+/// librdkafka reports a rejected password or token as an error event and keeps
+/// retrying, so without this the bridge would wait for records forever.
+#[derive(Default)]
+pub struct BridgeContext {
+    msk_iam: Option<MskIamSigner>,
+    authentication_failure: Mutex<Option<String>>,
+}
+
+impl BridgeContext {
+    pub fn new(msk_iam: Option<MskIamSigner>) -> Self {
+        Self {
+            msk_iam,
+            authentication_failure: Mutex::new(None),
+        }
+    }
+
+    /// Why the brokers last rejected the bridge's credentials, if they did
+    pub fn authentication_failure(&self) -> Option<String> {
+        self.authentication_failure
+            .lock()
+            .expect("error lock poisoned")
+            .clone()
+    }
+}
+
+impl ClientContext for BridgeContext {
+    // Only called when sasl.mechanism is OAUTHBEARER, which only MSK IAM sets
+    const ENABLE_REFRESH_OAUTH_TOKEN: bool = true;
+
+    fn error(&self, error: KafkaError, reason: &str) {
+        error!("librdkafka: {}: {}", error, reason);
+        if matches!(
+            error.rdkafka_error_code(),
+            Some(RDKafkaErrorCode::Authentication | RDKafkaErrorCode::SaslAuthenticationFailed)
+        ) {
+            *self
+                .authentication_failure
+                .lock()
+                .expect("error lock poisoned") = Some(format!("{}: {}", error, reason));
+        }
+    }
+
+    fn generate_oauth_token(
+        &self,
+        _oauthbearer_config: Option<&str>,
+    ) -> Result<OAuthToken, Box<dyn Error>> {
+        let signer = self
+            .msk_iam
+            .as_ref()
+            .ok_or("OAUTHBEARER is only supported with KAFKA_AUTH=msk-iam")?;
+        let credentials = signer.credentials.get();
+        let token = msk_iam::generate_token(&signer.region, &credentials, SystemTime::now());
+        if token.expires_at <= SystemTime::now() {
+            return Err("The AWS credentials for MSK IAM have expired".into());
+        }
+        Ok(OAuthToken {
+            lifetime_ms: token.expires_at_ms(),
+            token: token.token,
+            principal_name: credentials.access_key_id,
+        })
+    }
+}
+
+impl ConsumerContext for BridgeContext {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::msk_iam::Credentials;
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    fn parse(mode: &str, vars: &[(&str, &str)]) -> Result<KafkaAuth> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        KafkaAuth::parse(mode, |name| vars.get(name).cloned())
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(KafkaAuth::Plaintext, parse("", &[]).unwrap());
+        assert_eq!(KafkaAuth::Plaintext, parse("plaintext", &[]).unwrap());
+        assert_eq!(
+            KafkaAuth::Scram {
+                mechanism: ScramMechanism::Sha512,
+                username: "bridge".to_string(),
+                password: "secret".to_string(),
+            },
+            parse(
+                "SCRAM-SHA-512",
+                &[("KAFKA_USERNAME", "bridge"), ("KAFKA_PASSWORD", "secret")]
+            )
+            .unwrap()
+        );
+        assert_eq!(
+            KafkaAuth::MskIam {
+                region: Some("eu-west-1".to_string())
+            },
+            parse("msk-iam", &[("AWS_REGION", "eu-west-1")]).unwrap()
+        );
+        assert_eq!(
+            KafkaAuth::MskIam { region: None },
+            parse("msk-iam", &[]).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_missing_settings_and_unknown_modes() {
+        let error = parse("scram-sha-256", &[("KAFKA_USERNAME", "bridge")]).unwrap_err();
+        assert!(error.to_string().contains("KAFKA_PASSWORD"));
+        let error = parse("kerberos", &[]).unwrap_err();
+        assert!(error.to_string().contains("\"kerberos\""));
+    }
+
+    #[test]
+    fn test_configure() {
+        let mut config = ClientConfig::new();
+        KafkaAuth::MskIam { region: None }.configure(&mut config);
+        assert_eq!(Some("SASL_SSL"), config.get("security.protocol"));
+        assert_eq!(Some("OAUTHBEARER"), config.get("sasl.mechanism"));
+
+        let mut config = ClientConfig::new();
+        KafkaAuth::Plaintext.configure(&mut config);
+        assert_eq!(None, config.get("security.protocol"));
+    }
+
+    #[test]
+    fn test_context_signs_tokens_with_cached_credentials() {
+        let credentials = Credentials {
+            access_key_id: "AKIDEXAMPLE".to_string(),
+            secret_access_key: "secret".to_string(),
+            session_token: Some("session".to_string()),
+            expires_at: Some(SystemTime::now() + Duration::from_secs(60)),
+        };
+        let context = BridgeContext::new(Some(MskIamSigner {
+            region: "us-west-2".to_string(),
+            credentials: CredentialCache::new(credentials.clone()),
+        }));
+
+        let token = context.generate_oauth_token(None).unwrap();
+        assert_eq!("AKIDEXAMPLE", token.principal_name);
+        assert!(!token.token.is_empty());
+        // Capped by the credentials, which expire first
+        let expires_at_ms = credentials
+            .expires_at
+            .unwrap()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+        assert_eq!(expires_at_ms, token.lifetime_ms);
+
+        assert!(BridgeContext::default().generate_oauth_token(None).is_err());
+    }
+
+    #[test]
+    fn test_context_refuses_to_sign_with_expired_credentials() {
+        let context = BridgeContext::new(Some(MskIamSigner {
+            region: "us-west-2".to_string(),
+            credentials: CredentialCache::new(Credentials {
+                access_key_id: "AKIDEXAMPLE".to_string(),
+                secret_access_key: "secret".to_string(),
+                session_token: None,
+                expires_at: Some(SystemTime::now() - Duration::from_secs(1)),
+            }),
+        }));
+
+        let Err(error) = context.generate_oauth_token(None) else {
+            panic!("signed a token with expired credentials");
+        };
+        assert!(error.to_string().contains("expired"));
+    }
+
+    #[test]
+    fn test_context_remembers_authentication_failures() {
+        let context = BridgeContext::default();
+        context.error(
+            KafkaError::Global(RDKafkaErrorCode::BrokerTransportFailure),
+            "Disconnected",
+        );
+        assert_eq!(None, context.authentication_failure());
+
+        context.error(
+            KafkaError::Global(RDKafkaErrorCode::Authentication),
+            "SASL authentication error: Access denied",
+        );
+        assert!(context
+            .authentication_failure()
+            .unwrap()
+            .contains("Access denied"));
+    }
+}
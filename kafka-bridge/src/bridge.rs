@@ -0,0 +1,1001 @@
+//! Turning consumed Kafka records into rows of their target tables
+
+use anyhow::{bail, Context, Result};
+use databricks_zerobus_ingest_sdk::StreamConfigurationOptions;
+use prost_types::DescriptorProto;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
+use zerobus_common::descriptor::find_message_descriptor;
+use zerobus_common::dynamic::{DynamicEncoder, FieldErrorMode, KeyCase};
+use zerobus_common::failure_audit::{AuditOutcome, FailureAudit, FailureAuditor};
+use zerobus_common::ingest_filter::IngestFilter;
+use zerobus_common::metrics::{Metrics, Series};
+use zerobus_common::pipeline::{IngestSink, Pipeline};
+use zerobus_common::router::{message_name, TableRouter};
+use zerobus_common::shutdown::{self, UnackedSink};
+use zerobus_common::watermark::{Watermark, WatermarkSource, WATERMARK_COLUMN};
+
+use crate::debezium::{self, Event};
+use crate::stream_config::StreamConfigs;
+
+/// How record values are interpreted, selected by `MODE`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Each value is one JSON row, routed by topic
+    Json,
+    /// Each value is a Debezium change event, routed by source table
+    Debezium,
+}
+
+impl Mode {
+    pub fn parse(mode: &str) -> Result<Self> {
+        match mode.trim().to_ascii_lowercase().as_str() {
+            "" | "json" => Ok(Mode::Json),
+            "debezium" => Ok(Mode::Debezium),
+            _ => bail!("MODE must be \"json\" or \"debezium\", got {:?}", mode),
+        }
+    }
+}
+
+/// Opens a sink to a target table, with that table's stream options
+///
+/// Implemented over `ZerobusSdk` by the binary; tests hand out in-memory sinks.
+pub trait SinkFactory {
+    type Sink: UnackedSink;
+
+    fn open(
+        &self,
+        table: &str,
+        descriptor: DescriptorProto,
+        options: StreamConfigurationOptions,
+    ) -> impl Future<Output = Result<Self::Sink>> + Send;
+}
+
+/// Lets the bridges of several workers open their streams through one factory
+impl<F: SinkFactory + Sync> SinkFactory for Arc<F> {
+    type Sink = F::Sink;
+
+    fn open(
+        &self,
+        table: &str,
+        descriptor: DescriptorProto,
+        options: StreamConfigurationOptions,
+    ) -> impl Future<Output = Result<Self::Sink>> + Send {
+        F::open(self, table, descriptor, options)
+    }
+}
+
+/// Where a consumed record sits in its topic
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub partition: i32,
+    pub offset: i64,
+}
+
+/// The consumed record being handled, as far as its audit row needs it
+struct Origin<'a> {
+    topic: &'a str,
+    position: Option<Position>,
+    value: &'a [u8],
+    event_time: Option<SystemTime>,
+}
+
+/// Records consumed, by what became of them
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct BridgeStats {
+    /// Rows sent to a target table
+    pub rows: u64,
+    /// Null values, which Debezium sends after deletes for log compaction
+    pub tombstones: u64,
+    /// DDL events from a Debezium schema change topic
+    pub schema_changes: u64,
+    /// Debezium transaction BEGIN/END markers
+    pub transaction_markers: u64,
+    /// Records whose topic or source table has no target table
+    pub unrouted: u64,
+    /// Records `INGEST_FILTER` does not match
+    pub filtered: u64,
+    /// Records that could not be parsed or encoded
+    pub malformed: u64,
+}
+
+struct Target<S: IngestSink> {
+    encoder: DynamicEncoder,
+    pipeline: Pipeline<S>,
+    /// Set when a watermark is configured and the table has a watermark column
+    watermark: Option<Watermark>,
+    /// The table's metrics, fed from its pipeline, when metrics are collected
+    series: Option<Arc<Series>>,
+}
+
+/// Routes records to per-table pipelines, opening each table's stream on first use
+pub struct Bridge<F: SinkFactory> {
+    factory: F,
+    router: TableRouter,
+    mode: Mode,
+    /// Encoded `FileDescriptorSet` holding a `table_<name>` message per target table
+    descriptors: Vec<u8>,
+    ignore_unknown_fields: bool,
+    warn_unknown_fields: bool,
+    coerce_types: bool,
+    field_error_mode: FieldErrorMode,
+    key_case: KeyCase,
+    stream_configs: StreamConfigs,
+    /// Ceiling on each table's unacknowledged bytes; see [`Pipeline::max_pending_bytes`]
+    max_pending_bytes: Option<u64>,
+    watermark: Option<WatermarkSource>,
+    ingest_filter: Option<IngestFilter>,
+    targets: HashMap<String, Target<F::Sink>>,
+    stats: BridgeStats,
+    metrics: Option<Metrics>,
+    /// `source` label of the metrics of this bridge's streams
+    stream_label: String,
+    failure_audit: Option<FailureAuditor>,
+}
+
+impl<F: SinkFactory> Bridge<F> {
+    pub fn new(
+        factory: F,
+        router: TableRouter,
+        mode: Mode,
+        descriptors: Vec<u8>,
+        ignore_unknown_fields: bool,
+        stream_configs: StreamConfigs,
+    ) -> Self {
+        Self {
+            factory,
+            router,
+            mode,
+            descriptors,
+            ignore_unknown_fields,
+            warn_unknown_fields: false,
+            coerce_types: true,
+            field_error_mode: FieldErrorMode::Fail,
+            key_case: KeyCase::AsIs,
+            stream_configs,
+            max_pending_bytes: None,
+            watermark: None,
+            ingest_filter: None,
+            targets: HashMap::new(),
+            stats: BridgeStats::default(),
+            metrics: None,
+            stream_label: String::new(),
+            failure_audit: None,
+        }
+    }
+
+    /// Log rows whose unknown fields are dropped; see [`DynamicEncoder::warn_unknown_fields`]
+    pub fn warn_unknown_fields(mut self, warn: bool) -> Self {
+        self.warn_unknown_fields = warn;
+        self
+    }
+
+    /// Convert strings to numbers and booleans; see [`DynamicEncoder::coerce_types`]
+    pub fn coerce_types(mut self, coerce: bool) -> Self {
+        self.coerce_types = coerce;
+        self
+    }
+
+    /// What to do with values that cannot be converted to their column's type; see
+    /// [`DynamicEncoder::field_error_mode`]
+    pub fn field_error_mode(mut self, mode: FieldErrorMode) -> Self {
+        self.field_error_mode = mode;
+        self
+    }
+
+    /// How column names are spelled in the records; see [`DynamicEncoder::key_case`]
+    pub fn key_case(mut self, case: KeyCase) -> Self {
+        self.key_case = case;
+        self
+    }
+
+    /// Bound the unacknowledged bytes of each table's pipeline, so slow acks pause
+    /// consumption instead of growing memory
+    pub fn max_pending_bytes(mut self, max_bytes: Option<u64>) -> Self {
+        self.max_pending_bytes = max_bytes;
+        self
+    }
+
+    /// Stamp each table that has a watermark column with that table's own watermark
+    pub fn watermark(mut self, source: Option<WatermarkSource>) -> Self {
+        self.watermark = source;
+        self
+    }
+
+    /// Drop the records `filter` does not match, before they reach a table
+    ///
+    /// Dropped records are counted as `filtered` but not audited, as leaving them out is
+    /// what was asked for.
+    pub fn ingest_filter(mut self, filter: Option<IngestFilter>) -> Self {
+        self.ingest_filter = filter;
+        self
+    }
+
+    /// Record what becomes of each record in `metrics`, labelled by table and topic
+    pub fn metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Label the metrics of this bridge's streams with `source`, which is otherwise
+    /// empty, so the streams several bridges hold to one table are reported apart
+    pub fn stream_label(mut self, label: impl Into<String>) -> Self {
+        self.stream_label = label.into();
+        self
+    }
+
+    /// Leave an audit row for every malformed record, and every record with no table
+    ///
+    /// Rows that are not acknowledged are not audited: their offsets are not committed,
+    /// so they are consumed again.
+    pub fn failure_audit(mut self, auditor: Option<FailureAuditor>) -> Self {
+        self.failure_audit = auditor;
+        self
+    }
+
+    /// Handle the value of one record consumed from `topic`
+    ///
+    /// Records that cannot be used are counted and skipped. Errors are only returned
+    /// when a target table's stream cannot be opened or written to.
+    pub async fn handle(&mut self, topic: &str, value: Option<&[u8]>) -> Result<()> {
+        self.handle_at(topic, value, None).await
+    }
+
+    /// Handle one record like [`Bridge::handle`], observing the end-to-end latency from
+    /// its `event_time`, such as the Kafka record's timestamp
+    pub async fn handle_at(
+        &mut self,
+        topic: &str,
+        value: Option<&[u8]>,
+        event_time: Option<SystemTime>,
+    ) -> Result<()> {
+        self.handle_consumed(topic, None, value, event_time).await
+    }
+
+    /// Handle one record like [`Bridge::handle_at`], naming its `position` in the audit
+    /// rows of records that do not reach a table
+    pub async fn handle_consumed(
+        &mut self,
+        topic: &str,
+        position: Option<Position>,
+        value: Option<&[u8]>,
+        event_time: Option<SystemTime>,
+    ) -> Result<()> {
+        let origin = Origin {
+            topic,
+            position,
+            value: value.unwrap_or_default(),
+            event_time,
+        };
+        let (key, mut row, op) = match self.mode {
+            Mode::Json => match value.map(serde_json::from_slice::<Value>) {
+                Some(Ok(row)) => (topic.to_string(), row, None),
+                Some(Err(e)) => {
+                    warn!("Skipping malformed record on {}: {}", topic, e);
+                    self.stats.malformed += 1;
+                    self.skipped("", topic, "malformed");
+                    self.audit(&origin, "", AuditOutcome::Failed, |row| {
+                        row.error(&e.into())
+                    });
+                    return Ok(());
+                }
+                None => {
+                    self.stats.tombstones += 1;
+                    self.skipped("", topic, "tombstone");
+                    return Ok(());
+                }
+            },
+            Mode::Debezium => match debezium::parse(topic, value) {
+                Ok(Event::Change(change)) => {
+                    let row = change.image().cloned().unwrap_or_default();
+                    let key = change.source.qualified_table();
+                    (key, Value::Object(row), Some(change.op.name()))
+                }
+                Ok(Event::Tombstone) => {
+                    self.stats.tombstones += 1;
+                    self.skipped("", topic, "tombstone");
+                    return Ok(());
+                }
+                Ok(Event::SchemaChange) => {
+                    self.stats.schema_changes += 1;
+                    self.skipped("", topic, "schema_change");
+                    return Ok(());
+                }
+                Ok(Event::TransactionMarker) => {
+                    self.stats.transaction_markers += 1;
+                    self.skipped("", topic, "transaction_marker");
+                    return Ok(());
+                }
+                Err(e) => {
+                    warn!("Skipping malformed change event on {}: {:#}", topic, e);
+                    self.stats.malformed += 1;
+                    self.skipped("", topic, "malformed");
+                    self.audit(&origin, "", AuditOutcome::Failed, |row| row.error(&e));
+                    return Ok(());
+                }
+            },
+        };
+
+        let Some(table) = self.router.route(&key).map(str::to_string) else {
+            self.stats.unrouted += 1;
+            self.skipped("", topic, "unrouted");
+            self.audit(&origin, "", AuditOutcome::Filtered, |row| {
+                row.reason(format!("No table is routed for {}", key))
+            });
+            return Ok(());
+        };
+        // Checked before the table's stream is opened, so a table that only gets
+        // filtered records never has one
+        if let Some(filter) = &self.ingest_filter {
+            if !filter.matches(&row) {
+                self.stats.filtered += 1;
+                self.skipped(&table, topic, "filtered");
+                return Ok(());
+            }
+        }
+        let target = self.target(&table).await?;
+        // The op column is optional; tables without one just get the image
+        if let (Some(op), Value::Object(object)) = (op, &mut row) {
+            if target.encoder.has_field("op") {
+                object.insert("op".to_string(), op.into());
+            }
+        }
+        if let (Some(watermark), Value::Object(object)) = (&mut target.watermark, &mut row) {
+            if let Err(e) = watermark.stamp(object) {
+                warn!("Skipping row for {}: {:#}", table, e);
+                self.stats.malformed += 1;
+                self.skipped(&table, topic, "malformed");
+                self.audit(&origin, &table, AuditOutcome::Failed, |row| row.error(&e));
+                return Ok(());
+            }
+        }
+        let encoded = match target.encoder.encode(&row) {
+            Ok(encoded) => encoded,
+            Err(e) => {
+                warn!(
+                    "Skipping row for {} that does not match its table: {:#}",
+                    table, e
+                );
+                self.stats.malformed += 1;
+                self.skipped(&table, topic, "malformed");
+                self.audit(&origin, &table, AuditOutcome::Failed, |row| row.error(&e));
+                return Ok(());
+            }
+        };
+        let size = encoded.len();
+        target.pipeline.ingest_at(encoded, event_time).await?;
+        if let Some(series) = &target.series {
+            series.observe_pipeline(&target.pipeline);
+        }
+        self.stats.rows += 1;
+        if let Some(metrics) = &self.metrics {
+            let series = metrics.series(&table, topic);
+            series.record_bytes.observe(size as f64);
+            if let Some(event_time) = event_time {
+                series.observe_event_time(event_time);
+            }
+        }
+        Ok(())
+    }
+
+    /// Count a record from `topic` that did not reach a table, and why
+    fn skipped(&self, table: &str, topic: &str, kind: &'static str) {
+        if let Some(metrics) = &self.metrics {
+            let series = metrics.series(table, topic);
+            series.filtered.fetch_add(1, Ordering::Relaxed);
+            series.count(kind, 1);
+        }
+    }
+
+    /// Queue an audit row for the record `origin`, meant for `table` if it was routed,
+    /// when auditing
+    fn audit(
+        &self,
+        origin: &Origin,
+        table: &str,
+        outcome: AuditOutcome,
+        why: impl FnOnce(FailureAudit) -> FailureAudit,
+    ) {
+        let Some(auditor) = &self.failure_audit else {
+            return;
+        };
+        let mut row = why(FailureAudit::new(outcome, origin.value).produced_at(origin.event_time));
+        row.request_id = origin.position.map(|position| {
+            format!(
+                "{}[{}]@{}",
+                origin.topic, position.partition, position.offset
+            )
+        });
+        row.source = Some(env!("CARGO_PKG_NAME").to_string());
+        row.source_id = Some(origin.topic.to_string());
+        row.target_table = Some(table.to_string()).filter(|table| !table.is_empty());
+        auditor.record(row);
+    }
+
+    /// Wait for every row sent so far to be acknowledged
+    ///
+    /// Once this returns, the offsets of every record handled so far can be committed.
+    pub async fn checkpoint(&mut self) -> Result<()> {
+        for (table, target) in &mut self.targets {
+            let failed = target.pipeline.summary().failed;
+            target.pipeline.drain().await?;
+            if let Some(series) = &target.series {
+                series.observe_pipeline(&target.pipeline);
+            }
+            let newly_failed = target.pipeline.summary().failed - failed;
+            if newly_failed > 0 {
+                bail!("{} rows for {} were not acknowledged", newly_failed, table);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> &BridgeStats {
+        &self.stats
+    }
+
+    /// Rows ingested without some of their fields, when warning about unknown fields
+    pub fn rows_with_dropped_fields(&self) -> u64 {
+        self.targets
+            .values()
+            .map(|target| target.encoder.records_with_unknown_fields())
+            .sum()
+    }
+
+    /// Drain every table's stream within `grace` and close it
+    ///
+    /// Returns the number of rows still unacknowledged when the grace period ran out.
+    pub async fn finish(self, grace: Duration) -> Result<usize> {
+        let drains = self
+            .targets
+            .into_values()
+            .map(|target| shutdown::drain(target.pipeline, grace));
+        let mut unacked = 0;
+        for outcome in futures::future::join_all(drains).await {
+            unacked += outcome?.unacked.len();
+        }
+        Ok(unacked)
+    }
+
+    async fn target(&mut self, table: &str) -> Result<&mut Target<F::Sink>> {
+        if !self.targets.contains_key(table) {
+            let descriptor = find_message_descriptor(&self.descriptors, &message_name(table))
+                .with_context(|| format!("No descriptor for table {}", table))?;
+            let encoder = DynamicEncoder::new(&descriptor)?
+                .ignore_unknown_fields(self.ignore_unknown_fields)
+                .warn_unknown_fields(self.warn_unknown_fields)
+                .coerce_types(self.coerce_types)
+                .field_error_mode(self.field_error_mode)
+                .key_case(self.key_case);
+            let watermark = self
+                .watermark
+                .clone()
+                .filter(|_| encoder.has_field(WATERMARK_COLUMN))
+                .map(Watermark::new);
+            let options = self.stream_configs.for_table(table);
+            // The pipeline's window matches the stream's so it never waits on the SDK
+            let max_inflight = options.max_inflight_records;
+            let sink = self.factory.open(table, descriptor, options).await?;
+            info!(
+                "Opened stream to table: {} (max in flight: {})",
+                table, max_inflight
+            );
+            let mut pipeline =
+                Pipeline::new(sink, max_inflight).max_pending_bytes(self.max_pending_bytes);
+            let series = self
+                .metrics
+                .as_ref()
+                .map(|metrics| metrics.series(table, &self.stream_label));
+            if let Some(series) = &series {
+                series.mark_stream_opened();
+                pipeline = pipeline.ack_observer(series.ack_observer());
+            }
+            self.targets.insert(
+                table.to_string(),
+                Target {
+                    encoder,
+                    pipeline,
+                    watermark,
+                    series,
+                },
+            );
+            if let Some(metrics) = &self.metrics {
+                metrics.add_cached_streams(1);
+            }
+        }
+        Ok(self
+            .targets
+            .get_mut(table)
+            .expect("target was just inserted"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost::Message;
+    use prost_types::field_descriptor_proto::{Label, Type};
+    use prost_types::{FieldDescriptorProto, FileDescriptorProto, FileDescriptorSet};
+    use std::sync::{Arc, Mutex};
+    use zerobus_common::testing::MockSink;
+
+    /// Hands out one shared mock sink per table
+    #[derive(Clone, Default)]
+    struct MockFactory {
+        sinks: Arc<Mutex<HashMap<String, MockSink>>>,
+        /// `max_inflight_records` each table's stream was opened with
+        max_inflight: Arc<Mutex<HashMap<String, usize>>>,
+    }
+
+    impl MockFactory {
+        fn records(&self, table: &str) -> Vec<Vec<u8>> {
+            self.sinks.lock().unwrap()[table].records()
+        }
+    }
+
+    impl SinkFactory for MockFactory {
+        type Sink = MockSink;
+
+        async fn open(
+            &self,
+            table: &str,
+            _descriptor: DescriptorProto,
+            options: StreamConfigurationOptions,
+        ) -> Result<MockSink> {
+            self.max_inflight
+                .lock()
+                .unwrap()
+                .insert(table.to_string(), options.max_inflight_records);
+            let mut sinks = self.sinks.lock().unwrap();
+            Ok(sinks.entry(table.to_string()).or_default().clone())
+        }
+    }
+
+    fn field(name: &str, number: i32, kind: Type) -> FieldDescriptorProto {
+        FieldDescriptorProto {
+            name: Some(name.to_string()),
+            number: Some(number),
+            label: Some(Label::Optional as i32),
+            r#type: Some(kind as i32),
+            ..Default::default()
+        }
+    }
+
+    fn descriptors() -> Vec<u8> {
+        let customers = DescriptorProto {
+            name: Some("table_customers".to_string()),
+            field: vec![
+                field("id", 1, Type::Int64),
+                field("first_name", 2, Type::String),
+                field("last_name", 3, Type::String),
+                field("email", 4, Type::String),
+                field("op", 5, Type::String),
+                field("watermark", 6, Type::Int64),
+            ],
+            ..Default::default()
+        };
+        // No op column
+        let orders = DescriptorProto {
+            name: Some("table_orders".to_string()),
+            field: vec![
+                field("id", 1, Type::Int64),
+                field("customer_id", 2, Type::Int64),
+                field("quantity", 3, Type::Int32),
+                field("status", 4, Type::String),
+            ],
+            ..Default::default()
+        };
+        FileDescriptorSet {
+            file: vec![FileDescriptorProto {
+                name: Some("tables.proto".to_string()),
+                message_type: vec![customers, orders],
+                ..Default::default()
+            }],
+        }
+        .encode_to_vec()
+    }
+
+    fn fixture(name: &str) -> Vec<u8> {
+        std::fs::read(format!(
+            "{}/tests/fixtures/debezium/{}",
+            env!("CARGO_MANIFEST_DIR"),
+            name
+        ))
+        .unwrap()
+    }
+
+    fn default_options() -> StreamConfigurationOptions {
+        StreamConfigurationOptions {
+            max_inflight_records: 100,
+            ..Default::default()
+        }
+    }
+
+    fn router() -> TableRouter {
+        TableRouter::new(
+            "inventory.customers=main.cdc.customers,shop.orders=main.cdc.orders,clicks=main.raw.customers",
+            None,
+        )
+    }
+
+    fn bridge(factory: &MockFactory, mode: Mode) -> Bridge<MockFactory> {
+        let stream_configs = StreamConfigs::new(default_options());
+        Bridge::new(
+            factory.clone(),
+            router(),
+            mode,
+            descriptors(),
+            false,
+            stream_configs,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_debezium_routing_and_skips() {
+        let factory = MockFactory::default();
+        let mut bridge = bridge(&factory, Mode::Debezium);
+
+        let topic = "dbserver1.inventory.customers";
+        for name in [
+            "insert.json",
+            "schema_change.json",
+            "transaction_end.json",
+            "unwrapped.json",
+        ] {
+            bridge.handle(topic, Some(&fixture(name))).await.unwrap();
+        }
+        bridge.handle(topic, None).await.unwrap();
+        for name in ["update.json", "delete.json"] {
+            bridge
+                .handle("pgserver1.shop.orders", Some(&fixture(name)))
+                .await
+                .unwrap();
+        }
+        bridge
+            .handle(
+                "pgserver1.shop.accounts",
+                Some(&fixture("unwrapped_delete.json")),
+            )
+            .await
+            .unwrap();
+        bridge.handle(topic, Some(b"{not json")).await.unwrap();
+        bridge.checkpoint().await.unwrap();
+
+        assert_eq!(
+            &BridgeStats {
+                rows: 4,
+                tombstones: 1,
+                schema_changes: 1,
+                transaction_markers: 1,
+                unrouted: 1,
+                filtered: 0,
+                malformed: 1,
+            },
+            bridge.stats()
+        );
+
+        let encoder = DynamicEncoder::new(
+            &find_message_descriptor(&descriptors(), "table_customers").unwrap(),
+        )
+        .unwrap();
+        let customers = factory.records("main.cdc.customers");
+        assert_eq!(
+            encoder
+                .encode(&serde_json::json!({
+                    "id": 1004,
+                    "first_name": "Anne",
+                    "last_name": "Kretchmar",
+                    "email": "annek@noanswer.org",
+                    "op": "insert",
+                }))
+                .unwrap(),
+            customers[0]
+        );
+        assert_eq!(2, customers.len());
+
+        // The delete stores the before-image; orders has no op column
+        let encoder =
+            DynamicEncoder::new(&find_message_descriptor(&descriptors(), "table_orders").unwrap())
+                .unwrap();
+        assert_eq!(
+            encoder
+                .encode(&serde_json::json!({
+                    "id": 7,
+                    "customer_id": 1004,
+                    "quantity": 3,
+                    "status": "shipped",
+                }))
+                .unwrap(),
+            factory.records("main.cdc.orders")[1]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_json_mode_routes_by_topic() {
+        let factory = MockFactory::default();
+        let mut bridge = bridge(&factory, Mode::Json);
+
+        bridge
+            .handle("clicks", Some(br#"{"id": 1, "email": "a@example.com"}"#))
+            .await
+            .unwrap();
+        bridge.handle("views", Some(br#"{"id": 2}"#)).await.unwrap();
+        bridge
+            .handle("clicks", Some(br#"{"id": 3, "bogus": 1}"#))
+            .await
+            .unwrap();
+        bridge.checkpoint().await.unwrap();
+
+        assert_eq!(1, factory.records("main.raw.customers").len());
+        assert_eq!(1, bridge.stats().unrouted);
+        assert_eq!(1, bridge.stats().malformed);
+    }
+
+    #[tokio::test]
+    async fn test_records_the_ingest_filter_does_not_match_are_dropped() {
+        let factory = MockFactory::default();
+        let metrics = Metrics::default();
+        let filter = IngestFilter::parse(r#"$.first_name == "Anne""#).unwrap();
+        let mut bridge = bridge(&factory, Mode::Json)
+            .ingest_filter(Some(filter))
+            .metrics(metrics.clone());
+
+        bridge
+            .handle("clicks", Some(br#"{"id": 1, "first_name": "Anne"}"#))
+            .await
+            .unwrap();
+        bridge
+            .handle("clicks", Some(br#"{"id": 2, "first_name": "Bob"}"#))
+            .await
+            .unwrap();
+        bridge
+            .handle("clicks", Some(br#"{"id": 3}"#))
+            .await
+            .unwrap();
+        bridge.checkpoint().await.unwrap();
+
+        let records = factory.records("main.raw.customers");
+        assert_eq!(1, records.len());
+        let encoder = DynamicEncoder::new(
+            &find_message_descriptor(&descriptors(), "table_customers").unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            encoder
+                .encode(&serde_json::json!({"id": 1, "first_name": "Anne"}))
+                .unwrap(),
+            records[0]
+        );
+        assert_eq!(1, bridge.stats().rows);
+        assert_eq!(2, bridge.stats().filtered);
+
+        let text = metrics.render();
+        for sample in [
+            "zerobus_records_filtered_total{table=\"main.raw.customers\",source=\"clicks\"} 2\n",
+            "zerobus_source_records_total{table=\"main.raw.customers\",source=\"clicks\",kind=\"filtered\"} 2\n",
+        ] {
+            assert!(text.contains(sample), "{} missing from\n{}", sample, text);
+        }
+    }
+
+    /// Opens the failure audit stream over one shared mock sink
+    struct AuditFactory(MockSink);
+
+    impl zerobus_common::supervisor::StreamFactory for AuditFactory {
+        type Sink = MockSink;
+
+        async fn create(&self, _descriptor: DescriptorProto) -> Result<MockSink> {
+            Ok(self.0.clone())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_skipped_records_are_audited() {
+        let factory = MockFactory::default();
+        let audit_sink = MockSink::default();
+        let (auditor, audit_task) =
+            FailureAuditor::spawn(AuditFactory(audit_sink.clone()), 10, Arc::default());
+        let mut bridge = bridge(&factory, Mode::Json).failure_audit(Some(auditor.clone()));
+
+        let position = |offset| {
+            Some(Position {
+                partition: 2,
+                offset,
+            })
+        };
+        bridge
+            .handle_consumed("clicks", position(7), Some(b"{not json"), None)
+            .await
+            .unwrap();
+        bridge
+            .handle_consumed("views", position(8), Some(br#"{"id": 2}"#), None)
+            .await
+            .unwrap();
+        bridge
+            .handle_consumed("clicks", position(9), Some(br#"{"id": "x"}"#), None)
+            .await
+            .unwrap();
+        // Neither rows that reach their table nor tombstones are audited
+        bridge
+            .handle_consumed("clicks", position(10), Some(br#"{"id": 1}"#), None)
+            .await
+            .unwrap();
+        bridge
+            .handle_consumed("clicks", position(11), None, None)
+            .await
+            .unwrap();
+        auditor.close();
+        audit_task.await.unwrap();
+
+        let rows: Vec<FailureAudit> = audit_sink
+            .records()
+            .iter()
+            .map(|record| FailureAudit::decode(record.as_slice()).unwrap())
+            .collect();
+        assert_eq!(
+            vec![
+                ("clicks[2]@7", "failed", None),
+                ("views[2]@8", "filtered", None),
+                ("clicks[2]@9", "failed", Some("main.raw.customers")),
+            ],
+            rows.iter()
+                .map(|row| (
+                    row.request_id.as_deref().unwrap(),
+                    row.outcome.as_deref().unwrap(),
+                    row.target_table.as_deref()
+                ))
+                .collect::<Vec<_>>()
+        );
+        assert!(rows[0].error_class.is_some());
+        assert_eq!(None, rows[1].error_class);
+        assert_eq!(Some(9), rows[0].payload_size);
+        assert_eq!(Some("kafka-bridge"), rows[0].source.as_deref());
+        assert_eq!(Some("views"), rows[1].source_id.as_deref());
+        assert_eq!(3, auditor.written());
+    }
+
+    #[tokio::test]
+    async fn test_watermark_per_table() {
+        #[derive(Clone, PartialEq, prost::Message)]
+        struct Customer {
+            #[prost(int64, optional, tag = "1")]
+            id: Option<i64>,
+            #[prost(int64, optional, tag = "6")]
+            watermark: Option<i64>,
+        }
+
+        let factory = MockFactory::default();
+        let mut bridge = bridge(&factory, Mode::Debezium).watermark(Some(WatermarkSource::Counter));
+        let topic = "dbserver1.inventory.customers";
+        for name in ["insert.json", "unwrapped.json"] {
+            bridge.handle(topic, Some(&fixture(name))).await.unwrap();
+        }
+        bridge
+            .handle("pgserver1.shop.orders", Some(&fixture("update.json")))
+            .await
+            .unwrap();
+        bridge.checkpoint().await.unwrap();
+
+        let watermarks: Vec<i64> = factory
+            .records("main.cdc.customers")
+            .iter()
+            .map(|record| {
+                Customer::decode(record.as_slice())
+                    .unwrap()
+                    .watermark
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(2, watermarks.len());
+        assert!(watermarks[0] < watermarks[1]);
+        // Orders has no watermark column, and its rows are ingested as they were
+        assert_eq!(1, factory.records("main.cdc.orders").len());
+        assert_eq!(0, bridge.stats().malformed);
+    }
+
+    #[tokio::test]
+    async fn test_stream_config_overrides() {
+        let factory = MockFactory::default();
+        let stream_configs = StreamConfigs::parse(
+            default_options(),
+            r#"{"main.cdc.orders": {"max_inflight_records": 5000}}"#,
+        )
+        .unwrap();
+        let mut bridge = Bridge::new(
+            factory.clone(),
+            router(),
+            Mode::Debezium,
+            descriptors(),
+            false,
+            stream_configs,
+        );
+
+        bridge
+            .handle(
+                "dbserver1.inventory.customers",
+                Some(&fixture("insert.json")),
+            )
+            .await
+            .unwrap();
+        bridge
+            .handle("pgserver1.shop.orders", Some(&fixture("update.json")))
+            .await
+            .unwrap();
+        assert_eq!(0, bridge.finish(Duration::from_secs(1)).await.unwrap());
+
+        let max_inflight = factory.max_inflight.lock().unwrap();
+        assert_eq!(5000, max_inflight["main.cdc.orders"]);
+        assert_eq!(100, max_inflight["main.cdc.customers"]);
+    }
+
+    #[tokio::test]
+    async fn test_checkpoint_fails_on_unacked_rows() {
+        let factory = MockFactory::default();
+        factory.sinks.lock().unwrap().insert(
+            "main.raw.customers".to_string(),
+            MockSink::default().fail_acks_for(|_| true),
+        );
+        let mut bridge = bridge(&factory, Mode::Json);
+
+        bridge
+            .handle("clicks", Some(br#"{"id": 1}"#))
+            .await
+            .unwrap();
+
+        let error = bridge.checkpoint().await.unwrap_err();
+        assert!(error.to_string().contains("1 rows for main.raw.customers"));
+    }
+
+    #[tokio::test]
+    async fn test_metrics_by_table_and_topic() {
+        let factory = MockFactory::default();
+        let metrics = Metrics::default();
+        let mut bridge = bridge(&factory, Mode::Debezium).metrics(metrics.clone());
+
+        let topic = "dbserver1.inventory.customers";
+        let sent_at = SystemTime::now() - Duration::from_secs(2);
+        for name in ["insert.json", "unwrapped.json"] {
+            bridge
+                .handle_at(topic, Some(&fixture(name)), Some(sent_at))
+                .await
+                .unwrap();
+        }
+        bridge.handle(topic, None).await.unwrap();
+        bridge
+            .handle(
+                "pgserver1.shop.accounts",
+                Some(&fixture("unwrapped_delete.json")),
+            )
+            .await
+            .unwrap();
+        bridge.checkpoint().await.unwrap();
+
+        let text = metrics.render();
+        for sample in [
+            "zerobus_records_ingested_total{table=\"main.cdc.customers\"} 2\n",
+            "zerobus_in_flight_records{table=\"main.cdc.customers\"} 0\n",
+            "zerobus_ack_latency_seconds_count{table=\"main.cdc.customers\"} 2\n",
+            "zerobus_record_bytes_count{table=\"main.cdc.customers\",source=\"dbserver1.inventory.customers\"} 2\n",
+            "zerobus_end_to_end_latency_seconds_bucket{table=\"main.cdc.customers\",source=\"dbserver1.inventory.customers\",le=\"1\"} 0\n",
+            "zerobus_end_to_end_latency_seconds_bucket{table=\"main.cdc.customers\",source=\"dbserver1.inventory.customers\",le=\"5\"} 2\n",
+            "zerobus_records_filtered_total{source=\"dbserver1.inventory.customers\"} 1\n",
+            "zerobus_source_records_total{source=\"dbserver1.inventory.customers\",kind=\"tombstone\"} 1\n",
+            "zerobus_source_records_total{source=\"pgserver1.shop.accounts\",kind=\"unrouted\"} 1\n",
+            "zerobus_cached_streams 1\n",
+        ] {
+            assert!(text.contains(sample), "{} missing from\n{}", sample, text);
+        }
+    }
+
+    #[test]
+    fn test_mode() {
+        assert_eq!(Mode::Json, Mode::parse("").unwrap());
+        assert_eq!(Mode::Debezium, Mode::parse("Debezium").unwrap());
+        assert!(Mode::parse("avro").is_err());
+    }
+}
@@ -0,0 +1,350 @@
+//! Parsing of Debezium change events in JSON.
+//!
+//! Both shapes Debezium produces are detected automatically:
+//! - The full envelope (`before`, `after`, `source`, `op`, `ts_ms`, `transaction`),
+//!   optionally wrapped in `{"schema": ..., "payload": ...}` by the JSON converter
+//! - Rows unwrapped by the `ExtractNewRecordState` transform, with any `__op`,
+//!   `__db`, `__table`, `__source_ts_ms`, and `__deleted` fields it was told to add
+
+use anyhow::{bail, Context, Result};
+use serde_json::{Map, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Create,
+    Update,
+    Delete,
+    /// A row read by an initial or incremental snapshot
+    Read,
+}
+
+impl Op {
+    fn parse(op: &str) -> Result<Self> {
+        Ok(match op {
+            "c" => Op::Create,
+            "u" => Op::Update,
+            "d" => Op::Delete,
+            "r" => Op::Read,
+            other => bail!("Unsupported Debezium op {:?}", other),
+        })
+    }
+
+    /// The value written to the `op` column
+    pub fn name(&self) -> &'static str {
+        match self {
+            Op::Create => "insert",
+            Op::Update => "update",
+            Op::Delete => "delete",
+            Op::Read => "snapshot",
+        }
+    }
+}
+
+/// Where a change came from, from the envelope's `source` block
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Source {
+    pub db: String,
+    pub table: String,
+    /// When the change was made in the database, milliseconds since Unix epoch
+    pub ts_ms: Option<i64>,
+    /// Log position, for connectors that report one (e.g. Postgres)
+    pub lsn: Option<i64>,
+}
+
+impl Source {
+    /// The key tables are routed by: `<db>.<table>`
+    pub fn qualified_table(&self) -> String {
+        format!("{}.{}", self.db, self.table)
+    }
+}
+
+/// The envelope's `transaction` block, present when transaction metadata is enabled
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transaction {
+    pub id: String,
+    /// Position of the event among all events of the transaction
+    pub total_order: Option<i64>,
+    /// Position of the event among the transaction's events for the same table
+    pub data_collection_order: Option<i64>,
+}
+
+/// One row change
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    pub op: Op,
+    pub before: Option<Map<String, Value>>,
+    pub after: Option<Map<String, Value>>,
+    pub source: Source,
+    pub transaction: Option<Transaction>,
+}
+
+impl Change {
+    /// The row to store: the after-image, or the before-image of a delete
+    pub fn image(&self) -> Option<&Map<String, Value>> {
+        match self.op {
+            Op::Delete => self.before.as_ref(),
+            _ => self.after.as_ref(),
+        }
+    }
+}
+
+/// What a record on a Debezium topic holds
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    Change(Change),
+    /// A null value, which Debezium sends after a delete so compaction can drop the key
+    Tombstone,
+    /// A DDL event from the schema change topic
+    SchemaChange,
+    /// A `BEGIN` or `END` marker from the transaction metadata topic
+    TransactionMarker,
+}
+
+/// Parse the value of a record consumed from `topic`
+///
+/// Unwrapped rows that do not name their table are attributed to the last two
+/// segments of the topic, which Debezium names `<prefix>.<db>.<table>`.
+pub fn parse(topic: &str, value: Option<&[u8]>) -> Result<Event> {
+    let Some(value) = value else {
+        return Ok(Event::Tombstone);
+    };
+    let value: Value = serde_json::from_slice(value).context("Record is not valid JSON")?;
+    let value = match value {
+        // The JSON converter with schemas enabled
+        Value::Object(mut object)
+            if object.contains_key("schema") && object.contains_key("payload") =>
+        {
+            object.remove("payload").unwrap_or(Value::Null)
+        }
+        value => value,
+    };
+    let object = match value {
+        Value::Null => return Ok(Event::Tombstone),
+        Value::Object(object) => object,
+        other => bail!("Expected a JSON object, got {}", other),
+    };
+
+    if object.contains_key("ddl") || object.contains_key("tableChanges") {
+        return Ok(Event::SchemaChange);
+    }
+    if object
+        .get("status")
+        .and_then(Value::as_str)
+        .is_some_and(|s| s == "BEGIN" || s == "END")
+        && object.contains_key("event_count")
+    {
+        return Ok(Event::TransactionMarker);
+    }
+    if object.contains_key("op") && object.contains_key("source") {
+        return envelope(object).map(Event::Change);
+    }
+    unwrapped(topic, object).map(Event::Change)
+}
+
+fn envelope(mut object: Map<String, Value>) -> Result<Change> {
+    let op = Op::parse(
+        object
+            .get("op")
+            .and_then(Value::as_str)
+            .context("Envelope op is not a string")?,
+    )?;
+    let source = object
+        .get("source")
+        .and_then(Value::as_object)
+        .context("Envelope has no source block")?;
+    let source = Source {
+        db: string_field(source, "db").context("Source block has no db")?,
+        table: string_field(source, "table").context("Source block has no table")?,
+        ts_ms: source.get("ts_ms").and_then(Value::as_i64),
+        lsn: source.get("lsn").and_then(Value::as_i64),
+    };
+    let transaction = object
+        .get("transaction")
+        .and_then(Value::as_object)
+        .and_then(|transaction| {
+            Some(Transaction {
+                id: string_field(transaction, "id")?,
+                total_order: transaction.get("total_order").and_then(Value::as_i64),
+                data_collection_order: transaction
+                    .get("data_collection_order")
+                    .and_then(Value::as_i64),
+            })
+        });
+    let mut image = |name: &str| match object.remove(name) {
+        Some(Value::Object(row)) => Ok(Some(row)),
+        None | Some(Value::Null) => Ok(None),
+        Some(other) => bail!("Expected {} to be an object, got {}", name, other),
+    };
+    let change = Change {
+        op,
+        before: image("before")?,
+        after: image("after")?,
+        source,
+        transaction,
+    };
+    if change.image().is_none() {
+        bail!("{:?} event has no row image", op);
+    }
+    Ok(change)
+}
+
+fn unwrapped(topic: &str, mut row: Map<String, Value>) -> Result<Change> {
+    let deleted = row
+        .get("__deleted")
+        .is_some_and(|deleted| deleted == "true" || deleted == true);
+    let op = match row.get("__op").and_then(Value::as_str) {
+        Some(op) => Op::parse(op)?,
+        None if deleted => Op::Delete,
+        None => Op::Create,
+    };
+
+    let mut segments = topic.rsplit('.');
+    let topic_table = segments.next().map(str::to_string);
+    let topic_db = segments.next().map(str::to_string);
+    let source = Source {
+        db: string_field(&row, "__db")
+            .or(topic_db)
+            .with_context(|| format!("Row has no __db, and topic {} names no database", topic))?,
+        table: string_field(&row, "__table")
+            .or(topic_table)
+            .with_context(|| format!("Row has no __table, and topic {} names no table", topic))?,
+        ts_ms: row.get("__source_ts_ms").and_then(Value::as_i64),
+        lsn: row.get("__lsn").and_then(Value::as_i64),
+    };
+
+    // Fields added by the transform are metadata, not columns
+    row.retain(|key, _| !key.starts_with("__"));
+    let (before, after) = match op {
+        Op::Delete => (Some(row), None),
+        _ => (None, Some(row)),
+    };
+    Ok(Change {
+        op,
+        before,
+        after,
+        source,
+        transaction: None,
+    })
+}
+
+fn string_field(object: &Map<String, Value>, name: &str) -> Option<String> {
+    object.get(name).and_then(Value::as_str).map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn fixture(name: &str) -> Event {
+        let path = format!(
+            "{}/tests/fixtures/debezium/{}",
+            env!("CARGO_MANIFEST_DIR"),
+            name
+        );
+        let bytes = std::fs::read(&path).unwrap();
+        parse("dbserver1.inventory.customers", Some(&bytes)).unwrap()
+    }
+
+    fn change(event: Event) -> Change {
+        match event {
+            Event::Change(change) => change,
+            other => panic!("expected a change, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_insert_with_schema_wrapper() {
+        let change = change(fixture("insert.json"));
+
+        assert_eq!(Op::Create, change.op);
+        assert_eq!("inventory.customers", change.source.qualified_table());
+        assert_eq!(Some(1709296496000), change.source.ts_ms);
+        assert_eq!(
+            json!({"id": 1004, "first_name": "Anne", "last_name": "Kretchmar", "email": "annek@noanswer.org"}),
+            Value::Object(change.image().unwrap().clone())
+        );
+        let transaction = change.transaction.unwrap();
+        assert_eq!("file=mysql-bin.000003,pos=359", transaction.id);
+        assert_eq!(Some(1), transaction.total_order);
+    }
+
+    #[test]
+    fn test_update_and_delete() {
+        let update = change(fixture("update.json"));
+        assert_eq!(Op::Update, update.op);
+        assert_eq!("shop.orders", update.source.qualified_table());
+        assert_eq!(Some(24023128), update.source.lsn);
+        assert_eq!(json!("shipped"), update.image().unwrap()["status"]);
+        assert_eq!(json!("pending"), update.before.as_ref().unwrap()["status"]);
+        assert_eq!(None, update.transaction);
+
+        // A delete stores its before-image
+        let delete = change(fixture("delete.json"));
+        assert_eq!(Op::Delete, delete.op);
+        assert_eq!(None, delete.after);
+        assert_eq!(json!(7), delete.image().unwrap()["id"]);
+    }
+
+    #[test]
+    fn test_tombstones_and_metadata_events() {
+        assert_eq!(
+            Event::Tombstone,
+            parse("dbserver1.inventory.customers", None).unwrap()
+        );
+        assert_eq!(
+            Event::Tombstone,
+            parse("t", Some(br#"{"schema": null, "payload": null}"#)).unwrap()
+        );
+        assert_eq!(Event::SchemaChange, fixture("schema_change.json"));
+        assert_eq!(Event::TransactionMarker, fixture("transaction_end.json"));
+    }
+
+    #[test]
+    fn test_unwrapped_rows() {
+        let insert = change(fixture("unwrapped.json"));
+        assert_eq!(Op::Create, insert.op);
+        assert_eq!("inventory.customers", insert.source.qualified_table());
+        assert_eq!(Some(1709296530000), insert.source.ts_ms);
+        let row = insert.image().unwrap();
+        assert_eq!(4, row.len());
+        assert!(!row.contains_key("__op"));
+
+        // Without __db and __table, the topic names the table
+        let bytes = std::fs::read(format!(
+            "{}/tests/fixtures/debezium/unwrapped_delete.json",
+            env!("CARGO_MANIFEST_DIR")
+        ))
+        .unwrap();
+        let delete = change(parse("pgserver1.shop.accounts", Some(&bytes)).unwrap());
+        assert_eq!(Op::Delete, delete.op);
+        assert_eq!("shop.accounts", delete.source.qualified_table());
+        assert_eq!(json!(1005), delete.image().unwrap()["id"]);
+        assert!(!delete.image().unwrap().contains_key("__deleted"));
+    }
+
+    #[test]
+    fn test_malformed_events() {
+        assert!(parse("t", Some(b"not json")).is_err());
+        assert!(parse("t", Some(b"[1, 2]")).is_err());
+        let error = parse(
+            "t",
+            Some(br#"{"op": "c", "after": null, "source": {"db": "d", "table": "t"}}"#),
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("no row image"));
+
+        let error = parse(
+            "t",
+            Some(br#"{"op": 1, "after": {}, "source": {"db": "d", "table": "t"}}"#),
+        )
+        .unwrap_err();
+        assert_eq!("Envelope op is not a string", error.to_string());
+        let error = parse("customers", Some(br#"{"id": 7}"#)).unwrap_err();
+        assert_eq!(
+            "Row has no __db, and topic customers names no database",
+            error.to_string()
+        );
+        assert!(parse("customers", Some(br#"{"id": 7, "__db": "inventory"}"#)).is_ok());
+    }
+}
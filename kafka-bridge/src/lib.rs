@@ -0,0 +1,6 @@
+pub mod auth;
+pub mod bridge;
+pub mod debezium;
+pub mod msk_iam;
+pub mod stream_config;
+pub mod workers;
@@ -0,0 +1,419 @@
+use anyhow::{bail, Context, Result};
+use aws_config::BehaviorVersion;
+use databricks_zerobus_ingest_sdk::{
+    StreamConfigurationOptions, TableProperties, ZerobusSdk, ZerobusStream,
+};
+use kafka_bridge::auth::{BridgeContext, KafkaAuth, MskIamSigner};
+use kafka_bridge::bridge::{Bridge, Mode, Position, SinkFactory};
+use kafka_bridge::msk_iam::{self, CredentialCache};
+use kafka_bridge::stream_config::StreamConfigs;
+use kafka_bridge::workers::{Dispatch, WorkerSummary, Workers};
+use prost_types::DescriptorProto;
+use rdkafka::consumer::{CommitMode, Consumer, StreamConsumer};
+use rdkafka::{ClientConfig, Message, Offset};
+use std::collections::HashMap;
+use std::pin::pin;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::watch;
+use tokio::time::MissedTickBehavior;
+use tracing::{info, warn};
+use zerobus_common::dynamic::{coerce_from_env, FieldErrorMode, KeyCase};
+use zerobus_common::failure_audit::{self, FailureAuditor};
+use zerobus_common::ingest_filter::IngestFilter;
+use zerobus_common::log_level;
+use zerobus_common::metrics::{self, Metrics};
+use zerobus_common::pipeline::max_pending_bytes_from_env;
+use zerobus_common::router::TableRouter;
+use zerobus_common::shutdown;
+use zerobus_common::stats::{self, CloudWatchStats, StatsReporter};
+use zerobus_common::supervisor;
+use zerobus_common::watermark::WatermarkSource;
+
+/// Maximum number of unacknowledged records per table when MAX_INFLIGHT is not set
+const DEFAULT_MAX_INFLIGHT: usize = 10_000;
+
+/// How often offsets are committed when COMMIT_INTERVAL_SECS is not set
+const DEFAULT_COMMIT_INTERVAL_SECS: u64 = 5;
+
+/// How long to wait for a partition's high watermark when measuring consumer lag
+const LAG_TIMEOUT: Duration = Duration::from_secs(1);
+
+fn env(name: &str) -> Result<String> {
+    std::env::var(name).with_context(|| format!("{} environment variable must be set", name))
+}
+
+/// Opens a Zerobus stream per target table
+struct StreamFactory {
+    sdk: ZerobusSdk,
+    client_id: String,
+    client_secret: String,
+}
+
+impl SinkFactory for StreamFactory {
+    type Sink = ZerobusStream;
+
+    async fn open(
+        &self,
+        table: &str,
+        descriptor: DescriptorProto,
+        stream_options: StreamConfigurationOptions,
+    ) -> Result<ZerobusStream> {
+        let table_properties = TableProperties {
+            table_name: table.to_string(),
+            descriptor_proto: descriptor,
+        };
+        self.sdk
+            .create_stream(
+                table_properties,
+                self.client_id.clone(),
+                self.client_secret.clone(),
+                Some(stream_options),
+            )
+            .await
+            .with_context(|| format!("Failed to create stream to {}", table))
+    }
+}
+
+/// Opens the stream to the failure audit table through the bridge's factory
+struct AuditStreams {
+    streams: Arc<StreamFactory>,
+    table: String,
+}
+
+impl supervisor::StreamFactory for AuditStreams {
+    type Sink = ZerobusStream;
+
+    async fn create(&self, descriptor: DescriptorProto) -> Result<ZerobusStream> {
+        self.streams
+            .open(
+                &self.table,
+                descriptor,
+                StreamConfigurationOptions::default(),
+            )
+            .await
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let log_level = log_level::init()?;
+    tokio::spawn(log_level::on_hangup(log_level));
+    let metrics = metrics::serve_from_env().await?;
+    let (stop_stats_sender, stop_stats) = watch::channel(false);
+    let stats_reporter = match stats::interval_from_env()? {
+        Some(interval) => Some(
+            StatsReporter::new(metrics.clone(), interval)
+                .publish_to(CloudWatchStats::from_env().await)
+                .spawn(stop_stats),
+        ),
+        None => None,
+    };
+
+    let mode = Mode::parse(&std::env::var("MODE").unwrap_or_default())?;
+    let topics = env("KAFKA_TOPICS")?;
+    let topics: Vec<&str> = topics
+        .split(',')
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+        .collect();
+    let descriptor_path = env("DESCRIPTOR_SET")?;
+    let descriptors = std::fs::read(&descriptor_path)
+        .with_context(|| format!("Failed to read descriptor set {}", descriptor_path))?;
+    let ignore_unknown_fields = std::env::var("IGNORE_UNKNOWN_FIELDS")
+        .map(|value| value == "true" || value == "1")
+        .unwrap_or(false);
+    let warn_unknown_fields = std::env::var("WARN_UNKNOWN_FIELDS")
+        .map(|value| value == "true" || value == "1")
+        .unwrap_or(false);
+    let max_inflight = positive_env("MAX_INFLIGHT", DEFAULT_MAX_INFLIGHT as u64)? as usize;
+    let stream_configs = StreamConfigs::from_env(StreamConfigurationOptions {
+        max_inflight_records: max_inflight,
+        ..Default::default()
+    })?;
+    let commit_interval = Duration::from_secs(positive_env(
+        "COMMIT_INTERVAL_SECS",
+        DEFAULT_COMMIT_INTERVAL_SECS,
+    )?);
+    let grace = shutdown::grace_from_env()?;
+    let worker_count = positive_env("WORKER_COUNT", 1)? as usize;
+    let dispatch = Dispatch::parse(&std::env::var("WORKER_DISPATCH").unwrap_or_default())?;
+
+    let factory = Arc::new(StreamFactory {
+        sdk: ZerobusSdk::new(env("ZEROBUS_ENDPOINT")?, env("DATABRICKS_HOST")?)?,
+        client_id: env("DATABRICKS_CLIENT_ID")?,
+        client_secret: env("DATABRICKS_CLIENT_SECRET")?,
+    });
+    let router = TableRouter::from_env();
+    let coerce_types = coerce_from_env()?;
+    let field_error_mode = FieldErrorMode::from_env()?;
+    let key_case = KeyCase::from_env()?;
+    let max_pending_bytes = max_pending_bytes_from_env()?;
+    let watermark = WatermarkSource::from_env()?;
+    let ingest_filter = IngestFilter::from_env()?;
+    if let Some(filter) = &ingest_filter {
+        info!("Ingesting only records matching {}", filter.as_str());
+    }
+    let failure_audit = match failure_audit::from_env()? {
+        Some((table, capacity)) => {
+            info!("Auditing skipped records to {}", table);
+            let streams = AuditStreams {
+                streams: Arc::clone(&factory),
+                table,
+            };
+            Some(FailureAuditor::spawn(
+                streams,
+                capacity,
+                metrics.audit_write_failures(),
+            ))
+        }
+        None => None,
+    };
+    let bridges = (0..worker_count)
+        .map(|worker| {
+            let bridge = Bridge::new(
+                Arc::clone(&factory),
+                router.clone(),
+                mode,
+                descriptors.clone(),
+                ignore_unknown_fields,
+                stream_configs.clone(),
+            )
+            .warn_unknown_fields(warn_unknown_fields)
+            .coerce_types(coerce_types)
+            .field_error_mode(field_error_mode)
+            .key_case(key_case)
+            .max_pending_bytes(max_pending_bytes)
+            .watermark(watermark.clone())
+            .ingest_filter(ingest_filter.clone())
+            .failure_audit(failure_audit.as_ref().map(|(auditor, _)| auditor.clone()))
+            .metrics(metrics.clone());
+            // Each worker's streams are reported apart, as they are acknowledged apart
+            if worker_count > 1 {
+                bridge.stream_label(format!("worker-{}", worker))
+            } else {
+                bridge
+            }
+        })
+        .collect();
+    let mut workers = Workers::spawn(bridges, dispatch);
+
+    // Offsets are committed by hand, and only once the rows before them are acknowledged
+    let mut config = ClientConfig::new();
+    config
+        .set("bootstrap.servers", env("KAFKA_BROKERS")?)
+        .set("group.id", env("KAFKA_GROUP_ID")?)
+        .set("enable.auto.commit", "false")
+        .set("auto.offset.reset", "earliest");
+    let auth = KafkaAuth::from_env()?;
+    auth.configure(&mut config);
+    let msk_iam = match auth {
+        KafkaAuth::MskIam { region } => Some(msk_iam_signer(region).await?),
+        _ => None,
+    };
+    let consumer: StreamConsumer<BridgeContext> = config
+        .create_with_context(BridgeContext::new(msk_iam))
+        .context("Failed to create Kafka consumer")?;
+    consumer
+        .subscribe(&topics)
+        .context("Failed to subscribe to topics")?;
+    info!(
+        "Consuming {:?} in {:?} mode with {} workers",
+        topics, mode, worker_count
+    );
+
+    let mut commits = tokio::time::interval(commit_interval);
+    commits.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut shutdown_signal = pin!(shutdown::signal());
+    loop {
+        tokio::select! {
+            message = consumer.recv() => {
+                let message = message.context("Failed to consume from Kafka")?;
+                let event_time = message
+                    .timestamp()
+                    .to_millis()
+                    .and_then(|millis| u64::try_from(millis).ok())
+                    .map(|millis| UNIX_EPOCH + Duration::from_millis(millis));
+                workers
+                    .handle_at(
+                        message.topic(),
+                        Position {
+                            partition: message.partition(),
+                            offset: message.offset(),
+                        },
+                        message.key(),
+                        message.payload(),
+                        event_time,
+                    )
+                    .await?;
+            }
+            _ = commits.tick() => {
+                if let Some(reason) = consumer.context().authentication_failure() {
+                    bail!("Kafka rejected the bridge's credentials: {}", reason);
+                }
+                workers.checkpoint().await?;
+                commit(&consumer)?;
+                report_lag(&consumer, &metrics);
+            }
+            _ = &mut shutdown_signal => break,
+        }
+    }
+
+    let summaries = workers.finish(grace).await?;
+    let WorkerSummary {
+        stats,
+        rows_with_dropped_fields,
+        unacked,
+    } = total(&summaries);
+    if let Some((auditor, task)) = failure_audit {
+        auditor.close();
+        if tokio::time::timeout(grace, task).await.is_err() {
+            warn!(
+                "{} audit rows were still queued at shutdown",
+                auditor.queued()
+            );
+        }
+        info!(
+            "Audited {} skipped records; {} audit rows were not written",
+            auditor.written(),
+            auditor.write_failures()
+        );
+    }
+    let _ = stop_stats_sender.send(true);
+    if let Some(reporter) = stats_reporter {
+        let _ = reporter.await;
+    }
+    info!(
+        "Shut down: {} rows, {} tombstones, {} schema changes, {} transaction markers, {} unrouted, {} filtered, {} malformed",
+        stats.rows,
+        stats.tombstones,
+        stats.schema_changes,
+        stats.transaction_markers,
+        stats.unrouted,
+        stats.filtered,
+        stats.malformed
+    );
+    if rows_with_dropped_fields > 0 {
+        warn!(
+            "{} rows had fields their table does not have, which were dropped",
+            rows_with_dropped_fields
+        );
+    }
+    if unacked > 0 {
+        bail!(
+            "{} rows were not acknowledged before shutdown; their records will be consumed again",
+            unacked
+        );
+    }
+    commit(&consumer)
+}
+
+/// What the workers did, added up
+fn total(summaries: &[WorkerSummary]) -> WorkerSummary {
+    let mut total = WorkerSummary::default();
+    for summary in summaries {
+        total.stats.rows += summary.stats.rows;
+        total.stats.tombstones += summary.stats.tombstones;
+        total.stats.schema_changes += summary.stats.schema_changes;
+        total.stats.transaction_markers += summary.stats.transaction_markers;
+        total.stats.unrouted += summary.stats.unrouted;
+        total.stats.filtered += summary.stats.filtered;
+        total.stats.malformed += summary.stats.malformed;
+        total.rows_with_dropped_fields += summary.rows_with_dropped_fields;
+        total.unacked += summary.unacked;
+    }
+    total
+}
+
+/// Resolve AWS credentials and sign a first token, so missing credentials or region
+/// fail at startup, then keep the credentials fresh in the background
+async fn msk_iam_signer(region: Option<String>) -> Result<MskIamSigner> {
+    let aws_config = aws_config::load_defaults(BehaviorVersion::latest()).await;
+    let region = region
+        .or_else(|| aws_config.region().map(|region| region.to_string()))
+        .context("AWS_REGION must be set for KAFKA_AUTH=msk-iam")?;
+    let provider = aws_config
+        .credentials_provider()
+        .context("No AWS credentials provider is configured")?;
+    let credentials = msk_iam::resolve(&provider)
+        .await
+        .context("MSK IAM needs AWS credentials")?;
+    let token = msk_iam::generate_token(&region, &credentials, SystemTime::now());
+    info!(
+        "Authenticating to MSK in {} as {}, with tokens valid for {:?}",
+        region,
+        credentials.access_key_id,
+        token
+            .expires_at
+            .duration_since(SystemTime::now())
+            .unwrap_or_default()
+    );
+    let credentials = CredentialCache::new(credentials);
+    tokio::spawn(msk_iam::keep_fresh(provider, credentials.clone()));
+    Ok(MskIamSigner {
+        region,
+        credentials,
+    })
+}
+
+/// Record how many records each topic's assigned partitions have yet to deliver
+///
+/// Fetching the high watermarks blocks, so it runs off the async worker. Partitions
+/// nothing was consumed from yet have no position and are left out.
+fn report_lag(consumer: &StreamConsumer<BridgeContext>, metrics: &Metrics) {
+    let lag = tokio::task::block_in_place(|| -> Result<HashMap<String, u64>> {
+        let positions = consumer
+            .position()
+            .context("Failed to read the consumer's positions")?;
+        let mut lag: HashMap<String, u64> = HashMap::new();
+        for partition in positions.elements() {
+            let Offset::Offset(position) = partition.offset() else {
+                continue;
+            };
+            let (_, high) = consumer
+                .fetch_watermarks(partition.topic(), partition.partition(), LAG_TIMEOUT)
+                .with_context(|| {
+                    format!(
+                        "Failed to fetch the watermarks of {} [{}]",
+                        partition.topic(),
+                        partition.partition()
+                    )
+                })?;
+            *lag.entry(partition.topic().to_string()).or_default() +=
+                (high - position).max(0) as u64;
+        }
+        Ok(lag)
+    });
+    match lag {
+        Ok(lag) => {
+            for (topic, lag) in lag {
+                metrics.series("", &topic).set_lag(lag);
+            }
+        }
+        Err(e) => warn!("Failed to measure consumer lag: {:#}", e),
+    }
+}
+
+fn commit(consumer: &StreamConsumer<BridgeContext>) -> Result<()> {
+    match consumer.commit_consumer_state(CommitMode::Sync) {
+        // Nothing was consumed since the last commit
+        Err(rdkafka::error::KafkaError::ConsumerCommit(
+            rdkafka::types::RDKafkaErrorCode::NoOffset,
+        )) => Ok(()),
+        result => result.context("Failed to commit offsets"),
+    }
+}
+
+fn positive_env(name: &str, default: u64) -> Result<u64> {
+    let value = match std::env::var(name) {
+        Ok(value) => value
+            .trim()
+            .parse::<u64>()
+            .with_context(|| format!("{} must be a positive integer, got {:?}", name, value))?,
+        Err(_) => default,
+    };
+    if value == 0 {
+        bail!("{} must be a positive integer, got 0", name);
+    }
+    Ok(value)
+}
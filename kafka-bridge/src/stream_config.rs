@@ -0,0 +1,137 @@
+//! Per-table stream options, tuned by `STREAM_CONFIG_OVERRIDES`
+
+use anyhow::{bail, Context, Result};
+use databricks_zerobus_ingest_sdk::StreamConfigurationOptions;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// Options to change for one table; unset ones keep the default
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StreamOverride {
+    pub max_inflight_records: Option<usize>,
+    pub recovery: Option<bool>,
+    pub recovery_timeout_ms: Option<u64>,
+    pub recovery_backoff_ms: Option<u64>,
+    pub recovery_retries: Option<u32>,
+    pub server_lack_of_ack_timeout_ms: Option<u64>,
+    pub flush_timeout_ms: Option<u64>,
+}
+
+/// The options each table's stream is created with
+///
+/// A high-volume table may need a larger in-flight window than a table that gets a
+/// few rows a minute, so tables can override the defaults one option at a time.
+#[derive(Debug, Clone)]
+pub struct StreamConfigs {
+    default: StreamConfigurationOptions,
+    overrides: HashMap<String, StreamOverride>,
+}
+
+impl StreamConfigs {
+    /// Every table gets `default`
+    pub fn new(default: StreamConfigurationOptions) -> Self {
+        Self {
+            default,
+            overrides: HashMap::new(),
+        }
+    }
+
+    /// Overrides from `STREAM_CONFIG_OVERRIDES`, if it is set
+    pub fn from_env(default: StreamConfigurationOptions) -> Result<Self> {
+        match std::env::var("STREAM_CONFIG_OVERRIDES") {
+            Ok(json) if !json.trim().is_empty() => {
+                Self::parse(default, &json).context("Invalid STREAM_CONFIG_OVERRIDES")
+            }
+            _ => Ok(Self::new(default)),
+        }
+    }
+
+    /// Parse a JSON object of overrides keyed by table name, such as
+    /// `{"main.cdc.orders": {"max_inflight_records": 50000}}`
+    pub fn parse(default: StreamConfigurationOptions, json: &str) -> Result<Self> {
+        let overrides: HashMap<String, StreamOverride> = serde_json::from_str(json)?;
+        if let Some((table, _)) = overrides
+            .iter()
+            .find(|(_, table_override)| table_override.max_inflight_records == Some(0))
+        {
+            bail!("{}: max_inflight_records must be positive", table);
+        }
+        Ok(Self { default, overrides })
+    }
+
+    /// Options for the stream to `table`
+    pub fn for_table(&self, table: &str) -> StreamConfigurationOptions {
+        let mut options = self.default.clone();
+        let Some(table_override) = self.overrides.get(table) else {
+            return options;
+        };
+        if let Some(value) = table_override.max_inflight_records {
+            options.max_inflight_records = value;
+        }
+        if let Some(value) = table_override.recovery {
+            options.recovery = value;
+        }
+        if let Some(value) = table_override.recovery_timeout_ms {
+            options.recovery_timeout_ms = value;
+        }
+        if let Some(value) = table_override.recovery_backoff_ms {
+            options.recovery_backoff_ms = value;
+        }
+        if let Some(value) = table_override.recovery_retries {
+            options.recovery_retries = value;
+        }
+        if let Some(value) = table_override.server_lack_of_ack_timeout_ms {
+            options.server_lack_of_ack_timeout_ms = value;
+        }
+        if let Some(value) = table_override.flush_timeout_ms {
+            options.flush_timeout_ms = value;
+        }
+        options
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn default_options() -> StreamConfigurationOptions {
+        StreamConfigurationOptions {
+            max_inflight_records: 10_000,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_override_applies_to_its_table_only() {
+        let configs = StreamConfigs::parse(
+            default_options(),
+            r#"{"main.cdc.orders": {"max_inflight_records": 50000, "recovery_retries": 10}}"#,
+        )
+        .unwrap();
+
+        let orders = configs.for_table("main.cdc.orders");
+        assert_eq!(50_000, orders.max_inflight_records);
+        assert_eq!(10, orders.recovery_retries);
+        // Options the override does not name keep the default
+        assert_eq!(default_options().flush_timeout_ms, orders.flush_timeout_ms);
+
+        let customers = configs.for_table("main.cdc.customers");
+        assert_eq!(10_000, customers.max_inflight_records);
+        assert_eq!(
+            default_options().recovery_retries,
+            customers.recovery_retries
+        );
+    }
+
+    #[test]
+    fn test_invalid_overrides() {
+        let parse = |json: &str| StreamConfigs::parse(default_options(), json);
+
+        assert!(parse("{}").is_ok());
+        assert!(parse(r#"["main.cdc.orders"]"#).is_err());
+        assert!(parse(r#"{"main.cdc.orders": {"max_inflight": 5}}"#).is_err());
+        assert!(parse(r#"{"main.cdc.orders": {"max_inflight_records": 0}}"#).is_err());
+        assert!(parse(r#"{"main.cdc.orders": {"max_inflight_records": "many"}}"#).is_err());
+    }
+}
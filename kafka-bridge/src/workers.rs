@@ -0,0 +1,413 @@
+//! Spreading consumed records over several bridges, each on its own task
+//!
+//! Every worker owns a [`Bridge`] with its own stream to each table it writes to, so
+//! records are parsed, encoded, and sent in parallel. Records are dispatched by key, so
+//! the records of one Kafka key or partition keep their order, or round-robin.
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, SystemTime};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+use crate::bridge::{Bridge, BridgeStats, Position, SinkFactory};
+
+/// Records queued for each worker before the consumer waits for it
+const QUEUE_CAPACITY: usize = 1024;
+
+/// How records are spread over the workers, selected by `WORKER_DISPATCH`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dispatch {
+    /// By topic and Kafka key, or partition for records without a key
+    Key,
+    /// Each record to the next worker in turn
+    RoundRobin,
+}
+
+impl Dispatch {
+    pub fn parse(dispatch: &str) -> Result<Self> {
+        match dispatch.trim().to_ascii_lowercase().as_str() {
+            "" | "key" => Ok(Dispatch::Key),
+            "round-robin" => Ok(Dispatch::RoundRobin),
+            _ => bail!(
+                "WORKER_DISPATCH must be \"key\" or \"round-robin\", got {:?}",
+                dispatch
+            ),
+        }
+    }
+}
+
+/// A consumed record, as a worker is handed it
+struct Record {
+    topic: String,
+    position: Position,
+    value: Option<Vec<u8>>,
+    event_time: Option<SystemTime>,
+}
+
+enum Command {
+    Handle(Record),
+    Checkpoint(oneshot::Sender<Result<()>>),
+    Finish(Duration),
+}
+
+/// What a worker did, once its streams are closed
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct WorkerSummary {
+    pub stats: BridgeStats,
+    /// See [`Bridge::rows_with_dropped_fields`]
+    pub rows_with_dropped_fields: u64,
+    /// Rows still unacknowledged when the grace period ran out
+    pub unacked: usize,
+}
+
+/// Worker tasks, each running a bridge
+pub struct Workers {
+    dispatch: Dispatch,
+    senders: Vec<mpsc::Sender<Command>>,
+    handles: Vec<JoinHandle<Result<WorkerSummary>>>,
+    next: usize,
+}
+
+impl Workers {
+    /// Run each of `bridges` on a task of its own
+    pub fn spawn<F>(bridges: Vec<Bridge<F>>, dispatch: Dispatch) -> Self
+    where
+        F: SinkFactory + Send + 'static,
+        F::Sink: 'static,
+    {
+        assert!(!bridges.is_empty(), "at least one worker is needed");
+        let (senders, handles) = bridges
+            .into_iter()
+            .map(|bridge| {
+                let (sender, receiver) = mpsc::channel(QUEUE_CAPACITY);
+                (sender, tokio::spawn(run(bridge, receiver)))
+            })
+            .unzip();
+        Self {
+            dispatch,
+            senders,
+            handles,
+            next: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.senders.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.senders.is_empty()
+    }
+
+    /// The worker a record from `topic` and `partition`, with `key`, goes to
+    pub fn worker_for(&mut self, topic: &str, partition: i32, key: Option<&[u8]>) -> usize {
+        match self.dispatch {
+            Dispatch::Key => {
+                let mut hasher = DefaultHasher::new();
+                topic.hash(&mut hasher);
+                match key {
+                    Some(key) => key.hash(&mut hasher),
+                    None => partition.hash(&mut hasher),
+                }
+                (hasher.finish() % self.len() as u64) as usize
+            }
+            Dispatch::RoundRobin => {
+                let worker = self.next;
+                self.next = (self.next + 1) % self.len();
+                worker
+            }
+        }
+    }
+
+    /// Hand the record at `position` to its worker, like [`Bridge::handle_consumed`]
+    ///
+    /// Waits while the worker's queue is full. Fails with the worker's error when it
+    /// has stopped, as a bridge fails when a stream cannot be opened or written to.
+    pub async fn handle_at(
+        &mut self,
+        topic: &str,
+        position: Position,
+        key: Option<&[u8]>,
+        value: Option<&[u8]>,
+        event_time: Option<SystemTime>,
+    ) -> Result<()> {
+        let worker = self.worker_for(topic, position.partition, key);
+        let record = Record {
+            topic: topic.to_string(),
+            position,
+            value: value.map(<[u8]>::to_vec),
+            event_time,
+        };
+        if self.senders[worker]
+            .send(Command::Handle(record))
+            .await
+            .is_err()
+        {
+            return Err(self.failure(worker).await);
+        }
+        Ok(())
+    }
+
+    /// Wait until every worker has had every row handed to it so far acknowledged
+    ///
+    /// Once this returns, the offsets of every record handed over so far can be
+    /// committed.
+    pub async fn checkpoint(&mut self) -> Result<()> {
+        let mut replies = Vec::with_capacity(self.len());
+        for worker in 0..self.len() {
+            let (reply, receiver) = oneshot::channel();
+            if self.senders[worker]
+                .send(Command::Checkpoint(reply))
+                .await
+                .is_err()
+            {
+                return Err(self.failure(worker).await);
+            }
+            replies.push(receiver);
+        }
+        for (worker, reply) in replies.into_iter().enumerate() {
+            match reply.await {
+                Ok(result) => result.with_context(|| format!("Worker {}", worker))?,
+                Err(_) => return Err(self.failure(worker).await),
+            }
+        }
+        Ok(())
+    }
+
+    /// Drain and close every worker's streams within `grace`
+    ///
+    /// Returns each worker's summary, in worker order.
+    pub async fn finish(self, grace: Duration) -> Result<Vec<WorkerSummary>> {
+        for sender in &self.senders {
+            // A worker that already stopped reports why when it is joined
+            let _ = sender.send(Command::Finish(grace)).await;
+        }
+        drop(self.senders);
+        let mut summaries = Vec::with_capacity(self.handles.len());
+        for (worker, handle) in self.handles.into_iter().enumerate() {
+            let summary = handle
+                .await
+                .map_err(|e| anyhow!("Worker {} panicked: {}", worker, e))?
+                .with_context(|| format!("Worker {}", worker))?;
+            summaries.push(summary);
+        }
+        Ok(summaries)
+    }
+
+    /// Why a worker stopped taking commands
+    async fn failure(&mut self, worker: usize) -> anyhow::Error {
+        match (&mut self.handles[worker]).await {
+            Ok(Err(e)) => e.context(format!("Worker {}", worker)),
+            Ok(Ok(_)) => anyhow!("Worker {} stopped", worker),
+            Err(e) => anyhow!("Worker {} panicked: {}", worker, e),
+        }
+    }
+}
+
+/// Run commands against `bridge` until told to finish
+async fn run<F: SinkFactory>(
+    mut bridge: Bridge<F>,
+    mut commands: mpsc::Receiver<Command>,
+) -> Result<WorkerSummary> {
+    let mut grace = Duration::ZERO;
+    while let Some(command) = commands.recv().await {
+        match command {
+            Command::Handle(record) => {
+                bridge
+                    .handle_consumed(
+                        &record.topic,
+                        Some(record.position),
+                        record.value.as_deref(),
+                        record.event_time,
+                    )
+                    .await?
+            }
+            Command::Checkpoint(reply) => {
+                let _ = reply.send(bridge.checkpoint().await);
+            }
+            Command::Finish(finish_grace) => {
+                grace = finish_grace;
+                break;
+            }
+        }
+    }
+    let stats = bridge.stats().clone();
+    let rows_with_dropped_fields = bridge.rows_with_dropped_fields();
+    let unacked = bridge.finish(grace).await?;
+    Ok(WorkerSummary {
+        stats,
+        rows_with_dropped_fields,
+        unacked,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bridge::Mode;
+    use crate::stream_config::StreamConfigs;
+    use databricks_zerobus_ingest_sdk::StreamConfigurationOptions;
+    use prost::Message;
+    use prost_types::field_descriptor_proto::{Label, Type};
+    use prost_types::{
+        DescriptorProto, FieldDescriptorProto, FileDescriptorProto, FileDescriptorSet,
+    };
+    use std::sync::{Arc, Mutex};
+    use zerobus_common::router::TableRouter;
+    use zerobus_common::testing::MockSink;
+
+    /// Hands out one shared mock sink, counting the streams opened
+    #[derive(Default)]
+    struct MockFactory {
+        sink: MockSink,
+        opened: Mutex<usize>,
+    }
+
+    impl SinkFactory for MockFactory {
+        type Sink = MockSink;
+
+        async fn open(
+            &self,
+            _table: &str,
+            _descriptor: DescriptorProto,
+            _options: StreamConfigurationOptions,
+        ) -> Result<MockSink> {
+            *self.opened.lock().unwrap() += 1;
+            Ok(self.sink.clone())
+        }
+    }
+
+    fn descriptors() -> Vec<u8> {
+        let clicks = DescriptorProto {
+            name: Some("table_clicks".to_string()),
+            field: vec![FieldDescriptorProto {
+                name: Some("id".to_string()),
+                number: Some(1),
+                label: Some(Label::Optional as i32),
+                r#type: Some(Type::Int64 as i32),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        FileDescriptorSet {
+            file: vec![FileDescriptorProto {
+                name: Some("tables.proto".to_string()),
+                message_type: vec![clicks],
+                ..Default::default()
+            }],
+        }
+        .encode_to_vec()
+    }
+
+    fn workers(factory: &Arc<MockFactory>, count: usize, dispatch: Dispatch) -> Workers {
+        let bridges = (0..count)
+            .map(|_| {
+                Bridge::new(
+                    Arc::clone(factory),
+                    TableRouter::new("clicks=main.raw.clicks", None),
+                    Mode::Json,
+                    descriptors(),
+                    false,
+                    StreamConfigs::new(StreamConfigurationOptions {
+                        max_inflight_records: 100,
+                        ..Default::default()
+                    }),
+                )
+            })
+            .collect();
+        Workers::spawn(bridges, dispatch)
+    }
+
+    #[tokio::test]
+    async fn test_records_are_spread_over_every_worker() {
+        for dispatch in [Dispatch::RoundRobin, Dispatch::Key] {
+            let factory = Arc::new(MockFactory::default());
+            let mut workers = workers(&factory, 4, dispatch);
+            for id in 0..100 {
+                let key = format!("user-{}", id);
+                let value = format!(r#"{{"id": {}}}"#, id);
+                workers
+                    .handle_at(
+                        "clicks",
+                        Position {
+                            partition: id % 3,
+                            offset: id as i64,
+                        },
+                        Some(key.as_bytes()),
+                        Some(value.as_bytes()),
+                        None,
+                    )
+                    .await
+                    .unwrap();
+            }
+            workers.checkpoint().await.unwrap();
+            assert_eq!(100, factory.sink.records().len());
+
+            let summaries = workers.finish(Duration::from_secs(1)).await.unwrap();
+            let rows: Vec<u64> = summaries.iter().map(|s| s.stats.rows).collect();
+            assert_eq!(4, rows.len());
+            assert!(rows.iter().all(|rows| *rows > 0), "{:?}", rows);
+            assert_eq!(100, rows.iter().sum::<u64>());
+            assert!(summaries.iter().all(|s| s.unacked == 0));
+            // Each worker opened its own stream to the table
+            assert_eq!(4, *factory.opened.lock().unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_same_key_goes_to_the_same_worker() {
+        let factory = Arc::new(MockFactory::default());
+        let mut workers = workers(&factory, 4, Dispatch::Key);
+        let first = workers.worker_for("clicks", 0, Some(b"user-1"));
+        for partition in 0..10 {
+            assert_eq!(
+                first,
+                workers.worker_for("clicks", partition, Some(b"user-1"))
+            );
+        }
+        // Without a key, by partition
+        assert_eq!(
+            workers.worker_for("clicks", 2, None),
+            workers.worker_for("clicks", 2, None)
+        );
+        workers.finish(Duration::ZERO).await.unwrap();
+
+        assert_eq!(Dispatch::Key, Dispatch::parse("").unwrap());
+        assert_eq!(
+            Dispatch::RoundRobin,
+            Dispatch::parse("Round-Robin").unwrap()
+        );
+        assert!(Dispatch::parse("random").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_failed_checkpoint_names_the_worker() {
+        let factory = Arc::new(MockFactory {
+            sink: MockSink::default().fail_acks_for(|_| true),
+            ..Default::default()
+        });
+        let mut workers = workers(&factory, 2, Dispatch::RoundRobin);
+        workers
+            .handle_at(
+                "clicks",
+                Position {
+                    partition: 0,
+                    offset: 0,
+                },
+                None,
+                Some(br#"{"id": 1}"#),
+                None,
+            )
+            .await
+            .unwrap();
+
+        let error = workers.checkpoint().await.unwrap_err();
+        assert!(
+            format!("{:#}", error).starts_with("Worker 0: 1 rows"),
+            "{:#}",
+            error
+        );
+    }
+}
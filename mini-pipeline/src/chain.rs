@@ -0,0 +1,220 @@
+//! The transform chain every event goes through: redaction, then the field map, then
+//! the static tags
+//!
+//! - `REDACT_FIELDS` - Comma-separated JSON pointers whose values are replaced by
+//!   `"[REDACTED]"`, in the archived event as well as the typed row
+//! - `FIELD_MAP` - JSON object mapping typed columns to JSON pointers into the event;
+//!   without it the event's top-level fields are the columns
+//! - `STATIC_TAGS` - Comma-separated `column=value` pairs set on every typed row, such
+//!   as the environment or region the pipeline runs in
+
+use anyhow::{bail, Context, Result};
+use serde_json::{Map, Value};
+
+pub use zerobus_common::redact::{Redact, REDACTED};
+
+/// Typed columns, each read from a JSON pointer into the event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldMap {
+    fields: Vec<(String, String)>,
+}
+
+impl FieldMap {
+    pub fn parse(map: &str) -> Result<Self> {
+        let object: Map<String, Value> =
+            serde_json::from_str(map).context("Field map is not a JSON object")?;
+        let mut fields = Vec::with_capacity(object.len());
+        for (column, pointer) in object {
+            match pointer {
+                Value::String(pointer) if pointer.starts_with('/') => {
+                    fields.push((column, pointer))
+                }
+                other => bail!(
+                    "Field map column {} must map to a JSON pointer such as /order/id, got {}",
+                    column,
+                    other
+                ),
+            }
+        }
+        Ok(Self { fields })
+    }
+
+    /// Pointers that match nothing leave their column unset; objects and arrays are
+    /// stored as JSON strings
+    fn apply(&self, event: &Value) -> Map<String, Value> {
+        let mut row = Map::new();
+        for (column, pointer) in &self.fields {
+            match event.pointer(pointer) {
+                None | Some(Value::Null) => {}
+                Some(value @ (Value::Object(_) | Value::Array(_))) => {
+                    row.insert(column.clone(), Value::String(value.to_string()));
+                }
+                Some(value) => {
+                    row.insert(column.clone(), value.clone());
+                }
+            }
+        }
+        row
+    }
+}
+
+/// Columns set to the same value on every row
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StaticTags {
+    tags: Vec<(String, String)>,
+}
+
+impl StaticTags {
+    pub fn parse(tags: &str) -> Result<Self> {
+        let tags = tags
+            .split(',')
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| match pair.split_once('=') {
+                Some((column, value)) if !column.trim().is_empty() => {
+                    Ok((column.trim().to_string(), value.trim().to_string()))
+                }
+                _ => bail!("Static tag {:?} must be column=value", pair),
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { tags })
+    }
+}
+
+/// Redaction, field map, and static tags, applied in that order
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TransformChain {
+    redact: Redact,
+    field_map: Option<FieldMap>,
+    tags: StaticTags,
+}
+
+impl TransformChain {
+    pub fn new(redact: Redact, field_map: Option<FieldMap>, tags: StaticTags) -> Self {
+        Self {
+            redact,
+            field_map,
+            tags,
+        }
+    }
+
+    /// Read `REDACT_FIELDS`, `FIELD_MAP`, and `STATIC_TAGS`; each is optional
+    pub fn from_env() -> Result<Self> {
+        let var = |name: &str| {
+            std::env::var(name)
+                .ok()
+                .filter(|value| !value.trim().is_empty())
+        };
+        let redact = match var("REDACT_FIELDS") {
+            Some(fields) => Redact::parse(&fields).context("Invalid REDACT_FIELDS")?,
+            None => Redact::default(),
+        };
+        let field_map = match var("FIELD_MAP") {
+            Some(map) => Some(FieldMap::parse(&map).context("Invalid FIELD_MAP")?),
+            None => None,
+        };
+        let tags = match var("STATIC_TAGS") {
+            Some(tags) => StaticTags::parse(&tags).context("Invalid STATIC_TAGS")?,
+            None => StaticTags::default(),
+        };
+        Ok(Self::new(redact, field_map, tags))
+    }
+
+    /// Columns the chain sets by name, which every typed table must have
+    pub fn columns(&self) -> impl Iterator<Item = &str> {
+        let mapped = self.field_map.iter().flat_map(|map| &map.fields);
+        mapped
+            .chain(&self.tags.tags)
+            .map(|(column, _)| column.as_str())
+    }
+
+    /// The first link: the event as it may be stored, in the archive or anywhere else
+    pub fn redact(&self, event: &mut Value) {
+        self.redact.apply(event);
+    }
+
+    /// The rest of the chain: the typed row of an event already redacted
+    pub fn row(&self, event: &Value) -> Result<Map<String, Value>> {
+        let mut row = match (&self.field_map, event) {
+            (Some(field_map), _) => field_map.apply(event),
+            (None, Value::Object(object)) => object.clone(),
+            (None, _) => bail!("Event is not a JSON object"),
+        };
+        for (column, value) in &self.tags.tags {
+            row.insert(column.clone(), Value::from(value.as_str()));
+        }
+        Ok(row)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_chain() {
+        let chain = TransformChain::new(
+            Redact::parse("/customer/email, /card").unwrap(),
+            Some(
+                FieldMap::parse(
+                    r#"{"order_id": "/id", "email": "/customer/email", "items": "/items"}"#,
+                )
+                .unwrap(),
+            ),
+            StaticTags::parse("environment=prod,region = eu-west-1").unwrap(),
+        );
+        let mut event = json!({
+            "id": "ord_1042",
+            "customer": {"email": "ada@example.com", "name": "Ada"},
+            "card": {"last4": "4242"},
+            "items": [{"sku": "A-1"}]
+        });
+
+        chain.redact(&mut event);
+        assert_eq!(json!("[REDACTED]"), event["customer"]["email"]);
+        assert_eq!(json!("[REDACTED]"), event["card"]);
+        assert_eq!(json!("Ada"), event["customer"]["name"]);
+
+        let row = chain.row(&event).unwrap();
+        assert_eq!(
+            json!({
+                "order_id": "ord_1042",
+                "email": "[REDACTED]",
+                "items": r#"[{"sku":"A-1"}]"#,
+                "environment": "prod",
+                "region": "eu-west-1"
+            }),
+            Value::Object(row)
+        );
+
+        let mut columns: Vec<&str> = chain.columns().collect();
+        columns.sort();
+        assert_eq!(
+            vec!["email", "environment", "items", "order_id", "region"],
+            columns
+        );
+    }
+
+    #[test]
+    fn test_without_a_field_map() {
+        let chain = TransformChain::new(
+            Redact::default(),
+            None,
+            StaticTags::parse("environment=dev").unwrap(),
+        );
+        assert_eq!(
+            json!({"id": 7, "environment": "dev"}),
+            Value::Object(chain.row(&json!({"id": 7})).unwrap())
+        );
+        assert!(chain.row(&json!([1, 2])).is_err());
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        assert!(Redact::parse("customer.email").is_err());
+        assert!(FieldMap::parse(r#"{"order_id": "id"}"#).is_err());
+        assert!(StaticTags::parse("environment").is_err());
+        assert!(StaticTags::parse("=prod").is_err());
+    }
+}
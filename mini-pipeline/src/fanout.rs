@@ -0,0 +1,333 @@
+//! Writing each event to the raw archive table and to its typed table
+//!
+//! Every event is archived, redacted but otherwise as it was sent, with its source and
+//! receive time. The source is also routed through `TABLE_ROUTES` to a typed table,
+//! which gets the row the rest of the transform chain makes of it. Sources without a
+//! route, and no `DEFAULT_TABLE`, are only archived.
+
+use anyhow::{bail, Result};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use zerobus_common::cardinality::LabelGuard;
+use zerobus_common::dynamic::DynamicEncoder;
+use zerobus_common::pipeline::{IngestSink, IngestSummary, Pipeline};
+use zerobus_common::router::TableRouter;
+
+use crate::chain::TransformChain;
+
+/// Archive column holding the redacted event as JSON
+pub const PAYLOAD_COLUMN: &str = "payload";
+
+/// Rows of one table from one source, by outcome, since the start
+#[derive(Debug, Default)]
+pub struct TableMetrics {
+    pub ingested: AtomicU64,
+    /// Not acknowledged
+    pub failed: AtomicU64,
+    /// Events that could not be made into a row of the table
+    pub rejected: AtomicU64,
+    /// Encoded bytes of the acknowledged rows
+    pub bytes: AtomicU64,
+}
+
+/// A target table and the stream to it
+pub struct Table<S: IngestSink> {
+    pub name: String,
+    encoder: DynamicEncoder,
+    /// Requests take turns on the stream, so each is answered with its own outcome
+    pipeline: Mutex<Pipeline<S>>,
+    /// By source label
+    metrics: std::sync::Mutex<BTreeMap<String, Arc<TableMetrics>>>,
+}
+
+impl<S: IngestSink> Table<S> {
+    pub fn new(name: impl Into<String>, encoder: DynamicEncoder, pipeline: Pipeline<S>) -> Self {
+        Self {
+            name: name.into(),
+            encoder,
+            pipeline: Mutex::new(pipeline),
+            metrics: Default::default(),
+        }
+    }
+
+    /// Rows written to the table, by source label
+    pub fn metrics(&self) -> Vec<(String, Arc<TableMetrics>)> {
+        self.metrics
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(source, metrics)| (source.clone(), Arc::clone(metrics)))
+            .collect()
+    }
+
+    fn source_metrics(&self, source: &str) -> Arc<TableMetrics> {
+        let mut metrics = self.metrics.lock().unwrap();
+        Arc::clone(metrics.entry(source.to_string()).or_default())
+    }
+
+    /// Ingest `records` of `source` and wait for them, returning what became of them
+    async fn ingest(&self, source: &str, records: Vec<Vec<u8>>, rejected: u64) -> IngestSummary {
+        let metrics = self.source_metrics(source);
+        metrics.rejected.fetch_add(rejected, Ordering::Relaxed);
+        if records.is_empty() {
+            return IngestSummary::default();
+        }
+        let sent = records.len() as u64;
+        let mut pipeline = self.pipeline.lock().await;
+        let before = pipeline.summary().clone();
+        let result = pipeline.ingest_batch(records).await;
+        let after = pipeline.summary();
+
+        // Records after a send error are never sent, so they count as failed too
+        let ingested = after.ingested - before.ingested;
+        let failed_by_class = after
+            .failed_by_class
+            .iter()
+            .map(|(class, failed)| {
+                let earlier = before
+                    .failed_by_class
+                    .get(class)
+                    .copied()
+                    .unwrap_or_default();
+                (*class, failed - earlier)
+            })
+            .filter(|(_, failed)| *failed > 0)
+            .collect();
+        let summary = IngestSummary {
+            ingested,
+            failed: sent - ingested,
+            bytes: after.bytes - before.bytes,
+            first_error: result.err().map(|e| format!("{:#}", e)),
+            failed_by_class,
+            // Distributions are kept for the pipeline as a whole, not per request
+            ..IngestSummary::default()
+        };
+        metrics
+            .ingested
+            .fetch_add(summary.ingested, Ordering::Relaxed);
+        metrics.failed.fetch_add(summary.failed, Ordering::Relaxed);
+        metrics.bytes.fetch_add(summary.bytes, Ordering::Relaxed);
+        summary
+    }
+
+    /// Take the pipeline back once nothing else uses the table, so it can be finished
+    pub fn into_pipeline(self) -> Pipeline<S> {
+        self.pipeline.into_inner()
+    }
+}
+
+/// What became of the events of one request, per table
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestSummary {
+    pub source: String,
+    pub events: usize,
+    pub archive: TableSummary,
+    /// `None` when the source is only archived
+    pub typed: Option<TableSummary>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableSummary {
+    pub table: String,
+    pub summary: IngestSummary,
+    /// Events that could not be made into a row, with why
+    pub rejected: Vec<String>,
+}
+
+impl TableSummary {
+    fn to_json(&self) -> Value {
+        json!({
+            "table": self.table,
+            "ingested": self.summary.ingested,
+            "failed": self.summary.failed,
+            "rejected": self.rejected.len(),
+            "bytes": self.summary.bytes,
+            "error": self.summary.first_error,
+            "errors": self
+                .summary
+                .failed_by_class
+                .iter()
+                .map(|(class, failed)| (class.as_str(), *failed))
+                .collect::<BTreeMap<_, _>>(),
+            "rejections": self.rejected,
+        })
+    }
+}
+
+impl RequestSummary {
+    /// Whether a row was not acknowledged, so the request should be sent again
+    ///
+    /// Rejected events are not failures: sending them again would not change them.
+    pub fn failed(&self) -> bool {
+        self.tables().any(|table| table.summary.failed > 0)
+    }
+
+    pub fn tables(&self) -> impl Iterator<Item = &TableSummary> {
+        std::iter::once(&self.archive).chain(self.typed.iter())
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "source": self.source,
+            "events": self.events,
+            "tables": self.tables().map(TableSummary::to_json).collect::<Vec<_>>(),
+        })
+    }
+}
+
+/// The archive table, the typed tables, and how events get to them
+pub struct FanOut<S: IngestSink> {
+    chain: TransformChain,
+    router: TableRouter,
+    archive: Table<S>,
+    typed: HashMap<String, Table<S>>,
+    /// Bounds the table and source labels of the row counts
+    labels: LabelGuard,
+}
+
+impl<S: IngestSink> FanOut<S> {
+    /// Fails when a table is missing a column it is written, or a routed table has no
+    /// stream
+    pub fn new(
+        chain: TransformChain,
+        router: TableRouter,
+        archive: Table<S>,
+        typed: Vec<Table<S>>,
+    ) -> Result<Self> {
+        if !archive.encoder.has_field(PAYLOAD_COLUMN) {
+            bail!(
+                "Archive table {} has no {} column",
+                archive.name,
+                PAYLOAD_COLUMN
+            );
+        }
+        let typed: HashMap<String, Table<S>> = typed
+            .into_iter()
+            .map(|table| (table.name.clone(), table))
+            .collect();
+        for name in router.tables() {
+            let Some(table) = typed.get(name) else {
+                bail!("Table {} is routed to but has no stream", name);
+            };
+            for column in chain.columns() {
+                if !table.encoder.has_field(column) {
+                    bail!("Table {} has no column {}", name, column);
+                }
+            }
+        }
+        Ok(Self {
+            chain,
+            router,
+            archive,
+            typed,
+            labels: LabelGuard::default(),
+        })
+    }
+
+    /// Count the rows of at most `max` tables and sources apart; the sources of the
+    /// others are counted as `other`
+    pub fn max_series(mut self, max: usize) -> Self {
+        self.labels = LabelGuard::new(max);
+        self
+    }
+
+    /// Archive `events` of `source` and write their rows to its typed table, waiting
+    /// until both tables have acknowledged them
+    ///
+    /// `now` is the receive time, in microseconds since Unix epoch.
+    pub async fn ingest(&self, source: &str, events: Vec<Value>, now: i64) -> RequestSummary {
+        let typed = self
+            .router
+            .route(source)
+            .and_then(|table| self.typed.get(table));
+
+        let count = events.len();
+        let mut archived = Vec::with_capacity(count);
+        let mut archive_rejected = Vec::new();
+        let mut rows = Vec::with_capacity(count);
+        let mut typed_rejected = Vec::new();
+        for (index, mut event) in events.into_iter().enumerate() {
+            self.chain.redact(&mut event);
+            match self.archive_row(source, &event, now) {
+                Ok(record) => archived.push(record),
+                Err(e) => archive_rejected.push(format!("Event {}: {:#}", index, e)),
+            }
+            if let Some(table) = typed {
+                let row = self
+                    .chain
+                    .row(&event)
+                    .and_then(|row| table.encoder.encode(&Value::Object(row)));
+                match row {
+                    Ok(record) => rows.push(record),
+                    Err(e) => typed_rejected.push(format!("Event {}: {:#}", index, e)),
+                }
+            }
+        }
+
+        // The tables have a stream each, so both are written at once
+        let archive_rejections = archive_rejected.len() as u64;
+        let typed_rejections = typed_rejected.len() as u64;
+        let archive_source = self.labels.source(&self.archive.name, source);
+        let (archive_summary, typed_summary) = tokio::join!(
+            self.archive
+                .ingest(archive_source, archived, archive_rejections),
+            async {
+                match typed {
+                    Some(table) => {
+                        let typed_source = self.labels.source(&table.name, source);
+                        Some(table.ingest(typed_source, rows, typed_rejections).await)
+                    }
+                    None => None,
+                }
+            }
+        );
+
+        RequestSummary {
+            source: source.to_string(),
+            events: count,
+            archive: TableSummary {
+                table: self.archive.name.clone(),
+                summary: archive_summary,
+                rejected: archive_rejected,
+            },
+            typed: typed
+                .zip(typed_summary)
+                .map(|(table, summary)| TableSummary {
+                    table: table.name.clone(),
+                    summary,
+                    rejected: typed_rejected,
+                }),
+        }
+    }
+
+    /// The archive row of a redacted event; `source` and `received_at` are set when
+    /// the table has them
+    fn archive_row(&self, source: &str, event: &Value, now: i64) -> Result<Vec<u8>> {
+        let mut row = serde_json::Map::new();
+        row.insert(PAYLOAD_COLUMN.to_string(), Value::from(event.to_string()));
+        for (column, value) in [("source", Value::from(source)), ("received_at", now.into())] {
+            if self.archive.encoder.has_field(column) {
+                row.insert(column.to_string(), value);
+            }
+        }
+        self.archive.encoder.encode(&Value::Object(row))
+    }
+
+    /// Every table, the archive first
+    pub fn tables(&self) -> impl Iterator<Item = &Table<S>> {
+        let mut typed: Vec<&Table<S>> = self.typed.values().collect();
+        typed.sort_by(|a, b| a.name.cmp(&b.name));
+        std::iter::once(&self.archive).chain(typed)
+    }
+
+    /// Take the pipelines back, by table, so they can be finished
+    pub fn into_pipelines(self) -> Vec<(String, Pipeline<S>)> {
+        std::iter::once(self.archive)
+            .chain(self.typed.into_values())
+            .map(|table| (table.name.clone(), table.into_pipeline()))
+            .collect()
+    }
+}
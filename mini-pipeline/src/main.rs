@@ -0,0 +1,141 @@
+use anyhow::{bail, Context, Result};
+use databricks_zerobus_ingest_sdk::{
+    StreamConfigurationOptions, TableProperties, ZerobusSdk, ZerobusStream,
+};
+use mini_pipeline::chain::TransformChain;
+use mini_pipeline::fanout::{FanOut, Table};
+use mini_pipeline::server::{router, AppState};
+use std::time::Instant;
+use tracing::info;
+use zerobus_common::cardinality;
+use zerobus_common::descriptor::find_message_descriptor;
+use zerobus_common::dynamic::{coerce_from_env, DynamicEncoder, FieldErrorMode, KeyCase};
+use zerobus_common::log_level;
+use zerobus_common::pipeline::Pipeline;
+use zerobus_common::router::{message_name, TableRouter};
+use zerobus_common::shutdown;
+
+/// Maximum number of unacknowledged records per stream
+const MAX_INFLIGHT_RECORDS: usize = 10_000;
+
+/// Address to listen on when LISTEN_ADDR is not set
+const DEFAULT_LISTEN_ADDR: &str = "0.0.0.0:8080";
+
+fn env(name: &str) -> Result<String> {
+    std::env::var(name).with_context(|| format!("{} environment variable must be set", name))
+}
+
+/// Open a stream to `table`, with the encoder of its message in the descriptor set
+async fn open_table(
+    sdk: &ZerobusSdk,
+    descriptor_set: &[u8],
+    table: &str,
+    typed: bool,
+) -> Result<Table<ZerobusStream>> {
+    let descriptor = find_message_descriptor(descriptor_set, &message_name(table))
+        .with_context(|| format!("Table {}", table))?;
+    let mut encoder = DynamicEncoder::new(&descriptor)?;
+    if typed {
+        // Events carry whatever their sources send; the typed table keeps the fields
+        // it has columns for
+        encoder = encoder
+            .ignore_unknown_fields(true)
+            .coerce_types(coerce_from_env()?)
+            .field_error_mode(FieldErrorMode::from_env()?)
+            .key_case(KeyCase::from_env()?);
+    }
+
+    let table_properties = TableProperties {
+        table_name: table.to_string(),
+        descriptor_proto: descriptor,
+    };
+    let stream_options = StreamConfigurationOptions {
+        max_inflight_records: MAX_INFLIGHT_RECORDS,
+        ..Default::default()
+    };
+    let stream = sdk
+        .create_stream(
+            table_properties,
+            env("DATABRICKS_CLIENT_ID")?,
+            env("DATABRICKS_CLIENT_SECRET")?,
+            Some(stream_options),
+        )
+        .await
+        .with_context(|| format!("Failed to create stream to {}", table))?;
+    Ok(Table::new(
+        table,
+        encoder,
+        Pipeline::new(stream, MAX_INFLIGHT_RECORDS),
+    ))
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let log_level = log_level::init()?;
+    tokio::spawn(log_level::on_hangup(log_level.clone()));
+
+    let zerobus_endpoint = env("ZEROBUS_ENDPOINT")?;
+    let databricks_host = env("DATABRICKS_HOST")?;
+    let archive_table = env("ARCHIVE_TABLE")?;
+    let descriptor_set_path = std::env::var("DESCRIPTOR_SET")
+        .unwrap_or_else(|_| "gen/descriptors/tables.descriptor".to_string());
+    let listen_addr =
+        std::env::var("LISTEN_ADDR").unwrap_or_else(|_| DEFAULT_LISTEN_ADDR.to_string());
+    let grace = shutdown::grace_from_env()?;
+    let chain = TransformChain::from_env()?;
+    let table_router = TableRouter::from_env();
+
+    let descriptor_set = std::fs::read(&descriptor_set_path)
+        .with_context(|| format!("Failed to read descriptor set {}", descriptor_set_path))?;
+    let sdk = ZerobusSdk::new(zerobus_endpoint, databricks_host)?;
+
+    let archive = open_table(&sdk, &descriptor_set, &archive_table, false).await?;
+    let mut typed = Vec::new();
+    for table in table_router.tables() {
+        typed.push(open_table(&sdk, &descriptor_set, table, true).await?);
+        info!("Typed table {}", table);
+    }
+    let mut fanout = FanOut::new(chain, table_router, archive, typed)?;
+    if let Some(max) = cardinality::max_series_from_env()? {
+        fanout = fanout.max_series(max);
+    }
+    let state = AppState::new(fanout);
+
+    let listener = tokio::net::TcpListener::bind(&listen_addr)
+        .await
+        .with_context(|| format!("Failed to bind {}", listen_addr))?;
+    info!(
+        "Listening on http://{}, archiving to {}",
+        listen_addr, archive_table
+    );
+
+    let mut app = router(state.clone());
+    if let Some(admin) = log_level::admin_routes_from_env(&log_level) {
+        app = app.merge(admin);
+    }
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown::signal())
+        .await?;
+
+    // Every handler has returned, so this is the last reference to the fan-out. The
+    // tables share one grace period rather than each getting their own.
+    let started = Instant::now();
+    let mut unacked = 0;
+    let pipelines = state
+        .into_fanout()
+        .map(|fanout| fanout.into_pipelines())
+        .unwrap_or_default();
+    for (table, pipeline) in pipelines {
+        let outcome = shutdown::drain(pipeline, grace.saturating_sub(started.elapsed())).await?;
+        info!(
+            "{}: shut down after ingesting {} rows ({} failed)",
+            table, outcome.summary.ingested, outcome.summary.failed
+        );
+        unacked += outcome.unacked.len();
+    }
+    if unacked > 0 {
+        bail!("{} rows were not acknowledged before shutdown", unacked);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,193 @@
+use axum::body::Bytes;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde_json::{json, Value};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tracing::{error, info, warn};
+use zerobus_common::pipeline::IngestSink;
+
+use crate::fanout::{FanOut, RequestSummary};
+
+/// Requests by outcome
+#[derive(Debug, Default)]
+pub struct RequestMetrics {
+    /// Every row acknowledged, or rejected for good
+    pub ok: AtomicU64,
+    /// A row was not acknowledged
+    pub failed: AtomicU64,
+    /// Body that is not a JSON object or array
+    pub invalid: AtomicU64,
+}
+
+/// Shared handler state
+pub struct AppState<S: IngestSink> {
+    fanout: Arc<FanOut<S>>,
+    metrics: Arc<RequestMetrics>,
+}
+
+impl<S: IngestSink> Clone for AppState<S> {
+    fn clone(&self) -> Self {
+        Self {
+            fanout: Arc::clone(&self.fanout),
+            metrics: Arc::clone(&self.metrics),
+        }
+    }
+}
+
+impl<S: IngestSink> AppState<S> {
+    pub fn new(fanout: FanOut<S>) -> Self {
+        Self {
+            fanout: Arc::new(fanout),
+            metrics: Arc::default(),
+        }
+    }
+
+    /// Take the fan-out back once the server has stopped, so its pipelines can be
+    /// finished
+    pub fn into_fanout(self) -> Option<FanOut<S>> {
+        Arc::try_unwrap(self.fanout).ok()
+    }
+}
+
+/// `POST /events/{source}`, plus metrics and a health check
+pub fn router<S: IngestSink + 'static>(state: AppState<S>) -> Router {
+    Router::new()
+        .route("/events/:source", post(events::<S>))
+        .route("/metrics", get(metrics::<S>))
+        .route("/health", get(|| async { "OK" }))
+        .with_state(state)
+}
+
+/// Ingest the events of a request, a JSON object or an array of them
+///
+/// Answers with the request's summary: 200 once every row is acknowledged or rejected,
+/// and 500 when a row was not acknowledged, so the sender retries. A retried request is
+/// archived again, so the archive may hold an event more than once. A body that is not
+/// JSON is answered 400.
+async fn events<S: IngestSink>(
+    State(state): State<AppState<S>>,
+    Path(source): Path<String>,
+    body: Bytes,
+) -> (StatusCode, Json<Value>) {
+    let events = match serde_json::from_slice::<Value>(&body) {
+        Ok(Value::Array(events)) => events,
+        Ok(event @ Value::Object(_)) => vec![event],
+        Ok(_) | Err(_) => {
+            state.metrics.invalid.fetch_add(1, Ordering::Relaxed);
+            warn!(
+                "{}: rejecting a body that is not a JSON object or array",
+                source
+            );
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Body must be a JSON object or an array of objects"})),
+            );
+        }
+    };
+
+    let summary = state.fanout.ingest(&source, events, unix_micros()).await;
+    log_summary(&summary);
+    let status = if summary.failed() {
+        state.metrics.failed.fetch_add(1, Ordering::Relaxed);
+        StatusCode::INTERNAL_SERVER_ERROR
+    } else {
+        state.metrics.ok.fetch_add(1, Ordering::Relaxed);
+        StatusCode::OK
+    };
+    (status, Json(summary.to_json()))
+}
+
+fn log_summary(summary: &RequestSummary) {
+    for table in summary.tables() {
+        if let Some(e) = &table.summary.first_error {
+            error!("{}: {} failed: {}", summary.source, table.table, e);
+        }
+        for rejection in &table.rejected {
+            warn!("{}: {} rejected {}", summary.source, table.table, rejection);
+        }
+    }
+    info!(
+        "{}: {} events, {} archived",
+        summary.source, summary.events, summary.archive.summary.ingested
+    );
+}
+
+/// Request and row counts in the Prometheus text format
+async fn metrics<S: IngestSink>(State(state): State<AppState<S>>) -> String {
+    let mut text = String::from(
+        "# HELP mini_pipeline_requests_total Requests by outcome\n\
+         # TYPE mini_pipeline_requests_total counter\n",
+    );
+    for (outcome, count) in [
+        ("ok", &state.metrics.ok),
+        ("failed", &state.metrics.failed),
+        ("invalid", &state.metrics.invalid),
+    ] {
+        let _ = writeln!(
+            text,
+            "mini_pipeline_requests_total{{outcome=\"{}\"}} {}",
+            outcome,
+            count.load(Ordering::Relaxed)
+        );
+    }
+
+    text.push_str(
+        "# HELP mini_pipeline_rows_total Rows by table, source, and outcome\n\
+         # TYPE mini_pipeline_rows_total counter\n",
+    );
+    for table in state.fanout.tables() {
+        for (source, metrics) in table.metrics() {
+            for (outcome, count) in [
+                ("ingested", &metrics.ingested),
+                ("failed", &metrics.failed),
+                ("rejected", &metrics.rejected),
+            ] {
+                let _ = writeln!(
+                    text,
+                    "mini_pipeline_rows_total{{table=\"{}\",source=\"{}\",outcome=\"{}\"}} {}",
+                    table.name,
+                    escape(&source),
+                    outcome,
+                    count.load(Ordering::Relaxed)
+                );
+            }
+        }
+    }
+
+    text.push_str(
+        "# HELP mini_pipeline_bytes_total Encoded bytes of acknowledged rows by table and source\n\
+         # TYPE mini_pipeline_bytes_total counter\n",
+    );
+    for table in state.fanout.tables() {
+        for (source, metrics) in table.metrics() {
+            let _ = writeln!(
+                text,
+                "mini_pipeline_bytes_total{{table=\"{}\",source=\"{}\"}} {}",
+                table.name,
+                escape(&source),
+                metrics.bytes.load(Ordering::Relaxed)
+            );
+        }
+    }
+    text
+}
+
+/// A label value in the Prometheus text format; sources come from request paths, so
+/// they may hold anything
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Current time in microseconds since Unix epoch
+fn unix_micros() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_micros() as i64)
+}
@@ -0,0 +1,337 @@
+//! Posts events through the real router, transform chain, and pipelines into mock
+//! sinks, and decodes the rows each table received.
+
+use axum::body::Body;
+use axum::http::{Request, StatusCode};
+use axum::Router;
+use mini_pipeline::chain::{FieldMap, Redact, StaticTags, TransformChain};
+use mini_pipeline::fanout::{FanOut, Table};
+use mini_pipeline::server::{router, AppState};
+use prost::Message;
+use prost_types::field_descriptor_proto::{Label, Type};
+use prost_types::{DescriptorProto, FieldDescriptorProto};
+use serde_json::{json, Value};
+use tower::ServiceExt;
+use zerobus_common::dynamic::DynamicEncoder;
+use zerobus_common::pipeline::Pipeline;
+use zerobus_common::router::TableRouter;
+use zerobus_common::testing::MockSink;
+
+const ARCHIVE: &str = "main.events.archive";
+const ORDERS: &str = "main.shop.orders";
+
+#[derive(Clone, PartialEq, Message)]
+struct ArchiveRow {
+    #[prost(string, optional, tag = "1")]
+    source: Option<String>,
+    #[prost(int64, optional, tag = "2")]
+    received_at: Option<i64>,
+    #[prost(string, optional, tag = "3")]
+    payload: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct OrderRow {
+    #[prost(string, optional, tag = "1")]
+    order_id: Option<String>,
+    #[prost(string, optional, tag = "2")]
+    email: Option<String>,
+    #[prost(double, optional, tag = "3")]
+    amount: Option<f64>,
+    #[prost(string, optional, tag = "4")]
+    environment: Option<String>,
+}
+
+fn encoder(name: &str, fields: &[(&str, Type)]) -> DynamicEncoder {
+    let descriptor = DescriptorProto {
+        name: Some(name.to_string()),
+        field: fields
+            .iter()
+            .zip(1..)
+            .map(|((name, kind), number)| FieldDescriptorProto {
+                name: Some(name.to_string()),
+                number: Some(number),
+                label: Some(Label::Optional as i32),
+                r#type: Some(*kind as i32),
+                ..Default::default()
+            })
+            .collect(),
+        ..Default::default()
+    };
+    DynamicEncoder::new(&descriptor).unwrap()
+}
+
+/// Orders are routed to their typed table; every other source is only archived
+fn fanout(archive: MockSink, orders: MockSink) -> FanOut<MockSink> {
+    let chain = TransformChain::new(
+        Redact::parse("/customer/email").unwrap(),
+        Some(
+            FieldMap::parse(
+                r#"{"order_id": "/id", "email": "/customer/email", "amount": "/total"}"#,
+            )
+            .unwrap(),
+        ),
+        StaticTags::parse("environment=prod").unwrap(),
+    );
+    let archive = Table::new(
+        ARCHIVE,
+        encoder(
+            "table_archive",
+            &[
+                ("source", Type::String),
+                ("received_at", Type::Int64),
+                ("payload", Type::String),
+            ],
+        ),
+        Pipeline::new(archive, 100),
+    );
+    let orders = Table::new(
+        ORDERS,
+        encoder(
+            "table_orders",
+            &[
+                ("order_id", Type::String),
+                ("email", Type::String),
+                ("amount", Type::Double),
+                ("environment", Type::String),
+            ],
+        )
+        .ignore_unknown_fields(true)
+        .coerce_types(true),
+        Pipeline::new(orders, 100),
+    );
+    FanOut::new(
+        chain,
+        TableRouter::new(&format!("orders={}", ORDERS), None),
+        archive,
+        vec![orders],
+    )
+    .unwrap()
+}
+
+fn app(archive: MockSink, orders: MockSink) -> Router {
+    router(AppState::new(fanout(archive, orders)))
+}
+
+async fn post(app: &Router, path: &str, body: Value) -> (StatusCode, Value) {
+    let request = Request::post(path)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    let status = response.status();
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    (status, serde_json::from_slice(&bytes).unwrap())
+}
+
+async fn metrics(app: &Router) -> String {
+    let request = Request::get("/metrics").body(Body::empty()).unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    String::from_utf8(bytes.to_vec()).unwrap()
+}
+
+fn decode<M: Message + Default>(sink: &MockSink) -> Vec<M> {
+    sink.records()
+        .iter()
+        .map(|record| M::decode(record.as_slice()).unwrap())
+        .collect()
+}
+
+fn order(id: &str, total: Value) -> Value {
+    json!({
+        "id": id,
+        "customer": {"email": "ada@example.com", "name": "Ada"},
+        "total": total,
+        "channel": "web"
+    })
+}
+
+#[tokio::test]
+async fn test_events_reach_both_tables() {
+    let (archive, orders) = (MockSink::default(), MockSink::default());
+    let app = app(archive.clone(), orders.clone());
+
+    let (status, summary) = post(
+        &app,
+        "/events/orders",
+        json!([order("ord_1", json!(19.99)), order("ord_2", json!("5.50"))]),
+    )
+    .await;
+
+    assert_eq!(StatusCode::OK, status);
+    assert_eq!(json!("orders"), summary["source"]);
+    assert_eq!(json!(2), summary["events"]);
+    let tables = summary["tables"].as_array().unwrap();
+    assert_eq!(json!(ARCHIVE), tables[0]["table"]);
+    assert_eq!(json!(2), tables[0]["ingested"]);
+    assert_eq!(json!(ORDERS), tables[1]["table"]);
+    assert_eq!(json!(2), tables[1]["ingested"]);
+    assert_eq!(json!(0), tables[1]["failed"]);
+    assert!(tables[1]["bytes"].as_u64().unwrap() > 0);
+
+    // The archive holds the whole event, redacted
+    let archived: Vec<ArchiveRow> = decode(&archive);
+    assert_eq!(2, archived.len());
+    assert_eq!(Some("orders"), archived[0].source.as_deref());
+    assert!(archived[0].received_at.unwrap() > 0);
+    let payload: Value = serde_json::from_str(archived[0].payload.as_ref().unwrap()).unwrap();
+    assert_eq!(
+        json!({
+            "id": "ord_1",
+            "customer": {"email": "[REDACTED]", "name": "Ada"},
+            "total": 19.99,
+            "channel": "web"
+        }),
+        payload
+    );
+
+    // The typed table holds the mapped, tagged row
+    assert_eq!(
+        vec![
+            OrderRow {
+                order_id: Some("ord_1".to_string()),
+                email: Some("[REDACTED]".to_string()),
+                amount: Some(19.99),
+                environment: Some("prod".to_string()),
+            },
+            OrderRow {
+                order_id: Some("ord_2".to_string()),
+                email: Some("[REDACTED]".to_string()),
+                amount: Some(5.5),
+                environment: Some("prod".to_string()),
+            },
+        ],
+        decode::<OrderRow>(&orders)
+    );
+}
+
+#[tokio::test]
+async fn test_unrouted_sources_are_only_archived() {
+    let (archive, orders) = (MockSink::default(), MockSink::default());
+    let app = app(archive.clone(), orders.clone());
+
+    let (status, summary) = post(&app, "/events/clicks", json!({"page": "/pricing"})).await;
+
+    assert_eq!(StatusCode::OK, status);
+    assert_eq!(1, summary["tables"].as_array().unwrap().len());
+    assert_eq!(1, archive.records().len());
+    assert!(orders.records().is_empty());
+}
+
+#[tokio::test]
+async fn test_rejected_events_are_still_archived() {
+    let (archive, orders) = (MockSink::default(), MockSink::default());
+    let app = app(archive.clone(), orders.clone());
+
+    let (status, summary) = post(
+        &app,
+        "/events/orders",
+        json!([order("ord_3", json!(12)), order("ord_4", json!("a lot"))]),
+    )
+    .await;
+
+    // Sending the request again would not change the rejected event, so it succeeds
+    assert_eq!(StatusCode::OK, status);
+    let typed = &summary["tables"][1];
+    assert_eq!(json!(1), typed["ingested"]);
+    assert_eq!(json!(1), typed["rejected"]);
+    assert!(typed["rejections"][0]
+        .as_str()
+        .unwrap()
+        .starts_with("Event 1: "));
+    assert_eq!(2, archive.records().len());
+    assert_eq!(1, orders.records().len());
+
+    let text = metrics(&app).await;
+    assert!(text.contains(&format!(
+        "mini_pipeline_rows_total{{table=\"{}\",source=\"orders\",outcome=\"ingested\"}} 2",
+        ARCHIVE
+    )));
+    assert!(text.contains(&format!(
+        "mini_pipeline_rows_total{{table=\"{}\",source=\"orders\",outcome=\"rejected\"}} 1",
+        ORDERS
+    )));
+    assert!(text.contains("mini_pipeline_requests_total{outcome=\"ok\"} 1"));
+}
+
+#[tokio::test]
+async fn test_unacknowledged_rows_fail_the_request() {
+    let archive = MockSink::default();
+    let orders = MockSink::default().fail_acks_for(|_| true);
+    let app = app(archive.clone(), orders);
+
+    let (status, summary) = post(&app, "/events/orders", order("ord_5", json!(3))).await;
+
+    assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, status);
+    assert_eq!(json!(1), summary["tables"][0]["ingested"]);
+    assert_eq!(json!(1), summary["tables"][1]["failed"]);
+    assert!(summary["tables"][1]["error"].is_string());
+    assert_eq!(json!({"retryable": 1}), summary["tables"][1]["errors"]);
+
+    let (status, _) = post(&app, "/events/orders", json!("not an event")).await;
+    assert_eq!(StatusCode::BAD_REQUEST, status);
+
+    let text = metrics(&app).await;
+    assert!(text.contains("mini_pipeline_requests_total{outcome=\"failed\"} 1"));
+    assert!(text.contains("mini_pipeline_requests_total{outcome=\"invalid\"} 1"));
+    assert!(text.contains(&format!(
+        "mini_pipeline_rows_total{{table=\"{}\",source=\"orders\",outcome=\"failed\"}} 1",
+        ORDERS
+    )));
+}
+
+#[tokio::test]
+async fn test_rows_are_counted_by_table_and_source() {
+    let (archive, orders) = (MockSink::default(), MockSink::default());
+    // Room for the archive and typed series of orders and the archive series of clicks
+    let app = router(AppState::new(
+        fanout(archive.clone(), orders.clone()).max_series(3),
+    ));
+
+    post(&app, "/events/orders", order("ord_6", json!(8))).await;
+    post(
+        &app,
+        "/events/clicks",
+        json!([{"page": "/"}, {"page": "/docs"}]),
+    )
+    .await;
+    post(&app, "/events/signups", json!({"plan": "free"})).await;
+    post(&app, "/events/refunds", json!({"id": "ref_1"})).await;
+    post(&app, "/events/clicks", json!({"page": "/pricing"})).await;
+
+    let text = metrics(&app).await;
+    for sample in [
+        format!(
+            "mini_pipeline_rows_total{{table=\"{}\",source=\"orders\",outcome=\"ingested\"}} 1",
+            ARCHIVE
+        ),
+        format!(
+            "mini_pipeline_rows_total{{table=\"{}\",source=\"orders\",outcome=\"ingested\"}} 1",
+            ORDERS
+        ),
+        format!(
+            "mini_pipeline_rows_total{{table=\"{}\",source=\"clicks\",outcome=\"ingested\"}} 3",
+            ARCHIVE
+        ),
+        // Sources past the limit share one series
+        format!(
+            "mini_pipeline_rows_total{{table=\"{}\",source=\"other\",outcome=\"ingested\"}} 2",
+            ARCHIVE
+        ),
+    ] {
+        assert!(text.contains(&sample), "{} missing from\n{}", sample, text);
+    }
+    assert!(!text.contains("source=\"signups\""));
+    assert!(!text.contains("source=\"refunds\""));
+    let bytes = text
+        .lines()
+        .filter(|line| line.starts_with("mini_pipeline_bytes_total{"))
+        .count();
+    assert_eq!(4, bytes);
+    assert_eq!(6, archive.records().len());
+}
@@ -0,0 +1,365 @@
+//! Flattening OTLP attribute values into string maps.
+//!
+//! OTLP attributes are `AnyValue` unions that may nest arrays and key/value lists
+//! arbitrarily deep, while the tables store attributes as `MAP<STRING, STRING>`. Nested
+//! key/value lists are flattened into dotted keys so each leaf stays individually
+//! queryable (`attributes['http.request.header.host']`); every other value becomes a
+//! single string, with arrays rendered as JSON.
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use opentelemetry_proto::tonic::common::v1::any_value::Value;
+use opentelemetry_proto::tonic::common::v1::{AnyValue, KeyValue};
+use serde_json::Value as JsonValue;
+use std::collections::BTreeMap;
+
+/// Flatten a list of attributes into a map keyed by (dotted) attribute name
+///
+/// If flattening produces the same key twice (e.g. an attribute `a.b` next to an
+/// attribute `a` holding `{b: ...}`), the later one wins.
+pub fn flatten_attributes(attributes: &[KeyValue]) -> BTreeMap<String, String> {
+    let mut flattened = BTreeMap::new();
+    for attribute in attributes {
+        flatten_into(&attribute.key, attribute.value.as_ref(), &mut flattened);
+    }
+    flattened
+}
+
+fn flatten_into(key: &str, value: Option<&AnyValue>, flattened: &mut BTreeMap<String, String>) {
+    match value.and_then(|v| v.value.as_ref()) {
+        Some(Value::KvlistValue(list)) if !list.values.is_empty() => {
+            for entry in &list.values {
+                let nested_key = format!("{}.{}", key, entry.key);
+                flatten_into(&nested_key, entry.value.as_ref(), flattened);
+            }
+        }
+        _ => {
+            flattened.insert(key.to_string(), any_value_to_string(value));
+        }
+    }
+}
+
+/// Render a value as one string
+///
+/// Strings are used as-is, other scalars in their usual text form, bytes as base64
+/// (as in OTLP/JSON), and arrays and key/value lists as JSON. A value with nothing
+/// set becomes an empty string, so the attribute's key is still recorded.
+pub fn any_value_to_string(value: Option<&AnyValue>) -> String {
+    match value.and_then(|v| v.value.as_ref()) {
+        None => String::new(),
+        Some(Value::StringValue(s)) => s.clone(),
+        Some(Value::BoolValue(b)) => b.to_string(),
+        Some(Value::IntValue(i)) => i.to_string(),
+        Some(Value::DoubleValue(d)) => d.to_string(),
+        Some(Value::BytesValue(bytes)) => BASE64.encode(bytes),
+        Some(nested @ (Value::ArrayValue(_) | Value::KvlistValue(_))) => {
+            to_json(nested).to_string()
+        }
+    }
+}
+
+fn any_value_to_json(value: Option<&AnyValue>) -> JsonValue {
+    match value.and_then(|v| v.value.as_ref()) {
+        Some(value) => to_json(value),
+        None => JsonValue::Null,
+    }
+}
+
+fn to_json(value: &Value) -> JsonValue {
+    match value {
+        Value::StringValue(s) => JsonValue::String(s.clone()),
+        Value::BoolValue(b) => JsonValue::Bool(*b),
+        Value::IntValue(i) => JsonValue::from(*i),
+        // JSON has no NaN or infinity; keep them readable as strings
+        Value::DoubleValue(d) => serde_json::Number::from_f64(*d)
+            .map(JsonValue::Number)
+            .unwrap_or_else(|| JsonValue::String(d.to_string())),
+        Value::BytesValue(bytes) => JsonValue::String(BASE64.encode(bytes)),
+        Value::ArrayValue(array) => JsonValue::Array(
+            array
+                .values
+                .iter()
+                .map(|v| any_value_to_json(Some(v)))
+                .collect(),
+        ),
+        Value::KvlistValue(list) => JsonValue::Object(
+            list.values
+                .iter()
+                .map(|entry| (entry.key.clone(), any_value_to_json(entry.value.as_ref())))
+                .collect(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry_proto::tonic::common::v1::{ArrayValue, KeyValueList};
+    use proptest::arbitrary::any as arbitrary;
+    use proptest::collection::{btree_map, vec};
+    use proptest::prelude::*;
+
+    fn any(value: Value) -> AnyValue {
+        AnyValue { value: Some(value) }
+    }
+
+    fn kv(key: &str, value: Value) -> KeyValue {
+        KeyValue {
+            key: key.to_string(),
+            value: Some(any(value)),
+        }
+    }
+
+    fn string(s: &str) -> Value {
+        Value::StringValue(s.to_string())
+    }
+
+    fn array(values: Vec<Value>) -> Value {
+        Value::ArrayValue(ArrayValue {
+            values: values.into_iter().map(any).collect(),
+        })
+    }
+
+    fn kvlist(values: Vec<KeyValue>) -> Value {
+        Value::KvlistValue(KeyValueList { values })
+    }
+
+    #[test]
+    fn test_scalars() {
+        let flattened = flatten_attributes(&[
+            kv("http.method", string("GET")),
+            kv("http.status_code", Value::IntValue(200)),
+            kv("retry.offset", Value::IntValue(-3)),
+            kv("error", Value::BoolValue(false)),
+            kv("duration", Value::DoubleValue(1.5)),
+            kv("ratio", Value::DoubleValue(2.0)),
+            kv("payload", Value::BytesValue(vec![0xde, 0xad, 0xbe, 0xef])),
+        ]);
+
+        assert_eq!("GET", flattened["http.method"]);
+        assert_eq!("200", flattened["http.status_code"]);
+        assert_eq!("-3", flattened["retry.offset"]);
+        assert_eq!("false", flattened["error"]);
+        assert_eq!("1.5", flattened["duration"]);
+        assert_eq!("2", flattened["ratio"]);
+        assert_eq!("3q2+7w==", flattened["payload"]);
+    }
+
+    #[test]
+    fn test_nested_kvlists_become_dotted_keys() {
+        let flattened = flatten_attributes(&[kv(
+            "http",
+            kvlist(vec![
+                kv("method", string("POST")),
+                kv(
+                    "request",
+                    kvlist(vec![kv(
+                        "header",
+                        kvlist(vec![kv("host", string("example.com"))]),
+                    )]),
+                ),
+            ]),
+        )]);
+
+        assert_eq!(
+            vec!["http.method", "http.request.header.host"],
+            flattened.keys().map(String::as_str).collect::<Vec<_>>()
+        );
+        assert_eq!("POST", flattened["http.method"]);
+        assert_eq!("example.com", flattened["http.request.header.host"]);
+    }
+
+    #[test]
+    fn test_arrays_become_json() {
+        let flattened = flatten_attributes(&[
+            kv("tags", array(vec![string("a"), string("b")])),
+            kv(
+                "mixed",
+                array(vec![
+                    Value::IntValue(1),
+                    Value::DoubleValue(0.5),
+                    Value::BoolValue(true),
+                    Value::BytesValue(vec![1, 2]),
+                    array(vec![string("nested")]),
+                    kvlist(vec![kv("k", string("v"))]),
+                ]),
+            ),
+        ]);
+
+        assert_eq!(r#"["a","b"]"#, flattened["tags"]);
+        assert_eq!(
+            r#"[1,0.5,true,"AQI=",["nested"],{"k":"v"}]"#,
+            flattened["mixed"]
+        );
+    }
+
+    #[test]
+    fn test_non_finite_doubles() {
+        let flattened = flatten_attributes(&[
+            kv("nan", Value::DoubleValue(f64::NAN)),
+            kv("in_array", array(vec![Value::DoubleValue(f64::INFINITY)])),
+        ]);
+
+        assert_eq!("NaN", flattened["nan"]);
+        assert_eq!(r#"["inf"]"#, flattened["in_array"]);
+    }
+
+    #[test]
+    fn test_empty_values_keep_their_key() {
+        let flattened = flatten_attributes(&[
+            KeyValue {
+                key: "unset".to_string(),
+                value: None,
+            },
+            KeyValue {
+                key: "empty_any".to_string(),
+                value: Some(AnyValue { value: None }),
+            },
+            kv("empty_list", kvlist(vec![])),
+            kv("empty_array", array(vec![])),
+        ]);
+
+        assert_eq!("", flattened["unset"]);
+        assert_eq!("", flattened["empty_any"]);
+        assert_eq!("{}", flattened["empty_list"]);
+        assert_eq!("[]", flattened["empty_array"]);
+    }
+
+    #[test]
+    fn test_null_entries_inside_json() {
+        let with_null = any(Value::ArrayValue(ArrayValue {
+            values: vec![AnyValue { value: None }],
+        }));
+
+        assert_eq!("[null]", any_value_to_string(Some(&with_null)));
+    }
+
+    #[test]
+    fn test_colliding_keys_last_wins() {
+        let flattened = flatten_attributes(&[
+            kv("a.b", string("first")),
+            kv("a", kvlist(vec![kv("b", string("second"))])),
+        ]);
+
+        assert_eq!(1, flattened.len());
+        assert_eq!("second", flattened["a.b"]);
+    }
+
+    #[test]
+    fn test_any_value_to_string_for_log_bodies() {
+        assert_eq!(
+            "user logged in",
+            any_value_to_string(Some(&any(string("user logged in"))))
+        );
+        // Structured bodies stay structured as a JSON object
+        assert_eq!(
+            r#"{"event":"login","user":{"id":42}}"#,
+            any_value_to_string(Some(&any(kvlist(vec![
+                kv("event", string("login")),
+                kv("user", kvlist(vec![kv("id", Value::IntValue(42))])),
+            ]))))
+        );
+        assert_eq!("", any_value_to_string(None));
+    }
+
+    /// Values of every kind, with arrays and key/value lists nested up to four levels
+    /// deep; `keys` names the entries of the lists
+    fn arb_value(keys: &'static str) -> impl Strategy<Value = Option<Value>> {
+        let leaf = prop_oneof![
+            Just(None),
+            arbitrary::<String>().prop_map(|s| Some(Value::StringValue(s))),
+            arbitrary::<bool>().prop_map(|b| Some(Value::BoolValue(b))),
+            arbitrary::<i64>().prop_map(|i| Some(Value::IntValue(i))),
+            arbitrary::<f64>().prop_map(|d| Some(Value::DoubleValue(d))),
+            vec(arbitrary::<u8>(), 0..16).prop_map(|bytes| Some(Value::BytesValue(bytes))),
+        ];
+        leaf.prop_recursive(4, 64, 8, move |inner| {
+            prop_oneof![
+                vec(inner.clone(), 0..8).prop_map(|values| {
+                    Some(Value::ArrayValue(ArrayValue {
+                        values: values.into_iter().map(|value| AnyValue { value }).collect(),
+                    }))
+                }),
+                btree_map(keys, inner, 0..8).prop_map(|entries| Some(kvlist(entries_of(entries)))),
+            ]
+        })
+    }
+
+    fn entries_of(entries: BTreeMap<String, Option<Value>>) -> Vec<KeyValue> {
+        entries
+            .into_iter()
+            .map(|(key, value)| KeyValue {
+                key,
+                value: Some(AnyValue { value }),
+            })
+            .collect()
+    }
+
+    /// The keys a value flattens into: one per leaf of its non-empty key/value lists
+    fn leaves(value: Option<&AnyValue>) -> usize {
+        match value.and_then(|v| v.value.as_ref()) {
+            Some(Value::KvlistValue(list)) if !list.values.is_empty() => list
+                .values
+                .iter()
+                .map(|entry| leaves(entry.value.as_ref()))
+                .sum(),
+            _ => 1,
+        }
+    }
+
+    /// Names without dots, so flattened keys cannot collide
+    const PLAIN_KEYS: &str = "[a-z_]{1,8}";
+
+    proptest! {
+        #[test]
+        fn test_every_leaf_keeps_a_key_of_its_own(
+            attributes in btree_map(PLAIN_KEYS, arb_value(PLAIN_KEYS), 0..8),
+        ) {
+            let attributes = entries_of(attributes);
+            let flattened = flatten_attributes(&attributes);
+
+            let leaves: usize = attributes.iter().map(|kv| leaves(kv.value.as_ref())).sum();
+            prop_assert_eq!(leaves, flattened.len());
+            for key in flattened.keys() {
+                prop_assert!(
+                    attributes.iter().any(|kv| *key == kv.key
+                        || key.starts_with(&format!("{}.", kv.key))),
+                    "{:?} is not under any attribute",
+                    key
+                );
+            }
+        }
+
+        #[test]
+        fn test_any_keys_flatten_into_at_most_one_key_per_leaf(
+            attributes in vec((arbitrary::<String>(), arb_value("\\PC{0,8}")), 0..8),
+        ) {
+            let attributes: Vec<KeyValue> = attributes
+                .into_iter()
+                .map(|(key, value)| KeyValue { key, value: Some(AnyValue { value }) })
+                .collect();
+            let flattened = flatten_attributes(&attributes);
+
+            let leaves: usize = attributes.iter().map(|kv| leaves(kv.value.as_ref())).sum();
+            prop_assert!(flattened.len() <= leaves);
+        }
+
+        #[test]
+        fn test_values_render_as_their_string_or_json(value in arb_value(PLAIN_KEYS)) {
+            let rendered = any_value_to_string(Some(&AnyValue { value: value.clone() }));
+            match value {
+                None => prop_assert_eq!("", rendered),
+                Some(Value::StringValue(s)) => prop_assert_eq!(s, rendered),
+                Some(Value::BytesValue(bytes)) => {
+                    prop_assert_eq!(bytes, BASE64.decode(&rendered).unwrap())
+                }
+                Some(Value::ArrayValue(_) | Value::KvlistValue(_)) => prop_assert!(
+                    serde_json::from_str::<JsonValue>(&rendered).is_ok(),
+                    "{} is not JSON",
+                    rendered
+                ),
+                Some(_) => prop_assert!(!rendered.is_empty()),
+            }
+        }
+    }
+}
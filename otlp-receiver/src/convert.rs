@@ -0,0 +1,360 @@
+use opentelemetry_proto::tonic::collector::logs::v1::ExportLogsServiceRequest;
+use opentelemetry_proto::tonic::collector::trace::v1::ExportTraceServiceRequest;
+use opentelemetry_proto::tonic::common::v1::any_value::Value;
+use opentelemetry_proto::tonic::common::v1::InstrumentationScope;
+use opentelemetry_proto::tonic::logs::v1::LogRecord;
+use opentelemetry_proto::tonic::resource::v1::Resource;
+use opentelemetry_proto::tonic::trace::v1::span::SpanKind;
+use opentelemetry_proto::tonic::trace::v1::status::StatusCode;
+use opentelemetry_proto::tonic::trace::v1::Span;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::attributes::{any_value_to_string, flatten_attributes};
+use crate::proto::otlp_logs::TableOtlpLogs;
+use crate::proto::otlp_spans::TableOtlpSpans;
+
+const TRACE_ID_LEN: usize = 16;
+const SPAN_ID_LEN: usize = 8;
+
+/// Resource attribute naming the service that produced the telemetry
+const SERVICE_NAME_ATTRIBUTE: &str = "service.name";
+
+/// Rows converted from one export request, plus the records that were rejected
+#[derive(Debug)]
+pub struct Converted<T> {
+    pub rows: Vec<T>,
+    pub rejected: i64,
+    /// First rejection reason, reported back to the client
+    pub first_error: Option<String>,
+}
+
+impl<T> Default for Converted<T> {
+    fn default() -> Self {
+        Self {
+            rows: Vec::new(),
+            rejected: 0,
+            first_error: None,
+        }
+    }
+}
+
+impl<T> Converted<T> {
+    fn push(&mut self, row: Result<T, String>) {
+        match row {
+            Ok(row) => self.rows.push(row),
+            Err(reason) => {
+                self.rejected += 1;
+                if self.first_error.is_none() {
+                    self.first_error = Some(reason);
+                }
+            }
+        }
+    }
+}
+
+/// Attributes and identity shared by every record under one resource and scope
+struct Origin {
+    resource_attributes: BTreeMap<String, String>,
+    service_name: Option<String>,
+    scope_name: Option<String>,
+    scope_version: Option<String>,
+}
+
+impl Origin {
+    fn new(resource: Option<&Resource>, scope: Option<&InstrumentationScope>) -> Self {
+        let resource_attributes = resource
+            .map(|r| flatten_attributes(&r.attributes))
+            .unwrap_or_default();
+        let service_name = resource.and_then(|r| {
+            r.attributes
+                .iter()
+                .find(|kv| kv.key == SERVICE_NAME_ATTRIBUTE)
+                .and_then(|kv| kv.value.as_ref())
+                .and_then(|v| match &v.value {
+                    Some(Value::StringValue(s)) => Some(s.clone()),
+                    _ => None,
+                })
+        });
+        Self {
+            resource_attributes,
+            service_name,
+            scope_name: scope.map(|s| s.name.clone()).filter(|s| !s.is_empty()),
+            scope_version: scope.map(|s| s.version.clone()).filter(|s| !s.is_empty()),
+        }
+    }
+}
+
+/// Lowercase hex, the form trace and span IDs are shown in everywhere else
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .fold(String::with_capacity(bytes.len() * 2), |mut out, b| {
+            let _ = write!(out, "{:02x}", b);
+            out
+        })
+}
+
+/// Validate a required trace or span ID: the right length and not all zeroes
+fn required_id(name: &str, bytes: &[u8], len: usize) -> Result<String, String> {
+    if bytes.len() != len || bytes.iter().all(|&b| b == 0) {
+        return Err(format!("invalid {}: expected {} non-zero bytes", name, len));
+    }
+    Ok(hex(bytes))
+}
+
+/// Validate an optional trace or span ID; empty means not set
+fn optional_id(name: &str, bytes: &[u8], len: usize) -> Result<Option<String>, String> {
+    if bytes.is_empty() {
+        return Ok(None);
+    }
+    required_id(name, bytes, len).map(Some)
+}
+
+/// OTLP timestamps are unsigned nanoseconds; 0 means unknown
+fn nanos(name: &str, value: u64) -> Result<Option<i64>, String> {
+    if value == 0 {
+        return Ok(None);
+    }
+    i64::try_from(value)
+        .map(Some)
+        .map_err(|_| format!("{} {} is out of range", name, value))
+}
+
+fn span_kind(kind: i32) -> String {
+    SpanKind::try_from(kind)
+        .map(|k| k.as_str_name().trim_start_matches("SPAN_KIND_").to_string())
+        .unwrap_or_else(|_| kind.to_string())
+}
+
+fn status_code(code: i32) -> String {
+    StatusCode::try_from(code)
+        .map(|c| {
+            c.as_str_name()
+                .trim_start_matches("STATUS_CODE_")
+                .to_string()
+        })
+        .unwrap_or_else(|_| code.to_string())
+}
+
+fn span_row(
+    span: &Span,
+    origin: &Origin,
+    ingested_at: i64,
+    ingested_date: i32,
+) -> Result<TableOtlpSpans, String> {
+    let trace_id = required_id("trace_id", &span.trace_id, TRACE_ID_LEN)?;
+    let span_id = required_id("span_id", &span.span_id, SPAN_ID_LEN)?;
+    let parent_span_id = optional_id("parent_span_id", &span.parent_span_id, SPAN_ID_LEN)?;
+    let start = nanos("start_time_unix_nano", span.start_time_unix_nano)?;
+    let end = nanos("end_time_unix_nano", span.end_time_unix_nano)?;
+    let status = span.status.as_ref();
+
+    Ok(TableOtlpSpans {
+        trace_id: Some(trace_id),
+        span_id: Some(span_id),
+        parent_span_id,
+        trace_state: Some(span.trace_state.clone()).filter(|s| !s.is_empty()),
+        name: Some(span.name.clone()),
+        kind: Some(span_kind(span.kind)),
+        start_time_unix_nano: start,
+        end_time_unix_nano: end,
+        duration_nanos: start.zip(end).map(|(start, end)| end - start),
+        status_code: Some(status_code(status.map(|s| s.code).unwrap_or_default())),
+        status_message: status.map(|s| s.message.clone()).filter(|m| !m.is_empty()),
+        attributes: flatten_attributes(&span.attributes),
+        resource_attributes: origin.resource_attributes.clone(),
+        service_name: origin.service_name.clone(),
+        scope_name: origin.scope_name.clone(),
+        scope_version: origin.scope_version.clone(),
+        ingested_at: Some(ingested_at),
+        ingested_date: Some(ingested_date),
+    })
+}
+
+fn log_row(
+    record: &LogRecord,
+    origin: &Origin,
+    ingested_at: i64,
+    ingested_date: i32,
+) -> Result<TableOtlpLogs, String> {
+    Ok(TableOtlpLogs {
+        time_unix_nano: nanos("time_unix_nano", record.time_unix_nano)?,
+        observed_time_unix_nano: nanos("observed_time_unix_nano", record.observed_time_unix_nano)?,
+        severity_number: Some(record.severity_number),
+        severity_text: Some(record.severity_text.clone()).filter(|s| !s.is_empty()),
+        body: record
+            .body
+            .as_ref()
+            .map(|body| any_value_to_string(Some(body))),
+        attributes: flatten_attributes(&record.attributes),
+        resource_attributes: origin.resource_attributes.clone(),
+        service_name: origin.service_name.clone(),
+        scope_name: origin.scope_name.clone(),
+        scope_version: origin.scope_version.clone(),
+        trace_id: optional_id("trace_id", &record.trace_id, TRACE_ID_LEN)?,
+        span_id: optional_id("span_id", &record.span_id, SPAN_ID_LEN)?,
+        flags: Some(i64::from(record.flags)),
+        ingested_at: Some(ingested_at),
+        ingested_date: Some(ingested_date),
+    })
+}
+
+/// Convert every span in an export request into a row, rejecting invalid spans
+pub fn spans_to_rows(
+    request: &ExportTraceServiceRequest,
+    ingested_at: i64,
+    ingested_date: i32,
+) -> Converted<TableOtlpSpans> {
+    let mut converted = Converted::default();
+    for resource_spans in &request.resource_spans {
+        for scope_spans in &resource_spans.scope_spans {
+            let origin = Origin::new(resource_spans.resource.as_ref(), scope_spans.scope.as_ref());
+            for span in &scope_spans.spans {
+                converted.push(span_row(span, &origin, ingested_at, ingested_date));
+            }
+        }
+    }
+    converted
+}
+
+/// Convert every log record in an export request into a row, rejecting invalid records
+pub fn logs_to_rows(
+    request: &ExportLogsServiceRequest,
+    ingested_at: i64,
+    ingested_date: i32,
+) -> Converted<TableOtlpLogs> {
+    let mut converted = Converted::default();
+    for resource_logs in &request.resource_logs {
+        for scope_logs in &resource_logs.scope_logs {
+            let origin = Origin::new(resource_logs.resource.as_ref(), scope_logs.scope.as_ref());
+            for record in &scope_logs.log_records {
+                converted.push(log_row(record, &origin, ingested_at, ingested_date));
+            }
+        }
+    }
+    converted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    #[test]
+    fn test_spans_to_rows() {
+        let converted = spans_to_rows(&fixtures::trace_request(), 7, 19675);
+
+        assert_eq!(0, converted.rejected);
+        assert_eq!(2, converted.rows.len());
+
+        let server = &converted.rows[0];
+        assert_eq!(
+            Some("5b8efff798038103d269b633813fc60c".to_string()),
+            server.trace_id
+        );
+        assert_eq!(Some("eee19b7ec3c1b174".to_string()), server.span_id);
+        assert_eq!(None, server.parent_span_id);
+        assert_eq!(Some("GET /api/orders".to_string()), server.name);
+        assert_eq!(Some("SERVER".to_string()), server.kind);
+        assert_eq!(Some(1_700_000_000_000_000_000), server.start_time_unix_nano);
+        assert_eq!(Some(1_700_000_000_250_000_000), server.end_time_unix_nano);
+        assert_eq!(Some(250_000_000), server.duration_nanos);
+        assert_eq!(Some("OK".to_string()), server.status_code);
+        assert_eq!(
+            Some(&"GET".to_string()),
+            server.attributes.get("http.method")
+        );
+        assert_eq!(Some("checkout".to_string()), server.service_name);
+        assert_eq!(
+            Some(&"production".to_string()),
+            server.resource_attributes.get("deployment.environment")
+        );
+        assert_eq!(Some("io.opentelemetry.http".to_string()), server.scope_name);
+        assert_eq!(Some("1.2.0".to_string()), server.scope_version);
+        assert_eq!(Some(7), server.ingested_at);
+        assert_eq!(Some(19675), server.ingested_date);
+
+        let client = &converted.rows[1];
+        assert_eq!(Some("eee19b7ec3c1b174".to_string()), client.parent_span_id);
+        assert_eq!(Some("CLIENT".to_string()), client.kind);
+        assert_eq!(Some("ERROR".to_string()), client.status_code);
+        assert_eq!(
+            Some("connection refused".to_string()),
+            client.status_message
+        );
+    }
+
+    #[test]
+    fn test_invalid_spans_are_rejected() {
+        let mut request = fixtures::trace_request();
+        let spans = &mut request.resource_spans[0].scope_spans[0].spans;
+        spans[0].trace_id = vec![0; 16];
+        spans.push(Span {
+            span_id: vec![1; 4],
+            ..spans[1].clone()
+        });
+
+        let converted = spans_to_rows(&request, 0, 0);
+
+        assert_eq!(2, converted.rejected);
+        assert_eq!(1, converted.rows.len());
+        assert!(converted.first_error.unwrap().contains("trace_id"));
+    }
+
+    #[test]
+    fn test_unset_status_and_unknown_kind() {
+        let mut request = fixtures::trace_request();
+        let span = &mut request.resource_spans[0].scope_spans[0].spans[0];
+        span.status = None;
+        span.kind = 42;
+        span.end_time_unix_nano = 0;
+
+        let row = &spans_to_rows(&request, 0, 0).rows[0];
+
+        assert_eq!(Some("UNSET".to_string()), row.status_code);
+        assert_eq!(Some("42".to_string()), row.kind);
+        assert_eq!(None, row.end_time_unix_nano);
+        assert_eq!(None, row.duration_nanos);
+    }
+
+    #[test]
+    fn test_logs_to_rows() {
+        let converted = logs_to_rows(&fixtures::logs_request(), 7, 19675);
+
+        assert_eq!(0, converted.rejected);
+        assert_eq!(2, converted.rows.len());
+
+        let correlated = &converted.rows[0];
+        assert_eq!(Some(1_700_000_000_100_000_000), correlated.time_unix_nano);
+        assert_eq!(Some(9), correlated.severity_number);
+        assert_eq!(Some("INFO".to_string()), correlated.severity_text);
+        assert_eq!(Some("order created".to_string()), correlated.body);
+        assert_eq!(
+            Some("5b8efff798038103d269b633813fc60c".to_string()),
+            correlated.trace_id
+        );
+        assert_eq!(Some("eee19b7ec3c1b174".to_string()), correlated.span_id);
+        assert_eq!(Some("checkout".to_string()), correlated.service_name);
+
+        let structured = &converted.rows[1];
+        assert_eq!(None, structured.time_unix_nano);
+        assert_eq!(
+            Some(r#"{"order_id":1234,"status":"failed"}"#.to_string()),
+            structured.body
+        );
+        assert_eq!(None, structured.trace_id);
+    }
+
+    #[test]
+    fn test_invalid_logs_are_rejected() {
+        let mut request = fixtures::logs_request();
+        request.resource_logs[0].scope_logs[0].log_records[1].span_id = vec![1, 2, 3];
+
+        let converted = logs_to_rows(&request, 0, 0);
+
+        assert_eq!(1, converted.rejected);
+        assert_eq!(1, converted.rows.len());
+        assert!(converted.first_error.unwrap().contains("span_id"));
+    }
+}
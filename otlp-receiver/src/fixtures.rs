@@ -0,0 +1,146 @@
+//! Export requests shaped like those sent by an instrumented service, for tests.
+
+use opentelemetry_proto::tonic::collector::logs::v1::ExportLogsServiceRequest;
+use opentelemetry_proto::tonic::collector::trace::v1::ExportTraceServiceRequest;
+use opentelemetry_proto::tonic::common::v1::any_value::Value;
+use opentelemetry_proto::tonic::common::v1::{
+    AnyValue, InstrumentationScope, KeyValue, KeyValueList,
+};
+use opentelemetry_proto::tonic::logs::v1::{LogRecord, ResourceLogs, ScopeLogs};
+use opentelemetry_proto::tonic::resource::v1::Resource;
+use opentelemetry_proto::tonic::trace::v1::span::SpanKind;
+use opentelemetry_proto::tonic::trace::v1::status::StatusCode;
+use opentelemetry_proto::tonic::trace::v1::{ResourceSpans, ScopeSpans, Span, Status};
+
+pub const TRACE_ID: &str = "5b8efff798038103d269b633813fc60c";
+pub const SERVER_SPAN_ID: &str = "eee19b7ec3c1b174";
+pub const CLIENT_SPAN_ID: &str = "3f2b8c1d9a7e6f50";
+
+fn id(hex: &str) -> Vec<u8> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+fn kv(key: &str, value: Value) -> KeyValue {
+    KeyValue {
+        key: key.to_string(),
+        value: Some(AnyValue { value: Some(value) }),
+    }
+}
+
+fn string(s: &str) -> Value {
+    Value::StringValue(s.to_string())
+}
+
+fn resource() -> Option<Resource> {
+    Some(Resource {
+        attributes: vec![
+            kv("service.name", string("checkout")),
+            kv("deployment.environment", string("production")),
+        ],
+        ..Default::default()
+    })
+}
+
+fn scope() -> Option<InstrumentationScope> {
+    Some(InstrumentationScope {
+        name: "io.opentelemetry.http".to_string(),
+        version: "1.2.0".to_string(),
+        ..Default::default()
+    })
+}
+
+/// A server span and the failed client call it made
+pub fn trace_request() -> ExportTraceServiceRequest {
+    let server = Span {
+        trace_id: id(TRACE_ID),
+        span_id: id(SERVER_SPAN_ID),
+        name: "GET /api/orders".to_string(),
+        kind: SpanKind::Server as i32,
+        start_time_unix_nano: 1_700_000_000_000_000_000,
+        end_time_unix_nano: 1_700_000_000_250_000_000,
+        attributes: vec![
+            kv("http.method", string("GET")),
+            kv("http.route", string("/api/orders")),
+            kv("http.status_code", Value::IntValue(200)),
+        ],
+        status: Some(Status {
+            code: StatusCode::Ok as i32,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let client = Span {
+        trace_id: id(TRACE_ID),
+        span_id: id(CLIENT_SPAN_ID),
+        parent_span_id: id(SERVER_SPAN_ID),
+        name: "SELECT orders".to_string(),
+        kind: SpanKind::Client as i32,
+        start_time_unix_nano: 1_700_000_000_010_000_000,
+        end_time_unix_nano: 1_700_000_000_240_000_000,
+        attributes: vec![kv("db.system", string("postgresql"))],
+        status: Some(Status {
+            code: StatusCode::Error as i32,
+            message: "connection refused".to_string(),
+        }),
+        ..Default::default()
+    };
+
+    ExportTraceServiceRequest {
+        resource_spans: vec![ResourceSpans {
+            resource: resource(),
+            scope_spans: vec![ScopeSpans {
+                scope: scope(),
+                spans: vec![server, client],
+                ..Default::default()
+            }],
+            ..Default::default()
+        }],
+    }
+}
+
+/// A log line correlated with the server span and a structured log without a trace
+pub fn logs_request() -> ExportLogsServiceRequest {
+    let correlated = LogRecord {
+        time_unix_nano: 1_700_000_000_100_000_000,
+        observed_time_unix_nano: 1_700_000_000_100_500_000,
+        severity_number: 9,
+        severity_text: "INFO".to_string(),
+        body: Some(AnyValue {
+            value: Some(string("order created")),
+        }),
+        attributes: vec![kv("order.id", Value::IntValue(1234))],
+        trace_id: id(TRACE_ID),
+        span_id: id(SERVER_SPAN_ID),
+        flags: 1,
+        ..Default::default()
+    };
+    let structured = LogRecord {
+        observed_time_unix_nano: 1_700_000_000_300_000_000,
+        severity_number: 17,
+        severity_text: "ERROR".to_string(),
+        body: Some(AnyValue {
+            value: Some(Value::KvlistValue(KeyValueList {
+                values: vec![
+                    kv("order_id", Value::IntValue(1234)),
+                    kv("status", string("failed")),
+                ],
+            })),
+        }),
+        ..Default::default()
+    };
+
+    ExportLogsServiceRequest {
+        resource_logs: vec![ResourceLogs {
+            resource: resource(),
+            scope_logs: vec![ScopeLogs {
+                scope: scope(),
+                log_records: vec![correlated, structured],
+                ..Default::default()
+            }],
+            ..Default::default()
+        }],
+    }
+}
@@ -0,0 +1,200 @@
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::header::{CONTENT_ENCODING, CONTENT_TYPE};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::Router;
+use opentelemetry_proto::tonic::collector::logs::v1::ExportLogsServiceRequest;
+use opentelemetry_proto::tonic::collector::trace::v1::ExportTraceServiceRequest;
+use prost::Message;
+use tracing::{error, warn};
+use zerobus_common::pipeline::IngestSink;
+
+use crate::service::OtlpReceiver;
+
+const PROTOBUF_CONTENT_TYPE: &str = "application/x-protobuf";
+
+/// OTLP/HTTP routes (binary protobuf encoding only)
+pub fn router<S: IngestSink + 'static>(receiver: OtlpReceiver<S>) -> Router {
+    Router::new()
+        .route("/v1/traces", post(traces::<S>))
+        .route("/v1/logs", post(logs::<S>))
+        .with_state(receiver)
+}
+
+async fn traces<S: IngestSink + 'static>(
+    State(receiver): State<OtlpReceiver<S>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let request = match decode::<ExportTraceServiceRequest>(&headers, body) {
+        Ok(request) => request,
+        Err(response) => return response,
+    };
+    match receiver.export_traces(request).await {
+        Ok(response) => protobuf(response),
+        Err(e) => unavailable(e),
+    }
+}
+
+async fn logs<S: IngestSink + 'static>(
+    State(receiver): State<OtlpReceiver<S>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let request = match decode::<ExportLogsServiceRequest>(&headers, body) {
+        Ok(request) => request,
+        Err(response) => return response,
+    };
+    match receiver.export_logs(request).await {
+        Ok(response) => protobuf(response),
+        Err(e) => unavailable(e),
+    }
+}
+
+/// Decode a request body, or produce the non-retryable error response for it
+fn decode<M: Message + Default>(headers: &HeaderMap, body: Bytes) -> Result<M, Response> {
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    if !content_type.starts_with(PROTOBUF_CONTENT_TYPE) {
+        return Err((
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            format!("Only {} is supported", PROTOBUF_CONTENT_TYPE),
+        )
+            .into_response());
+    }
+    if let Some(encoding) = headers.get(CONTENT_ENCODING) {
+        if encoding != "identity" {
+            return Err((
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                format!("Unsupported Content-Encoding: {:?}", encoding),
+            )
+                .into_response());
+        }
+    }
+
+    M::decode(body).map_err(|e| {
+        warn!("Rejecting undecodable export request: {}", e);
+        (StatusCode::BAD_REQUEST, e.to_string()).into_response()
+    })
+}
+
+fn protobuf(message: impl Message) -> Response {
+    (
+        [(CONTENT_TYPE, PROTOBUF_CONTENT_TYPE)],
+        message.encode_to_vec(),
+    )
+        .into_response()
+}
+
+/// 503 is one of the status codes OTLP/HTTP exporters retry with backoff
+fn unavailable(e: anyhow::Error) -> Response {
+    error!("Failed to ingest export request: {:#}", e);
+    (StatusCode::SERVICE_UNAVAILABLE, format!("{:#}", e)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use axum::body::{to_bytes, Body};
+    use axum::http::Request;
+    use opentelemetry_proto::tonic::collector::logs::v1::ExportLogsServiceResponse;
+    use tower::ServiceExt;
+    use zerobus_common::pipeline::Pipeline;
+    use zerobus_common::testing::MockSink;
+
+    fn app(spans: &MockSink, logs: &MockSink) -> Router {
+        router(OtlpReceiver::new(
+            Pipeline::new(spans.clone(), 100),
+            Pipeline::new(logs.clone(), 100),
+        ))
+    }
+
+    fn post(path: &str, content_type: &str, body: Vec<u8>) -> Request<Body> {
+        Request::post(path)
+            .header(CONTENT_TYPE, content_type)
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_http_export_logs() {
+        let logs = MockSink::default();
+        let mut request = fixtures::logs_request();
+        request.resource_logs[0].scope_logs[0].log_records[0].trace_id = vec![1; 3];
+
+        let response = app(&MockSink::default(), &logs)
+            .oneshot(post(
+                "/v1/logs",
+                PROTOBUF_CONTENT_TYPE,
+                request.encode_to_vec(),
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(StatusCode::OK, response.status());
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let response = ExportLogsServiceResponse::decode(body).unwrap();
+        assert_eq!(1, response.partial_success.unwrap().rejected_log_records);
+        assert_eq!(1, logs.records().len());
+    }
+
+    #[tokio::test]
+    async fn test_http_export_traces() {
+        let spans = MockSink::default();
+
+        let response = app(&spans, &MockSink::default())
+            .oneshot(post(
+                "/v1/traces",
+                PROTOBUF_CONTENT_TYPE,
+                fixtures::trace_request().encode_to_vec(),
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(2, spans.records().len());
+    }
+
+    #[tokio::test]
+    async fn test_http_rejects_json_and_garbage() {
+        let app = app(&MockSink::default(), &MockSink::default());
+
+        let json = app
+            .clone()
+            .oneshot(post("/v1/traces", "application/json", b"{}".to_vec()))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::UNSUPPORTED_MEDIA_TYPE, json.status());
+
+        let garbage = app
+            .oneshot(post(
+                "/v1/traces",
+                PROTOBUF_CONTENT_TYPE,
+                vec![0xff, 0xff, 0xff],
+            ))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, garbage.status());
+    }
+
+    #[tokio::test]
+    async fn test_http_ingest_failure_is_retryable() {
+        let spans = MockSink::default().fail_acks_for(|_| true);
+
+        let response = app(&spans, &MockSink::default())
+            .oneshot(post(
+                "/v1/traces",
+                PROTOBUF_CONTENT_TYPE,
+                fixtures::trace_request().encode_to_vec(),
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status());
+    }
+}
@@ -0,0 +1,8 @@
+pub mod attributes;
+pub mod convert;
+pub mod http;
+pub mod proto;
+pub mod service;
+
+#[cfg(test)]
+mod fixtures;
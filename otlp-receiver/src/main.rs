@@ -0,0 +1,119 @@
+use anyhow::{bail, Context, Result};
+use databricks_zerobus_ingest_sdk::{StreamConfigurationOptions, TableProperties, ZerobusSdk};
+use opentelemetry_proto::tonic::collector::logs::v1::logs_service_server::LogsServiceServer;
+use opentelemetry_proto::tonic::collector::trace::v1::trace_service_server::TraceServiceServer;
+use otlp_receiver::proto::{load_logs_descriptor_proto, load_spans_descriptor_proto};
+use otlp_receiver::service::OtlpReceiver;
+use prost_types::DescriptorProto;
+use std::net::SocketAddr;
+use tonic::codec::CompressionEncoding;
+use tracing::info;
+use zerobus_common::log_level;
+use zerobus_common::pipeline::Pipeline;
+use zerobus_common::shutdown;
+
+/// Maximum number of unacknowledged records per stream
+const MAX_INFLIGHT_RECORDS: usize = 10_000;
+
+/// Standard OTLP ports
+const DEFAULT_GRPC_LISTEN_ADDR: &str = "0.0.0.0:4317";
+const DEFAULT_HTTP_LISTEN_ADDR: &str = "0.0.0.0:4318";
+
+fn env(name: &str) -> Result<String> {
+    std::env::var(name).with_context(|| format!("{} environment variable must be set", name))
+}
+
+async fn create_pipeline(
+    sdk: &ZerobusSdk,
+    table_name: String,
+    descriptor_proto: DescriptorProto,
+) -> Result<Pipeline<databricks_zerobus_ingest_sdk::ZerobusStream>> {
+    let table_properties = TableProperties {
+        table_name: table_name.clone(),
+        descriptor_proto,
+    };
+    let stream_options = StreamConfigurationOptions {
+        max_inflight_records: MAX_INFLIGHT_RECORDS,
+        ..Default::default()
+    };
+    let stream = sdk
+        .create_stream(
+            table_properties,
+            env("DATABRICKS_CLIENT_ID")?,
+            env("DATABRICKS_CLIENT_SECRET")?,
+            Some(stream_options),
+        )
+        .await
+        .with_context(|| format!("Failed to create stream to {}", table_name))?;
+    info!("Created stream to table: {}", table_name);
+
+    Ok(Pipeline::new(stream, MAX_INFLIGHT_RECORDS))
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let log_level = log_level::init()?;
+    tokio::spawn(log_level::on_hangup(log_level.clone()));
+
+    let grpc_addr: SocketAddr = std::env::var("GRPC_LISTEN_ADDR")
+        .unwrap_or_else(|_| DEFAULT_GRPC_LISTEN_ADDR.to_string())
+        .parse()
+        .context("Invalid GRPC_LISTEN_ADDR")?;
+    let http_addr =
+        std::env::var("HTTP_LISTEN_ADDR").unwrap_or_else(|_| DEFAULT_HTTP_LISTEN_ADDR.to_string());
+    let grace = shutdown::grace_from_env()?;
+
+    let sdk = ZerobusSdk::new(env("ZEROBUS_ENDPOINT")?, env("DATABRICKS_HOST")?)?;
+    let spans = create_pipeline(
+        &sdk,
+        env("SPANS_TABLE_NAME")?,
+        load_spans_descriptor_proto(),
+    )
+    .await?;
+    let logs = create_pipeline(&sdk, env("LOGS_TABLE_NAME")?, load_logs_descriptor_proto()).await?;
+    let receiver = OtlpReceiver::new(spans, logs);
+
+    let grpc = tonic::transport::Server::builder()
+        .add_service(
+            TraceServiceServer::new(receiver.clone()).accept_compressed(CompressionEncoding::Gzip),
+        )
+        .add_service(
+            LogsServiceServer::new(receiver.clone()).accept_compressed(CompressionEncoding::Gzip),
+        )
+        .serve_with_shutdown(grpc_addr, shutdown::signal());
+    info!("Listening for OTLP/gRPC on {}", grpc_addr);
+
+    let listener = tokio::net::TcpListener::bind(&http_addr)
+        .await
+        .with_context(|| format!("Failed to bind {}", http_addr))?;
+    let mut app = otlp_receiver::http::router(receiver.clone());
+    if let Some(admin) = log_level::admin_routes_from_env(&log_level) {
+        app = app.merge(admin);
+    }
+    let http = axum::serve(listener, app).with_graceful_shutdown(shutdown::signal());
+    info!("Listening for OTLP/HTTP on {}", http_addr);
+
+    tokio::try_join!(async { grpc.await.context("gRPC server failed") }, async {
+        http.await.context("HTTP server failed")
+    },)?;
+
+    // Both servers have stopped, so this is the last reference to the pipelines
+    if let Some((spans, logs)) = receiver.into_pipelines() {
+        // Drained side by side so both streams share one grace period
+        let (spans, logs) =
+            tokio::try_join!(shutdown::drain(spans, grace), shutdown::drain(logs, grace))?;
+        info!(
+            "Shut down after ingesting {} spans and {} log records",
+            spans.summary.ingested, logs.summary.ingested
+        );
+        if !spans.unacked.is_empty() || !logs.unacked.is_empty() {
+            bail!(
+                "{} spans and {} log records were not acknowledged before shutdown",
+                spans.unacked.len(),
+                logs.unacked.len()
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,32 @@
+use prost_types::DescriptorProto;
+
+// Modules for generated protobuf code
+pub mod otlp_spans {
+    include!("../gen/rust/otlp_spans.rs");
+}
+
+pub mod otlp_logs {
+    include!("../gen/rust/otlp_logs.rs");
+}
+
+/// Load the descriptor of the spans table from the embedded descriptor file
+pub fn load_spans_descriptor_proto() -> DescriptorProto {
+    const DESCRIPTOR_BYTES: &[u8] = include_bytes!("../gen/descriptors/otlp_spans.descriptor");
+
+    zerobus_common::descriptor::load_descriptor_proto(
+        DESCRIPTOR_BYTES,
+        "otlp_spans.proto",
+        "table_otlp_spans",
+    )
+}
+
+/// Load the descriptor of the logs table from the embedded descriptor file
+pub fn load_logs_descriptor_proto() -> DescriptorProto {
+    const DESCRIPTOR_BYTES: &[u8] = include_bytes!("../gen/descriptors/otlp_logs.descriptor");
+
+    zerobus_common::descriptor::load_descriptor_proto(
+        DESCRIPTOR_BYTES,
+        "otlp_logs.proto",
+        "table_otlp_logs",
+    )
+}
@@ -0,0 +1,304 @@
+use anyhow::{Context, Result};
+use opentelemetry_proto::tonic::collector::logs::v1::logs_service_server::LogsService;
+use opentelemetry_proto::tonic::collector::logs::v1::{
+    ExportLogsPartialSuccess, ExportLogsServiceRequest, ExportLogsServiceResponse,
+};
+use opentelemetry_proto::tonic::collector::trace::v1::trace_service_server::TraceService;
+use opentelemetry_proto::tonic::collector::trace::v1::{
+    ExportTracePartialSuccess, ExportTraceServiceRequest, ExportTraceServiceResponse,
+};
+use prost::Message;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tonic::{Request, Response, Status};
+use tracing::{error, info, warn};
+use zerobus_common::pipeline::{IngestSink, Pipeline};
+
+use crate::convert::{logs_to_rows, spans_to_rows};
+
+/// Receives OTLP exports and ingests each signal into its own table
+///
+/// Shared by the gRPC and HTTP endpoints. Each signal has one stream; requests take
+/// turns on it so a response only reports success once that request's rows are durable.
+pub struct OtlpReceiver<S: IngestSink> {
+    spans: Arc<Mutex<Pipeline<S>>>,
+    logs: Arc<Mutex<Pipeline<S>>>,
+}
+
+impl<S: IngestSink> Clone for OtlpReceiver<S> {
+    fn clone(&self) -> Self {
+        Self {
+            spans: Arc::clone(&self.spans),
+            logs: Arc::clone(&self.logs),
+        }
+    }
+}
+
+impl<S: IngestSink> OtlpReceiver<S> {
+    pub fn new(spans: Pipeline<S>, logs: Pipeline<S>) -> Self {
+        Self {
+            spans: Arc::new(Mutex::new(spans)),
+            logs: Arc::new(Mutex::new(logs)),
+        }
+    }
+
+    /// Take the span and log pipelines back once the servers have stopped
+    pub fn into_pipelines(self) -> Option<(Pipeline<S>, Pipeline<S>)> {
+        let spans = Arc::try_unwrap(self.spans).ok()?.into_inner();
+        let logs = Arc::try_unwrap(self.logs).ok()?.into_inner();
+        Some((spans, logs))
+    }
+
+    /// Ingest the valid spans of a request
+    ///
+    /// Invalid spans are dropped and reported through `partial_success`. An error means
+    /// the valid spans were not all acknowledged and the client should retry.
+    pub async fn export_traces(
+        &self,
+        request: ExportTraceServiceRequest,
+    ) -> Result<ExportTraceServiceResponse> {
+        let (ingested_at, ingested_date) = ingestion_time()?;
+        let converted = spans_to_rows(&request, ingested_at, ingested_date);
+
+        if !converted.rows.is_empty() {
+            self.spans
+                .lock()
+                .await
+                .ingest_batch(converted.rows.iter().map(|row| row.encode_to_vec()))
+                .await?;
+        }
+        info!(
+            "Ingested {} spans ({} rejected)",
+            converted.rows.len(),
+            converted.rejected
+        );
+
+        // Per the OTLP spec, partial_success is left unset when everything was accepted
+        let partial_success = (converted.rejected > 0).then(|| {
+            let error_message = converted.first_error.unwrap_or_default();
+            warn!("Rejected {} spans: {}", converted.rejected, error_message);
+            ExportTracePartialSuccess {
+                rejected_spans: converted.rejected,
+                error_message,
+            }
+        });
+        Ok(ExportTraceServiceResponse { partial_success })
+    }
+
+    /// Ingest the valid log records of a request; see [`Self::export_traces`]
+    pub async fn export_logs(
+        &self,
+        request: ExportLogsServiceRequest,
+    ) -> Result<ExportLogsServiceResponse> {
+        let (ingested_at, ingested_date) = ingestion_time()?;
+        let converted = logs_to_rows(&request, ingested_at, ingested_date);
+
+        if !converted.rows.is_empty() {
+            self.logs
+                .lock()
+                .await
+                .ingest_batch(converted.rows.iter().map(|row| row.encode_to_vec()))
+                .await?;
+        }
+        info!(
+            "Ingested {} log records ({} rejected)",
+            converted.rows.len(),
+            converted.rejected
+        );
+
+        let partial_success = (converted.rejected > 0).then(|| {
+            let error_message = converted.first_error.unwrap_or_default();
+            warn!(
+                "Rejected {} log records: {}",
+                converted.rejected, error_message
+            );
+            ExportLogsPartialSuccess {
+                rejected_log_records: converted.rejected,
+                error_message,
+            }
+        });
+        Ok(ExportLogsServiceResponse { partial_success })
+    }
+}
+
+/// Current time as (microseconds, days) since Unix epoch
+fn ingestion_time() -> Result<(i64, i32)> {
+    let since_epoch = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .context("Failed to get system time")?;
+    Ok((
+        since_epoch.as_micros() as i64,
+        since_epoch.as_secs() as i32 / 86400,
+    ))
+}
+
+/// Failed ingestion is retryable: UNAVAILABLE tells OTLP exporters to back off and resend
+fn unavailable(e: anyhow::Error) -> Status {
+    error!("Failed to ingest export request: {:#}", e);
+    Status::unavailable(format!("{:#}", e))
+}
+
+#[tonic::async_trait]
+impl<S: IngestSink + 'static> TraceService for OtlpReceiver<S> {
+    async fn export(
+        &self,
+        request: Request<ExportTraceServiceRequest>,
+    ) -> Result<Response<ExportTraceServiceResponse>, Status> {
+        self.export_traces(request.into_inner())
+            .await
+            .map(Response::new)
+            .map_err(unavailable)
+    }
+}
+
+#[tonic::async_trait]
+impl<S: IngestSink + 'static> LogsService for OtlpReceiver<S> {
+    async fn export(
+        &self,
+        request: Request<ExportLogsServiceRequest>,
+    ) -> Result<Response<ExportLogsServiceResponse>, Status> {
+        self.export_logs(request.into_inner())
+            .await
+            .map(Response::new)
+            .map_err(unavailable)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use crate::proto::otlp_logs::TableOtlpLogs;
+    use crate::proto::otlp_spans::TableOtlpSpans;
+    use opentelemetry_proto::tonic::collector::logs::v1::logs_service_client::LogsServiceClient;
+    use opentelemetry_proto::tonic::collector::logs::v1::logs_service_server::LogsServiceServer;
+    use opentelemetry_proto::tonic::collector::trace::v1::trace_service_client::TraceServiceClient;
+    use opentelemetry_proto::tonic::collector::trace::v1::trace_service_server::TraceServiceServer;
+    use tokio_stream::wrappers::TcpListenerStream;
+    use zerobus_common::testing::MockSink;
+
+    fn receiver(spans: &MockSink, logs: &MockSink) -> OtlpReceiver<MockSink> {
+        OtlpReceiver::new(
+            Pipeline::new(spans.clone(), 100),
+            Pipeline::new(logs.clone(), 100),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_grpc_export_trace_request() {
+        let spans = MockSink::default();
+        let logs = MockSink::default();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(TraceServiceServer::new(receiver(&spans, &logs)))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+
+        let mut client = TraceServiceClient::connect(format!("http://{}", addr))
+            .await
+            .unwrap();
+        let response = client
+            .export(fixtures::trace_request())
+            .await
+            .unwrap()
+            .into_inner();
+
+        assert_eq!(None, response.partial_success);
+        let rows: Vec<_> = spans
+            .records()
+            .iter()
+            .map(|record| TableOtlpSpans::decode(record.as_slice()).unwrap())
+            .collect();
+        assert_eq!(2, rows.len());
+        assert_eq!(Some(fixtures::SERVER_SPAN_ID.to_string()), rows[0].span_id);
+        assert_eq!(Some(fixtures::CLIENT_SPAN_ID.to_string()), rows[1].span_id);
+        assert!(logs.records().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_rejected_spans_are_reported_as_partial_success() {
+        let spans = MockSink::default();
+        let mut request = fixtures::trace_request();
+        request.resource_spans[0].scope_spans[0].spans[1]
+            .span_id
+            .clear();
+
+        let response = receiver(&spans, &MockSink::default())
+            .export_traces(request)
+            .await
+            .unwrap();
+
+        let partial_success = response.partial_success.unwrap();
+        assert_eq!(1, partial_success.rejected_spans);
+        assert!(partial_success.error_message.contains("span_id"));
+        assert_eq!(1, spans.records().len());
+    }
+
+    #[tokio::test]
+    async fn test_export_logs() {
+        let logs = MockSink::default();
+
+        let response = receiver(&MockSink::default(), &logs)
+            .export_logs(fixtures::logs_request())
+            .await
+            .unwrap();
+
+        assert_eq!(None, response.partial_success);
+        assert_eq!(2, logs.records().len());
+    }
+
+    #[tokio::test]
+    async fn test_grpc_export_logs_request() {
+        let spans = MockSink::default();
+        let logs = MockSink::default();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(LogsServiceServer::new(receiver(&spans, &logs)))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+
+        let mut client = LogsServiceClient::connect(format!("http://{}", addr))
+            .await
+            .unwrap();
+        let response = client
+            .export(fixtures::logs_request())
+            .await
+            .unwrap()
+            .into_inner();
+
+        assert_eq!(None, response.partial_success);
+        let rows: Vec<_> = logs
+            .records()
+            .iter()
+            .map(|record| TableOtlpLogs::decode(record.as_slice()).unwrap())
+            .collect();
+        assert_eq!(2, rows.len());
+        assert_eq!(Some("order created".to_string()), rows[0].body);
+        assert_eq!(Some("checkout".to_string()), rows[0].service_name);
+        assert_eq!(
+            Some("io.opentelemetry.http".to_string()),
+            rows[0].scope_name
+        );
+        assert_eq!(Some(fixtures::TRACE_ID.to_string()), rows[0].trace_id);
+        assert_eq!(Some("ERROR".to_string()), rows[1].severity_text);
+        assert!(spans.records().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unacknowledged_rows_are_unavailable() {
+        let spans = MockSink::default().fail_acks_for(|_| true);
+
+        let status = TraceService::export(
+            &receiver(&spans, &MockSink::default()),
+            Request::new(fixtures::trace_request()),
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(tonic::Code::Unavailable, status.code());
+    }
+}
@@ -0,0 +1,392 @@
+//! Turning decoded `pgoutput` messages into CDC rows, and tracking which LSN is safe
+//! to confirm back to Postgres.
+
+use anyhow::{bail, Context, Result};
+use prost::Message;
+use std::collections::{HashMap, VecDeque};
+use tracing::{debug, warn};
+use zerobus_common::pipeline::{IngestSink, IngestSummary, Pipeline};
+
+use crate::lsn::Lsn;
+use crate::pgoutput::{self, Begin, LogicalMessage, Relation, TupleValue};
+use crate::proto::cdc_events::TableCdcEvents;
+use crate::values::row_to_json;
+
+/// What one replication message amounts to
+#[derive(Debug, PartialEq)]
+pub enum Change {
+    Rows(Vec<TableCdcEvents>),
+    /// The transaction ended; confirming `end_lsn` tells Postgres it is no longer needed
+    Commit {
+        end_lsn: Lsn,
+    },
+    None,
+}
+
+/// Keeps the relations and the open transaction needed to interpret row changes
+#[derive(Default)]
+pub struct ChangeDecoder {
+    relations: HashMap<u32, Relation>,
+    transaction: Option<Begin>,
+}
+
+impl ChangeDecoder {
+    /// Interpret one message sent at `lsn`
+    ///
+    /// `ingested_at` is microseconds since Unix epoch.
+    pub fn decode(
+        &mut self,
+        lsn: Lsn,
+        message: LogicalMessage,
+        ingested_at: i64,
+    ) -> Result<Change> {
+        let (relation_id, op, before, after) = match message {
+            LogicalMessage::Begin(begin) => {
+                self.transaction = Some(begin);
+                return Ok(Change::None);
+            }
+            LogicalMessage::Commit(commit) => {
+                self.transaction = None;
+                return Ok(Change::Commit {
+                    end_lsn: commit.end_lsn,
+                });
+            }
+            LogicalMessage::Relation(relation) => {
+                self.relations.insert(relation.id, relation);
+                return Ok(Change::None);
+            }
+            LogicalMessage::Other(tag) => {
+                debug!("Ignoring pgoutput message {:?}", tag as char);
+                return Ok(Change::None);
+            }
+            LogicalMessage::Insert { relation_id, new } => (relation_id, "insert", None, Some(new)),
+            LogicalMessage::Update {
+                relation_id,
+                old,
+                new,
+            } => (relation_id, "update", old, Some(new)),
+            LogicalMessage::Delete { relation_id, old } => (relation_id, "delete", Some(old), None),
+            LogicalMessage::Truncate { relation_ids } => {
+                let rows = relation_ids
+                    .into_iter()
+                    .map(|id| self.row(lsn, id, "truncate", None, None, ingested_at))
+                    .collect::<Result<_>>()?;
+                return Ok(Change::Rows(rows));
+            }
+        };
+        let row = self.row(lsn, relation_id, op, before, after, ingested_at)?;
+        Ok(Change::Rows(vec![row]))
+    }
+
+    /// Whether a transaction has begun but not yet committed
+    pub fn in_transaction(&self) -> bool {
+        self.transaction.is_some()
+    }
+
+    fn row(
+        &self,
+        lsn: Lsn,
+        relation_id: u32,
+        op: &str,
+        before: Option<Vec<TupleValue>>,
+        after: Option<Vec<TupleValue>>,
+        ingested_at: i64,
+    ) -> Result<TableCdcEvents> {
+        let transaction = self
+            .transaction
+            .as_ref()
+            .context("Row change outside a transaction")?;
+        let relation = self
+            .relations
+            .get(&relation_id)
+            .with_context(|| format!("Row change for unknown relation {}", relation_id))?;
+        let to_json = |values: Vec<TupleValue>| row_to_json(&relation.columns, &values).to_string();
+
+        Ok(TableCdcEvents {
+            source_table: Some(format!("{}.{}", relation.namespace, relation.name)),
+            op: Some(op.to_string()),
+            before: before.map(to_json),
+            after: after.map(to_json),
+            lsn: Some(lsn.to_string()),
+            commit_lsn: Some(transaction.final_lsn.0 as i64),
+            commit_ts: Some(transaction.commit_ts),
+            xid: Some(i64::from(transaction.xid)),
+            ingested_at: Some(ingested_at),
+            ingested_date: Some((ingested_at / 86_400_000_000) as i32),
+        })
+    }
+}
+
+/// Ingests the changes of a replication stream and works out the flush LSN to confirm
+///
+/// A commit's LSN is only confirmed once every row sent up to that commit has been
+/// acknowledged, so Postgres keeps the WAL of anything not yet durable in the table.
+/// If a row is not acknowledged the confirmed LSN stops advancing and an error is
+/// returned; restarting replays everything after the last confirmed commit.
+pub struct CdcIngestor<S: IngestSink> {
+    decoder: ChangeDecoder,
+    pipeline: Pipeline<S>,
+    /// Rows handed to the pipeline so far
+    sent: u64,
+    /// Commits not yet confirmed, with the number of rows sent up to each
+    commits: VecDeque<(u64, Lsn)>,
+    confirmed: Lsn,
+}
+
+impl<S: IngestSink> CdcIngestor<S> {
+    /// `confirmed` is where the slot starts, i.e. its current confirmed flush LSN
+    pub fn new(pipeline: Pipeline<S>, confirmed: Lsn) -> Self {
+        Self {
+            decoder: ChangeDecoder::default(),
+            pipeline,
+            sent: 0,
+            commits: VecDeque::new(),
+            confirmed,
+        }
+    }
+
+    /// Decode and ingest one `pgoutput` message sent at `lsn`
+    pub async fn handle(&mut self, lsn: Lsn, message: &[u8], ingested_at: i64) -> Result<()> {
+        let message = pgoutput::decode(message)
+            .with_context(|| format!("Failed to decode pgoutput message at {}", lsn))?;
+        match self.decoder.decode(lsn, message, ingested_at)? {
+            Change::Rows(rows) => {
+                for row in rows {
+                    self.pipeline.ingest(row.encode_to_vec()).await?;
+                    self.sent += 1;
+                }
+            }
+            Change::Commit { end_lsn } => self.commits.push_back((self.sent, end_lsn)),
+            Change::None => {}
+        }
+        self.advance()?;
+        Ok(())
+    }
+
+    /// The server is at `wal_end` and has nothing more to send for now
+    ///
+    /// With no transaction open and every commit confirmed, the WAL up to `wal_end`
+    /// holds nothing for this slot, so it can be confirmed too. This keeps an idle
+    /// slot from retaining WAL written for tables outside the publication.
+    pub fn keepalive(&mut self, wal_end: Lsn) {
+        if !self.decoder.in_transaction() && self.commits.is_empty() && self.pipeline.pending() == 0
+        {
+            self.confirmed = self.confirmed.max(wal_end);
+        }
+    }
+
+    /// Wait for every outstanding ack and return the LSN that is safe to confirm
+    pub async fn checkpoint(&mut self) -> Result<Lsn> {
+        self.pipeline.drain().await?;
+        self.advance()
+    }
+
+    /// Latest LSN whose rows have all been acknowledged
+    pub fn confirmed(&self) -> Lsn {
+        self.confirmed
+    }
+
+    /// Drain outstanding acks and close the sink
+    pub async fn finish(mut self) -> Result<(IngestSummary, Lsn)> {
+        let confirmed = self.checkpoint().await;
+        let summary = self.pipeline.finish().await?;
+        Ok((summary, confirmed?))
+    }
+
+    /// Confirm every commit whose rows have all been acknowledged
+    fn advance(&mut self) -> Result<Lsn> {
+        let summary = self.pipeline.summary();
+        if summary.failed > 0 {
+            // Acks after a failure no longer line up with the rows sent, so nothing
+            // past the last confirmed commit can be trusted
+            warn!(
+                "{} rows were not acknowledged; holding the confirmed LSN at {}",
+                summary.failed, self.confirmed
+            );
+            bail!(
+                "{} rows were not acknowledged: {}",
+                summary.failed,
+                summary.first_error.as_deref().unwrap_or("unknown error")
+            );
+        }
+        while let Some(&(rows, end_lsn)) = self.commits.front() {
+            if rows > summary.ingested {
+                break;
+            }
+            self.confirmed = self.confirmed.max(end_lsn);
+            self.commits.pop_front();
+        }
+        Ok(self.confirmed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pgoutput::fixture_messages;
+    use serde_json::{json, Value};
+    use zerobus_common::testing::MockSink;
+
+    const INGESTED_AT: i64 = 1_700_000_000_000_000;
+
+    fn fixture() -> Vec<(Lsn, Vec<u8>)> {
+        fixture_messages(include_str!("../tests/fixtures/changes.wal"))
+    }
+
+    fn lsn(s: &str) -> Lsn {
+        s.parse().unwrap()
+    }
+
+    fn json(column: &Option<String>) -> Value {
+        serde_json::from_str(column.as_deref().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_fixture_rows() {
+        let mut decoder = ChangeDecoder::default();
+        let mut rows = Vec::new();
+        let mut commits = Vec::new();
+        for (lsn, bytes) in fixture() {
+            match decoder
+                .decode(lsn, pgoutput::decode(&bytes).unwrap(), INGESTED_AT)
+                .unwrap()
+            {
+                Change::Rows(changed) => rows.extend(changed),
+                Change::Commit { end_lsn } => commits.push(end_lsn.to_string()),
+                Change::None => {}
+            }
+        }
+
+        let ops: Vec<(&str, &str)> = rows.iter().map(|r| (r.source_table(), r.op())).collect();
+        assert_eq!(
+            vec![
+                ("public.orders", "insert"),
+                ("public.orders", "insert"),
+                ("public.orders", "update"),
+                ("public.orders", "update"),
+                ("public.orders", "delete"),
+                ("public.notes", "insert"),
+                ("public.notes", "update"),
+                ("public.notes", "update"),
+                ("public.notes", "truncate"),
+            ],
+            ops
+        );
+        assert_eq!(8, commits.len());
+        assert!(!decoder.in_transaction());
+
+        let insert = &rows[0];
+        assert_eq!("0/1527BC8", insert.lsn());
+        assert_eq!(0x1527DA0, insert.commit_lsn());
+        assert_eq!(726, insert.xid());
+        assert_eq!(None, insert.before);
+        assert_eq!(json!("19.99"), json(&insert.after)["price"]);
+        assert_eq!(19675, insert.ingested_date());
+
+        // Changing the key sends the old key columns
+        let key_change = &rows[3];
+        assert_eq!(json!(2), json(&key_change.before)["id"]);
+        assert_eq!(json!(20), json(&key_change.after)["id"]);
+
+        let delete = &rows[4];
+        assert_eq!(json!(20), json(&delete.before)["id"]);
+        assert_eq!(None, delete.after);
+
+        // The unchanged TOASTed body is left out rather than reported as null
+        let toast = &rows[6];
+        assert_eq!(None, toast.before);
+        assert_eq!(json!({"id": 1, "title": "final"}), json(&toast.after));
+
+        assert_eq!(
+            (None, None),
+            (rows[8].before.clone(), rows[8].after.clone())
+        );
+    }
+
+    #[test]
+    fn test_row_outside_transaction() {
+        let fixture = fixture();
+        let mut decoder = ChangeDecoder::default();
+        let decode = |index: usize| pgoutput::decode(&fixture[index].1).unwrap();
+
+        decoder.decode(Lsn(0), decode(1), INGESTED_AT).unwrap();
+        let error = decoder.decode(Lsn(0), decode(2), INGESTED_AT).unwrap_err();
+        assert!(error.to_string().contains("outside a transaction"));
+    }
+
+    #[tokio::test]
+    async fn test_lsn_advances_after_acks() {
+        let fixture = fixture();
+        let sink = MockSink::default();
+        let mut ingestor = CdcIngestor::new(Pipeline::new(sink.clone(), 100), Lsn(0));
+
+        // First transaction: two inserts and a commit, not acknowledged yet
+        for (lsn, bytes) in &fixture[..5] {
+            ingestor.handle(*lsn, bytes, INGESTED_AT).await.unwrap();
+        }
+        assert_eq!(2, sink.records().len());
+        assert_eq!(Lsn(0), ingestor.confirmed());
+        ingestor.keepalive(lsn("0/1600000"));
+        assert_eq!(Lsn(0), ingestor.confirmed());
+
+        assert_eq!(lsn("0/1527DD0"), ingestor.checkpoint().await.unwrap());
+
+        // An open transaction is not confirmed, even once its rows are acknowledged
+        for (lsn, bytes) in &fixture[5..7] {
+            ingestor.handle(*lsn, bytes, INGESTED_AT).await.unwrap();
+        }
+        assert_eq!(lsn("0/1527DD0"), ingestor.checkpoint().await.unwrap());
+        ingestor.keepalive(lsn("0/1600000"));
+        assert_eq!(lsn("0/1527DD0"), ingestor.confirmed());
+
+        let (commit_lsn, commit) = &fixture[7];
+        ingestor
+            .handle(*commit_lsn, commit, INGESTED_AT)
+            .await
+            .unwrap();
+        assert_eq!(lsn("0/1527EC0"), ingestor.checkpoint().await.unwrap());
+
+        // Idle at a commit boundary, the server's position can be confirmed
+        ingestor.keepalive(lsn("0/1600000"));
+        assert_eq!(lsn("0/1600000"), ingestor.confirmed());
+
+        let (summary, confirmed) = ingestor.finish().await.unwrap();
+        assert_eq!(3, summary.ingested);
+        assert_eq!(lsn("0/1600000"), confirmed);
+        assert!(sink.closed());
+    }
+
+    #[tokio::test]
+    async fn test_window_drain_confirms_earlier_commits() {
+        // With a window of one row, sending each row drains the previous one
+        let sink = MockSink::default();
+        let mut ingestor = CdcIngestor::new(Pipeline::new(sink, 1), Lsn(0));
+        for (lsn, bytes) in &fixture()[..8] {
+            ingestor.handle(*lsn, bytes, INGESTED_AT).await.unwrap();
+        }
+
+        // The update of the second transaction drained the first transaction's rows
+        assert_eq!(lsn("0/1527DD0"), ingestor.confirmed());
+    }
+
+    #[tokio::test]
+    async fn test_failed_ack_holds_lsn() {
+        let fixture = fixture();
+        let sink = MockSink::default()
+            .fail_acks_for(|record| TableCdcEvents::decode(record).unwrap().op() == "delete");
+        let mut ingestor = CdcIngestor::new(Pipeline::new(sink, 100), Lsn(0));
+
+        for (lsn, bytes) in &fixture[..11] {
+            ingestor.handle(*lsn, bytes, INGESTED_AT).await.unwrap();
+        }
+        assert_eq!(lsn("0/1527F98"), ingestor.checkpoint().await.unwrap());
+
+        // The delete's transaction commits, but its row is never acknowledged
+        for (lsn, bytes) in &fixture[11..17] {
+            ingestor.handle(*lsn, bytes, INGESTED_AT).await.unwrap();
+        }
+        let error = ingestor.checkpoint().await.unwrap_err();
+        assert!(error.to_string().contains("1 rows were not acknowledged"));
+        assert_eq!(lsn("0/1527F98"), ingestor.confirmed());
+    }
+}
@@ -0,0 +1,535 @@
+//! A replication connection to Postgres.
+//!
+//! The crates.io release of `tokio-postgres` cannot run `START_REPLICATION`, whose
+//! response switches the connection to `COPY BOTH`. This is the part of the
+//! frontend/backend protocol a logical replication client needs, on the message framing
+//! of `postgres-protocol`: startup in database replication mode, cleartext, MD5, and
+//! SCRAM-SHA-256 authentication, simple queries, and the `COPY BOTH` stream.
+//!
+//! See <https://www.postgresql.org/docs/current/protocol-flow.html>.
+
+use anyhow::{bail, Context, Result};
+use bytes::{Buf, Bytes, BytesMut};
+use fallible_iterator::FallibleIterator;
+use postgres_protocol::authentication::md5_hash;
+use postgres_protocol::authentication::sasl::{ChannelBinding, ScramSha256, SCRAM_SHA_256};
+use postgres_protocol::message::backend::{ErrorResponseBody, Message};
+use postgres_protocol::message::frontend;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_postgres::config::Host;
+use tokio_postgres::Config;
+
+/// Port connected to when the connection string names none
+const DEFAULT_PORT: u16 = 5432;
+
+/// Tag of `CopyBothResponse`, which `postgres-protocol` does not parse
+const COPY_BOTH_RESPONSE: u8 = b'W';
+
+trait Socket: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Socket for T {}
+
+/// What the server sent
+enum Received {
+    Message(Message),
+    /// The replication stream has started
+    CopyBoth,
+}
+
+/// A row answered to a simple query, with every value as text
+#[derive(Debug, Clone, PartialEq)]
+pub struct Row {
+    columns: Arc<Vec<String>>,
+    values: Vec<Option<String>>,
+}
+
+impl Row {
+    /// The value of the column at `index`; `None` for NULL or a column the row lacks
+    pub fn get(&self, index: usize) -> Option<&str> {
+        self.values.get(index)?.as_deref()
+    }
+
+    /// The value of the column named `column`; `None` for NULL or a column the row lacks
+    pub fn named(&self, column: &str) -> Option<&str> {
+        let index = self.columns.iter().position(|name| name == column)?;
+        self.get(index)
+    }
+}
+
+/// A connection in database replication mode, which runs both replication commands
+/// and SQL
+pub struct ReplicationConnection {
+    socket: Box<dyn Socket>,
+    read: BytesMut,
+    write: BytesMut,
+}
+
+impl ReplicationConnection {
+    /// Connect to the first host in `config` that accepts, and authenticate
+    pub async fn connect(config: &Config) -> Result<Self> {
+        let user = config
+            .get_user()
+            .context("The connection string names no user")?;
+        let hosts = config.get_hosts();
+        if hosts.is_empty() {
+            bail!("The connection string names no host");
+        }
+
+        let mut failure = None;
+        for (i, host) in hosts.iter().enumerate() {
+            let port = match config.get_ports() {
+                [] => DEFAULT_PORT,
+                [port] => *port,
+                ports => ports.get(i).copied().unwrap_or(DEFAULT_PORT),
+            };
+            match Self::open(host, port).await {
+                Ok(socket) => {
+                    let mut connection = Self::new(socket);
+                    connection.start_up(config, user).await?;
+                    return Ok(connection);
+                }
+                Err(e) => failure = Some(e),
+            }
+        }
+        Err(failure.unwrap_or_else(|| anyhow::anyhow!("No host to connect to")))
+    }
+
+    fn new(socket: Box<dyn Socket>) -> Self {
+        Self {
+            socket,
+            read: BytesMut::new(),
+            write: BytesMut::new(),
+        }
+    }
+
+    async fn open(host: &Host, port: u16) -> Result<Box<dyn Socket>> {
+        match host {
+            Host::Tcp(host) => {
+                let socket = tokio::net::TcpStream::connect((host.as_str(), port))
+                    .await
+                    .with_context(|| format!("Failed to connect to {}:{}", host, port))?;
+                socket.set_nodelay(true)?;
+                Ok(Box::new(socket))
+            }
+            #[cfg(unix)]
+            Host::Unix(dir) => {
+                let path = dir.join(format!(".s.PGSQL.{}", port));
+                let socket = tokio::net::UnixStream::connect(&path)
+                    .await
+                    .with_context(|| format!("Failed to connect to {}", path.display()))?;
+                Ok(Box::new(socket))
+            }
+        }
+    }
+
+    async fn start_up(&mut self, config: &Config, user: &str) -> Result<()> {
+        let mut parameters = vec![
+            ("user", user),
+            ("database", config.get_dbname().unwrap_or(user)),
+            ("replication", "database"),
+        ];
+        if let Some(application_name) = config.get_application_name() {
+            parameters.push(("application_name", application_name));
+        }
+        frontend::startup_message(parameters, &mut self.write)?;
+        self.flush().await?;
+
+        let password = || {
+            config
+                .get_password()
+                .context("The server asked for a password, and the connection string has none")
+        };
+        let mut scram = None;
+        loop {
+            match self.receive_message().await? {
+                Message::AuthenticationOk => break,
+                Message::AuthenticationCleartextPassword => {
+                    frontend::password_message(password()?, &mut self.write)?;
+                }
+                Message::AuthenticationMd5Password(body) => {
+                    let hash = md5_hash(user.as_bytes(), password()?, body.salt());
+                    frontend::password_message(hash.as_bytes(), &mut self.write)?;
+                }
+                Message::AuthenticationSasl(body) => {
+                    let offered: Vec<String> =
+                        body.mechanisms().map(|m| Ok(m.to_string())).collect()?;
+                    if !offered.iter().any(|m| m == SCRAM_SHA_256) {
+                        bail!(
+                            "The server offered no supported SASL mechanism: {:?}",
+                            offered
+                        );
+                    }
+                    let state = ScramSha256::new(password()?, ChannelBinding::unsupported());
+                    frontend::sasl_initial_response(
+                        SCRAM_SHA_256,
+                        state.message(),
+                        &mut self.write,
+                    )?;
+                    scram = Some(state);
+                }
+                Message::AuthenticationSaslContinue(body) => {
+                    let state = scram.as_mut().context("SASL continued before it started")?;
+                    state.update(body.data()).context("Invalid SCRAM message")?;
+                    frontend::sasl_response(state.message(), &mut self.write)?;
+                }
+                Message::AuthenticationSaslFinal(body) => {
+                    let state = scram.as_mut().context("SASL finished before it started")?;
+                    state
+                        .finish(body.data())
+                        .context("The server failed SCRAM verification")?;
+                    continue;
+                }
+                Message::ErrorResponse(body) => {
+                    return Err(server_error(&body)).context("Failed to authenticate")
+                }
+                _ => bail!("Unsupported authentication request"),
+            }
+            self.flush().await?;
+        }
+
+        loop {
+            match self.receive_message().await? {
+                Message::ReadyForQuery(_) => return Ok(()),
+                Message::ParameterStatus(_)
+                | Message::BackendKeyData(_)
+                | Message::NoticeResponse(_) => {}
+                Message::ErrorResponse(body) => return Err(server_error(&body)),
+                _ => bail!("Unexpected message while starting up"),
+            }
+        }
+    }
+
+    /// Run `query`, a replication command or SQL, and return the rows it answers
+    pub async fn simple_query(&mut self, query: &str) -> Result<Vec<Row>> {
+        frontend::query(query, &mut self.write)?;
+        self.flush().await?;
+
+        let mut columns = Arc::new(Vec::new());
+        let mut rows = Vec::new();
+        let mut failed = None;
+        loop {
+            match self.receive_message().await? {
+                Message::RowDescription(body) => {
+                    columns = Arc::new(body.fields().map(|f| Ok(f.name().to_string())).collect()?);
+                }
+                Message::DataRow(body) => {
+                    let buffer = body.buffer();
+                    let values = body
+                        .ranges()
+                        .map(|range| {
+                            Ok(range
+                                .map(|range| String::from_utf8_lossy(&buffer[range]).into_owned()))
+                        })
+                        .collect()?;
+                    rows.push(Row {
+                        columns: Arc::clone(&columns),
+                        values,
+                    });
+                }
+                Message::CommandComplete(_)
+                | Message::EmptyQueryResponse
+                | Message::NoticeResponse(_)
+                | Message::ParameterStatus(_) => {}
+                // The server still answers ReadyForQuery after an error
+                Message::ErrorResponse(body) => failed = Some(server_error(&body)),
+                Message::ReadyForQuery(_) => break,
+                _ => bail!("Unexpected message in the answer to a query"),
+            }
+        }
+        match failed {
+            Some(e) => Err(e),
+            None => Ok(rows),
+        }
+    }
+
+    /// Run `START_REPLICATION`, after which [`Self::recv`] reads the stream it starts
+    pub async fn start_replication(&mut self, query: &str) -> Result<()> {
+        frontend::query(query, &mut self.write)?;
+        self.flush().await?;
+        loop {
+            match self.receive().await? {
+                Received::CopyBoth => return Ok(()),
+                Received::Message(Message::NoticeResponse(_) | Message::ParameterStatus(_)) => {}
+                Received::Message(Message::ErrorResponse(body)) => return Err(server_error(&body)),
+                Received::Message(_) => bail!("Unexpected message while starting replication"),
+            }
+        }
+    }
+
+    /// The next message of the replication stream, or `None` once the server ends it
+    ///
+    /// Cancel safe: a message partly read when the future is dropped is finished by the
+    /// next call.
+    pub async fn recv(&mut self) -> Result<Option<Bytes>> {
+        loop {
+            match self.receive().await? {
+                Received::Message(Message::CopyData(body)) => return Ok(Some(body.into_bytes())),
+                Received::Message(Message::CopyDone) => return Ok(None),
+                Received::Message(Message::NoticeResponse(_) | Message::ParameterStatus(_)) => {}
+                Received::Message(Message::ErrorResponse(body)) => return Err(server_error(&body)),
+                _ => bail!("Unexpected message in the replication stream"),
+            }
+        }
+    }
+
+    /// Send `data` to the server in the replication stream
+    pub async fn send(&mut self, data: &[u8]) -> Result<()> {
+        frontend::CopyData::new(data)?.write(&mut self.write);
+        self.flush().await
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        self.socket
+            .write_all(&self.write)
+            .await
+            .context("Failed to write to Postgres")?;
+        self.write.clear();
+        self.socket
+            .flush()
+            .await
+            .context("Failed to write to Postgres")
+    }
+
+    async fn receive_message(&mut self) -> Result<Message> {
+        match self.receive().await? {
+            Received::Message(message) => Ok(message),
+            Received::CopyBoth => bail!("Unexpected COPY BOTH response"),
+        }
+    }
+
+    async fn receive(&mut self) -> Result<Received> {
+        loop {
+            if self.read.first() == Some(&COPY_BOTH_RESPONSE) && self.read.len() >= 5 {
+                let len =
+                    u32::from_be_bytes([self.read[1], self.read[2], self.read[3], self.read[4]]);
+                // The tag, then a length counting itself and the column formats
+                if self.read.len() > len as usize {
+                    self.read.advance(len as usize + 1);
+                    return Ok(Received::CopyBoth);
+                }
+            } else if let Some(message) =
+                Message::parse(&mut self.read).context("Invalid message from Postgres")?
+            {
+                return Ok(Received::Message(message));
+            }
+            let read = self
+                .socket
+                .read_buf(&mut self.read)
+                .await
+                .context("Failed to read from Postgres")?;
+            if read == 0 {
+                bail!("Postgres closed the connection");
+            }
+        }
+    }
+}
+
+/// The severity, message, detail, and SQLSTATE code of an error response
+fn server_error(body: &ErrorResponseBody) -> anyhow::Error {
+    let (mut severity, mut message, mut detail, mut code) =
+        ("ERROR".to_string(), String::new(), None, String::new());
+    let mut fields = body.fields();
+    while let Ok(Some(field)) = fields.next() {
+        let value = String::from_utf8_lossy(field.value_bytes()).into_owned();
+        match field.type_() {
+            b'S' => severity = value,
+            b'M' => message = value,
+            b'D' => detail = Some(value),
+            b'C' => code = value,
+            _ => {}
+        }
+    }
+    match detail {
+        Some(detail) => anyhow::anyhow!("{}: {} ({}): {}", severity, message, code, detail),
+        None => anyhow::anyhow!("{}: {} ({})", severity, message, code),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// A backend message
+    fn message(tag: u8, body: &[u8]) -> Vec<u8> {
+        let mut message = vec![tag];
+        message.extend_from_slice(&(body.len() as u32 + 4).to_be_bytes());
+        message.extend_from_slice(body);
+        message
+    }
+
+    fn cstr(value: &str) -> Vec<u8> {
+        [value.as_bytes(), &[0]].concat()
+    }
+
+    /// A frontend message's tag and body, or the body of the startup message
+    async fn read_frontend(stream: &mut TcpStream, tagged: bool) -> (u8, Vec<u8>) {
+        let tag = if tagged {
+            stream.read_u8().await.unwrap()
+        } else {
+            0
+        };
+        let len = stream.read_u32().await.unwrap() as usize;
+        let mut body = vec![0; len - 4];
+        stream.read_exact(&mut body).await.unwrap();
+        (tag, body)
+    }
+
+    fn ready() -> Vec<u8> {
+        message(b'Z', b"I")
+    }
+
+    /// Accept one connection, authenticate it with MD5, and start it up
+    async fn accept(listener: &TcpListener) -> TcpStream {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let (_, startup) = read_frontend(&mut stream, false).await;
+        let startup = String::from_utf8_lossy(&startup).into_owned();
+        assert!(startup.contains("user\0cdc\0"), "{:?}", startup);
+        assert!(startup.contains("database\0app\0"), "{:?}", startup);
+        assert!(startup.contains("replication\0database\0"), "{:?}", startup);
+
+        let salt = [1, 2, 3, 4];
+        let challenge = [&5u32.to_be_bytes()[..], &salt].concat();
+        stream.write_all(&message(b'R', &challenge)).await.unwrap();
+        let (tag, password) = read_frontend(&mut stream, true).await;
+        assert_eq!(b'p', tag);
+        assert_eq!(cstr(&md5_hash(b"cdc", b"s3cret", salt)), password);
+
+        let started = [
+            message(b'R', &0u32.to_be_bytes()),
+            message(b'S', &[cstr("server_version"), cstr("15.4")].concat()),
+            message(b'K', &[1, 0, 0, 0, 2, 0, 0, 0]),
+            ready(),
+        ]
+        .concat();
+        stream.write_all(&started).await.unwrap();
+        stream
+    }
+
+    async fn connect(listener: &TcpListener) -> Result<ReplicationConnection> {
+        let config: Config = format!(
+            "host=127.0.0.1 port={} user=cdc password=s3cret dbname=app",
+            listener.local_addr().unwrap().port()
+        )
+        .parse()
+        .unwrap();
+        ReplicationConnection::connect(&config).await
+    }
+
+    #[tokio::test]
+    async fn test_query_and_stream() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = async {
+            let mut stream = accept(&listener).await;
+
+            let (tag, query) = read_frontend(&mut stream, true).await;
+            assert_eq!(
+                (b'Q', cstr("CREATE_REPLICATION_SLOT cdc LOGICAL pgoutput")),
+                (tag, query)
+            );
+            let mut columns = 2u16.to_be_bytes().to_vec();
+            for name in ["consistent_point", "snapshot_name"] {
+                columns.extend(cstr(name));
+                columns.extend_from_slice(&[0; 18]);
+            }
+            let mut row = 2u16.to_be_bytes().to_vec();
+            row.extend_from_slice(&9u32.to_be_bytes());
+            row.extend_from_slice(b"0/1527BC8");
+            row.extend_from_slice(&(-1i32).to_be_bytes());
+            let answer = [
+                message(b'T', &columns),
+                message(b'D', &row),
+                message(b'C', &cstr("CREATE_REPLICATION_SLOT")),
+                ready(),
+            ]
+            .concat();
+            stream.write_all(&answer).await.unwrap();
+
+            let (tag, _) = read_frontend(&mut stream, true).await;
+            assert_eq!(b'Q', tag);
+            let started = [message(b'W', &[0, 0, 0]), message(b'd', b"k...")].concat();
+            stream.write_all(&started).await.unwrap();
+
+            let (tag, status) = read_frontend(&mut stream, true).await;
+            assert_eq!((b'd', b"r...".to_vec()), (tag, status));
+            stream.write_all(&message(b'c', &[])).await.unwrap();
+        };
+        let client = async {
+            let mut connection = connect(&listener).await.unwrap();
+            let rows = connection
+                .simple_query("CREATE_REPLICATION_SLOT cdc LOGICAL pgoutput")
+                .await
+                .unwrap();
+            assert_eq!(1, rows.len());
+            assert_eq!(Some("0/1527BC8"), rows[0].get(0));
+            assert_eq!(Some("0/1527BC8"), rows[0].named("consistent_point"));
+            assert_eq!(None, rows[0].named("snapshot_name"));
+            assert_eq!(None, rows[0].named("slot_name"));
+
+            connection
+                .start_replication("START_REPLICATION SLOT cdc LOGICAL 0/1527BC8")
+                .await
+                .unwrap();
+            assert_eq!(
+                Some(Bytes::from_static(b"k...")),
+                connection.recv().await.unwrap()
+            );
+            connection.send(b"r...").await.unwrap();
+            assert_eq!(None, connection.recv().await.unwrap());
+        };
+        tokio::join!(server, client);
+    }
+
+    #[tokio::test]
+    async fn test_server_errors() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let error = |message: &str| {
+            let mut fields = Vec::new();
+            for (field, value) in [(b'S', "ERROR"), (b'C', "42704"), (b'M', message)] {
+                fields.push(field);
+                fields.extend(cstr(value));
+            }
+            fields.push(0);
+            self::message(b'E', &fields)
+        };
+        let server = async {
+            let mut stream = accept(&listener).await;
+            read_frontend(&mut stream, true).await;
+            let answer = [error("replication slot \"cdc\" does not exist"), ready()].concat();
+            stream.write_all(&answer).await.unwrap();
+
+            // A password the server rejects
+            let (mut stream, _) = listener.accept().await.unwrap();
+            read_frontend(&mut stream, false).await;
+            stream
+                .write_all(&message(b'R', &3u32.to_be_bytes()))
+                .await
+                .unwrap();
+            read_frontend(&mut stream, true).await;
+            stream
+                .write_all(&error("password authentication failed"))
+                .await
+                .unwrap();
+        };
+        let client = async {
+            let mut connection = connect(&listener).await.unwrap();
+            let failed = connection
+                .start_replication("START_REPLICATION SLOT cdc LOGICAL 0/0")
+                .await
+                .unwrap_err();
+            assert_eq!(
+                "ERROR: replication slot \"cdc\" does not exist (42704)",
+                failed.to_string()
+            );
+
+            let failed = connect(&listener).await.err().unwrap();
+            assert!(
+                format!("{:#}", failed).ends_with("password authentication failed (42704)"),
+                "{:#}",
+                failed
+            );
+        };
+        tokio::join!(server, client);
+    }
+}
@@ -0,0 +1,7 @@
+pub mod cdc;
+pub mod connection;
+pub mod lsn;
+pub mod pgoutput;
+pub mod proto;
+pub mod replication;
+pub mod values;
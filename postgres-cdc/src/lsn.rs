@@ -0,0 +1,40 @@
+use anyhow::{Context, Result};
+use std::fmt;
+use std::str::FromStr;
+
+/// A position in the write-ahead log, written `X/Y` by Postgres
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Lsn(pub u64);
+
+impl fmt::Display for Lsn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:X}/{:X}", self.0 >> 32, self.0 & 0xFFFF_FFFF)
+    }
+}
+
+impl FromStr for Lsn {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (high, low) = s
+            .split_once('/')
+            .with_context(|| format!("LSN must be X/Y, got {:?}", s))?;
+        let high = u32::from_str_radix(high, 16).with_context(|| format!("Invalid LSN {:?}", s))?;
+        let low = u32::from_str_radix(low, 16).with_context(|| format!("Invalid LSN {:?}", s))?;
+        Ok(Lsn((u64::from(high) << 32) | u64::from(low)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lsn_round_trip() {
+        let lsn: Lsn = "16/B374D848".parse().unwrap();
+        assert_eq!(Lsn(0x16_B374_D848), lsn);
+        assert_eq!("16/B374D848", lsn.to_string());
+        assert_eq!("0/0", Lsn::default().to_string());
+        assert!("B374D848".parse::<Lsn>().is_err());
+    }
+}
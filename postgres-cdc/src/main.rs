@@ -0,0 +1,247 @@
+use anyhow::{bail, Context, Result};
+use databricks_zerobus_ingest_sdk::{StreamConfigurationOptions, TableProperties, ZerobusSdk};
+use postgres_cdc::cdc::CdcIngestor;
+use postgres_cdc::connection::{ReplicationConnection, Row};
+use postgres_cdc::lsn::Lsn;
+use postgres_cdc::proto::load_descriptor_proto;
+use postgres_cdc::replication::{parse_server_message, standby_status_update, ServerMessage};
+use std::pin::pin;
+use std::time::{Duration, SystemTime};
+use tokio::time::MissedTickBehavior;
+use tracing::{error, info};
+use zerobus_common::pipeline::{IngestSink, Pipeline};
+use zerobus_common::shutdown;
+
+/// Maximum number of unacknowledged records per stream when MAX_INFLIGHT is not set
+const DEFAULT_MAX_INFLIGHT: usize = 10_000;
+
+/// Slot to stream from when SLOT_NAME is not set
+const DEFAULT_SLOT_NAME: &str = "zerobus_cdc";
+
+/// How often the confirmed LSN is reported when STATUS_INTERVAL_SECS is not set
+const DEFAULT_STATUS_INTERVAL_SECS: u64 = 10;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .with_target(false)
+        .init();
+
+    let zerobus_endpoint = std::env::var("ZEROBUS_ENDPOINT")
+        .context("ZEROBUS_ENDPOINT environment variable must be set")?;
+    let databricks_host = std::env::var("DATABRICKS_HOST")
+        .context("DATABRICKS_HOST environment variable must be set")?;
+    let client_id = std::env::var("DATABRICKS_CLIENT_ID")
+        .context("DATABRICKS_CLIENT_ID environment variable must be set")?;
+    let client_secret = std::env::var("DATABRICKS_CLIENT_SECRET")
+        .context("DATABRICKS_CLIENT_SECRET environment variable must be set")?;
+    let table_name =
+        std::env::var("TABLE_NAME").context("TABLE_NAME environment variable must be set")?;
+    let pg_connection =
+        std::env::var("PG_CONNECTION").context("PG_CONNECTION environment variable must be set")?;
+    let publication =
+        std::env::var("PUBLICATION").context("PUBLICATION environment variable must be set")?;
+    let slot_name = slot_name()?;
+    let max_inflight = positive_env("MAX_INFLIGHT", DEFAULT_MAX_INFLIGHT as u64)? as usize;
+    let status_interval = Duration::from_secs(positive_env(
+        "STATUS_INTERVAL_SECS",
+        DEFAULT_STATUS_INTERVAL_SECS,
+    )?);
+
+    let config: tokio_postgres::Config = pg_connection
+        .parse()
+        .context("PG_CONNECTION is not a valid connection string")?;
+    let mut connection = ReplicationConnection::connect(&config)
+        .await
+        .context("Failed to connect to Postgres")?;
+
+    let start = ensure_slot(&mut connection, &slot_name).await?;
+
+    let sdk = ZerobusSdk::new(zerobus_endpoint, databricks_host)?;
+    let table_properties = TableProperties {
+        table_name: table_name.clone(),
+        descriptor_proto: load_descriptor_proto("cdc_events.proto", "table_cdc_events"),
+    };
+    let stream_options = StreamConfigurationOptions {
+        max_inflight_records: max_inflight,
+        ..Default::default()
+    };
+    let stream = sdk
+        .create_stream(
+            table_properties,
+            client_id,
+            client_secret,
+            Some(stream_options),
+        )
+        .await
+        .context("Failed to create stream")?;
+    info!("Created stream to table: {}", table_name);
+
+    let query = format!(
+        "START_REPLICATION SLOT {} LOGICAL {} (proto_version '1', publication_names '{}')",
+        slot_name,
+        start,
+        publication.replace('\'', "''")
+    );
+    connection
+        .start_replication(&query)
+        .await
+        .context("Failed to start replication")?;
+    info!(
+        "Streaming publication {} from slot {} at {}",
+        publication, slot_name, start
+    );
+
+    let mut ingestor = CdcIngestor::new(Pipeline::new(stream, max_inflight), start);
+    let streamed = stream_changes(&mut connection, &mut ingestor, status_interval).await;
+    if let Err(e) = &streamed {
+        error!("Replication stopped: {:#}", e);
+    }
+
+    // Whatever happened, report the progress that is known to be durable
+    let confirmed = ingestor.confirmed();
+    let confirmed = match ingestor.finish().await {
+        Ok((summary, confirmed)) => {
+            info!(
+                "Shut down: {} rows ingested, {} failed",
+                summary.ingested, summary.failed
+            );
+            confirmed
+        }
+        Err(e) => {
+            error!("Failed to drain outstanding rows: {:#}", e);
+            confirmed
+        }
+    };
+    connection
+        .send(&standby_status_update(confirmed, SystemTime::now()))
+        .await
+        .context("Failed to send final status update")?;
+    info!("Confirmed flush LSN {}", confirmed);
+
+    streamed
+}
+
+/// Relay replication messages to the ingestor until Ctrl+C or the server ends the stream
+async fn stream_changes<S: IngestSink>(
+    connection: &mut ReplicationConnection,
+    ingestor: &mut CdcIngestor<S>,
+    status_interval: Duration,
+) -> Result<()> {
+    let mut status = tokio::time::interval(status_interval);
+    status.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut shutdown = pin!(shutdown::signal());
+
+    loop {
+        tokio::select! {
+            message = connection.recv() => {
+                let message = message.context("Failed to read from replication stream")?;
+                let Some(message) = message else {
+                    info!("Server ended the replication stream");
+                    return Ok(());
+                };
+                match parse_server_message(&message)? {
+                    ServerMessage::XLogData { wal_start, data } => {
+                        ingestor.handle(wal_start, data, now_micros()?).await?;
+                    }
+                    ServerMessage::Keepalive { wal_end, reply_requested } => {
+                        ingestor.checkpoint().await?;
+                        ingestor.keepalive(wal_end);
+                        if reply_requested {
+                            send_status(connection, ingestor.confirmed()).await?;
+                        }
+                    }
+                }
+            }
+            _ = status.tick() => {
+                let confirmed = ingestor.checkpoint().await?;
+                send_status(connection, confirmed).await?;
+            }
+            _ = &mut shutdown => return Ok(()),
+        }
+    }
+}
+
+async fn send_status(connection: &mut ReplicationConnection, confirmed: Lsn) -> Result<()> {
+    connection
+        .send(&standby_status_update(confirmed, SystemTime::now()))
+        .await
+        .context("Failed to send status update")
+}
+
+/// Return the slot's confirmed flush LSN, creating the slot if it does not exist
+async fn ensure_slot(connection: &mut ReplicationConnection, slot_name: &str) -> Result<Lsn> {
+    let existing = connection
+        .simple_query(&format!(
+            "SELECT confirmed_flush_lsn FROM pg_replication_slots WHERE slot_name = '{}'",
+            slot_name
+        ))
+        .await
+        .context("Failed to look up replication slot")?;
+    if let Some(lsn) = first_column(&existing)? {
+        info!("Using replication slot {}", slot_name);
+        return lsn.parse();
+    }
+
+    let created = connection
+        .simple_query(&format!(
+            "CREATE_REPLICATION_SLOT {} LOGICAL pgoutput NOEXPORT_SNAPSHOT",
+            slot_name
+        ))
+        .await
+        .with_context(|| format!("Failed to create replication slot {}", slot_name))?;
+    let lsn = created
+        .iter()
+        .find_map(|row| row.named("consistent_point"))
+        .context("CREATE_REPLICATION_SLOT returned no consistent point")?;
+    info!("Created replication slot {} at {}", slot_name, lsn);
+    lsn.parse()
+}
+
+fn first_column(rows: &[Row]) -> Result<Option<&str>> {
+    match rows.first().map(|row| row.get(0)) {
+        // A slot that has never confirmed anything reports NULL
+        Some(None) => bail!("Replication slot exists but has no confirmed flush LSN"),
+        Some(lsn) => Ok(lsn),
+        None => Ok(None),
+    }
+}
+
+/// Read `SLOT_NAME`, which Postgres limits to lowercase letters, digits, and underscores
+fn slot_name() -> Result<String> {
+    let name = std::env::var("SLOT_NAME").unwrap_or_else(|_| DEFAULT_SLOT_NAME.to_string());
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+    if !valid {
+        bail!(
+            "SLOT_NAME may only contain lowercase letters, digits, and underscores, got {:?}",
+            name
+        );
+    }
+    Ok(name)
+}
+
+fn positive_env(name: &str, default: u64) -> Result<u64> {
+    let value = match std::env::var(name) {
+        Ok(value) => value
+            .trim()
+            .parse::<u64>()
+            .with_context(|| format!("{} must be a positive integer, got {:?}", name, value))?,
+        Err(_) => default,
+    };
+    if value == 0 {
+        bail!("{} must be a positive integer, got 0", name);
+    }
+    Ok(value)
+}
+
+/// Current time in microseconds since Unix epoch
+fn now_micros() -> Result<i64> {
+    Ok(SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .context("Failed to get system time")?
+        .as_micros() as i64)
+}
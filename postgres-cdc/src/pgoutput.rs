@@ -0,0 +1,399 @@
+//! Decoding of `pgoutput` logical replication messages (protocol version 1).
+//!
+//! See <https://www.postgresql.org/docs/current/protocol-logicalrep-message-formats.html>.
+
+use anyhow::{bail, Context, Result};
+
+use crate::lsn::Lsn;
+
+/// Microseconds between the Unix epoch and the Postgres epoch (2000-01-01)
+const POSTGRES_EPOCH_MICROS: i64 = 946_684_800_000_000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LogicalMessage {
+    Begin(Begin),
+    Commit(Commit),
+    Relation(Relation),
+    Insert {
+        relation_id: u32,
+        new: Vec<TupleValue>,
+    },
+    Update {
+        relation_id: u32,
+        /// The replica identity columns (`K`) or the whole old row (`O`), when sent
+        old: Option<Vec<TupleValue>>,
+        new: Vec<TupleValue>,
+    },
+    Delete {
+        relation_id: u32,
+        old: Vec<TupleValue>,
+    },
+    Truncate {
+        relation_ids: Vec<u32>,
+    },
+    /// Origin, type, and logical decoding messages, which carry no row changes
+    Other(u8),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Begin {
+    /// LSN of the commit record of the transaction
+    pub final_lsn: Lsn,
+    /// Commit time, microseconds since Unix epoch
+    pub commit_ts: i64,
+    pub xid: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Commit {
+    pub commit_lsn: Lsn,
+    /// LSN just past the commit record; confirming it acknowledges the transaction
+    pub end_lsn: Lsn,
+    /// Commit time, microseconds since Unix epoch
+    pub commit_ts: i64,
+}
+
+/// A table's schema, sent before its first change in each session and after DDL
+#[derive(Debug, Clone, PartialEq)]
+pub struct Relation {
+    pub id: u32,
+    pub namespace: String,
+    pub name: String,
+    pub columns: Vec<Column>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Column {
+    pub name: String,
+    pub type_oid: u32,
+    /// Part of the replica identity (usually the primary key)
+    pub is_key: bool,
+}
+
+/// One column of a row
+#[derive(Debug, Clone, PartialEq)]
+pub enum TupleValue {
+    Null,
+    /// A TOASTed value that the update did not change, so it was not sent
+    Unchanged,
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// Decode one `pgoutput` message
+pub fn decode(message: &[u8]) -> Result<LogicalMessage> {
+    let mut buf = Buf(message);
+    let tag = buf.u8()?;
+    let decoded = match tag {
+        b'B' => LogicalMessage::Begin(Begin {
+            final_lsn: Lsn(buf.u64()?),
+            commit_ts: timestamp(buf.i64()?),
+            xid: buf.u32()?,
+        }),
+        b'C' => {
+            let _flags = buf.u8()?;
+            LogicalMessage::Commit(Commit {
+                commit_lsn: Lsn(buf.u64()?),
+                end_lsn: Lsn(buf.u64()?),
+                commit_ts: timestamp(buf.i64()?),
+            })
+        }
+        b'R' => {
+            let id = buf.u32()?;
+            let namespace = buf.cstring()?;
+            let name = buf.cstring()?;
+            let _replica_identity = buf.u8()?;
+            let count = buf.u16()?;
+            let columns = (0..count)
+                .map(|_| {
+                    let flags = buf.u8()?;
+                    let name = buf.cstring()?;
+                    let type_oid = buf.u32()?;
+                    let _type_modifier = buf.u32()?;
+                    Ok(Column {
+                        name,
+                        type_oid,
+                        is_key: flags & 1 != 0,
+                    })
+                })
+                .collect::<Result<_>>()?;
+            LogicalMessage::Relation(Relation {
+                id,
+                namespace,
+                name,
+                columns,
+            })
+        }
+        b'I' => {
+            let relation_id = buf.u32()?;
+            buf.expect(b'N')?;
+            LogicalMessage::Insert {
+                relation_id,
+                new: buf.tuple()?,
+            }
+        }
+        b'U' => {
+            let relation_id = buf.u32()?;
+            let old = match buf.u8()? {
+                b'K' | b'O' => {
+                    let old = buf.tuple()?;
+                    buf.expect(b'N')?;
+                    Some(old)
+                }
+                b'N' => None,
+                other => bail!("Unexpected tuple type {:?} in update", other as char),
+            };
+            LogicalMessage::Update {
+                relation_id,
+                old,
+                new: buf.tuple()?,
+            }
+        }
+        b'D' => {
+            let relation_id = buf.u32()?;
+            match buf.u8()? {
+                b'K' | b'O' => {}
+                other => bail!("Unexpected tuple type {:?} in delete", other as char),
+            }
+            LogicalMessage::Delete {
+                relation_id,
+                old: buf.tuple()?,
+            }
+        }
+        b'T' => {
+            let count = buf.u32()?;
+            let _options = buf.u8()?;
+            LogicalMessage::Truncate {
+                relation_ids: (0..count).map(|_| buf.u32()).collect::<Result<_>>()?,
+            }
+        }
+        b'O' | b'Y' | b'M' => return Ok(LogicalMessage::Other(tag)),
+        other => bail!("Unknown pgoutput message type {:?}", other as char),
+    };
+    if !buf.0.is_empty() {
+        bail!(
+            "{} trailing bytes after {:?} message",
+            buf.0.len(),
+            tag as char
+        );
+    }
+    Ok(decoded)
+}
+
+fn timestamp(postgres_micros: i64) -> i64 {
+    postgres_micros + POSTGRES_EPOCH_MICROS
+}
+
+/// Big-endian reader over a message
+struct Buf<'a>(&'a [u8]);
+
+impl<'a> Buf<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.0.len() < n {
+            bail!(
+                "Message ends early: needed {} bytes, {} left",
+                n,
+                self.0.len()
+            );
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into()?))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into()?))
+    }
+
+    fn i64(&mut self) -> Result<i64> {
+        Ok(i64::from_be_bytes(self.take(8)?.try_into()?))
+    }
+
+    fn expect(&mut self, tag: u8) -> Result<()> {
+        match self.u8()? {
+            found if found == tag => Ok(()),
+            found => bail!("Expected {:?}, found {:?}", tag as char, found as char),
+        }
+    }
+
+    fn cstring(&mut self) -> Result<String> {
+        let end = self
+            .0
+            .iter()
+            .position(|b| *b == 0)
+            .context("Unterminated string")?;
+        let s = std::str::from_utf8(self.take(end)?).context("String is not valid UTF-8")?;
+        self.take(1)?;
+        Ok(s.to_string())
+    }
+
+    fn tuple(&mut self) -> Result<Vec<TupleValue>> {
+        let count = self.u16()?;
+        (0..count)
+            .map(|_| {
+                Ok(match self.u8()? {
+                    b'n' => TupleValue::Null,
+                    b'u' => TupleValue::Unchanged,
+                    b't' => {
+                        let len = self.u32()? as usize;
+                        let text = std::str::from_utf8(self.take(len)?)
+                            .context("Text value is not valid UTF-8")?;
+                        TupleValue::Text(text.to_string())
+                    }
+                    b'b' => {
+                        let len = self.u32()? as usize;
+                        TupleValue::Binary(self.take(len)?.to_vec())
+                    }
+                    other => bail!("Unknown tuple value kind {:?}", other as char),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Messages of a captured fixture: `<lsn> <xid> <hex>` lines, `#` comments
+#[cfg(test)]
+pub(crate) fn fixture_messages(fixture: &str) -> Vec<(Lsn, Vec<u8>)> {
+    fixture
+        .lines()
+        .filter(|line| !line.starts_with('#') && !line.trim().is_empty())
+        .map(|line| {
+            let mut parts = line.split(' ');
+            let lsn = parts.next().unwrap().parse().unwrap();
+            let hex = parts.nth(1).unwrap();
+            let bytes = (0..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+                .collect();
+            (lsn, bytes)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURE: &str = include_str!("../tests/fixtures/changes.wal");
+
+    fn messages() -> Vec<LogicalMessage> {
+        fixture_messages(FIXTURE)
+            .iter()
+            .map(|(_, bytes)| decode(bytes).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_transaction_and_relation() {
+        let messages = messages();
+
+        let LogicalMessage::Begin(begin) = &messages[0] else {
+            panic!("expected begin, got {:?}", messages[0]);
+        };
+        assert_eq!(726, begin.xid);
+        assert_eq!("0/1527DA0", begin.final_lsn.to_string());
+
+        let LogicalMessage::Relation(relation) = &messages[1] else {
+            panic!("expected relation, got {:?}", messages[1]);
+        };
+        assert_eq!(
+            ("public", "orders"),
+            (relation.namespace.as_str(), relation.name.as_str())
+        );
+        let columns: Vec<(&str, u32, bool)> = relation
+            .columns
+            .iter()
+            .map(|c| (c.name.as_str(), c.type_oid, c.is_key))
+            .collect();
+        assert_eq!(("id", 20, true), columns[0]);
+        assert_eq!(("price", 1700, false), columns[3]);
+        assert_eq!(("blob", 17, false), columns[11]);
+
+        let LogicalMessage::Commit(commit) = &messages[4] else {
+            panic!("expected commit, got {:?}", messages[4]);
+        };
+        assert_eq!(begin.final_lsn, commit.commit_lsn);
+        assert_eq!("0/1527DD0", commit.end_lsn.to_string());
+        assert_eq!(begin.commit_ts, commit.commit_ts);
+        // Captured in 2026
+        assert!(commit.commit_ts > 1_767_225_600_000_000);
+    }
+
+    #[test]
+    fn test_tuples() {
+        let messages = messages();
+
+        let LogicalMessage::Insert { new, .. } = &messages[2] else {
+            panic!("expected insert, got {:?}", messages[2]);
+        };
+        assert_eq!(12, new.len());
+        assert_eq!(TupleValue::Text("alice".to_string()), new[1]);
+        assert_eq!(TupleValue::Text("19.99".to_string()), new[3]);
+        assert_eq!(TupleValue::Text("\\xdeadbeef".to_string()), new[11]);
+
+        let LogicalMessage::Insert { new, .. } = &messages[3] else {
+            panic!("expected insert, got {:?}", messages[3]);
+        };
+        assert_eq!(TupleValue::Text("2".to_string()), new[0]);
+        assert!(new[1..].iter().all(|value| *value == TupleValue::Null));
+
+        // Changing the key sends the old key
+        let LogicalMessage::Update { old, new, .. } = &messages[9] else {
+            panic!("expected update, got {:?}", messages[9]);
+        };
+        let old = old.as_ref().unwrap();
+        assert_eq!(TupleValue::Text("2".to_string()), old[0]);
+        assert_eq!(TupleValue::Text("20".to_string()), new[0]);
+
+        let LogicalMessage::Delete { old, .. } = &messages[12] else {
+            panic!("expected delete, got {:?}", messages[12]);
+        };
+        assert_eq!(TupleValue::Text("20".to_string()), old[0]);
+    }
+
+    #[test]
+    fn test_unchanged_toast_and_full_identity() {
+        let messages = messages();
+
+        // An update that leaves a TOASTed column alone does not send it
+        let LogicalMessage::Update { old, new, .. } = &messages[19] else {
+            panic!("expected update, got {:?}", messages[19]);
+        };
+        assert_eq!(None, *old);
+        assert_eq!(TupleValue::Text("final".to_string()), new[1]);
+        assert_eq!(TupleValue::Unchanged, new[2]);
+
+        // With REPLICA IDENTITY FULL the whole old row is sent
+        let LogicalMessage::Update { old, .. } = &messages[23] else {
+            panic!("expected update, got {:?}", messages[23]);
+        };
+        let old = old.as_ref().unwrap();
+        assert_eq!(TupleValue::Text("final".to_string()), old[1]);
+        assert!(matches!(&old[2], TupleValue::Text(body) if body.len() == 3000));
+
+        assert!(matches!(
+            &messages[27],
+            LogicalMessage::Truncate { relation_ids } if relation_ids == &[0x4008]
+        ));
+    }
+
+    #[test]
+    fn test_truncated_message() {
+        let (_, bytes) = &fixture_messages(FIXTURE)[2];
+
+        let error = decode(&bytes[..bytes.len() - 3]).unwrap_err();
+        assert!(error.to_string().contains("ends early"));
+    }
+}
@@ -0,0 +1,110 @@
+//! Framing of the streaming replication protocol carried over `COPY BOTH`.
+//!
+//! See <https://www.postgresql.org/docs/current/protocol-replication.html>.
+
+use anyhow::{bail, Result};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::lsn::Lsn;
+
+/// Seconds between the Unix epoch and the Postgres epoch (2000-01-01)
+const POSTGRES_EPOCH_SECS: u64 = 946_684_800;
+
+/// A message from the server
+#[derive(Debug, PartialEq)]
+pub enum ServerMessage<'a> {
+    /// WAL data, here one `pgoutput` message starting at `wal_start`
+    XLogData { wal_start: Lsn, data: &'a [u8] },
+    /// The server's decoding position, sent when idle and on `wal_sender_timeout`
+    Keepalive { wal_end: Lsn, reply_requested: bool },
+}
+
+pub fn parse_server_message(message: &[u8]) -> Result<ServerMessage<'_>> {
+    let read_u64 = |at: usize| -> Result<u64> {
+        match message.get(at..at + 8) {
+            Some(bytes) => Ok(u64::from_be_bytes(bytes.try_into()?)),
+            None => bail!("Replication message of {} bytes ends early", message.len()),
+        }
+    };
+    match message.first() {
+        // w, wal start, wal end, send time, data
+        Some(b'w') if message.len() >= 25 => Ok(ServerMessage::XLogData {
+            wal_start: Lsn(read_u64(1)?),
+            data: &message[25..],
+        }),
+        // k, wal end, send time, reply requested
+        Some(b'k') if message.len() == 18 => Ok(ServerMessage::Keepalive {
+            wal_end: Lsn(read_u64(1)?),
+            reply_requested: message[17] == 1,
+        }),
+        Some(tag) => bail!(
+            "Unexpected replication message {:?} of {} bytes",
+            *tag as char,
+            message.len()
+        ),
+        None => bail!("Empty replication message"),
+    }
+}
+
+/// Standby status update confirming `flushed` as written, flushed, and applied
+pub fn standby_status_update(flushed: Lsn, now: SystemTime) -> Vec<u8> {
+    let since_postgres_epoch = now
+        .duration_since(UNIX_EPOCH + Duration::from_secs(POSTGRES_EPOCH_SECS))
+        .unwrap_or_default();
+
+    let mut update = Vec::with_capacity(34);
+    update.push(b'r');
+    for _ in 0..3 {
+        update.extend_from_slice(&flushed.0.to_be_bytes());
+    }
+    update.extend_from_slice(&(since_postgres_epoch.as_micros() as i64).to_be_bytes());
+    // Do not ask the server to reply
+    update.push(0);
+    update
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_server_messages() {
+        let mut xlog = vec![b'w'];
+        xlog.extend_from_slice(&0x1527BC8u64.to_be_bytes());
+        xlog.extend_from_slice(&0x1527DD0u64.to_be_bytes());
+        xlog.extend_from_slice(&0i64.to_be_bytes());
+        xlog.extend_from_slice(b"B...");
+        assert_eq!(
+            ServerMessage::XLogData {
+                wal_start: Lsn(0x1527BC8),
+                data: b"B...",
+            },
+            parse_server_message(&xlog).unwrap()
+        );
+
+        let mut keepalive = vec![b'k'];
+        keepalive.extend_from_slice(&0x1527DD0u64.to_be_bytes());
+        keepalive.extend_from_slice(&0i64.to_be_bytes());
+        keepalive.push(1);
+        assert_eq!(
+            ServerMessage::Keepalive {
+                wal_end: Lsn(0x1527DD0),
+                reply_requested: true,
+            },
+            parse_server_message(&keepalive).unwrap()
+        );
+
+        assert!(parse_server_message(&keepalive[..10]).is_err());
+    }
+
+    #[test]
+    fn test_standby_status_update() {
+        let now = UNIX_EPOCH + Duration::from_secs(POSTGRES_EPOCH_SECS + 1);
+        let update = standby_status_update(Lsn(0x1527DD0), now);
+
+        assert_eq!(34, update.len());
+        assert_eq!(b'r', update[0]);
+        assert_eq!(0x1527DD0u64.to_be_bytes(), update[17..25]);
+        assert_eq!(1_000_000i64.to_be_bytes(), update[25..33]);
+    }
+}
@@ -0,0 +1,135 @@
+//! Conversion of `pgoutput` text-format column values to JSON.
+
+use base64::{engine::general_purpose, Engine as _};
+use serde_json::{Map, Number, Value};
+
+use crate::pgoutput::{Column, TupleValue};
+
+const BOOL: u32 = 16;
+const BYTEA: u32 = 17;
+const INT8: u32 = 20;
+const INT2: u32 = 21;
+const INT4: u32 = 23;
+const OID: u32 = 26;
+const JSON: u32 = 114;
+const FLOAT4: u32 = 700;
+const FLOAT8: u32 = 701;
+const JSONB: u32 = 3802;
+
+/// Build a JSON object for a row, keyed by column name
+///
+/// Unchanged TOASTed columns are left out, since their value was not sent.
+pub fn row_to_json(columns: &[Column], values: &[TupleValue]) -> Value {
+    let row: Map<String, Value> = columns
+        .iter()
+        .zip(values)
+        .filter_map(|(column, value)| {
+            let value = match value {
+                TupleValue::Null => Value::Null,
+                TupleValue::Unchanged => return None,
+                TupleValue::Text(text) => text_to_json(column.type_oid, text),
+                TupleValue::Binary(bytes) => Value::String(general_purpose::STANDARD.encode(bytes)),
+            };
+            Some((column.name.clone(), value))
+        })
+        .collect();
+    Value::Object(row)
+}
+
+/// Convert a value in Postgres text output format
+///
+/// Booleans, integers, and floats become JSON scalars and JSON columns are embedded.
+/// `bytea` becomes base64. Everything else, including `numeric` (to keep its precision),
+/// timestamps, and arrays, stays in Postgres' text form.
+pub fn text_to_json(type_oid: u32, text: &str) -> Value {
+    let converted = match type_oid {
+        BOOL => Some(Value::Bool(text == "t")),
+        INT2 | INT4 | INT8 | OID => text.parse::<i64>().ok().map(Value::from),
+        // NaN and Infinity have no JSON number, so they fall through as strings
+        FLOAT4 | FLOAT8 => text
+            .parse::<f64>()
+            .ok()
+            .and_then(Number::from_f64)
+            .map(Value::Number),
+        JSON | JSONB => serde_json::from_str(text).ok(),
+        BYTEA => text
+            .strip_prefix("\\x")
+            .and_then(decode_hex)
+            .map(|bytes| Value::String(general_purpose::STANDARD.encode(bytes))),
+        _ => None,
+    };
+    converted.unwrap_or_else(|| Value::String(text.to_string()))
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pgoutput::{decode, fixture_messages, LogicalMessage};
+    use serde_json::json;
+
+    #[test]
+    fn test_fixture_row_types() {
+        let messages: Vec<LogicalMessage> =
+            fixture_messages(include_str!("../tests/fixtures/changes.wal"))
+                .iter()
+                .map(|(_, bytes)| decode(bytes).unwrap())
+                .collect();
+        let (LogicalMessage::Relation(relation), LogicalMessage::Insert { new, .. }) =
+            (&messages[1], &messages[2])
+        else {
+            panic!("expected a relation then an insert");
+        };
+
+        assert_eq!(
+            json!({
+                "id": 1,
+                "customer": "alice",
+                "quantity": 3,
+                "price": "19.99",
+                "shipped": false,
+                "weight": 1.5,
+                "tags": "{a,b}",
+                "details": {"gift": true},
+                "placed_at": "2024-03-01 12:34:56.789+00",
+                "ship_date": "2024-03-02",
+                "token": "a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a11",
+                "blob": "3q2+7w==",
+            }),
+            row_to_json(&relation.columns, new)
+        );
+    }
+
+    #[test]
+    fn test_special_values() {
+        assert_eq!(json!("NaN"), text_to_json(FLOAT8, "NaN"));
+        assert_eq!(json!("-Infinity"), text_to_json(FLOAT4, "-Infinity"));
+        assert_eq!(json!("\\xzz"), text_to_json(BYTEA, "\\xzz"));
+
+        let columns = [
+            Column {
+                name: "id".to_string(),
+                type_oid: INT4,
+                is_key: true,
+            },
+            Column {
+                name: "body".to_string(),
+                type_oid: 25,
+                is_key: false,
+            },
+        ];
+        assert_eq!(
+            json!({"id": null}),
+            row_to_json(&columns, &[TupleValue::Null, TupleValue::Unchanged])
+        );
+    }
+}
@@ -0,0 +1,9 @@
+fn main() -> std::io::Result<()> {
+    // The remote-write wire format is fixed by Prometheus, so it is compiled here with
+    // prost-build rather than generated from a Unity Catalog table with buf. A bundled
+    // protoc keeps the build from depending on one being installed.
+    let protoc = protoc_bin_vendored::protoc_bin_path().expect("bundled protoc not available");
+    std::env::set_var("PROTOC", protoc);
+
+    prost_build::compile_protos(&["remote/remote.proto"], &["remote/"])
+}
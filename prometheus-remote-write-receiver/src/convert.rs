@@ -0,0 +1,276 @@
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+
+use crate::proto::prometheus_samples::TablePrometheusSamples;
+use crate::proto::remote::{Label, TimeSeries, WriteRequest};
+
+/// Bit pattern Prometheus uses for staleness markers (`value.StaleNaN`)
+///
+/// A staleness marker is written when a series disappears from a scrape. It is a NaN,
+/// so it has to be recognised by its exact bits: ordinary NaN sample values are
+/// legitimate and are kept as-is.
+pub const STALE_NAN_BITS: u64 = 0x7ff0_0000_0000_0002;
+
+/// Label holding the metric name
+const METRIC_NAME_LABEL: &str = "__name__";
+
+/// Whether a sample value is a staleness marker rather than a measurement
+pub fn is_stale_marker(value: f64) -> bool {
+    value.to_bits() == STALE_NAN_BITS
+}
+
+/// What a row was produced from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleType {
+    Sample,
+    Exemplar,
+}
+
+impl SampleType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SampleType::Sample => "sample",
+            SampleType::Exemplar => "exemplar",
+        }
+    }
+}
+
+/// Remote-write timestamps are milliseconds; TIMESTAMP columns are microseconds
+fn millis_to_micros(millis: i64) -> Result<i64> {
+    millis
+        .checked_mul(1000)
+        .with_context(|| format!("Timestamp {} is out of range", millis))
+}
+
+fn label_map(labels: &[Label]) -> BTreeMap<String, String> {
+    labels
+        .iter()
+        .map(|label| (label.name.clone(), label.value.clone()))
+        .collect()
+}
+
+/// Split a series' labels into its metric name and the remaining labels
+fn series_identity(series: &TimeSeries) -> Result<(String, BTreeMap<String, String>)> {
+    let mut labels = label_map(&series.labels);
+    match labels.remove(METRIC_NAME_LABEL) {
+        Some(name) if !name.is_empty() => Ok((name, labels)),
+        _ => bail!("Time series has no {} label", METRIC_NAME_LABEL),
+    }
+}
+
+/// Convert every sample and exemplar in a write request into table rows
+///
+/// Fails if any series is invalid, so a request is either converted completely or
+/// rejected before anything is ingested.
+pub fn to_table_rows(
+    request: &WriteRequest,
+    ingested_at: i64,
+    ingested_date: i32,
+) -> Result<Vec<TablePrometheusSamples>> {
+    let mut rows = Vec::new();
+
+    for series in &request.timeseries {
+        let (metric_name, labels) = series_identity(series)?;
+
+        for sample in &series.samples {
+            let is_stale = is_stale_marker(sample.value);
+            rows.push(TablePrometheusSamples {
+                metric_name: Some(metric_name.clone()),
+                labels: labels.clone(),
+                timestamp: Some(millis_to_micros(sample.timestamp)?),
+                // A staleness marker carries no measurement
+                value: (!is_stale).then_some(sample.value),
+                is_stale: Some(is_stale),
+                sample_type: Some(SampleType::Sample.as_str().to_string()),
+                exemplar_labels: BTreeMap::new(),
+                ingested_at: Some(ingested_at),
+                ingested_date: Some(ingested_date),
+            });
+        }
+
+        // Exemplars are stored as their own rows next to the samples of the series
+        // they annotate, keeping their labels (e.g. trace_id) separate from the
+        // series labels
+        for exemplar in &series.exemplars {
+            rows.push(TablePrometheusSamples {
+                metric_name: Some(metric_name.clone()),
+                labels: labels.clone(),
+                timestamp: Some(millis_to_micros(exemplar.timestamp)?),
+                value: Some(exemplar.value),
+                is_stale: Some(false),
+                sample_type: Some(SampleType::Exemplar.as_str().to_string()),
+                exemplar_labels: label_map(&exemplar.labels),
+                ingested_at: Some(ingested_at),
+                ingested_date: Some(ingested_date),
+            });
+        }
+    }
+
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::remote::{Exemplar, Sample};
+
+    fn label(name: &str, value: &str) -> Label {
+        Label {
+            name: name.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    fn series(labels: Vec<Label>, samples: Vec<Sample>) -> TimeSeries {
+        TimeSeries {
+            labels,
+            samples,
+            exemplars: vec![],
+        }
+    }
+
+    fn request(timeseries: Vec<TimeSeries>) -> WriteRequest {
+        WriteRequest {
+            timeseries,
+            metadata: vec![],
+        }
+    }
+
+    #[test]
+    fn test_samples_become_rows() {
+        let request = request(vec![series(
+            vec![
+                label("__name__", "http_requests_total"),
+                label("job", "api"),
+                label("code", "200"),
+            ],
+            vec![
+                Sample {
+                    value: 41.0,
+                    timestamp: 1_700_000_000_000,
+                },
+                Sample {
+                    value: 42.0,
+                    timestamp: 1_700_000_015_000,
+                },
+            ],
+        )]);
+
+        let rows = to_table_rows(&request, 7, 19675).unwrap();
+        assert_eq!(2, rows.len());
+
+        let row = &rows[0];
+        assert_eq!(Some("http_requests_total".to_string()), row.metric_name);
+        // __name__ is removed and the remaining labels are sorted by name
+        assert_eq!(
+            vec!["code", "job"],
+            row.labels.keys().map(String::as_str).collect::<Vec<_>>()
+        );
+        assert_eq!(Some(1_700_000_000_000_000), row.timestamp);
+        assert_eq!(Some(41.0), row.value);
+        assert_eq!(Some(false), row.is_stale);
+        assert_eq!(Some("sample".to_string()), row.sample_type);
+        assert_eq!(Some(7), row.ingested_at);
+        assert_eq!(Some(19675), row.ingested_date);
+        assert_eq!(Some(42.0), rows[1].value);
+    }
+
+    #[test]
+    fn test_staleness_marker() {
+        let request = request(vec![series(
+            vec![label("__name__", "go_goroutines")],
+            vec![
+                Sample {
+                    value: f64::from_bits(STALE_NAN_BITS),
+                    timestamp: 1_700_000_000_000,
+                },
+                Sample {
+                    value: f64::NAN,
+                    timestamp: 1_700_000_015_000,
+                },
+            ],
+        )]);
+
+        let rows = to_table_rows(&request, 0, 0).unwrap();
+
+        assert_eq!(Some(true), rows[0].is_stale);
+        assert_eq!(None, rows[0].value);
+
+        // An ordinary NaN is a real value, not a staleness marker
+        assert_eq!(Some(false), rows[1].is_stale);
+        assert!(rows[1].value.unwrap().is_nan());
+    }
+
+    #[test]
+    fn test_exemplars_become_rows() {
+        let mut with_exemplar = series(
+            vec![
+                label("__name__", "request_duration_seconds_bucket"),
+                label("le", "0.5"),
+            ],
+            vec![Sample {
+                value: 12.0,
+                timestamp: 1_700_000_000_000,
+            }],
+        );
+        with_exemplar.exemplars.push(Exemplar {
+            labels: vec![label("trace_id", "4bf92f3577b34da6a3ce929d0e0e4736")],
+            value: 0.31,
+            timestamp: 1_699_999_999_250,
+        });
+
+        let rows = to_table_rows(&request(vec![with_exemplar]), 0, 0).unwrap();
+        assert_eq!(2, rows.len());
+
+        let exemplar = &rows[1];
+        assert_eq!(Some("exemplar".to_string()), exemplar.sample_type);
+        assert_eq!(
+            Some("request_duration_seconds_bucket".to_string()),
+            exemplar.metric_name
+        );
+        assert_eq!(Some(&"0.5".to_string()), exemplar.labels.get("le"));
+        assert_eq!(
+            Some(&"4bf92f3577b34da6a3ce929d0e0e4736".to_string()),
+            exemplar.exemplar_labels.get("trace_id")
+        );
+        assert_eq!(Some(0.31), exemplar.value);
+        assert_eq!(Some(1_699_999_999_250_000), exemplar.timestamp);
+        assert!(rows[0].exemplar_labels.is_empty());
+    }
+
+    #[test]
+    fn test_series_without_name_is_rejected() {
+        let request = request(vec![
+            series(
+                vec![label("__name__", "up")],
+                vec![Sample {
+                    value: 1.0,
+                    timestamp: 0,
+                }],
+            ),
+            series(
+                vec![label("job", "api")],
+                vec![Sample {
+                    value: 1.0,
+                    timestamp: 0,
+                }],
+            ),
+        ]);
+
+        let error = to_table_rows(&request, 0, 0).unwrap_err();
+        assert!(error.to_string().contains("__name__"));
+    }
+
+    #[test]
+    fn test_out_of_range_timestamp_is_rejected() {
+        let request = request(vec![series(
+            vec![label("__name__", "up")],
+            vec![Sample {
+                value: 1.0,
+                timestamp: i64::MAX,
+            }],
+        )]);
+
+        assert!(to_table_rows(&request, 0, 0).is_err());
+    }
+}
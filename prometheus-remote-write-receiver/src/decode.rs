@@ -0,0 +1,87 @@
+use anyhow::{bail, Context, Result};
+use prost::Message;
+
+use crate::proto::remote::WriteRequest;
+
+/// Largest decompressed request accepted; the length is read from the snappy header
+/// before anything is allocated
+pub const MAX_DECOMPRESSED_BYTES: usize = 32 * 1024 * 1024;
+
+/// Decode a remote-write request body: a snappy block (not framed) wrapping a
+/// protobuf `WriteRequest`
+pub fn decode_write_request(body: &[u8]) -> Result<WriteRequest> {
+    let len = snap::raw::decompress_len(body).context("Invalid snappy block header")?;
+    if len > MAX_DECOMPRESSED_BYTES {
+        bail!(
+            "Decompressed request is {} bytes, more than the {} byte limit",
+            len,
+            MAX_DECOMPRESSED_BYTES
+        );
+    }
+
+    let decompressed = snap::raw::Decoder::new()
+        .decompress_vec(body)
+        .context("Failed to decompress snappy body")?;
+
+    WriteRequest::decode(decompressed.as_slice()).context("Failed to decode WriteRequest")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CAPTURED_REQUEST: &[u8] = include_bytes!("../testdata/write_request.snappy");
+
+    #[test]
+    fn test_decode_captured_request() {
+        let request = decode_write_request(CAPTURED_REQUEST).unwrap();
+
+        assert_eq!(3, request.timeseries.len());
+        assert_eq!(1, request.metadata.len());
+        assert_eq!("up", request.metadata[0].metric_family_name);
+
+        let requests_total = &request.timeseries[1];
+        assert_eq!("__name__", requests_total.labels[0].name);
+        assert_eq!(
+            "prometheus_http_requests_total",
+            requests_total.labels[0].value
+        );
+        assert_eq!(2, requests_total.samples.len());
+        assert_eq!(1, requests_total.exemplars.len());
+    }
+
+    #[test]
+    fn test_decode_round_trip() {
+        let request = WriteRequest::default();
+        let body = snap::raw::Encoder::new()
+            .compress_vec(&request.encode_to_vec())
+            .unwrap();
+
+        assert_eq!(request, decode_write_request(&body).unwrap());
+    }
+
+    #[test]
+    fn test_decode_rejects_uncompressed_protobuf() {
+        // A common misconfiguration: a raw protobuf body without snappy
+        let body = snap::raw::Decoder::new()
+            .decompress_vec(CAPTURED_REQUEST)
+            .unwrap();
+        assert!(decode_write_request(&body).is_err());
+        assert!(decode_write_request(b"not snappy").is_err());
+    }
+
+    #[test]
+    fn test_decode_rejects_oversized_request() {
+        // Snappy header claiming a 64 MiB body
+        let mut body = Vec::new();
+        let mut len = 64 * 1024 * 1024u64;
+        while len >= 0x80 {
+            body.push((len as u8) | 0x80);
+            len >>= 7;
+        }
+        body.push(len as u8);
+
+        let error = decode_write_request(&body).unwrap_err();
+        assert!(error.to_string().contains("byte limit"));
+    }
+}
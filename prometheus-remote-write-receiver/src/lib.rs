@@ -0,0 +1,4 @@
+pub mod convert;
+pub mod decode;
+pub mod proto;
+pub mod server;
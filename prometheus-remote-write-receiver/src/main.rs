@@ -0,0 +1,93 @@
+use anyhow::{bail, Context, Result};
+use databricks_zerobus_ingest_sdk::{StreamConfigurationOptions, TableProperties, ZerobusSdk};
+use prometheus_remote_write_receiver::proto::load_descriptor_proto;
+use prometheus_remote_write_receiver::server::{router, AppState};
+use tracing::info;
+use zerobus_common::log_level;
+use zerobus_common::pipeline::Pipeline;
+use zerobus_common::shutdown;
+
+/// Maximum number of unacknowledged records per stream
+const MAX_INFLIGHT_RECORDS: usize = 10_000;
+
+/// Address to listen on when LISTEN_ADDR is not set
+const DEFAULT_LISTEN_ADDR: &str = "0.0.0.0:9201";
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let log_level = log_level::init()?;
+    tokio::spawn(log_level::on_hangup(log_level.clone()));
+
+    let zerobus_endpoint = std::env::var("ZEROBUS_ENDPOINT")
+        .context("ZEROBUS_ENDPOINT environment variable must be set")?;
+    let databricks_host = std::env::var("DATABRICKS_HOST")
+        .context("DATABRICKS_HOST environment variable must be set")?;
+    let client_id = std::env::var("DATABRICKS_CLIENT_ID")
+        .context("DATABRICKS_CLIENT_ID environment variable must be set")?;
+    let client_secret = std::env::var("DATABRICKS_CLIENT_SECRET")
+        .context("DATABRICKS_CLIENT_SECRET environment variable must be set")?;
+    let table_name =
+        std::env::var("TABLE_NAME").context("TABLE_NAME environment variable must be set")?;
+    let listen_addr =
+        std::env::var("LISTEN_ADDR").unwrap_or_else(|_| DEFAULT_LISTEN_ADDR.to_string());
+    let grace = shutdown::grace_from_env()?;
+
+    let sdk = ZerobusSdk::new(zerobus_endpoint, databricks_host)?;
+
+    let table_properties = TableProperties {
+        table_name: table_name.clone(),
+        descriptor_proto: load_descriptor_proto(
+            "prometheus_samples.proto",
+            "table_prometheus_samples",
+        ),
+    };
+    let stream_options = StreamConfigurationOptions {
+        max_inflight_records: MAX_INFLIGHT_RECORDS,
+        ..Default::default()
+    };
+    let stream = sdk
+        .create_stream(
+            table_properties,
+            client_id,
+            client_secret,
+            Some(stream_options),
+        )
+        .await
+        .context("Failed to create stream")?;
+    info!("Created stream to table: {}", table_name);
+
+    let state = AppState::new(Pipeline::new(stream, MAX_INFLIGHT_RECORDS));
+
+    let listener = tokio::net::TcpListener::bind(&listen_addr)
+        .await
+        .with_context(|| format!("Failed to bind {}", listen_addr))?;
+    info!(
+        "Listening for remote-write requests on http://{}/api/v1/write",
+        listen_addr
+    );
+
+    let mut app = router(state.clone());
+    if let Some(admin) = log_level::admin_routes_from_env(&log_level) {
+        app = app.merge(admin);
+    }
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown::signal())
+        .await?;
+
+    // Every handler has returned, so this is the last reference to the pipeline
+    if let Some(pipeline) = state.into_pipeline() {
+        let outcome = shutdown::drain(pipeline, grace).await?;
+        info!(
+            "Shut down after ingesting {} rows ({} failed)",
+            outcome.summary.ingested, outcome.summary.failed
+        );
+        if !outcome.unacked.is_empty() {
+            bail!(
+                "{} rows were not acknowledged before shutdown",
+                outcome.unacked.len()
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,19 @@
+use prost_types::DescriptorProto;
+
+// Module for generated protobuf code
+pub mod prometheus_samples {
+    include!("../gen/rust/prometheus_samples.rs");
+}
+
+/// Prometheus remote-write messages, compiled by build.rs
+pub mod remote {
+    include!(concat!(env!("OUT_DIR"), "/prometheus.rs"));
+}
+
+/// Load the protobuf descriptor from the embedded descriptor file
+pub fn load_descriptor_proto(file_name: &str, message_name: &str) -> DescriptorProto {
+    const DESCRIPTOR_BYTES: &[u8] =
+        include_bytes!("../gen/descriptors/prometheus_samples.descriptor");
+
+    zerobus_common::descriptor::load_descriptor_proto(DESCRIPTOR_BYTES, file_name, message_name)
+}
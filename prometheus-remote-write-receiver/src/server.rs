@@ -0,0 +1,233 @@
+use anyhow::{Context, Result};
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::header::CONTENT_ENCODING;
+use axum::http::{HeaderMap, StatusCode};
+use axum::routing::{get, post};
+use axum::Router;
+use prost::Message;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
+use zerobus_common::pipeline::{IngestSink, Pipeline};
+
+use crate::convert::to_table_rows;
+use crate::decode::decode_write_request;
+use crate::proto::prometheus_samples::TablePrometheusSamples;
+
+/// Shared handler state
+///
+/// Prometheus sends from several shards concurrently; requests take turns on the one
+/// stream so each response can report exactly whether that request's samples were
+/// acknowledged.
+pub struct AppState<S: IngestSink> {
+    pipeline: Arc<Mutex<Pipeline<S>>>,
+}
+
+impl<S: IngestSink> Clone for AppState<S> {
+    fn clone(&self) -> Self {
+        Self {
+            pipeline: Arc::clone(&self.pipeline),
+        }
+    }
+}
+
+impl<S: IngestSink> AppState<S> {
+    pub fn new(pipeline: Pipeline<S>) -> Self {
+        Self {
+            pipeline: Arc::new(Mutex::new(pipeline)),
+        }
+    }
+
+    /// Take the pipeline back once the server has stopped, so it can be finished
+    pub fn into_pipeline(self) -> Option<Pipeline<S>> {
+        Arc::try_unwrap(self.pipeline).ok().map(Mutex::into_inner)
+    }
+}
+
+/// Routes for the remote-write endpoint and a health check
+pub fn router<S: IngestSink + 'static>(state: AppState<S>) -> Router {
+    Router::new()
+        .route("/api/v1/write", post(write::<S>))
+        .route("/health", get(|| async { "OK" }))
+        .with_state(state)
+}
+
+/// Handle a remote-write request
+///
+/// Status codes follow the remote-write spec: 2xx once every sample is durable, 4xx
+/// for requests that will never succeed (Prometheus drops them), and 5xx for failures
+/// worth retrying (Prometheus resends the same batch with backoff).
+async fn write<S: IngestSink + 'static>(
+    State(state): State<AppState<S>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> (StatusCode, String) {
+    if let Some(encoding) = headers.get(CONTENT_ENCODING) {
+        if !encoding.as_bytes().eq_ignore_ascii_case(b"snappy") {
+            return (
+                StatusCode::BAD_REQUEST,
+                format!("Unsupported Content-Encoding: {:?}", encoding),
+            );
+        }
+    }
+
+    let request = match decode_write_request(&body) {
+        Ok(request) => request,
+        Err(e) => {
+            warn!("Rejecting undecodable write request: {:#}", e);
+            return (StatusCode::BAD_REQUEST, format!("{:#}", e));
+        }
+    };
+
+    let rows = match ingestion_time().and_then(|(ingested_at, ingested_date)| {
+        to_table_rows(&request, ingested_at, ingested_date)
+    }) {
+        Ok(rows) => rows,
+        Err(e) => {
+            warn!("Rejecting invalid write request: {:#}", e);
+            return (StatusCode::BAD_REQUEST, format!("{:#}", e));
+        }
+    };
+
+    let row_count = rows.len();
+    match ingest_rows(&state, rows).await {
+        Ok(()) => {
+            info!(
+                "Ingested {} rows from {} series",
+                row_count,
+                request.timeseries.len()
+            );
+            (StatusCode::OK, String::new())
+        }
+        Err(e) => {
+            error!("Failed to ingest write request: {:#}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e))
+        }
+    }
+}
+
+/// Current time as (microseconds, days) since Unix epoch
+fn ingestion_time() -> Result<(i64, i32)> {
+    let since_epoch = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .context("Failed to get system time")?;
+    Ok((
+        since_epoch.as_micros() as i64,
+        since_epoch.as_secs() as i32 / 86400,
+    ))
+}
+
+/// Ingest a request's rows and wait until all of them are acknowledged
+async fn ingest_rows<S: IngestSink>(
+    state: &AppState<S>,
+    rows: Vec<TablePrometheusSamples>,
+) -> Result<()> {
+    let mut pipeline = state.pipeline.lock().await;
+    pipeline
+        .ingest_batch(rows.iter().map(|row| row.encode_to_vec()))
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use axum::body::Body;
+    use axum::http::Request;
+    use tower::ServiceExt;
+    use zerobus_common::testing::MockSink;
+
+    const CAPTURED_REQUEST: &[u8] = include_bytes!("../testdata/write_request.snappy");
+
+    fn app(sink: MockSink) -> Router {
+        router(AppState::new(Pipeline::new(sink, 100)))
+    }
+
+    async fn post_write(app: Router, body: &'static [u8], encoding: &str) -> StatusCode {
+        let request = Request::post("/api/v1/write")
+            .header("Content-Type", "application/x-protobuf")
+            .header("Content-Encoding", encoding)
+            .header("X-Prometheus-Remote-Write-Version", "0.1.0")
+            .body(Body::from(body))
+            .unwrap();
+        app.oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn test_captured_request_is_ingested() {
+        let sink = MockSink::default();
+
+        let status = post_write(app(sink.clone()), CAPTURED_REQUEST, "snappy").await;
+
+        assert_eq!(StatusCode::OK, status);
+        let rows: Vec<_> = sink
+            .records()
+            .iter()
+            .map(|record| TablePrometheusSamples::decode(record.as_slice()).unwrap())
+            .collect();
+        // up, two requests_total samples and their exemplar, and a staleness marker
+        assert_eq!(5, rows.len());
+        assert_eq!(Some("up".to_string()), rows[0].metric_name);
+        assert_eq!(Some(&"prometheus".to_string()), rows[0].labels.get("job"));
+        assert_eq!(Some("exemplar".to_string()), rows[3].sample_type);
+        assert_eq!(Some("go_goroutines".to_string()), rows[4].metric_name);
+        assert_eq!(Some(true), rows[4].is_stale);
+        assert_eq!(None, rows[4].value);
+        assert_eq!(1, sink.flushes());
+    }
+
+    #[tokio::test]
+    async fn test_undecodable_body_is_bad_request() {
+        let sink = MockSink::default();
+
+        let status = post_write(app(sink.clone()), b"not a write request", "snappy").await;
+
+        assert_eq!(StatusCode::BAD_REQUEST, status);
+        assert!(sink.records().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_encoding_is_bad_request() {
+        let sink = MockSink::default();
+
+        let status = post_write(app(sink.clone()), CAPTURED_REQUEST, "gzip").await;
+
+        assert_eq!(StatusCode::BAD_REQUEST, status);
+        assert!(sink.records().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unacknowledged_samples_are_server_error() {
+        let sink = MockSink::default().fail_acks_for(|_| true);
+
+        let status = post_write(app(sink), CAPTURED_REQUEST, "snappy").await;
+
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, status);
+    }
+
+    #[tokio::test]
+    async fn test_stream_error_is_server_error() {
+        let sink = MockSink::default().fail_ingests_with(|_| Some(anyhow!("stream closed")));
+
+        let status = post_write(app(sink), CAPTURED_REQUEST, "snappy").await;
+
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, status);
+    }
+
+    #[tokio::test]
+    async fn test_failures_are_not_carried_into_the_next_request() {
+        let failing = Arc::new(std::sync::atomic::AtomicBool::new(true));
+        let should_fail = Arc::clone(&failing);
+        let sink = MockSink::default()
+            .fail_acks_for(move |_| should_fail.load(std::sync::atomic::Ordering::SeqCst));
+        let app = app(sink);
+
+        let status = post_write(app.clone(), CAPTURED_REQUEST, "snappy").await;
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, status);
+
+        failing.store(false, std::sync::atomic::Ordering::SeqCst);
+        let status = post_write(app, CAPTURED_REQUEST, "snappy").await;
+        assert_eq!(StatusCode::OK, status);
+    }
+}
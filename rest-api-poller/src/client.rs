@@ -0,0 +1,238 @@
+//! Requests to a source, rate limited and retried
+
+use anyhow::{bail, Context, Result};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, RETRY_AFTER};
+use reqwest::{Client, StatusCode, Url};
+use serde_json::Value;
+use std::time::{Duration, SystemTime};
+use tokio::time::Instant;
+use tracing::warn;
+use zerobus_common::rng::{OsRng, Rng};
+
+/// Wait before the first retry when the response says nothing; doubled per attempt, then
+/// jittered
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Longest wait between attempts, however long a `Retry-After` asks for
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Spaces requests at least `interval` apart
+#[derive(Debug)]
+pub struct RateLimiter {
+    interval: Duration,
+    next: Option<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(requests_per_second: Option<f64>) -> Self {
+        Self {
+            interval: requests_per_second
+                .map(|rate| Duration::from_secs_f64(1.0 / rate))
+                .unwrap_or_default(),
+            next: None,
+        }
+    }
+
+    /// Wait until the next request may be sent
+    pub async fn wait(&mut self) {
+        if let Some(next) = self.next {
+            tokio::time::sleep_until(next).await;
+        }
+        self.next = Some(Instant::now() + self.interval);
+    }
+}
+
+/// A successful response
+#[derive(Debug)]
+pub struct Page {
+    pub headers: HeaderMap,
+    pub body: Value,
+}
+
+/// Sends a source's requests with its headers, within its rate limit
+pub struct Fetcher {
+    client: Client,
+    headers: HeaderMap,
+    limiter: RateLimiter,
+    max_retries: u32,
+    rng: Box<dyn Rng>,
+}
+
+impl Fetcher {
+    pub fn new(
+        headers: impl IntoIterator<Item = (String, String)>,
+        requests_per_second: Option<f64>,
+        max_retries: u32,
+    ) -> Result<Self> {
+        let headers = headers
+            .into_iter()
+            .map(|(name, value)| {
+                let name = HeaderName::from_bytes(name.as_bytes())
+                    .with_context(|| format!("Invalid header name {:?}", name))?;
+                let mut value = HeaderValue::from_str(&value)
+                    .with_context(|| format!("Invalid value for header {}", name))?;
+                value.set_sensitive(true);
+                Ok((name, value))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            client: Client::new(),
+            headers,
+            limiter: RateLimiter::new(requests_per_second),
+            max_retries,
+            rng: Box::new(OsRng),
+        })
+    }
+
+    /// Draw the backoff jitter from `rng` rather than the operating system
+    pub fn rng(mut self, rng: impl Rng + 'static) -> Self {
+        self.rng = Box::new(rng);
+        self
+    }
+
+    /// GET `url` and parse the body as JSON
+    ///
+    /// 429 and 5xx responses and connection errors are retried up to `max_retries`
+    /// times, after the response's `Retry-After` if it has one and a jittered
+    /// exponential backoff otherwise. Other error statuses fail right away.
+    pub async fn get(&mut self, url: &Url) -> Result<Page> {
+        let mut attempt = 0;
+        loop {
+            self.limiter.wait().await;
+            let result = self
+                .client
+                .get(url.clone())
+                .headers(self.headers.clone())
+                .send()
+                .await;
+
+            let (retry_after, error) = match result {
+                Ok(response) if response.status().is_success() => {
+                    let headers = response.headers().clone();
+                    let body = response
+                        .json()
+                        .await
+                        .with_context(|| format!("{} did not return JSON", url))?;
+                    return Ok(Page { headers, body });
+                }
+                Ok(response) if retryable(response.status()) => (
+                    retry_after(response.headers(), SystemTime::now()),
+                    format!("{} answered {}", url, response.status()),
+                ),
+                Ok(response) => bail!("{} answered {}", url, response.status()),
+                Err(e) => (None, format!("Request to {} failed: {}", url, e)),
+            };
+
+            if attempt >= self.max_retries {
+                bail!("{}, giving up after {} retries", error, attempt);
+            }
+            let backoff = backoff(attempt, retry_after, self.rng.as_ref());
+            attempt += 1;
+            warn!(
+                "{}; retry {} of {} in {:?}",
+                error, attempt, self.max_retries, backoff
+            );
+            tokio::time::sleep(backoff).await;
+        }
+    }
+}
+
+/// How long to wait before retry `attempt`, counted from 0: as long as `Retry-After`
+/// asks, or an exponential backoff jittered by `rng` so sources failing together do not
+/// retry together
+fn backoff(attempt: u32, retry_after: Option<Duration>, rng: &dyn Rng) -> Duration {
+    retry_after
+        .unwrap_or_else(|| rng.jitter(INITIAL_BACKOFF * 2u32.saturating_pow(attempt)))
+        .min(MAX_BACKOFF)
+}
+
+fn retryable(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// How long a `Retry-After` header asks to wait, in seconds or as an HTTP date
+fn retry_after(headers: &HeaderMap, now: SystemTime) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(date.duration_since(now).unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use zerobus_common::rng::SeededRng;
+
+    #[test]
+    fn test_retry_after() {
+        let now = httpdate::parse_http_date("Mon, 10 Jun 2024 12:00:00 GMT").unwrap();
+        let header = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(RETRY_AFTER, value.parse().unwrap());
+            headers
+        };
+
+        assert_eq!(Some(Duration::from_secs(7)), retry_after(&header("7"), now));
+        assert_eq!(
+            Some(Duration::from_secs(90)),
+            retry_after(&header("Mon, 10 Jun 2024 12:01:30 GMT"), now)
+        );
+        // A date in the past means now
+        assert_eq!(
+            Some(Duration::ZERO),
+            retry_after(&header("Mon, 10 Jun 2024 11:00:00 GMT"), now)
+        );
+        assert_eq!(None, retry_after(&header("soon"), now));
+        assert_eq!(None, retry_after(&HeaderMap::new(), now));
+    }
+
+    #[test]
+    fn test_backoff_is_reproducible_under_a_seed() {
+        let backoffs = |seed| {
+            let rng = SeededRng::new(seed);
+            (0..12)
+                .map(|attempt| backoff(attempt, None, &rng))
+                .collect::<Vec<_>>()
+        };
+        let first = backoffs(42);
+        assert_eq!(first, backoffs(42));
+        assert_ne!(first, backoffs(7));
+
+        for (attempt, backoff) in first.iter().enumerate() {
+            let full = (INITIAL_BACKOFF * 2u32.pow(attempt as u32)).min(MAX_BACKOFF);
+            assert!(
+                (full / 2..=full).contains(backoff),
+                "attempt {}: {:?}",
+                attempt,
+                backoff
+            );
+        }
+        assert_eq!(MAX_BACKOFF, first[11]);
+
+        // Retry-After is waited as asked
+        let rng = SeededRng::new(42);
+        assert_eq!(
+            Duration::from_secs(7),
+            backoff(3, Some(Duration::from_secs(7)), &rng)
+        );
+        assert_eq!(
+            MAX_BACKOFF,
+            backoff(0, Some(Duration::from_secs(3600)), &rng)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate_limiter() {
+        let mut limiter = RateLimiter::new(Some(4.0));
+        let start = Instant::now();
+
+        for _ in 0..5 {
+            limiter.wait().await;
+        }
+
+        // The first request goes right away, the other four 250ms apart
+        assert_eq!(Duration::from_secs(1), start.elapsed());
+    }
+}
@@ -0,0 +1,284 @@
+//! Sources, read from the JSON file named by POLLER_CONFIG
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+
+/// Retries of a request answered 429 or 5xx when the source does not say
+const DEFAULT_MAX_RETRIES: u32 = 5;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Where each source's watermark is kept between runs
+    pub watermarks: WatermarkStoreConfig,
+    pub sources: Vec<SourceConfig>,
+}
+
+/// One API to poll: when, how to page through it, and where its items go
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SourceConfig {
+    /// Names the source in logs and keys its watermark
+    pub name: String,
+    /// Cron expression, with or without a leading seconds field, in UTC
+    pub schedule: String,
+    pub url: String,
+    /// Request headers; `${NAME}` is replaced by the environment variable `NAME`
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Query parameters sent with every request, templated like the headers
+    #[serde(default)]
+    pub query: BTreeMap<String, String>,
+    /// JSON pointer to the array of items in a response (default: the whole body)
+    #[serde(default)]
+    pub items_pointer: String,
+    #[serde(default)]
+    pub pagination: Pagination,
+    /// How far the last run got, so the next one only fetches newer items
+    #[serde(default)]
+    pub watermark: Option<Watermark>,
+    pub table: String,
+    pub descriptor_set: PathBuf,
+    /// Message in the descriptor set (default: `table_<table>`)
+    #[serde(default)]
+    pub message: Option<String>,
+    /// Columns taken from each item, as JSON pointers; without it the item's fields
+    /// are the columns
+    #[serde(default)]
+    pub fields: Option<BTreeMap<String, String>>,
+    /// Drop item fields that are not columns instead of failing the run
+    #[serde(default)]
+    pub ignore_unknown_fields: bool,
+    /// Requests per second sent to the source, unlimited when unset
+    #[serde(default)]
+    pub requests_per_second: Option<f64>,
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+}
+
+/// How the next page of a response is found
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(tag = "strategy", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum Pagination {
+    /// A single request returns everything
+    #[default]
+    None,
+    /// The response holds a cursor for the next page, sent back as a query parameter
+    Cursor {
+        /// JSON pointer to the cursor; a missing, null, or empty cursor ends the run
+        cursor_pointer: String,
+        param: String,
+    },
+    /// The response's `Link` header has the URL of the `rel="next"` page, as GitHub
+    /// and most REST APIs following RFC 8288 do
+    LinkHeader,
+    /// Pages are addressed by offset; a page shorter than `page_size` is the last
+    Offset {
+        offset_param: String,
+        limit_param: String,
+        page_size: u64,
+    },
+}
+
+/// What a source's watermark holds
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum Watermark {
+    /// The last page cursor, which the next run starts from; needs cursor pagination
+    Cursor,
+    /// The largest value of an item field, such as `updated_at`, sent to the source
+    /// as `param` so it only returns items past it
+    ///
+    /// Values are compared as numbers when both are numbers and as strings otherwise,
+    /// which orders ISO 8601 timestamps of one format correctly.
+    MaxValue { pointer: String, param: String },
+}
+
+/// Where watermarks are stored
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum WatermarkStoreConfig {
+    /// A JSON file mapping source names to watermarks
+    File(PathBuf),
+    /// A DynamoDB table keyed by a `source` string attribute, for pollers without
+    /// durable local disk
+    Dynamodb { table: String },
+}
+
+fn default_max_retries() -> u32 {
+    DEFAULT_MAX_RETRIES
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let json = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Self::parse(&json).with_context(|| format!("Invalid config {}", path.display()))
+    }
+
+    pub fn parse(json: &str) -> Result<Self> {
+        let config: Config = serde_json::from_str(json)?;
+        if config.sources.is_empty() {
+            bail!("No sources are configured");
+        }
+
+        let mut names = HashSet::new();
+        for source in &config.sources {
+            if !names.insert(source.name.as_str()) {
+                bail!("Source name {:?} is used twice", source.name);
+            }
+            let pointers = source
+                .fields
+                .iter()
+                .flat_map(|fields| fields.values())
+                .chain([&source.items_pointer]);
+            for pointer in pointers {
+                if !pointer.is_empty() && !pointer.starts_with('/') {
+                    bail!(
+                        "Source {:?}: {:?} is not a JSON pointer; they start with '/'",
+                        source.name,
+                        pointer
+                    );
+                }
+            }
+            match (&source.watermark, &source.pagination) {
+                (Some(Watermark::Cursor), Pagination::Cursor { .. }) => {}
+                (Some(Watermark::Cursor), _) => {
+                    bail!(
+                        "Source {:?}: a cursor watermark needs cursor pagination",
+                        source.name
+                    )
+                }
+                _ => {}
+            }
+            if let Pagination::Offset { page_size: 0, .. } = source.pagination {
+                bail!("Source {:?}: page_size must be positive", source.name);
+            }
+            if let Some(rate) = source.requests_per_second {
+                if !rate.is_finite() || rate <= 0.0 {
+                    bail!(
+                        "Source {:?}: requests_per_second must be positive",
+                        source.name
+                    );
+                }
+            }
+        }
+        Ok(config)
+    }
+}
+
+impl SourceConfig {
+    /// The message of the source's table in its descriptor set
+    pub fn message_name(&self) -> String {
+        self.message.clone().unwrap_or_else(|| {
+            format!(
+                "table_{}",
+                self.table.rsplit('.').next().unwrap_or(&self.table)
+            )
+        })
+    }
+}
+
+/// Replace each `${NAME}` in `template` with `lookup(NAME)`
+///
+/// Fails on a name `lookup` does not know, so a missing token is caught before the
+/// first request rather than sent as an empty header.
+pub fn render(template: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("${") {
+        rendered.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find('}') else {
+            bail!("Unclosed ${{ in {:?}", template);
+        };
+        let name = &rest[start + 2..start + end];
+        let value =
+            lookup(name).with_context(|| format!("{} environment variable must be set", name))?;
+        rendered.push_str(&value);
+        rest = &rest[start + end + 1..];
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+/// [`render`] with values from the environment
+pub fn render_env(template: &str) -> Result<String> {
+    render(template, |name| std::env::var(name).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXAMPLE: &str = include_str!("../config/sources.example.json");
+
+    #[test]
+    fn test_example_config() {
+        let config = Config::parse(EXAMPLE).unwrap();
+
+        assert_eq!(
+            WatermarkStoreConfig::File(PathBuf::from("state/watermarks.json")),
+            config.watermarks
+        );
+        let tickets = &config.sources[0];
+        assert_eq!("tickets", tickets.name);
+        assert_eq!(
+            Pagination::Cursor {
+                cursor_pointer: "/meta/after_cursor".to_string(),
+                param: "page[after]".to_string(),
+            },
+            tickets.pagination
+        );
+        assert_eq!(Some(Watermark::Cursor), tickets.watermark);
+        assert_eq!("table_support_tickets", tickets.message_name());
+        assert_eq!(5, tickets.max_retries);
+
+        assert_eq!(Pagination::LinkHeader, config.sources[1].pagination);
+        assert_eq!(
+            Some(Watermark::MaxValue {
+                pointer: "/updated_at".to_string(),
+                param: "since".to_string(),
+            }),
+            config.sources[1].watermark
+        );
+        assert!(matches!(
+            config.sources[2].pagination,
+            Pagination::Offset { page_size: 100, .. }
+        ));
+    }
+
+    #[test]
+    fn test_invalid_configs() {
+        let source = |extra: &str| {
+            format!(
+                r#"{{"watermarks": {{"file": "w.json"}}, "sources": [{{"name": "s", "schedule": "* * * * *", "url": "http://localhost", "table": "t", "descriptor_set": "d"{}}}]}}"#,
+                extra
+            )
+        };
+
+        Config::parse(&source("")).unwrap();
+        assert!(Config::parse(&source(r#", "items_pointer": "data""#)).is_err());
+        assert!(Config::parse(&source(r#", "watermark": {"kind": "cursor"}"#)).is_err());
+        assert!(Config::parse(&source(
+            r#", "pagination": {"strategy": "offset", "offset_param": "o", "limit_param": "l", "page_size": 0}"#
+        ))
+        .is_err());
+        assert!(Config::parse(&source(r#", "requests_per_second": 0"#)).is_err());
+        assert!(Config::parse(&source(r#", "pagination": {"strategy": "pages"}"#)).is_err());
+    }
+
+    #[test]
+    fn test_render() {
+        let lookup = |name: &str| (name == "API_TOKEN").then(|| "s3cret".to_string());
+
+        assert_eq!(
+            "Bearer s3cret",
+            render("Bearer ${API_TOKEN}", lookup).unwrap()
+        );
+        assert_eq!("plain", render("plain", lookup).unwrap());
+        assert!(render("Bearer ${MISSING}", lookup).is_err());
+        assert!(render("Bearer ${API_TOKEN", lookup).is_err());
+    }
+}
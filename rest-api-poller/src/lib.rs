@@ -0,0 +1,7 @@
+pub mod client;
+pub mod config;
+pub mod paginate;
+pub mod poller;
+pub mod schedule;
+pub mod transform;
+pub mod watermark;
@@ -0,0 +1,174 @@
+use anyhow::{bail, Context, Result};
+use databricks_zerobus_ingest_sdk::{StreamConfigurationOptions, TableProperties, ZerobusSdk};
+use rest_api_poller::config::Config;
+use rest_api_poller::poller::{run_scheduled, Source};
+use rest_api_poller::schedule::Schedule;
+use rest_api_poller::transform::Transform;
+use rest_api_poller::watermark::WatermarkStore;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::watch;
+use tracing::{error, info};
+use zerobus_common::descriptor::find_message_descriptor;
+use zerobus_common::dynamic::DynamicEncoder;
+use zerobus_common::metrics;
+use zerobus_common::pipeline::Pipeline;
+use zerobus_common::shutdown;
+use zerobus_common::stats::{self, CloudWatchStats, StatsReporter};
+
+/// Maximum number of unacknowledged records per stream
+const MAX_INFLIGHT_RECORDS: usize = 10_000;
+
+fn env(name: &str) -> Result<String> {
+    std::env::var(name).with_context(|| format!("{} environment variable must be set", name))
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .with_target(false)
+        .init();
+    let metrics = metrics::serve_from_env().await?;
+
+    let zerobus_endpoint = env("ZEROBUS_ENDPOINT")?;
+    let databricks_host = env("DATABRICKS_HOST")?;
+    let client_id = env("DATABRICKS_CLIENT_ID")?;
+    let client_secret = env("DATABRICKS_CLIENT_SECRET")?;
+    let config = Config::load(Path::new(&env("POLLER_CONFIG")?))?;
+    let run_once = std::env::var("RUN_ONCE")
+        .map(|value| value == "true" || value == "1")
+        .unwrap_or(false);
+
+    let sdk = ZerobusSdk::new(zerobus_endpoint, databricks_host)?;
+    let store = Arc::new(WatermarkStore::from_config(&config.watermarks).await);
+
+    // Sources often share a descriptor set, so each file is read once
+    let mut descriptor_sets: HashMap<PathBuf, Vec<u8>> = HashMap::new();
+    let mut sources = Vec::with_capacity(config.sources.len());
+    for source in &config.sources {
+        let schedule = Schedule::parse(&source.schedule)
+            .with_context(|| format!("Source {:?}", source.name))?;
+        if !descriptor_sets.contains_key(&source.descriptor_set) {
+            let bytes = std::fs::read(&source.descriptor_set).with_context(|| {
+                format!(
+                    "Failed to read descriptor set {}",
+                    source.descriptor_set.display()
+                )
+            })?;
+            descriptor_sets.insert(source.descriptor_set.clone(), bytes);
+        }
+        let descriptor = find_message_descriptor(
+            &descriptor_sets[&source.descriptor_set],
+            &source.message_name(),
+        )
+        .with_context(|| format!("Source {:?}", source.name))?;
+        let encoder =
+            DynamicEncoder::new(&descriptor)?.ignore_unknown_fields(source.ignore_unknown_fields);
+        let transform = Transform::new(encoder, source)?;
+
+        let table_properties = TableProperties {
+            table_name: source.table.clone(),
+            descriptor_proto: descriptor,
+        };
+        let stream_options = StreamConfigurationOptions {
+            max_inflight_records: MAX_INFLIGHT_RECORDS,
+            ..Default::default()
+        };
+        let stream = sdk
+            .create_stream(
+                table_properties,
+                client_id.clone(),
+                client_secret.clone(),
+                Some(stream_options),
+            )
+            .await
+            .with_context(|| format!("Failed to create stream to {}", source.table))?;
+        info!(
+            "Source {:?}: {} -> {} on {:?}",
+            source.name, source.url, source.table, source.schedule
+        );
+
+        let pipeline = Pipeline::new(stream, MAX_INFLIGHT_RECORDS);
+        let series = metrics.series(&source.table, &source.name);
+        series.mark_stream_opened();
+        sources.push((
+            Source::new(source, transform, pipeline)?.metrics(series),
+            schedule,
+        ));
+    }
+    metrics.set_cached_streams(sources.len());
+
+    let (stop_stats_sender, stop_stats) = watch::channel(false);
+    let stats_reporter = match stats::interval_from_env()? {
+        Some(interval) => Some(
+            StatsReporter::new(metrics.clone(), interval)
+                .publish_to(CloudWatchStats::from_env().await)
+                .spawn(stop_stats),
+        ),
+        None => None,
+    };
+
+    let (stop_sender, stop) = watch::channel(false);
+    tokio::spawn(async move {
+        shutdown::signal().await;
+        let _ = stop_sender.send(true);
+    });
+
+    let mut failed = 0;
+    let finished = if run_once {
+        let mut finished = Vec::with_capacity(sources.len());
+        for (mut source, _) in sources {
+            match source.run(&store, &stop).await {
+                Ok(summary) => info!(
+                    "{}: {} items from {} pages ({} skipped), watermark {:?}",
+                    source.name(),
+                    summary.items,
+                    summary.pages,
+                    summary.skipped,
+                    summary.watermark
+                ),
+                Err(e) => {
+                    error!("{}: run failed: {:#}", source.name(), e);
+                    failed += 1;
+                }
+            }
+            finished.push(source);
+        }
+        finished
+    } else {
+        let tasks: Vec<_> = sources
+            .into_iter()
+            .map(|(source, schedule)| {
+                let store = Arc::clone(&store);
+                let stop = stop.clone();
+                tokio::spawn(async move { run_scheduled(source, schedule, &store, stop).await })
+            })
+            .collect();
+        let mut finished = Vec::with_capacity(tasks.len());
+        for task in tasks {
+            finished.push(task.await.context("Source task panicked")?);
+        }
+        finished
+    };
+
+    // Every run waits for its rows to be acknowledged, so nothing is outstanding here
+    for source in finished {
+        let name = source.name().to_string();
+        let summary = source.finish().await?;
+        info!(
+            "{}: shut down after ingesting {} rows ({} failed)",
+            name, summary.ingested, summary.failed
+        );
+    }
+    let _ = stop_stats_sender.send(true);
+    if let Some(reporter) = stats_reporter {
+        let _ = reporter.await;
+    }
+    if failed > 0 {
+        bail!("{} sources failed", failed);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,247 @@
+//! Finding the next page of a response
+
+use anyhow::{Context, Result};
+use reqwest::header::{HeaderMap, LINK};
+use reqwest::Url;
+use serde_json::Value;
+
+use crate::client::Page;
+use crate::config::Pagination;
+
+/// Where the next page is, if there is one
+#[derive(Debug, Clone, PartialEq)]
+pub struct NextPage {
+    pub url: Url,
+    /// The cursor it was asked for with, under cursor pagination
+    pub cursor: Option<String>,
+}
+
+/// The URL of the first page, before any watermark is applied
+pub fn first_page(pagination: &Pagination, url: Url) -> Url {
+    match pagination {
+        Pagination::Offset {
+            offset_param,
+            limit_param,
+            page_size,
+        } => {
+            let url = with_param(url, limit_param, &page_size.to_string());
+            with_param(url, offset_param, "0")
+        }
+        _ => url,
+    }
+}
+
+/// The page after `page`, which was fetched from `url` and held `items` items
+pub fn next_page(
+    pagination: &Pagination,
+    url: &Url,
+    page: &Page,
+    items: usize,
+) -> Result<Option<NextPage>> {
+    match pagination {
+        Pagination::None => Ok(None),
+        Pagination::Cursor {
+            cursor_pointer,
+            param,
+        } => {
+            let cursor = match page.body.pointer(cursor_pointer) {
+                Some(Value::String(cursor)) if !cursor.is_empty() => cursor.clone(),
+                Some(Value::Number(cursor)) => cursor.to_string(),
+                _ => return Ok(None),
+            };
+            // Some APIs hand back the cursor they were asked with on the last page
+            if query_param(url, param).as_deref() == Some(cursor.as_str()) {
+                return Ok(None);
+            }
+            Ok(Some(NextPage {
+                url: with_param(url.clone(), param, &cursor),
+                cursor: Some(cursor),
+            }))
+        }
+        Pagination::LinkHeader => {
+            let Some(next) = next_link(&page.headers) else {
+                return Ok(None);
+            };
+            let url = url
+                .join(&next)
+                .with_context(|| format!("Invalid next page link {:?}", next))?;
+            Ok(Some(NextPage { url, cursor: None }))
+        }
+        Pagination::Offset {
+            offset_param,
+            page_size,
+            ..
+        } => {
+            if (items as u64) < *page_size {
+                return Ok(None);
+            }
+            let offset = query_param(url, offset_param)
+                .and_then(|offset| offset.parse::<u64>().ok())
+                .unwrap_or(0);
+            Ok(Some(NextPage {
+                url: with_param(url.clone(), offset_param, &(offset + page_size).to_string()),
+                cursor: None,
+            }))
+        }
+    }
+}
+
+/// `url` with `name` set to `value`, replacing any value it had
+pub fn with_param(mut url: Url, name: &str, value: &str) -> Url {
+    let pairs: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(key, _)| key != name)
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect();
+    url.query_pairs_mut()
+        .clear()
+        .extend_pairs(pairs)
+        .append_pair(name, value);
+    url
+}
+
+fn query_param(url: &Url, name: &str) -> Option<String> {
+    url.query_pairs()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+}
+
+/// The `rel="next"` target of a `Link` header, such as
+/// `<https://api.github.com/repositories/1/issues?page=2>; rel="next"`
+fn next_link(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get_all(LINK)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .find_map(|link| {
+            let (target, params) = link.trim().split_once(';')?;
+            let is_next = params.split(';').any(|param| {
+                param
+                    .trim()
+                    .strip_prefix("rel=")
+                    .map(|rel| {
+                        rel.trim_matches('"')
+                            .split_whitespace()
+                            .any(|r| r == "next")
+                    })
+                    .unwrap_or(false)
+            });
+            is_next.then(|| {
+                target
+                    .trim()
+                    .trim_start_matches('<')
+                    .trim_end_matches('>')
+                    .to_string()
+            })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn page(body: Value) -> Page {
+        Page {
+            headers: HeaderMap::new(),
+            body,
+        }
+    }
+
+    #[test]
+    fn test_cursor() {
+        let pagination = Pagination::Cursor {
+            cursor_pointer: "/meta/next".to_string(),
+            param: "after".to_string(),
+        };
+        let url = Url::parse("https://api.example.com/items?limit=10").unwrap();
+
+        let next = next_page(
+            &pagination,
+            &url,
+            &page(json!({"meta": {"next": "c2"}})),
+            10,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            "https://api.example.com/items?limit=10&after=c2",
+            next.url.as_str()
+        );
+        assert_eq!(Some("c2".to_string()), next.cursor);
+
+        // The same cursor again, or none at all, is the end
+        let last = page(json!({"meta": {"next": "c2"}}));
+        assert_eq!(None, next_page(&pagination, &next.url, &last, 3).unwrap());
+        let last = page(json!({"meta": {"next": null}}));
+        assert_eq!(None, next_page(&pagination, &next.url, &last, 3).unwrap());
+    }
+
+    #[test]
+    fn test_link_header() {
+        let url = Url::parse("https://api.github.com/repos/o/r/issues?per_page=2").unwrap();
+        let mut response = page(json!([]));
+        response.headers.insert(
+            LINK,
+            r#"<https://api.github.com/repositories/1/issues?per_page=2&page=3>; rel="next", <https://api.github.com/repositories/1/issues?per_page=2&page=9>; rel="last""#
+                .parse()
+                .unwrap(),
+        );
+
+        let next = next_page(&Pagination::LinkHeader, &url, &response, 2)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            "https://api.github.com/repositories/1/issues?per_page=2&page=3",
+            next.url.as_str()
+        );
+
+        // Relative links resolve against the page they came from
+        response
+            .headers
+            .insert(LINK, "</issues?page=4>; rel=\"next\"".parse().unwrap());
+        let next = next_page(&Pagination::LinkHeader, &url, &response, 2)
+            .unwrap()
+            .unwrap();
+        assert_eq!("https://api.github.com/issues?page=4", next.url.as_str());
+
+        response
+            .headers
+            .insert(LINK, "</issues?page=1>; rel=\"prev\"".parse().unwrap());
+        assert_eq!(
+            None,
+            next_page(&Pagination::LinkHeader, &url, &response, 2).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_offset() {
+        let pagination = Pagination::Offset {
+            offset_param: "offset".to_string(),
+            limit_param: "limit".to_string(),
+            page_size: 2,
+        };
+        let url = first_page(
+            &pagination,
+            Url::parse("https://api.example.com/rates").unwrap(),
+        );
+        assert_eq!(
+            "https://api.example.com/rates?limit=2&offset=0",
+            url.as_str()
+        );
+
+        let next = next_page(&pagination, &url, &page(json!([1, 2])), 2)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            "https://api.example.com/rates?limit=2&offset=2",
+            next.url.as_str()
+        );
+        // A short page is the last one
+        assert_eq!(
+            None,
+            next_page(&pagination, &next.url, &page(json!([3])), 1).unwrap()
+        );
+    }
+}
@@ -0,0 +1,665 @@
+//! Polling a source: one run pages through its API from the last watermark
+
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
+use reqwest::Url;
+use serde_json::Value;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::sync::watch;
+use tracing::{error, info, warn};
+use zerobus_common::metrics::Series;
+use zerobus_common::pipeline::{IngestSink, IngestSummary, Pipeline};
+
+use crate::client::{Fetcher, Page};
+use crate::config::{render_env, Pagination, SourceConfig, Watermark};
+use crate::paginate::{first_page, next_page, with_param};
+use crate::schedule::Schedule;
+use crate::transform::Transform;
+use crate::watermark::{self, WatermarkStore};
+
+/// What one run of a source did
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RunSummary {
+    pub pages: u64,
+    /// Items ingested and acknowledged
+    pub items: u64,
+    /// Items that are not rows of the table
+    pub skipped: u64,
+    /// The watermark the next run starts from
+    pub watermark: Option<String>,
+}
+
+/// A configured API and the stream to its table
+pub struct Source<S: IngestSink> {
+    name: String,
+    url: Url,
+    pagination: Pagination,
+    items_pointer: String,
+    watermark: Option<Watermark>,
+    fetcher: Fetcher,
+    transform: Transform,
+    pipeline: Pipeline<S>,
+    series: Option<Arc<Series>>,
+}
+
+impl<S: IngestSink> Source<S> {
+    /// Fails if a `${NAME}` in the URL, query, or headers is not in the environment
+    pub fn new(config: &SourceConfig, transform: Transform, pipeline: Pipeline<S>) -> Result<Self> {
+        let mut url = Url::parse(&render_env(&config.url)?)
+            .with_context(|| format!("Source {:?} has an invalid url", config.name))?;
+        for (name, value) in &config.query {
+            url = with_param(url, name, &render_env(value)?);
+        }
+        let headers = config
+            .headers
+            .iter()
+            .map(|(name, value)| Ok((name.clone(), render_env(value)?)))
+            .collect::<Result<Vec<_>>>()
+            .with_context(|| format!("Source {:?}", config.name))?;
+
+        Ok(Self {
+            name: config.name.clone(),
+            url,
+            pagination: config.pagination.clone(),
+            items_pointer: config.items_pointer.clone(),
+            watermark: config.watermark.clone(),
+            fetcher: Fetcher::new(headers, config.requests_per_second, config.max_retries)?,
+            transform,
+            pipeline,
+            series: None,
+        })
+    }
+
+    /// Record the source's pages, items, and acknowledgments in `series`
+    pub fn metrics(mut self, series: Arc<Series>) -> Self {
+        self.pipeline = self.pipeline.ack_observer(series.ack_observer());
+        self.series = Some(series);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Fetch every page past the stored watermark, ingest its items, and store the
+    /// new watermark
+    ///
+    /// Each page's rows are acknowledged before the next page is fetched, and the
+    /// watermark is only stored once the last page is, so a run that fails or is
+    /// stopped is repeated in full by the next one.
+    pub async fn run(
+        &mut self,
+        store: &WatermarkStore,
+        stop: &watch::Receiver<bool>,
+    ) -> Result<RunSummary> {
+        let previous = store.load(&self.name).await?;
+        let mut url = first_page(&self.pagination, self.url.clone());
+        match (&self.watermark, &self.pagination, &previous) {
+            (Some(Watermark::Cursor), Pagination::Cursor { param, .. }, Some(cursor)) => {
+                url = with_param(url, param, cursor);
+            }
+            (Some(Watermark::MaxValue { param, .. }), _, Some(value)) => {
+                url = with_param(url, param, value);
+            }
+            _ => {}
+        }
+
+        let mut summary = RunSummary::default();
+        let mut latest = previous.clone();
+        loop {
+            if *stop.borrow() {
+                bail!("Stopped after {} pages", summary.pages);
+            }
+            let page = self.fetcher.get(&url).await?;
+            let items = items(&page, &self.items_pointer)?;
+
+            let mut records = Vec::with_capacity(items.len());
+            for item in items {
+                match self.transform.encode(item) {
+                    Ok(record) => records.push(record),
+                    Err(e) => {
+                        warn!("{}: skipping item: {:#}", self.name, e);
+                        summary.skipped += 1;
+                        if let Some(series) = &self.series {
+                            series.filtered.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                }
+                if let Some(Watermark::MaxValue { pointer, .. }) = &self.watermark {
+                    let value = item.pointer(pointer).and_then(watermark::value_of);
+                    if let Some(value) = value {
+                        let newer = match latest.as_deref() {
+                            Some(latest) => watermark::compare(&value, latest).is_gt(),
+                            None => true,
+                        };
+                        if newer {
+                            latest = Some(value);
+                        }
+                    }
+                }
+            }
+            let count = records.len() as u64;
+            if let Some(series) = &self.series {
+                for record in &records {
+                    series.record_bytes.observe(record.len() as f64);
+                }
+            }
+            let ingested = self
+                .pipeline
+                .ingest_batch(records)
+                .await
+                .with_context(|| format!("Failed to ingest a page from {}", url));
+            if let Some(series) = &self.series {
+                series.observe_pipeline(&self.pipeline);
+            }
+            ingested?;
+            summary.items += count;
+            summary.pages += 1;
+            if let Some(series) = &self.series {
+                series.count("page", 1);
+            }
+
+            match next_page(&self.pagination, &url, &page, items.len())? {
+                Some(next) => {
+                    if let (Some(Watermark::Cursor), Some(cursor)) = (&self.watermark, next.cursor)
+                    {
+                        latest = Some(cursor);
+                    }
+                    url = next.url;
+                }
+                None => break,
+            }
+        }
+
+        if self.watermark.is_some() && latest != previous {
+            if let Some(latest) = &latest {
+                store.save(&self.name, latest).await?;
+            }
+        }
+        summary.watermark = latest;
+        Ok(summary)
+    }
+
+    /// Close the stream once the source will not run again
+    pub async fn finish(self) -> Result<IngestSummary> {
+        self.pipeline.finish().await
+    }
+}
+
+/// The items of a page; a missing or null array is an empty page
+fn items<'a>(page: &'a Page, pointer: &str) -> Result<&'a [Value]> {
+    match page.body.pointer(pointer) {
+        Some(Value::Array(items)) => Ok(items),
+        None | Some(Value::Null) => Ok(&[]),
+        Some(_) => bail!("{:?} is not an array of items", pointer),
+    }
+}
+
+/// Run `source` on `schedule` until `stop` is set, then hand it back
+///
+/// Runs of a source never overlap: runs that come due while one is still going are
+/// skipped, with a warning.
+pub async fn run_scheduled<S: IngestSink>(
+    mut source: Source<S>,
+    schedule: Schedule,
+    store: &WatermarkStore,
+    mut stop: watch::Receiver<bool>,
+) -> Source<S> {
+    while !*stop.borrow() {
+        let now = Utc::now();
+        let Some(due) = schedule.next_after(now) else {
+            warn!("{}: the schedule has no more runs", source.name);
+            break;
+        };
+        let wait = (due - now).to_std().unwrap_or_default();
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            _ = stop.changed() => break,
+        }
+
+        match source.run(store, &stop).await {
+            Ok(summary) => info!(
+                "{}: {} items from {} pages ({} skipped), watermark {:?}",
+                source.name, summary.items, summary.pages, summary.skipped, summary.watermark
+            ),
+            Err(e) => error!("{}: run failed: {:#}", source.name, e),
+        }
+
+        let missed = schedule.missed(due, Utc::now());
+        if missed > 0 {
+            warn!(
+                "{}: the run due at {} took so long that {} runs due since were skipped",
+                source.name, due, missed
+            );
+        }
+    }
+    source
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::watermark::FileStore;
+    use axum::extract::{Query, State};
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::response::{IntoResponse, Response};
+    use axum::routing::get;
+    use axum::{Json, Router};
+    use prost::Message;
+    use prost_types::field_descriptor_proto::{Label, Type};
+    use prost_types::{DescriptorProto, FieldDescriptorProto};
+    use serde_json::json;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use zerobus_common::dynamic::DynamicEncoder;
+    use zerobus_common::metrics::Metrics;
+    use zerobus_common::testing::MockSink;
+
+    #[derive(Clone, PartialEq, Message)]
+    struct Row {
+        #[prost(int64, optional, tag = "1")]
+        id: Option<i64>,
+        #[prost(string, optional, tag = "2")]
+        title: Option<String>,
+        #[prost(string, optional, tag = "3")]
+        updated_at: Option<String>,
+    }
+
+    fn field(name: &str, number: i32, kind: Type) -> FieldDescriptorProto {
+        FieldDescriptorProto {
+            name: Some(name.to_string()),
+            number: Some(number),
+            label: Some(Label::Optional as i32),
+            r#type: Some(kind as i32),
+            ..Default::default()
+        }
+    }
+
+    fn item(id: i64, updated_at: &str) -> Value {
+        json!({"id": id, "title": format!("item {}", id), "updated_at": updated_at, "extra": true})
+    }
+
+    /// Twelve items, oldest first, as the mock API holds them
+    fn catalog() -> Vec<Value> {
+        (1..=12)
+            .map(|id| item(id, &format!("2024-06-{:02}T00:00:00Z", id)))
+            .collect()
+    }
+
+    /// Requests the mock API received, as `<path>?<query>`
+    type Requests = Arc<Mutex<Vec<String>>>;
+
+    /// A mock API serving the catalog in pages of five, with each pagination strategy
+    ///
+    /// The first request to `/limited` is answered 429.
+    async fn serve(requests: Requests) -> String {
+        fn record(requests: &Requests, path: &str, query: &HashMap<String, String>) {
+            let mut query: Vec<_> = query.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+            query.sort();
+            requests
+                .lock()
+                .unwrap()
+                .push(format!("{}?{}", path, query.join("&")));
+        }
+
+        async fn cursor(
+            State(requests): State<Requests>,
+            headers: HeaderMap,
+            Query(query): Query<HashMap<String, String>>,
+        ) -> Response {
+            record(&requests, "/cursor", &query);
+            if headers.get("authorization").and_then(|v| v.to_str().ok())
+                != Some("Bearer test-token")
+            {
+                return StatusCode::UNAUTHORIZED.into_response();
+            }
+            // The cursor is the id of the last item returned
+            let after: i64 = query.get("after").map_or(0, |after| after.parse().unwrap());
+            let page: Vec<Value> = catalog()
+                .into_iter()
+                .filter(|item| item["id"].as_i64().unwrap() > after)
+                .take(5)
+                .collect();
+            let next = page
+                .last()
+                .map_or(after, |item| item["id"].as_i64().unwrap());
+            Json(json!({"data": page, "meta": {"next_cursor": next.to_string()}})).into_response()
+        }
+
+        async fn link(
+            State(requests): State<Requests>,
+            Query(query): Query<HashMap<String, String>>,
+        ) -> Response {
+            record(&requests, "/link", &query);
+            let since = query.get("since").cloned().unwrap_or_default();
+            let matching: Vec<Value> = catalog()
+                .into_iter()
+                .filter(|item| item["updated_at"].as_str().unwrap() >= since.as_str())
+                .collect();
+            let page_number: usize = query.get("page").map_or(1, |page| page.parse().unwrap());
+            let page: Vec<Value> = matching
+                .iter()
+                .skip((page_number - 1) * 5)
+                .take(5)
+                .cloned()
+                .collect();
+            let mut headers = HeaderMap::new();
+            if matching.len() > page_number * 5 {
+                let next = format!(
+                    "</link?since={}&page={}>; rel=\"next\"",
+                    since,
+                    page_number + 1
+                );
+                headers.insert("link", next.parse().unwrap());
+            }
+            (headers, Json(Value::Array(page))).into_response()
+        }
+
+        async fn offset(
+            State(requests): State<Requests>,
+            Query(query): Query<HashMap<String, String>>,
+        ) -> Response {
+            record(&requests, "/offset", &query);
+            let offset: usize = query["offset"].parse().unwrap();
+            let limit: usize = query["limit"].parse().unwrap();
+            let page: Vec<Value> = catalog().into_iter().skip(offset).take(limit).collect();
+            Json(json!({"results": page})).into_response()
+        }
+
+        async fn limited(
+            State(requests): State<Requests>,
+            Query(query): Query<HashMap<String, String>>,
+        ) -> Response {
+            record(&requests, "/limited", &query);
+            if requests.lock().unwrap().len() == 1 {
+                return (StatusCode::TOO_MANY_REQUESTS, [("retry-after", "1")]).into_response();
+            }
+            Json(catalog()[..2].to_vec()).into_response()
+        }
+
+        let app = Router::new()
+            .route("/cursor", get(cursor))
+            .route("/link", get(link))
+            .route("/offset", get(offset))
+            .route("/limited", get(limited))
+            .with_state(requests);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}", address)
+    }
+
+    /// The source described by `json`, over a mock sink
+    fn source(json: &str, sink: MockSink) -> Source<MockSink> {
+        let config = format!(
+            r#"{{"watermarks": {{"file": "unused.json"}}, "sources": [{}]}}"#,
+            json
+        );
+        let config = Config::parse(&config).unwrap().sources.remove(0);
+        let descriptor = DescriptorProto {
+            name: Some("table_items".to_string()),
+            field: vec![
+                field("id", 1, Type::Int64),
+                field("title", 2, Type::String),
+                field("updated_at", 3, Type::String),
+            ],
+            ..Default::default()
+        };
+        let encoder = DynamicEncoder::new(&descriptor)
+            .unwrap()
+            .ignore_unknown_fields(config.ignore_unknown_fields);
+        let transform = Transform::new(encoder, &config).unwrap();
+        Source::new(&config, transform, Pipeline::new(sink, 100)).unwrap()
+    }
+
+    fn ids(sink: &MockSink) -> Vec<i64> {
+        sink.records()
+            .iter()
+            .map(|record| Row::decode(record.as_slice()).unwrap().id.unwrap())
+            .collect()
+    }
+
+    struct Fixture {
+        url: String,
+        requests: Requests,
+        store: WatermarkStore,
+        stop: watch::Receiver<bool>,
+        _dir: tempfile::TempDir,
+        _stop: watch::Sender<bool>,
+    }
+
+    async fn fixture() -> Fixture {
+        let requests = Requests::default();
+        let url = serve(requests.clone()).await;
+        let dir = tempfile::tempdir().unwrap();
+        let store = WatermarkStore::File(FileStore::new(dir.path().join("watermarks.json")));
+        let (stop_sender, stop) = watch::channel(false);
+        Fixture {
+            url,
+            requests,
+            store,
+            stop,
+            _dir: dir,
+            _stop: stop_sender,
+        }
+    }
+
+    impl Fixture {
+        fn take_requests(&self) -> Vec<String> {
+            std::mem::take(&mut *self.requests.lock().unwrap())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cursor_pagination_resumes_from_watermark() {
+        std::env::set_var("POLLER_TEST_TOKEN", "test-token");
+        let fixture = fixture().await;
+        let sink = MockSink::default();
+        let mut source = source(
+            &format!(
+                r#"{{"name": "cursor", "schedule": "* * * * *", "url": "{}/cursor",
+                    "headers": {{"Authorization": "Bearer ${{POLLER_TEST_TOKEN}}"}},
+                    "items_pointer": "/data",
+                    "pagination": {{"strategy": "cursor", "cursor_pointer": "/meta/next_cursor", "param": "after"}},
+                    "watermark": {{"kind": "cursor"}},
+                    "fields": {{"id": "/id", "title": "/title"}},
+                    "table": "main.api.items", "descriptor_set": "d"}}"#,
+                fixture.url
+            ),
+            sink.clone(),
+        );
+
+        let summary = source.run(&fixture.store, &fixture.stop).await.unwrap();
+
+        assert_eq!((1..=12).collect::<Vec<_>>(), ids(&sink));
+        assert_eq!(
+            RunSummary {
+                pages: 4,
+                items: 12,
+                skipped: 0,
+                watermark: Some("12".to_string()),
+            },
+            summary
+        );
+        assert_eq!(
+            vec![
+                "/cursor?",
+                "/cursor?after=5",
+                "/cursor?after=10",
+                "/cursor?after=12"
+            ],
+            fixture.take_requests()
+        );
+        assert_eq!(
+            Some("12".to_string()),
+            fixture.store.load("cursor").await.unwrap()
+        );
+
+        // The next run starts where this one ended and finds nothing new
+        let summary = source.run(&fixture.store, &fixture.stop).await.unwrap();
+        assert_eq!(0, summary.items);
+        assert_eq!(vec!["/cursor?after=12"], fixture.take_requests());
+        assert_eq!(12, sink.records().len());
+    }
+
+    #[tokio::test]
+    async fn test_link_header_pagination_with_max_value_watermark() {
+        let fixture = fixture().await;
+        fixture
+            .store
+            .save("link", "2024-06-07T00:00:00Z")
+            .await
+            .unwrap();
+        let sink = MockSink::default();
+        let mut source = source(
+            &format!(
+                r#"{{"name": "link", "schedule": "* * * * *", "url": "{}/link",
+                    "pagination": {{"strategy": "link-header"}},
+                    "watermark": {{"kind": "max-value", "pointer": "/updated_at", "param": "since"}},
+                    "ignore_unknown_fields": true,
+                    "table": "main.api.items", "descriptor_set": "d"}}"#,
+                fixture.url
+            ),
+            sink.clone(),
+        );
+
+        let summary = source.run(&fixture.store, &fixture.stop).await.unwrap();
+
+        assert_eq!((7..=12).collect::<Vec<_>>(), ids(&sink));
+        assert_eq!(2, summary.pages);
+        assert_eq!(
+            vec![
+                "/link?since=2024-06-07T00:00:00Z",
+                "/link?page=2&since=2024-06-07T00:00:00Z",
+            ],
+            fixture.take_requests()
+        );
+        assert_eq!(
+            Some("2024-06-12T00:00:00Z".to_string()),
+            fixture.store.load("link").await.unwrap()
+        );
+        let row = Row::decode(sink.records()[0].as_slice()).unwrap();
+        assert_eq!(Some("item 7".to_string()), row.title);
+    }
+
+    #[tokio::test]
+    async fn test_offset_pagination() {
+        let fixture = fixture().await;
+        let sink = MockSink::default();
+        let mut source = source(
+            &format!(
+                r#"{{"name": "offset", "schedule": "* * * * *", "url": "{}/offset",
+                    "items_pointer": "/results",
+                    "pagination": {{"strategy": "offset", "offset_param": "offset", "limit_param": "limit", "page_size": 5}},
+                    "ignore_unknown_fields": true,
+                    "table": "main.api.items", "descriptor_set": "d"}}"#,
+                fixture.url
+            ),
+            sink.clone(),
+        );
+
+        let summary = source.run(&fixture.store, &fixture.stop).await.unwrap();
+
+        assert_eq!(12, summary.items);
+        assert_eq!(None, summary.watermark);
+        assert_eq!(
+            vec![
+                "/offset?limit=5&offset=0",
+                "/offset?limit=5&offset=5",
+                "/offset?limit=5&offset=10",
+            ],
+            fixture.take_requests()
+        );
+        // Without a watermark nothing is stored
+        assert_eq!(None, fixture.store.load("offset").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_metrics_follow_the_run() {
+        let fixture = fixture().await;
+        let metrics = Metrics::default();
+        let sink = MockSink::default()
+            .fail_acks_for(|record| Row::decode(record).is_ok_and(|row| row.id == Some(12)));
+        let mut source = source(
+            &format!(
+                r#"{{"name": "offset", "schedule": "* * * * *", "url": "{}/offset",
+                    "items_pointer": "/results",
+                    "pagination": {{"strategy": "offset", "offset_param": "offset", "limit_param": "limit", "page_size": 5}},
+                    "ignore_unknown_fields": true,
+                    "table": "main.api.items", "descriptor_set": "d"}}"#,
+                fixture.url
+            ),
+            sink,
+        )
+        .metrics(metrics.series("main.api.items", "offset"));
+
+        // The last page has a row that is not acknowledged
+        assert!(source.run(&fixture.store, &fixture.stop).await.is_err());
+
+        let text = metrics.render();
+        let labels = "{table=\"main.api.items\",source=\"offset\"}";
+        for sample in [
+            format!("zerobus_records_ingested_total{} 11\n", labels),
+            format!("zerobus_records_failed_total{} 1\n", labels),
+            format!("zerobus_ack_latency_seconds_count{} 11\n", labels),
+            format!("zerobus_record_bytes_count{} 12\n", labels),
+            format!("zerobus_in_flight_records{} 0\n", labels),
+            "zerobus_source_records_total{table=\"main.api.items\",source=\"offset\",kind=\"page\"} 2\n"
+                .to_string(),
+        ] {
+            assert!(text.contains(&sample), "{} missing from\n{}", sample, text);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_too_many_requests_is_retried() {
+        let fixture = fixture().await;
+        let sink = MockSink::default();
+        let mut source = source(
+            &format!(
+                r#"{{"name": "limited", "schedule": "* * * * *", "url": "{}/limited",
+                    "ignore_unknown_fields": true,
+                    "table": "main.api.items", "descriptor_set": "d"}}"#,
+                fixture.url
+            ),
+            sink.clone(),
+        );
+
+        let summary = source.run(&fixture.store, &fixture.stop).await.unwrap();
+
+        assert_eq!(2, summary.items);
+        assert_eq!(2, fixture.take_requests().len());
+    }
+
+    #[tokio::test]
+    async fn test_failed_page_keeps_the_watermark() {
+        let fixture = fixture().await;
+        fixture
+            .store
+            .save("link", "2024-06-07T00:00:00Z")
+            .await
+            .unwrap();
+        let sink =
+            MockSink::default().fail_acks_for(|record| Row::decode(record).unwrap().id == Some(11));
+        let mut source = source(
+            &format!(
+                r#"{{"name": "link", "schedule": "* * * * *", "url": "{}/link",
+                    "pagination": {{"strategy": "link-header"}},
+                    "watermark": {{"kind": "max-value", "pointer": "/updated_at", "param": "since"}},
+                    "ignore_unknown_fields": true,
+                    "table": "main.api.items", "descriptor_set": "d"}}"#,
+                fixture.url
+            ),
+            sink.clone(),
+        );
+
+        assert!(source.run(&fixture.store, &fixture.stop).await.is_err());
+
+        assert_eq!(
+            Some("2024-06-07T00:00:00Z".to_string()),
+            fixture.store.load("link").await.unwrap()
+        );
+    }
+}
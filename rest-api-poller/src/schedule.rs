@@ -0,0 +1,88 @@
+//! Cron schedules of the sources
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use std::str::FromStr;
+
+/// When a source is polled
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    cron: cron::Schedule,
+}
+
+impl Schedule {
+    /// Parse a cron expression
+    ///
+    /// The usual five fields (minute to day of week) are accepted as well as the six or
+    /// seven of the `cron` crate, which start with seconds and may end with a year.
+    pub fn parse(expression: &str) -> Result<Self> {
+        let fields = expression.split_whitespace().count();
+        let expression = if fields == 5 {
+            format!("0 {}", expression)
+        } else {
+            expression.to_string()
+        };
+        let cron = cron::Schedule::from_str(&expression)
+            .with_context(|| format!("Invalid cron expression {:?}", expression))?;
+        Ok(Self { cron })
+    }
+
+    /// The first time after `now` the source is due
+    pub fn next_after(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.cron.after(&now).next()
+    }
+
+    /// How many runs came due after `due` and by `now`
+    ///
+    /// A run that takes longer than the schedule's interval is not followed by the runs
+    /// it overlapped, which would only fetch the little that changed meanwhile, back to
+    /// back; they are skipped and the next run is the first one due after it ends.
+    pub fn missed(&self, due: DateTime<Utc>, now: DateTime<Utc>) -> usize {
+        self.cron
+            .after(&due)
+            .take_while(|time| *time <= now)
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(time: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(time).unwrap().to_utc()
+    }
+
+    #[test]
+    fn test_schedules() {
+        let every_five_minutes = Schedule::parse("*/5 * * * *").unwrap();
+        assert_eq!(
+            Some(at("2024-06-10T12:05:00Z")),
+            every_five_minutes.next_after(at("2024-06-10T12:03:17Z"))
+        );
+        // A run due exactly now is the next one, not this one
+        assert_eq!(
+            Some(at("2024-06-10T12:10:00Z")),
+            every_five_minutes.next_after(at("2024-06-10T12:05:00Z"))
+        );
+
+        let with_seconds = Schedule::parse("0 30 6 * * *").unwrap();
+        assert_eq!(
+            Some(at("2024-06-11T06:30:00Z")),
+            with_seconds.next_after(at("2024-06-10T07:00:00Z"))
+        );
+
+        // A run due at 12:05 that ended at 12:17 overlapped the 12:10 and 12:15 runs
+        assert_eq!(
+            2,
+            every_five_minutes.missed(at("2024-06-10T12:05:00Z"), at("2024-06-10T12:17:00Z"))
+        );
+        assert_eq!(
+            0,
+            every_five_minutes.missed(at("2024-06-10T12:05:00Z"), at("2024-06-10T12:06:00Z"))
+        );
+
+        assert!(Schedule::parse("every hour").is_err());
+        assert!(Schedule::parse("61 * * * *").is_err());
+    }
+}
@@ -0,0 +1,62 @@
+//! Turning an item of a response into a row of the source's table
+
+use anyhow::{bail, Result};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use zerobus_common::dynamic::DynamicEncoder;
+
+use crate::config::SourceConfig;
+
+/// Builds and encodes the row for each item of a source
+pub struct Transform {
+    encoder: DynamicEncoder,
+    /// Columns and the JSON pointers they are read from; `None` takes the whole item
+    fields: Option<BTreeMap<String, String>>,
+}
+
+impl Transform {
+    /// Fails if the source names a column the table does not have
+    pub fn new(encoder: DynamicEncoder, source: &SourceConfig) -> Result<Self> {
+        for column in source.fields.iter().flat_map(|fields| fields.keys()) {
+            if !encoder.has_field(column) {
+                bail!(
+                    "Source {:?}: table {} has no column {:?}",
+                    source.name,
+                    source.table,
+                    column
+                );
+            }
+        }
+        Ok(Self {
+            encoder,
+            fields: source.fields.clone(),
+        })
+    }
+
+    /// Encode the row for an item
+    ///
+    /// Pointers that match nothing leave their column unset. Objects and arrays picked
+    /// by a pointer are stored as JSON strings.
+    pub fn encode(&self, item: &Value) -> Result<Vec<u8>> {
+        if !item.is_object() {
+            bail!("Item is not a JSON object");
+        }
+        let Some(fields) = &self.fields else {
+            return self.encoder.encode(item);
+        };
+
+        let mut row = Map::new();
+        for (column, pointer) in fields {
+            match item.pointer(pointer) {
+                None | Some(Value::Null) => {}
+                Some(value @ (Value::Object(_) | Value::Array(_))) => {
+                    row.insert(column.clone(), Value::String(value.to_string()));
+                }
+                Some(value) => {
+                    row.insert(column.clone(), value.clone());
+                }
+            }
+        }
+        self.encoder.encode(&Value::Object(row))
+    }
+}
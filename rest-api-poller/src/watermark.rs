@@ -0,0 +1,232 @@
+//! Where each source's watermark is kept between runs
+
+use anyhow::{Context, Result};
+use aws_sdk_dynamodb::types::AttributeValue;
+use serde_json::{Map, Value};
+use std::cmp::Ordering;
+use std::path::PathBuf;
+use tokio::sync::Mutex;
+use zerobus_common::durable;
+
+use crate::config::WatermarkStoreConfig;
+
+/// Watermarks by source name
+pub enum WatermarkStore {
+    File(FileStore),
+    Dynamodb(DynamoStore),
+}
+
+impl WatermarkStore {
+    pub async fn from_config(config: &WatermarkStoreConfig) -> Self {
+        match config {
+            WatermarkStoreConfig::File(path) => Self::File(FileStore::new(path.clone())),
+            WatermarkStoreConfig::Dynamodb { table } => {
+                let config = aws_config::load_from_env().await;
+                Self::Dynamodb(DynamoStore {
+                    client: aws_sdk_dynamodb::Client::new(&config),
+                    table: table.clone(),
+                })
+            }
+        }
+    }
+
+    /// The watermark the last successful run of `source` left, if any
+    pub async fn load(&self, source: &str) -> Result<Option<String>> {
+        match self {
+            Self::File(store) => store.load(source).await,
+            Self::Dynamodb(store) => store.load(source).await,
+        }
+    }
+
+    pub async fn save(&self, source: &str, watermark: &str) -> Result<()> {
+        match self {
+            Self::File(store) => store.save(source, watermark).await,
+            Self::Dynamodb(store) => store.save(source, watermark).await,
+        }
+    }
+}
+
+/// A JSON object of watermarks by source name, in one local file
+pub struct FileStore {
+    path: PathBuf,
+    /// Sources save concurrently, and each save rewrites the whole file
+    lock: Mutex<()>,
+}
+
+impl FileStore {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            lock: Mutex::new(()),
+        }
+    }
+
+    async fn read(&self) -> Result<Map<String, Value>> {
+        let path = self.path.clone();
+        match tokio::task::spawn_blocking(move || durable::read_file(&path))
+            .await
+            .context("Failed to read the watermarks")??
+        {
+            Some(bytes) => serde_json::from_slice(&bytes)
+                .with_context(|| format!("Invalid watermark file {}", self.path.display())),
+            None => Ok(Map::new()),
+        }
+    }
+
+    async fn load(&self, source: &str) -> Result<Option<String>> {
+        let _guard = self.lock.lock().await;
+        Ok(self
+            .read()
+            .await?
+            .get(source)
+            .and_then(Value::as_str)
+            .map(str::to_string))
+    }
+
+    /// Replaces the whole file with [`durable::replace_file`], so a crash leaves every
+    /// source's watermark as it was before or after this save
+    async fn save(&self, source: &str, watermark: &str) -> Result<()> {
+        let _guard = self.lock.lock().await;
+        let mut watermarks = self.read().await?;
+        watermarks.insert(source.to_string(), Value::from(watermark));
+
+        let (path, contents) = (self.path.clone(), serde_json::to_vec_pretty(&watermarks)?);
+        tokio::task::spawn_blocking(move || durable::replace_file(&path, &contents))
+            .await
+            .context("Failed to save the watermarks")?
+    }
+}
+
+/// A DynamoDB table with a `source` string partition key and a `watermark` attribute
+pub struct DynamoStore {
+    client: aws_sdk_dynamodb::Client,
+    table: String,
+}
+
+impl DynamoStore {
+    async fn load(&self, source: &str) -> Result<Option<String>> {
+        let output = self
+            .client
+            .get_item()
+            .table_name(&self.table)
+            .key("source", AttributeValue::S(source.to_string()))
+            .consistent_read(true)
+            .send()
+            .await
+            .with_context(|| format!("Failed to read the watermark of {}", source))?;
+        Ok(output
+            .item
+            .and_then(|mut item| item.remove("watermark"))
+            .and_then(|watermark| watermark.as_s().ok().cloned()))
+    }
+
+    async fn save(&self, source: &str, watermark: &str) -> Result<()> {
+        self.client
+            .put_item()
+            .table_name(&self.table)
+            .item("source", AttributeValue::S(source.to_string()))
+            .item("watermark", AttributeValue::S(watermark.to_string()))
+            .item(
+                "updated_at",
+                AttributeValue::S(chrono::Utc::now().to_rfc3339()),
+            )
+            .send()
+            .await
+            .with_context(|| format!("Failed to save the watermark of {}", source))?;
+        Ok(())
+    }
+}
+
+/// Order two watermark values: as numbers when both are, as strings otherwise
+pub fn compare(a: &str, b: &str) -> Ordering {
+    match (a.parse::<f64>(), b.parse::<f64>()) {
+        (Ok(a), Ok(b)) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+        _ => a.cmp(b),
+    }
+}
+
+/// A watermark value read from an item field
+pub fn value_of(value: &Value) -> Option<String> {
+    match value {
+        Value::String(value) if !value.is_empty() => Some(value.clone()),
+        Value::Number(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_file_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state").join("watermarks.json");
+        let store = WatermarkStore::File(FileStore::new(path.clone()));
+
+        assert_eq!(None, store.load("issues").await.unwrap());
+        store.save("issues", "2024-06-10T12:00:00Z").await.unwrap();
+        store.save("tickets", "c2").await.unwrap();
+        store.save("issues", "2024-06-11T08:30:00Z").await.unwrap();
+
+        // Reopened, as on the next start
+        let store = WatermarkStore::File(FileStore::new(path.clone()));
+        assert_eq!(
+            Some("2024-06-11T08:30:00Z".to_string()),
+            store.load("issues").await.unwrap()
+        );
+        assert_eq!(Some("c2".to_string()), store.load("tickets").await.unwrap());
+        assert!(!path.with_extension("json.tmp").exists());
+
+        // Emptied by a crash, which must not restart every source from scratch
+        std::fs::write(&path, "").unwrap();
+        assert!(store.load("issues").await.is_err());
+    }
+
+    #[test]
+    fn test_compare() {
+        assert_eq!(Ordering::Less, compare("9", "10"));
+        assert_eq!(Ordering::Greater, compare("1718000000.5", "1718000000"));
+        assert_eq!(
+            Ordering::Less,
+            compare("2024-06-10T12:00:00Z", "2024-06-11T08:30:00Z")
+        );
+    }
+}
+
+/// Against the DynamoDB API of LocalStack; run with `--features localstack`
+#[cfg(all(test, feature = "localstack"))]
+mod localstack_tests {
+    use super::*;
+    use zerobus_common::localstack::{self, TestTable};
+
+    #[tokio::test]
+    async fn test_dynamo_store_round_trips_watermarks() {
+        let config = localstack::config().await;
+        let table = TestTable::create(&config, "watermarks", "source")
+            .await
+            .unwrap();
+        let store = WatermarkStore::Dynamodb(DynamoStore {
+            client: table.client.clone(),
+            table: table.name.clone(),
+        });
+
+        assert_eq!(None, store.load("issues").await.unwrap());
+        store.save("issues", "2024-06-10T12:00:00Z").await.unwrap();
+        store.save("issues", "2024-06-11T08:30:00Z").await.unwrap();
+        assert_eq!(
+            Some("2024-06-11T08:30:00Z".to_string()),
+            store.load("issues").await.unwrap()
+        );
+
+        // Two pollers saving at once: both writes land and the item holds one of them
+        let (first, second) =
+            tokio::join!(store.save("tickets", "c2"), store.save("tickets", "c3"));
+        first.unwrap();
+        second.unwrap();
+        let saved = store.load("tickets").await.unwrap().unwrap();
+        assert!(saved == "c2" || saved == "c3", "{}", saved);
+
+        table.delete().await.unwrap();
+    }
+}
@@ -0,0 +1,154 @@
+//! Parsing Events API requests and turning events into table rows.
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::proto::slack_events::TableSlackEvents;
+
+/// Header Slack sets on a retried delivery: `1`, `2`, or `3`
+pub const RETRY_NUM_HEADER: &str = "X-Slack-Retry-Num";
+
+/// Header saying why Slack retried, such as `http_timeout`
+pub const RETRY_REASON_HEADER: &str = "X-Slack-Retry-Reason";
+
+/// The outer object of an Events API request
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Envelope {
+    /// Sent once when the request URL is saved; answered with the challenge
+    UrlVerification {
+        challenge: String,
+    },
+    EventCallback(Callback),
+    /// Requests that carry no event, such as `app_rate_limited`
+    #[serde(other)]
+    Other,
+}
+
+/// An event delivery
+#[derive(Debug, Deserialize)]
+pub struct Callback {
+    /// Unique across workspaces; retries keep it
+    pub event_id: String,
+    #[serde(default)]
+    pub team_id: Option<String>,
+    /// When the event happened, in Unix seconds
+    #[serde(default)]
+    pub event_time: Option<i64>,
+    pub event: Value,
+}
+
+pub fn parse(body: &[u8]) -> Result<Envelope> {
+    serde_json::from_slice(body).context("Body is not an Events API request")
+}
+
+/// Build the row for one event
+///
+/// The channel, user, and `ts` sit in different places depending on the event type:
+/// a message names its channel, a reaction the channel of the message it reacts to,
+/// and `channel_created` carries the whole channel object. Events with no `ts` of
+/// their own take their `event_ts`.
+pub fn to_table_row(
+    callback: &Callback,
+    ingested_at: i64,
+    ingested_date: i32,
+) -> Result<TableSlackEvents> {
+    let event = &callback.event;
+    let channel = string_at(event, "/channel")
+        .or_else(|| string_at(event, "/channel/id"))
+        .or_else(|| string_at(event, "/item/channel"));
+    let user = string_at(event, "/user")
+        .or_else(|| string_at(event, "/user/id"))
+        .or_else(|| string_at(event, "/channel/creator"));
+    let ts = string_at(event, "/ts").or_else(|| string_at(event, "/event_ts"));
+
+    Ok(TableSlackEvents {
+        event_id: Some(callback.event_id.clone()),
+        team_id: callback.team_id.clone(),
+        event_type: string_at(event, "/type"),
+        event_subtype: string_at(event, "/subtype"),
+        channel,
+        user,
+        ts,
+        event_time: callback.event_time.map(|seconds| seconds * 1_000_000),
+        event: Some(serde_json::to_string(event).context("Failed to serialize the event")?),
+        ingested_at: Some(ingested_at),
+        ingested_date: Some(ingested_date),
+    })
+}
+
+fn string_at(event: &Value, pointer: &str) -> Option<String> {
+    event
+        .pointer(pointer)
+        .and_then(Value::as_str)
+        .map(str::to_string)
+}
+
+#[cfg(test)]
+pub(crate) mod testing {
+    /// A fixture under `tests/fixtures`
+    pub fn fixture(name: &str) -> Vec<u8> {
+        let path = format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name);
+        std::fs::read(&path).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::fixture;
+    use super::*;
+
+    fn row(name: &str) -> TableSlackEvents {
+        match parse(&fixture(name)).unwrap() {
+            Envelope::EventCallback(callback) => to_table_row(&callback, 0, 0).unwrap(),
+            other => panic!("{} is not an event: {:?}", name, other),
+        }
+    }
+
+    #[test]
+    fn test_message() {
+        let row = row("message.json");
+        assert_eq!(Some("Ev07P3K9QX1A".to_string()), row.event_id);
+        assert_eq!(Some("T0123ABCD".to_string()), row.team_id);
+        assert_eq!(Some("message".to_string()), row.event_type);
+        assert_eq!(None, row.event_subtype);
+        assert_eq!(Some("C024BE91L".to_string()), row.channel);
+        assert_eq!(Some("U2147483697".to_string()), row.user);
+        assert_eq!(Some("1727284536.000200".to_string()), row.ts);
+        assert_eq!(Some(1_727_284_536_000_000), row.event_time);
+
+        let event: Value = serde_json::from_str(&row.event.unwrap()).unwrap();
+        assert_eq!("Deploy of checkout-api finished", event["text"]);
+    }
+
+    #[test]
+    fn test_reaction_and_channel_events() {
+        let row = self::row("reaction_added.json");
+        assert_eq!(Some("reaction_added".to_string()), row.event_type);
+        assert_eq!(Some("C024BE91L".to_string()), row.channel);
+        assert_eq!(Some("U061F1EUR".to_string()), row.user);
+        assert_eq!(Some("1727284601.000300".to_string()), row.ts);
+
+        let row = self::row("channel_created.json");
+        assert_eq!(Some("channel_created".to_string()), row.event_type);
+        assert_eq!(Some("C07P3QK1Z9E".to_string()), row.channel);
+        assert_eq!(Some("U061F1EUR".to_string()), row.user);
+    }
+
+    #[test]
+    fn test_other_envelopes() {
+        match parse(&fixture("url_verification.json")).unwrap() {
+            Envelope::UrlVerification { challenge } => assert_eq!(
+                "3eZbrw1aBm2rZgRNFdxV2595E9CY3gmdALWMmHkvFXO7tYXAYM8P",
+                challenge
+            ),
+            other => panic!("Expected url_verification, got {:?}", other),
+        }
+        let rate_limited = br#"{"token": "x", "type": "app_rate_limited", "team_id": "T1"}"#;
+        assert!(matches!(parse(rate_limited).unwrap(), Envelope::Other));
+
+        assert!(parse(b"payload=%7B%7D").is_err());
+        assert!(parse(br#"{"type": "event_callback", "event": {}}"#).is_err());
+    }
+}
@@ -0,0 +1,124 @@
+use anyhow::{bail, Context, Result};
+use databricks_zerobus_ingest_sdk::{StreamConfigurationOptions, TableProperties, ZerobusSdk};
+use slack_events_receiver::proto::load_descriptor_proto;
+use slack_events_receiver::server::{ingest_queued, router, AppState};
+use slack_events_receiver::signature::DEFAULT_TOLERANCE_SECS;
+use tokio::sync::mpsc;
+use tracing::info;
+use zerobus_common::log_level;
+use zerobus_common::pipeline::Pipeline;
+use zerobus_common::shutdown;
+
+/// Maximum number of unacknowledged records per stream
+const MAX_INFLIGHT_RECORDS: usize = 10_000;
+
+/// Address to listen on when LISTEN_ADDR is not set
+const DEFAULT_LISTEN_ADDR: &str = "0.0.0.0:8080";
+
+/// Event ids remembered for deduplication when DEDUP_CAPACITY is not set
+const DEFAULT_DEDUP_CAPACITY: usize = 10_000;
+
+/// Events waiting to be ingested when QUEUE_CAPACITY is not set
+const DEFAULT_QUEUE_CAPACITY: usize = 1_000;
+
+fn env(name: &str) -> Result<String> {
+    std::env::var(name).with_context(|| format!("{} environment variable must be set", name))
+}
+
+/// Read a positive integer from `name`, or `default` when it is not set
+fn positive_from_env(name: &str, default: usize) -> Result<usize> {
+    match std::env::var(name) {
+        Ok(value) => value
+            .trim()
+            .parse::<usize>()
+            .ok()
+            .filter(|parsed| *parsed > 0)
+            .with_context(|| format!("{} must be a positive integer, got {:?}", name, value)),
+        Err(_) => Ok(default),
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let log_level = log_level::init()?;
+    tokio::spawn(log_level::on_hangup(log_level.clone()));
+
+    let zerobus_endpoint = env("ZEROBUS_ENDPOINT")?;
+    let databricks_host = env("DATABRICKS_HOST")?;
+    let client_id = env("DATABRICKS_CLIENT_ID")?;
+    let client_secret = env("DATABRICKS_CLIENT_SECRET")?;
+    let table_name = env("TABLE_NAME")?;
+    let signing_secret = env("SLACK_SIGNING_SECRET")?;
+    if signing_secret.is_empty() {
+        bail!("SLACK_SIGNING_SECRET must not be empty");
+    }
+    let listen_addr =
+        std::env::var("LISTEN_ADDR").unwrap_or_else(|_| DEFAULT_LISTEN_ADDR.to_string());
+    let dedup_capacity = positive_from_env("DEDUP_CAPACITY", DEFAULT_DEDUP_CAPACITY)?;
+    let queue_capacity = positive_from_env("QUEUE_CAPACITY", DEFAULT_QUEUE_CAPACITY)?;
+    let tolerance_secs =
+        positive_from_env("SIGNATURE_TOLERANCE_SECS", DEFAULT_TOLERANCE_SECS as usize)? as u64;
+    let grace = shutdown::grace_from_env()?;
+
+    let sdk = ZerobusSdk::new(zerobus_endpoint, databricks_host)?;
+
+    let table_properties = TableProperties {
+        table_name: table_name.clone(),
+        descriptor_proto: load_descriptor_proto("slack_events.proto", "table_slack_events"),
+    };
+    let stream_options = StreamConfigurationOptions {
+        max_inflight_records: MAX_INFLIGHT_RECORDS,
+        ..Default::default()
+    };
+    let stream = sdk
+        .create_stream(
+            table_properties,
+            client_id,
+            client_secret,
+            Some(stream_options),
+        )
+        .await
+        .context("Failed to create stream")?;
+    info!("Created stream to table: {}", table_name);
+
+    let (queue, queued) = mpsc::channel(queue_capacity);
+    let worker = tokio::spawn(async move {
+        let mut pipeline = Pipeline::new(stream, MAX_INFLIGHT_RECORDS);
+        ingest_queued(queued, &mut pipeline).await;
+        pipeline
+    });
+
+    let state = AppState::new(signing_secret, tolerance_secs, dedup_capacity, queue);
+    let listener = tokio::net::TcpListener::bind(&listen_addr)
+        .await
+        .with_context(|| format!("Failed to bind {}", listen_addr))?;
+    info!(
+        "Listening for Slack events on http://{}/slack/events",
+        listen_addr
+    );
+
+    // Stopping the server drops the last sender, so the worker ingests what is still
+    // queued and returns
+    let mut app = router(state);
+    if let Some(admin) = log_level::admin_routes_from_env(&log_level) {
+        app = app.merge(admin);
+    }
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown::signal())
+        .await?;
+
+    let pipeline = worker.await.context("Ingest worker panicked")?;
+    let outcome = shutdown::drain(pipeline, grace).await?;
+    info!(
+        "Shut down after ingesting {} events ({} failed)",
+        outcome.summary.ingested, outcome.summary.failed
+    );
+    if !outcome.unacked.is_empty() {
+        bail!(
+            "{} events were not acknowledged before shutdown",
+            outcome.unacked.len()
+        );
+    }
+
+    Ok(())
+}
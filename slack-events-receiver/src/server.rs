@@ -0,0 +1,337 @@
+use anyhow::{Context, Result};
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::routing::{get, post};
+use axum::Router;
+use prost::Message;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::{debug, error, info, warn};
+use zerobus_common::dedup::RecentIds;
+use zerobus_common::pipeline::{IngestSink, Pipeline};
+
+use crate::event::{parse, to_table_row, Envelope, RETRY_NUM_HEADER, RETRY_REASON_HEADER};
+use crate::proto::slack_events::TableSlackEvents;
+use crate::signature::{verify, SIGNATURE_HEADER, TIMESTAMP_HEADER};
+
+/// Shared handler state
+///
+/// Slack wants an answer within 3 seconds, so handlers only verify and queue each
+/// event; [`ingest_queued`] ingests them in the background.
+#[derive(Clone)]
+pub struct AppState {
+    secret: Arc<Vec<u8>>,
+    tolerance_secs: u64,
+    recent: Arc<Mutex<RecentIds>>,
+    queue: mpsc::Sender<TableSlackEvents>,
+}
+
+impl AppState {
+    /// State checking signatures with `secret`, accepting request timestamps within
+    /// `tolerance_secs` of now, remembering the last `dedup_capacity` event ids, and
+    /// queueing rows on `queue`
+    pub fn new(
+        secret: impl Into<Vec<u8>>,
+        tolerance_secs: u64,
+        dedup_capacity: usize,
+        queue: mpsc::Sender<TableSlackEvents>,
+    ) -> Self {
+        Self {
+            secret: Arc::new(secret.into()),
+            tolerance_secs,
+            recent: Arc::new(Mutex::new(RecentIds::new(dedup_capacity))),
+            queue,
+        }
+    }
+}
+
+/// Routes for the Events API request URL and a health check
+pub fn router(state: AppState) -> Router {
+    Router::new()
+        .route("/slack/events", post(events))
+        .route("/health", get(|| async { "OK" }))
+        .with_state(state)
+}
+
+/// Ingest queued rows until every sender is gone
+///
+/// The sink is flushed whenever the queue runs dry, so a quiet workspace's events are
+/// not left waiting for a full window. Slack has already been answered by the time a
+/// row gets here, so a row that fails to ingest is logged and counted in the
+/// pipeline's summary, not retried.
+pub async fn ingest_queued<S: IngestSink>(
+    mut queued: mpsc::Receiver<TableSlackEvents>,
+    pipeline: &mut Pipeline<S>,
+) {
+    while let Some(row) = queued.recv().await {
+        let event_id = row.event_id.clone().unwrap_or_default();
+        if let Err(e) = pipeline.ingest(row.encode_to_vec()).await {
+            error!("Failed to ingest event {}: {:#}", event_id, e);
+        }
+        if queued.is_empty() {
+            if let Err(e) = pipeline.drain().await {
+                error!("Failed to flush events: {:#}", e);
+            }
+        }
+    }
+}
+
+/// Handle an Events API request
+///
+/// Answers 401 unless the request carries a valid, recent signature, and 400 for
+/// bodies that are not Events API requests. A `url_verification` request is answered
+/// with its challenge. An event is answered 200 as soon as it is queued, or if it was
+/// already queued, as it is when Slack retries a slow answer. When the queue is full
+/// the answer is 503, and Slack retries the event after a minute, then five, then
+/// thirty.
+async fn events(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> (StatusCode, String) {
+    let now = unix_now();
+    if let Err(e) = verify(
+        &state.secret,
+        &body,
+        header(&headers, TIMESTAMP_HEADER),
+        header(&headers, SIGNATURE_HEADER),
+        now,
+        state.tolerance_secs,
+    ) {
+        warn!("Rejecting unsigned request: {:#}", e);
+        return (StatusCode::UNAUTHORIZED, format!("{:#}", e));
+    }
+
+    let callback = match parse(&body) {
+        Ok(Envelope::UrlVerification { challenge }) => {
+            info!("Answering url_verification challenge");
+            return (StatusCode::OK, challenge);
+        }
+        Ok(Envelope::EventCallback(callback)) => callback,
+        Ok(Envelope::Other) => {
+            debug!("Ignoring request without an event");
+            return (StatusCode::OK, String::new());
+        }
+        Err(e) => {
+            warn!("Rejecting request: {:#}", e);
+            return (StatusCode::BAD_REQUEST, format!("{:#}", e));
+        }
+    };
+    let event_id = callback.event_id.as_str();
+
+    let row = match ingestion_time().and_then(|(ingested_at, ingested_date)| {
+        to_table_row(&callback, ingested_at, ingested_date)
+    }) {
+        Ok(row) => row,
+        Err(e) => {
+            warn!("Rejecting event {}: {:#}", event_id, e);
+            return (StatusCode::BAD_REQUEST, format!("{:#}", e));
+        }
+    };
+
+    if !state.recent.lock().unwrap().insert(event_id) {
+        info!(
+            "Skipping retry {} of event {} ({})",
+            header(&headers, RETRY_NUM_HEADER).unwrap_or("?"),
+            event_id,
+            header(&headers, RETRY_REASON_HEADER).unwrap_or("no reason given")
+        );
+        return (StatusCode::OK, "Duplicate event".to_string());
+    }
+
+    let reason = match state.queue.try_send(row) {
+        Ok(()) => {
+            debug!("Queued event {}", event_id);
+            return (StatusCode::OK, String::new());
+        }
+        Err(TrySendError::Full(_)) => "Ingest queue is full",
+        Err(TrySendError::Closed(_)) => "Shutting down",
+    };
+    // Accept the event when Slack retries it
+    state.recent.lock().unwrap().remove(event_id);
+    warn!("Refusing event {}: {}", event_id, reason);
+    (StatusCode::SERVICE_UNAVAILABLE, reason.to_string())
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+/// Current Unix time in seconds
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_secs())
+}
+
+/// Current time as (microseconds, days) since Unix epoch
+fn ingestion_time() -> Result<(i64, i32)> {
+    let since_epoch = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .context("Failed to get system time")?;
+    Ok((
+        since_epoch.as_micros() as i64,
+        since_epoch.as_secs() as i32 / 86400,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::testing::fixture;
+    use crate::signature::{sign, DEFAULT_TOLERANCE_SECS};
+    use axum::body::{to_bytes, Body};
+    use axum::http::Request;
+    use tower::ServiceExt;
+    use zerobus_common::testing::MockSink;
+
+    const SECRET: &str = "test-signing-secret";
+
+    fn app(queue_capacity: usize) -> (Router, mpsc::Receiver<TableSlackEvents>) {
+        let (queue, queued) = mpsc::channel(queue_capacity);
+        let state = AppState::new(SECRET, DEFAULT_TOLERANCE_SECS, 10, queue);
+        (router(state), queued)
+    }
+
+    fn request(body: Vec<u8>, timestamp: u64, signature: Option<String>) -> Request<Body> {
+        let mut request = Request::post("/slack/events")
+            .header("Content-Type", "application/json")
+            .header(TIMESTAMP_HEADER, timestamp.to_string());
+        if let Some(signature) = signature {
+            request = request.header(SIGNATURE_HEADER, signature);
+        }
+        request.body(Body::from(body)).unwrap()
+    }
+
+    fn signed(fixture_name: &str) -> Request<Body> {
+        let body = fixture(fixture_name);
+        let timestamp = unix_now();
+        let signature = sign(SECRET.as_bytes(), timestamp, &body);
+        request(body, timestamp, Some(signature))
+    }
+
+    fn retry(fixture_name: &str, retry_num: u32) -> Request<Body> {
+        let mut request = signed(fixture_name);
+        request
+            .headers_mut()
+            .insert(RETRY_NUM_HEADER, retry_num.to_string().parse().unwrap());
+        request
+    }
+
+    async fn send(app: &Router, request: Request<Body>) -> (StatusCode, String) {
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_url_verification_is_answered_with_challenge() {
+        let (app, mut queued) = app(10);
+
+        let (status, body) = send(&app, signed("url_verification.json")).await;
+
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!("3eZbrw1aBm2rZgRNFdxV2595E9CY3gmdALWMmHkvFXO7tYXAYM8P", body);
+        assert!(queued.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bad_signatures_are_unauthorized() {
+        let (app, mut queued) = app(10);
+        let body = fixture("url_verification.json");
+        let timestamp = unix_now();
+
+        let forged = sign(b"another secret", timestamp, &body);
+        let (status_forged, _) = send(&app, request(body.clone(), timestamp, Some(forged))).await;
+        let (status_missing, _) = send(&app, request(body.clone(), timestamp, None)).await;
+
+        // Signed correctly, but ten minutes ago
+        let stale = timestamp - 600;
+        let signature = sign(SECRET.as_bytes(), stale, &body);
+        let (status_stale, message) = send(&app, request(body, stale, Some(signature))).await;
+
+        assert_eq!(StatusCode::UNAUTHORIZED, status_forged);
+        assert_eq!(StatusCode::UNAUTHORIZED, status_missing);
+        assert_eq!(StatusCode::UNAUTHORIZED, status_stale);
+        assert!(
+            message.contains("outside the 300s tolerance"),
+            "{}",
+            message
+        );
+        assert!(queued.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_events_are_queued_and_ingested() {
+        let (app, queued) = app(10);
+
+        assert_eq!(StatusCode::OK, send(&app, signed("message.json")).await.0);
+        assert_eq!(
+            StatusCode::OK,
+            send(&app, signed("reaction_added.json")).await.0
+        );
+        // Answering slowly made Slack retry the message
+        let (status, body) = send(&app, retry("message.json", 1)).await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!("Duplicate event", body);
+
+        // Dropping the router closes the queue, which ends the worker
+        drop(app);
+        let sink = MockSink::default();
+        let mut pipeline = Pipeline::new(sink.clone(), 100);
+        ingest_queued(queued, &mut pipeline).await;
+
+        let rows: Vec<TableSlackEvents> = sink
+            .records()
+            .iter()
+            .map(|record| TableSlackEvents::decode(record.as_slice()).unwrap())
+            .collect();
+        let event_ids: Vec<&str> = rows.iter().map(|row| row.event_id()).collect();
+        assert_eq!(vec!["Ev07P3K9QX1A", "Ev07P3M2TB4C"], event_ids);
+        assert_eq!("C024BE91L", rows[0].channel());
+        assert_eq!(2, pipeline.summary().ingested);
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_refuses_events_until_it_drains() {
+        // No worker is reading, so the queue stays full after one event
+        let (app, mut queued) = app(1);
+
+        assert_eq!(StatusCode::OK, send(&app, signed("message.json")).await.0);
+        let (status, body) = send(&app, signed("reaction_added.json")).await;
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, status);
+        assert_eq!("Ingest queue is full", body);
+        assert_eq!(
+            StatusCode::SERVICE_UNAVAILABLE,
+            send(&app, retry("reaction_added.json", 1)).await.0
+        );
+
+        // Once the worker catches up, Slack's next retry is taken
+        assert_eq!(
+            Some("Ev07P3K9QX1A"),
+            queued.recv().await.unwrap().event_id.as_deref()
+        );
+        assert_eq!(
+            StatusCode::OK,
+            send(&app, retry("reaction_added.json", 2)).await.0
+        );
+        assert_eq!(
+            Some("Ev07P3M2TB4C"),
+            queued.recv().await.unwrap().event_id.as_deref()
+        );
+        assert!(queued.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_non_event_bodies_are_bad_request() {
+        let (app, _queued) = app(10);
+        let body = b"payload=%7B%7D".to_vec();
+        let timestamp = unix_now();
+        let signature = sign(SECRET.as_bytes(), timestamp, &body);
+
+        let (status, _) = send(&app, request(body, timestamp, Some(signature))).await;
+        assert_eq!(StatusCode::BAD_REQUEST, status);
+    }
+}
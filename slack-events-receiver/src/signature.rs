@@ -0,0 +1,165 @@
+//! Verifying the signature Slack sends with every request.
+
+use anyhow::{anyhow, bail, Context, Result};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Header holding `v0=<hex>`, the HMAC-SHA256 of `v0:<timestamp>:<body>` keyed with the
+/// app's signing secret
+pub const SIGNATURE_HEADER: &str = "X-Slack-Signature";
+
+/// Header holding the Unix time in seconds at which Slack sent the request
+pub const TIMESTAMP_HEADER: &str = "X-Slack-Request-Timestamp";
+
+/// How far a request's timestamp may be from now, the window Slack recommends
+pub const DEFAULT_TOLERANCE_SECS: u64 = 300;
+
+/// Check `signature` and `timestamp`, the values of the signature and timestamp
+/// headers, against the body, `now` being the current Unix time in seconds
+///
+/// The timestamp is covered by the signature, so refusing timestamps more than
+/// `tolerance_secs` from now keeps a captured request from being replayed later. The
+/// digest is compared in constant time, so response times do not reveal how much of a
+/// forged signature was right.
+pub fn verify(
+    secret: &[u8],
+    body: &[u8],
+    timestamp: Option<&str>,
+    signature: Option<&str>,
+    now: u64,
+    tolerance_secs: u64,
+) -> Result<()> {
+    let timestamp = timestamp.with_context(|| format!("Missing {} header", TIMESTAMP_HEADER))?;
+    let sent_at = timestamp
+        .trim()
+        .parse::<u64>()
+        .with_context(|| format!("Malformed {} header", TIMESTAMP_HEADER))?;
+    if now.abs_diff(sent_at) > tolerance_secs {
+        bail!(
+            "Request timestamp is {}s from now, outside the {}s tolerance",
+            now.abs_diff(sent_at),
+            tolerance_secs
+        );
+    }
+
+    let signature = signature.with_context(|| format!("Missing {} header", SIGNATURE_HEADER))?;
+    let digest = signature
+        .strip_prefix("v0=")
+        .and_then(|digest| hex::decode(digest).ok())
+        .with_context(|| format!("Malformed {} header", SIGNATURE_HEADER))?;
+
+    mac(secret, sent_at, body)
+        .verify_slice(&digest)
+        .map_err(|_| anyhow!("Signature does not match the request"))
+}
+
+/// The signature header value for `body` sent at `timestamp`, as Slack computes it
+pub fn sign(secret: &[u8], timestamp: u64, body: &[u8]) -> String {
+    format!(
+        "v0={}",
+        hex::encode(mac(secret, timestamp, body).finalize().into_bytes())
+    )
+}
+
+fn mac(secret: &[u8], timestamp: u64, body: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(format!("v0:{}:", timestamp).as_bytes());
+    mac.update(body);
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Example from Slack's "Verifying requests from Slack" guide
+    const SECRET: &[u8] = b"8f742231b10e8888abcd99yyyzzz85a5";
+    const TIMESTAMP: u64 = 1531420618;
+    const BODY: &[u8] = concat!(
+        "token=xyzz0WbapA4vBCDEFasx0q6G&team_id=T1DC2JH3J&team_domain=testteamnow",
+        "&channel_id=G8PSS9T3V&channel_name=foobar&user_id=U2CERLKJA&user_name=roadrunner",
+        "&command=%2Fwebhook-collect&text=",
+        "&response_url=https%3A%2F%2Fhooks.slack.com%2Fcommands%2FT1DC2JH3J%2F397700885554",
+        "%2F96rGlfmibIGlgcZRskXaIFfN",
+        "&trigger_id=398738663015.47445629121.803a0bc887a14d10d2c447fce8b6703c"
+    )
+    .as_bytes();
+    const SIGNATURE: &str = "v0=a2114d57b48eac39b9ad189dd8316235a7b4a8d21a10bd27519666489c69b503";
+
+    fn check(
+        body: &[u8],
+        timestamp: Option<&str>,
+        signature: Option<&str>,
+        now: u64,
+    ) -> Result<()> {
+        verify(
+            SECRET,
+            body,
+            timestamp,
+            signature,
+            now,
+            DEFAULT_TOLERANCE_SECS,
+        )
+    }
+
+    #[test]
+    fn test_valid_signature() {
+        assert_eq!(SIGNATURE, sign(SECRET, TIMESTAMP, BODY));
+        let timestamp = TIMESTAMP.to_string();
+        check(BODY, Some(&timestamp), Some(SIGNATURE), TIMESTAMP + 10).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_signature() {
+        let timestamp = TIMESTAMP.to_string();
+        let error = verify(
+            b"wrong secret",
+            BODY,
+            Some(&timestamp),
+            Some(SIGNATURE),
+            TIMESTAMP,
+            DEFAULT_TOLERANCE_SECS,
+        )
+        .unwrap_err();
+        assert_eq!("Signature does not match the request", error.to_string());
+        assert!(check(
+            b"token=forged",
+            Some(&timestamp),
+            Some(SIGNATURE),
+            TIMESTAMP
+        )
+        .is_err());
+
+        // The timestamp is signed too
+        let shifted = (TIMESTAMP + 1).to_string();
+        assert!(check(BODY, Some(&shifted), Some(SIGNATURE), TIMESTAMP).is_err());
+
+        assert!(check(BODY, Some(&timestamp), Some(&SIGNATURE[3..]), TIMESTAMP).is_err());
+        assert!(check(BODY, Some(&timestamp), Some("v0=not-hex"), TIMESTAMP).is_err());
+        assert!(check(BODY, Some(&timestamp), None, TIMESTAMP).is_err());
+    }
+
+    #[test]
+    fn test_timestamp_tolerance() {
+        let timestamp = TIMESTAMP.to_string();
+        let within = TIMESTAMP + DEFAULT_TOLERANCE_SECS;
+        check(BODY, Some(&timestamp), Some(SIGNATURE), within).unwrap();
+
+        // A replay six minutes later, or a request from a clock running ahead
+        let error = check(BODY, Some(&timestamp), Some(SIGNATURE), TIMESTAMP + 360).unwrap_err();
+        assert_eq!(
+            "Request timestamp is 360s from now, outside the 300s tolerance",
+            error.to_string()
+        );
+        assert!(check(BODY, Some(&timestamp), Some(SIGNATURE), TIMESTAMP - 360).is_err());
+
+        let error = check(BODY, None, Some(SIGNATURE), TIMESTAMP).unwrap_err();
+        assert_eq!(
+            "Missing X-Slack-Request-Timestamp header",
+            error.to_string()
+        );
+        assert!(check(BODY, Some("yesterday"), Some(SIGNATURE), TIMESTAMP).is_err());
+    }
+}
@@ -0,0 +1,15 @@
+-- A staging table as an edge device might keep it. Load it with
+--   sqlite3 edge.db < examples/readings.sql
+CREATE TABLE IF NOT EXISTS readings (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    sensor TEXT NOT NULL,
+    value REAL,
+    payload BLOB,
+    recorded_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    synced INTEGER NOT NULL DEFAULT 0
+);
+
+INSERT INTO readings (sensor, value, payload) VALUES
+    ('boiler-1', 71.5, X'01ff'),
+    ('boiler-2', '68', NULL),
+    ('boiler-3', NULL, NULL);
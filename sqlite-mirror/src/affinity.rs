@@ -0,0 +1,286 @@
+//! Turning SQLite values into JSON the dynamic encoder accepts for each field
+//!
+//! SQLite stores a value with whatever type it was written with, whatever the column
+//! was declared as: an `INTEGER` column can hold `'42'` as TEXT, a `BOOLEAN` column
+//! holds 0 and 1, and timestamps are usually TEXT such as `2024-09-25 17:15:36`. The
+//! conversions here follow SQLite's own affinity rules, driven by the type of the field
+//! the column lands in, so the encoder sees values of the field's JSON type even with
+//! `COERCE=false`.
+
+use base64::{engine::general_purpose, Engine as _};
+use chrono::{NaiveDate, NaiveDateTime};
+use prost_types::field_descriptor_proto::{Label, Type};
+use prost_types::DescriptorProto;
+use rusqlite::types::ValueRef;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+/// Text formats SQLite's date and time functions read and write
+const DATETIME_FORMATS: &[&str] = &[
+    "%Y-%m-%d %H:%M:%S%.f",
+    "%Y-%m-%dT%H:%M:%S%.f",
+    "%Y-%m-%d %H:%M:%S%.fZ",
+    "%Y-%m-%dT%H:%M:%S%.fZ",
+    "%Y-%m-%d %H:%M",
+    "%Y-%m-%dT%H:%M",
+];
+
+/// How a column's values are converted, from the field of the same name
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Target {
+    Bool,
+    /// `int32`: also the type of DATE columns, in days since the epoch
+    Int32,
+    /// `int64`: also the type of TIMESTAMP columns, in microseconds since the epoch
+    Int64,
+    /// Every other integer and floating point type
+    Number,
+    String,
+    Bytes,
+    /// A nested message or a repeated field, read from TEXT holding JSON
+    Json,
+    Other,
+}
+
+/// The table's fields by name
+pub struct FieldTypes {
+    targets: HashMap<String, Target>,
+}
+
+impl FieldTypes {
+    pub fn new(descriptor: &DescriptorProto) -> Self {
+        let targets = descriptor
+            .field
+            .iter()
+            .map(|field| {
+                let target = if field.label() == Label::Repeated {
+                    Target::Json
+                } else {
+                    match field.r#type() {
+                        Type::Bool => Target::Bool,
+                        Type::Int32 | Type::Sint32 | Type::Sfixed32 => Target::Int32,
+                        Type::Int64 | Type::Sint64 | Type::Sfixed64 => Target::Int64,
+                        Type::Double
+                        | Type::Float
+                        | Type::Uint32
+                        | Type::Uint64
+                        | Type::Fixed32
+                        | Type::Fixed64 => Target::Number,
+                        Type::String => Target::String,
+                        Type::Bytes => Target::Bytes,
+                        Type::Message | Type::Group => Target::Json,
+                        Type::Enum => Target::Other,
+                    }
+                };
+                (field.name().to_string(), target)
+            })
+            .collect();
+        Self { targets }
+    }
+
+    /// Build the JSON object for one row from its column names and values
+    ///
+    /// NULLs become `null`, which the encoder leaves unset. Columns without a field keep
+    /// their SQLite type (BLOBs as base64), for the encoder to refuse or drop.
+    pub fn to_json<'a>(
+        &self,
+        columns: impl IntoIterator<Item = (&'a str, ValueRef<'a>)>,
+    ) -> Map<String, Value> {
+        columns
+            .into_iter()
+            .map(|(column, value)| {
+                let target = self.targets.get(column).copied().unwrap_or(Target::Other);
+                (column.to_string(), convert(target, value))
+            })
+            .collect()
+    }
+}
+
+fn convert(target: Target, value: ValueRef) -> Value {
+    match value {
+        ValueRef::Null => Value::Null,
+        ValueRef::Integer(i) => match target {
+            Target::Bool => Value::Bool(i != 0),
+            Target::String => Value::String(i.to_string()),
+            Target::Bytes => base64(i.to_string().as_bytes()),
+            _ => Value::from(i),
+        },
+        ValueRef::Real(f) => match target {
+            Target::Bool => Value::Bool(f != 0.0),
+            Target::String => Value::String(f.to_string()),
+            Target::Bytes => base64(f.to_string().as_bytes()),
+            // NaN and infinity have no JSON number; the encoder parses them from text
+            _ => serde_json::Number::from_f64(f)
+                .map(Value::Number)
+                .unwrap_or_else(|| Value::String(f.to_string())),
+        },
+        ValueRef::Text(bytes) => match target {
+            Target::Bytes => base64(bytes),
+            _ => convert_text(target, &String::from_utf8_lossy(bytes)),
+        },
+        ValueRef::Blob(bytes) => match (target, std::str::from_utf8(bytes)) {
+            (Target::Bytes | Target::Other, _) | (_, Err(_)) => base64(bytes),
+            // Text written as a BLOB, e.g. by a client that binds every string as bytes
+            (_, Ok(text)) => convert_text(target, text),
+        },
+    }
+}
+
+/// TEXT in a numeric field is converted when it looks like a number, as SQLite does
+/// when storing into a column with NUMERIC affinity, and dates and times become the
+/// day or microsecond counts of DATE and TIMESTAMP columns. Text that fits none of
+/// these is passed on as a string for the encoder to coerce or refuse.
+fn convert_text(target: Target, text: &str) -> Value {
+    let number = || {
+        let trimmed = text.trim();
+        trimmed.parse::<i64>().map(Value::from).ok().or_else(|| {
+            trimmed
+                .parse::<f64>()
+                .ok()
+                .filter(|f| f.is_finite())
+                .and_then(serde_json::Number::from_f64)
+                .map(Value::Number)
+        })
+    };
+    let converted = match target {
+        Target::Bool => number()
+            .map(|n| Value::Bool(n.as_f64() != Some(0.0)))
+            .or_else(|| match text.trim().to_ascii_lowercase().as_str() {
+                "true" => Some(Value::Bool(true)),
+                "false" => Some(Value::Bool(false)),
+                _ => None,
+            }),
+        Target::Int32 => number().or_else(|| days(text).map(Value::from)),
+        Target::Int64 => number().or_else(|| micros(text).map(Value::from)),
+        Target::Number => number(),
+        Target::Json => serde_json::from_str(text).ok(),
+        Target::String | Target::Bytes | Target::Other => None,
+    };
+    converted.unwrap_or_else(|| Value::String(text.to_string()))
+}
+
+/// `YYYY-MM-DD` as days since the Unix epoch
+fn days(text: &str) -> Option<i64> {
+    let date = NaiveDate::parse_from_str(text.trim(), "%Y-%m-%d").ok()?;
+    Some((date - NaiveDate::from_ymd_opt(1970, 1, 1)?).num_days())
+}
+
+/// A date and time, taken as UTC like SQLite's `CURRENT_TIMESTAMP`, as microseconds
+/// since the Unix epoch
+fn micros(text: &str) -> Option<i64> {
+    let text = text.trim();
+    DATETIME_FORMATS
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(text, format).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(text, "%Y-%m-%d")
+                .ok()
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+        })
+        .map(|datetime| datetime.and_utc().timestamp_micros())
+}
+
+fn base64(bytes: &[u8]) -> Value {
+    Value::String(general_purpose::STANDARD.encode(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost_types::FieldDescriptorProto;
+    use serde_json::json;
+
+    fn field(name: &str, r#type: Type, label: Label) -> FieldDescriptorProto {
+        FieldDescriptorProto {
+            name: Some(name.to_string()),
+            r#type: Some(r#type as i32),
+            label: Some(label as i32),
+            ..Default::default()
+        }
+    }
+
+    fn types() -> FieldTypes {
+        FieldTypes::new(&DescriptorProto {
+            name: Some("table_readings".to_string()),
+            field: vec![
+                field("id", Type::Int64, Label::Optional),
+                field("value", Type::Double, Label::Optional),
+                field("ok", Type::Bool, Label::Optional),
+                field("sensor", Type::String, Label::Optional),
+                field("raw", Type::Bytes, Label::Optional),
+                field("day", Type::Int32, Label::Optional),
+                field("tags", Type::String, Label::Repeated),
+            ],
+            ..Default::default()
+        })
+    }
+
+    fn convert(column: &str, value: ValueRef) -> Value {
+        types().to_json([(column, value)]).remove(column).unwrap()
+    }
+
+    #[test]
+    fn test_numbers_stored_as_text() {
+        assert_eq!(json!(42), convert("id", ValueRef::Text(b"42")));
+        assert_eq!(json!(42), convert("id", ValueRef::Text(b" 42 ")));
+        assert_eq!(json!(21.5), convert("value", ValueRef::Text(b"21.5")));
+        assert_eq!(json!(3), convert("value", ValueRef::Text(b"3")));
+        // Left for the encoder, which fails the field unless FIELD_ERROR_MODE=null
+        assert_eq!(json!("n/a"), convert("value", ValueRef::Text(b"n/a")));
+
+        assert_eq!(json!(true), convert("ok", ValueRef::Integer(1)));
+        assert_eq!(json!(false), convert("ok", ValueRef::Text(b"0")));
+        assert_eq!(json!(true), convert("ok", ValueRef::Text(b"TRUE")));
+        assert_eq!(json!(true), convert("ok", ValueRef::Real(0.5)));
+
+        assert_eq!(json!("7"), convert("sensor", ValueRef::Integer(7)));
+        assert_eq!(json!(1.5), convert("value", ValueRef::Real(1.5)));
+        assert_eq!(
+            json!("inf"),
+            convert("value", ValueRef::Real(f64::INFINITY))
+        );
+    }
+
+    #[test]
+    fn test_dates_and_times() {
+        assert_eq!(
+            json!(1_727_284_536_000_000_i64),
+            convert("id", ValueRef::Text(b"2024-09-25 17:15:36"))
+        );
+        assert_eq!(
+            json!(1_727_284_536_250_000_i64),
+            convert("id", ValueRef::Text(b"2024-09-25T17:15:36.25Z"))
+        );
+        assert_eq!(json!(19_991), convert("day", ValueRef::Text(b"2024-09-25")));
+        // Only integer fields take dates
+        assert_eq!(
+            json!("2024-09-25"),
+            convert("sensor", ValueRef::Text(b"2024-09-25"))
+        );
+    }
+
+    #[test]
+    fn test_nulls_blobs_and_json() {
+        let row = types().to_json([
+            ("id", ValueRef::Null),
+            ("raw", ValueRef::Blob(&[0xde, 0xad, 0xbe, 0xef])),
+            ("sensor", ValueRef::Blob(b"boiler-2")),
+            ("tags", ValueRef::Text(br#"["a", "b"]"#)),
+            ("extra", ValueRef::Blob(&[0xff])),
+        ]);
+        assert_eq!(
+            json!({
+                "id": null,
+                "raw": "3q2+7w==",
+                "sensor": "boiler-2",
+                "tags": ["a", "b"],
+                "extra": "/w==",
+            }),
+            Value::Object(row)
+        );
+
+        assert_eq!(json!("aGk="), convert("raw", ValueRef::Text(b"hi")));
+        // Not UTF-8, so it cannot be text
+        assert_eq!(json!("/w=="), convert("sensor", ValueRef::Blob(&[0xff])));
+    }
+}
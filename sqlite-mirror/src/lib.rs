@@ -0,0 +1,4 @@
+pub mod affinity;
+pub mod mirror;
+pub mod source;
+pub mod state;
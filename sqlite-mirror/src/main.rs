@@ -0,0 +1,240 @@
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use databricks_zerobus_ingest_sdk::{StreamConfigurationOptions, TableProperties, ZerobusSdk};
+use sqlite_mirror::mirror::{Mirror, SyncSummary};
+use sqlite_mirror::source::{OnSync, Source, SourceOptions, Watermark};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::{error, info};
+use zerobus_common::descriptor::find_message_descriptor;
+use zerobus_common::dynamic::{coerce_from_env, DynamicEncoder, FieldErrorMode, KeyCase};
+use zerobus_common::pipeline::{IngestSink, Pipeline};
+use zerobus_common::shutdown;
+
+/// What to do with rows once they are acknowledged
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum OnSyncArg {
+    /// Leave them; only the watermark moves
+    Keep,
+    /// Delete them
+    Delete,
+    /// Set --synced-column to 1
+    Flag,
+}
+
+/// Mirror a table of a SQLite database into a Unity Catalog table
+///
+/// Rows past a watermark are read in batches, and the watermark, kept in the database
+/// itself, only moves once every row of a batch is acknowledged. Without --interval,
+/// the table is synced until caught up and the command exits, for running from cron or
+/// a systemd timer.
+#[derive(Parser, Debug)]
+#[command(name = "zb-sqlite-mirror", version)]
+struct Args {
+    /// SQLite database file; other processes may write to it while it is mirrored
+    #[arg(long)]
+    database: PathBuf,
+
+    /// Table of the database to mirror
+    #[arg(long)]
+    source_table: String,
+
+    /// Target table, e.g. main.edge.readings
+    #[arg(long)]
+    table: String,
+
+    /// Descriptor set and message to encode rows with, as <path>#<message>
+    #[arg(long)]
+    descriptor: String,
+
+    /// Column rows are read in order of: rowid, or a column that only grows, such as
+    /// an insertion timestamp
+    #[arg(long, default_value = "rowid")]
+    watermark_column: String,
+
+    #[arg(long, value_enum, default_value_t = OnSyncArg::Keep)]
+    on_sync: OnSyncArg,
+
+    /// Column set to 1 on synced rows, with --on-sync flag
+    #[arg(long)]
+    synced_column: Option<String>,
+
+    /// Rows per batch; the watermark is saved after each
+    #[arg(long, default_value_t = 1000)]
+    batch_size: usize,
+
+    /// Keep running, syncing new rows every this many seconds
+    #[arg(long)]
+    interval: Option<u64>,
+
+    /// How long to wait for another process's write lock, in milliseconds
+    #[arg(long, default_value_t = 5000)]
+    busy_timeout_ms: u64,
+
+    /// Leave the database's journal mode alone instead of switching it to WAL
+    #[arg(long)]
+    no_wal: bool,
+
+    /// Table of the database the watermark is kept in
+    #[arg(long, default_value = "zerobus_mirror_state")]
+    state_table: String,
+
+    /// Drop columns the target table does not have instead of failing the row
+    #[arg(long)]
+    ignore_unknown_fields: bool,
+
+    /// With --ignore-unknown-fields, log every row whose extra columns are dropped
+    #[arg(long)]
+    warn_unknown_fields: bool,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .with_target(false)
+        .init();
+
+    let args = Args::parse();
+
+    let zerobus_endpoint = std::env::var("ZEROBUS_ENDPOINT")
+        .context("ZEROBUS_ENDPOINT environment variable must be set")?;
+    let databricks_host = std::env::var("DATABRICKS_HOST")
+        .context("DATABRICKS_HOST environment variable must be set")?;
+    let client_id = std::env::var("DATABRICKS_CLIENT_ID")
+        .context("DATABRICKS_CLIENT_ID environment variable must be set")?;
+    let client_secret = std::env::var("DATABRICKS_CLIENT_SECRET")
+        .context("DATABRICKS_CLIENT_SECRET environment variable must be set")?;
+
+    let on_sync = match (args.on_sync, args.synced_column) {
+        (OnSyncArg::Flag, Some(column)) => OnSync::Flag(column),
+        (OnSyncArg::Flag, None) => bail!("--on-sync flag needs --synced-column"),
+        (_, Some(_)) => bail!("--synced-column only applies to --on-sync flag"),
+        (OnSyncArg::Keep, None) => OnSync::Keep,
+        (OnSyncArg::Delete, None) => OnSync::Delete,
+    };
+    let source = Source::open(
+        &args.database,
+        SourceOptions {
+            table: args.source_table.clone(),
+            watermark: Watermark::parse(&args.watermark_column),
+            on_sync,
+            state_table: args.state_table,
+            busy_timeout: Duration::from_millis(args.busy_timeout_ms),
+            enable_wal: !args.no_wal,
+        },
+    )?;
+
+    let Some((descriptor_path, message_name)) = args.descriptor.rsplit_once('#') else {
+        bail!(
+            "--descriptor must be <path>#<message>, got {:?}",
+            args.descriptor
+        );
+    };
+    let descriptor_bytes = std::fs::read(descriptor_path)
+        .with_context(|| format!("Failed to read descriptor {}", descriptor_path))?;
+    let descriptor_proto = find_message_descriptor(&descriptor_bytes, message_name)?;
+    let encoder = DynamicEncoder::new(&descriptor_proto)?
+        .ignore_unknown_fields(args.ignore_unknown_fields)
+        .warn_unknown_fields(args.warn_unknown_fields)
+        .coerce_types(coerce_from_env()?)
+        .field_error_mode(FieldErrorMode::from_env()?)
+        .key_case(KeyCase::from_env()?);
+
+    let max_inflight = args.batch_size.max(1);
+    let sdk = ZerobusSdk::new(zerobus_endpoint, databricks_host)?;
+    let table_properties = TableProperties {
+        table_name: args.table.clone(),
+        descriptor_proto: descriptor_proto.clone(),
+    };
+    let stream_options = StreamConfigurationOptions {
+        max_inflight_records: max_inflight,
+        ..Default::default()
+    };
+    let stream = sdk
+        .create_stream(
+            table_properties,
+            client_id,
+            client_secret,
+            Some(stream_options),
+        )
+        .await
+        .context("Failed to create stream")?;
+    info!("Created stream to table: {}", args.table);
+
+    let mut mirror = Mirror::new(
+        source,
+        &descriptor_proto,
+        encoder,
+        Pipeline::new(stream, max_inflight),
+        args.batch_size,
+    )?;
+    match mirror.position() {
+        Some(position) => info!(
+            "Resuming {} after rowid {}",
+            args.source_table, position.rowid
+        ),
+        None => info!(
+            "No saved watermark; mirroring {} from the start",
+            args.source_table
+        ),
+    }
+
+    let result = match args.interval {
+        Some(interval) => run(&mut mirror, Duration::from_secs(interval.max(1))).await,
+        None => mirror.sync().await,
+    };
+    if let Err(e) = &result {
+        error!("Sync stopped: {:#}", e);
+    }
+
+    let records_with_unknown_fields = mirror.encoder().records_with_unknown_fields();
+    let fields_left_unset = mirror.encoder().fields_left_unset();
+    let summary = mirror.finish().await?;
+    info!(
+        "Shut down: {} rows ingested, {} failed",
+        summary.ingested, summary.failed
+    );
+    if records_with_unknown_fields > 0 {
+        info!(
+            "{} rows had columns the table does not have; those columns were dropped",
+            records_with_unknown_fields
+        );
+    }
+    if fields_left_unset > 0 {
+        info!(
+            "{} values could not be converted and were left unset",
+            fields_left_unset
+        );
+    }
+
+    let total = result?;
+    if total.malformed > 0 {
+        bail!(
+            "{} of {} rows could not be encoded and were skipped",
+            total.malformed,
+            total.read
+        );
+    }
+    Ok(())
+}
+
+/// Sync every `interval` until Ctrl+C or SIGTERM, which is only acted on once the
+/// table is caught up so a batch is never abandoned halfway
+async fn run<S: IngestSink>(mirror: &mut Mirror<S>, interval: Duration) -> Result<SyncSummary> {
+    let (stop, mut stopped) = watch::channel(false);
+    tokio::spawn(async move {
+        shutdown::signal().await;
+        let _ = stop.send(true);
+    });
+
+    let mut total = SyncSummary::default();
+    loop {
+        total.add(&mirror.sync().await?);
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            _ = stopped.wait_for(|stopped| *stopped) => return Ok(total),
+        }
+    }
+}
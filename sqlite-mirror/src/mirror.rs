@@ -0,0 +1,327 @@
+//! Ingesting batches of rows and advancing the watermark once they are acknowledged
+
+use anyhow::{Context, Result};
+use prost_types::DescriptorProto;
+use rusqlite::types::ValueRef;
+use serde_json::Value;
+use tracing::{info, warn};
+use zerobus_common::dynamic::DynamicEncoder;
+use zerobus_common::pipeline::{IngestSink, IngestSummary, Pipeline};
+
+use crate::affinity::FieldTypes;
+use crate::source::Source;
+use crate::state::Position;
+
+/// Counts for one batch, or added up over several
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SyncSummary {
+    /// Rows read from the source table
+    pub read: u64,
+    /// Rows that could not be encoded; they are skipped, and the watermark passes them
+    pub malformed: u64,
+}
+
+impl SyncSummary {
+    pub fn add(&mut self, other: &SyncSummary) {
+        self.read += other.read;
+        self.malformed += other.malformed;
+    }
+}
+
+pub struct Mirror<S: IngestSink> {
+    source: Source,
+    types: FieldTypes,
+    encoder: DynamicEncoder,
+    pipeline: Pipeline<S>,
+    batch_size: usize,
+    position: Option<Position>,
+}
+
+impl<S: IngestSink> Mirror<S> {
+    /// Resume from the position saved in the database
+    pub fn new(
+        source: Source,
+        descriptor: &DescriptorProto,
+        encoder: DynamicEncoder,
+        pipeline: Pipeline<S>,
+        batch_size: usize,
+    ) -> Result<Self> {
+        let position = source.load_position()?;
+        Ok(Self {
+            source,
+            types: FieldTypes::new(descriptor),
+            encoder,
+            pipeline,
+            batch_size: batch_size.max(1),
+            position,
+        })
+    }
+
+    pub fn position(&self) -> Option<&Position> {
+        self.position.as_ref()
+    }
+
+    pub fn encoder(&self) -> &DynamicEncoder {
+        &self.encoder
+    }
+
+    /// Ingest the next batch, then delete or flag its rows and save the watermark
+    ///
+    /// The watermark only moves once every row of the batch is acknowledged. If any
+    /// is not, this fails and the next attempt reads the same rows again, so rows that
+    /// were acknowledged are sent twice rather than a row being lost.
+    pub async fn sync_batch(&mut self) -> Result<SyncSummary> {
+        let batch = self
+            .source
+            .read_batch(self.position.as_ref(), self.batch_size)?;
+        let Some(end) = batch.end() else {
+            return Ok(SyncSummary::default());
+        };
+
+        let mut summary = SyncSummary {
+            read: batch.rows.len() as u64,
+            malformed: 0,
+        };
+        let mut records = Vec::with_capacity(batch.rows.len());
+        for row in &batch.rows {
+            let columns = batch.columns.iter().map(String::as_str);
+            let values = row.values.iter().map(ValueRef::from);
+            let object = self.types.to_json(columns.zip(values));
+            match self.encoder.encode(&Value::Object(object)) {
+                Ok(record) => records.push(record),
+                Err(e) => {
+                    warn!(
+                        "Skipping row {} of {}: {:#}",
+                        row.rowid,
+                        self.source.table(),
+                        e
+                    );
+                    summary.malformed += 1;
+                }
+            }
+        }
+
+        self.pipeline.ingest_batch(records).await.with_context(|| {
+            format!(
+                "Rows up to rowid {} were not all acknowledged; the watermark was not advanced",
+                end.rowid
+            )
+        })?;
+        self.source.commit(&batch, &end)?;
+        self.position = Some(end);
+        Ok(summary)
+    }
+
+    /// Sync batches until one comes back short, meaning the table has been caught up
+    pub async fn sync(&mut self) -> Result<SyncSummary> {
+        let mut total = SyncSummary::default();
+        loop {
+            let summary = self.sync_batch().await?;
+            total.add(&summary);
+            if summary.read < self.batch_size as u64 {
+                break;
+            }
+        }
+        if total.read > 0 {
+            info!(
+                "Synced {} rows of {} ({} malformed); watermark at rowid {}",
+                total.read,
+                self.source.table(),
+                total.malformed,
+                self.position.as_ref().map_or(0, |position| position.rowid)
+            );
+        }
+        Ok(total)
+    }
+
+    pub async fn finish(self) -> Result<IngestSummary> {
+        self.pipeline.finish().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::testing::{database, options};
+    use crate::source::OnSync;
+    use prost::Message;
+    use prost_types::field_descriptor_proto::{Label, Type};
+    use prost_types::FieldDescriptorProto;
+    use rusqlite::Connection;
+    use std::path::Path;
+    use zerobus_common::testing::MockSink;
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct Reading {
+        #[prost(int64, optional, tag = "1")]
+        id: Option<i64>,
+        #[prost(string, optional, tag = "2")]
+        sensor: Option<String>,
+        #[prost(double, optional, tag = "3")]
+        value: Option<f64>,
+        #[prost(int64, optional, tag = "4")]
+        recorded_at: Option<i64>,
+    }
+
+    fn descriptor() -> DescriptorProto {
+        let field = |name: &str, number: i32, r#type: Type| FieldDescriptorProto {
+            name: Some(name.to_string()),
+            number: Some(number),
+            r#type: Some(r#type as i32),
+            label: Some(Label::Optional as i32),
+            ..Default::default()
+        };
+        DescriptorProto {
+            name: Some("table_readings".to_string()),
+            field: vec![
+                field("id", 1, Type::Int64),
+                field("sensor", 2, Type::String),
+                field("value", 3, Type::Double),
+                field("recorded_at", 4, Type::Int64),
+            ],
+            ..Default::default()
+        }
+    }
+
+    fn mirror(path: &Path, watermark: &str, on_sync: OnSync, sink: MockSink) -> Mirror<MockSink> {
+        let source = Source::open(path, options(watermark, on_sync)).unwrap();
+        let descriptor = descriptor();
+        // The flag column is not a column of the table
+        let encoder = DynamicEncoder::new(&descriptor)
+            .unwrap()
+            .ignore_unknown_fields(true);
+        Mirror::new(source, &descriptor, encoder, Pipeline::new(sink, 100), 2).unwrap()
+    }
+
+    fn readings(sink: &MockSink) -> Vec<Reading> {
+        sink.records()
+            .iter()
+            .map(|record| Reading::decode(record.as_slice()).unwrap())
+            .collect()
+    }
+
+    fn count(path: &Path, sql: &str) -> i64 {
+        let conn = Connection::open(path).unwrap();
+        conn.query_row(sql, [], |row| row.get(0)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_resume_from_watermark() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = database(
+            dir.path(),
+            "INSERT INTO readings (sensor, value, recorded_at) VALUES
+                ('boiler-1', 71.5, '2024-09-25 17:15:36'),
+                ('boiler-2', '68', '2024-09-25 17:15:37'),
+                (X'626f696c65722d33', NULL, 1727284538000000);",
+        );
+
+        let sink = MockSink::default();
+        let mut first = mirror(&path, "rowid", OnSync::Keep, sink.clone());
+        let summary = first.sync().await.unwrap();
+        assert_eq!(3, summary.read);
+        assert_eq!(0, summary.malformed);
+        assert_eq!(3, first.position().unwrap().rowid);
+        first.finish().await.unwrap();
+
+        let rows = readings(&sink);
+        assert_eq!(Some(71.5), rows[0].value);
+        assert_eq!(Some(1_727_284_536_000_000), rows[0].recorded_at);
+        // Written as TEXT into the untyped column
+        assert_eq!(Some(68.0), rows[1].value);
+        assert_eq!(Some("boiler-3".to_string()), rows[2].sensor);
+        assert_eq!(None, rows[2].value);
+        // An INTEGER the TEXT column stored as text
+        assert_eq!(Some(1_727_284_538_000_000), rows[2].recorded_at);
+
+        // Written while the mirror was not running
+        Connection::open(&path)
+            .unwrap()
+            .execute_batch(
+                "INSERT INTO readings (sensor, value) VALUES
+                    ('boiler-4', 70),
+                    ('boiler-5', 'n/a');",
+            )
+            .unwrap();
+        let sink = MockSink::default();
+        let mut second = mirror(&path, "rowid", OnSync::Keep, sink.clone());
+        assert_eq!(3, second.position().unwrap().rowid);
+        let summary = second.sync().await.unwrap();
+        assert_eq!(2, summary.read);
+        assert_eq!(1, summary.malformed);
+        let rows = readings(&sink);
+        assert_eq!(1, rows.len());
+        assert_eq!(Some(4), rows[0].id);
+        assert_eq!(5, second.position().unwrap().rowid);
+        assert_eq!(5, count(&path, "SELECT count(*) FROM readings"));
+    }
+
+    #[tokio::test]
+    async fn test_failed_acks_keep_watermark() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = database(
+            dir.path(),
+            "INSERT INTO readings (sensor) VALUES ('boiler-1'), ('boiler-2'), ('boiler-3');",
+        );
+
+        let sink = MockSink::default().fail_acks_for(|record| {
+            Reading::decode(record).unwrap().sensor.as_deref() == Some("boiler-3")
+        });
+        let mut mirror = mirror(&path, "rowid", OnSync::Delete, sink.clone());
+        let error = mirror.sync().await.unwrap_err();
+        assert_eq!(
+            "Rows up to rowid 3 were not all acknowledged; the watermark was not advanced",
+            error.to_string()
+        );
+        // The first batch went through; the second is read again next time
+        assert_eq!(2, mirror.position().unwrap().rowid);
+        assert_eq!(1, count(&path, "SELECT count(*) FROM readings"));
+        assert_eq!(
+            2,
+            count(&path, "SELECT last_rowid FROM zerobus_mirror_state")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delete_and_flag_synced_rows() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = database(
+            dir.path(),
+            "INSERT INTO readings (sensor, recorded_at) VALUES
+                ('boiler-1', '2024-09-25 17:15:36'),
+                ('boiler-2', '2024-09-25 17:15:36'),
+                ('boiler-3', NULL);",
+        );
+
+        let sink = MockSink::default();
+        let flag = OnSync::Flag("synced".to_string());
+        let mut mirror = mirror(&path, "recorded_at", flag, sink.clone());
+        assert_eq!(2, mirror.sync().await.unwrap().read);
+        assert_eq!(
+            2,
+            count(&path, "SELECT count(*) FROM readings WHERE synced = 1")
+        );
+        // Rows without a watermark are never read
+        assert_eq!(
+            1,
+            count(&path, "SELECT count(*) FROM readings WHERE synced IS NULL")
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = database(
+            dir.path(),
+            "INSERT INTO readings (sensor) VALUES ('boiler-1'), ('boiler-2'), ('boiler-3');",
+        );
+        let mut mirror = self::mirror(&path, "rowid", OnSync::Delete, MockSink::default());
+        assert_eq!(3, mirror.sync().await.unwrap().read);
+        assert_eq!(0, count(&path, "SELECT count(*) FROM readings"));
+
+        // AUTOINCREMENT keeps the next row ahead of the watermark
+        Connection::open(&path)
+            .unwrap()
+            .execute("INSERT INTO readings (sensor) VALUES ('boiler-4')", [])
+            .unwrap();
+        assert_eq!(1, mirror.sync().await.unwrap().read);
+        assert_eq!(4, mirror.position().unwrap().rowid);
+    }
+}
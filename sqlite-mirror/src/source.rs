@@ -0,0 +1,388 @@
+//! Reading batches of rows past the watermark from a database other processes write to
+
+use anyhow::{bail, Context, Result};
+use rusqlite::types::Value as SqlValue;
+use rusqlite::{params, params_from_iter, Connection, OpenFlags, OptionalExtension};
+use rusqlite::{ToSql, TransactionBehavior};
+use std::path::Path;
+use std::time::Duration;
+use tracing::warn;
+
+use crate::state::{Position, StateTable};
+
+/// The column rows are read in order of
+#[derive(Debug, Clone, PartialEq)]
+pub enum Watermark {
+    Rowid,
+    /// A column that only grows, such as an insertion timestamp
+    Column(String),
+}
+
+impl Watermark {
+    pub fn parse(column: &str) -> Self {
+        match column.to_ascii_lowercase().as_str() {
+            "rowid" | "_rowid_" | "oid" => Self::Rowid,
+            _ => Self::Column(column.to_string()),
+        }
+    }
+
+    pub fn column(&self) -> &str {
+        match self {
+            Self::Rowid => "rowid",
+            Self::Column(column) => column,
+        }
+    }
+}
+
+/// What happens to rows once they are acknowledged
+#[derive(Debug, Clone, PartialEq)]
+pub enum OnSync {
+    Keep,
+    Delete,
+    /// Set the column to 1
+    Flag(String),
+}
+
+pub struct SourceOptions {
+    pub table: String,
+    pub watermark: Watermark,
+    pub on_sync: OnSync,
+    pub state_table: String,
+    /// How long to wait for a writer's lock before failing
+    pub busy_timeout: Duration,
+    /// Switch the database to write-ahead logging, so reads and writes do not block
+    /// each other
+    pub enable_wal: bool,
+}
+
+/// A row read from the source table
+#[derive(Debug, Clone)]
+pub struct SourceRow {
+    pub rowid: i64,
+    /// The watermark column's value; NULL with the rowid as the watermark
+    pub watermark: SqlValue,
+    /// In the order of [`Batch::columns`]
+    pub values: Vec<SqlValue>,
+}
+
+#[derive(Debug, Default)]
+pub struct Batch {
+    pub columns: Vec<String>,
+    pub rows: Vec<SourceRow>,
+}
+
+impl Batch {
+    /// The position after the last row, or `None` for an empty batch
+    pub fn end(&self) -> Option<Position> {
+        self.rows.last().map(|row| Position {
+            value: row.watermark.clone(),
+            rowid: row.rowid,
+        })
+    }
+}
+
+pub struct Source {
+    conn: Connection,
+    options: SourceOptions,
+    state: StateTable,
+}
+
+impl Source {
+    /// Open an existing database and check the table can be mirrored as configured
+    pub fn open(path: &Path, options: SourceOptions) -> Result<Self> {
+        let conn = Connection::open_with_flags(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_WRITE
+                | OpenFlags::SQLITE_OPEN_URI
+                | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+        conn.busy_timeout(options.busy_timeout)?;
+
+        if options.enable_wal {
+            let mode: String = conn
+                .query_row("PRAGMA journal_mode = WAL", [], |row| row.get(0))
+                .context("Failed to switch the database to WAL mode")?;
+            if !mode.eq_ignore_ascii_case("wal") {
+                warn!(
+                    "{} stays in {} journal mode; writers will block while a batch is read",
+                    path.display(),
+                    mode
+                );
+            }
+        }
+
+        check_table(&conn, &options)?;
+        let state = StateTable::new(options.state_table.clone());
+        state.create(&conn)?;
+        Ok(Self {
+            conn,
+            options,
+            state,
+        })
+    }
+
+    pub fn table(&self) -> &str {
+        &self.options.table
+    }
+
+    pub fn load_position(&self) -> Result<Option<Position>> {
+        self.state
+            .load(&self.conn, &self.options.table, &self.options.watermark)
+    }
+
+    /// Read up to `limit` rows after `after`, in watermark order
+    ///
+    /// Rows whose watermark column is NULL are never read. The statement is finished
+    /// before returning, so no read transaction stays open while the rows are ingested
+    /// and the WAL can be checkpointed in the meantime.
+    pub fn read_batch(&self, after: Option<&Position>, limit: usize) -> Result<Batch> {
+        let table = quote(&self.options.table);
+        let (watermark, filter, params): (_, _, Vec<&dyn ToSql>) =
+            match (&self.options.watermark, after) {
+                (Watermark::Rowid, None) => ("NULL".to_string(), String::new(), vec![]),
+                (Watermark::Rowid, Some(after)) => (
+                    "NULL".to_string(),
+                    "WHERE rowid > ?1".to_string(),
+                    vec![&after.rowid],
+                ),
+                (Watermark::Column(column), None) => {
+                    let column = quote(column);
+                    let filter = format!("WHERE {} IS NOT NULL", column);
+                    (column, filter, vec![])
+                }
+                (Watermark::Column(column), Some(after)) => {
+                    let column = quote(column);
+                    let filter = format!("WHERE {0} > ?1 OR ({0} = ?1 AND rowid > ?2)", column);
+                    (column, filter, vec![&after.value, &after.rowid])
+                }
+            };
+        let order = match &self.options.watermark {
+            Watermark::Rowid => "rowid".to_string(),
+            Watermark::Column(_) => format!("{}, rowid", watermark),
+        };
+        let sql = format!(
+            "SELECT rowid, {}, * FROM {} {} ORDER BY {} LIMIT {}",
+            watermark, table, filter, order, limit
+        );
+
+        let mut statement = self
+            .conn
+            .prepare(&sql)
+            .with_context(|| format!("Failed to query {}", self.options.table))?;
+        let columns: Vec<String> = statement
+            .column_names()
+            .into_iter()
+            .skip(2)
+            .map(str::to_string)
+            .collect();
+        let width = columns.len();
+        let rows = statement
+            .query_map(params_from_iter(params), |row| {
+                Ok(SourceRow {
+                    rowid: row.get(0)?,
+                    watermark: row.get(1)?,
+                    values: (0..width)
+                        .map(|i| row.get(i + 2))
+                        .collect::<rusqlite::Result<_>>()?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .with_context(|| format!("Failed to read {}", self.options.table))?;
+        Ok(Batch { columns, rows })
+    }
+
+    /// Delete or flag the batch's rows and save `position`, in one transaction
+    pub fn commit(&mut self, batch: &Batch, position: &Position) -> Result<()> {
+        let tx = self
+            .conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .context("Failed to lock the database for writing")?;
+        let table = quote(&self.options.table);
+        let update = match &self.options.on_sync {
+            OnSync::Keep => None,
+            OnSync::Delete => Some(format!("DELETE FROM {} WHERE rowid = ?1", table)),
+            OnSync::Flag(column) => Some(format!(
+                "UPDATE {} SET {} = 1 WHERE rowid = ?1",
+                table,
+                quote(column)
+            )),
+        };
+        if let Some(update) = update {
+            let mut statement = tx.prepare(&update)?;
+            for row in &batch.rows {
+                statement
+                    .execute(params![row.rowid])
+                    .with_context(|| format!("Failed to mark row {} synced", row.rowid))?;
+            }
+        }
+        self.state
+            .save(&tx, &self.options.table, &self.options.watermark, position)?;
+        tx.commit().context("Failed to commit the watermark")
+    }
+}
+
+/// Quote an identifier for SQL
+pub fn quote(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
+
+fn check_table(conn: &Connection, options: &SourceOptions) -> Result<()> {
+    let sql: String = conn
+        .query_row(
+            "SELECT sql FROM sqlite_master WHERE type = 'table' AND name = ?1",
+            params![options.table],
+            |row| row.get(0),
+        )
+        .optional()?
+        .with_context(|| format!("No table {} in the database", options.table))?;
+    let sql = sql.to_ascii_uppercase();
+    if sql.contains("WITHOUT ROWID") {
+        bail!(
+            "{} is a WITHOUT ROWID table, which cannot be mirrored",
+            options.table
+        );
+    }
+    // Without AUTOINCREMENT, SQLite hands the largest rowid out again once its row is
+    // deleted, and a row inserted after that would sit behind the watermark
+    if options.watermark == Watermark::Rowid
+        && options.on_sync == OnSync::Delete
+        && !sql.contains("AUTOINCREMENT")
+    {
+        bail!(
+            "Deleting synced rows of {} with the rowid as the watermark needs an \
+             INTEGER PRIMARY KEY AUTOINCREMENT column, or rowids are reused",
+            options.table
+        );
+    }
+
+    let mut statement = conn.prepare("SELECT name FROM pragma_table_info(?1)")?;
+    let columns = statement
+        .query_map(params![options.table], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let mut required = vec![];
+    if let Watermark::Column(column) = &options.watermark {
+        required.push(column);
+    }
+    if let OnSync::Flag(column) = &options.on_sync {
+        required.push(column);
+    }
+    for column in required {
+        if !columns.iter().any(|c| c.eq_ignore_ascii_case(column)) {
+            bail!("{} has no column {}", options.table, column);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
+
+    pub fn options(watermark: &str, on_sync: OnSync) -> SourceOptions {
+        SourceOptions {
+            table: "readings".to_string(),
+            watermark: Watermark::parse(watermark),
+            on_sync,
+            state_table: "zerobus_mirror_state".to_string(),
+            busy_timeout: Duration::from_millis(100),
+            enable_wal: true,
+        }
+    }
+
+    /// A database with a `readings` table, written to with `sql`
+    pub fn database(dir: &Path, sql: &str) -> std::path::PathBuf {
+        let path = dir.join("edge.db");
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE readings (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                sensor TEXT,
+                -- Untyped, so values are kept as they were written
+                value,
+                recorded_at TEXT,
+                synced INTEGER
+            );",
+        )
+        .unwrap();
+        conn.execute_batch(sql).unwrap();
+        path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::{database, options};
+    use super::*;
+
+    fn rowids(batch: &Batch) -> Vec<i64> {
+        batch.rows.iter().map(|row| row.rowid).collect()
+    }
+
+    #[test]
+    fn test_read_in_watermark_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = database(
+            dir.path(),
+            "INSERT INTO readings (sensor, recorded_at) VALUES
+                ('a', '2024-09-25 10:00:02'),
+                ('b', '2024-09-25 10:00:01'),
+                ('c', '2024-09-25 10:00:02'),
+                ('d', NULL),
+                ('e', '2024-09-25 10:00:02');",
+        );
+        let source = Source::open(&path, options("recorded_at", OnSync::Keep)).unwrap();
+
+        let batch = source.read_batch(None, 2).unwrap();
+        assert_eq!(
+            vec!["id", "sensor", "value", "recorded_at", "synced"],
+            batch.columns
+        );
+        assert_eq!(vec![2, 1], rowids(&batch));
+        assert_eq!(SqlValue::Text("b".to_string()), batch.rows[0].values[1]);
+
+        // The batch ended among rows with the same timestamp
+        let after = batch.end().unwrap();
+        let batch = source.read_batch(Some(&after), 10).unwrap();
+        assert_eq!(vec![3, 5], rowids(&batch));
+        assert!(source
+            .read_batch(batch.end().as_ref(), 10)
+            .unwrap()
+            .rows
+            .is_empty());
+
+        let mode: String = source
+            .conn
+            .query_row("PRAGMA journal_mode", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!("wal", mode);
+    }
+
+    #[test]
+    fn test_unsupported_tables() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = database(
+            dir.path(),
+            "CREATE TABLE plain (sensor TEXT);
+             CREATE TABLE keyed (sensor TEXT PRIMARY KEY) WITHOUT ROWID;",
+        );
+        let open = |table: &str, watermark: &str, on_sync: OnSync| {
+            let options = SourceOptions {
+                table: table.to_string(),
+                ..options(watermark, on_sync)
+            };
+            Source::open(&path, options).map(|_| ())
+        };
+
+        open("readings", "rowid", OnSync::Delete).unwrap();
+        open("plain", "rowid", OnSync::Flag("sensor".to_string())).unwrap();
+        assert!(open("plain", "rowid", OnSync::Delete).is_err());
+        assert!(open("keyed", "sensor", OnSync::Keep).is_err());
+        assert!(open("missing", "rowid", OnSync::Keep).is_err());
+        assert!(open("readings", "updated_at", OnSync::Keep).is_err());
+        assert!(open("readings", "rowid", OnSync::Flag("done".to_string())).is_err());
+
+        let missing = dir.path().join("missing.db");
+        assert!(Source::open(&missing, options("rowid", OnSync::Keep)).is_err());
+    }
+}
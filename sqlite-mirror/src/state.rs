@@ -0,0 +1,160 @@
+//! The table in the mirrored database that holds each source table's watermark
+//!
+//! Keeping the watermark in the database itself lets it be saved in the same
+//! transaction that deletes or flags the synced rows, so the two never disagree after
+//! a crash.
+
+use anyhow::{bail, Context, Result};
+use rusqlite::types::Value as SqlValue;
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::source::{quote, Watermark};
+
+/// Where the mirror got to: the watermark column's value and the rowid of the last
+/// row that was acknowledged
+///
+/// The rowid breaks ties between rows with the same watermark value, so a batch can
+/// end in the middle of them. With the rowid as the watermark, `value` is NULL.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Position {
+    pub value: SqlValue,
+    pub rowid: i64,
+}
+
+pub struct StateTable {
+    name: String,
+}
+
+impl StateTable {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self { name: name.into() }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn create(&self, conn: &Connection) -> Result<()> {
+        conn.execute_batch(&format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+                source_table TEXT PRIMARY KEY,
+                watermark_column TEXT NOT NULL,
+                watermark_value,
+                last_rowid INTEGER NOT NULL,
+                synced_at TEXT NOT NULL
+            )",
+            quote(&self.name)
+        ))
+        .with_context(|| format!("Failed to create state table {}", self.name))
+    }
+
+    /// The saved position of `source_table`, or `None` before its first batch
+    ///
+    /// Fails if the table was mirrored by a different watermark column, since the old
+    /// position means nothing for the new one.
+    pub fn load(
+        &self,
+        conn: &Connection,
+        source_table: &str,
+        watermark: &Watermark,
+    ) -> Result<Option<Position>> {
+        let saved = conn
+            .query_row(
+                &format!(
+                    "SELECT watermark_column, watermark_value, last_rowid FROM {} \
+                     WHERE source_table = ?1",
+                    quote(&self.name)
+                ),
+                params![source_table],
+                |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        Position {
+                            value: row.get(1)?,
+                            rowid: row.get(2)?,
+                        },
+                    ))
+                },
+            )
+            .optional()
+            .with_context(|| format!("Failed to read state table {}", self.name))?;
+
+        match saved {
+            Some((column, _)) if column != watermark.column() => bail!(
+                "{} was mirrored by watermark column {}, not {}; delete its row from {} \
+                 to start over",
+                source_table,
+                column,
+                watermark.column(),
+                self.name
+            ),
+            Some((_, position)) => Ok(Some(position)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn save(
+        &self,
+        conn: &Connection,
+        source_table: &str,
+        watermark: &Watermark,
+        position: &Position,
+    ) -> Result<()> {
+        conn.execute(
+            &format!(
+                "INSERT OR REPLACE INTO {} VALUES (?1, ?2, ?3, ?4, \
+                 strftime('%Y-%m-%d %H:%M:%f', 'now'))",
+                quote(&self.name)
+            ),
+            params![
+                source_table,
+                watermark.column(),
+                position.value,
+                position.rowid
+            ],
+        )
+        .with_context(|| format!("Failed to save the watermark to {}", self.name))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_and_load() {
+        let conn = Connection::open_in_memory().unwrap();
+        let state = StateTable::new("zerobus_mirror_state");
+        state.create(&conn).unwrap();
+        let watermark = Watermark::Column("recorded_at".to_string());
+
+        assert_eq!(None, state.load(&conn, "readings", &watermark).unwrap());
+        let position = Position {
+            value: SqlValue::Text("2024-09-25 17:15:36".to_string()),
+            rowid: 41,
+        };
+        state
+            .save(&conn, "readings", &watermark, &position)
+            .unwrap();
+        let position = Position {
+            rowid: 42,
+            ..position
+        };
+        state
+            .save(&conn, "readings", &watermark, &position)
+            .unwrap();
+        assert_eq!(
+            Some(position),
+            state.load(&conn, "readings", &watermark).unwrap()
+        );
+        assert_eq!(None, state.load(&conn, "events", &watermark).unwrap());
+
+        let error = state
+            .load(&conn, "readings", &Watermark::Rowid)
+            .unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("readings was mirrored by watermark column recorded_at, not rowid"));
+    }
+}
@@ -0,0 +1,323 @@
+use std::collections::{BTreeMap, BTreeSet};
+use tracing::debug;
+
+use crate::parse::{parse_packet, Metric, MetricType, Sample, Tags};
+use crate::proto::statsd_metrics::TableStatsdMetrics;
+
+/// A metric series: the same name and type with the same tags
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct SeriesKey {
+    name: String,
+    metric_type: MetricType,
+    tags: Tags,
+}
+
+/// Running aggregate of one series within a window
+#[derive(Debug)]
+enum Aggregate {
+    /// Sum of the values, each scaled up by its sample rate
+    Counter { sum: f64, count: f64 },
+    /// Last value written
+    Gauge { value: f64, count: f64 },
+    /// Every observed value; `count` is scaled up by the sample rates
+    Timer { values: Vec<f64>, count: f64 },
+    /// Distinct members
+    Set {
+        members: BTreeSet<String>,
+        count: f64,
+    },
+}
+
+/// Everything aggregated in one window
+#[derive(Debug, Default)]
+pub struct FlushedWindow {
+    /// One row per series, ordered by name, type, and tags
+    pub rows: Vec<TableStatsdMetrics>,
+    /// Lines that could not be parsed and were skipped
+    pub malformed: u64,
+}
+
+/// Aggregates parsed metrics over a flush window
+#[derive(Debug, Default)]
+pub struct Aggregator {
+    series: BTreeMap<SeriesKey, Aggregate>,
+    /// Last value of every gauge seen, so a delta in a later window has a base
+    gauges: BTreeMap<SeriesKey, f64>,
+    malformed: u64,
+}
+
+impl Aggregator {
+    /// Parse a datagram and aggregate its metrics, counting malformed lines
+    pub fn add_packet(&mut self, packet: &[u8]) {
+        for parsed in parse_packet(packet) {
+            match parsed {
+                Ok(metric) => self.add(metric),
+                Err(e) => {
+                    debug!("Skipping malformed line: {:#}", e);
+                    self.malformed += 1;
+                }
+            }
+        }
+    }
+
+    pub fn add(&mut self, metric: Metric) {
+        let key = SeriesKey {
+            name: metric.name,
+            metric_type: metric.sample.metric_type(),
+            tags: metric.tags,
+        };
+        // A sample sent at rate 0.1 stands for 10 events
+        let weight = 1.0 / metric.sample_rate;
+
+        match metric.sample {
+            Sample::Counter(value) => {
+                let entry = self.series.entry(key).or_insert(Aggregate::Counter {
+                    sum: 0.0,
+                    count: 0.0,
+                });
+                if let Aggregate::Counter { sum, count } = entry {
+                    *sum += value * weight;
+                    *count += weight;
+                }
+            }
+            Sample::Gauge(value) => self.set_gauge(key, |_| value),
+            Sample::GaugeDelta(delta) => self.set_gauge(key, |current| current + delta),
+            Sample::Timer(value) => {
+                let entry = self.series.entry(key).or_insert(Aggregate::Timer {
+                    values: Vec::new(),
+                    count: 0.0,
+                });
+                if let Aggregate::Timer { values, count } = entry {
+                    values.push(value);
+                    *count += weight;
+                }
+            }
+            Sample::Set(member) => {
+                let entry = self.series.entry(key).or_insert(Aggregate::Set {
+                    members: BTreeSet::new(),
+                    count: 0.0,
+                });
+                if let Aggregate::Set { members, count } = entry {
+                    members.insert(member);
+                    *count += 1.0;
+                }
+            }
+        }
+    }
+
+    /// Gauges ignore sample rates: each update replaces the value
+    fn set_gauge(&mut self, key: SeriesKey, update: impl FnOnce(f64) -> f64) {
+        let last = self.gauges.get(&key).copied().unwrap_or(0.0);
+        let entry = self.series.entry(key).or_insert(Aggregate::Gauge {
+            value: last,
+            count: 0.0,
+        });
+        if let Aggregate::Gauge { value, count } = entry {
+            *value = update(*value);
+            *count += 1.0;
+        }
+    }
+
+    /// Whether no series has data in the current window
+    pub fn is_empty(&self) -> bool {
+        self.series.is_empty()
+    }
+
+    /// Close the current window, returning one row per series, and start a new one
+    ///
+    /// Times are microseconds since Unix epoch; `ingested_date` is days since Unix epoch.
+    pub fn flush(
+        &mut self,
+        window_start: i64,
+        window_end: i64,
+        ingested_at: i64,
+        ingested_date: i32,
+    ) -> FlushedWindow {
+        let series = std::mem::take(&mut self.series);
+        let rows = series
+            .into_iter()
+            .map(|(key, aggregate)| {
+                let mut row = TableStatsdMetrics {
+                    metric_name: Some(key.name.clone()),
+                    metric_type: Some(key.metric_type.as_str().to_string()),
+                    tags: key.tags.clone(),
+                    window_start: Some(window_start),
+                    window_end: Some(window_end),
+                    ingested_at: Some(ingested_at),
+                    ingested_date: Some(ingested_date),
+                    ..Default::default()
+                };
+                match aggregate {
+                    Aggregate::Counter { sum, count } => {
+                        row.value = Some(sum);
+                        row.sum = Some(sum);
+                        row.sample_count = Some(count);
+                    }
+                    Aggregate::Gauge { value, count } => {
+                        self.gauges.insert(key, value);
+                        row.value = Some(value);
+                        row.sample_count = Some(count);
+                    }
+                    Aggregate::Timer { values, count } => {
+                        let summary = TimerSummary::new(values);
+                        row.sample_count = Some(count);
+                        row.min = Some(summary.percentile(0.0));
+                        row.max = Some(summary.percentile(1.0));
+                        row.sum = Some(summary.sum());
+                        row.mean = Some(summary.sum() / summary.sorted.len() as f64);
+                        row.p50 = Some(summary.percentile(0.50));
+                        row.p90 = Some(summary.percentile(0.90));
+                        row.p95 = Some(summary.percentile(0.95));
+                        row.p99 = Some(summary.percentile(0.99));
+                    }
+                    Aggregate::Set { members, count } => {
+                        row.value = Some(members.len() as f64);
+                        row.sample_count = Some(count);
+                    }
+                }
+                row
+            })
+            .collect();
+
+        FlushedWindow {
+            rows,
+            malformed: std::mem::take(&mut self.malformed),
+        }
+    }
+}
+
+/// Observed timer values of a window, sorted
+struct TimerSummary {
+    sorted: Vec<f64>,
+}
+
+impl TimerSummary {
+    /// `values` is never empty: a timer series is created by its first value
+    fn new(mut values: Vec<f64>) -> Self {
+        values.sort_by(f64::total_cmp);
+        Self { sorted: values }
+    }
+
+    fn sum(&self) -> f64 {
+        self.sorted.iter().sum()
+    }
+
+    /// Nearest-rank percentile: the smallest value with at least `q` of the values at
+    /// or below it
+    fn percentile(&self, q: f64) -> f64 {
+        let rank = (q * self.sorted.len() as f64).ceil() as usize;
+        self.sorted[rank.clamp(1, self.sorted.len()) - 1]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flush(aggregator: &mut Aggregator) -> FlushedWindow {
+        aggregator.flush(1_000, 2_000, 3_000, 0)
+    }
+
+    #[test]
+    fn test_counter_sample_rate_scaling() {
+        let mut aggregator = Aggregator::default();
+        aggregator.add_packet(b"requests:1|c|@0.1\nrequests:2|c\nrequests:1|c|@0.5");
+
+        let window = flush(&mut aggregator);
+
+        assert_eq!(1, window.rows.len());
+        let row = &window.rows[0];
+        assert_eq!(Some("counter".to_string()), row.metric_type);
+        // 1/0.1 + 2 + 1/0.5
+        assert_eq!(Some(14.0), row.value);
+        assert_eq!(Some(13.0), row.sample_count);
+        assert_eq!(Some(1_000), row.window_start);
+        assert_eq!(Some(2_000), row.window_end);
+    }
+
+    #[test]
+    fn test_gauges_keep_last_write_and_apply_deltas() {
+        let mut aggregator = Aggregator::default();
+        aggregator.add_packet(b"queue:10|g\nqueue:4|g\nqueue:+3|g");
+        let first = flush(&mut aggregator);
+        assert_eq!(Some(7.0), first.rows[0].value);
+        assert_eq!(Some(3.0), first.rows[0].sample_count);
+
+        // A delta in a later window applies to the last flushed value
+        aggregator.add_packet(b"queue:-2|g");
+        let second = flush(&mut aggregator);
+        assert_eq!(Some(5.0), second.rows[0].value);
+
+        // Gauges that were not updated are not repeated
+        assert!(flush(&mut aggregator).rows.is_empty());
+    }
+
+    #[test]
+    fn test_timer_percentiles() {
+        let mut aggregator = Aggregator::default();
+        let packet: Vec<String> = (1..=100)
+            .rev()
+            .map(|v| format!("latency:{}|ms", v))
+            .collect();
+        aggregator.add_packet(packet.join("\n").as_bytes());
+        aggregator.add_packet(b"latency:50|ms|@0.5");
+
+        let row = &flush(&mut aggregator).rows[0];
+
+        assert_eq!(Some("timer".to_string()), row.metric_type);
+        assert_eq!(None, row.value);
+        assert_eq!(Some(102.0), row.sample_count);
+        assert_eq!(Some(1.0), row.min);
+        assert_eq!(Some(100.0), row.max);
+        assert_eq!(Some(5100.0), row.sum);
+        assert_eq!(Some(5100.0 / 101.0), row.mean);
+        assert_eq!(Some(50.0), row.p50);
+        assert_eq!(Some(90.0), row.p90);
+        assert_eq!(Some(95.0), row.p95);
+        assert_eq!(Some(99.0), row.p99);
+    }
+
+    #[test]
+    fn test_sets_count_distinct_members() {
+        let mut aggregator = Aggregator::default();
+        aggregator.add_packet(b"visitors:alice|s\nvisitors:bob|s\nvisitors:alice|s");
+
+        let row = &flush(&mut aggregator).rows[0];
+
+        assert_eq!(Some(2.0), row.value);
+        assert_eq!(Some(3.0), row.sample_count);
+    }
+
+    #[test]
+    fn test_series_are_split_by_tags_and_type() {
+        let mut aggregator = Aggregator::default();
+        aggregator.add_packet(
+            b"requests:1|c|#env:prod\nrequests:1|c|#env:dev\nrequests:1|c|#env:prod\nrequests:5|g\nbogus",
+        );
+
+        let window = flush(&mut aggregator);
+
+        let series: Vec<_> = window
+            .rows
+            .iter()
+            .map(|row| {
+                (
+                    row.metric_type.clone().unwrap(),
+                    row.tags.get("env").cloned(),
+                    row.value.unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                ("counter".to_string(), Some("dev".to_string()), 1.0),
+                ("counter".to_string(), Some("prod".to_string()), 2.0),
+                ("gauge".to_string(), None, 5.0),
+            ],
+            series
+        );
+        assert_eq!(1, window.malformed);
+        assert!(aggregator.is_empty());
+        assert_eq!(0, flush(&mut aggregator).malformed);
+    }
+}
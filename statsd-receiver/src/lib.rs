@@ -0,0 +1,4 @@
+pub mod aggregate;
+pub mod parse;
+pub mod proto;
+pub mod receiver;
@@ -0,0 +1,122 @@
+use anyhow::{bail, Context, Result};
+use databricks_zerobus_ingest_sdk::{StreamConfigurationOptions, TableProperties, ZerobusSdk};
+use statsd_receiver::proto::load_descriptor_proto;
+use statsd_receiver::receiver::{read_packets, run_flush_loop};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tracing::info;
+use zerobus_common::pipeline::Pipeline;
+use zerobus_common::shutdown;
+
+/// Maximum number of unacknowledged records per stream
+const MAX_INFLIGHT_RECORDS: usize = 10_000;
+
+/// Address to listen on when LISTEN_ADDR is not set
+const DEFAULT_LISTEN_ADDR: &str = "0.0.0.0:8125";
+
+/// Aggregation window when FLUSH_INTERVAL_SECS is not set
+const DEFAULT_FLUSH_INTERVAL_SECS: u64 = 10;
+
+/// Datagrams buffered between the socket and the aggregator
+const PACKET_QUEUE_CAPACITY: usize = 10_000;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .with_target(false)
+        .init();
+
+    let zerobus_endpoint = std::env::var("ZEROBUS_ENDPOINT")
+        .context("ZEROBUS_ENDPOINT environment variable must be set")?;
+    let databricks_host = std::env::var("DATABRICKS_HOST")
+        .context("DATABRICKS_HOST environment variable must be set")?;
+    let client_id = std::env::var("DATABRICKS_CLIENT_ID")
+        .context("DATABRICKS_CLIENT_ID environment variable must be set")?;
+    let client_secret = std::env::var("DATABRICKS_CLIENT_SECRET")
+        .context("DATABRICKS_CLIENT_SECRET environment variable must be set")?;
+    let table_name =
+        std::env::var("TABLE_NAME").context("TABLE_NAME environment variable must be set")?;
+    let listen_addr =
+        std::env::var("LISTEN_ADDR").unwrap_or_else(|_| DEFAULT_LISTEN_ADDR.to_string());
+    let flush_interval = flush_interval()?;
+    let grace = shutdown::grace_from_env()?;
+
+    let sdk = ZerobusSdk::new(zerobus_endpoint, databricks_host)?;
+
+    let table_properties = TableProperties {
+        table_name: table_name.clone(),
+        descriptor_proto: load_descriptor_proto("statsd_metrics.proto", "table_statsd_metrics"),
+    };
+    let stream_options = StreamConfigurationOptions {
+        max_inflight_records: MAX_INFLIGHT_RECORDS,
+        ..Default::default()
+    };
+    let stream = sdk
+        .create_stream(
+            table_properties,
+            client_id,
+            client_secret,
+            Some(stream_options),
+        )
+        .await
+        .context("Failed to create stream")?;
+    info!("Created stream to table: {}", table_name);
+
+    let socket = UdpSocket::bind(&listen_addr)
+        .await
+        .with_context(|| format!("Failed to bind {}", listen_addr))?;
+    info!(
+        "Listening for StatsD datagrams on udp://{}, flushing every {:?}",
+        listen_addr, flush_interval
+    );
+
+    let (packets, queued) = mpsc::channel(PACKET_QUEUE_CAPACITY);
+    let reader = tokio::spawn(read_packets(socket, packets));
+
+    // Stopping the reader closes the queue, which makes the flush loop flush the
+    // partial window and return
+    let stop_reader = reader.abort_handle();
+    tokio::spawn(async move {
+        shutdown::signal().await;
+        stop_reader.abort();
+    });
+
+    let mut pipeline = Pipeline::new(stream, MAX_INFLIGHT_RECORDS);
+    let stats = run_flush_loop(queued, &mut pipeline, flush_interval).await?;
+    let outcome = shutdown::drain(pipeline, grace).await?;
+    info!(
+        "Shut down after {} windows: {} rows ingested, {} failed, {} malformed lines",
+        stats.windows, outcome.summary.ingested, outcome.summary.failed, stats.malformed
+    );
+    if !outcome.unacked.is_empty() {
+        bail!(
+            "{} rows were not acknowledged before shutdown",
+            outcome.unacked.len()
+        );
+    }
+
+    // The reader only stops on its own if the socket failed
+    if let Ok(Err(e)) = reader.await {
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Read the aggregation window from `FLUSH_INTERVAL_SECS`
+fn flush_interval() -> Result<Duration> {
+    let secs = match std::env::var("FLUSH_INTERVAL_SECS") {
+        Ok(value) => value.trim().parse::<u64>().with_context(|| {
+            format!(
+                "FLUSH_INTERVAL_SECS must be a positive integer, got {:?}",
+                value
+            )
+        })?,
+        Err(_) => DEFAULT_FLUSH_INTERVAL_SECS,
+    };
+    if secs == 0 {
+        bail!("FLUSH_INTERVAL_SECS must be a positive integer, got 0");
+    }
+    Ok(Duration::from_secs(secs))
+}
@@ -0,0 +1,235 @@
+//! StatsD line protocol, including the DogStatsD sample rate and tag extensions
+//!
+//! A datagram carries one metric per line:
+//! `<name>:<value>|<type>[|@<sample rate>][|#<tag>:<value>,<tag>...]`
+
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+
+/// Tags of a metric, sorted by name; tags without a value map to an empty string
+pub type Tags = BTreeMap<String, String>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum MetricType {
+    Counter,
+    Gauge,
+    Timer,
+    Set,
+}
+
+impl MetricType {
+    /// Name stored in the `metric_type` column
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MetricType::Counter => "counter",
+            MetricType::Gauge => "gauge",
+            MetricType::Timer => "timer",
+            MetricType::Set => "set",
+        }
+    }
+}
+
+/// Value of a single metric line
+#[derive(Debug, Clone, PartialEq)]
+pub enum Sample {
+    Counter(f64),
+    /// Absolute gauge value
+    Gauge(f64),
+    /// `+N` or `-N`: change relative to the gauge's current value
+    GaugeDelta(f64),
+    /// Timers, histograms, and DogStatsD distributions
+    Timer(f64),
+    Set(String),
+}
+
+impl Sample {
+    pub fn metric_type(&self) -> MetricType {
+        match self {
+            Sample::Counter(_) => MetricType::Counter,
+            Sample::Gauge(_) | Sample::GaugeDelta(_) => MetricType::Gauge,
+            Sample::Timer(_) => MetricType::Timer,
+            Sample::Set(_) => MetricType::Set,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metric {
+    pub name: String,
+    pub sample: Sample,
+    /// Fraction of events the client sent, in (0, 1]
+    pub sample_rate: f64,
+    pub tags: Tags,
+}
+
+/// Parse every line of a datagram
+///
+/// Lines are parsed independently so one malformed line does not discard the rest of
+/// the packet. A datagram that is not UTF-8 yields a single error.
+pub fn parse_packet(packet: &[u8]) -> Vec<Result<Metric>> {
+    let text = match std::str::from_utf8(packet) {
+        Ok(text) => text,
+        Err(e) => return vec![Err(e).context("Datagram is not valid UTF-8")],
+    };
+    text.split('\n')
+        .map(|line| line.trim_end_matches('\r'))
+        .filter(|line| !line.is_empty())
+        .map(parse_line)
+        .collect()
+}
+
+/// Parse a single metric line
+pub fn parse_line(line: &str) -> Result<Metric> {
+    if line.starts_with("_e{") || line.starts_with("_sc|") {
+        bail!("DogStatsD events and service checks are not supported");
+    }
+
+    let (name, rest) = line
+        .split_once(':')
+        .with_context(|| format!("Missing ':' after the metric name in {:?}", line))?;
+    if name.is_empty() {
+        bail!("Empty metric name in {:?}", line);
+    }
+
+    let mut sections = rest.split('|');
+    // split always yields at least one item
+    let value = sections.next().unwrap_or_default();
+    let metric_type = sections
+        .next()
+        .with_context(|| format!("Missing metric type in {:?}", line))?;
+
+    let mut sample_rate = 1.0;
+    let mut tags = Tags::new();
+    for section in sections {
+        if let Some(rate) = section.strip_prefix('@') {
+            sample_rate = rate
+                .parse::<f64>()
+                .with_context(|| format!("Invalid sample rate {:?}", rate))?;
+            if !(sample_rate > 0.0 && sample_rate <= 1.0) {
+                bail!("Sample rate {} is not in (0, 1]", rate);
+            }
+        } else if let Some(tag_list) = section.strip_prefix('#') {
+            parse_tags(tag_list, &mut tags);
+        }
+        // Other DogStatsD sections (container ID `c:`, timestamp `T`) are ignored
+    }
+
+    let sample = match metric_type {
+        "c" => Sample::Counter(parse_number(value)?),
+        "g" if value.starts_with(['+', '-']) => Sample::GaugeDelta(parse_number(value)?),
+        "g" => Sample::Gauge(parse_number(value)?),
+        "ms" | "h" | "d" => Sample::Timer(parse_number(value)?),
+        "s" if value.is_empty() => bail!("Empty set member in {:?}", line),
+        "s" => Sample::Set(value.to_string()),
+        other => bail!("Unknown metric type {:?}", other),
+    };
+
+    Ok(Metric {
+        name: name.to_string(),
+        sample,
+        sample_rate,
+        tags,
+    })
+}
+
+/// Add `key:value` (or bare `key`) tags from a comma-separated list
+fn parse_tags(tag_list: &str, tags: &mut Tags) {
+    for tag in tag_list.split(',').filter(|tag| !tag.is_empty()) {
+        let (key, value) = tag.split_once(':').unwrap_or((tag, ""));
+        tags.insert(key.to_string(), value.to_string());
+    }
+}
+
+fn parse_number(value: &str) -> Result<f64> {
+    let number = value
+        .parse::<f64>()
+        .with_context(|| format!("Invalid metric value {:?}", value))?;
+    if !number.is_finite() {
+        bail!("Metric value {:?} is not finite", value);
+    }
+    Ok(number)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_metric_types() {
+        assert_eq!(
+            Sample::Counter(3.0),
+            parse_line("requests:3|c").unwrap().sample
+        );
+        assert_eq!(Sample::Gauge(0.5), parse_line("load:0.5|g").unwrap().sample);
+        assert_eq!(
+            Sample::GaugeDelta(-2.0),
+            parse_line("queue:-2|g").unwrap().sample
+        );
+        assert_eq!(
+            Sample::GaugeDelta(4.0),
+            parse_line("queue:+4|g").unwrap().sample
+        );
+        assert_eq!(
+            Sample::Timer(12.5),
+            parse_line("latency:12.5|ms").unwrap().sample
+        );
+        assert_eq!(Sample::Timer(7.0), parse_line("size:7|h").unwrap().sample);
+        assert_eq!(
+            Sample::Set("user-1".to_string()),
+            parse_line("visitors:user-1|s").unwrap().sample
+        );
+    }
+
+    #[test]
+    fn test_parse_sample_rate_and_tags() {
+        let metric = parse_line("requests:1|c|@0.25|#env:prod,region:us-west-2,canary").unwrap();
+
+        assert_eq!("requests", metric.name);
+        assert_eq!(0.25, metric.sample_rate);
+        assert_eq!(
+            Tags::from([
+                ("canary".to_string(), String::new()),
+                ("env".to_string(), "prod".to_string()),
+                ("region".to_string(), "us-west-2".to_string()),
+            ]),
+            metric.tags
+        );
+
+        // Tags may come before the sample rate, and tag values may contain ':'
+        let metric = parse_line("requests:1|c|#url:http://x|@0.5").unwrap();
+        assert_eq!(0.5, metric.sample_rate);
+        assert_eq!(Some(&"http://x".to_string()), metric.tags.get("url"));
+    }
+
+    #[test]
+    fn test_parse_multi_metric_packet() {
+        let parsed = parse_packet(b"a:1|c\r\nb:2|g\n\nnot a metric\nc:3|ms|@2\nd:4|ms\n");
+
+        assert_eq!(5, parsed.len());
+        let names: Vec<_> = parsed
+            .iter()
+            .filter_map(|metric| metric.as_ref().ok())
+            .map(|metric| metric.name.as_str())
+            .collect();
+        assert_eq!(vec!["a", "b", "d"], names);
+    }
+
+    #[test]
+    fn test_parse_malformed_lines() {
+        for line in [
+            "no-value",
+            ":1|c",
+            "requests:1",
+            "requests:abc|c",
+            "requests:nan|c",
+            "requests:1|x",
+            "requests:1|c|@0",
+            "visitors:|s",
+            "_e{5,4}:title|text",
+            "_sc|redis.can_connect|0",
+        ] {
+            assert!(parse_line(line).is_err(), "{:?} should not parse", line);
+        }
+        assert_eq!(1, parse_packet(&[0xff, 0xfe]).len());
+    }
+}
@@ -0,0 +1,233 @@
+use anyhow::{Context, Result};
+use prost::Message;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::time::{Instant, MissedTickBehavior};
+use tracing::{error, info, warn};
+use zerobus_common::pipeline::{IngestSink, Pipeline};
+
+use crate::aggregate::Aggregator;
+
+/// Largest possible UDP payload
+const MAX_DATAGRAM_SIZE: usize = 65_535;
+
+/// Counts over the lifetime of the flush loop
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ReceiverStats {
+    /// Windows closed, including empty ones
+    pub windows: u64,
+    /// Rows acknowledged by Zerobus
+    pub rows: u64,
+    /// Rows of windows that failed to ingest
+    pub failed_rows: u64,
+    /// Lines that could not be parsed
+    pub malformed: u64,
+}
+
+/// Receive datagrams and queue them for aggregation
+///
+/// Datagrams are dropped when the queue is full rather than waiting, so a slow flush
+/// never backs up into the socket. Returns once the aggregation side has gone away.
+pub async fn read_packets(socket: UdpSocket, packets: mpsc::Sender<Vec<u8>>) -> Result<()> {
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    let mut dropped: u64 = 0;
+    loop {
+        let (len, _) = socket
+            .recv_from(&mut buf)
+            .await
+            .context("Failed to receive datagram")?;
+        match packets.try_send(buf[..len].to_vec()) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                dropped += 1;
+                if dropped.is_power_of_two() {
+                    warn!(
+                        "Aggregation is falling behind; dropped {} datagrams",
+                        dropped
+                    );
+                }
+            }
+            Err(TrySendError::Closed(_)) => return Ok(()),
+        }
+    }
+}
+
+/// Aggregate queued datagrams and ingest one row per series at the end of every window
+///
+/// Runs until every sender of `packets` is dropped, then flushes the partial window.
+/// A window whose rows fail to ingest is logged and dropped; the loop keeps going.
+pub async fn run_flush_loop<S: IngestSink>(
+    mut packets: mpsc::Receiver<Vec<u8>>,
+    pipeline: &mut Pipeline<S>,
+    window: Duration,
+) -> Result<ReceiverStats> {
+    let mut aggregator = Aggregator::default();
+    let mut stats = ReceiverStats::default();
+    let mut ticks = tokio::time::interval_at(Instant::now() + window, window);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut window_start = now_micros()?;
+
+    loop {
+        tokio::select! {
+            packet = packets.recv() => match packet {
+                Some(packet) => aggregator.add_packet(&packet),
+                None => break,
+            },
+            _ = ticks.tick() => {
+                window_start = flush_window(&mut aggregator, pipeline, window_start, &mut stats).await?;
+            }
+        }
+    }
+
+    flush_window(&mut aggregator, pipeline, window_start, &mut stats).await?;
+    Ok(stats)
+}
+
+/// Ingest the rows of the current window; returns the start of the next window
+async fn flush_window<S: IngestSink>(
+    aggregator: &mut Aggregator,
+    pipeline: &mut Pipeline<S>,
+    window_start: i64,
+    stats: &mut ReceiverStats,
+) -> Result<i64> {
+    let window_end = now_micros()?;
+    let ingested_date = (window_end / 86_400_000_000) as i32;
+    let flushed = aggregator.flush(window_start, window_end, window_end, ingested_date);
+
+    stats.windows += 1;
+    if flushed.malformed > 0 {
+        warn!("Skipped {} malformed lines", flushed.malformed);
+        stats.malformed += flushed.malformed;
+    }
+    if flushed.rows.is_empty() {
+        return Ok(window_end);
+    }
+
+    let rows = flushed.rows.len() as u64;
+    match pipeline
+        .ingest_batch(flushed.rows.iter().map(|row| row.encode_to_vec()))
+        .await
+    {
+        Ok(()) => {
+            info!("Ingested {} metric rows", rows);
+            stats.rows += rows;
+        }
+        Err(e) => {
+            error!("Failed to ingest window of {} rows: {:#}", rows, e);
+            stats.failed_rows += rows;
+        }
+    }
+    Ok(window_end)
+}
+
+/// Current time in microseconds since Unix epoch
+fn now_micros() -> Result<i64> {
+    Ok(std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .context("Failed to get system time")?
+        .as_micros() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::statsd_metrics::TableStatsdMetrics;
+    use tokio::task::JoinHandle;
+    use zerobus_common::testing::MockSink;
+
+    const WINDOW: Duration = Duration::from_secs(10);
+
+    fn spawn_flush_loop(sink: &MockSink) -> (mpsc::Sender<Vec<u8>>, JoinHandle<ReceiverStats>) {
+        let (packets, receiver) = mpsc::channel(16);
+        let mut pipeline = Pipeline::new(sink.clone(), 100);
+        let handle = tokio::spawn(async move {
+            run_flush_loop(receiver, &mut pipeline, WINDOW)
+                .await
+                .unwrap()
+        });
+        (packets, handle)
+    }
+
+    fn rows(sink: &MockSink) -> Vec<TableStatsdMetrics> {
+        sink.records()
+            .iter()
+            .map(|record| TableStatsdMetrics::decode(record.as_slice()).unwrap())
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_flush_loop_ingests_once_per_window() {
+        let sink = MockSink::default();
+        let (packets, handle) = spawn_flush_loop(&sink);
+
+        packets.send(b"requests:1|c".to_vec()).await.unwrap();
+        packets
+            .send(b"requests:1|c|@0.5\nlatency:5|ms".to_vec())
+            .await
+            .unwrap();
+        tokio::time::sleep(WINDOW / 2).await;
+        assert!(sink.records().is_empty());
+
+        tokio::time::sleep(WINDOW / 2 + Duration::from_millis(1)).await;
+        let first = rows(&sink);
+        assert_eq!(2, first.len());
+        assert_eq!(Some("latency".to_string()), first[0].metric_name);
+        assert_eq!(Some(3.0), first[1].value);
+
+        // An empty window ingests nothing
+        tokio::time::sleep(WINDOW).await;
+        assert_eq!(2, sink.records().len());
+
+        packets
+            .send(b"requests:4|c\nnot a metric".to_vec())
+            .await
+            .unwrap();
+        tokio::time::sleep(WINDOW).await;
+        assert_eq!(Some(4.0), rows(&sink)[2].value);
+
+        // Closing the queue flushes the partial window
+        packets.send(b"queue:7|g".to_vec()).await.unwrap();
+        drop(packets);
+        let stats = handle.await.unwrap();
+
+        assert_eq!(Some(7.0), rows(&sink)[3].value);
+        assert_eq!(4, stats.rows);
+        assert_eq!(1, stats.malformed);
+        assert_eq!(4, stats.windows);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_failed_window_does_not_stop_the_loop() {
+        let sink = MockSink::default().fail_acks_for(|record| {
+            TableStatsdMetrics::decode(record)
+                .unwrap()
+                .metric_name
+                .as_deref()
+                == Some("bad")
+        });
+        let (packets, handle) = spawn_flush_loop(&sink);
+
+        packets.send(b"bad:1|c".to_vec()).await.unwrap();
+        tokio::time::sleep(WINDOW + Duration::from_millis(1)).await;
+        packets.send(b"good:1|c".to_vec()).await.unwrap();
+        drop(packets);
+        let stats = handle.await.unwrap();
+
+        assert_eq!(1, stats.rows);
+        assert_eq!(1, stats.failed_rows);
+    }
+
+    #[tokio::test]
+    async fn test_read_packets_queues_datagrams() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let (packets, mut receiver) = mpsc::channel(16);
+        tokio::spawn(read_packets(socket, packets));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(b"requests:1|c", addr).await.unwrap();
+
+        assert_eq!(Some(b"requests:1|c".to_vec()), receiver.recv().await);
+    }
+}
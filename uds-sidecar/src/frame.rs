@@ -0,0 +1,180 @@
+//! Framing of records and replies on a sidecar connection.
+//!
+//! Producers may mix two kinds of frames on one connection:
+//! - JSON: one object per line, encoded against the table's descriptor by the daemon
+//! - Binary: a 4-byte big-endian length followed by a record already encoded as the
+//!   table's protobuf message
+//!
+//! Frames are limited to less than 16 MiB, so the first byte of a binary frame is always
+//! `0x00`, which never starts a JSON line. Every frame gets one reply line, in the order
+//! the frames were sent.
+
+use anyhow::{bail, Context, Result};
+use serde_json::json;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+
+/// Largest frame the length prefix can describe with a leading zero byte
+pub const MAX_FRAME_LIMIT: usize = 0x00FF_FFFF;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Frame {
+    /// One line of JSON, without its line ending
+    Json(Vec<u8>),
+    /// A pre-encoded protobuf record
+    Binary(Vec<u8>),
+}
+
+/// Read the next frame, or `None` once the producer has closed its end
+///
+/// Blank lines between frames are skipped. Fails on frames larger than `max_frame_bytes`
+/// and on a connection closed partway through a binary frame; framing cannot be
+/// recovered after either.
+pub async fn read_frame<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    max_frame_bytes: usize,
+) -> Result<Option<Frame>> {
+    loop {
+        let first = match reader.fill_buf().await?.first() {
+            Some(&byte) => byte,
+            None => return Ok(None),
+        };
+
+        if first == 0 {
+            let mut length = [0u8; 4];
+            reader
+                .read_exact(&mut length)
+                .await
+                .context("Connection closed inside a frame length")?;
+            let length = u32::from_be_bytes(length) as usize;
+            if length > max_frame_bytes {
+                bail!(
+                    "Frame of {} bytes exceeds the limit of {} bytes",
+                    length,
+                    max_frame_bytes
+                );
+            }
+            let mut record = vec![0u8; length];
+            reader
+                .read_exact(&mut record)
+                .await
+                .context("Connection closed inside a binary frame")?;
+            return Ok(Some(Frame::Binary(record)));
+        }
+
+        // One byte over the limit leaves room for the newline
+        let mut line = Vec::new();
+        (&mut *reader)
+            .take(max_frame_bytes as u64 + 1)
+            .read_until(b'\n', &mut line)
+            .await?;
+        if line.last() == Some(&b'\n') {
+            line.pop();
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+        } else if line.len() > max_frame_bytes {
+            bail!("JSON frame exceeds the limit of {} bytes", max_frame_bytes);
+        }
+        if line.iter().all(u8::is_ascii_whitespace) {
+            continue;
+        }
+        return Ok(Some(Frame::Json(line)));
+    }
+}
+
+/// Encode a binary frame, as a producer sends it
+pub fn binary_frame(record: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(4 + record.len());
+    frame.extend_from_slice(&(record.len() as u32).to_be_bytes());
+    frame.extend_from_slice(record);
+    frame
+}
+
+/// The answer to one frame, written back as a single JSON line
+///
+/// `seq` counts the frames of a connection from 0. `Ok` means the record was durably
+/// written; an error means it was not, and the producer may retry it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reply {
+    pub seq: u64,
+    pub result: Result<(), String>,
+}
+
+impl Reply {
+    pub fn to_line(&self) -> Vec<u8> {
+        let reply = match &self.result {
+            Ok(()) => json!({"seq": self.seq, "status": "ack"}),
+            Err(error) => json!({"seq": self.seq, "status": "err", "error": error}),
+        };
+        let mut line = reply.to_string().into_bytes();
+        line.push(b'\n');
+        line
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn frames(input: &[u8], max_frame_bytes: usize) -> Result<Vec<Frame>> {
+        let mut reader = input;
+        let mut frames = Vec::new();
+        while let Some(frame) = read_frame(&mut reader, max_frame_bytes).await? {
+            frames.push(frame);
+        }
+        Ok(frames)
+    }
+
+    #[tokio::test]
+    async fn test_mixed_frames() {
+        let mut input = b"{\"id\": 1}\n\r\n".to_vec();
+        input.extend(binary_frame(&[0x08, 0x02]));
+        input.extend(b"{\"id\": 3}\r\n{\"id\": 4}");
+
+        assert_eq!(
+            vec![
+                Frame::Json(b"{\"id\": 1}".to_vec()),
+                Frame::Binary(vec![0x08, 0x02]),
+                Frame::Json(b"{\"id\": 3}".to_vec()),
+                // The last line may end without a newline
+                Frame::Json(b"{\"id\": 4}".to_vec()),
+            ],
+            frames(&input, 1024).await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_frame_limits() {
+        assert_eq!(
+            vec![Frame::Json(b"12345678".to_vec())],
+            frames(b"12345678\n", 8).await.unwrap()
+        );
+        let error = frames(b"123456789\n", 8).await.unwrap_err();
+        assert!(error.to_string().contains("exceeds"));
+
+        let error = frames(&binary_frame(&[0; 9]), 8).await.unwrap_err();
+        assert!(error.to_string().contains("9 bytes"));
+
+        let mut truncated = binary_frame(&[1, 2, 3]);
+        truncated.pop();
+        assert!(frames(&truncated, 8).await.is_err());
+    }
+
+    #[test]
+    fn test_reply_lines() {
+        let ack = Reply {
+            seq: 3,
+            result: Ok(()),
+        };
+        assert_eq!(b"{\"seq\":3,\"status\":\"ack\"}\n".to_vec(), ack.to_line());
+
+        let err = Reply {
+            seq: 4,
+            result: Err("Unknown field \"x\"".to_string()),
+        };
+        assert_eq!(
+            "{\"error\":\"Unknown field \\\"x\\\"\",\"seq\":4,\"status\":\"err\"}\n",
+            String::from_utf8(err.to_line()).unwrap()
+        );
+    }
+}
@@ -0,0 +1,3 @@
+pub mod frame;
+pub mod server;
+pub mod socket;
@@ -0,0 +1,158 @@
+use anyhow::{bail, Context, Result};
+use databricks_zerobus_ingest_sdk::{StreamConfigurationOptions, TableProperties, ZerobusSdk};
+use std::path::PathBuf;
+use tracing::info;
+use uds_sidecar::frame::MAX_FRAME_LIMIT;
+use uds_sidecar::server::{serve, Limits};
+use uds_sidecar::socket;
+use zerobus_common::descriptor::find_message_descriptor;
+use zerobus_common::dynamic::{
+    coerce_from_env, validate_records_from_env, DynamicEncoder, FieldErrorMode, KeyCase,
+};
+use zerobus_common::pipeline::Pipeline;
+use zerobus_common::shutdown;
+
+/// Socket path when SOCKET_PATH is not set
+const DEFAULT_SOCKET_PATH: &str = "/tmp/zerobus-sidecar.sock";
+
+/// Socket file mode when SOCKET_MODE is not set: the owner and its group may connect
+const DEFAULT_SOCKET_MODE: &str = "660";
+
+/// Largest frame accepted when MAX_FRAME_BYTES is not set
+const DEFAULT_MAX_FRAME_BYTES: u64 = 1024 * 1024;
+
+/// Unanswered frames per connection when CONNECTION_INFLIGHT is not set
+const DEFAULT_CONNECTION_INFLIGHT: u64 = 1_000;
+
+/// Unanswered frames across all connections when MAX_INFLIGHT is not set
+const DEFAULT_MAX_INFLIGHT: u64 = 10_000;
+
+fn env(name: &str) -> Result<String> {
+    std::env::var(name).with_context(|| format!("{} environment variable must be set", name))
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .with_target(false)
+        .init();
+
+    let zerobus_endpoint = env("ZEROBUS_ENDPOINT")?;
+    let databricks_host = env("DATABRICKS_HOST")?;
+    let client_id = env("DATABRICKS_CLIENT_ID")?;
+    let client_secret = env("DATABRICKS_CLIENT_SECRET")?;
+    let table_name = env("TABLE_NAME")?;
+    let descriptor_path = env("DESCRIPTOR_SET")?;
+    let message_name = std::env::var("MESSAGE_NAME").unwrap_or_else(|_| {
+        format!(
+            "table_{}",
+            table_name.rsplit('.').next().unwrap_or(&table_name)
+        )
+    });
+    let ignore_unknown_fields = std::env::var("IGNORE_UNKNOWN_FIELDS")
+        .map(|value| value == "true" || value == "1")
+        .unwrap_or(false);
+    let warn_unknown_fields = std::env::var("WARN_UNKNOWN_FIELDS")
+        .map(|value| value == "true" || value == "1")
+        .unwrap_or(false);
+    let socket_path = PathBuf::from(
+        std::env::var("SOCKET_PATH").unwrap_or_else(|_| DEFAULT_SOCKET_PATH.to_string()),
+    );
+    let socket_mode = socket::parse_mode(
+        &std::env::var("SOCKET_MODE").unwrap_or_else(|_| DEFAULT_SOCKET_MODE.to_string()),
+    )?;
+    let limits = Limits {
+        max_frame_bytes: positive_env("MAX_FRAME_BYTES", DEFAULT_MAX_FRAME_BYTES)? as usize,
+        connection_inflight: positive_env("CONNECTION_INFLIGHT", DEFAULT_CONNECTION_INFLIGHT)?
+            as usize,
+        global_inflight: positive_env("MAX_INFLIGHT", DEFAULT_MAX_INFLIGHT)? as usize,
+    };
+    if limits.max_frame_bytes > MAX_FRAME_LIMIT {
+        bail!(
+            "MAX_FRAME_BYTES must be at most {}, got {}",
+            MAX_FRAME_LIMIT,
+            limits.max_frame_bytes
+        );
+    }
+    let grace = shutdown::grace_from_env()?;
+
+    let descriptors = std::fs::read(&descriptor_path)
+        .with_context(|| format!("Failed to read descriptor set {}", descriptor_path))?;
+    let descriptor_proto = find_message_descriptor(&descriptors, &message_name)?;
+    let encoder = DynamicEncoder::new(&descriptor_proto)?
+        .ignore_unknown_fields(ignore_unknown_fields)
+        .warn_unknown_fields(warn_unknown_fields)
+        .coerce_types(coerce_from_env()?)
+        .field_error_mode(FieldErrorMode::from_env()?)
+        .key_case(KeyCase::from_env()?)
+        .validate_records(validate_records_from_env()?);
+
+    let sdk = ZerobusSdk::new(zerobus_endpoint, databricks_host)?;
+    let table_properties = TableProperties {
+        table_name: table_name.clone(),
+        descriptor_proto,
+    };
+    let stream_options = StreamConfigurationOptions {
+        max_inflight_records: limits.global_inflight,
+        ..Default::default()
+    };
+    let stream = sdk
+        .create_stream(
+            table_properties,
+            client_id,
+            client_secret,
+            Some(stream_options),
+        )
+        .await
+        .context("Failed to create stream")?;
+    info!("Created stream to table: {}", table_name);
+
+    let listener = socket::bind(&socket_path, socket_mode).await?;
+    info!(
+        "Listening on {} (mode {:o}), {} in-flight frames per connection, {} in total",
+        socket_path.display(),
+        socket_mode,
+        limits.connection_inflight,
+        limits.global_inflight
+    );
+
+    let pipeline = Pipeline::new(stream, limits.global_inflight);
+    let served = serve(
+        listener,
+        encoder,
+        limits,
+        pipeline,
+        grace,
+        shutdown::signal(),
+    )
+    .await;
+    let _ = std::fs::remove_file(&socket_path);
+
+    let outcome = served?;
+    info!(
+        "Shut down: {} rows ingested, {} failed",
+        outcome.summary.ingested, outcome.summary.failed
+    );
+    if !outcome.unacked.is_empty() {
+        bail!(
+            "{} rows were not acknowledged before shutdown; their producers were told to retry",
+            outcome.unacked.len()
+        );
+    }
+    Ok(())
+}
+
+fn positive_env(name: &str, default: u64) -> Result<u64> {
+    let value = match std::env::var(name) {
+        Ok(value) => value
+            .trim()
+            .parse::<u64>()
+            .with_context(|| format!("{} must be a positive integer, got {:?}", name, value))?,
+        Err(_) => default,
+    };
+    if value == 0 {
+        bail!("{} must be a positive integer, got 0", name);
+    }
+    Ok(value)
+}
@@ -0,0 +1,278 @@
+//! The socket server: connections, in-flight limits, and the ingest loop.
+//!
+//! Each connection reads frames and submits them to a single ingest loop that owns the
+//! pipeline. The loop ingests whatever is queued, then drains, which resolves the reply
+//! of every frame it sent. A connection writes its replies back in frame order.
+
+use anyhow::{anyhow, Context, Result};
+use serde_json::Value;
+use std::future::Future;
+use std::pin::pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{mpsc, oneshot, watch, OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinSet;
+use tracing::{info, warn};
+use zerobus_common::dynamic::DynamicEncoder;
+use zerobus_common::pipeline::Pipeline;
+use zerobus_common::shutdown::{self, DrainOutcome, UnackedSink};
+
+use crate::frame::{read_frame, Frame, Reply};
+
+/// Bounds on frames and on records waiting for their acknowledgment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub max_frame_bytes: usize,
+    /// Frames one connection may have unanswered before the daemon stops reading from it
+    pub connection_inflight: usize,
+    /// Frames all connections together may have unanswered
+    pub global_inflight: usize,
+}
+
+/// A record submitted by a connection, with where its outcome goes
+struct Submission {
+    record: Vec<u8>,
+    outcome: oneshot::Sender<Result<(), String>>,
+}
+
+/// A frame whose reply has not been written yet
+///
+/// The permits are held until then, so unanswered frames count against both limits.
+struct Unanswered {
+    seq: u64,
+    outcome: oneshot::Receiver<Result<(), String>>,
+    _permits: Vec<OwnedSemaphorePermit>,
+}
+
+struct Shared {
+    encoder: DynamicEncoder,
+    limits: Limits,
+    global: Arc<Semaphore>,
+    submissions: mpsc::Sender<Submission>,
+}
+
+/// Accept connections on `listener` until `stop` resolves, then drain
+///
+/// On shutdown the daemon stops accepting connections and reading frames, and waits up
+/// to `grace` for the acknowledgments of the frames already sent. Frames that are not
+/// acknowledged by then, or that were still queued, are answered with an error.
+pub async fn serve<S: UnackedSink + 'static>(
+    listener: UnixListener,
+    encoder: DynamicEncoder,
+    limits: Limits,
+    pipeline: Pipeline<S>,
+    grace: Duration,
+    stop: impl Future<Output = ()>,
+) -> Result<DrainOutcome> {
+    let (submissions, queued) = mpsc::channel(limits.global_inflight);
+    let (stopping, stopped) = watch::channel(false);
+    let mut ingest = tokio::spawn(ingest_loop(queued, pipeline, stopped.clone()));
+    let shared = Arc::new(Shared {
+        encoder,
+        limits,
+        global: Arc::new(Semaphore::new(limits.global_inflight)),
+        submissions,
+    });
+
+    let mut connections = JoinSet::new();
+    let mut stop = pin!(stop);
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    let shared = Arc::clone(&shared);
+                    connections.spawn(handle_connection(stream, shared, stopped.clone()));
+                }
+                Err(e) => warn!("Failed to accept connection: {}", e),
+            },
+            Some(joined) = connections.join_next(), if !connections.is_empty() => {
+                if let Ok(Err(e)) = joined {
+                    warn!("Connection closed: {:#}", e);
+                }
+            }
+            // The loop only returns before shutdown if the stream failed
+            ingested = &mut ingest => {
+                return Err(match ingested.context("Ingest loop panicked")? {
+                    Ok(_) => anyhow!("Ingest loop stopped before shutdown"),
+                    Err(e) => e,
+                });
+            }
+            _ = &mut stop => break,
+        }
+    }
+
+    info!("Shutting down: draining {} connections", connections.len());
+    drop(listener);
+    stopping.send_replace(true);
+    drop(shared);
+    let pipeline = ingest.await.context("Ingest loop panicked")??;
+    let outcome = shutdown::drain(pipeline, grace).await?;
+
+    // Every outcome is settled now, so connections only have replies left to write
+    let closed = tokio::time::timeout(grace, async {
+        while let Some(joined) = connections.join_next().await {
+            if let Ok(Err(e)) = joined {
+                warn!("Connection closed: {:#}", e);
+            }
+        }
+    })
+    .await;
+    if closed.is_err() {
+        warn!(
+            "Closing {} connections that did not read their replies",
+            connections.len()
+        );
+    }
+    Ok(outcome)
+}
+
+/// Ingest submitted records, draining after each burst so their replies go out
+///
+/// Returns the pipeline once every connection is gone or shutdown has begun; a drain
+/// still waiting on acknowledgments at that point is left to the grace period.
+async fn ingest_loop<S: UnackedSink>(
+    mut queued: mpsc::Receiver<Submission>,
+    mut pipeline: Pipeline<S>,
+    mut stopped: watch::Receiver<bool>,
+) -> Result<Pipeline<S>> {
+    loop {
+        let submission = tokio::select! {
+            submission = queued.recv() => submission,
+            _ = stopped.wait_for(|stopped| *stopped) => None,
+        };
+        let Some(submission) = submission else {
+            break;
+        };
+        submit(&mut pipeline, submission).await?;
+        while let Ok(submission) = queued.try_recv() {
+            submit(&mut pipeline, submission).await?;
+        }
+
+        tokio::select! {
+            drained = pipeline.drain() => drained?,
+            _ = stopped.wait_for(|stopped| *stopped) => break,
+        }
+    }
+    Ok(pipeline)
+}
+
+async fn submit<S: UnackedSink>(pipeline: &mut Pipeline<S>, submission: Submission) -> Result<()> {
+    let outcome = submission.outcome;
+    pipeline
+        .ingest_with_callback(submission.record, move |result| {
+            let _ = outcome.send(result.map_err(|e| format!("{:#}", e)));
+        })
+        .await
+}
+
+async fn handle_connection(
+    stream: UnixStream,
+    shared: Arc<Shared>,
+    mut stopped: watch::Receiver<bool>,
+) -> Result<()> {
+    let (reader, writer) = stream.into_split();
+    let (unanswered, replies) = mpsc::unbounded_channel();
+    let writer = tokio::spawn(write_replies(writer, replies));
+
+    let read = tokio::select! {
+        read = read_frames(reader, &shared, unanswered) => read,
+        _ = stopped.wait_for(|stopped| *stopped) => Ok(()),
+    };
+    drop(shared);
+    let written = writer.await.context("Reply writer panicked")?;
+    read.and(written)
+}
+
+async fn read_frames(
+    reader: OwnedReadHalf,
+    shared: &Shared,
+    unanswered: mpsc::UnboundedSender<Unanswered>,
+) -> Result<()> {
+    let mut reader = BufReader::new(reader);
+    let connection = Arc::new(Semaphore::new(shared.limits.connection_inflight));
+    for seq in 0.. {
+        let connection_permit = Arc::clone(&connection).acquire_owned().await?;
+        let (outcome, reply) = oneshot::channel();
+        let mut permits = vec![connection_permit];
+
+        let frame = match read_frame(&mut reader, shared.limits.max_frame_bytes).await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                // Answer the frame that broke framing, then hang up
+                let _ = outcome.send(Err(format!("{:#}", e)));
+                let _ = unanswered.send(Unanswered {
+                    seq,
+                    outcome: reply,
+                    _permits: permits,
+                });
+                return Err(e);
+            }
+        };
+        match encode(&shared.encoder, frame) {
+            Ok(record) => {
+                permits.push(Arc::clone(&shared.global).acquire_owned().await?);
+                // Fails only once the ingest loop is gone, which answers the frame
+                // through the dropped outcome
+                let _ = shared
+                    .submissions
+                    .send(Submission { record, outcome })
+                    .await;
+            }
+            Err(e) => {
+                let _ = outcome.send(Err(format!("{:#}", e)));
+            }
+        }
+
+        let unanswered_frame = Unanswered {
+            seq,
+            outcome: reply,
+            _permits: permits,
+        };
+        if unanswered.send(unanswered_frame).is_err() {
+            // The writer failed, so nothing more can be answered
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+fn encode(encoder: &DynamicEncoder, frame: Frame) -> Result<Vec<u8>> {
+    match frame {
+        Frame::Binary(record) => {
+            encoder
+                .check(&record)
+                .context("Record does not match the table's message")?;
+            Ok(record)
+        }
+        Frame::Json(line) => {
+            let value: Value = serde_json::from_slice(&line).context("Frame is not valid JSON")?;
+            encoder.encode(&value)
+        }
+    }
+}
+
+async fn write_replies(
+    mut writer: OwnedWriteHalf,
+    mut unanswered: mpsc::UnboundedReceiver<Unanswered>,
+) -> Result<()> {
+    while let Some(frame) = unanswered.recv().await {
+        let result = frame
+            .outcome
+            .await
+            .unwrap_or_else(|_| Err("Not acknowledged before shutdown".to_string()));
+        let reply = Reply {
+            seq: frame.seq,
+            result,
+        };
+        writer
+            .write_all(&reply.to_line())
+            .await
+            .context("Failed to write reply")?;
+    }
+    writer.shutdown().await?;
+    Ok(())
+}
@@ -0,0 +1,76 @@
+//! Binding the daemon's socket file.
+
+use anyhow::{bail, Context, Result};
+use std::fs::Permissions;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::Path;
+use tokio::net::UnixListener;
+
+/// Parse an octal file mode such as `660` or `0o660`
+pub fn parse_mode(mode: &str) -> Result<u32> {
+    let digits = mode.trim().trim_start_matches("0o");
+    let parsed = u32::from_str_radix(digits, 8)
+        .with_context(|| format!("SOCKET_MODE must be an octal mode, got {:?}", mode))?;
+    if parsed > 0o777 {
+        bail!("SOCKET_MODE must be an octal mode, got {:?}", mode);
+    }
+    Ok(parsed)
+}
+
+/// Listen on `path` and give the socket file `mode`
+///
+/// A socket file left behind by a daemon that did not shut down cleanly is replaced.
+/// Fails if another daemon is still listening on it, or if `path` is not a socket.
+pub async fn bind(path: &Path, mode: u32) -> Result<UnixListener> {
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            bail!("{} exists and is not a socket", path.display());
+        }
+        if tokio::net::UnixStream::connect(path).await.is_ok() {
+            bail!("Another daemon is listening on {}", path.display());
+        }
+        std::fs::remove_file(path)
+            .with_context(|| format!("Failed to remove stale socket {}", path.display()))?;
+    }
+
+    let listener =
+        UnixListener::bind(path).with_context(|| format!("Failed to bind {}", path.display()))?;
+    std::fs::set_permissions(path, Permissions::from_mode(mode))
+        .with_context(|| format!("Failed to set the mode of {}", path.display()))?;
+    Ok(listener)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mode() {
+        assert_eq!(0o660, parse_mode("660").unwrap());
+        assert_eq!(0o600, parse_mode("0600").unwrap());
+        assert_eq!(0o770, parse_mode("0o770").unwrap());
+        assert!(parse_mode("rw-rw----").is_err());
+        assert!(parse_mode("1777").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bind_replaces_stale_sockets() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ingest.sock");
+
+        let listener = bind(&path, 0o600).await.unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(0o600, mode & 0o777);
+        assert!(bind(&path, 0o600).await.is_err());
+
+        // Dropping the listener leaves the file behind, as a crash would
+        drop(listener);
+        bind(&path, 0o660).await.unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(0o660, mode & 0o777);
+
+        let file = dir.path().join("not-a-socket");
+        std::fs::write(&file, b"").unwrap();
+        assert!(bind(&file, 0o600).await.is_err());
+    }
+}
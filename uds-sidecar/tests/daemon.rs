@@ -0,0 +1,282 @@
+//! Drives the daemon over a real socket with the in-memory sink in place of a stream.
+
+use anyhow::Result;
+use prost_types::field_descriptor_proto::{Label, Type};
+use prost_types::{DescriptorProto, FieldDescriptorProto};
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::unix::OwnedReadHalf;
+use tokio::net::UnixStream;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use uds_sidecar::frame::binary_frame;
+use uds_sidecar::server::{serve, Limits};
+use uds_sidecar::socket;
+use zerobus_common::dynamic::DynamicEncoder;
+use zerobus_common::pipeline::Pipeline;
+use zerobus_common::shutdown::DrainOutcome;
+use zerobus_common::testing::MockSink;
+
+struct Daemon {
+    path: PathBuf,
+    stop: oneshot::Sender<()>,
+    served: JoinHandle<Result<DrainOutcome>>,
+    _dir: tempfile::TempDir,
+}
+
+fn field(name: &str, number: i32, kind: Type) -> FieldDescriptorProto {
+    FieldDescriptorProto {
+        name: Some(name.to_string()),
+        number: Some(number),
+        label: Some(Label::Optional as i32),
+        r#type: Some(kind as i32),
+        ..Default::default()
+    }
+}
+
+fn encoder() -> DynamicEncoder {
+    let descriptor = DescriptorProto {
+        name: Some("table_events".to_string()),
+        field: vec![field("id", 1, Type::Int64), field("body", 2, Type::String)],
+        ..Default::default()
+    };
+    DynamicEncoder::new(&descriptor).unwrap()
+}
+
+async fn start(sink: MockSink, limits: Limits, grace: Duration) -> Daemon {
+    start_with_encoder(encoder(), sink, limits, grace).await
+}
+
+async fn start_with_encoder(
+    encoder: DynamicEncoder,
+    sink: MockSink,
+    limits: Limits,
+    grace: Duration,
+) -> Daemon {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("ingest.sock");
+    let listener = socket::bind(&path, 0o600).await.unwrap();
+    let (stop, stopped) = oneshot::channel();
+    let pipeline = Pipeline::new(sink, limits.global_inflight);
+    let served = tokio::spawn(serve(listener, encoder, limits, pipeline, grace, async {
+        let _ = stopped.await;
+    }));
+    Daemon {
+        path,
+        stop,
+        served,
+        _dir: dir,
+    }
+}
+
+/// Send `frames`, half-close, and collect every reply line
+///
+/// Writes may fail once the daemon hangs up, which some tests make it do.
+async fn exchange(path: &PathBuf, frames: Vec<Vec<u8>>) -> Vec<Value> {
+    let stream = UnixStream::connect(path).await.unwrap();
+    let (reader, mut writer) = stream.into_split();
+    let sender = tokio::spawn(async move {
+        for frame in frames {
+            if writer.write_all(&frame).await.is_err() {
+                return;
+            }
+        }
+        let _ = writer.shutdown().await;
+    });
+
+    let replies = read_replies(reader).await;
+    sender.await.unwrap();
+    replies
+}
+
+/// Reply lines until the daemon closes the connection
+///
+/// A daemon hanging up on unread frames resets the connection after its last reply.
+async fn read_replies(reader: OwnedReadHalf) -> Vec<Value> {
+    let mut lines = BufReader::new(reader).lines();
+    let mut replies = Vec::new();
+    while let Ok(Some(line)) = lines.next_line().await {
+        replies.push(serde_json::from_str(&line).unwrap());
+    }
+    replies
+}
+
+fn json_frame(value: Value) -> Vec<u8> {
+    let mut frame = value.to_string().into_bytes();
+    frame.push(b'\n');
+    frame
+}
+
+#[tokio::test]
+async fn test_concurrent_connections_get_their_own_replies() {
+    // Bodies ending in "fail" are rejected by the mock stream
+    let sink = MockSink::default().fail_acks_for(|record| record.ends_with(b"fail"));
+    let limits = Limits {
+        max_frame_bytes: 1024,
+        connection_inflight: 4,
+        global_inflight: 8,
+    };
+    let daemon = start(sink.clone(), limits, Duration::from_secs(5)).await;
+
+    let clients: Vec<_> = (0..8i64)
+        .map(|connection| {
+            let path = daemon.path.clone();
+            let frames = (0..40i64)
+                .map(|i| {
+                    let id = connection * 1000 + i;
+                    match i % 4 {
+                        0 => json_frame(json!({"id": id, "body": "ok"})),
+                        1 => json_frame(json!({"id": id, "body": "please fail"})),
+                        2 => json_frame(json!({"id": id, "unknown": true})),
+                        _ => binary_frame(&encoder().encode(&json!({"id": id})).unwrap()),
+                    }
+                })
+                .collect();
+            tokio::spawn(async move { exchange(&path, frames).await })
+        })
+        .collect();
+
+    for client in clients {
+        let replies = client.await.unwrap();
+        assert_eq!(40, replies.len());
+        for (seq, reply) in replies.iter().enumerate() {
+            assert_eq!(json!(seq), reply["seq"]);
+            match seq % 4 {
+                1 => assert_eq!(json!("mock ack failure"), reply["error"]),
+                2 => assert!(reply["error"]
+                    .as_str()
+                    .unwrap()
+                    .contains("Unknown field \"unknown\"")),
+                _ => assert_eq!(json!("ack"), reply["status"]),
+            }
+        }
+    }
+
+    daemon.stop.send(()).unwrap();
+    let outcome = daemon.served.await.unwrap().unwrap();
+    assert_eq!(8 * 20, outcome.summary.ingested);
+    assert_eq!(8 * 10, outcome.summary.failed);
+    assert!(outcome.unacked.is_empty());
+    assert_eq!(8 * 30, sink.records().len());
+    assert!(sink.closed());
+}
+
+#[tokio::test]
+async fn test_shutdown_answers_unacknowledged_frames() {
+    let sink = MockSink::default().stall_acks_for(|record| record == [0x08, 0x00]);
+    let limits = Limits {
+        max_frame_bytes: 1024,
+        connection_inflight: 2,
+        global_inflight: 100,
+    };
+    let daemon = start(sink.clone(), limits, Duration::from_millis(100)).await;
+
+    let stream = UnixStream::connect(&daemon.path).await.unwrap();
+    let (reader, mut writer) = stream.into_split();
+    for id in 0..3 {
+        writer
+            .write_all(&json_frame(json!({"id": id})))
+            .await
+            .unwrap();
+    }
+
+    // The first ack never comes, so the drain waiting for it holds the second record
+    // back from the stream
+    while sink.records().is_empty() {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(1, sink.records().len());
+
+    // Both frames in flight are answered; the third was never read, as the connection
+    // was at its limit
+    daemon.stop.send(()).unwrap();
+    let replies = read_replies(reader).await;
+    assert_eq!(
+        vec![
+            json!({"seq": 0, "status": "err", "error": "Not acknowledged before shutdown"}),
+            json!({"seq": 1, "status": "err", "error": "Not acknowledged before shutdown"}),
+        ],
+        replies
+    );
+
+    let outcome = daemon.served.await.unwrap().unwrap();
+    assert_eq!(1, outcome.unacked.len());
+    assert!(!sink.closed());
+}
+
+#[tokio::test]
+async fn test_oversized_frame_closes_the_connection() {
+    let limits = Limits {
+        max_frame_bytes: 16,
+        connection_inflight: 10,
+        global_inflight: 10,
+    };
+    let daemon = start(MockSink::default(), limits, Duration::from_secs(1)).await;
+
+    let frames = vec![
+        json_frame(json!({"id": 1})),
+        binary_frame(&[0; 17]),
+        json_frame(json!({"id": 2})),
+    ];
+    let replies = exchange(&daemon.path, frames).await;
+    assert_eq!(2, replies.len());
+    assert_eq!(json!("ack"), replies[0]["status"]);
+    assert_eq!(json!(1), replies[1]["seq"]);
+    assert!(replies[1]["error"].as_str().unwrap().contains("17 bytes"));
+
+    daemon.stop.send(()).unwrap();
+    daemon.served.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_invalid_binary_frame_is_answered_without_sending_it() {
+    let sink = MockSink::default();
+    let limits = Limits {
+        max_frame_bytes: 1024,
+        connection_inflight: 10,
+        global_inflight: 10,
+    };
+    let validating = encoder().validate_records(true);
+    let daemon = start_with_encoder(validating, sink.clone(), limits, Duration::from_secs(1)).await;
+
+    // Field 3 is not in the table's message
+    let frames = vec![
+        binary_frame(&[0x18, 0x01]),
+        binary_frame(&encoder().encode(&json!({"id": 2})).unwrap()),
+    ];
+    let replies = exchange(&daemon.path, frames).await;
+    assert_eq!(2, replies.len());
+    assert_eq!(json!("err"), replies[0]["status"]);
+    assert!(replies[0]["error"]
+        .as_str()
+        .unwrap()
+        .contains("Unknown field number 3"));
+    assert_eq!(json!("ack"), replies[1]["status"]);
+    assert_eq!(1, sink.records().len());
+
+    daemon.stop.send(()).unwrap();
+    daemon.served.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_binary_frames_are_not_validated_by_default() {
+    let sink = MockSink::default();
+    let limits = Limits {
+        max_frame_bytes: 1024,
+        connection_inflight: 10,
+        global_inflight: 10,
+    };
+    let daemon = start(sink.clone(), limits, Duration::from_secs(1)).await;
+
+    // Without VALIDATE_RECORDS the record is left for the server to reject
+    let replies = exchange(&daemon.path, vec![binary_frame(&[0x18, 0x01])]).await;
+    assert_eq!(1, replies.len());
+    assert_eq!(json!("ack"), replies[0]["status"]);
+    assert_eq!(vec![vec![0x18, 0x01]], sink.records());
+
+    daemon.stop.send(()).unwrap();
+    daemon.served.await.unwrap().unwrap();
+}
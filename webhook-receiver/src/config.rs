@@ -0,0 +1,244 @@
+//! Routes, read from the JSON file named by WEBHOOK_CONFIG
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+
+/// Stripe's header, and how old its signatures may be, when the route does not say
+const STRIPE_HEADER: &str = "Stripe-Signature";
+const STRIPE_TOLERANCE_SECS: u64 = 300;
+
+/// Paths served for every receiver, which routes may not use
+const RESERVED_PATHS: [&str; 2] = ["/health", "/metrics"];
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub routes: Vec<RouteConfig>,
+}
+
+/// One webhook source: where it posts, how it signs, and where its events go
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    /// Names the route in logs and metrics
+    pub name: String,
+    pub path: String,
+    pub verification: Verification,
+    /// Environment variable holding the route's secrets, separated by commas
+    ///
+    /// A request signed with any of them is accepted, so a new secret can be added
+    /// before the sender switches to it and the old one removed after.
+    #[serde(default)]
+    pub secrets_env: Option<String>,
+    pub table: String,
+    pub descriptor_set: PathBuf,
+    /// Message in the descriptor set (default: `table_<table>`)
+    #[serde(default)]
+    pub message: Option<String>,
+    /// Columns taken from the body, as JSON pointers such as `/data/object/id`
+    ///
+    /// Without it, the fields of the body are the columns.
+    #[serde(default)]
+    pub fields: Option<BTreeMap<String, String>>,
+    /// Columns taken from request headers, such as the sender's event id
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Column that gets the whole body as a JSON string
+    #[serde(default)]
+    pub payload_column: Option<String>,
+    /// Drop body fields that are not columns instead of rejecting the request
+    #[serde(default)]
+    pub ignore_unknown_fields: bool,
+}
+
+/// How a route's requests are authenticated
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "scheme", rename_all = "kebab-case")]
+pub enum Verification {
+    /// HMAC-SHA256 of the body in a header, as GitHub, Shopify, and most internal
+    /// senders do
+    HmacSha256 {
+        header: String,
+        /// Text before the digest, such as `sha256=`
+        #[serde(default)]
+        prefix: String,
+        #[serde(default)]
+        encoding: Encoding,
+    },
+    /// HMAC-SHA1 of the body in a header, for older senders
+    HmacSha1 {
+        header: String,
+        #[serde(default)]
+        prefix: String,
+        #[serde(default)]
+        encoding: Encoding,
+    },
+    /// Stripe's `t=<timestamp>,v1=<hex>` header, signing `<timestamp>.<body>`
+    Stripe {
+        #[serde(default = "stripe_header")]
+        header: String,
+        /// How far the timestamp may be from now, in either direction
+        #[serde(default = "stripe_tolerance")]
+        tolerance_secs: u64,
+    },
+    /// Accept every request, for senders authenticated some other way
+    None,
+}
+
+/// How a digest is written in its header
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Hex,
+    Base64,
+}
+
+fn stripe_header() -> String {
+    STRIPE_HEADER.to_string()
+}
+
+fn stripe_tolerance() -> u64 {
+    STRIPE_TOLERANCE_SECS
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let json = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Self::parse(&json).with_context(|| format!("Invalid config {}", path.display()))
+    }
+
+    pub fn parse(json: &str) -> Result<Self> {
+        let config: Config = serde_json::from_str(json)?;
+        if config.routes.is_empty() {
+            bail!("No routes are configured");
+        }
+
+        let mut names = HashSet::new();
+        let mut paths = HashSet::new();
+        for route in &config.routes {
+            if !names.insert(route.name.as_str()) {
+                bail!("Route name {:?} is used twice", route.name);
+            }
+            if !route.path.starts_with('/') || RESERVED_PATHS.contains(&route.path.as_str()) {
+                bail!("Route {:?} cannot use path {:?}", route.name, route.path);
+            }
+            if !paths.insert(route.path.as_str()) {
+                bail!("Path {:?} is used by two routes", route.path);
+            }
+            match (&route.verification, &route.secrets_env) {
+                (Verification::None, Some(_)) => {
+                    bail!("Route {:?} has secrets but verifies nothing", route.name)
+                }
+                (Verification::None, None) | (_, Some(_)) => {}
+                (_, None) => bail!("Route {:?} needs secrets_env", route.name),
+            }
+            for pointer in route.fields.iter().flat_map(|fields| fields.values()) {
+                if !pointer.is_empty() && !pointer.starts_with('/') {
+                    bail!(
+                        "Route {:?}: {:?} is not a JSON pointer; they start with '/'",
+                        route.name,
+                        pointer
+                    );
+                }
+            }
+        }
+        Ok(config)
+    }
+}
+
+impl RouteConfig {
+    /// The message of the route's table in its descriptor set
+    pub fn message_name(&self) -> String {
+        self.message.clone().unwrap_or_else(|| {
+            format!(
+                "table_{}",
+                self.table.rsplit('.').next().unwrap_or(&self.table)
+            )
+        })
+    }
+}
+
+/// Read a route's secrets from `name`
+pub fn secrets_from_env(name: &str) -> Result<Vec<Vec<u8>>> {
+    let value = std::env::var(name)
+        .with_context(|| format!("{} environment variable must be set", name))?;
+    let secrets: Vec<Vec<u8>> = value
+        .split(',')
+        .map(str::trim)
+        .filter(|secret| !secret.is_empty())
+        .map(|secret| secret.as_bytes().to_vec())
+        .collect();
+    if secrets.is_empty() {
+        bail!("{} holds no secrets", name);
+    }
+    Ok(secrets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXAMPLE: &str = include_str!("../config/routes.example.json");
+
+    #[test]
+    fn test_example_config() {
+        let config = Config::parse(EXAMPLE).unwrap();
+
+        let stripe = &config.routes[0];
+        assert_eq!("stripe", stripe.name);
+        assert_eq!(
+            Verification::Stripe {
+                header: "Stripe-Signature".to_string(),
+                tolerance_secs: 300
+            },
+            stripe.verification
+        );
+        assert_eq!("table_stripe_events", stripe.message_name());
+        assert_eq!(
+            Some(&"/data/object/id".to_string()),
+            stripe.fields.as_ref().unwrap().get("object_id")
+        );
+
+        let shopify = &config.routes[1];
+        assert_eq!(
+            Verification::HmacSha256 {
+                header: "X-Shopify-Hmac-Sha256".to_string(),
+                prefix: String::new(),
+                encoding: Encoding::Base64
+            },
+            shopify.verification
+        );
+        assert_eq!(
+            Some(&"X-Shopify-Topic".to_string()),
+            shopify.headers.get("topic")
+        );
+        assert_eq!(Verification::None, config.routes[3].verification);
+    }
+
+    #[test]
+    fn test_invalid_configs() {
+        let route = |path: &str, verification: &str| {
+            format!(
+                r#"{{"name": "r{}", "path": "{}", "verification": {}, "table": "t", "descriptor_set": "d"}}"#,
+                path, path, verification
+            )
+        };
+        let config = |routes: &[String]| format!(r#"{{"routes": [{}]}}"#, routes.join(","));
+        let none = r#"{"scheme": "none"}"#;
+
+        Config::parse(&config(&[route("/a", none)])).unwrap();
+        assert!(Config::parse(r#"{"routes": []}"#).is_err());
+        assert!(Config::parse(&config(&[route("/metrics", none)])).is_err());
+        assert!(Config::parse(&config(&[route("no-slash", none)])).is_err());
+        let duplicate_path = r#"{"name": "other", "path": "/a", "verification": {"scheme": "none"}, "table": "t", "descriptor_set": "d"}"#;
+        assert!(Config::parse(&config(&[route("/a", none), duplicate_path.to_string()])).is_err());
+        // Signed schemes need secrets
+        let stripe = r#"{"scheme": "stripe"}"#;
+        assert!(Config::parse(&config(&[route("/a", stripe)])).is_err());
+        assert!(Config::parse(&config(&[route("/a", r#"{"scheme": "md5"}"#)])).is_err());
+    }
+}
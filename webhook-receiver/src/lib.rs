@@ -0,0 +1,5 @@
+pub mod config;
+pub mod replay;
+pub mod server;
+pub mod transform;
+pub mod verify;
@@ -0,0 +1,171 @@
+use anyhow::{bail, Context, Result};
+use databricks_zerobus_ingest_sdk::{StreamConfigurationOptions, TableProperties, ZerobusSdk};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::{info, warn};
+use webhook_receiver::config::{secrets_from_env, Config, Verification};
+use webhook_receiver::replay::{self, ReplayLimits, ReplayStore};
+use webhook_receiver::server::{self, router, AppState, Route};
+use webhook_receiver::transform::Transform;
+use webhook_receiver::verify::Verifier;
+use zerobus_common::descriptor::find_message_descriptor;
+use zerobus_common::dynamic::DynamicEncoder;
+use zerobus_common::log_level;
+use zerobus_common::pipeline::Pipeline;
+use zerobus_common::shutdown;
+
+/// Maximum number of unacknowledged records per stream
+const MAX_INFLIGHT_RECORDS: usize = 10_000;
+
+/// Address to listen on when LISTEN_ADDR is not set
+const DEFAULT_LISTEN_ADDR: &str = "0.0.0.0:8080";
+
+fn env(name: &str) -> Result<String> {
+    std::env::var(name).with_context(|| format!("{} environment variable must be set", name))
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let log_level = log_level::init()?;
+    tokio::spawn(log_level::on_hangup(log_level.clone()));
+
+    let zerobus_endpoint = env("ZEROBUS_ENDPOINT")?;
+    let databricks_host = env("DATABRICKS_HOST")?;
+    let client_id = env("DATABRICKS_CLIENT_ID")?;
+    let client_secret = env("DATABRICKS_CLIENT_SECRET")?;
+    let config = Config::load(Path::new(&env("WEBHOOK_CONFIG")?))?;
+    let listen_addr =
+        std::env::var("LISTEN_ADDR").unwrap_or_else(|_| DEFAULT_LISTEN_ADDR.to_string());
+    let grace = shutdown::grace_from_env()?;
+    let replay_dir = std::env::var("REPLAY_DIR")
+        .ok()
+        .filter(|dir| !dir.is_empty());
+    let replay_limits = ReplayLimits::from_env()?;
+
+    let sdk = ZerobusSdk::new(zerobus_endpoint, databricks_host)?;
+
+    // Routes often share a descriptor set, so each file is read once
+    let mut descriptor_sets: HashMap<PathBuf, Vec<u8>> = HashMap::new();
+    let mut routes = Vec::with_capacity(config.routes.len());
+    for route in &config.routes {
+        if !descriptor_sets.contains_key(&route.descriptor_set) {
+            let bytes = std::fs::read(&route.descriptor_set).with_context(|| {
+                format!(
+                    "Failed to read descriptor set {}",
+                    route.descriptor_set.display()
+                )
+            })?;
+            descriptor_sets.insert(route.descriptor_set.clone(), bytes);
+        }
+        let descriptor = find_message_descriptor(
+            &descriptor_sets[&route.descriptor_set],
+            &route.message_name(),
+        )
+        .with_context(|| format!("Route {:?}", route.name))?;
+
+        let encoder =
+            DynamicEncoder::new(&descriptor)?.ignore_unknown_fields(route.ignore_unknown_fields);
+        let transform = Transform::new(encoder, route)?;
+        let secrets = match &route.secrets_env {
+            Some(name) => secrets_from_env(name)?,
+            None => Vec::new(),
+        };
+        if route.verification == Verification::None {
+            warn!(
+                "Route {:?} accepts unsigned requests on {}",
+                route.name, route.path
+            );
+        }
+        let verifier = Verifier::new(route.verification.clone(), secrets)
+            .with_context(|| format!("Route {:?}", route.name))?;
+
+        let table_properties = TableProperties {
+            table_name: route.table.clone(),
+            descriptor_proto: descriptor,
+        };
+        let stream_options = StreamConfigurationOptions {
+            max_inflight_records: MAX_INFLIGHT_RECORDS,
+            ..Default::default()
+        };
+        let stream = sdk
+            .create_stream(
+                table_properties,
+                client_id.clone(),
+                client_secret.clone(),
+                Some(stream_options),
+            )
+            .await
+            .with_context(|| format!("Failed to create stream to {}", route.table))?;
+        info!("Route {:?}: {} -> {}", route.name, route.path, route.table);
+
+        routes.push(Route::new(
+            route.name.clone(),
+            route.path.clone(),
+            verifier,
+            transform,
+            Pipeline::new(stream, MAX_INFLIGHT_RECORDS),
+        ));
+    }
+    let mut state = AppState::new(routes);
+
+    // Requests a crash or an outage left unacknowledged go first, so they are not
+    // overtaken by the ones that arrive after this start
+    let store = match &replay_dir {
+        Some(dir) => {
+            let (store, replayed) = ReplayStore::open(Path::new(dir), replay_limits)?;
+            let store = Arc::new(store);
+            state = state.replay_store(Arc::clone(&store));
+            server::replay(&state, replayed).await?;
+            tokio::spawn(replay::trim_periodically(Arc::clone(&store)));
+            Some(store)
+        }
+        None => None,
+    };
+
+    let listener = tokio::net::TcpListener::bind(&listen_addr)
+        .await
+        .with_context(|| format!("Failed to bind {}", listen_addr))?;
+    info!(
+        "Listening for webhooks on http://{} ({} routes)",
+        listen_addr,
+        config.routes.len()
+    );
+
+    let mut app = router(state.clone());
+    if let Some(admin) = log_level::admin_routes_from_env(&log_level) {
+        app = app.merge(admin);
+    }
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown::signal())
+        .await?;
+
+    // Every handler has returned, so these are the last references to the pipelines.
+    // The routes share one grace period rather than each getting their own.
+    let started = Instant::now();
+    let mut unacked = 0;
+    for (name, pipeline) in state.into_pipelines().into_iter().flatten() {
+        let outcome = shutdown::drain(pipeline, grace.saturating_sub(started.elapsed())).await?;
+        info!(
+            "{}: shut down after ingesting {} rows ({} failed)",
+            name, outcome.summary.ingested, outcome.summary.failed
+        );
+        unacked += outcome.unacked.len();
+    }
+    // Record the last acknowledgments, so the next start only replays what is left
+    if let Some(store) = &store {
+        store.trim()?;
+        if store.unacked() > 0 {
+            info!(
+                "{} requests are left in the replay store for the next start",
+                store.unacked()
+            );
+        }
+    }
+    if unacked > 0 {
+        bail!("{} rows were not acknowledged before shutdown", unacked);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,744 @@
+//! A write-ahead store of accepted requests, replayed after a crash or an outage
+//!
+//! With `REPLAY_DIR` set, every request that passes verification and fits its table is
+//! appended to the current segment file and synced to disk before it is ingested. Its
+//! frame is acknowledged once the row is, and a background task deletes segments whose
+//! frames are all acknowledged. On startup, frames that were never acknowledged are
+//! ingested again before the receiver accepts new requests, so a request answered 202
+//! survives both a Zerobus outage and a crash of the receiver.
+//!
+//! A segment is a sequence of frames:
+//!
+//! ```text
+//! length: u32 BE | crc32: u32 BE | sequence: u64 BE | request: <length> bytes
+//! ```
+//!
+//! The CRC covers the sequence number and the request. A frame cut short by a crash, or
+//! one whose CRC does not match, ends its segment: the segment is truncated there. The
+//! `checkpoint` file, rewritten on every trim, holds the next sequence number at the
+//! time and the frames below it still unacknowledged, so a frame acknowledged before a
+//! crash is only replayed if no trim followed its acknowledgment. Delivery is
+//! at-least-once.
+
+use anyhow::{bail, Context, Result};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{info, warn};
+use zerobus_common::durable;
+
+/// Size at which the current segment is closed and a new one started
+pub const DEFAULT_SEGMENT_BYTES: u64 = 16 * 1024 * 1024;
+
+/// Disk space segments may take up before requests are refused with 503
+pub const DEFAULT_MAX_BYTES: u64 = 1024 * 1024 * 1024;
+
+/// How often acknowledged segments are deleted
+pub const TRIM_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Bytes before a frame's request: length, CRC, and sequence number
+const FRAME_HEADER_BYTES: usize = 16;
+
+const SEGMENT_EXTENSION: &str = "wal";
+const CHECKPOINT_FILE: &str = "checkpoint";
+
+/// A request as it was received, enough to build its row again
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredRequest {
+    /// Name of the route the request was sent to
+    pub route: String,
+    pub headers: Vec<(String, Vec<u8>)>,
+    pub body: Vec<u8>,
+}
+
+impl StoredRequest {
+    /// Route name and each header as a u16 length and the bytes, the header count as a
+    /// u16, header values with u32 lengths, then the body
+    fn encode(&self) -> Result<Vec<u8>> {
+        let mut buf = Vec::with_capacity(self.body.len() + 256);
+        put_u16_bytes(&mut buf, self.route.as_bytes())?;
+        let count = u16::try_from(self.headers.len()).context("Too many headers to store")?;
+        buf.extend_from_slice(&count.to_be_bytes());
+        for (name, value) in &self.headers {
+            put_u16_bytes(&mut buf, name.as_bytes())?;
+            let length = u32::try_from(value.len()).context("Header value too long to store")?;
+            buf.extend_from_slice(&length.to_be_bytes());
+            buf.extend_from_slice(value);
+        }
+        buf.extend_from_slice(&self.body);
+        Ok(buf)
+    }
+
+    fn decode(mut buf: &[u8]) -> Result<Self> {
+        let route = String::from_utf8(take_u16_bytes(&mut buf)?.to_vec())
+            .context("Route name is not UTF-8")?;
+        let count = u16::from_be_bytes(take(&mut buf, 2)?.try_into().unwrap());
+        let mut headers = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let name = String::from_utf8(take_u16_bytes(&mut buf)?.to_vec())
+                .context("Header name is not UTF-8")?;
+            let length = u32::from_be_bytes(take(&mut buf, 4)?.try_into().unwrap());
+            headers.push((name, take(&mut buf, length as usize)?.to_vec()));
+        }
+        Ok(Self {
+            route,
+            headers,
+            body: buf.to_vec(),
+        })
+    }
+}
+
+fn put_u16_bytes(buf: &mut Vec<u8>, bytes: &[u8]) -> Result<()> {
+    let length = u16::try_from(bytes.len()).context("Name too long to store")?;
+    buf.extend_from_slice(&length.to_be_bytes());
+    buf.extend_from_slice(bytes);
+    Ok(())
+}
+
+fn take_u16_bytes<'a>(buf: &mut &'a [u8]) -> Result<&'a [u8]> {
+    let length = u16::from_be_bytes(take(buf, 2)?.try_into().unwrap());
+    take(buf, length as usize)
+}
+
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if buf.len() < len {
+        bail!("Stored request is truncated");
+    }
+    let (head, tail) = buf.split_at(len);
+    *buf = tail;
+    Ok(head)
+}
+
+/// Why a request could not be stored
+#[derive(Debug)]
+pub enum AppendError {
+    /// Storing it would exceed the disk budget; the sender should retry later
+    Full,
+    Failed(anyhow::Error),
+}
+
+impl fmt::Display for AppendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppendError::Full => write!(f, "Replay store is full"),
+            AppendError::Failed(e) => write!(f, "Failed to store request: {:#}", e),
+        }
+    }
+}
+
+impl std::error::Error for AppendError {}
+
+/// Limits of a [`ReplayStore`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplayLimits {
+    /// Size at which a segment is closed and a new one started
+    pub segment_bytes: u64,
+    /// Disk space all segments may take up together
+    pub max_bytes: u64,
+}
+
+impl Default for ReplayLimits {
+    fn default() -> Self {
+        Self {
+            segment_bytes: DEFAULT_SEGMENT_BYTES,
+            max_bytes: DEFAULT_MAX_BYTES,
+        }
+    }
+}
+
+impl ReplayLimits {
+    /// From `REPLAY_SEGMENT_BYTES` and `REPLAY_MAX_BYTES`, each defaulting when unset
+    pub fn from_env() -> Result<Self> {
+        let read = |name: &str, default: u64| match std::env::var(name) {
+            Ok(value) if !value.trim().is_empty() => value
+                .trim()
+                .parse::<u64>()
+                .with_context(|| format!("{} must be a number of bytes", name)),
+            _ => Ok(default),
+        };
+        let limits = Self {
+            segment_bytes: read("REPLAY_SEGMENT_BYTES", DEFAULT_SEGMENT_BYTES)?,
+            max_bytes: read("REPLAY_MAX_BYTES", DEFAULT_MAX_BYTES)?,
+        };
+        if limits.segment_bytes == 0 || limits.max_bytes < limits.segment_bytes {
+            bail!(
+                "REPLAY_SEGMENT_BYTES must be positive and at most REPLAY_MAX_BYTES ({})",
+                limits.max_bytes
+            );
+        }
+        Ok(limits)
+    }
+}
+
+/// A frame read back on startup, to be ingested again and then acknowledged
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Replayed {
+    pub sequence: u64,
+    pub request: StoredRequest,
+}
+
+/// A closed or current segment
+#[derive(Debug, Default)]
+struct Segment {
+    bytes: u64,
+    /// Sequence numbers of its first and last frames, if it has any
+    sequences: Option<(u64, u64)>,
+}
+
+/// Which frames were acknowledged as of the last trim
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Checkpoint {
+    /// Every frame below this was stored before the trim
+    next_sequence: u64,
+    /// Frames below `next_sequence` that were not acknowledged at the time
+    unacked: BTreeSet<u64>,
+}
+
+impl Checkpoint {
+    fn replays(&self, sequence: u64) -> bool {
+        sequence >= self.next_sequence || self.unacked.contains(&sequence)
+    }
+}
+
+struct State {
+    current: File,
+    current_id: u64,
+    segments: BTreeMap<u64, Segment>,
+    /// Bytes of every segment on disk
+    disk_bytes: u64,
+    next_sequence: u64,
+    /// Frames stored or replayed but not acknowledged yet
+    unacked: BTreeSet<u64>,
+    checkpoint: Checkpoint,
+}
+
+/// The segment files in one directory
+pub struct ReplayStore {
+    dir: PathBuf,
+    limits: ReplayLimits,
+    state: Mutex<State>,
+}
+
+impl ReplayStore {
+    /// Open the store in `dir`, creating it if needed
+    ///
+    /// Returns the frames that were not acknowledged before the last shutdown or crash,
+    /// oldest first; they count as unacknowledged until [`ReplayStore::ack`]ed. New
+    /// frames go to a fresh segment.
+    pub fn open(dir: &Path, limits: ReplayLimits) -> Result<(Self, Vec<Replayed>)> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create replay directory {}", dir.display()))?;
+        let checkpoint = read_checkpoint(dir)?;
+
+        let mut segments = BTreeMap::new();
+        let mut replayed = Vec::new();
+        let mut last_sequence = None;
+        for id in segment_ids(dir)? {
+            let path = segment_path(dir, id);
+            let (frames, valid_bytes) = read_segment(&path)?;
+            let bytes = std::fs::metadata(&path)?.len();
+            if valid_bytes < bytes {
+                warn!(
+                    "Truncating {} after {} bytes: the rest is an incomplete or corrupt frame",
+                    path.display(),
+                    valid_bytes
+                );
+                OpenOptions::new()
+                    .write(true)
+                    .open(&path)?
+                    .set_len(valid_bytes)?;
+            }
+            let sequences = frames
+                .first()
+                .zip(frames.last())
+                .map(|(first, last)| (first.sequence, last.sequence));
+            last_sequence = sequences.map(|(_, last)| last).or(last_sequence);
+            replayed.extend(
+                frames
+                    .into_iter()
+                    .filter(|frame| checkpoint.replays(frame.sequence)),
+            );
+            segments.insert(
+                id,
+                Segment {
+                    bytes: valid_bytes,
+                    sequences,
+                },
+            );
+        }
+
+        let next_sequence = last_sequence.map_or(checkpoint.next_sequence, |last| {
+            (last + 1).max(checkpoint.next_sequence)
+        });
+        let current_id = segments.keys().next_back().map_or(0, |id| id + 1);
+        let current = create_segment(dir, current_id)?;
+        segments.insert(current_id, Segment::default());
+        let disk_bytes = segments.values().map(|segment| segment.bytes).sum();
+        let unacked = replayed.iter().map(|frame| frame.sequence).collect();
+        if !replayed.is_empty() {
+            info!(
+                "{} stored requests to replay from {}",
+                replayed.len(),
+                dir.display()
+            );
+        }
+
+        let store = Self {
+            dir: dir.to_path_buf(),
+            limits,
+            state: Mutex::new(State {
+                current,
+                current_id,
+                segments,
+                disk_bytes,
+                next_sequence,
+                unacked,
+                checkpoint,
+            }),
+        };
+        Ok((store, replayed))
+    }
+
+    /// Append `request` and sync it to disk, returning its sequence number
+    ///
+    /// Starts a new segment first when the current one would grow past the segment
+    /// size. Fails with [`AppendError::Full`] when the frame would take the store past
+    /// its disk budget.
+    pub fn append(&self, request: &StoredRequest) -> Result<u64, AppendError> {
+        let payload = request.encode().map_err(AppendError::Failed)?;
+        let length = u32::try_from(payload.len())
+            .context("Request too large to store")
+            .map_err(AppendError::Failed)?;
+        let frame_bytes = (FRAME_HEADER_BYTES + payload.len()) as u64;
+
+        let mut state = self.state.lock().expect("replay store lock poisoned");
+        if state.disk_bytes + frame_bytes > self.limits.max_bytes {
+            return Err(AppendError::Full);
+        }
+        let current_bytes = state.segments[&state.current_id].bytes;
+        if current_bytes > 0 && current_bytes + frame_bytes > self.limits.segment_bytes {
+            self.rotate(&mut state).map_err(AppendError::Failed)?;
+        }
+
+        let sequence = state.next_sequence;
+        let mut frame = Vec::with_capacity(frame_bytes as usize);
+        frame.extend_from_slice(&length.to_be_bytes());
+        frame.extend_from_slice(&checksum(sequence, &payload).to_be_bytes());
+        frame.extend_from_slice(&sequence.to_be_bytes());
+        frame.extend_from_slice(&payload);
+        let written = state
+            .current
+            .write_all(&frame)
+            .and_then(|()| state.current.sync_data());
+        if let Err(e) = written {
+            // A partial frame fails its CRC on replay; later frames go to a new segment
+            // so they are not stranded behind it
+            if let Err(e) = self.rotate(&mut state) {
+                warn!("{:#}", e);
+            }
+            return Err(AppendError::Failed(
+                anyhow::Error::new(e).context("Failed to write to the replay store"),
+            ));
+        }
+
+        state.next_sequence += 1;
+        state.disk_bytes += frame_bytes;
+        state.unacked.insert(sequence);
+        let current_id = state.current_id;
+        let segment = state
+            .segments
+            .get_mut(&current_id)
+            .expect("current segment is tracked");
+        segment.bytes += frame_bytes;
+        segment.sequences = Some(
+            segment
+                .sequences
+                .map_or((sequence, sequence), |(first, _)| (first, sequence)),
+        );
+        Ok(sequence)
+    }
+
+    /// Mark the frame `sequence` acknowledged, so it is not replayed once the next trim
+    /// writes the checkpoint
+    pub fn ack(&self, sequence: u64) {
+        self.state
+            .lock()
+            .expect("replay store lock poisoned")
+            .unacked
+            .remove(&sequence);
+    }
+
+    /// Write the checkpoint and delete closed segments whose frames are all
+    /// acknowledged, returning how many were deleted
+    pub fn trim(&self) -> Result<usize> {
+        let mut state = self.state.lock().expect("replay store lock poisoned");
+        let checkpoint = Checkpoint {
+            next_sequence: state.next_sequence,
+            unacked: state.unacked.clone(),
+        };
+        if checkpoint != state.checkpoint {
+            write_checkpoint(&self.dir, &checkpoint)?;
+            state.checkpoint = checkpoint;
+        }
+
+        let current_id = state.current_id;
+        let trimmed: Vec<u64> = state
+            .segments
+            .iter()
+            .filter(|(id, segment)| {
+                **id != current_id
+                    && segment.sequences.is_none_or(|(first, last)| {
+                        state.unacked.range(first..=last).next().is_none()
+                    })
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        for id in &trimmed {
+            let path = segment_path(&self.dir, *id);
+            match std::fs::remove_file(&path) {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Failed to delete {}", path.display()))
+                }
+            }
+            let segment = state.segments.remove(id).expect("segment is tracked");
+            state.disk_bytes -= segment.bytes;
+        }
+        Ok(trimmed.len())
+    }
+
+    /// Frames stored but not acknowledged
+    pub fn unacked(&self) -> usize {
+        self.state
+            .lock()
+            .expect("replay store lock poisoned")
+            .unacked
+            .len()
+    }
+
+    /// Bytes all segments take up on disk
+    pub fn disk_bytes(&self) -> u64 {
+        self.state
+            .lock()
+            .expect("replay store lock poisoned")
+            .disk_bytes
+    }
+
+    fn rotate(&self, state: &mut State) -> Result<()> {
+        let id = state.current_id + 1;
+        state.current = create_segment(&self.dir, id)?;
+        state.current_id = id;
+        state.segments.insert(id, Segment::default());
+        Ok(())
+    }
+}
+
+/// Trim `store` every [`TRIM_INTERVAL`], for as long as the receiver runs
+pub async fn trim_periodically(store: Arc<ReplayStore>) {
+    let mut interval = tokio::time::interval(TRIM_INTERVAL);
+    loop {
+        interval.tick().await;
+        let store = Arc::clone(&store);
+        match tokio::task::spawn_blocking(move || store.trim()).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => warn!("Failed to trim the replay store: {:#}", e),
+            Err(e) => warn!("Replay store trim panicked: {}", e),
+        }
+    }
+}
+
+fn checksum(sequence: u64, payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&sequence.to_be_bytes());
+    hasher.update(payload);
+    hasher.finalize()
+}
+
+fn segment_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:020}.{}", id, SEGMENT_EXTENSION))
+}
+
+/// Ids of the segment files in `dir`, in order
+fn segment_ids(dir: &Path) -> Result<Vec<u64>> {
+    let mut ids = Vec::new();
+    for entry in std::fs::read_dir(dir)
+        .with_context(|| format!("Failed to list replay directory {}", dir.display()))?
+    {
+        let path = entry?.path();
+        if path.extension().and_then(|extension| extension.to_str()) != Some(SEGMENT_EXTENSION) {
+            continue;
+        }
+        if let Some(id) = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse().ok())
+        {
+            ids.push(id);
+        }
+    }
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+fn create_segment(dir: &Path, id: u64) -> Result<File> {
+    let path = segment_path(dir, id);
+    let file = OpenOptions::new()
+        .create_new(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    durable::sync_dir(dir)?;
+    Ok(file)
+}
+
+/// The frames of a segment up to the first incomplete or corrupt one, and the bytes
+/// they take up
+fn read_segment(path: &Path) -> Result<(Vec<Replayed>, u64)> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut reader = BufReader::new(file);
+    let mut frames = Vec::new();
+    let mut valid_bytes = 0;
+    loop {
+        let mut header = [0u8; FRAME_HEADER_BYTES];
+        if !read_full(&mut reader, &mut header)? {
+            break;
+        }
+        let length = u32::from_be_bytes(header[0..4].try_into().unwrap()) as usize;
+        let crc = u32::from_be_bytes(header[4..8].try_into().unwrap());
+        let sequence = u64::from_be_bytes(header[8..16].try_into().unwrap());
+        let mut payload = vec![0u8; length];
+        if !read_full(&mut reader, &mut payload)? || checksum(sequence, &payload) != crc {
+            break;
+        }
+        let Ok(request) = StoredRequest::decode(&payload) else {
+            break;
+        };
+        valid_bytes += (FRAME_HEADER_BYTES + length) as u64;
+        frames.push(Replayed { sequence, request });
+    }
+    Ok((frames, valid_bytes))
+}
+
+/// Fill `buf`, or return false if the file ends first
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> Result<bool> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The next sequence number on the first line, then one unacknowledged frame per line
+fn read_checkpoint(dir: &Path) -> Result<Checkpoint> {
+    let path = dir.join(CHECKPOINT_FILE);
+    let Some(contents) = durable::read_file(&path)? else {
+        return Ok(Checkpoint::default());
+    };
+    let text = String::from_utf8(contents)
+        .with_context(|| format!("{} is not a checkpoint", path.display()))?;
+    let mut sequences = text.lines().map(|line| {
+        line.trim()
+            .parse::<u64>()
+            .with_context(|| format!("{} holds {:?}, not a sequence number", path.display(), line))
+    });
+    Ok(Checkpoint {
+        next_sequence: sequences.next().transpose()?.unwrap_or(0),
+        unacked: sequences.collect::<Result<_>>()?,
+    })
+}
+
+fn write_checkpoint(dir: &Path, checkpoint: &Checkpoint) -> Result<()> {
+    let mut text = format!("{}\n", checkpoint.next_sequence);
+    for sequence in &checkpoint.unacked {
+        text.push_str(&format!("{}\n", sequence));
+    }
+    durable::replace_file(&dir.join(CHECKPOINT_FILE), text.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(body: &str) -> StoredRequest {
+        StoredRequest {
+            route: "stripe".to_string(),
+            headers: vec![
+                ("content-type".to_string(), b"application/json".to_vec()),
+                ("stripe-signature".to_string(), b"t=1,v1=ab".to_vec()),
+            ],
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    fn limits(segment_bytes: u64, max_bytes: u64) -> ReplayLimits {
+        ReplayLimits {
+            segment_bytes,
+            max_bytes,
+        }
+    }
+
+    fn bodies(replayed: &[Replayed]) -> Vec<String> {
+        replayed
+            .iter()
+            .map(|frame| String::from_utf8(frame.request.body.clone()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_request_encoding_round_trips() {
+        let stored = request(r#"{"id": "evt_1"}"#);
+        assert_eq!(
+            stored,
+            StoredRequest::decode(&stored.encode().unwrap()).unwrap()
+        );
+        let empty = StoredRequest {
+            route: String::new(),
+            headers: Vec::new(),
+            body: Vec::new(),
+        };
+        assert_eq!(
+            empty,
+            StoredRequest::decode(&empty.encode().unwrap()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_unacked_frames_are_replayed_after_reopening() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, replayed) = ReplayStore::open(dir.path(), ReplayLimits::default()).unwrap();
+        assert!(replayed.is_empty());
+
+        let first = store.append(&request("1")).unwrap();
+        let second = store.append(&request("2")).unwrap();
+        store.append(&request("3")).unwrap();
+        store.ack(first);
+        store.ack(second);
+        store.trim().unwrap();
+        assert_eq!(1, store.unacked());
+        drop(store);
+
+        let (store, replayed) = ReplayStore::open(dir.path(), ReplayLimits::default()).unwrap();
+        assert_eq!(vec!["3"], bodies(&replayed));
+        assert_eq!(request("3"), replayed[0].request);
+        assert_eq!(1, store.unacked());
+        // New frames continue the sequence
+        assert!(store.append(&request("4")).unwrap() > replayed[0].sequence);
+    }
+
+    #[test]
+    fn test_acks_after_the_last_checkpoint_are_replayed_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, _) = ReplayStore::open(dir.path(), ReplayLimits::default()).unwrap();
+        let first = store.append(&request("1")).unwrap();
+        let second = store.append(&request("2")).unwrap();
+        store.ack(first);
+        store.trim().unwrap();
+        // Acknowledged, but the process dies before the next checkpoint
+        store.ack(second);
+        drop(store);
+
+        let (_, replayed) = ReplayStore::open(dir.path(), ReplayLimits::default()).unwrap();
+        assert_eq!(vec!["2"], bodies(&replayed));
+    }
+
+    #[test]
+    fn test_an_unacked_frame_does_not_hold_back_later_acks() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, _) = ReplayStore::open(dir.path(), ReplayLimits::default()).unwrap();
+        // Waiting for a replay while later requests are ingested
+        store.append(&request("1")).unwrap();
+        for body in ["2", "3"] {
+            let sequence = store.append(&request(body)).unwrap();
+            store.ack(sequence);
+        }
+        store.trim().unwrap();
+        drop(store);
+
+        let (_, replayed) = ReplayStore::open(dir.path(), ReplayLimits::default()).unwrap();
+        assert_eq!(vec!["1"], bodies(&replayed));
+    }
+
+    #[test]
+    fn test_segments_rotate_and_are_trimmed_once_acknowledged() {
+        let dir = tempfile::tempdir().unwrap();
+        let frame_bytes = (FRAME_HEADER_BYTES + request("1").encode().unwrap().len()) as u64;
+        // Two frames per segment
+        let (store, _) =
+            ReplayStore::open(dir.path(), limits(frame_bytes * 2, frame_bytes * 100)).unwrap();
+
+        let sequences: Vec<u64> = (0..5)
+            .map(|i| store.append(&request(&i.to_string())).unwrap())
+            .collect();
+        assert_eq!(3, segment_ids(dir.path()).unwrap().len());
+        assert_eq!(frame_bytes * 5, store.disk_bytes());
+
+        // The first segment is fully acknowledged, the second only half
+        for sequence in &sequences[..3] {
+            store.ack(*sequence);
+        }
+        assert_eq!(1, store.trim().unwrap());
+        assert_eq!(2, segment_ids(dir.path()).unwrap().len());
+        assert_eq!(frame_bytes * 3, store.disk_bytes());
+
+        // The current segment is kept even when everything is acknowledged
+        for sequence in &sequences[3..] {
+            store.ack(*sequence);
+        }
+        assert_eq!(1, store.trim().unwrap());
+        assert_eq!(1, segment_ids(dir.path()).unwrap().len());
+        drop(store);
+
+        let (_, replayed) = ReplayStore::open(dir.path(), ReplayLimits::default()).unwrap();
+        assert!(replayed.is_empty());
+    }
+
+    #[test]
+    fn test_appends_fail_when_the_disk_budget_is_spent() {
+        let dir = tempfile::tempdir().unwrap();
+        let frame_bytes = (FRAME_HEADER_BYTES + request("1").encode().unwrap().len()) as u64;
+        let (store, _) =
+            ReplayStore::open(dir.path(), limits(frame_bytes, frame_bytes * 2)).unwrap();
+
+        let first = store.append(&request("1")).unwrap();
+        store.append(&request("2")).unwrap();
+        assert!(matches!(
+            store.append(&request("3")),
+            Err(AppendError::Full)
+        ));
+
+        // Trimming the acknowledged segment makes room again
+        store.ack(first);
+        store.trim().unwrap();
+        store.append(&request("3")).unwrap();
+    }
+
+    #[test]
+    fn test_torn_and_corrupt_frames_end_their_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, _) = ReplayStore::open(dir.path(), ReplayLimits::default()).unwrap();
+        store.append(&request("1")).unwrap();
+        store.append(&request("2")).unwrap();
+        drop(store);
+        let path = segment_path(dir.path(), 0);
+        let whole = std::fs::read(&path).unwrap();
+
+        // A crash partway through the second frame
+        std::fs::write(&path, &whole[..whole.len() - 3]).unwrap();
+        let (_, replayed) = ReplayStore::open(dir.path(), ReplayLimits::default()).unwrap();
+        assert_eq!(vec!["1"], bodies(&replayed));
+        let frame_bytes = whole.len() as u64 / 2;
+        assert_eq!(frame_bytes, std::fs::metadata(&path).unwrap().len());
+
+        // A flipped bit in the first frame's body
+        let mut corrupt = whole.clone();
+        corrupt[frame_bytes as usize - 1] ^= 0x01;
+        std::fs::write(&path, &corrupt).unwrap();
+        let (_, replayed) = ReplayStore::open(dir.path(), ReplayLimits::default()).unwrap();
+        assert!(replayed.is_empty());
+    }
+}
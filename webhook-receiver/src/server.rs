@@ -0,0 +1,601 @@
+use anyhow::{bail, Context, Result};
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::{HeaderMap, HeaderName, HeaderValue, StatusCode};
+use axum::routing::{get, post};
+use axum::Router;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
+use zerobus_common::pipeline::{IngestSink, Pipeline};
+
+use crate::replay::{AppendError, ReplayStore, Replayed, StoredRequest};
+use crate::transform::Transform;
+use crate::verify::Verifier;
+
+/// Requests of one route, by outcome
+#[derive(Debug, Default)]
+pub struct RouteMetrics {
+    pub ingested: AtomicU64,
+    /// Missing or wrong signature
+    pub unauthorized: AtomicU64,
+    /// Body that is not a row of the table
+    pub rejected: AtomicU64,
+    /// Row not acknowledged
+    pub failed: AtomicU64,
+    /// Row not acknowledged, but the request is in the replay store
+    pub stored: AtomicU64,
+    /// Refused because the replay store is full
+    pub throttled: AtomicU64,
+}
+
+/// A configured webhook source and the stream to its table
+pub struct Route<S: IngestSink> {
+    pub name: String,
+    pub path: String,
+    verifier: Verifier,
+    transform: Transform,
+    pipeline: Mutex<Pipeline<S>>,
+    metrics: RouteMetrics,
+}
+
+impl<S: IngestSink> Route<S> {
+    pub fn new(
+        name: String,
+        path: String,
+        verifier: Verifier,
+        transform: Transform,
+        pipeline: Pipeline<S>,
+    ) -> Self {
+        Self {
+            name,
+            path,
+            verifier,
+            transform,
+            pipeline: Mutex::new(pipeline),
+            metrics: RouteMetrics::default(),
+        }
+    }
+
+    pub fn metrics(&self) -> &RouteMetrics {
+        &self.metrics
+    }
+}
+
+/// Shared handler state
+///
+/// Each route has its own stream, and requests of a route take turns on it so each
+/// response can report exactly whether that request's row was acknowledged.
+pub struct AppState<S: IngestSink> {
+    routes: Arc<Vec<Route<S>>>,
+    /// Where requests are written before they are ingested, when `REPLAY_DIR` is set
+    store: Option<Arc<ReplayStore>>,
+}
+
+impl<S: IngestSink> Clone for AppState<S> {
+    fn clone(&self) -> Self {
+        Self {
+            routes: Arc::clone(&self.routes),
+            store: self.store.clone(),
+        }
+    }
+}
+
+impl<S: IngestSink> AppState<S> {
+    pub fn new(routes: Vec<Route<S>>) -> Self {
+        Self {
+            routes: Arc::new(routes),
+            store: None,
+        }
+    }
+
+    /// Write every accepted request to `store` before ingesting it
+    pub fn replay_store(mut self, store: Arc<ReplayStore>) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    /// Take the pipelines back, by route name, once the server has stopped, so they
+    /// can be finished
+    pub fn into_pipelines(self) -> Option<Vec<(String, Pipeline<S>)>> {
+        let routes = Arc::try_unwrap(self.routes).ok()?;
+        Some(
+            routes
+                .into_iter()
+                .map(|route| (route.name, route.pipeline.into_inner()))
+                .collect(),
+        )
+    }
+}
+
+/// A route per configured source, plus metrics and a health check
+pub fn router<S: IngestSink + 'static>(state: AppState<S>) -> Router {
+    let mut router = Router::new();
+    for (index, route) in state.routes.iter().enumerate() {
+        router = router.route(
+            &route.path,
+            post(
+                move |State(state): State<AppState<S>>, headers: HeaderMap, body: Bytes| {
+                    webhook(state, index, headers, body)
+                },
+            ),
+        );
+    }
+    router
+        .route("/metrics", get(metrics::<S>))
+        .route("/health", get(|| async { "OK" }))
+        .with_state(state)
+}
+
+/// Handle a request to the route at `index`
+///
+/// Answers 401 unless the request passes the route's verification, 400 if the body is
+/// not a row of the route's table, 202 once the row is durable, and 500 otherwise so
+/// senders that retry (most do on any 5xx) send it again.
+///
+/// With a replay store, the request is stored before it is ingested: a row that is not
+/// acknowledged is still answered 202, as the request is replayed on the next start,
+/// and a full store is answered 503 so the sender backs off.
+async fn webhook<S: IngestSink>(
+    state: AppState<S>,
+    index: usize,
+    headers: HeaderMap,
+    body: Bytes,
+) -> (StatusCode, String) {
+    let route = &state.routes[index];
+    let metrics = &route.metrics;
+
+    let verified = unix_time().and_then(|now| route.verifier.verify(&headers, &body, now));
+    if let Err(e) = verified {
+        metrics.unauthorized.fetch_add(1, Ordering::Relaxed);
+        warn!("{}: rejecting unverified request: {:#}", route.name, e);
+        return (StatusCode::UNAUTHORIZED, format!("{:#}", e));
+    }
+
+    let record = match route.transform.encode(&headers, &body) {
+        Ok(record) => record,
+        Err(e) => {
+            metrics.rejected.fetch_add(1, Ordering::Relaxed);
+            warn!("{}: rejecting request: {:#}", route.name, e);
+            return (StatusCode::BAD_REQUEST, format!("{:#}", e));
+        }
+    };
+
+    let sequence = match &state.store {
+        None => None,
+        Some(store) => match store_request(store, &route.name, &headers, &body).await {
+            Ok(sequence) => Some(sequence),
+            Err(AppendError::Full) => {
+                metrics.throttled.fetch_add(1, Ordering::Relaxed);
+                warn!("{}: refusing request: replay store is full", route.name);
+                return (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    AppendError::Full.to_string(),
+                );
+            }
+            Err(e) => {
+                metrics.failed.fetch_add(1, Ordering::Relaxed);
+                error!("{}: {}", route.name, e);
+                return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+            }
+        },
+    };
+
+    let mut pipeline = route.pipeline.lock().await;
+    match (pipeline.ingest_batch([record]).await, sequence) {
+        (Ok(()), sequence) => {
+            if let (Some(store), Some(sequence)) = (&state.store, sequence) {
+                store.ack(sequence);
+            }
+            metrics.ingested.fetch_add(1, Ordering::Relaxed);
+            info!("{}: ingested a row", route.name);
+            (StatusCode::ACCEPTED, String::new())
+        }
+        (Err(e), Some(_)) => {
+            metrics.stored.fetch_add(1, Ordering::Relaxed);
+            error!(
+                "{}: failed to ingest, keeping the request for replay: {:#}",
+                route.name, e
+            );
+            (StatusCode::ACCEPTED, String::new())
+        }
+        (Err(e), None) => {
+            metrics.failed.fetch_add(1, Ordering::Relaxed);
+            error!("{}: failed to ingest: {:#}", route.name, e);
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e))
+        }
+    }
+}
+
+/// Append a request to the store, off the async runtime since it waits for the disk
+async fn store_request(
+    store: &Arc<ReplayStore>,
+    route: &str,
+    headers: &HeaderMap,
+    body: &Bytes,
+) -> Result<u64, AppendError> {
+    let request = StoredRequest {
+        route: route.to_string(),
+        headers: headers
+            .iter()
+            .map(|(name, value)| (name.as_str().to_string(), value.as_bytes().to_vec()))
+            .collect(),
+        body: body.to_vec(),
+    };
+    let store = Arc::clone(store);
+    tokio::task::spawn_blocking(move || store.append(&request))
+        .await
+        .map_err(|e| AppendError::Failed(e.into()))?
+}
+
+/// Ingest the requests left in the replay store by the last run, before any new
+/// request is accepted
+///
+/// Each route's rows are ingested as one group and acknowledged in the store once they
+/// all are. Requests for a route that no longer exists, or that no longer fit its
+/// table, are logged and dropped. Fails, leaving the requests in the store, when rows
+/// are not acknowledged.
+pub async fn replay<S: IngestSink>(state: &AppState<S>, replayed: Vec<Replayed>) -> Result<usize> {
+    let Some(store) = &state.store else {
+        return Ok(0);
+    };
+    let mut groups: Vec<(Vec<u64>, Vec<Vec<u8>>)> = vec![Default::default(); state.routes.len()];
+    for frame in replayed {
+        let request = &frame.request;
+        let Some(index) = state
+            .routes
+            .iter()
+            .position(|route| route.name == request.route)
+        else {
+            warn!(
+                "Dropping stored request for route {:?}, which is no longer configured",
+                request.route
+            );
+            store.ack(frame.sequence);
+            continue;
+        };
+        let mut headers = HeaderMap::new();
+        for (name, value) in &request.headers {
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::from_bytes(name.as_bytes()),
+                HeaderValue::from_bytes(value),
+            ) {
+                headers.append(name, value);
+            }
+        }
+        match state.routes[index]
+            .transform
+            .encode(&headers, &request.body)
+        {
+            Ok(record) => {
+                groups[index].0.push(frame.sequence);
+                groups[index].1.push(record);
+            }
+            Err(e) => {
+                warn!(
+                    "{}: dropping stored request that no longer fits the table: {:#}",
+                    request.route, e
+                );
+                store.ack(frame.sequence);
+            }
+        }
+    }
+
+    let mut ingested = 0;
+    for (route, (sequences, records)) in state.routes.iter().zip(groups) {
+        if records.is_empty() {
+            continue;
+        }
+        let mut pipeline = route.pipeline.lock().await;
+        if let Err(e) = pipeline.ingest_batch(records).await {
+            bail!(
+                "{}: replayed rows were not acknowledged, leaving {} requests in the replay store: {:#}",
+                route.name,
+                sequences.len(),
+                e
+            );
+        }
+        for sequence in &sequences {
+            store.ack(*sequence);
+        }
+        route
+            .metrics
+            .ingested
+            .fetch_add(sequences.len() as u64, Ordering::Relaxed);
+        info!(
+            "{}: replayed {} stored requests",
+            route.name,
+            sequences.len()
+        );
+        ingested += sequences.len();
+    }
+    Ok(ingested)
+}
+
+/// Request counts in the Prometheus text format
+async fn metrics<S: IngestSink>(State(state): State<AppState<S>>) -> String {
+    let mut text = String::from(
+        "# HELP webhook_requests_total Webhook requests by route and outcome\n\
+         # TYPE webhook_requests_total counter\n",
+    );
+    for route in state.routes.iter() {
+        let metrics = &route.metrics;
+        for (outcome, count) in [
+            ("ingested", &metrics.ingested),
+            ("unauthorized", &metrics.unauthorized),
+            ("rejected", &metrics.rejected),
+            ("failed", &metrics.failed),
+            ("stored", &metrics.stored),
+            ("throttled", &metrics.throttled),
+        ] {
+            let _ = writeln!(
+                text,
+                "webhook_requests_total{{route=\"{}\",outcome=\"{}\"}} {}",
+                route.name,
+                outcome,
+                count.load(Ordering::Relaxed)
+            );
+        }
+    }
+    text
+}
+
+/// Current time in seconds since Unix epoch
+fn unix_time() -> Result<u64> {
+    Ok(std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .context("Failed to get system time")?
+        .as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::replay::ReplayLimits;
+    use axum::body::Body;
+    use axum::http::Request;
+    use hmac::{Hmac, Mac};
+    use prost_types::field_descriptor_proto::{Label, Type};
+    use prost_types::{DescriptorProto, FieldDescriptorProto};
+    use sha2::Sha256;
+    use tower::ServiceExt;
+    use zerobus_common::dynamic::DynamicEncoder;
+    use zerobus_common::testing::MockSink;
+
+    const STRIPE_EVENT: &[u8] = include_bytes!("../testdata/stripe_event.json");
+    const DEPLOY: &[u8] = include_bytes!("../testdata/deploy.json");
+
+    fn field(name: &str, number: i32) -> FieldDescriptorProto {
+        FieldDescriptorProto {
+            name: Some(name.to_string()),
+            number: Some(number),
+            label: Some(Label::Optional as i32),
+            r#type: Some(Type::String as i32),
+            ..Default::default()
+        }
+    }
+
+    /// The stripe and deploys routes of the example config, over mock sinks
+    fn routes(stripe: MockSink, deploys: MockSink) -> Vec<Route<MockSink>> {
+        let config = Config::parse(include_str!("../config/routes.example.json")).unwrap();
+        let stripe_table = DescriptorProto {
+            name: Some("table_stripe_events".to_string()),
+            field: [
+                "event_id",
+                "event_type",
+                "object_id",
+                "created",
+                "livemode",
+                "payload",
+            ]
+            .iter()
+            .zip(1..)
+            .map(|(name, number)| field(name, number))
+            .collect(),
+            ..Default::default()
+        };
+        let deploys_table = DescriptorProto {
+            name: Some("table_deploys".to_string()),
+            field: vec![field("service", 1), field("version", 2)],
+            ..Default::default()
+        };
+
+        let route = |index: usize, descriptor: &DescriptorProto, secrets: &[&str], sink| {
+            let route = &config.routes[index];
+            let encoder = DynamicEncoder::new(descriptor)
+                .unwrap()
+                .ignore_unknown_fields(route.ignore_unknown_fields);
+            Route::new(
+                route.name.clone(),
+                route.path.clone(),
+                Verifier::new(
+                    route.verification.clone(),
+                    secrets.iter().map(|s| s.as_bytes().to_vec()).collect(),
+                )
+                .unwrap(),
+                Transform::new(encoder, route).unwrap(),
+                Pipeline::new(sink, 100),
+            )
+        };
+        vec![
+            route(0, &stripe_table, &["whsec_old", "whsec_new"], stripe),
+            route(3, &deploys_table, &[], deploys),
+        ]
+    }
+
+    fn app(stripe: MockSink, deploys: MockSink) -> (Router, AppState<MockSink>) {
+        let state = AppState::new(routes(stripe, deploys));
+        (router(state.clone()), state)
+    }
+
+    fn stripe_signature(secret: &str, timestamp: u64) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(format!("{}.", timestamp).as_bytes());
+        mac.update(STRIPE_EVENT);
+        format!(
+            "t={},v1={}",
+            timestamp,
+            hex::encode(mac.finalize().into_bytes())
+        )
+    }
+
+    async fn post(
+        app: Router,
+        path: &str,
+        signature: Option<String>,
+        body: &'static [u8],
+    ) -> StatusCode {
+        let mut request = Request::post(path).header("Content-Type", "application/json");
+        if let Some(signature) = signature {
+            request = request.header("Stripe-Signature", signature);
+        }
+        let request = request.body(Body::from(body)).unwrap();
+        app.oneshot(request).await.unwrap().status()
+    }
+
+    async fn metrics_text(app: Router) -> String {
+        let request = Request::get("/metrics").body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_requests_are_verified_and_routed() {
+        let stripe = MockSink::default();
+        let deploys = MockSink::default();
+        let (app, _) = app(stripe.clone(), deploys.clone());
+        let now = unix_time().unwrap();
+
+        // Signed with either secret of the rotation
+        for secret in ["whsec_old", "whsec_new"] {
+            let signature = stripe_signature(secret, now);
+            let status = post(
+                app.clone(),
+                "/webhooks/stripe",
+                Some(signature),
+                STRIPE_EVENT,
+            )
+            .await;
+            assert_eq!(StatusCode::ACCEPTED, status);
+        }
+        let status = post(app.clone(), "/webhooks/deploys", None, DEPLOY).await;
+        assert_eq!(StatusCode::ACCEPTED, status);
+
+        assert_eq!(2, stripe.records().len());
+        assert_eq!(1, deploys.records().len());
+    }
+
+    #[tokio::test]
+    async fn test_unverified_requests_are_unauthorized() {
+        let stripe = MockSink::default();
+        let (app, _) = app(stripe.clone(), MockSink::default());
+        let now = unix_time().unwrap();
+
+        let forged = stripe_signature("whsec_guess", now);
+        let stale = stripe_signature("whsec_new", now - 600);
+        for signature in [Some(forged), Some(stale), None] {
+            let status = post(app.clone(), "/webhooks/stripe", signature, STRIPE_EVENT).await;
+            assert_eq!(StatusCode::UNAUTHORIZED, status);
+        }
+
+        assert!(stripe.records().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_metrics_count_outcomes_per_route() {
+        let stripe = MockSink::default().fail_acks_for(|_| true);
+        let (app, state) = app(stripe, MockSink::default());
+        let now = unix_time().unwrap();
+
+        let signature = Some(stripe_signature("whsec_new", now));
+        let failed = post(app.clone(), "/webhooks/stripe", signature, STRIPE_EVENT).await;
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, failed);
+        post(app.clone(), "/webhooks/stripe", None, STRIPE_EVENT).await;
+        post(app.clone(), "/webhooks/deploys", None, DEPLOY).await;
+        let rejected = post(app.clone(), "/webhooks/deploys", None, b"not json").await;
+        assert_eq!(StatusCode::BAD_REQUEST, rejected);
+
+        let text = metrics_text(app).await;
+        assert!(text.contains("webhook_requests_total{route=\"stripe\",outcome=\"failed\"} 1\n"));
+        assert!(
+            text.contains("webhook_requests_total{route=\"stripe\",outcome=\"unauthorized\"} 1\n")
+        );
+        assert!(text.contains("webhook_requests_total{route=\"deploys\",outcome=\"ingested\"} 1\n"));
+        assert!(text.contains("webhook_requests_total{route=\"deploys\",outcome=\"rejected\"} 1\n"));
+        assert_eq!(
+            1,
+            state.routes[1].metrics().ingested.load(Ordering::Relaxed)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stored_requests_are_replayed_on_the_next_start() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = unix_time().unwrap();
+
+        // Zerobus does not acknowledge, but the requests are kept
+        let (store, _) = ReplayStore::open(dir.path(), ReplayLimits::default()).unwrap();
+        let store = Arc::new(store);
+        let state = AppState::new(routes(
+            MockSink::default().fail_acks_for(|_| true),
+            MockSink::default(),
+        ))
+        .replay_store(Arc::clone(&store));
+        let app = router(state.clone());
+        let signature = Some(stripe_signature("whsec_new", now));
+        let status = post(app.clone(), "/webhooks/stripe", signature, STRIPE_EVENT).await;
+        assert_eq!(StatusCode::ACCEPTED, status);
+        let status = post(app.clone(), "/webhooks/deploys", None, DEPLOY).await;
+        assert_eq!(StatusCode::ACCEPTED, status);
+        assert!(metrics_text(app)
+            .await
+            .contains("webhook_requests_total{route=\"stripe\",outcome=\"stored\"} 1\n"));
+        assert_eq!(1, store.unacked());
+        // As on shutdown, so the acknowledged request is not replayed
+        store.trim().unwrap();
+        drop(state);
+        drop(store);
+
+        // The next start ingests the stored request, with the headers it came with
+        let stripe = MockSink::default();
+        let (store, replayed) = ReplayStore::open(dir.path(), ReplayLimits::default()).unwrap();
+        assert_eq!(1, replayed.len());
+        let store = Arc::new(store);
+        let state = AppState::new(routes(stripe.clone(), MockSink::default()))
+            .replay_store(Arc::clone(&store));
+        assert_eq!(1, replay(&state, replayed).await.unwrap());
+        assert_eq!(1, stripe.records().len());
+        assert_eq!(0, store.unacked());
+        store.trim().unwrap();
+
+        let (_, replayed) = ReplayStore::open(dir.path(), ReplayLimits::default()).unwrap();
+        assert!(replayed.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_full_replay_store_answers_503() {
+        let dir = tempfile::tempdir().unwrap();
+        let limits = ReplayLimits {
+            segment_bytes: 64,
+            max_bytes: 64,
+        };
+        let (store, _) = ReplayStore::open(dir.path(), limits).unwrap();
+        let deploys = MockSink::default();
+        let state = AppState::new(routes(MockSink::default(), deploys.clone()))
+            .replay_store(Arc::new(store));
+        let app = router(state);
+
+        let status = post(app.clone(), "/webhooks/deploys", None, DEPLOY).await;
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, status);
+        assert!(deploys.records().is_empty());
+        assert!(metrics_text(app)
+            .await
+            .contains("webhook_requests_total{route=\"deploys\",outcome=\"throttled\"} 1\n"));
+    }
+}
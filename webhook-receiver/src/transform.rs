@@ -0,0 +1,227 @@
+//! Turning a verified request into a row of the route's table
+
+use anyhow::{bail, Context, Result};
+use axum::http::{HeaderMap, HeaderName};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use zerobus_common::dynamic::DynamicEncoder;
+
+use crate::config::RouteConfig;
+
+/// Builds and encodes the row for each request of a route
+pub struct Transform {
+    encoder: DynamicEncoder,
+    /// Columns and the JSON pointers they are read from; `None` takes the whole body
+    fields: Option<BTreeMap<String, String>>,
+    headers: Vec<(String, HeaderName)>,
+    payload_column: Option<String>,
+}
+
+impl Transform {
+    /// Fails if the route names a column the table does not have
+    pub fn new(encoder: DynamicEncoder, route: &RouteConfig) -> Result<Self> {
+        let columns = route
+            .fields
+            .iter()
+            .flat_map(|fields| fields.keys())
+            .chain(route.headers.keys())
+            .chain(route.payload_column.iter());
+        for column in columns {
+            if !encoder.has_field(column) {
+                bail!(
+                    "Route {:?}: table {} has no column {:?}",
+                    route.name,
+                    route.table,
+                    column
+                );
+            }
+        }
+        let headers = route
+            .headers
+            .iter()
+            .map(|(column, header)| {
+                let header = HeaderName::from_bytes(header.as_bytes())
+                    .with_context(|| format!("Invalid header name {:?}", header))?;
+                Ok((column.clone(), header))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            encoder,
+            fields: route.fields.clone(),
+            headers,
+            payload_column: route.payload_column.clone(),
+        })
+    }
+
+    /// Encode the row for a request
+    ///
+    /// Pointers that match nothing and headers that are missing leave their column
+    /// unset. Objects and arrays picked by a pointer are stored as JSON strings.
+    pub fn encode(&self, headers: &HeaderMap, body: &[u8]) -> Result<Vec<u8>> {
+        let payload = std::str::from_utf8(body).context("Body is not UTF-8")?;
+        let event: Value = serde_json::from_str(payload).context("Body is not valid JSON")?;
+        let Value::Object(object) = event else {
+            bail!("Body is not a JSON object");
+        };
+
+        let mut row = match &self.fields {
+            None => object,
+            Some(fields) => {
+                let event = Value::Object(object);
+                let mut row = Map::new();
+                for (column, pointer) in fields {
+                    match event.pointer(pointer) {
+                        None | Some(Value::Null) => {}
+                        Some(value @ (Value::Object(_) | Value::Array(_))) => {
+                            row.insert(column.clone(), Value::String(value.to_string()));
+                        }
+                        Some(value) => {
+                            row.insert(column.clone(), value.clone());
+                        }
+                    }
+                }
+                row
+            }
+        };
+        for (column, header) in &self.headers {
+            if let Some(value) = headers.get(header).and_then(|value| value.to_str().ok()) {
+                row.insert(column.clone(), Value::from(value));
+            }
+        }
+        if let Some(column) = &self.payload_column {
+            row.insert(column.clone(), Value::from(payload));
+        }
+
+        self.encoder.encode(&Value::Object(row))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use prost::Message;
+    use prost_types::field_descriptor_proto::{Label, Type};
+    use prost_types::{DescriptorProto, FieldDescriptorProto};
+
+    const STRIPE_EVENT: &[u8] = include_bytes!("../testdata/stripe_event.json");
+
+    /// The columns of the Stripe table in the example config
+    #[derive(Clone, PartialEq, Message)]
+    struct StripeEvent {
+        #[prost(string, optional, tag = "1")]
+        event_id: Option<String>,
+        #[prost(string, optional, tag = "2")]
+        event_type: Option<String>,
+        #[prost(string, optional, tag = "3")]
+        object_id: Option<String>,
+        #[prost(int64, optional, tag = "4")]
+        created: Option<i64>,
+        #[prost(bool, optional, tag = "5")]
+        livemode: Option<bool>,
+        #[prost(string, optional, tag = "6")]
+        payload: Option<String>,
+        #[prost(string, optional, tag = "7")]
+        request_id: Option<String>,
+    }
+
+    fn field(name: &str, number: i32, kind: Type) -> FieldDescriptorProto {
+        FieldDescriptorProto {
+            name: Some(name.to_string()),
+            number: Some(number),
+            label: Some(Label::Optional as i32),
+            r#type: Some(kind as i32),
+            ..Default::default()
+        }
+    }
+
+    fn encoder() -> DynamicEncoder {
+        let descriptor = DescriptorProto {
+            name: Some("table_stripe_events".to_string()),
+            field: vec![
+                field("event_id", 1, Type::String),
+                field("event_type", 2, Type::String),
+                field("object_id", 3, Type::String),
+                field("created", 4, Type::Int64),
+                field("livemode", 5, Type::Bool),
+                field("payload", 6, Type::String),
+                field("request_id", 7, Type::String),
+            ],
+            ..Default::default()
+        };
+        DynamicEncoder::new(&descriptor).unwrap()
+    }
+
+    fn stripe_route() -> RouteConfig {
+        let config = Config::parse(include_str!("../config/routes.example.json")).unwrap();
+        config.routes[0].clone()
+    }
+
+    #[test]
+    fn test_fields_are_extracted() {
+        let mut route = stripe_route();
+        route
+            .headers
+            .insert("request_id".to_string(), "Stripe-Request-Id".to_string());
+        let transform = Transform::new(encoder(), &route).unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("Stripe-Request-Id", "req_8KZ3mN2pQ4rS5t".parse().unwrap());
+
+        let record = transform.encode(&headers, STRIPE_EVENT).unwrap();
+
+        let row = StripeEvent::decode(record.as_slice()).unwrap();
+        assert_eq!(
+            StripeEvent {
+                event_id: Some("evt_1PQ8xYLkdIwHu7ixA2zB3cD4".to_string()),
+                event_type: Some("payment_intent.succeeded".to_string()),
+                object_id: Some("pi_3PQ8xXLkdIwHu7ix0Ab1Cd2E".to_string()),
+                created: Some(1718000000),
+                livemode: Some(false),
+                payload: Some(String::from_utf8(STRIPE_EVENT.to_vec()).unwrap()),
+                request_id: Some("req_8KZ3mN2pQ4rS5t".to_string()),
+            },
+            row
+        );
+    }
+
+    #[test]
+    fn test_body_is_the_row_without_fields() {
+        let mut route = stripe_route();
+        route.fields = None;
+        route.payload_column = None;
+        let transform = Transform::new(encoder(), &route).unwrap();
+
+        let body = br#"{"event_id": "evt_1", "created": "42", "object_id": {"nested": true}}"#;
+        let row = StripeEvent::decode(
+            transform
+                .encode(&HeaderMap::new(), body)
+                .unwrap()
+                .as_slice(),
+        )
+        .unwrap();
+
+        assert_eq!(Some("evt_1".to_string()), row.event_id);
+        assert_eq!(Some(42), row.created);
+        assert_eq!(Some(r#"{"nested":true}"#.to_string()), row.object_id);
+        // Fields that are not columns are rejected unless the route ignores them
+        assert!(transform
+            .encode(&HeaderMap::new(), br#"{"amount": 1}"#)
+            .is_err());
+        assert!(transform.encode(&HeaderMap::new(), b"[]").is_err());
+    }
+
+    #[test]
+    fn test_unknown_columns_are_rejected() {
+        let mut route = stripe_route();
+        route
+            .fields
+            .as_mut()
+            .unwrap()
+            .insert("amount".to_string(), "/data/object/amount".to_string());
+
+        let error = Transform::new(encoder(), &route).err().unwrap();
+
+        assert!(error.to_string().contains("has no column \"amount\""));
+    }
+}
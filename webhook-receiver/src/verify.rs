@@ -0,0 +1,339 @@
+//! Checking a request's signature against the secrets of its route
+
+use anyhow::{anyhow, bail, Context, Result};
+use axum::http::HeaderMap;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use hmac::digest::KeyInit;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use sha2::Sha256;
+
+use crate::config::{Encoding, Verification};
+
+/// Verifies requests with one scheme and any of a route's current secrets
+pub struct Verifier {
+    scheme: Verification,
+    secrets: Vec<Vec<u8>>,
+}
+
+impl Verifier {
+    pub fn new(scheme: Verification, secrets: Vec<Vec<u8>>) -> Result<Self> {
+        if scheme != Verification::None && secrets.is_empty() {
+            bail!("Signed schemes need at least one secret");
+        }
+        Ok(Self { scheme, secrets })
+    }
+
+    /// Check the request, `now` being the current Unix time in seconds
+    ///
+    /// Digests are compared in constant time, so response times do not reveal how much
+    /// of a forged signature was right.
+    pub fn verify(&self, headers: &HeaderMap, body: &[u8], now: u64) -> Result<()> {
+        match &self.scheme {
+            Verification::HmacSha256 {
+                header,
+                prefix,
+                encoding,
+            } => {
+                let digest = header_digest(headers, header, prefix, *encoding)?;
+                self.check::<Hmac<Sha256>>(&[body], &digest)
+            }
+            Verification::HmacSha1 {
+                header,
+                prefix,
+                encoding,
+            } => {
+                let digest = header_digest(headers, header, prefix, *encoding)?;
+                self.check::<Hmac<Sha1>>(&[body], &digest)
+            }
+            Verification::Stripe {
+                header,
+                tolerance_secs,
+            } => self.verify_stripe(headers, header, *tolerance_secs, body, now),
+            Verification::None => Ok(()),
+        }
+    }
+
+    /// Stripe signs `<timestamp>.<body>` and sends `t=<timestamp>,v1=<hex>`
+    ///
+    /// While Stripe rolls a secret the header carries one `v1` per secret, and any of
+    /// them may match. The timestamp is covered by the signature, so checking it
+    /// against `tolerance_secs` keeps a captured request from being replayed later.
+    fn verify_stripe(
+        &self,
+        headers: &HeaderMap,
+        header: &str,
+        tolerance_secs: u64,
+        body: &[u8],
+        now: u64,
+    ) -> Result<()> {
+        let value = header_value(headers, header)?;
+        let mut timestamp = None;
+        let mut signatures = Vec::new();
+        for item in value.split(',') {
+            match item.trim().split_once('=') {
+                Some(("t", t)) => timestamp = t.parse::<u64>().ok(),
+                Some(("v1", signature)) => signatures.extend(hex::decode(signature).ok()),
+                // v0 is a test-mode signature with a different scheme
+                _ => {}
+            }
+        }
+        let timestamp = timestamp.with_context(|| format!("Malformed {} header", header))?;
+        if signatures.is_empty() {
+            bail!("{} header has no v1 signature", header);
+        }
+
+        let signed_prefix = format!("{}.", timestamp);
+        let signed = [signed_prefix.as_bytes(), body];
+        if !signatures
+            .iter()
+            .any(|signature| self.check::<Hmac<Sha256>>(&signed, signature).is_ok())
+        {
+            bail!("Signature does not match the payload");
+        }
+
+        let age = now.abs_diff(timestamp);
+        if age > tolerance_secs {
+            bail!(
+                "Timestamp is {}s away from now, more than the {}s tolerance",
+                age,
+                tolerance_secs
+            );
+        }
+        Ok(())
+    }
+
+    /// Whether `digest` is the MAC of `parts` under any of the secrets
+    fn check<M: Mac + KeyInit>(&self, parts: &[&[u8]], digest: &[u8]) -> Result<()> {
+        let matches = self.secrets.iter().any(|secret| {
+            let mut mac =
+                <M as Mac>::new_from_slice(secret).expect("HMAC takes keys of any length");
+            for part in parts {
+                mac.update(part);
+            }
+            mac.verify_slice(digest).is_ok()
+        });
+        if !matches {
+            bail!("Signature does not match the payload");
+        }
+        Ok(())
+    }
+}
+
+fn header_value<'a>(headers: &'a HeaderMap, header: &str) -> Result<&'a str> {
+    headers
+        .get(header)
+        .and_then(|value| value.to_str().ok())
+        .with_context(|| format!("Missing {} header", header))
+}
+
+fn header_digest(
+    headers: &HeaderMap,
+    header: &str,
+    prefix: &str,
+    encoding: Encoding,
+) -> Result<Vec<u8>> {
+    let value = header_value(headers, header)?;
+    let digest = value
+        .trim()
+        .strip_prefix(prefix)
+        .ok_or_else(|| anyhow!("{} header does not start with {:?}", header, prefix))?;
+    match encoding {
+        Encoding::Hex => hex::decode(digest).ok(),
+        Encoding::Base64 => BASE64_STANDARD.decode(digest).ok(),
+    }
+    .with_context(|| format!("Malformed {} header", header))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STRIPE_EVENT: &[u8] = include_bytes!("../testdata/stripe_event.json");
+    const SHOPIFY_ORDER: &[u8] = include_bytes!("../testdata/shopify_order.json");
+    const GITHUB_PING: &[u8] = include_bytes!("../testdata/github_ping.json");
+
+    // Signatures of the fixtures, computed independently of this module
+    const STRIPE_TIMESTAMP: u64 = 1718000000;
+    const STRIPE_V1_OLD: &str = "500213c02932ca46e70ff8042683ed7c31b8464b59eb722ee8eb372a6f51da98";
+    const STRIPE_V1_NEW: &str = "18ccf082f939ec858764e276a41338c56dacc8faf05cedad691222042bd18202";
+    const SHOPIFY_HMAC: &str = "Ik/d3hCEqk3r1WvVaGw4k90p/60WEEzDyt4rTPXPCgc=";
+    const GITHUB_SHA1: &str = "sha1=4508f572e34d37788f2a4abf69ea7322e0b71fc2";
+
+    fn headers(name: &str, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            axum::http::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+            value.parse().unwrap(),
+        );
+        headers
+    }
+
+    fn secrets(secrets: &[&str]) -> Vec<Vec<u8>> {
+        secrets.iter().map(|s| s.as_bytes().to_vec()).collect()
+    }
+
+    fn stripe(keys: &[&str]) -> Verifier {
+        let scheme = Verification::Stripe {
+            header: "Stripe-Signature".to_string(),
+            tolerance_secs: 300,
+        };
+        Verifier::new(scheme, secrets(keys)).unwrap()
+    }
+
+    fn stripe_header(signatures: &[&str]) -> HeaderMap {
+        let mut value = format!("t={}", STRIPE_TIMESTAMP);
+        for signature in signatures {
+            value.push_str(&format!(",v1={}", signature));
+        }
+        headers("Stripe-Signature", &value)
+    }
+
+    #[test]
+    fn test_stripe_signature() {
+        let verifier = stripe(&["whsec_test_new"]);
+        let header = stripe_header(&[STRIPE_V1_NEW]);
+
+        verifier
+            .verify(&header, STRIPE_EVENT, STRIPE_TIMESTAMP + 10)
+            .unwrap();
+
+        let tampered = String::from_utf8_lossy(STRIPE_EVENT).replace("2000", "1");
+        assert!(verifier
+            .verify(&header, tampered.as_bytes(), STRIPE_TIMESTAMP)
+            .is_err());
+        let v0_only = headers(
+            "Stripe-Signature",
+            &format!("t={},v0=abcd", STRIPE_TIMESTAMP),
+        );
+        assert!(verifier
+            .verify(&v0_only, STRIPE_EVENT, STRIPE_TIMESTAMP)
+            .is_err());
+        assert!(verifier
+            .verify(&HeaderMap::new(), STRIPE_EVENT, STRIPE_TIMESTAMP)
+            .is_err());
+    }
+
+    #[test]
+    fn test_stripe_timestamp_tolerance() {
+        let verifier = stripe(&["whsec_test_new"]);
+        let header = stripe_header(&[STRIPE_V1_NEW]);
+
+        verifier
+            .verify(&header, STRIPE_EVENT, STRIPE_TIMESTAMP + 300)
+            .unwrap();
+        verifier
+            .verify(&header, STRIPE_EVENT, STRIPE_TIMESTAMP - 300)
+            .unwrap();
+
+        // A captured request replayed later, or one from a sender whose clock is far ahead
+        let error = verifier
+            .verify(&header, STRIPE_EVENT, STRIPE_TIMESTAMP + 301)
+            .unwrap_err();
+        assert!(error.to_string().contains("301s away from now"));
+        assert!(verifier
+            .verify(&header, STRIPE_EVENT, STRIPE_TIMESTAMP - 301)
+            .is_err());
+
+        // The timestamp is signed, so it cannot be moved forward to pass the check
+        let moved = headers(
+            "Stripe-Signature",
+            &format!("t={},v1={}", STRIPE_TIMESTAMP + 1000, STRIPE_V1_NEW),
+        );
+        let error = verifier
+            .verify(&moved, STRIPE_EVENT, STRIPE_TIMESTAMP + 1000)
+            .unwrap_err();
+        assert_eq!("Signature does not match the payload", error.to_string());
+    }
+
+    #[test]
+    fn test_rotated_secrets() {
+        let header_old = stripe_header(&[STRIPE_V1_OLD]);
+        let header_new = stripe_header(&[STRIPE_V1_NEW]);
+        // While Stripe rolls its secret, it signs with both
+        let header_both = stripe_header(&[STRIPE_V1_OLD, STRIPE_V1_NEW]);
+        let now = STRIPE_TIMESTAMP;
+
+        // Before the rotation only the old secret is configured
+        let before = stripe(&["whsec_test_old"]);
+        before.verify(&header_old, STRIPE_EVENT, now).unwrap();
+        before.verify(&header_both, STRIPE_EVENT, now).unwrap();
+        assert!(before.verify(&header_new, STRIPE_EVENT, now).is_err());
+
+        // During it both are, so requests signed with either are accepted
+        let during = stripe(&["whsec_test_old", "whsec_test_new"]);
+        during.verify(&header_old, STRIPE_EVENT, now).unwrap();
+        during.verify(&header_new, STRIPE_EVENT, now).unwrap();
+        during.verify(&header_both, STRIPE_EVENT, now).unwrap();
+
+        // After it the old secret is removed and no longer accepted
+        let after = stripe(&["whsec_test_new"]);
+        assert!(after.verify(&header_old, STRIPE_EVENT, now).is_err());
+        after.verify(&header_new, STRIPE_EVENT, now).unwrap();
+
+        // The same holds for plain HMAC headers
+        let scheme = Verification::HmacSha256 {
+            header: "X-Shopify-Hmac-Sha256".to_string(),
+            prefix: String::new(),
+            encoding: Encoding::Base64,
+        };
+        let shopify = Verifier::new(scheme, secrets(&["shpss_rotated_out", "shpss_test"])).unwrap();
+        let header = headers("X-Shopify-Hmac-Sha256", SHOPIFY_HMAC);
+        shopify.verify(&header, SHOPIFY_ORDER, now).unwrap();
+    }
+
+    #[test]
+    fn test_hmac_sha256_base64() {
+        let scheme = Verification::HmacSha256 {
+            header: "X-Shopify-Hmac-Sha256".to_string(),
+            prefix: String::new(),
+            encoding: Encoding::Base64,
+        };
+        let verifier = Verifier::new(scheme, secrets(&["shpss_test"])).unwrap();
+
+        let header = headers("X-Shopify-Hmac-Sha256", SHOPIFY_HMAC);
+        verifier.verify(&header, SHOPIFY_ORDER, 0).unwrap();
+
+        let wrong = Verifier::new(verifier.scheme.clone(), secrets(&["another"])).unwrap();
+        assert!(wrong.verify(&header, SHOPIFY_ORDER, 0).is_err());
+        let hex_instead = headers("X-Shopify-Hmac-Sha256", &"ab".repeat(32));
+        assert!(verifier.verify(&hex_instead, SHOPIFY_ORDER, 0).is_err());
+        assert!(verifier
+            .verify(&HeaderMap::new(), SHOPIFY_ORDER, 0)
+            .is_err());
+    }
+
+    #[test]
+    fn test_hmac_sha1_with_prefix() {
+        let scheme = Verification::HmacSha1 {
+            header: "X-Hub-Signature".to_string(),
+            prefix: "sha1=".to_string(),
+            encoding: Encoding::Hex,
+        };
+        let verifier = Verifier::new(scheme, secrets(&["legacy-secret"])).unwrap();
+
+        verifier
+            .verify(&headers("X-Hub-Signature", GITHUB_SHA1), GITHUB_PING, 0)
+            .unwrap();
+
+        let unprefixed = headers("X-Hub-Signature", &GITHUB_SHA1[5..]);
+        let error = verifier.verify(&unprefixed, GITHUB_PING, 0).unwrap_err();
+        assert!(error.to_string().contains("does not start with \"sha1=\""));
+        assert!(verifier
+            .verify(&headers("X-Hub-Signature", GITHUB_SHA1), SHOPIFY_ORDER, 0)
+            .is_err());
+    }
+
+    #[test]
+    fn test_none_accepts_everything() {
+        let verifier = Verifier::new(Verification::None, Vec::new()).unwrap();
+        verifier.verify(&HeaderMap::new(), b"anything", 0).unwrap();
+
+        let scheme = Verification::HmacSha256 {
+            header: "X-Signature".to_string(),
+            prefix: String::new(),
+            encoding: Encoding::Hex,
+        };
+        assert!(Verifier::new(scheme, Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,165 @@
+//! Kills a process while it appends to the replay store, then checks what the next
+//! start replays: every request stored and not acknowledged, intact, and no request
+//! acknowledged before a trim.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use webhook_receiver::replay::{ReplayLimits, ReplayStore, StoredRequest};
+
+/// Set in the child process to the store's directory
+const CHILD_DIR: &str = "REPLAY_CRASH_CHILD_DIR";
+
+/// Small segments, so the child rotates and trims many times before it is killed
+fn limits() -> ReplayLimits {
+    ReplayLimits {
+        segment_bytes: 64 * 1024,
+        ..Default::default()
+    }
+}
+
+/// A request large enough that a kill often lands partway through writing it
+fn request(index: u64) -> StoredRequest {
+    StoredRequest {
+        route: "deploys".to_string(),
+        headers: vec![("content-type".to_string(), b"application/json".to_vec())],
+        body: format!(
+            r#"{{"index": {}, "padding": "{}"}}"#,
+            index,
+            "x".repeat(4096)
+        )
+        .into_bytes(),
+    }
+}
+
+fn index(request: &StoredRequest) -> u64 {
+    let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+    body["index"].as_u64().unwrap()
+}
+
+/// The child: store requests until killed, acknowledging every other one and trimming
+/// every tenth, reporting each step on stdout once it is done
+#[test]
+#[ignore = "run in a child process by test_replay_after_kill"]
+fn append_until_killed() {
+    let Ok(dir) = std::env::var(CHILD_DIR) else {
+        return;
+    };
+    let (store, replayed) = ReplayStore::open(Path::new(&dir), limits()).unwrap();
+    assert!(replayed.is_empty());
+    for index in 0.. {
+        let sequence = store.append(&request(index)).unwrap();
+        println!("stored {} {}", sequence, index);
+        if index % 2 == 0 {
+            store.ack(sequence);
+            println!("acked {}", sequence);
+        }
+        if index % 10 == 9 {
+            store.trim().unwrap();
+            println!("trimmed");
+        }
+    }
+}
+
+/// What the child reported before it was killed
+#[derive(Default)]
+struct Reported {
+    /// Index of the request stored under each sequence number
+    stored: BTreeMap<u64, u64>,
+    /// Acknowledged sequence numbers, and whether a trim finished after each
+    acked: BTreeMap<u64, bool>,
+}
+
+/// Run the child in `dir` and kill it once it has stored `kill_after` requests
+fn run_and_kill(dir: &Path, kill_after: usize) -> Reported {
+    let mut child = Command::new(std::env::current_exe().unwrap())
+        .args(["append_until_killed", "--exact", "--ignored", "--nocapture"])
+        .env(CHILD_DIR, dir)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let mut reported = Reported::default();
+    let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+    while reported.stored.len() < kill_after {
+        let line = lines
+            .next()
+            .expect("child exited before it was killed")
+            .unwrap();
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["stored", sequence, index] => {
+                reported
+                    .stored
+                    .insert(sequence.parse().unwrap(), index.parse().unwrap());
+            }
+            ["acked", sequence] => {
+                reported.acked.insert(sequence.parse().unwrap(), false);
+            }
+            ["trimmed"] => reported
+                .acked
+                .values_mut()
+                .for_each(|trimmed| *trimmed = true),
+            _ => {}
+        }
+    }
+    // SIGKILL, with the child most likely partway through its next append
+    child.kill().unwrap();
+    child.wait().unwrap();
+    reported
+}
+
+#[test]
+fn test_replay_after_kill() {
+    let dir = tempfile::tempdir().unwrap();
+    for (round, kill_after) in [40, 117, 263, 75, 190].into_iter().enumerate() {
+        let reported = run_and_kill(dir.path(), kill_after);
+
+        let (store, replayed) = ReplayStore::open(dir.path(), limits()).unwrap();
+        let replayed: BTreeMap<u64, u64> = replayed
+            .iter()
+            .map(|frame| (frame.sequence, index(&frame.request)))
+            .collect();
+
+        // Nothing stored and not acknowledged is lost, and it comes back intact
+        for (sequence, index) in &reported.stored {
+            if !reported.acked.contains_key(sequence) {
+                assert_eq!(
+                    Some(index),
+                    replayed.get(sequence),
+                    "round {}: request {} was lost",
+                    round,
+                    sequence
+                );
+            }
+        }
+        let last_reported = *reported.stored.keys().next_back().unwrap();
+        for (sequence, index) in &replayed {
+            match reported.stored.get(sequence) {
+                Some(stored) => assert_eq!(stored, index, "round {}", round),
+                // Synced after the last line the child got to print
+                None => assert!(*sequence > last_reported, "round {}", round),
+            }
+            // Acknowledged requests are replayed only if no trim followed the ack
+            assert_ne!(
+                Some(&true),
+                reported.acked.get(sequence),
+                "round {}: request {} was acknowledged and checkpointed",
+                round,
+                sequence
+            );
+        }
+
+        // Acknowledge the replay, as the receiver does, so the next round starts clean
+        let sequences: BTreeSet<u64> = replayed.keys().copied().collect();
+        for sequence in sequences {
+            store.ack(sequence);
+        }
+        store.trim().unwrap();
+    }
+
+    let (_, replayed) = ReplayStore::open(dir.path(), limits()).unwrap();
+    assert!(replayed.is_empty());
+}